@@ -0,0 +1,33 @@
+#!/bin/bash
+# Builds the Quick Look preview extension as a universal binary and
+# assembles the signed VLArchQuickLook.appex that quicklook/tauri.conf.json
+# copies into the app's PlugIns.
+set -e
+
+VERSION=$(jq -r '.version' package.json)
+SRC_DIR=src-tauri/quicklook
+TARGET_DIR=../../target
+APPEX=$TARGET_DIR/quicklook/VLArchQuickLook.appex
+BIN=vlarch-quicklook
+TARGETS="aarch64-apple-darwin x86_64-apple-darwin"
+# Ad-hoc signing when there is no identity, for local builds
+IDENTITY=${APPLE_SIGNING_IDENTITY:--}
+
+BINARIES=()
+for target in $TARGETS; do
+  echo "Building the Quick Look extension for $target..."
+  cargo build --manifest-path src-tauri/Cargo.toml --release --features quicklook --bin $BIN --target $target
+  BINARIES+=("$TARGET_DIR/$target/release/$BIN")
+done
+
+rm -rf "$APPEX"
+mkdir -p "$APPEX/Contents/MacOS"
+lipo -create -output "$APPEX/Contents/MacOS/$BIN" "${BINARIES[@]}"
+sed "s/\$VERSION/$VERSION/g" "$SRC_DIR/Info.plist" > "$APPEX/Contents/Info.plist"
+
+SIGN_ARGS=(--force --options runtime --entitlements "$SRC_DIR/QuickLook.entitlements" --sign "$IDENTITY")
+if [ "$IDENTITY" != "-" ]; then
+  SIGN_ARGS+=(--timestamp)
+fi
+codesign "${SIGN_ARGS[@]}" "$APPEX"
+echo "Built $APPEX"
@@ -0,0 +1,16 @@
+#!/bin/bash
+# Builds the library's DLL with the Explorer thumbnail provider for the
+# target given as the first argument, and copies it to where
+# thumbnails/tauri.conf.json bundles it from as vlarch-thumbnails.dll.
+set -e
+
+TARGET=${1:?usage: build-thumbnails.sh <target>}
+TARGET_DIR=../../target
+DLL=$TARGET_DIR/thumbnails/vlarch-thumbnails.dll
+
+echo "Building the thumbnail provider for $TARGET..."
+cargo build --manifest-path src-tauri/Cargo.toml --release --features thumbnails --lib --target "$TARGET"
+
+mkdir -p "$(dirname "$DLL")"
+cp "$TARGET_DIR/$TARGET/release/vlarchlib.dll" "$DLL"
+echo "Built $DLL"
@@ -0,0 +1,226 @@
+//! Opening books up to their first chapter, and parsing all their chapters
+//! for the search index, on books made up here: an EPUB, a PDF and a CBZ.
+//! Books in the directory `VLARCH_BENCH_BOOKS` names are measured as well.
+//!
+//! Runs are compared with the one before, and changes beyond the noise
+//! threshold below are reported as regressions or improvements:
+//!
+//! ```text
+//! cargo bench --bench parsers -- --save-baseline main
+//! cargo bench --bench parsers -- --baseline main
+//! ```
+
+use std::fs::File;
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use image::{ImageFormat, Rgb, RgbImage};
+use lopdf::content::{Content, Operation};
+use lopdf::{dictionary, Document, Object, Stream};
+use vlarchlib::bench;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+const CHAPTERS: usize = 40;
+const PARAGRAPHS: usize = 60;
+const PAGES: usize = 40;
+const LINES: usize = 40;
+const COMIC_PAGES: usize = 24;
+
+const PARAGRAPH: &str = "It was the best of times, it was the worst of times, it was the \
+    age of wisdom, it was the age of foolishness, it was the epoch of belief, it was the \
+    epoch of incredulity, it was the season of Light, it was the season of Darkness.";
+
+/// Made books, by name.
+fn fixtures() -> Vec<(String, PathBuf)> {
+    let dir = std::env::temp_dir().join("vlarch-bench");
+    std::fs::create_dir_all(&dir).expect("bench dir");
+    let mut books = Vec::new();
+    for (name, make) in [
+        ("book.epub", make_epub as fn(&Path)),
+        ("book.pdf", make_pdf),
+        ("comic.cbz", make_cbz),
+    ] {
+        let path = dir.join(name);
+        if !path.exists() {
+            make(&path);
+        }
+        books.push((name.to_string(), path));
+    }
+    if let Some(dir) = std::env::var_os("VLARCH_BENCH_BOOKS") {
+        let mut own = std::fs::read_dir(dir)
+            .expect("VLARCH_BENCH_BOOKS")
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| bench::open_first_chapter(path).is_ok())
+            .collect::<Vec<_>>();
+        own.sort();
+        for path in own {
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            books.push((name, path));
+        }
+    }
+    books
+}
+
+fn make_epub(path: &Path) {
+    let mut zip = ZipWriter::new(File::create(path).expect("epub"));
+    let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    let deflated = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    zip.start_file("mimetype", stored).unwrap();
+    zip.write_all(b"application/epub+zip").unwrap();
+    zip.start_file("META-INF/container.xml", deflated).unwrap();
+    zip.write_all(
+        br#"<?xml version="1.0"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles><rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/></rootfiles>
+</container>"#,
+    )
+    .unwrap();
+
+    let mut manifest = String::new();
+    let mut spine = String::new();
+    for i in 0..CHAPTERS {
+        manifest.push_str(&format!(
+            r#"<item id="c{i}" href="text/chapter{i}.xhtml" media-type="application/xhtml+xml"/>"#
+        ));
+        spine.push_str(&format!(r#"<itemref idref="c{i}"/>"#));
+        let body = (0..PARAGRAPHS)
+            .map(|p| format!("<p id=\"p{p}\">{PARAGRAPH}</p>"))
+            .collect::<String>();
+        zip.start_file(format!("OEBPS/text/chapter{i}.xhtml"), deflated)
+            .unwrap();
+        write!(
+            zip,
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml"><head><title>Chapter {i}</title></head>
+<body><h1>Chapter {i}</h1>{body}</body></html>"#
+        )
+        .unwrap();
+    }
+    zip.start_file("OEBPS/content.opf", deflated).unwrap();
+    write!(
+        zip,
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="id">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="id">urn:uuid:00000000-0000-0000-0000-000000000000</dc:identifier>
+    <dc:title>Benchmark</dc:title><dc:language>en</dc:language>
+  </metadata>
+  <manifest>{manifest}</manifest>
+  <spine>{spine}</spine>
+</package>"#
+    )
+    .unwrap();
+    zip.finish().unwrap();
+}
+
+fn make_pdf(path: &Path) {
+    let mut doc = Document::with_version("1.5");
+    let pages_id = doc.new_object_id();
+    let font_id = doc.add_object(dictionary! {
+        "Type" => "Font",
+        "Subtype" => "Type1",
+        "BaseFont" => "Times-Roman",
+    });
+    let resources_id = doc.add_object(dictionary! {
+        "Font" => dictionary! { "F1" => font_id },
+    });
+    let mut kids = Vec::new();
+    for _ in 0..PAGES {
+        let mut operations = vec![
+            Operation::new("BT", vec![]),
+            Operation::new("Tf", vec!["F1".into(), 10.into()]),
+            Operation::new("TL", vec![14.into()]),
+            Operation::new("Td", vec![40.into(), 800.into()]),
+        ];
+        for _ in 0..LINES {
+            operations.push(Operation::new(
+                "Tj",
+                vec![Object::string_literal(&PARAGRAPH[..90])],
+            ));
+            operations.push(Operation::new("T*", vec![]));
+        }
+        operations.push(Operation::new("ET", vec![]));
+        let content = Content { operations }.encode().unwrap();
+        let content_id = doc.add_object(Stream::new(dictionary! {}, content));
+        kids.push(Object::from(doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "Contents" => content_id,
+        })));
+    }
+    doc.objects.insert(
+        pages_id,
+        Object::Dictionary(dictionary! {
+            "Type" => "Pages",
+            "Kids" => kids,
+            "Count" => PAGES as i64,
+            "Resources" => resources_id,
+            "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+        }),
+    );
+    let catalog_id = doc.add_object(dictionary! {
+        "Type" => "Catalog",
+        "Pages" => pages_id,
+    });
+    doc.trailer.set("Root", catalog_id);
+    doc.save(path).expect("pdf");
+}
+
+fn make_cbz(path: &Path) {
+    let mut zip = ZipWriter::new(File::create(path).expect("cbz"));
+    let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    for i in 0..COMIC_PAGES {
+        let page = RgbImage::from_fn(1200, 1800, |x, y| {
+            let shade = ((x / 40 + y / 40 + i as u32) % 2 * 200) as u8;
+            Rgb([shade, shade, shade])
+        });
+        let mut png = Cursor::new(Vec::new());
+        page.write_to(&mut png, ImageFormat::Png).unwrap();
+        // Comics are mostly stored, their images are compressed already
+        zip.start_file(format!("{i:03}.png"), stored).unwrap();
+        zip.write_all(png.get_ref()).unwrap();
+    }
+    zip.finish().unwrap();
+}
+
+fn open_first_chapter(c: &mut Criterion) {
+    let mut group = c.benchmark_group("open_first_chapter");
+    for (name, path) in fixtures() {
+        group.bench_function(&name, |b| {
+            b.iter(|| bench::open_first_chapter(&path).expect("open"))
+        });
+    }
+    group.finish();
+}
+
+fn extract_chapters(c: &mut Criterion) {
+    let mut group = c.benchmark_group("extract_chapters");
+    group.sample_size(20);
+    for (name, path) in fixtures() {
+        if bench::extract_chapters(&path).is_err() {
+            continue;
+        }
+        group.bench_function(&name, |b| {
+            b.iter(|| bench::extract_chapters(&path).expect("chapters"))
+        });
+    }
+    group.finish();
+}
+
+fn config() -> Criterion {
+    Criterion::default()
+        .noise_threshold(0.05)
+        .significance_level(0.01)
+        .measurement_time(Duration::from_secs(5))
+}
+
+criterion_group! {
+    name = benches;
+    config = config();
+    targets = open_first_chapter, extract_chapters
+}
+criterion_main!(benches);
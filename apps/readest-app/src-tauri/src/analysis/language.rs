@@ -0,0 +1,240 @@
+//! Guesses the language a book is written in: from the script most of its
+//! letters are in and, for the scripts several languages share, from whose
+//! most common words turn up most often in it.
+
+/// Words of the text kept to tell apart the languages of a script.
+const SAMPLE_WORDS: usize = 20_000;
+/// Letters below which there is too little text to tell.
+const MIN_LETTERS: u64 = 200;
+/// Share of the sampled words that have to be common words of the best
+/// language for the guess to stand.
+const MIN_SHARE: f64 = 0.05;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Script {
+    Latin,
+    Cyrillic,
+    Greek,
+    Arabic,
+    Hebrew,
+    Devanagari,
+    Thai,
+    Hangul,
+    Han,
+    Kana,
+}
+
+const SCRIPTS: [Script; 10] = [
+    Script::Latin,
+    Script::Cyrillic,
+    Script::Greek,
+    Script::Arabic,
+    Script::Hebrew,
+    Script::Devanagari,
+    Script::Thai,
+    Script::Hangul,
+    Script::Han,
+    Script::Kana,
+];
+
+fn script(c: char) -> Option<Script> {
+    Some(match c as u32 {
+        0x41..=0x24f | 0x1e00..=0x1eff => Script::Latin,
+        0x370..=0x3ff | 0x1f00..=0x1fff => Script::Greek,
+        0x400..=0x52f => Script::Cyrillic,
+        0x590..=0x5ff => Script::Hebrew,
+        0x600..=0x6ff | 0x750..=0x77f | 0xfb50..=0xfdff | 0xfe70..=0xfeff => Script::Arabic,
+        0x900..=0x97f => Script::Devanagari,
+        0xe00..=0xe7f => Script::Thai,
+        0x1100..=0x11ff | 0x3130..=0x318f | 0xac00..=0xd7af => Script::Hangul,
+        0x3040..=0x30ff | 0x31f0..=0x31ff => Script::Kana,
+        0x3400..=0x4dbf | 0x4e00..=0x9fff | 0xf900..=0xfaff | 0x20000..=0x2fa1f => Script::Han,
+        _ => return None,
+    })
+}
+
+struct Profile {
+    code: &'static str,
+    script: Script,
+    /// Its most common words, lowercase.
+    words: &'static [&'static str],
+}
+
+#[rustfmt::skip]
+const PROFILES: &[Profile] = &[
+    Profile { code: "en", script: Script::Latin, words: &[
+        "the", "of", "and", "to", "in", "a", "is", "that", "it", "was", "he", "for", "with", "as",
+        "his", "on", "be", "at", "by", "i", "you", "not", "this", "but", "had", "she", "her", "they",
+    ] },
+    Profile { code: "fr", script: Script::Latin, words: &[
+        "de", "la", "le", "et", "les", "des", "en", "un", "une", "du", "que", "est", "pas", "il",
+        "elle", "pour", "dans", "qui", "ne", "sur", "au", "se", "avec", "plus", "je", "mais", "nous",
+    ] },
+    Profile { code: "de", script: Script::Latin, words: &[
+        "der", "die", "und", "in", "den", "von", "zu", "das", "mit", "sich", "des", "auf", "für",
+        "ist", "im", "dem", "nicht", "ein", "eine", "als", "auch", "es", "er", "sie", "ich", "war",
+    ] },
+    Profile { code: "es", script: Script::Latin, words: &[
+        "de", "la", "que", "el", "en", "y", "a", "los", "del", "se", "las", "por", "un", "para",
+        "con", "no", "una", "su", "al", "lo", "como", "más", "pero", "sus", "le", "ya", "había",
+    ] },
+    Profile { code: "it", script: Script::Latin, words: &[
+        "di", "e", "il", "la", "che", "a", "per", "un", "in", "è", "non", "una", "sono", "del",
+        "della", "si", "le", "lo", "gli", "da", "con", "ma", "come", "anche", "nel", "più", "era",
+    ] },
+    Profile { code: "pt", script: Script::Latin, words: &[
+        "de", "a", "o", "que", "e", "do", "da", "em", "um", "para", "é", "com", "não", "uma", "os",
+        "no", "se", "na", "por", "mais", "as", "dos", "como", "mas", "ao", "ele", "das", "foi",
+    ] },
+    Profile { code: "nl", script: Script::Latin, words: &[
+        "de", "en", "van", "het", "een", "in", "is", "dat", "op", "te", "zijn", "met", "voor",
+        "niet", "aan", "er", "die", "maar", "ook", "als", "bij", "door", "om", "hij", "ik", "je",
+    ] },
+    Profile { code: "sv", script: Script::Latin, words: &[
+        "och", "i", "att", "det", "som", "en", "på", "är", "av", "för", "med", "till", "den",
+        "har", "de", "inte", "om", "ett", "han", "men", "var", "jag", "hon", "sig", "så", "vi",
+    ] },
+    Profile { code: "da", script: Script::Latin, words: &[
+        "og", "i", "at", "det", "er", "en", "til", "på", "de", "med", "af", "som", "for", "ikke",
+        "der", "den", "han", "jeg", "var", "sig", "har", "hun", "et", "men", "efter", "hvad",
+    ] },
+    Profile { code: "no", script: Script::Latin, words: &[
+        "og", "i", "det", "er", "en", "til", "på", "som", "at", "de", "med", "ikke", "den", "for",
+        "av", "har", "han", "jeg", "var", "seg", "hun", "et", "men", "etter", "hva", "ble",
+    ] },
+    Profile { code: "fi", script: Script::Latin, words: &[
+        "ja", "on", "ei", "se", "että", "hän", "oli", "ole", "mutta", "joka", "kun", "niin",
+        "myös", "tai", "ovat", "sen", "hänen", "mitä", "vain", "jo", "nyt", "kuin", "minä", "sitä",
+    ] },
+    Profile { code: "pl", script: Script::Latin, words: &[
+        "i", "w", "nie", "na", "się", "z", "do", "to", "że", "jest", "jak", "o", "co", "ale", "po",
+        "tak", "za", "od", "jego", "już", "przez", "jej", "czy", "był", "dla", "tylko",
+    ] },
+    Profile { code: "cs", script: Script::Latin, words: &[
+        "a", "se", "na", "je", "v", "že", "to", "s", "z", "do", "o", "jako", "ale", "by", "si",
+        "jsem", "jeho", "tak", "byl", "pro", "když", "jsou", "už", "jak", "po", "bylo",
+    ] },
+    Profile { code: "tr", script: Script::Latin, words: &[
+        "ve", "bir", "bu", "da", "de", "için", "ile", "çok", "ne", "gibi", "daha", "o", "ama",
+        "kadar", "sonra", "olarak", "var", "değil", "her", "ben", "mi", "şey", "diye",
+    ] },
+    Profile { code: "ro", script: Script::Latin, words: &[
+        "și", "de", "în", "a", "la", "că", "pe", "cu", "nu", "un", "o", "din", "se", "este", "mai",
+        "ce", "care", "pentru", "ca", "sau", "să", "fost", "lui", "au",
+    ] },
+    Profile { code: "hu", script: Script::Latin, words: &[
+        "a", "az", "és", "hogy", "nem", "is", "egy", "meg", "de", "van", "ez", "csak", "már",
+        "még", "mint", "volt", "el", "ha", "azt", "ki", "mert", "ami", "vagy", "minden",
+    ] },
+    Profile { code: "id", script: Script::Latin, words: &[
+        "yang", "dan", "di", "itu", "dengan", "untuk", "tidak", "ini", "dari", "dalam", "akan",
+        "pada", "juga", "saya", "ke", "karena", "ada", "bisa", "mereka", "atau",
+    ] },
+    Profile { code: "vi", script: Script::Latin, words: &[
+        "và", "của", "là", "có", "không", "những", "được", "một", "người", "trong", "cho", "này",
+        "các", "đã", "với", "để", "anh", "tôi", "khi", "như", "thì", "ra", "đó", "lại",
+    ] },
+    Profile { code: "ru", script: Script::Cyrillic, words: &[
+        "и", "в", "не", "на", "что", "я", "с", "он", "как", "а", "то", "это", "все", "она", "так",
+        "его", "но", "же", "к", "у", "ты", "из", "за", "бы", "по", "только", "мне", "было",
+    ] },
+    Profile { code: "uk", script: Script::Cyrillic, words: &[
+        "і", "в", "не", "на", "що", "я", "з", "він", "як", "а", "та", "це", "й", "до", "у", "але",
+        "вона", "так", "його", "ти", "за", "ж", "від", "про", "її", "мені", "було",
+    ] },
+    Profile { code: "bg", script: Script::Cyrillic, words: &[
+        "и", "в", "на", "да", "се", "не", "е", "че", "от", "за", "с", "по", "а", "то", "са",
+        "като", "ще", "но", "който", "му", "си", "той", "тя", "беше", "ми",
+    ] },
+    Profile { code: "sr", script: Script::Cyrillic, words: &[
+        "и", "у", "је", "да", "се", "на", "не", "за", "су", "од", "са", "то", "али", "као", "што",
+        "из", "ће", "био", "још", "по", "их", "јер", "само", "ни",
+    ] },
+    Profile { code: "ar", script: Script::Arabic, words: &[
+        "في", "من", "على", "أن", "إلى", "التي", "الذي", "عن", "هذا", "ما", "لا", "كان", "مع",
+        "هذه", "كل", "أو", "ذلك", "قد", "بين", "إن", "ثم", "لم", "هو",
+    ] },
+    Profile { code: "fa", script: Script::Arabic, words: &[
+        "و", "در", "به", "از", "که", "این", "را", "با", "است", "برای", "آن", "یک", "خود", "تا",
+        "بر", "هم", "شد", "می", "کرد", "او", "نیز", "بود",
+    ] },
+    Profile { code: "ur", script: Script::Arabic, words: &[
+        "کے", "میں", "کی", "ہے", "اور", "سے", "کو", "نے", "کا", "یہ", "ایک", "پر", "ہیں", "تھا",
+        "بھی", "کہ", "وہ", "ہو", "جو", "تو", "گیا",
+    ] },
+];
+
+/// Takes in the letters and words of a text, then guesses its language.
+#[derive(Default)]
+pub struct Detector {
+    letters: [u64; SCRIPTS.len()],
+    /// The first words of the scripts with profiles, lowercase.
+    sample: Vec<(Script, String)>,
+}
+
+impl Detector {
+    pub fn letter(&mut self, c: char) {
+        if let Some(script) = script(c) {
+            self.letters[script as usize] += 1;
+        }
+    }
+
+    pub fn wants_words(&self) -> bool {
+        self.sample.len() < SAMPLE_WORDS
+    }
+
+    pub fn word(&mut self, word: &str) {
+        let Some(script) = word.chars().find_map(script) else {
+            return;
+        };
+        if self.wants_words() && PROFILES.iter().any(|p| p.script == script) {
+            self.sample.push((script, word.to_lowercase()));
+        }
+    }
+
+    fn count(&self, script: Script) -> u64 {
+        self.letters[script as usize]
+    }
+
+    /// The ISO 639-1 code of the language most of the text is in, unless
+    /// there is too little of it or it could be several.
+    pub fn language(&self) -> Option<&'static str> {
+        if self.letters.iter().sum::<u64>() < MIN_LETTERS {
+            return None;
+        }
+        let (index, _) = self
+            .letters
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, count)| **count)?;
+        let dominant = SCRIPTS[index];
+        match dominant {
+            Script::Greek => Some("el"),
+            Script::Hebrew => Some("he"),
+            Script::Devanagari => Some("hi"),
+            Script::Thai => Some("th"),
+            Script::Hangul => Some("ko"),
+            // Japanese mixes kana in, which Chinese hardly ever has
+            Script::Han | Script::Kana => {
+                let (han, kana) = (self.count(Script::Han), self.count(Script::Kana));
+                Some(if kana * 20 >= han + kana { "ja" } else { "zh" })
+            }
+            Script::Latin | Script::Cyrillic | Script::Arabic => self.by_words(dominant),
+        }
+    }
+
+    fn by_words(&self, script: Script) -> Option<&'static str> {
+        let words = self
+            .sample
+            .iter()
+            .filter(|(s, _)| *s == script)
+            .map(|(_, word)| word.as_str())
+            .collect::<Vec<_>>();
+        let (profile, hits) = PROFILES
+            .iter()
+            .filter(|p| p.script == script)
+            .map(|p| (p, words.iter().filter(|w| p.words.contains(w)).count()))
+            .max_by_key(|(_, hits)| *hits)?;
+        (hits as f64 >= MIN_SHARE * words.len() as f64 && hits > 0).then_some(profile.code)
+    }
+}
@@ -0,0 +1,321 @@
+//! Text analysis of books: how many words and characters they have, how
+//! long they take to read and which language most of their text is in.
+//! Books are analyzed as they are imported and the results kept in the
+//! `book_analysis` table of the library index, so that the library can
+//! sort and filter by length and language in its queries.
+//!
+//! Chinese and Japanese are written without spaces, so each ideograph and
+//! kana counts as a word, as word processors count them, and they are
+//! read at their own pace. Thai and the other scripts without spaces
+//! between words count a word per run of letters.
+
+mod language;
+
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Mutex;
+
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::Serialize;
+use tauri::{command, AppHandle, Manager, State};
+
+use crate::error::{Error, Result};
+use crate::formats;
+use crate::jobs;
+use crate::library::access;
+use crate::library::db::{self, Book, BookQuery, LibraryDb};
+use crate::utils::now_millis;
+
+/// How fast adults read silently, in words a minute.
+const WORDS_PER_MINUTE: f64 = 238.0;
+/// Characters that join the parts of a word rather than separate words.
+const JOINERS: &[char] = &['\'', '\u{2019}', '-', '\u{2010}', '\u{ad}'];
+
+/// Words of book `b`, `NULL` until it is analyzed.
+pub(crate) const WORDS: &str = "(SELECT a.words FROM book_analysis a WHERE a.book_hash = b.hash)";
+/// Language of book `b`: the one its metadata names or, when it names
+/// none, the one its text is in.
+pub(crate) const LANGUAGE: &str = "COALESCE(NULLIF(NULLIF(b.primary_language, ''), 'und'), \
+     (SELECT a.language FROM book_analysis a WHERE a.book_hash = b.hash))";
+
+/// Ideographs and kana a minute, which Japanese, with more kana, reads
+/// faster.
+fn ideographs_per_minute(language: Option<&str>) -> f64 {
+    match language {
+        Some("ja") => 357.0,
+        _ => 255.0,
+    }
+}
+
+fn is_ideograph(c: char) -> bool {
+    matches!(c as u32,
+        0x3040..=0x30ff | 0x31f0..=0x31ff | 0x3400..=0x4dbf | 0x4e00..=0x9fff
+            | 0xf900..=0xfaff | 0x20000..=0x2fa1f)
+}
+
+/// Whether `c` is part of a word, combining marks included.
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric()
+        || matches!(c as u32,
+            0x300..=0x36f | 0x591..=0x5c7 | 0x610..=0x61a | 0x64b..=0x65f
+                | 0x900..=0x903 | 0x93a..=0x94f | 0x951..=0x957 | 0x962..=0x963
+                | 0xe31 | 0xe34..=0xe3a | 0xe47..=0xe4e)
+}
+
+#[derive(Default)]
+struct Counter {
+    /// Words of the scripts written with spaces.
+    words: u64,
+    ideographs: u64,
+    /// Characters other than whitespace.
+    characters: u64,
+    in_word: bool,
+    word: String,
+    detector: language::Detector,
+}
+
+impl Counter {
+    fn feed(&mut self, text: &str) {
+        for c in text.chars() {
+            if !c.is_whitespace() {
+                self.characters += 1;
+            }
+            if c.is_alphabetic() {
+                self.detector.letter(c);
+            }
+            if is_ideograph(c) {
+                self.end_word();
+                self.ideographs += 1;
+            } else if is_word_char(c) || (self.in_word && JOINERS.contains(&c)) {
+                if !self.in_word {
+                    self.in_word = true;
+                    self.words += 1;
+                }
+                if self.detector.wants_words() {
+                    self.word.push(c);
+                }
+            } else {
+                self.end_word();
+            }
+        }
+        self.end_word();
+    }
+
+    fn end_word(&mut self) {
+        self.in_word = false;
+        if !self.word.is_empty() {
+            self.detector.word(&self.word);
+            self.word.clear();
+        }
+    }
+
+    fn finish(self, book_hash: &str) -> BookAnalysis {
+        let language = self.detector.language();
+        let minutes = self.words as f64 / WORDS_PER_MINUTE
+            + self.ideographs as f64 / ideographs_per_minute(language);
+        BookAnalysis {
+            book_hash: book_hash.to_string(),
+            words: self.words + self.ideographs,
+            characters: self.characters,
+            reading_minutes: minutes.ceil() as u64,
+            language: language.map(str::to_string),
+            analyzed_at: now_millis(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BookAnalysis {
+    pub book_hash: String,
+    pub words: u64,
+    /// Characters other than whitespace.
+    pub characters: u64,
+    pub reading_minutes: u64,
+    /// ISO 639-1 code of the language most of the text is in, when that
+    /// is clear.
+    pub language: Option<String>,
+    pub analyzed_at: i64,
+}
+
+fn analysis_from_row(row: &Row) -> rusqlite::Result<BookAnalysis> {
+    Ok(BookAnalysis {
+        book_hash: row.get(0)?,
+        words: row.get::<_, i64>(1)? as u64,
+        characters: row.get::<_, i64>(2)? as u64,
+        reading_minutes: row.get::<_, i64>(3)? as u64,
+        language: row.get(4)?,
+        analyzed_at: row.get(5)?,
+    })
+}
+
+/// Analyzes the text of the book at `path`, or returns `None` for formats
+/// without text and books under DRM, whose text reads as noise.
+pub fn analyze(book_hash: &str, path: &Path) -> Result<Option<BookAnalysis>> {
+    if crate::drm::detect(path)?.is_some() {
+        return Ok(None);
+    }
+    let chapters = match formats::extract_chapters(path) {
+        Ok(chapters) => chapters,
+        Err(Error::UnsupportedFormat(_)) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut counter = Counter::default();
+    for chapter in &chapters {
+        counter.feed(&chapter.text);
+    }
+    Ok(Some(counter.finish(book_hash)))
+}
+
+pub fn record(conn: &Connection, analysis: &BookAnalysis) -> Result<()> {
+    conn.execute(
+        "INSERT INTO book_analysis
+            (book_hash, words, characters, reading_minutes, language, analyzed_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+         ON CONFLICT(book_hash) DO UPDATE SET
+            words = excluded.words, characters = excluded.characters,
+            reading_minutes = excluded.reading_minutes, language = excluded.language,
+            analyzed_at = excluded.analyzed_at",
+        params![
+            analysis.book_hash,
+            analysis.words as i64,
+            analysis.characters as i64,
+            analysis.reading_minutes as i64,
+            analysis.language,
+            analysis.analyzed_at,
+        ],
+    )?;
+    Ok(())
+}
+
+fn get(conn: &Connection, book_hash: &str) -> Result<Option<BookAnalysis>> {
+    Ok(conn
+        .query_row(
+            "SELECT book_hash, words, characters, reading_minutes, language, analyzed_at
+             FROM book_analysis WHERE book_hash = ?1",
+            [book_hash],
+            analysis_from_row,
+        )
+        .optional()?)
+}
+
+/// The books read for analysis since launch, so that one that fails is
+/// not read again each time it is saved.
+#[derive(Default)]
+pub struct Analyzed(Mutex<HashSet<String>>);
+
+impl Analyzed {
+    /// Marks `book_hash` read, returning whether it was not yet.
+    pub fn insert(&self, book_hash: &str) -> bool {
+        self.0.lock().unwrap().insert(book_hash.to_string())
+    }
+}
+
+/// Analyzes `book` and records the result, unless its file is not on this
+/// device or has no text.
+fn analyze_book(app: &AppHandle, book: &Book) -> Result<Option<BookAnalysis>> {
+    let Some(path) = crate::library::book_path(app, book) else {
+        return Ok(None);
+    };
+    app.state::<Analyzed>().insert(&book.hash);
+    let Some(analysis) = analyze(&book.hash, &path)? else {
+        return Ok(None);
+    };
+    record(&app.state::<LibraryDb>().conn(), &analysis)?;
+    Ok(Some(analysis))
+}
+
+/// Analyzes in the background those of `books` that are on this device
+/// but not analyzed yet, such as those the frontend just imported.
+pub fn analyze_new(app: &AppHandle, books: &[Book]) {
+    let analyzed = app.state::<Analyzed>();
+    let db = app.state::<LibraryDb>();
+    let books = {
+        let conn = db.conn();
+        books
+            .iter()
+            .filter(|book| book.deleted_at.is_none() && book.downloaded_at.is_some())
+            .filter(|book| matches!(get(&conn, &book.hash), Ok(None)))
+            .filter(|book| analyzed.insert(&book.hash))
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    if books.is_empty() {
+        return;
+    }
+    let app = app.clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        for book in &books {
+            if let Err(e) = analyze_book(&app, book) {
+                log::warn!("Failed to analyze {}: {e}", book.hash);
+            }
+        }
+    });
+}
+
+/// The analyses of those of books `hashes` that were analyzed.
+#[command]
+pub async fn get_book_analysis(
+    app: AppHandle,
+    db: State<'_, LibraryDb>,
+    hashes: Vec<String>,
+) -> Result<Vec<BookAnalysis>> {
+    let conn = db.conn();
+    let Some(hidden) = access::lock(&app, &conn)?.hidden_books(&conn)? else {
+        return Ok(Vec::new());
+    };
+    let mut analyses = Vec::new();
+    for hash in hashes.iter().filter(|hash| !hidden.contains(hash)) {
+        analyses.extend(get(&conn, hash)?);
+    }
+    Ok(analyses)
+}
+
+/// Analyzes the books that are not yet, or books `hashes` again, as an
+/// `analyze` job that stops after the current book when cancelled.
+/// Returns how many were analyzed.
+#[command]
+pub async fn analyze_library(app: AppHandle, hashes: Option<Vec<String>>) -> Result<usize> {
+    let handle = app.clone();
+    jobs::run(&handle, "analyze", |job| async move {
+        tauri::async_runtime::spawn_blocking(move || {
+            let db = app.state::<LibraryDb>();
+            let books = {
+                let conn = db.conn();
+                let books = db::query_books(&conn, &BookQuery::default())?.books;
+                match &hashes {
+                    Some(hashes) => books
+                        .into_iter()
+                        .filter(|book| hashes.contains(&book.hash))
+                        .collect::<Vec<_>>(),
+                    None => {
+                        let mut stmt = conn.prepare("SELECT book_hash FROM book_analysis")?;
+                        let done = stmt
+                            .query_map([], |row| row.get::<_, String>(0))?
+                            .collect::<rusqlite::Result<HashSet<_>>>()?;
+                        books
+                            .into_iter()
+                            .filter(|book| !done.contains(&book.hash))
+                            .collect()
+                    }
+                }
+            };
+            let total = books.len();
+            let mut analyzed = 0;
+            for (i, book) in books.iter().enumerate() {
+                if job.is_cancelled() {
+                    break;
+                }
+                job.progress("analyzing", i, total, Some(book.title.clone()));
+                match analyze_book(&app, book) {
+                    Ok(Some(_)) => analyzed += 1,
+                    Ok(None) => {}
+                    Err(e) => log::warn!("Failed to analyze {}: {e}", book.hash),
+                }
+            }
+            Ok(analyzed)
+        })
+        .await?
+    })
+    .await
+}
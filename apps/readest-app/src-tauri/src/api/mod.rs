@@ -0,0 +1,4 @@
+//! A local API for tools outside the app, such as Obsidian plugins and
+//! scripts, see [`server`].
+
+pub mod server;
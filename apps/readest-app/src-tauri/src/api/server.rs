@@ -0,0 +1,560 @@
+//! Opt-in JSON API over HTTP on localhost, for tools on the same machine
+//! to read the library, annotations and reading progress and to add
+//! annotations. Requests carry the token the app shows as
+//! `Authorization: Bearer <token>`; only hosts naming the loopback are
+//! served, so web pages cannot reach it through DNS rebinding.
+//!
+//! It goes through the same functions as the commands of the frontend:
+//! books locked behind the library PIN are left out, and private notes are
+//! seen only while the book is unlocked. A fixed number of workers serve
+//! the requests.
+//!
+//! - `GET /api/v1/books?q=&format=&tags=&sort=&ascending=&limit=&offset=`
+//! - `GET /api/v1/books/<hash>`
+//! - `GET /api/v1/books/<hash>/progress`
+//! - `GET /api/v1/books/<hash>/annotations`
+//! - `POST /api/v1/books/<hash>/annotations`
+//! - `GET /api/v1/annotations?since=`, the annotations of all books changed
+//!   since the time given
+
+use std::io::Read;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tauri::{command, AppHandle, Manager, State};
+use tiny_http::{Header, Method, Request, Response, Server, StatusCode};
+
+use crate::error::{Error, Result};
+use crate::hooks;
+use crate::import::annotations::unique_id;
+use crate::library::{access, db};
+use crate::secrets::{self, storage};
+use crate::store;
+use crate::utils::{now_millis, same};
+
+const CONFIG_FILE: &str = "api-server.json";
+const TOKEN_SECRET: &str = "api-token";
+const DEFAULT_PORT: u16 = 8761;
+const ROOT: &str = "/api/v1";
+const PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 500;
+const MAX_BODY: u64 = 1024 * 1024;
+/// Requests served at once; the others wait for a worker.
+const WORKERS: usize = 4;
+const NOTE_TYPES: &[&str] = &["annotation", "bookmark", "excerpt"];
+const NOTE_STYLES: &[&str] = &["highlight", "underline", "squiggly"];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ApiServerConfig {
+    pub enabled: bool,
+    pub port: u16,
+}
+
+impl Default for ApiServerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: DEFAULT_PORT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiServerStatus {
+    pub running: bool,
+    pub port: Option<u16>,
+    pub error: Option<String>,
+}
+
+struct Running {
+    server: Arc<Server>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+struct Inner {
+    config: ApiServerConfig,
+    running: Option<Running>,
+    error: Option<String>,
+}
+
+pub struct ApiServer(Mutex<Inner>);
+
+/// An error answered with `status`.
+struct Failure {
+    status: u16,
+    message: String,
+}
+
+impl Failure {
+    fn new(status: u16, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<Error> for Failure {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::PrivateNotes(_) => Failure::new(423, e.to_string()),
+            e => {
+                log::warn!("Local API request failed: {e}");
+                Failure::new(500, e.to_string())
+            }
+        }
+    }
+}
+
+impl From<serde_json::Error> for Failure {
+    fn from(e: serde_json::Error) -> Self {
+        Failure::new(400, e.to_string())
+    }
+}
+
+type Answer = std::result::Result<(u16, Value), Failure>;
+
+impl Inner {
+    fn stop(&mut self) {
+        if let Some(running) = self.running.take() {
+            // Each unblocks one worker
+            for _ in &running.workers {
+                running.server.unblock();
+            }
+            for worker in running.workers {
+                let _ = worker.join();
+            }
+        }
+    }
+
+    /// Binds the port, retrying briefly while a previous server releases it.
+    fn bind(port: u16) -> std::result::Result<Server, String> {
+        let mut attempts = 0;
+        loop {
+            match Server::http((Ipv4Addr::LOCALHOST, port)) {
+                Ok(server) => return Ok(server),
+                Err(_) if attempts < 5 => {
+                    attempts += 1;
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+    }
+
+    /// Starts the server when enabled, with the token in the keychain,
+    /// which is made the first time. Runs on a blocking pool thread, see
+    /// [`secrets`].
+    fn start(&mut self, app: &AppHandle) {
+        self.stop();
+        self.error = None;
+        if !self.config.enabled {
+            return;
+        }
+        let started = token().and_then(|token| {
+            let server = Self::bind(self.config.port).map_err(Error::ApiServer)?;
+            Ok((Arc::new(server), Arc::new(token)))
+        });
+        match started {
+            Ok((server, token)) => {
+                let port = self.config.port;
+                let workers = (0..WORKERS)
+                    .map(|_| {
+                        let (app, incoming, token) = (app.clone(), server.clone(), token.clone());
+                        std::thread::spawn(move || {
+                            for request in incoming.incoming_requests() {
+                                handle(&app, &token, port, request);
+                            }
+                        })
+                    })
+                    .collect();
+                self.running = Some(Running { server, workers });
+            }
+            Err(e) => {
+                log::warn!("Failed to start the local API: {e}");
+                self.error = Some(e.to_string());
+            }
+        }
+    }
+
+    fn status(&self) -> ApiServerStatus {
+        ApiServerStatus {
+            running: self.running.is_some(),
+            port: self
+                .running
+                .as_ref()
+                .and_then(|r| r.server.server_addr().to_ip())
+                .map(|addr| addr.port()),
+            error: self.error.clone(),
+        }
+    }
+}
+
+fn new_token() -> String {
+    let bytes = rand::thread_rng().gen::<[u8; 32]>();
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// The token clients must send, made the first time it is needed.
+fn token() -> Result<String> {
+    if let Some(token) = secrets::get(TOKEN_SECRET)? {
+        return Ok(token);
+    }
+    let token = new_token();
+    secrets::store(TOKEN_SECRET, Some(&token))?;
+    Ok(token)
+}
+
+pub fn init(app: &AppHandle) {
+    let inner = Inner {
+        config: store::load(app, CONFIG_FILE),
+        running: None,
+        error: None,
+    };
+    let enabled = inner.config.enabled;
+    app.manage(ApiServer(Mutex::new(inner)));
+    if enabled {
+        let app = app.clone();
+        tauri::async_runtime::spawn_blocking(move || {
+            app.state::<ApiServer>().0.lock().unwrap().start(&app);
+        });
+    }
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+}
+
+fn request_header<'a>(request: &'a Request, name: &'static str) -> Option<&'a str> {
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv(name))
+        .map(|h| h.value.as_str())
+}
+
+fn respond(request: Request, status: u16, body: &Value) {
+    let response = Response::from_string(body.to_string())
+        .with_status_code(StatusCode(status))
+        .with_header(header("Content-Type", "application/json"));
+    if let Err(e) = request.respond(response) {
+        log::warn!("Failed to send a local API response: {e}");
+    }
+}
+
+fn is_local_host(host: &str, port: u16) -> bool {
+    let name = host.strip_suffix(&format!(":{port}")).unwrap_or(host);
+    matches!(name, "localhost" | "127.0.0.1")
+}
+
+fn handle(app: &AppHandle, token: &str, port: u16, mut request: Request) {
+    if !request_header(&request, "Host").is_some_and(|host| is_local_host(host, port)) {
+        return respond(request, 421, &json!({ "error": "unknown host" }));
+    }
+    let authorized = request_header(&request, "Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|sent| same(sent.trim(), token));
+    if !authorized {
+        return respond(request, 401, &json!({ "error": "missing or wrong token" }));
+    }
+
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let params = url::form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect::<Vec<_>>();
+    let segments = path
+        .strip_prefix(ROOT)
+        .map(|rest| rest.trim_matches('/').split('/').collect::<Vec<_>>());
+    let answer = match (request.method(), segments.as_deref()) {
+        (Method::Get, Some([""])) => Ok((
+            200,
+            json!({ "name": "VL-Arch", "version": app.package_info().version.to_string() }),
+        )),
+        (_, Some(["books", hash, ..])) if !hash.chars().all(|c| c.is_ascii_alphanumeric()) => {
+            Err(Failure::new(400, "invalid book hash"))
+        }
+        (Method::Get, Some(["books"])) => list_books(app, &params),
+        (Method::Get, Some(["books", hash])) => {
+            get_book(app, hash).map(|book| (200, serde_json::to_value(book).unwrap_or_default()))
+        }
+        (Method::Get, Some(["books", hash, "progress"])) => progress(app, hash),
+        (Method::Get, Some(["books", hash, "annotations"])) => annotations(app, hash),
+        (Method::Post, Some(["books", hash, "annotations"])) => {
+            let hash = hash.to_string();
+            let mut body = Vec::new();
+            match request
+                .as_reader()
+                .take(MAX_BODY + 1)
+                .read_to_end(&mut body)
+            {
+                Ok(_) if body.len() as u64 > MAX_BODY => Err(Failure::new(413, "body too large")),
+                Ok(_) => add_annotation(app, &hash, &body),
+                Err(e) => Err(Failure::new(400, e.to_string())),
+            }
+        }
+        (Method::Get, Some(["annotations"])) => changed_annotations(app, &params),
+        (_, Some(_)) => Err(Failure::new(404, "no such endpoint")),
+        _ => Err(Failure::new(404, "not found")),
+    };
+    match answer {
+        Ok((status, body)) => respond(request, status, &body),
+        Err(failure) => respond(
+            request,
+            failure.status,
+            &json!({ "error": failure.message }),
+        ),
+    }
+}
+
+fn param<'a>(params: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    params
+        .iter()
+        .find(|(k, _)| k == name)
+        .map(|(_, v)| v.as_str())
+}
+
+fn list_books(app: &AppHandle, params: &[(String, String)]) -> Answer {
+    let number = |name: &str| {
+        param(params, name)
+            .map(|value| {
+                value
+                    .parse::<u32>()
+                    .map_err(|_| Failure::new(400, format!("invalid {name}")))
+            })
+            .transpose()
+    };
+    let sort_by = match param(params, "sort") {
+        None | Some("updatedAt") => db::SortField::UpdatedAt,
+        Some("title") => db::SortField::Title,
+        Some("author") => db::SortField::Author,
+        Some("createdAt") => db::SortField::CreatedAt,
+        Some("progress") => db::SortField::Progress,
+        Some(sort) => return Err(Failure::new(400, format!("cannot sort by {sort}"))),
+    };
+    let tags = param(params, "tags").map(|tags| {
+        tags.split(',')
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
+            .map(String::from)
+            .collect()
+    });
+    let db = app.state::<db::LibraryDb>();
+    let conn = db.conn();
+    let query = db::BookQuery {
+        search: param(params, "q").map(String::from),
+        format: param(params, "format").map(String::from),
+        tags,
+        sort_by,
+        ascending: param(params, "ascending") == Some("true"),
+        limit: Some(number("limit")?.unwrap_or(PAGE_SIZE).min(MAX_PAGE_SIZE)),
+        offset: number("offset")?,
+        lock: access::lock(app, &conn)?,
+        ..db::BookQuery::default()
+    };
+    let page = db::query_books(&conn, &query)?;
+    Ok((200, serde_json::to_value(page)?))
+}
+
+/// Book `hash`, unless it is deleted or locked.
+fn get_book(app: &AppHandle, hash: &str) -> std::result::Result<db::Book, Failure> {
+    let db = app.state::<db::LibraryDb>();
+    let conn = db.conn();
+    let lock = access::lock(app, &conn)?;
+    db::get_unlocked_book(&conn, hash, &lock)?
+        .filter(|book| book.deleted_at.is_none())
+        .ok_or_else(|| Failure::new(404, "no such book"))
+}
+
+/// The config of book `hash` as the frontend reads it, empty when it has
+/// none yet.
+fn config(app: &AppHandle, hash: &str) -> std::result::Result<Value, Failure> {
+    match storage::load_book_config(app, hash)? {
+        Some(config) => Ok(serde_json::from_str(&config).map_err(Error::from)?),
+        None => Ok(json!({})),
+    }
+}
+
+fn live_notes(config: &Value) -> impl Iterator<Item = &Value> {
+    config["booknotes"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|note| note["deletedAt"].is_null())
+}
+
+fn progress(app: &AppHandle, hash: &str) -> Answer {
+    let book = get_book(app, hash)?;
+    let config = config(app, hash)?;
+    Ok((
+        200,
+        json!({
+            "bookHash": book.hash,
+            "progress": book.progress,
+            "location": config["location"],
+            "updatedAt": config["updatedAt"],
+        }),
+    ))
+}
+
+fn annotations(app: &AppHandle, hash: &str) -> Answer {
+    get_book(app, hash)?;
+    let config = config(app, hash)?;
+    Ok((200, Value::Array(live_notes(&config).cloned().collect())))
+}
+
+fn changed_annotations(app: &AppHandle, params: &[(String, String)]) -> Answer {
+    let since = match param(params, "since") {
+        Some(since) => since
+            .parse::<i64>()
+            .map_err(|_| Failure::new(400, "invalid since"))?,
+        None => 0,
+    };
+    let books = {
+        let db = app.state::<db::LibraryDb>();
+        let conn = db.conn();
+        let query = db::BookQuery {
+            lock: access::lock(app, &conn)?,
+            ..db::BookQuery::default()
+        };
+        db::query_books(&conn, &query)?.books
+    };
+    let mut changed = Vec::new();
+    for book in books {
+        let config = config(app, &book.hash)?;
+        let notes = live_notes(&config)
+            .filter(|note| note["updatedAt"].as_i64().unwrap_or_default() > since)
+            .cloned()
+            .collect::<Vec<_>>();
+        if !notes.is_empty() {
+            changed.push(json!({
+                "bookHash": book.hash,
+                "title": book.title,
+                "author": book.author,
+                "annotations": notes,
+            }));
+        }
+    }
+    Ok((200, Value::Array(changed)))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NewAnnotation {
+    #[serde(rename = "type", default = "default_type")]
+    kind: String,
+    cfi: String,
+    text: Option<String>,
+    note: Option<String>,
+    style: Option<String>,
+    color: Option<String>,
+}
+
+fn default_type() -> String {
+    "annotation".to_string()
+}
+
+fn add_annotation(app: &AppHandle, hash: &str, body: &[u8]) -> Answer {
+    let new: NewAnnotation = serde_json::from_slice(body)?;
+    if !NOTE_TYPES.contains(&new.kind.as_str()) {
+        return Err(Failure::new(400, format!("unknown type {}", new.kind)));
+    }
+    if !new.cfi.starts_with("epubcfi(") {
+        return Err(Failure::new(400, "cfi must be an EPUB CFI"));
+    }
+    let style = match (new.kind.as_str(), new.style) {
+        ("annotation", None) => Some("highlight".to_string()),
+        (_, Some(style)) if !NOTE_STYLES.contains(&style.as_str()) => {
+            return Err(Failure::new(400, format!("unknown style {style}")));
+        }
+        (_, style) => style,
+    };
+    let book = get_book(app, hash)?;
+    let now = now_millis();
+    let mut note = json!({
+        "id": unique_id(),
+        "type": new.kind,
+        "cfi": new.cfi,
+        "note": new.note.unwrap_or_default(),
+        "createdAt": now,
+        "updatedAt": now,
+    });
+    for (field, value) in [("text", new.text), ("style", style), ("color", new.color)] {
+        if let Some(value) = value {
+            note[field] = value.into();
+        }
+    }
+
+    storage::update_book_config(app, hash, |config| {
+        let Some(object) = config.as_object_mut() else {
+            return Err(Error::InvalidBook(format!("invalid config of {hash}")));
+        };
+        match object
+            .entry("booknotes")
+            .or_insert_with(|| Value::Array(Vec::new()))
+        {
+            Value::Array(notes) => notes.push(note.clone()),
+            _ => return Err(Error::InvalidBook(format!("invalid notes of {hash}"))),
+        }
+        object.insert("updatedAt".into(), now.into());
+        Ok(())
+    })?;
+    hooks::annotation_added(app, &book, &note);
+    Ok((201, note))
+}
+
+#[command]
+pub fn get_api_server_config(server: State<'_, ApiServer>) -> ApiServerConfig {
+    server.0.lock().unwrap().config.clone()
+}
+
+#[command]
+pub fn get_api_server_status(server: State<'_, ApiServer>) -> ApiServerStatus {
+    server.0.lock().unwrap().status()
+}
+
+/// Saves `config` and restarts the server to apply it.
+#[command]
+pub async fn set_api_server_config(
+    app: AppHandle,
+    config: ApiServerConfig,
+) -> Result<ApiServerStatus> {
+    tauri::async_runtime::spawn_blocking(move || {
+        store::save(&app, CONFIG_FILE, &config)?;
+        let server = app.state::<ApiServer>();
+        let mut inner = server.0.lock().unwrap();
+        inner.config = config;
+        inner.start(&app);
+        Ok(inner.status())
+    })
+    .await?
+}
+
+/// The token for clients, made the first time it is asked for.
+#[command]
+pub async fn get_api_token() -> Result<String> {
+    tauri::async_runtime::spawn_blocking(token).await?
+}
+
+/// Replaces the token, so clients given the old one lose access.
+#[command]
+pub async fn reset_api_token(app: AppHandle) -> Result<String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let token = new_token();
+        secrets::store(TOKEN_SECRET, Some(&token))?;
+        let server = app.state::<ApiServer>();
+        let mut inner = server.0.lock().unwrap();
+        if inner.running.is_some() {
+            inner.start(&app);
+        }
+        Ok(token)
+    })
+    .await?
+}
@@ -0,0 +1,361 @@
+//! How the system looks and when that changes, so the reader can switch
+//! themes by itself: whether the OS is in dark mode, Night Shift on macOS
+//! and night light on Windows and GNOME, and sunrise and sunset. Sun times
+//! come from the place in the settings, or from GNOME's settings daemon
+//! when none is set.
+//!
+//! Each change is sent as a `theme-change` event with the schedule as it is
+//! after it. Changes the system tells about are sent as they happen, and the
+//! scheduled ones, sunrise, sunset and Night Shift on a schedule, when they
+//! are due; the rest is checked again every few minutes.
+
+mod sun;
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle, Emitter, Manager, Theme, Window, WindowEvent};
+use tokio::sync::Notify;
+
+use crate::error::{Error, Result};
+use crate::stats::{self, DAY_MS};
+use crate::store;
+use crate::utils::now_millis;
+
+const SETTINGS_FILE: &str = "appearance.json";
+const EVENT: &str = "theme-change";
+/// Longest the schedule goes unchecked, for what the system does not tell
+/// about.
+const RECHECK: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AppearanceSettings {
+    /// The place sun times are for, in degrees, east positive.
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    /// As the frontend last passed it, for the local times of schedules.
+    pub utc_offset_minutes: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NightLight {
+    pub active: bool,
+    /// Local time it turns on and off, in minutes after midnight, when it
+    /// is on a schedule.
+    pub starts_at: Option<u32>,
+    pub ends_at: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AppearanceSchedule {
+    pub dark: bool,
+    /// None where the system has none or does not tell.
+    pub night_light: Option<NightLight>,
+    /// Today's, where the place is known and the sun rises and sets.
+    pub sunrise: Option<i64>,
+    pub sunset: Option<i64>,
+    /// Whether the sun is up, where the place is known.
+    pub daylight: Option<bool>,
+    /// When the next scheduled change is due.
+    pub next_change_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+enum Change {
+    DarkMode,
+    NightLight,
+    Sunrise,
+    Sunset,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ThemeChange {
+    change: Change,
+    schedule: AppearanceSchedule,
+}
+
+#[derive(Default)]
+pub struct Appearance {
+    /// Wakes the watcher to look again.
+    changed: Notify,
+}
+
+/// How night light is scheduled, as the system says; only macOS says.
+enum NightSchedule {
+    None,
+    #[cfg(target_os = "macos")]
+    SunsetToSunrise,
+    #[cfg(target_os = "macos")]
+    Custom(u32, u32),
+}
+
+struct SystemNightLight {
+    active: bool,
+    schedule: NightSchedule,
+    /// Local sunrise and sunset, in hours after midnight, when the system
+    /// knows them.
+    sun: Option<(f64, f64)>,
+}
+
+#[cfg(target_os = "macos")]
+fn system_night_light() -> Option<SystemNightLight> {
+    use crate::macos::night_shift::{self, Mode};
+
+    let status = night_shift::status()?;
+    Some(SystemNightLight {
+        active: status.active,
+        schedule: match status.mode {
+            Mode::Off => NightSchedule::None,
+            Mode::SunsetToSunrise => NightSchedule::SunsetToSunrise,
+            Mode::Custom(from, to) => NightSchedule::Custom(from, to),
+        },
+        sun: None,
+    })
+}
+
+#[cfg(windows)]
+fn system_night_light() -> Option<SystemNightLight> {
+    Some(SystemNightLight {
+        active: crate::windows::night_light::active()?,
+        schedule: NightSchedule::None,
+        sun: None,
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn system_night_light() -> Option<SystemNightLight> {
+    let night_light = crate::linux::appearance::night_light()?;
+    Some(SystemNightLight {
+        active: night_light.active,
+        schedule: NightSchedule::None,
+        sun: night_light.sunrise.zip(night_light.sunset),
+    })
+}
+
+fn dark(app: &AppHandle) -> bool {
+    #[cfg(target_os = "linux")]
+    if let Some(dark) = crate::linux::appearance::prefers_dark() {
+        return dark;
+    }
+    app.get_webview_window("main")
+        .and_then(|window| window.theme().ok())
+        .is_some_and(|theme| theme == Theme::Dark)
+}
+
+/// When local time `minute` comes next after `now`, with local midnight of
+/// today at `midnight`.
+fn next_at(minute: u32, midnight: i64, now: i64) -> i64 {
+    let at = midnight + i64::from(minute) * 60_000;
+    if at > now {
+        at
+    } else {
+        at + DAY_MS
+    }
+}
+
+fn schedule(app: &AppHandle, settings: &AppearanceSettings) -> AppearanceSchedule {
+    let now = now_millis();
+    let offset = stats::offset_ms(settings.utc_offset_minutes);
+    let day = stats::local_day(now, offset);
+    let midnight = day * DAY_MS - offset;
+    let system = system_night_light();
+
+    let place = settings.latitude.zip(settings.longitude);
+    let sun_on = |day: i64| match place {
+        Some((latitude, longitude)) => sun::times(day, latitude, longitude),
+        None => system
+            .as_ref()
+            .and_then(|system| system.sun)
+            .map(|(rise, set)| {
+                let hours = |hours: f64| (hours * 3_600_000.0) as i64;
+                let midnight = day * DAY_MS - offset;
+                (midnight + hours(rise), midnight + hours(set))
+            }),
+    };
+    let today = sun_on(day);
+    let tomorrow = sun_on(day + 1);
+    let daylight = today.map(|(rise, set)| (rise..set).contains(&now));
+
+    let night_light = system.map(|system| {
+        let (starts_at, ends_at) = match system.schedule {
+            NightSchedule::None => (None, None),
+            #[cfg(target_os = "macos")]
+            NightSchedule::SunsetToSunrise => {
+                let minute = |at: i64| ((at - midnight).rem_euclid(DAY_MS) / 60_000) as u32;
+                match today {
+                    Some((rise, set)) => (Some(minute(set)), Some(minute(rise))),
+                    None => (None, None),
+                }
+            }
+            #[cfg(target_os = "macos")]
+            NightSchedule::Custom(from, to) => (Some(from), Some(to)),
+        };
+        NightLight {
+            active: system.active,
+            starts_at,
+            ends_at,
+        }
+    });
+
+    let sun_changes = [today, tomorrow]
+        .into_iter()
+        .flatten()
+        .flat_map(|(rise, set)| [rise, set]);
+    let night_light_changes = night_light
+        .iter()
+        .flat_map(|night_light| [night_light.starts_at, night_light.ends_at])
+        .flatten()
+        .map(|minute| next_at(minute, midnight, now));
+    let next_change_at = sun_changes
+        .chain(night_light_changes)
+        .filter(|&at| at > now)
+        .min();
+
+    AppearanceSchedule {
+        dark: dark(app),
+        night_light,
+        sunrise: today.map(|(rise, _)| rise),
+        sunset: today.map(|(_, set)| set),
+        daylight,
+        next_change_at,
+    }
+}
+
+/// The changes from `before` to `after`.
+fn changes(before: &AppearanceSchedule, after: &AppearanceSchedule) -> Vec<Change> {
+    let mut changes = Vec::new();
+    if before.dark != after.dark {
+        changes.push(Change::DarkMode);
+    }
+    let active = |schedule: &AppearanceSchedule| {
+        schedule
+            .night_light
+            .as_ref()
+            .map(|night_light| night_light.active)
+    };
+    if active(before) != active(after) {
+        changes.push(Change::NightLight);
+    }
+    match (before.daylight, after.daylight) {
+        (Some(false), Some(true)) => changes.push(Change::Sunrise),
+        (Some(true), Some(false)) => changes.push(Change::Sunset),
+        _ => {}
+    }
+    changes
+}
+
+async fn watch(app: AppHandle) {
+    let mut last: Option<AppearanceSchedule> = None;
+    loop {
+        let handle = app.clone();
+        let Ok(current) = tauri::async_runtime::spawn_blocking(move || {
+            let settings: AppearanceSettings = store::load(&handle, SETTINGS_FILE);
+            schedule(&handle, &settings)
+        })
+        .await
+        else {
+            return;
+        };
+        if let Some(last) = &last {
+            for change in changes(last, &current) {
+                let event = ThemeChange {
+                    change,
+                    schedule: current.clone(),
+                };
+                let _ = app.emit(EVENT, event);
+            }
+        }
+        let wait = current
+            .next_change_at
+            .map(|at| Duration::from_millis((at - now_millis()).max(0) as u64))
+            .map_or(RECHECK, |wait| wait.min(RECHECK));
+        last = Some(current);
+        let appearance = app.state::<Appearance>();
+        // A second late, so the change is past when it is looked at
+        let _ = tokio::time::timeout(wait + Duration::from_secs(1), appearance.changed.notified())
+            .await;
+    }
+}
+
+fn wake(app: &AppHandle) {
+    if let Some(appearance) = app.try_state::<Appearance>() {
+        appearance.changed.notify_one();
+    }
+}
+
+pub fn on_window_event(window: &Window, event: &WindowEvent) {
+    if matches!(event, WindowEvent::ThemeChanged(_)) {
+        wake(window.app_handle());
+    }
+}
+
+fn listen(app: &AppHandle) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        let app = app.clone();
+        crate::linux::appearance::listen(move || wake(&app))?;
+    }
+    #[cfg(windows)]
+    {
+        let app = app.clone();
+        crate::windows::night_light::listen(move || wake(&app))?;
+    }
+    #[cfg(target_os = "macos")]
+    let _ = app;
+    Ok(())
+}
+
+pub fn init(app: &AppHandle) {
+    app.manage(Appearance::default());
+    if let Err(e) = listen(app) {
+        log::warn!("Failed to watch the system appearance: {e}");
+    }
+    tauri::async_runtime::spawn(watch(app.clone()));
+}
+
+#[command]
+pub fn get_appearance_settings(app: AppHandle) -> AppearanceSettings {
+    store::load(&app, SETTINGS_FILE)
+}
+
+#[command]
+pub fn set_appearance_settings(app: AppHandle, settings: AppearanceSettings) -> Result<()> {
+    if settings.latitude.is_some() != settings.longitude.is_some() {
+        return Err(Error::Appearance(
+            "latitude and longitude go together".to_string(),
+        ));
+    }
+    if settings
+        .latitude
+        .is_some_and(|latitude| !(-90.0..=90.0).contains(&latitude))
+        || settings
+            .longitude
+            .is_some_and(|longitude| !(-180.0..=180.0).contains(&longitude))
+    {
+        return Err(Error::Appearance("no such place".to_string()));
+    }
+    store::save(&app, SETTINGS_FILE, &settings)?;
+    wake(&app);
+    Ok(())
+}
+
+/// The schedule as it is now, with the UTC offset as for the statistics,
+/// which is kept for the schedules that follow.
+#[command]
+pub async fn get_appearance_schedule(
+    app: AppHandle,
+    utc_offset_minutes: i32,
+) -> Result<AppearanceSchedule> {
+    let mut settings: AppearanceSettings = store::load(&app, SETTINGS_FILE);
+    if settings.utc_offset_minutes != utc_offset_minutes {
+        settings.utc_offset_minutes = utc_offset_minutes;
+        store::save(&app, SETTINGS_FILE, &settings)?;
+        wake(&app);
+    }
+    Ok(tauri::async_runtime::spawn_blocking(move || schedule(&app, &settings)).await?)
+}
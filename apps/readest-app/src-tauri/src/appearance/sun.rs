@@ -0,0 +1,44 @@
+//! Sunrise and sunset from the place, by the sunrise equation, which is
+//! within a minute or two away from the poles.
+
+use crate::stats::DAY_MS;
+
+/// Julian day of the Unix epoch.
+const UNIX_EPOCH_JD: f64 = 2_440_587.5;
+/// Julian day of J2000.0, noon of 1 January 2000.
+const J2000: f64 = 2_451_545.0;
+/// Days from the Unix epoch to 1 January 2000.
+const J2000_DAYS: i64 = 10_957;
+/// Tilt of the Earth's axis.
+const OBLIQUITY: f64 = 23.4397;
+/// Altitude of the centre of the sun as it rises, below the horizon for
+/// refraction and its size.
+const HORIZON: f64 = -0.833;
+
+fn millis(julian_day: f64) -> i64 {
+    ((julian_day - UNIX_EPOCH_JD) * DAY_MS as f64).round() as i64
+}
+
+/// Sunrise and sunset of `day`, days since the Unix epoch, at `latitude`
+/// and `longitude` in degrees, east positive, as milliseconds since the
+/// Unix epoch. None while the sun stays up or down all day.
+pub fn times(day: i64, latitude: f64, longitude: f64) -> Option<(i64, i64)> {
+    let n = (day - J2000_DAYS) as f64 + 0.0008;
+    let mean_noon = n - longitude / 360.0;
+    let anomaly = (357.5291 + 0.985_600_28 * mean_noon).rem_euclid(360.0);
+    let m = anomaly.to_radians();
+    let center = 1.9148 * m.sin() + 0.02 * (2.0 * m).sin() + 0.0003 * (3.0 * m).sin();
+    let ecliptic = (anomaly + center + 180.0 + 102.9372)
+        .rem_euclid(360.0)
+        .to_radians();
+    let noon = J2000 + mean_noon + 0.0053 * m.sin() - 0.0069 * (2.0 * ecliptic).sin();
+    let declination = (ecliptic.sin() * OBLIQUITY.to_radians().sin()).asin();
+    let latitude = latitude.to_radians();
+    let cos_hour_angle = (HORIZON.to_radians().sin() - latitude.sin() * declination.sin())
+        / (latitude.cos() * declination.cos());
+    if !(-1.0..=1.0).contains(&cos_hour_angle) {
+        return None;
+    }
+    let half_day = cos_hour_angle.acos().to_degrees() / 360.0;
+    Some((millis(noon - half_day), millis(noon + half_day)))
+}
@@ -0,0 +1,102 @@
+//! Registers the app as a handler for book files from settings, for users
+//! of portable builds or who skipped it in the installer. Windows gets
+//! per-user ProgIDs in the registry and Linux a desktop entry made the
+//! default through `xdg-mime`. On macOS the bundle declares its types and
+//! Launch Services picks them up on its own.
+
+use serde::Serialize;
+use tauri::{command, AppHandle};
+
+use crate::error::{Error, Result};
+
+#[cfg(windows)]
+mod registry;
+#[cfg(all(unix, not(target_os = "macos")))]
+mod xdg;
+
+#[cfg(windows)]
+use registry as platform;
+#[cfg(all(unix, not(target_os = "macos")))]
+use xdg as platform;
+
+pub struct FileType {
+    pub extension: &'static str,
+    /// Matches the `fileAssociations` of `tauri.conf.json`.
+    pub mime_type: &'static str,
+}
+
+pub const FILE_TYPES: &[FileType] = &[
+    FileType {
+        extension: "epub",
+        mime_type: "application/epub+zip",
+    },
+    FileType {
+        extension: "mobi",
+        mime_type: "application/x-mobipocket-ebook",
+    },
+    FileType {
+        extension: "azw3",
+        mime_type: "application/vnd.amazon.mobi8-ebook",
+    },
+    FileType {
+        extension: "cbz",
+        mime_type: "application/vnd.comicbook+zip",
+    },
+    FileType {
+        extension: "pdf",
+        mime_type: "application/pdf",
+    },
+];
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileAssociation {
+    pub extension: String,
+    /// The app is registered as a handler, with its current location.
+    pub registered: bool,
+    /// Files of this type open in the app by default.
+    pub default: bool,
+}
+
+fn file_types(extensions: Option<Vec<String>>) -> Result<Vec<&'static FileType>> {
+    let Some(extensions) = extensions else {
+        return Ok(FILE_TYPES.iter().collect());
+    };
+    extensions
+        .iter()
+        .map(|ext| {
+            let ext = ext.trim_start_matches('.');
+            FILE_TYPES
+                .iter()
+                .find(|file_type| file_type.extension.eq_ignore_ascii_case(ext))
+                .ok_or_else(|| Error::UnsupportedFormat(ext.to_string()))
+        })
+        .collect()
+}
+
+fn statuses(app: &AppHandle) -> Result<Vec<FileAssociation>> {
+    FILE_TYPES
+        .iter()
+        .map(|file_type| platform::status(app, file_type))
+        .collect()
+}
+
+/// Reports for each supported type whether the app handles it.
+#[command]
+pub async fn get_file_associations(app: AppHandle) -> Result<Vec<FileAssociation>> {
+    tauri::async_runtime::spawn_blocking(move || statuses(&app)).await?
+}
+
+/// Registers the app for `extensions`, or for every supported type when
+/// omitted, and returns the updated status of all types.
+#[command]
+pub async fn register_file_associations(
+    app: AppHandle,
+    extensions: Option<Vec<String>>,
+) -> Result<Vec<FileAssociation>> {
+    tauri::async_runtime::spawn_blocking(move || {
+        platform::register(&app, &file_types(extensions)?)?;
+        statuses(&app)
+    })
+    .await?
+}
@@ -0,0 +1,109 @@
+//! Per-user registration under `HKEY_CURRENT_USER\Software\Classes`, which
+//! needs no elevation. Windows keeps the user's choice of default app out
+//! of reach of other programs, so registering lists the app under "Open
+//! with" and in the Default Apps settings, and only makes it the default
+//! for types that have no handler yet.
+
+use tauri::AppHandle;
+use windows::Win32::UI::Shell::{SHChangeNotify, SHCNE_ASSOCCHANGED, SHCNF_IDLIST};
+use windows_registry::{CLASSES_ROOT, CURRENT_USER};
+
+use super::{FileAssociation, FileType};
+use crate::error::Result;
+
+const CLASSES: &str = r"Software\Classes";
+const FILE_EXTS: &str = r"Software\Microsoft\Windows\CurrentVersion\Explorer\FileExts";
+const APP_NAME: &str = "VL-Arch";
+
+fn prog_id(file_type: &FileType) -> String {
+    format!("{APP_NAME}.{}", file_type.extension)
+}
+
+fn open_command() -> Result<String> {
+    let exe = std::env::current_exe()?;
+    Ok(format!("\"{}\" \"%1\"", exe.display()))
+}
+
+/// ProgID that opens files of `file_type`: the user's choice if they made
+/// one, otherwise the default of the merged classes.
+fn current_handler(file_type: &FileType) -> Option<String> {
+    let ext = file_type.extension;
+    CURRENT_USER
+        .open(format!(r"{FILE_EXTS}\.{ext}\UserChoice"))
+        .and_then(|key| key.get_string("ProgId"))
+        .or_else(|_| {
+            CLASSES_ROOT
+                .open(format!(".{ext}"))
+                .and_then(|key| key.get_string(""))
+        })
+        .ok()
+        .filter(|prog_id| !prog_id.is_empty())
+}
+
+pub fn status(_app: &AppHandle, file_type: &FileType) -> Result<FileAssociation> {
+    let prog_id = prog_id(file_type);
+    let command = open_command()?;
+    let listed = CURRENT_USER
+        .open(format!(
+            r"{CLASSES}\.{}\OpenWithProgids",
+            file_type.extension
+        ))
+        .and_then(|key| key.get_type(&prog_id))
+        .is_ok();
+    // A registration left behind by a copy of the app elsewhere does not count
+    let current = CURRENT_USER
+        .open(format!(r"{CLASSES}\{prog_id}\shell\open\command"))
+        .and_then(|key| key.get_string(""))
+        .is_ok_and(|value| value == command);
+    Ok(FileAssociation {
+        extension: file_type.extension.to_string(),
+        registered: listed && current,
+        default: current_handler(file_type).as_deref() == Some(&prog_id),
+    })
+}
+
+pub fn register(_app: &AppHandle, file_types: &[&FileType]) -> Result<()> {
+    let exe = std::env::current_exe()?;
+    let command = open_command()?;
+    let classes = CURRENT_USER.create(CLASSES)?;
+
+    let exe_name = exe
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| format!("{APP_NAME}.exe"));
+    let application = classes.create(format!(r"Applications\{exe_name}"))?;
+    application.set_string("FriendlyAppName", APP_NAME)?;
+    application
+        .create(r"shell\open\command")?
+        .set_string("", &command)?;
+    let supported_types = application.create("SupportedTypes")?;
+
+    for file_type in file_types {
+        let ext = file_type.extension;
+        let prog_id = prog_id(file_type);
+        let class = classes.create(&prog_id)?;
+        class.set_string("", format!("{} file", ext.to_uppercase()))?;
+        class
+            .create("DefaultIcon")?
+            .set_string("", format!("\"{}\",0", exe.display()))?;
+        class
+            .create(r"shell\open\command")?
+            .set_string("", &command)?;
+
+        let extension = classes.create(format!(".{ext}"))?;
+        extension
+            .create("OpenWithProgids")?
+            .set_string(&prog_id, "")?;
+        if current_handler(file_type).is_none() {
+            extension.set_string("", &prog_id)?;
+        }
+        if extension.get_string("Content Type").is_err() {
+            extension.set_string("Content Type", file_type.mime_type)?;
+        }
+        supported_types.set_string(format!(".{ext}"), "")?;
+    }
+
+    // Tells Explorer to pick up the new handlers without a restart
+    unsafe { SHChangeNotify(SHCNE_ASSOCCHANGED, SHCNF_IDLIST, None, None) };
+    Ok(())
+}
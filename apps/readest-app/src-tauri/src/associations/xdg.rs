@@ -0,0 +1,106 @@
+//! A desktop entry in the user's applications dir, made the default for
+//! each MIME type with `xdg-mime`. The entry is hidden from menus, where the
+//! packaged one, if any, already shows the app.
+
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::process::Command;
+
+use tauri::{AppHandle, Manager};
+
+use super::{FileAssociation, FileType, FILE_TYPES};
+use crate::error::{Error, Result};
+
+/// Named apart from the packaged entry, which this one would otherwise hide.
+const DESKTOP_FILE: &str = "vl-arch-open.desktop";
+
+fn desktop_file(app: &AppHandle) -> Result<PathBuf> {
+    Ok(app
+        .path()
+        .data_dir()?
+        .join("applications")
+        .join(DESKTOP_FILE))
+}
+
+/// `Exec` line launching this copy of the app, or the AppImage it runs from.
+fn exec_line() -> Result<String> {
+    let exe = match std::env::var_os("APPIMAGE") {
+        Some(path) => PathBuf::from(path),
+        None => std::env::current_exe()?,
+    };
+    let mut quoted = String::new();
+    for c in exe.to_string_lossy().chars() {
+        match c {
+            // Escaped once for the quoted argument and once more for the
+            // string value it is in
+            '"' | '`' | '$' => quoted.push_str(&format!("\\\\{c}")),
+            '\\' => quoted.push_str(r"\\\\"),
+            '%' => quoted.push_str("%%"),
+            _ => quoted.push(c),
+        }
+    }
+    Ok(format!("Exec=\"{quoted}\" %F"))
+}
+
+fn xdg_mime(args: &[&str]) -> Result<String> {
+    let output = Command::new("xdg-mime")
+        .args(args)
+        .output()
+        .map_err(|e| match e.kind() {
+            ErrorKind::NotFound => Error::FileAssociation("xdg-mime is not installed".into()),
+            _ => e.into(),
+        })?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::FileAssociation(stderr.trim().to_string()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+pub fn status(app: &AppHandle, file_type: &FileType) -> Result<FileAssociation> {
+    let exec = exec_line()?;
+    // A desktop entry left behind by a copy of the app elsewhere does not count
+    let registered = std::fs::read_to_string(desktop_file(app)?)
+        .is_ok_and(|entry| entry.lines().any(|line| line == exec));
+    let default = xdg_mime(&["query", "default", file_type.mime_type])? == DESKTOP_FILE;
+    Ok(FileAssociation {
+        extension: file_type.extension.to_string(),
+        registered,
+        default: registered && default,
+    })
+}
+
+pub fn register(app: &AppHandle, file_types: &[&FileType]) -> Result<()> {
+    let path = desktop_file(app)?;
+    let mime_types = FILE_TYPES
+        .iter()
+        .map(|file_type| format!("{};", file_type.mime_type))
+        .collect::<String>();
+    let entry = format!(
+        "[Desktop Entry]\n\
+         Type=Application\n\
+         Name=VL-Arch\n\
+         Icon=vl-arch\n\
+         {}\n\
+         MimeType={mime_types}\n\
+         NoDisplay=true\n\
+         Terminal=false\n",
+        exec_line()?
+    );
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(&path, entry)?;
+
+    let mut args = vec!["default", DESKTOP_FILE];
+    args.extend(file_types.iter().map(|file_type| file_type.mime_type));
+    xdg_mime(&args)?;
+
+    // Refreshes the MIME cache of the applications dir where the tool exists
+    if let Some(dir) = path.parent() {
+        if let Err(e) = Command::new("update-desktop-database").arg(dir).status() {
+            log::debug!("Skipped update-desktop-database: {e}");
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,164 @@
+//! Recording from the default input device through cpal, which rodio is
+//! built on. Like the output stream, the input stream cannot move between
+//! threads, so it lives on a thread of its own until it is stopped.
+
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use rodio::cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use rodio::cpal::{self, SampleFormat};
+
+use crate::error::{Error, Result};
+
+/// Rate of the recordings, which is all speech needs and what speech
+/// recognition takes.
+pub const SAMPLE_RATE: u32 = 16_000;
+
+fn audio_error(e: impl std::fmt::Display) -> Error {
+    Error::Audio(e.to_string())
+}
+
+/// Samples as they come, mixed down to mono, at the rate of the device.
+struct Captured {
+    samples: Vec<f32>,
+    /// Samples past which the rest are dropped.
+    limit: usize,
+}
+
+impl Captured {
+    fn push(&mut self, frame: impl Iterator<Item = f32>, channels: usize) {
+        if self.samples.len() < self.limit {
+            let sum = frame.sum::<f32>();
+            self.samples.push(sum / channels as f32);
+        }
+    }
+}
+
+pub struct Recorder {
+    captured: Arc<Mutex<Captured>>,
+    rate: u32,
+    started: Instant,
+    stop: Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+/// Builds the input stream of `device` for samples of type `T`.
+fn input_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    captured: Arc<Mutex<Captured>>,
+) -> Result<cpal::Stream>
+where
+    T: cpal::SizedSample,
+    f32: cpal::FromSample<T>,
+{
+    let channels = usize::from(config.channels.max(1));
+    device
+        .build_input_stream(
+            config,
+            move |data: &[T], _| {
+                let mut captured = captured.lock().unwrap();
+                for frame in data.chunks(channels) {
+                    captured.push(frame.iter().map(|&s| s.to_sample::<f32>()), channels);
+                }
+            },
+            |e| log::warn!("Recording error: {e}"),
+            None,
+        )
+        .map_err(audio_error)
+}
+
+impl Recorder {
+    /// Starts recording from the default input device, for `max` at most.
+    pub fn start(max: Duration) -> Result<Recorder> {
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let (stop_tx, stop_rx) = mpsc::channel::<()>();
+        let thread = std::thread::Builder::new()
+            .name("audio-input".to_string())
+            .spawn(move || {
+                let opened = (|| -> Result<_> {
+                    let device = cpal::default_host()
+                        .default_input_device()
+                        .ok_or_else(|| audio_error("no microphone"))?;
+                    let supported = device.default_input_config().map_err(audio_error)?;
+                    let config = supported.config();
+                    let captured = Arc::new(Mutex::new(Captured {
+                        samples: Vec::new(),
+                        limit: (max.as_secs_f64() * f64::from(config.sample_rate.0)) as usize,
+                    }));
+                    let stream = match supported.sample_format() {
+                        SampleFormat::F32 => {
+                            input_stream::<f32>(&device, &config, captured.clone())
+                        }
+                        SampleFormat::I16 => {
+                            input_stream::<i16>(&device, &config, captured.clone())
+                        }
+                        SampleFormat::U16 => {
+                            input_stream::<u16>(&device, &config, captured.clone())
+                        }
+                        SampleFormat::I32 => {
+                            input_stream::<i32>(&device, &config, captured.clone())
+                        }
+                        format => Err(audio_error(format!("unsupported sample format {format}"))),
+                    }?;
+                    stream.play().map_err(audio_error)?;
+                    Ok((stream, captured, config.sample_rate.0))
+                })();
+                match opened {
+                    Ok((_stream, captured, rate)) => {
+                        let _ = ready_tx.send(Ok((captured, rate)));
+                        // Returns once stopped or the sender is dropped
+                        let _ = stop_rx.recv();
+                    }
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e));
+                    }
+                }
+            })?;
+        let (captured, rate) = ready_rx.recv().map_err(audio_error)??;
+        Ok(Recorder {
+            captured,
+            rate,
+            started: Instant::now(),
+            stop: stop_tx,
+            thread,
+        })
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Stops recording, returning what was recorded as 16-bit mono samples
+    /// at [`SAMPLE_RATE`].
+    pub fn stop(self) -> Vec<i16> {
+        let _ = self.stop.send(());
+        let _ = self.thread.join();
+        let samples = std::mem::take(&mut self.captured.lock().unwrap().samples);
+        resample(&samples, self.rate, SAMPLE_RATE)
+            .into_iter()
+            .map(|s| (s.clamp(-1.0, 1.0) * f32::from(i16::MAX)) as i16)
+            .collect()
+    }
+}
+
+/// Linear resampling, which is enough for speech.
+fn resample(samples: &[f32], from: u32, to: u32) -> Vec<f32> {
+    if from == to || samples.is_empty() {
+        return samples.to_vec();
+    }
+    let step = f64::from(from) / f64::from(to);
+    let len = (samples.len() as f64 / step) as usize;
+    (0..len)
+        .map(|i| {
+            let position = i as f64 * step;
+            let index = position as usize;
+            let fraction = (position - index as f64) as f32;
+            let a = samples[index];
+            let b = samples.get(index + 1).copied().unwrap_or(a);
+            a + (b - a) * fraction
+        })
+        .collect()
+}
@@ -0,0 +1,182 @@
+//! ID3v2.3 and v2.4 tags of MP3 audiobooks: the text frames for title,
+//! author, album and length, and `CHAP` frames for chapters.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use super::{Chapter, Metadata};
+use crate::error::Result;
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    data.get(offset..offset + 4)
+        .map_or(0, |b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// A 28-bit integer stored in the low seven bits of four bytes.
+fn synchsafe_at(data: &[u8], offset: usize) -> u32 {
+    data.get(offset..offset + 4).map_or(0, |b| {
+        b.iter()
+            .fold(0, |value, &byte| (value << 7) | (byte & 0x7F) as u32)
+    })
+}
+
+/// Undoes unsynchronisation, which inserts a zero byte after each 0xFF.
+fn resynchronise(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(data.len());
+    let mut previous = 0;
+    for &byte in data {
+        if !(previous == 0xFF && byte == 0) {
+            result.push(byte);
+        }
+        previous = byte;
+    }
+    result
+}
+
+fn decode_utf16(data: &[u8], big_endian: bool) -> String {
+    let units = data
+        .chunks_exact(2)
+        .map(|b| {
+            if big_endian {
+                u16::from_be_bytes([b[0], b[1]])
+            } else {
+                u16::from_le_bytes([b[0], b[1]])
+            }
+        })
+        .take_while(|&unit| unit != 0)
+        .collect::<Vec<_>>();
+    String::from_utf16_lossy(&units)
+}
+
+/// Decodes a text frame body: an encoding byte, then the text.
+fn text(frame: &[u8]) -> Option<String> {
+    let (&encoding, data) = frame.split_first()?;
+    let text = match encoding {
+        0 => data
+            .iter()
+            .take_while(|&&byte| byte != 0)
+            .map(|&byte| byte as char)
+            .collect(),
+        1 => match data {
+            [0xFE, 0xFF, rest @ ..] => decode_utf16(rest, true),
+            [0xFF, 0xFE, rest @ ..] => decode_utf16(rest, false),
+            _ => decode_utf16(data, false),
+        },
+        2 => decode_utf16(data, true),
+        _ => {
+            let end = data
+                .iter()
+                .position(|&byte| byte == 0)
+                .unwrap_or(data.len());
+            String::from_utf8_lossy(&data[..end]).into_owned()
+        }
+    };
+    let text = text.trim().to_string();
+    (!text.is_empty()).then_some(text)
+}
+
+struct Frames<'a> {
+    data: &'a [u8],
+    major: u8,
+}
+
+impl<'a> Iterator for Frames<'a> {
+    type Item = (&'a [u8], Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let header = self.data.get(..10)?;
+        // Padding follows the last frame
+        if header[0] == 0 {
+            return None;
+        }
+        let size = if self.major >= 4 {
+            synchsafe_at(header, 4)
+        } else {
+            u32_at(header, 4)
+        } as usize;
+        let body = self.data.get(10..10 + size)?;
+        // Per-frame unsynchronisation only exists in v2.4
+        let unsynchronised = self.major >= 4 && header[9] & 0x02 != 0;
+        let body = if unsynchronised {
+            resynchronise(body)
+        } else {
+            body.to_vec()
+        };
+        let id = &header[..4];
+        self.data = &self.data[10 + size..];
+        Some((id, body))
+    }
+}
+
+fn frames(data: &[u8], major: u8) -> Frames<'_> {
+    Frames { data, major }
+}
+
+/// A `CHAP` frame: an element id, start and end times in milliseconds,
+/// byte offsets, then subframes with the title.
+fn chapter(frame: &[u8], major: u8) -> Option<Chapter> {
+    let id_end = frame.iter().position(|&byte| byte == 0)?;
+    let times = frame.get(id_end + 1..id_end + 17)?;
+    let title = frames(&frame[id_end + 17..], major)
+        .find(|(id, _)| *id == b"TIT2")
+        .and_then(|(_, body)| text(&body))
+        .unwrap_or_else(|| String::from_utf8_lossy(&frame[..id_end]).into_owned());
+    Some(Chapter {
+        title,
+        start_ms: u32_at(times, 0) as u64,
+        end_ms: u32_at(times, 4) as u64,
+    })
+}
+
+pub fn read(path: &Path) -> Result<Metadata> {
+    let mut metadata = Metadata::default();
+    let mut file = File::open(path)?;
+    let mut header = [0u8; 10];
+    if file.read_exact(&mut header).is_err() || &header[..3] != b"ID3" {
+        return Ok(metadata);
+    }
+    let major = header[3];
+    if !(3..=4).contains(&major) {
+        return Ok(metadata);
+    }
+    let flags = header[5];
+    let mut tag = vec![0; synchsafe_at(&header, 6) as usize];
+    file.read_exact(&mut tag)?;
+    // v2.3 unsynchronises the whole tag, v2.4 each frame
+    if major == 3 && flags & 0x80 != 0 {
+        tag = resynchronise(&tag);
+    }
+    let mut start = 0;
+    if flags & 0x40 != 0 {
+        // The extended header counts its own size field in v2.4 only
+        start = if major >= 4 {
+            synchsafe_at(&tag, 0) as usize
+        } else {
+            u32_at(&tag, 0) as usize + 4
+        };
+    }
+
+    for (id, body) in frames(tag.get(start..).unwrap_or_default(), major) {
+        let field = match id {
+            b"TIT2" => &mut metadata.title,
+            b"TPE1" => &mut metadata.author,
+            b"TALB" => &mut metadata.album,
+            b"TCOM" => &mut metadata.narrator,
+            b"TLEN" => {
+                metadata.duration_ms = text(&body).and_then(|ms| ms.parse().ok());
+                continue;
+            }
+            b"CHAP" => {
+                metadata.chapters.extend(chapter(&body, major));
+                continue;
+            }
+            _ => continue,
+        };
+        if field.is_none() {
+            *field = text(&body);
+        }
+    }
+    metadata.chapters.sort_by_key(|chapter| chapter.start_ms);
+    Ok(metadata)
+}
@@ -0,0 +1,367 @@
+//! Audiobook playback for M4B and MP3 files. Chapters come from the file:
+//! the chapter track or Nero chapters of M4B files and ID3 `CHAP` frames
+//! of MP3 files. Audio is decoded and played natively, so it keeps going
+//! with the window in the background, and the position of each audiobook
+//! is remembered for the next time it is opened. While playing, the
+//! position is reported as `audio-event` events.
+//!
+//! EPUB books narrated through media overlays are played the same way, see
+//! [`overlay`].
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::Serialize;
+use tauri::{command, AppHandle, Emitter, Manager, State};
+
+use crate::error::{Error, Result};
+use crate::store;
+
+pub mod capture;
+mod id3;
+mod mp4;
+pub mod overlay;
+mod player;
+mod stretch;
+
+use player::Player;
+
+const POSITIONS_FILE: &str = "audiobook-positions.json";
+pub const EVENT: &str = "audio-event";
+
+const TICK: Duration = Duration::from_millis(500);
+/// Ticks between saving the position while playing.
+const SAVE_TICKS: u32 = 10;
+const SPEEDS: std::ops::RangeInclusive<f32> = 0.5..=3.0;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum AudioEvent {
+    Position {
+        position_ms: u64,
+        playing: bool,
+    },
+    /// Playback reached the end of the audiobook.
+    Ended,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Chapter {
+    pub title: String,
+    pub start_ms: u64,
+    pub end_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AudiobookInfo {
+    pub path: String,
+    pub title: String,
+    pub author: Option<String>,
+    pub album: Option<String>,
+    pub narrator: Option<String>,
+    pub duration_ms: u64,
+    pub chapters: Vec<Chapter>,
+    /// Where playback resumes, from the last time the audiobook was open.
+    pub position_ms: u64,
+}
+
+/// Tags and chapters as read from the file.
+#[derive(Debug, Default)]
+struct Metadata {
+    title: Option<String>,
+    author: Option<String>,
+    album: Option<String>,
+    narrator: Option<String>,
+    duration_ms: Option<u64>,
+    chapters: Vec<Chapter>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    Mp4,
+    Mp3,
+}
+
+impl Format {
+    fn of(path: &Path) -> Result<Format> {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+        match extension.as_str() {
+            "m4b" | "m4a" | "mp4" => Ok(Format::Mp4),
+            "mp3" => Ok(Format::Mp3),
+            _ => Err(Error::UnsupportedFormat(extension)),
+        }
+    }
+}
+
+struct Session {
+    path: PathBuf,
+    format: Format,
+    player: Player,
+    speed: f32,
+    /// Whether the end was reported, so it is reported once.
+    ended: bool,
+    /// Set when the session is dropped, which stops its ticker.
+    closed: Arc<AtomicBool>,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Session {
+    fn position_ms(&self) -> u64 {
+        self.player.position().as_millis() as u64
+    }
+
+    /// Opens the file again once playback has ended, since the decoder is
+    /// gone by then.
+    fn reopen_if_ended(&mut self, position: Duration) -> Result<()> {
+        if self.player.ended() {
+            self.player = Player::open(&self.path, self.format, position)?;
+            self.player.set_speed(self.speed);
+            self.ended = false;
+        }
+        Ok(())
+    }
+}
+
+/// The open audiobook; one plays at a time.
+#[derive(Default)]
+pub struct Audio(Mutex<Option<Session>>);
+
+fn position_key(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+fn saved_position(app: &AppHandle, path: &Path) -> u64 {
+    let positions: BTreeMap<String, u64> = store::load(app, POSITIONS_FILE);
+    positions.get(&position_key(path)).copied().unwrap_or(0)
+}
+
+/// Saves where playback is, or forgets it once the audiobook is finished.
+fn save_position(app: &AppHandle, session: &Session) {
+    let mut positions: BTreeMap<String, u64> = store::load(app, POSITIONS_FILE);
+    let key = position_key(&session.path);
+    if session.ended {
+        positions.remove(&key);
+    } else {
+        positions.insert(key, session.position_ms());
+    }
+    if let Err(e) = store::save(app, POSITIONS_FILE, &positions) {
+        log::warn!("Failed to save audiobook position: {e}");
+    }
+}
+
+fn emit_position(app: &AppHandle, session: &Session) {
+    let event = AudioEvent::Position {
+        position_ms: session.position_ms(),
+        playing: session.player.is_playing(),
+    };
+    let _ = app.emit(EVENT, event);
+}
+
+/// Reports the position of the session while it plays, until it is closed.
+fn spawn_ticker(app: AppHandle, closed: Arc<AtomicBool>) -> Result<()> {
+    std::thread::Builder::new()
+        .name("audio-position".to_string())
+        .spawn(move || {
+            let mut ticks = 0u32;
+            loop {
+                std::thread::sleep(TICK);
+                let audio = app.state::<Audio>();
+                let mut session = audio.0.lock().unwrap();
+                // Another session may have replaced this one
+                let Some(session) = session.as_mut().filter(|_| !closed.load(Ordering::Relaxed))
+                else {
+                    return;
+                };
+                if session.player.ended() {
+                    if !session.ended {
+                        session.ended = true;
+                        save_position(&app, session);
+                        let _ = app.emit(EVENT, AudioEvent::Ended);
+                    }
+                    continue;
+                }
+                if !session.player.is_playing() {
+                    continue;
+                }
+                emit_position(&app, session);
+                ticks += 1;
+                if ticks % SAVE_TICKS == 0 {
+                    save_position(&app, session);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Gives chapters without an end the start of the next one.
+fn fill_chapter_ends(chapters: &mut [Chapter], duration_ms: u64) {
+    let starts = chapters
+        .iter()
+        .skip(1)
+        .map(|chapter| chapter.start_ms)
+        .chain([duration_ms])
+        .collect::<Vec<_>>();
+    for (chapter, next) in chapters.iter_mut().zip(starts) {
+        if chapter.end_ms <= chapter.start_ms {
+            chapter.end_ms = next.max(chapter.start_ms);
+        }
+    }
+}
+
+fn open(app: &AppHandle, path: PathBuf) -> Result<AudiobookInfo> {
+    let format = Format::of(&path)?;
+    let metadata = match format {
+        Format::Mp4 => mp4::read(&path)?,
+        Format::Mp3 => id3::read(&path)?,
+    };
+    let audio = app.state::<Audio>();
+    let mut current = audio.0.lock().unwrap();
+    if let Some(session) = current.take() {
+        save_position(app, &session);
+    }
+
+    let position_ms = saved_position(app, &path);
+    let player = Player::open(&path, format, Duration::from_millis(position_ms))?;
+    let duration_ms = metadata
+        .duration_ms
+        .or_else(|| {
+            player
+                .duration()
+                .map(|duration| duration.as_millis() as u64)
+        })
+        .unwrap_or(0);
+    let mut chapters = metadata.chapters;
+    fill_chapter_ends(&mut chapters, duration_ms);
+    let title = metadata.title.unwrap_or_else(|| {
+        path.file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    });
+
+    let closed = Arc::new(AtomicBool::new(false));
+    spawn_ticker(app.clone(), closed.clone())?;
+    *current = Some(Session {
+        path: path.clone(),
+        format,
+        player,
+        speed: 1.0,
+        ended: false,
+        closed,
+    });
+    Ok(AudiobookInfo {
+        path: position_key(&path),
+        title,
+        author: metadata.author,
+        album: metadata.album,
+        narrator: metadata.narrator,
+        duration_ms,
+        chapters,
+        position_ms,
+    })
+}
+
+fn with_session<T>(app: &AppHandle, f: impl FnOnce(&mut Session) -> Result<T>) -> Result<T> {
+    let audio = app.state::<Audio>();
+    let mut session = audio.0.lock().unwrap();
+    let session = session
+        .as_mut()
+        .ok_or_else(|| Error::Audio("no audiobook is open".to_string()))?;
+    f(session)
+}
+
+/// Runs `f` on the open session off the async runtime, as seeking waits
+/// for the audio thread and may reopen the file.
+async fn run<T: Send + 'static>(
+    app: AppHandle,
+    f: impl FnOnce(&AppHandle, &mut Session) -> Result<T> + Send + 'static,
+) -> Result<T> {
+    tauri::async_runtime::spawn_blocking(move || with_session(&app, |session| f(&app, session)))
+        .await?
+}
+
+/// Opens the audiobook at `path`, paused where it was last left, replacing
+/// the one open before.
+#[command]
+pub async fn audio_open(app: AppHandle, path: String) -> Result<AudiobookInfo> {
+    tauri::async_runtime::spawn_blocking(move || open(&app, PathBuf::from(path))).await?
+}
+
+/// Plays from the current position, or from the start once it has ended.
+#[command]
+pub async fn audio_play(app: AppHandle) -> Result<()> {
+    run(app, |app, session| {
+        session.reopen_if_ended(Duration::ZERO)?;
+        session.player.play();
+        emit_position(app, session);
+        Ok(())
+    })
+    .await
+}
+
+#[command]
+pub async fn audio_pause(app: AppHandle) -> Result<()> {
+    run(app, |app, session| {
+        session.player.pause();
+        emit_position(app, session);
+        save_position(app, session);
+        Ok(())
+    })
+    .await
+}
+
+#[command]
+pub async fn audio_seek(app: AppHandle, position_ms: u64) -> Result<()> {
+    run(app, move |app, session| {
+        let position = Duration::from_millis(position_ms);
+        if session.player.ended() {
+            session.reopen_if_ended(position)?;
+        } else {
+            session.player.seek(position)?;
+        }
+        emit_position(app, session);
+        save_position(app, session);
+        Ok(())
+    })
+    .await
+}
+
+/// Sets the playback speed as a multiple of the normal speed, from 0.5 to
+/// 3, keeping the pitch of the voice.
+#[command]
+pub async fn audio_set_speed(app: AppHandle, speed: f32) -> Result<()> {
+    if !SPEEDS.contains(&speed) {
+        return Err(Error::Audio(format!("unsupported speed {speed}")));
+    }
+    run(app, move |_, session| {
+        session.speed = speed;
+        session.player.set_speed(speed);
+        Ok(())
+    })
+    .await
+}
+
+/// Stops playback and closes the audiobook, remembering the position.
+#[command]
+pub fn audio_close(app: AppHandle, audio: State<'_, Audio>) {
+    if let Some(session) = audio.0.lock().unwrap().take() {
+        save_position(&app, &session);
+    }
+}
@@ -0,0 +1,305 @@
+//! M4B/M4A metadata: iTunes tags, the duration and the chapters, from a
+//! Nero `chpl` atom or from the QuickTime chapter text track most
+//! audiobook tools write. Only the `moov` atom and the chapter titles are
+//! read; audiobooks are often too large to load whole.
+
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use super::{Chapter, Metadata};
+use crate::error::{Error, Result};
+
+/// Largest `moov` atom read, which holds sample tables and cover art.
+const MAX_MOOV_SIZE: u64 = 64 << 20;
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    data.get(offset..offset + 2)
+        .map_or(0, |b| u16::from_be_bytes([b[0], b[1]]))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    data.get(offset..offset + 4)
+        .map_or(0, |b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    data.get(offset..offset + 8).map_or(0, |b| {
+        u64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]])
+    })
+}
+
+fn invalid(message: &str) -> Error {
+    Error::InvalidBook(message.to_string())
+}
+
+/// The atoms directly inside `data`, as type and body.
+fn atoms(data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut pos = 0;
+    std::iter::from_fn(move || {
+        let header = data.get(pos..pos + 8)?;
+        let (size, header_len) = match u32_at(header, 0) {
+            0 => (data.len() - pos, 8),
+            1 => (usize::try_from(u64_at(data, pos + 8)).ok()?, 16),
+            size => (size as usize, 8),
+        };
+        if size < header_len || pos + size > data.len() {
+            return None;
+        }
+        let atom = (&header[4..8], &data[pos + header_len..pos + size]);
+        pos += size;
+        Some(atom)
+    })
+}
+
+fn child<'a>(data: &'a [u8], kind: &[u8]) -> Option<&'a [u8]> {
+    atoms(data).find(|(k, _)| *k == kind).map(|(_, body)| body)
+}
+
+/// Follows a path of atom types, e.g. `[b"mdia", b"minf"]`.
+fn descend<'a>(data: &'a [u8], path: &[&[u8]]) -> Option<&'a [u8]> {
+    path.iter().try_fold(data, |data, kind| child(data, kind))
+}
+
+/// Reads the `moov` atom, wherever it is among the top-level atoms.
+fn read_moov(file: &mut BufReader<File>) -> Result<Vec<u8>> {
+    let len = file.seek(SeekFrom::End(0))?;
+    let mut pos = 0;
+    while pos + 8 <= len {
+        file.seek(SeekFrom::Start(pos))?;
+        let mut header = [0u8; 16];
+        file.read_exact(&mut header[..8])?;
+        let (size, header_len) = match u32_at(&header, 0) {
+            0 => (len - pos, 8),
+            1 => {
+                file.read_exact(&mut header[8..])?;
+                (u64_at(&header, 8), 16)
+            }
+            size => (size as u64, 8),
+        };
+        if size < header_len {
+            break;
+        }
+        if &header[4..8] == b"moov" {
+            if size > MAX_MOOV_SIZE {
+                return Err(invalid("moov atom too large"));
+            }
+            let mut moov = vec![0; (size - header_len) as usize];
+            file.read_exact(&mut moov)?;
+            return Ok(moov);
+        }
+        pos += size;
+    }
+    Err(invalid("no moov atom"))
+}
+
+/// Timescale and duration of a `mvhd` or `mdhd` atom.
+fn header_times(body: &[u8]) -> (u32, u64) {
+    if body.first() == Some(&1) {
+        (u32_at(body, 20), u64_at(body, 24))
+    } else {
+        (u32_at(body, 12), u32_at(body, 16) as u64)
+    }
+}
+
+fn to_ms(value: u64, timescale: u32) -> u64 {
+    if timescale == 0 {
+        return 0;
+    }
+    (value as u128 * 1000 / timescale as u128) as u64
+}
+
+/// Value of an iTunes metadata item from its `data` atom.
+fn item_text(item: &[u8]) -> Option<String> {
+    let data = child(item, b"data")?;
+    // Type indicator and locale come first
+    let text = String::from_utf8_lossy(data.get(8..)?).trim().to_string();
+    (!text.is_empty()).then_some(text)
+}
+
+fn read_tags(moov: &[u8], metadata: &mut Metadata) {
+    let meta = descend(moov, &[b"udta", b"meta"]).or_else(|| child(moov, b"meta"));
+    // `meta` is a full atom, with version and flags before its children
+    let Some(ilst) = meta.and_then(|meta| child(meta.get(4..)?, b"ilst")) else {
+        return;
+    };
+    for (kind, item) in atoms(ilst) {
+        let field = match kind {
+            b"\xa9nam" => &mut metadata.title,
+            b"\xa9ART" | b"aART" => &mut metadata.author,
+            b"\xa9alb" => &mut metadata.album,
+            b"\xa9nrt" | b"\xa9wrt" => &mut metadata.narrator,
+            _ => continue,
+        };
+        if field.is_none() {
+            *field = item_text(item);
+        }
+    }
+}
+
+/// Nero chapters: start times in 100 ns units and titles.
+fn nero_chapters(chpl: &[u8]) -> Vec<Chapter> {
+    // Version 1 has four more bytes before the count
+    let mut pos = if chpl.first() == Some(&1) { 8 } else { 4 };
+    let count = chpl.get(pos).copied().unwrap_or(0);
+    pos += 1;
+    let mut chapters = Vec::new();
+    for _ in 0..count {
+        let Some(&len) = chpl.get(pos + 8) else {
+            break;
+        };
+        let start = u64_at(chpl, pos);
+        let Some(title) = chpl.get(pos + 9..pos + 9 + len as usize) else {
+            break;
+        };
+        chapters.push(Chapter {
+            title: String::from_utf8_lossy(title).trim().to_string(),
+            start_ms: start / 10_000,
+            end_ms: 0,
+        });
+        pos += 9 + len as usize;
+    }
+    chapters
+}
+
+fn track_id(trak: &[u8]) -> Option<u32> {
+    let tkhd = child(trak, b"tkhd")?;
+    Some(if tkhd.first() == Some(&1) {
+        u32_at(tkhd, 20)
+    } else {
+        u32_at(tkhd, 12)
+    })
+}
+
+/// Start time in track units, file offset and size of each sample.
+fn samples(trak: &[u8]) -> Option<Vec<(u64, u64, u32)>> {
+    let stbl = descend(trak, &[b"mdia", b"minf", b"stbl"])?;
+
+    let stts = child(stbl, b"stts")?;
+    let mut times = Vec::new();
+    let mut time = 0u64;
+    for i in 0..u32_at(stts, 4) as usize {
+        let count = u32_at(stts, 8 + i * 8);
+        let delta = u32_at(stts, 12 + i * 8) as u64;
+        for _ in 0..count {
+            times.push(time);
+            time += delta;
+        }
+    }
+
+    let stsz = child(stbl, b"stsz")?;
+    let uniform = u32_at(stsz, 4);
+    let sizes = (0..u32_at(stsz, 8) as usize)
+        .map(|i| {
+            if uniform != 0 {
+                uniform
+            } else {
+                u32_at(stsz, 12 + i * 4)
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let chunks = if let Some(stco) = child(stbl, b"stco") {
+        (0..u32_at(stco, 4) as usize)
+            .map(|i| u32_at(stco, 8 + i * 4) as u64)
+            .collect::<Vec<_>>()
+    } else {
+        let co64 = child(stbl, b"co64")?;
+        (0..u32_at(co64, 4) as usize)
+            .map(|i| u64_at(co64, 8 + i * 8))
+            .collect()
+    };
+
+    // Runs of chunks with the same number of samples, by first chunk
+    let stsc = child(stbl, b"stsc")?;
+    let runs = (0..u32_at(stsc, 4) as usize)
+        .map(|i| (u32_at(stsc, 8 + i * 12), u32_at(stsc, 12 + i * 12)))
+        .collect::<Vec<_>>();
+
+    let mut result = Vec::new();
+    let mut sample = 0;
+    for (i, &chunk_offset) in chunks.iter().enumerate() {
+        let chunk = i as u32 + 1;
+        let per_chunk = runs
+            .iter()
+            .rev()
+            .find(|(first, _)| *first <= chunk)
+            .map_or(0, |&(_, count)| count);
+        let mut offset = chunk_offset;
+        for _ in 0..per_chunk {
+            let (Some(&time), Some(&size)) = (times.get(sample), sizes.get(sample)) else {
+                return Some(result);
+            };
+            result.push((time, offset, size));
+            offset += size as u64;
+            sample += 1;
+        }
+    }
+    Some(result)
+}
+
+/// Decodes the text of a text sample: a length, then UTF-8 or UTF-16.
+fn sample_text(sample: &[u8]) -> String {
+    let len = u16_at(sample, 0) as usize;
+    let text = sample.get(2..2 + len).unwrap_or_default();
+    if let Some(utf16) = text.strip_prefix(&[0xFE, 0xFF]) {
+        let units = utf16
+            .chunks_exact(2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .collect::<Vec<_>>();
+        return String::from_utf16_lossy(&units).trim().to_string();
+    }
+    String::from_utf8_lossy(text).trim().to_string()
+}
+
+/// Chapters from the text track that a `tref/chap` atom points to.
+fn track_chapters(moov: &[u8], file: &mut BufReader<File>) -> Result<Vec<Chapter>> {
+    let traks = atoms(moov)
+        .filter(|(kind, _)| *kind == b"trak")
+        .map(|(_, body)| body)
+        .collect::<Vec<_>>();
+    let Some(chapter_id) = traks
+        .iter()
+        .find_map(|trak| descend(trak, &[b"tref", b"chap"]))
+        .map(|chap| u32_at(chap, 0))
+    else {
+        return Ok(Vec::new());
+    };
+    let Some(trak) = traks.iter().find(|trak| track_id(trak) == Some(chapter_id)) else {
+        return Ok(Vec::new());
+    };
+    let timescale = descend(trak, &[b"mdia", b"mdhd"]).map_or(0, |mdhd| header_times(mdhd).0);
+
+    let mut chapters = Vec::new();
+    for (time, offset, size) in samples(trak).unwrap_or_default() {
+        // Titles are short; a larger sample is not a title
+        let mut sample = vec![0; size.min(4096) as usize];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut sample)?;
+        chapters.push(Chapter {
+            title: sample_text(&sample),
+            start_ms: to_ms(time, timescale),
+            end_ms: 0,
+        });
+    }
+    Ok(chapters)
+}
+
+pub fn read(path: &Path) -> Result<Metadata> {
+    let mut file = BufReader::new(File::open(path)?);
+    let moov = read_moov(&mut file)?;
+
+    let mut metadata = Metadata::default();
+    if let Some(mvhd) = child(&moov, b"mvhd") {
+        let (timescale, duration) = header_times(mvhd);
+        metadata.duration_ms = Some(to_ms(duration, timescale)).filter(|&ms| ms > 0);
+    }
+    read_tags(&moov, &mut metadata);
+    metadata.chapters = track_chapters(&moov, &mut file)?;
+    if metadata.chapters.is_empty() {
+        if let Some(chpl) = descend(&moov, &[b"udta", b"chpl"]) {
+            metadata.chapters = nero_chapters(chpl);
+        }
+    }
+    Ok(metadata)
+}
@@ -0,0 +1,458 @@
+//! Read-aloud of EPUB 3 books with media overlays, the narrated books for
+//! children and the hybrids of ebook and audiobook. The SMIL document of
+//! each chapter pairs fragments of its text with clips of the audio files
+//! in the book; these are played one after the other, across chapters,
+//! and each fragment is reported as `media-overlay-event` when its clip
+//! starts, for the frontend to highlight it and turn the page.
+//!
+//! Audio files are read from the book into memory as they are reached, so
+//! clips of the same file play on without seeking between them.
+
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::Serialize;
+use tauri::{command, AppHandle, Emitter, Manager, State};
+
+use super::player::Player;
+use super::{Format, SPEEDS};
+use crate::error::{Error, Result};
+use crate::formats::epub::{attr, parse_xml, resolve_href, EpubArchive};
+use crate::library::{self, db};
+
+pub const EVENT: &str = "media-overlay-event";
+
+/// Short, so that the end of a clip is caught within a word.
+const TICK: Duration = Duration::from_millis(40);
+/// Clips that start at most this far from where the audio is play on from
+/// there instead of seeking.
+const CONTIGUOUS_MS: u64 = 250;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum OverlayEvent {
+    /// Fragment `index` of chapter `href`, the element `element_id`,
+    /// started playing or is where playback moved to.
+    Fragment {
+        href: String,
+        element_id: Option<String>,
+        chapter: usize,
+        index: usize,
+    },
+    State {
+        playing: bool,
+    },
+    /// Playback reached the end of the last chapter.
+    Ended,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Fragment {
+    /// Id of the element of the chapter that is read, `None` when the clip
+    /// reads the whole chapter.
+    pub element_id: Option<String>,
+    /// Entry of the audio file in the book.
+    #[serde(skip)]
+    audio: String,
+    pub begin_ms: u64,
+    /// `None` when the clip plays to the end of the file.
+    pub end_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OverlayChapter {
+    /// Path of the chapter in the book, as in the spine.
+    pub href: String,
+    pub fragments: Vec<Fragment>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MediaOverlay {
+    pub book_hash: String,
+    /// Class to give the element being read, `None` when the book names
+    /// none and the frontend's own is used.
+    pub active_class: Option<String>,
+    /// Class to give the document while it is read aloud.
+    pub playback_active_class: Option<String>,
+    /// The narrated chapters in reading order.
+    pub chapters: Vec<OverlayChapter>,
+}
+
+/// The audio file the player has open.
+struct Track {
+    audio: String,
+    player: Player,
+}
+
+struct Session {
+    path: PathBuf,
+    chapters: Vec<OverlayChapter>,
+    /// The chapter and fragment being read.
+    current: (usize, usize),
+    track: Option<Track>,
+    playing: bool,
+    ended: bool,
+    speed: f32,
+    /// Set when the session is dropped, which stops its ticker.
+    closed: Arc<AtomicBool>,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Relaxed);
+    }
+}
+
+/// The open media overlay; one plays at a time.
+#[derive(Default)]
+pub struct Overlays(Mutex<Option<Session>>);
+
+/// Parses a SMIL clock value, such as `0:01:02.5`, `02:03.250`, `4.5s`,
+/// `800ms`, `1.5min` or `12`, into milliseconds.
+fn parse_clock(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let seconds = if value.contains(':') {
+        let parts = value.split(':').collect::<Vec<_>>();
+        if parts.len() > 3 {
+            return None;
+        }
+        parts.iter().try_fold(0.0, |total, part| {
+            Some(total * 60.0 + part.trim().parse::<f64>().ok()?)
+        })?
+    } else {
+        let (number, scale) = if let Some(number) = value.strip_suffix("ms") {
+            (number, 0.001)
+        } else if let Some(number) = value.strip_suffix("min") {
+            (number, 60.0)
+        } else if let Some(number) = value.strip_suffix('h') {
+            (number, 3600.0)
+        } else if let Some(number) = value.strip_suffix('s') {
+            (number, 1.0)
+        } else {
+            (value, 1.0)
+        };
+        number.trim().parse::<f64>().ok()? * scale
+    };
+    (seconds.is_finite() && seconds >= 0.0).then(|| (seconds * 1000.0).round() as u64)
+}
+
+/// The fragments of the SMIL document at `smil_path` in the book, in the
+/// order they are read.
+fn parse_smil(smil: &str, smil_path: &str) -> Result<Vec<Fragment>> {
+    let doc = parse_xml(smil)?;
+    let is = |node: &roxmltree::Node, name: &str| {
+        node.is_element() && node.tag_name().name().eq_ignore_ascii_case(name)
+    };
+    let mut fragments = Vec::new();
+    for par in doc.descendants().filter(|n| is(n, "par")) {
+        let text = par.children().find(|n| is(n, "text"));
+        let audio = par.children().find(|n| is(n, "audio"));
+        let (Some(text), Some(audio)) = (text, audio) else {
+            continue;
+        };
+        let Some(src) = attr(audio, "src") else {
+            continue;
+        };
+        let element_id = attr(text, "src")
+            .and_then(|src| src.split_once('#'))
+            .map(|(_, id)| id.to_string())
+            .filter(|id| !id.is_empty());
+        fragments.push(Fragment {
+            element_id,
+            audio: resolve_href(smil_path, src),
+            begin_ms: attr(audio, "clipBegin").and_then(parse_clock).unwrap_or(0),
+            end_ms: attr(audio, "clipEnd").and_then(parse_clock),
+        });
+    }
+    Ok(fragments)
+}
+
+/// The media overlay of the EPUB at `path`, `None` when it has none.
+fn read_overlay(book_hash: &str, path: &Path) -> Result<Option<MediaOverlay>> {
+    let mut archive = EpubArchive::open(path)?;
+    let package = archive.package()?;
+    let mut chapters = Vec::new();
+    for item in package.spine_items() {
+        let Some(smil) = item
+            .media_overlay
+            .as_deref()
+            .and_then(|id| package.item(id))
+        else {
+            continue;
+        };
+        let fragments = archive
+            .read_entry_string(&smil.href)
+            .and_then(|text| parse_smil(&text, &smil.href));
+        match fragments {
+            Ok(fragments) if !fragments.is_empty() => chapters.push(OverlayChapter {
+                href: item.href.clone(),
+                fragments,
+            }),
+            Ok(_) => {}
+            Err(e) => log::warn!("Ignoring media overlay {} of {book_hash}: {e}", smil.href),
+        }
+    }
+    if chapters.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(MediaOverlay {
+        book_hash: book_hash.to_string(),
+        active_class: package.active_class,
+        playback_active_class: package.playback_active_class,
+        chapters,
+    }))
+}
+
+impl Session {
+    fn fragment(&self, (chapter, index): (usize, usize)) -> &Fragment {
+        &self.chapters[chapter].fragments[index]
+    }
+
+    fn after(&self, (chapter, index): (usize, usize)) -> Option<(usize, usize)> {
+        if index + 1 < self.chapters[chapter].fragments.len() {
+            Some((chapter, index + 1))
+        } else if chapter + 1 < self.chapters.len() {
+            Some((chapter + 1, 0))
+        } else {
+            None
+        }
+    }
+
+    /// Fragment `element_id` of chapter `href`, the first of the chapter
+    /// when not given.
+    fn find(&self, href: &str, element_id: Option<&str>) -> Option<(usize, usize)> {
+        let chapter = self.chapters.iter().position(|c| c.href == href)?;
+        let index = match element_id {
+            Some(id) => self.chapters[chapter]
+                .fragments
+                .iter()
+                .position(|f| f.element_id.as_deref() == Some(id))?,
+            None => 0,
+        };
+        Some((chapter, index))
+    }
+
+    /// Moves to fragment `at`, playing on when the audio is already there,
+    /// else seeking to its clip or opening its file.
+    fn start(&mut self, app: &AppHandle, at: (usize, usize)) -> Result<()> {
+        let fragment = self.fragment(at).clone();
+        let begin = Duration::from_millis(fragment.begin_ms);
+        match &self.track {
+            Some(track) if track.audio == fragment.audio && !track.player.ended() => {
+                let position = track.player.position().as_millis() as u64;
+                if position.abs_diff(fragment.begin_ms) > CONTIGUOUS_MS {
+                    track.player.seek(begin)?;
+                }
+            }
+            _ => {
+                // The player of the previous file stops before reading the next
+                self.track = None;
+                let format = Format::of(Path::new(&fragment.audio))?;
+                let data = EpubArchive::open(&self.path)?.read_entry(&fragment.audio)?;
+                let player = Player::from_reader(Cursor::new(data), format, begin)?;
+                player.set_speed(self.speed);
+                self.track = Some(Track {
+                    audio: fragment.audio.clone(),
+                    player,
+                });
+            }
+        }
+        if let Some(track) = self.track.as_ref().filter(|_| self.playing) {
+            track.player.play();
+        }
+        self.current = at;
+        self.ended = false;
+        let event = OverlayEvent::Fragment {
+            href: self.chapters[at.0].href.clone(),
+            element_id: fragment.element_id,
+            chapter: at.0,
+            index: at.1,
+        };
+        let _ = app.emit(EVENT, event);
+        Ok(())
+    }
+
+    /// Moves on to the next fragment once the clip of the current one is
+    /// over.
+    fn tick(&mut self, app: &AppHandle) -> Result<()> {
+        let over = match &self.track {
+            Some(track) => {
+                let position = track.player.position().as_millis() as u64;
+                let end = self.fragment(self.current).end_ms;
+                track.player.ended() || end.is_some_and(|end| position >= end)
+            }
+            None => false,
+        };
+        if !over {
+            return Ok(());
+        }
+        match self.after(self.current) {
+            Some(next) => self.start(app, next),
+            None => {
+                self.set_playing(app, false);
+                self.ended = true;
+                let _ = app.emit(EVENT, OverlayEvent::Ended);
+                Ok(())
+            }
+        }
+    }
+
+    fn set_playing(&mut self, app: &AppHandle, playing: bool) {
+        self.playing = playing;
+        if let Some(track) = &self.track {
+            if playing {
+                track.player.play();
+            } else {
+                track.player.pause();
+            }
+        }
+        let _ = app.emit(EVENT, OverlayEvent::State { playing });
+    }
+}
+
+/// Follows the clips of the session while it plays, until it is closed.
+fn spawn_ticker(app: AppHandle, closed: Arc<AtomicBool>) -> Result<()> {
+    std::thread::Builder::new()
+        .name("media-overlay".to_string())
+        .spawn(move || loop {
+            std::thread::sleep(TICK);
+            let overlays = app.state::<Overlays>();
+            let mut session = overlays.0.lock().unwrap();
+            // Another session may have replaced this one
+            let Some(session) = session.as_mut().filter(|_| !closed.load(Ordering::Relaxed)) else {
+                return;
+            };
+            if !session.playing {
+                continue;
+            }
+            if let Err(e) = session.tick(&app) {
+                log::warn!("Failed to play the media overlay: {e}");
+                session.set_playing(&app, false);
+            }
+        })?;
+    Ok(())
+}
+
+fn open(app: &AppHandle, book_hash: &str) -> Result<Option<MediaOverlay>> {
+    let book = {
+        let db = app.state::<db::LibraryDb>();
+        let book = db::get_book(&db.conn(), book_hash)?;
+        book.ok_or_else(|| Error::InvalidBook(format!("no book {book_hash}")))?
+    };
+    let path = library::book_path(app, &book)
+        .ok_or_else(|| Error::InvalidBook(format!("{} has no file", book.title)))?;
+    let overlays = app.state::<Overlays>();
+    let mut current = overlays.0.lock().unwrap();
+    *current = None;
+    let Some(overlay) = read_overlay(book_hash, &path)? else {
+        return Ok(None);
+    };
+
+    let closed = Arc::new(AtomicBool::new(false));
+    spawn_ticker(app.clone(), closed.clone())?;
+    *current = Some(Session {
+        path,
+        chapters: overlay.chapters.clone(),
+        current: (0, 0),
+        track: None,
+        playing: false,
+        ended: false,
+        speed: 1.0,
+        closed,
+    });
+    Ok(Some(overlay))
+}
+
+/// Runs `f` on the open session off the async runtime, as moving to
+/// another clip may read its file from the book.
+async fn run<T: Send + 'static>(
+    app: AppHandle,
+    f: impl FnOnce(&AppHandle, &mut Session) -> Result<T> + Send + 'static,
+) -> Result<T> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let overlays = app.state::<Overlays>();
+        let mut session = overlays.0.lock().unwrap();
+        let session = session
+            .as_mut()
+            .ok_or_else(|| Error::Audio("no media overlay is open".to_string()))?;
+        f(&app, session)
+    })
+    .await?
+}
+
+/// Opens the media overlay of book `book_hash`, paused at its start,
+/// replacing the one open before. Returns `None` for books that are not
+/// narrated.
+#[command]
+pub async fn media_overlay_open(app: AppHandle, book_hash: String) -> Result<Option<MediaOverlay>> {
+    tauri::async_runtime::spawn_blocking(move || open(&app, &book_hash)).await?
+}
+
+/// Plays from fragment `element_id` of chapter `href` when given, from its
+/// first fragment without `element_id`, else from where playback is, or
+/// from the start once it has ended.
+#[command]
+pub async fn media_overlay_play(
+    app: AppHandle,
+    href: Option<String>,
+    element_id: Option<String>,
+) -> Result<()> {
+    run(app, move |app, session| {
+        let at = match href {
+            Some(href) => session
+                .find(&href, element_id.as_deref())
+                .ok_or_else(|| Error::Audio(format!("{href} is not narrated")))?,
+            None if session.ended => (0, 0),
+            None => session.current,
+        };
+        session.playing = true;
+        if session.track.is_none() || session.ended || session.current != at {
+            session.start(app, at)?;
+        }
+        session.set_playing(app, true);
+        Ok(())
+    })
+    .await
+}
+
+#[command]
+pub async fn media_overlay_pause(app: AppHandle) -> Result<()> {
+    run(app, |app, session| {
+        session.set_playing(app, false);
+        Ok(())
+    })
+    .await
+}
+
+/// Sets the playback speed as a multiple of the normal speed, from 0.5 to
+/// 3, keeping the pitch of the voice.
+#[command]
+pub async fn media_overlay_set_speed(app: AppHandle, speed: f32) -> Result<()> {
+    if !SPEEDS.contains(&speed) {
+        return Err(Error::Audio(format!("unsupported speed {speed}")));
+    }
+    run(app, move |_, session| {
+        session.speed = speed;
+        if let Some(track) = &session.track {
+            track.player.set_speed(speed);
+        }
+        Ok(())
+    })
+    .await
+}
+
+/// Stops playback and closes the media overlay.
+#[command]
+pub fn media_overlay_close(overlays: State<'_, Overlays>) {
+    overlays.0.lock().unwrap().take();
+}
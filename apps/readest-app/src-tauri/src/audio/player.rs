@@ -0,0 +1,120 @@
+//! Decoding and output through rodio. The output stream cannot move between
+//! threads, so it lives on a thread of its own for as long as the player.
+
+use std::fs::File;
+use std::io::{BufReader, Read, Seek};
+use std::path::Path;
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rodio::decoder::Mp4Type;
+use rodio::{Decoder, OutputStream, OutputStreamHandle, Sink, Source};
+
+use super::stretch::{Controls, Stretch};
+use super::Format;
+use crate::error::{Error, Result};
+
+fn audio_error(e: impl std::fmt::Display) -> Error {
+    Error::Audio(e.to_string())
+}
+
+/// Opens the default output device on a new thread, which keeps it open
+/// until the returned sender is dropped.
+fn open_output() -> Result<(OutputStreamHandle, Sender<()>)> {
+    let (ready_tx, ready_rx) = mpsc::channel();
+    let (stop_tx, stop_rx) = mpsc::channel::<()>();
+    std::thread::Builder::new()
+        .name("audio-output".to_string())
+        .spawn(move || match OutputStream::try_default() {
+            Ok((_stream, handle)) => {
+                let _ = ready_tx.send(Ok(handle));
+                // Returns once the sender is dropped
+                let _ = stop_rx.recv();
+            }
+            Err(e) => {
+                let _ = ready_tx.send(Err(e));
+            }
+        })?;
+    let handle = ready_rx.recv().map_err(audio_error)?.map_err(audio_error)?;
+    Ok((handle, stop_tx))
+}
+
+pub struct Player {
+    sink: Sink,
+    controls: Arc<Controls>,
+    /// Duration as the decoder reports it, which MP3 files may lack.
+    duration: Option<Duration>,
+    _output: Sender<()>,
+}
+
+impl Player {
+    /// Opens `path` paused at `position`.
+    pub fn open(path: &Path, format: Format, position: Duration) -> Result<Player> {
+        Self::from_reader(BufReader::new(File::open(path)?), format, position)
+    }
+
+    /// Opens the audio `file` reads, such as an entry of a book held in
+    /// memory, paused at `position`.
+    pub fn from_reader<R>(file: R, format: Format, position: Duration) -> Result<Player>
+    where
+        R: Read + Seek + Send + Sync + 'static,
+    {
+        let decoder = match format {
+            Format::Mp4 => Decoder::new_mp4(file, Mp4Type::M4b),
+            Format::Mp3 => Decoder::new_mp3(file),
+        }
+        .map_err(audio_error)?;
+        let duration = decoder.total_duration();
+        let mut source = Stretch::new(decoder.convert_samples::<f32>());
+        if !position.is_zero() {
+            source.try_seek(position).map_err(audio_error)?;
+        }
+        let controls = source.controls();
+
+        let (handle, output) = open_output()?;
+        let sink = Sink::try_new(&handle).map_err(audio_error)?;
+        sink.pause();
+        sink.append(source);
+        Ok(Player {
+            sink,
+            controls,
+            duration,
+            _output: output,
+        })
+    }
+
+    pub fn duration(&self) -> Option<Duration> {
+        self.duration
+    }
+
+    pub fn play(&self) {
+        self.sink.play();
+    }
+
+    pub fn pause(&self) {
+        self.sink.pause();
+    }
+
+    pub fn is_playing(&self) -> bool {
+        !self.sink.is_paused() && !self.sink.empty()
+    }
+
+    /// Whether playback reached the end of the file.
+    pub fn ended(&self) -> bool {
+        self.sink.empty()
+    }
+
+    pub fn position(&self) -> Duration {
+        self.controls.position()
+    }
+
+    pub fn seek(&self, position: Duration) -> Result<()> {
+        self.sink.try_seek(position).map_err(audio_error)
+    }
+
+    /// `speed` is a multiple of the normal speed; the pitch is kept.
+    pub fn set_speed(&self, speed: f32) {
+        self.controls.set_speed(speed);
+    }
+}
@@ -0,0 +1,294 @@
+//! Playback speed without the pitch change of resampling, by WSOLA: the
+//! input is cut into overlapping windows taken `speed` times further apart
+//! than they are laid down, each shifted slightly to line up with the
+//! previous one so voices stay smooth. At normal speed samples pass through.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rodio::source::SeekError;
+use rodio::Source;
+
+/// Stride of the similarity search, in frames, trading accuracy for speed.
+const SEARCH_STRIDE: usize = 4;
+
+/// Speed and position shared between the audio thread and the player.
+pub struct Controls {
+    speed: AtomicU32,
+    /// Position in the source, in frames.
+    position: AtomicU64,
+    sample_rate: u32,
+}
+
+impl Controls {
+    pub fn speed(&self) -> f32 {
+        f32::from_bits(self.speed.load(Ordering::Relaxed))
+    }
+
+    pub fn set_speed(&self, speed: f32) {
+        self.speed.store(speed.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn position(&self) -> Duration {
+        let frames = self.position.load(Ordering::Relaxed);
+        Duration::from_secs_f64(frames as f64 / self.sample_rate.max(1) as f64)
+    }
+}
+
+pub struct Stretch<S> {
+    inner: S,
+    controls: Arc<Controls>,
+    channels: usize,
+    /// Frames per window, and the output hop of half a window.
+    window: Vec<f32>,
+    hop: usize,
+    /// How far a window may move to line up, in frames.
+    tolerance: usize,
+    /// Samples read from the source since the start.
+    read: u64,
+    /// Samples read but not yet consumed.
+    input: Vec<f32>,
+    /// Where the next window would start at the exact speed, in frames
+    /// into `input`.
+    nominal: f64,
+    /// Start of the previous window, once stretching.
+    previous: Option<usize>,
+    /// Second half of the previous window, to add to the next one.
+    overlap: Vec<f32>,
+    output: VecDeque<f32>,
+    ended: bool,
+}
+
+impl<S> Stretch<S>
+where
+    S: Source<Item = f32>,
+{
+    pub fn new(inner: S) -> Self {
+        let channels = inner.channels().max(1) as usize;
+        let sample_rate = inner.sample_rate();
+        // 40 ms windows suit speech
+        let hop = (sample_rate / 50).max(1) as usize;
+        let window = (0..hop * 2)
+            .map(|i| 0.5 - 0.5 * (std::f32::consts::PI * i as f32 / hop as f32).cos())
+            .collect();
+        let controls = Arc::new(Controls {
+            speed: AtomicU32::new(1f32.to_bits()),
+            position: AtomicU64::new(0),
+            sample_rate,
+        });
+        Stretch {
+            inner,
+            controls,
+            channels,
+            window,
+            hop,
+            tolerance: (sample_rate / 100) as usize,
+            read: 0,
+            input: Vec::new(),
+            nominal: 0.0,
+            previous: None,
+            overlap: Vec::new(),
+            output: VecDeque::new(),
+            ended: false,
+        }
+    }
+
+    pub fn controls(&self) -> Arc<Controls> {
+        self.controls.clone()
+    }
+
+    fn frames(&self) -> usize {
+        self.input.len() / self.channels
+    }
+
+    fn set_position(&self, frames: u64) {
+        self.controls.position.store(frames, Ordering::Relaxed);
+    }
+
+    /// Reads until `input` holds `frames` frames, or the source ends.
+    fn fill(&mut self, frames: usize) -> bool {
+        while self.frames() < frames {
+            let Some(sample) = self.inner.next() else {
+                return false;
+            };
+            self.input.push(sample);
+            self.read += 1;
+        }
+        true
+    }
+
+    /// Mono sum of the frame at `frame` in `input`.
+    fn mono(&self, frame: usize) -> f32 {
+        let start = frame * self.channels;
+        self.input[start..start + self.channels].iter().sum()
+    }
+
+    /// The start in `[from, to]` whose window best matches the one at
+    /// `target`, by normalized cross-correlation.
+    fn best_start(&self, from: usize, to: usize, target: usize) -> usize {
+        let len = self.window.len();
+        let target = (0..len)
+            .step_by(SEARCH_STRIDE)
+            .map(|i| self.mono(target + i))
+            .collect::<Vec<_>>();
+        let mut best = (f32::MIN, from);
+        for start in from..=to {
+            let (mut dot, mut energy) = (0.0, 0.0);
+            for (j, &t) in target.iter().enumerate() {
+                let sample = self.mono(start + j * SEARCH_STRIDE);
+                dot += sample * t;
+                energy += sample * sample;
+            }
+            let score = dot / (energy + f32::EPSILON).sqrt();
+            if score > best.0 {
+                best = (score, start);
+            }
+        }
+        best.1
+    }
+
+    /// Hands the input after the previous window to the output, fading it
+    /// in over the pending overlap, which makes up the rest of the previous
+    /// window, so switching back to normal speed is seamless.
+    fn flush(&mut self) {
+        let from = match self.previous {
+            Some(previous) => (previous + self.hop) * self.channels,
+            None => ((self.nominal as usize) * self.channels).min(self.input.len()),
+        };
+        let tail = self.input.get(from..).unwrap_or_default();
+        for (i, &sample) in tail.iter().enumerate() {
+            let sample = match self.overlap.get(i) {
+                Some(overlap) => sample * self.window[i / self.channels] + overlap,
+                None => sample,
+            };
+            self.output.push_back(sample);
+        }
+        self.reset();
+    }
+
+    fn reset(&mut self) {
+        self.input.clear();
+        self.nominal = 0.0;
+        self.previous = None;
+        self.overlap.clear();
+    }
+
+    /// Lays down one more window, adding a hop of output.
+    fn stretch(&mut self, speed: f32) {
+        let (hop, tolerance, len) = (self.hop, self.tolerance, self.window.len());
+        let nominal = self.nominal.round() as usize;
+        let from = nominal.saturating_sub(tolerance);
+        let to = nominal + tolerance;
+        let target = self.previous.map(|previous| previous + hop);
+        let needed = (to + len).max(target.map_or(0, |target| target + len));
+        if !self.fill(needed) {
+            self.ended = true;
+            return self.flush();
+        }
+
+        let start = match target {
+            Some(target) => self.best_start(from, to, target),
+            None => nominal,
+        };
+        let channels = self.channels;
+        let segment = &self.input[start * channels..(start + len) * channels];
+        for (i, &sample) in segment[..hop * channels].iter().enumerate() {
+            // The first window has nothing to overlap and is not faded in
+            let gain = if self.previous.is_some() {
+                self.window[i / channels]
+            } else {
+                1.0
+            };
+            let overlap = self.overlap.get(i).copied().unwrap_or(0.0);
+            self.output.push_back(sample * gain + overlap);
+        }
+        self.overlap.clear();
+        self.overlap.extend(
+            segment[hop * channels..]
+                .iter()
+                .enumerate()
+                .map(|(i, &sample)| sample * self.window[hop + i / channels]),
+        );
+        let consumed = (self.read / channels as u64) - self.frames() as u64;
+        self.set_position(consumed + start as u64);
+        self.nominal += speed as f64 * hop as f64;
+
+        // Drop the input no later window can start in
+        let dropped = (self.nominal as usize)
+            .saturating_sub(tolerance)
+            .min(start + hop);
+        self.input.drain(..dropped * channels);
+        self.nominal -= dropped as f64;
+        self.previous = Some(start - dropped);
+    }
+}
+
+impl<S> Iterator for Stretch<S>
+where
+    S: Source<Item = f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        loop {
+            if let Some(sample) = self.output.pop_front() {
+                return Some(sample);
+            }
+            if self.ended {
+                return None;
+            }
+            let speed = self.controls.speed();
+            if (speed - 1.0).abs() < 0.01 {
+                if !self.input.is_empty() {
+                    self.flush();
+                    continue;
+                }
+                let sample = self.inner.next()?;
+                self.read += 1;
+                if self.read % self.channels as u64 == 0 {
+                    self.set_position(self.read / self.channels as u64);
+                }
+                return Some(sample);
+            }
+            self.stretch(speed);
+        }
+    }
+}
+
+impl<S> Source for Stretch<S>
+where
+    S: Source<Item = f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        if self.output.is_empty() && self.input.is_empty() {
+            self.inner.current_frame_len()
+        } else {
+            None
+        }
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels as u16
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.controls.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+
+    fn try_seek(&mut self, position: Duration) -> Result<(), SeekError> {
+        self.inner.try_seek(position)?;
+        self.reset();
+        self.output.clear();
+        self.ended = false;
+        let frames = (position.as_secs_f64() * self.controls.sample_rate as f64) as u64;
+        self.read = frames * self.channels as u64;
+        self.set_position(frames);
+        Ok(())
+    }
+}
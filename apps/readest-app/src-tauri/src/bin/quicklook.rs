@@ -0,0 +1,7 @@
+//! The Quick Look preview extension, bundled in the app by
+//! `scripts/build-quicklook.sh`.
+
+fn main() {
+    #[cfg(target_os = "macos")]
+    std::process::exit(vlarchlib::run_quicklook_extension());
+}
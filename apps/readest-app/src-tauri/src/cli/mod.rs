@@ -0,0 +1,474 @@
+//! Library maintenance from the command line, for scripts and cron jobs:
+//!
+//! ```text
+//! vlarch import <dir> [--threads <n>]
+//! vlarch export-annotations [--book <hash>] [--format markdown|json|csv] [--out <file>]
+//! vlarch convert <file>... [--out <dir>]
+//! vlarch sync
+//! ```
+//!
+//! The commands run on the same library, settings and keychain entries as
+//! the app, through a Tauri app that is built but never opens a window or
+//! runs its event loop. Progress goes to stderr and results to stdout.
+//! Tauri still initializes the platform toolkit, so on Linux a display is
+//! needed; `xvfb-run vlarch sync` does for headless machines.
+//!
+//! Only the subcommands above are taken over, so files passed to open in
+//! the app keep working. The hidden `--bench-open` mode is no subcommand
+//! and works without this feature, see [`crate::formats::bench::run`].
+
+use std::collections::HashMap;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tauri::{AppHandle, Listener};
+
+use crate::commands::sync::{sync_book_data, SyncResult};
+use crate::error::{Error, Result};
+use crate::export::annotations::{self, ExportFormat};
+use crate::library::{self, import};
+use crate::secrets::storage;
+use crate::sync::{BookNote, BookSyncData};
+use crate::{convert, hooks, jobs};
+
+const USAGE: &str = "\
+Usage:
+  vlarch import <dir> [--threads <n>]
+      Imports every book under <dir>, skipping those already in the library.
+  vlarch export-annotations [--book <hash>] [--format markdown|json|csv] [--out <file>]
+      Exports highlights and notes, of every book unless --book is given,
+      to <file> or stdout.
+  vlarch convert <file>... [--out <dir>]
+      Converts MOBI, AZW3 and other books to EPUB, into <dir> or the cache.
+  vlarch sync
+      Syncs reading progress and notes with the configured sync provider.";
+
+/// Exit codes: usage errors and failures, the way shells expect them.
+const EXIT_FAILED: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+
+enum Command {
+    Import {
+        dir: PathBuf,
+        threads: Option<usize>,
+    },
+    ExportAnnotations {
+        book: Option<String>,
+        format: ExportFormat,
+        out: Option<PathBuf>,
+    },
+    Convert {
+        files: Vec<PathBuf>,
+        out: Option<PathBuf>,
+    },
+    Sync,
+    Help,
+}
+
+/// Positional arguments and `--name value` options.
+struct Args {
+    positional: Vec<String>,
+    options: HashMap<&'static str, String>,
+}
+
+impl Args {
+    /// Splits `args`, taking only the options named in `known`.
+    fn parse(args: Vec<String>, known: &[&'static str]) -> std::result::Result<Self, String> {
+        let mut positional = Vec::new();
+        let mut options = HashMap::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                positional.extend(args.by_ref());
+                break;
+            }
+            let Some(option) = arg.strip_prefix("--") else {
+                positional.push(arg);
+                continue;
+            };
+            let (name, value) = match option.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (option, None),
+            };
+            let Some(&name) = known.iter().find(|known| **known == name) else {
+                return Err(format!("unknown option --{name}"));
+            };
+            let value = value
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("--{name} needs a value"))?;
+            options.insert(name, value);
+        }
+        Ok(Self {
+            positional,
+            options,
+        })
+    }
+
+    fn path(&self, name: &str) -> Option<PathBuf> {
+        self.options.get(name).map(PathBuf::from)
+    }
+}
+
+impl Command {
+    /// The command `name` runs with `args`, or `None` when `name` is not a
+    /// subcommand and the app should start as usual.
+    fn parse(name: &str, args: Vec<String>) -> Option<std::result::Result<Self, String>> {
+        let command = match name {
+            "import" => Args::parse(args, &["threads"]).and_then(|args| {
+                let [dir] = args.positional.as_slice() else {
+                    return Err("import takes one directory".to_string());
+                };
+                let threads = match args.options.get("threads") {
+                    Some(threads) => Some(
+                        threads
+                            .parse()
+                            .map_err(|_| format!("invalid thread count {threads}"))?,
+                    ),
+                    None => None,
+                };
+                Ok(Self::Import {
+                    dir: PathBuf::from(dir),
+                    threads,
+                })
+            }),
+            "export-annotations" => {
+                Args::parse(args, &["book", "format", "out"]).and_then(|args| {
+                    if !args.positional.is_empty() {
+                        return Err("export-annotations takes no arguments".to_string());
+                    }
+                    let format = match args.options.get("format") {
+                        Some(format) => ExportFormat::parse(format)
+                            .ok_or_else(|| format!("unknown format {format}"))?,
+                        None => ExportFormat::Markdown,
+                    };
+                    Ok(Self::ExportAnnotations {
+                        book: args.options.get("book").cloned(),
+                        format,
+                        out: args.path("out"),
+                    })
+                })
+            }
+            "convert" => Args::parse(args, &["out"]).and_then(|args| {
+                if args.positional.is_empty() {
+                    return Err("convert takes at least one file".to_string());
+                }
+                Ok(Self::Convert {
+                    out: args.path("out"),
+                    files: args.positional.into_iter().map(PathBuf::from).collect(),
+                })
+            }),
+            "sync" => Args::parse(args, &[]).and_then(|args| match args.positional.is_empty() {
+                true => Ok(Self::Sync),
+                false => Err("sync takes no arguments".to_string()),
+            }),
+            "help" | "--help" | "-h" => Ok(Self::Help),
+            _ => return None,
+        };
+        Some(command)
+    }
+}
+
+/// Runs the subcommand in the command line arguments, returning the exit
+/// code, or `None` if there is none and the app should start.
+pub fn run() -> Option<i32> {
+    let mut args = crate::paths::portable::without_data_dir_arg(std::env::args())
+        .into_iter()
+        .skip(1);
+    let name = args.next()?;
+    let command = Command::parse(&name, args.collect())?;
+    crate::attach_console();
+    let code = match command {
+        Ok(Command::Help) => {
+            println!("{USAGE}");
+            0
+        }
+        Ok(command) => match execute(command) {
+            Ok(true) => 0,
+            Ok(false) => EXIT_FAILED,
+            Err(e) => {
+                eprintln!("vlarch: {e}");
+                EXIT_FAILED
+            }
+        },
+        Err(e) => {
+            eprintln!("vlarch: {e}\n\n{USAGE}");
+            EXIT_USAGE
+        }
+    };
+    Some(code)
+}
+
+/// Runs `command`, returning whether every book it worked on succeeded.
+fn execute(command: Command) -> std::result::Result<bool, Box<dyn std::error::Error>> {
+    #[allow(unused_mut)]
+    let mut app = tauri::Builder::default().build(crate::context())?;
+    // No Dock icon for a command line tool
+    #[cfg(target_os = "macos")]
+    app.set_activation_policy(tauri::ActivationPolicy::Accessory);
+
+    let handle = app.handle().clone();
+    crate::logging::init(&handle, tracing_subscriber::filter::LevelFilter::WARN);
+    crate::init_state(&handle)?;
+    let last_phase = Mutex::new(String::new());
+    handle.listen(jobs::EVENT, move |event| {
+        report_progress(event.payload(), &last_phase)
+    });
+
+    let result = tauri::async_runtime::block_on(async move {
+        match command {
+            Command::Import { dir, threads } => import_books(handle, dir, threads).await,
+            Command::ExportAnnotations { book, format, out } => {
+                tauri::async_runtime::spawn_blocking(move || {
+                    export_annotations(&handle, book.as_deref(), format, out.as_deref())
+                })
+                .await?
+            }
+            Command::Convert { files, out } => convert_books(handle, files, out).await,
+            Command::Sync => sync_books(handle).await,
+            Command::Help => Ok(true),
+        }
+    });
+    hooks::wait(app.handle());
+    Ok(result?)
+}
+
+/// Shows the phase and percentage of the running job on one line when
+/// stderr is a terminal, and each phase once when it is not.
+fn report_progress(payload: &str, last_phase: &Mutex<String>) {
+    let Ok(progress) = serde_json::from_str::<Value>(payload) else {
+        return;
+    };
+    let terminal = std::io::stderr().is_terminal();
+    let mut stderr = std::io::stderr().lock();
+    if progress["state"] != "running" {
+        if terminal {
+            let _ = write!(stderr, "\r\x1b[K");
+        }
+        return;
+    }
+    let phase = progress["phase"].as_str().unwrap_or_default();
+    if !terminal {
+        let mut last_phase = last_phase.lock().unwrap();
+        if *last_phase != phase {
+            *last_phase = phase.to_string();
+            let _ = writeln!(stderr, "{phase}...");
+        }
+        return;
+    }
+    let mut line = phase.to_string();
+    if let Some(percent) = progress["percent"].as_f64() {
+        line.push_str(&format!(" {percent:.0}%"));
+    }
+    if let Some(message) = progress["message"].as_str() {
+        line.push(' ');
+        line.push_str(message);
+    }
+    // Longer lines would wrap and leave their start behind
+    let line = line.chars().take(79).collect::<String>();
+    let _ = write!(stderr, "\r\x1b[K{line}");
+    let _ = stderr.flush();
+}
+
+async fn import_books(app: AppHandle, dir: PathBuf, threads: Option<usize>) -> Result<bool> {
+    let results = import::import_directory(app, dir, threads).await?;
+    let mut imported = 0;
+    let mut duplicates = 0;
+    let mut failed = 0;
+    for result in &results {
+        match &result.error {
+            Some(error) => {
+                failed += 1;
+                eprintln!("{}: {error}", result.path.display());
+            }
+            None if result.duplicate => duplicates += 1,
+            None => {
+                imported += 1;
+                println!("{}", result.path.display());
+            }
+        }
+    }
+    eprintln!(
+        "Imported {imported} books, skipped {duplicates} already in the library, {failed} failed"
+    );
+    Ok(failed == 0)
+}
+
+fn export_annotations(
+    app: &AppHandle,
+    book: Option<&str>,
+    format: ExportFormat,
+    out: Option<&Path>,
+) -> Result<bool> {
+    let (_, content) = annotations::render(app, book, format)?;
+    match out {
+        Some(out) => std::fs::write(out, content)?,
+        None => std::io::stdout().lock().write_all(content.as_bytes())?,
+    }
+    Ok(true)
+}
+
+async fn convert_books(app: AppHandle, files: Vec<PathBuf>, out: Option<PathBuf>) -> Result<bool> {
+    if let Some(out) = &out {
+        std::fs::create_dir_all(out)?;
+    }
+    let mut failed = false;
+    for file in files {
+        let converted = match convert::convert_book_to_epub(app.clone(), file.clone()).await {
+            Ok(converted) => converted,
+            Err(e) => {
+                failed = true;
+                eprintln!("{}: {e}", file.display());
+                continue;
+            }
+        };
+        let path = match &out {
+            Some(out) => {
+                let name = file.file_stem().unwrap_or_default().to_string_lossy();
+                let path = out.join(format!("{name}.epub"));
+                std::fs::copy(&converted.path, &path)?;
+                path
+            }
+            None => converted.path,
+        };
+        println!("{}", path.display());
+    }
+    Ok(!failed)
+}
+
+/// The fields of the frontend's per-book `config.json` that are synced.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StoredState {
+    progress: Option<(i64, i64)>,
+    location: Option<String>,
+    xpointer: Option<String>,
+    #[serde(default)]
+    booknotes: Vec<BookNote>,
+    #[serde(default)]
+    updated_at: i64,
+}
+
+/// A book config read from the books dir, and whether it was encrypted.
+struct StoredConfig {
+    path: PathBuf,
+    config: Value,
+    encrypted: bool,
+}
+
+/// The reading state of every book with a config in the books dir. The
+/// app hands its state to [`sync_book_data`] itself; here it is read from
+/// where the app keeps it.
+fn read_configs(app: &AppHandle) -> Result<Vec<(BookSyncData, StoredConfig)>> {
+    let entries = match std::fs::read_dir(library::books_dir(app)?) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut books = Vec::new();
+    for entry in entries.flatten() {
+        let book_hash = entry.file_name().to_string_lossy().into_owned();
+        let Ok(path) = storage::config_path(app, &book_hash) else {
+            continue;
+        };
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let encrypted = storage::is_encrypted(&data);
+        let parsed = storage::decrypt(data).and_then(|data| {
+            let config = serde_json::from_slice::<Value>(&data)?;
+            let state = serde_json::from_value::<StoredState>(config.clone())?;
+            Ok((config, state))
+        });
+        let (config, state) = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                log::warn!("Skipping config of {book_hash}: {e}");
+                continue;
+            }
+        };
+        if crate::secrets::notes::is_protected(&config) {
+            log::info!("Skipping config of {book_hash}: its notes are protected");
+            continue;
+        }
+        books.push((
+            BookSyncData {
+                book_hash,
+                progress: state.progress,
+                location: state.location,
+                xpointer: state.xpointer,
+                booknotes: state.booknotes,
+                updated_at: state.updated_at,
+            },
+            StoredConfig {
+                path,
+                config,
+                encrypted,
+            },
+        ));
+    }
+    Ok(books)
+}
+
+/// Writes the merged state of a book back into its config.
+fn write_config(stored: StoredConfig, data: &BookSyncData) -> Result<()> {
+    let StoredConfig {
+        path,
+        mut config,
+        encrypted,
+    } = stored;
+    let Some(object) = config.as_object_mut() else {
+        return Err(Error::InvalidBook(format!(
+            "invalid config of {}",
+            data.book_hash
+        )));
+    };
+    object.insert("progress".into(), json!(data.progress));
+    object.insert("location".into(), json!(data.location));
+    object.insert("xpointer".into(), json!(data.xpointer));
+    object.insert("booknotes".into(), serde_json::to_value(&data.booknotes)?);
+    object.insert("updatedAt".into(), data.updated_at.into());
+    storage::write_file(&path, serde_json::to_vec(&config)?, encrypted)
+}
+
+async fn sync_books(app: AppHandle) -> Result<bool> {
+    let handle = app.clone();
+    let stored = tauri::async_runtime::spawn_blocking(move || read_configs(&handle)).await??;
+    let books = stored.iter().map(|(book, _)| book.clone()).collect();
+    let mut configs = stored
+        .into_iter()
+        .map(|(book, config)| (book.book_hash.clone(), (book, config)))
+        .collect::<HashMap<_, _>>();
+    let results = sync_book_data(app, books).await?;
+
+    let mut synced = 0;
+    let mut updated = 0;
+    let mut failed = 0;
+    for SyncResult {
+        book_hash,
+        data,
+        error,
+    } in results
+    {
+        let Some(data) = data else {
+            failed += 1;
+            eprintln!("{book_hash}: {}", error.unwrap_or_default());
+            continue;
+        };
+        synced += 1;
+        let Some((local, config)) = configs.remove(&book_hash) else {
+            continue;
+        };
+        if data != local {
+            tauri::async_runtime::spawn_blocking(move || write_config(config, &data)).await??;
+            updated += 1;
+            println!("{book_hash}");
+        }
+    }
+    eprintln!("Synced {synced} books, {updated} updated locally, {failed} failed");
+    Ok(failed == 0)
+}
@@ -0,0 +1,150 @@
+//! Quick add of books copied to the clipboard. When the [`watcher`] finds
+//! one, the user is asked with a notification whether to add it, or with a
+//! `clipboard-offer` event while looking at the app and on macOS. Links
+//! are downloaded and files imported as an `import` job, after which the
+//! book is opened.
+
+pub mod watcher;
+
+use std::path::PathBuf;
+
+use tauri::{command, AppHandle, Emitter, Manager};
+use url::Url;
+
+use self::watcher::{ClipboardWatcher, Copied};
+use crate::deep_link::{self, DeepLink};
+use crate::error::{Error, Result};
+use crate::jobs;
+use crate::library::db;
+use crate::library::import;
+use crate::net::{
+    self,
+    downloads::{self, Dest, DownloadRequest},
+};
+use crate::paths;
+use crate::utils::unique_path;
+
+const OFFER_EVENT: &str = "clipboard-offer";
+/// Where linked books are downloaded under the app data dir before they
+/// are imported.
+const DOWNLOADS_DIR: &str = "clipboard";
+
+/// Asks with a notification of the system, which adds `copied` when
+/// clicked.
+#[cfg(any(windows, target_os = "linux"))]
+fn ask(app: &AppHandle, copied: &Copied) -> Result<()> {
+    let summary = crate::i18n::t(app, "Add Copied Book?");
+    let action = crate::i18n::t(app, "Add to Library");
+    #[cfg(windows)]
+    {
+        // Clicking starts a second instance with the link
+        let link = Url::parse_with_params(
+            &format!("{}://add", deep_link::SCHEME),
+            [("source", copied.source())],
+        )?;
+        crate::windows::toast::ask(app, &summary, copied.name(), &action, link.as_str())
+    }
+    #[cfg(target_os = "linux")]
+    {
+        let (handle, source) = (app.clone(), copied.source());
+        crate::linux::notifications::ask(app, &summary, copied.name(), &action, move || {
+            add_in_background(&handle, source)
+        })
+    }
+}
+
+/// Offers to add `copied`, just found on the clipboard.
+fn offer(app: &AppHandle, copied: Copied) {
+    #[cfg(any(windows, target_os = "linux"))]
+    {
+        let in_front = app
+            .get_webview_window("main")
+            .is_some_and(|window| window.is_focused().unwrap_or(false));
+        if !in_front {
+            match ask(app, &copied) {
+                Ok(()) => return,
+                Err(e) => log::warn!("Failed to show a notification: {e}"),
+            }
+        }
+    }
+    let _ = app.emit(OFFER_EVENT, copied);
+}
+
+/// Downloads the book at `url` as `name` through the download queue.
+async fn download(app: &AppHandle, url: &str, name: &str) -> Result<PathBuf> {
+    let dir = paths::data_dir(app)?.join(DOWNLOADS_DIR);
+    let url = Url::parse(url)?;
+    let request = DownloadRequest {
+        name: name.to_string(),
+        dest: Dest::File(unique_path(&dir, name)),
+        size: None,
+        checksum: None,
+    };
+    let fetch = downloads::fetch_with(move || net::client().get(url.clone()));
+    Ok(downloads::download(app, request, fetch, |_| {}).await?.path)
+}
+
+async fn add(app: &AppHandle, copied: Copied) -> Result<db::Book> {
+    jobs::run(app, "import", |job| async move {
+        let (path, downloaded) = match &copied {
+            Copied::Link { url, name } => {
+                job.phase("downloading", Some(name.clone()));
+                (job.cancellable(download(app, url, name)).await?, true)
+            }
+            Copied::File { path, .. } => (path.clone(), false),
+        };
+        job.phase("importing", Some(copied.name().to_string()));
+        let handle = app.clone();
+        tauri::async_runtime::spawn_blocking(move || {
+            // Copied files are the user's, downloads are the app's own
+            if downloaded {
+                import::import_and_discard(&handle, &path)
+            } else {
+                import::import_book(&handle, &path)
+            }
+        })
+        .await?
+    })
+    .await
+}
+
+/// What `source` is a link to or the path of, if the settings of the
+/// watcher let it be added.
+fn allowed(app: &AppHandle, source: &str) -> Result<Copied> {
+    let settings = app.state::<ClipboardWatcher>().settings();
+    watcher::detect(&settings, source)
+        .ok_or_else(|| Error::Clipboard(format!("{source} is not a book that can be added")))
+}
+
+/// Adds `source` without waiting, for `vlarch://add` links and answered
+/// notifications. The book is opened once added.
+pub fn add_in_background(app: &AppHandle, source: String) {
+    let copied = match allowed(app, &source) {
+        Ok(copied) => copied,
+        Err(e) => {
+            log::warn!("Ignoring copied book: {e}");
+            return;
+        }
+    };
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        match add(&app, copied).await {
+            Ok(book) => deep_link::dispatch(
+                &app,
+                vec![DeepLink::Open {
+                    book: book.hash,
+                    cfi: None,
+                }],
+            ),
+            Err(e) => log::warn!("Failed to add {source}: {e}"),
+        }
+    });
+}
+
+/// Adds `source`, the `url` or `path` of a `clipboard-offer` event, to the
+/// library.
+#[command]
+pub async fn add_copied_book(app: AppHandle, source: String) -> Result<db::Book> {
+    let copied = allowed(&app, &source)?;
+    add(&app, copied).await
+}
@@ -0,0 +1,259 @@
+//! The clipboard, checked every second while the user has the watcher on,
+//! for a link to a book file or the path of one, as copied from a browser
+//! or a file manager. Each kind of source has an allowlist, of hosts for
+//! links and of folders for files, so only those the user trusts are
+//! offered; what was on the clipboard when the watcher started is not.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle, Manager, State};
+use url::Url;
+
+use crate::error::Result;
+use crate::formats::is_book_file;
+use crate::store;
+use crate::utils::sanitize_file_name;
+
+const SETTINGS_FILE: &str = "clipboard.json";
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Longer text is not a link or a path someone copied to add.
+const MAX_TEXT_LEN: usize = 4096;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Source {
+    pub enabled: bool,
+    /// Hosts, with their subdomains, or folders, with their subfolders,
+    /// that are offered; empty for all of them.
+    pub allow: Vec<String>,
+}
+
+impl Default for Source {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            allow: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct WatcherSettings {
+    /// Off until the user turns it on.
+    pub enabled: bool,
+    /// `http(s)` links to book files.
+    pub links: Source,
+    /// Paths and `file://` links of book files on this computer.
+    pub files: Source,
+}
+
+/// A book found on the clipboard.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum Copied {
+    Link { url: String, name: String },
+    File { path: PathBuf, name: String },
+}
+
+impl Copied {
+    /// What was copied, as [`detect`] takes it back.
+    pub fn source(&self) -> String {
+        match self {
+            Copied::Link { url, .. } => url.clone(),
+            Copied::File { path, .. } => path.to_string_lossy().into_owned(),
+        }
+    }
+
+    /// The name of the file, to show the user.
+    pub fn name(&self) -> &str {
+        match self {
+            Copied::Link { name, .. } | Copied::File { name, .. } => name,
+        }
+    }
+}
+
+fn host_allowed(source: &Source, host: &str) -> bool {
+    let host = host.to_ascii_lowercase();
+    source.allow.is_empty()
+        || source.allow.iter().any(|allowed| {
+            let allowed = allowed.trim().trim_start_matches("*.").to_ascii_lowercase();
+            !allowed.is_empty()
+                && (host == allowed
+                    || host
+                        .strip_suffix(allowed.as_str())
+                        .is_some_and(|sub| sub.ends_with('.')))
+        })
+}
+
+fn folder_allowed(source: &Source, path: &Path) -> bool {
+    source.allow.is_empty()
+        || source.allow.iter().any(|folder| {
+            // Both canonical, so links and `..` cannot get around it
+            std::fs::canonicalize(folder.trim()).is_ok_and(|folder| path.starts_with(folder))
+        })
+}
+
+fn detect_link(source: &Source, url: Url) -> Option<Copied> {
+    if !host_allowed(source, url.host_str()?) {
+        return None;
+    }
+    let segment = url.path_segments()?.next_back()?;
+    let name = percent_encoding::percent_decode_str(segment).decode_utf8_lossy();
+    let name = sanitize_file_name(&name);
+    if !is_book_file(Path::new(&name)) {
+        return None;
+    }
+    Some(Copied::Link {
+        url: url.to_string(),
+        name,
+    })
+}
+
+fn detect_file(source: &Source, path: &Path) -> Option<Copied> {
+    if !path.is_absolute() || !is_book_file(path) || !path.is_file() {
+        return None;
+    }
+    if !folder_allowed(source, &std::fs::canonicalize(path).ok()?) {
+        return None;
+    }
+    let name = path.file_name()?.to_string_lossy().into_owned();
+    Some(Copied::File {
+        path: path.to_path_buf(),
+        name,
+    })
+}
+
+/// The book `text`, copied to the clipboard, is a link to or the path of,
+/// when `settings` let it be offered.
+pub fn detect(settings: &WatcherSettings, text: &str) -> Option<Copied> {
+    // Windows' "Copy as path" quotes it
+    let text = text.trim().trim_matches('"');
+    if text.is_empty() || text.len() > MAX_TEXT_LEN || text.contains('\n') {
+        return None;
+    }
+    match Url::parse(text) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => {
+            detect_link(&settings.links, url).filter(|_| settings.links.enabled)
+        }
+        Ok(url) if url.scheme() == "file" => {
+            detect_file(&settings.files, &url.to_file_path().ok()?)
+                .filter(|_| settings.files.enabled)
+        }
+        // Drive letters parse as schemes
+        _ => detect_file(&settings.files, Path::new(text)).filter(|_| settings.files.enabled),
+    }
+}
+
+/// Text of the clipboard, the first book of the files copied in a file
+/// manager before anything else.
+fn read(clipboard: &mut arboard::Clipboard) -> Option<String> {
+    let files = clipboard.get().file_list().unwrap_or_default();
+    match files.into_iter().find(|file| is_book_file(file)) {
+        Some(file) => Some(file.to_string_lossy().into_owned()),
+        None => clipboard.get_text().ok(),
+    }
+}
+
+fn watch(app: AppHandle, stop: Arc<AtomicBool>) {
+    let mut clipboard = match arboard::Clipboard::new() {
+        Ok(clipboard) => clipboard,
+        Err(e) => {
+            log::warn!("Failed to open the clipboard: {e}");
+            return;
+        }
+    };
+    let mut last = read(&mut clipboard);
+    while !stop.load(Ordering::Relaxed) {
+        std::thread::sleep(POLL_INTERVAL);
+        let text = read(&mut clipboard);
+        if text.is_none() || text == last {
+            continue;
+        }
+        last = text;
+        let settings = app.state::<ClipboardWatcher>().settings();
+        if let Some(copied) = last.as_deref().and_then(|text| detect(&settings, text)) {
+            super::offer(&app, copied);
+        }
+    }
+}
+
+pub struct ClipboardWatcher {
+    settings: Mutex<WatcherSettings>,
+    /// Stops the running watcher when set.
+    running: Mutex<Option<Arc<AtomicBool>>>,
+}
+
+impl ClipboardWatcher {
+    pub fn settings(&self) -> WatcherSettings {
+        self.settings.lock().unwrap().clone()
+    }
+}
+
+fn start(app: &AppHandle) -> Result<()> {
+    let state = app.state::<ClipboardWatcher>();
+    let mut running = state.running.lock().unwrap();
+    if running.is_some() {
+        return Ok(());
+    }
+    let stop = Arc::new(AtomicBool::new(false));
+    let (handle, stopping) = (app.clone(), stop.clone());
+    std::thread::Builder::new()
+        .name("clipboard".to_string())
+        .spawn(move || watch(handle, stopping))?;
+    *running = Some(stop);
+    Ok(())
+}
+
+fn stop(app: &AppHandle) {
+    if let Some(stop) = app
+        .state::<ClipboardWatcher>()
+        .running
+        .lock()
+        .unwrap()
+        .take()
+    {
+        stop.store(true, Ordering::Relaxed);
+    }
+}
+
+pub fn init(app: &AppHandle) {
+    let settings: WatcherSettings = store::load(app, SETTINGS_FILE);
+    let enabled = settings.enabled;
+    app.manage(ClipboardWatcher {
+        settings: Mutex::new(settings),
+        running: Mutex::new(None),
+    });
+    if enabled {
+        if let Err(e) = start(app) {
+            log::warn!("Failed to start the clipboard watcher: {e}");
+        }
+    }
+}
+
+#[command]
+pub fn get_clipboard_watcher(watcher: State<'_, ClipboardWatcher>) -> WatcherSettings {
+    watcher.settings()
+}
+
+/// Saves `settings`, starting or stopping the watcher to match.
+#[command]
+pub fn set_clipboard_watcher(app: AppHandle, settings: WatcherSettings) -> Result<()> {
+    store::save(&app, SETTINGS_FILE, &settings)?;
+    let enabled = settings.enabled;
+    *app.state::<ClipboardWatcher>().settings.lock().unwrap() = settings;
+    if enabled {
+        start(&app)
+    } else {
+        stop(&app);
+        Ok(())
+    }
+}
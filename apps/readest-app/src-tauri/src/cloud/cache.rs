@@ -0,0 +1,167 @@
+//! The library books whose files are in a cloud account, and the local
+//! copies of the ones that were opened. Copies live in the app cache dir,
+//! and once they take more than the limit the least recently opened ones
+//! are deleted; they are downloaded again when next opened.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Manager};
+
+use crate::error::Result;
+use crate::paths;
+use crate::store;
+use crate::utils::now_millis;
+
+const BOOKS_FILE: &str = "cloud-books.json";
+const SETTINGS_FILE: &str = "cloud-cache.json";
+const CACHE_SUBDIR: &str = "cloud-books";
+const DEFAULT_LIMIT: u64 = 2 * 1024 * 1024 * 1024;
+
+/// Where the file of a library book is in a cloud account.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoteBook {
+    pub account: String,
+    /// Id of the file with the provider.
+    pub id: String,
+    pub name: String,
+    pub size: u64,
+    /// When the local copy was last opened, if there is one.
+    pub opened_at: Option<i64>,
+}
+
+impl RemoteBook {
+    fn file_name(&self, hash: &str) -> String {
+        let extension = std::path::Path::new(&self.name)
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+        format!("{hash}.{extension}")
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CacheSettings {
+    /// Bytes the local copies may take.
+    pub limit: u64,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        Self {
+            limit: DEFAULT_LIMIT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheUsage {
+    pub limit: u64,
+    pub used: u64,
+    /// Books with a local copy.
+    pub books: usize,
+}
+
+struct Inner {
+    books: BTreeMap<String, RemoteBook>,
+    settings: CacheSettings,
+    dir: PathBuf,
+}
+
+pub struct CloudCache(Mutex<Inner>);
+
+impl CloudCache {
+    pub fn remote(&self, hash: &str) -> Option<RemoteBook> {
+        self.0.lock().unwrap().books.get(hash).cloned()
+    }
+
+    /// Where the local copy of book `hash` goes, if it is a cloud book.
+    pub fn download_path(&self, hash: &str) -> Option<PathBuf> {
+        let inner = self.0.lock().unwrap();
+        let book = inner.books.get(hash)?;
+        Some(inner.dir.join(book.file_name(hash)))
+    }
+
+    /// The local copy of book `hash`, if it was downloaded.
+    pub fn path(&self, hash: &str) -> Option<PathBuf> {
+        self.download_path(hash).filter(|path| path.is_file())
+    }
+
+    pub fn add(&self, app: &AppHandle, hash: &str, book: RemoteBook) -> Result<()> {
+        let mut inner = self.0.lock().unwrap();
+        inner.books.insert(hash.to_string(), book);
+        store::save(app, BOOKS_FILE, &inner.books)
+    }
+
+    /// Records that book `hash` was opened, so its copy is kept the longest.
+    pub fn touch(&self, app: &AppHandle, hash: &str) -> Result<()> {
+        let mut inner = self.0.lock().unwrap();
+        if let Some(book) = inner.books.get_mut(hash) {
+            book.opened_at = Some(now_millis());
+        }
+        store::save(app, BOOKS_FILE, &inner.books)
+    }
+
+    /// Deletes the least recently opened copies until they fit the limit,
+    /// except the copy of `keep` that is being opened.
+    pub fn evict(&self, keep: Option<&str>) -> Result<()> {
+        let inner = self.0.lock().unwrap();
+        let mut copies = inner
+            .books
+            .iter()
+            .filter_map(|(hash, book)| {
+                let path = inner.dir.join(book.file_name(hash));
+                let size = std::fs::metadata(&path).ok()?.len();
+                Some((book.opened_at.unwrap_or_default(), hash, path, size))
+            })
+            .collect::<Vec<_>>();
+        copies.sort_by_key(|(opened_at, ..)| *opened_at);
+        let mut used = copies.iter().map(|(.., size)| size).sum::<u64>();
+        for (_, hash, path, size) in copies {
+            if used <= inner.settings.limit {
+                break;
+            }
+            if Some(hash.as_str()) == keep {
+                continue;
+            }
+            std::fs::remove_file(&path)?;
+            used -= size;
+        }
+        Ok(())
+    }
+
+    pub fn usage(&self) -> CacheUsage {
+        let inner = self.0.lock().unwrap();
+        let sizes = inner
+            .books
+            .iter()
+            .filter_map(|(hash, book)| std::fs::metadata(inner.dir.join(book.file_name(hash))).ok())
+            .map(|metadata| metadata.len())
+            .collect::<Vec<_>>();
+        CacheUsage {
+            limit: inner.settings.limit,
+            used: sizes.iter().sum(),
+            books: sizes.len(),
+        }
+    }
+
+    pub fn set_limit(&self, app: &AppHandle, limit: u64) -> Result<()> {
+        let mut inner = self.0.lock().unwrap();
+        inner.settings.limit = limit;
+        store::save(app, SETTINGS_FILE, &inner.settings)
+    }
+}
+
+pub fn init(app: &AppHandle) -> Result<()> {
+    app.manage(CloudCache(Mutex::new(Inner {
+        books: store::load(app, BOOKS_FILE),
+        settings: store::load(app, SETTINGS_FILE),
+        dir: paths::cache_dir(app)?.join(CACHE_SUBDIR),
+    })));
+    Ok(())
+}
@@ -0,0 +1,119 @@
+//! Dropbox, through the v2 HTTP API. Files are addressed by their Dropbox
+//! id, which stays the same when they are moved or renamed.
+
+use std::ops::Range;
+
+use reqwest::header::RANGE;
+use reqwest::Response;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::{oauth, range_header, RemoteEntry, StorageProvider};
+use crate::error::{Error, Result};
+use crate::net;
+
+const API_URL: &str = "https://api.dropboxapi.com/2/";
+const CONTENT_URL: &str = "https://content.dropboxapi.com/2/";
+const TOKEN_URL: &str = "https://api.dropboxapi.com/oauth2/token";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DropboxConfig {
+    /// App key the user authorized, with PKCE so there is no secret.
+    pub client_id: String,
+    pub refresh_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ListResponse {
+    entries: Vec<Entry>,
+    cursor: String,
+    has_more: bool,
+}
+
+#[derive(Deserialize)]
+struct Entry {
+    #[serde(rename = ".tag")]
+    tag: String,
+    id: Option<String>,
+    name: String,
+    size: Option<u64>,
+}
+
+pub struct DropboxProvider {
+    access_token: String,
+}
+
+impl DropboxProvider {
+    pub async fn new(config: &DropboxConfig) -> Result<Self> {
+        let access_token = oauth::access_token(
+            TOKEN_URL,
+            &config.client_id,
+            None,
+            config.refresh_token.as_deref(),
+        )
+        .await?;
+        Ok(Self { access_token })
+    }
+
+    async fn call(&self, endpoint: &str, body: serde_json::Value) -> Result<ListResponse> {
+        let response = net::client()
+            .post(format!("{API_URL}{endpoint}"))
+            .bearer_auth(&self.access_token)
+            .json(&body)
+            .send()
+            .await?;
+        Ok(super::check_status(response)?.json().await?)
+    }
+}
+
+impl StorageProvider for DropboxProvider {
+    async fn list(&self, folder: Option<&str>) -> Result<Vec<RemoteEntry>> {
+        // The root is the empty path
+        let mut page = self
+            .call(
+                "files/list_folder",
+                json!({ "path": folder.unwrap_or_default() }),
+            )
+            .await?;
+        let mut entries = Vec::new();
+        loop {
+            for entry in page.entries {
+                let Some(id) = entry.id else { continue };
+                entries.push(RemoteEntry {
+                    id,
+                    name: entry.name,
+                    folder: entry.tag == "folder",
+                    size: entry.size,
+                });
+            }
+            if !page.has_more {
+                return Ok(entries);
+            }
+            page = self
+                .call(
+                    "files/list_folder/continue",
+                    json!({ "cursor": page.cursor }),
+                )
+                .await?;
+        }
+    }
+
+    async fn get(&self, id: &str, range: Option<Range<u64>>) -> Result<Response> {
+        let arg = serde_json::to_string(&json!({ "path": id }))?;
+        let request = net::client()
+            .post(format!("{CONTENT_URL}files/download"))
+            .bearer_auth(&self.access_token)
+            .header("Dropbox-API-Arg", arg);
+        let request = match range {
+            Some(range) => request.header(RANGE, range_header(&range)),
+            None => request,
+        };
+        let response = request.send().await?;
+        if response.status() == reqwest::StatusCode::CONFLICT {
+            // Dropbox answers 409 for paths that do not exist
+            return Err(Error::Cloud("the file is no longer in Dropbox".into()));
+        }
+        super::check_status(response)
+    }
+}
@@ -0,0 +1,440 @@
+//! Book files kept in a cloud account: S3-compatible object storage,
+//! Dropbox or Google Drive. Remote files are added to the library without
+//! downloading them, hashed from ranged reads of the few samples the book
+//! hash needs, and each is downloaded into the cache when first opened.
+
+use std::future::Future;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use md5::{Digest, Md5};
+use reqwest::{Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle, Manager, State};
+
+use crate::error::{Error, Result};
+use crate::jobs;
+use crate::library::{self, db};
+use crate::net::downloads::{self, Checksum, Dest, DownloadRequest, Fetch};
+use crate::secrets;
+use crate::store;
+use crate::utils::now_millis;
+
+pub mod cache;
+mod dropbox;
+mod gdrive;
+mod oauth;
+mod s3;
+
+use cache::{CacheUsage, CloudCache, RemoteBook};
+use dropbox::{DropboxConfig, DropboxProvider};
+use gdrive::{GoogleDriveConfig, GoogleDriveProvider};
+use s3::{S3Config, S3Provider};
+
+const ACCOUNTS_FILE: &str = "cloud-accounts.json";
+/// Prefix of the keychain entries of account secrets, followed by the
+/// account id and the field.
+#[cfg(desktop)]
+const SECRET_PREFIX: &str = "cloud.";
+#[cfg(desktop)]
+const SECRET_NAMES: [&str; 3] = ["secretAccessKey", "refreshToken", "clientSecret"];
+
+/// A file or folder in a cloud account.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoteEntry {
+    /// What the provider addresses the entry by: a key, path or file id.
+    pub id: String,
+    pub name: String,
+    pub folder: bool,
+    pub size: Option<u64>,
+}
+
+/// Remote storage of book files.
+pub trait StorageProvider {
+    /// The files and folders in `folder`, or at the top when `None`.
+    fn list(&self, folder: Option<&str>) -> impl Future<Output = Result<Vec<RemoteEntry>>> + Send;
+
+    /// Requests the content of file `id`, only the bytes in `range` when
+    /// given. Providers may ignore the range and send the whole file.
+    fn get(
+        &self,
+        id: &str,
+        range: Option<Range<u64>>,
+    ) -> impl Future<Output = Result<Response>> + Send;
+}
+
+fn range_header(range: &Range<u64>) -> String {
+    format!("bytes={}-{}", range.start, range.end.saturating_sub(1))
+}
+
+fn check_status(response: Response) -> Result<Response> {
+    match response.status() {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+            Err(Error::Cloud("the account has no access".into()))
+        }
+        status if !status.is_success() => Err(Error::HttpStatus(status.as_u16())),
+        _ => Ok(response),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum CloudProviderConfig {
+    S3(S3Config),
+    Dropbox(DropboxConfig),
+    GoogleDrive(GoogleDriveConfig),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CloudAccount {
+    pub id: String,
+    /// Name the user gave the account.
+    pub name: String,
+    #[serde(flatten)]
+    pub provider: CloudProviderConfig,
+}
+
+impl CloudAccount {
+    /// Access keys and tokens, by name.
+    #[cfg(desktop)]
+    fn secrets_mut(&mut self) -> Vec<(&'static str, &mut Option<String>)> {
+        match &mut self.provider {
+            CloudProviderConfig::S3(config) => {
+                vec![("secretAccessKey", &mut config.secret_access_key)]
+            }
+            CloudProviderConfig::Dropbox(config) => {
+                vec![("refreshToken", &mut config.refresh_token)]
+            }
+            CloudProviderConfig::GoogleDrive(config) => vec![
+                ("refreshToken", &mut config.refresh_token),
+                ("clientSecret", &mut config.client_secret),
+            ],
+        }
+    }
+}
+
+#[cfg(desktop)]
+fn secret_key(account: &str, name: &str) -> String {
+    format!("{SECRET_PREFIX}{account}.{name}")
+}
+
+/// Saves the accounts, moving their secrets to the keychain, and removes
+/// the secrets of accounts that are gone.
+#[cfg(desktop)]
+fn save_accounts(app: &AppHandle, mut accounts: Vec<CloudAccount>) -> Result<()> {
+    let previous: Vec<CloudAccount> = store::load(app, ACCOUNTS_FILE);
+    for account in &previous {
+        if !accounts.iter().any(|a| a.id == account.id) {
+            for name in SECRET_NAMES {
+                secrets::store(&secret_key(&account.id, name), None)?;
+            }
+        }
+    }
+    for account in &mut accounts {
+        let id = account.id.clone();
+        for (name, value) in account.secrets_mut() {
+            secrets::store(&secret_key(&id, name), value.take().as_deref())?;
+        }
+    }
+    store::save(app, ACCOUNTS_FILE, &accounts)
+}
+
+#[cfg(desktop)]
+fn load_accounts(app: &AppHandle) -> Result<Vec<CloudAccount>> {
+    let mut accounts: Vec<CloudAccount> = store::load(app, ACCOUNTS_FILE);
+    for account in &mut accounts {
+        let id = account.id.clone();
+        for (name, value) in account.secrets_mut() {
+            *value = secrets::get(&secret_key(&id, name))?;
+        }
+    }
+    Ok(accounts)
+}
+
+#[cfg(not(desktop))]
+fn save_accounts(app: &AppHandle, accounts: Vec<CloudAccount>) -> Result<()> {
+    store::save(app, ACCOUNTS_FILE, &accounts)
+}
+
+#[cfg(not(desktop))]
+fn load_accounts(app: &AppHandle) -> Result<Vec<CloudAccount>> {
+    Ok(store::load(app, ACCOUNTS_FILE))
+}
+
+async fn load_account(app: &AppHandle, id: &str) -> Result<CloudAccount> {
+    let app = app.clone();
+    let accounts = secrets::blocking(move || load_accounts(&app)).await?;
+    accounts
+        .into_iter()
+        .find(|account| account.id == id)
+        .ok_or_else(|| Error::Cloud(format!("no account {id}")))
+}
+
+enum Provider {
+    S3(S3Provider),
+    Dropbox(DropboxProvider),
+    GoogleDrive(GoogleDriveProvider),
+}
+
+impl Provider {
+    /// Connects to the provider, signing in with the refresh token for the
+    /// ones using OAuth.
+    async fn new(config: &CloudProviderConfig) -> Result<Self> {
+        Ok(match config {
+            CloudProviderConfig::S3(config) => Self::S3(S3Provider::new(config)?),
+            CloudProviderConfig::Dropbox(config) => {
+                Self::Dropbox(DropboxProvider::new(config).await?)
+            }
+            CloudProviderConfig::GoogleDrive(config) => {
+                Self::GoogleDrive(GoogleDriveProvider::new(config).await?)
+            }
+        })
+    }
+
+    async fn list(&self, folder: Option<&str>) -> Result<Vec<RemoteEntry>> {
+        match self {
+            Self::S3(provider) => provider.list(folder).await,
+            Self::Dropbox(provider) => provider.list(folder).await,
+            Self::GoogleDrive(provider) => provider.list(folder).await,
+        }
+    }
+
+    async fn get(&self, id: &str, range: Option<Range<u64>>) -> Result<Response> {
+        match self {
+            Self::S3(provider) => provider.get(id, range).await,
+            Self::Dropbox(provider) => provider.get(id, range).await,
+            Self::GoogleDrive(provider) => provider.get(id, range).await,
+        }
+    }
+
+    /// The bytes of `range` of file `id`, read from the start of the body
+    /// when the provider sends the whole file.
+    async fn read_range(&self, id: &str, range: Range<u64>) -> Result<Vec<u8>> {
+        let mut response = self.get(id, Some(range.clone())).await?;
+        let skip = match response.status() {
+            StatusCode::PARTIAL_CONTENT => 0,
+            _ => range.start as usize,
+        };
+        let end = skip + (range.end - range.start) as usize;
+        let mut body = Vec::new();
+        while body.len() < end {
+            match response.chunk().await? {
+                Some(chunk) => body.extend_from_slice(&chunk),
+                None => break,
+            }
+        }
+        body.truncate(end);
+        Ok(body.split_off(skip.min(body.len())))
+    }
+
+    /// The book hash of file `id` of `size` bytes, as
+    /// [`library::partial_md5`] computes it.
+    async fn partial_md5(&self, id: &str, size: u64) -> Result<String> {
+        let mut hasher = Md5::new();
+        for range in library::partial_md5_samples(size) {
+            hasher.update(self.read_range(id, range).await?);
+        }
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+}
+
+/// Adds the remote file `entry` to the library as a book that is not
+/// downloaded yet. Returns the library book with the same hash instead if
+/// there is one.
+async fn add_book(
+    app: &AppHandle,
+    account: &str,
+    provider: &Provider,
+    entry: RemoteEntry,
+) -> Result<db::Book> {
+    let size = entry
+        .size
+        .ok_or_else(|| Error::Cloud(format!("the size of {} is unknown", entry.name)))?;
+    let hash = provider.partial_md5(&entry.id, size).await?;
+    let existing = db::get_book(&app.state::<db::LibraryDb>().conn(), &hash)?;
+    if let Some(existing) = existing.filter(|book| book.deleted_at.is_none()) {
+        return Ok(existing);
+    }
+
+    let path = Path::new(&entry.name);
+    let title = path.file_stem().map_or_else(
+        || entry.name.clone(),
+        |stem| stem.to_string_lossy().into_owned(),
+    );
+    let now = now_millis();
+    // The metadata is read by the frontend once the file is downloaded
+    let book = db::Book {
+        hash: hash.clone(),
+        format: crate::formats::extension(path).to_ascii_uppercase(),
+        title: title.clone(),
+        source_title: Some(title),
+        author: String::new(),
+        group_id: None,
+        group_name: None,
+        tags: Vec::new(),
+        cover_image_url: None,
+        file_path: None,
+        url: None,
+        primary_language: None,
+        progress: None,
+        metadata: None,
+        created_at: now,
+        updated_at: now,
+        deleted_at: None,
+        uploaded_at: None,
+        downloaded_at: None,
+        cover_downloaded_at: None,
+    };
+    app.state::<CloudCache>().add(
+        app,
+        &hash,
+        RemoteBook {
+            account: account.to_string(),
+            id: entry.id,
+            name: entry.name,
+            size,
+            opened_at: None,
+        },
+    )?;
+    db::upsert_books(
+        &mut app.state::<db::LibraryDb>().conn(),
+        std::slice::from_ref(&book),
+    )?;
+    #[cfg(any(target_os = "macos", windows))]
+    crate::system_search::update(app, std::slice::from_ref(&book));
+    Ok(book)
+}
+
+/// The local copy of cloud book `hash`, downloaded as a `download` job if
+/// there is none yet, or `None` when `hash` is not a cloud book.
+pub async fn fetch(app: &AppHandle, hash: &str) -> Result<Option<PathBuf>> {
+    let cache = app.state::<CloudCache>();
+    let (Some(remote), Some(dest)) = (cache.remote(hash), cache.download_path(hash)) else {
+        return Ok(None);
+    };
+    if !dest.is_file() {
+        let account = load_account(app, &remote.account).await?;
+        jobs::run(app, "download", |job| async move {
+            job.phase("connecting", Some(remote.name.clone()));
+            let provider = Arc::new(job.cancellable(Provider::new(&account.provider)).await?);
+            let id = remote.id.clone();
+            let fetch: Fetch = Arc::new(move |range| {
+                let (provider, id) = (provider.clone(), id.clone());
+                Box::pin(async move { provider.get(&id, range).await })
+            });
+            let request = DownloadRequest {
+                name: remote.name.clone(),
+                dest: Dest::File(dest),
+                size: Some(remote.size),
+                checksum: Some(Checksum::PartialMd5(hash.to_string())),
+            };
+            let download = downloads::download(app, request, fetch, |progress| {
+                let total = progress.total.unwrap_or(remote.size);
+                job.progress(
+                    "downloading",
+                    progress.received as usize,
+                    total as usize,
+                    None,
+                );
+            });
+            job.cancellable(download).await?;
+            Ok(())
+        })
+        .await?;
+        let state = app.state::<db::LibraryDb>();
+        let mut conn = state.conn();
+        if let Some(mut book) = db::get_book(&conn, hash)? {
+            book.downloaded_at = Some(now_millis());
+            db::upsert_books(&mut conn, std::slice::from_ref(&book))?;
+        }
+    }
+    cache.touch(app, hash)?;
+    cache.evict(Some(hash))?;
+    Ok(cache.path(hash))
+}
+
+#[command]
+pub async fn get_cloud_accounts(app: AppHandle) -> Result<Vec<CloudAccount>> {
+    secrets::blocking(move || load_accounts(&app)).await
+}
+
+#[command]
+pub async fn set_cloud_accounts(app: AppHandle, accounts: Vec<CloudAccount>) -> Result<()> {
+    secrets::blocking(move || save_accounts(&app, accounts)).await
+}
+
+/// Verifies that `account` can be listed before the user saves it.
+#[command]
+pub async fn test_cloud_account(account: CloudAccount) -> Result<()> {
+    Provider::new(&account.provider).await?.list(None).await?;
+    Ok(())
+}
+
+/// The files and folders in `folder` of account `account`, or at the top.
+#[command]
+pub async fn list_cloud_folder(
+    app: AppHandle,
+    account: String,
+    folder: Option<String>,
+) -> Result<Vec<RemoteEntry>> {
+    let account = load_account(&app, &account).await?;
+    let provider = Provider::new(&account.provider).await?;
+    provider.list(folder.as_deref()).await
+}
+
+/// Adds the remote `files` of account `account` to the library as an
+/// `import` job. Cancelling keeps the books added so far.
+#[command]
+pub async fn add_cloud_books(
+    app: AppHandle,
+    account: String,
+    files: Vec<RemoteEntry>,
+) -> Result<Vec<db::Book>> {
+    let config = load_account(&app, &account).await?;
+    let handle = app.clone();
+    jobs::run(&handle, "import", |job| async move {
+        job.phase("connecting", None);
+        let provider = job.cancellable(Provider::new(&config.provider)).await?;
+        let files = files
+            .into_iter()
+            .filter(|file| !file.folder)
+            .collect::<Vec<_>>();
+        let total = files.len();
+        let mut books = Vec::with_capacity(total);
+        for (i, file) in files.into_iter().enumerate() {
+            let name = file.name.clone();
+            job.progress("importing", i, total, Some(name.clone()));
+            match job
+                .cancellable(add_book(&app, &account, &provider, file))
+                .await
+            {
+                Ok(book) => books.push(book),
+                Err(Error::Cancelled) => break,
+                Err(e) => log::warn!("Failed to add {name} from the cloud: {e}"),
+            }
+        }
+        Ok(books)
+    })
+    .await
+}
+
+#[command]
+pub fn get_cloud_cache(cache: State<'_, CloudCache>) -> CacheUsage {
+    cache.usage()
+}
+
+/// Limits the local copies of cloud books to `limit` bytes, deleting the
+/// least recently opened ones that no longer fit.
+#[command]
+pub async fn set_cloud_cache_limit(app: AppHandle, limit: u64) -> Result<CacheUsage> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let cache = app.state::<CloudCache>();
+        cache.set_limit(&app, limit)?;
+        cache.evict(None)?;
+        Ok(cache.usage())
+    })
+    .await?
+}
@@ -0,0 +1,43 @@
+//! OAuth 2.0 access tokens of Dropbox and Google Drive. The frontend runs
+//! the authorization flow and hands over the refresh token, which is
+//! exchanged for a short-lived access token whenever a provider connects.
+
+use serde::Deserialize;
+
+use crate::error::{Error, Result};
+use crate::net;
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+/// Exchanges `refresh_token` at `token_url` for an access token.
+/// `client_secret` is only needed by providers that issue one to desktop
+/// apps, like Google.
+pub async fn access_token(
+    token_url: &str,
+    client_id: &str,
+    client_secret: Option<&str>,
+    refresh_token: Option<&str>,
+) -> Result<String> {
+    let refresh_token = refresh_token
+        .filter(|token| !token.is_empty())
+        .ok_or_else(|| Error::Cloud("the account is not signed in".into()))?;
+    let mut form = vec![
+        ("grant_type", "refresh_token"),
+        ("refresh_token", refresh_token),
+        ("client_id", client_id),
+    ];
+    if let Some(secret) = client_secret.filter(|secret| !secret.is_empty()) {
+        form.push(("client_secret", secret));
+    }
+    let response = net::client().post(token_url).form(&form).send().await?;
+    if !response.status().is_success() {
+        return Err(Error::Cloud(format!(
+            "the sign-in expired ({})",
+            response.status().as_u16()
+        )));
+    }
+    Ok(response.json::<TokenResponse>().await?.access_token)
+}
@@ -0,0 +1,221 @@
+//! S3-compatible object storage: AWS S3, Cloudflare R2, Backblaze B2,
+//! MinIO, Wasabi and the like. Requests are signed with AWS Signature
+//! Version 4. Folders are key prefixes ending in `/`, and files are
+//! addressed by their key.
+
+use std::ops::Range;
+
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::header::RANGE;
+use reqwest::{Method, Response};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use super::{range_header, RemoteEntry, StorageProvider};
+use crate::error::{Error, Result};
+use crate::formats::epub::parse_xml;
+use crate::net;
+use crate::utils::{format_rfc3339, now_millis};
+
+const DEFAULT_REGION: &str = "us-east-1";
+/// SHA-256 of an empty body, which every request here has.
+const EMPTY_HASH: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+/// Everything but the unreserved characters of RFC 3986 is encoded.
+const ENCODE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct S3Config {
+    /// Such as `https://s3.eu-west-1.amazonaws.com` or
+    /// `https://<account>.r2.cloudflarestorage.com`.
+    pub endpoint: String,
+    /// `us-east-1` when unset; R2 uses `auto`.
+    pub region: Option<String>,
+    pub bucket: String,
+    /// Folder in the bucket listings start at.
+    pub prefix: Option<String>,
+    pub access_key_id: String,
+    pub secret_access_key: Option<String>,
+    /// Puts the bucket in the host name rather than the path, which some
+    /// providers require.
+    #[serde(default)]
+    pub virtual_hosted: bool,
+}
+
+pub struct S3Provider {
+    base: Url,
+    region: String,
+    prefix: String,
+    access_key_id: String,
+    secret_access_key: String,
+}
+
+fn encode(text: &str) -> String {
+    utf8_percent_encode(text, ENCODE).to_string()
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// The text of the first child of `node` named `name`.
+fn child_text<'a>(node: roxmltree::Node<'a, '_>, name: &str) -> Option<&'a str> {
+    node.children()
+        .find(|child| child.tag_name().name() == name)
+        .and_then(|child| child.text())
+}
+
+impl S3Provider {
+    pub fn new(config: &S3Config) -> Result<Self> {
+        let mut base = Url::parse(config.endpoint.trim())?;
+        if config.virtual_hosted {
+            let host = base
+                .host_str()
+                .ok_or_else(|| Error::Cloud("the endpoint has no host".into()))?;
+            let host = format!("{}.{host}", config.bucket);
+            base.set_host(Some(&host))?;
+            base.set_path("/");
+        } else {
+            base.set_path(&format!("/{}/", encode(&config.bucket)));
+        }
+        let prefix = config
+            .prefix
+            .as_deref()
+            .map(|prefix| prefix.trim_matches('/'))
+            .filter(|prefix| !prefix.is_empty())
+            .map(|prefix| format!("{prefix}/"))
+            .unwrap_or_default();
+        Ok(Self {
+            base,
+            region: config
+                .region
+                .clone()
+                .filter(|region| !region.is_empty())
+                .unwrap_or_else(|| DEFAULT_REGION.to_string()),
+            prefix,
+            access_key_id: config.access_key_id.clone(),
+            secret_access_key: config
+                .secret_access_key
+                .clone()
+                .ok_or_else(|| Error::Cloud("no secret access key is set".into()))?,
+        })
+    }
+
+    /// A signed request for the object `key`, or the bucket when empty,
+    /// with the query `params`.
+    fn request(&self, key: &str, params: &[(&str, &str)]) -> Result<reqwest::RequestBuilder> {
+        let path = format!(
+            "{}{}",
+            self.base.path(),
+            key.split('/').map(encode).collect::<Vec<_>>().join("/")
+        );
+        let mut params = params
+            .iter()
+            .map(|(name, value)| (encode(name), encode(value)))
+            .collect::<Vec<_>>();
+        params.sort();
+        let query = params
+            .iter()
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect::<Vec<_>>()
+            .join("&");
+        let mut url = self.base.clone();
+        url.set_path(&path);
+        url.set_query((!query.is_empty()).then_some(query.as_str()));
+
+        let host = match url.port() {
+            Some(port) => format!("{}:{port}", url.host_str().unwrap_or_default()),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+        // 2024-03-04T22:06:07Z becomes 20240304T220607Z
+        let timestamp = format_rfc3339(now_millis()).replace(['-', ':'], "");
+        let date = &timestamp[..8];
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "GET\n{path}\n{query}\nhost:{host}\nx-amz-content-sha256:{EMPTY_HASH}\nx-amz-date:{timestamp}\n\n{signed_headers}\n{EMPTY_HASH}"
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{:x}",
+            Sha256::digest(canonical_request.as_bytes())
+        );
+        let mut key = format!("AWS4{}", self.secret_access_key).into_bytes();
+        for part in [date, &self.region, "s3", "aws4_request"] {
+            key = hmac(&key, part);
+        }
+        let signature = hmac(&key, &string_to_sign)
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+            self.access_key_id
+        );
+        Ok(net::client()
+            .request(Method::GET, url)
+            .header("x-amz-content-sha256", EMPTY_HASH)
+            .header("x-amz-date", timestamp)
+            .header("authorization", authorization))
+    }
+}
+
+impl StorageProvider for S3Provider {
+    async fn list(&self, folder: Option<&str>) -> Result<Vec<RemoteEntry>> {
+        let prefix = folder.unwrap_or(&self.prefix);
+        let mut entries = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut params = vec![("list-type", "2"), ("delimiter", "/"), ("prefix", prefix)];
+            if let Some(token) = &token {
+                params.push(("continuation-token", token));
+            }
+            let response = super::check_status(self.request("", &params)?.send().await?)?;
+            let body = response.text().await?;
+            let doc = parse_xml(&body)?;
+            let root = doc.root_element();
+            for node in root.children().filter(roxmltree::Node::is_element) {
+                let (id, folder) = match node.tag_name().name() {
+                    "Contents" => (child_text(node, "Key"), false),
+                    "CommonPrefixes" => (child_text(node, "Prefix"), true),
+                    _ => continue,
+                };
+                let Some(id) = id.filter(|id| *id != prefix) else {
+                    continue;
+                };
+                entries.push(RemoteEntry {
+                    id: id.to_string(),
+                    name: id
+                        .trim_end_matches('/')
+                        .rsplit('/')
+                        .next()
+                        .unwrap_or(id)
+                        .to_string(),
+                    folder,
+                    size: child_text(node, "Size").and_then(|size| size.parse().ok()),
+                });
+            }
+            let truncated = child_text(root, "IsTruncated") == Some("true");
+            match child_text(root, "NextContinuationToken") {
+                Some(next) if truncated => token = Some(next.to_string()),
+                _ => return Ok(entries),
+            }
+        }
+    }
+
+    async fn get(&self, id: &str, range: Option<Range<u64>>) -> Result<Response> {
+        let request = self.request(id, &[])?;
+        let request = match range {
+            Some(range) => request.header(RANGE, range_header(&range)),
+            None => request,
+        };
+        super::check_status(request.send().await?)
+    }
+}
@@ -0,0 +1,106 @@
+//! Serve comic archives page by page so the reader never has to unpack a
+//! whole CBZ/CBR in the webview.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use tauri::ipc::Response;
+use tauri::{command, AppHandle, Manager};
+
+use crate::error::Result;
+use crate::formats::comic::{ComicArchive, ComicInfo, ComicPage};
+use crate::formats::epub::Metadata;
+
+/// Archives kept open between page requests.
+const MAX_OPEN_ARCHIVES: usize = 4;
+const DEFAULT_THUMBNAIL_SIZE: u32 = 512;
+
+type Archive = Arc<Mutex<ComicArchive>>;
+
+/// Recently used archives by path, most recent last, so turning pages does
+/// not re-read the archive directory every time. Each is locked on its own,
+/// so reading a page of one comic does not hold up the others.
+#[derive(Default)]
+pub struct OpenComics(Mutex<Vec<(PathBuf, Archive)>>);
+
+impl OpenComics {
+    fn archive(&self, path: &Path) -> Result<Archive> {
+        {
+            let mut archives = self.0.lock().unwrap();
+            if let Some(i) = archives.iter().position(|(open, _)| open == path) {
+                let entry = archives.remove(i);
+                let archive = entry.1.clone();
+                archives.push(entry);
+                return Ok(archive);
+            }
+        }
+        let archive = Arc::new(Mutex::new(ComicArchive::open(path)?));
+        let mut archives = self.0.lock().unwrap();
+        archives.retain(|(open, _)| open != path);
+        if archives.len() >= MAX_OPEN_ARCHIVES {
+            archives.remove(0);
+        }
+        archives.push((path.to_path_buf(), archive.clone()));
+        Ok(archive)
+    }
+
+    pub(crate) fn with<T>(
+        &self,
+        path: &Path,
+        f: impl FnOnce(&mut ComicArchive) -> Result<T>,
+    ) -> Result<T> {
+        let archive = self.archive(path)?;
+        let mut archive = archive.lock().unwrap();
+        f(&mut archive)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ComicBook {
+    pub metadata: Metadata,
+    pub info: Option<ComicInfo>,
+    pub pages: Vec<ComicPage>,
+}
+
+async fn with_comic<T: Send + 'static>(
+    app: AppHandle,
+    path: PathBuf,
+    f: impl FnOnce(&mut ComicArchive) -> Result<T> + Send + 'static,
+) -> Result<T> {
+    tauri::async_runtime::spawn_blocking(move || app.state::<OpenComics>().with(&path, f)).await?
+}
+
+#[command]
+pub async fn open_comic(app: AppHandle, path: PathBuf) -> Result<ComicBook> {
+    with_comic(app, path, |comic| {
+        Ok(ComicBook {
+            metadata: comic.metadata(),
+            info: comic.info().cloned(),
+            pages: comic.pages().to_vec(),
+        })
+    })
+    .await
+}
+
+/// Returns the raw image bytes of one page.
+#[command]
+pub async fn read_comic_page(app: AppHandle, path: PathBuf, index: usize) -> Result<Response> {
+    let data = with_comic(app, path, move |comic| comic.read_page(index)).await?;
+    Ok(Response::new(data))
+}
+
+/// Returns a JPEG thumbnail of one page, the cover by default.
+#[command]
+pub async fn get_comic_thumbnail(
+    app: AppHandle,
+    path: PathBuf,
+    index: Option<usize>,
+    max_size: Option<u32>,
+) -> Result<Response> {
+    let index = index.unwrap_or(0);
+    let max_size = max_size.unwrap_or(DEFAULT_THUMBNAIL_SIZE);
+    let data = with_comic(app, path, move |comic| comic.thumbnail(index, max_size)).await?;
+    Ok(Response::new(data))
+}
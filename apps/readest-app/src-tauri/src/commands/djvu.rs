@@ -0,0 +1,78 @@
+//! Serve DjVu documents page by page as images, since the webview has no
+//! DjVu decoder of its own.
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::Serialize;
+use tauri::ipc::Response;
+use tauri::{command, AppHandle, Manager};
+
+use crate::error::Result;
+use crate::formats::djvu::DjvuDocument;
+use crate::formats::pdf::OutlineItem;
+
+/// Documents kept open between page requests, each with its page cache.
+const MAX_OPEN_DOCUMENTS: usize = 2;
+
+/// Recently used documents, most recent last.
+#[derive(Default)]
+pub struct OpenDjvus(Mutex<Vec<DjvuDocument>>);
+
+impl OpenDjvus {
+    pub(crate) fn with<T>(
+        &self,
+        path: &Path,
+        f: impl FnOnce(&mut DjvuDocument) -> Result<T>,
+    ) -> Result<T> {
+        let mut documents = self.0.lock().unwrap();
+        let mut document = match documents.iter().position(|d| d.path() == path) {
+            Some(i) => documents.remove(i),
+            None => DjvuDocument::open(path)?,
+        };
+        let result = f(&mut document);
+        if documents.len() >= MAX_OPEN_DOCUMENTS {
+            documents.remove(0);
+        }
+        documents.push(document);
+        result
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DjvuInfo {
+    pub page_count: u32,
+    pub outline: Vec<OutlineItem>,
+}
+
+async fn with_djvu<T: Send + 'static>(
+    app: AppHandle,
+    path: PathBuf,
+    f: impl FnOnce(&mut DjvuDocument) -> Result<T> + Send + 'static,
+) -> Result<T> {
+    tauri::async_runtime::spawn_blocking(move || app.state::<OpenDjvus>().with(&path, f)).await?
+}
+
+#[command]
+pub async fn get_djvu_info(app: AppHandle, path: PathBuf) -> Result<DjvuInfo> {
+    with_djvu(app, path, |djvu| {
+        Ok(DjvuInfo {
+            page_count: djvu.page_count(),
+            outline: djvu.outline(),
+        })
+    })
+    .await
+}
+
+/// Returns page `page`, counted from 1, as a PNG `width` pixels wide.
+#[command]
+pub async fn render_djvu_page(
+    app: AppHandle,
+    path: PathBuf,
+    page: u32,
+    width: u32,
+) -> Result<Response> {
+    let data = with_djvu(app, path, move |djvu| djvu.render_page(page, width)).await?;
+    Ok(Response::new(data))
+}
@@ -0,0 +1,110 @@
+//! Serve FictionBook files chapter by chapter, rendered to XHTML, so the
+//! reader can page through them like EPUB spine documents.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use tauri::ipc::Response;
+use tauri::{command, AppHandle, Manager};
+
+use crate::error::{Error, Result};
+use crate::formats::epub::{Metadata, TocItem};
+use crate::formats::fb2::Fb2Book;
+
+/// Books kept parsed between chapter requests.
+const MAX_OPEN_BOOKS: usize = 2;
+
+/// Recently used books, most recent last, so turning chapters does not
+/// parse the whole file again.
+#[derive(Default)]
+pub struct OpenFb2Books(Mutex<Vec<(PathBuf, Arc<Fb2Book>)>>);
+
+impl OpenFb2Books {
+    fn get(&self, path: &Path) -> Result<Arc<Fb2Book>> {
+        {
+            let mut books = self.0.lock().unwrap();
+            if let Some(i) = books.iter().position(|(p, _)| p == path) {
+                let entry = books.remove(i);
+                let book = entry.1.clone();
+                books.push(entry);
+                return Ok(book);
+            }
+        }
+        // Parsed without the lock, a large book takes a while
+        let book = Arc::new(Fb2Book::open(path)?);
+        let mut books = self.0.lock().unwrap();
+        if books.len() >= MAX_OPEN_BOOKS {
+            books.remove(0);
+        }
+        books.push((path.to_path_buf(), book.clone()));
+        Ok(book)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Fb2Chapter {
+    pub href: String,
+    pub title: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Fb2Info {
+    pub metadata: Metadata,
+    pub toc: Vec<TocItem>,
+    /// Chapters in reading order.
+    pub spine: Vec<Fb2Chapter>,
+}
+
+async fn with_book<T: Send + 'static>(
+    app: AppHandle,
+    path: PathBuf,
+    f: impl FnOnce(&Fb2Book) -> Result<T> + Send + 'static,
+) -> Result<T> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let book = app.state::<OpenFb2Books>().get(&path)?;
+        f(&book)
+    })
+    .await?
+}
+
+#[command]
+pub async fn open_fb2(app: AppHandle, path: PathBuf) -> Result<Fb2Info> {
+    with_book(app, path, |book| {
+        Ok(Fb2Info {
+            metadata: book.metadata().clone(),
+            toc: book.toc().to_vec(),
+            spine: book
+                .spine()
+                .map(|(href, title)| Fb2Chapter {
+                    href: href.to_string(),
+                    title: title.map(String::from),
+                })
+                .collect(),
+        })
+    })
+    .await
+}
+
+/// Returns the XHTML of chapter `href`, with its images inlined.
+#[command]
+pub async fn read_fb2_chapter(app: AppHandle, path: PathBuf, href: String) -> Result<String> {
+    with_book(app, path, move |book| {
+        book.chapter(&href)
+            .map(String::from)
+            .ok_or_else(|| Error::InvalidBook(format!("no chapter {href}")))
+    })
+    .await
+}
+
+/// Returns the raw bytes of the binary `id`, such as the cover.
+#[command]
+pub async fn read_fb2_binary(app: AppHandle, path: PathBuf, id: String) -> Result<Response> {
+    with_book(app, path, move |book| match book.binary(&id)? {
+        Some((_, data)) => Ok(Response::new(data)),
+        None => Err(Error::InvalidBook(format!("no binary {id}"))),
+    })
+    .await
+}
@@ -0,0 +1,83 @@
+//! Extract book metadata natively so the library can import large batches
+//! without parsing every archive in the webview.
+
+use serde::Serialize;
+use tauri::command;
+
+use crate::error::Result;
+use crate::formats::epub::{EpubArchive, Metadata};
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CoverImage {
+    pub mime_type: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BookMetadata {
+    #[serde(flatten)]
+    pub metadata: Metadata,
+    pub cover: Option<CoverImage>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MetadataResult {
+    pub path: String,
+    pub metadata: Option<BookMetadata>,
+    pub error: Option<String>,
+}
+
+pub fn read_epub_metadata(path: &str, include_cover: bool) -> Result<BookMetadata> {
+    let mut epub = EpubArchive::open(path)?;
+    let package = epub.package()?;
+    let cover = match package.cover() {
+        Some(item) if include_cover => Some(CoverImage {
+            mime_type: item.media_type.clone(),
+            data: epub.read_entry(&item.href)?,
+        }),
+        _ => None,
+    };
+    Ok(BookMetadata {
+        metadata: package.metadata,
+        cover,
+    })
+}
+
+#[command]
+pub async fn extract_epub_metadata(
+    path: String,
+    include_cover: Option<bool>,
+) -> Result<BookMetadata> {
+    let include_cover = include_cover.unwrap_or(true);
+    tauri::async_runtime::spawn_blocking(move || read_epub_metadata(&path, include_cover)).await?
+}
+
+#[command]
+pub async fn extract_epub_metadata_batch(
+    paths: Vec<String>,
+    include_cover: Option<bool>,
+) -> Result<Vec<MetadataResult>> {
+    let include_cover = include_cover.unwrap_or(false);
+    let results = tauri::async_runtime::spawn_blocking(move || {
+        paths
+            .into_iter()
+            .map(|path| match read_epub_metadata(&path, include_cover) {
+                Ok(metadata) => MetadataResult {
+                    path,
+                    metadata: Some(metadata),
+                    error: None,
+                },
+                Err(e) => MetadataResult {
+                    path,
+                    metadata: None,
+                    error: Some(e.to_string()),
+                },
+            })
+            .collect()
+    })
+    .await?;
+    Ok(results)
+}
@@ -0,0 +1,9 @@
+pub mod comic;
+#[cfg(feature = "djvu")]
+pub mod djvu;
+pub mod fb2;
+pub mod metadata;
+pub mod pdf;
+pub mod search;
+pub mod sync;
+pub mod text;
@@ -0,0 +1,57 @@
+//! PDF outline and text for building the table of contents and the search
+//! index without loading the document in pdf.js.
+
+use std::path::PathBuf;
+
+use serde::Serialize;
+use tauri::command;
+
+use crate::error::{Error, Result};
+use crate::formats::epub::Metadata;
+use crate::formats::pdf::{OutlineItem, PageText, PdfDocument};
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PdfInfo {
+    pub metadata: Metadata,
+    pub page_count: u32,
+    pub outline: Vec<OutlineItem>,
+}
+
+#[command]
+pub async fn get_pdf_info(path: PathBuf) -> Result<PdfInfo> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let pdf = PdfDocument::open(&path)?;
+        Ok(PdfInfo {
+            metadata: pdf.metadata(),
+            page_count: pdf.page_count(),
+            outline: pdf.outline(),
+        })
+    })
+    .await?
+}
+
+/// Returns the text of `pages`, or of every page when omitted. Pages that
+/// cannot be decoded come back with empty text.
+#[command]
+pub async fn extract_pdf_text(path: PathBuf, pages: Option<Vec<u32>>) -> Result<Vec<PageText>> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let pdf = PdfDocument::open(&path)?;
+        let pages = pages.unwrap_or_else(|| (1..=pdf.page_count()).collect());
+        pages
+            .into_iter()
+            .map(|page| {
+                let text = match pdf.page_text(page) {
+                    Ok(text) => text,
+                    Err(Error::Pdf(e)) => {
+                        log::warn!("Failed to extract text of page {page}: {e}");
+                        String::new()
+                    }
+                    Err(e) => return Err(e),
+                };
+                Ok(PageText { page, text })
+            })
+            .collect()
+    })
+    .await?
+}
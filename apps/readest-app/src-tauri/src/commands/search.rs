@@ -0,0 +1,171 @@
+//! Commands for the library-wide full-text search index.
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle, Emitter, Manager, State};
+
+use crate::error::Result;
+use crate::formats::extract_chapters;
+use crate::jobs;
+use crate::library::access::Access;
+use crate::library::db::LibraryDb;
+use crate::search::{SearchHit, SearchIndex};
+
+const DEFAULT_SEARCH_LIMIT: usize = 50;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IndexRequest {
+    pub hash: String,
+    pub path: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IndexResult {
+    pub hash: String,
+    /// False when the book was already indexed and has not changed since.
+    pub indexed: bool,
+    pub chapters: usize,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct IndexProgress {
+    current: usize,
+    total: usize,
+    result: IndexResult,
+}
+
+/// Identifies a particular version of a file on disk so unchanged books can be skipped.
+fn file_fingerprint(path: &Path) -> Result<String> {
+    let meta = std::fs::metadata(path)?;
+    let modified = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    Ok(format!("{}-{modified}", meta.len()))
+}
+
+fn index_one(index: &SearchIndex, request: &IndexRequest, force: bool) -> IndexResult {
+    let run = || -> Result<(bool, usize)> {
+        let path = Path::new(&request.path);
+        let fingerprint = file_fingerprint(path)?;
+        if !force && index.fingerprint(&request.hash)?.as_deref() == Some(fingerprint.as_str()) {
+            return Ok((false, 0));
+        }
+        let chapters = extract_chapters(path)?;
+        index.index_book(&request.hash, &fingerprint, &chapters)?;
+        Ok((true, chapters.len()))
+    };
+    match run() {
+        Ok((indexed, chapters)) => IndexResult {
+            hash: request.hash.clone(),
+            indexed,
+            chapters,
+            error: None,
+        },
+        Err(e) => IndexResult {
+            hash: request.hash.clone(),
+            indexed: false,
+            chapters: 0,
+            error: Some(e.to_string()),
+        },
+    }
+}
+
+#[command]
+pub async fn index_book(
+    app: AppHandle,
+    _index: State<'_, SearchIndex>,
+    hash: String,
+    path: String,
+    force: Option<bool>,
+) -> Result<IndexResult> {
+    let request = IndexRequest { hash, path };
+    let result = tauri::async_runtime::spawn_blocking(move || {
+        index_one(
+            &app.state::<SearchIndex>(),
+            &request,
+            force.unwrap_or(false),
+        )
+    })
+    .await?;
+    Ok(result)
+}
+
+/// Incrementally indexes `books`, skipping those whose files have not changed,
+/// and emits a `search-index-progress` event after each book. Runs as an
+/// `index` job, which stops after the current book when cancelled.
+#[command]
+pub async fn index_books(
+    app: AppHandle,
+    _index: State<'_, SearchIndex>,
+    books: Vec<IndexRequest>,
+) -> Result<Vec<IndexResult>> {
+    let handle = app.clone();
+    jobs::run(&handle, "index", |job| async move {
+        let results = tauri::async_runtime::spawn_blocking(move || {
+            let index = app.state::<SearchIndex>();
+            let total = books.len();
+            job.progress("indexing", 0, total, None);
+            books
+                .iter()
+                .take_while(|_| !job.is_cancelled())
+                .enumerate()
+                .map(|(i, request)| {
+                    let result = index_one(&index, request, false);
+                    job.progress("indexing", i + 1, total, None);
+                    let _ = app.emit(
+                        "search-index-progress",
+                        IndexProgress {
+                            current: i + 1,
+                            total,
+                            result: result.clone(),
+                        },
+                    );
+                    result
+                })
+                .collect()
+        })
+        .await?;
+        Ok(results)
+    })
+    .await
+}
+
+#[command]
+pub async fn remove_from_search_index(
+    index: State<'_, SearchIndex>,
+    hashes: Vec<String>,
+) -> Result<()> {
+    index.remove_books(&hashes)
+}
+
+#[command]
+pub async fn search_library(
+    index: State<'_, SearchIndex>,
+    db: State<'_, LibraryDb>,
+    access: State<'_, Access>,
+    query: String,
+    book_hashes: Option<Vec<String>>,
+    limit: Option<usize>,
+) -> Result<Vec<SearchHit>> {
+    let hidden = {
+        let conn = db.conn();
+        access.lock(&conn)?.hidden_books(&conn)?
+    };
+    let Some(hidden) = hidden else {
+        return Ok(Vec::new());
+    };
+    index.search(
+        &query,
+        book_hashes.as_deref(),
+        &hidden,
+        limit.unwrap_or(DEFAULT_SEARCH_LIMIT),
+    )
+}
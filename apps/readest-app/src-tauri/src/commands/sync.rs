@@ -0,0 +1,231 @@
+//! Commands for syncing reading progress and annotations with a remote provider.
+
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle};
+
+use crate::error::{Error, Result};
+use crate::jobs;
+use crate::secrets;
+use crate::store;
+use crate::sync::endpoint::{EndpointConfig, EndpointTransport};
+use crate::sync::kosync::{KoSyncConfig, KoSyncProvider};
+use crate::sync::protocol::EncryptedProvider;
+use crate::sync::webdav::{WebDavConfig, WebDavProvider};
+use crate::sync::{sync_book, BookSyncData};
+
+const SYNC_PROVIDER_FILE: &str = "sync-provider.json";
+/// Keychain entries of the secret fields, which stay out of the config file.
+#[cfg(desktop)]
+const SECRET_PREFIX: &str = "sync-provider.";
+#[cfg(desktop)]
+const SECRET_NAMES: [&str; 3] = ["password", "token", "passphrase"];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SyncProviderConfig {
+    WebDav(WebDavConfig),
+    /// A self-hosted sync endpoint, always end-to-end encrypted.
+    Endpoint(EndpointConfig),
+    /// A KOReader sync server, which keeps reading positions only.
+    KoSync(KoSyncConfig),
+}
+
+impl SyncProviderConfig {
+    /// Passwords, tokens and passphrases, by name.
+    #[cfg(desktop)]
+    fn secrets_mut(&mut self) -> Vec<(&'static str, &mut Option<String>)> {
+        match self {
+            Self::WebDav(config) => vec![
+                ("password", &mut config.password),
+                ("passphrase", &mut config.passphrase),
+            ],
+            Self::Endpoint(config) => vec![
+                ("token", &mut config.token),
+                ("passphrase", &mut config.passphrase),
+            ],
+            Self::KoSync(config) => vec![("password", &mut config.password)],
+        }
+    }
+}
+
+/// Saves the provider config, moving its secrets to the keychain.
+#[cfg(desktop)]
+fn save_config(app: &AppHandle, mut config: Option<SyncProviderConfig>) -> Result<()> {
+    let mut fields = config
+        .as_mut()
+        .map(|config| config.secrets_mut())
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    // Secrets the new config has no field for are removed
+    for name in SECRET_NAMES {
+        let value = fields
+            .iter_mut()
+            .find(|(field, _)| *field == name)
+            .and_then(|(_, value)| value.take());
+        secrets::store(&format!("{SECRET_PREFIX}{name}"), value.as_deref())?;
+    }
+    store::save(app, SYNC_PROVIDER_FILE, &config)
+}
+
+/// Loads the provider config with its secrets. Secrets found in the file,
+/// written before they moved to the keychain, are moved now.
+#[cfg(desktop)]
+fn load_config(app: &AppHandle) -> Result<Option<SyncProviderConfig>> {
+    let config: Option<SyncProviderConfig> = store::load(app, SYNC_PROVIDER_FILE);
+    let Some(mut config) = config else {
+        return Ok(None);
+    };
+    if config
+        .secrets_mut()
+        .iter()
+        .any(|(_, value)| value.is_some())
+    {
+        save_config(app, Some(config.clone()))?;
+        return Ok(Some(config));
+    }
+    for (name, value) in config.secrets_mut() {
+        *value = secrets::get(&format!("{SECRET_PREFIX}{name}"))?;
+    }
+    Ok(Some(config))
+}
+
+#[cfg(not(desktop))]
+fn save_config(app: &AppHandle, config: Option<SyncProviderConfig>) -> Result<()> {
+    store::save(app, SYNC_PROVIDER_FILE, &config)
+}
+
+#[cfg(not(desktop))]
+fn load_config(app: &AppHandle) -> Result<Option<SyncProviderConfig>> {
+    Ok(store::load(app, SYNC_PROVIDER_FILE))
+}
+
+/// Whether a provider is configured, without reading its secrets.
+pub(crate) fn is_configured(app: &AppHandle) -> bool {
+    store::load::<Option<SyncProviderConfig>>(app, SYNC_PROVIDER_FILE).is_some()
+}
+
+enum Provider {
+    WebDav(WebDavProvider),
+    EncryptedWebDav(EncryptedProvider<WebDavProvider>),
+    Endpoint(EncryptedProvider<EndpointTransport>),
+    KoSync(KoSyncProvider),
+}
+
+impl Provider {
+    /// Connects to the provider. With encryption this derives the keys and
+    /// checks the passphrase against the server.
+    async fn new(config: SyncProviderConfig) -> Result<Self> {
+        match config {
+            SyncProviderConfig::WebDav(config) => {
+                let passphrase = config.passphrase.clone().filter(|p| !p.is_empty());
+                let provider = WebDavProvider::new(config)?;
+                match passphrase {
+                    Some(passphrase) => Ok(Self::EncryptedWebDav(
+                        EncryptedProvider::new(provider, &passphrase).await?,
+                    )),
+                    None => Ok(Self::WebDav(provider)),
+                }
+            }
+            SyncProviderConfig::Endpoint(config) => {
+                let passphrase = config
+                    .passphrase
+                    .as_deref()
+                    .filter(|p| !p.is_empty())
+                    .ok_or_else(|| Error::SyncEncryption("no passphrase is set".to_string()))?;
+                let transport = EndpointTransport::new(&config)?;
+                Ok(Self::Endpoint(
+                    EncryptedProvider::new(transport, passphrase).await?,
+                ))
+            }
+            SyncProviderConfig::KoSync(config) => Ok(Self::KoSync(KoSyncProvider::new(&config)?)),
+        }
+    }
+
+    async fn check(&self) -> Result<()> {
+        match self {
+            Self::WebDav(provider) => provider.check().await,
+            Self::EncryptedWebDav(provider) => provider.transport().check().await,
+            Self::Endpoint(provider) => provider.transport().check().await,
+            Self::KoSync(provider) => provider.check().await,
+        }
+    }
+
+    async fn sync(&self, local: &BookSyncData) -> Result<BookSyncData> {
+        match self {
+            Self::WebDav(provider) => sync_book(provider, local).await,
+            Self::EncryptedWebDav(provider) => sync_book(provider, local).await,
+            Self::Endpoint(provider) => sync_book(provider, local).await,
+            Self::KoSync(provider) => provider.sync(local).await,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncResult {
+    pub book_hash: String,
+    /// The merged state the frontend should apply locally.
+    pub data: Option<BookSyncData>,
+    pub error: Option<String>,
+}
+
+#[command]
+pub async fn get_sync_provider(app: AppHandle) -> Result<Option<SyncProviderConfig>> {
+    secrets::blocking(move || match load_config(&app) {
+        // Still show the provider, saving or syncing says what is missing
+        #[cfg(desktop)]
+        Err(Error::KeychainUnavailable(e)) => {
+            log::warn!("Loading the sync provider without its secrets: {e}");
+            Ok(store::load(&app, SYNC_PROVIDER_FILE))
+        }
+        result => result,
+    })
+    .await
+}
+
+#[command]
+pub async fn set_sync_provider(app: AppHandle, provider: Option<SyncProviderConfig>) -> Result<()> {
+    secrets::blocking(move || save_config(&app, provider)).await
+}
+
+/// Verifies that `provider` is reachable before the user saves it.
+#[command]
+pub async fn test_sync_provider(provider: SyncProviderConfig) -> Result<()> {
+    Provider::new(provider).await?.check().await
+}
+
+/// Syncs each book with the configured provider, merging local and remote
+/// state by timestamp, as a `sync` job. Cancelling keeps the books synced
+/// so far.
+#[command]
+pub async fn sync_book_data(app: AppHandle, books: Vec<BookSyncData>) -> Result<Vec<SyncResult>> {
+    let handle = app.clone();
+    jobs::run(&handle, "sync", |job| async move {
+        job.phase("connecting", None);
+        let config = tauri::async_runtime::spawn_blocking(move || load_config(&app)).await??;
+        let provider = job
+            .cancellable(Provider::new(config.ok_or(Error::SyncNotConfigured)?))
+            .await?;
+
+        let total = books.len();
+        let mut results = Vec::with_capacity(total);
+        job.progress("syncing", 0, total, None);
+        for local in &books {
+            let (data, error) = match job.cancellable(provider.sync(local)).await {
+                Ok(data) => (Some(data), None),
+                // Keep what was synced before
+                Err(Error::Cancelled) => break,
+                Err(e) => (None, Some(e.to_string())),
+            };
+            results.push(SyncResult {
+                book_hash: local.book_hash.clone(),
+                data,
+                error,
+            });
+            job.progress("syncing", results.len(), total, None);
+        }
+        Ok(results)
+    })
+    .await
+}
@@ -0,0 +1,117 @@
+//! Serve plain text and Markdown files chapter by chapter, rendered to
+//! XHTML, so the reader can page through them like EPUB spine documents.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use tauri::{command, AppHandle, Manager};
+
+use crate::convert::transcode;
+use crate::error::{Error, Result};
+use crate::formats::epub::{Metadata, TocItem};
+use crate::formats::text::{TextBook, TextOptions};
+
+/// Books kept split into chapters between chapter requests.
+const MAX_OPEN_BOOKS: usize = 2;
+
+/// Recently used books with the options they were read with, most recent
+/// last, so turning chapters does not decode the whole file again.
+#[derive(Default)]
+pub struct OpenTextBooks(Mutex<Vec<(PathBuf, TextOptions, Arc<TextBook>)>>);
+
+impl OpenTextBooks {
+    fn get(&self, path: &Path, options: &TextOptions) -> Result<Arc<TextBook>> {
+        {
+            let mut books = self.0.lock().unwrap();
+            if let Some(i) = books.iter().position(|(p, o, _)| p == path && o == options) {
+                let entry = books.remove(i);
+                let book = entry.2.clone();
+                books.push(entry);
+                return Ok(book);
+            }
+        }
+        // Split without the lock, a large novel takes a while
+        let book = Arc::new(TextBook::open(path, options)?);
+        let mut books = self.0.lock().unwrap();
+        if books.len() >= MAX_OPEN_BOOKS {
+            books.remove(0);
+        }
+        books.push((path.to_path_buf(), options.clone(), book.clone()));
+        Ok(book)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TextChapter {
+    pub href: String,
+    pub title: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TextBookInfo {
+    pub metadata: Metadata,
+    /// Encoding the file was decoded from.
+    pub encoding: String,
+    pub toc: Vec<TocItem>,
+    /// Chapters in reading order.
+    pub spine: Vec<TextChapter>,
+}
+
+async fn with_book<T: Send + 'static>(
+    app: AppHandle,
+    path: PathBuf,
+    options: Option<TextOptions>,
+    f: impl FnOnce(&TextBook) -> Result<T> + Send + 'static,
+) -> Result<T> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let mut options = options.unwrap_or_default();
+        if options.encoding.is_none() {
+            options.encoding = transcode::override_for(&app, &path);
+        }
+        let book = app.state::<OpenTextBooks>().get(&path, &options)?;
+        f(&book)
+    })
+    .await?
+}
+
+#[command]
+pub async fn open_text_book(
+    app: AppHandle,
+    path: PathBuf,
+    options: Option<TextOptions>,
+) -> Result<TextBookInfo> {
+    with_book(app, path, options, |book| {
+        Ok(TextBookInfo {
+            metadata: book.metadata().clone(),
+            encoding: book.encoding().to_string(),
+            toc: book.toc().to_vec(),
+            spine: book
+                .spine()
+                .map(|(href, title)| TextChapter {
+                    href: href.to_string(),
+                    title: title.map(String::from),
+                })
+                .collect(),
+        })
+    })
+    .await
+}
+
+/// Returns the XHTML of chapter `href`, split with the same `options` the
+/// book was opened with.
+#[command]
+pub async fn read_text_chapter(
+    app: AppHandle,
+    path: PathBuf,
+    href: String,
+    options: Option<TextOptions>,
+) -> Result<String> {
+    with_book(app, path, options, move |book| {
+        book.chapter(&href)
+            .ok_or_else(|| Error::InvalidBook(format!("no chapter {href}")))
+    })
+    .await
+}
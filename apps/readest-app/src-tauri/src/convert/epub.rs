@@ -0,0 +1,239 @@
+//! Writes EPUB 3 packages for books produced by the converters.
+
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::error::Result;
+use crate::formats::epub::Metadata;
+use crate::utils::escape_xml;
+
+const CONTENT_DIR: &str = "OEBPS";
+
+/// An entry of the table of contents.
+#[derive(Debug, Clone)]
+pub struct NavPoint {
+    pub label: String,
+    /// Target relative to the content directory, e.g. `part0001.xhtml#c2`.
+    pub href: String,
+    pub children: Vec<NavPoint>,
+}
+
+struct Item {
+    id: String,
+    href: String,
+    media_type: String,
+    properties: Option<&'static str>,
+    data: Vec<u8>,
+}
+
+#[derive(Default)]
+pub struct EpubBuilder {
+    pub metadata: Metadata,
+    pub toc: Vec<NavPoint>,
+    items: Vec<Item>,
+    spine: Vec<String>,
+}
+
+impl EpubBuilder {
+    pub fn new(metadata: Metadata) -> Self {
+        Self {
+            metadata,
+            ..Self::default()
+        }
+    }
+
+    fn push(&mut self, href: &str, media_type: &str, data: Vec<u8>) -> String {
+        let id = format!("item{}", self.items.len() + 1);
+        self.items.push(Item {
+            id: id.clone(),
+            href: href.to_string(),
+            media_type: media_type.to_string(),
+            properties: None,
+            data,
+        });
+        id
+    }
+
+    /// Adds a content document at the end of the reading order.
+    pub fn add_chapter(&mut self, href: &str, media_type: &str, data: Vec<u8>) {
+        let id = self.push(href, media_type, data);
+        self.spine.push(id);
+    }
+
+    /// Adds a resource (image, stylesheet, font) referenced by the chapters.
+    pub fn add_resource(&mut self, href: &str, media_type: &str, data: Vec<u8>) {
+        self.push(href, media_type, data);
+    }
+
+    pub fn add_cover(&mut self, href: &str, media_type: &str, data: Vec<u8>) {
+        self.push(href, media_type, data);
+        self.items.last_mut().unwrap().properties = Some("cover-image");
+    }
+
+    fn nav_document(&self) -> String {
+        fn write_points(out: &mut String, points: &[NavPoint]) {
+            out.push_str("<ol>\n");
+            for point in points {
+                let _ = write!(
+                    out,
+                    "<li><a href=\"{}\">{}</a>",
+                    escape_xml(&point.href),
+                    escape_xml(&point.label)
+                );
+                if !point.children.is_empty() {
+                    write_points(out, &point.children);
+                }
+                out.push_str("</li>\n");
+            }
+            out.push_str("</ol>\n");
+        }
+
+        let fallback;
+        let toc = if self.toc.is_empty() {
+            // Without a source TOC, list every chapter so the book stays navigable
+            fallback = self
+                .spine
+                .iter()
+                .filter_map(|id| self.items.iter().find(|item| &item.id == id))
+                .enumerate()
+                .map(|(i, item)| NavPoint {
+                    label: format!("{}", i + 1),
+                    href: item.href.clone(),
+                    children: Vec::new(),
+                })
+                .collect::<Vec<_>>();
+            &fallback
+        } else {
+            &self.toc
+        };
+
+        let mut out = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <html xmlns=\"http://www.w3.org/1999/xhtml\" xmlns:epub=\"http://www.idpf.org/2007/ops\">\n\
+             <head><title>{}</title></head>\n<body>\n<nav epub:type=\"toc\" id=\"toc\">\n",
+            escape_xml(&self.metadata.title)
+        );
+        write_points(&mut out, toc);
+        out.push_str("</nav>\n</body>\n</html>\n");
+        out
+    }
+
+    fn package_document(&self) -> String {
+        let meta = &self.metadata;
+        let mut out = String::from(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <package xmlns=\"http://www.idpf.org/2007/opf\" version=\"3.0\" unique-identifier=\"uid\">\n\
+             <metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n",
+        );
+        let identifier = meta
+            .identifiers
+            .first()
+            .map(|id| id.value.clone())
+            .unwrap_or_else(|| format!("urn:vlarch:{:x}", crate::utils::now_millis()));
+        let _ = writeln!(
+            out,
+            "<dc:identifier id=\"uid\">{}</dc:identifier>",
+            escape_xml(&identifier)
+        );
+        let _ = writeln!(out, "<dc:title>{}</dc:title>", escape_xml(&meta.title));
+        for author in &meta.authors {
+            let _ = writeln!(out, "<dc:creator>{}</dc:creator>", escape_xml(author));
+        }
+        let languages = if meta.language.is_empty() {
+            vec!["und".to_string()]
+        } else {
+            meta.language.clone()
+        };
+        for language in &languages {
+            let _ = writeln!(out, "<dc:language>{}</dc:language>", escape_xml(language));
+        }
+        let optional = [
+            ("publisher", &meta.publisher),
+            ("date", &meta.published),
+            ("description", &meta.description),
+        ];
+        for (name, value) in optional {
+            if let Some(value) = value {
+                let _ = writeln!(out, "<dc:{name}>{}</dc:{name}>", escape_xml(value));
+            }
+        }
+        for subject in &meta.subjects {
+            let _ = writeln!(out, "<dc:subject>{}</dc:subject>", escape_xml(subject));
+        }
+        let _ = writeln!(
+            out,
+            "<meta property=\"dcterms:modified\">{}</meta>",
+            crate::utils::format_rfc3339(crate::utils::now_millis())
+        );
+        out.push_str("</metadata>\n<manifest>\n");
+        out.push_str(
+            "<item id=\"nav\" href=\"nav.xhtml\" media-type=\"application/xhtml+xml\" properties=\"nav\"/>\n",
+        );
+        for item in &self.items {
+            let _ = write!(
+                out,
+                "<item id=\"{}\" href=\"{}\" media-type=\"{}\"",
+                item.id,
+                escape_xml(&item.href),
+                escape_xml(&item.media_type)
+            );
+            if let Some(properties) = item.properties {
+                let _ = write!(out, " properties=\"{properties}\"");
+            }
+            out.push_str("/>\n");
+        }
+        out.push_str("</manifest>\n<spine>\n");
+        for id in &self.spine {
+            let _ = writeln!(out, "<itemref idref=\"{id}\"/>");
+        }
+        out.push_str("</spine>\n</package>\n");
+        out
+    }
+
+    /// Writes the package to `path`, replacing any existing file once complete.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("epub.tmp");
+        {
+            let mut zip = ZipWriter::new(BufWriter::new(File::create(&tmp)?));
+            let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+            let deflated =
+                SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+            // The mimetype entry must come first and be stored uncompressed
+            zip.start_file("mimetype", stored)?;
+            zip.write_all(b"application/epub+zip")?;
+            zip.start_file("META-INF/container.xml", deflated)?;
+            write!(
+                zip,
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                 <container version=\"1.0\" xmlns=\"urn:oasis:names:tc:opendocument:xmlns:container\">\n\
+                 <rootfiles><rootfile full-path=\"{CONTENT_DIR}/content.opf\" \
+                 media-type=\"application/oebps-package+xml\"/></rootfiles>\n</container>\n"
+            )?;
+            zip.start_file(format!("{CONTENT_DIR}/content.opf"), deflated)?;
+            zip.write_all(self.package_document().as_bytes())?;
+            zip.start_file(format!("{CONTENT_DIR}/nav.xhtml"), deflated)?;
+            zip.write_all(self.nav_document().as_bytes())?;
+            for item in &self.items {
+                // Images and fonts are usually compressed already
+                let options = if item.media_type.starts_with("image/")
+                    && item.media_type != "image/svg+xml"
+                {
+                    stored
+                } else {
+                    deflated
+                };
+                zip.start_file(format!("{CONTENT_DIR}/{}", item.href), options)?;
+                zip.write_all(&item.data)?;
+            }
+            zip.finish()?.flush()?;
+        }
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,1430 @@
+//! Unpacks DRM-free MOBI (PalmDOC/MOBI 6) and KF8 (AZW3) books and repackages
+//! them as EPUB. KF8 content is reassembled from its skeleton and fragment
+//! tables; MOBI 6 markup is split at page breaks, its `filepos` links and
+//! `recindex` images are rewritten to regular hrefs, and it is made into
+//! XHTML.
+//!
+//! Every count and offset in the headers is checked against the data it
+//! points into before it is used, so a damaged or hostile file fails to
+//! convert, or converts partly, rather than hanging or panicking.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::Read;
+use std::path::Path;
+
+use super::epub::{EpubBuilder, NavPoint};
+use crate::error::{Error, Result};
+use crate::formats::epub::{Identifier, Metadata};
+use crate::formats::html::decode_entities;
+use crate::utils::escape_xml;
+
+const NULL_INDEX: u32 = 0xFFFF_FFFF;
+const PALMDOC_COMPRESSION: u16 = 2;
+const HUFFCDIC_COMPRESSION: u16 = 17480;
+const UTF8_ENCODING: u32 = 65001;
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    data.get(offset..offset.saturating_add(2))
+        .map_or(0, |b| u16::from_be_bytes([b[0], b[1]]))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    data.get(offset..offset.saturating_add(4))
+        .map_or(NULL_INDEX, |b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// `len` bytes of `data` from `start`, if it has them.
+fn slice_at(data: &[u8], start: usize, len: usize) -> Option<&[u8]> {
+    data.get(start..start.checked_add(len)?)
+}
+
+fn invalid(message: &str) -> Error {
+    Error::InvalidBook(message.to_string())
+}
+
+/// Palm database container: a list of records.
+struct Pdb {
+    data: Vec<u8>,
+    offsets: Vec<usize>,
+}
+
+impl Pdb {
+    fn parse(data: Vec<u8>) -> Result<Self> {
+        if data.len() < 78 {
+            return Err(invalid("file too short for a Palm database"));
+        }
+        let count = u16_at(&data, 76) as usize;
+        let offsets = (0..count)
+            .map(|i| u32_at(&data, 78 + i * 8) as usize)
+            .collect::<Vec<_>>();
+        if offsets.iter().any(|&o| o > data.len()) {
+            return Err(invalid("record offset out of range"));
+        }
+        Ok(Self { data, offsets })
+    }
+
+    fn record(&self, index: usize) -> Option<&[u8]> {
+        let start = *self.offsets.get(index)?;
+        let end = self
+            .offsets
+            .get(index + 1)
+            .copied()
+            .unwrap_or(self.data.len());
+        self.data.get(start..end.max(start))
+    }
+}
+
+/// Decodes text in the book's declared encoding (UTF-8 or Windows-1252).
+fn decode(bytes: &[u8], encoding: u32) -> String {
+    if encoding == UTF8_ENCODING {
+        return String::from_utf8_lossy(bytes).into_owned();
+    }
+    const CP1252: [char; 32] = [
+        '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8d}', 'Ž',
+        '\u{8f}', '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9d}',
+        'ž', 'Ÿ',
+    ];
+    bytes
+        .iter()
+        .map(|&b| match b {
+            0x80..=0x9f => CP1252[(b - 0x80) as usize],
+            b => b as char,
+        })
+        .collect()
+}
+
+#[derive(Default)]
+struct Exth {
+    authors: Vec<String>,
+    publisher: Option<String>,
+    description: Option<String>,
+    isbn: Option<String>,
+    subjects: Vec<String>,
+    published: Option<String>,
+    asin: Option<String>,
+    cover_offset: Option<u32>,
+    title: Option<String>,
+    language: Option<String>,
+    kf8_boundary: Option<u32>,
+}
+
+impl Exth {
+    fn parse(data: &[u8], encoding: u32) -> Self {
+        let mut exth = Exth::default();
+        if data.get(0..4) != Some(b"EXTH") {
+            return exth;
+        }
+        // Each record takes at least 8 bytes
+        let count = (u32_at(data, 8) as usize).min(data.len() / 8);
+        let mut pos = 12;
+        for _ in 0..count {
+            let kind = u32_at(data, pos);
+            let len = (u32_at(data, pos + 4) as usize).max(8);
+            let Some(value) = slice_at(data, pos, len).map(|record| &record[8..]) else {
+                break;
+            };
+            let text =
+                || Some(decode(value, encoding).trim().to_string()).filter(|s| !s.is_empty());
+            match kind {
+                100 => exth.authors.extend(text()),
+                101 => exth.publisher = text(),
+                103 => exth.description = text(),
+                104 => exth.isbn = text(),
+                105 => exth.subjects.extend(text()),
+                106 => exth.published = text(),
+                113 => exth.asin = text(),
+                121 => exth.kf8_boundary = Some(u32_at(value, 0)),
+                201 => exth.cover_offset = Some(u32_at(value, 0)),
+                503 => exth.title = text(),
+                524 => exth.language = text(),
+                _ => {}
+            }
+            pos += len;
+        }
+        exth
+    }
+}
+
+/// Fields of the PalmDOC and MOBI headers in a book's first record.
+struct Header {
+    compression: u16,
+    text_length: usize,
+    text_records: usize,
+    encryption: u16,
+    encoding: u32,
+    version: u32,
+    title: String,
+    first_resource: u32,
+    extra_flags: u16,
+    fdst_index: u32,
+    ncx_index: u32,
+    frag_index: u32,
+    skel_index: u32,
+    exth: Exth,
+}
+
+impl Header {
+    fn parse(record: &[u8]) -> Result<Self> {
+        if record.get(16..20) != Some(b"MOBI") {
+            return Err(invalid("missing MOBI header"));
+        }
+        let header_len = u32_at(record, 20) as usize;
+        let encoding = u32_at(record, 28);
+        let name_offset = u32_at(record, 84) as usize;
+        let name_len = u32_at(record, 88) as usize;
+        let title = slice_at(record, name_offset, name_len)
+            .map(|name| decode(name, encoding))
+            .unwrap_or_default();
+        let has_exth = u32_at(record, 128) & 0x40 != 0;
+        let exth = if has_exth {
+            Exth::parse(
+                record
+                    .get(header_len.saturating_add(16)..)
+                    .unwrap_or_default(),
+                encoding,
+            )
+        } else {
+            Exth::default()
+        };
+        let extended = |offset: usize| {
+            if header_len.saturating_add(16) > offset + 3 {
+                u32_at(record, offset)
+            } else {
+                NULL_INDEX
+            }
+        };
+        Ok(Self {
+            compression: u16_at(record, 0),
+            text_length: u32_at(record, 4) as usize,
+            text_records: u16_at(record, 8) as usize,
+            encryption: u16_at(record, 12),
+            encoding,
+            version: u32_at(record, 36),
+            title,
+            first_resource: u32_at(record, 108),
+            extra_flags: if header_len >= 0xE4 {
+                u16_at(record, 0xF2)
+            } else {
+                0
+            },
+            fdst_index: extended(0xC0),
+            ncx_index: extended(0xF4),
+            frag_index: extended(0xF8),
+            skel_index: extended(0xFC),
+            exth,
+        })
+    }
+}
+
+/// Strips the trailing entries that follow the text in each text record.
+fn trim_trailing_entries(mut data: &[u8], flags: u16) -> &[u8] {
+    for _ in 0..(flags >> 1).count_ones() {
+        let tail = &data[data.len().saturating_sub(4)..];
+        let mut size = 0usize;
+        for &b in tail {
+            if b & 0x80 != 0 {
+                size = 0;
+            }
+            size = (size << 7) | (b & 0x7f) as usize;
+        }
+        data = &data[..data.len().saturating_sub(size)];
+    }
+    if flags & 1 != 0 {
+        if let Some(&last) = data.last() {
+            let size = (last & 3) as usize + 1;
+            data = &data[..data.len().saturating_sub(size)];
+        }
+    }
+    data
+}
+
+fn palmdoc_decompress(input: &[u8], out: &mut Vec<u8>) {
+    let mut i = 0;
+    while i < input.len() {
+        let c = input[i];
+        i += 1;
+        match c {
+            1..=8 => {
+                let end = (i + c as usize).min(input.len());
+                out.extend_from_slice(&input[i..end]);
+                i = end;
+            }
+            0x80..=0xbf => {
+                let Some(&next) = input.get(i) else { break };
+                i += 1;
+                let pair = ((c as usize) << 8) | next as usize;
+                let distance = (pair >> 3) & 0x7ff;
+                let length = (pair & 7) + 3;
+                if distance == 0 || distance > out.len() {
+                    continue;
+                }
+                // Copies may overlap their own output, so go byte by byte
+                let start = out.len() - distance;
+                for k in 0..length {
+                    out.push(out[start + k]);
+                }
+            }
+            0xc0..=0xff => {
+                out.push(b' ');
+                out.push(c ^ 0x80);
+            }
+            c => out.push(c),
+        }
+    }
+}
+
+/// Reads a forward-encoded variable-width integer used in index records.
+fn varlen(data: &[u8], pos: usize) -> (u32, usize) {
+    let mut value = 0u32;
+    let mut len = 0;
+    for &b in data.iter().skip(pos).take(4) {
+        value = (value << 7) | (b & 0x7f) as u32;
+        len += 1;
+        if b & 0x80 != 0 {
+            break;
+        }
+    }
+    (value, len.max(1))
+}
+
+struct IndexEntry {
+    name: String,
+    tags: HashMap<u8, Vec<u32>>,
+}
+
+impl IndexEntry {
+    fn tag(&self, tag: u8, i: usize) -> Option<u32> {
+        self.tags
+            .get(&tag)
+            .and_then(|values| values.get(i))
+            .copied()
+    }
+}
+
+struct Index {
+    entries: Vec<IndexEntry>,
+    /// Strings referenced by entries, keyed by their CNCX offset.
+    cncx: HashMap<u32, String>,
+}
+
+/// An opened MOBI or KF8 book.
+struct Book {
+    pdb: Pdb,
+    header: Header,
+    /// Record index of the header in use; KF8 sections of combined files start past 0.
+    start: usize,
+}
+
+impl Book {
+    fn record(&self, index: u32) -> Option<&[u8]> {
+        if index == NULL_INDEX {
+            return None;
+        }
+        self.pdb.record(self.start.checked_add(index as usize)?)
+    }
+
+    fn text(&self) -> Result<Vec<u8>> {
+        // The declared length is only a hint until the text is decompressed
+        let mut text = Vec::with_capacity(self.header.text_length.min(self.pdb.data.len()));
+        for i in 1..=self.header.text_records {
+            if text.len() >= self.header.text_length {
+                break;
+            }
+            let Some(record) = self.record(i as u32) else {
+                break;
+            };
+            let data = trim_trailing_entries(record, self.header.extra_flags);
+            match self.header.compression {
+                PALMDOC_COMPRESSION => palmdoc_decompress(data, &mut text),
+                HUFFCDIC_COMPRESSION => {
+                    return Err(Error::UnsupportedFormat(
+                        "MOBI with HUFF/CDIC compression".into(),
+                    ))
+                }
+                _ => text.extend_from_slice(data),
+            }
+        }
+        text.truncate(self.header.text_length);
+        Ok(text)
+    }
+
+    fn index(&self, index: u32) -> Option<Index> {
+        let main = self.record(index)?;
+        if main.get(0..4) != Some(b"INDX") {
+            return None;
+        }
+        let header_len = u32_at(main, 4) as usize;
+        // Records past the end of the file are not read, so stop there
+        let available = self
+            .pdb
+            .offsets
+            .len()
+            .saturating_sub(self.start)
+            .try_into()
+            .unwrap_or(u32::MAX)
+            .saturating_sub(index);
+        let records = u32_at(main, 24).min(available);
+        let cncx_records = u32_at(main, 52).min(available);
+        let tagx = main.get(header_len..)?;
+        if tagx.get(0..4) != Some(b"TAGX") {
+            return None;
+        }
+        let tagx_len = u32_at(tagx, 4) as usize;
+        let control_bytes = u32_at(tagx, 8) as usize;
+        // (tag, values per entry, mask, end-of-control-byte flag)
+        let tag_table = tagx
+            .get(12..tagx_len)?
+            .chunks_exact(4)
+            .map(|c| (c[0], c[1] as usize, c[2], c[3]))
+            .collect::<Vec<_>>();
+
+        let mut cncx = HashMap::new();
+        for i in 0..cncx_records {
+            let number = index
+                .checked_add(records)
+                .and_then(|n| n.checked_add(i + 1));
+            let Some(record) = number.and_then(|n| self.record(n)) else {
+                break;
+            };
+            let mut pos = 0;
+            while pos < record.len() {
+                let key = (i << 16) | pos as u32;
+                let (len, n) = varlen(record, pos);
+                pos += n;
+                let end = (pos + len as usize).min(record.len());
+                cncx.insert(key, decode(&record[pos..end], self.header.encoding));
+                pos = end;
+            }
+        }
+
+        let mut entries = Vec::new();
+        for i in 0..records {
+            let Some(record) = index.checked_add(i + 1).and_then(|n| self.record(n)) else {
+                break;
+            };
+            let idxt = u32_at(record, 20) as usize;
+            // The IDXT table holds a 2 byte offset per entry
+            let room = record.len().saturating_sub(idxt.saturating_add(4)) / 2;
+            let count = (u32_at(record, 24) as usize).min(room);
+            for j in 0..count {
+                let offset = u16_at(record, idxt + 4 + 2 * j) as usize;
+                let Some(&name_len) = record.get(offset) else {
+                    continue;
+                };
+                let name_end = offset + 1 + name_len as usize;
+                let name = record
+                    .get(offset + 1..name_end)
+                    .map(|n| String::from_utf8_lossy(n).into_owned())
+                    .unwrap_or_default();
+
+                let mut pending = Vec::new();
+                let mut control_index = 0;
+                let mut pos = name_end.saturating_add(control_bytes);
+                for &(tag, values, mask, end) in &tag_table {
+                    if end & 1 != 0 {
+                        control_index += 1;
+                        continue;
+                    }
+                    let control = record.get(name_end + control_index).copied().unwrap_or(0);
+                    let value = control & mask;
+                    if value == mask && mask.count_ones() > 1 {
+                        let (bytes, n) = varlen(record, pos);
+                        pos += n;
+                        pending.push((tag, None, Some(bytes as usize), values));
+                    } else if value != 0 {
+                        let count = (value >> mask.trailing_zeros()) as usize;
+                        pending.push((tag, Some(count), None, values));
+                    }
+                }
+                let mut tags = HashMap::new();
+                for (tag, count, bytes, values) in pending {
+                    let mut list = Vec::new();
+                    match (count, bytes) {
+                        (Some(count), _) => {
+                            for _ in 0..count * values {
+                                if pos >= record.len() {
+                                    break;
+                                }
+                                let (v, n) = varlen(record, pos);
+                                list.push(v);
+                                pos += n;
+                            }
+                        }
+                        (None, Some(bytes)) => {
+                            let mut read = 0;
+                            while read < bytes && pos < record.len() {
+                                let (v, n) = varlen(record, pos);
+                                list.push(v);
+                                pos += n;
+                                read += n;
+                            }
+                        }
+                        _ => {}
+                    }
+                    tags.insert(tag, list);
+                }
+                entries.push(IndexEntry { name, tags });
+            }
+        }
+        Some(Index { entries, cncx })
+    }
+
+    fn metadata(&self) -> Metadata {
+        let exth = &self.header.exth;
+        let mut identifiers = Vec::new();
+        if let Some(isbn) = &exth.isbn {
+            identifiers.push(Identifier {
+                scheme: Some("ISBN".into()),
+                value: isbn.clone(),
+            });
+        }
+        if let Some(asin) = &exth.asin {
+            identifiers.push(Identifier {
+                scheme: Some("ASIN".into()),
+                value: asin.clone(),
+            });
+        }
+        Metadata {
+            title: exth
+                .title
+                .clone()
+                .unwrap_or_else(|| self.header.title.clone()),
+            authors: exth.authors.clone(),
+            language: exth.language.iter().cloned().collect(),
+            publisher: exth.publisher.clone(),
+            published: exth.published.clone(),
+            description: exth.description.clone(),
+            subjects: exth.subjects.clone(),
+            identifiers,
+            ..Metadata::default()
+        }
+    }
+
+    /// Extracts resource `index` (0-based from the first resource record),
+    /// returning its file name, media type and content.
+    fn resource(&self, index: u32) -> Option<(String, &'static str, Vec<u8>)> {
+        let first = self.header.first_resource;
+        if first == NULL_INDEX {
+            return None;
+        }
+        let data = self.record(first.checked_add(index)?)?;
+        let n = index + 1;
+        if data.starts_with(b"FONT") {
+            let (ext, media_type, font) = decode_font(data)?;
+            return Some((format!("font{n:05}.{ext}"), media_type, font));
+        }
+        let (ext, media_type) = sniff_image(data)?;
+        Some((format!("img{n:05}.{ext}"), media_type, data.to_vec()))
+    }
+
+    fn add_cover(&self, epub: &mut EpubBuilder, added: &mut BTreeSet<u32>) {
+        let Some(offset) = self.header.exth.cover_offset else {
+            return;
+        };
+        if let Some((name, media_type, data)) = self.resource(offset) {
+            epub.add_cover(&name, media_type, data);
+            added.insert(offset);
+        }
+    }
+}
+
+fn sniff_image(data: &[u8]) -> Option<(&'static str, &'static str)> {
+    match data {
+        [0xff, 0xd8, 0xff, ..] => Some(("jpg", "image/jpeg")),
+        [0x89, b'P', b'N', b'G', ..] => Some(("png", "image/png")),
+        [b'G', b'I', b'F', b'8', ..] => Some(("gif", "image/gif")),
+        [b'B', b'M', ..] => Some(("bmp", "image/bmp")),
+        _ => None,
+    }
+}
+
+/// Decodes an embedded KF8 font, which may be XOR-obfuscated and zlib-compressed.
+fn decode_font(data: &[u8]) -> Option<(&'static str, &'static str, Vec<u8>)> {
+    let flags = u32_at(data, 8);
+    let start = u32_at(data, 12) as usize;
+    let key_len = u32_at(data, 16) as usize;
+    let key_start = u32_at(data, 20) as usize;
+    let mut font = data.get(start..)?.to_vec();
+    if flags & 2 != 0 && key_len > 0 {
+        let key = slice_at(data, key_start, key_len)?;
+        for (i, b) in font.iter_mut().take(1040).enumerate() {
+            *b ^= key[i % key_len];
+        }
+    }
+    if flags & 1 != 0 {
+        let size = u32_at(data, 4);
+        let mut out = Vec::with_capacity((size as usize).min(data.len()));
+        // No more than the size the font declares
+        flate2::read::ZlibDecoder::new(font.as_slice())
+            .take(size.into())
+            .read_to_end(&mut out)
+            .ok()?;
+        font = out;
+    }
+    match font.get(0..4)? {
+        b"OTTO" => Some(("otf", "font/otf", font)),
+        _ => Some(("ttf", "font/ttf", font)),
+    }
+}
+
+/// Parses a base-32 number as used in `kindle:` URLs (digits `0-9A-V`).
+fn base32(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 32).ok()
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|w| w.eq_ignore_ascii_case(needle))
+        .map(|p| p + from)
+}
+
+pub fn convert(source: &Path, output: &Path) -> Result<()> {
+    let pdb = Pdb::parse(std::fs::read(source)?)?;
+    let record0 = pdb
+        .record(0)
+        .ok_or_else(|| invalid("missing header record"))?;
+    let mut header = Header::parse(record0)?;
+    let mut start = 0;
+    // Combined MOBI 6 + KF8 files carry a second header at the KF8 boundary
+    if let Some(boundary) = header.exth.kf8_boundary.filter(|&b| b != NULL_INDEX) {
+        if let Some(kf8) = pdb.record(boundary as usize).map(Header::parse) {
+            let mut kf8 = kf8?;
+            // Metadata lives in the first header when the KF8 one lacks it
+            if kf8.exth.title.is_none() && kf8.exth.authors.is_empty() {
+                kf8.exth = header.exth;
+            }
+            header = kf8;
+            start = boundary as usize;
+        }
+    }
+    if header.encryption != 0 {
+        return Err(Error::UnsupportedFormat("DRM-protected MOBI".into()));
+    }
+    let book = Book { pdb, header, start };
+
+    let mut epub = EpubBuilder::new(book.metadata());
+    if book.header.version >= 8 && book.header.skel_index != NULL_INDEX {
+        kf8::build(&book, &mut epub)?;
+    } else {
+        mobi6::build(&book, &mut epub)?;
+    }
+    epub.write(output)
+}
+
+fn nav_tree(index: &Index, target: impl Fn(&IndexEntry) -> Option<String>) -> Vec<NavPoint> {
+    // NCX entries are stored depth first with a depth tag (4) and parent index (21)
+    let mut points: Vec<(Option<u32>, NavPoint)> = Vec::new();
+    for entry in &index.entries {
+        let Some(href) = target(entry) else {
+            continue;
+        };
+        let label = entry
+            .tag(3, 0)
+            .and_then(|offset| index.cncx.get(&offset))
+            .cloned()
+            .unwrap_or_default();
+        points.push((
+            entry.tag(21, 0),
+            NavPoint {
+                label,
+                href,
+                children: Vec::new(),
+            },
+        ));
+    }
+    // Attach children to parents, walking backwards so indices stay valid
+    let mut roots = Vec::new();
+    let mut children: BTreeMap<usize, Vec<NavPoint>> = BTreeMap::new();
+    for (i, (parent, mut point)) in points.into_iter().enumerate().rev() {
+        if let Some(mut kids) = children.remove(&i) {
+            kids.reverse();
+            point.children = kids;
+        }
+        match parent {
+            Some(parent) if (parent as usize) < i => {
+                children.entry(parent as usize).or_default().push(point)
+            }
+            _ => roots.push(point),
+        }
+    }
+    roots.reverse();
+    roots
+}
+
+mod mobi6 {
+    use super::*;
+
+    /// Elements without content, written self-closed.
+    const VOID_ELEMENTS: &[&str] = &[
+        "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param",
+        "source", "track", "wbr",
+    ];
+    /// Elements that end an open paragraph, as in HTML.
+    const ENDS_PARAGRAPH: &[&str] = &[
+        "p",
+        "div",
+        "h1",
+        "h2",
+        "h3",
+        "h4",
+        "h5",
+        "h6",
+        "ul",
+        "ol",
+        "dl",
+        "table",
+        "blockquote",
+        "pre",
+        "hr",
+    ];
+
+    fn part_name(part: usize) -> String {
+        format!("part{part:04}.xhtml")
+    }
+
+    /// Parses the number following `attr=` at `pos`, with or without quotes.
+    fn attr_number(text: &[u8], pos: usize) -> Option<(usize, usize)> {
+        let mut i = pos;
+        while matches!(text.get(i), Some(b'"' | b'\'')) {
+            i += 1;
+        }
+        let digits_start = i;
+        while text.get(i).is_some_and(u8::is_ascii_digit) {
+            i += 1;
+        }
+        let value = std::str::from_utf8(&text[digits_start..i])
+            .ok()?
+            .parse()
+            .ok()?;
+        while matches!(text.get(i), Some(b'"' | b'\'')) {
+            i += 1;
+        }
+        Some((value, i))
+    }
+
+    /// Positions of every `attr=` occurrence along with the parsed value.
+    fn attr_values(text: &[u8], attr: &[u8]) -> Vec<(usize, usize, usize)> {
+        let mut found = Vec::new();
+        let mut from = 0;
+        while let Some(pos) = find_bytes(text, attr, from) {
+            from = pos + attr.len();
+            if let Some((value, end)) = attr_number(text, from) {
+                found.push((pos, end, value));
+            }
+        }
+        found
+    }
+
+    pub fn build(book: &Book, epub: &mut EpubBuilder) -> Result<()> {
+        let text = book.text()?;
+        let ncx = book.index(book.header.ncx_index);
+
+        let mut targets = attr_values(&text, b"filepos=")
+            .into_iter()
+            .map(|(_, _, value)| value)
+            .collect::<BTreeSet<_>>();
+        if let Some(ncx) = &ncx {
+            targets.extend(
+                ncx.entries
+                    .iter()
+                    .filter_map(|e| e.tag(1, 0))
+                    .map(|v| v as usize),
+            );
+        }
+        let mut breaks = Vec::new();
+        let mut from = 0;
+        while let Some(pos) = find_bytes(&text, b"<mbp:pagebreak", from) {
+            breaks.push(pos);
+            from = pos + 1;
+        }
+        let part_of = |offset: usize| breaks.partition_point(|&b| b <= offset);
+
+        // Split at page breaks, inserting anchors for link targets as we go
+        let mut events = breaks
+            .iter()
+            .map(|&b| (b, None))
+            .chain(
+                targets
+                    .iter()
+                    .filter(|&&t| t < text.len())
+                    .map(|&t| (t, Some(t))),
+            )
+            .collect::<Vec<_>>();
+        events.sort();
+        let mut parts = vec![Vec::new()];
+        let mut pos = 0;
+        for (offset, target) in events {
+            let offset = offset.max(pos);
+            parts
+                .last_mut()
+                .unwrap()
+                .extend_from_slice(&text[pos..offset]);
+            pos = offset;
+            match target {
+                Some(target) => {
+                    let anchor = format!("<a id=\"filepos{target}\"></a>");
+                    let part = parts.last_mut().unwrap();
+                    // Never split a tag: move the anchor in front of it
+                    let open = part.iter().rposition(|&b| b == b'<');
+                    let close = part.iter().rposition(|&b| b == b'>');
+                    match open {
+                        Some(open) if close.map_or(true, |close| close < open) => {
+                            part.splice(open..open, anchor.bytes());
+                        }
+                        _ => part.extend_from_slice(anchor.as_bytes()),
+                    }
+                }
+                None => {
+                    pos = find_bytes(&text, b">", offset).map_or(text.len(), |end| end + 1);
+                    parts.push(Vec::new());
+                }
+            }
+        }
+        parts
+            .last_mut()
+            .unwrap()
+            .extend_from_slice(&text[pos.min(text.len())..]);
+
+        let mut resources = BTreeSet::new();
+        book.add_cover(epub, &mut resources);
+        let mut names = HashMap::new();
+        let count = parts.len();
+        for (i, part) in parts.into_iter().enumerate() {
+            let part = rewrite_links(&part, &part_of, book, &mut resources, &mut names);
+            let mut html = decode(&part, book.header.encoding);
+            if i == 0 {
+                if let Some(body) = html
+                    .find("<body")
+                    .and_then(|p| html[p..].find('>').map(|e| p + e + 1))
+                {
+                    // Keep anchors for targets that fell inside the stripped head
+                    let anchors = html[..body]
+                        .match_indices("<a id=\"filepos")
+                        .filter_map(|(p, _)| html[p..].find("</a>").map(|e| &html[p..p + e + 4]))
+                        .collect::<String>();
+                    html.replace_range(..body, &anchors);
+                }
+            }
+            if i + 1 == count {
+                if let Some(end) = html.rfind("</body>") {
+                    html.truncate(end);
+                }
+            }
+            let document = format!(
+                "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<!DOCTYPE html>\n\
+                 <html xmlns=\"http://www.w3.org/1999/xhtml\"><head><title>{}</title></head>\n\
+                 <body>{}</body></html>\n",
+                escape_xml(&epub.metadata.title),
+                to_xhtml(&html)
+            );
+            epub.add_chapter(
+                &part_name(i),
+                "application/xhtml+xml",
+                document.into_bytes(),
+            );
+        }
+        for (index, (name, media_type, data)) in resources
+            .iter()
+            .filter_map(|&i| Some((i, book.resource(i)?)))
+        {
+            if Some(index) != book.header.exth.cover_offset {
+                epub.add_resource(&name, media_type, data);
+            }
+        }
+
+        if let Some(ncx) = &ncx {
+            epub.toc = nav_tree(ncx, |entry| {
+                let offset = entry.tag(1, 0)? as usize;
+                Some(format!("{}#filepos{offset}", part_name(part_of(offset))))
+            });
+        }
+        Ok(())
+    }
+
+    /// Text or an attribute value as XHTML takes it: entities decoded,
+    /// characters XML cannot hold dropped and markup escaped.
+    fn xml_text(text: &str) -> String {
+        let text = decode_entities(text)
+            .chars()
+            .filter(|&c| {
+                !matches!(c, '\0'..='\u{8}' | '\u{b}' | '\u{c}' | '\u{e}'..='\u{1f}')
+                    && !matches!(c, '\u{fffe}' | '\u{ffff}')
+            })
+            .collect::<String>();
+        escape_xml(&text)
+    }
+
+    /// Where the tag at the start of `html` ends, or `None` if another one
+    /// starts first, which makes its `<` text.
+    fn tag_end(html: &str) -> Option<usize> {
+        let mut quote = None;
+        for (i, c) in html.char_indices().skip(1) {
+            match (quote, c) {
+                (_, '<') => return None,
+                (None, '"' | '\'') => quote = Some(c),
+                (Some(q), c) if c == q => quote = None,
+                (None, '>') => return Some(i),
+                _ => {}
+            }
+        }
+        None
+    }
+
+    fn is_name(name: &str) -> bool {
+        name.starts_with(|c: char| c.is_ascii_alphabetic())
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    }
+
+    /// Writes the attributes in `attributes`, the rest of a start tag after
+    /// its name, quoted and once each.
+    fn push_attributes(out: &mut String, attributes: &str) {
+        let mut seen = Vec::new();
+        let mut rest = attributes;
+        loop {
+            rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '/');
+            if rest.is_empty() {
+                break;
+            }
+            let name_end = rest
+                .find(|c: char| c.is_whitespace() || matches!(c, '=' | '/'))
+                .unwrap_or(rest.len());
+            if name_end == 0 {
+                // A stray `=`
+                rest = &rest[1..];
+                continue;
+            }
+            let name = rest[..name_end].to_ascii_lowercase();
+            rest = rest[name_end..].trim_start();
+            let value = match rest.strip_prefix('=').map(str::trim_start) {
+                Some(value) => match value.chars().next() {
+                    Some(quote @ ('"' | '\'')) => {
+                        let end = value[1..].find(quote).map_or(value.len(), |end| end + 1);
+                        rest = value.get(end + 1..).unwrap_or_default();
+                        &value[1..end]
+                    }
+                    _ => {
+                        let end = value.find(char::is_whitespace).unwrap_or(value.len());
+                        rest = &value[end..];
+                        &value[..end]
+                    }
+                },
+                // `<hr noshade>`
+                None => name.as_str(),
+            };
+            if is_name(&name) && !seen.contains(&name) {
+                out.push_str(&format!(" {name}=\"{}\"", xml_text(value)));
+                seen.push(name);
+            }
+        }
+    }
+
+    /// Makes MOBI markup, HTML as old browsers took it, well-formed XHTML:
+    /// names are lowercased and attributes quoted, entities and stray `<`
+    /// or `&` escaped, void elements closed, end tags without a start tag
+    /// dropped and elements left open closed at the end. Comments and tags
+    /// with a prefix, such as `mbp:pagebreak`, are left out.
+    pub(super) fn to_xhtml(html: &str) -> String {
+        let mut out = String::with_capacity(html.len() + html.len() / 8);
+        let mut open: Vec<String> = Vec::new();
+        let mut rest = html;
+        while let Some(start) = rest.find('<') {
+            out.push_str(&xml_text(&rest[..start]));
+            rest = &rest[start..];
+            if let Some(comment) = rest.strip_prefix("<!--") {
+                rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+                continue;
+            }
+            let is_tag = rest[1..]
+                .starts_with(|c: char| c.is_ascii_alphabetic() || matches!(c, '/' | '!' | '?'));
+            let Some(end) = tag_end(rest).filter(|_| is_tag) else {
+                out.push_str("&lt;");
+                rest = &rest[1..];
+                continue;
+            };
+            let tag = &rest[1..end];
+            rest = &rest[end + 1..];
+            if tag.starts_with(['!', '?']) {
+                continue;
+            }
+            let (closing, tag) = match tag.strip_prefix('/') {
+                Some(tag) => (true, tag),
+                None => (false, tag),
+            };
+            let name_end = tag
+                .find(|c: char| c.is_whitespace() || c == '/')
+                .unwrap_or(tag.len());
+            let name = tag[..name_end].to_ascii_lowercase();
+            // The document around the parts is written separately
+            if !is_name(&name) || matches!(name.as_str(), "html" | "head" | "body") {
+                continue;
+            }
+            if closing {
+                if let Some(at) = open.iter().rposition(|open| *open == name) {
+                    for name in open.drain(at..).rev() {
+                        out.push_str(&format!("</{name}>"));
+                    }
+                }
+                continue;
+            }
+            if ENDS_PARAGRAPH.contains(&name.as_str()) && open.last().is_some_and(|p| p == "p") {
+                open.pop();
+                out.push_str("</p>");
+            }
+            out.push('<');
+            out.push_str(&name);
+            push_attributes(&mut out, &tag[name_end..]);
+            if VOID_ELEMENTS.contains(&name.as_str()) || tag.trim_end().ends_with('/') {
+                out.push_str("/>");
+            } else {
+                out.push('>');
+                open.push(name);
+            }
+        }
+        out.push_str(&xml_text(rest));
+        for name in open.into_iter().rev() {
+            out.push_str(&format!("</{name}>"));
+        }
+        out
+    }
+
+    /// Rewrites `filepos` links and `recindex` image references in one part.
+    fn rewrite_links(
+        part: &[u8],
+        part_of: &impl Fn(usize) -> usize,
+        book: &Book,
+        resources: &mut BTreeSet<u32>,
+        names: &mut HashMap<u32, String>,
+    ) -> Vec<u8> {
+        let mut edits = attr_values(part, b"filepos=")
+            .into_iter()
+            .map(|(start, end, value)| {
+                (
+                    start,
+                    end,
+                    format!("href=\"{}#filepos{value}\"", part_name(part_of(value))),
+                )
+            })
+            .collect::<Vec<_>>();
+        for (start, end, value) in attr_values(part, b" recindex=") {
+            // recindex is 1-based from the first resource record
+            let index = (value as u32).saturating_sub(1);
+            let name = names.entry(index).or_insert_with(|| {
+                book.resource(index)
+                    .map(|(name, ..)| name)
+                    .unwrap_or_default()
+            });
+            if !name.is_empty() {
+                resources.insert(index);
+                edits.push((start, end, format!(" src=\"{name}\"")));
+            }
+        }
+        edits.sort_by_key(|(start, ..)| *start);
+
+        let mut out = Vec::with_capacity(part.len());
+        let mut pos = 0;
+        for (start, end, replacement) in edits {
+            if start < pos {
+                continue;
+            }
+            out.extend_from_slice(&part[pos..start]);
+            out.extend_from_slice(replacement.as_bytes());
+            pos = end;
+        }
+        out.extend_from_slice(&part[pos..]);
+        out
+    }
+}
+
+mod kf8 {
+    use super::*;
+
+    struct Fragment {
+        insert_offset: usize,
+        length: usize,
+    }
+
+    struct Assembled {
+        sections: Vec<Section>,
+        /// Section index and insert offset of each fragment, for resolving
+        /// `kindle:pos:fid` links.
+        fragments: Vec<(usize, usize)>,
+    }
+
+    struct Section {
+        /// Start of the section in the assembled text.
+        offset: usize,
+        text: Vec<u8>,
+    }
+
+    fn part_name(section: usize) -> String {
+        format!("part{section:04}.xhtml")
+    }
+
+    /// Splits the raw text into flows using the FDST record. Flow 0 is the
+    /// markup; the others hold stylesheets and SVG images.
+    fn flows(book: &Book, text: &[u8]) -> Vec<(usize, usize)> {
+        let fdst = book
+            .record(book.header.fdst_index)
+            .filter(|r| r.starts_with(b"FDST"));
+        let Some(fdst) = fdst else {
+            return vec![(0, text.len())];
+        };
+        let count = (u32_at(fdst, 8) as usize).min(fdst.len().saturating_sub(12) / 8);
+        (0..count)
+            .map(|i| {
+                let start = (u32_at(fdst, 12 + i * 8) as usize).min(text.len());
+                let end = (u32_at(fdst, 16 + i * 8) as usize).min(text.len());
+                (start, end.max(start))
+            })
+            .collect()
+    }
+
+    fn assemble(book: &Book, markup: &[u8]) -> Result<Assembled> {
+        let skeletons = book
+            .index(book.header.skel_index)
+            .ok_or_else(|| invalid("missing KF8 skeleton index"))?;
+        let fragments = book
+            .index(book.header.frag_index)
+            .ok_or_else(|| invalid("missing KF8 fragment index"))?;
+        let fragments = fragments
+            .entries
+            .iter()
+            .map(|entry| Fragment {
+                insert_offset: entry.name.parse().unwrap_or_default(),
+                length: entry.tag(6, 1).unwrap_or_default() as usize,
+            })
+            .collect::<Vec<_>>();
+
+        let mut sections = Vec::new();
+        let mut fragment_sections = Vec::with_capacity(fragments.len());
+        let mut next_fragment = 0;
+        for (i, skeleton) in skeletons.entries.iter().enumerate() {
+            let count = skeleton.tag(1, 0).unwrap_or_default() as usize;
+            let offset = skeleton.tag(6, 0).unwrap_or_default() as usize;
+            let length = skeleton.tag(6, 1).unwrap_or_default() as usize;
+            let mut text = slice_at(markup, offset, length)
+                .unwrap_or_default()
+                .to_vec();
+            let mut base = offset.saturating_add(length);
+            for fragment in fragments.iter().skip(next_fragment).take(count) {
+                let data = slice_at(markup, base, fragment.length).unwrap_or_default();
+                let at = fragment
+                    .insert_offset
+                    .saturating_sub(offset)
+                    .min(text.len());
+                text.splice(at..at, data.iter().copied());
+                base = base.saturating_add(fragment.length);
+                fragment_sections.push((i, fragment.insert_offset));
+            }
+            next_fragment = next_fragment.saturating_add(count);
+            sections.push(Section { offset, text });
+        }
+        Ok(Assembled {
+            sections,
+            fragments: fragment_sections,
+        })
+    }
+
+    /// Finds the closest `id` attribute at or before `offset` in `text`.
+    fn id_before(text: &[u8], offset: usize) -> Option<String> {
+        let mut end = offset.min(text.len());
+        while let Some(open) = text[..end].iter().rposition(|&b| b == b'<') {
+            let close = find_bytes(text, b">", open).unwrap_or(text.len());
+            let tag = &text[open..close];
+            if let Some(pos) = find_bytes(tag, b" id=", 0) {
+                let value = &tag[pos + 4..];
+                let quote = *value.first()?;
+                if quote == b'"' || quote == b'\'' {
+                    let len = value[1..].iter().position(|&b| b == quote)?;
+                    return Some(String::from_utf8_lossy(&value[1..1 + len]).into_owned());
+                }
+            }
+            end = open;
+        }
+        None
+    }
+
+    pub fn build(book: &Book, epub: &mut EpubBuilder) -> Result<()> {
+        let text = book.text()?;
+        let flows = flows(book, &text);
+        let (markup_start, markup_end) = flows.first().copied().unwrap_or((0, text.len()));
+        let Assembled {
+            sections,
+            fragments,
+        } = assemble(book, &text[markup_start..markup_end])?;
+
+        let resolve_position = |fid: u32, off: u32| -> Option<String> {
+            let (section, insert_offset) = *fragments.get(fid as usize)?;
+            let local = insert_offset
+                .saturating_add(off as usize)
+                .saturating_sub(sections[section].offset);
+            let name = part_name(section);
+            Some(match id_before(&sections[section].text, local) {
+                Some(id) => format!("{name}#{id}"),
+                None => name,
+            })
+        };
+
+        let mut resources = BTreeSet::new();
+        book.add_cover(epub, &mut resources);
+        let mut used_flows = BTreeMap::new();
+        for (i, section) in sections.iter().enumerate() {
+            let html = String::from_utf8_lossy(&section.text);
+            let html = rewrite_urls(
+                &html,
+                &mut resources,
+                &mut used_flows,
+                &resolve_position,
+                book,
+            );
+            epub.add_chapter(&part_name(i), "application/xhtml+xml", html.into_bytes());
+        }
+
+        // Stylesheets can reference fonts and images too
+        let mut pending = used_flows.into_iter().collect::<Vec<_>>();
+        let mut written = BTreeSet::new();
+        while let Some((flow, svg)) = pending.pop() {
+            if !written.insert(flow) {
+                continue;
+            }
+            let Some(&(start, end)) = flows.get(flow as usize) else {
+                continue;
+            };
+            let content = String::from_utf8_lossy(&text[start..end]);
+            let mut nested = BTreeMap::new();
+            let content = rewrite_urls(
+                &content,
+                &mut resources,
+                &mut nested,
+                &resolve_position,
+                book,
+            );
+            pending.extend(nested);
+            let media_type = if svg { "image/svg+xml" } else { "text/css" };
+            epub.add_resource(&flow_name(flow, svg), media_type, content.into_bytes());
+        }
+        for (index, (name, media_type, data)) in resources
+            .iter()
+            .filter_map(|&i| Some((i, book.resource(i)?)))
+        {
+            if Some(index) != book.header.exth.cover_offset {
+                epub.add_resource(&name, media_type, data);
+            }
+        }
+
+        if let Some(ncx) = book.index(book.header.ncx_index) {
+            epub.toc = nav_tree(&ncx, |entry| match (entry.tag(6, 0), entry.tag(6, 1)) {
+                (Some(fid), Some(off)) => resolve_position(fid, off),
+                _ => {
+                    let offset = entry.tag(1, 0)? as usize;
+                    let section = sections.iter().rposition(|s| s.offset <= offset)?;
+                    Some(part_name(section))
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn flow_name(flow: u32, svg: bool) -> String {
+        let ext = if svg { "svg" } else { "css" };
+        format!("flow{flow:04}.{ext}")
+    }
+
+    /// Replaces `kindle:embed`, `kindle:flow` and `kindle:pos` URLs with
+    /// relative hrefs, recording the resources and flows they reference.
+    fn rewrite_urls(
+        text: &str,
+        resources: &mut BTreeSet<u32>,
+        flows: &mut BTreeMap<u32, bool>,
+        resolve_position: &impl Fn(u32, u32) -> Option<String>,
+        book: &Book,
+    ) -> String {
+        let mut out = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(start) = rest.find("kindle:") {
+            out.push_str(&rest[..start]);
+            let url_end = rest[start..]
+                .find(|c: char| matches!(c, '"' | '\'' | ')' | '>') || c.is_whitespace())
+                .map_or(rest.len(), |end| start + end);
+            let url = &rest[start..url_end];
+            let path = url.split('?').next().unwrap_or(url);
+            let parts = path.split(':').collect::<Vec<_>>();
+            let replacement = match parts.as_slice() {
+                ["kindle", "embed", id] => base32(id).and_then(|n| {
+                    let index = n.checked_sub(1)?;
+                    let (name, ..) = book.resource(index)?;
+                    resources.insert(index);
+                    Some(name)
+                }),
+                ["kindle", "flow", id] => base32(id).map(|flow| {
+                    let svg = url.split("mime=").nth(1).is_some_and(|m| m.contains("svg"));
+                    flows.insert(flow, svg);
+                    flow_name(flow, svg)
+                }),
+                ["kindle", "pos", "fid", fid, "off", off] => base32(fid)
+                    .zip(base32(off))
+                    .and_then(|(fid, off)| resolve_position(fid, off)),
+                _ => None,
+            };
+            out.push_str(replacement.as_deref().unwrap_or(url));
+            rest = &rest[url_end..];
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A Palm database holding `records`.
+    fn pdb(records: &[&[u8]]) -> Vec<u8> {
+        let mut data = vec![0; 76];
+        data.extend_from_slice(&(records.len() as u16).to_be_bytes());
+        let mut offset = data.len() + records.len() * 8;
+        for record in records {
+            data.extend_from_slice(&(offset as u32).to_be_bytes());
+            data.extend_from_slice(&[0; 4]);
+            offset += record.len();
+        }
+        for record in records {
+            data.extend_from_slice(record);
+        }
+        data
+    }
+
+    /// A first record with a MOBI header of `header_len` bytes and
+    /// `extra` after it.
+    fn header_record(header_len: u32, extra: &[u8]) -> Vec<u8> {
+        let mut record = vec![0; 16 + header_len as usize];
+        record[16..20].copy_from_slice(b"MOBI");
+        record[20..24].copy_from_slice(&header_len.to_be_bytes());
+        record[28..32].copy_from_slice(&UTF8_ENCODING.to_be_bytes());
+        record.extend_from_slice(extra);
+        record
+    }
+
+    #[test]
+    fn rejects_truncated_databases() {
+        assert!(Pdb::parse(vec![0; 20]).is_err());
+        // More records than there are offsets for
+        let mut data = vec![0; 100];
+        data[76..78].copy_from_slice(&1000u16.to_be_bytes());
+        assert!(Pdb::parse(data).is_err());
+        let mut data = pdb(&[b"record"]);
+        data[78..82].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(Pdb::parse(data).is_err());
+    }
+
+    #[test]
+    fn header_fields_out_of_range_are_ignored() {
+        assert!(Header::parse(b"too short").is_err());
+        let mut record = header_record(0xE8, &[]);
+        record[84..88].copy_from_slice(&0xFFFF_FFF0u32.to_be_bytes());
+        record[88..92].copy_from_slice(&0x100u32.to_be_bytes());
+        // An EXTH block claiming more records than it holds
+        record[128..132].copy_from_slice(&0x40u32.to_be_bytes());
+        record.extend_from_slice(b"EXTH\0\0\0\x0c\xff\xff\xff\xff");
+        let header = Header::parse(&record).unwrap();
+        assert_eq!(header.title, "");
+        assert!(header.exth.authors.is_empty());
+    }
+
+    #[test]
+    fn exth_stops_at_a_record_past_its_end() {
+        let mut data = b"EXTH\0\0\0\0\xff\xff\xff\xff".to_vec();
+        data.extend_from_slice(b"\0\0\0\x64\0\0\0\x0bAnn");
+        data.extend_from_slice(b"\0\0\0\x65\x7f\xff\xff\xff");
+        let exth = Exth::parse(&data, UTF8_ENCODING);
+        assert_eq!(exth.authors, ["Ann"]);
+        assert_eq!(exth.publisher, None);
+    }
+
+    #[test]
+    fn palmdoc_skips_bad_back_references() {
+        let mut out = Vec::new();
+        palmdoc_decompress(&[b'a', b'b', 0x80, 0x10, 0xc1, 3, b'z'], &mut out);
+        assert_eq!(out, b"ababa Az");
+        // A distance before the start, then a pair cut short
+        let mut out = Vec::new();
+        palmdoc_decompress(&[0x80, 0x10, 0x80], &mut out);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn trailing_entries_larger_than_the_record() {
+        assert_eq!(trim_trailing_entries(b"helloX\x82", 2), b"hello");
+        assert!(trim_trailing_entries(&[0x85], 0xFFFF).is_empty());
+        assert_eq!(varlen(&[0x01, 0x81], 0), (129, 2));
+        assert_eq!(varlen(&[], 5), (0, 1));
+    }
+
+    #[test]
+    fn index_counts_are_bounded_by_the_records() {
+        let mut indx = vec![0; 56];
+        indx[0..4].copy_from_slice(b"INDX");
+        indx[4..8].copy_from_slice(&56u32.to_be_bytes());
+        indx[24..28].copy_from_slice(&u32::MAX.to_be_bytes());
+        indx[52..56].copy_from_slice(&u32::MAX.to_be_bytes());
+        indx.extend_from_slice(b"TAGX\0\0\0\x0c\0\0\0\x01");
+        let record0 = header_record(0xE8, &[]);
+        let book = Book {
+            pdb: Pdb::parse(pdb(&[&record0, &indx])).unwrap(),
+            header: Header::parse(&record0).unwrap(),
+            start: 0,
+        };
+        assert!(book.index(1).unwrap().entries.is_empty());
+        assert!(book.index(0).is_none());
+        assert!(book.index(NULL_INDEX).is_none());
+        assert!(book.index(7).is_none());
+    }
+
+    #[test]
+    fn fonts_with_keys_out_of_range_are_dropped() {
+        let mut font = b"FONT".to_vec();
+        font.extend_from_slice(&[0, 0, 0, 4, 0, 0, 0, 2, 0, 0, 0, 24]);
+        font.extend_from_slice(&[0, 0, 0, 16, 0, 0, 1, 0]);
+        font.extend_from_slice(b"OTTO");
+        assert!(decode_font(&font).is_none());
+    }
+
+    #[test]
+    fn garbage_fails_to_convert() {
+        let dir = std::env::temp_dir().join(format!("vlarch-mobi-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("garbage.mobi");
+        std::fs::write(&source, pdb(&[b"not a header", b"text"])).unwrap();
+        assert!(convert(&source, &dir.join("garbage.epub")).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn mobi6_markup_becomes_xhtml() {
+        assert_eq!(
+            mobi6::to_xhtml("<P ALIGN=center>One<p>Two<br>x < y & z</b>"),
+            "<p align=\"center\">One</p><p>Two<br/>x &lt; y &amp; z</p>"
+        );
+        assert_eq!(
+            mobi6::to_xhtml("a<!-- c --><mbp:pagebreak/><i>b"),
+            "a<i>b</i>"
+        );
+        assert_eq!(
+            mobi6::to_xhtml("<img src='a.jpg' src=\"b\" alt>"),
+            "<img src=\"a.jpg\" alt=\"alt\"/>"
+        );
+        assert_eq!(mobi6::to_xhtml("1 <b"), "1 &lt;b");
+    }
+
+    #[test]
+    fn mobi6_tag_soup_parses_as_xml() {
+        let soup = "<html><body><p>A<table><tr><td>1<td>2</table><font size=+1>&nbsp;\
+                    <a href=\"#x\" onclick='f(\"<\")'>b<div\x01>c</i></p></html><unclosed";
+        let xhtml = format!("<body>{}</body>", mobi6::to_xhtml(soup));
+        assert!(roxmltree::Document::parse(&xhtml).is_ok(), "{xhtml}");
+    }
+}
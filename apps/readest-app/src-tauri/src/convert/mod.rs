@@ -0,0 +1,142 @@
+//! Conversion of formats the reader cannot open well into EPUB. Converted
+//! books are cached under the app cache dir, keyed by the source file.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use tauri::{command, AppHandle};
+
+use crate::error::{Error, Result};
+use crate::{jobs, paths, plugins};
+
+pub mod epub;
+pub mod mobi;
+pub mod transcode;
+
+const CACHE_DIR: &str = "converted";
+
+/// Extensions of the formats that are converted before opening.
+const CONVERTIBLE_EXTENSIONS: &[&str] = &["mobi", "azw", "azw3"];
+
+pub fn is_convertible(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| {
+            CONVERTIBLE_EXTENSIONS
+                .iter()
+                .any(|known| known.eq_ignore_ascii_case(ext))
+        })
+}
+
+/// Location of the converted copy of `source` with extension `ext`, which
+/// changes whenever the source file or `variant` does.
+fn cache_path(app: &AppHandle, source: &Path, variant: Option<&str>, ext: &str) -> Result<PathBuf> {
+    let meta = std::fs::metadata(source)?;
+    let mut hasher = DefaultHasher::new();
+    source.hash(&mut hasher);
+    meta.len().hash(&mut hasher);
+    meta.modified().ok().hash(&mut hasher);
+    if let Some(variant) = variant {
+        variant.hash(&mut hasher);
+    }
+    let stem = source
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "book".into());
+    Ok(paths::cache_dir(app)?
+        .join(CACHE_DIR)
+        .join(format!("{:016x}", hasher.finish()))
+        .join(format!("{stem}.{ext}")))
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConvertedBook {
+    pub path: PathBuf,
+    /// Whether a previously converted copy was reused.
+    pub cached: bool,
+}
+
+/// Whether `source` is converted before opening, natively or by a plugin.
+pub fn needs_conversion(app: &AppHandle, source: &Path) -> bool {
+    is_convertible(source) || plugins::decoder(app, source).is_some()
+}
+
+/// Converts `source` to EPUB, reusing the cached copy when it is up to date.
+pub fn convert_to_epub(app: &AppHandle, source: &Path) -> Result<ConvertedBook> {
+    let decoder = match is_convertible(source) {
+        true => None,
+        false => match plugins::decoder(app, source) {
+            Some(decoder) => Some(decoder),
+            None => {
+                let ext = source.extension().unwrap_or_default().to_string_lossy();
+                return Err(Error::UnsupportedFormat(ext.into_owned()));
+            }
+        },
+    };
+    let version = decoder.as_ref().map(plugins::Decoder::version);
+    let path = cache_path(app, source, version.as_deref(), "epub")?;
+    if path.is_file() {
+        return Ok(ConvertedBook { path, cached: true });
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    match decoder {
+        Some(decoder) => decoder.decode(source, &path)?,
+        None => mobi::convert(source, &path)?,
+    }
+    Ok(ConvertedBook {
+        path,
+        cached: false,
+    })
+}
+
+/// Swaps convertible files opened from the OS for their EPUB conversions,
+/// and text files in other encodings for their UTF-8 copies, keeping the
+/// original when that fails.
+#[cfg(desktop)]
+pub fn prepare_open_files(app: &AppHandle, files: Vec<PathBuf>) -> Vec<PathBuf> {
+    files
+        .into_iter()
+        .map(|file| {
+            let prepared = if needs_conversion(app, &file) {
+                convert_to_epub(app, &file).map(|converted| Some(converted.path))
+            } else if transcode::is_text_file(&file) {
+                transcode::transcode(app, &file)
+            } else {
+                return file;
+            };
+            match prepared {
+                Ok(Some(path)) => {
+                    crate::allow_file_in_scopes(app, vec![path.clone()]);
+                    path
+                }
+                Ok(None) => file,
+                Err(e) => {
+                    log::warn!("Failed to prepare {file:?}: {e}");
+                    file
+                }
+            }
+        })
+        .collect()
+}
+
+/// Converts the book at `path` as a `convert` job. Cancelling returns at
+/// once, while the conversion finishes into the cache.
+#[command]
+pub async fn convert_book_to_epub(app: AppHandle, path: PathBuf) -> Result<ConvertedBook> {
+    let handle = app.clone();
+    let converted = jobs::run(&app, "convert", |job| async move {
+        let name = path.file_name().map(|n| n.to_string_lossy().into_owned());
+        job.phase("converting", name);
+        job.run_blocking(move || convert_to_epub(&handle, &path))
+            .await
+    })
+    .await?;
+    #[cfg(desktop)]
+    crate::allow_file_in_scopes(&app, vec![converted.path.clone()]);
+    Ok(converted)
+}
@@ -0,0 +1,143 @@
+//! UTF-8 copies of text and HTML books stored in other encodings, made
+//! when they are opened since the webview reads local files as UTF-8
+//! unless told otherwise. The copies are cached like EPUB conversions, and
+//! the encoding of a book can be overridden when the guess is wrong.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use encoding_rs::Encoding;
+use regex::Regex;
+use serde::Serialize;
+use tauri::{command, AppHandle};
+
+use crate::error::Result;
+use crate::formats::{self, charset};
+use crate::store;
+
+const ENCODINGS_FILE: &str = "book-encodings.json";
+
+/// Extensions of the files that are transcoded before opening.
+const TEXT_EXTENSIONS: &[&str] = &["txt", "md", "html", "htm", "xhtml"];
+
+pub fn is_text_file(path: &Path) -> bool {
+    let ext = formats::extension(path);
+    TEXT_EXTENSIONS.iter().any(|known| *known == ext)
+}
+
+fn is_html(path: &Path) -> bool {
+    matches!(formats::extension(path).as_str(), "html" | "htm" | "xhtml")
+}
+
+fn encoding_key(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+/// The encoding label set for `path` by the user, if any.
+pub fn override_for(app: &AppHandle, path: &Path) -> Option<String> {
+    let overrides: BTreeMap<String, String> = store::load(app, ENCODINGS_FILE);
+    overrides.get(&encoding_key(path)).cloned()
+}
+
+/// The encoding `path` is read as, and whether the user chose it: its byte
+/// order mark, then the override, then an HTML declaration, then the guess.
+fn encoding_of(app: &AppHandle, path: &Path, bytes: &[u8]) -> Result<(&'static Encoding, bool)> {
+    if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+        return Ok((encoding, false));
+    }
+    if let Some(label) = override_for(app, path) {
+        return Ok((charset::for_label(&label)?, true));
+    }
+    let declared = is_html(path).then(|| charset::declared(bytes)).flatten();
+    Ok((declared.unwrap_or_else(|| charset::detect(bytes)), false))
+}
+
+/// Rewrites the charset declarations at the top of `html` to UTF-8.
+fn declare_utf8(html: &str) -> String {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    let pattern = PATTERN.get_or_init(|| {
+        Regex::new(r#"(?i)(<meta[^>]*charset\s*=\s*["']?|<\?xml[^>]*encoding\s*=\s*["'])[\w:.-]+"#)
+            .expect("valid declaration pattern")
+    });
+    pattern.replacen(html, 2, "${1}utf-8").into_owned()
+}
+
+/// Writes a UTF-8 copy of `source`, reusing the cached one when it is up
+/// to date, and returns its path. Returns `None` for files that already
+/// are UTF-8.
+pub fn transcode(app: &AppHandle, source: &Path) -> Result<Option<PathBuf>> {
+    let bytes = std::fs::read(source)?;
+    let (encoding, _) = encoding_of(app, source, &bytes)?;
+    if encoding == encoding_rs::UTF_8 {
+        return Ok(None);
+    }
+    let ext = formats::extension(source);
+    let path = super::cache_path(app, source, Some(encoding.name()), &ext)?;
+    if path.is_file() {
+        return Ok(Some(path));
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let (text, _, _) = encoding.decode(&bytes);
+    let text = if is_html(source) {
+        declare_utf8(&text)
+    } else {
+        text.into_owned()
+    };
+    std::fs::write(&path, text)?;
+    log::info!("Transcoded {source:?} from {}", encoding.name());
+    Ok(Some(path))
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BookEncoding {
+    /// Name of the encoding, such as `Shift_JIS`.
+    pub encoding: String,
+    /// Whether it was set by the user rather than detected.
+    pub overridden: bool,
+}
+
+#[command]
+pub async fn get_book_encoding(app: AppHandle, path: PathBuf) -> Result<BookEncoding> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let bytes = std::fs::read(&path)?;
+        let (encoding, overridden) = encoding_of(&app, &path, &bytes)?;
+        Ok(BookEncoding {
+            encoding: encoding.name().to_string(),
+            overridden,
+        })
+    })
+    .await?
+}
+
+/// Reads `path` as `encoding` from now on, or as the detected encoding
+/// when it is `None`, and returns the file to open the book from.
+#[command]
+pub async fn set_book_encoding(
+    app: AppHandle,
+    path: PathBuf,
+    encoding: Option<String>,
+) -> Result<PathBuf> {
+    let handle = app.clone();
+    let opened = tauri::async_runtime::spawn_blocking(move || {
+        let mut overrides: BTreeMap<String, String> = store::load(&handle, ENCODINGS_FILE);
+        match encoding {
+            Some(label) => {
+                let encoding = charset::for_label(&label)?;
+                overrides.insert(encoding_key(&path), encoding.name().to_string());
+            }
+            None => {
+                overrides.remove(&encoding_key(&path));
+            }
+        }
+        store::save(&handle, ENCODINGS_FILE, &overrides)?;
+        Ok::<_, crate::error::Error>(transcode(&handle, &path)?.unwrap_or(path))
+    })
+    .await??;
+    #[cfg(desktop)]
+    crate::allow_file_in_scopes(&app, vec![opened.clone()]);
+    Ok(opened)
+}
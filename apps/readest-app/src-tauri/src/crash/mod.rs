@@ -0,0 +1,269 @@
+//! Crash reports, kept under `crashes/` in the app data directory: a
+//! backtrace for each panic of the backend, and a minidump for each native
+//! crash, written by the [`monitor`] process. Reports stay on the device
+//! unless the user agrees to send them, either each new one on the next
+//! launch or one at a time from the list. Uploads go to the endpoint the
+//! build was made with, `VLARCH_CRASH_UPLOAD_URL`; builds without one
+//! only keep reports locally.
+
+use std::backtrace::Backtrace;
+use std::path::{Path, PathBuf};
+
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use reqwest::multipart::{Form, Part};
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle};
+
+use crate::error::{Error, Result};
+use crate::net;
+use crate::paths;
+use crate::store;
+use crate::utils::{format_rfc3339, now_millis};
+
+pub mod monitor;
+
+const SETTINGS_FILE: &str = "crash-reporting.json";
+const REPORTS_DIR: &str = "crashes";
+const UPLOAD_URL: Option<&str> = option_env!("VLARCH_CRASH_UPLOAD_URL");
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CrashSettings {
+    /// Keep reports of crashes on the device. Takes effect on the next
+    /// launch.
+    pub capture: bool,
+    /// Send new reports on the next launch, which the user must have
+    /// agreed to.
+    pub upload: bool,
+}
+
+impl Default for CrashSettings {
+    fn default() -> Self {
+        Self {
+            capture: true,
+            upload: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CrashKind {
+    Panic,
+    Native,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CrashReport {
+    pub id: String,
+    pub kind: CrashKind,
+    pub created_at: String,
+    pub version: String,
+    pub os: String,
+    pub arch: String,
+    /// What panicked and where.
+    #[serde(default)]
+    pub message: Option<String>,
+    #[serde(default)]
+    pub backtrace: Option<String>,
+    /// File name of the minidump beside the report.
+    #[serde(default)]
+    pub minidump: Option<String>,
+    #[serde(default)]
+    pub uploaded: bool,
+}
+
+impl CrashReport {
+    fn new(kind: CrashKind) -> Self {
+        let suffix: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(8)
+            .map(char::from)
+            .collect();
+        let now = now_millis();
+        Self {
+            id: format!("{now}-{}", suffix.to_lowercase()),
+            kind,
+            created_at: format_rfc3339(now),
+            version: env!("CARGO_PKG_VERSION").into(),
+            os: std::env::consts::OS.into(),
+            arch: std::env::consts::ARCH.into(),
+            message: None,
+            backtrace: None,
+            minidump: None,
+            uploaded: false,
+        }
+    }
+
+    fn save(&self, dir: &Path) -> Result<()> {
+        std::fs::create_dir_all(dir)?;
+        let tmp = dir.join(format!("{}.tmp", self.id));
+        std::fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(&tmp, dir.join(format!("{}.json", self.id)))?;
+        Ok(())
+    }
+}
+
+fn reports_dir(app: &AppHandle) -> Result<PathBuf> {
+    Ok(paths::data_dir(app)?.join(REPORTS_DIR))
+}
+
+/// The stored reports, newest first.
+fn reports(dir: &Path) -> Vec<CrashReport> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut reports = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .filter_map(|path| serde_json::from_slice(&std::fs::read(&path).ok()?).ok())
+        .collect::<Vec<CrashReport>>();
+    reports.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+    reports
+}
+
+fn find_report(dir: &Path, id: &str) -> Result<CrashReport> {
+    reports(dir)
+        .into_iter()
+        .find(|report| report.id == id)
+        .ok_or_else(|| Error::CrashReport(format!("no crash report {id}")))
+}
+
+/// Writes a report with the backtrace of each panic, then panics as before.
+fn install_panic_hook(dir: PathBuf) {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let backtrace = Backtrace::force_capture();
+        let payload = info
+            .payload()
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| info.payload().downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "Box<dyn Any>".into());
+        let location = info
+            .location()
+            .map(|location| format!("{}:{}", location.file(), location.line()))
+            .unwrap_or_default();
+        let thread = std::thread::current()
+            .name()
+            .unwrap_or("<unnamed>")
+            .to_string();
+        let mut report = CrashReport::new(CrashKind::Panic);
+        report.message = Some(format!(
+            "thread '{thread}' panicked at {location}: {payload}"
+        ));
+        report.backtrace = Some(backtrace.to_string());
+        if let Err(e) = report.save(&dir) {
+            eprintln!("Failed to save the crash report: {e}");
+        }
+        previous(info);
+    }));
+}
+
+async fn upload(dir: &Path, report: &mut CrashReport) -> Result<()> {
+    let url = UPLOAD_URL
+        .ok_or_else(|| Error::CrashReport("this build cannot send crash reports".into()))?;
+    let mut form = Form::new().text("report", serde_json::to_string(&report)?);
+    if let Some(minidump) = &report.minidump {
+        let bytes = tokio::fs::read(dir.join(minidump)).await?;
+        // The field name crash servers take minidumps under
+        form = form.part(
+            "upload_file_minidump",
+            Part::bytes(bytes).file_name(minidump.clone()),
+        );
+    }
+    let response = net::client().post(url).multipart(form).send().await?;
+    if !response.status().is_success() {
+        return Err(Error::HttpStatus(response.status().as_u16()));
+    }
+    report.uploaded = true;
+    report.save(dir)
+}
+
+/// Sends the reports not sent yet, when the user agreed to it.
+async fn upload_pending(dir: PathBuf) {
+    for mut report in reports(&dir).into_iter().filter(|report| !report.uploaded) {
+        if let Err(e) = upload(&dir, &mut report).await {
+            log::warn!("Failed to send crash report {}: {e}", report.id);
+            return;
+        }
+    }
+}
+
+/// Starts keeping crash reports, and sends those left by earlier crashes
+/// when the user agreed to.
+pub fn init(app: &AppHandle) {
+    let settings: CrashSettings = store::load(app, SETTINGS_FILE);
+    let dir = match reports_dir(app) {
+        Ok(dir) => dir,
+        Err(e) => {
+            log::warn!("Failed to resolve the crash reports directory: {e}");
+            return;
+        }
+    };
+    if settings.capture {
+        install_panic_hook(dir.clone());
+        let dir = dir.clone();
+        // Waits for the monitor to come up
+        tauri::async_runtime::spawn_blocking(move || {
+            if let Err(e) = monitor::attach(&dir) {
+                log::warn!("Failed to start the crash monitor: {e}");
+            }
+        });
+    }
+    if settings.upload && UPLOAD_URL.is_some() {
+        tauri::async_runtime::spawn(upload_pending(dir));
+    }
+}
+
+#[command]
+pub fn get_crash_settings(app: AppHandle) -> CrashSettings {
+    store::load(&app, SETTINGS_FILE)
+}
+
+#[command]
+pub fn set_crash_settings(app: AppHandle, settings: CrashSettings) -> Result<()> {
+    store::save(&app, SETTINGS_FILE, &settings)
+}
+
+#[command]
+pub async fn list_crash_reports(app: AppHandle) -> Result<Vec<CrashReport>> {
+    let dir = reports_dir(&app)?;
+    tauri::async_runtime::spawn_blocking(move || Ok(reports(&dir))).await?
+}
+
+/// Deletes the report `id` with its minidump.
+#[command]
+pub async fn delete_crash_report(app: AppHandle, id: String) -> Result<()> {
+    let dir = reports_dir(&app)?;
+    tauri::async_runtime::spawn_blocking(move || {
+        let report = find_report(&dir, &id)?;
+        if let Some(minidump) = &report.minidump {
+            let _ = std::fs::remove_file(dir.join(minidump));
+        }
+        std::fs::remove_file(dir.join(format!("{id}.json")))?;
+        Ok(())
+    })
+    .await?
+}
+
+#[command]
+pub async fn clear_crash_reports(app: AppHandle) -> Result<()> {
+    let dir = reports_dir(&app)?;
+    match tokio::fs::remove_dir_all(&dir).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Sends the report `id`, which the user chose to.
+#[command]
+pub async fn upload_crash_report(app: AppHandle, id: String) -> Result<CrashReport> {
+    let dir = reports_dir(&app)?;
+    let mut report = find_report(&dir, &id)?;
+    upload(&dir, &mut report).await?;
+    Ok(report)
+}
@@ -0,0 +1,143 @@
+//! Minidumps of native crashes. A process that crashed cannot be trusted to
+//! write its own minidump, so the app starts a copy of itself as a monitor
+//! that the crash handler asks to write it. The monitor quits with the app.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::AtomicBool;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crash_handler::{make_crash_event, CrashContext, CrashEventResult, CrashHandler};
+use minidumper::{Client, LoopAction, MinidumpBinary, Server, ServerHandler};
+
+use super::{CrashKind, CrashReport};
+use crate::error::{Error, Result};
+
+/// First argument of the monitor process, followed by the socket name and
+/// the reports directory.
+const MONITOR_ARG: &str = "--crash-monitor";
+const CONNECT_ATTEMPTS: u32 = 50;
+const CONNECT_INTERVAL: Duration = Duration::from_millis(100);
+
+struct Handler {
+    dir: PathBuf,
+    /// Report of the minidump being written.
+    report: Mutex<Option<CrashReport>>,
+}
+
+impl ServerHandler for Handler {
+    fn create_minidump_file(&self) -> std::io::Result<(File, PathBuf)> {
+        let mut report = CrashReport::new(CrashKind::Native);
+        let name = format!("{}.dmp", report.id);
+        std::fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(&name);
+        let file = File::create(&path)?;
+        report.minidump = Some(name);
+        *self.report.lock().unwrap() = Some(report);
+        Ok((file, path))
+    }
+
+    fn on_minidump_created(
+        &self,
+        result: std::result::Result<MinidumpBinary, minidumper::Error>,
+    ) -> LoopAction {
+        let report = self.report.lock().unwrap().take();
+        match result {
+            Ok(mut binary) => {
+                let _ = binary.file.flush();
+                if let Some(report) = report {
+                    if let Err(e) = report.save(&self.dir) {
+                        eprintln!("Failed to save the crash report: {e}");
+                    }
+                }
+            }
+            Err(e) => {
+                eprintln!("Failed to write the minidump: {e}");
+                if let Some(name) = report.and_then(|report| report.minidump) {
+                    let _ = std::fs::remove_file(self.dir.join(name));
+                }
+            }
+        }
+        // The crashed app is gone
+        LoopAction::Exit
+    }
+
+    fn on_message(&self, _kind: u32, _buffer: Vec<u8>) {}
+
+    fn on_client_disconnected(&self, num_clients: usize) -> LoopAction {
+        if num_clients == 0 {
+            LoopAction::Exit
+        } else {
+            LoopAction::Continue
+        }
+    }
+}
+
+/// Runs the monitor when the process was started as one, returning its exit
+/// code.
+pub fn run() -> Option<i32> {
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() != Some(MONITOR_ARG) {
+        return None;
+    }
+    let (Some(socket), Some(dir)) = (args.next(), args.next()) else {
+        return Some(2);
+    };
+    let handler = Handler {
+        dir: PathBuf::from(dir),
+        report: Mutex::new(None),
+    };
+    let result = Server::with_name(socket.as_str())
+        .and_then(|mut server| server.run(Box::new(handler), &AtomicBool::new(false), None));
+    match result {
+        Ok(()) => Some(0),
+        Err(e) => {
+            eprintln!("Crash monitor failed: {e}");
+            Some(1)
+        }
+    }
+}
+
+/// Starts the monitor and has native crashes of the app written by it to
+/// `dir`.
+pub fn attach(dir: &Path) -> Result<()> {
+    let socket = format!("vlarch-crash-{}", std::process::id());
+    let mut monitor = Command::new(std::env::current_exe()?)
+        .arg(MONITOR_ARG)
+        .arg(&socket)
+        .arg(dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .spawn()?;
+
+    let mut client = None;
+    for _ in 0..CONNECT_ATTEMPTS {
+        if let Ok(connected) = Client::with_name(socket.as_str()) {
+            client = Some(connected);
+            break;
+        }
+        std::thread::sleep(CONNECT_INTERVAL);
+    }
+    let Some(client) = client else {
+        let _ = monitor.kill();
+        return Err(Error::CrashReport("the crash monitor did not start".into()));
+    };
+
+    let handler = CrashHandler::attach(unsafe {
+        make_crash_event(move |context: &CrashContext| {
+            CrashEventResult::Handled(client.request_dump(context).is_ok())
+        })
+    })
+    .map_err(|e| Error::CrashReport(e.to_string()))?;
+    // Only the monitor may inspect the app for its minidump
+    #[cfg(target_os = "linux")]
+    handler.set_ptracer(Some(monitor.id()));
+    #[cfg(not(target_os = "linux"))]
+    let _ = monitor;
+    // Crashes are handled for as long as the app runs
+    std::mem::forget(handler);
+    Ok(())
+}
@@ -0,0 +1,143 @@
+//! `vlarch://` links such as `vlarch://open?book=<hash>&cfi=<location>`,
+//! `vlarch://clip?url=<page>`, `vlarch://lookup?word=<word>`,
+//! `vlarch://import-folder` and `vlarch://add?source=<link or path>`.
+//! Links reach the app on the command line on Windows and Linux, both at
+//! launch and through the single-instance guard, and as `Opened` run events
+//! on macOS. Links that arrive before the frontend is listening are queued
+//! until it asks for them.
+
+use std::sync::Mutex;
+
+use serde::Serialize;
+use tauri::{command, AppHandle, Emitter, Manager, State, Url};
+
+/// Scheme registered for the app in `tauri.conf.json`.
+pub const SCHEME: &str = "vlarch";
+
+const EVENT: &str = "deep-link";
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase", tag = "action")]
+pub enum DeepLink {
+    /// Opens a library book, at `cfi` when given.
+    Open { book: String, cfi: Option<String> },
+    /// Saves the article of a web page to the library, handled here rather
+    /// than by the frontend, see [`crate::feeds::clip`].
+    Clip { url: String },
+    /// Looks `word` up in the dictionaries.
+    Lookup { word: String },
+    /// Asks for a folder of books to import, as the Linux desktop entry's
+    /// action does.
+    ImportFolder,
+    /// Adds the book a link or a path copied to the clipboard is of, as
+    /// the toasts offering it do, see [`crate::clipboard`].
+    Add { source: String },
+}
+
+impl DeepLink {
+    pub fn parse(url: &Url) -> Option<Self> {
+        if url.scheme() != SCHEME {
+            return None;
+        }
+        // `vlarch://open?..` has the action as host, `vlarch:open?..` as path
+        let action = url
+            .host_str()
+            .filter(|host| !host.is_empty())
+            .unwrap_or_else(|| url.path().trim_matches('/'));
+        let param = |name: &str| {
+            url.query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+                .filter(|value| !value.is_empty())
+        };
+        match action {
+            "open" => {
+                let book = param("book")?;
+                if !book.chars().all(|c| c.is_ascii_alphanumeric()) {
+                    return None;
+                }
+                Some(Self::Open {
+                    book,
+                    cfi: param("cfi"),
+                })
+            }
+            "clip" => Some(Self::Clip { url: param("url")? }),
+            "lookup" => Some(Self::Lookup {
+                word: param("word")?,
+            }),
+            "import-folder" => Some(Self::ImportFolder),
+            "add" => Some(Self::Add {
+                source: param("source")?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Parses the `vlarch://` arguments of a command line, ignoring the rest.
+pub fn links_from_argv(argv: &[String]) -> Vec<DeepLink> {
+    argv.iter()
+        .skip(1)
+        .filter_map(|arg| Url::parse(arg).ok())
+        .filter_map(|url| {
+            let link = DeepLink::parse(&url);
+            if link.is_none() && url.scheme() == SCHEME {
+                log::warn!("Ignoring unsupported link {url}");
+            }
+            link
+        })
+        .collect()
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Set once the frontend has taken the pending links; later links are
+    /// sent as events.
+    listening: bool,
+    pending: Vec<DeepLink>,
+}
+
+#[derive(Default)]
+pub struct DeepLinks(Mutex<Inner>);
+
+/// Delivers `links` to the frontend, or queues them until it is listening.
+/// Pages to clip and copied books are added right away.
+pub fn dispatch(app: &AppHandle, links: Vec<DeepLink>) {
+    let links = links
+        .into_iter()
+        .filter_map(|link| match link {
+            DeepLink::Clip { url } => {
+                crate::feeds::clip::clip_in_background(app, url);
+                None
+            }
+            DeepLink::Add { source } => {
+                crate::clipboard::add_in_background(app, source);
+                None
+            }
+            link => Some(link),
+        })
+        .collect::<Vec<_>>();
+    if links.is_empty() {
+        return;
+    }
+    let state = app.state::<DeepLinks>();
+    let mut inner = state.0.lock().unwrap();
+    if !inner.listening {
+        inner.pending.extend(links);
+        return;
+    }
+    drop(inner);
+    crate::show_main_window(app);
+    for link in links {
+        let _ = app.emit(EVENT, link);
+    }
+}
+
+/// Returns the links the app was opened with and switches to `deep-link`
+/// events for the ones that follow.
+#[command]
+pub fn take_pending_deep_links(links: State<'_, DeepLinks>) -> Vec<DeepLink> {
+    let mut inner = links.0.lock().unwrap();
+    inner.listening = true;
+    std::mem::take(&mut inner.pending)
+}
@@ -0,0 +1,374 @@
+//! E-readers connected over USB as mass storage. Mounted volumes are
+//! recognized by the folders each kind of device keeps at its root, books
+//! are copied to the folder the device reads them from, and the volume can
+//! be ejected once the copies are flushed.
+
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use serde::Serialize;
+use tauri::{command, AppHandle, Emitter};
+
+use crate::error::{Error, Result};
+use crate::formats::is_book_file;
+
+const PROGRESS_EVENT: &str = "device-transfer-progress";
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DeviceKind {
+    Kobo,
+    Kindle,
+    PocketBook,
+}
+
+impl DeviceKind {
+    /// Recognizes the device mounted at `root`.
+    fn detect(root: &Path) -> Option<Self> {
+        if root.join(".kobo").is_dir() {
+            Some(Self::Kobo)
+        } else if root.join("documents").is_dir() && root.join("system").is_dir() {
+            Some(Self::Kindle)
+        } else if root.join("system").join("config").is_dir() {
+            Some(Self::PocketBook)
+        } else {
+            None
+        }
+    }
+
+    /// Folder books are copied to, relative to the root. Kobo and PocketBook
+    /// find books anywhere on the volume.
+    fn books_dir(self) -> &'static str {
+        match self {
+            Self::Kindle => "documents",
+            Self::Kobo | Self::PocketBook => "",
+        }
+    }
+
+    /// Extensions of the book formats the device opens.
+    fn formats(self) -> &'static [&'static str] {
+        match self {
+            Self::Kobo => &["epub", "pdf", "mobi", "cbz"],
+            Self::Kindle => &["azw3", "azw", "mobi", "pdf"],
+            Self::PocketBook => &["epub", "pdf", "fb2", "mobi", "cbz"],
+        }
+    }
+
+    /// Whether `name` at the root holds the device's own files, besides the
+    /// hidden folders every device has.
+    fn is_system_dir(self, name: &str) -> bool {
+        match self {
+            Self::Kindle | Self::PocketBook => name.eq_ignore_ascii_case("system"),
+            Self::Kobo => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Device {
+    pub kind: DeviceKind,
+    /// Name of the volume.
+    pub name: String,
+    /// Where the volume is mounted.
+    pub path: PathBuf,
+    /// Where books are copied to.
+    pub books_dir: PathBuf,
+    pub formats: &'static [&'static str],
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceBook {
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified_at: Option<i64>,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TransferProgress {
+    /// Index of the file being copied.
+    file: usize,
+    files: usize,
+    bytes: u64,
+    total_bytes: u64,
+}
+
+fn device_error(message: impl Into<String>) -> Error {
+    Error::Device(message.into())
+}
+
+fn device_at(path: PathBuf) -> Option<Device> {
+    let kind = DeviceKind::detect(&path)?;
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_string_lossy().into_owned());
+    Some(Device {
+        kind,
+        name,
+        books_dir: path.join(kind.books_dir()),
+        formats: kind.formats(),
+        path,
+    })
+}
+
+fn device(path: &Path) -> Result<Device> {
+    device_at(path.to_path_buf())
+        .ok_or_else(|| device_error(format!("no e-reader at {}", path.display())))
+}
+
+/// Decodes the octal escapes of whitespace and backslashes in `/proc/mounts`.
+#[cfg(target_os = "linux")]
+fn unescape_mount(field: &str) -> String {
+    field
+        .replace("\\040", " ")
+        .replace("\\011", "\t")
+        .replace("\\134", "\\")
+}
+
+/// Block devices and mount points of the removable volumes, which desktops
+/// mount under `/media` or `/run/media`, and users often under `/mnt`.
+#[cfg(target_os = "linux")]
+fn mounts() -> Vec<(String, PathBuf)> {
+    let Ok(table) = std::fs::read_to_string("/proc/mounts") else {
+        return Vec::new();
+    };
+    table
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            let source = unescape_mount(fields.next()?);
+            let target = unescape_mount(fields.next()?);
+            let removable = ["/media/", "/run/media/", "/mnt/"]
+                .iter()
+                .any(|prefix| target.starts_with(prefix));
+            removable.then(|| (source, PathBuf::from(target)))
+        })
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+fn volumes() -> Vec<PathBuf> {
+    mounts().into_iter().map(|(_, target)| target).collect()
+}
+
+#[cfg(target_os = "macos")]
+fn volumes() -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir("/Volumes") else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        // The startup disk is a link to the root
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_dir()))
+        .map(|entry| entry.path())
+        .collect()
+}
+
+#[cfg(windows)]
+fn volumes() -> Vec<PathBuf> {
+    ('D'..='Z')
+        .map(|letter| PathBuf::from(format!("{letter}:\\")))
+        .filter(|root| root.is_dir())
+        .collect()
+}
+
+fn run(command: &mut Command) -> Result<Output> {
+    let output = command.output().map_err(|e| match e.kind() {
+        ErrorKind::NotFound => device_error(format!(
+            "{} is not available",
+            command.get_program().to_string_lossy()
+        )),
+        _ => e.into(),
+    })?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(device_error(stderr.trim().to_string()));
+    }
+    Ok(output)
+}
+
+/// Unmounts the volume through UDisks, so no root rights are needed, and
+/// powers the device off when it allows.
+#[cfg(target_os = "linux")]
+fn eject(root: &Path) -> Result<()> {
+    let source = mounts()
+        .into_iter()
+        .find(|(_, target)| target == root)
+        .map(|(source, _)| source)
+        .ok_or_else(|| device_error(format!("{} is not mounted", root.display())))?;
+    run(Command::new("udisksctl")
+        .args(["unmount", "--no-user-interaction", "-b"])
+        .arg(&source))?;
+    if let Err(e) = run(Command::new("udisksctl")
+        .args(["power-off", "--no-user-interaction", "-b"])
+        .arg(&source))
+    {
+        log::warn!("Failed to power off {source}: {e}");
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn eject(root: &Path) -> Result<()> {
+    run(Command::new("diskutil").arg("eject").arg(root)).map(|_| ())
+}
+
+/// Uses the Eject verb of Explorer, which flushes and stops the device like
+/// the notification area icon does.
+#[cfg(windows)]
+fn eject(root: &Path) -> Result<()> {
+    let drive = root.to_string_lossy().trim_end_matches('\\').to_string();
+    let script = format!(
+        "(New-Object -ComObject Shell.Application).Namespace(17).ParseName('{drive}').InvokeVerb('Eject')"
+    );
+    run(Command::new("powershell").args(["-NoProfile", "-NonInteractive", "-Command", &script]))
+        .map(|_| ())
+}
+
+fn scan(device: &Device, dir: &Path, top: bool, books: &mut Vec<DeviceBook>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        match entry.file_type() {
+            Ok(t) if t.is_dir() => {
+                let hidden = name.starts_with('.') || (top && device.kind.is_system_dir(&name));
+                if !hidden {
+                    scan(device, &path, false, books);
+                }
+            }
+            Ok(t) if t.is_file() && is_book_file(&path) => {
+                let Ok(metadata) = entry.metadata() else {
+                    continue;
+                };
+                let modified_at = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_millis() as i64);
+                books.push(DeviceBook {
+                    path,
+                    size: metadata.len(),
+                    modified_at,
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Copies `files` into `dir`, each through a temporary file that is synced
+/// before it takes the final name, so an unplugged device keeps no partial
+/// books.
+fn copy_files(app: &AppHandle, files: &[PathBuf], dir: &Path) -> Result<Vec<PathBuf>> {
+    let total_bytes = files
+        .iter()
+        .map(|file| std::fs::metadata(file).map(|m| m.len()))
+        .sum::<std::io::Result<u64>>()?;
+    std::fs::create_dir_all(dir)?;
+
+    let mut copied = Vec::with_capacity(files.len());
+    let mut bytes = 0;
+    let mut last_progress = Instant::now();
+    let mut buf = vec![0; CHUNK_SIZE];
+    for (i, file) in files.iter().enumerate() {
+        let name = file
+            .file_name()
+            .ok_or_else(|| Error::InvalidBook(format!("{} is not a file", file.display())))?;
+        let dest = dir.join(name);
+        let tmp = dest.with_extension("part");
+        let mut reader = File::open(file)?;
+        let mut writer = File::create(&tmp)?;
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            writer.write_all(&buf[..n])?;
+            bytes += n as u64;
+            if last_progress.elapsed() >= PROGRESS_INTERVAL {
+                last_progress = Instant::now();
+                let _ = app.emit(
+                    PROGRESS_EVENT,
+                    TransferProgress {
+                        file: i,
+                        files: files.len(),
+                        bytes,
+                        total_bytes,
+                    },
+                );
+            }
+        }
+        writer.sync_all()?;
+        drop(writer);
+        std::fs::rename(&tmp, &dest)?;
+        let _ = app.emit(
+            PROGRESS_EVENT,
+            TransferProgress {
+                file: i,
+                files: files.len(),
+                bytes,
+                total_bytes,
+            },
+        );
+        copied.push(dest);
+    }
+    Ok(copied)
+}
+
+/// E-readers mounted now.
+#[command]
+pub async fn list_devices() -> Result<Vec<Device>> {
+    let devices = tauri::async_runtime::spawn_blocking(|| {
+        volumes().into_iter().filter_map(device_at).collect()
+    })
+    .await?;
+    Ok(devices)
+}
+
+/// Books on the device mounted at `path`.
+#[command]
+pub async fn list_device_books(path: PathBuf) -> Result<Vec<DeviceBook>> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let device = device(&path)?;
+        let mut books = Vec::new();
+        scan(&device, &device.books_dir, true, &mut books);
+        books.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(books)
+    })
+    .await?
+}
+
+/// Copies `files` to the books folder of the device mounted at `path`,
+/// replacing books with the same names, and returns where they went.
+#[command]
+pub async fn copy_to_device(
+    app: AppHandle,
+    path: PathBuf,
+    files: Vec<PathBuf>,
+) -> Result<Vec<PathBuf>> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let device = device(&path)?;
+        copy_files(&app, &files, &device.books_dir)
+    })
+    .await?
+}
+
+/// Unmounts the device at `path` so it can be unplugged safely.
+#[command]
+pub async fn eject_device(path: PathBuf) -> Result<()> {
+    tauri::async_runtime::spawn_blocking(move || eject(&device(&path)?.path)).await?
+}
@@ -0,0 +1,249 @@
+//! Diagnostics bundles to attach to bug reports: a zip with the recent log
+//! files, the settings with passwords, tokens and keys blanked out, and
+//! what the app runs on. "Report An Issue..." offers one before opening
+//! the issue tracker.
+
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde_json::{json, Value};
+use tauri::{command, AppHandle};
+use tauri_plugin_dialog::{DialogExt, MessageDialogButtons, MessageDialogKind};
+use tauri_plugin_opener::OpenerExt;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::error::Result;
+use crate::i18n;
+use crate::logging;
+use crate::paths;
+use crate::utils::{format_rfc3339, now_millis};
+
+const ISSUES_URL: &str = "https://github.com/vl-arch/vl-arch/issues";
+/// Tail of each log file put in the bundle.
+const MAX_LOG_BYTES: u64 = 4 * 1024 * 1024;
+/// Parts of setting names whose values are left out, matched without case
+/// or separators.
+const SECRET_NAMES: &[&str] = &[
+    "token",
+    "secret",
+    "password",
+    "passphrase",
+    "apikey",
+    "accesskey",
+    "privatekey",
+    "auth",
+    "cookie",
+    "credential",
+];
+const REDACTED: &str = "[redacted]";
+
+fn is_secret(name: &str) -> bool {
+    let name = name
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .collect::<String>()
+        .to_lowercase();
+    name == "key" || SECRET_NAMES.iter().any(|secret| name.contains(secret))
+}
+
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (name, value) in map.iter_mut() {
+                if is_secret(name) && !value.is_null() {
+                    *value = Value::String(REDACTED.into());
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+fn environment(app: &AppHandle) -> Value {
+    let features = [
+        ("rar", cfg!(feature = "rar")),
+        ("djvu", cfg!(feature = "djvu")),
+        ("ocr", cfg!(feature = "ocr")),
+    ]
+    .into_iter()
+    .filter(|(_, enabled)| *enabled)
+    .map(|(name, _)| name)
+    .collect::<Vec<_>>();
+    json!({
+        "app": app.package_info().name,
+        "version": app.package_info().version.to_string(),
+        "tauri": tauri::VERSION,
+        "debug": cfg!(debug_assertions),
+        "features": features,
+        "os": tauri_plugin_os::type_().to_string(),
+        "osVersion": tauri_plugin_os::version().to_string(),
+        "arch": tauri_plugin_os::arch(),
+        "locale": tauri_plugin_os::locale(),
+        "createdAt": format_rfc3339(now_millis()),
+    })
+}
+
+/// The log files, newest first.
+fn log_files(app: &AppHandle) -> Result<Vec<PathBuf>> {
+    let dir = logging::log_dir(app)?;
+    let Ok(entries) = std::fs::read_dir(&dir) else {
+        return Ok(Vec::new());
+    };
+    let mut files = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with(logging::FILE_PREFIX)
+        })
+        .filter_map(|entry| {
+            let modified = entry.metadata().ok()?.modified().ok()?;
+            Some((modified, entry.path()))
+        })
+        .collect::<Vec<(SystemTime, PathBuf)>>();
+    files.sort_by_key(|(modified, _)| std::cmp::Reverse(*modified));
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
+
+/// The settings files of the backend and of the frontend.
+fn config_files(app: &AppHandle) -> Result<Vec<PathBuf>> {
+    let mut files = std::fs::read_dir(paths::config_dir(app)?)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    let frontend = paths::data_dir(app)?.join("settings.json");
+    if frontend.is_file() && !files.contains(&frontend) {
+        files.push(frontend);
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn tail(path: &Path) -> Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(MAX_LOG_BYTES)))?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Writes a diagnostics bundle to `path`.
+fn write_bundle(app: &AppHandle, path: &Path) -> Result<()> {
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    let mut zip = ZipWriter::new(BufWriter::new(File::create(path)?));
+
+    zip.start_file("environment.json", options)?;
+    zip.write_all(&serde_json::to_vec_pretty(&environment(app))?)?;
+
+    for file in log_files(app)? {
+        let Some(name) = file.file_name() else {
+            continue;
+        };
+        let bytes = match tail(&file) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                log::warn!("Failed to read {file:?} for diagnostics: {e}");
+                continue;
+            }
+        };
+        zip.start_file(format!("logs/{}", name.to_string_lossy()), options)?;
+        zip.write_all(&bytes)?;
+    }
+
+    for file in config_files(app)? {
+        let Some(name) = file.file_name() else {
+            continue;
+        };
+        // Files that are not JSON could hold anything, so they are left out
+        let Some(mut value) = std::fs::read(&file)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<Value>(&bytes).ok())
+        else {
+            continue;
+        };
+        redact(&mut value);
+        zip.start_file(format!("config/{}", name.to_string_lossy()), options)?;
+        zip.write_all(&serde_json::to_vec_pretty(&value)?)?;
+    }
+
+    zip.finish()?.flush()?;
+    Ok(())
+}
+
+fn bundle_name() -> String {
+    let date = format_rfc3339(now_millis());
+    format!("vl-arch-diagnostics-{}.zip", &date[..10])
+}
+
+/// Writes a diagnostics bundle to a file the user picks in a save dialog,
+/// returning where, or `None` if the dialog was cancelled.
+fn save_bundle(app: &AppHandle) -> Result<Option<PathBuf>> {
+    let Some(file) = app
+        .dialog()
+        .file()
+        .add_filter("Zip", &["zip"])
+        .set_file_name(bundle_name())
+        .blocking_save_file()
+    else {
+        return Ok(None);
+    };
+    let path = file
+        .into_path()
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    write_bundle(app, &path)?;
+    Ok(Some(path))
+}
+
+/// Offers to create a diagnostics bundle, showing it in the file manager
+/// once written, then opens the issue tracker. Menus without
+/// "Report An Issue..." of their own have the frontend call it.
+#[command]
+pub fn report_issue(app: AppHandle) {
+    tauri::async_runtime::spawn_blocking(move || {
+        let create = app
+            .dialog()
+            .message(i18n::t(
+                &app,
+                "A diagnostics bundle with the recent logs, your settings without passwords or \
+                 tokens, and details of your system helps to look into the issue. You can attach \
+                 it to the report.",
+            ))
+            .title(i18n::t(&app, "Create a Diagnostics Bundle?"))
+            .kind(MessageDialogKind::Info)
+            .buttons(MessageDialogButtons::OkCancelCustom(
+                i18n::t(&app, "Create Bundle"),
+                i18n::t(&app, "Skip"),
+            ))
+            .blocking_show();
+        if create {
+            match save_bundle(&app) {
+                Ok(Some(path)) => {
+                    let _ = app.opener().reveal_item_in_dir(path);
+                }
+                Ok(None) => {}
+                Err(e) => log::warn!("Failed to create a diagnostics bundle: {e}"),
+            }
+        }
+        let _ = app.opener().open_url(ISSUES_URL, None::<&str>);
+    });
+}
+
+/// Creates a diagnostics bundle in a file the user picks, returning where,
+/// or `None` if the dialog was cancelled.
+#[command]
+pub async fn create_diagnostics_bundle(app: AppHandle) -> Result<Option<PathBuf>> {
+    tauri::async_runtime::spawn_blocking(move || save_bundle(&app)).await?
+}
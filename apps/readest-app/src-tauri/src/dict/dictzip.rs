@@ -0,0 +1,159 @@
+//! Random access into dictzip files (`.dict.dz`): gzip files compressed in
+//! independent chunks, with the chunk sizes listed in the `RA` extra field.
+//! Plain gzip files without the table are inflated into memory once.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Mutex;
+
+use flate2::read::GzDecoder;
+use flate2::{Decompress, FlushDecompress};
+
+use crate::error::{Error, Result};
+
+const FEXTRA: u8 = 0x04;
+const FNAME: u8 = 0x08;
+const FCOMMENT: u8 = 0x10;
+const FHCRC: u8 = 0x02;
+
+pub enum DictFile {
+    Plain(Mutex<File>),
+    Chunked {
+        file: Mutex<File>,
+        /// Uncompressed size of every chunk but the last.
+        chunk_len: u64,
+        /// File offset of every chunk, plus the end of the last one.
+        offsets: Vec<u64>,
+    },
+    Memory(Vec<u8>),
+}
+
+impl DictFile {
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut file = File::open(path)?;
+        if !path.extension().is_some_and(|ext| ext == "dz") {
+            return Ok(Self::Plain(Mutex::new(file)));
+        }
+        let mut header = Vec::new();
+        (&mut file).take(64 * 1024).read_to_end(&mut header)?;
+        match parse_header(&header) {
+            Some((chunk_len, sizes, data_start)) => {
+                let mut offsets = Vec::with_capacity(sizes.len() + 1);
+                let mut offset = data_start;
+                offsets.push(offset);
+                for size in sizes {
+                    offset += size;
+                    offsets.push(offset);
+                }
+                Ok(Self::Chunked {
+                    file: Mutex::new(file),
+                    chunk_len,
+                    offsets,
+                })
+            }
+            None => {
+                file.seek(SeekFrom::Start(0))?;
+                let mut data = Vec::new();
+                GzDecoder::new(file).read_to_end(&mut data)?;
+                Ok(Self::Memory(data))
+            }
+        }
+    }
+
+    /// Reads `size` bytes at `offset` of the uncompressed data.
+    pub fn read(&self, offset: u64, size: u64) -> Result<Vec<u8>> {
+        match self {
+            Self::Plain(file) => {
+                let mut file = file.lock().unwrap();
+                file.seek(SeekFrom::Start(offset))?;
+                let mut data = Vec::with_capacity(size as usize);
+                (&mut *file).take(size).read_to_end(&mut data)?;
+                Ok(data)
+            }
+            Self::Memory(data) => {
+                let start = (offset as usize).min(data.len());
+                let end = (start + size as usize).min(data.len());
+                Ok(data[start..end].to_vec())
+            }
+            Self::Chunked {
+                file,
+                chunk_len,
+                offsets,
+            } => {
+                let chunk_count = offsets.len() as u64 - 1;
+                let first = offset / chunk_len;
+                let last = ((offset + size).saturating_sub(1) / chunk_len).min(chunk_count - 1);
+                if size == 0 || first >= chunk_count {
+                    return Ok(Vec::new());
+                }
+                let mut file = file.lock().unwrap();
+                let mut data = Vec::with_capacity(((last - first + 1) * chunk_len) as usize);
+                for chunk in first..=last {
+                    let (start, end) = (offsets[chunk as usize], offsets[chunk as usize + 1]);
+                    let mut compressed = vec![0; (end - start) as usize];
+                    file.seek(SeekFrom::Start(start))?;
+                    file.read_exact(&mut compressed)?;
+                    inflate_chunk(&compressed, *chunk_len as usize, &mut data)?;
+                }
+                let start = (offset - first * chunk_len) as usize;
+                let end = (start + size as usize).min(data.len());
+                Ok(data.get(start..end).unwrap_or_default().to_vec())
+            }
+        }
+    }
+}
+
+/// Returns the chunk length, the compressed chunk sizes and the offset of
+/// the first chunk, or `None` when the file has no chunk table.
+fn parse_header(header: &[u8]) -> Option<(u64, Vec<u64>, u64)> {
+    if header.get(..3)? != [0x1f, 0x8b, 0x08] {
+        return None;
+    }
+    let flags = *header.get(3)?;
+    let mut pos = 10;
+    let mut table = None;
+    if flags & FEXTRA != 0 {
+        let xlen = u16::from_le_bytes([*header.get(pos)?, *header.get(pos + 1)?]) as usize;
+        pos += 2;
+        let extra = header.get(pos..pos + xlen)?;
+        pos += xlen;
+        let mut i = 0;
+        while i + 4 <= extra.len() {
+            let len = u16::from_le_bytes([extra[i + 2], extra[i + 3]]) as usize;
+            let field = extra.get(i + 4..i + 4 + len)?;
+            if &extra[i..i + 2] == b"RA" && field.len() >= 6 {
+                let u16_at = |j: usize| u16::from_le_bytes([field[j], field[j + 1]]) as u64;
+                let (chunk_len, count) = (u16_at(2), u16_at(4) as usize);
+                if chunk_len > 0 && field.len() >= 6 + count * 2 {
+                    table = Some((chunk_len, (0..count).map(|c| u16_at(6 + c * 2)).collect()));
+                }
+            }
+            i += 4 + len;
+        }
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            pos += header.get(pos..)?.iter().position(|&b| b == 0)? + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        pos += 2;
+    }
+    let (chunk_len, sizes) = table?;
+    Some((chunk_len, sizes, pos as u64))
+}
+
+/// Inflates one chunk, a raw deflate stream ended by a full flush.
+fn inflate_chunk(compressed: &[u8], chunk_len: usize, out: &mut Vec<u8>) -> Result<()> {
+    let mut inflater = Decompress::new(false);
+    let start = out.len();
+    out.reserve(chunk_len);
+    inflater
+        .decompress_vec(compressed, out, FlushDecompress::Sync)
+        .map_err(|e| Error::InvalidDictionary(format!("corrupt dictzip chunk: {e}")))?;
+    if out.len() == start {
+        return Err(Error::InvalidDictionary("empty dictzip chunk".into()));
+    }
+    Ok(())
+}
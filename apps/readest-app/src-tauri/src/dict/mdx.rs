@@ -0,0 +1,442 @@
+//! MDict dictionaries (`.mdx`, format versions 1 and 2). The headword list is
+//! read into memory when the dictionary is opened; definitions are inflated
+//! from their record block on lookup. Resources in companion `.mdd` files
+//! (images, sounds, style sheets) are not loaded.
+
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Mutex;
+
+use encoding_rs::Encoding;
+use flate2::read::ZlibDecoder;
+
+use super::{Entry, Index};
+use crate::error::{Error, Result};
+
+/// Definitions that only redirect to another headword.
+const LINK_PREFIX: &str = "@@@LINK=";
+/// How many redirects to follow before giving up.
+const MAX_LINKS: usize = 5;
+
+struct Key {
+    word: String,
+    /// Offset of the definition in the uncompressed record data.
+    offset: u64,
+}
+
+struct RecordBlock {
+    /// Where the compressed block starts in the file.
+    file_offset: u64,
+    compressed_size: u64,
+    /// Where the block starts in the uncompressed record data.
+    offset: u64,
+}
+
+pub struct Mdx {
+    name: String,
+    encoding: &'static Encoding,
+    keys: Vec<Key>,
+    index: Index,
+    /// Definition start offsets in ascending order, to find where each ends.
+    boundaries: Vec<u64>,
+    records: Vec<RecordBlock>,
+    records_size: u64,
+    file: Mutex<File>,
+}
+
+/// Reads the big-endian numbers of the file, 8 bytes wide from version 2.
+struct Numbers {
+    wide: bool,
+}
+
+impl Numbers {
+    fn width(&self) -> usize {
+        if self.wide {
+            8
+        } else {
+            4
+        }
+    }
+
+    fn read(&self, bytes: &[u8], pos: &mut usize) -> Result<u64> {
+        let width = self.width();
+        let bytes = bytes
+            .get(*pos..*pos + width)
+            .ok_or_else(|| Error::InvalidDictionary("truncated mdx block".into()))?;
+        *pos += width;
+        Ok(bytes.iter().fold(0, |n, &b| n << 8 | b as u64))
+    }
+
+    fn read_from(&self, reader: &mut impl Read, count: usize) -> Result<Vec<u64>> {
+        let mut bytes = vec![0; count * self.width()];
+        reader.read_exact(&mut bytes)?;
+        let mut pos = 0;
+        (0..count).map(|_| self.read(&bytes, &mut pos)).collect()
+    }
+}
+
+impl Mdx {
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let header_len = read_u32(&mut reader)? as usize;
+        let mut header = vec![0; header_len];
+        reader.read_exact(&mut header)?;
+        // Skip the header checksum
+        reader.seek_relative(4)?;
+        let header = decode_utf16le(&header);
+        let header = header.trim_end_matches('\0');
+        let doc = roxmltree::Document::parse(header)?;
+        let attr = |name: &str| doc.root_element().attribute(name).unwrap_or_default();
+
+        let version = attr("GeneratedByEngineVersion")
+            .trim()
+            .parse::<f32>()
+            .unwrap_or(2.0);
+        if version >= 3.0 {
+            return Err(Error::UnsupportedFormat(format!("mdx version {version}")));
+        }
+        let numbers = Numbers {
+            wide: version >= 2.0,
+        };
+        let encrypted = match attr("Encrypted") {
+            "Yes" => 1,
+            value => value.parse::<u8>().unwrap_or(0),
+        };
+        if encrypted & 1 != 0 {
+            return Err(Error::UnsupportedFormat(
+                "mdx that needs a registration key".into(),
+            ));
+        }
+        let encoding = match attr("Encoding").to_ascii_lowercase().as_str() {
+            "" | "utf-8" | "utf8" => encoding_rs::UTF_8,
+            "utf-16" | "utf16" => encoding_rs::UTF_16LE,
+            // GB18030 is a superset of the GBK and GB2312 labels used by older files
+            "gbk" | "gb2312" => encoding_rs::GB18030,
+            label => Encoding::for_label(label.as_bytes()).unwrap_or(encoding_rs::UTF_8),
+        };
+        let title = attr("Title").trim();
+        let name = if title.is_empty() || title.starts_with("Title (No HTML") {
+            path.file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        } else {
+            title.to_string()
+        };
+
+        // Keyword section: block counts and sizes, the block info, then the key blocks
+        let (key_info_size, key_blocks_size) = if numbers.wide {
+            let sizes = numbers.read_from(&mut reader, 5)?;
+            reader.seek_relative(4)?;
+            (sizes[3], sizes[4])
+        } else {
+            let sizes = numbers.read_from(&mut reader, 4)?;
+            (sizes[2], sizes[3])
+        };
+        let mut key_info = vec![0; key_info_size as usize];
+        reader.read_exact(&mut key_info)?;
+        if numbers.wide {
+            if encrypted & 2 != 0 {
+                decrypt_key_info(&mut key_info);
+            }
+            key_info = decompress(&key_info)?;
+        }
+        let unit = if encoding == encoding_rs::UTF_16LE {
+            2
+        } else {
+            1
+        };
+        let key_block_sizes = parse_key_info(&key_info, &numbers, unit)?;
+
+        let mut keys = Vec::new();
+        let mut remaining = key_blocks_size;
+        for size in key_block_sizes {
+            if size > remaining {
+                return Err(Error::InvalidDictionary(
+                    "key block past section end".into(),
+                ));
+            }
+            remaining -= size;
+            let mut block = vec![0; size as usize];
+            reader.read_exact(&mut block)?;
+            parse_keys(&decompress(&block)?, &numbers, encoding, unit, &mut keys)?;
+        }
+        reader.seek_relative(remaining as i64)?;
+
+        // Record section: block count, entry count, info size and data size
+        let sizes = numbers.read_from(&mut reader, 4)?;
+        let block_sizes = numbers.read_from(&mut reader, sizes[0] as usize * 2)?;
+        let mut file_offset = reader.stream_position()?;
+        let mut offset = 0;
+        let records = block_sizes
+            .chunks_exact(2)
+            .map(|sizes| {
+                let block = RecordBlock {
+                    file_offset,
+                    compressed_size: sizes[0],
+                    offset,
+                };
+                file_offset += sizes[0];
+                offset += sizes[1];
+                block
+            })
+            .collect();
+
+        let mut index = Index::default();
+        for (i, key) in keys.iter().enumerate() {
+            index.insert(&key.word, i);
+        }
+        let mut boundaries = keys.iter().map(|key| key.offset).collect::<Vec<_>>();
+        boundaries.sort_unstable();
+        boundaries.dedup();
+        Ok(Self {
+            name,
+            encoding,
+            keys,
+            index,
+            boundaries,
+            records,
+            records_size: offset,
+            file: Mutex::new(reader.into_inner()),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn word_count(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn lookup(&self, word: &str) -> Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        let mut seen = Vec::new();
+        for &i in self.index.get(word) {
+            let mut key = &self.keys[i];
+            let mut html = self.definition(key.offset)?;
+            // Follow redirects to the headword that holds the definition
+            for _ in 0..MAX_LINKS {
+                let Some(target) = html.strip_prefix(LINK_PREFIX) else {
+                    break;
+                };
+                let Some(&target) = self.index.get(target.trim()).first() else {
+                    break;
+                };
+                key = &self.keys[target];
+                html = self.definition(key.offset)?;
+            }
+            // Variants of a headword often link to it, which is found anyway
+            if !html.starts_with(LINK_PREFIX) && !seen.contains(&key.offset) {
+                seen.push(key.offset);
+                entries.push(Entry {
+                    word: key.word.clone(),
+                    html,
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    fn definition(&self, start: u64) -> Result<String> {
+        let end = match self.boundaries.binary_search(&start) {
+            Ok(i) => self.boundaries.get(i + 1).copied(),
+            Err(i) => self.boundaries.get(i).copied(),
+        }
+        .unwrap_or(self.records_size);
+        let first = self
+            .records
+            .partition_point(|block| block.offset <= start)
+            .checked_sub(1)
+            .ok_or_else(|| Error::InvalidDictionary("record offset out of range".into()))?;
+
+        // A definition may continue into the following blocks
+        let mut data = Vec::new();
+        let mut file = self.file.lock().unwrap();
+        for block in self.records[first..]
+            .iter()
+            .take_while(|block| block.offset < end)
+        {
+            let mut compressed = vec![0; block.compressed_size as usize];
+            file.seek(SeekFrom::Start(block.file_offset))?;
+            file.read_exact(&mut compressed)?;
+            data.extend(decompress(&compressed)?);
+        }
+        drop(file);
+        let offset = self.records[first].offset;
+        let from = ((start - offset) as usize).min(data.len());
+        let to = ((end - offset) as usize).clamp(from, data.len());
+        let (text, _, _) = self.encoding.decode(&data[from..to]);
+        Ok(text.trim_end_matches('\0').trim().to_string())
+    }
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn decode_utf16le(bytes: &[u8]) -> String {
+    let units = bytes
+        .chunks_exact(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+        .collect::<Vec<_>>();
+    String::from_utf16_lossy(&units)
+}
+
+/// Inflates a compressed block: a 4-byte type, a 4-byte checksum and the data.
+fn decompress(block: &[u8]) -> Result<Vec<u8>> {
+    let data = block.get(8..).unwrap_or_default();
+    match block.get(..4) {
+        Some([0, 0, 0, 0]) => Ok(data.to_vec()),
+        Some([1, 0, 0, 0]) => Err(Error::UnsupportedFormat("LZO compressed mdx".into())),
+        Some([2, 0, 0, 0]) => {
+            let mut inflated = Vec::new();
+            ZlibDecoder::new(data).read_to_end(&mut inflated)?;
+            Ok(inflated)
+        }
+        _ => Err(Error::InvalidDictionary("unknown mdx block type".into())),
+    }
+}
+
+/// Decrypts the key block info in place. The key is derived from the block
+/// checksum, so no registration is needed.
+fn decrypt_key_info(block: &mut [u8]) {
+    if block.len() < 8 {
+        return;
+    }
+    let mut seed = [0; 8];
+    seed[..4].copy_from_slice(&block[4..8]);
+    seed[4..].copy_from_slice(&0x3695u32.to_le_bytes());
+    let key = ripemd128(&seed);
+    let mut previous = 0x36;
+    for (i, byte) in block[8..].iter_mut().enumerate() {
+        let value = *byte;
+        *byte = value.rotate_left(4) ^ previous ^ (i as u8) ^ key[i % key.len()];
+        previous = value;
+    }
+}
+
+/// Returns the compressed size of every key block.
+fn parse_key_info(info: &[u8], numbers: &Numbers, unit: usize) -> Result<Vec<u64>> {
+    let truncated = || Error::InvalidDictionary("truncated key block info".into());
+    // Version 2 stores key lengths in two bytes and NUL-terminates the keys
+    let (size_width, terminator) = if numbers.wide { (2, 1) } else { (1, 0) };
+    let mut sizes = Vec::new();
+    let mut pos = 0;
+    while pos < info.len() {
+        numbers.read(info, &mut pos)?;
+        // Skip the first and last key of the block
+        for _ in 0..2 {
+            let len = info.get(pos..pos + size_width).ok_or_else(truncated)?;
+            let len = len.iter().fold(0, |n, &b| n << 8 | b as usize);
+            pos += size_width + (len + terminator) * unit;
+        }
+        let compressed = numbers.read(info, &mut pos)?;
+        numbers.read(info, &mut pos)?;
+        sizes.push(compressed);
+    }
+    Ok(sizes)
+}
+
+fn parse_keys(
+    block: &[u8],
+    numbers: &Numbers,
+    encoding: &'static Encoding,
+    unit: usize,
+    keys: &mut Vec<Key>,
+) -> Result<()> {
+    let mut pos = 0;
+    while pos < block.len() {
+        let offset = numbers.read(block, &mut pos)?;
+        let text = &block[pos..];
+        let len = text
+            .chunks(unit)
+            .position(|c| c.iter().all(|&b| b == 0))
+            .map_or(text.len(), |i| i * unit);
+        let (word, _) = encoding.decode_without_bom_handling(&text[..len]);
+        keys.push(Key {
+            word: word.into_owned(),
+            offset,
+        });
+        pos += len + unit;
+    }
+    Ok(())
+}
+
+/// RIPEMD-128, which MDict uses to derive the key info encryption key.
+fn ripemd128(message: &[u8]) -> [u8; 16] {
+    const R: [usize; 64] = [
+        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 7, 4, 13, 1, 10, 6, 15, 3, 12, 0, 9,
+        5, 2, 14, 11, 8, 3, 10, 14, 4, 9, 15, 8, 1, 2, 7, 0, 6, 13, 11, 5, 12, 1, 9, 11, 10, 0, 8,
+        12, 4, 13, 3, 7, 15, 14, 5, 6, 2,
+    ];
+    const R2: [usize; 64] = [
+        5, 14, 7, 0, 9, 2, 11, 4, 13, 6, 15, 8, 1, 10, 3, 12, 6, 11, 3, 7, 0, 13, 5, 10, 14, 15, 8,
+        12, 4, 9, 1, 2, 15, 5, 1, 3, 7, 14, 6, 9, 11, 8, 12, 2, 10, 0, 4, 13, 8, 6, 4, 1, 3, 11,
+        15, 0, 5, 12, 2, 13, 9, 7, 10, 14,
+    ];
+    const S: [u32; 64] = [
+        11, 14, 15, 12, 5, 8, 7, 9, 11, 13, 14, 15, 6, 7, 9, 8, 7, 6, 8, 13, 11, 9, 7, 15, 7, 12,
+        15, 9, 11, 7, 13, 12, 11, 13, 6, 7, 14, 9, 13, 15, 14, 8, 13, 6, 5, 12, 7, 5, 11, 12, 14,
+        15, 14, 15, 9, 8, 9, 14, 5, 6, 8, 6, 5, 12,
+    ];
+    const S2: [u32; 64] = [
+        8, 9, 9, 11, 13, 15, 15, 5, 7, 7, 8, 11, 14, 14, 12, 6, 9, 13, 15, 7, 12, 8, 9, 11, 7, 7,
+        12, 7, 6, 15, 13, 11, 9, 7, 15, 11, 8, 6, 6, 14, 12, 13, 5, 14, 13, 13, 7, 5, 15, 5, 8, 11,
+        14, 14, 6, 14, 6, 9, 12, 9, 12, 5, 15, 8,
+    ];
+    const K: [u32; 4] = [0, 0x5a82_7999, 0x6ed9_eba1, 0x8f1b_bcdc];
+    const K2: [u32; 4] = [0x50a2_8be6, 0x5c4d_d124, 0x6d70_3ef3, 0];
+    fn f(round: usize, x: u32, y: u32, z: u32) -> u32 {
+        match round {
+            0 => x ^ y ^ z,
+            1 => (x & y) | (!x & z),
+            2 => (x | !y) ^ z,
+            _ => (x & z) | (y & !z),
+        }
+    }
+
+    let mut padded = message.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend(((message.len() as u64) * 8).to_le_bytes());
+
+    let mut h: [u32; 4] = [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476];
+    for block in padded.chunks_exact(64) {
+        let x = block
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect::<Vec<_>>();
+        let [mut a, mut b, mut c, mut d] = h;
+        let [mut a2, mut b2, mut c2, mut d2] = h;
+        for j in 0..64 {
+            let round = j / 16;
+            let t = a
+                .wrapping_add(f(round, b, c, d))
+                .wrapping_add(x[R[j]])
+                .wrapping_add(K[round])
+                .rotate_left(S[j]);
+            (a, b, c, d) = (d, t, b, c);
+            let t = a2
+                .wrapping_add(f(3 - round, b2, c2, d2))
+                .wrapping_add(x[R2[j]])
+                .wrapping_add(K2[round])
+                .rotate_left(S2[j]);
+            (a2, b2, c2, d2) = (d2, t, b2, c2);
+        }
+        let t = h[1].wrapping_add(c).wrapping_add(d2);
+        h[1] = h[2].wrapping_add(d).wrapping_add(a2);
+        h[2] = h[3].wrapping_add(a).wrapping_add(b2);
+        h[3] = h[0].wrapping_add(b).wrapping_add(c2);
+        h[0] = t;
+    }
+    let mut digest = [0; 16];
+    for (chunk, word) in digest.chunks_exact_mut(4).zip(h) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    digest
+}
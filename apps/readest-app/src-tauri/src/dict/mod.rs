@@ -0,0 +1,282 @@
+//! Offline dictionary lookup in user-provided StarDict and MDict (`.mdx`)
+//! dictionaries. Each dictionary keeps a case-insensitive index of its
+//! headwords in memory; dictionaries are opened on the first lookup.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use tauri::{command, AppHandle, Manager};
+
+use crate::error::{Error, Result};
+use crate::library::db::LibraryDb;
+use crate::store;
+use crate::vocab::{self, WordContext};
+
+mod dictzip;
+mod mdx;
+mod stardict;
+
+const DICTIONARIES_FILE: &str = "dictionaries.json";
+
+/// Headword entry numbers by lowercased headword.
+#[derive(Default)]
+struct Index(HashMap<String, Vec<usize>>);
+
+impl Index {
+    fn insert(&mut self, word: &str, entry: usize) {
+        let entries = self.0.entry(fold(word)).or_default();
+        if !entries.contains(&entry) {
+            entries.push(entry);
+        }
+    }
+
+    fn get(&self, word: &str) -> &[usize] {
+        self.0.get(&fold(word)).map_or(&[], Vec::as_slice)
+    }
+}
+
+fn fold(word: &str) -> String {
+    word.trim().to_lowercase()
+}
+
+/// A definition as found in a dictionary.
+struct Entry {
+    word: String,
+    html: String,
+}
+
+enum Dictionary {
+    StarDict(stardict::StarDict),
+    Mdx(mdx::Mdx),
+}
+
+impl Dictionary {
+    fn open(path: &Path) -> Result<Self> {
+        let ext = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        match ext.as_str() {
+            "ifo" => Ok(Self::StarDict(stardict::StarDict::open(path)?)),
+            "mdx" => Ok(Self::Mdx(mdx::Mdx::open(path)?)),
+            _ => Err(Error::UnsupportedFormat(ext)),
+        }
+    }
+
+    fn format(&self) -> &'static str {
+        match self {
+            Self::StarDict(_) => "stardict",
+            Self::Mdx(_) => "mdx",
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            Self::StarDict(dict) => dict.name(),
+            Self::Mdx(dict) => dict.name(),
+        }
+    }
+
+    fn word_count(&self) -> usize {
+        match self {
+            Self::StarDict(dict) => dict.word_count(),
+            Self::Mdx(dict) => dict.word_count(),
+        }
+    }
+
+    fn lookup(&self, word: &str) -> Result<Vec<Entry>> {
+        match self {
+            Self::StarDict(dict) => dict.lookup(word),
+            Self::Mdx(dict) => dict.lookup(word),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DictionaryInfo {
+    pub path: PathBuf,
+    pub name: String,
+    pub format: String,
+    pub word_count: usize,
+    /// Whether the dictionary could be opened. Dictionaries on unmounted
+    /// drives stay in the list but are skipped by lookups.
+    pub available: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Definition {
+    /// Name of the dictionary the definition comes from.
+    pub dictionary: String,
+    pub word: String,
+    pub html: String,
+}
+
+struct Loaded {
+    path: PathBuf,
+    dict: Option<Arc<Dictionary>>,
+}
+
+impl Loaded {
+    fn open(path: PathBuf) -> Self {
+        let dict = match Dictionary::open(&path) {
+            Ok(dict) => Some(Arc::new(dict)),
+            Err(e) => {
+                log::warn!("Failed to open dictionary {path:?}: {e}");
+                None
+            }
+        };
+        Self { path, dict }
+    }
+
+    fn info(&self) -> DictionaryInfo {
+        let name = || {
+            self.path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        };
+        DictionaryInfo {
+            path: self.path.clone(),
+            name: self
+                .dict
+                .as_ref()
+                .map_or_else(name, |dict| dict.name().to_string()),
+            format: self
+                .dict
+                .as_ref()
+                .map_or("", |dict| dict.format())
+                .to_string(),
+            word_count: self.dict.as_ref().map_or(0, |dict| dict.word_count()),
+            available: self.dict.is_some(),
+        }
+    }
+}
+
+/// The configured dictionaries in lookup order, opened on first use.
+#[derive(Default)]
+pub struct Dictionaries(Mutex<Option<Vec<Loaded>>>);
+
+/// Runs `f` on the dictionary list, opening the saved dictionaries first if
+/// this is the first call.
+fn with_dictionaries<T>(app: &AppHandle, f: impl FnOnce(&mut Vec<Loaded>) -> T) -> T {
+    let state = app.state::<Dictionaries>();
+    let mut dictionaries = state.0.lock().unwrap();
+    let dictionaries = dictionaries.get_or_insert_with(|| {
+        let paths: Vec<PathBuf> = store::load(app, DICTIONARIES_FILE);
+        paths.into_iter().map(Loaded::open).collect()
+    });
+    f(dictionaries)
+}
+
+fn save(app: &AppHandle, dictionaries: &[Loaded]) -> Result<Vec<DictionaryInfo>> {
+    let paths = dictionaries.iter().map(|d| &d.path).collect::<Vec<_>>();
+    store::save(app, DICTIONARIES_FILE, &paths)?;
+    Ok(dictionaries.iter().map(Loaded::info).collect())
+}
+
+#[command]
+pub async fn get_dictionaries(app: AppHandle) -> Result<Vec<DictionaryInfo>> {
+    let dictionaries = tauri::async_runtime::spawn_blocking(move || {
+        with_dictionaries(&app, |dictionaries| {
+            dictionaries.iter().map(Loaded::info).collect()
+        })
+    })
+    .await?;
+    Ok(dictionaries)
+}
+
+/// Adds the StarDict dictionary described by an `.ifo` file, or an `.mdx`
+/// file, after the dictionaries already configured.
+#[command]
+pub async fn add_dictionary(app: AppHandle, path: PathBuf) -> Result<Vec<DictionaryInfo>> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let dict = Arc::new(Dictionary::open(&path)?);
+        with_dictionaries(&app, |dictionaries| {
+            match dictionaries.iter_mut().find(|d| d.path == path) {
+                Some(loaded) => loaded.dict = Some(dict),
+                None => dictionaries.push(Loaded {
+                    path,
+                    dict: Some(dict),
+                }),
+            }
+            save(&app, dictionaries)
+        })
+    })
+    .await?
+}
+
+#[command]
+pub async fn remove_dictionary(app: AppHandle, path: PathBuf) -> Result<Vec<DictionaryInfo>> {
+    tauri::async_runtime::spawn_blocking(move || {
+        with_dictionaries(&app, |dictionaries| {
+            dictionaries.retain(|d| d.path != path);
+            save(&app, dictionaries)
+        })
+    })
+    .await?
+}
+
+/// Looks `word` up in every available dictionary. When nothing matches, the
+/// word is retried without surrounding punctuation, as selections often
+/// include it. With the `context` it was selected in, a word that is found
+/// is added to the vocabulary.
+#[command]
+pub async fn lookup_word(
+    app: AppHandle,
+    word: String,
+    context: Option<WordContext>,
+) -> Result<Vec<Definition>> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let dicts = with_dictionaries(&app, |dictionaries| {
+            dictionaries
+                .iter()
+                .filter_map(|d| d.dict.clone())
+                .collect::<Vec<_>>()
+        });
+        let trimmed = word.trim_matches(|c: char| !c.is_alphanumeric());
+        let candidates = if trimmed != word.trim() {
+            vec![word.as_str(), trimmed]
+        } else {
+            vec![word.as_str()]
+        };
+        for candidate in candidates {
+            let mut definitions = Vec::new();
+            for dict in &dicts {
+                let entries = match dict.lookup(candidate) {
+                    Ok(entries) => entries,
+                    Err(e) => {
+                        log::warn!("Lookup of {candidate:?} in {} failed: {e}", dict.name());
+                        continue;
+                    }
+                };
+                // Entries spelled exactly as the selection come first
+                let (mut exact, other): (Vec<_>, Vec<_>) = entries
+                    .into_iter()
+                    .partition(|entry| entry.word == candidate.trim());
+                exact.extend(other);
+                definitions.extend(exact.into_iter().map(|entry| Definition {
+                    dictionary: dict.name().to_string(),
+                    word: entry.word,
+                    html: entry.html,
+                }));
+            }
+            if !definitions.is_empty() {
+                if let Some(context) = &context {
+                    let db = app.state::<LibraryDb>();
+                    let definition = Some(definitions[0].html.as_str());
+                    let saved = vocab::save(&db.conn(), candidate, definition, context);
+                    if let Err(e) = saved {
+                        log::warn!("Failed to add {candidate:?} to the vocabulary: {e}");
+                    }
+                }
+                return Ok(definitions);
+            }
+        }
+        Ok(Vec::new())
+    })
+    .await?
+}
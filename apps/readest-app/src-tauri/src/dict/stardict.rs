@@ -0,0 +1,247 @@
+//! StarDict dictionaries: an `.ifo` description, an `.idx` word list (maybe
+//! gzipped), the `.dict` definitions (maybe dictzipped) and an optional
+//! `.syn` synonym list.
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use flate2::read::GzDecoder;
+
+use super::dictzip::DictFile;
+use super::{Entry, Index};
+use crate::error::{Error, Result};
+use crate::utils::escape_xml;
+
+struct IdxEntry {
+    word: String,
+    offset: u64,
+    size: u64,
+}
+
+pub struct StarDict {
+    name: String,
+    /// Field types of every definition, when the `.ifo` fixes them.
+    same_type_sequence: Option<Vec<u8>>,
+    entries: Vec<IdxEntry>,
+    index: Index,
+    dict: DictFile,
+}
+
+/// Returns the first of `base` + `extensions` that exists.
+fn companion(base: &Path, extensions: &[&str]) -> Option<PathBuf> {
+    extensions
+        .iter()
+        .map(|ext| {
+            let mut path = base.as_os_str().to_owned();
+            path.push(ext);
+            PathBuf::from(path)
+        })
+        .find(|path| path.is_file())
+}
+
+fn read_maybe_gzipped(path: &Path) -> Result<Vec<u8>> {
+    let data = std::fs::read(path)?;
+    if !data.starts_with(&[0x1f, 0x8b]) {
+        return Ok(data);
+    }
+    let mut inflated = Vec::new();
+    GzDecoder::new(&data[..]).read_to_end(&mut inflated)?;
+    Ok(inflated)
+}
+
+fn parse_ifo(text: &str) -> Result<HashMap<&str, &str>> {
+    let mut lines = text.lines();
+    if lines.next().map(str::trim) != Some("StarDict's dict ifo file") {
+        return Err(Error::InvalidDictionary(
+            "missing StarDict ifo header".into(),
+        ));
+    }
+    Ok(lines
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim(), value.trim()))
+        .collect())
+}
+
+impl StarDict {
+    /// Opens the dictionary described by the `.ifo` file at `path`.
+    pub fn open(path: &Path) -> Result<Self> {
+        let ifo = std::fs::read_to_string(path)?;
+        let ifo = parse_ifo(&ifo)?;
+        let base = path.with_extension("");
+        let missing = |what: &str| Error::InvalidDictionary(format!("missing {what} file"));
+        let idx_path = companion(&base, &[".idx", ".idx.gz"]).ok_or_else(|| missing("idx"))?;
+        let dict_path = companion(&base, &[".dict", ".dict.dz"]).ok_or_else(|| missing("dict"))?;
+        let offset_bits = ifo.get("idxoffsetbits").copied().unwrap_or("32");
+
+        let idx = read_maybe_gzipped(&idx_path)?;
+        let entries = parse_idx(&idx, offset_bits == "64")?;
+        let mut index = Index::default();
+        for (i, entry) in entries.iter().enumerate() {
+            index.insert(&entry.word, i);
+        }
+        if let Some(syn_path) = companion(&base, &[".syn"]) {
+            let syn = std::fs::read(syn_path)?;
+            for (word, i) in parse_syn(&syn) {
+                if i < entries.len() {
+                    index.insert(&word, i);
+                }
+            }
+        }
+
+        let name = ifo
+            .get("bookname")
+            .map(|name| name.to_string())
+            .filter(|name| !name.is_empty())
+            .or_else(|| Some(base.file_name()?.to_string_lossy().into_owned()))
+            .unwrap_or_default();
+        Ok(Self {
+            name,
+            same_type_sequence: ifo
+                .get("sametypesequence")
+                .filter(|types| !types.is_empty())
+                .map(|types| types.as_bytes().to_vec()),
+            entries,
+            index,
+            dict: DictFile::open(&dict_path)?,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn word_count(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn lookup(&self, word: &str) -> Result<Vec<Entry>> {
+        self.index
+            .get(word)
+            .iter()
+            .map(|&i| {
+                let entry = &self.entries[i];
+                let data = self.dict.read(entry.offset, entry.size)?;
+                Ok(Entry {
+                    word: entry.word.clone(),
+                    html: self.to_html(&data),
+                })
+            })
+            .collect()
+    }
+
+    fn to_html(&self, data: &[u8]) -> String {
+        let fields = match &self.same_type_sequence {
+            Some(types) => split_typed_fields(data, types),
+            None => split_fields(data),
+        };
+        fields
+            .into_iter()
+            .filter_map(|(kind, field)| field_html(kind, field))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+fn parse_idx(data: &[u8], offset_64: bool) -> Result<Vec<IdxEntry>> {
+    let offset_len = if offset_64 { 8 } else { 4 };
+    let mut entries = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let corrupt = || Error::InvalidDictionary("truncated idx file".into());
+        let end = rest.iter().position(|&b| b == 0).ok_or_else(corrupt)?;
+        let word = String::from_utf8_lossy(&rest[..end]).into_owned();
+        let numbers = rest
+            .get(end + 1..end + 1 + offset_len + 4)
+            .ok_or_else(corrupt)?;
+        let (offset, size) = numbers.split_at(offset_len);
+        let offset = if offset_64 {
+            u64::from_be_bytes(offset.try_into().unwrap())
+        } else {
+            u32::from_be_bytes(offset.try_into().unwrap()) as u64
+        };
+        let size = u32::from_be_bytes(size.try_into().unwrap()) as u64;
+        entries.push(IdxEntry { word, offset, size });
+        rest = &rest[end + 1 + offset_len + 4..];
+    }
+    Ok(entries)
+}
+
+/// Synonyms and the index of the `.idx` entry they point to.
+fn parse_syn(data: &[u8]) -> Vec<(String, usize)> {
+    let mut synonyms = Vec::new();
+    let mut rest = data;
+    while let Some(end) = rest.iter().position(|&b| b == 0) {
+        let Some(index) = rest.get(end + 1..end + 5) else {
+            break;
+        };
+        let index = u32::from_be_bytes(index.try_into().unwrap()) as usize;
+        synonyms.push((String::from_utf8_lossy(&rest[..end]).into_owned(), index));
+        rest = &rest[end + 5..];
+    }
+    synonyms
+}
+
+/// Splits one field off `data`. Lowercase types are NUL-terminated text,
+/// uppercase types are binary data prefixed with their size. The last field
+/// of a `sametypesequence` entry has neither and runs to the end.
+fn take_field(data: &[u8], kind: u8, last: bool) -> (&[u8], &[u8]) {
+    if last {
+        return (data, &[]);
+    }
+    if kind.is_ascii_lowercase() {
+        let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+        (&data[..end], data.get(end + 1..).unwrap_or_default())
+    } else {
+        let size = data
+            .get(..4)
+            .map(|size| u32::from_be_bytes(size.try_into().unwrap()) as usize)
+            .unwrap_or_default();
+        let end = (4 + size).min(data.len());
+        (data.get(4..end).unwrap_or_default(), &data[end..])
+    }
+}
+
+fn split_typed_fields<'a>(data: &'a [u8], types: &[u8]) -> Vec<(u8, &'a [u8])> {
+    let mut fields = Vec::new();
+    let mut rest = data;
+    for (i, &kind) in types.iter().enumerate() {
+        let (field, tail) = take_field(rest, kind, i + 1 == types.len());
+        fields.push((kind, field));
+        rest = tail;
+    }
+    fields
+}
+
+fn split_fields(data: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut fields = Vec::new();
+    let mut rest = data;
+    while let Some((&kind, tail)) = rest.split_first() {
+        let (field, tail) = take_field(tail, kind, false);
+        fields.push((kind, field));
+        rest = tail;
+    }
+    fields
+}
+
+fn field_html(kind: u8, field: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(field);
+    let text = text.trim();
+    if text.is_empty() {
+        return None;
+    }
+    match kind {
+        // HTML, Pango markup and XDXF are close enough to render as markup
+        b'h' | b'g' | b'x' => Some(text.to_string()),
+        b't' => Some(format!(
+            "<div class=\"phonetic\">[{}]</div>",
+            escape_xml(text)
+        )),
+        b'm' | b'l' | b'y' | b'k' | b'w' => Some(format!(
+            "<div>{}</div>",
+            escape_xml(text).replace('\n', "<br>")
+        )),
+        // Sounds, pictures and other binary resources are not shown
+        _ => None,
+    }
+}
@@ -0,0 +1,137 @@
+//! Adobe Content Server tokens, the `.acsm` files that stores and library
+//! lending (OverDrive and others using Adobe Digital Editions) hand out in
+//! place of a book. A token names the book and the server that fulfills
+//! it; fulfilling takes a device activated with the reader's Adobe ID and
+//! returns the book encrypted for that device, which reading then has to
+//! decrypt. VL-Arch does neither, so fulfilling reads and checks the token
+//! and then says what the reader can do instead.
+
+use std::path::{Path, PathBuf};
+
+use roxmltree::Node;
+use serde::Serialize;
+use tauri::command;
+
+use crate::error::{Error, Result};
+use crate::formats::epub::parse_xml;
+use crate::utils::{now_millis, parse_rfc3339};
+
+const ADEPT_NS: &str = "http://ns.adobe.com/adept";
+const DC_NS: &str = "http://purl.org/dc/elements/1.1/";
+/// Formats the fulfilled book could be in for VL-Arch to open it, were it
+/// not for the DRM.
+const FORMATS: &[&str] = &["application/epub+zip", "application/pdf"];
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AcsmToken {
+    /// Server that fulfills the token.
+    pub operator_url: String,
+    pub transaction: Option<String>,
+    /// Whether the book is lent rather than bought.
+    pub loan: bool,
+    /// Milliseconds since the Unix epoch after which the token is void.
+    pub expires_at: Option<i64>,
+    pub resource: Option<String>,
+    pub title: Option<String>,
+    pub creator: Option<String>,
+    pub publisher: Option<String>,
+    pub language: Option<String>,
+    pub format: Option<String>,
+}
+
+impl AcsmToken {
+    fn expired(&self) -> bool {
+        self.expires_at.is_some_and(|at| at <= now_millis())
+    }
+}
+
+fn drm_error(message: impl Into<String>) -> Error {
+    Error::Drm(message.into())
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, ns: &str, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|child| child.has_tag_name((ns, name)))
+}
+
+fn child_text(node: Node, ns: &str, name: &str) -> Option<String> {
+    child(node, ns, name)
+        .and_then(|child| child.text())
+        .map(str::trim)
+        .filter(|text| !text.is_empty())
+        .map(str::to_string)
+}
+
+pub fn parse(text: &str) -> Result<AcsmToken> {
+    let doc = parse_xml(text)?;
+    let token = doc.root_element();
+    if !token.has_tag_name((ADEPT_NS, "fulfillmentToken")) {
+        return Err(drm_error("not an Adobe fulfillment token"));
+    }
+    let operator_url = child_text(token, ADEPT_NS, "operatorURL")
+        .ok_or_else(|| drm_error("the token names no fulfillment server"))?;
+    let item = child(token, ADEPT_NS, "resourceItemInfo");
+    let metadata = item.and_then(|item| child(item, ADEPT_NS, "metadata"));
+    let dc = |name| metadata.and_then(|metadata| child_text(metadata, DC_NS, name));
+    Ok(AcsmToken {
+        operator_url,
+        transaction: child_text(token, ADEPT_NS, "transaction"),
+        loan: token.attribute("fulfillmentType") == Some("loan"),
+        expires_at: child_text(token, ADEPT_NS, "expiration")
+            .as_deref()
+            .and_then(parse_rfc3339),
+        resource: item.and_then(|item| child_text(item, ADEPT_NS, "resource")),
+        title: dc("title"),
+        creator: dc("creator"),
+        publisher: dc("publisher"),
+        language: dc("language"),
+        format: dc("format"),
+    })
+}
+
+pub fn read(path: &Path) -> Result<AcsmToken> {
+    let bytes = std::fs::read(path)?;
+    parse(String::from_utf8_lossy(&bytes).trim_start_matches('\u{feff}'))
+}
+
+/// Fulfills `token`, as far as VL-Arch can: every case it cannot handle
+/// fails with what the reader can do instead.
+fn fulfill(token: &AcsmToken) -> Result<PathBuf> {
+    let title = token
+        .title
+        .as_deref()
+        .map_or("this book".to_string(), |title| format!("\"{title}\""));
+    if token.expired() {
+        return Err(drm_error(format!(
+            "the link to {title} has expired, download it again from the {}",
+            if token.loan { "library" } else { "store" }
+        )));
+    }
+    if let Some(format) = token
+        .format
+        .as_deref()
+        .filter(|format| !FORMATS.contains(format))
+    {
+        return Err(drm_error(format!(
+            "{title} comes as {format}, which VL-Arch cannot open"
+        )));
+    }
+    // Activation with an Adobe ID only makes sense together with decrypting
+    // what comes back, which is removing the DRM
+    Err(drm_error(format!(
+        "{title} is protected with Adobe DRM, which VL-Arch cannot open; \
+         open the .acsm file in Adobe Digital Editions to read it"
+    )))
+}
+
+/// What the token at `path` is for, for the frontend to show.
+#[command]
+pub async fn inspect_acsm(path: PathBuf) -> Result<AcsmToken> {
+    read(&path)
+}
+
+/// Fulfills the token at `path`, returning where the book was saved.
+#[command]
+pub async fn fulfill_acsm(path: PathBuf) -> Result<PathBuf> {
+    fulfill(&read(&path)?)
+}
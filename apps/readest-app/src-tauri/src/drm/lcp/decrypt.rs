@@ -0,0 +1,198 @@
+//! Decryption of the entries of LCP-protected EPUBs. Each is encrypted
+//! with AES-256-CBC under the content key, its IV in the first block, and
+//! may have been deflated first. CBC decrypts any block from the one
+//! before it, so a range of an entry that was not deflated is decrypted
+//! on its own, as media are read in ranges; deflated ones, text mostly,
+//! are decrypted and inflated whole.
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::ops::Range;
+
+use aes::cipher::block_padding::Pkcs7;
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockDecrypt, BlockDecryptMut, KeyInit, KeyIvInit};
+use aes::Aes256;
+use flate2::read::DeflateDecoder;
+use percent_encoding::percent_decode_str;
+
+use super::lcp_error;
+use crate::error::Result;
+use crate::formats::epub::stream::{MappedArchive, MAX_RESERVED};
+use crate::formats::epub::{attr, parse_xml};
+
+const ENCRYPTION_PATH: &str = "META-INF/encryption.xml";
+const AES256_CBC: &str = "http://www.w3.org/2001/04/xmlenc#aes256-cbc";
+const BLOCK: u64 = 16;
+/// The `Compression` method of deflated entries.
+const DEFLATE: &str = "8";
+
+/// `data`, an IV followed by the blocks encrypted with `key`, decrypted.
+pub(super) fn decrypt(key: &[u8; 32], data: &[u8]) -> Result<Vec<u8>> {
+    if data.len() < 2 * BLOCK as usize || data.len() % BLOCK as usize != 0 {
+        return Err(lcp_error("the encrypted data is truncated"));
+    }
+    let (iv, data) = data.split_at(BLOCK as usize);
+    let mut buf = data.to_vec();
+    let len = cbc::Decryptor::<Aes256>::new(key.into(), iv.into())
+        .decrypt_padded_mut::<Pkcs7>(&mut buf)
+        .map_err(|_| lcp_error("the key does not decrypt the data"))?
+        .len();
+    buf.truncate(len);
+    Ok(buf)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    deflated: bool,
+    /// Size once decrypted and inflated, when the EPUB says.
+    original_length: Option<u64>,
+}
+
+pub struct Decryptor {
+    key: [u8; 32],
+    cipher: Aes256,
+    entries: HashMap<String, Entry>,
+}
+
+impl Decryptor {
+    /// Reads which entries of `archive` are encrypted with the content key.
+    pub fn new(archive: &mut MappedArchive, key: [u8; 32]) -> Result<Self> {
+        let mut entries = HashMap::new();
+        if archive.size(ENCRYPTION_PATH)?.is_some() {
+            let text = archive.read(ENCRYPTION_PATH)?.into_vec();
+            let text = String::from_utf8_lossy(&text);
+            let doc = parse_xml(text.trim_start_matches('\u{feff}'))?;
+            for data in doc
+                .descendants()
+                .filter(|node| node.tag_name().name() == "EncryptedData")
+            {
+                let element = |name: &str| {
+                    data.descendants()
+                        .find(|node| node.tag_name().name() == name)
+                };
+                let encrypted = element("EncryptionMethod")
+                    .and_then(|node| attr(node, "Algorithm"))
+                    == Some(AES256_CBC);
+                // Font obfuscation and other keys than the license's are not ours
+                let with_content_key = element("RetrievalMethod")
+                    .and_then(|node| attr(node, "URI"))
+                    .is_some_and(|uri| uri.contains("license.lcpl"));
+                let Some(uri) = element("CipherReference").and_then(|node| attr(node, "URI"))
+                else {
+                    continue;
+                };
+                if !encrypted || !with_content_key {
+                    continue;
+                }
+                let compression = element("Compression");
+                entries.insert(
+                    percent_decode_str(uri).decode_utf8_lossy().into_owned(),
+                    Entry {
+                        deflated: compression.and_then(|node| attr(node, "Method"))
+                            == Some(DEFLATE),
+                        original_length: compression
+                            .and_then(|node| attr(node, "OriginalLength"))
+                            .and_then(|length| length.parse().ok()),
+                    },
+                );
+            }
+        }
+        Ok(Self {
+            key,
+            cipher: Aes256::new(&key.into()),
+            entries,
+        })
+    }
+
+    pub fn is_encrypted(&self, name: &str) -> bool {
+        self.entries.contains_key(name)
+    }
+
+    /// Blocks `blocks` of the plaintext of entry `name`, read with the
+    /// block before them.
+    fn decrypt_blocks(
+        &self,
+        archive: &mut MappedArchive,
+        name: &str,
+        blocks: Range<u64>,
+    ) -> Result<Vec<u8>> {
+        let data = archive.read_range(name, blocks.start * BLOCK..(blocks.end + 1) * BLOCK)?;
+        if data.len() < BLOCK as usize || data.len() % BLOCK as usize != 0 {
+            return Err(lcp_error(format!("{name} is truncated")));
+        }
+        let mut plain = Vec::with_capacity(data.len() - BLOCK as usize);
+        for pair in data.windows(2 * BLOCK as usize).step_by(BLOCK as usize) {
+            let (previous, block) = pair.split_at(BLOCK as usize);
+            let mut block = GenericArray::clone_from_slice(block);
+            self.cipher.decrypt_block(&mut block);
+            plain.extend(block.iter().zip(previous).map(|(b, p)| b ^ p));
+        }
+        Ok(plain)
+    }
+
+    fn decrypt_whole(
+        &self,
+        archive: &mut MappedArchive,
+        name: &str,
+        entry: Entry,
+    ) -> Result<Vec<u8>> {
+        let data = decrypt(&self.key, &archive.read(name)?)?;
+        if !entry.deflated {
+            return Ok(data);
+        }
+        let reserved = entry.original_length.unwrap_or(0).min(MAX_RESERVED);
+        let mut inflated = Vec::with_capacity(reserved as usize);
+        DeflateDecoder::new(data.as_slice()).read_to_end(&mut inflated)?;
+        Ok(inflated)
+    }
+
+    /// Size of encrypted entry `name` once decrypted.
+    pub fn size(&self, archive: &mut MappedArchive, name: &str) -> Result<Option<u64>> {
+        let Some(&entry) = self.entries.get(name) else {
+            return Ok(None);
+        };
+        let Some(size) = archive.size(name)? else {
+            return Ok(None);
+        };
+        if entry.deflated {
+            return match entry.original_length {
+                Some(length) => Ok(Some(length)),
+                None => Ok(Some(self.decrypt_whole(archive, name, entry)?.len() as u64)),
+            };
+        }
+        // The padding is in the last block
+        let blocks = size / BLOCK;
+        if blocks < 2 {
+            return Err(lcp_error(format!("{name} is truncated")));
+        }
+        let last = self.decrypt_blocks(archive, name, blocks - 2..blocks - 1)?;
+        let padding = u64::from(last.last().copied().unwrap_or_default());
+        if padding == 0 || padding > BLOCK {
+            return Err(lcp_error(format!("the key does not decrypt {name}")));
+        }
+        Ok(Some((blocks - 1) * BLOCK - padding))
+    }
+
+    /// Bytes `range` of encrypted entry `name` decrypted, clamped to its size.
+    pub fn read_range(
+        &self,
+        archive: &mut MappedArchive,
+        name: &str,
+        range: Range<u64>,
+    ) -> Result<Vec<u8>> {
+        let entry = self.entries[name];
+        let size = self.size(archive, name)?.unwrap_or_default();
+        let end = range.end.min(size);
+        let start = range.start.min(end);
+        if entry.deflated || (start == 0 && end == size) {
+            let data = self.decrypt_whole(archive, name, entry)?;
+            let end = (end as usize).min(data.len());
+            return Ok(data[(start as usize).min(end)..end].to_vec());
+        }
+        let first = start / BLOCK;
+        let data = self.decrypt_blocks(archive, name, first..end.div_ceil(BLOCK))?;
+        let offset = (first * BLOCK) as usize;
+        Ok(data[start as usize - offset..end as usize - offset].to_vec())
+    }
+}
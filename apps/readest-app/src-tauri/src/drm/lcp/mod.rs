@@ -0,0 +1,562 @@
+//! Readium LCP, the DRM many public libraries lend EPUBs with. A loan comes
+//! as an `.lcpl` license, which points at the EPUB it is for; fulfilling
+//! it downloads the EPUB and puts the license inside, as readers expect.
+//! The license holds the content key, encrypted with a key derived from
+//! the reader's passphrase, and is signed by the provider. Unlocking checks
+//! the signature, the loan period and the passphrase, whose derived key is
+//! then kept in the keychain so that the books of the same provider open
+//! without asking again. Entries of the EPUB are decrypted as they are
+//! read through the `book://` protocol, see [`decrypt`].
+//!
+//! Only the basic profile is supported: the keys of licenses issued for
+//! the production profile are derived with a transform that EDRLab only
+//! hands to certified readers. The signing certificate is checked to have
+//! signed the license, not to chain up to EDRLab's root.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures_util::TryStreamExt;
+use ring::signature;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tauri::{command, AppHandle, Manager};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::error::{Error, Result};
+use crate::formats::epub::stream::MappedArchive;
+use crate::utils::{now_millis, parse_rfc3339};
+use crate::{net, paths, secrets};
+
+pub mod decrypt;
+
+pub use decrypt::Decryptor;
+
+/// Where an EPUB keeps its license.
+pub const LICENSE_PATH: &str = "META-INF/license.lcpl";
+const BASIC_PROFILE: &str = "http://readium.org/lcp/basic-profile";
+const SHA256: &str = "http://www.w3.org/2001/04/xmlenc#sha256";
+const RSA_SHA256: &str = "http://www.w3.org/2001/04/xmldsig-more#rsa-sha256";
+const ECDSA_SHA256: &str = "http://www.w3.org/2001/04/xmldsig-more#ecdsa-sha256";
+const DOWNLOADS_DIR: &str = "lcp";
+/// Keychain entries of the user keys that opened books of a provider.
+const KEYS_SECRET_PREFIX: &str = "lcp.";
+/// User keys remembered per provider, most recent first.
+const MAX_KEYS: usize = 8;
+
+#[derive(Debug, Clone, Deserialize)]
+struct License {
+    id: String,
+    issued: String,
+    provider: String,
+    encryption: Encryption,
+    #[serde(default)]
+    links: Vec<Link>,
+    #[serde(default)]
+    rights: Rights,
+    signature: Signature,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Encryption {
+    profile: String,
+    content_key: EncryptedKey,
+    user_key: UserKey,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct EncryptedKey {
+    encrypted_value: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct UserKey {
+    algorithm: String,
+    #[serde(default)]
+    text_hint: String,
+    key_check: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Link {
+    rel: String,
+    href: String,
+    #[serde(rename = "type")]
+    mime_type: Option<String>,
+    length: Option<u64>,
+    /// Base64 SHA-256 of the resource.
+    hash: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct Rights {
+    print: Option<u64>,
+    copy: Option<u64>,
+    start: Option<String>,
+    end: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Signature {
+    algorithm: String,
+    certificate: String,
+    value: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct StatusDocument {
+    status: String,
+}
+
+/// A license with the JSON it was read from, which its signature covers.
+struct Parsed {
+    license: License,
+    json: Value,
+    bytes: Vec<u8>,
+}
+
+fn lcp_error(message: impl Into<String>) -> Error {
+    Error::Drm(message.into())
+}
+
+impl License {
+    fn link(&self, rel: &str) -> Option<&Link> {
+        self.links.iter().find(|link| link.rel == rel)
+    }
+
+    fn starts_at(&self) -> Option<i64> {
+        self.rights.start.as_deref().and_then(parse_rfc3339)
+    }
+
+    fn ends_at(&self) -> Option<i64> {
+        self.rights.end.as_deref().and_then(parse_rfc3339)
+    }
+}
+
+/// `value` serialized as the signature covers it: keys in order, no
+/// whitespace.
+fn canonical(value: &Value, out: &mut String) {
+    match value {
+        Value::Object(map) => {
+            let mut entries = map.iter().collect::<Vec<_>>();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            out.push('{');
+            for (i, (key, value)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&Value::String(key.clone()).to_string());
+                out.push(':');
+                canonical(value, out);
+            }
+            out.push('}');
+        }
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                canonical(item, out);
+            }
+            out.push(']');
+        }
+        value => out.push_str(&value.to_string()),
+    }
+}
+
+/// One DER element at the start of `data`: its tag, its contents and what
+/// follows it.
+fn der(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = data.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (usize::from(first), rest)
+    } else {
+        let n = usize::from(first & 0x7f);
+        if n == 0 || n > 4 || rest.len() < n {
+            return None;
+        }
+        let len = rest[..n]
+            .iter()
+            .fold(0usize, |len, &b| len << 8 | usize::from(b));
+        (len, &rest[n..])
+    };
+    (rest.len() >= len).then(|| (tag, &rest[..len], &rest[len..]))
+}
+
+/// The subject public key of X.509 certificate `cert`, as `ring` takes it.
+fn public_key(cert: &[u8]) -> Option<&[u8]> {
+    const VERSION_TAG: u8 = 0xa0;
+    let (_, cert, _) = der(cert)?;
+    let (_, mut tbs, _) = der(cert)?;
+    if tbs.first() == Some(&VERSION_TAG) {
+        tbs = der(tbs)?.2;
+    }
+    // Serial number, signature algorithm, issuer, validity and subject
+    for _ in 0..5 {
+        tbs = der(tbs)?.2;
+    }
+    let (_, info, _) = der(tbs)?;
+    let (_, key, _) = der(der(info)?.2)?;
+    // A bit string starts with its count of unused bits
+    key.split_first().map(|(_, key)| key)
+}
+
+impl Parsed {
+    fn from_bytes(bytes: Vec<u8>) -> Result<Self> {
+        let json: Value = serde_json::from_slice(&bytes)?;
+        let license = serde_json::from_value(json.clone())
+            .map_err(|e| lcp_error(format!("invalid LCP license: {e}")))?;
+        Ok(Self {
+            license,
+            json,
+            bytes,
+        })
+    }
+
+    /// Checks that the certificate in the license signed it.
+    fn verify_signature(&self) -> Result<()> {
+        let mut unsigned = self.json.clone();
+        if let Some(map) = unsigned.as_object_mut() {
+            map.remove("signature");
+        }
+        let mut message = String::new();
+        canonical(&unsigned, &mut message);
+        let signed = &self.license.signature;
+        let invalid = || lcp_error("the signature of the license is invalid");
+        let cert = BASE64.decode(&signed.certificate).map_err(|_| invalid())?;
+        let value = BASE64.decode(&signed.value).map_err(|_| invalid())?;
+        let key = public_key(&cert).ok_or_else(invalid)?;
+        let algorithm: &dyn signature::VerificationAlgorithm = match signed.algorithm.as_str() {
+            RSA_SHA256 => &signature::RSA_PKCS1_2048_8192_SHA256,
+            ECDSA_SHA256 if value.len() == 64 => &signature::ECDSA_P256_SHA256_FIXED,
+            ECDSA_SHA256 => &signature::ECDSA_P256_SHA256_ASN1,
+            other => return Err(lcp_error(format!("unsupported signature {other}"))),
+        };
+        signature::UnparsedPublicKey::new(algorithm, key)
+            .verify(message.as_bytes(), &value)
+            .map_err(|_| invalid())
+    }
+
+    /// Checks the signature, the profile and the loan period.
+    fn validate(&self) -> Result<()> {
+        let license = &self.license;
+        if license.encryption.profile != BASIC_PROFILE {
+            return Err(lcp_error(
+                "this LCP book needs a reader certified by EDRLab, which VL-Arch is not",
+            ));
+        }
+        if license.encryption.user_key.algorithm != SHA256 {
+            return Err(lcp_error(format!(
+                "unsupported passphrase hash {}",
+                license.encryption.user_key.algorithm
+            )));
+        }
+        self.verify_signature()?;
+        let now = now_millis();
+        if license.starts_at().is_some_and(|start| now < start) {
+            return Err(lcp_error(format!(
+                "the loan starts on {}",
+                license.rights.start.as_deref().unwrap_or_default()
+            )));
+        }
+        if license.ends_at().is_some_and(|end| end <= now) {
+            return Err(lcp_error("the loan has ended"));
+        }
+        Ok(())
+    }
+
+    /// The content key, when `user_key` is the one the license is for.
+    fn content_key(&self, user_key: &[u8; 32]) -> Option<[u8; 32]> {
+        let encryption = &self.license.encryption;
+        let check = BASE64.decode(&encryption.user_key.key_check).ok()?;
+        if decrypt::decrypt(user_key, &check).ok()? != self.license.id.as_bytes() {
+            return None;
+        }
+        let content_key = BASE64
+            .decode(&encryption.content_key.encrypted_value)
+            .ok()?;
+        decrypt::decrypt(user_key, &content_key)
+            .ok()?
+            .try_into()
+            .ok()
+    }
+}
+
+/// The license of `path`, an `.lcpl` file or an EPUB with one inside.
+fn read_license(path: &Path) -> Result<Parsed> {
+    if crate::formats::extension(path) == "lcpl" {
+        return Parsed::from_bytes(std::fs::read(path)?);
+    }
+    let mut zip = ZipArchive::new(BufReader::new(File::open(path)?))?;
+    let mut entry = zip
+        .by_name(LICENSE_PATH)
+        .map_err(|_| lcp_error("the book has no LCP license"))?;
+    let mut bytes = Vec::new();
+    entry.read_to_end(&mut bytes)?;
+    Parsed::from_bytes(bytes)
+}
+
+fn user_key(passphrase: &str) -> [u8; 32] {
+    Sha256::digest(passphrase.as_bytes()).into()
+}
+
+fn keys_secret(provider: &str) -> String {
+    format!("{KEYS_SECRET_PREFIX}{provider}")
+}
+
+/// The user keys that opened books of `provider` before.
+fn stored_keys(provider: &str) -> Vec<[u8; 32]> {
+    let stored = match secrets::get(&keys_secret(provider)) {
+        Ok(stored) => stored.unwrap_or_default(),
+        Err(e) => {
+            log::warn!("Failed to read the LCP keys of {provider}: {e}");
+            return Vec::new();
+        }
+    };
+    stored
+        .split(',')
+        .filter_map(|key| BASE64.decode(key).ok()?.try_into().ok())
+        .collect()
+}
+
+fn remember_key(provider: &str, key: &[u8; 32]) -> Result<()> {
+    let mut keys = stored_keys(provider);
+    keys.retain(|stored| stored != key);
+    keys.insert(0, *key);
+    keys.truncate(MAX_KEYS);
+    let value = keys
+        .iter()
+        .map(|key| BASE64.encode(key))
+        .collect::<Vec<_>>()
+        .join(",");
+    secrets::store(&keys_secret(provider), Some(&value))
+}
+
+/// Content keys of the licenses unlocked this run, by license id.
+#[derive(Default)]
+pub struct LcpKeys(Mutex<HashMap<String, [u8; 32]>>);
+
+impl LcpKeys {
+    /// The content key of `parsed`, from this run or from the user keys in
+    /// the keychain.
+    fn content_key(&self, parsed: &Parsed) -> Option<[u8; 32]> {
+        let id = &parsed.license.id;
+        if let Some(key) = self.0.lock().unwrap().get(id) {
+            return Some(*key);
+        }
+        let key = stored_keys(&parsed.license.provider)
+            .iter()
+            .find_map(|user_key| parsed.content_key(user_key))?;
+        self.0.lock().unwrap().insert(id.clone(), key);
+        Some(key)
+    }
+}
+
+/// What decrypts the entries of `archive`, if it has an LCP license. Fails
+/// when the license is not valid or not unlocked yet.
+pub fn decryptor(app: &AppHandle, archive: &mut MappedArchive) -> Result<Option<Decryptor>> {
+    if archive.size(LICENSE_PATH)?.is_none() {
+        return Ok(None);
+    }
+    let parsed = Parsed::from_bytes(archive.read(LICENSE_PATH)?.into_vec())?;
+    parsed.validate()?;
+    let key = app
+        .state::<LcpKeys>()
+        .content_key(&parsed)
+        .ok_or_else(|| lcp_error("the passphrase of the book is needed to open it"))?;
+    Ok(Some(Decryptor::new(archive, key)?))
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LicenseInfo {
+    pub id: String,
+    pub provider: String,
+    pub issued: String,
+    /// Helps the reader remember the passphrase.
+    pub hint: String,
+    /// Page where the provider helps further.
+    pub hint_url: Option<String>,
+    /// Where the book of an `.lcpl` file is downloaded from.
+    pub publication_url: Option<String>,
+    pub starts_at: Option<i64>,
+    pub ends_at: Option<i64>,
+    /// Pages that may be printed, unlimited when `None`.
+    pub print: Option<u64>,
+    /// Characters that may be copied, unlimited when `None`.
+    pub copy: Option<u64>,
+    /// Whether the book opens without asking for the passphrase.
+    pub unlocked: bool,
+}
+
+/// The license of `path`, an `.lcpl` file or an EPUB with one inside.
+#[command]
+pub async fn get_lcp_license(app: AppHandle, path: PathBuf) -> Result<LicenseInfo> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let parsed = read_license(&path)?;
+        let unlocked = app.state::<LcpKeys>().content_key(&parsed).is_some();
+        let license = &parsed.license;
+        Ok(LicenseInfo {
+            id: license.id.clone(),
+            provider: license.provider.clone(),
+            issued: license.issued.clone(),
+            hint: license.encryption.user_key.text_hint.clone(),
+            hint_url: license.link("hint").map(|link| link.href.clone()),
+            publication_url: license.link("publication").map(|link| link.href.clone()),
+            starts_at: license.starts_at(),
+            ends_at: license.ends_at(),
+            print: license.rights.print,
+            copy: license.rights.copy,
+            unlocked,
+        })
+    })
+    .await?
+}
+
+/// Unlocks the book of license `path` with `passphrase`, which is kept in
+/// the keychain, as a derived key, for the books of the same provider.
+#[command]
+pub async fn unlock_lcp(app: AppHandle, path: PathBuf, passphrase: String) -> Result<()> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let parsed = read_license(&path)?;
+        parsed.validate()?;
+        let user_key = user_key(&passphrase);
+        let content_key = parsed
+            .content_key(&user_key)
+            .ok_or_else(|| lcp_error("wrong passphrase"))?;
+        app.state::<LcpKeys>()
+            .0
+            .lock()
+            .unwrap()
+            .insert(parsed.license.id.clone(), content_key);
+        remember_key(&parsed.license.provider, &user_key)
+    })
+    .await?
+}
+
+/// Fails when the status document of the license says the loan is over.
+/// Being offline is no reason not to open a book, so failing to fetch it
+/// is not either.
+async fn check_status(license: &License) -> Result<()> {
+    let Some(link) = license.link("status") else {
+        return Ok(());
+    };
+    let status = async {
+        net::client()
+            .get(&link.href)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<StatusDocument>()
+            .await
+    };
+    match status.await {
+        Ok(document) => match document.status.as_str() {
+            "revoked" | "returned" | "cancelled" | "expired" => {
+                Err(lcp_error(format!("the license was {}", document.status)))
+            }
+            _ => Ok(()),
+        },
+        Err(e) => {
+            log::warn!(
+                "Failed to fetch the status of LCP license {}: {e}",
+                license.id
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Copies the EPUB at `from` to `to` with `license` in it, in place of any
+/// it had.
+fn embed_license(from: &Path, to: &Path, license: &[u8]) -> Result<()> {
+    let mut source = ZipArchive::new(BufReader::new(File::open(from)?))?;
+    let mut zip = ZipWriter::new(BufWriter::new(File::create(to)?));
+    for i in 0..source.len() {
+        let entry = source.by_index_raw(i)?;
+        if entry.name() != LICENSE_PATH {
+            zip.raw_copy_file(entry)?;
+        }
+    }
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    zip.start_file(LICENSE_PATH, options)?;
+    zip.write_all(license)?;
+    zip.finish()?.flush()?;
+    Ok(())
+}
+
+/// Downloads the EPUB of the `.lcpl` license at `path` and puts the
+/// license in it, returning where it was saved for the frontend to import.
+#[command]
+pub async fn fulfill_lcp_license(app: AppHandle, path: PathBuf) -> Result<PathBuf> {
+    let parsed = read_license(&path)?;
+    parsed.validate()?;
+    check_status(&parsed.license).await?;
+    let link = parsed
+        .license
+        .link("publication")
+        .ok_or_else(|| lcp_error("the license links to no book"))?;
+    if link
+        .mime_type
+        .as_deref()
+        .is_some_and(|mime| mime != "application/epub+zip")
+    {
+        return Err(lcp_error(format!(
+            "the book comes as {}, which VL-Arch cannot open",
+            link.mime_type.as_deref().unwrap_or_default()
+        )));
+    }
+
+    let dir = paths::cache_dir(&app)?.join(DOWNLOADS_DIR);
+    tokio::fs::create_dir_all(&dir).await?;
+    let download = dir.join(format!("{}.part", parsed.license.id));
+    let response = net::client()
+        .get(&link.href)
+        .send()
+        .await?
+        .error_for_status()?;
+    let mut file = tokio::io::BufWriter::new(tokio::fs::File::create(&download).await?);
+    let mut hasher = Sha256::new();
+    let mut size = 0u64;
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = stream.try_next().await? {
+        hasher.update(&chunk);
+        size += chunk.len() as u64;
+        tokio::io::AsyncWriteExt::write_all(&mut file, &chunk).await?;
+    }
+    tokio::io::AsyncWriteExt::flush(&mut file).await?;
+    drop(file);
+    let corrupt = link.length.is_some_and(|length| length != size)
+        || link
+            .hash
+            .as_ref()
+            .is_some_and(|hash| *hash != BASE64.encode(hasher.finalize()));
+    if corrupt {
+        let _ = tokio::fs::remove_file(&download).await;
+        return Err(lcp_error("the downloaded book does not match its license"));
+    }
+
+    let title = path
+        .file_stem()
+        .map(|stem| crate::utils::sanitize_file_name(&stem.to_string_lossy()))
+        .unwrap_or_else(|| parsed.license.id.clone());
+    let book = dir.join(format!("{title}.epub"));
+    let embedded = tauri::async_runtime::spawn_blocking({
+        let (download, book) = (download.clone(), book.clone());
+        move || embed_license(&download, &book, &parsed.bytes)
+    })
+    .await?;
+    let _ = tokio::fs::remove_file(&download).await;
+    embedded?;
+    crate::allow_file_in_scopes(&app, vec![book.clone()]);
+    Ok(book)
+}
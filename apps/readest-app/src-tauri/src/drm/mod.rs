@@ -0,0 +1,110 @@
+//! Recognizes books under DRM, so that importing one fails saying what
+//! protects it rather than leaving a book that opens garbled. VL-Arch
+//! does not remove DRM; [`acsm`] reads the tokens Adobe's stores and
+//! library lending hand out instead of books, and [`lcp`] opens the books
+//! lent with Readium LCP, as any reader the lender allows does.
+
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use memmap2::Mmap;
+use zip::ZipArchive;
+
+use crate::error::{Error, Result};
+use crate::formats::{self, epub};
+
+#[cfg(feature = "acsm")]
+pub mod acsm;
+#[cfg(all(desktop, feature = "lcp"))]
+pub mod lcp;
+
+/// Algorithms of the font obfuscation EPUBs may use without DRM.
+const FONT_OBFUSCATION: &[&str] = &[
+    "http://www.idpf.org/2008/embedding",
+    "http://ns.adobe.com/pdf/enc#RC",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheme {
+    /// Adobe Content Server (ADEPT), of Adobe Digital Editions.
+    Adobe,
+    /// Readium LCP.
+    Lcp,
+    /// Apple Books (FairPlay).
+    Apple,
+    /// Encrypted content of some other kind.
+    Unknown,
+}
+
+impl Scheme {
+    fn name(self) -> &'static str {
+        match self {
+            Scheme::Adobe => "Adobe DRM",
+            Scheme::Lcp => "Readium LCP",
+            Scheme::Apple => "Apple FairPlay",
+            Scheme::Unknown => "DRM",
+        }
+    }
+}
+
+fn epub_scheme(path: &Path) -> Result<Option<Scheme>> {
+    let mut zip = ZipArchive::new(BufReader::new(File::open(path)?))?;
+    let has = |zip: &ZipArchive<_>, name: &str| zip.file_names().any(|n| n == name);
+    if has(&zip, "META-INF/rights.xml") {
+        return Ok(Some(Scheme::Adobe));
+    }
+    if has(&zip, "META-INF/license.lcpl") {
+        return Ok(Some(Scheme::Lcp));
+    }
+    if has(&zip, "META-INF/sinf.xml") {
+        return Ok(Some(Scheme::Apple));
+    }
+    let Ok(mut entry) = zip.by_name("META-INF/encryption.xml") else {
+        return Ok(None);
+    };
+    let mut text = String::new();
+    entry.read_to_string(&mut text)?;
+    let doc = epub::parse_xml(&text)?;
+    let encrypted = doc
+        .descendants()
+        .filter(|node| node.tag_name().name() == "EncryptionMethod")
+        .filter_map(|node| epub::attr(node, "Algorithm"))
+        .any(|algorithm| !FONT_OBFUSCATION.contains(&algorithm));
+    Ok(encrypted.then_some(Scheme::Unknown))
+}
+
+/// Adobe's PDFs name their security handler in the encryption dictionary,
+/// which is never in a compressed object stream, so the raw bytes tell.
+fn pdf_scheme(path: &Path) -> Result<Option<Scheme>> {
+    const HANDLER: &[u8] = b"/EBX_HANDLER";
+    let file = File::open(path)?;
+    // SAFETY: the map is read-only and dropped before returning; a book
+    // truncated meanwhile by another program is not guarded against.
+    let map = unsafe { Mmap::map(&file)? };
+    let adobe = map.windows(HANDLER.len()).any(|w| w == HANDLER);
+    Ok(adobe.then_some(Scheme::Adobe))
+}
+
+/// The DRM of the book at `path`, if it has any.
+pub fn detect(path: &Path) -> Result<Option<Scheme>> {
+    match formats::extension(path).as_str() {
+        "epub" => epub_scheme(path),
+        "pdf" => pdf_scheme(path),
+        _ => Ok(None),
+    }
+}
+
+/// Fails for a book under DRM, naming it.
+pub fn check(path: &Path) -> Result<()> {
+    match detect(path)? {
+        #[cfg(all(desktop, feature = "lcp"))]
+        Some(Scheme::Lcp) => Ok(()),
+        Some(scheme) => Err(Error::Drm(format!(
+            "{} uses {}, which VL-Arch cannot open",
+            path.file_name().unwrap_or_default().to_string_lossy(),
+            scheme.name()
+        ))),
+        None => Ok(()),
+    }
+}
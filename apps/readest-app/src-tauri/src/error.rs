@@ -0,0 +1,221 @@
+use serde::{ser::Serializer, Serialize};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Tauri(#[from] tauri::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+    #[error(transparent)]
+    Search(#[from] tantivy::TantivyError),
+    #[error(transparent)]
+    Zip(#[from] zip::result::ZipError),
+    #[error(transparent)]
+    Xml(#[from] roxmltree::Error),
+    #[error(transparent)]
+    Pdf(#[from] lopdf::Error),
+    #[error(transparent)]
+    Regex(#[from] regex::Error),
+    #[error(transparent)]
+    Image(#[from] image::ImageError),
+    #[cfg(feature = "rar")]
+    #[error(transparent)]
+    Rar(#[from] unrar::error::UnrarError),
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error(transparent)]
+    Url(#[from] url::ParseError),
+    #[cfg(desktop)]
+    #[error(transparent)]
+    Notify(#[from] notify_debouncer_full::notify::Error),
+    #[cfg(desktop)]
+    #[error(transparent)]
+    GlobalShortcut(#[from] tauri_plugin_global_shortcut::Error),
+    #[cfg(desktop)]
+    #[error(transparent)]
+    Keyring(#[from] keyring::Error),
+    #[cfg(windows)]
+    #[error(transparent)]
+    Windows(#[from] windows::core::Error),
+    #[cfg(target_os = "linux")]
+    #[error(transparent)]
+    DBus(#[from] zbus::Error),
+    #[cfg(desktop)]
+    #[error(transparent)]
+    Updater(#[from] tauri_plugin_updater::Error),
+    #[error("invalid book: {0}")]
+    InvalidBook(String),
+    #[error("unsupported format: {0}")]
+    UnsupportedFormat(String),
+    #[error("request failed with status code {0}")]
+    HttpStatus(u16),
+    #[error("invalid feed: {0}")]
+    InvalidFeed(String),
+    #[error("clipping failed: {0}")]
+    Clip(String),
+    #[error("feeds are already being updated")]
+    FeedsUpdating,
+    #[error("invalid dictionary: {0}")]
+    InvalidDictionary(String),
+    #[error("invalid library: {0}")]
+    InvalidLibrary(String),
+    #[error("invalid backup: {0}")]
+    InvalidBackup(String),
+    #[error("invalid annotations: {0}")]
+    InvalidAnnotations(String),
+    #[error("remote data changed during sync")]
+    SyncConflict,
+    #[error("no sync provider is configured")]
+    SyncNotConfigured,
+    #[error("sync encryption failed: {0}")]
+    SyncEncryption(String),
+    #[error("encryption failed: {0}")]
+    Encryption(String),
+    #[error("speech synthesis failed: {0}")]
+    Tts(String),
+    #[error("media controls failed: {0}")]
+    MediaControls(String),
+    #[error("audio playback failed: {0}")]
+    Audio(String),
+    #[error("invalid reading session: {0}")]
+    InvalidSession(String),
+    #[error("device operation failed: {0}")]
+    Device(String),
+    #[error("book storage failed: {0}")]
+    Storage(String),
+    #[error("cloud storage failed: {0}")]
+    Cloud(String),
+    #[cfg(feature = "djvu")]
+    #[error("djvu decoding failed: {0}")]
+    Djvu(String),
+    #[error("translation failed: {0}")]
+    Translation(String),
+    #[error("no translation provider is configured")]
+    TranslationNotConfigured,
+    #[error("unusable font: {0}")]
+    Font(String),
+    #[cfg(feature = "ocr")]
+    #[error("text recognition failed: {0}")]
+    Ocr(String),
+    #[error("printing failed: {0}")]
+    Print(String),
+    #[error("sending email failed: {0}")]
+    Email(String),
+    #[error("no email account is configured")]
+    EmailNotConfigured,
+    #[error("no annotations to export")]
+    NoAnnotations,
+    #[error("file association failed: {0}")]
+    FileAssociation(String),
+    #[error("shortcut {0} is already in use")]
+    ShortcutConflict(String),
+    #[error("no scheduled task named {0}")]
+    UnknownTask(String),
+    #[error("no collection with id {0}")]
+    UnknownCollection(i64),
+    #[error("cancelled")]
+    Cancelled,
+    #[error("invalid proxy: {0}")]
+    Proxy(String),
+    #[cfg(desktop)]
+    #[error("sharing failed: {0}")]
+    Share(String),
+    #[error("invalid plugin: {0}")]
+    InvalidPlugin(String),
+    #[error("plugin failed: {0}")]
+    Plugin(String),
+    #[error("no plugin {0}")]
+    UnknownPlugin(String),
+    #[error("invalid hook: {0}")]
+    InvalidHook(String),
+    #[error("hook failed: {0}")]
+    Hook(String),
+    #[cfg(desktop)]
+    #[error("crash reporting failed: {0}")]
+    CrashReport(String),
+    #[cfg(desktop)]
+    #[error("update failed: {0}")]
+    Update(String),
+    #[cfg(desktop)]
+    #[error("invalid data directory: {0}")]
+    DataDir(String),
+    #[cfg(desktop)]
+    #[error("profile: {0}")]
+    Profile(String),
+    #[error("access: {0}")]
+    Access(String),
+    #[error("protected book: {0}")]
+    Drm(String),
+    #[error("no vocabulary word with id {0}")]
+    UnknownVocabWord(i64),
+    #[error("invalid vocabulary word: {0}")]
+    InvalidVocab(String),
+    #[error("no vocabulary to export")]
+    NoVocabulary,
+    #[error("template: {0}")]
+    Template(String),
+    #[error("no note template with id {0}")]
+    UnknownTemplate(String),
+    #[error("Anki: {0}")]
+    Anki(String),
+    #[error("text transform: {0}")]
+    Transform(String),
+    #[error("pagination: {0}")]
+    Pagination(String),
+    #[error("invalid tag: {0}")]
+    InvalidTag(String),
+    #[error("book {0} is not in the trash")]
+    NotInTrash(String),
+    #[error("repair failed: {0}")]
+    Repair(String),
+    #[cfg(feature = "heif")]
+    #[error("HEIF: {0}")]
+    Heif(String),
+    #[error("math: {0}")]
+    Math(String),
+    #[error("user style: {0}")]
+    UserStyle(String),
+    #[error("clipboard: {0}")]
+    Clipboard(String),
+    #[error("download: {0}")]
+    Download(String),
+    #[error("reading goal: {0}")]
+    Goal(String),
+    #[error(transparent)]
+    Notification(#[from] tauri_plugin_notification::Error),
+    #[error("focus: {0}")]
+    Focus(String),
+    #[error("private notes: {0}")]
+    PrivateNotes(String),
+    #[error("edition: {0}")]
+    Edition(String),
+    #[error("voice note: {0}")]
+    VoiceNote(String),
+    #[error("appearance: {0}")]
+    Appearance(String),
+    #[error("local API: {0}")]
+    ApiServer(String),
+    #[cfg(desktop)]
+    #[error(
+        "the system keychain is not available ({0}); on Linux a Secret Service such as \
+         GNOME Keyring or KWallet has to be running and unlocked"
+    )]
+    KeychainUnavailable(String),
+    #[error("the server sent the whole file for a range")]
+    RangeIgnored,
+}
+
+impl Serialize for Error {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.to_string().as_ref())
+    }
+}
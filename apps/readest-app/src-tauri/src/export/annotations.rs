@@ -0,0 +1,500 @@
+//! Highlights and notes exported to Markdown, JSON or CSV, for moving them
+//! into note-taking apps. Notes are read from the per-book `config.json` the
+//! frontend keeps in the books dir, and grouped by the chapter they are in.
+
+use std::io::Write;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle, Manager};
+use tauri_plugin_dialog::DialogExt;
+use tauri_plugin_fs::{FsExt, OpenOptions};
+
+use crate::error::{Error, Result};
+use crate::formats::epub::{EpubArchive, TocItem};
+use crate::formats::pdf::{OutlineItem, PdfDocument};
+use crate::library::{self, access, db};
+use crate::utils::{format_rfc3339, sanitize_file_name};
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Markdown,
+    Json,
+    Csv,
+}
+
+impl ExportFormat {
+    /// The format named `name`, such as `md` or `markdown`.
+    #[cfg(feature = "cli")]
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "markdown" | "md" => Some(Self::Markdown),
+            "json" => Some(Self::Json),
+            "csv" => Some(Self::Csv),
+            _ => None,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Markdown => "md",
+            Self::Json => "json",
+            Self::Csv => "csv",
+        }
+    }
+
+    fn filter_name(self) -> &'static str {
+        match self {
+            Self::Markdown => "Markdown",
+            Self::Json => "JSON",
+            Self::Csv => "CSV",
+        }
+    }
+}
+
+/// The fields of the frontend's `BookNote` that are exported.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BookNote {
+    #[serde(rename = "type")]
+    kind: String,
+    cfi: String,
+    text: Option<String>,
+    style: Option<String>,
+    color: Option<String>,
+    #[serde(default)]
+    note: String,
+    created_at: i64,
+    updated_at: i64,
+    deleted_at: Option<i64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct BookConfig {
+    #[serde(default)]
+    booknotes: Vec<BookNote>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Annotation {
+    #[serde(rename = "type")]
+    kind: String,
+    /// Title of the table of contents entry the note is in.
+    chapter: Option<String>,
+    text: String,
+    note: String,
+    color: Option<String>,
+    style: Option<String>,
+    cfi: String,
+    created_at: String,
+    updated_at: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BookAnnotations {
+    book_hash: String,
+    title: String,
+    author: String,
+    annotations: Vec<Annotation>,
+}
+
+/// Numbers of the steps and offsets of a CFI in order, which compare like
+/// the positions they point to. Range CFIs are reduced to their start.
+fn cfi_steps(cfi: &str) -> Vec<u32> {
+    let inner = cfi
+        .trim()
+        .strip_prefix("epubcfi(")
+        .and_then(|cfi| cfi.strip_suffix(')'))
+        .unwrap_or(cfi);
+    let mut parts = inner.split(',');
+    let start = parts.next().unwrap_or_default().to_string() + parts.next().unwrap_or_default();
+
+    let mut steps = Vec::new();
+    let mut number = None;
+    let mut in_assertion = false;
+    for c in start.chars() {
+        match c {
+            '[' => in_assertion = true,
+            ']' => in_assertion = false,
+            _ if in_assertion => {}
+            c if c.is_ascii_digit() => {
+                let digit = c.to_digit(10).unwrap_or_default();
+                number = Some(
+                    number
+                        .unwrap_or(0u32)
+                        .saturating_mul(10)
+                        .saturating_add(digit),
+                );
+                continue;
+            }
+            _ => {}
+        }
+        steps.extend(number.take());
+    }
+    steps.extend(number);
+    steps
+}
+
+/// Index of the spine item a CFI points into, from its second step.
+fn spine_index(steps: &[u32]) -> Option<usize> {
+    let step = *steps.get(1)?;
+    (step >= 2).then(|| step as usize / 2 - 1)
+}
+
+/// Chapter titles by spine index: the first table of contents entry that
+/// points into each spine item, or the last one before it.
+pub(super) fn epub_chapters(path: &Path) -> Result<Vec<Option<String>>> {
+    fn flatten<'a>(items: &'a [TocItem], out: &mut Vec<&'a TocItem>) {
+        for item in items {
+            out.push(item);
+            flatten(&item.children, out);
+        }
+    }
+
+    let mut epub = EpubArchive::open(path)?;
+    let package = epub.package()?;
+    let toc = epub.toc()?;
+    let mut entries = Vec::new();
+    flatten(&toc, &mut entries);
+
+    let mut current = None;
+    Ok(package
+        .spine_items()
+        .map(|item| {
+            if let Some(entry) = entries.iter().find(|entry| entry.href == item.href) {
+                current = Some(entry.label.clone());
+            }
+            current.clone()
+        })
+        .collect())
+}
+
+/// Chapter titles by page index, from the last bookmark at or before each page.
+fn pdf_chapters(path: &Path) -> Result<Vec<Option<String>>> {
+    fn flatten(items: &[OutlineItem], out: &mut Vec<(u32, String)>) {
+        for item in items {
+            if let Some(page) = item.page {
+                out.push((page, item.title.clone()));
+            }
+            flatten(&item.children, out);
+        }
+    }
+
+    let pdf = PdfDocument::open(path)?;
+    let mut entries = Vec::new();
+    flatten(&pdf.outline(), &mut entries);
+    // Keep the outline order for bookmarks on the same page
+    entries.sort_by_key(|&(page, _)| page);
+    Ok((1..=pdf.page_count())
+        .map(|page| {
+            entries
+                .iter()
+                .rev()
+                .find(|(start, _)| *start <= page)
+                .map(|(_, title)| title.clone())
+        })
+        .collect())
+}
+
+fn chapters(path: &Path) -> Vec<Option<String>> {
+    let ext = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let chapters = match ext.as_str() {
+        "epub" => epub_chapters(path),
+        "pdf" => pdf_chapters(path),
+        _ => return Vec::new(),
+    };
+    chapters.unwrap_or_else(|e| {
+        log::warn!("Failed to read the chapters of {path:?}: {e}");
+        Vec::new()
+    })
+}
+
+/// Reads a book config, which may be encrypted at rest on desktop.
+#[cfg(desktop)]
+fn read_config(path: &Path) -> Result<Option<Vec<u8>>> {
+    crate::secrets::storage::read_file(path)
+}
+
+#[cfg(not(desktop))]
+fn read_config(path: &Path) -> Result<Option<Vec<u8>>> {
+    match std::fs::read(path) {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The highlights and notes of one book with the steps of their CFIs, in
+/// reading order.
+fn sorted_notes(app: &AppHandle, book_hash: &str) -> Result<Vec<(Vec<u32>, BookNote)>> {
+    let config_path = library::config_path(app, book_hash)?;
+    let config: BookConfig = match read_config(&config_path)? {
+        Some(bytes) => serde_json::from_slice(&bytes)?,
+        None => return Ok(Vec::new()),
+    };
+    let mut notes = config
+        .booknotes
+        .into_iter()
+        .filter(|note| note.deleted_at.is_none() && note.kind != "bookmark")
+        .map(|note| (cfi_steps(&note.cfi), note))
+        .collect::<Vec<_>>();
+    notes.sort_by(|(a, a_note), (b, b_note)| {
+        a.cmp(b).then(a_note.created_at.cmp(&b_note.created_at))
+    });
+    Ok(notes)
+}
+
+/// The CFIs of the highlights, notes and bookmarks of `book_hash`, with
+/// the spine item each points into.
+pub(crate) fn note_locations(
+    app: &AppHandle,
+    book_hash: &str,
+) -> Result<Vec<(String, Option<usize>)>> {
+    let config_path = library::config_path(app, book_hash)?;
+    let config: BookConfig = match read_config(&config_path)? {
+        Some(bytes) => serde_json::from_slice(&bytes)?,
+        None => return Ok(Vec::new()),
+    };
+    Ok(config
+        .booknotes
+        .into_iter()
+        .filter(|note| note.deleted_at.is_none())
+        .map(|note| {
+            let index = spine_index(&cfi_steps(&note.cfi));
+            (note.cfi, index)
+        })
+        .collect())
+}
+
+/// A highlight or note of an EPUB, by the spine item it is in.
+pub(super) struct SpineNote {
+    pub spine_index: usize,
+    pub text: String,
+    pub note: String,
+}
+
+/// The highlights and notes of `book_hash` in reading order.
+pub(super) fn spine_notes(app: &AppHandle, book_hash: &str) -> Result<Vec<SpineNote>> {
+    Ok(sorted_notes(app, book_hash)?
+        .into_iter()
+        .filter_map(|(steps, note)| {
+            Some(SpineNote {
+                spine_index: spine_index(&steps)?,
+                text: note.text.unwrap_or_default(),
+                note: note.note,
+            })
+        })
+        .collect())
+}
+
+/// Collects the highlights and notes of one book, or `None` if it has none.
+fn book_annotations(app: &AppHandle, book_hash: &str) -> Result<Option<BookAnnotations>> {
+    let notes = sorted_notes(app, book_hash)?;
+    if notes.is_empty() {
+        return Ok(None);
+    }
+
+    let book = db::get_book(&app.state::<db::LibraryDb>().conn(), book_hash)?;
+    let chapters = book
+        .as_ref()
+        .and_then(|book| library::book_path(app, book))
+        .map(|path| chapters(&path))
+        .unwrap_or_default();
+    let annotations = notes
+        .into_iter()
+        .map(|(steps, note)| Annotation {
+            kind: note.kind,
+            chapter: spine_index(&steps).and_then(|i| chapters.get(i).cloned().flatten()),
+            text: note.text.unwrap_or_default(),
+            note: note.note,
+            color: note.color,
+            style: note.style,
+            cfi: note.cfi,
+            created_at: format_rfc3339(note.created_at),
+            updated_at: format_rfc3339(note.updated_at),
+        })
+        .collect();
+    Ok(Some(BookAnnotations {
+        book_hash: book_hash.to_string(),
+        title: book
+            .as_ref()
+            .map_or_else(|| book_hash.to_string(), |book| book.title.clone()),
+        author: book.map(|book| book.author).unwrap_or_default(),
+        annotations,
+    }))
+}
+
+fn to_markdown(books: &[BookAnnotations]) -> String {
+    let mut out = String::new();
+    for (i, book) in books.iter().enumerate() {
+        if i > 0 {
+            out.push_str("---\n\n");
+        }
+        out.push_str(&format!("# {}\n\n", book.title));
+        if !book.author.is_empty() {
+            out.push_str(&format!("**Author**: {}\n\n", book.author));
+        }
+        let mut chapter = None;
+        for annotation in &book.annotations {
+            if annotation.chapter.is_some() && chapter != annotation.chapter.as_ref() {
+                chapter = annotation.chapter.as_ref();
+                out.push_str(&format!(
+                    "## {}\n\n",
+                    annotation.chapter.as_deref().unwrap_or_default()
+                ));
+            }
+            for line in annotation.text.lines() {
+                out.push_str(&format!("> {line}\n"));
+            }
+            out.push('\n');
+            if !annotation.note.is_empty() {
+                out.push_str(&format!("**Note**: {}\n\n", annotation.note.trim()));
+            }
+            out.push_str(&format!("*{}*\n\n", annotation.created_at));
+        }
+    }
+    out
+}
+
+pub(crate) fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn to_csv(books: &[BookAnnotations]) -> String {
+    let mut out = String::from(
+        "book_hash,title,author,chapter,type,text,note,color,style,created_at,updated_at,cfi\r\n",
+    );
+    for book in books {
+        for annotation in &book.annotations {
+            let fields: [&str; 12] = [
+                &book.book_hash,
+                &book.title,
+                &book.author,
+                annotation.chapter.as_deref().unwrap_or_default(),
+                &annotation.kind,
+                &annotation.text,
+                &annotation.note,
+                annotation.color.as_deref().unwrap_or_default(),
+                annotation.style.as_deref().unwrap_or_default(),
+                &annotation.created_at,
+                &annotation.updated_at,
+                &annotation.cfi,
+            ];
+            let row = fields.map(csv_field).join(",");
+            out.push_str(&row);
+            out.push_str("\r\n");
+        }
+    }
+    out
+}
+
+/// The highlights and notes of `book_hash`, or of every book when omitted,
+/// rendered in `format`, and the name the file should have without its
+/// extension.
+pub(crate) fn render(
+    app: &AppHandle,
+    book_hash: Option<&str>,
+    format: ExportFormat,
+) -> Result<(String, String)> {
+    let mut hashes = match book_hash {
+        Some(hash) => vec![hash.to_string()],
+        None => match std::fs::read_dir(library::books_dir(app)?) {
+            Ok(entries) => entries
+                .flatten()
+                .filter(|entry| entry.path().is_dir())
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        },
+    };
+    let hidden = {
+        let db = app.state::<db::LibraryDb>();
+        let conn = db.conn();
+        access::lock(app, &conn)?.hidden_books(&conn)?
+    };
+    hashes.retain(|hash| hidden.as_ref().is_some_and(|hidden| !hidden.contains(hash)));
+    let mut books = Vec::new();
+    for hash in &hashes {
+        match book_annotations(app, hash) {
+            Ok(Some(book)) => books.push(book),
+            Ok(None) => {}
+            // One broken config should not stop a full export
+            Err(e) if book_hash.is_none() => log::warn!("Skipping notes of {hash}: {e}"),
+            Err(e) => return Err(e),
+        }
+    }
+    if books.is_empty() {
+        return Err(Error::NoAnnotations);
+    }
+    books.sort_by_cached_key(|book| book.title.to_lowercase());
+
+    let content = match format {
+        ExportFormat::Markdown => to_markdown(&books),
+        ExportFormat::Json => serde_json::to_string_pretty(&books)?,
+        ExportFormat::Csv => to_csv(&books),
+    };
+    let name = match book_hash {
+        Some(_) => sanitize_file_name(&books[0].title),
+        None => "annotations".to_string(),
+    };
+    Ok((name, content))
+}
+
+/// Writes `content` to a file named `name` the user picks in a save
+/// dialog, returning where, or `None` if the dialog was cancelled.
+pub(crate) fn save_with_dialog(
+    app: &AppHandle,
+    name: &str,
+    filter_name: &str,
+    extension: &str,
+    content: &[u8],
+) -> Result<Option<String>> {
+    let Some(file) = app
+        .dialog()
+        .file()
+        .add_filter(filter_name, &[extension])
+        .set_file_name(format!("{name}.{extension}"))
+        .blocking_save_file()
+    else {
+        return Ok(None);
+    };
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    let location = file.to_string();
+    app.fs().open(file, options)?.write_all(content)?;
+    Ok(Some(location))
+}
+
+/// Exports the highlights and notes of `book_hash`, or of every book when
+/// omitted, to a file the user picks in a save dialog. Returns where the
+/// file was written, or `None` if the dialog was cancelled.
+#[command]
+pub async fn export_annotations(
+    app: AppHandle,
+    book_hash: Option<String>,
+    format: ExportFormat,
+) -> Result<Option<String>> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let (name, content) = render(&app, book_hash.as_deref(), format)?;
+        save_with_dialog(
+            &app,
+            &name,
+            format.filter_name(),
+            format.extension(),
+            content.as_bytes(),
+        )
+    })
+    .await?
+}
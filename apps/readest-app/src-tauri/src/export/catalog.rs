@@ -0,0 +1,495 @@
+//! The library's catalog exported to CSV for spreadsheets, OPML for
+//! outliners and BibTeX for reference managers, of every book or of those
+//! in a collection. The columns are the user's choice; BibTeX has fields
+//! for only some of them, and leaves the others out. Locked books are left
+//! out as they are from the library view.
+
+use std::collections::HashMap;
+
+use rusqlite::Connection;
+use serde::Deserialize;
+use serde_json::Value;
+use tauri::{command, AppHandle, Manager};
+
+use super::annotations::{csv_field, save_with_dialog};
+use crate::error::{Error, Result};
+use crate::library::access::Access;
+use crate::library::collections;
+use crate::library::db::{self, Book, BookQuery, LibraryDb, SortField};
+use crate::library::identifiers::normalize_isbn;
+use crate::utils::{escape_xml, format_rfc3339, sanitize_file_name};
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CatalogFormat {
+    Csv,
+    Opml,
+    Bibtex,
+}
+
+impl CatalogFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Opml => "opml",
+            Self::Bibtex => "bib",
+        }
+    }
+
+    fn filter_name(self) -> &'static str {
+        match self {
+            Self::Csv => "CSV",
+            Self::Opml => "OPML",
+            Self::Bibtex => "BibTeX",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Column {
+    Title,
+    Subtitle,
+    Author,
+    Series,
+    SeriesIndex,
+    Publisher,
+    Published,
+    Language,
+    Isbn,
+    Format,
+    Tags,
+    Group,
+    /// Percent read.
+    Progress,
+    Words,
+    AddedAt,
+    UpdatedAt,
+    LastReadAt,
+    Hash,
+    FilePath,
+    Url,
+    Description,
+}
+
+/// Exported when no columns are given.
+const DEFAULT_COLUMNS: &[Column] = &[
+    Column::Title,
+    Column::Author,
+    Column::Series,
+    Column::SeriesIndex,
+    Column::Publisher,
+    Column::Published,
+    Column::Language,
+    Column::Isbn,
+    Column::Format,
+    Column::Tags,
+    Column::Progress,
+    Column::AddedAt,
+];
+
+impl Column {
+    /// Header of the column in CSV.
+    fn csv_name(self) -> &'static str {
+        match self {
+            Column::Title => "title",
+            Column::Subtitle => "subtitle",
+            Column::Author => "author",
+            Column::Series => "series",
+            Column::SeriesIndex => "series_index",
+            Column::Publisher => "publisher",
+            Column::Published => "published",
+            Column::Language => "language",
+            Column::Isbn => "isbn",
+            Column::Format => "format",
+            Column::Tags => "tags",
+            Column::Group => "group",
+            Column::Progress => "progress",
+            Column::Words => "words",
+            Column::AddedAt => "added_at",
+            Column::UpdatedAt => "updated_at",
+            Column::LastReadAt => "last_read_at",
+            Column::Hash => "hash",
+            Column::FilePath => "file_path",
+            Column::Url => "url",
+            Column::Description => "description",
+        }
+    }
+
+    /// Attribute of an outline in OPML.
+    fn opml_name(self) -> &'static str {
+        match self {
+            Column::SeriesIndex => "seriesIndex",
+            Column::AddedAt => "addedAt",
+            Column::UpdatedAt => "updatedAt",
+            Column::LastReadAt => "lastReadAt",
+            Column::FilePath => "filePath",
+            column => column.csv_name(),
+        }
+    }
+
+    /// Field of a BibTeX entry, for columns that have one.
+    fn bibtex_field(self) -> Option<&'static str> {
+        Some(match self {
+            Column::Title => "title",
+            Column::Subtitle => "subtitle",
+            Column::Author => "author",
+            Column::Series => "series",
+            Column::SeriesIndex => "number",
+            Column::Publisher => "publisher",
+            Column::Published => "year",
+            Column::Language => "language",
+            Column::Isbn => "isbn",
+            Column::Tags => "keywords",
+            Column::Url => "url",
+            Column::FilePath => "file",
+            Column::Description => "abstract",
+            _ => return None,
+        })
+    }
+}
+
+/// What the catalog needs from beyond the books' own rows, each read for
+/// all books at once.
+#[derive(Default)]
+struct Extras {
+    isbns: HashMap<String, String>,
+    words: HashMap<String, i64>,
+    last_read: HashMap<String, i64>,
+}
+
+fn pairs<T: rusqlite::types::FromSql>(conn: &Connection, sql: &str) -> Result<HashMap<String, T>> {
+    let mut stmt = conn.prepare(sql)?;
+    let rows = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(rows)
+}
+
+impl Extras {
+    fn load(conn: &Connection, columns: &[Column]) -> Result<Extras> {
+        let mut extras = Extras::default();
+        if columns.contains(&Column::Isbn) {
+            // The first found of each book, as the later ones overwrite it
+            extras.isbns = pairs(
+                conn,
+                "SELECT book_hash, value FROM book_identifiers WHERE scheme = 'isbn'
+                 ORDER BY position DESC",
+            )?;
+        }
+        if columns.contains(&Column::Words) {
+            extras.words = pairs(conn, "SELECT book_hash, words FROM book_analysis")?;
+        }
+        if columns.contains(&Column::LastReadAt) {
+            extras.last_read = pairs(
+                conn,
+                "SELECT book_hash, MAX(ended_at) FROM reading_sessions GROUP BY book_hash",
+            )?;
+        }
+        Ok(extras)
+    }
+}
+
+fn metadata<'a>(book: &'a Book, key: &str) -> Option<&'a Value> {
+    book.metadata.as_ref()?.get(key)
+}
+
+fn metadata_text(book: &Book, key: &str) -> String {
+    match metadata(book, key) {
+        Some(Value::String(text)) => text.trim().to_string(),
+        _ => String::new(),
+    }
+}
+
+fn date(millis: i64) -> String {
+    format_rfc3339(millis)[..10].to_string()
+}
+
+fn isbn(book: &Book, extras: &Extras) -> String {
+    let identifier = metadata_text(book, "identifier").to_ascii_lowercase();
+    let identifier = identifier
+        .trim_start_matches("urn:")
+        .trim_start_matches("isbn:");
+    identifier
+        .chars()
+        .all(|c| c.is_ascii_digit() || matches!(c, 'x' | '-' | ' '))
+        .then(|| normalize_isbn(identifier))
+        .flatten()
+        .or_else(|| extras.isbns.get(&book.hash).cloned())
+        .unwrap_or_default()
+}
+
+/// The value of `column` for `book`, empty when it has none.
+fn value(book: &Book, column: Column, extras: &Extras) -> String {
+    match column {
+        Column::Title => book.title.clone(),
+        Column::Subtitle => metadata_text(book, "subtitle"),
+        Column::Author => book.author.clone(),
+        Column::Series => metadata_text(book, "series"),
+        Column::SeriesIndex => metadata(book, "seriesIndex")
+            .and_then(Value::as_f64)
+            .map(|index| index.to_string())
+            .unwrap_or_default(),
+        Column::Publisher => metadata_text(book, "publisher"),
+        Column::Published => metadata_text(book, "published"),
+        Column::Language => book.primary_language.clone().unwrap_or_default(),
+        Column::Isbn => isbn(book, extras),
+        Column::Format => book.format.clone(),
+        Column::Tags => book.tags.join("; "),
+        Column::Group => book.group_name.clone().unwrap_or_default(),
+        Column::Progress => book
+            .progress
+            .filter(|&(_, total)| total > 0)
+            .map(|(current, total)| (current.min(total) * 100 / total).to_string())
+            .unwrap_or_default(),
+        Column::Words => extras
+            .words
+            .get(&book.hash)
+            .map(i64::to_string)
+            .unwrap_or_default(),
+        Column::AddedAt => date(book.created_at),
+        Column::UpdatedAt => date(book.updated_at),
+        Column::LastReadAt => extras
+            .last_read
+            .get(&book.hash)
+            .map(|&at| date(at))
+            .unwrap_or_default(),
+        Column::Hash => book.hash.clone(),
+        Column::FilePath => book.file_path.clone().unwrap_or_default(),
+        Column::Url => book.url.clone().unwrap_or_default(),
+        Column::Description => {
+            crate::formats::html::html_to_text(&metadata_text(book, "description"))
+        }
+    }
+}
+
+fn to_csv(books: &[Book], columns: &[Column], extras: &Extras) -> String {
+    let header = columns.iter().map(|column| column.csv_name());
+    let mut out = header.collect::<Vec<_>>().join(",");
+    out.push_str("\r\n");
+    for book in books {
+        let row = columns
+            .iter()
+            .map(|&column| csv_field(&value(book, column, extras)))
+            .collect::<Vec<_>>();
+        out.push_str(&row.join(","));
+        out.push_str("\r\n");
+    }
+    out
+}
+
+fn opml_outline(out: &mut String, indent: &str, book: &Book, columns: &[Column], extras: &Extras) {
+    out.push_str(&format!(
+        "{indent}<outline text=\"{}\"",
+        escape_xml(&book.title)
+    ));
+    for &column in columns.iter().filter(|&&column| column != Column::Title) {
+        let value = value(book, column, extras);
+        if !value.is_empty() {
+            // Attribute values lose their line breaks anyway
+            let value = value.replace(['\r', '\n'], " ");
+            out.push_str(&format!(
+                " {}=\"{}\"",
+                column.opml_name(),
+                escape_xml(&value)
+            ));
+        }
+    }
+    out.push_str("/>\n");
+}
+
+/// The books nested in outlines of their groups, with those in no group
+/// after the groups.
+fn to_opml(name: &str, books: &[Book], columns: &[Column], extras: &Extras) -> String {
+    let mut groups: Vec<(&str, Vec<&Book>)> = Vec::new();
+    let mut ungrouped = Vec::new();
+    for book in books {
+        match book.group_name.as_deref().filter(|group| !group.is_empty()) {
+            Some(group) => match groups.iter_mut().find(|(name, _)| *name == group) {
+                Some((_, books)) => books.push(book),
+                None => groups.push((group, vec![book])),
+            },
+            None => ungrouped.push(book),
+        }
+    }
+    groups.sort_by_cached_key(|(name, _)| name.to_lowercase());
+
+    let mut out =
+        String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<opml version=\"2.0\">\n");
+    out.push_str(&format!(
+        "  <head>\n    <title>{}</title>\n  </head>\n  <body>\n",
+        escape_xml(name)
+    ));
+    for (group, books) in &groups {
+        out.push_str(&format!("    <outline text=\"{}\">\n", escape_xml(group)));
+        for book in books {
+            opml_outline(&mut out, "      ", book, columns, extras);
+        }
+        out.push_str("    </outline>\n");
+    }
+    for book in ungrouped {
+        opml_outline(&mut out, "    ", book, columns, extras);
+    }
+    out.push_str("  </body>\n</opml>\n");
+    out
+}
+
+fn escape_bibtex(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => out.push_str("\\textbackslash{}"),
+            '{' | '}' | '&' | '%' | '$' | '#' | '_' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '~' => out.push_str("\\textasciitilde{}"),
+            '^' => out.push_str("\\textasciicircum{}"),
+            '\r' | '\n' => out.push(' '),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// A citation key such as `tolkien1954fellowship`, from the family name
+/// of the first author, the year and the first word of the title.
+fn citation_key(book: &Book, year: &str) -> String {
+    let first_author = book
+        .author
+        .split(['&', ';'])
+        .next()
+        .unwrap_or_default()
+        .split(" and ")
+        .next()
+        .unwrap_or_default();
+    // "Family, Given" or "Given Family"
+    let family = match first_author.split_once(',') {
+        Some((family, _)) => family,
+        None => first_author.split_whitespace().last().unwrap_or_default(),
+    };
+    let word = book
+        .title
+        .split_whitespace()
+        .find(|word| word.chars().filter(char::is_ascii_alphanumeric).count() > 3)
+        .or_else(|| book.title.split_whitespace().next())
+        .unwrap_or_default();
+    let key = [family, year, word]
+        .concat()
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .collect::<String>()
+        .to_ascii_lowercase();
+    if key.is_empty() {
+        format!("book{}", &book.hash[..book.hash.len().min(8)])
+    } else {
+        key
+    }
+}
+
+fn to_bibtex(books: &[Book], columns: &[Column], extras: &Extras) -> String {
+    let mut keys: HashMap<String, u32> = HashMap::new();
+    let mut out = String::new();
+    for book in books {
+        let published = metadata_text(book, "published");
+        let year = published
+            .as_bytes()
+            .windows(4)
+            .find(|digits| digits.iter().all(u8::is_ascii_digit))
+            .map(|digits| String::from_utf8_lossy(digits).into_owned())
+            .unwrap_or_default();
+        let mut key = citation_key(book, &year);
+        let seen = keys.entry(key.clone()).or_default();
+        *seen += 1;
+        if *seen > 1 {
+            // tolkien1954fellowship, then tolkien1954fellowshipb
+            key.push(char::from(b'a' + ((*seen - 1) % 26) as u8));
+        }
+        out.push_str(&format!("@book{{{key},\n"));
+        for &column in columns {
+            let Some(field) = column.bibtex_field() else {
+                continue;
+            };
+            let value = match column {
+                Column::Published => year.clone(),
+                Column::Author => value(book, column, extras)
+                    .replace(" & ", " and ")
+                    .replace("; ", " and "),
+                Column::Tags => book.tags.join(", "),
+                _ => value(book, column, extras),
+            };
+            if !value.is_empty() {
+                out.push_str(&format!("  {field} = {{{}}},\n", escape_bibtex(&value)));
+            }
+        }
+        out.push_str("}\n\n");
+    }
+    out
+}
+
+/// The catalog of `books` in `format`, with a title of `name` where the
+/// format has one.
+fn render(
+    conn: &Connection,
+    name: &str,
+    books: &[Book],
+    format: CatalogFormat,
+    columns: &[Column],
+) -> Result<String> {
+    let extras = Extras::load(conn, columns)?;
+    Ok(match format {
+        CatalogFormat::Csv => to_csv(books, columns, &extras),
+        CatalogFormat::Opml => to_opml(name, books, columns, &extras),
+        CatalogFormat::Bibtex => to_bibtex(books, columns, &extras),
+    })
+}
+
+/// Exports the catalog of the library, or of collection `collection_id`,
+/// with `columns` or the default ones, to a file the user picks in a save
+/// dialog. Returns where the file was written, or `None` if the dialog
+/// was cancelled.
+#[command]
+pub async fn export_catalog(
+    app: AppHandle,
+    format: CatalogFormat,
+    columns: Option<Vec<Column>>,
+    collection_id: Option<i64>,
+) -> Result<Option<String>> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let columns = columns
+            .filter(|columns| !columns.is_empty())
+            .unwrap_or_else(|| DEFAULT_COLUMNS.to_vec());
+        let (name, content) = {
+            let db = app.state::<LibraryDb>();
+            let access = app.state::<Access>();
+            let conn = db.conn();
+            let query = BookQuery {
+                sort_by: SortField::Title,
+                ascending: true,
+                lock: access.lock(&conn)?,
+                ..BookQuery::default()
+            };
+            let (name, page) = match collection_id {
+                Some(id) => {
+                    let collection = collections::get(&conn, id)?;
+                    if access.is_locked(&collection) {
+                        return Err(Error::Access(format!("{} is locked", collection.name)));
+                    }
+                    let page = collections::evaluate(&conn, &collection.rule, &query)?;
+                    (collection.name, page)
+                }
+                None => ("Library".to_string(), db::query_books(&conn, &query)?),
+            };
+            let content = render(&conn, &name, &page.books, format, &columns)?;
+            (name, content)
+        };
+        save_with_dialog(
+            &app,
+            &sanitize_file_name(&name),
+            format.filter_name(),
+            format.extension(),
+            content.as_bytes(),
+        )
+    })
+    .await?
+}
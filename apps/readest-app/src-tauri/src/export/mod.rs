@@ -0,0 +1,4 @@
+pub mod annotations;
+pub mod catalog;
+pub mod pdf;
+pub mod templates;
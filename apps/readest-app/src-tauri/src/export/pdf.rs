@@ -0,0 +1,695 @@
+//! Typesetting book text into a paginated PDF, for sharing excerpts and for
+//! printing. Lines break greedily at spaces and between CJK characters, and
+//! every page gets a number. Text is set in a TrueType font embedded with
+//! only the glyphs used, or in Times when there is none, which only covers
+//! Windows-1252.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use lopdf::content::{Content, Operation};
+use lopdf::{dictionary, Document, Object, ObjectId, Stream, StringFormat};
+use serde::Deserialize;
+use tauri::{command, AppHandle, Manager};
+use tauri_plugin_dialog::DialogExt;
+use tauri_plugin_fs::{FsExt, OpenOptions};
+
+use super::annotations;
+use crate::error::{Error, Result};
+use crate::fonts::subset::subset;
+use crate::fonts::truetype::{extract_face, TrueTypeFont};
+use crate::formats::epub::EpubArchive;
+use crate::formats::html::html_to_text;
+use crate::library::{self, db};
+use crate::utils::sanitize_file_name;
+
+const FONT_RESOURCE: &str = "F1";
+const DEFAULT_MARGIN: f32 = 64.0;
+const DEFAULT_FONT_SIZE: f32 = 11.0;
+/// Line height as a multiple of the font size.
+const LEADING: f32 = 1.45;
+
+/// Advances of Times-Roman for `' '..='~'`, in thousandths of the font size.
+const TIMES_WIDTHS: [u16; 95] = [
+    250, 333, 408, 500, 500, 833, 778, 180, 333, 333, 500, 564, 250, 333, 250, 278, 500, 500, 500,
+    500, 500, 500, 500, 500, 500, 500, 278, 278, 564, 564, 564, 444, 921, 722, 667, 667, 722, 611,
+    556, 722, 722, 333, 389, 722, 611, 889, 722, 722, 556, 722, 667, 556, 611, 722, 722, 944, 722,
+    722, 611, 333, 278, 333, 469, 500, 333, 444, 500, 444, 500, 444, 333, 500, 500, 278, 278, 500,
+    278, 778, 500, 500, 500, 500, 333, 389, 278, 500, 500, 722, 500, 500, 444, 480, 200, 480, 541,
+];
+
+/// Characters of Windows-1252 bytes `0x80..=0x9F`, where it differs from Latin-1.
+const WIN_ANSI_HIGH: [char; 32] = [
+    '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8D}', 'Ž', '\u{8F}',
+    '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9D}', 'ž', 'Ÿ',
+];
+
+fn win_ansi(c: char) -> Option<u8> {
+    match c as u32 {
+        0x20..=0x7E | 0xA0..=0xFF => Some(c as u8),
+        _ => WIN_ANSI_HIGH
+            .iter()
+            .position(|&high| high == c && !('\u{80}'..='\u{9F}').contains(&high))
+            .map(|i| 0x80 + i as u8),
+    }
+}
+
+/// Whether a line may break before and after `c`.
+fn is_cjk(c: char) -> bool {
+    matches!(c as u32,
+        0x2E80..=0x9FFF | 0xAC00..=0xD7AF | 0xF900..=0xFAFF | 0xFF00..=0xFFEF | 0x20000..=0x3FFFF)
+}
+
+/// Page size in points.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PageSize {
+    #[default]
+    A4,
+    A5,
+    Letter,
+    Legal,
+    Custom {
+        width: f32,
+        height: f32,
+    },
+}
+
+impl PageSize {
+    fn dimensions(self) -> (f32, f32) {
+        match self {
+            Self::A4 => (595.0, 842.0),
+            Self::A5 => (420.0, 595.0),
+            Self::Letter => (612.0, 792.0),
+            Self::Legal => (612.0, 1008.0),
+            Self::Custom { width, height } => (width.max(72.0), height.max(72.0)),
+        }
+    }
+}
+
+/// Margins in points.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Margins {
+    pub top: f32,
+    pub right: f32,
+    pub bottom: f32,
+    pub left: f32,
+}
+
+impl Default for Margins {
+    fn default() -> Self {
+        Self {
+            top: DEFAULT_MARGIN,
+            right: DEFAULT_MARGIN,
+            bottom: DEFAULT_MARGIN,
+            left: DEFAULT_MARGIN,
+        }
+    }
+}
+
+pub struct PageSetup {
+    pub size: PageSize,
+    pub margins: Margins,
+    pub font_size: f32,
+}
+
+impl Default for PageSetup {
+    fn default() -> Self {
+        Self {
+            size: PageSize::default(),
+            margins: Margins::default(),
+            font_size: DEFAULT_FONT_SIZE,
+        }
+    }
+}
+
+pub enum Font {
+    Times,
+    Embedded { font: TrueTypeFont, name: String },
+}
+
+impl Font {
+    fn embedded(font: TrueTypeFont, file: &str) -> Self {
+        let name = Path::new(file)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy())
+            .unwrap_or_default()
+            .chars()
+            .filter(char::is_ascii_alphanumeric)
+            .collect::<String>();
+        Self::Embedded {
+            font,
+            name: if name.is_empty() {
+                "Embedded".into()
+            } else {
+                name
+            },
+        }
+    }
+
+    /// Advance of `c` in thousandths of the font size.
+    fn advance(&self, c: char) -> f32 {
+        match self {
+            Self::Times => match c as u32 {
+                // Accented letters are close enough to the average for breaking lines
+                code @ 0x20..=0x7E => TIMES_WIDTHS[code as usize - 0x20] as f32,
+                _ => 500.0,
+            },
+            Self::Embedded { font, .. } => font.advance(font.glyph(c).unwrap_or(0)),
+        }
+    }
+
+    fn width(&self, text: &str, size: f32) -> f32 {
+        text.chars().map(|c| self.advance(c)).sum::<f32>() * size / 1000.0
+    }
+
+    /// How many characters of `text` it has no glyph for.
+    fn missing(&self, text: &str) -> usize {
+        text.chars()
+            .filter(|c| !c.is_whitespace())
+            .filter(|&c| match self {
+                Self::Times => win_ansi(c).is_none(),
+                Self::Embedded { font, .. } => font.glyph(c).is_none(),
+            })
+            .count()
+    }
+}
+
+/// The font the book embeds that covers all of `text`, preferring upright
+/// regular faces, or Times when none does.
+pub fn book_font(epub: &mut EpubArchive<impl Read + Seek>, text: &str) -> Font {
+    let Ok(package) = epub.package() else {
+        return Font::Times;
+    };
+    let mut fonts = package
+        .manifest
+        .iter()
+        .filter(|item| {
+            item.media_type.contains("font") || item.href.to_ascii_lowercase().ends_with(".ttf")
+        })
+        .map(|item| item.href.clone())
+        .collect::<Vec<_>>();
+    fonts.sort_by_key(|href| {
+        let href = href.to_ascii_lowercase();
+        ["bold", "italic", "oblique"]
+            .iter()
+            .any(|style| href.contains(style))
+    });
+    for href in fonts {
+        // Obfuscated fonts fail to parse and are skipped like any other
+        let Ok(font) = epub.read_entry(&href).and_then(TrueTypeFont::parse) else {
+            continue;
+        };
+        let font = Font::embedded(font, &href);
+        if font.missing(text) == 0 {
+            return font;
+        }
+    }
+    Font::Times
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockStyle {
+    Body,
+    Heading,
+    /// A highlighted passage, indented and smaller.
+    Quote,
+    /// A note on the passage before it.
+    Note,
+}
+
+impl BlockStyle {
+    /// Font size, indent and space before, in points, with body text at `size`.
+    fn metrics(self, size: f32) -> (f32, f32, f32) {
+        match self {
+            Self::Body => (size, 0.0, size * 0.6),
+            Self::Heading => (size * 1.4, 0.0, size * 1.2),
+            Self::Quote => (size * 0.9, size * 2.0, size * 0.9),
+            Self::Note => (size * 0.9, size * 3.0, size * 0.3),
+        }
+    }
+}
+
+pub struct Block {
+    pub text: String,
+    pub style: BlockStyle,
+    /// Whether the block starts a new page.
+    pub page_break: bool,
+}
+
+impl Block {
+    pub fn new(text: impl Into<String>, style: BlockStyle) -> Self {
+        Self {
+            text: text.into(),
+            style,
+            page_break: false,
+        }
+    }
+
+    /// Body blocks of the non-empty lines of `text`.
+    pub fn paragraphs(text: &str) -> Vec<Self> {
+        text.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| Self::new(line, BlockStyle::Body))
+            .collect()
+    }
+}
+
+/// Splits `paragraph` into lines no wider than `max` points at `size`.
+fn wrap(font: &Font, paragraph: &str, size: f32, max: f32) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    let mut width = 0.0;
+    // Where the line can break, as a byte offset into it
+    let mut breakable = None;
+    for c in paragraph.chars() {
+        let c = if c.is_whitespace() { ' ' } else { c };
+        if c == ' ' && (line.is_empty() || line.ends_with(' ')) {
+            continue;
+        }
+        let advance = font.advance(c) * size / 1000.0;
+        if c != ' ' && width + advance > max && !line.is_empty() {
+            let rest = line.split_off(breakable.unwrap_or(line.len()));
+            lines.push(line.trim_end().to_string());
+            line = rest;
+            width = font.width(&line, size);
+            breakable = None;
+        }
+        if is_cjk(c) && !line.is_empty() {
+            breakable = Some(line.len());
+        }
+        line.push(c);
+        width += advance;
+        if c == ' ' || is_cjk(c) {
+            breakable = Some(line.len());
+        }
+    }
+    if !line.trim().is_empty() {
+        lines.push(line.trim_end().to_string());
+    }
+    lines
+}
+
+/// Text strings of a PDF, which are UTF-16 with a byte order mark.
+fn text_string(text: &str) -> Object {
+    let mut bytes = vec![0xFE, 0xFF];
+    bytes.extend(text.encode_utf16().flat_map(u16::to_be_bytes));
+    Object::String(bytes, StringFormat::Hexadecimal)
+}
+
+struct Writer<'a> {
+    font: &'a Font,
+    setup: &'a PageSetup,
+    /// Characters of the glyphs used, for widths and the `ToUnicode` map.
+    glyphs: BTreeMap<u16, char>,
+    pages: Vec<Vec<Operation>>,
+}
+
+impl Writer<'_> {
+    fn encode(&mut self, text: &str) -> Object {
+        match self.font {
+            Font::Times => Object::String(
+                text.chars().map(|c| win_ansi(c).unwrap_or(b'?')).collect(),
+                StringFormat::Literal,
+            ),
+            Font::Embedded { font, .. } => {
+                let mut bytes = Vec::with_capacity(text.len() * 2);
+                for c in text.chars() {
+                    let glyph = font.glyph(c).unwrap_or(0);
+                    self.glyphs.entry(glyph).or_insert(c);
+                    bytes.extend(glyph.to_be_bytes());
+                }
+                Object::String(bytes, StringFormat::Hexadecimal)
+            }
+        }
+    }
+
+    fn show(&mut self, text: &str, x: f32, y: f32, size: f32) {
+        let encoded = self.encode(text);
+        let page = self.pages.last_mut().expect("a page is open");
+        page.extend([
+            Operation::new("BT", vec![]),
+            Operation::new("Tf", vec![FONT_RESOURCE.into(), size.into()]),
+            Operation::new("Td", vec![x.into(), y.into()]),
+            Operation::new("Tj", vec![encoded]),
+            Operation::new("ET", vec![]),
+        ]);
+    }
+
+    fn new_page(&mut self) {
+        self.pages.push(Vec::new());
+        let number = self.pages.len().to_string();
+        let size = self.setup.font_size * 0.8;
+        let (page_width, _) = self.setup.size.dimensions();
+        let x = (page_width - self.font.width(&number, size)) / 2.0;
+        self.show(&number, x, self.setup.margins.bottom / 2.0, size);
+    }
+
+    fn widths(&self, font: &TrueTypeFont) -> Object {
+        let mut widths = Vec::with_capacity(self.glyphs.len() * 2);
+        for &glyph in self.glyphs.keys() {
+            widths.push(Object::from(glyph));
+            widths.push(vec![Object::from(font.advance(glyph).round() as i64)].into());
+        }
+        widths.into()
+    }
+
+    fn to_unicode(&self) -> Vec<u8> {
+        let mut cmap = String::from(
+            "/CIDInit /ProcSet findresource begin\n12 dict begin\nbegincmap\n\
+             /CIDSystemInfo << /Registry (Adobe) /Ordering (UCS) /Supplement 0 >> def\n\
+             /CMapName /Adobe-Identity-UCS def\n/CMapType 2 def\n\
+             1 begincodespacerange\n<0000> <FFFF>\nendcodespacerange\n",
+        );
+        let glyphs = self.glyphs.iter().collect::<Vec<_>>();
+        for chunk in glyphs.chunks(100) {
+            cmap.push_str(&format!("{} beginbfchar\n", chunk.len()));
+            for (glyph, c) in chunk {
+                let units = c
+                    .encode_utf16(&mut [0; 2])
+                    .iter()
+                    .map(|unit| format!("{unit:04X}"))
+                    .collect::<String>();
+                cmap.push_str(&format!("<{glyph:04X}> <{units}>\n"));
+            }
+            cmap.push_str("endbfchar\n");
+        }
+        cmap.push_str("endcmap\nCMapName currentdict /CMap defineresource pop\nend\nend\n");
+        cmap.into_bytes()
+    }
+
+    fn font_object(&self, doc: &mut Document) -> ObjectId {
+        let Font::Embedded { font, name } = self.font else {
+            return doc.add_object(dictionary! {
+                "Type" => "Font",
+                "Subtype" => "Type1",
+                "BaseFont" => "Times-Roman",
+                "Encoding" => "WinAnsiEncoding",
+            });
+        };
+        let chars = self.glyphs.values().copied().collect::<BTreeSet<_>>();
+        let glyphs = self.glyphs.keys().copied().collect::<BTreeSet<_>>();
+        let (data, name) = match subset(&font.data, &chars, &glyphs) {
+            // Subset fonts are named with a tag of six capital letters
+            Ok(data) => (data, format!("VLARCH+{name}")),
+            Err(e) => {
+                log::warn!("Embedding the whole font, subsetting failed: {e}");
+                (font.data.clone(), name.clone())
+            }
+        };
+        let mut file = Stream::new(dictionary! { "Length1" => data.len() as i64 }, data);
+        let _ = file.compress();
+        let file = doc.add_object(file);
+        let [x_min, y_min, x_max, y_max] = font.bbox;
+        let descriptor = doc.add_object(dictionary! {
+            "Type" => "FontDescriptor",
+            "FontName" => name.as_str(),
+            // Nonsymbolic
+            "Flags" => 32,
+            "FontBBox" => vec![
+                font.scale(x_min).into(),
+                font.scale(y_min).into(),
+                font.scale(x_max).into(),
+                font.scale(y_max).into(),
+            ],
+            "ItalicAngle" => 0,
+            "Ascent" => font.scale(font.ascent),
+            "Descent" => font.scale(font.descent),
+            "CapHeight" => font.scale(font.ascent),
+            "StemV" => 80,
+            "FontFile2" => file,
+        });
+        let cid_font = doc.add_object(dictionary! {
+            "Type" => "Font",
+            "Subtype" => "CIDFontType2",
+            "BaseFont" => name.as_str(),
+            "CIDSystemInfo" => dictionary! {
+                "Registry" => Object::string_literal("Adobe"),
+                "Ordering" => Object::string_literal("Identity"),
+                "Supplement" => 0,
+            },
+            "FontDescriptor" => descriptor,
+            "W" => self.widths(font),
+            "CIDToGIDMap" => "Identity",
+        });
+        let mut to_unicode = Stream::new(dictionary! {}, self.to_unicode());
+        let _ = to_unicode.compress();
+        let to_unicode = doc.add_object(to_unicode);
+        doc.add_object(dictionary! {
+            "Type" => "Font",
+            "Subtype" => "Type0",
+            "BaseFont" => name.as_str(),
+            "Encoding" => "Identity-H",
+            "DescendantFonts" => vec![cid_font.into()],
+            "ToUnicode" => to_unicode,
+        })
+    }
+}
+
+/// Sets `blocks` in `font` on pages of `setup`, as a document named `title`.
+pub fn typeset(blocks: &[Block], font: &Font, title: &str, setup: &PageSetup) -> Result<Document> {
+    let (page_width, page_height) = setup.size.dimensions();
+    let margins = setup.margins;
+    let width = (page_width - margins.left - margins.right).max(setup.font_size * 4.0);
+    let mut writer = Writer {
+        font,
+        setup,
+        glyphs: BTreeMap::new(),
+        pages: Vec::new(),
+    };
+    let top = page_height - margins.top;
+    let mut y = top;
+    writer.new_page();
+    for block in blocks {
+        let (size, indent, space) = block.style.metrics(setup.font_size);
+        let lines = wrap(font, &block.text, size, width - indent);
+        if lines.is_empty() {
+            continue;
+        }
+        if block.page_break && y < top {
+            writer.new_page();
+            y = top;
+        } else if y < top {
+            y -= space;
+        }
+        for line in lines {
+            if y - size < margins.bottom {
+                writer.new_page();
+                y = top;
+            }
+            y -= size;
+            writer.show(&line, margins.left + indent, y, size);
+            y -= size * (LEADING - 1.0);
+        }
+    }
+
+    let mut doc = Document::with_version("1.7");
+    let font_id = writer.font_object(&mut doc);
+    let pages_id = doc.new_object_id();
+    let mut kids = Vec::with_capacity(writer.pages.len());
+    for operations in std::mem::take(&mut writer.pages) {
+        let mut content = Stream::new(dictionary! {}, Content { operations }.encode()?);
+        let _ = content.compress();
+        let content = doc.add_object(content);
+        kids.push(Object::from(doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "Contents" => content,
+        })));
+    }
+    let count = kids.len() as i64;
+    doc.objects.insert(
+        pages_id,
+        dictionary! {
+            "Type" => "Pages",
+            "Kids" => kids,
+            "Count" => count,
+            "MediaBox" => vec![0.into(), 0.into(), page_width.into(), page_height.into()],
+            "Resources" => dictionary! {
+                "Font" => dictionary! { FONT_RESOURCE => font_id },
+            },
+        }
+        .into(),
+    );
+    let catalog = doc.add_object(dictionary! {
+        "Type" => "Catalog",
+        "Pages" => pages_id,
+    });
+    let info = doc.add_object(dictionary! { "Title" => text_string(title) });
+    doc.trailer.set("Root", catalog);
+    doc.trailer.set("Info", info);
+    Ok(doc)
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum FontChoice {
+    /// A font the book embeds, if one covers the text.
+    #[default]
+    Book,
+    Times,
+    /// A TrueType font file of the user's.
+    File {
+        path: PathBuf,
+        /// Face in a font collection.
+        #[serde(default)]
+        index: u32,
+    },
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PdfOptions {
+    #[serde(default)]
+    pub page_size: PageSize,
+    #[serde(default)]
+    pub margins: Margins,
+    #[serde(default)]
+    pub font: FontChoice,
+    pub font_size: Option<f32>,
+    /// Whether highlights and notes follow the chapter they are in.
+    #[serde(default)]
+    pub annotations: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum PdfContent {
+    /// Spine documents `from..=to`, the whole book when unset.
+    Chapters {
+        from: Option<usize>,
+        to: Option<usize>,
+    },
+    /// Text selected in the reader, one paragraph per line.
+    Selection { text: String },
+}
+
+/// Blocks of the spine documents `from..=to`, each starting a page, with
+/// the `notes` in each after it.
+fn chapter_blocks(
+    path: &Path,
+    from: Option<usize>,
+    to: Option<usize>,
+    notes: &[annotations::SpineNote],
+) -> Result<Vec<Block>> {
+    let titles = annotations::epub_chapters(path).unwrap_or_default();
+    let mut epub = EpubArchive::open(path)?;
+    let package = epub.package()?;
+    let spine = package.spine_items().collect::<Vec<_>>();
+    let last = to.unwrap_or(usize::MAX).min(spine.len().saturating_sub(1));
+    let mut blocks = Vec::new();
+    for index in from.unwrap_or(0)..=last {
+        let Some(item) = spine
+            .get(index)
+            .filter(|item| item.media_type.contains("html"))
+        else {
+            continue;
+        };
+        let mut chapter = Block::paragraphs(&html_to_text(&epub.read_entry_string(&item.href)?));
+        let title = titles.get(index).cloned().flatten();
+        if let (Some(first), Some(title)) = (chapter.first_mut(), title) {
+            if first.text.eq_ignore_ascii_case(title.trim()) {
+                first.style = BlockStyle::Heading;
+            }
+        }
+        if let Some(first) = chapter.first_mut() {
+            first.page_break = true;
+        }
+        blocks.extend(chapter);
+        for note in notes.iter().filter(|n| n.spine_index == index) {
+            if !note.text.trim().is_empty() {
+                blocks.push(Block::new(note.text.trim(), BlockStyle::Quote));
+            }
+            if !note.note.trim().is_empty() {
+                blocks.push(Block::new(note.note.trim(), BlockStyle::Note));
+            }
+        }
+    }
+    Ok(blocks)
+}
+
+fn export(
+    app: &AppHandle,
+    book_hash: &str,
+    content: &PdfContent,
+    options: &PdfOptions,
+) -> Result<Option<String>> {
+    let book = db::get_book(&app.state::<db::LibraryDb>().conn(), book_hash)?
+        .ok_or_else(|| Error::InvalidBook(format!("no book {book_hash}")))?;
+    let path = library::book_path(app, &book)
+        .ok_or_else(|| Error::InvalidBook(format!("{} has no file", book.title)))?;
+    let is_epub = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("epub"));
+
+    let blocks = match content {
+        PdfContent::Chapters { from, to } => {
+            if !is_epub {
+                return Err(Error::UnsupportedFormat(book.format.to_lowercase()));
+            }
+            let notes = if options.annotations {
+                annotations::spine_notes(app, book_hash)?
+            } else {
+                Vec::new()
+            };
+            chapter_blocks(&path, *from, *to, &notes)?
+        }
+        PdfContent::Selection { text } => Block::paragraphs(text),
+    };
+    let font = match &options.font {
+        FontChoice::Book if is_epub => {
+            let text = blocks.iter().map(|b| b.text.as_str()).collect::<String>();
+            book_font(&mut EpubArchive::open(&path)?, &text)
+        }
+        FontChoice::Book | FontChoice::Times => Font::Times,
+        FontChoice::File { path, index } => Font::embedded(
+            TrueTypeFont::parse(extract_face(&std::fs::read(path)?, *index)?)?,
+            &path.to_string_lossy(),
+        ),
+    };
+    let setup = PageSetup {
+        size: options.page_size,
+        margins: options.margins,
+        font_size: options
+            .font_size
+            .unwrap_or(DEFAULT_FONT_SIZE)
+            .clamp(4.0, 72.0),
+    };
+    let mut doc = typeset(&blocks, &font, &book.title, &setup)?;
+
+    let Some(file) = app
+        .dialog()
+        .file()
+        .add_filter("PDF", &["pdf"])
+        .set_file_name(format!("{}.pdf", sanitize_file_name(&book.title)))
+        .blocking_save_file()
+    else {
+        return Ok(None);
+    };
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    let location = file.to_string();
+    let mut writer = std::io::BufWriter::new(app.fs().open(file, options)?);
+    doc.save_to(&mut writer)?;
+    writer.flush()?;
+    Ok(Some(location))
+}
+
+/// Typesets `content` of book `book_hash` into a PDF saved where the user
+/// picks in a save dialog. Returns where it was written, or `None` if the
+/// dialog was cancelled.
+#[command]
+pub async fn export_pdf(
+    app: AppHandle,
+    book_hash: String,
+    content: PdfContent,
+    options: Option<PdfOptions>,
+) -> Result<Option<String>> {
+    tauri::async_runtime::spawn_blocking(move || {
+        export(&app, &book_hash, &content, &options.unwrap_or_default())
+    })
+    .await?
+}
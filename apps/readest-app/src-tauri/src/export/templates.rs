@@ -0,0 +1,657 @@
+//! Note templates: a selection, with the book it is in, rendered through a
+//! template of the user's into text for the clipboard, a Markdown file it
+//! is written or appended to, or a note added to Anki through the
+//! AnkiConnect add-on. Rendering is done here so a template gives the same
+//! result on every platform.
+//!
+//! Templates are a subset of Handlebars: `{{name}}` is replaced with a
+//! value, HTML-escaped for Anki, and `{{{name}}}` never escaped;
+//! `{{#if name}}..{{else}}..{{/if}}` and `{{#unless name}}..{{/unless}}`
+//! test whether a value is empty; `{{#each tags}}{{this}}{{/each}}` goes
+//! through a list; `{{! ..}}` is a comment. Naming a value that does not
+//! exist is an error.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tauri::{command, AppHandle, Manager};
+use url::Url;
+
+use crate::error::{Error, Result};
+use crate::library::db::{self, LibraryDb};
+use crate::store;
+use crate::utils::{format_rfc3339, now_millis, sanitize_file_name};
+
+const TEMPLATES_FILE: &str = "note_templates.json";
+const ANKI_CONNECT_URL: &str = "http://127.0.0.1:8765";
+const ANKI_CONNECT_VERSION: u32 = 6;
+
+/// The values templates can name.
+const VARIABLES: &[&str] = &[
+    "text", "note", "context", "chapter", "page", "cfi", "color", "link", "title", "author",
+    "format", "language", "hash", "tags", "date", "time",
+];
+
+fn template_error(message: impl Into<String>) -> Error {
+    Error::Template(message.into())
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Text(String),
+    Value {
+        name: String,
+        raw: bool,
+    },
+    If {
+        name: String,
+        negate: bool,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+    Each {
+        name: String,
+        body: Vec<Node>,
+    },
+}
+
+enum Token<'a> {
+    Text(&'a str),
+    Tag { content: &'a str, raw: bool },
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token<'_>>> {
+    let mut tokens = Vec::new();
+    let mut rest = source;
+    while let Some(start) = rest.find("{{") {
+        if start > 0 {
+            tokens.push(Token::Text(&rest[..start]));
+        }
+        let raw = rest[start + 2..].starts_with('{');
+        let (open, close) = if raw { (3, "}}}") } else { (2, "}}") };
+        let inner = &rest[start + open..];
+        let end = inner
+            .find(close)
+            .ok_or_else(|| template_error(format!("unclosed {}", &rest[start..start + open])))?;
+        tokens.push(Token::Tag {
+            content: inner[..end].trim(),
+            raw,
+        });
+        rest = &inner[end + close.len()..];
+    }
+    if !rest.is_empty() {
+        tokens.push(Token::Text(rest));
+    }
+    Ok(tokens)
+}
+
+fn check_name(name: &str, in_each: bool) -> Result<String> {
+    if VARIABLES.contains(&name) || (in_each && (name == "this" || name == "@index")) {
+        Ok(name.to_string())
+    } else {
+        Err(template_error(format!("unknown value {{{{{name}}}}}")))
+    }
+}
+
+struct Parser<'a> {
+    tokens: std::vec::IntoIter<Token<'a>>,
+}
+
+impl Parser<'_> {
+    /// Nodes up to the end of the source or, inside `block`, up to its
+    /// `{{else}}` or closing tag, which is returned.
+    fn nodes(&mut self, block: Option<&str>, in_each: bool) -> Result<(Vec<Node>, &'static str)> {
+        let mut nodes = Vec::new();
+        while let Some(token) = self.tokens.next() {
+            let (content, raw) = match token {
+                Token::Text(text) => {
+                    nodes.push(Node::Text(text.to_string()));
+                    continue;
+                }
+                Token::Tag { content, raw } => (content, raw),
+            };
+            if content.starts_with('!') {
+                continue;
+            }
+            if let Some(open) = content.strip_prefix('#') {
+                let (helper, name) = open.split_once(char::is_whitespace).unwrap_or((open, ""));
+                let name = name.trim();
+                nodes.push(match helper {
+                    "if" | "unless" => {
+                        let name = check_name(name, in_each)?;
+                        let (then, end) = self.nodes(Some(helper), in_each)?;
+                        let otherwise = match end {
+                            "else" => self.nodes(Some(helper), in_each)?.0,
+                            _ => Vec::new(),
+                        };
+                        Node::If {
+                            name,
+                            negate: helper == "unless",
+                            then,
+                            otherwise,
+                        }
+                    }
+                    "each" => {
+                        let name = check_name(name, in_each)?;
+                        let (body, _) = self.nodes(Some(helper), true)?;
+                        Node::Each { name, body }
+                    }
+                    _ => return Err(template_error(format!("unknown block {{{{#{helper}}}}}"))),
+                });
+                continue;
+            }
+            if content == "else" {
+                return match block {
+                    Some("if" | "unless") => Ok((nodes, "else")),
+                    _ => Err(template_error("{{else}} outside of {{#if}}")),
+                };
+            }
+            if let Some(close) = content.strip_prefix('/') {
+                return match block {
+                    Some(block) if block == close.trim() => Ok((nodes, "close")),
+                    _ => Err(template_error(format!(
+                        "unexpected {{{{/{}}}}}",
+                        close.trim()
+                    ))),
+                };
+            }
+            nodes.push(Node::Value {
+                name: check_name(content, in_each)?,
+                raw,
+            });
+        }
+        match block {
+            Some(block) => Err(template_error(format!("unclosed {{{{#{block}}}}}"))),
+            None => Ok((nodes, "end")),
+        }
+    }
+}
+
+fn parse(source: &str) -> Result<Vec<Node>> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?.into_iter(),
+    };
+    Ok(parser.nodes(None, false)?.0)
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Text(String),
+    List(Vec<String>),
+}
+
+impl Value {
+    fn is_empty(&self) -> bool {
+        match self {
+            Value::Text(text) => text.is_empty(),
+            Value::List(items) => items.is_empty(),
+        }
+    }
+}
+
+type Vars = BTreeMap<&'static str, Value>;
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The current item of the innermost `{{#each}}` and its index.
+type Item<'a> = Option<(&'a str, usize)>;
+
+fn value(vars: &Vars, item: Item, name: &str) -> Value {
+    match (name, item) {
+        ("this", Some((this, _))) => Value::Text(this.to_string()),
+        ("@index", Some((_, index))) => Value::Text(index.to_string()),
+        _ => vars
+            .get(name)
+            .cloned()
+            .unwrap_or(Value::Text(String::new())),
+    }
+}
+
+fn render_nodes(nodes: &[Node], vars: &Vars, item: Item, escape: bool, out: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::Value { name, raw } => {
+                let text = match value(vars, item, name) {
+                    Value::Text(text) => text,
+                    Value::List(items) => items.join(", "),
+                };
+                if escape && !raw {
+                    out.push_str(&escape_html(&text));
+                } else {
+                    out.push_str(&text);
+                }
+            }
+            Node::If {
+                name,
+                negate,
+                then,
+                otherwise,
+            } => {
+                let shown = value(vars, item, name).is_empty() == *negate;
+                render_nodes(
+                    if shown { then } else { otherwise },
+                    vars,
+                    item,
+                    escape,
+                    out,
+                );
+            }
+            Node::Each { name, body } => {
+                let items = match value(vars, item, name) {
+                    Value::Text(text) if text.is_empty() => Vec::new(),
+                    Value::Text(text) => vec![text],
+                    Value::List(items) => items,
+                };
+                for (index, this) in items.iter().enumerate() {
+                    render_nodes(body, vars, Some((this, index)), escape, out);
+                }
+            }
+        }
+    }
+}
+
+fn render(source: &str, vars: &Vars, escape: bool) -> Result<String> {
+    let mut out = String::new();
+    render_nodes(&parse(source)?, vars, None, escape, &mut out);
+    Ok(out)
+}
+
+/// Where a rendered selection goes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum TemplateTarget {
+    /// Returned for the frontend to copy.
+    Clipboard,
+    /// Written to `folder`, in the file `file_name` renders to, after what
+    /// it holds when `append` is set.
+    #[serde(rename_all = "camelCase")]
+    Markdown {
+        folder: PathBuf,
+        file_name: String,
+        #[serde(default)]
+        append: bool,
+    },
+    /// Added to Anki as a note of type `model` in `deck`, each field
+    /// rendered from its template.
+    #[serde(rename_all = "camelCase")]
+    Anki {
+        deck: String,
+        model: String,
+        fields: BTreeMap<String, String>,
+        #[serde(default)]
+        tags: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NoteTemplate {
+    pub id: String,
+    pub name: String,
+    /// The template of the text, not used for Anki.
+    #[serde(default)]
+    pub body: String,
+    pub target: TemplateTarget,
+}
+
+impl NoteTemplate {
+    /// Every template it holds.
+    fn sources(&self) -> Vec<&str> {
+        let mut sources = vec![self.body.as_str()];
+        match &self.target {
+            TemplateTarget::Clipboard => {}
+            TemplateTarget::Markdown { file_name, .. } => sources.push(file_name),
+            TemplateTarget::Anki { fields, tags, .. } => {
+                sources.extend(fields.values().map(String::as_str));
+                sources.extend(tags.iter().map(String::as_str));
+            }
+        }
+        sources
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Stored {
+    templates: Vec<NoteTemplate>,
+}
+
+impl Default for Stored {
+    fn default() -> Self {
+        Self {
+            templates: vec![
+                NoteTemplate {
+                    id: "quote".to_string(),
+                    name: "Markdown quote".to_string(),
+                    body: "> {{text}}\n\n— {{author}}, *{{title}}*{{#if note}}\n\n{{note}}{{/if}}"
+                        .to_string(),
+                    target: TemplateTarget::Clipboard,
+                },
+                NoteTemplate {
+                    id: "anki".to_string(),
+                    name: "Anki flashcard".to_string(),
+                    body: String::new(),
+                    target: TemplateTarget::Anki {
+                        deck: "Default".to_string(),
+                        model: "Basic".to_string(),
+                        fields: BTreeMap::from([
+                            ("Front".to_string(), "{{text}}".to_string()),
+                            (
+                                "Back".to_string(),
+                                "{{#if note}}{{note}}<br>{{/if}}<i>{{title}}</i>, {{author}}"
+                                    .to_string(),
+                            ),
+                        ]),
+                        tags: vec!["vl-arch".to_string()],
+                    },
+                },
+            ],
+        }
+    }
+}
+
+/// A selection in a book, as the reader has it.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Selection {
+    pub book_hash: Option<String>,
+    pub text: String,
+    /// The note written on the selection.
+    pub note: Option<String>,
+    /// The sentence or paragraph around it.
+    pub context: Option<String>,
+    pub chapter: Option<String>,
+    pub page: Option<String>,
+    pub cfi: Option<String>,
+    pub color: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TemplateOutput {
+    /// The rendered body, which the frontend copies for the clipboard.
+    pub text: String,
+    /// The rendered fields of an Anki note.
+    pub fields: BTreeMap<String, String>,
+    /// The Markdown file written.
+    pub path: Option<String>,
+    pub anki_note_id: Option<i64>,
+}
+
+fn vars(app: &AppHandle, selection: &Selection) -> Result<Vars> {
+    let book = match &selection.book_hash {
+        Some(hash) => db::get_book(&app.state::<LibraryDb>().conn(), hash)?,
+        None => None,
+    };
+    let text = |value: Option<&str>| Value::Text(value.unwrap_or_default().to_string());
+    let link = match (&selection.book_hash, &selection.cfi) {
+        (Some(hash), Some(cfi)) => {
+            Url::parse_with_params("vlarch://open", [("book", hash), ("cfi", cfi)])
+                .map(String::from)
+                .ok()
+        }
+        _ => None,
+    };
+    let time = format_rfc3339(now_millis());
+    Ok(Vars::from([
+        ("text", Value::Text(selection.text.trim().to_string())),
+        ("note", text(selection.note.as_deref())),
+        ("context", text(selection.context.as_deref())),
+        ("chapter", text(selection.chapter.as_deref())),
+        ("page", text(selection.page.as_deref())),
+        ("cfi", text(selection.cfi.as_deref())),
+        ("color", text(selection.color.as_deref())),
+        ("link", text(link.as_deref())),
+        ("title", text(book.as_ref().map(|b| b.title.as_str()))),
+        ("author", text(book.as_ref().map(|b| b.author.as_str()))),
+        ("format", text(book.as_ref().map(|b| b.format.as_str()))),
+        (
+            "language",
+            text(book.as_ref().and_then(|b| b.primary_language.as_deref())),
+        ),
+        ("hash", text(selection.book_hash.as_deref())),
+        (
+            "tags",
+            Value::List(book.map(|b| b.tags).unwrap_or_default()),
+        ),
+        ("date", Value::Text(time[..10].to_string())),
+        ("time", Value::Text(time)),
+    ]))
+}
+
+/// `template` rendered for `selection`, without sending it anywhere.
+fn render_template(
+    app: &AppHandle,
+    template: &NoteTemplate,
+    selection: &Selection,
+) -> Result<TemplateOutput> {
+    let vars = vars(app, selection)?;
+    let anki = matches!(template.target, TemplateTarget::Anki { .. });
+    let mut output = TemplateOutput {
+        text: render(&template.body, &vars, anki)?,
+        ..TemplateOutput::default()
+    };
+    if let TemplateTarget::Anki { fields, .. } = &template.target {
+        for (name, source) in fields {
+            output
+                .fields
+                .insert(name.clone(), render(source, &vars, true)?);
+        }
+    }
+    Ok(output)
+}
+
+#[derive(Deserialize)]
+struct AnkiResponse {
+    result: Option<serde_json::Value>,
+    error: Option<String>,
+}
+
+/// Calls `action` of AnkiConnect, which only answers on this machine.
+async fn anki_connect(action: &str, params: serde_json::Value) -> Result<serde_json::Value> {
+    let client = reqwest::Client::builder().no_proxy().build()?;
+    let response = client
+        .post(ANKI_CONNECT_URL)
+        .json(&json!({ "action": action, "version": ANKI_CONNECT_VERSION, "params": params }))
+        .send()
+        .await
+        .map_err(|_| {
+            Error::Anki("Anki is not running or AnkiConnect is not installed".to_string())
+        })?
+        .json::<AnkiResponse>()
+        .await?;
+    match response.error {
+        Some(error) => Err(Error::Anki(error)),
+        None => Ok(response.result.unwrap_or_default()),
+    }
+}
+
+/// Sends `output`, rendered from `template`, where the template says.
+async fn deliver(
+    app: &AppHandle,
+    template: &NoteTemplate,
+    selection: &Selection,
+    mut output: TemplateOutput,
+) -> Result<TemplateOutput> {
+    match &template.target {
+        TemplateTarget::Clipboard => {}
+        TemplateTarget::Markdown {
+            folder,
+            file_name,
+            append,
+        } => {
+            let vars = vars(app, selection)?;
+            let mut name = sanitize_file_name(render(file_name, &vars, false)?.trim());
+            if name.is_empty() {
+                return Err(template_error("the file name is empty"));
+            }
+            if !name.to_lowercase().ends_with(".md") {
+                name.push_str(".md");
+            }
+            let path = folder.join(name);
+            let mut content = output.text.clone();
+            if *append {
+                if let Ok(existing) = std::fs::read_to_string(&path) {
+                    if !existing.trim().is_empty() {
+                        content = format!("{}\n\n{content}", existing.trim_end());
+                    }
+                }
+            }
+            std::fs::create_dir_all(folder)?;
+            std::fs::write(&path, content)?;
+            output.path = Some(path.to_string_lossy().into_owned());
+        }
+        TemplateTarget::Anki {
+            deck, model, tags, ..
+        } => {
+            let vars = vars(app, selection)?;
+            let tags = tags
+                .iter()
+                .map(|tag| render(tag, &vars, false))
+                .collect::<Result<Vec<_>>>()?
+                .into_iter()
+                // Anki tags cannot hold spaces
+                .flat_map(|tag| {
+                    tag.split_whitespace()
+                        .map(str::to_string)
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            let note = json!({
+                "note": {
+                    "deckName": deck,
+                    "modelName": model,
+                    "fields": output.fields,
+                    "tags": tags,
+                    "options": { "allowDuplicate": false },
+                }
+            });
+            output.anki_note_id = anki_connect("addNote", note).await?.as_i64();
+        }
+    }
+    Ok(output)
+}
+
+fn load(app: &AppHandle) -> Vec<NoteTemplate> {
+    store::load::<Stored>(app, TEMPLATES_FILE).templates
+}
+
+#[command]
+pub async fn get_note_templates(app: AppHandle) -> Result<Vec<NoteTemplate>> {
+    Ok(load(&app))
+}
+
+/// Replaces the templates, once each of them parses.
+#[command]
+pub async fn set_note_templates(app: AppHandle, templates: Vec<NoteTemplate>) -> Result<()> {
+    for template in &templates {
+        for source in template.sources() {
+            parse(source).map_err(|e| template_error(format!("{}: {}", template.name, e)))?;
+        }
+    }
+    store::save(&app, TEMPLATES_FILE, &Stored { templates })
+}
+
+/// Renders `selection` through `template`, which need not be saved, for
+/// the template editor to show.
+#[command]
+pub async fn preview_note_template(
+    app: AppHandle,
+    template: NoteTemplate,
+    selection: Selection,
+) -> Result<TemplateOutput> {
+    render_template(&app, &template, &selection)
+}
+
+/// Renders `selection` through the template `id` and sends it where the
+/// template says: back for the clipboard, to a Markdown file or to Anki.
+#[command]
+pub async fn apply_note_template(
+    app: AppHandle,
+    id: String,
+    selection: Selection,
+) -> Result<TemplateOutput> {
+    let template = load(&app)
+        .into_iter()
+        .find(|template| template.id == id)
+        .ok_or_else(|| Error::UnknownTemplate(id))?;
+    let output = render_template(&app, &template, &selection)?;
+    deliver(&app, &template, &selection, output).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> Vars {
+        Vars::from([
+            ("text", Value::Text("a < b & c".to_string())),
+            ("note", Value::Text(String::new())),
+            ("title", Value::Text("Dune".to_string())),
+            (
+                "tags",
+                Value::List(vec!["scifi".to_string(), "classic".to_string()]),
+            ),
+        ])
+    }
+
+    fn error(source: &str) -> String {
+        parse(source).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn values_are_escaped_only_for_anki() {
+        let source = "{{text}} | {{{text}}} | {{tags}}{{! not shown }} | {{author}}";
+        assert_eq!(
+            render(source, &vars(), true).unwrap(),
+            "a &lt; b &amp; c | a < b & c | scifi, classic | "
+        );
+        assert_eq!(
+            render(source, &vars(), false).unwrap(),
+            "a < b & c | a < b & c | scifi, classic | "
+        );
+    }
+
+    #[test]
+    fn blocks_test_emptiness_and_go_through_lists() {
+        let source = "{{#if note}}{{note}}{{else}}no note{{/if}}, \
+                      {{#unless title}}untitled{{else}}{{title}}{{/unless}}: \
+                      {{#each tags}}{{@index}}={{this}} {{/each}}{{#each text}}[{{this}}]{{/each}}";
+        assert_eq!(
+            render(source, &vars(), false).unwrap(),
+            "no note, Dune: 0=scifi 1=classic [a < b & c]"
+        );
+        assert_eq!(
+            render("{{#each note}}x{{/each}}", &vars(), false).unwrap(),
+            ""
+        );
+    }
+
+    #[test]
+    fn broken_templates_are_errors() {
+        assert!(error("{{nope}}").contains("unknown value {{nope}}"));
+        assert!(error("{{this}}").contains("unknown value {{this}}"));
+        assert!(error("a {{text").contains("unclosed {{"));
+        assert!(error("{{{text}}").contains("unclosed {{{"));
+        assert!(error("{{#if note}}x").contains("unclosed {{#if}}"));
+        assert!(error("{{#each tags}}{{else}}{{/each}}").contains("{{else}} outside"));
+        assert!(error("{{#if note}}x{{/each}}").contains("unexpected {{/each}}"));
+        assert!(error("{{#with note}}{{/with}}").contains("unknown block {{#with}}"));
+    }
+
+    #[test]
+    fn default_templates_parse() {
+        for template in Stored::default().templates {
+            for source in template.sources() {
+                parse(source).unwrap();
+            }
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! Web pages clipped into the library. The article is extracted from the
+//! page as it is for feed items and saved, with its images, as an EPUB of
+//! one chapter. Browsers send pages with a bookmarklet opening
+//! `vlarch://clip?url=<page>`, such as
+//! `javascript:location.href='vlarch://clip?url='+encodeURIComponent(location.href)`,
+//! and the clipped book is then opened like an `open` link.
+
+#[cfg(desktop)]
+use serde::Serialize;
+#[cfg(desktop)]
+use tauri::Emitter;
+use tauri::{command, AppHandle};
+use url::Url;
+
+use super::{add_article, add_to_library, download_images, fetch_article, Article};
+use crate::convert::epub::EpubBuilder;
+use crate::error::{Error, Result};
+use crate::formats::epub::{Identifier, Metadata};
+use crate::library::db;
+use crate::utils::{format_rfc3339, now_millis};
+
+const CHAPTER_HREF: &str = "article.xhtml";
+#[cfg(desktop)]
+const FAILED_EVENT: &str = "web-clip-failed";
+
+#[cfg(desktop)]
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ClipFailure {
+    url: String,
+    error: String,
+}
+
+/// Saves the article of the page at `url` to the library.
+pub async fn clip(app: &AppHandle, url: &str) -> Result<db::Book> {
+    let url = Url::parse(url.trim())?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(Error::Clip(format!("cannot clip {} links", url.scheme())));
+    }
+    let content = fetch_article(&url)
+        .await?
+        .ok_or_else(|| Error::Clip("no article found on the page".into()))?;
+    let images = download_images(Some(&content), &mut 0).await;
+    let site = url.host_str().unwrap_or_default().to_string();
+    let title = content
+        .title()
+        .map(String::from)
+        .unwrap_or_else(|| url.to_string());
+    let article = Article {
+        title: title.clone(),
+        link: Some(url.to_string()),
+        author: None,
+        published: None,
+        content: Some(content),
+        images,
+    };
+
+    let handle = app.clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        let mut epub = EpubBuilder::new(Metadata {
+            title,
+            publisher: Some(site.clone()).filter(|site| !site.is_empty()),
+            published: Some(format_rfc3339(now_millis())[..10].to_string()),
+            description: Some(format!("Clipped from {url}")),
+            identifiers: vec![Identifier {
+                scheme: None,
+                value: format!("urn:vlarch:clip:{:x}", now_millis()),
+            }],
+            ..Metadata::default()
+        });
+        add_article(&mut epub, CHAPTER_HREF, &article, &site, "und");
+        add_to_library(&handle, &epub)
+    })
+    .await?
+}
+
+/// Clips `url` without waiting, for links. The book is opened once saved.
+#[cfg(desktop)]
+pub fn clip_in_background(app: &AppHandle, url: String) {
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        match clip(&app, &url).await {
+            Ok(book) => crate::deep_link::dispatch(
+                &app,
+                vec![crate::deep_link::DeepLink::Open {
+                    book: book.hash,
+                    cfi: None,
+                }],
+            ),
+            Err(e) => {
+                log::warn!("Failed to clip {url}: {e}");
+                let error = e.to_string();
+                let _ = app.emit(FAILED_EVENT, ClipFailure { url, error });
+            }
+        }
+    });
+}
+
+/// Saves the article of the page at `url` to the library.
+#[command]
+pub async fn clip_url(app: AppHandle, url: String) -> Result<db::Book> {
+    clip(&app, &url).await
+}
@@ -0,0 +1,552 @@
+//! News feeds compiled into ebooks, like the news sources of Calibre. The
+//! configured RSS and Atom feeds are fetched by the `feeds` scheduled
+//! task (see [`crate::tasks`]) or on demand, the article
+//! each new item links to is downloaded and extracted from its page, and
+//! the articles of all feeds become one EPUB, with their images, that is
+//! added to the library.
+//!
+//! Items already in an issue are remembered per feed so the next issue only
+//! has what is new, and a `feed-issue` event tells the frontend when the
+//! scheduled task added one. Single web pages are saved the same way, see
+//! [`clip`].
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use reqwest::header::{ACCEPT, CONTENT_TYPE};
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle, Emitter, Manager};
+use url::Url;
+
+use crate::convert::epub::{EpubBuilder, NavPoint};
+use crate::error::{Error, Result};
+use crate::formats::epub::{Identifier, Metadata};
+use crate::library::db;
+use crate::utils::{escape_xml, format_rfc3339, now_millis, sanitize_file_name};
+use crate::{net, paths, store};
+
+pub mod clip;
+mod parse;
+mod readability;
+
+use parse::{NewsFeed, NewsItem};
+use readability::Content;
+
+const FEEDS_FILE: &str = "feeds.json";
+const STATE_FILE: &str = "feeds-state.json";
+/// Where issues and clipped pages are written under the app data dir
+/// before they are imported.
+const ISSUES_DIR: &str = "feeds";
+const EVENT: &str = "feed-issue";
+const FEED_ACCEPT: &str = "application/rss+xml, application/atom+xml, application/rdf+xml;q=0.9, application/xml;q=0.8, text/xml;q=0.8, */*;q=0.5";
+const PAGE_ACCEPT: &str = "text/html, application/xhtml+xml;q=0.9, */*;q=0.5";
+const DEFAULT_MAX_ARTICLES: usize = 20;
+/// Older items are left out, as a newspaper would.
+const MAX_ARTICLE_AGE_DAYS: i64 = 7;
+/// Item ids remembered per feed.
+const MAX_SEEN: usize = 1000;
+const MAX_IMAGES_PER_ARTICLE: usize = 20;
+const MAX_IMAGE_BYTES: usize = 5 * 1024 * 1024;
+const STYLE: &str = "img{max-width:100%;height:auto}\
+                     .byline{font-size:0.85em;opacity:0.7}\
+                     .source{font-size:0.85em;word-break:break-all}";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct FeedSubscription {
+    pub url: String,
+    pub title: String,
+    /// Whether to download the page of each item and extract the article
+    /// from it, rather than keep what the feed carries.
+    pub fetch_articles: bool,
+}
+
+impl Default for FeedSubscription {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            title: String::new(),
+            fetch_articles: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct FeedSettings {
+    /// Most articles taken from each feed for an issue.
+    pub max_articles: usize,
+    pub feeds: Vec<FeedSubscription>,
+}
+
+impl Default for FeedSettings {
+    fn default() -> Self {
+        Self {
+            max_articles: DEFAULT_MAX_ARTICLES,
+            feeds: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct FeedState {
+    /// Ids of the items already in an issue, by feed URL, oldest first.
+    seen: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FeedIssue {
+    pub book: db::Book,
+    pub article_count: usize,
+}
+
+/// Whether an update is running, so the scheduler and the user cannot
+/// start two at once.
+#[derive(Default)]
+pub struct Feeds(AtomicBool);
+
+struct Updating<'a>(&'a AtomicBool);
+
+impl Drop for Updating<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+struct Image {
+    href: String,
+    media_type: &'static str,
+    data: Vec<u8>,
+}
+
+struct Article {
+    title: String,
+    link: Option<String>,
+    author: Option<String>,
+    published: Option<i64>,
+    content: Option<Content>,
+    /// The downloaded images, by their URL.
+    images: Vec<(String, Image)>,
+}
+
+fn image_type(data: &[u8]) -> Option<(&'static str, &'static str)> {
+    match data {
+        [0xff, 0xd8, 0xff, ..] => Some(("jpg", "image/jpeg")),
+        [0x89, b'P', b'N', b'G', ..] => Some(("png", "image/png")),
+        [b'G', b'I', b'F', b'8', ..] => Some(("gif", "image/gif")),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => {
+            Some(("webp", "image/webp"))
+        }
+        _ => None,
+    }
+}
+
+async fn fetch(url: &Url, accept: &str) -> Result<reqwest::Response> {
+    let response = net::client()
+        .get(url.clone())
+        .header(ACCEPT, accept)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(Error::HttpStatus(response.status().as_u16()));
+    }
+    Ok(response)
+}
+
+async fn fetch_feed(url: &Url) -> Result<NewsFeed> {
+    let response = fetch(url, FEED_ACCEPT).await?;
+    // Follow redirects so relative links resolve against the final location
+    let url = response.url().clone();
+    let body = response.text().await?;
+    parse::parse_news_feed(&url, &body)
+}
+
+/// The article on the page at `link`, if it has one.
+async fn fetch_article(link: &Url) -> Result<Option<Content>> {
+    let response = fetch(link, PAGE_ACCEPT).await?;
+    let is_html = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map_or(true, |v| v.contains("html"));
+    if !is_html {
+        return Ok(None);
+    }
+    let url = response.url().clone();
+    let body = response.text().await?;
+    Ok(readability::extract(&body, &url))
+}
+
+async fn fetch_image(url: &str) -> Option<(&'static str, &'static str, Vec<u8>)> {
+    let response = fetch(&Url::parse(url).ok()?, "image/*").await.ok()?;
+    if response
+        .content_length()
+        .is_some_and(|len| len as usize > MAX_IMAGE_BYTES)
+    {
+        return None;
+    }
+    let data = response.bytes().await.ok()?;
+    if data.len() > MAX_IMAGE_BYTES {
+        return None;
+    }
+    let (ext, media_type) = image_type(&data)?;
+    Some((ext, media_type, data.to_vec()))
+}
+
+/// The images of `content`, by their URL. `image_count` numbers them
+/// across the book.
+async fn download_images(
+    content: Option<&Content>,
+    image_count: &mut usize,
+) -> Vec<(String, Image)> {
+    let mut images = Vec::new();
+    let urls = content.map(Content::images).unwrap_or_default();
+    for url in urls.into_iter().take(MAX_IMAGES_PER_ARTICLE) {
+        if let Some((ext, media_type, data)) = fetch_image(&url).await {
+            *image_count += 1;
+            let href = format!("images/{}.{ext}", *image_count);
+            images.push((
+                url,
+                Image {
+                    href,
+                    media_type,
+                    data,
+                },
+            ));
+        }
+    }
+    images
+}
+
+/// The article of `item`, extracted from its page when `fetch_articles` is
+/// set and that gives more than the feed, with its images downloaded.
+/// `image_count` numbers the images across the issue.
+async fn load_article(
+    item: NewsItem,
+    feed_url: &Url,
+    fetch_articles: bool,
+    image_count: &mut usize,
+) -> Article {
+    let link = item.link.as_deref().and_then(|link| Url::parse(link).ok());
+    let base = link.as_ref().unwrap_or(feed_url);
+    let mut content = item
+        .content
+        .as_deref()
+        .or(item.summary.as_deref())
+        .map(|html| readability::clean(html, base));
+    if let Some(link) = link.as_ref().filter(|_| fetch_articles) {
+        match fetch_article(link).await {
+            Ok(Some(extracted)) => {
+                let feed_len = content.as_ref().map_or(0, Content::text_len);
+                if extracted.text_len() > feed_len {
+                    content = Some(extracted);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => log::warn!("Failed to fetch article {link}: {e}"),
+        }
+    }
+
+    let images = download_images(content.as_ref(), image_count).await;
+    let title = match item.title.trim() {
+        "" => content
+            .as_ref()
+            .and_then(Content::title)
+            .or(item.link.as_deref())
+            .unwrap_or_default()
+            .to_string(),
+        title => title.to_string(),
+    };
+    Article {
+        title,
+        link: item.link,
+        author: item.author,
+        published: item.published,
+        content,
+        images,
+    }
+}
+
+/// The chapter of `article`, with `source` as the first part of its byline.
+fn article_document(article: &Article, source: &str, lang: &str) -> String {
+    let mut byline = vec![source.to_string()];
+    byline.extend(article.author.clone());
+    byline.extend(
+        article
+            .published
+            .map(|at| format_rfc3339(at)[..10].to_string()),
+    );
+    let body = article
+        .content
+        .as_ref()
+        .map(|content| {
+            content.to_xhtml(|src| {
+                article
+                    .images
+                    .iter()
+                    .find(|(url, _)| url == src)
+                    .map(|(_, image)| image.href.clone())
+            })
+        })
+        .unwrap_or_default();
+    let link = article
+        .link
+        .as_deref()
+        .map(|link| {
+            format!(
+                "<p class=\"source\"><a href=\"{0}\">{0}</a></p>\n",
+                escape_xml(link)
+            )
+        })
+        .unwrap_or_default();
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<!DOCTYPE html>\n\
+         <html xmlns=\"http://www.w3.org/1999/xhtml\" lang=\"{}\">\n\
+         <head><title>{}</title><style>{STYLE}</style></head>\n<body>\n\
+         <h1>{}</h1>\n<p class=\"byline\">{}</p>\n{body}\n{link}</body>\n</html>\n",
+        escape_xml(lang),
+        escape_xml(&article.title),
+        escape_xml(&article.title),
+        escape_xml(&byline.join(" · ")),
+    )
+}
+
+/// `path`, or the first of `path (1)`, `path (2)`… that does not exist.
+fn unique_path(path: PathBuf) -> PathBuf {
+    if !path.exists() {
+        return path;
+    }
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    (1..)
+        .map(|i| path.with_file_name(format!("{stem} ({i}).epub")))
+        .find(|p| !p.exists())
+        .unwrap()
+}
+
+fn add_article(epub: &mut EpubBuilder, href: &str, article: &Article, source: &str, lang: &str) {
+    let document = article_document(article, source, lang);
+    epub.add_chapter(href, "application/xhtml+xml", document.into_bytes());
+    for (_, image) in &article.images {
+        epub.add_resource(&image.href, image.media_type, image.data.clone());
+    }
+}
+
+/// Writes `epub` and imports it into the library.
+fn add_to_library(app: &AppHandle, epub: &EpubBuilder) -> Result<db::Book> {
+    let dir = paths::data_dir(app)?.join(ISSUES_DIR);
+    std::fs::create_dir_all(&dir)?;
+    let name = sanitize_file_name(&epub.metadata.title);
+    let path = unique_path(dir.join(format!("{name}.epub")));
+    epub.write(&path)?;
+    crate::library::import::import_and_discard(app, &path)
+}
+
+/// Writes the issue, one chapter per article grouped by feed, and adds it
+/// to the library.
+fn write_issue(
+    app: &AppHandle,
+    feeds: &[(FeedSubscription, Option<String>, Vec<Article>)],
+) -> Result<FeedIssue> {
+    let now = now_millis();
+    let date = format_rfc3339(now)[..10].to_string();
+    let name = match feeds {
+        [(feed, ..)] => feed.title.clone(),
+        _ => "News".to_string(),
+    };
+    let title = format!("{name} {date}");
+    let language = feeds.iter().find_map(|(_, language, _)| language.clone());
+    let titles = feeds
+        .iter()
+        .map(|(f, ..)| f.title.as_str())
+        .collect::<Vec<_>>();
+    let mut epub = EpubBuilder::new(Metadata {
+        title: title.clone(),
+        language: language.iter().cloned().collect(),
+        published: Some(date),
+        description: Some(format!("Articles from {}", titles.join(", "))),
+        subjects: vec!["News".to_string()],
+        identifiers: vec![Identifier {
+            scheme: None,
+            value: format!("urn:vlarch:news:{now:x}"),
+        }],
+        ..Metadata::default()
+    });
+
+    let lang = language.as_deref().unwrap_or("und");
+    let mut article_count = 0;
+    for (feed, _, articles) in feeds {
+        let mut section = NavPoint {
+            label: feed.title.clone(),
+            href: String::new(),
+            children: Vec::new(),
+        };
+        for article in articles {
+            article_count += 1;
+            let href = format!("article-{article_count}.xhtml");
+            add_article(&mut epub, &href, article, &feed.title, lang);
+            if section.href.is_empty() {
+                section.href = href.clone();
+            }
+            section.children.push(NavPoint {
+                label: article.title.clone(),
+                href,
+                children: Vec::new(),
+            });
+        }
+        epub.toc.push(section);
+    }
+
+    let book = add_to_library(app, &epub)?;
+    Ok(FeedIssue {
+        book,
+        article_count,
+    })
+}
+
+/// Fetches every feed and makes an issue of the items not in one yet, or
+/// returns `None` when there are none.
+async fn update(app: &AppHandle) -> Result<Option<FeedIssue>> {
+    let feeds = app.state::<Feeds>();
+    if feeds.0.swap(true, Ordering::Acquire) {
+        return Err(Error::FeedsUpdating);
+    }
+    let _updating = Updating(&feeds.0);
+
+    let settings: FeedSettings = store::load(app, FEEDS_FILE);
+    let mut state: FeedState = store::load(app, STATE_FILE);
+    let oldest = now_millis() - MAX_ARTICLE_AGE_DAYS * 86_400_000;
+    let mut issue = Vec::new();
+    let mut seen = Vec::new();
+    let mut error = None;
+    let mut reached = 0;
+    let mut image_count = 0;
+    for subscription in &settings.feeds {
+        let url = Url::parse(&subscription.url)?;
+        let feed = match fetch_feed(&url).await {
+            Ok(feed) => feed,
+            Err(e) => {
+                log::warn!("Failed to fetch feed {url}: {e}");
+                error = Some(e);
+                continue;
+            }
+        };
+        reached += 1;
+        let known = state.seen.get(&subscription.url);
+        let mut items = feed
+            .items
+            .into_iter()
+            .filter(|item| !known.is_some_and(|known| known.contains(&item.id)))
+            .filter(|item| item.published.map_or(true, |at| at >= oldest))
+            .collect::<Vec<_>>();
+        // Newest first, items without a date keep their place in the feed
+        items.sort_by_key(|item| std::cmp::Reverse(item.published.unwrap_or(i64::MAX)));
+        items.truncate(settings.max_articles.max(1));
+        if items.is_empty() {
+            continue;
+        }
+        seen.push((
+            subscription.url.clone(),
+            items.iter().map(|item| item.id.clone()).collect::<Vec<_>>(),
+        ));
+        let mut articles = Vec::with_capacity(items.len());
+        for item in items {
+            articles.push(
+                load_article(item, &url, subscription.fetch_articles, &mut image_count).await,
+            );
+        }
+        issue.push((subscription.clone(), feed.language, articles));
+    }
+    if issue.is_empty() {
+        // Fail so the scheduler retries when no feed could be reached
+        if let Some(e) = error.filter(|_| reached == 0) {
+            return Err(e);
+        }
+        return Ok(None);
+    }
+
+    let handle = app.clone();
+    let issue =
+        tauri::async_runtime::spawn_blocking(move || write_issue(&handle, &issue)).await??;
+    for (url, ids) in seen {
+        let known = state.seen.entry(url).or_default();
+        known.extend(ids);
+        if known.len() > MAX_SEEN {
+            known.drain(..known.len() - MAX_SEEN);
+        }
+    }
+    store::save(app, STATE_FILE, &state)?;
+    Ok(Some(issue))
+}
+
+/// Makes an issue for the scheduler, telling the frontend about it.
+pub async fn update_on_schedule(app: AppHandle) -> Result<()> {
+    match update(&app).await {
+        Ok(Some(issue)) => {
+            let _ = app.emit(EVENT, issue);
+            Ok(())
+        }
+        // The user started one, which is as good
+        Ok(None) | Err(Error::FeedsUpdating) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[command]
+pub fn get_feeds(app: AppHandle) -> FeedSettings {
+    store::load(&app, FEEDS_FILE)
+}
+
+/// Saves the feeds, forgetting the items seen in feeds
+/// that were removed.
+#[command]
+pub fn set_feeds(app: AppHandle, settings: FeedSettings) -> Result<()> {
+    store::save(&app, FEEDS_FILE, &settings)?;
+    let mut state: FeedState = store::load(&app, STATE_FILE);
+    state
+        .seen
+        .retain(|url, _| settings.feeds.iter().any(|f| &f.url == url));
+    store::save(&app, STATE_FILE, &state)
+}
+
+/// Subscribes to the feed at `url`, which is fetched to check it and to
+/// get its title.
+#[command]
+pub async fn add_feed(app: AppHandle, url: String) -> Result<FeedSettings> {
+    let parsed = Url::parse(url.trim())?;
+    let feed = fetch_feed(&parsed).await?;
+    let mut settings: FeedSettings = store::load(&app, FEEDS_FILE);
+    let url = parsed.to_string();
+    if !settings.feeds.iter().any(|f| f.url == url) {
+        let title = match feed.title.trim() {
+            "" => parsed.host_str().unwrap_or(&url).to_string(),
+            title => title.to_string(),
+        };
+        settings.feeds.push(FeedSubscription {
+            url,
+            title,
+            ..FeedSubscription::default()
+        });
+        store::save(&app, FEEDS_FILE, &settings)?;
+    }
+    Ok(settings)
+}
+
+#[command]
+pub fn remove_feed(app: AppHandle, url: String) -> Result<FeedSettings> {
+    let mut settings: FeedSettings = store::load(&app, FEEDS_FILE);
+    settings.feeds.retain(|f| f.url != url);
+    set_feeds(app, settings.clone())?;
+    Ok(settings)
+}
+
+/// Makes an issue now, whatever the schedule says.
+#[command]
+pub async fn update_feeds(app: AppHandle) -> Result<Option<FeedIssue>> {
+    update(&app).await
+}
@@ -0,0 +1,319 @@
+//! Parsing of RSS 2.0, RSS 1.0 (RDF) and Atom news feeds into a single
+//! model. Feeds in the wild are often sloppy, so HTML entities XML does not
+//! declare are decoded before parsing and dates in either RFC 822 or RFC
+//! 3339 are read.
+
+use std::borrow::Cow;
+
+use roxmltree::Node;
+use url::Url;
+
+use crate::error::{Error, Result};
+use crate::formats::epub::{attr, parse_xml};
+use crate::formats::html::rewrite_named_entities;
+
+#[derive(Debug, Clone, Default)]
+pub struct NewsFeed {
+    pub title: String,
+    pub language: Option<String>,
+    pub items: Vec<NewsItem>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct NewsItem {
+    /// The guid or id of the item, else its link or title.
+    pub id: String,
+    pub title: String,
+    pub link: Option<String>,
+    pub author: Option<String>,
+    /// Milliseconds since the Unix epoch.
+    pub published: Option<i64>,
+    /// HTML of the full article, when the feed carries it.
+    pub content: Option<String>,
+    /// HTML of the summary.
+    pub summary: Option<String>,
+}
+
+fn invalid(message: &str) -> Error {
+    Error::InvalidFeed(message.to_string())
+}
+
+/// Replaces the named entities XML does not know with the characters they
+/// stand for, or escapes their ampersand when they are unknown too.
+fn declare_entities(body: &str) -> Cow<'_, str> {
+    rewrite_named_entities(body, |name, decoded| match decoded {
+        Some(c) => c.to_string(),
+        None => format!("&amp;{name};"),
+    })
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children()
+        .find(|n| n.is_element() && n.tag_name().name() == name)
+}
+
+fn child_text(node: Node, name: &str) -> Option<String> {
+    node.children()
+        .filter(|n| n.is_element() && n.tag_name().name() == name)
+        .map(|n| {
+            n.descendants()
+                .filter(|d| d.is_text())
+                .filter_map(|d| d.text())
+                .collect::<String>()
+                .trim()
+                .to_string()
+        })
+        .find(|t| !t.is_empty())
+}
+
+/// Markup of an Atom text construct: escaped HTML as is, and XHTML as the
+/// source of its `div`.
+fn atom_content(body: &str, node: Node) -> Option<String> {
+    if attr(node, "type") == Some("xhtml") {
+        let div = node.children().find(|n| n.is_element())?;
+        return Some(body[div.range()].to_string());
+    }
+    let text = node.text().unwrap_or_default().trim();
+    if text.is_empty() {
+        None
+    } else if matches!(attr(node, "type"), Some("html" | "text/html")) {
+        Some(text.to_string())
+    } else {
+        Some(format!("<p>{}</p>", crate::utils::escape_xml(text)))
+    }
+}
+
+fn resolve(base: &Url, href: &str) -> String {
+    base.join(href.trim())
+        .map_or_else(|_| href.trim().to_string(), String::from)
+}
+
+pub fn parse_news_feed(url: &Url, body: &str) -> Result<NewsFeed> {
+    let body = declare_entities(body);
+    let doc = parse_xml(&body)?;
+    let root = doc.root_element();
+    match root.tag_name().name() {
+        "rss" => {
+            let channel = child(root, "channel").ok_or_else(|| invalid("no channel"))?;
+            Ok(parse_rss(url, channel, channel))
+        }
+        // RSS 1.0 lists its items next to the channel
+        "RDF" => {
+            let channel = child(root, "channel").ok_or_else(|| invalid("no channel"))?;
+            Ok(parse_rss(url, channel, root))
+        }
+        "feed" => Ok(parse_atom(url, &body, root)),
+        _ => Err(invalid("expected an RSS or Atom feed")),
+    }
+}
+
+fn parse_rss(url: &Url, channel: Node, items: Node) -> NewsFeed {
+    let items = items
+        .children()
+        .filter(|n| n.is_element() && n.tag_name().name() == "item")
+        .map(|item| {
+            let link = child_text(item, "link").map(|link| resolve(url, &link));
+            let title = child_text(item, "title")
+                .map(|title| crate::formats::html::html_to_text(&title))
+                .unwrap_or_default();
+            NewsItem {
+                id: child_text(item, "guid")
+                    .or_else(|| attr(item, "about").map(String::from))
+                    .or_else(|| link.clone())
+                    .unwrap_or_else(|| title.clone()),
+                title,
+                link,
+                author: child_text(item, "creator").or_else(|| child_text(item, "author")),
+                published: child_text(item, "pubDate")
+                    .or_else(|| child_text(item, "date"))
+                    .and_then(|date| parse_date(&date)),
+                content: child_text(item, "encoded"),
+                summary: child_text(item, "description"),
+            }
+        })
+        .collect();
+    NewsFeed {
+        title: child_text(channel, "title").unwrap_or_default(),
+        language: child_text(channel, "language"),
+        items,
+    }
+}
+
+fn parse_atom(url: &Url, body: &str, root: Node) -> NewsFeed {
+    let items = root
+        .children()
+        .filter(|n| n.is_element() && n.tag_name().name() == "entry")
+        .map(|entry| {
+            let link = entry
+                .children()
+                .filter(|n| n.is_element() && n.tag_name().name() == "link")
+                .find(|n| matches!(attr(*n, "rel"), None | Some("alternate")))
+                .and_then(|n| attr(n, "href"))
+                .map(|href| resolve(url, href));
+            let title = child(entry, "title")
+                .and_then(|title| atom_content(body, title))
+                .map(|title| crate::formats::html::html_to_text(&title))
+                .unwrap_or_default();
+            NewsItem {
+                id: child_text(entry, "id")
+                    .or_else(|| link.clone())
+                    .unwrap_or_else(|| title.clone()),
+                title,
+                link,
+                author: child(entry, "author").and_then(|author| child_text(author, "name")),
+                published: child_text(entry, "published")
+                    .or_else(|| child_text(entry, "updated"))
+                    .and_then(|date| parse_date(&date)),
+                content: child(entry, "content").and_then(|content| atom_content(body, content)),
+                summary: child(entry, "summary").and_then(|summary| atom_content(body, summary)),
+            }
+        })
+        .collect();
+    NewsFeed {
+        title: child(root, "title")
+            .and_then(|title| atom_content(body, title))
+            .map(|title| crate::formats::html::html_to_text(&title))
+            .unwrap_or_default(),
+        language: attr(root, "lang").map(String::from),
+        items,
+    }
+}
+
+/// Parses a date in RFC 3339, as Atom and Dublin Core use, or in RFC 822,
+/// as RSS does, into milliseconds since the Unix epoch.
+pub fn parse_date(text: &str) -> Option<i64> {
+    crate::utils::parse_rfc3339(text).or_else(|| parse_rfc822(text))
+}
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+
+/// Parses dates like `Tue, 10 Jun 2003 04:00:00 GMT`. The weekday and the
+/// seconds are optional, and two-digit years are taken to be this century.
+fn parse_rfc822(text: &str) -> Option<i64> {
+    let text = text.trim();
+    let text = text.split_once(',').map_or(text, |(_, rest)| rest);
+    let mut parts = text.split_whitespace();
+    let day: u32 = parts.next()?.parse().ok()?;
+    let month = parts.next()?.get(..3)?.to_ascii_lowercase();
+    let month = MONTHS.iter().position(|m| *m == month)? + 1;
+    let year: u32 = match parts.next()?.parse().ok()? {
+        year @ 0..=99 => 2000 + year,
+        year => year,
+    };
+    let mut time = parts.next().unwrap_or("00:00").split(':');
+    let hour: u32 = time.next()?.parse().ok()?;
+    let minute: u32 = time.next()?.parse().ok()?;
+    let second: u32 = time.next().map_or(Some(0), |s| s.parse().ok())?;
+    let offset = match parts.next().unwrap_or("GMT") {
+        "GMT" | "UT" | "UTC" | "Z" => "Z".to_string(),
+        "EDT" => "-04:00".to_string(),
+        "EST" | "CDT" => "-05:00".to_string(),
+        "CST" | "MDT" => "-06:00".to_string(),
+        "MST" | "PDT" => "-07:00".to_string(),
+        "PST" => "-08:00".to_string(),
+        zone if zone.len() == 5 && zone.starts_with(['+', '-']) => {
+            format!("{}:{}", &zone[..3], &zone[3..])
+        }
+        _ => "Z".to_string(),
+    };
+    crate::utils::parse_rfc3339(&format!(
+        "{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}{offset}"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url() -> Url {
+        Url::parse("https://example.com/blog/feed.xml").unwrap()
+    }
+
+    #[test]
+    fn rss_with_html_entities_and_relative_links() {
+        let body = r#"<?xml version="1.0"?>
+<rss version="2.0" xmlns:dc="http://purl.org/dc/elements/1.1/"><channel>
+  <title>News &mdash; daily</title>
+  <item>
+    <title>A &amp; B&nbsp;&bogus;</title>
+    <link> posts/1.html </link>
+    <dc:creator>Ann</dc:creator>
+    <pubDate>Tue, 10 Jun 2003 04:00:00 GMT</pubDate>
+  </item>
+  <item><description>&lt;p&gt;untitled&lt;/p&gt;</description></item>
+</channel></rss>"#;
+        let feed = parse_news_feed(&url(), body).unwrap();
+        assert_eq!(feed.title, "News — daily");
+        let [first, second] = feed.items.as_slice() else {
+            panic!("{:?}", feed.items);
+        };
+        assert_eq!(first.title, "A & B &bogus;");
+        assert_eq!(first.id, "https://example.com/blog/posts/1.html");
+        assert_eq!(first.author.as_deref(), Some("Ann"));
+        assert_eq!(first.published, Some(1_055_217_600_000));
+        assert_eq!(second.id, "");
+        assert_eq!(second.summary.as_deref(), Some("<p>untitled</p>"));
+    }
+
+    #[test]
+    fn rdf_items_sit_beside_the_channel() {
+        let body = r#"<rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#"
+  xmlns="http://purl.org/rss/1.0/"><channel><title>R</title></channel>
+  <item rdf:about="urn:1"><title>One</title></item></rdf:RDF>"#;
+        let feed = parse_news_feed(&url(), body).unwrap();
+        assert_eq!(feed.items.len(), 1);
+        assert_eq!(feed.items[0].id, "urn:1");
+    }
+
+    #[test]
+    fn atom_text_constructs() {
+        let body = r#"<feed xmlns="http://www.w3.org/2005/Atom" xml:lang="en">
+  <title type="html">&lt;b&gt;Blog&lt;/b&gt;</title>
+  <entry>
+    <title>1 &lt; 2</title>
+    <link rel="edit" href="/edit/1"/><link href="/1"/>
+    <updated>2024-01-02T03:04:05Z</updated>
+    <content type="xhtml"><div xmlns="http://www.w3.org/1999/xhtml"><p>x</p></div></content>
+    <summary>plain &amp; simple</summary>
+  </entry>
+</feed>"#;
+        let feed = parse_news_feed(&url(), body).unwrap();
+        assert_eq!(feed.title, "Blog");
+        assert_eq!(feed.language.as_deref(), Some("en"));
+        let entry = &feed.items[0];
+        assert_eq!(entry.title, "1 < 2");
+        assert_eq!(entry.link.as_deref(), Some("https://example.com/1"));
+        assert_eq!(entry.published, Some(1_704_164_645_000));
+        assert_eq!(
+            entry.content.as_deref(),
+            Some(r#"<div xmlns="http://www.w3.org/1999/xhtml"><p>x</p></div>"#)
+        );
+        assert_eq!(entry.summary.as_deref(), Some("<p>plain &amp; simple</p>"));
+    }
+
+    #[test]
+    fn rejects_what_is_not_a_feed() {
+        assert!(parse_news_feed(&url(), "<html><body/></html>").is_err());
+        assert!(parse_news_feed(&url(), "<rss version=\"2.0\"/>").is_err());
+        assert!(parse_news_feed(&url(), "<rss><channel>").is_err());
+        assert!(parse_news_feed(&url(), "").is_err());
+    }
+
+    #[test]
+    fn sloppy_dates() {
+        assert_eq!(
+            parse_date("10 Jun 03 04:00 EDT"),
+            parse_date("2003-06-10T08:00:00Z")
+        );
+        assert_eq!(
+            parse_date("Tue, 10 Jun 2003 04:00:00 +0130"),
+            parse_date("2003-06-10T02:30:00Z")
+        );
+        assert_eq!(parse_date("Tue, 10 Foo 2003"), None);
+        assert_eq!(parse_date("Tue, 10 Jun 2003 4"), None);
+        assert_eq!(parse_date("10 J"), None);
+        assert_eq!(parse_date(""), None);
+    }
+}
@@ -0,0 +1,730 @@
+//! Extraction of the article from a web page, after the scoring of Mozilla's
+//! Readability: paragraphs give points to their ancestors, the ancestor with
+//! the most points short of links is the article, and siblings scoring
+//! nearly as well join it. Pages are parsed by a tolerant scanner into a
+//! small tree, since news sites rarely serve well-formed markup, and what
+//! is kept is written back out as XHTML for the EPUB.
+
+use std::fmt::Write as _;
+use std::sync::OnceLock;
+
+use regex::Regex;
+use url::Url;
+
+use crate::formats::html::decode_entities;
+use crate::utils::escape_xml;
+
+const VOID_TAGS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
+/// Elements whose content is not markup.
+const RAW_TAGS: &[&str] = &["script", "style", "textarea", "title"];
+/// Elements that are never part of an article.
+const DROPPED_TAGS: &[&str] = &[
+    "aside", "button", "canvas", "embed", "footer", "form", "iframe", "input", "link", "meta",
+    "nav", "noscript", "object", "select", "svg", "template",
+];
+const BLOCK_TAGS: &[&str] = &[
+    "address",
+    "article",
+    "blockquote",
+    "dd",
+    "div",
+    "dl",
+    "dt",
+    "figure",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "li",
+    "main",
+    "ol",
+    "p",
+    "pre",
+    "section",
+    "table",
+    "ul",
+];
+/// Elements written out as they are, the others are replaced by their
+/// content.
+const KEPT_TAGS: &[&str] = &[
+    "a",
+    "abbr",
+    "b",
+    "blockquote",
+    "br",
+    "caption",
+    "cite",
+    "code",
+    "dd",
+    "del",
+    "dfn",
+    "div",
+    "dl",
+    "dt",
+    "em",
+    "figcaption",
+    "figure",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "i",
+    "img",
+    "ins",
+    "kbd",
+    "li",
+    "mark",
+    "ol",
+    "p",
+    "pre",
+    "q",
+    "s",
+    "samp",
+    "small",
+    "strong",
+    "sub",
+    "sup",
+    "table",
+    "tbody",
+    "td",
+    "tfoot",
+    "th",
+    "thead",
+    "tr",
+    "u",
+    "ul",
+    "var",
+];
+/// Elements written out as a `div`.
+const SECTION_TAGS: &[&str] = &["article", "main", "section"];
+/// Deepest nesting kept, deeper elements are added to the deepest one.
+const MAX_DEPTH: usize = 256;
+/// Shortest text a paragraph needs to count.
+const MIN_PARAGRAPH_CHARS: usize = 25;
+/// Shortest article accepted, shorter extractions are likely navigation.
+const MIN_ARTICLE_CHARS: usize = 250;
+
+const UNLIKELY: &str = r"(?i)-ad-|ai2html|banner|breadcrumbs|combx|comment|community|cookie|cover-wrap|disqus|extra|footer|gdpr|header|legends|menu|newsletter|pager|pagination|popup|related|remark|replies|rss|share|shoutbox|sidebar|skyscraper|social|sponsor|subscribe|supplemental|yom-remote";
+const MAYBE_CANDIDATE: &str = r"(?i)and|article|body|column|content|main|shadow";
+const POSITIVE: &str =
+    r"(?i)article|blog|body|content|entry|hentry|h-entry|main|page|post|story|text";
+const NEGATIVE: &str = r"(?i)-ad-|hidden|^hid$| hid$| hid |^hid |banner|combx|comment|com-|contact|foot|footnote|gdpr|masthead|media|meta|outbrain|promo|related|scroll|share|shoutbox|sidebar|skyscraper|sponsor|shopping|tags|tool|widget";
+
+enum Kind {
+    Element {
+        name: String,
+        attrs: Vec<(String, String)>,
+    },
+    Text(String),
+}
+
+struct Node {
+    kind: Kind,
+    children: Vec<usize>,
+}
+
+/// A parsed page. Nodes are created in document order, so every node comes
+/// after its parent.
+struct Dom {
+    nodes: Vec<Node>,
+    title: Option<String>,
+}
+
+impl Dom {
+    fn name(&self, node: usize) -> &str {
+        match &self.nodes[node].kind {
+            Kind::Element { name, .. } => name,
+            Kind::Text(_) => "",
+        }
+    }
+
+    fn attr(&self, node: usize, attr: &str) -> Option<&str> {
+        match &self.nodes[node].kind {
+            Kind::Element { attrs, .. } => attrs
+                .iter()
+                .find(|(name, _)| name == attr)
+                .map(|(_, value)| value.as_str()),
+            Kind::Text(_) => None,
+        }
+    }
+
+    fn set_attr(&mut self, node: usize, attr: &str, value: String) {
+        if let Kind::Element { attrs, .. } = &mut self.nodes[node].kind {
+            match attrs.iter_mut().find(|(name, _)| name == attr) {
+                Some(existing) => existing.1 = value,
+                None => attrs.push((attr.to_string(), value)),
+            }
+        }
+    }
+
+    fn push(&mut self, parent: usize, kind: Kind) -> usize {
+        if let (Kind::Text(text), Some(&last)) = (&kind, self.nodes[parent].children.last()) {
+            if let Kind::Text(previous) = &mut self.nodes[last].kind {
+                previous.push_str(text);
+                return last;
+            }
+        }
+        self.nodes.push(Node {
+            kind,
+            children: Vec::new(),
+        });
+        let index = self.nodes.len() - 1;
+        self.nodes[parent].children.push(index);
+        index
+    }
+
+    /// The nodes under `node` in document order, `node` first.
+    fn descendants(&self, node: usize) -> Vec<usize> {
+        let mut found = Vec::new();
+        let mut pending = vec![node];
+        while let Some(next) = pending.pop() {
+            found.push(next);
+            pending.extend(self.nodes[next].children.iter().rev());
+        }
+        found
+    }
+
+    fn text(&self, node: usize) -> String {
+        self.descendants(node)
+            .into_iter()
+            .filter_map(|n| match &self.nodes[n].kind {
+                Kind::Text(text) => Some(text.as_str()),
+                Kind::Element { .. } => None,
+            })
+            .collect()
+    }
+}
+
+/// Name, attributes, whether it closes itself, and length of a start tag.
+type Tag = (String, Vec<(String, String)>, bool, usize);
+
+/// Reads the tag starting at `<` of `html`.
+fn parse_tag(html: &str) -> Option<Tag> {
+    let bytes = html.as_bytes();
+    let mut at = 1;
+    let name_end = html[at..]
+        .find(|c: char| c.is_ascii_whitespace() || c == '/' || c == '>')
+        .map_or(html.len(), |end| at + end);
+    let name = html[at..name_end].to_ascii_lowercase();
+    // Drop namespace prefixes such as `xhtml:p`
+    let name = name.rsplit(':').next().unwrap_or_default().to_string();
+    at = name_end;
+    let mut attrs = Vec::new();
+    let mut self_closing = false;
+    loop {
+        while at < bytes.len() && (bytes[at].is_ascii_whitespace() || bytes[at] == b'/') {
+            self_closing = bytes[at] == b'/';
+            at += 1;
+        }
+        match bytes.get(at)? {
+            b'>' => return Some((name, attrs, self_closing, at + 1)),
+            _ => self_closing = false,
+        }
+        let end = html[at..]
+            .find(|c: char| c.is_ascii_whitespace() || c == '=' || c == '>' || c == '/')
+            .map_or(html.len(), |end| at + end.max(1));
+        let attr = html[at..end].to_ascii_lowercase();
+        at = end;
+        while at < bytes.len() && bytes[at].is_ascii_whitespace() {
+            at += 1;
+        }
+        let mut value = String::new();
+        if bytes.get(at) == Some(&b'=') {
+            at += 1;
+            while at < bytes.len() && bytes[at].is_ascii_whitespace() {
+                at += 1;
+            }
+            let (start, end, next) = match bytes.get(at)? {
+                quote @ (b'"' | b'\'') => {
+                    let close = html[at + 1..].find(*quote as char)? + at + 1;
+                    (at + 1, close, close + 1)
+                }
+                _ => {
+                    let end = html[at..]
+                        .find(|c: char| c.is_ascii_whitespace() || c == '>')
+                        .map_or(html.len(), |end| at + end);
+                    (at, end, end)
+                }
+            };
+            value = decode_entities(&html[start..end]);
+            at = next;
+        }
+        if !attr.is_empty() && !attrs.iter().any(|(name, _)| *name == attr) {
+            attrs.push((attr, value));
+        }
+    }
+}
+
+/// Pops the innermost open element named one of `names`, with the elements
+/// inside it, unless one of `boundary` is opened after it.
+fn close_open(dom: &Dom, stack: &mut Vec<usize>, names: &[&str], boundary: &[&str]) {
+    for i in (1..stack.len()).rev() {
+        let name = dom.name(stack[i]);
+        if names.contains(&name) {
+            stack.truncate(i);
+            return;
+        }
+        if boundary.contains(&name) {
+            return;
+        }
+    }
+}
+
+fn parse(html: &str) -> Dom {
+    let mut dom = Dom {
+        nodes: vec![Node {
+            kind: Kind::Element {
+                name: String::new(),
+                attrs: Vec::new(),
+            },
+            children: Vec::new(),
+        }],
+        title: None,
+    };
+    let mut stack = vec![0];
+    let mut rest = html;
+    let text = |dom: &mut Dom, stack: &[usize], text: &str| {
+        if !text.is_empty() {
+            dom.push(*stack.last().unwrap(), Kind::Text(decode_entities(text)));
+        }
+    };
+    while let Some(start) = rest.find('<') {
+        text(&mut dom, &stack, &rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("<!--") {
+            rest = after.find("-->").map_or("", |end| &after[end + 3..]);
+            continue;
+        }
+        if rest.starts_with("<!") || rest.starts_with("<?") {
+            rest = rest.find('>').map_or("", |end| &rest[end + 1..]);
+            continue;
+        }
+        if let Some(after) = rest.strip_prefix("</") {
+            let end = after.find('>').unwrap_or(after.len());
+            let name = after[..end]
+                .trim()
+                .to_ascii_lowercase()
+                .rsplit(':')
+                .next()
+                .unwrap_or_default()
+                .to_string();
+            if let Some(i) = stack.iter().rposition(|&n| n != 0 && dom.name(n) == name) {
+                stack.truncate(i);
+            }
+            rest = after.get(end + 1..).unwrap_or("");
+            continue;
+        }
+        if !rest[1..].starts_with(|c: char| c.is_ascii_alphabetic()) {
+            text(&mut dom, &stack, "<");
+            rest = &rest[1..];
+            continue;
+        }
+        let Some((name, attrs, self_closing, len)) = parse_tag(rest) else {
+            break;
+        };
+        rest = &rest[len..];
+        if RAW_TAGS.contains(&name.as_str()) {
+            let close = rest.to_ascii_lowercase().find(&format!("</{name}"));
+            let content = &rest[..close.unwrap_or(rest.len())];
+            if name == "title" && dom.title.is_none() {
+                dom.title = Some(decode_entities(content).trim().to_string());
+            }
+            rest = close
+                .and_then(|close| rest[close..].find('>').map(|end| &rest[close + end + 1..]))
+                .unwrap_or("");
+            continue;
+        }
+
+        // The end tags HTML lets authors leave out
+        match name.as_str() {
+            "li" => close_open(&dom, &mut stack, &["li"], &["ul", "ol"]),
+            "dt" | "dd" => close_open(&dom, &mut stack, &["dt", "dd"], &["dl"]),
+            "td" | "th" => close_open(&dom, &mut stack, &["td", "th"], &["tr", "table"]),
+            "tr" => close_open(&dom, &mut stack, &["tr"], &["table"]),
+            "option" => close_open(&dom, &mut stack, &["option"], &["select"]),
+            _ => {}
+        }
+        if BLOCK_TAGS.contains(&name.as_str()) {
+            close_open(
+                &dom,
+                &mut stack,
+                &["p"],
+                &["button", "caption", "table", "td", "th"],
+            );
+        }
+        let void = self_closing || VOID_TAGS.contains(&name.as_str());
+        let node = dom.push(*stack.last().unwrap(), Kind::Element { name, attrs });
+        if !void && stack.len() < MAX_DEPTH {
+            stack.push(node);
+        }
+    }
+    text(&mut dom, &stack, rest);
+    dom
+}
+
+struct Patterns {
+    unlikely: Regex,
+    maybe: Regex,
+    positive: Regex,
+    negative: Regex,
+}
+
+impl Patterns {
+    /// The patterns, compiled the first time they are needed.
+    fn get() -> &'static Self {
+        static PATTERNS: OnceLock<Patterns> = OnceLock::new();
+        PATTERNS.get_or_init(|| Self {
+            unlikely: Regex::new(UNLIKELY).expect("valid pattern"),
+            maybe: Regex::new(MAYBE_CANDIDATE).expect("valid pattern"),
+            positive: Regex::new(POSITIVE).expect("valid pattern"),
+            negative: Regex::new(NEGATIVE).expect("valid pattern"),
+        })
+    }
+
+    /// Points for the class and id of an element.
+    fn class_weight(&self, dom: &Dom, node: usize) -> f32 {
+        let mut weight = 0.0;
+        for name in ["class", "id"] {
+            if let Some(value) = dom.attr(node, name).filter(|v| !v.is_empty()) {
+                if self.negative.is_match(value) {
+                    weight -= 25.0;
+                }
+                if self.positive.is_match(value) {
+                    weight += 25.0;
+                }
+            }
+        }
+        weight
+    }
+}
+
+fn is_hidden(dom: &Dom, node: usize) -> bool {
+    dom.attr(node, "hidden").is_some()
+        || dom.attr(node, "aria-hidden") == Some("true")
+        || dom.attr(node, "style").is_some_and(|style| {
+            let style = style.replace(' ', "").to_ascii_lowercase();
+            style.contains("display:none") || style.contains("visibility:hidden")
+        })
+}
+
+/// Detaches the elements that cannot be part of the content, and makes
+/// links and images absolute.
+fn prune(dom: &mut Dom, base: &Url, patterns: Option<&Patterns>) {
+    for node in dom.descendants(0) {
+        let mut children = std::mem::take(&mut dom.nodes[node].children);
+        children.retain(|&child| {
+            let name = dom.name(child);
+            if !matches!(dom.nodes[child].kind, Kind::Element { .. }) {
+                return true;
+            }
+            if DROPPED_TAGS.contains(&name) || is_hidden(dom, child) {
+                return false;
+            }
+            let Some(patterns) = patterns else {
+                return true;
+            };
+            if matches!(name, "html" | "body" | "article" | "main" | "a") {
+                return true;
+            }
+            let names = format!(
+                "{} {}",
+                dom.attr(child, "class").unwrap_or_default(),
+                dom.attr(child, "id").unwrap_or_default()
+            );
+            !patterns.unlikely.is_match(&names) || patterns.maybe.is_match(&names)
+        });
+        dom.nodes[node].children = children;
+
+        let resolve = |value: &str| {
+            base.join(value.trim())
+                .map_or_else(|_| value.to_string(), String::from)
+        };
+        match dom.name(node) {
+            "a" => {
+                if let Some(href) = dom.attr(node, "href").map(resolve) {
+                    dom.set_attr(node, "href", href);
+                }
+            }
+            "img" => {
+                // Lazily loaded images keep the real source aside
+                let src = ["data-src", "data-original", "data-lazy-src", "src"]
+                    .iter()
+                    .filter_map(|attr| dom.attr(node, attr))
+                    .find(|src| !src.is_empty() && !src.starts_with("data:"))
+                    .map(resolve);
+                if let Some(src) = src {
+                    dom.set_attr(node, "src", src);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Lengths of the text under each node and of the part of it in links.
+fn text_lengths(dom: &Dom) -> (Vec<usize>, Vec<usize>) {
+    let mut text = vec![0; dom.nodes.len()];
+    let mut links = vec![0; dom.nodes.len()];
+    for node in (0..dom.nodes.len()).rev() {
+        if let Kind::Text(value) = &dom.nodes[node].kind {
+            text[node] = value
+                .split_whitespace()
+                .map(|word| word.chars().count() + 1)
+                .sum();
+        }
+        for &child in &dom.nodes[node].children {
+            text[node] += text[child];
+            links[node] += links[child];
+        }
+        if dom.name(node) == "a" {
+            links[node] = text[node];
+        }
+    }
+    (text, links)
+}
+
+fn link_density(text: &[usize], links: &[usize], node: usize) -> f32 {
+    if text[node] == 0 {
+        0.0
+    } else {
+        links[node] as f32 / text[node] as f32
+    }
+}
+
+fn base_score(dom: &Dom, node: usize, patterns: &Patterns) -> f32 {
+    let score = match dom.name(node) {
+        "div" | "article" => 5.0,
+        "pre" | "td" | "blockquote" => 3.0,
+        "address" | "ol" | "ul" | "dl" | "dd" | "dt" | "li" | "form" => -3.0,
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "th" => -5.0,
+        _ => 0.0,
+    };
+    score + patterns.class_weight(dom, node)
+}
+
+/// Whether a `div` holds no blocks, and so counts as a paragraph.
+fn is_paragraph_div(dom: &Dom, node: usize) -> bool {
+    dom.nodes[node]
+        .children
+        .iter()
+        .all(|&child| !BLOCK_TAGS.contains(&dom.name(child)))
+}
+
+/// What is kept of a page, ready to be written as XHTML.
+pub struct Content {
+    dom: Dom,
+    nodes: Vec<usize>,
+}
+
+impl Content {
+    /// The title of the page.
+    pub fn title(&self) -> Option<&str> {
+        self.dom.title.as_deref().filter(|title| !title.is_empty())
+    }
+
+    /// Length of the text, in characters.
+    pub fn text_len(&self) -> usize {
+        self.nodes
+            .iter()
+            .map(|&node| {
+                self.dom
+                    .text(node)
+                    .split_whitespace()
+                    .map(|word| word.chars().count())
+                    .sum::<usize>()
+            })
+            .sum()
+    }
+
+    /// Absolute URLs of the images, in document order.
+    pub fn images(&self) -> Vec<String> {
+        let mut images = Vec::new();
+        for &node in &self.nodes {
+            for n in self.dom.descendants(node) {
+                if self.dom.name(n) == "img" {
+                    if let Some(src) = self.dom.attr(n, "src") {
+                        if (src.starts_with("http://") || src.starts_with("https://"))
+                            && !images.iter().any(|i| i == src)
+                        {
+                            images.push(src.to_string());
+                        }
+                    }
+                }
+            }
+        }
+        images
+    }
+
+    /// Writes the content as XHTML. `image` gives the href of each image in
+    /// the book, images without one are left out.
+    pub fn to_xhtml(&self, image: impl Fn(&str) -> Option<String>) -> String {
+        let mut out = String::new();
+        for &node in &self.nodes {
+            self.write(&mut out, node, &image);
+        }
+        out
+    }
+
+    fn write(&self, out: &mut String, node: usize, image: &impl Fn(&str) -> Option<String>) {
+        let dom = &self.dom;
+        let name = match &dom.nodes[node].kind {
+            Kind::Text(text) => {
+                out.push_str(&escape_xml(text));
+                return;
+            }
+            Kind::Element { name, .. } => name.as_str(),
+        };
+        let tag = if SECTION_TAGS.contains(&name) {
+            "div"
+        } else if KEPT_TAGS.contains(&name) {
+            name
+        } else {
+            for &child in &dom.nodes[node].children {
+                self.write(out, child, image);
+            }
+            return;
+        };
+        let mut attrs = String::new();
+        let mut push_attr = |name: &str, value: &str| {
+            let _ = write!(attrs, " {name}=\"{}\"", escape_xml(value));
+        };
+        match tag {
+            "img" => {
+                let Some(href) = dom.attr(node, "src").and_then(image) else {
+                    return;
+                };
+                push_attr("src", &href);
+                push_attr("alt", dom.attr(node, "alt").unwrap_or_default());
+            }
+            "a" => {
+                if let Some(href) = dom.attr(node, "href").filter(|href| {
+                    ["http://", "https://", "mailto:"]
+                        .iter()
+                        .any(|scheme| href.starts_with(scheme))
+                }) {
+                    push_attr("href", href);
+                }
+            }
+            "td" | "th" => {
+                for span in ["colspan", "rowspan"] {
+                    if let Some(value) = dom.attr(node, span).filter(|v| v.parse::<u32>().is_ok()) {
+                        push_attr(span, value);
+                    }
+                }
+            }
+            "ol" => {
+                if let Some(start) = dom.attr(node, "start").filter(|v| v.parse::<i32>().is_ok()) {
+                    push_attr("start", start);
+                }
+            }
+            _ => {}
+        }
+        if VOID_TAGS.contains(&tag) {
+            let _ = write!(out, "<{tag}{attrs}/>");
+            return;
+        }
+        let _ = write!(out, "<{tag}{attrs}>");
+        for &child in &dom.nodes[node].children {
+            self.write(out, child, image);
+        }
+        let _ = write!(out, "</{tag}>");
+    }
+}
+
+/// The whole of an HTML fragment, such as the content of a feed item,
+/// without scripts and other things that do not belong in a book.
+pub fn clean(html: &str, base: &Url) -> Content {
+    let mut dom = parse(html);
+    prune(&mut dom, base, None);
+    Content {
+        nodes: dom.nodes[0].children.clone(),
+        dom,
+    }
+}
+
+/// The article of a web page, or `None` when no part of it reads like one.
+pub fn extract(html: &str, base: &Url) -> Option<Content> {
+    let patterns = Patterns::get();
+    let mut dom = parse(html);
+    prune(&mut dom, base, Some(patterns));
+    let (text, links) = text_lengths(&dom);
+
+    let mut parents = vec![usize::MAX; dom.nodes.len()];
+    for node in 0..dom.nodes.len() {
+        for &child in &dom.nodes[node].children {
+            parents[child] = node;
+        }
+    }
+    let mut scores: Vec<Option<f32>> = vec![None; dom.nodes.len()];
+    for node in dom.descendants(0) {
+        let counts = match dom.name(node) {
+            "p" | "pre" | "td" | "blockquote" => true,
+            "div" => is_paragraph_div(&dom, node),
+            _ => false,
+        };
+        if !counts || text[node] < MIN_PARAGRAPH_CHARS {
+            continue;
+        }
+        let paragraph = dom.text(node);
+        let commas = paragraph.matches([',', '，', '、']).count();
+        let points = 1.0 + commas as f32 + (text[node] as f32 / 100.0).min(3.0);
+        let mut ancestor = parents[node];
+        for level in 0..5 {
+            if ancestor == usize::MAX || ancestor == 0 {
+                break;
+            }
+            let score =
+                scores[ancestor].get_or_insert_with(|| base_score(&dom, ancestor, patterns));
+            *score += match level {
+                0 => points,
+                1 => points / 2.0,
+                _ => points / (level as f32 * 3.0),
+            };
+            ancestor = parents[ancestor];
+        }
+    }
+    let final_score =
+        |node: usize| scores[node].map(|score| score * (1.0 - link_density(&text, &links, node)));
+    let (top, top_score) = (0..dom.nodes.len())
+        .filter_map(|node| Some((node, final_score(node)?)))
+        .max_by(|a, b| a.1.total_cmp(&b.1))?;
+
+    // Siblings of the article that are likely more of it
+    let threshold = (top_score * 0.2).max(10.0);
+    let parent = parents[top];
+    let nodes = if parent == usize::MAX {
+        vec![top]
+    } else {
+        dom.nodes[parent]
+            .children
+            .iter()
+            .copied()
+            .filter(|&sibling| {
+                if sibling == top {
+                    return true;
+                }
+                if final_score(sibling).is_some_and(|score| score >= threshold) {
+                    return true;
+                }
+                if dom.name(sibling) != "p" {
+                    return false;
+                }
+                let density = link_density(&text, &links, sibling);
+                let len = text[sibling];
+                (len > 80 && density < 0.25)
+                    || (len > 0 && density == 0.0 && dom.text(sibling).contains(". "))
+            })
+            .collect()
+    };
+    let content = Content { dom, nodes };
+    (content.text_len() >= MIN_ARTICLE_CHARS).then_some(content)
+}
@@ -0,0 +1,391 @@
+//! The focus timer: a reading session of a set length, or one that runs
+//! until stopped, with a reminder to take a break every so often. On
+//! request it turns on Do Not Disturb for the session, Focus Assist on
+//! Windows and, through shortcuts the user makes, a Focus on macOS, and
+//! puts it back as it was after. Its reminders are then held back by the
+//! system like any other, so the frontend shows them from the
+//! `focus-session` events too.
+//!
+//! Ended sessions go to the [`stats`](crate::stats), which count them apart
+//! from the reading sessions the reader records.
+
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle, Emitter, Manager, State};
+use tauri_plugin_notification::NotificationExt;
+
+use crate::error::{Error, Result};
+use crate::library::db::LibraryDb;
+use crate::stats::{self, NewFocusSession};
+use crate::store;
+use crate::utils::now_millis;
+
+const SETTINGS_FILE: &str = "focus.json";
+const FOCUS_EVENT: &str = "focus-session";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct FocusSettings {
+    /// Length of a session, 0 for sessions that run until stopped.
+    pub minutes: u32,
+    /// Minutes between break reminders, 0 for none.
+    pub break_every_minutes: u32,
+    pub do_not_disturb: bool,
+    /// Shortcuts run on macOS to turn a Focus on and off.
+    pub focus_on_shortcut: String,
+    pub focus_off_shortcut: String,
+}
+
+impl Default for FocusSettings {
+    fn default() -> Self {
+        Self {
+            minutes: 50,
+            break_every_minutes: 25,
+            do_not_disturb: false,
+            focus_on_shortcut: "Start Reading Focus".to_string(),
+            focus_off_shortcut: "Stop Reading Focus".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FocusSession {
+    pub id: String,
+    pub book_hash: Option<String>,
+    pub started_at: i64,
+    /// None for a session that runs until stopped.
+    pub ends_at: Option<i64>,
+    pub break_every_minutes: u32,
+    /// Break reminders given so far.
+    pub breaks: u32,
+    /// Whether Do Not Disturb was turned on for it.
+    pub do_not_disturb: bool,
+}
+
+impl FocusSession {
+    /// When the next break reminder or the end is due, and whether it is
+    /// the end.
+    fn next(&self) -> Option<(i64, bool)> {
+        let every = i64::from(self.break_every_minutes) * 60_000;
+        let next_break =
+            (every > 0).then(|| self.started_at + every * (i64::from(self.breaks) + 1));
+        match (next_break, self.ends_at) {
+            (Some(at), Some(end)) if at < end => Some((at, false)),
+            (_, Some(end)) => Some((end, true)),
+            (Some(at), None) => Some((at, false)),
+            (None, None) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+enum FocusEvent {
+    Started {
+        session: FocusSession,
+    },
+    Break {
+        session: FocusSession,
+        elapsed_ms: i64,
+    },
+    Ended {
+        session: FocusSession,
+        ended_at: i64,
+        /// Whether it ran its length rather than being stopped.
+        completed: bool,
+    },
+}
+
+/// What to put back when Do Not Disturb was turned on for a session.
+enum Previous {
+    #[cfg(windows)]
+    Profile(u32),
+    #[cfg(target_os = "macos")]
+    Focus,
+}
+
+#[cfg(windows)]
+fn turn_on(_settings: &FocusSettings) -> Result<Previous> {
+    use crate::windows::focus_assist;
+
+    let previous = focus_assist::profile()?;
+    if previous == focus_assist::OFF {
+        focus_assist::set_profile(focus_assist::PRIORITY_ONLY)?;
+    }
+    Ok(Previous::Profile(previous))
+}
+
+#[cfg(target_os = "macos")]
+fn turn_on(settings: &FocusSettings) -> Result<Previous> {
+    crate::macos::focus::run_shortcut(&settings.focus_on_shortcut)?;
+    Ok(Previous::Focus)
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+fn turn_on(_settings: &FocusSettings) -> Result<Previous> {
+    Err(Error::Focus(
+        "Do Not Disturb cannot be turned on here".to_string(),
+    ))
+}
+
+fn put_back(settings: &FocusSettings, previous: Previous) -> Result<()> {
+    #[cfg(not(target_os = "macos"))]
+    let _ = settings;
+    match previous {
+        #[cfg(windows)]
+        Previous::Profile(profile) => crate::windows::focus_assist::set_profile(profile),
+        #[cfg(target_os = "macos")]
+        Previous::Focus => crate::macos::focus::run_shortcut(&settings.focus_off_shortcut),
+    }
+}
+
+struct Running {
+    session: FocusSession,
+    settings: FocusSettings,
+    previous: Option<Previous>,
+    timer: tauri::async_runtime::JoinHandle<()>,
+}
+
+#[derive(Default)]
+pub struct FocusTimer(Mutex<Option<Running>>);
+
+impl FocusTimer {
+    fn session(&self) -> Option<FocusSession> {
+        self.0
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|running| running.session.clone())
+    }
+}
+
+#[cfg(desktop)]
+fn tr(app: &AppHandle, key: &str) -> String {
+    crate::i18n::t(app, key)
+}
+
+#[cfg(mobile)]
+fn tr(_app: &AppHandle, key: &str) -> String {
+    key.to_string()
+}
+
+/// Emits `event`, and tells the user with a notification too unless they
+/// are looking at the app.
+fn announce(app: &AppHandle, event: FocusEvent) {
+    let text = match &event {
+        FocusEvent::Started { .. } => None,
+        FocusEvent::Break { elapsed_ms, .. } => Some((
+            "Time for a Break",
+            "You've been reading for {{minutes}} min.",
+            *elapsed_ms,
+        )),
+        FocusEvent::Ended {
+            session,
+            ended_at,
+            completed: true,
+        } => Some((
+            "Focus Session Over",
+            "You read for {{minutes}} min.",
+            ended_at - session.started_at,
+        )),
+        FocusEvent::Ended { .. } => None,
+    };
+    let in_front = app
+        .get_webview_window("main")
+        .is_some_and(|window| window.is_focused().unwrap_or(false));
+    if let Some((title, body, elapsed_ms)) = text.filter(|_| !in_front) {
+        let body = tr(app, body).replace("{{minutes}}", &(elapsed_ms / 60_000).to_string());
+        if let Err(e) = app
+            .notification()
+            .builder()
+            .title(tr(app, title))
+            .body(body)
+            .show()
+        {
+            log::warn!("Failed to show a notification: {e}");
+        }
+    }
+    let _ = app.emit(FOCUS_EVENT, event);
+}
+
+/// Ends the running session, if it is `id` when given, putting Do Not
+/// Disturb back and recording it.
+fn finish(app: &AppHandle, id: Option<&str>, completed: bool) -> Option<FocusSession> {
+    let running = {
+        let timer = app.state::<FocusTimer>();
+        let mut current = timer.0.lock().unwrap();
+        if id.is_some_and(|id| current.as_ref().is_some_and(|r| r.session.id != id)) {
+            return None;
+        }
+        current.take()?
+    };
+    if !completed {
+        running.timer.abort();
+    }
+    let Running {
+        session,
+        settings,
+        previous,
+        ..
+    } = running;
+    if let Some(previous) = previous {
+        if let Err(e) = put_back(&settings, previous) {
+            log::warn!("Failed to turn Do Not Disturb off: {e}");
+        }
+    }
+    let ended_at = now_millis();
+    let record = NewFocusSession {
+        book_hash: session.book_hash.clone(),
+        started_at: session.started_at,
+        ended_at,
+        breaks: session.breaks,
+        completed,
+    };
+    if let Err(e) = stats::record_focus_session(&app.state::<LibraryDb>().conn(), &record) {
+        log::warn!("Failed to record the focus session: {e}");
+    }
+    announce(
+        app,
+        FocusEvent::Ended {
+            session: session.clone(),
+            ended_at,
+            completed,
+        },
+    );
+    Some(session)
+}
+
+async fn run_timer(app: AppHandle, id: String) {
+    loop {
+        let Some((at, end)) = app.state::<FocusTimer>().session().and_then(|s| s.next()) else {
+            return;
+        };
+        let wait = (at - now_millis()).max(0) as u64;
+        tokio::time::sleep(std::time::Duration::from_millis(wait)).await;
+        if end {
+            // Shortcuts that turn a Focus off take a while
+            let (app, id) = (app.clone(), id.clone());
+            let _ =
+                tauri::async_runtime::spawn_blocking(move || finish(&app, Some(&id), true)).await;
+            return;
+        }
+        let session = {
+            let timer = app.state::<FocusTimer>();
+            let mut current = timer.0.lock().unwrap();
+            let Some(running) = current.as_mut().filter(|r| r.session.id == id) else {
+                return;
+            };
+            running.session.breaks += 1;
+            running.session.clone()
+        };
+        let elapsed_ms = now_millis() - session.started_at;
+        announce(
+            &app,
+            FocusEvent::Break {
+                session,
+                elapsed_ms,
+            },
+        );
+    }
+}
+
+/// Ends the running session when the app quits, so Do Not Disturb does not
+/// stay on.
+#[cfg(desktop)]
+pub fn on_exit(app: &AppHandle) {
+    finish(app, None, false);
+}
+
+#[command]
+pub fn get_focus_settings(app: AppHandle) -> FocusSettings {
+    store::load(&app, SETTINGS_FILE)
+}
+
+#[command]
+pub fn set_focus_settings(app: AppHandle, settings: FocusSettings) -> Result<()> {
+    store::save(&app, SETTINGS_FILE, &settings)
+}
+
+#[command]
+pub fn get_focus_session(timer: State<'_, FocusTimer>) -> Option<FocusSession> {
+    timer.session()
+}
+
+/// Starts a session, of `minutes` when given instead of the length in the
+/// settings, on book `book_hash` if it is for one. When Do Not Disturb
+/// cannot be turned on, the session runs without it.
+#[command]
+pub async fn start_focus_session(
+    app: AppHandle,
+    book_hash: Option<String>,
+    minutes: Option<u32>,
+) -> Result<FocusSession> {
+    if app.state::<FocusTimer>().session().is_some() {
+        return Err(Error::Focus("a session is running already".to_string()));
+    }
+    let settings: FocusSettings = store::load(&app, SETTINGS_FILE);
+    let previous = if settings.do_not_disturb {
+        let settings = settings.clone();
+        match tauri::async_runtime::spawn_blocking(move || turn_on(&settings)).await? {
+            Ok(previous) => Some(previous),
+            Err(e) => {
+                log::warn!("Failed to turn Do Not Disturb on: {e}");
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let started_at = now_millis();
+    let minutes = minutes.unwrap_or(settings.minutes);
+    let session = FocusSession {
+        id: {
+            use rand::Rng;
+            format!("{:016x}", rand::thread_rng().gen::<u64>())
+        },
+        book_hash,
+        started_at,
+        ends_at: (minutes > 0).then(|| started_at + i64::from(minutes) * 60_000),
+        break_every_minutes: settings.break_every_minutes,
+        breaks: 0,
+        do_not_disturb: previous.is_some(),
+    };
+    {
+        let timer = app.state::<FocusTimer>();
+        let mut current = timer.0.lock().unwrap();
+        if current.is_some() {
+            // Started while Do Not Disturb was being turned on
+            drop(current);
+            if let Some(previous) = previous {
+                put_back(&settings, previous)?;
+            }
+            return Err(Error::Focus("a session is running already".to_string()));
+        }
+        let timer = tauri::async_runtime::spawn(run_timer(app.clone(), session.id.clone()));
+        *current = Some(Running {
+            session: session.clone(),
+            settings,
+            previous,
+            timer,
+        });
+    }
+    announce(
+        &app,
+        FocusEvent::Started {
+            session: session.clone(),
+        },
+    );
+    Ok(session)
+}
+
+/// Stops the running session, returning it, or none if none was running.
+#[command]
+pub async fn stop_focus_session(app: AppHandle) -> Result<Option<FocusSession>> {
+    Ok(tauri::async_runtime::spawn_blocking(move || finish(&app, None, false)).await?)
+}
@@ -0,0 +1,307 @@
+//! Fonts installed on the system, for the reader's font picker, and font
+//! files cut down to what a book needs before they are embedded in it.
+//! Faces are listed from the font files themselves, so collections show
+//! every face and the names are the ones the fonts give.
+
+pub mod subset;
+pub mod truetype;
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use rayon::prelude::*;
+use serde::Serialize;
+use tauri::ipc::Response;
+use tauri::{command, AppHandle, Manager};
+
+use crate::error::Result;
+use crate::formats::epub::stream::MappedFile;
+use truetype::{face_count, face_tables, u16_at, u32_at};
+
+const FONT_EXTENSIONS: &[&str] = &["ttf", "otf", "ttc", "otc"];
+
+const FAMILY: u16 = 1;
+const SUBFAMILY: u16 = 2;
+const FULL_NAME: u16 = 4;
+const TYPOGRAPHIC_FAMILY: u16 = 16;
+const TYPOGRAPHIC_SUBFAMILY: u16 = 17;
+/// Windows language id of US English.
+const ENGLISH: u16 = 0x0409;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SystemFont {
+    pub family: String,
+    /// Family name in another language, such as the Chinese name of a CJK
+    /// font, when the font has one.
+    pub localized_family: Option<String>,
+    pub style: String,
+    pub full_name: String,
+    /// CSS weight, 400 for regular and 700 for bold.
+    pub weight: u16,
+    pub italic: bool,
+    pub monospace: bool,
+    pub path: PathBuf,
+    /// Face in a font collection, 0 for single fonts.
+    pub index: u32,
+}
+
+/// System fonts, listed the first time they are asked for.
+#[derive(Default)]
+pub struct SystemFonts(Mutex<Option<Arc<Vec<SystemFont>>>>);
+
+fn font_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    #[cfg(target_os = "windows")]
+    {
+        let windir = std::env::var_os("WINDIR").unwrap_or_else(|| "C:\\Windows".into());
+        dirs.push(PathBuf::from(windir).join("Fonts"));
+        if let Some(local) = std::env::var_os("LOCALAPPDATA") {
+            dirs.push(PathBuf::from(local).join("Microsoft\\Windows\\Fonts"));
+        }
+    }
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    {
+        dirs.push("/System/Library/Fonts".into());
+        dirs.push("/Library/Fonts".into());
+        if let Some(home) = std::env::var_os("HOME") {
+            dirs.push(PathBuf::from(home).join("Library/Fonts"));
+        }
+    }
+    #[cfg(target_os = "android")]
+    dirs.push("/system/fonts".into());
+    #[cfg(all(
+        unix,
+        not(any(target_os = "macos", target_os = "ios", target_os = "android"))
+    ))]
+    {
+        dirs.push("/usr/share/fonts".into());
+        dirs.push("/usr/local/share/fonts".into());
+        if let Some(home) = std::env::var_os("HOME").map(PathBuf::from) {
+            dirs.push(home.join(".local/share/fonts"));
+            dirs.push(home.join(".fonts"));
+        }
+    }
+    dirs
+}
+
+fn is_font_file(path: &Path) -> bool {
+    let ext = crate::formats::extension(path);
+    FONT_EXTENSIONS.iter().any(|known| *known == ext)
+}
+
+fn scan(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(t) if t.is_dir() => scan(&path, files),
+            Ok(t) if t.is_file() && is_font_file(&path) => files.push(path),
+            _ => {}
+        }
+    }
+}
+
+fn decode_name(platform: u16, encoding: u16, bytes: &[u8]) -> Option<String> {
+    let name = match (platform, encoding) {
+        (0, _) | (3, 0 | 1 | 10) => {
+            let units = bytes
+                .chunks_exact(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]]));
+            char::decode_utf16(units)
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect::<String>()
+        }
+        (1, 0) => encoding_rs::MACINTOSH
+            .decode_without_bom_handling(bytes)
+            .0
+            .into_owned(),
+        _ => return None,
+    };
+    let name = name.trim_matches(char::from(0)).trim();
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// Name `id` from the `name` table, in English, and in another language
+/// when it differs.
+fn name(table: &[u8], id: u16) -> Result<(Option<String>, Option<String>)> {
+    let count = u16_at(table, 2)? as usize;
+    let storage = u16_at(table, 4)? as usize;
+    // English names by platform preference: Windows, Unicode, Macintosh
+    let mut english: Option<(u8, String)> = None;
+    let mut other = None;
+    for i in 0..count {
+        let field = |offset: usize| u16_at(table, 6 + i * 12 + offset);
+        if field(6)? != id {
+            continue;
+        }
+        let (platform, encoding, language) = (field(0)?, field(2)?, field(4)?);
+        let start = storage + field(10)? as usize;
+        let Some(value) = table
+            .get(start..start + field(8)? as usize)
+            .and_then(|bytes| decode_name(platform, encoding, bytes))
+        else {
+            continue;
+        };
+        let rank = match (platform, language) {
+            (3, ENGLISH) => 0,
+            (0, _) => 1,
+            (1, 0) => 2,
+            (3, _) => {
+                other.get_or_insert(value);
+                continue;
+            }
+            _ => continue,
+        };
+        if english.as_ref().map_or(true, |(best, _)| rank < *best) {
+            english = Some((rank, value));
+        }
+    }
+    let english = english.map(|(_, value)| value);
+    let other = other.filter(|other| Some(other) != english.as_ref());
+    Ok((english, other))
+}
+
+/// Face `index` of a font file, or `None` when it has no usable name.
+fn describe(data: &[u8], path: &Path, index: u32) -> Result<Option<SystemFont>> {
+    let (_, tables) = face_tables(data, index)?;
+    let find = |tag: &[u8; 4]| {
+        tables
+            .iter()
+            .find(|(t, _)| t == tag)
+            .map(|(_, table)| *table)
+    };
+    let Some(names) = find(b"name") else {
+        return Ok(None);
+    };
+    let (family, localized_family) = match name(names, TYPOGRAPHIC_FAMILY)? {
+        (Some(family), localized) => (Some(family), localized),
+        _ => name(names, FAMILY)?,
+    };
+    let Some(family) = family.or_else(|| localized_family.clone()) else {
+        return Ok(None);
+    };
+    let style = match name(names, TYPOGRAPHIC_SUBFAMILY)?.0 {
+        Some(style) => style,
+        None => name(names, SUBFAMILY)?
+            .0
+            .unwrap_or_else(|| "Regular".to_string()),
+    };
+    let full_name = name(names, FULL_NAME)?
+        .0
+        .unwrap_or_else(|| format!("{family} {style}"));
+    let mac_style = find(b"head")
+        .and_then(|head| u16_at(head, 44).ok())
+        .unwrap_or(0);
+    let os2 = find(b"OS/2");
+    let weight = os2
+        .and_then(|os2| u16_at(os2, 4).ok())
+        .filter(|weight| (1..=1000).contains(weight))
+        .unwrap_or(if mac_style & 1 != 0 { 700 } else { 400 });
+    let italic = match os2.and_then(|os2| u16_at(os2, 62).ok()) {
+        Some(selection) => selection & 1 != 0,
+        None => mac_style & 2 != 0,
+    };
+    let monospace = find(b"post")
+        .and_then(|post| u32_at(post, 12).ok())
+        .is_some_and(|fixed| fixed != 0);
+    Ok(Some(SystemFont {
+        family,
+        localized_family,
+        style,
+        full_name,
+        weight,
+        italic,
+        monospace,
+        path: path.to_path_buf(),
+        index,
+    }))
+}
+
+/// Every face of the font file at `path`.
+fn faces(path: &Path) -> Result<Vec<SystemFont>> {
+    let data = MappedFile::open(path)?;
+    let data = data.as_ref();
+    let mut faces = Vec::new();
+    for index in 0..face_count(data)? {
+        faces.extend(describe(data, path, index)?);
+    }
+    Ok(faces)
+}
+
+fn list_fonts() -> Vec<SystemFont> {
+    let mut files = Vec::new();
+    for dir in font_dirs() {
+        scan(&dir, &mut files);
+    }
+    let mut fonts = files
+        .par_iter()
+        .flat_map_iter(|path| {
+            faces(path).unwrap_or_else(|e| {
+                log::debug!("Skipping font {path:?}: {e}");
+                Vec::new()
+            })
+        })
+        .collect::<Vec<_>>();
+    // The same font is often installed both for the user and system-wide
+    fonts.sort_by_cached_key(|font| {
+        (
+            font.family.to_lowercase(),
+            font.italic,
+            font.weight,
+            font.style.clone(),
+        )
+    });
+    fonts.dedup_by(|a, b| a.family == b.family && a.style == b.style);
+    log::info!(
+        "Found {} system fonts in {} files",
+        fonts.len(),
+        files.len()
+    );
+    fonts
+}
+
+/// Lists the installed font faces by family, listing them again when
+/// `refresh` is set, for fonts installed while the app runs.
+#[command]
+pub async fn list_system_fonts(app: AppHandle, refresh: Option<bool>) -> Result<Vec<SystemFont>> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let state = app.state::<SystemFonts>();
+        if !refresh.unwrap_or(false) {
+            if let Some(fonts) = state.0.lock().unwrap().clone() {
+                return Ok(fonts.to_vec());
+            }
+        }
+        // List without the lock, scanning a large font folder takes a while
+        let fonts = Arc::new(list_fonts());
+        *state.0.lock().unwrap() = Some(fonts.clone());
+        Ok(fonts.to_vec())
+    })
+    .await?
+}
+
+/// Returns face `index` of the font file as a font of its own, with only
+/// the glyphs of `text` when it is given. OpenType fonts with PostScript
+/// outlines are returned whole.
+#[command]
+pub async fn embed_font(
+    path: PathBuf,
+    index: Option<u32>,
+    text: Option<String>,
+) -> Result<Response> {
+    let data = tauri::async_runtime::spawn_blocking(move || {
+        let face = truetype::extract_face(MappedFile::open(&path)?.as_ref(), index.unwrap_or(0))?;
+        match text {
+            Some(text) if !truetype::has_cff_outlines(&face) => {
+                let chars = text.chars().collect::<BTreeSet<_>>();
+                subset::subset(&face, &chars, &BTreeSet::new())
+            }
+            _ => Ok(face),
+        }
+    })
+    .await??;
+    Ok(Response::new(data))
+}
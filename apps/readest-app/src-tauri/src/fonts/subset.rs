@@ -0,0 +1,215 @@
+//! Subsetting TrueType fonts down to the glyphs a text needs. Glyph ids stay
+//! as they are, so text already encoded against the whole font remains
+//! valid: the outlines of the other glyphs are emptied, and the character
+//! map only lists the characters kept.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::truetype::{face_tables, font_error, i16_at, u16_at, u32_at, write_font, TrueTypeFont};
+use crate::error::Result;
+
+/// Tables that substitute or position glyphs, or hold other glyph data,
+/// which would point at emptied glyphs.
+const DROPPED_TABLES: &[&[u8; 4]] = &[
+    b"GSUB", b"GPOS", b"GDEF", b"morx", b"kerx", b"DSIG", b"hdmx", b"LTSH", b"VDMX", b"EBDT",
+    b"EBLC", b"EBSC", b"CBDT", b"CBLC", b"sbix", b"SVG ",
+];
+
+const ARG_1_AND_2_ARE_WORDS: u16 = 0x0001;
+const WE_HAVE_A_SCALE: u16 = 0x0008;
+const MORE_COMPONENTS: u16 = 0x0020;
+const WE_HAVE_AN_X_AND_Y_SCALE: u16 = 0x0040;
+const WE_HAVE_A_TWO_BY_TWO: u16 = 0x0080;
+
+/// Glyphs a composite glyph is made of, none for simple glyphs.
+fn components(glyph: &[u8]) -> Result<Vec<u16>> {
+    let mut components = Vec::new();
+    if glyph.len() < 10 || i16_at(glyph, 0)? >= 0 {
+        return Ok(components);
+    }
+    let mut at = 10;
+    loop {
+        let flags = u16_at(glyph, at)?;
+        components.push(u16_at(glyph, at + 2)?);
+        at += if flags & ARG_1_AND_2_ARE_WORDS != 0 {
+            8
+        } else {
+            6
+        };
+        at += if flags & WE_HAVE_A_SCALE != 0 {
+            2
+        } else if flags & WE_HAVE_AN_X_AND_Y_SCALE != 0 {
+            4
+        } else if flags & WE_HAVE_A_TWO_BY_TWO != 0 {
+            8
+        } else {
+            0
+        };
+        if flags & MORE_COMPONENTS == 0 {
+            return Ok(components);
+        }
+    }
+}
+
+fn push_u16s(out: &mut Vec<u8>, values: impl IntoIterator<Item = u16>) {
+    for value in values {
+        out.extend(value.to_be_bytes());
+    }
+}
+
+/// A Windows Unicode character map for `mapping`: a format 12 subtable for
+/// every character, and a format 4 one for the BMP when it fits.
+fn cmap(mapping: &BTreeMap<u32, u16>) -> Vec<u8> {
+    // Runs of characters whose glyphs are consecutive too
+    let mut groups: Vec<(u32, u32, u32)> = Vec::new();
+    for (&c, &glyph) in mapping {
+        match groups.last_mut() {
+            Some(last) if last.1 + 1 == c && last.2 + (c - last.0) == glyph as u32 => last.1 = c,
+            _ => groups.push((c, c, glyph as u32)),
+        }
+    }
+
+    let mut full = Vec::new();
+    push_u16s(&mut full, [12, 0]);
+    full.extend((16 + groups.len() as u32 * 12).to_be_bytes());
+    full.extend(0u32.to_be_bytes());
+    full.extend((groups.len() as u32).to_be_bytes());
+    for &(start, end, glyph) in &groups {
+        for value in [start, end, glyph] {
+            full.extend(value.to_be_bytes());
+        }
+    }
+
+    // Segments with a delta each, and the closing one format 4 requires
+    let mut segments = groups
+        .iter()
+        .filter(|group| group.0 < 0xFFFF)
+        .map(|&(start, end, glyph)| {
+            let (start, end) = (start as u16, end.min(0xFFFE) as u16);
+            (start, end, (glyph as u16).wrapping_sub(start))
+        })
+        .collect::<Vec<_>>();
+    segments.push((0xFFFF, 0xFFFF, 1));
+    let mut subtables = Vec::new();
+    let length = 16 + segments.len() * 8;
+    if let Ok(length) = u16::try_from(length) {
+        let count = segments.len() as u16;
+        let selector = 15 - count.leading_zeros() as u16;
+        let search_range = 2 << selector;
+        let mut bmp = Vec::new();
+        push_u16s(
+            &mut bmp,
+            [
+                4,
+                length,
+                0,
+                count * 2,
+                search_range,
+                selector,
+                count * 2 - search_range,
+            ],
+        );
+        push_u16s(&mut bmp, segments.iter().map(|s| s.1));
+        push_u16s(&mut bmp, [0]);
+        push_u16s(&mut bmp, segments.iter().map(|s| s.0));
+        push_u16s(&mut bmp, segments.iter().map(|s| s.2));
+        push_u16s(&mut bmp, segments.iter().map(|_| 0));
+        subtables.push((1, bmp));
+    }
+    subtables.push((10, full));
+
+    let mut out = Vec::new();
+    push_u16s(&mut out, [0, subtables.len() as u16]);
+    let mut offset = 4 + subtables.len() as u32 * 8;
+    for (encoding, subtable) in &subtables {
+        push_u16s(&mut out, [3, *encoding]);
+        out.extend(offset.to_be_bytes());
+        offset += subtable.len() as u32;
+    }
+    for (_, subtable) in subtables {
+        out.extend(subtable);
+    }
+    out
+}
+
+/// Subsets a TrueType font file: `chars` keep their glyphs, as do `glyphs`
+/// given by id, glyph 0 and the components of the composite glyphs kept.
+pub fn subset(data: &[u8], chars: &BTreeSet<char>, glyphs: &BTreeSet<u16>) -> Result<Vec<u8>> {
+    let font = TrueTypeFont::parse(data.to_vec())?;
+    let (version, tables) = face_tables(data, 0)?;
+    let find = |tag: &[u8; 4]| {
+        tables
+            .iter()
+            .find(|(t, _)| t == tag)
+            .map(|(_, table)| *table)
+            .ok_or_else(|| font_error(&format!("no {} table", String::from_utf8_lossy(tag))))
+    };
+    let head = find(b"head")?;
+    let loca = find(b"loca")?;
+    let glyf = find(b"glyf")?;
+    let glyph_count = u16_at(find(b"maxp")?, 4)? as usize;
+    if head.len() < 54 {
+        return Err(font_error("truncated head table"));
+    }
+    let long_offsets = i16_at(head, 50)? != 0;
+    let offset = |glyph: usize| -> Result<usize> {
+        if long_offsets {
+            Ok(u32_at(loca, glyph * 4)? as usize)
+        } else {
+            Ok(u16_at(loca, glyph * 2)? as usize * 2)
+        }
+    };
+    let outline = |glyph: usize| -> Result<&[u8]> {
+        glyf.get(offset(glyph)?..offset(glyph + 1)?)
+            .ok_or_else(|| font_error("glyph out of bounds"))
+    };
+
+    let mapping = chars
+        .iter()
+        .filter_map(|&c| Some((c as u32, font.glyph(c)?)))
+        .filter(|&(_, glyph)| (glyph as usize) < glyph_count)
+        .collect::<BTreeMap<_, _>>();
+    let mut keep = mapping
+        .values()
+        .chain(glyphs)
+        .copied()
+        .chain([0])
+        .filter(|&glyph| (glyph as usize) < glyph_count)
+        .collect::<BTreeSet<_>>();
+    let mut pending = keep.iter().copied().collect::<Vec<_>>();
+    while let Some(glyph) = pending.pop() {
+        for component in components(outline(glyph as usize)?)? {
+            if (component as usize) < glyph_count && keep.insert(component) {
+                pending.push(component);
+            }
+        }
+    }
+
+    let mut new_glyf = Vec::new();
+    let mut new_loca = Vec::with_capacity((glyph_count + 1) * 4);
+    for glyph in 0..glyph_count {
+        new_loca.extend((new_glyf.len() as u32).to_be_bytes());
+        if keep.contains(&(glyph as u16)) {
+            new_glyf.extend(outline(glyph)?);
+            new_glyf.resize(new_glyf.len().next_multiple_of(4), 0);
+        }
+    }
+    new_loca.extend((new_glyf.len() as u32).to_be_bytes());
+    let mut new_head = head.to_vec();
+    new_head[50..52].copy_from_slice(&1u16.to_be_bytes());
+
+    let mut subset = tables
+        .iter()
+        .filter(|(tag, _)| {
+            !DROPPED_TABLES.contains(&tag) && !matches!(tag, b"glyf" | b"loca" | b"head" | b"cmap")
+        })
+        .map(|(tag, table)| (*tag, table.to_vec()))
+        .collect::<Vec<_>>();
+    subset.extend([
+        (*b"glyf", new_glyf),
+        (*b"loca", new_loca),
+        (*b"head", new_head),
+        (*b"cmap", cmap(&mapping)),
+    ]);
+    Ok(write_font(version, subset))
+}
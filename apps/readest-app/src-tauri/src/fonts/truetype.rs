@@ -0,0 +1,333 @@
+//! The few tables of a TrueType font needed to lay text out in it and embed
+//! it in a PDF: character to glyph mapping, advance widths and the metrics
+//! of the font descriptor. Also reads the table directory of any sfnt font,
+//! including each face of a collection, and writes one back out.
+
+use crate::error::{Error, Result};
+
+/// `ttcf`, the tag font collections start with.
+const COLLECTION_TAG: &[u8; 4] = b"ttcf";
+/// What the font checksum plus `checkSumAdjustment` in `head` must add up to.
+const CHECKSUM_MAGIC: u32 = 0xB1B0_AFBA;
+
+pub struct TrueTypeFont {
+    pub data: Vec<u8>,
+    pub units_per_em: u16,
+    pub ascent: i16,
+    pub descent: i16,
+    pub bbox: [i16; 4],
+    advances: Vec<u16>,
+    /// Sorted ranges of characters and the glyph of their first one.
+    ranges: Vec<(u32, u32, u32)>,
+}
+
+pub(super) fn font_error(message: &str) -> Error {
+    Error::Font(message.to_string())
+}
+
+pub(super) fn u16_at(data: &[u8], offset: usize) -> Result<u16> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or_else(|| font_error("truncated table"))
+}
+
+pub(super) fn i16_at(data: &[u8], offset: usize) -> Result<i16> {
+    u16_at(data, offset).map(|v| v as i16)
+}
+
+pub(super) fn u32_at(data: &[u8], offset: usize) -> Result<u32> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| font_error("truncated table"))
+}
+
+fn table<'a>(data: &'a [u8], tag: &[u8; 4]) -> Result<Option<&'a [u8]>> {
+    let count = u16_at(data, 4)? as usize;
+    for i in 0..count {
+        let record = 12 + i * 16;
+        if data.get(record..record + 4) != Some(tag) {
+            continue;
+        }
+        let offset = u32_at(data, record + 8)? as usize;
+        let len = u32_at(data, record + 12)? as usize;
+        return data
+            .get(offset..offset + len)
+            .map(Some)
+            .ok_or_else(|| font_error("table out of bounds"));
+    }
+    Ok(None)
+}
+
+pub(super) fn is_collection(data: &[u8]) -> bool {
+    data.get(..4) == Some(COLLECTION_TAG)
+}
+
+/// Whether the font has PostScript outlines in a `CFF ` table rather than
+/// TrueType ones.
+pub(super) fn has_cff_outlines(data: &[u8]) -> bool {
+    data.get(..4) == Some(b"OTTO")
+}
+
+/// Number of faces in the font file, more than one for collections.
+pub(super) fn face_count(data: &[u8]) -> Result<u32> {
+    if is_collection(data) {
+        u32_at(data, 8)
+    } else {
+        Ok(1)
+    }
+}
+
+/// Tables of a face by tag, in the order of its table directory.
+pub(super) type Tables<'a> = Vec<([u8; 4], &'a [u8])>;
+
+/// Version tag and tables of face `index` of the font file, which is either
+/// a single font or a collection.
+pub(super) fn face_tables(data: &[u8], index: u32) -> Result<(u32, Tables<'_>)> {
+    if index >= face_count(data)? {
+        return Err(font_error(&format!("no face {index}")));
+    }
+    let directory = if is_collection(data) {
+        u32_at(data, 12 + index as usize * 4)? as usize
+    } else {
+        0
+    };
+    let version = u32_at(data, directory)?;
+    let count = u16_at(data, directory + 4)? as usize;
+    let tables = (0..count)
+        .map(|i| {
+            let record = directory + 12 + i * 16;
+            let tag = data
+                .get(record..record + 4)
+                .and_then(|tag| <[u8; 4]>::try_from(tag).ok())
+                .ok_or_else(|| font_error("truncated table directory"))?;
+            // Offsets are from the start of the file, in collections too
+            let offset = u32_at(data, record + 8)? as usize;
+            let len = u32_at(data, record + 12)? as usize;
+            let table = data
+                .get(offset..offset + len)
+                .ok_or_else(|| font_error("table out of bounds"))?;
+            Ok((tag, table))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok((version, tables))
+}
+
+/// Face `index` of the font file as a font file of its own.
+pub fn extract_face(data: &[u8], index: u32) -> Result<Vec<u8>> {
+    if !is_collection(data) {
+        return if index == 0 {
+            Ok(data.to_vec())
+        } else {
+            Err(font_error(&format!("no face {index}")))
+        };
+    }
+    let (version, tables) = face_tables(data, index)?;
+    Ok(write_font(
+        version,
+        tables
+            .into_iter()
+            .map(|(tag, table)| (tag, table.to_vec()))
+            .collect(),
+    ))
+}
+
+fn checksum(data: &[u8]) -> u32 {
+    data.chunks(4).fold(0u32, |sum, chunk| {
+        let mut word = [0; 4];
+        word[..chunk.len()].copy_from_slice(chunk);
+        sum.wrapping_add(u32::from_be_bytes(word))
+    })
+}
+
+/// Writes `tables` as a font file with sfnt `version`, filling in the
+/// table checksums and the `checkSumAdjustment` of `head`.
+pub(super) fn write_font(version: u32, mut tables: Vec<([u8; 4], Vec<u8>)>) -> Vec<u8> {
+    tables.sort_by_key(|(tag, _)| *tag);
+    let count = tables.len() as u16;
+    let selector = if count == 0 {
+        0
+    } else {
+        15 - count.leading_zeros() as u16
+    };
+    let search_range = (1u16 << selector) * 16;
+    let mut out = Vec::new();
+    out.extend(version.to_be_bytes());
+    out.extend(count.to_be_bytes());
+    out.extend(search_range.to_be_bytes());
+    out.extend(selector.to_be_bytes());
+    out.extend((count * 16).saturating_sub(search_range).to_be_bytes());
+
+    let mut offset = 12 + tables.len() * 16;
+    let mut head_offset = None;
+    for (tag, table) in &mut tables {
+        if tag == b"head" && table.len() >= 12 {
+            table[8..12].fill(0);
+            head_offset = Some(offset);
+        }
+        out.extend(*tag);
+        out.extend(checksum(table).to_be_bytes());
+        out.extend((offset as u32).to_be_bytes());
+        out.extend((table.len() as u32).to_be_bytes());
+        offset += table.len().next_multiple_of(4);
+    }
+    for (_, table) in &tables {
+        out.extend(table.iter());
+        out.resize(out.len().next_multiple_of(4), 0);
+    }
+    if let Some(at) = head_offset {
+        let adjustment = CHECKSUM_MAGIC.wrapping_sub(checksum(&out));
+        out[at + 8..at + 12].copy_from_slice(&adjustment.to_be_bytes());
+    }
+    out
+}
+
+fn required<'a>(data: &'a [u8], tag: &[u8; 4]) -> Result<&'a [u8]> {
+    table(data, tag)?
+        .ok_or_else(|| font_error(&format!("no {} table", String::from_utf8_lossy(tag))))
+}
+
+/// Ranges of a format 4 subtable, the 16-bit one every font has.
+fn segment_ranges(cmap: &[u8]) -> Result<Vec<(u32, u32, u32)>> {
+    let segments = u16_at(cmap, 6)? as usize / 2;
+    let ends = 14;
+    let starts = ends + segments * 2 + 2;
+    let deltas = starts + segments * 2;
+    let range_offsets = deltas + segments * 2;
+    let mut ranges = Vec::new();
+    for i in 0..segments {
+        let end = u16_at(cmap, ends + i * 2)? as u32;
+        let start = u16_at(cmap, starts + i * 2)? as u32;
+        let delta = u16_at(cmap, deltas + i * 2)?;
+        let range_offset = u16_at(cmap, range_offsets + i * 2)? as usize;
+        if start > end || start == 0xFFFF {
+            continue;
+        }
+        if range_offset == 0 {
+            // Glyphs wrap around modulo 65536, so runs are split where they do
+            for c in start..=end {
+                let glyph = (c as u16).wrapping_add(delta) as u32;
+                push_range(&mut ranges, c, glyph);
+            }
+        } else {
+            for c in start..=end {
+                let at = range_offsets + i * 2 + range_offset + (c - start) as usize * 2;
+                let glyph = u16_at(cmap, at)?;
+                if glyph != 0 {
+                    push_range(&mut ranges, c, glyph.wrapping_add(delta) as u32);
+                }
+            }
+        }
+    }
+    Ok(ranges)
+}
+
+/// Extends the last range with `c` if it continues it, or starts a new one.
+fn push_range(ranges: &mut Vec<(u32, u32, u32)>, c: u32, glyph: u32) {
+    if glyph == 0 {
+        return;
+    }
+    if let Some(last) = ranges.last_mut() {
+        if last.1 + 1 == c && last.2 + (c - last.0) == glyph {
+            last.1 = c;
+            return;
+        }
+    }
+    ranges.push((c, c, glyph));
+}
+
+/// Ranges of a format 12 subtable, which covers characters beyond the BMP.
+fn group_ranges(cmap: &[u8]) -> Result<Vec<(u32, u32, u32)>> {
+    let groups = u32_at(cmap, 12)? as usize;
+    (0..groups)
+        .map(|i| {
+            let at = 16 + i * 12;
+            Ok((
+                u32_at(cmap, at)?,
+                u32_at(cmap, at + 4)?,
+                u32_at(cmap, at + 8)?,
+            ))
+        })
+        .collect()
+}
+
+fn unicode_ranges(cmap: &[u8]) -> Result<Vec<(u32, u32, u32)>> {
+    let count = u16_at(cmap, 2)? as usize;
+    let mut best: Option<(u16, usize)> = None;
+    for i in 0..count {
+        let record = 4 + i * 8;
+        let platform = u16_at(cmap, record)?;
+        let encoding = u16_at(cmap, record + 2)?;
+        let offset = u32_at(cmap, record + 4)? as usize;
+        let unicode = platform == 0 || (platform == 3 && matches!(encoding, 1 | 10));
+        if !unicode {
+            continue;
+        }
+        let format = u16_at(cmap, offset)?;
+        if matches!(format, 4 | 12) && best.map_or(true, |(f, _)| format > f) {
+            best = Some((format, offset));
+        }
+    }
+    let mut ranges = match best {
+        Some((12, offset)) => group_ranges(&cmap[offset..])?,
+        Some((_, offset)) => segment_ranges(&cmap[offset..])?,
+        None => return Err(font_error("no unicode cmap")),
+    };
+    ranges.sort_unstable();
+    Ok(ranges)
+}
+
+impl TrueTypeFont {
+    /// Reads a font with TrueType outlines, which PDF embeds as `FontFile2`.
+    pub fn parse(data: Vec<u8>) -> Result<Self> {
+        let version = u32_at(&data, 0)?;
+        if version != 0x0001_0000 && &data[..4] != b"true" {
+            return Err(font_error("not a TrueType font"));
+        }
+        required(&data, b"glyf")?;
+        let head = required(&data, b"head")?;
+        let hhea = required(&data, b"hhea")?;
+        let hmtx = required(&data, b"hmtx")?;
+        let metrics = u16_at(hhea, 34)? as usize;
+        let advances = (0..metrics)
+            .map(|i| u16_at(hmtx, i * 4))
+            .collect::<Result<Vec<_>>>()?;
+        if advances.is_empty() {
+            return Err(font_error("no glyph metrics"));
+        }
+        let ranges = unicode_ranges(required(&data, b"cmap")?)?;
+        Ok(Self {
+            units_per_em: u16_at(head, 18)?.max(1),
+            bbox: [
+                i16_at(head, 36)?,
+                i16_at(head, 38)?,
+                i16_at(head, 40)?,
+                i16_at(head, 42)?,
+            ],
+            ascent: i16_at(hhea, 4)?,
+            descent: i16_at(hhea, 6)?,
+            advances,
+            ranges,
+            data,
+        })
+    }
+
+    /// Glyph of `c`, or `None` if the font has none.
+    pub fn glyph(&self, c: char) -> Option<u16> {
+        let c = c as u32;
+        let i = self.ranges.partition_point(|range| range.1 < c);
+        let &(start, _, glyph) = self.ranges.get(i).filter(|range| range.0 <= c)?;
+        u16::try_from(glyph + (c - start)).ok()
+    }
+
+    /// Advance of `glyph` in thousandths of the font size.
+    pub fn advance(&self, glyph: u16) -> f32 {
+        // Glyphs past the last metric share its advance
+        let advance = self.advances[(glyph as usize).min(self.advances.len() - 1)];
+        advance as f32 * 1000.0 / self.units_per_em as f32
+    }
+
+    /// `value` in font units, in thousandths of the font size.
+    pub fn scale(&self, value: i16) -> i64 {
+        value as i64 * 1000 / self.units_per_em as i64
+    }
+}
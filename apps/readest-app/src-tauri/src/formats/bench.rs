@@ -0,0 +1,284 @@
+//! Opening a book up to its first chapter, the path that decides how soon
+//! the reader shows something, as the benchmarks in `benches/parsers.rs`
+//! and `vlarch --bench-open` measure it. EPUB and CBZ entries are read
+//! from the memory-mapped archive as the `book://` protocol reads them,
+//! before any processing, see [`crate::resources`]; PDFs go through the
+//! native parser, see [`pdf`].
+//!
+//! Left out of the usage of the app, `vlarch --bench-open <file>
+//! [--runs <n>] [--max-ms <ms>] [--max-memory-mb <mb>]` opens a book up to
+//! its first chapter a few times and prints how long that took and the
+//! peak memory as JSON. It fails when the median time or the memory is
+//! over the limits given, so scripts can catch regressions. It needs no
+//! app, and so no display, and is there in builds without the `cli`
+//! feature.
+
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+#[cfg(desktop)]
+use serde_json::json;
+
+use super::comic::ComicArchive;
+use super::epub::stream::MappedArchive;
+use super::epub::EpubArchive;
+use super::pdf::PdfDocument;
+use crate::error::{Error, Result};
+
+pub use super::extract_chapters;
+
+/// First argument of the benchmark mode.
+#[cfg(desktop)]
+const BENCH_ARG: &str = "--bench-open";
+/// Times the book is opened when `--runs` is not given.
+#[cfg(desktop)]
+const RUNS: usize = 5;
+/// Exit codes: usage errors and failures, the way shells expect them.
+#[cfg(desktop)]
+const EXIT_FAILED: i32 = 1;
+#[cfg(desktop)]
+const EXIT_USAGE: i32 = 2;
+
+/// What opening a book took and what it got to.
+#[derive(Debug, Clone)]
+pub struct Opened {
+    pub format: String,
+    /// Entry of the first chapter or page, the page number for PDFs.
+    pub chapter: String,
+    pub chapter_bytes: usize,
+    /// Reading the container, package or page list.
+    pub open: Duration,
+    /// Reading the first chapter after that.
+    pub first_chapter: Duration,
+}
+
+/// Opens `path` and reads its first chapter, or first page of a comic.
+pub fn open_first_chapter(path: &Path) -> Result<Opened> {
+    let format = super::extension(path);
+    let start = Instant::now();
+    let (chapter, read): (String, Box<dyn FnOnce() -> Result<usize> + '_>) = match format.as_str() {
+        "epub" => {
+            let package = EpubArchive::open(path)?.package()?;
+            let href = package
+                .spine_items()
+                .next()
+                .map(|item| item.href.clone())
+                .ok_or_else(|| Error::InvalidBook("empty spine".into()))?;
+            let name = href.clone();
+            (href, Box::new(move || read_entry(path, &name)))
+        }
+        "cbz" => {
+            let comic = ComicArchive::open(path)?;
+            let name = comic
+                .pages()
+                .first()
+                .map(|page| page.name.clone())
+                .ok_or_else(|| Error::InvalidBook("no pages".into()))?;
+            (name.clone(), Box::new(move || read_entry(path, &name)))
+        }
+        "pdf" => {
+            let pdf = PdfDocument::open(path)?;
+            if pdf.page_count() == 0 {
+                return Err(Error::InvalidBook("no pages".into()));
+            }
+            ("1".into(), Box::new(move || Ok(pdf.page_text(1)?.len())))
+        }
+        ext => return Err(Error::UnsupportedFormat(ext.to_string())),
+    };
+    let open = start.elapsed();
+    let chapter_bytes = read()?;
+    Ok(Opened {
+        format,
+        chapter,
+        chapter_bytes,
+        open,
+        first_chapter: start.elapsed() - open,
+    })
+}
+
+/// Reads entry `name` of the zipped book at `path` from a fresh map, as
+/// the first request of a book to the protocol does.
+fn read_entry(path: &Path, name: &str) -> Result<usize> {
+    Ok(MappedArchive::open(path)?.read(name)?.len())
+}
+
+/// Options of `--bench-open`.
+#[cfg(desktop)]
+struct Options {
+    file: std::path::PathBuf,
+    runs: usize,
+    max_ms: Option<f64>,
+    max_memory_mb: Option<f64>,
+}
+
+#[cfg(desktop)]
+impl Options {
+    /// Parses the arguments after `--bench-open`, as `--name value` or
+    /// `--name=value`.
+    fn parse(args: impl IntoIterator<Item = String>) -> std::result::Result<Self, String> {
+        let mut files = Vec::new();
+        let mut options = Self {
+            file: Default::default(),
+            runs: RUNS,
+            max_ms: None,
+            max_memory_mb: None,
+        };
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let Some(option) = arg.strip_prefix("--") else {
+                files.push(arg);
+                continue;
+            };
+            let (name, value) = match option.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (option, None),
+            };
+            if !matches!(name, "runs" | "max-ms" | "max-memory-mb") {
+                return Err(format!("unknown option --{name}"));
+            }
+            let value = value
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("--{name} needs a value"))?;
+            let invalid = || format!("invalid --{name} {value}");
+            match name {
+                "runs" => options.runs = value.parse::<usize>().map_err(|_| invalid())?.max(1),
+                "max-ms" => options.max_ms = Some(value.parse().map_err(|_| invalid())?),
+                _ => options.max_memory_mb = Some(value.parse().map_err(|_| invalid())?),
+            }
+        }
+        let [file] =
+            <[String; 1]>::try_from(files).map_err(|_| format!("{BENCH_ARG} takes one file"))?;
+        options.file = file.into();
+        Ok(options)
+    }
+}
+
+/// Runs `--bench-open` when it is the first command line argument, returning
+/// the exit code, or `None` when it is not and the app should start.
+#[cfg(desktop)]
+pub fn run() -> Option<i32> {
+    let mut args = crate::paths::portable::without_data_dir_arg(std::env::args())
+        .into_iter()
+        .skip(1);
+    if args.next()? != BENCH_ARG {
+        return None;
+    }
+    crate::attach_console();
+    let options = match Options::parse(args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("vlarch: {e}");
+            return Some(EXIT_USAGE);
+        }
+    };
+    let code = match report(&options) {
+        Ok(true) => 0,
+        Ok(false) => EXIT_FAILED,
+        Err(e) => {
+            eprintln!("vlarch: {}: {e}", options.file.display());
+            EXIT_FAILED
+        }
+    };
+    Some(code)
+}
+
+/// Opens the book up to its first chapter as often as the options say,
+/// printing the timings and returning whether they are within the limits.
+#[cfg(desktop)]
+fn report(options: &Options) -> Result<bool> {
+    let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+    let baseline = peak_memory();
+    let mut opened = Vec::with_capacity(options.runs);
+    for _ in 0..options.runs {
+        opened.push(open_first_chapter(&options.file)?);
+    }
+    let peak = peak_memory();
+    let cold = opened[0].clone();
+    let mut totals = opened
+        .iter()
+        .map(|opened| millis(opened.open + opened.first_chapter))
+        .collect::<Vec<_>>();
+    totals.sort_by(f64::total_cmp);
+    let median = totals[totals.len() / 2];
+    let report = json!({
+        "file": options.file,
+        "format": cold.format,
+        "chapter": cold.chapter,
+        "chapterBytes": cold.chapter_bytes,
+        "runs": options.runs,
+        "coldMs": millis(cold.open + cold.first_chapter),
+        "coldOpenMs": millis(cold.open),
+        "coldFirstChapterMs": millis(cold.first_chapter),
+        "medianMs": median,
+        "minMs": totals[0],
+        "maxMs": totals[totals.len() - 1],
+        "peakMemoryBytes": peak,
+        "peakMemoryGrowthBytes": peak.zip(baseline).map(|(peak, baseline)| peak - baseline),
+    });
+    println!("{}", serde_json::to_string_pretty(&report)?);
+
+    let mut within = true;
+    if let Some(max_ms) = options.max_ms.filter(|max| median > *max) {
+        eprintln!("Median of {median:.1} ms is over the limit of {max_ms} ms");
+        within = false;
+    }
+    let peak_mb = peak.map(|peak| peak as f64 / (1024.0 * 1024.0));
+    match (options.max_memory_mb, peak_mb) {
+        (Some(max), Some(peak)) if peak > max => {
+            eprintln!("Peak memory of {peak:.1} MB is over the limit of {max} MB");
+            within = false;
+        }
+        (Some(_), None) => eprintln!("Peak memory is not known on this system"),
+        _ => {}
+    }
+    Ok(within)
+}
+
+/// The most memory the process has had resident, in bytes.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn peak_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))?;
+    let kib = line
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kib * 1024)
+}
+
+#[cfg(target_os = "macos")]
+pub fn peak_memory() -> Option<u64> {
+    // SAFETY: getrusage only writes the struct it is given
+    let mut usage = unsafe { std::mem::zeroed::<libc::rusage>() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return None;
+    }
+    // In bytes here, unlike on Linux
+    u64::try_from(usage.ru_maxrss).ok()
+}
+
+#[cfg(windows)]
+pub fn peak_memory() -> Option<u64> {
+    use windows::Win32::System::ProcessStatus::{GetProcessMemoryInfo, PROCESS_MEMORY_COUNTERS};
+    use windows::Win32::System::Threading::GetCurrentProcess;
+
+    let mut counters = PROCESS_MEMORY_COUNTERS::default();
+    let size = std::mem::size_of::<PROCESS_MEMORY_COUNTERS>() as u32;
+    // SAFETY: the counters are as large as told
+    unsafe { GetProcessMemoryInfo(GetCurrentProcess(), &mut counters, size) }.ok()?;
+    Some(counters.PeakWorkingSetSize as u64)
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    windows
+)))]
+pub fn peak_memory() -> Option<u64> {
+    None
+}
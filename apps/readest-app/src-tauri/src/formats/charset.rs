@@ -0,0 +1,109 @@
+//! Guessing the encoding of text and HTML files that do not say what it
+//! is, which for books from CJK locales is often GBK, Big5, Shift_JIS or
+//! EUC-KR rather than UTF-8.
+
+use encoding_rs::Encoding;
+
+use crate::error::{Error, Result};
+
+/// Bytes looked at to guess the encoding.
+const SAMPLE_SIZE: usize = 64 * 1024;
+/// Bytes searched for an HTML or XML encoding declaration.
+const DECLARATION_SIZE: usize = 1024;
+
+/// Frequent Chinese characters in simplified and traditional forms, which
+/// text decoded with the wrong encoding rarely contains.
+const COMMON_HANZI: &str = "的一是不了人我在有他她这這个個们們来來上大为為和国國地到以说說时時要就\
+                            出会會也你对對生能而子那得于於着著下自之年过過发發后後作里裡道没沒";
+
+/// How many characters of `text` are typical of the language `encoding`
+/// is used for.
+fn score(encoding: &'static Encoding, text: &str) -> usize {
+    text.chars()
+        .filter(|&c| match encoding.name() {
+            // Kana, which every Japanese sentence is full of
+            "Shift_JIS" => ('\u{3041}'..='\u{30ff}').contains(&c),
+            // Hangul syllables
+            "EUC-KR" => ('\u{ac00}'..='\u{d7a3}').contains(&c),
+            _ => COMMON_HANZI.contains(c),
+        })
+        .count()
+}
+
+/// The encoding `label` names, such as `gbk` or `shift_jis`.
+pub fn for_label(label: &str) -> Result<&'static Encoding> {
+    Encoding::for_label(label.trim().as_bytes())
+        .ok_or_else(|| Error::InvalidBook(format!("unknown encoding {label}")))
+}
+
+/// The encoding `bytes` look like, from their byte order mark or else
+/// their content, falling back to Windows-1252.
+pub fn detect(bytes: &[u8]) -> &'static Encoding {
+    if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+        return encoding;
+    }
+    let sample = &bytes[..bytes.len().min(SAMPLE_SIZE)];
+    // Latin text stored as UTF-16 has a zero in every other byte
+    let pairs = sample.len() / 2;
+    let zeros = |skip: usize| {
+        sample
+            .iter()
+            .skip(skip)
+            .step_by(2)
+            .filter(|&&b| b == 0)
+            .count()
+    };
+    if pairs > 0 && zeros(1) * 10 > pairs * 3 {
+        return encoding_rs::UTF_16LE;
+    }
+    if pairs > 0 && zeros(0) * 10 > pairs * 3 {
+        return encoding_rs::UTF_16BE;
+    }
+    match std::str::from_utf8(sample) {
+        Ok(_) => return encoding_rs::UTF_8,
+        // The sample may end inside a character
+        Err(e) if e.error_len().is_none() => return encoding_rs::UTF_8,
+        Err(_) => {}
+    }
+    // Text in one CJK encoding often decodes without errors in another, so
+    // the one that gives the characters of its language wins
+    [
+        encoding_rs::GBK,
+        encoding_rs::BIG5,
+        encoding_rs::SHIFT_JIS,
+        encoding_rs::EUC_KR,
+    ]
+    .into_iter()
+    .filter_map(|encoding| {
+        let (text, _) = encoding.decode_without_bom_handling(sample);
+        let errors = text.chars().filter(|&c| c == '\u{fffd}').count();
+        let hits = score(encoding, &text);
+        (errors <= 1 && hits > 0).then_some((encoding, hits))
+    })
+    .min_by_key(|&(_, hits)| std::cmp::Reverse(hits))
+    .map_or(encoding_rs::WINDOWS_1252, |(encoding, _)| encoding)
+}
+
+/// The encoding named by a `<meta charset>` or `<?xml encoding?>`
+/// declaration at the top of an HTML document.
+pub fn declared(bytes: &[u8]) -> Option<&'static Encoding> {
+    let head = String::from_utf8_lossy(&bytes[..bytes.len().min(DECLARATION_SIZE)]).to_lowercase();
+    ["charset=", "encoding="].into_iter().find_map(|key| {
+        let value = head.split_once(key)?.1.trim_start_matches(['"', '\'', ' ']);
+        let end = value
+            .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | ':' | '.')))
+            .unwrap_or(value.len());
+        Encoding::for_label(&value.as_bytes()[..end])
+    })
+}
+
+/// Decodes `bytes` as `label`, or as the encoding they look like. A byte
+/// order mark wins over both.
+pub fn decode(bytes: &[u8], label: Option<&str>) -> Result<(String, &'static Encoding)> {
+    let encoding = match label {
+        Some(label) => for_label(label)?,
+        None => detect(bytes),
+    };
+    let (text, encoding, _) = encoding.decode(bytes);
+    Ok((text.into_owned(), encoding))
+}
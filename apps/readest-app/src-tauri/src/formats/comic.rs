@@ -0,0 +1,389 @@
+//! Comic book archives: CBZ, and CBR when built with the `rar` feature.
+//! Pages are the images of the archive in natural file name order. They are
+//! read one at a time, so a comic is never held in memory as a whole.
+
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use zip::ZipArchive;
+
+use super::epub::Metadata;
+use crate::error::{Error, Result};
+
+const COMIC_INFO: &str = "comicinfo.xml";
+
+/// Image types shown as pages.
+const PAGE_EXTENSIONS: &[(&str, &str)] = &[
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("png", "image/png"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("bmp", "image/bmp"),
+];
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ComicPage {
+    pub index: usize,
+    /// Path of the image inside the archive.
+    pub name: String,
+    pub mime_type: &'static str,
+    /// Uncompressed size in bytes.
+    pub size: u64,
+}
+
+/// Metadata from a `ComicInfo.xml` file (the ComicRack schema).
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ComicInfo {
+    pub title: Option<String>,
+    pub series: Option<String>,
+    pub number: Option<String>,
+    pub volume: Option<String>,
+    pub summary: Option<String>,
+    pub writers: Vec<String>,
+    pub artists: Vec<String>,
+    pub publisher: Option<String>,
+    /// Publication date as `YYYY`, `YYYY-MM` or `YYYY-MM-DD`.
+    pub published: Option<String>,
+    pub language: Option<String>,
+    pub genres: Vec<String>,
+    pub page_count: Option<usize>,
+    /// Whether pages are read right to left.
+    pub manga: bool,
+}
+
+impl ComicInfo {
+    pub fn parse(xml: &str) -> Result<Self> {
+        let doc = roxmltree::Document::parse(xml)?;
+        let text = |name: &str| {
+            doc.root_element()
+                .children()
+                .find(|n| n.tag_name().name().eq_ignore_ascii_case(name))
+                .and_then(|n| n.text())
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(String::from)
+        };
+        let list = |names: &[&str]| {
+            let mut values = Vec::new();
+            for name in names {
+                for value in text(name).iter().flat_map(|s| s.split(',')) {
+                    let value = value.trim().to_string();
+                    if !value.is_empty() && !values.contains(&value) {
+                        values.push(value);
+                    }
+                }
+            }
+            values
+        };
+
+        let published = text("Year").map(|year| {
+            let part = |name| {
+                text(name)
+                    .and_then(|v| v.parse::<u32>().ok())
+                    .filter(|&v| v > 0)
+            };
+            match (part("Month"), part("Day")) {
+                (Some(month), Some(day)) => format!("{year}-{month:02}-{day:02}"),
+                (Some(month), None) => format!("{year}-{month:02}"),
+                _ => year,
+            }
+        });
+
+        Ok(Self {
+            title: text("Title"),
+            series: text("Series"),
+            number: text("Number"),
+            volume: text("Volume"),
+            summary: text("Summary"),
+            writers: list(&["Writer"]),
+            artists: list(&["Penciller", "Inker", "Colorist", "CoverArtist"]),
+            publisher: text("Publisher"),
+            published,
+            language: text("LanguageISO"),
+            genres: list(&["Genre"]),
+            page_count: text("PageCount").and_then(|v| v.parse().ok()),
+            manga: text("Manga").is_some_and(|v| v.eq_ignore_ascii_case("YesAndRightToLeft")),
+        })
+    }
+
+    /// Maps the comic metadata onto the fields used for other books.
+    pub fn to_metadata(&self, fallback_title: &str) -> Metadata {
+        let title = match (&self.title, &self.series, &self.number) {
+            (Some(title), _, _) => title.clone(),
+            (None, Some(series), Some(number)) => format!("{series} #{number}"),
+            (None, Some(series), None) => series.clone(),
+            _ => fallback_title.to_string(),
+        };
+        let mut authors = self.writers.clone();
+        authors.extend(
+            self.artists
+                .iter()
+                .filter(|artist| !self.writers.contains(artist))
+                .cloned(),
+        );
+        Metadata {
+            title,
+            authors,
+            language: self.language.iter().cloned().collect(),
+            publisher: self.publisher.clone(),
+            published: self.published.clone(),
+            description: self.summary.clone(),
+            subjects: self.genres.clone(),
+            series: self.series.clone(),
+            series_index: self.number.as_deref().and_then(|n| n.parse().ok()),
+            ..Metadata::default()
+        }
+    }
+}
+
+enum Backend {
+    Zip(ZipArchive<BufReader<File>>),
+    /// RAR archives can only be read sequentially, so pages are found by name.
+    #[cfg(feature = "rar")]
+    Rar,
+}
+
+pub struct ComicArchive {
+    path: PathBuf,
+    backend: Backend,
+    pages: Vec<ComicPage>,
+    /// Archive entry index of each page.
+    entries: Vec<usize>,
+    info: Option<ComicInfo>,
+}
+
+impl ComicArchive {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let ext = super::extension(path);
+        match ext.as_str() {
+            "cbz" | "zip" => Self::open_zip(path),
+            #[cfg(feature = "rar")]
+            "cbr" | "rar" => Self::open_rar(path),
+            _ => Err(Error::UnsupportedFormat(ext)),
+        }
+    }
+
+    fn open_zip(path: &Path) -> Result<Self> {
+        let mut zip = ZipArchive::new(BufReader::new(File::open(path)?))?;
+        let mut images = Vec::new();
+        let mut info_entry = None;
+        for i in 0..zip.len() {
+            let entry = zip.by_index_raw(i)?;
+            if entry.is_dir() {
+                continue;
+            }
+            let name = entry.name().to_string();
+            if file_name(&name).eq_ignore_ascii_case(COMIC_INFO) {
+                info_entry = Some(i);
+            } else if let Some(mime_type) = page_mime_type(&name) {
+                images.push((name, mime_type, entry.size(), i));
+            }
+        }
+        let info = match info_entry {
+            Some(i) => {
+                let mut xml = String::new();
+                zip.by_index(i)?.read_to_string(&mut xml)?;
+                parse_info(&xml)
+            }
+            None => None,
+        };
+        let (pages, entries) = sort_pages(images);
+        Ok(Self {
+            path: path.to_path_buf(),
+            backend: Backend::Zip(zip),
+            pages,
+            entries,
+            info,
+        })
+    }
+
+    #[cfg(feature = "rar")]
+    fn open_rar(path: &Path) -> Result<Self> {
+        let mut images = Vec::new();
+        let mut has_info = false;
+        for (i, header) in unrar::Archive::new(path).open_for_listing()?.enumerate() {
+            let header = header?;
+            if !header.is_file() {
+                continue;
+            }
+            let name = header.filename.to_string_lossy().replace('\\', "/");
+            if file_name(&name).eq_ignore_ascii_case(COMIC_INFO) {
+                has_info = true;
+            } else if let Some(mime_type) = page_mime_type(&name) {
+                images.push((name, mime_type, header.unpacked_size, i));
+            }
+        }
+        let info = if has_info {
+            read_rar_entry(path, |name| {
+                file_name(name).eq_ignore_ascii_case(COMIC_INFO)
+            })?
+            .and_then(|data| parse_info(&String::from_utf8_lossy(&data)))
+        } else {
+            None
+        };
+        let (pages, entries) = sort_pages(images);
+        Ok(Self {
+            path: path.to_path_buf(),
+            backend: Backend::Rar,
+            pages,
+            entries,
+            info,
+        })
+    }
+
+    pub fn pages(&self) -> &[ComicPage] {
+        &self.pages
+    }
+
+    pub fn info(&self) -> Option<&ComicInfo> {
+        self.info.as_ref()
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        let stem = self
+            .path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        match &self.info {
+            Some(info) => info.to_metadata(&stem),
+            None => Metadata {
+                title: stem,
+                ..Metadata::default()
+            },
+        }
+    }
+
+    /// Reads the image of page `index`.
+    pub fn read_page(&mut self, index: usize) -> Result<Vec<u8>> {
+        if index >= self.pages.len() {
+            return Err(Error::InvalidBook(format!("page {index} out of range")));
+        }
+        match &mut self.backend {
+            Backend::Zip(zip) => {
+                let mut entry = zip.by_index(self.entries[index])?;
+                let mut data = Vec::with_capacity(entry.size() as usize);
+                entry.read_to_end(&mut data)?;
+                Ok(data)
+            }
+            #[cfg(feature = "rar")]
+            Backend::Rar => {
+                let page = &self.pages[index].name;
+                read_rar_entry(&self.path, |name| name == page)?
+                    .ok_or_else(|| Error::InvalidBook(format!("missing page {page}")))
+            }
+        }
+    }
+
+    /// JPEG thumbnail of page `index` that fits in `max_size` pixels.
+    pub fn thumbnail(&mut self, index: usize, max_size: u32) -> Result<Vec<u8>> {
+        let image = image::load_from_memory(&self.read_page(index)?)?;
+        super::encode_thumbnail(&image, max_size)
+    }
+}
+
+#[cfg(feature = "rar")]
+fn read_rar_entry(path: &Path, matches: impl Fn(&str) -> bool) -> Result<Option<Vec<u8>>> {
+    let mut archive = unrar::Archive::new(path).open_for_processing()?;
+    while let Some(header) = archive.read_header()? {
+        let name = header.entry().filename.to_string_lossy().replace('\\', "/");
+        archive = if header.entry().is_file() && matches(&name) {
+            return Ok(Some(header.read()?.0));
+        } else {
+            header.skip()?
+        };
+    }
+    Ok(None)
+}
+
+fn parse_info(xml: &str) -> Option<ComicInfo> {
+    match ComicInfo::parse(xml) {
+        Ok(info) => Some(info),
+        Err(e) => {
+            log::warn!("Ignoring invalid ComicInfo.xml: {e}");
+            None
+        }
+    }
+}
+
+fn file_name(name: &str) -> &str {
+    name.rsplit('/').next().unwrap_or(name)
+}
+
+fn page_mime_type(name: &str) -> Option<&'static str> {
+    // Skip resource forks and other hidden files
+    if name.starts_with("__MACOSX/") || file_name(name).starts_with('.') {
+        return None;
+    }
+    let ext = name.rsplit_once('.')?.1;
+    PAGE_EXTENSIONS
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(ext))
+        .map(|&(_, mime_type)| mime_type)
+}
+
+fn sort_pages(mut images: Vec<(String, &'static str, u64, usize)>) -> (Vec<ComicPage>, Vec<usize>) {
+    images.sort_by(|a, b| natural_cmp(&a.0, &b.0));
+    images
+        .into_iter()
+        .enumerate()
+        .map(|(index, (name, mime_type, size, entry))| {
+            (
+                ComicPage {
+                    index,
+                    name,
+                    mime_type,
+                    size,
+                },
+                entry,
+            )
+        })
+        .unzip()
+}
+
+/// Compares file names so that `page2` sorts before `page10`.
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    loop {
+        match (a.first(), b.first()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let x_len = a.iter().take_while(|c| c.is_ascii_digit()).count();
+                let y_len = b.iter().take_while(|c| c.is_ascii_digit()).count();
+                let x_num = trim_zeros(&a[..x_len]);
+                let y_num = trim_zeros(&b[..y_len]);
+                let order = x_num.len().cmp(&y_num.len()).then_with(|| x_num.cmp(y_num));
+                if order != Ordering::Equal {
+                    return order;
+                }
+                a = &a[x_len..];
+                b = &b[y_len..];
+            }
+            (Some(x), Some(y)) => {
+                let order = x.to_ascii_lowercase().cmp(&y.to_ascii_lowercase());
+                if order != Ordering::Equal {
+                    return order;
+                }
+                a = &a[1..];
+                b = &b[1..];
+            }
+        }
+    }
+}
+
+fn trim_zeros(digits: &[u8]) -> &[u8] {
+    let start = digits
+        .iter()
+        .position(|&c| c != b'0')
+        .unwrap_or(digits.len());
+    &digits[start..]
+}
@@ -0,0 +1,359 @@
+//! DjVu page count, outline and page rendering through the DjVuLibre
+//! decoding API. Pages are numbered from 1 as in [`super::pdf`], and
+//! rendered pages are kept in a small cache so paging back and forth does
+//! not decode them again.
+
+use std::ffi::{CStr, CString};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use image::{DynamicImage, ImageFormat, RgbImage};
+
+use super::pdf::OutlineItem;
+use crate::error::{Error, Result};
+
+/// Widest page the reader may ask for, larger requests are scaled down.
+const MAX_RENDER_WIDTH: u32 = 4096;
+/// Total size of the encoded pages kept per document.
+const MAX_CACHE_BYTES: usize = 32 * 1024 * 1024;
+/// Nesting limit when walking the outline.
+const MAX_DEPTH: usize = 32;
+
+#[allow(non_camel_case_types)]
+mod ffi {
+    use std::os::raw::{c_char, c_int, c_uint, c_ulong, c_void};
+
+    pub enum ddjvu_context_t {}
+    pub enum ddjvu_document_t {}
+    pub enum ddjvu_page_t {}
+    pub enum ddjvu_job_t {}
+    pub enum ddjvu_format_t {}
+    pub enum ddjvu_message_t {}
+    pub type miniexp_t = *mut c_void;
+
+    #[repr(C)]
+    pub struct ddjvu_rect_t {
+        pub x: c_int,
+        pub y: c_int,
+        pub w: c_uint,
+        pub h: c_uint,
+    }
+
+    /// `DDJVU_JOB_OK`, a lower status means the job is still running.
+    pub const DDJVU_JOB_OK: c_int = 2;
+    pub const DDJVU_FORMAT_RGB24: c_int = 1;
+    pub const DDJVU_RENDER_COLOR: c_int = 0;
+    /// Returned for annotations that are not decoded yet.
+    pub const MINIEXP_DUMMY: miniexp_t = 2 as miniexp_t;
+
+    #[link(name = "djvulibre")]
+    extern "C" {
+        pub fn ddjvu_context_create(programname: *const c_char) -> *mut ddjvu_context_t;
+        pub fn ddjvu_context_release(context: *mut ddjvu_context_t);
+        pub fn ddjvu_message_wait(context: *mut ddjvu_context_t) -> *mut ddjvu_message_t;
+        pub fn ddjvu_message_peek(context: *mut ddjvu_context_t) -> *mut ddjvu_message_t;
+        pub fn ddjvu_message_pop(context: *mut ddjvu_context_t);
+        pub fn ddjvu_job_status(job: *mut ddjvu_job_t) -> c_int;
+        pub fn ddjvu_job_release(job: *mut ddjvu_job_t);
+        pub fn ddjvu_document_create_by_filename_utf8(
+            context: *mut ddjvu_context_t,
+            filename: *const c_char,
+            cache: c_int,
+        ) -> *mut ddjvu_document_t;
+        pub fn ddjvu_document_job(document: *mut ddjvu_document_t) -> *mut ddjvu_job_t;
+        pub fn ddjvu_document_get_pagenum(document: *mut ddjvu_document_t) -> c_int;
+        pub fn ddjvu_document_get_outline(document: *mut ddjvu_document_t) -> miniexp_t;
+        pub fn ddjvu_miniexp_release(document: *mut ddjvu_document_t, expr: miniexp_t);
+        pub fn ddjvu_page_create_by_pageno(
+            document: *mut ddjvu_document_t,
+            pageno: c_int,
+        ) -> *mut ddjvu_page_t;
+        pub fn ddjvu_page_job(page: *mut ddjvu_page_t) -> *mut ddjvu_job_t;
+        pub fn ddjvu_page_get_width(page: *mut ddjvu_page_t) -> c_int;
+        pub fn ddjvu_page_get_height(page: *mut ddjvu_page_t) -> c_int;
+        pub fn ddjvu_page_render(
+            page: *mut ddjvu_page_t,
+            mode: c_int,
+            pagerect: *const ddjvu_rect_t,
+            renderrect: *const ddjvu_rect_t,
+            pixelformat: *const ddjvu_format_t,
+            rowsize: c_ulong,
+            imagebuffer: *mut c_char,
+        ) -> c_int;
+        pub fn ddjvu_format_create(
+            style: c_int,
+            nargs: c_int,
+            args: *mut c_uint,
+        ) -> *mut ddjvu_format_t;
+        pub fn ddjvu_format_set_row_order(format: *mut ddjvu_format_t, top_to_bottom: c_int);
+        pub fn ddjvu_format_release(format: *mut ddjvu_format_t);
+        pub fn miniexp_length(p: miniexp_t) -> c_int;
+        pub fn miniexp_nth(n: c_int, l: miniexp_t) -> miniexp_t;
+        pub fn miniexp_stringp(p: miniexp_t) -> c_int;
+        pub fn miniexp_to_str(p: miniexp_t) -> *const c_char;
+    }
+
+    /// `miniexp_consp`, an inline function in the C headers: pairs are
+    /// non-null pointers with the two low bits clear.
+    pub fn miniexp_consp(p: miniexp_t) -> bool {
+        !p.is_null() && (p as usize) & 3 == 0
+    }
+}
+
+struct RenderedPage {
+    page: u32,
+    width: u32,
+    png: Vec<u8>,
+}
+
+pub struct DjvuDocument {
+    path: PathBuf,
+    context: *mut ffi::ddjvu_context_t,
+    document: *mut ffi::ddjvu_document_t,
+    page_count: u32,
+    /// Recently rendered pages, most recent last.
+    cache: Vec<RenderedPage>,
+}
+
+// The decoding context is thread safe, and a document is only ever used
+// through `&mut self` or behind a lock.
+unsafe impl Send for DjvuDocument {}
+
+impl DjvuDocument {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let filename = path
+            .to_str()
+            .and_then(|name| CString::new(name).ok())
+            .ok_or_else(|| Error::InvalidBook(format!("unsupported path {}", path.display())))?;
+        let context = unsafe { ffi::ddjvu_context_create(c"vl-arch".as_ptr()) };
+        if context.is_null() {
+            return Err(Error::Djvu("cannot create decoding context".into()));
+        }
+        let document =
+            unsafe { ffi::ddjvu_document_create_by_filename_utf8(context, filename.as_ptr(), 1) };
+        if document.is_null() {
+            unsafe { ffi::ddjvu_context_release(context) };
+            return Err(Error::InvalidBook(format!(
+                "cannot open {}",
+                path.display()
+            )));
+        }
+        let mut djvu = Self {
+            path: path.to_path_buf(),
+            context,
+            document,
+            page_count: 0,
+            cache: Vec::new(),
+        };
+        // Dropping `djvu` releases the document if decoding fails
+        let job = unsafe { ffi::ddjvu_document_job(document) };
+        if !djvu.wait(job) {
+            return Err(Error::InvalidBook(format!(
+                "cannot decode {}",
+                path.display()
+            )));
+        }
+        djvu.page_count = unsafe { ffi::ddjvu_document_get_pagenum(document) }.max(0) as u32;
+        Ok(djvu)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn page_count(&self) -> u32 {
+        self.page_count
+    }
+
+    /// Runs the message loop until `job` finishes, returns whether it succeeded.
+    fn wait(&self, job: *mut ffi::ddjvu_job_t) -> bool {
+        loop {
+            let status = unsafe { ffi::ddjvu_job_status(job) };
+            if status >= ffi::DDJVU_JOB_OK {
+                return status == ffi::DDJVU_JOB_OK;
+            }
+            self.pump();
+        }
+    }
+
+    /// Waits for the next message, then discards every pending one.
+    fn pump(&self) {
+        unsafe {
+            ffi::ddjvu_message_wait(self.context);
+            while !ffi::ddjvu_message_peek(self.context).is_null() {
+                ffi::ddjvu_message_pop(self.context);
+            }
+        }
+    }
+
+    /// The bookmarks of the document, empty if it has none.
+    pub fn outline(&self) -> Vec<OutlineItem> {
+        let outline = loop {
+            let outline = unsafe { ffi::ddjvu_document_get_outline(self.document) };
+            if outline != ffi::MINIEXP_DUMMY {
+                break outline;
+            }
+            self.pump();
+        };
+        if outline.is_null() {
+            return Vec::new();
+        }
+        // The outline is `(bookmarks item...)`
+        let items = unsafe { outline_items(outline, 1, 0) };
+        unsafe { ffi::ddjvu_miniexp_release(self.document, outline) };
+        items
+    }
+
+    /// Page `page` as a PNG `width` pixels wide.
+    pub fn render_page(&mut self, page: u32, width: u32) -> Result<Vec<u8>> {
+        let width = width.clamp(1, MAX_RENDER_WIDTH);
+        if let Some(i) = self
+            .cache
+            .iter()
+            .position(|cached| cached.page == page && cached.width == width)
+        {
+            let cached = self.cache.remove(i);
+            let png = cached.png.clone();
+            self.cache.push(cached);
+            return Ok(png);
+        }
+
+        let image = self.render_image(page, |w, h| {
+            let height = (h as f64 * width as f64 / w as f64).round() as u32;
+            (width, height.max(1))
+        })?;
+        let mut png = Cursor::new(Vec::new());
+        image.write_to(&mut png, ImageFormat::Png)?;
+        let png = png.into_inner();
+
+        let mut size = png.len();
+        let mut keep = self.cache.len();
+        while keep > 0 && size + self.cache[keep - 1].png.len() <= MAX_CACHE_BYTES {
+            keep -= 1;
+            size += self.cache[keep].png.len();
+        }
+        self.cache.drain(..keep);
+        self.cache.push(RenderedPage {
+            page,
+            width,
+            png: png.clone(),
+        });
+        Ok(png)
+    }
+
+    /// JPEG thumbnail of page `page` that fits in `max_size` pixels.
+    pub fn thumbnail(&self, page: u32, max_size: u32) -> Result<Vec<u8>> {
+        let image = self.render_image(page, |w, h| {
+            let scale = (max_size as f64 / w.max(h) as f64).min(1.0);
+            let size = |side: u32| ((side as f64 * scale).round() as u32).max(1);
+            (size(w), size(h))
+        })?;
+        super::encode_thumbnail(&DynamicImage::ImageRgb8(image), max_size)
+    }
+
+    /// Decodes page `page` and renders it at the size `size` picks from
+    /// the page's own pixel size.
+    fn render_image(
+        &self,
+        page: u32,
+        size: impl FnOnce(u32, u32) -> (u32, u32),
+    ) -> Result<RgbImage> {
+        if page == 0 || page > self.page_count {
+            return Err(Error::InvalidBook(format!("page {page} out of range")));
+        }
+        let handle = unsafe { ffi::ddjvu_page_create_by_pageno(self.document, page as i32 - 1) };
+        if handle.is_null() {
+            return Err(Error::Djvu(format!("cannot load page {page}")));
+        }
+        let job = unsafe { ffi::ddjvu_page_job(handle) };
+        let result = if self.wait(job) {
+            let (w, h) = unsafe {
+                (
+                    ffi::ddjvu_page_get_width(handle).max(1) as u32,
+                    ffi::ddjvu_page_get_height(handle).max(1) as u32,
+                )
+            };
+            let (width, height) = size(w, h);
+            Ok(render(handle, width, height))
+        } else {
+            Err(Error::Djvu(format!("cannot decode page {page}")))
+        };
+        unsafe { ffi::ddjvu_job_release(job) };
+        result
+    }
+}
+
+impl Drop for DjvuDocument {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::ddjvu_job_release(ffi::ddjvu_document_job(self.document));
+            ffi::ddjvu_context_release(self.context);
+        }
+    }
+}
+
+/// Renders a decoded page scaled to `width` × `height`.
+fn render(page: *mut ffi::ddjvu_page_t, width: u32, height: u32) -> RgbImage {
+    let rect = ffi::ddjvu_rect_t {
+        x: 0,
+        y: 0,
+        w: width,
+        h: height,
+    };
+    let row_size = width as usize * 3;
+    let mut pixels = vec![0u8; row_size * height as usize];
+    let rendered = unsafe {
+        let format = ffi::ddjvu_format_create(ffi::DDJVU_FORMAT_RGB24, 0, std::ptr::null_mut());
+        ffi::ddjvu_format_set_row_order(format, 1);
+        let rendered = ffi::ddjvu_page_render(
+            page,
+            ffi::DDJVU_RENDER_COLOR,
+            &rect,
+            &rect,
+            format,
+            row_size as _,
+            pixels.as_mut_ptr().cast(),
+        );
+        ffi::ddjvu_format_release(format);
+        rendered
+    };
+    if rendered == 0 {
+        // Nothing to draw, the page is blank
+        pixels.fill(0xff);
+    }
+    RgbImage::from_raw(width, height, pixels).expect("buffer matches the image size")
+}
+
+/// Converts the items of `list` from index `start` on, each of them
+/// `("title" "#page" child...)`.
+unsafe fn outline_items(list: ffi::miniexp_t, start: i32, depth: usize) -> Vec<OutlineItem> {
+    if depth > MAX_DEPTH {
+        return Vec::new();
+    }
+    let mut items = Vec::new();
+    for i in start..ffi::miniexp_length(list) {
+        let item = ffi::miniexp_nth(i, list);
+        if !ffi::miniexp_consp(item) {
+            continue;
+        }
+        let title = string(ffi::miniexp_nth(0, item)).unwrap_or_default();
+        // Links are usually `#<page number>`, links by page name are skipped
+        let page = string(ffi::miniexp_nth(1, item))
+            .and_then(|url| url.strip_prefix('#').and_then(|page| page.parse().ok()));
+        items.push(OutlineItem {
+            title: title.trim().to_string(),
+            page,
+            children: outline_items(item, 2, depth + 1),
+        });
+    }
+    items
+}
+
+unsafe fn string(expr: ffi::miniexp_t) -> Option<String> {
+    if ffi::miniexp_stringp(expr) == 0 {
+        return None;
+    }
+    let ptr = ffi::miniexp_to_str(expr);
+    (!ptr.is_null()).then(|| CStr::from_ptr(ptr).to_string_lossy().into_owned())
+}
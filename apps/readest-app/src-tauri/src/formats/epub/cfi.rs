@@ -0,0 +1,305 @@
+//! The text of EPUB spine documents, normalized for finding passages in
+//! it, with the CFI of each character. This is how highlights from other
+//! readers and from earlier editions of a book are found again.
+
+use roxmltree::Node;
+
+use super::parse_xml;
+
+/// Elements that start a new line of text, so their words are not run
+/// together with the ones around them.
+const BLOCK_ELEMENTS: &[&str] = &[
+    "address",
+    "article",
+    "aside",
+    "blockquote",
+    "br",
+    "dd",
+    "div",
+    "dl",
+    "dt",
+    "figcaption",
+    "figure",
+    "footer",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "header",
+    "hr",
+    "li",
+    "ol",
+    "p",
+    "pre",
+    "section",
+    "table",
+    "td",
+    "th",
+    "tr",
+    "ul",
+];
+/// Characters of the start and end of a highlight searched for when the
+/// whole text is not found, as when it spans a footnote marker.
+pub(crate) const ANCHOR_CHARS: usize = 40;
+
+/// Lowercases `c` and folds typographic quotes and dashes, which e-readers
+/// and EPUB files do not always agree on. `None` for characters to skip.
+pub(crate) fn fold(c: char) -> Option<char> {
+    match c {
+        '\u{00ad}' | '\u{200b}' => None,
+        '\u{2018}' | '\u{2019}' | '\u{02bc}' => Some('\''),
+        '\u{201c}' | '\u{201d}' | '\u{00ab}' | '\u{00bb}' => Some('"'),
+        '\u{2013}' | '\u{2014}' => Some('-'),
+        c => c.to_lowercase().next(),
+    }
+}
+
+/// `text` lowercased and folded, with whitespace collapsed.
+pub(crate) fn normalize(text: &str) -> String {
+    text.split_whitespace()
+        .map(|word| word.chars().filter_map(fold).collect::<String>())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Where a character of [`DocumentText`] is: a text chunk, by the CFI
+/// steps to it, and an offset in UTF-16 code units, as the DOM counts.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Position {
+    pub chunk: usize,
+    pub offset: u32,
+    pub len: u32,
+}
+
+/// The normalized text of a spine document, with the position of each
+/// character in the document.
+#[derive(Debug, Default)]
+pub(crate) struct DocumentText {
+    pub text: String,
+    pub positions: Vec<Position>,
+    /// CFI steps from the root element to each text chunk.
+    pub chunks: Vec<Vec<u32>>,
+    /// CFI steps from the root element to the body.
+    pub body: Vec<u32>,
+    pending_space: bool,
+}
+
+/// The CFI steps from the root element to `node`: even for elements,
+/// counting element siblings, and odd for the text between them.
+fn cfi_steps(node: Node) -> Vec<u32> {
+    let mut steps = Vec::new();
+    let mut current = node;
+    while let Some(parent) = current.parent_element() {
+        let elements_before = current
+            .prev_siblings()
+            .skip(1)
+            .filter(Node::is_element)
+            .count() as u32;
+        steps.push(match current.is_element() {
+            true => (elements_before + 1) * 2,
+            false => elements_before * 2 + 1,
+        });
+        current = parent;
+    }
+    steps.reverse();
+    steps
+}
+
+impl DocumentText {
+    pub fn parse(xhtml: &str) -> Option<Self> {
+        let doc = parse_xml(xhtml).ok()?;
+        let body = doc.root_element().children().find(|node| {
+            node.is_element() && node.tag_name().name().eq_ignore_ascii_case("body")
+        })?;
+        let mut text = Self {
+            body: cfi_steps(body),
+            ..Self::default()
+        };
+        text.collect(body);
+        Some(text)
+    }
+
+    fn collect(&mut self, node: Node) {
+        for child in node.children() {
+            if child.is_text() {
+                self.push_chunk(child);
+                continue;
+            }
+            if !child.is_element() {
+                continue;
+            }
+            let name = child.tag_name().name().to_ascii_lowercase();
+            if matches!(name.as_str(), "script" | "style") {
+                continue;
+            }
+            let block = BLOCK_ELEMENTS.contains(&name.as_str());
+            self.pending_space |= block;
+            self.collect(child);
+            self.pending_space |= block;
+        }
+    }
+
+    fn push_chunk(&mut self, node: Node) {
+        let chunk = self.chunks.len();
+        self.chunks.push(cfi_steps(node));
+        let mut offset = 0;
+        for c in node.text().unwrap_or_default().chars() {
+            let len = c.len_utf16() as u32;
+            let position = Position { chunk, offset, len };
+            offset += len;
+            if c.is_whitespace() {
+                self.pending_space = true;
+                continue;
+            }
+            let Some(c) = fold(c) else { continue };
+            if self.pending_space && !self.text.is_empty() {
+                self.text.push(' ');
+                self.positions.push(position);
+            }
+            self.pending_space = false;
+            self.text.push(c);
+            self.positions.push(position);
+        }
+    }
+
+    /// The first and last character of `needle`, which is normalized, in
+    /// this text. Without an exact match, its start and end are looked for
+    /// close together.
+    pub fn find(&self, needle: &str) -> Option<(Position, Position)> {
+        let char_index = |byte: usize| self.text[..byte].chars().count();
+        let (start, end) = match self.text.find(needle) {
+            Some(start) => (start, start + needle.len()),
+            None => {
+                let chars = needle.chars().collect::<Vec<_>>();
+                if chars.len() < ANCHOR_CHARS * 2 {
+                    return None;
+                }
+                let head = chars[..ANCHOR_CHARS].iter().collect::<String>();
+                let tail = chars[chars.len() - ANCHOR_CHARS..]
+                    .iter()
+                    .collect::<String>();
+                let start = self.text.find(&head)?;
+                let window = (start + needle.len() * 3 / 2).min(self.text.len());
+                let window = (0..=window)
+                    .rev()
+                    .find(|&i| self.text.is_char_boundary(i))?;
+                let end = start + self.text[start..window].find(&tail)? + tail.len();
+                (start, end)
+            }
+        };
+        let first = char_index(start);
+        let last = first + self.text[start..end].chars().count() - 1;
+        Some((self.positions[first], self.positions[last]))
+    }
+
+    /// A range CFI from the start of `first` to the end of `last`, like
+    /// `epubcfi(/6/8!/4/10,/1:5,/3:20)`.
+    pub fn range_cfi(&self, spine_index: usize, first: Position, last: Position) -> String {
+        let start = &self.chunks[first.chunk];
+        let end = &self.chunks[last.chunk];
+        // The shared part stops at the element the chunks are in
+        let common = start
+            .iter()
+            .zip(end)
+            .take_while(|(a, b)| a == b)
+            .count()
+            .min(start.len() - 1)
+            .min(end.len() - 1);
+        format!(
+            "epubcfi(/6/{}!{},{}:{},{}:{})",
+            (spine_index + 1) * 2,
+            path(&start[..common]),
+            path(&start[common..]),
+            first.offset,
+            path(&end[common..]),
+            last.offset + last.len,
+        )
+    }
+
+    /// A CFI of the start of `position`, like `epubcfi(/6/8!/4/10/1:5)`.
+    pub fn point_cfi(&self, spine_index: usize, position: Position) -> String {
+        format!(
+            "epubcfi(/6/{}!{}:{})",
+            (spine_index + 1) * 2,
+            path(&self.chunks[position.chunk]),
+            position.offset,
+        )
+    }
+
+    /// A CFI of the start of the body.
+    pub fn start_cfi(&self, spine_index: usize) -> String {
+        format!("epubcfi(/6/{}!{})", (spine_index + 1) * 2, path(&self.body))
+    }
+}
+
+fn path(steps: &[u32]) -> String {
+    steps.iter().map(|step| format!("/{step}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(body: &str) -> DocumentText {
+        DocumentText::parse(&format!(
+            "<html xmlns=\"http://www.w3.org/1999/xhtml\"><head><title>T</title></head>\
+             <body>{body}</body></html>"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn text_is_folded_and_collapsed() {
+        assert_eq!(
+            normalize("  \u{201c}It\u{2019}s\u{00ad}\n  Over\u{2014}Done\u{201d} "),
+            "\"it's over-done\""
+        );
+        let text = document("<p>Hello <b>world</b>\u{2019}s</p><p>Next<script>x</script> line</p>");
+        assert_eq!(text.text, "hello world's next line");
+        assert_eq!(text.body, [4]);
+    }
+
+    #[test]
+    fn passages_become_range_cfis() {
+        let text = document("<p>Hello <b>world</b>\u{2019}s</p><p>Next line</p>");
+        let (first, last) = text.find("world's").unwrap();
+        assert_eq!(
+            text.range_cfi(2, first, last),
+            "epubcfi(/6/6!/4/2,/2/1:0,/3:2)"
+        );
+        assert_eq!(text.point_cfi(0, first), "epubcfi(/6/2!/4/2/2/1:0)");
+        assert_eq!(text.start_cfi(0), "epubcfi(/6/2!/4)");
+
+        let (first, last) = text.find("hello world's next").unwrap();
+        assert_eq!(
+            text.range_cfi(0, first, last),
+            "epubcfi(/6/2!/4,/2/1:0,/4/1:4)"
+        );
+        assert!(text.find("goodbye").is_none());
+    }
+
+    #[test]
+    fn offsets_count_utf16_units() {
+        let text = document("<p>\u{1f600} x</p>");
+        let (first, last) = text.find("x").unwrap();
+        assert_eq!((first.offset, last.offset + last.len), (3, 4));
+    }
+
+    #[test]
+    fn long_passages_are_found_by_their_ends() {
+        let head = "It was the best of times, it was the worst of times,";
+        let tail = "it was the age of wisdom, it was the age of foolishness";
+        let text = document(&format!("<p>{head}<sup>1</sup> {tail}.</p>"));
+        let (first, last) = text.find(&normalize(&format!("{head} {tail}"))).unwrap();
+        // The tail ends in the text after the marker, past its space
+        assert_eq!(
+            text.range_cfi(0, first, last),
+            format!("epubcfi(/6/2!/4/2,/1:0,/3:{})", tail.len() + 1)
+        );
+        assert!(text
+            .find(&normalize("It was the best of times, it was none"))
+            .is_none());
+    }
+}
@@ -0,0 +1,491 @@
+//! Minimal EPUB reader: resolves the OPF package through `META-INF/container.xml`
+//! and parses its metadata, manifest and spine.
+
+use std::io::{Cursor, Read, Seek};
+use std::path::Path;
+
+use roxmltree::{Document, Node, ParsingOptions};
+use serde::Serialize;
+use zip::ZipArchive;
+
+use super::html::html_to_text;
+use super::Chapter;
+use crate::error::{Error, Result};
+use stream::{MappedFile, MappedReader};
+
+pub mod cfi;
+pub mod stream;
+
+const CONTAINER_PATH: &str = "META-INF/container.xml";
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Identifier {
+    pub scheme: Option<String>,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Metadata {
+    pub title: String,
+    pub subtitle: Option<String>,
+    pub authors: Vec<String>,
+    pub language: Vec<String>,
+    pub publisher: Option<String>,
+    pub published: Option<String>,
+    pub description: Option<String>,
+    pub subjects: Vec<String>,
+    pub identifiers: Vec<Identifier>,
+    pub series: Option<String>,
+    pub series_index: Option<f32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ManifestItem {
+    pub id: String,
+    /// Path of the resource relative to the archive root.
+    pub href: String,
+    pub media_type: String,
+    pub properties: Vec<String>,
+    /// Id of the media overlay item, the SMIL document that narrates it.
+    pub media_overlay: Option<String>,
+}
+
+/// An entry of the table of contents.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TocItem {
+    pub label: String,
+    /// Path of the target document relative to the archive root, without
+    /// the fragment.
+    pub href: String,
+    pub children: Vec<TocItem>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Package {
+    pub metadata: Metadata,
+    pub manifest: Vec<ManifestItem>,
+    /// Manifest ids in reading order.
+    pub spine: Vec<String>,
+    pub cover_id: Option<String>,
+    /// Classes for the media overlay element being read, and for the
+    /// document while one is, from `media:active-class` and
+    /// `media:playback-active-class`.
+    pub active_class: Option<String>,
+    pub playback_active_class: Option<String>,
+}
+
+impl Package {
+    pub fn item(&self, id: &str) -> Option<&ManifestItem> {
+        self.manifest.iter().find(|item| item.id == id)
+    }
+
+    pub fn cover(&self) -> Option<&ManifestItem> {
+        self.cover_id.as_deref().and_then(|id| self.item(id))
+    }
+
+    /// Manifest items in reading order.
+    pub fn spine_items(&self) -> impl Iterator<Item = &ManifestItem> {
+        self.spine.iter().filter_map(|idref| self.item(idref))
+    }
+}
+
+pub struct EpubArchive<R: Read + Seek> {
+    zip: ZipArchive<R>,
+    opf_path: String,
+}
+
+impl EpubArchive<MappedReader> {
+    /// Opens the EPUB at `path` memory-mapped, see [`stream`].
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_reader(Cursor::new(MappedFile::open(path.as_ref())?))
+    }
+}
+
+impl<R: Read + Seek> EpubArchive<R> {
+    pub fn from_reader(reader: R) -> Result<Self> {
+        let mut zip = ZipArchive::new(reader)?;
+        let container = read_zip_string(&mut zip, CONTAINER_PATH)?;
+        let doc = parse_xml(&container)?;
+        let opf_path = doc
+            .descendants()
+            .find(|n| n.has_tag_name_local("rootfile"))
+            .and_then(|n| attr(n, "full-path"))
+            .ok_or_else(|| Error::InvalidBook("missing rootfile in container.xml".into()))?
+            .to_string();
+        Ok(Self { zip, opf_path })
+    }
+
+    pub fn read_entry(&mut self, name: &str) -> Result<Vec<u8>> {
+        let mut entry = self.zip.by_name(name)?;
+        let mut buf = Vec::with_capacity(entry.size() as usize);
+        entry.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
+    pub fn read_entry_string(&mut self, name: &str) -> Result<String> {
+        read_zip_string(&mut self.zip, name)
+    }
+
+    pub fn package(&mut self) -> Result<Package> {
+        let opf_path = self.opf_path.clone();
+        let opf = self.read_entry_string(&opf_path)?;
+        parse_package(&opf, &opf_path)
+    }
+
+    /// Plain text of every spine document, in reading order.
+    pub fn chapters(&mut self) -> Result<Vec<Chapter>> {
+        let package = self.package()?;
+        let mut chapters = Vec::new();
+        for (index, item) in package.spine_items().enumerate() {
+            if !item.media_type.contains("html") {
+                continue;
+            }
+            let html = match self.read_entry_string(&item.href) {
+                Ok(html) => html,
+                Err(e) => {
+                    log::warn!("Skipping unreadable chapter {}: {e}", item.href);
+                    continue;
+                }
+            };
+            chapters.push(Chapter {
+                index,
+                href: item.href.clone(),
+                text: html_to_text(&html),
+            });
+        }
+        Ok(chapters)
+    }
+
+    /// The table of contents from the EPUB 3 navigation document, or the
+    /// EPUB 2 NCX when there is none. Empty if the book has neither.
+    pub fn toc(&mut self) -> Result<Vec<TocItem>> {
+        let package = self.package()?;
+        let nav = package
+            .manifest
+            .iter()
+            .find(|item| item.properties.iter().any(|p| p == "nav"));
+        if let Some(nav) = nav {
+            let html = self.read_entry_string(&nav.href)?;
+            let doc = parse_xml(&html)?;
+            let toc = doc
+                .descendants()
+                .filter(|n| n.has_tag_name_local("nav"))
+                .find(|n| attr(*n, "type") == Some("toc"))
+                .and_then(|n| n.children().find(|c| c.has_tag_name_local("ol")));
+            if let Some(ol) = toc {
+                return Ok(nav_items(ol, &nav.href));
+            }
+        }
+        let ncx = package
+            .manifest
+            .iter()
+            .find(|item| item.media_type == "application/x-dtbncx+xml");
+        if let Some(ncx) = ncx {
+            let xml = self.read_entry_string(&ncx.href)?;
+            let doc = parse_xml(&xml)?;
+            if let Some(nav_map) = doc.descendants().find(|n| n.has_tag_name_local("navMap")) {
+                return Ok(ncx_items(nav_map, &ncx.href));
+            }
+        }
+        Ok(Vec::new())
+    }
+}
+
+fn nav_items(ol: Node, base: &str) -> Vec<TocItem> {
+    ol.children()
+        .filter(|n| n.has_tag_name_local("li"))
+        .filter_map(|li| {
+            let label = li
+                .children()
+                .find(|n| n.has_tag_name_local("a") || n.has_tag_name_local("span"))?;
+            Some(TocItem {
+                label: text_of(label),
+                href: attr(label, "href")
+                    .map(|href| resolve_href(base, href))
+                    .unwrap_or_default(),
+                children: li
+                    .children()
+                    .find(|n| n.has_tag_name_local("ol"))
+                    .map(|ol| nav_items(ol, base))
+                    .unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+fn ncx_items(parent: Node, base: &str) -> Vec<TocItem> {
+    parent
+        .children()
+        .filter(|n| n.has_tag_name_local("navPoint"))
+        .map(|point| TocItem {
+            label: point
+                .children()
+                .find(|n| n.has_tag_name_local("navLabel"))
+                .map(text_of)
+                .unwrap_or_default(),
+            href: point
+                .children()
+                .find(|n| n.has_tag_name_local("content"))
+                .and_then(|n| attr(n, "src"))
+                .map(|src| resolve_href(base, src))
+                .unwrap_or_default(),
+            children: ncx_items(point, base),
+        })
+        .collect()
+}
+
+fn read_zip_string<R: Read + Seek>(zip: &mut ZipArchive<R>, name: &str) -> Result<String> {
+    let mut entry = zip.by_name(name)?;
+    let mut buf = Vec::with_capacity(entry.size() as usize);
+    entry.read_to_end(&mut buf)?;
+    let text = String::from_utf8_lossy(&buf);
+    Ok(text.trim_start_matches('\u{feff}').to_string())
+}
+
+pub(crate) fn parse_xml(text: &str) -> Result<Document<'_>> {
+    let options = ParsingOptions {
+        allow_dtd: true,
+        ..ParsingOptions::default()
+    };
+    Ok(Document::parse_with_options(text, options)?)
+}
+
+trait LocalName {
+    fn has_tag_name_local(&self, name: &str) -> bool;
+}
+
+impl LocalName for Node<'_, '_> {
+    fn has_tag_name_local(&self, name: &str) -> bool {
+        self.is_element() && self.tag_name().name().eq_ignore_ascii_case(name)
+    }
+}
+
+/// Looks up an attribute by local name, ignoring any namespace prefix.
+pub(crate) fn attr<'a>(node: Node<'a, '_>, name: &str) -> Option<&'a str> {
+    node.attributes()
+        .find(|a| a.name() == name)
+        .map(|a| a.value())
+}
+
+fn text_of(node: Node) -> String {
+    node.descendants()
+        .filter(|n| n.is_text())
+        .filter_map(|n| n.text())
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Resolves `href` relative to the directory of `base` inside the archive.
+pub fn resolve_href(base: &str, href: &str) -> String {
+    let href = href.split('#').next().unwrap_or_default();
+    let href = percent_encoding::percent_decode_str(href).decode_utf8_lossy();
+    let mut parts: Vec<&str> = match base.rfind('/') {
+        Some(idx) if !href.starts_with('/') => base[..idx].split('/').collect(),
+        _ => Vec::new(),
+    };
+    for segment in href.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            s => parts.push(s),
+        }
+    }
+    parts.join("/")
+}
+
+fn parse_package(opf: &str, opf_path: &str) -> Result<Package> {
+    let doc = parse_xml(opf)?;
+    let root = doc.root_element();
+
+    let manifest = root
+        .descendants()
+        .filter(|n| n.has_tag_name_local("item"))
+        .filter_map(|n| {
+            Some(ManifestItem {
+                id: attr(n, "id")?.to_string(),
+                href: resolve_href(opf_path, attr(n, "href")?),
+                media_type: attr(n, "media-type").unwrap_or_default().to_string(),
+                properties: attr(n, "properties")
+                    .map(|p| p.split_whitespace().map(String::from).collect())
+                    .unwrap_or_default(),
+                media_overlay: attr(n, "media-overlay").map(String::from),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let spine = root
+        .descendants()
+        .filter(|n| n.has_tag_name_local("itemref"))
+        .filter_map(|n| attr(n, "idref").map(String::from))
+        .collect::<Vec<_>>();
+
+    let metadata_node = root
+        .children()
+        .find(|n| n.has_tag_name_local("metadata"))
+        .ok_or_else(|| Error::InvalidBook("missing metadata in package document".into()))?;
+    let metadata = parse_metadata(metadata_node);
+
+    let cover_id = find_cover_id(metadata_node, &manifest);
+    let overlay_class = |property: &str| {
+        metadata_node
+            .children()
+            .filter(|n| n.has_tag_name_local("meta") && attr(*n, "refines").is_none())
+            .find(|n| attr(*n, "property") == Some(property))
+            .map(text_of)
+            .filter(|class| !class.is_empty())
+    };
+
+    Ok(Package {
+        metadata,
+        manifest,
+        spine,
+        cover_id,
+        active_class: overlay_class("media:active-class"),
+        playback_active_class: overlay_class("media:playback-active-class"),
+    })
+}
+
+/// Collects EPUB 3 `<meta refines="#id" property="...">` values for an element.
+fn refinements(metadata: Node, id: Option<&str>, property: &str) -> Option<String> {
+    let id = id?;
+    metadata
+        .children()
+        .filter(|n| n.has_tag_name_local("meta"))
+        .find(|n| {
+            attr(*n, "refines").map(|r| r.trim_start_matches('#')) == Some(id)
+                && attr(*n, "property") == Some(property)
+        })
+        .map(text_of)
+}
+
+fn parse_metadata(node: Node) -> Metadata {
+    let mut metadata = Metadata::default();
+
+    for child in node.children().filter(|n| n.is_element()) {
+        let id = attr(child, "id");
+        let value = text_of(child);
+        match child.tag_name().name() {
+            "title" if !value.is_empty() => {
+                let title_type = refinements(node, id, "title-type");
+                if metadata.title.is_empty() && title_type.as_deref() != Some("subtitle") {
+                    metadata.title = value;
+                } else if metadata.subtitle.is_none() {
+                    metadata.subtitle = Some(value);
+                }
+            }
+            "creator" if !value.is_empty() => {
+                let role = attr(child, "role")
+                    .map(String::from)
+                    .or_else(|| refinements(node, id, "role"));
+                if role.as_deref().map_or(true, |r| r == "aut") {
+                    metadata.authors.push(value);
+                }
+            }
+            "language" if !value.is_empty() => metadata.language.push(value),
+            "publisher" if !value.is_empty() => metadata.publisher = Some(value),
+            "date" if !value.is_empty() => {
+                let event = attr(child, "event");
+                if metadata.published.is_none() || event == Some("publication") {
+                    metadata.published = Some(value);
+                }
+            }
+            "description" if !value.is_empty() => metadata.description = Some(value),
+            "subject" if !value.is_empty() => metadata.subjects.push(value),
+            "identifier" if !value.is_empty() => {
+                let scheme = attr(child, "scheme")
+                    .map(String::from)
+                    .or_else(|| refinements(node, id, "identifier-type"));
+                metadata
+                    .identifiers
+                    .push(normalize_identifier(scheme, value));
+            }
+            "meta" => parse_meta(child, node, &mut metadata),
+            _ => {}
+        }
+    }
+
+    metadata
+}
+
+fn parse_meta(meta: Node, metadata_node: Node, metadata: &mut Metadata) {
+    // EPUB 2 / Calibre style: <meta name="calibre:series" content="..."/>
+    if let (Some(name), Some(content)) = (attr(meta, "name"), attr(meta, "content")) {
+        match name {
+            "calibre:series" => metadata.series = Some(content.to_string()),
+            "calibre:series_index" => metadata.series_index = content.parse().ok(),
+            _ => {}
+        }
+        return;
+    }
+    // EPUB 3: <meta property="belongs-to-collection" id="c1">Series</meta>
+    if attr(meta, "property") == Some("belongs-to-collection") && attr(meta, "refines").is_none() {
+        let id = attr(meta, "id");
+        let collection_type = refinements(metadata_node, id, "collection-type");
+        if collection_type.as_deref().map_or(true, |t| t == "series") && metadata.series.is_none() {
+            metadata.series = Some(text_of(meta));
+            metadata.series_index =
+                refinements(metadata_node, id, "group-position").and_then(|p| p.parse().ok());
+        }
+    }
+}
+
+fn normalize_identifier(scheme: Option<String>, value: String) -> Identifier {
+    let lower = value.to_ascii_lowercase();
+    for prefix in ["urn:isbn:", "urn:uuid:", "urn:doi:"] {
+        if let Some(rest) = lower.strip_prefix(prefix) {
+            let scheme = prefix.trim_start_matches("urn:").trim_end_matches(':');
+            return Identifier {
+                scheme: Some(scheme.to_string()),
+                value: value[value.len() - rest.len()..].to_string(),
+            };
+        }
+    }
+    Identifier {
+        scheme: scheme.map(|s| s.to_ascii_lowercase()),
+        value,
+    }
+}
+
+fn find_cover_id(metadata: Node, manifest: &[ManifestItem]) -> Option<String> {
+    let is_image = |item: &&ManifestItem| item.media_type.starts_with("image/");
+
+    // EPUB 3: manifest item with the cover-image property
+    if let Some(item) = manifest
+        .iter()
+        .find(|item| item.properties.iter().any(|p| p == "cover-image"))
+    {
+        return Some(item.id.clone());
+    }
+    // EPUB 2: <meta name="cover" content="item-id"/>
+    let meta_cover = metadata
+        .children()
+        .filter(|n| n.has_tag_name_local("meta"))
+        .find(|n| attr(*n, "name") == Some("cover"))
+        .and_then(|n| attr(n, "content"));
+    if let Some(content) = meta_cover {
+        // Some books put the href instead of the id into the content attribute
+        if let Some(item) = manifest
+            .iter()
+            .filter(is_image)
+            .find(|item| item.id == content || item.href.ends_with(content))
+        {
+            return Some(item.id.clone());
+        }
+    }
+    // Fall back to an image that looks like a cover
+    manifest
+        .iter()
+        .filter(is_image)
+        .find(|item| {
+            item.id.to_ascii_lowercase().contains("cover")
+                || item.href.to_ascii_lowercase().contains("cover")
+        })
+        .map(|item| item.id.clone())
+}
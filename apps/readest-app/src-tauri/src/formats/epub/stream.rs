@@ -0,0 +1,158 @@
+//! Memory-mapped access to zipped books. The archive is mapped rather than
+//! read, so its pages come from the OS file cache and can be dropped again
+//! under memory pressure, and a multi-hundred-megabyte fixed-layout EPUB
+//! costs little resident memory. Entries are inflated one at a time when
+//! asked for, and stored ones, usually images and media, are sliced from the
+//! map without inflating anything. A compressed entry read by range is kept
+//! inflated for the ranges after it.
+
+use std::fs::File;
+use std::io::{self, Cursor, Read};
+use std::ops::{Deref, Range};
+use std::path::Path;
+use std::sync::Arc;
+
+use memmap2::Mmap;
+use zip::result::ZipError;
+use zip::{CompressionMethod, ZipArchive};
+
+use crate::error::{Error, Result};
+
+/// Compressed entries up to this size are kept inflated once a range of
+/// them is read, rather than inflated from the start for every range.
+const MAX_KEPT_ENTRY: u64 = 32 * 1024 * 1024;
+/// Most memory reserved ahead for inflating an entry, whose declared size
+/// may be anything.
+pub(crate) const MAX_RESERVED: u64 = 4 * 1024 * 1024;
+
+/// A read-only map of a whole file, shared by everything reading from it.
+/// Windows keeps a mapped file from being deleted until the map is dropped.
+#[derive(Clone)]
+pub struct MappedFile(Arc<Mmap>);
+
+impl MappedFile {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path)?;
+        // SAFETY: the map is read-only. The app replaces book files by
+        // renaming new ones over them, which leaves mapped pages intact;
+        // another program truncating a book while it is open is not
+        // guarded against.
+        let map = unsafe { Mmap::map(&file)? };
+        Ok(Self(Arc::new(map)))
+    }
+}
+
+impl AsRef<[u8]> for MappedFile {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Reader of a mapped file, for [`ZipArchive`].
+pub type MappedReader = Cursor<MappedFile>;
+
+/// The contents of an entry, either a range of the map or inflated data.
+pub enum EntryData {
+    Mapped(MappedFile, Range<usize>),
+    Inflated(Vec<u8>),
+    /// A range of the entry kept inflated.
+    Kept(Arc<[u8]>, Range<usize>),
+}
+
+impl Deref for EntryData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Self::Mapped(map, range) => &map.as_ref()[range.clone()],
+            Self::Inflated(data) => data,
+            Self::Kept(data, range) => &data[range.clone()],
+        }
+    }
+}
+
+impl EntryData {
+    pub fn into_vec(self) -> Vec<u8> {
+        match self {
+            Self::Mapped(..) | Self::Kept(..) => self.to_vec(),
+            Self::Inflated(data) => data,
+        }
+    }
+}
+
+pub struct MappedArchive {
+    map: MappedFile,
+    zip: ZipArchive<MappedReader>,
+    /// The compressed entry last read by range, inflated, by name.
+    kept: Option<(String, Arc<[u8]>)>,
+}
+
+impl MappedArchive {
+    pub fn open(path: &Path) -> Result<Self> {
+        let map = MappedFile::open(path)?;
+        let zip = ZipArchive::new(Cursor::new(map.clone()))?;
+        Ok(Self {
+            map,
+            zip,
+            kept: None,
+        })
+    }
+
+    /// Inflated size of entry `name`, or `None` if there is none.
+    pub fn size(&mut self, name: &str) -> Result<Option<u64>> {
+        match self.zip.by_name(name) {
+            Ok(entry) => Ok(Some(entry.size())),
+            Err(ZipError::FileNotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Bytes `range` of entry `name`, clamped to its size. Stored entries
+    /// are sliced from the map. Compressed ones are inflated whole and kept
+    /// when a part of them is asked for, unless they are large, which are
+    /// inflated only up to the end of the range.
+    pub fn read_range(&mut self, name: &str, range: Range<u64>) -> Result<EntryData> {
+        if let Some((_, data)) = self.kept.as_ref().filter(|(kept, _)| kept == name) {
+            return Ok(EntryData::Kept(data.clone(), clamp(range, data.len())));
+        }
+        let mut entry = self.zip.by_name(name)?;
+        let end = range.end.min(entry.size());
+        let start = range.start.min(end);
+        if entry.compression() == CompressionMethod::Stored {
+            let offset = entry.data_start();
+            let bounds = usize::try_from(offset + start)
+                .ok()
+                .zip(usize::try_from(offset + end).ok());
+            return match bounds {
+                Some((from, to)) if to <= self.map.as_ref().len() => {
+                    Ok(EntryData::Mapped(self.map.clone(), from..to))
+                }
+                _ => Err(Error::InvalidBook(format!("{name} is out of bounds"))),
+            };
+        }
+        let size = entry.size();
+        if (start, end) != (0, size) && size <= MAX_KEPT_ENTRY {
+            let mut data = Vec::with_capacity(size.min(MAX_RESERVED) as usize);
+            entry.take(size).read_to_end(&mut data)?;
+            let data = Arc::<[u8]>::from(data);
+            self.kept = Some((name.to_string(), data.clone()));
+            return Ok(EntryData::Kept(data.clone(), clamp(start..end, data.len())));
+        }
+        io::copy(&mut (&mut entry).take(start), &mut io::sink())?;
+        let mut data = Vec::with_capacity((end - start).min(MAX_RESERVED) as usize);
+        entry.take(end - start).read_to_end(&mut data)?;
+        Ok(EntryData::Inflated(data))
+    }
+
+    /// The whole of entry `name`.
+    pub fn read(&mut self, name: &str) -> Result<EntryData> {
+        self.read_range(name, 0..u64::MAX)
+    }
+}
+
+/// `range` clamped to data of `len` bytes.
+fn clamp(range: Range<u64>, len: usize) -> Range<usize> {
+    let end = usize::try_from(range.end).unwrap_or(usize::MAX).min(len);
+    let start = usize::try_from(range.start).unwrap_or(usize::MAX).min(end);
+    start..end
+}
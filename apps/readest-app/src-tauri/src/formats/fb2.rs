@@ -0,0 +1,603 @@
+//! FictionBook 2 reader, for plain `.fb2` files and zipped `.fbz` or
+//! `.fb2.zip` ones. The XML is rendered into one XHTML chapter per top-level
+//! section of the main body, with images inlined as data URLs from the
+//! binaries they are stored in, and the notes bodies gathered into a last
+//! chapter that note references link to, as in EPUB. The `href` of a
+//! chapter is a file name of its own, such as `section-3.xhtml`.
+
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::path::Path;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use encoding_rs::Encoding;
+use roxmltree::Node;
+use zip::ZipArchive;
+
+use super::epub::{parse_xml, Identifier, Metadata, TocItem};
+use super::html::html_to_text;
+use super::Chapter;
+use crate::error::{Error, Result};
+use crate::utils::escape_xml;
+
+const NOTES_HREF: &str = "notes.xhtml";
+/// Largest decompressed FB2 read from a zip.
+const MAX_SIZE: u64 = 256 * 1024 * 1024;
+
+const STYLE: &str = "\
+body { margin: 0 }
+h1, h2, h3, h4, h5, h6 { text-align: center }
+p { margin: 0; text-indent: 1.5em }
+.subtitle, .epigraph .text-author, .poem .text-author { text-align: right; font-style: italic }
+.epigraph { margin-left: 30%; font-style: italic }
+.poem { margin: 1em 2em }
+.stanza { margin-bottom: 1em }
+.stanza p { text-indent: 0 }
+.image { text-align: center; text-indent: 0 }
+.image img { max-width: 100% }
+";
+
+struct Binary {
+    content_type: String,
+    /// Base64 data without whitespace.
+    data: String,
+}
+
+struct Section {
+    href: String,
+    title: Option<String>,
+    html: String,
+}
+
+pub struct Fb2Book {
+    metadata: Metadata,
+    cover: Option<String>,
+    sections: Vec<Section>,
+    toc: Vec<TocItem>,
+    binaries: HashMap<String, Binary>,
+}
+
+fn invalid(message: impl Into<String>) -> Error {
+    Error::InvalidBook(message.into())
+}
+
+/// Decodes the file as the encoding its XML declaration names, which for
+/// older Russian books is usually Windows-1251.
+fn decode(bytes: &[u8]) -> String {
+    let head = String::from_utf8_lossy(&bytes[..bytes.len().min(200)]);
+    let label = head
+        .split_once("encoding=")
+        .and_then(|(_, rest)| {
+            let quote = rest.chars().next().filter(|c| matches!(c, '"' | '\''))?;
+            rest[1..].split(quote).next()
+        })
+        .unwrap_or("utf-8");
+    let encoding = Encoding::for_label(label.trim().as_bytes()).unwrap_or(encoding_rs::UTF_8);
+    let (text, _, _) = encoding.decode(bytes);
+    text.into_owned()
+}
+
+/// The FB2 document in a zip, the first entry with the extension or else
+/// the first entry.
+fn unzip(bytes: Vec<u8>) -> Result<Vec<u8>> {
+    let mut zip = ZipArchive::new(Cursor::new(bytes))?;
+    let name = zip
+        .file_names()
+        .find(|name| name.to_ascii_lowercase().ends_with(".fb2"))
+        .map(str::to_string);
+    let entry = match name {
+        Some(name) => zip.by_name(&name)?,
+        None => zip.by_index(0)?,
+    };
+    let mut data = Vec::new();
+    entry.take(MAX_SIZE).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|n| n.tag_name().name() == name)
+}
+
+fn children<'a, 'input: 'a>(
+    node: Node<'a, 'input>,
+    name: &'a str,
+) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    node.children().filter(move |n| n.tag_name().name() == name)
+}
+
+/// Text of all descendants, with a line per paragraph.
+fn text_of(node: Node) -> String {
+    let mut text = String::new();
+    for n in node.descendants() {
+        if n.is_text() {
+            text.push_str(n.text().unwrap_or_default());
+        } else if n.tag_name().name() == "p" && !text.is_empty() {
+            text.push('\n');
+        }
+    }
+    text.split('\n')
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn non_empty(text: String) -> Option<String> {
+    let text = text.trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+/// The `xlink:href` of a node, whatever prefix the file binds to XLink.
+fn href<'a>(node: Node<'a, '_>) -> Option<&'a str> {
+    node.attributes()
+        .find(|attr| attr.name() == "href")
+        .map(|attr| attr.value())
+}
+
+fn author_name(author: Node) -> Option<String> {
+    let part = |name| child(author, name).map(text_of).unwrap_or_default();
+    let full = ["first-name", "middle-name", "last-name"]
+        .map(part)
+        .into_iter()
+        .filter(|p| !p.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    non_empty(full).or_else(|| non_empty(part("nickname")))
+}
+
+fn parse_metadata(description: Option<Node>) -> (Metadata, Option<String>) {
+    let mut metadata = Metadata::default();
+    let Some(description) = description else {
+        return (metadata, None);
+    };
+    let mut cover = None;
+    if let Some(info) = child(description, "title-info") {
+        let field = |name| child(info, name).map(text_of).and_then(non_empty);
+        metadata.title = field("book-title").unwrap_or_default();
+        metadata.authors = children(info, "author").filter_map(author_name).collect();
+        metadata.language = field("lang").into_iter().collect();
+        metadata.description = child(info, "annotation").map(text_of).and_then(non_empty);
+        metadata.subjects = children(info, "genre")
+            .filter_map(|genre| non_empty(text_of(genre)))
+            .collect();
+        metadata.published = child(info, "date").and_then(|date| {
+            date.attribute("value")
+                .map(String::from)
+                .or_else(|| non_empty(text_of(date)))
+        });
+        if let Some(sequence) = child(info, "sequence") {
+            metadata.series = sequence
+                .attribute("name")
+                .map(String::from)
+                .and_then(non_empty);
+            metadata.series_index = sequence
+                .attribute("number")
+                .and_then(|n| n.trim().parse().ok());
+        }
+        cover = child(info, "coverpage")
+            .and_then(|page| child(page, "image"))
+            .and_then(href)
+            .map(|href| href.trim_start_matches('#').to_string());
+    }
+    if let Some(publish) = child(description, "publish-info") {
+        let field = |name| child(publish, name).map(text_of).and_then(non_empty);
+        metadata.publisher = field("publisher");
+        metadata.published = metadata.published.take().or_else(|| field("year"));
+        metadata
+            .identifiers
+            .extend(field("isbn").map(|value| Identifier {
+                scheme: Some("ISBN".into()),
+                value,
+            }));
+    }
+    if let Some(id) = child(description, "document-info")
+        .and_then(|info| child(info, "id"))
+        .map(text_of)
+        .and_then(non_empty)
+    {
+        metadata.identifiers.push(Identifier {
+            scheme: Some("FB2".into()),
+            value: id,
+        });
+    }
+    (metadata, cover)
+}
+
+/// Renders FB2 markup into XHTML, knowing which chapter each id is in so
+/// links can point there.
+struct Renderer<'a> {
+    binaries: &'a HashMap<String, Binary>,
+    /// Hrefs of the chapters by the ids of the elements in them.
+    targets: HashMap<String, String>,
+}
+
+impl Renderer<'_> {
+    fn image(&self, node: Node, out: &mut String) {
+        let Some(binary) = href(node)
+            .map(|href| href.trim_start_matches('#'))
+            .and_then(|id| self.binaries.get(id))
+        else {
+            return;
+        };
+        let alt = node.attribute("alt").unwrap_or_default();
+        out.push_str(&format!(
+            "<img src=\"data:{};base64,{}\" alt=\"{}\"/>",
+            escape_xml(&binary.content_type),
+            binary.data,
+            escape_xml(alt)
+        ));
+    }
+
+    fn inline(&self, node: Node, out: &mut String) {
+        for n in node.children() {
+            if n.is_text() {
+                out.push_str(&escape_xml(n.text().unwrap_or_default()));
+                continue;
+            }
+            let tag = match n.tag_name().name() {
+                "strong" => "strong",
+                "emphasis" => "em",
+                "strikethrough" => "del",
+                "sub" => "sub",
+                "sup" => "sup",
+                "code" => "code",
+                "style" => "span",
+                "image" => {
+                    self.image(n, out);
+                    continue;
+                }
+                "a" => {
+                    self.link(n, out);
+                    continue;
+                }
+                _ => {
+                    self.inline(n, out);
+                    continue;
+                }
+            };
+            out.push_str(&format!("<{tag}>"));
+            self.inline(n, out);
+            out.push_str(&format!("</{tag}>"));
+        }
+    }
+
+    fn link(&self, node: Node, out: &mut String) {
+        let target = href(node).unwrap_or_default();
+        let (href, note) = match target.strip_prefix('#') {
+            Some(id) => match self.targets.get(id) {
+                Some(chapter) => (format!("{chapter}#{id}"), chapter == NOTES_HREF),
+                None => (String::new(), false),
+            },
+            None => (target.to_string(), false),
+        };
+        if href.is_empty() {
+            self.inline(node, out);
+            return;
+        }
+        let kind = if note || node.attribute("type") == Some("note") {
+            " epub:type=\"noteref\""
+        } else {
+            ""
+        };
+        out.push_str(&format!("<a href=\"{}\"{kind}>", escape_xml(&href)));
+        self.inline(node, out);
+        out.push_str("</a>");
+    }
+
+    fn id_attr(node: Node) -> String {
+        node.attribute("id")
+            .map(|id| format!(" id=\"{}\"", escape_xml(id)))
+            .unwrap_or_default()
+    }
+
+    fn paragraph(&self, node: Node, class: Option<&str>, out: &mut String) {
+        let class = class
+            .map(|class| format!(" class=\"{class}\""))
+            .unwrap_or_default();
+        out.push_str(&format!("<p{}{class}>", Self::id_attr(node)));
+        self.inline(node, out);
+        out.push_str("</p>\n");
+    }
+
+    fn title(&self, node: Node, depth: usize, out: &mut String) {
+        let level = depth.clamp(1, 6);
+        out.push_str(&format!("<h{level}>"));
+        let mut first = true;
+        for p in children(node, "p") {
+            if !first {
+                out.push_str("<br/>");
+            }
+            first = false;
+            self.inline(p, out);
+        }
+        out.push_str(&format!("</h{level}>\n"));
+    }
+
+    /// Renders the block content of `node`, with titles at `depth`.
+    fn blocks(&self, node: Node, depth: usize, out: &mut String) {
+        for n in node.children().filter(Node::is_element) {
+            self.block(n, depth, out);
+        }
+    }
+
+    fn block(&self, node: Node, depth: usize, out: &mut String) {
+        match node.tag_name().name() {
+            "title" => self.title(node, depth, out),
+            "p" | "v" => self.paragraph(node, None, out),
+            "subtitle" => self.paragraph(node, Some("subtitle"), out),
+            "text-author" | "date" => self.paragraph(node, Some("text-author"), out),
+            "empty-line" => out.push_str("<p>&#160;</p>\n"),
+            "image" => {
+                out.push_str(&format!("<div class=\"image\"{}>", Self::id_attr(node)));
+                self.image(node, out);
+                out.push_str("</div>\n");
+            }
+            "epigraph" => self.wrapped(node, "blockquote", "epigraph", depth, out),
+            "cite" | "annotation" => self.wrapped(node, "blockquote", "cite", depth, out),
+            "poem" => self.wrapped(node, "div", "poem", depth + 1, out),
+            "stanza" => self.wrapped(node, "div", "stanza", depth + 1, out),
+            "section" => self.wrapped(node, "section", "section", depth + 1, out),
+            "table" => self.table(node, out),
+            _ => {}
+        }
+    }
+
+    fn wrapped(&self, node: Node, tag: &str, class: &str, depth: usize, out: &mut String) {
+        out.push_str(&format!(
+            "<{tag} class=\"{class}\"{}>\n",
+            Self::id_attr(node)
+        ));
+        self.blocks(node, depth, out);
+        out.push_str(&format!("</{tag}>\n"));
+    }
+
+    fn table(&self, node: Node, out: &mut String) {
+        out.push_str("<table>\n");
+        for row in children(node, "tr") {
+            out.push_str("<tr>");
+            for cell in row.children().filter(Node::is_element) {
+                let tag = if cell.tag_name().name() == "th" {
+                    "th"
+                } else {
+                    "td"
+                };
+                out.push_str(&format!("<{tag}>"));
+                self.inline(cell, out);
+                out.push_str(&format!("</{tag}>"));
+            }
+            out.push_str("</tr>\n");
+        }
+        out.push_str("</table>\n");
+    }
+
+    /// Renders the notes bodies as footnotes, one per section.
+    fn notes(&self, bodies: &[Node], out: &mut String) {
+        for body in bodies {
+            if let Some(title) = child(*body, "title") {
+                self.title(title, 1, out);
+            }
+            for section in children(*body, "section") {
+                out.push_str(&format!(
+                    "<aside epub:type=\"footnote\"{}>\n",
+                    Self::id_attr(section)
+                ));
+                self.blocks(section, 2, out);
+                out.push_str("</aside>\n");
+            }
+        }
+    }
+}
+
+fn document(title: &str, lang: &str, body: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<!DOCTYPE html>\n\
+         <html xmlns=\"http://www.w3.org/1999/xhtml\" xmlns:epub=\"http://www.idpf.org/2007/ops\" lang=\"{}\">\n\
+         <head><title>{}</title><style>{STYLE}</style></head>\n<body>\n{body}</body>\n</html>\n",
+        escape_xml(lang),
+        escape_xml(title),
+    )
+}
+
+/// Entries of the table of contents for the titled sections in `node`.
+fn toc_items(node: Node, href: &str) -> Vec<TocItem> {
+    children(node, "section")
+        .filter_map(|section| {
+            let label = child(section, "title").map(text_of).and_then(non_empty)?;
+            Some(TocItem {
+                label: label.replace('\n', " "),
+                href: href.to_string(),
+                children: toc_items(section, href),
+            })
+        })
+        .collect()
+}
+
+impl Fb2Book {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let bytes = std::fs::read(path)?;
+        if bytes.starts_with(b"PK\x03\x04") {
+            Self::parse(&unzip(bytes)?)
+        } else {
+            Self::parse(&bytes)
+        }
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let text = decode(bytes);
+        let doc = parse_xml(text.trim_start_matches('\u{feff}'))?;
+        let root = doc.root_element();
+        if root.tag_name().name() != "FictionBook" {
+            return Err(invalid("not a FictionBook document"));
+        }
+        let (metadata, cover) = parse_metadata(child(root, "description"));
+        let binaries = children(root, "binary")
+            .filter_map(|binary| {
+                let id = binary.attribute("id")?.to_string();
+                let data = binary
+                    .text()
+                    .unwrap_or_default()
+                    .split_ascii_whitespace()
+                    .collect::<String>();
+                let content_type = binary
+                    .attribute("content-type")
+                    .unwrap_or("image/jpeg")
+                    .to_string();
+                Some((id, Binary { content_type, data }))
+            })
+            .collect::<HashMap<_, _>>();
+
+        // The first body is the text, the others hold notes and comments
+        let mut bodies = children(root, "body");
+        let main = bodies
+            .next()
+            .ok_or_else(|| invalid("FictionBook without a body"))?;
+        let notes = bodies.collect::<Vec<_>>();
+
+        let parts = children(main, "section").collect::<Vec<_>>();
+        let href_of = |i: usize| format!("section-{}.xhtml", i + 1);
+        let mut targets = HashMap::new();
+        for (i, part) in parts.iter().enumerate() {
+            for id in part.descendants().filter_map(|n| n.attribute("id")) {
+                targets.insert(id.to_string(), href_of(i));
+            }
+        }
+        for body in &notes {
+            for id in body.descendants().filter_map(|n| n.attribute("id")) {
+                targets.insert(id.to_string(), NOTES_HREF.to_string());
+            }
+        }
+        let renderer = Renderer {
+            binaries: &binaries,
+            targets,
+        };
+
+        let lang = metadata.language.first().cloned().unwrap_or_default();
+        let mut sections = Vec::new();
+        let mut toc = Vec::new();
+        // What precedes the first section, the book title and epigraphs,
+        // opens the first chapter
+        let mut front = String::new();
+        for n in main
+            .children()
+            .filter(|n| n.is_element() && n.tag_name().name() != "section")
+        {
+            renderer.block(n, 1, &mut front);
+        }
+        if parts.is_empty() {
+            sections.push(Section {
+                href: href_of(0),
+                title: None,
+                html: front,
+            });
+        } else {
+            for (i, part) in parts.iter().enumerate() {
+                let mut html = if i == 0 {
+                    std::mem::take(&mut front)
+                } else {
+                    String::new()
+                };
+                renderer.wrapped(*part, "section", "section", 2, &mut html);
+                let title = child(*part, "title")
+                    .map(text_of)
+                    .and_then(non_empty)
+                    .map(|title| title.replace('\n', " "));
+                if let Some(label) = &title {
+                    toc.push(TocItem {
+                        label: label.clone(),
+                        href: href_of(i),
+                        children: toc_items(*part, &href_of(i)),
+                    });
+                }
+                sections.push(Section {
+                    href: href_of(i),
+                    title,
+                    html,
+                });
+            }
+        }
+        if !notes.is_empty() {
+            let mut html = String::new();
+            renderer.notes(&notes, &mut html);
+            let title = notes
+                .iter()
+                .find_map(|body| child(*body, "title").map(text_of).and_then(non_empty))
+                .unwrap_or_else(|| "Notes".into());
+            toc.push(TocItem {
+                label: title.replace('\n', " "),
+                href: NOTES_HREF.into(),
+                children: Vec::new(),
+            });
+            sections.push(Section {
+                href: NOTES_HREF.into(),
+                title: Some(title),
+                html,
+            });
+        }
+        for section in &mut sections {
+            let title = section.title.as_deref().unwrap_or(&metadata.title);
+            section.html = document(title, &lang, &section.html);
+        }
+        Ok(Self {
+            metadata,
+            cover,
+            sections,
+            toc,
+            binaries,
+        })
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    pub fn toc(&self) -> &[TocItem] {
+        &self.toc
+    }
+
+    /// Hrefs and titles of the chapters in reading order.
+    pub fn spine(&self) -> impl Iterator<Item = (&str, Option<&str>)> {
+        self.sections
+            .iter()
+            .map(|section| (section.href.as_str(), section.title.as_deref()))
+    }
+
+    /// XHTML of the chapter `href`.
+    pub fn chapter(&self, href: &str) -> Option<&str> {
+        self.sections
+            .iter()
+            .find(|section| section.href == href)
+            .map(|section| section.html.as_str())
+    }
+
+    /// Plain text of every chapter, in reading order.
+    pub fn chapters(&self) -> Vec<Chapter> {
+        self.sections
+            .iter()
+            .enumerate()
+            .map(|(index, section)| Chapter {
+                index,
+                href: section.href.clone(),
+                text: html_to_text(&section.html),
+            })
+            .collect()
+    }
+
+    /// Decoded data and content type of the binary `id`.
+    pub fn binary(&self, id: &str) -> Result<Option<(&str, Vec<u8>)>> {
+        let Some(binary) = self.binaries.get(id) else {
+            return Ok(None);
+        };
+        let data = BASE64
+            .decode(&binary.data)
+            .map_err(|e| invalid(format!("binary {id}: {e}")))?;
+        Ok(Some((&binary.content_type, data)))
+    }
+
+    pub fn cover(&self) -> Result<Option<Vec<u8>>> {
+        match &self.cover {
+            Some(id) => Ok(self.binary(id)?.map(|(_, data)| data)),
+            None => Ok(None),
+        }
+    }
+}
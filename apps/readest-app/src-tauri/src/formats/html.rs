@@ -0,0 +1,261 @@
+//! Tolerant HTML/XHTML to plain text conversion. Book content is often not
+//! well-formed XML (undeclared entities, stray tags), so this scans the markup
+//! instead of building a DOM.
+
+use std::borrow::Cow;
+use std::sync::OnceLock;
+
+use regex::{Captures, Regex};
+
+const SKIPPED_TAGS: &[&str] = &["head", "script", "style", "svg", "math", "rt", "rp"];
+
+const BLOCK_TAGS: &[&str] = &[
+    "address",
+    "article",
+    "aside",
+    "blockquote",
+    "br",
+    "dd",
+    "div",
+    "dl",
+    "dt",
+    "figcaption",
+    "figure",
+    "footer",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "header",
+    "hr",
+    "li",
+    "ol",
+    "p",
+    "pre",
+    "section",
+    "table",
+    "td",
+    "th",
+    "tr",
+    "ul",
+];
+
+/// Converts markup to text with one line per block element.
+pub fn html_to_text(html: &str) -> String {
+    let mut raw = String::with_capacity(html.len() / 2);
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        push_text(&mut raw, &rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("<!--") {
+            rest = after.find("-->").map_or("", |end| &after[end + 3..]);
+            continue;
+        }
+        if let Some(after) = rest.strip_prefix("<![CDATA[") {
+            let end = after.find("]]>").unwrap_or(after.len());
+            raw.push_str(&after[..end]);
+            rest = after.get(end + 3..).unwrap_or("");
+            continue;
+        }
+        let Some(end) = rest.find('>') else {
+            rest = "";
+            break;
+        };
+        let tag = &rest[1..end];
+        rest = &rest[end + 1..];
+
+        let closing = tag.starts_with('/');
+        let name = tag_name(tag);
+        if !closing && !tag.ends_with('/') && SKIPPED_TAGS.contains(&name.as_str()) {
+            rest = skip_element(rest, &name);
+            continue;
+        }
+        if BLOCK_TAGS.contains(&name.as_str()) {
+            raw.push('\n');
+        }
+    }
+    push_text(&mut raw, rest);
+    normalize_whitespace(&raw)
+}
+
+/// Appends decoded text, treating source line breaks as plain whitespace.
+fn push_text(out: &mut String, text: &str) {
+    out.extend(decode_entities(text).chars().map(|c| match c {
+        '\n' | '\r' => ' ',
+        c => c,
+    }));
+}
+
+pub(crate) fn tag_name(tag: &str) -> String {
+    let tag = tag.trim_start_matches('/');
+    let name = tag
+        .split(|c: char| c.is_whitespace() || c == '/')
+        .next()
+        .unwrap_or_default();
+    // Drop namespace prefixes such as `xhtml:p`
+    let name = name.rsplit(':').next().unwrap_or(name);
+    name.to_ascii_lowercase()
+}
+
+/// Returns the input after the closing tag of `name`.
+fn skip_element<'a>(html: &'a str, name: &str) -> &'a str {
+    let mut from = 0;
+    while let Some(pos) = html[from..].find("</").map(|pos| from + pos) {
+        let tag = html.as_bytes().get(pos + 2..pos + 2 + name.len());
+        if tag.is_some_and(|tag| tag.eq_ignore_ascii_case(name.as_bytes())) {
+            let after = &html[pos..];
+            return after.find('>').map_or("", |end| &after[end + 1..]);
+        }
+        from = pos + 2;
+    }
+    ""
+}
+
+fn normalize_whitespace(text: &str) -> String {
+    text.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub fn decode_entities(text: &str) -> String {
+    if !text.contains('&') {
+        return text.to_string();
+    }
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let decoded = rest[1..]
+            .find(';')
+            .filter(|&end| end <= 10)
+            .and_then(|end| decode_entity(&rest[1..=end]).map(|c| (c, end + 2)));
+        match decoded {
+            Some((c, len)) => {
+                out.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// `text` with the named character references XML does not declare, all
+/// but its five, rewritten by `rewrite`, which is given the name and the
+/// character HTML decodes it to, if it knows it.
+pub fn rewrite_named_entities<'a>(
+    text: &'a str,
+    mut rewrite: impl FnMut(&str, Option<char>) -> String,
+) -> Cow<'a, str> {
+    static ENTITY: OnceLock<Regex> = OnceLock::new();
+    let entity = ENTITY
+        .get_or_init(|| Regex::new(r"&([A-Za-z][A-Za-z0-9]*);").expect("valid entity pattern"));
+    entity.replace_all(text, |captures: &Captures| {
+        let name = &captures[1];
+        if matches!(name, "amp" | "lt" | "gt" | "quot" | "apos") {
+            return captures[0].to_string();
+        }
+        rewrite(name, decode_entity(name))
+    })
+}
+
+fn decode_entity(entity: &str) -> Option<char> {
+    if let Some(num) = entity.strip_prefix('#') {
+        let code = match num.strip_prefix(['x', 'X']) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => num.parse().ok()?,
+        };
+        return char::from_u32(code);
+    }
+    let c = match entity {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => '\u{a0}',
+        "shy" => '\u{ad}',
+        "ensp" => '\u{2002}',
+        "emsp" => '\u{2003}',
+        "thinsp" => '\u{2009}',
+        "ndash" => '–',
+        "mdash" => '—',
+        "hellip" => '…',
+        "lsquo" => '‘',
+        "rsquo" => '’',
+        "ldquo" => '“',
+        "rdquo" => '”',
+        "laquo" => '«',
+        "raquo" => '»',
+        "middot" => '·',
+        "bull" => '•',
+        "copy" => '©',
+        "reg" => '®',
+        "trade" => '™',
+        "deg" => '°',
+        "times" => '×',
+        _ => return None,
+    };
+    Some(c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_become_lines() {
+        let html = "<h1>Title</h1>\n<p>One\ntwo</p><p>three<br/>four</p>";
+        assert_eq!(html_to_text(html), "Title\nOne two\nthree\nfour");
+    }
+
+    #[test]
+    fn skipped_elements_are_dropped() {
+        let html = "<head><title>T</title></head><p>a<SCRIPT>if (x</script) y</Script>b\
+                    <ruby>漢<rt>kan</rt></ruby><svg:math/>c</p>";
+        assert_eq!(html_to_text(html), "ab漢c");
+    }
+
+    #[test]
+    fn unterminated_markup_ends_the_text() {
+        assert_eq!(html_to_text("<p>a<!-- never closed <p>b"), "a");
+        assert_eq!(html_to_text("<p>a<![CDATA[x < y"), "ax < y");
+        assert_eq!(html_to_text("<p>a</p><p"), "a");
+        assert_eq!(html_to_text("a<style>b"), "a");
+        assert_eq!(html_to_text("é<"), "é");
+    }
+
+    #[test]
+    fn tag_names_drop_prefixes_and_case() {
+        assert_eq!(tag_name("/XHTML:P class=\"x\""), "p");
+        assert_eq!(tag_name("br/"), "br");
+        assert_eq!(tag_name(""), "");
+    }
+
+    #[test]
+    fn unknown_and_broken_entities_stay() {
+        assert_eq!(
+            decode_entities("&amp; &#x41;&#66; &nbsp;&foo; &#xFFFFFF; & &averyverylongname;"),
+            "& AB \u{a0}&foo; &#xFFFFFF; & &averyverylongname;"
+        );
+        assert_eq!(decode_entities("a &"), "a &");
+    }
+
+    #[test]
+    fn only_entities_xml_lacks_are_rewritten() {
+        let rewritten = rewrite_named_entities("&lt;&nbsp;&foo;&#160;", |name, c| match c {
+            Some(c) => c.to_string(),
+            None => format!("[{name}]"),
+        });
+        assert_eq!(rewritten, "&lt;\u{a0}[foo]&#160;");
+    }
+}
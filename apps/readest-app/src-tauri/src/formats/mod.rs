@@ -0,0 +1,152 @@
+use std::io::Cursor;
+use std::path::Path;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::DynamicImage;
+use serde::Serialize;
+
+use crate::error::{Error, Result};
+
+pub mod bench;
+pub mod charset;
+pub mod comic;
+#[cfg(feature = "djvu")]
+pub mod djvu;
+pub mod epub;
+pub mod fb2;
+pub mod html;
+pub mod pdf;
+pub mod text;
+
+const THUMBNAIL_QUALITY: u8 = 80;
+/// Size of the cover rendered from the first page of a DjVu document.
+#[cfg(feature = "djvu")]
+const DJVU_COVER_SIZE: u32 = 1024;
+
+/// File extensions of the formats the reader can open.
+pub const BOOK_EXTENSIONS: &[&str] = &[
+    "epub", "pdf", "mobi", "azw", "azw3", "cbz", "fb2", "fbz", "txt", "md",
+];
+
+/// Extensions of the formats that only open when built with their feature.
+const OPTIONAL_EXTENSIONS: &[&str] = &[
+    #[cfg(feature = "rar")]
+    "cbr",
+    #[cfg(feature = "djvu")]
+    "djvu",
+    #[cfg(feature = "djvu")]
+    "djv",
+];
+
+pub fn is_book_file(path: &Path) -> bool {
+    let ext = extension(path);
+    BOOK_EXTENSIONS
+        .iter()
+        .chain(OPTIONAL_EXTENSIONS)
+        .any(|known| *known == ext)
+}
+
+/// MIME type of a book file with extension `ext`.
+pub fn mime_type(ext: &str) -> &'static str {
+    match ext.to_ascii_lowercase().as_str() {
+        "epub" => "application/epub+zip",
+        "pdf" => "application/pdf",
+        "mobi" | "azw" => "application/x-mobipocket-ebook",
+        "azw3" => "application/vnd.amazon.ebook",
+        "cbz" => "application/vnd.comicbook+zip",
+        "cbr" => "application/vnd.comicbook-rar",
+        "fb2" => "application/x-fictionbook+xml",
+        "fbz" => "application/x-zip-compressed-fb2",
+        "djvu" | "djv" => "image/vnd.djvu",
+        "txt" => "text/plain",
+        "md" | "markdown" => "text/markdown",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Text content of one section of a book.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Chapter {
+    /// Position in reading order.
+    pub index: usize,
+    pub href: String,
+    pub text: String,
+}
+
+/// Lowercase extension of `path`, `fbz` for `.fb2.zip` files.
+pub fn extension(path: &Path) -> String {
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let zipped_fb2 = ext == "zip"
+        && path
+            .file_stem()
+            .and_then(|stem| Path::new(stem).extension())
+            .is_some_and(|inner| inner.eq_ignore_ascii_case("fb2"));
+    if zipped_fb2 {
+        "fbz".into()
+    } else {
+        ext
+    }
+}
+
+/// Extracts the text of `path` chapter by chapter for the formats that have a native parser.
+pub fn extract_chapters(path: &Path) -> Result<Vec<Chapter>> {
+    match extension(path).as_str() {
+        "epub" => epub::EpubArchive::open(path)?.chapters(),
+        "pdf" => pdf::PdfDocument::open(path)?.chapters(),
+        "fb2" | "fbz" => Ok(fb2::Fb2Book::open(path)?.chapters()),
+        "txt" | "md" | "markdown" => {
+            Ok(text::TextBook::open(path, &text::TextOptions::default())?.chapters())
+        }
+        ext => Err(Error::UnsupportedFormat(ext.to_string())),
+    }
+}
+
+/// Extracts the cover image of `path`, if the format has a native parser and
+/// the book has one.
+pub fn extract_cover(path: &Path) -> Result<Option<Vec<u8>>> {
+    match extension(path).as_str() {
+        "epub" => {
+            let mut epub = epub::EpubArchive::open(path)?;
+            match epub.package()?.cover() {
+                Some(item) => Ok(Some(epub.read_entry(&item.href)?)),
+                None => Ok(None),
+            }
+        }
+        "fb2" | "fbz" => fb2::Fb2Book::open(path)?.cover(),
+        #[cfg(feature = "djvu")]
+        "djvu" | "djv" => {
+            let djvu = djvu::DjvuDocument::open(path)?;
+            if djvu.page_count() == 0 {
+                return Ok(None);
+            }
+            Ok(Some(djvu.thumbnail(1, DJVU_COVER_SIZE)?))
+        }
+        "cbz" | "cbr" => {
+            let mut comic = comic::ComicArchive::open(path)?;
+            if comic.pages().is_empty() {
+                return Ok(None);
+            }
+            Ok(Some(comic.read_page(0)?))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Scales `image` down to fit in `max_size` × `max_size` and encodes it as JPEG.
+pub fn encode_thumbnail(image: &DynamicImage, max_size: u32) -> Result<Vec<u8>> {
+    let image = if image.width() > max_size || image.height() > max_size {
+        image.thumbnail(max_size, max_size)
+    } else {
+        image.clone()
+    };
+    let mut out = Cursor::new(Vec::new());
+    image
+        .into_rgb8()
+        .write_with_encoder(JpegEncoder::new_with_quality(&mut out, THUMBNAIL_QUALITY))?;
+    Ok(out.into_inner())
+}
@@ -0,0 +1,327 @@
+//! PDF outline, page count and per-page text, read natively so the library
+//! can build a table of contents and a search index without pdf.js. Pages are
+//! numbered from 1, and the `href` of a PDF [`Chapter`] is its page number.
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use lopdf::{Dictionary, Document, Object, ObjectId};
+use serde::Serialize;
+
+use super::epub::Metadata;
+use super::Chapter;
+use crate::error::{Error, Result};
+
+/// Nesting limit when following destinations and name trees.
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutlineItem {
+    pub title: String,
+    /// Target page, if the bookmark points into this document.
+    pub page: Option<u32>,
+    pub children: Vec<OutlineItem>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PageText {
+    pub page: u32,
+    pub text: String,
+}
+
+pub struct PdfDocument {
+    doc: Document,
+    /// Page object ids in page order.
+    pages: Vec<ObjectId>,
+}
+
+impl PdfDocument {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let mut doc = Document::load(path)?;
+        if doc.is_encrypted() {
+            // Files that only restrict printing or copying open with an empty password
+            doc.decrypt("")
+                .map_err(|_| Error::UnsupportedFormat("encrypted pdf".into()))?;
+        }
+        let pages = doc.page_iter().collect();
+        Ok(Self { doc, pages })
+    }
+
+    pub fn page_count(&self) -> u32 {
+        self.pages.len() as u32
+    }
+
+    /// Title, authors and dates from the document information dictionary.
+    pub fn metadata(&self) -> Metadata {
+        let info = self
+            .doc
+            .trailer
+            .get(b"Info")
+            .ok()
+            .and_then(|info| self.resolve(info).as_dict().ok());
+        let field = |key: &[u8]| {
+            info.and_then(|info| info.get(key).ok())
+                .and_then(|value| self.resolve(value).as_str().ok())
+                .map(decode_text_string)
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+        };
+        let split = |value: Option<String>, separators: &[char]| {
+            value
+                .iter()
+                .flat_map(|value| value.split(separators))
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+        Metadata {
+            title: field(b"Title").unwrap_or_default(),
+            authors: split(field(b"Author"), &[';', '&']),
+            description: field(b"Subject"),
+            subjects: split(field(b"Keywords"), &[',', ';']),
+            published: field(b"CreationDate").and_then(|date| parse_date(&date)),
+            ..Metadata::default()
+        }
+    }
+
+    /// The bookmarks of the document, empty if it has none.
+    pub fn outline(&self) -> Vec<OutlineItem> {
+        let first = self
+            .doc
+            .catalog()
+            .ok()
+            .and_then(|catalog| catalog.get(b"Outlines").ok())
+            .and_then(|outlines| self.resolve(outlines).as_dict().ok())
+            .and_then(|outlines| outlines.get(b"First").ok());
+        let pages = self
+            .pages
+            .iter()
+            .enumerate()
+            .map(|(i, &id)| (id, i as u32 + 1))
+            .collect();
+        self.outline_items(first, &pages, &mut HashSet::new())
+    }
+
+    fn outline_items(
+        &self,
+        first: Option<&Object>,
+        pages: &HashMap<ObjectId, u32>,
+        seen: &mut HashSet<ObjectId>,
+    ) -> Vec<OutlineItem> {
+        let mut items = Vec::new();
+        let mut next = first;
+        while let Some(object) = next {
+            // Outline items are always indirect; stop on loops in broken files
+            let Ok(id) = object.as_reference() else {
+                break;
+            };
+            if !seen.insert(id) {
+                break;
+            }
+            let Ok(node) = self.doc.get_dictionary(id) else {
+                break;
+            };
+            let title = node
+                .get(b"Title")
+                .ok()
+                .and_then(|title| self.resolve(title).as_str().ok())
+                .map(decode_text_string)
+                .unwrap_or_default();
+            items.push(OutlineItem {
+                title: title.trim().to_string(),
+                page: self
+                    .outline_destination(node)
+                    .and_then(|dest| self.destination_page(dest, pages, 0)),
+                children: self.outline_items(node.get(b"First").ok(), pages, seen),
+            });
+            next = node.get(b"Next").ok();
+        }
+        items
+    }
+
+    fn outline_destination<'a>(&'a self, node: &'a Dictionary) -> Option<&'a Object> {
+        if let Ok(dest) = node.get(b"Dest") {
+            return Some(dest);
+        }
+        let action = self.resolve(node.get(b"A").ok()?).as_dict().ok()?;
+        match action.get(b"S").and_then(Object::as_name) {
+            Ok(b"GoTo") => action.get(b"D").ok(),
+            _ => None,
+        }
+    }
+
+    /// Resolves an explicit or named destination to a page number.
+    fn destination_page(
+        &self,
+        dest: &Object,
+        pages: &HashMap<ObjectId, u32>,
+        depth: usize,
+    ) -> Option<u32> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        match self.resolve(dest) {
+            Object::Array(array) => match array.first()? {
+                Object::Reference(id) => pages.get(id).copied(),
+                // Some generators write a page index instead of a reference
+                Object::Integer(index) => u32::try_from(*index)
+                    .ok()
+                    .filter(|&index| index < self.page_count())
+                    .map(|index| index + 1),
+                _ => None,
+            },
+            Object::Dictionary(dict) => {
+                self.destination_page(dict.get(b"D").ok()?, pages, depth + 1)
+            }
+            Object::Name(name) => {
+                let dests = self.doc.catalog().ok()?.get(b"Dests").ok()?;
+                let dest = self.resolve(dests).as_dict().ok()?.get(name).ok()?;
+                self.destination_page(dest, pages, depth + 1)
+            }
+            Object::String(name, _) => {
+                let names = self.doc.catalog().ok()?.get(b"Names").ok()?;
+                let tree = self.resolve(names).as_dict().ok()?.get(b"Dests").ok()?;
+                let tree = self.resolve(tree).as_dict().ok()?;
+                let dest = self.find_in_name_tree(tree, name, 0)?;
+                self.destination_page(dest, pages, depth + 1)
+            }
+            _ => None,
+        }
+    }
+
+    fn find_in_name_tree<'a>(
+        &'a self,
+        node: &'a Dictionary,
+        key: &[u8],
+        depth: usize,
+    ) -> Option<&'a Object> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        if let Ok(names) = node.get(b"Names") {
+            let names = self.resolve(names).as_array().ok()?;
+            return names
+                .chunks(2)
+                .find(|pair| self.resolve(&pair[0]).as_str().ok() == Some(key))
+                .and_then(|pair| pair.get(1));
+        }
+        let kids = self.resolve(node.get(b"Kids").ok()?).as_array().ok()?;
+        kids.iter()
+            .filter_map(|kid| self.resolve(kid).as_dict().ok())
+            .filter(|kid| {
+                // Skip subtrees whose key range cannot contain `key`
+                let Some(limits) = kid
+                    .get(b"Limits")
+                    .ok()
+                    .and_then(|limits| self.resolve(limits).as_array().ok())
+                else {
+                    return true;
+                };
+                let bound = |i: usize| limits.get(i).and_then(|v| self.resolve(v).as_str().ok());
+                bound(0).map_or(true, |low| key >= low) && bound(1).map_or(true, |high| key <= high)
+            })
+            .find_map(|kid| self.find_in_name_tree(kid, key, depth + 1))
+    }
+
+    fn resolve<'a>(&'a self, object: &'a Object) -> &'a Object {
+        self.doc
+            .dereference(object)
+            .map(|(_, object)| object)
+            .unwrap_or(object)
+    }
+
+    pub fn page_text(&self, page: u32) -> Result<String> {
+        if page == 0 || page > self.page_count() {
+            return Err(Error::InvalidBook(format!("page {page} out of range")));
+        }
+        Ok(self.doc.extract_text(&[page])?.trim().to_string())
+    }
+
+    /// Writes pages `from..=to` to `dest` as they are, embedded fonts and
+    /// all. The outline is dropped since most of its targets are gone.
+    pub fn save_pages(&self, from: u32, to: u32, dest: &Path) -> Result<()> {
+        if from == 0 || from > to || to > self.page_count() {
+            return Err(Error::InvalidBook(format!(
+                "pages {from}-{to} out of range"
+            )));
+        }
+        let mut doc = self.doc.clone();
+        let deleted = (1..from)
+            .chain(to + 1..=self.page_count())
+            .collect::<Vec<_>>();
+        doc.delete_pages(&deleted);
+        if let Ok(catalog) = doc.catalog_mut() {
+            catalog.remove(b"Outlines");
+        }
+        doc.prune_objects();
+        doc.save(dest)?;
+        Ok(())
+    }
+
+    /// Text of every page, skipping pages whose content cannot be decoded.
+    pub fn chapters(&self) -> Result<Vec<Chapter>> {
+        let mut chapters = Vec::new();
+        for page in 1..=self.page_count() {
+            match self.page_text(page) {
+                Ok(text) if !text.is_empty() => chapters.push(Chapter {
+                    index: page as usize - 1,
+                    href: page.to_string(),
+                    text,
+                }),
+                Ok(_) => {}
+                Err(e) => log::warn!("Skipping unreadable page {page}: {e}"),
+            }
+        }
+        Ok(chapters)
+    }
+}
+
+/// Decodes a PDF text string, which is UTF-16BE with a byte order mark,
+/// UTF-8 with a byte order mark, or PDFDocEncoding.
+fn decode_text_string(bytes: &[u8]) -> String {
+    if let Some(utf16) = bytes.strip_prefix(&[0xfe, 0xff]) {
+        let units = utf16
+            .chunks_exact(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+            .collect::<Vec<_>>();
+        return String::from_utf16_lossy(&units);
+    }
+    if let Some(utf8) = bytes.strip_prefix(&[0xef, 0xbb, 0xbf]) {
+        return String::from_utf8_lossy(utf8).into_owned();
+    }
+    bytes.iter().map(|&b| pdf_doc_char(b)).collect()
+}
+
+fn pdf_doc_char(byte: u8) -> char {
+    // PDFDocEncoding matches Latin-1 except for 0x18-0x1F and 0x80-0xA0
+    const HIGH: [char; 33] = [
+        '•', '†', '‡', '…', '—', '–', 'ƒ', '⁄', '‹', '›', '−', '‰', '„', '“', '”', '‘', '’', '‚',
+        '™', 'ﬁ', 'ﬂ', 'Ł', 'Œ', 'Š', 'Ÿ', 'Ž', 'ı', 'ł', 'œ', 'š', 'ž', '\u{fffd}', '€',
+    ];
+    const LOW: [char; 8] = ['˘', 'ˇ', 'ˆ', '˙', '˝', '˛', '˚', '˜'];
+    match byte {
+        0x18..=0x1f => LOW[(byte - 0x18) as usize],
+        0x80..=0xa0 => HIGH[(byte - 0x80) as usize],
+        _ => byte as char,
+    }
+}
+
+/// Converts a PDF date (`D:YYYYMMDDHHmmSS...`) to `YYYY`, `YYYY-MM` or `YYYY-MM-DD`.
+fn parse_date(date: &str) -> Option<String> {
+    let digits = date.strip_prefix("D:").unwrap_or(date);
+    let digits = &digits[..digits.bytes().take_while(u8::is_ascii_digit).count()];
+    match digits.len() {
+        0..=3 => None,
+        4..=5 => Some(digits[..4].to_string()),
+        6..=7 => Some(format!("{}-{}", &digits[..4], &digits[4..6])),
+        _ => Some(format!(
+            "{}-{}-{}",
+            &digits[..4],
+            &digits[4..6],
+            &digits[6..8]
+        )),
+    }
+}
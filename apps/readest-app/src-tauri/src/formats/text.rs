@@ -0,0 +1,815 @@
+//! Plain text and Markdown books. The file is decoded from the encoding it
+//! declares with a byte order mark, or else the one [`charset::detect`]
+//! guesses, then split into chapters at the lines that look like headings,
+//! such as `第十二章` or `Chapter XII` in text files and `#` headings in
+//! Markdown. Each chapter is rendered to XHTML on request, and its `href` is
+//! a file name of its own, such as `chapter-3.xhtml`.
+
+use std::ops::Range;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use encoding_rs::Encoding;
+use regex::{Regex, RegexSet};
+use serde::Deserialize;
+
+use super::charset;
+use super::epub::{Metadata, TocItem};
+use super::html::html_to_text;
+use super::Chapter;
+use crate::error::Result;
+use crate::utils::escape_xml;
+
+/// Longest line taken for a heading.
+const MAX_HEADING_CHARS: usize = 60;
+/// Size of the parts a text without headings is split into.
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Lines searched for the author at the top of the file.
+const AUTHOR_LINES: usize = 30;
+
+/// Numbers as they appear after `Chapter` or `Part`, for rules in verbose
+/// mode, which ignores the line breaks.
+macro_rules! number {
+    () => {
+        r"(?:\d+|[ivxlcdm]+|one|two|three|four|five|six|seven|eight|nine|ten|eleven|twelve|
+           thirteen|fourteen|fifteen|sixteen|seventeen|eighteen|nineteen|twenty|thirty|forty|
+           fifty|first|second|third|fourth|fifth|sixth|seventh|eighth|ninth|tenth|last)"
+    };
+}
+
+/// Built-in heading rules for plain text, with the level of the headings
+/// each one matches.
+const HEADING_RULES: &[(u8, &str)] = &[
+    (
+        1,
+        r"^第[0-9０-９零〇一二三四五六七八九十百千万萬两兩]+[卷部集篇].*$",
+    ),
+    (
+        2,
+        r"^第[0-9０-９零〇一二三四五六七八九十百千万萬两兩]+[章节節回话話].*$",
+    ),
+    (
+        2,
+        r"^(?:序章|序言|序|楔子|引子|引言|前言|后记|後記|尾声|尾聲|终章|終章|番外)(?:[\s:：、.．·—-].*)?$",
+    ),
+    (
+        1,
+        concat!(
+            r"(?ix)^(?:part|book|volume)\s+",
+            number!(),
+            r"\b(?:[\s.:—-].*)?$"
+        ),
+    ),
+    (
+        2,
+        concat!(r"(?ix)^chapter\s+", number!(), r"\b(?:[\s.:—-].*)?$"),
+    ),
+    (
+        2,
+        r"(?i)^(?:prologue|epilogue|preface|introduction|afterword)$",
+    ),
+];
+
+const STYLE: &str = "\
+body { margin: 0 }
+h1, h2 { text-align: center }
+p { margin: 0; text-indent: 2em }
+.markdown p { margin: 0 0 1em; text-indent: 0 }
+pre { white-space: pre-wrap }
+img { max-width: 100% }
+";
+
+/// How a text book is read, set by the user when the guesses are wrong.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TextOptions {
+    /// Encoding label such as `gbk`, detected when omitted.
+    pub encoding: Option<String>,
+    /// Regular expression matching whole heading lines, used instead of the
+    /// built-in rules.
+    pub chapter_pattern: Option<String>,
+}
+
+struct Heading {
+    /// The heading line, newline included.
+    line: Range<usize>,
+    title: String,
+    level: u8,
+}
+
+struct Section {
+    href: String,
+    title: Option<String>,
+    level: u8,
+    body: Range<usize>,
+}
+
+pub struct TextBook {
+    metadata: Metadata,
+    encoding: &'static Encoding,
+    markdown: bool,
+    /// Paragraphs are separated by blank lines and wrapped across lines.
+    wrapped: bool,
+    text: String,
+    sections: Vec<Section>,
+    toc: Vec<TocItem>,
+}
+
+/// Lines of `text` with their byte ranges, newlines included.
+fn lines(text: &str) -> impl Iterator<Item = (Range<usize>, &str)> {
+    let mut start = 0;
+    text.split_inclusive('\n').map(move |line| {
+        let range = start..start + line.len();
+        start = range.end;
+        (range, line)
+    })
+}
+
+/// `#` headings outside code blocks, with their level.
+fn markdown_headings(text: &str) -> Vec<Heading> {
+    let mut headings = Vec::new();
+    let mut fence = None;
+    for (range, line) in lines(text) {
+        let trimmed = line.trim();
+        if let Some(open) = fence {
+            if trimmed.starts_with(open) {
+                fence = None;
+            }
+            continue;
+        }
+        fence = ["```", "~~~"]
+            .into_iter()
+            .find(|marker| trimmed.starts_with(marker));
+        if let Some((level, title)) = atx_heading(line) {
+            headings.push(Heading {
+                line: range,
+                title: title.to_string(),
+                level,
+            });
+        }
+    }
+    headings
+}
+
+/// Lines matching `rules`, taking the level of the first rule that matches.
+fn text_headings(text: &str, rules: &RegexSet, levels: &[u8]) -> Vec<Heading> {
+    lines(text)
+        .filter_map(|(range, line)| {
+            let title = line.trim();
+            if title.is_empty() || title.chars().count() > MAX_HEADING_CHARS {
+                return None;
+            }
+            let rule = rules.matches(title).into_iter().next()?;
+            Some(Heading {
+                line: range,
+                title: title.to_string(),
+                level: levels[rule],
+            })
+        })
+        .collect()
+}
+
+/// The headings a Markdown book is split at: those of the highest level
+/// that gives at least two chapters, and any above it.
+fn split_headings(mut headings: Vec<Heading>) -> Vec<Heading> {
+    let level = (1..=6)
+        .find(|&level| headings.iter().filter(|h| h.level <= level).count() >= 2)
+        .unwrap_or(0);
+    headings.retain(|heading| heading.level <= level);
+    headings
+}
+
+/// Byte ranges of parts of about [`CHUNK_SIZE`], cut at blank lines where possible.
+fn chunks(text: &str) -> Vec<Range<usize>> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    for (range, line) in lines(text) {
+        let size = range.end - start;
+        if size >= CHUNK_SIZE && (line.trim().is_empty() || size >= CHUNK_SIZE * 2) {
+            chunks.push(start..range.end);
+            start = range.end;
+        }
+    }
+    if start < text.len() || chunks.is_empty() {
+        chunks.push(start..text.len());
+    }
+    chunks
+}
+
+/// Whether paragraphs are separated by blank lines and hard-wrapped, as in
+/// most English text files, rather than written one per line. Wrapped
+/// lines are short and mostly end inside a sentence.
+fn is_wrapped(text: &str) -> bool {
+    let (mut lines, mut blocks, mut short, mut sentences) = (0, 0, 0, 0);
+    let mut in_block = false;
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            in_block = false;
+            continue;
+        }
+        lines += 1;
+        if line.chars().count() <= 80 {
+            short += 1;
+        }
+        if line.ends_with(['.', '!', '?', '"', '”', '。', '！', '？', '」', '』', '…']) {
+            sentences += 1;
+        }
+        if !in_block {
+            blocks += 1;
+            in_block = true;
+        }
+    }
+    lines >= blocks * 2 && lines <= blocks * 20 && short * 10 >= lines * 9 && sentences * 2 < lines
+}
+
+/// Nests the entries of the table of contents by heading level.
+fn nest(items: &[(u8, TocItem)]) -> Vec<TocItem> {
+    let mut toc = Vec::new();
+    let mut i = 0;
+    while i < items.len() {
+        let (level, item) = &items[i];
+        let end = items[i + 1..]
+            .iter()
+            .position(|(next, _)| next <= level)
+            .map_or(items.len(), |n| i + 1 + n);
+        let mut item = item.clone();
+        item.children = nest(&items[i + 1..end]);
+        toc.push(item);
+        i = end;
+    }
+    toc
+}
+
+/// The author named in a `作者：` or `By` line at the top of `front`.
+fn find_author(text: &str) -> Option<String> {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    let pattern = PATTERN.get_or_init(|| {
+        Regex::new(r"(?i)^(?:作\s*者|著\s*者|author)\s*[:：]\s*(.{1,40})$|^(?i:by)\s+(.{1,40})$")
+            .expect("valid author pattern")
+    });
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .take(AUTHOR_LINES)
+        .find_map(|line| {
+            let captures = pattern.captures(line)?;
+            let author = captures.get(1).or_else(|| captures.get(2))?;
+            Some(author.as_str().trim().to_string())
+        })
+}
+
+fn document(title: &str, lang: &str, class: &str, body: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<!DOCTYPE html>\n\
+         <html xmlns=\"http://www.w3.org/1999/xhtml\" lang=\"{}\">\n\
+         <head><title>{}</title><style>{STYLE}</style></head>\n<body class=\"{class}\">\n{body}</body>\n</html>\n",
+        escape_xml(lang),
+        escape_xml(title),
+    )
+}
+
+fn is_cjk(c: char) -> bool {
+    c >= '\u{2e80}'
+}
+
+/// Paragraphs of plain text, one per line or, in wrapped text, one per
+/// block of lines.
+fn text_paragraphs(body: &str, wrapped: bool, out: &mut String) {
+    let mut paragraph = String::new();
+    let mut flush = |paragraph: &mut String| {
+        if !paragraph.is_empty() {
+            out.push_str(&format!("<p>{}</p>\n", escape_xml(paragraph)));
+            paragraph.clear();
+        }
+    };
+    for line in body.lines().map(str::trim) {
+        if line.is_empty() || !wrapped {
+            flush(&mut paragraph);
+        }
+        if line.is_empty() {
+            continue;
+        }
+        if !paragraph.is_empty() && !paragraph.ends_with(is_cjk) {
+            paragraph.push(' ');
+        }
+        paragraph.push_str(line);
+    }
+    flush(&mut paragraph);
+}
+
+/// Level and text of a `#` heading line.
+fn atx_heading(line: &str) -> Option<(u8, &str)> {
+    let line = line.trim();
+    let level = line.bytes().take_while(|&b| b == b'#').count();
+    let rest = &line[level..];
+    if !(1..=6).contains(&level) || !(rest.is_empty() || rest.starts_with([' ', '\t'])) {
+        return None;
+    }
+    Some((level as u8, rest.trim().trim_end_matches('#').trim_end()))
+}
+
+fn is_rule(line: &str) -> bool {
+    let marks = line.replace([' ', '\t'], "");
+    marks.len() >= 3
+        && ['-', '*', '_']
+            .into_iter()
+            .any(|mark| marks.chars().all(|c| c == mark))
+}
+
+/// Whether the line starts an ordered or unordered list item, and its text.
+fn list_item(line: &str) -> Option<(bool, &str)> {
+    if let Some(item) = ["- ", "* ", "+ "]
+        .into_iter()
+        .find_map(|marker| line.strip_prefix(marker))
+    {
+        return Some((false, item));
+    }
+    let digits = line.bytes().take_while(u8::is_ascii_digit).count();
+    let rest = &line[digits..];
+    if digits == 0 || digits > 9 {
+        return None;
+    }
+    rest.strip_prefix(". ")
+        .or_else(|| rest.strip_prefix(") "))
+        .map(|item| (true, item))
+}
+
+/// Renders the Markdown blocks of `body`: headings, paragraphs, quotes,
+/// lists, rules and code blocks.
+fn markdown(body: &str, out: &mut String) {
+    fn flush(paragraph: &mut Vec<&str>, out: &mut String) {
+        if !paragraph.is_empty() {
+            out.push_str("<p>");
+            inline(&paragraph.join(" "), out);
+            out.push_str("</p>\n");
+            paragraph.clear();
+        }
+    }
+    let mut paragraph = Vec::new();
+    let mut lines = body.lines().peekable();
+    while let Some(line) = lines.next() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            flush(&mut paragraph, out);
+            continue;
+        }
+        if let Some(fence) = ["```", "~~~"]
+            .into_iter()
+            .find(|marker| trimmed.starts_with(marker))
+        {
+            flush(&mut paragraph, out);
+            out.push_str("<pre><code>");
+            for line in lines.by_ref() {
+                if line.trim_start().starts_with(fence) {
+                    break;
+                }
+                out.push_str(&escape_xml(line));
+                out.push('\n');
+            }
+            out.push_str("</code></pre>\n");
+        } else if let Some((level, title)) = atx_heading(trimmed) {
+            flush(&mut paragraph, out);
+            out.push_str(&format!("<h{level}>"));
+            inline(title, out);
+            out.push_str(&format!("</h{level}>\n"));
+        } else if is_rule(trimmed) {
+            flush(&mut paragraph, out);
+            out.push_str("<hr/>\n");
+        } else if let Some(quote) = trimmed.strip_prefix('>') {
+            flush(&mut paragraph, out);
+            let mut quoted = vec![quote.trim()];
+            while let Some(quote) = lines.peek().and_then(|l| l.trim().strip_prefix('>')) {
+                quoted.push(quote.trim());
+                lines.next();
+            }
+            out.push_str("<blockquote><p>");
+            inline(&quoted.join(" "), out);
+            out.push_str("</p></blockquote>\n");
+        } else if let Some((ordered, item)) = list_item(trimmed) {
+            flush(&mut paragraph, out);
+            let mut items = vec![item.to_string()];
+            while let Some(next) = lines.peek() {
+                match list_item(next.trim()) {
+                    Some((kind, item)) if kind == ordered => items.push(item.to_string()),
+                    // Indented lines continue the item above
+                    None if next.starts_with([' ', '\t']) && !next.trim().is_empty() => {
+                        let last = items.last_mut().expect("list has an item");
+                        last.push(' ');
+                        last.push_str(next.trim());
+                    }
+                    _ => break,
+                }
+                lines.next();
+            }
+            let tag = if ordered { "ol" } else { "ul" };
+            out.push_str(&format!("<{tag}>\n"));
+            for item in items {
+                out.push_str("<li>");
+                inline(&item, out);
+                out.push_str("</li>\n");
+            }
+            out.push_str(&format!("</{tag}>\n"));
+        } else {
+            paragraph.push(trimmed);
+        }
+    }
+    flush(&mut paragraph, out);
+}
+
+/// Renders Markdown emphasis, code spans, links and images.
+fn inline(text: &str, out: &mut String) {
+    let mut rest = text;
+    while let Some(i) = rest.find(['\\', '`', '*', '_', '[', '!']) {
+        out.push_str(&escape_xml(&rest[..i]));
+        let tail = &rest[i..];
+        rest = match span(tail, out) {
+            Some(after) => after,
+            None => {
+                let len = tail.chars().next().map_or(1, char::len_utf8);
+                out.push_str(&escape_xml(&tail[..len]));
+                &tail[len..]
+            }
+        };
+    }
+    out.push_str(&escape_xml(rest));
+}
+
+/// Renders the span `text` starts with and returns the text after it, or
+/// `None` if it does not start one.
+fn span<'a>(text: &'a str, out: &mut String) -> Option<&'a str> {
+    if let Some(rest) = text.strip_prefix('\\') {
+        let c = rest.chars().next().filter(char::is_ascii_punctuation)?;
+        out.push_str(&escape_xml(&rest[..1]));
+        return Some(&rest[c.len_utf8()..]);
+    }
+    if let Some(rest) = text.strip_prefix('`') {
+        let (code, after) = rest.split_once('`')?;
+        out.push_str(&format!("<code>{}</code>", escape_xml(code)));
+        return Some(after);
+    }
+    // Single underscores are left alone, they mostly appear inside words
+    for (marker, tag) in [("**", "strong"), ("__", "strong"), ("*", "em")] {
+        let Some(rest) = text.strip_prefix(marker) else {
+            continue;
+        };
+        let Some(end) = rest.find(marker).filter(|&end| end > 0) else {
+            continue;
+        };
+        if rest.starts_with(char::is_whitespace) {
+            continue;
+        }
+        out.push_str(&format!("<{tag}>"));
+        inline(&rest[..end], out);
+        out.push_str(&format!("</{tag}>"));
+        return Some(&rest[end + marker.len()..]);
+    }
+    let (image, rest) = match text.strip_prefix("![") {
+        Some(rest) => (true, rest),
+        None => (false, text.strip_prefix('[')?),
+    };
+    let (label, rest) = rest.split_once("](")?;
+    let (target, after) = rest.split_once(')')?;
+    // Drop the optional title after the URL
+    let url = target.split_whitespace().next().unwrap_or_default();
+    if image {
+        // Only remote images can be shown, local ones are not served
+        if url.starts_with("https://") || url.starts_with("http://") {
+            out.push_str(&format!(
+                "<img src=\"{}\" alt=\"{}\"/>",
+                escape_xml(url),
+                escape_xml(label)
+            ));
+        } else {
+            out.push_str(&escape_xml(label));
+        }
+    } else {
+        out.push_str(&format!("<a href=\"{}\">", escape_xml(url)));
+        inline(label, out);
+        out.push_str("</a>");
+    }
+    Some(after)
+}
+
+impl TextBook {
+    /// Opens a text file, or a Markdown one if it has the `md` extension.
+    pub fn open(path: impl AsRef<Path>, options: &TextOptions) -> Result<Self> {
+        let path = path.as_ref();
+        let markdown = matches!(super::extension(path).as_str(), "md" | "markdown");
+        let mut book = Self::parse(&std::fs::read(path)?, markdown, options)?;
+        let stem = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or_default();
+        // Web novels are often named `《Title》作者：Author.txt`
+        let (title, author) = match stem.split_once('《').and_then(|(_, s)| s.split_once('》')) {
+            Some((title, rest)) => (title, rest.split_once(['：', ':']).map(|(_, a)| a.trim())),
+            None => (stem, None),
+        };
+        if book.metadata.title.is_empty() {
+            book.metadata.title = title.trim().to_string();
+        }
+        if book.metadata.authors.is_empty() {
+            book.metadata
+                .authors
+                .extend(author.filter(|author| !author.is_empty()).map(String::from));
+        }
+        Ok(book)
+    }
+
+    pub fn parse(bytes: &[u8], markdown: bool, options: &TextOptions) -> Result<Self> {
+        let (text, encoding) = charset::decode(bytes, options.encoding.as_deref())?;
+        let text = text
+            .trim_start_matches('\u{feff}')
+            .replace("\r\n", "\n")
+            .replace('\r', "\n");
+
+        let headings = match &options.chapter_pattern {
+            Some(pattern) => {
+                let rules = RegexSet::new([pattern])?;
+                text_headings(&text, &rules, &[1])
+            }
+            None if markdown => split_headings(markdown_headings(&text)),
+            None => {
+                let rules = RegexSet::new(HEADING_RULES.iter().map(|(_, rule)| rule))?;
+                let levels = HEADING_RULES
+                    .iter()
+                    .map(|(level, _)| *level)
+                    .collect::<Vec<_>>();
+                text_headings(&text, &rules, &levels)
+            }
+        };
+
+        let href_of = |i: usize| format!("chapter-{}.xhtml", i + 1);
+        let mut sections = Vec::new();
+        if headings.len() < 2 {
+            for body in chunks(&text) {
+                sections.push(Section {
+                    href: href_of(sections.len()),
+                    title: None,
+                    level: 1,
+                    body,
+                });
+            }
+        } else {
+            // Text before the first heading, such as the title and blurb
+            let front = 0..headings[0].line.start;
+            if !text[front.clone()].trim().is_empty() {
+                sections.push(Section {
+                    href: href_of(0),
+                    title: None,
+                    level: 1,
+                    body: front,
+                });
+            }
+            for (i, heading) in headings.iter().enumerate() {
+                let end = headings
+                    .get(i + 1)
+                    .map_or(text.len(), |next| next.line.start);
+                sections.push(Section {
+                    href: href_of(sections.len()),
+                    title: Some(heading.title.clone()).filter(|title| !title.is_empty()),
+                    level: heading.level,
+                    body: heading.line.end..end,
+                });
+            }
+        }
+
+        let entries = sections
+            .iter()
+            .filter_map(|section| {
+                let title = section.title.as_ref()?;
+                let label = if markdown {
+                    title.replace(['*', '`'], "")
+                } else {
+                    title.clone()
+                };
+                Some((
+                    section.level,
+                    TocItem {
+                        label,
+                        href: section.href.clone(),
+                        children: Vec::new(),
+                    },
+                ))
+            })
+            .collect::<Vec<_>>();
+        let toc = nest(&entries);
+
+        let mut titles = headings.iter().filter(|heading| heading.level == 1);
+        let title = match (titles.next(), titles.next()) {
+            (Some(title), None) if markdown => title.title.replace(['*', '`'], ""),
+            _ => String::new(),
+        };
+        let front = headings
+            .first()
+            .map_or(text.as_str(), |heading| &text[..heading.line.start]);
+        let language = match encoding.name() {
+            "GBK" | "Big5" => Some("zh"),
+            "Shift_JIS" => Some("ja"),
+            "EUC-KR" => Some("ko"),
+            _ => None,
+        };
+        let metadata = Metadata {
+            title,
+            authors: find_author(front).into_iter().collect(),
+            language: language.into_iter().map(String::from).collect(),
+            ..Metadata::default()
+        };
+
+        Ok(Self {
+            metadata,
+            encoding,
+            markdown,
+            wrapped: !markdown && is_wrapped(&text),
+            text,
+            sections,
+            toc,
+        })
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// Name of the encoding the file was decoded from, such as `GBK`.
+    pub fn encoding(&self) -> &'static str {
+        self.encoding.name()
+    }
+
+    pub fn toc(&self) -> &[TocItem] {
+        &self.toc
+    }
+
+    /// Hrefs and titles of the chapters in reading order.
+    pub fn spine(&self) -> impl Iterator<Item = (&str, Option<&str>)> {
+        self.sections
+            .iter()
+            .map(|section| (section.href.as_str(), section.title.as_deref()))
+    }
+
+    /// XHTML of the chapter `href`.
+    pub fn chapter(&self, href: &str) -> Option<String> {
+        let section = self.sections.iter().find(|section| section.href == href)?;
+        Some(self.render(section))
+    }
+
+    fn render(&self, section: &Section) -> String {
+        let mut body = String::new();
+        if let Some(title) = &section.title {
+            let level = section.level.clamp(1, 6);
+            body.push_str(&format!("<h{level}>"));
+            if self.markdown {
+                inline(title, &mut body);
+            } else {
+                body.push_str(&escape_xml(title));
+            }
+            body.push_str(&format!("</h{level}>\n"));
+        }
+        let text = &self.text[section.body.clone()];
+        if self.markdown {
+            markdown(text, &mut body);
+        } else {
+            text_paragraphs(text, self.wrapped, &mut body);
+        }
+        let title = section.title.as_deref().unwrap_or(&self.metadata.title);
+        let lang = self.metadata.language.first().map_or("", String::as_str);
+        let class = if self.markdown { "markdown" } else { "text" };
+        document(title, lang, class, &body)
+    }
+
+    /// Plain text of every chapter, in reading order.
+    pub fn chapters(&self) -> Vec<Chapter> {
+        self.sections
+            .iter()
+            .enumerate()
+            .map(|(index, section)| Chapter {
+                index,
+                href: section.href.clone(),
+                text: html_to_text(&self.render(section)),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str, markdown: bool) -> TextBook {
+        TextBook::parse(text.as_bytes(), markdown, &TextOptions::default()).unwrap()
+    }
+
+    fn spine(book: &TextBook) -> Vec<(&str, Option<&str>)> {
+        book.spine().collect()
+    }
+
+    /// Labels of the table of contents, children after their parent and
+    /// indented by depth.
+    fn toc(items: &[TocItem], depth: usize, out: &mut Vec<String>) {
+        for item in items {
+            out.push(format!("{}{}", "  ".repeat(depth), item.label));
+            toc(&item.children, depth + 1, out);
+        }
+    }
+
+    fn labels(book: &TextBook) -> Vec<String> {
+        let mut labels = Vec::new();
+        toc(book.toc(), 0, &mut labels);
+        labels
+    }
+
+    #[test]
+    fn chinese_chapters_nest_in_volumes_after_the_front() {
+        let book = parse(
+            "书名\n作者：张三\n\n第一卷 起\n第一章 开始\n正文\n第二章 继续\n更多\n",
+            false,
+        );
+        assert_eq!(
+            spine(&book),
+            [
+                ("chapter-1.xhtml", None),
+                ("chapter-2.xhtml", Some("第一卷 起")),
+                ("chapter-3.xhtml", Some("第一章 开始")),
+                ("chapter-4.xhtml", Some("第二章 继续")),
+            ]
+        );
+        assert_eq!(
+            labels(&book),
+            ["第一卷 起", "  第一章 开始", "  第二章 继续"]
+        );
+        assert_eq!(book.metadata().authors, ["张三"]);
+        assert!(book
+            .chapter("chapter-3.xhtml")
+            .unwrap()
+            .contains("<h2>第一章 开始</h2>"));
+    }
+
+    #[test]
+    fn english_headings_need_a_number_or_a_name() {
+        let book = parse(
+            "Chapter One\nIt began.\n\nChapter and verse\n\nCHAPTER II. The Return\nMore.\nEpilogue\nEnd.\n",
+            false,
+        );
+        assert_eq!(
+            spine(&book),
+            [
+                ("chapter-1.xhtml", Some("Chapter One")),
+                ("chapter-2.xhtml", Some("CHAPTER II. The Return")),
+                ("chapter-3.xhtml", Some("Epilogue")),
+            ]
+        );
+        let chapters = book.chapters();
+        assert_eq!(
+            chapters[0].text,
+            "Chapter One\nIt began.\nChapter and verse"
+        );
+        assert_eq!(chapters[2].index, 2);
+    }
+
+    #[test]
+    fn markdown_splits_at_the_level_giving_two_chapters() {
+        let book = parse(
+            "# The Book\n\nIntro.\n\n## One\n\nA.\n\n```\n## Not a heading\n```\n\n## Two\n\nB.\n",
+            true,
+        );
+        assert_eq!(labels(&book), ["The Book", "  One", "  Two"]);
+        assert_eq!(book.metadata().title, "The Book");
+        assert!(book
+            .chapter("chapter-2.xhtml")
+            .unwrap()
+            .contains("Not a heading"));
+    }
+
+    #[test]
+    fn custom_patterns_replace_the_rules() {
+        let options = TextOptions {
+            chapter_pattern: Some(r"^== .+ ==$".to_string()),
+            ..TextOptions::default()
+        };
+        let text = "Chapter One\n== Start ==\nA.\n== End ==\nB.\n";
+        let book = TextBook::parse(text.as_bytes(), false, &options).unwrap();
+        assert_eq!(
+            spine(&book),
+            [
+                ("chapter-1.xhtml", None),
+                ("chapter-2.xhtml", Some("== Start ==")),
+                ("chapter-3.xhtml", Some("== End ==")),
+            ]
+        );
+    }
+
+    #[test]
+    fn texts_without_headings_are_cut_at_blank_lines() {
+        let paragraph = format!("{}\n\n", "word ".repeat(CHUNK_SIZE / 8));
+        let text = paragraph.repeat(4);
+        let book = parse(&text, false);
+        assert_eq!(spine(&book).len(), 2);
+        assert!(book.toc().is_empty());
+        assert_eq!(
+            chunks(&text),
+            [0..paragraph.len() * 2, paragraph.len() * 2..text.len()]
+        );
+        assert_eq!(chunks(""), [Range { start: 0, end: 0 }]);
+    }
+}
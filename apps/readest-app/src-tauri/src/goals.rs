@@ -0,0 +1,334 @@
+//! Daily reading goals: minutes to read each day, and pages to turn if the
+//! user wants, measured by the [`stats`](crate::stats) sessions. The user is
+//! reminded with a notification at the time they choose while today's goal
+//! is not reached yet, told when they are a few minutes from it, and when
+//! they reach it, each at most once a day and never in their quiet hours;
+//! what is held back by quiet hours goes out when they end.
+//!
+//! Goals are checked after each session is recorded and by the `goals`
+//! scheduled task, so reminders go out only while the app runs. Settings
+//! are kept in the profile's config dir like other settings, so each
+//! profile has its own goals.
+
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle, Manager};
+use tauri_plugin_notification::NotificationExt;
+
+use crate::error::{Error, Result};
+use crate::library::db::LibraryDb;
+use crate::stats::{self, DAY_MS};
+use crate::store;
+use crate::utils::now_millis;
+
+const SETTINGS_FILE: &str = "goals.json";
+/// The notices sent today, so none is sent twice.
+const SENT_FILE: &str = "goals_sent.json";
+/// Emitted with the [`Notice`] instead of a notification while the user is
+/// looking at the app.
+#[cfg(desktop)]
+const NOTICE_EVENT: &str = "reading-goal";
+const DAY_MINUTES: u32 = 24 * 60;
+
+/// A span of local time, in minutes after midnight, which may go past
+/// midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QuietHours {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl QuietHours {
+    fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct GoalSettings {
+    /// Off until the user sets a goal.
+    pub enabled: bool,
+    pub daily_minutes: u32,
+    /// Pages to turn each day too.
+    pub daily_pages: Option<u32>,
+    /// Local time of the reminder, in minutes after midnight; none for no
+    /// reminder.
+    pub remind_at: Option<u32>,
+    /// Minutes from the goal at which the user is told how close they are,
+    /// 0 for never.
+    pub nudge_minutes: u32,
+    pub notify_reached: bool,
+    pub quiet_hours: Option<QuietHours>,
+    /// As the frontend last passed it, for checks it does not start.
+    pub utc_offset_minutes: i32,
+}
+
+impl Default for GoalSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            daily_minutes: 30,
+            daily_pages: None,
+            remind_at: Some(19 * 60),
+            nudge_minutes: 10,
+            notify_reached: true,
+            quiet_hours: Some(QuietHours {
+                start: 22 * 60,
+                end: 8 * 60,
+            }),
+            utc_offset_minutes: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GoalProgress {
+    /// Today, as `YYYY-MM-DD`.
+    pub day: String,
+    pub read_ms: i64,
+    pub pages: i64,
+    pub daily_minutes: u32,
+    pub daily_pages: Option<u32>,
+    /// Minutes still to read, rounded up.
+    pub minutes_left: u32,
+    pub pages_left: u32,
+    pub reached: bool,
+}
+
+impl GoalProgress {
+    fn of(settings: &GoalSettings, today: stats::PeriodTotal) -> GoalProgress {
+        let goal_ms = i64::from(settings.daily_minutes) * 60_000;
+        let minutes_left = (((goal_ms - today.duration_ms).max(0) + 59_999) / 60_000) as u32;
+        let pages_left = settings
+            .daily_pages
+            .map_or(0, |pages| (i64::from(pages) - today.pages).max(0) as u32);
+        GoalProgress {
+            day: today.start,
+            read_ms: today.duration_ms,
+            pages: today.pages,
+            daily_minutes: settings.daily_minutes,
+            daily_pages: settings.daily_pages,
+            minutes_left,
+            pages_left,
+            reached: minutes_left == 0 && pages_left == 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Notice {
+    Reminder,
+    Nudge,
+    Reached,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct Sent {
+    day: String,
+    reminder: bool,
+    nudge: bool,
+    reached: bool,
+}
+
+impl Sent {
+    fn flag(&mut self, notice: Notice) -> &mut bool {
+        match notice {
+            Notice::Reminder => &mut self.reminder,
+            Notice::Nudge => &mut self.nudge,
+            Notice::Reached => &mut self.reached,
+        }
+    }
+}
+
+#[cfg(desktop)]
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NoticeEvent {
+    notice: Notice,
+    progress: GoalProgress,
+}
+
+/// Held while checking, so a session recorded during the scheduled check
+/// does not send the same notice again.
+#[derive(Default)]
+pub struct ReadingGoals(Mutex<()>);
+
+fn validate(settings: &GoalSettings) -> Result<()> {
+    if settings.daily_minutes == 0 && settings.daily_pages.unwrap_or(0) == 0 {
+        return Err(Error::Goal("a goal needs minutes or pages".to_string()));
+    }
+    let times = settings.remind_at.into_iter().chain(
+        settings
+            .quiet_hours
+            .iter()
+            .flat_map(|quiet| [quiet.start, quiet.end]),
+    );
+    if times.into_iter().any(|minute| minute >= DAY_MINUTES) {
+        return Err(Error::Goal("times are minutes after midnight".to_string()));
+    }
+    Ok(())
+}
+
+fn progress(app: &AppHandle, settings: &GoalSettings) -> Result<GoalProgress> {
+    let today = stats::today_total(
+        &app.state::<LibraryDb>().conn(),
+        settings.utc_offset_minutes,
+    )?;
+    Ok(GoalProgress::of(settings, today))
+}
+
+/// The notice to send now, if any.
+fn due(
+    settings: &GoalSettings,
+    progress: &GoalProgress,
+    sent: &mut Sent,
+    minute: u32,
+) -> Option<Notice> {
+    let notice = if progress.reached {
+        settings.notify_reached.then_some(Notice::Reached)?
+    } else if progress.read_ms > 0
+        && progress.minutes_left > 0
+        && progress.minutes_left <= settings.nudge_minutes
+        && !sent.nudge
+    {
+        Notice::Nudge
+    } else if settings.remind_at.is_some_and(|at| minute >= at) {
+        Notice::Reminder
+    } else {
+        return None;
+    };
+    (!*sent.flag(notice)).then_some(notice)
+}
+
+#[cfg(desktop)]
+fn tr(app: &AppHandle, key: &str) -> String {
+    crate::i18n::t(app, key)
+}
+
+#[cfg(mobile)]
+fn tr(_app: &AppHandle, key: &str) -> String {
+    key.to_string()
+}
+
+fn text(app: &AppHandle, notice: Notice, progress: &GoalProgress) -> (String, String) {
+    let (title, body) = match notice {
+        Notice::Reminder if progress.minutes_left == 0 => {
+            ("Time to Read", "{{pages}} pages left for today's goal.")
+        }
+        Notice::Reminder => ("Time to Read", "{{minutes}} min left for today's goal."),
+        Notice::Nudge => ("Almost There", "You're {{minutes}} min from today's goal."),
+        Notice::Reached => ("Daily Goal Reached", "You read {{minutes}} min today."),
+    };
+    let minutes = match notice {
+        Notice::Reached => progress.read_ms / 60_000,
+        _ => i64::from(progress.minutes_left),
+    };
+    let body = tr(app, body)
+        .replace("{{minutes}}", &minutes.to_string())
+        .replace("{{pages}}", &progress.pages_left.to_string());
+    (tr(app, title), body)
+}
+
+fn send(app: &AppHandle, notice: Notice, progress: &GoalProgress) -> Result<()> {
+    #[cfg(desktop)]
+    if app
+        .get_webview_window("main")
+        .is_some_and(|window| window.is_focused().unwrap_or(false))
+    {
+        use tauri::Emitter;
+
+        let progress = progress.clone();
+        return Ok(app.emit(NOTICE_EVENT, NoticeEvent { notice, progress })?);
+    }
+    let (title, body) = text(app, notice, progress);
+    app.notification()
+        .builder()
+        .title(title)
+        .body(body)
+        .show()?;
+    Ok(())
+}
+
+fn try_check(app: &AppHandle) -> Result<()> {
+    let settings: GoalSettings = store::load(app, SETTINGS_FILE);
+    if !settings.enabled {
+        return Ok(());
+    }
+    let state = app.state::<ReadingGoals>();
+    let _checking = state.0.lock().unwrap();
+    let offset = stats::offset_ms(settings.utc_offset_minutes);
+    let minute = ((now_millis() + offset).rem_euclid(DAY_MS) / 60_000) as u32;
+    if settings
+        .quiet_hours
+        .is_some_and(|quiet| quiet.contains(minute))
+    {
+        return Ok(());
+    }
+    let progress = progress(app, &settings)?;
+    let mut sent: Sent = store::load(app, SENT_FILE);
+    if sent.day != progress.day {
+        sent = Sent {
+            day: progress.day.clone(),
+            ..Sent::default()
+        };
+    }
+    let Some(notice) = due(&settings, &progress, &mut sent, minute) else {
+        return Ok(());
+    };
+    send(app, notice, &progress)?;
+    *sent.flag(notice) = true;
+    store::save(app, SENT_FILE, &sent)
+}
+
+/// Sends the notice that is due, if any.
+pub fn check(app: &AppHandle) {
+    if let Err(e) = try_check(app) {
+        log::warn!("Failed to check the reading goal: {e}");
+    }
+}
+
+pub fn init(app: &AppHandle) {
+    app.manage(ReadingGoals::default());
+}
+
+#[command]
+pub fn get_reading_goal(app: AppHandle) -> GoalSettings {
+    store::load(&app, SETTINGS_FILE)
+}
+
+/// Saves `settings`, asking for permission to notify when the goal is
+/// turned on.
+#[command]
+pub async fn set_reading_goal(app: AppHandle, settings: GoalSettings) -> Result<()> {
+    validate(&settings)?;
+    store::save(&app, SETTINGS_FILE, &settings)?;
+    if settings.enabled {
+        if let Err(e) = app.notification().request_permission() {
+            log::warn!("Failed to ask for permission to notify: {e}");
+        }
+        check(&app);
+    }
+    Ok(())
+}
+
+/// How far today's goal is, with the UTC offset as for the statistics,
+/// which is kept for the checks that follow.
+#[command]
+pub async fn get_goal_progress(app: AppHandle, utc_offset_minutes: i32) -> Result<GoalProgress> {
+    let mut settings: GoalSettings = store::load(&app, SETTINGS_FILE);
+    if settings.utc_offset_minutes != utc_offset_minutes {
+        settings.utc_offset_minutes = utc_offset_minutes;
+        store::save(&app, SETTINGS_FILE, &settings)?;
+    }
+    progress(&app, &settings)
+}
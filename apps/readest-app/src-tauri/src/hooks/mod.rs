@@ -0,0 +1,443 @@
+//! Programs the user has run on library events, such as a script that
+//! appends every finished book to a reading log. Each hook names a program
+//! and its arguments, with `{title}` style placeholders filled in from the
+//! event; the same values reach it as a JSON object on stdin. Programs are
+//! started directly, not through a shell, so a title cannot inject
+//! commands; wrap them in `sh -c '...' sh {title}` to use one.
+//!
+//! A hook runs only once the user has allowed its exact command line in a
+//! prompt. Editing the command asks again, and declining disables it. The
+//! approvals live in a file of their own that backups leave out, so
+//! restoring one, or copying in someone else's settings, allows nothing.
+
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tauri::{command, AppHandle, Manager, State, Wry};
+use tauri_plugin_dialog::{Dialog, DialogExt, MessageDialogButtons, MessageDialogKind};
+
+use crate::error::{Error, Result};
+use crate::library::{self, db};
+use crate::store;
+use crate::utils::{format_rfc3339, now_millis};
+
+const HOOKS_FILE: &str = "hooks.json";
+const APPROVALS_FILE: &str = "hook-approvals.json";
+/// How long a hook may run before it is killed.
+const TIMEOUT: Duration = Duration::from_secs(300);
+/// How much of what a hook writes to stderr is kept for the log.
+const STDERR_LIMIT: u64 = 16 * 1024;
+
+/// Placeholders arguments may use. Those an event has no value for, such
+/// as `{text}` for a finished book, are left empty.
+const VARIABLES: &[&str] = &[
+    "event", "time", "hash", "title", "author", "format", "path", "progress", "text", "note",
+    "cfi", "color", "style",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum HookEvent {
+    BookImported,
+    BookOpened,
+    BookFinished,
+    BookDeleted,
+    AnnotationAdded,
+}
+
+impl HookEvent {
+    fn as_str(self) -> &'static str {
+        match self {
+            HookEvent::BookImported => "bookImported",
+            HookEvent::BookOpened => "bookOpened",
+            HookEvent::BookFinished => "bookFinished",
+            HookEvent::BookDeleted => "bookDeleted",
+            HookEvent::AnnotationAdded => "annotationAdded",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Hook {
+    pub id: String,
+    pub event: HookEvent,
+    /// Path of the program, or its name to look up on `PATH`.
+    pub program: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+impl Hook {
+    /// Identifies the command line the user allowed.
+    fn fingerprint(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.program.as_bytes());
+        for arg in &self.args {
+            hasher.update([0]);
+            hasher.update(arg.as_bytes());
+        }
+        format!("{:x}", hasher.finalize())
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.program.trim().is_empty() {
+            return Err(Error::InvalidHook(format!("{} runs no program", self.id)));
+        }
+        let known = VARIABLES
+            .iter()
+            .map(|name| (*name, String::new()))
+            .collect::<Vars>();
+        for arg in &self.args {
+            expand(arg, &known).map_err(|e| Error::InvalidHook(format!("{}: {e}", self.id)))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct Stored {
+    hooks: Vec<Hook>,
+    /// Fingerprints of the command lines the user allowed, saved to
+    /// `APPROVALS_FILE`.
+    #[serde(skip)]
+    approved: Vec<String>,
+}
+
+impl Stored {
+    fn load(app: &AppHandle) -> Self {
+        Stored {
+            approved: store::load(app, APPROVALS_FILE),
+            ..store::load(app, HOOKS_FILE)
+        }
+    }
+
+    fn save(&self, app: &AppHandle) -> Result<()> {
+        store::save(app, HOOKS_FILE, self)?;
+        store::save(app, APPROVALS_FILE, &self.approved)
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HookInfo {
+    #[serde(flatten)]
+    pub hook: Hook,
+    /// Whether the user allowed the command; otherwise they are asked the
+    /// next time the event happens.
+    pub approved: bool,
+}
+
+#[derive(Default)]
+pub struct Hooks {
+    stored: Mutex<Option<Stored>>,
+    /// Held while asking the user, so a burst of events asks once.
+    prompt: Mutex<()>,
+    running: Mutex<Vec<JoinHandle<()>>>,
+}
+
+fn with_hooks<T>(app: &AppHandle, f: impl FnOnce(&mut Stored) -> T) -> T {
+    let hooks = app.state::<Hooks>();
+    let mut stored = hooks.stored.lock().unwrap();
+    f(stored.get_or_insert_with(|| Stored::load(app)))
+}
+
+/// Values of the placeholders for one event.
+type Vars = BTreeMap<&'static str, String>;
+
+/// Fills the `{name}` placeholders of `template` in from `vars`, with `{{`
+/// and `}}` for literal braces.
+fn expand(template: &str, vars: &Vars) -> std::result::Result<String, String> {
+    let mut expanded = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find(['{', '}']) {
+        expanded.push_str(&rest[..start]);
+        let brace = rest.as_bytes()[start];
+        rest = &rest[start + 1..];
+        if rest.as_bytes().first() == Some(&brace) {
+            expanded.push(char::from(brace));
+            rest = &rest[1..];
+            continue;
+        }
+        let end = match (brace, rest.find('}')) {
+            (b'{', Some(end)) => end,
+            _ => return Err(format!("unmatched {}", char::from(brace))),
+        };
+        let name = &rest[..end];
+        let value = vars
+            .get(name)
+            .ok_or_else(|| format!("unknown placeholder {{{name}}}"))?;
+        expanded.push_str(value);
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+fn book_vars(app: &AppHandle, event: HookEvent, book: &db::Book) -> Vars {
+    let progress = book
+        .progress
+        .filter(|(_, total)| *total > 0)
+        .map(|(current, total)| (100 * current / total).to_string());
+    let path = library::book_path(app, book).map(|path| path.to_string_lossy().into_owned());
+    let mut vars = VARIABLES
+        .iter()
+        .map(|name| (*name, String::new()))
+        .collect::<Vars>();
+    vars.extend([
+        ("event", event.as_str().to_string()),
+        ("time", format_rfc3339(now_millis())),
+        ("hash", book.hash.clone()),
+        ("title", book.title.clone()),
+        ("author", book.author.clone()),
+        ("format", book.format.clone()),
+        ("path", path.unwrap_or_default()),
+        ("progress", progress.unwrap_or_default()),
+    ]);
+    vars
+}
+
+/// Asks the user whether `hook` may run, once per command line.
+fn approve(app: &AppHandle, hook: &Hook) -> bool {
+    let hooks = app.state::<Hooks>();
+    let _prompt = hooks.prompt.lock().unwrap();
+    let fingerprint = hook.fingerprint();
+    let known = with_hooks(app, |stored| {
+        let current = stored
+            .hooks
+            .iter()
+            .find(|h| h.id == hook.id)
+            .is_some_and(|h| h.enabled && h.fingerprint() == fingerprint);
+        current.then(|| stored.approved.contains(&fingerprint))
+    });
+    match known {
+        Some(true) => return true,
+        // Edited, disabled or declined while this event waited
+        None => return false,
+        Some(false) => {}
+    }
+
+    let command_line = std::iter::once(&hook.program)
+        .chain(&hook.args)
+        .map(|part| format!("{part:?}"))
+        .collect::<Vec<_>>()
+        .join(" ");
+    // The command line tool has no one to ask
+    if app.try_state::<Dialog<Wry>>().is_none() {
+        log::warn!("Skipping hook {}, which was not allowed yet", hook.id);
+        return false;
+    }
+    let allowed = app
+        .dialog()
+        .message(format!(
+            "A hook wants to run this command when {}:\n\n{command_line}\n\nOnly allow \
+             commands you set up yourself.",
+            match hook.event {
+                HookEvent::BookImported => "a book is imported",
+                HookEvent::BookOpened => "a book is opened",
+                HookEvent::BookFinished => "a book is finished",
+                HookEvent::BookDeleted => "a book is deleted",
+                HookEvent::AnnotationAdded => "an annotation is added",
+            }
+        ))
+        .title("Allow hook?")
+        .kind(MessageDialogKind::Warning)
+        .buttons(MessageDialogButtons::OkCancelCustom(
+            "Allow".to_string(),
+            "Don't Allow".to_string(),
+        ))
+        .blocking_show();
+
+    let saved = with_hooks(app, |stored| {
+        if allowed {
+            stored.approved.push(fingerprint);
+        } else if let Some(h) = stored.hooks.iter_mut().find(|h| h.id == hook.id) {
+            h.enabled = false;
+        }
+        stored.save(app)
+    });
+    if let Err(e) = saved {
+        log::warn!("Failed to save hooks: {e}");
+    }
+    allowed
+}
+
+/// Runs `hook` with `vars`, waiting up to `TIMEOUT` for it to exit.
+fn run(hook: &Hook, vars: &Vars) -> Result<()> {
+    let args = hook
+        .args
+        .iter()
+        .map(|arg| expand(arg, vars))
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| Error::InvalidHook(format!("{}: {e}", hook.id)))?;
+    let mut child = Command::new(&hook.program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // Read while the hook runs, a full pipe would block it until the timeout
+    let stderr = child.stderr.take().map(|mut pipe| {
+        std::thread::spawn(move || {
+            let mut stderr = Vec::new();
+            let _ = (&mut pipe).take(STDERR_LIMIT).read_to_end(&mut stderr);
+            let _ = std::io::copy(&mut pipe, &mut std::io::sink());
+            String::from_utf8_lossy(&stderr).into_owned()
+        })
+    });
+    if let Some(mut stdin) = child.stdin.take() {
+        // Programs that ignore stdin close it early
+        let _ = stdin.write_all(&serde_json::to_vec(vars)?);
+    }
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if started.elapsed() > TIMEOUT {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(Error::Hook(format!("{} timed out", hook.id)));
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    };
+    if !status.success() {
+        let stderr = stderr
+            .and_then(|thread| thread.join().ok())
+            .unwrap_or_default();
+        return Err(Error::Hook(format!(
+            "{} exited with {status}: {}",
+            hook.id,
+            stderr.trim()
+        )));
+    }
+    Ok(())
+}
+
+fn hooks_for(app: &AppHandle, event: HookEvent) -> Vec<Hook> {
+    with_hooks(app, |stored| {
+        stored
+            .hooks
+            .iter()
+            .filter(|hook| hook.enabled && hook.event == event)
+            .cloned()
+            .collect()
+    })
+}
+
+/// Runs `hooks` on a thread of their own, one after another, so slow
+/// scripts hold up neither the caller nor each other.
+fn fire(app: &AppHandle, hooks: Vec<Hook>, vars: Vars) {
+    let handle = app.clone();
+    let thread = std::thread::spawn(move || {
+        for hook in hooks {
+            if !approve(&handle, &hook) {
+                continue;
+            }
+            if let Err(e) = run(&hook, &vars) {
+                log::warn!("Hook {} failed: {e}", hook.id);
+            }
+        }
+    });
+    let state = app.state::<Hooks>();
+    let mut running = state.running.lock().unwrap();
+    running.retain(|thread| !thread.is_finished());
+    running.push(thread);
+}
+
+/// Waits for the hooks still running, before the process exits.
+#[cfg(feature = "cli")]
+pub fn wait(app: &AppHandle) {
+    let running = std::mem::take(&mut *app.state::<Hooks>().running.lock().unwrap());
+    for thread in running {
+        let _ = thread.join();
+    }
+}
+
+/// Runs the hooks of `event` for `book`.
+pub fn book_event(app: &AppHandle, event: HookEvent, book: &db::Book) {
+    let hooks = hooks_for(app, event);
+    if !hooks.is_empty() {
+        fire(app, hooks, book_vars(app, event, book));
+    }
+}
+
+/// Runs the `annotationAdded` hooks for `note`, a booknote of `book`.
+pub fn annotation_added(app: &AppHandle, book: &db::Book, note: &Value) {
+    let event = HookEvent::AnnotationAdded;
+    let hooks = hooks_for(app, event);
+    if hooks.is_empty() {
+        return;
+    }
+    let mut vars = book_vars(app, event, book);
+    for name in ["text", "note", "cfi", "color", "style"] {
+        if let Some(value) = note[name].as_str() {
+            vars.insert(name, value.to_string());
+        }
+    }
+    fire(app, hooks, vars);
+}
+
+#[command]
+pub async fn get_hooks(app: AppHandle) -> Result<Vec<HookInfo>> {
+    Ok(with_hooks(&app, |stored| {
+        stored
+            .hooks
+            .iter()
+            .map(|hook| HookInfo {
+                hook: hook.clone(),
+                approved: stored.approved.contains(&hook.fingerprint()),
+            })
+            .collect()
+    }))
+}
+
+/// Replaces the hooks with `hooks`. Approvals of command lines no hook
+/// runs any more are forgotten.
+#[command]
+pub async fn set_hooks(app: AppHandle, hooks: Vec<Hook>) -> Result<()> {
+    for hook in &hooks {
+        hook.validate()?;
+    }
+    with_hooks(&app, |stored| {
+        stored
+            .approved
+            .retain(|fingerprint| hooks.iter().any(|h| h.fingerprint() == *fingerprint));
+        stored.hooks = hooks;
+        stored.save(&app)
+    })
+}
+
+/// Runs the hooks of an event only the frontend sees, such as a book being
+/// opened or an annotation made in the reader, which comes as `annotation`.
+#[command]
+pub async fn trigger_hooks(
+    app: AppHandle,
+    db: State<'_, db::LibraryDb>,
+    event: HookEvent,
+    book_hash: String,
+    annotation: Option<Value>,
+) -> Result<()> {
+    let book = db::get_book(&db.conn(), &book_hash)?
+        .ok_or_else(|| Error::InvalidBook(format!("no book {book_hash}")))?;
+    match annotation {
+        Some(note) if event == HookEvent::AnnotationAdded => annotation_added(&app, &book, &note),
+        _ => book_event(&app, event, &book),
+    }
+    Ok(())
+}
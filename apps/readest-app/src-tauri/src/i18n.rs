@@ -0,0 +1,113 @@
+//! Strings of the native parts of the app, the menu bar and the tray, in
+//! the language the frontend shows. They come from the bundled frontend's
+//! `locales/<lang>/native.json`, beside its own translations and keyed by
+//! the English text like those, so a missing string stays English. The
+//! frontend reports its language, which is kept for the next start.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle, Manager};
+
+use crate::error::Result;
+use crate::store;
+
+const SETTINGS_FILE: &str = "locale.json";
+const NAMESPACE: &str = "native";
+/// Languages shown in another one, as the frontend falls back.
+const FALLBACKS: &[(&str, &str)] = &[
+    ("zh", "zh-CN"),
+    ("zh-HK", "zh-TW"),
+    ("kk", "ru"),
+    ("ky", "ru"),
+    ("tk", "ru"),
+    ("uz", "ru"),
+    ("ug", "ru"),
+    ("tt", "ru"),
+];
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct Settings {
+    locale: Option<String>,
+}
+
+struct Catalog {
+    app_name: String,
+    strings: HashMap<String, String>,
+}
+
+#[derive(Default)]
+pub struct Localization(Mutex<Option<Catalog>>);
+
+/// Locales to look for the strings of `locale` in, best first.
+fn candidates(locale: &str) -> Vec<String> {
+    let locale = locale.replace('_', "-");
+    let mut candidates = vec![locale.clone()];
+    if let Some((language, _)) = locale.split_once('-') {
+        candidates.push(language.to_string());
+    }
+    for candidate in candidates.clone() {
+        if let Some((_, fallback)) = FALLBACKS.iter().find(|(from, _)| *from == candidate) {
+            candidates.push(fallback.to_string());
+        }
+    }
+    candidates
+}
+
+fn load_strings(app: &AppHandle, locale: &str) -> HashMap<String, String> {
+    let resolver = app.asset_resolver();
+    for candidate in candidates(locale) {
+        let Some(asset) = resolver.get(format!("locales/{candidate}/{NAMESPACE}.json")) else {
+            continue;
+        };
+        match serde_json::from_slice(&asset.bytes) {
+            Ok(strings) => return strings,
+            // Missing assets resolve to a page of the frontend
+            Err(e) => log::debug!("No {NAMESPACE} strings for {candidate}: {e}"),
+        }
+    }
+    HashMap::new()
+}
+
+fn load_catalog(app: &AppHandle) -> Catalog {
+    let locale = store::load::<Settings>(app, SETTINGS_FILE)
+        .locale
+        .or_else(tauri_plugin_os::locale)
+        .unwrap_or_else(|| "en".into());
+    Catalog {
+        app_name: app.package_info().name.clone(),
+        strings: load_strings(app, &locale),
+    }
+}
+
+/// `key` in the current language, with `{{name}}` standing for the name of
+/// the app.
+pub fn t(app: &AppHandle, key: &str) -> String {
+    let localization = app.state::<Localization>();
+    let mut catalog = localization.0.lock().unwrap();
+    let catalog = catalog.get_or_insert_with(|| load_catalog(app));
+    catalog
+        .strings
+        .get(key)
+        .map_or(key, String::as_str)
+        .replace("{{name}}", &catalog.app_name)
+}
+
+/// Switches the native strings to `locale`, as the frontend does on a
+/// change of language, and rebuilds the menus with them.
+#[command]
+pub async fn set_locale(app: AppHandle, locale: String) -> Result<()> {
+    store::save(
+        &app,
+        SETTINGS_FILE,
+        &Settings {
+            locale: Some(locale),
+        },
+    )?;
+    app.state::<Localization>().0.lock().unwrap().take();
+    #[cfg(target_os = "macos")]
+    crate::macos::menu::rebuild(&app)?;
+    tauri::async_runtime::spawn_blocking(move || crate::recent::refresh(&app)).await?
+}
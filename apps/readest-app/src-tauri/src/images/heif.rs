@@ -0,0 +1,171 @@
+//! HEIC and AVIF decoding through the C API of libheif, with whichever of
+//! its decoders, libde265 and dav1d or libaom, the system has.
+
+use std::ffi::CStr;
+use std::os::raw::c_int;
+
+use image::{DynamicImage, RgbImage, RgbaImage};
+
+use crate::error::{Error, Result};
+
+#[allow(non_camel_case_types)]
+mod ffi {
+    use std::os::raw::{c_char, c_int, c_void};
+
+    pub enum heif_context {}
+    pub enum heif_image_handle {}
+    pub enum heif_image {}
+
+    #[repr(C)]
+    pub struct heif_error {
+        pub code: c_int,
+        pub subcode: c_int,
+        pub message: *const c_char,
+    }
+
+    /// `heif_colorspace_RGB`.
+    pub const COLORSPACE_RGB: c_int = 1;
+    /// `heif_chroma_interleaved_RGB`.
+    pub const CHROMA_INTERLEAVED_RGB: c_int = 10;
+    /// `heif_chroma_interleaved_RGBA`.
+    pub const CHROMA_INTERLEAVED_RGBA: c_int = 11;
+    /// `heif_channel_interleaved`.
+    pub const CHANNEL_INTERLEAVED: c_int = 10;
+
+    #[link(name = "heif")]
+    extern "C" {
+        pub fn heif_context_alloc() -> *mut heif_context;
+        pub fn heif_context_free(context: *mut heif_context);
+        pub fn heif_context_read_from_memory_without_copy(
+            context: *mut heif_context,
+            memory: *const c_void,
+            size: usize,
+            options: *const c_void,
+        ) -> heif_error;
+        pub fn heif_context_get_primary_image_handle(
+            context: *mut heif_context,
+            handle: *mut *mut heif_image_handle,
+        ) -> heif_error;
+        pub fn heif_image_handle_has_alpha_channel(handle: *const heif_image_handle) -> c_int;
+        pub fn heif_image_handle_release(handle: *const heif_image_handle);
+        pub fn heif_decode_image(
+            handle: *const heif_image_handle,
+            image: *mut *mut heif_image,
+            colorspace: c_int,
+            chroma: c_int,
+            options: *const c_void,
+        ) -> heif_error;
+        pub fn heif_image_get_width(image: *const heif_image, channel: c_int) -> c_int;
+        pub fn heif_image_get_height(image: *const heif_image, channel: c_int) -> c_int;
+        pub fn heif_image_get_plane_readonly(
+            image: *const heif_image,
+            channel: c_int,
+            stride: *mut c_int,
+        ) -> *const u8;
+        pub fn heif_image_release(image: *const heif_image);
+    }
+}
+
+fn check(error: ffi::heif_error) -> Result<()> {
+    if error.code == 0 {
+        return Ok(());
+    }
+    let message = if error.message.is_null() {
+        format!("error {}", error.code)
+    } else {
+        unsafe { CStr::from_ptr(error.message) }
+            .to_string_lossy()
+            .into_owned()
+    };
+    Err(Error::Heif(message))
+}
+
+/// Frees what libheif allocated as decoding goes, however far it went.
+struct Decoding {
+    context: *mut ffi::heif_context,
+    handle: *mut ffi::heif_image_handle,
+    image: *mut ffi::heif_image,
+}
+
+impl Drop for Decoding {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.image.is_null() {
+                ffi::heif_image_release(self.image);
+            }
+            if !self.handle.is_null() {
+                ffi::heif_image_handle_release(self.handle);
+            }
+            ffi::heif_context_free(self.context);
+        }
+    }
+}
+
+/// The primary image of a HEIF file, such as a HEIC photo or an AVIF.
+pub fn decode(data: &[u8]) -> Result<DynamicImage> {
+    let context = unsafe { ffi::heif_context_alloc() };
+    if context.is_null() {
+        return Err(Error::Heif("cannot create a context".to_string()));
+    }
+    let mut decoding = Decoding {
+        context,
+        handle: std::ptr::null_mut(),
+        image: std::ptr::null_mut(),
+    };
+    let (width, height, stride, alpha, pixels) = unsafe {
+        check(ffi::heif_context_read_from_memory_without_copy(
+            context,
+            data.as_ptr().cast(),
+            data.len(),
+            std::ptr::null(),
+        ))?;
+        check(ffi::heif_context_get_primary_image_handle(
+            context,
+            &mut decoding.handle,
+        ))?;
+        let alpha = ffi::heif_image_handle_has_alpha_channel(decoding.handle) != 0;
+        let chroma = if alpha {
+            ffi::CHROMA_INTERLEAVED_RGBA
+        } else {
+            ffi::CHROMA_INTERLEAVED_RGB
+        };
+        check(ffi::heif_decode_image(
+            decoding.handle,
+            &mut decoding.image,
+            ffi::COLORSPACE_RGB,
+            chroma,
+            std::ptr::null(),
+        ))?;
+        let width = ffi::heif_image_get_width(decoding.image, ffi::CHANNEL_INTERLEAVED);
+        let height = ffi::heif_image_get_height(decoding.image, ffi::CHANNEL_INTERLEAVED);
+        let mut stride: c_int = 0;
+        let pixels = ffi::heif_image_get_plane_readonly(
+            decoding.image,
+            ffi::CHANNEL_INTERLEAVED,
+            &mut stride,
+        );
+        (width, height, stride, alpha, pixels)
+    };
+    if pixels.is_null() || width <= 0 || height <= 0 {
+        return Err(Error::Heif("no image".to_string()));
+    }
+    let (width, height, stride) = (width as usize, height as usize, stride as usize);
+    let channels = if alpha { 4 } else { 3 };
+    let row = width * channels;
+    if stride < row {
+        return Err(Error::Heif("invalid image plane".to_string()));
+    }
+    let plane = unsafe { std::slice::from_raw_parts(pixels, stride * height) };
+    let mut buffer = Vec::with_capacity(row * height);
+    for y in 0..height {
+        buffer.extend_from_slice(&plane[y * stride..y * stride + row]);
+    }
+    drop(decoding);
+    let (width, height) = (width as u32, height as u32);
+    let invalid = || Error::Heif("invalid image size".to_string());
+    Ok(if alpha {
+        DynamicImage::ImageRgba8(RgbaImage::from_raw(width, height, buffer).ok_or_else(invalid)?)
+    } else {
+        DynamicImage::ImageRgb8(RgbImage::from_raw(width, height, buffer).ok_or_else(invalid)?)
+    })
+}
@@ -0,0 +1,241 @@
+//! Images of books as they are served over the `book://` protocol: those
+//! in formats webviews cannot show, JPEG XL and, with the `heif` feature,
+//! HEIC and AVIF, are converted, and those much larger than the window
+//! are scaled down to it, so that image-heavy books neither show broken
+//! images nor hold pictures of tens of megapixels in memory. Results are
+//! cached in the app cache dir, the least recently made deleted first.
+//!
+//! GIFs are served as they are, to keep their animation.
+
+#[cfg(feature = "heif")]
+mod heif;
+
+use std::io::Cursor;
+use std::path::Path;
+use std::sync::Mutex;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat, ImageReader};
+use sha2::{Digest, Sha256};
+use tauri::{command, AppHandle, Manager, State};
+
+use crate::error::Result;
+use crate::paths;
+
+const CACHE_DIR: &str = "book-images";
+/// Bytes the cached images may take.
+const CACHE_LIMIT: u64 = 256 * 1024 * 1024;
+const JPEG_QUALITY: u8 = 88;
+/// How much larger than the window images are kept, for zooming in.
+const ZOOM_HEADROOM: f64 = 1.5;
+/// Limits are rounded up to multiples of this, so that resizing the
+/// window a little reuses the cached images.
+const LIMIT_STEP: u32 = 256;
+/// Formats the webview shows, which are left alone unless too large.
+const SHOWN_FORMATS: &[ImageFormat] = &[
+    ImageFormat::Jpeg,
+    ImageFormat::Png,
+    ImageFormat::WebP,
+    ImageFormat::Bmp,
+];
+
+/// The size images are fit in, in physical pixels, as the reader last
+/// told it; the largest window when it did not.
+#[derive(Default)]
+pub struct ImageViewport(Mutex<Option<(u32, u32)>>);
+
+impl ImageViewport {
+    fn limit(&self, app: &AppHandle) -> Option<(u32, u32)> {
+        let viewport = (*self.0.lock().unwrap()).or_else(|| {
+            app.webview_windows()
+                .values()
+                .filter_map(|window| window.inner_size().ok())
+                .map(|size| (size.width, size.height))
+                .max_by_key(|(width, height)| *width as u64 * *height as u64)
+        })?;
+        let step = |size: u32| {
+            let size = (size as f64 * ZOOM_HEADROOM).ceil() as u32;
+            size.div_ceil(LIMIT_STEP).max(1) * LIMIT_STEP
+        };
+        Some((step(viewport.0), step(viewport.1)))
+    }
+}
+
+/// Formats that are converted whatever their size.
+fn unshown_format(name: &str, data: &[u8]) -> Option<&'static str> {
+    let extension = name
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_ascii_lowercase())
+        .unwrap_or_default();
+    let brand = data
+        .get(4..8)
+        .filter(|boxed| *boxed == b"ftyp")
+        .and_then(|_| data.get(8..12));
+    if extension == "jxl" || data.starts_with(&[0xff, 0x0a]) || data.get(4..8) == Some(b"JXL ") {
+        return Some("jxl");
+    }
+    if matches!(extension.as_str(), "heic" | "heif")
+        || matches!(brand, Some(b"heic" | b"heix" | b"mif1" | b"msf1"))
+    {
+        return Some("heif");
+    }
+    if extension == "avif" || matches!(brand, Some(b"avif" | b"avis")) {
+        return Some("avif");
+    }
+    None
+}
+
+fn decode(format: &str, data: &[u8]) -> Result<Option<DynamicImage>> {
+    match format {
+        "jxl" => {
+            let decoder = jxl_oxide::integration::JxlDecoder::new(Cursor::new(data))?;
+            Ok(Some(DynamicImage::from_decoder(decoder)?))
+        }
+        #[cfg(feature = "heif")]
+        "heif" | "avif" => heif::decode(data).map(Some),
+        // AVIF that the webview shows itself without libheif
+        _ => Ok(None),
+    }
+}
+
+/// `image` encoded as JPEG, or PNG when it is not opaque.
+fn encode(image: &DynamicImage) -> Result<(Vec<u8>, &'static str)> {
+    let mut out = Cursor::new(Vec::new());
+    if image.color().has_alpha() {
+        image.write_to(&mut out, ImageFormat::Png)?;
+        Ok((out.into_inner(), "image/png"))
+    } else {
+        image
+            .to_rgb8()
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut out, JPEG_QUALITY))?;
+        Ok((out.into_inner(), "image/jpeg"))
+    }
+}
+
+fn fits((width, height): (u32, u32), (max_width, max_height): (u32, u32)) -> bool {
+    width <= max_width && height <= max_height
+}
+
+fn cache_key(book: &Path, name: &str, size: usize, limit: Option<(u32, u32)>) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(book.to_string_lossy().as_bytes());
+    hasher.update([0]);
+    hasher.update(name.as_bytes());
+    hasher.update(size.to_le_bytes());
+    if let Some((width, height)) = limit {
+        hasher.update(width.to_le_bytes());
+        hasher.update(height.to_le_bytes());
+    }
+    format!("{:x}", hasher.finalize())[..24].to_string()
+}
+
+fn cached(dir: &Path, key: &str) -> Option<(Vec<u8>, &'static str)> {
+    [("jpg", "image/jpeg"), ("png", "image/png")]
+        .into_iter()
+        .find_map(|(extension, mime)| {
+            let data = std::fs::read(dir.join(format!("{key}.{extension}"))).ok()?;
+            Some((data, mime))
+        })
+}
+
+/// Deletes the oldest cached images until they fit the limit.
+fn evict(dir: &Path) -> Result<()> {
+    let mut files = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            Some((metadata.modified().ok()?, metadata.len(), entry.path()))
+        })
+        .collect::<Vec<_>>();
+    let mut used = files.iter().map(|(_, size, _)| size).sum::<u64>();
+    files.sort();
+    for (_, size, path) in files {
+        if used <= CACHE_LIMIT {
+            break;
+        }
+        std::fs::remove_file(&path)?;
+        used -= size;
+    }
+    Ok(())
+}
+
+fn store(dir: &Path, key: &str, data: &[u8], mime: &str) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let extension = if mime == "image/png" { "png" } else { "jpg" };
+    let target = dir.join(format!("{key}.{extension}"));
+    // Write then rename so concurrent requests never see a partial file
+    let tmp = target.with_extension("tmp");
+    std::fs::write(&tmp, data)?;
+    std::fs::rename(&tmp, &target)?;
+    evict(dir)
+}
+
+/// Image `name` of the book at `book`, `data`, made fit for the webview,
+/// with its content type; `None` when it is served as it is.
+pub fn prepare(
+    app: &AppHandle,
+    book: &Path,
+    name: &str,
+    data: &[u8],
+) -> Result<Option<(Vec<u8>, &'static str)>> {
+    let unshown = unshown_format(name, data);
+    let limit = app.state::<ImageViewport>().limit(app);
+    if unshown.is_none() {
+        let reader = ImageReader::new(Cursor::new(data)).with_guessed_format()?;
+        if !reader.format().is_some_and(|f| SHOWN_FORMATS.contains(&f)) {
+            return Ok(None);
+        }
+        match limit {
+            Some(limit) if !fits(reader.into_dimensions()?, limit) => {}
+            _ => return Ok(None),
+        }
+    }
+
+    let dir = paths::cache_dir(app)?.join(CACHE_DIR);
+    let key = cache_key(book, name, data.len(), limit);
+    if let Some(hit) = cached(&dir, &key) {
+        return Ok(Some(hit));
+    }
+    let image = match unshown {
+        Some(format) => match decode(format, data)? {
+            Some(image) => image,
+            None => return Ok(None),
+        },
+        None => image::load_from_memory(data)?,
+    };
+    let image = match limit {
+        Some(limit) if !fits((image.width(), image.height()), limit) => {
+            image.resize(limit.0, limit.1, FilterType::CatmullRom)
+        }
+        _ => image,
+    };
+    let (data, mime) = encode(&image)?;
+    store(&dir, &key, &data, mime)?;
+    Ok(Some((data, mime)))
+}
+
+/// Sets the size images of books are fit in, in physical pixels, as the
+/// reading area changes, or goes back to the largest window with 0.
+#[command]
+pub fn set_image_viewport(viewport: State<'_, ImageViewport>, width: u32, height: u32) {
+    *viewport.0.lock().unwrap() = (width > 0 && height > 0).then_some((width, height));
+}
+
+/// Deletes the cached images of books, returning the bytes freed.
+#[command]
+pub async fn clear_image_cache(app: AppHandle) -> Result<u64> {
+    let dir = paths::cache_dir(&app)?.join(CACHE_DIR);
+    let freed = std::fs::read_dir(&dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok()?.metadata().ok())
+                .map(|metadata| metadata.len())
+                .sum()
+        })
+        .unwrap_or(0);
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir)?;
+    }
+    Ok(freed)
+}
@@ -0,0 +1,562 @@
+//! Highlights and notes made on Kindle and Kobo e-readers, added to the
+//! per-book configs of the matching library books. Kindles append them to
+//! `documents/My Clippings.txt` and Kobos keep them in the `Bookmark` table
+//! of `.kobo/KoboReader.sqlite`.
+//!
+//! Books are matched by their file when the device copy can be read, and
+//! by title and author otherwise. Neither device records a position the
+//! reader understands, so each highlight is found again by its text in the
+//! EPUB and saved with a range CFI around it.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use rusqlite::{Connection, OpenFlags};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tauri::{command, AppHandle, Manager};
+
+use crate::error::{Error, Result};
+use crate::formats::epub::cfi::{normalize, DocumentText};
+use crate::formats::epub::EpubArchive;
+use crate::hooks;
+use crate::library::{self, db};
+use crate::secrets::storage;
+use crate::utils::{now_millis, parse_rfc3339, similarity, title_words, words};
+
+const KINDLE_CLIPPINGS: &str = "documents/My Clippings.txt";
+const KINDLE_SEPARATOR: &str = "==========";
+const KOBO_DATABASE: &str = ".kobo/KoboReader.sqlite";
+/// Where Kobos mount their storage, which the paths in the database start with.
+const KOBO_MOUNT: &str = "file:///mnt/onboard/";
+
+/// Words the Kindle uses for notes and bookmarks, in the languages it
+/// supports. Everything else is a highlight.
+const KINDLE_NOTE_WORDS: &[&str] = &["note", "notiz", "nota"];
+const KINDLE_BOOKMARK_WORDS: &[&str] = &[
+    "bookmark",
+    "lesezeichen",
+    "signet",
+    "marcador",
+    "segnalibro",
+];
+const KINDLE_LOCATION_WORDS: &[&str] = &[
+    "location",
+    "position",
+    "posición",
+    "emplacement",
+    "posizione",
+    "pos.",
+];
+const MONTHS: [&str; 12] = [
+    "january",
+    "february",
+    "march",
+    "april",
+    "may",
+    "june",
+    "july",
+    "august",
+    "september",
+    "october",
+    "november",
+    "december",
+];
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AnnotationSource {
+    Kindle,
+    Kobo,
+}
+
+/// A highlight as read from the device.
+#[derive(Debug, Clone)]
+struct Clipping {
+    title: String,
+    author: String,
+    /// Hash of the device copy of the book, when it could be read.
+    book_hash: Option<String>,
+    text: String,
+    note: String,
+    /// Kindle location range, which ties notes to their highlight.
+    location: Option<(u32, u32)>,
+    /// Path in the EPUB of the document the highlight is in, from Kobos.
+    document: Option<String>,
+    created_at: i64,
+    updated_at: i64,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AnnotationImport {
+    pub imported: usize,
+    /// Highlights the book already had, from an earlier import.
+    pub duplicates: usize,
+    /// Highlights whose text was not found in the book, or whose book is
+    /// not an EPUB.
+    pub unplaced: usize,
+    /// Titles on the device with no matching book in the library.
+    pub unmatched: Vec<String>,
+}
+
+/// Title and author of a Kindle clipping header, `Title (Author)`. Titles
+/// may have parentheses of their own, so the author is in the last ones.
+fn split_title_author(header: &str) -> (String, String) {
+    if let Some(inner) = header.strip_suffix(')') {
+        let mut depth = 0;
+        for (i, c) in inner.char_indices().rev() {
+            match c {
+                ')' => depth += 1,
+                '(' if depth == 0 => {
+                    return (
+                        inner[..i].trim().to_string(),
+                        inner[i + 1..].trim().to_string(),
+                    );
+                }
+                '(' => depth -= 1,
+                _ => {}
+            }
+        }
+    }
+    (header.trim().to_string(), String::new())
+}
+
+/// The location range in the info line of a clipping, such as
+/// `- Your Highlight on page 5 | Location 70-72 | Added on ...`.
+fn kindle_location(info: &str) -> Option<(u32, u32)> {
+    let segment = info.split('|').find(|segment| {
+        let segment = segment.to_lowercase();
+        KINDLE_LOCATION_WORDS
+            .iter()
+            .any(|word| segment.contains(word))
+    })?;
+    let digits = segment
+        .chars()
+        .skip_while(|c| !c.is_ascii_digit())
+        .take_while(|c| c.is_ascii_digit() || *c == '-')
+        .collect::<String>();
+    let mut bounds = digits.split('-').filter_map(|n| n.parse().ok());
+    let start = bounds.next()?;
+    Some((start, bounds.next().unwrap_or(start)))
+}
+
+/// The date of an English clipping, `Added on Monday, March 4, 2024
+/// 10:00:00 PM`. Kindles write it in local time, which is taken as UTC.
+fn kindle_date(info: &str) -> Option<i64> {
+    let segment = info.rsplit('|').next()?.to_lowercase();
+    let tokens = segment
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|token| !token.is_empty())
+        .collect::<Vec<_>>();
+    let month_at = tokens.iter().position(|token| MONTHS.contains(token))?;
+    let month = MONTHS.iter().position(|month| *month == tokens[month_at])? + 1;
+    let day: u32 = tokens.get(month_at + 1)?.parse().ok()?;
+    let year: u32 = tokens.get(month_at + 2)?.parse().ok()?;
+    let time = tokens.get(month_at + 3).copied().unwrap_or("0:00:00");
+    let mut parts = time
+        .split(':')
+        .map(|part| part.parse::<u32>().unwrap_or_default());
+    let (mut hour, minute, second) = (
+        parts.next().unwrap_or_default(),
+        parts.next().unwrap_or_default(),
+        parts.next().unwrap_or_default(),
+    );
+    match tokens.get(month_at + 4).copied() {
+        Some("pm") if hour < 12 => hour += 12,
+        Some("am") if hour == 12 => hour = 0,
+        _ => {}
+    }
+    parse_rfc3339(&format!(
+        "{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}Z"
+    ))
+}
+
+fn overlaps(a: Option<(u32, u32)>, b: Option<(u32, u32)>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a.0 <= b.1 && b.0 <= a.1,
+        _ => false,
+    }
+}
+
+/// Parses `My Clippings.txt`. A highlight that was changed on the device
+/// appears once for every version, so only the longest is kept, and notes
+/// are attached to the highlight they were made on.
+fn parse_kindle(text: &str) -> Vec<Clipping> {
+    let now = now_millis();
+    let mut highlights: Vec<Clipping> = Vec::new();
+    let mut notes = Vec::new();
+    for entry in text.split(KINDLE_SEPARATOR) {
+        let mut lines = entry
+            .lines()
+            .map(|line| line.trim_start_matches('\u{feff}').trim())
+            .skip_while(|line| line.is_empty());
+        let (Some(header), Some(info)) = (lines.next(), lines.next()) else {
+            continue;
+        };
+        let body = lines.collect::<Vec<_>>().join("\n").trim().to_string();
+        let kind = info.split('|').next().unwrap_or_default().to_lowercase();
+        if body.is_empty() || KINDLE_BOOKMARK_WORDS.iter().any(|word| kind.contains(word)) {
+            continue;
+        }
+        let (title, author) = split_title_author(header);
+        let created_at = kindle_date(info).unwrap_or(now);
+        let clipping = Clipping {
+            title,
+            author,
+            book_hash: None,
+            text: body,
+            note: String::new(),
+            location: kindle_location(info),
+            document: None,
+            created_at,
+            updated_at: created_at,
+        };
+        if KINDLE_NOTE_WORDS.iter().any(|word| kind.contains(word)) {
+            notes.push(clipping);
+            continue;
+        }
+        highlights.retain(|earlier| {
+            !(earlier.title == clipping.title
+                && overlaps(earlier.location, clipping.location)
+                && clipping.text.contains(&earlier.text))
+        });
+        let replaced = highlights.iter().any(|later| {
+            later.title == clipping.title
+                && overlaps(later.location, clipping.location)
+                && later.text.contains(&clipping.text)
+        });
+        if !replaced {
+            highlights.push(clipping);
+        }
+    }
+
+    // A note is at the end of the highlight it belongs to
+    for note in notes {
+        let at = note.location.map(|(start, _)| start);
+        let highlight = highlights.iter_mut().rev().find(|highlight| {
+            highlight.title == note.title
+                && highlight
+                    .location
+                    .zip(at)
+                    .is_some_and(|((start, end), at)| start <= at && at <= end)
+        });
+        match highlight {
+            Some(highlight) => {
+                highlight.note = note.text;
+                highlight.updated_at = highlight.updated_at.max(note.created_at);
+            }
+            // Notes made without a highlight have no text to be found by
+            None => log::info!("Skipping a note without a highlight in {}", note.title),
+        }
+    }
+    highlights
+}
+
+/// Path in the EPUB of the document a Kobo highlight is in, from its
+/// content id: `file:///mnt/onboard/book.epub#(3)OEBPS/ch03.xhtml` for
+/// sideloaded EPUBs and `<volume>!OEBPS!ch03.xhtml` for kepubs.
+fn kobo_document(content_id: &str, volume_id: &str) -> Option<String> {
+    let rest = content_id.strip_prefix(volume_id).unwrap_or(content_id);
+    let path = match rest.split_once('#') {
+        Some((_, fragment)) => fragment.split_once(')').map_or(fragment, |(_, path)| path),
+        None => rest.trim_start_matches('!'),
+    };
+    let path = path.replace('!', "/");
+    let path = path.split('#').next().unwrap_or_default();
+    (!path.is_empty()).then(|| path.to_string())
+}
+
+/// Reads the highlights from a Kobo database. When the device is mounted
+/// at `root`, sideloaded books are hashed so they match exactly.
+fn read_kobo(database: &Path, root: Option<&Path>) -> Result<Vec<Clipping>> {
+    let conn = Connection::open_with_flags(database, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut stmt = conn.prepare(
+        "SELECT b.ContentID, b.VolumeID, b.Text, b.Annotation, b.DateCreated, b.DateModified, \
+         c.Title, c.Attribution \
+         FROM Bookmark b JOIN content c ON c.ContentID = b.VolumeID \
+         WHERE b.Text IS NOT NULL AND TRIM(b.Text) <> '' \
+         ORDER BY b.VolumeID, b.DateCreated",
+    )?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, Option<String>>(3)?,
+                row.get::<_, Option<String>>(4)?,
+                row.get::<_, Option<String>>(5)?,
+                row.get::<_, Option<String>>(6)?,
+                row.get::<_, Option<String>>(7)?,
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let now = now_millis();
+    let mut hashes: HashMap<String, Option<String>> = HashMap::new();
+    let mut clippings = Vec::with_capacity(rows.len());
+    for (content_id, volume_id, text, note, created, modified, title, author) in rows {
+        let book_hash = hashes
+            .entry(volume_id.clone())
+            .or_insert_with(|| {
+                let relative = volume_id.strip_prefix(KOBO_MOUNT)?;
+                let path = root?.join(
+                    percent_encoding::percent_decode_str(relative)
+                        .decode_utf8_lossy()
+                        .as_ref(),
+                );
+                library::partial_md5(&path).ok()
+            })
+            .clone();
+        let created_at = created.as_deref().and_then(parse_rfc3339).unwrap_or(now);
+        clippings.push(Clipping {
+            title: title.unwrap_or_default(),
+            author: author.unwrap_or_default(),
+            book_hash,
+            text: text.trim().to_string(),
+            note: note.unwrap_or_default().trim().to_string(),
+            location: None,
+            document: kobo_document(&content_id, &volume_id),
+            created_at,
+            updated_at: modified
+                .as_deref()
+                .and_then(parse_rfc3339)
+                .unwrap_or(created_at),
+        });
+    }
+    Ok(clippings)
+}
+
+/// Reads the highlights at `path`, which is either the export file or the
+/// root of the mounted device.
+fn read_source(source: AnnotationSource, path: &Path) -> Result<Vec<Clipping>> {
+    match source {
+        AnnotationSource::Kindle => {
+            let file = match path.is_dir() {
+                true => path.join(KINDLE_CLIPPINGS),
+                false => path.to_path_buf(),
+            };
+            let bytes = std::fs::read(&file)?;
+            Ok(parse_kindle(&String::from_utf8_lossy(&bytes)))
+        }
+        AnnotationSource::Kobo => {
+            let (database, root) = match path.is_dir() {
+                true => (path.join(KOBO_DATABASE), Some(path.to_path_buf())),
+                // The database is in `.kobo` at the root of the device
+                false => (
+                    path.to_path_buf(),
+                    path.parent()
+                        .filter(|dir| dir.ends_with(".kobo"))
+                        .and_then(Path::parent)
+                        .map(PathBuf::from),
+                ),
+            };
+            read_kobo(&database, root.as_deref()).map_err(|e| match e {
+                Error::Sqlite(e) => Error::InvalidAnnotations(e.to_string()),
+                e => e,
+            })
+        }
+    }
+}
+
+/// The library book a clipping was made in: the same file when it could
+/// be hashed, or the book most alike in title, backed by the author.
+fn match_book<'a>(clipping: &Clipping, books: &'a [db::Book]) -> Option<&'a db::Book> {
+    if let Some(book) = clipping
+        .book_hash
+        .as_ref()
+        .and_then(|hash| books.iter().find(|book| &book.hash == hash))
+    {
+        return Some(book);
+    }
+    let title = title_words(&clipping.title);
+    let author = words(&clipping.author);
+    books
+        .iter()
+        .filter_map(|book| {
+            let title_score = similarity(&title, &title_words(&book.title))
+                .max(similarity(&words(&clipping.title), &words(&book.title)));
+            let author_score = similarity(&author, &words(&book.author));
+            let matches = title_score >= 0.8 || (title_score >= 0.6 && author_score >= 0.5);
+            matches.then_some((book, title_score + author_score / 4.0))
+        })
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(book, _)| book)
+}
+
+/// The spine documents of an EPUB by their index in the spine, with
+/// their paths in the archive.
+fn epub_documents(path: &Path) -> Result<Vec<(usize, String, DocumentText)>> {
+    let mut epub = EpubArchive::open(path)?;
+    let package = epub.package()?;
+    let mut documents = Vec::new();
+    for (index, idref) in package.spine.iter().enumerate() {
+        let Some(item) = package
+            .item(idref)
+            .filter(|item| item.media_type.contains("html"))
+        else {
+            continue;
+        };
+        let Ok(xhtml) = epub.read_entry_string(&item.href) else {
+            continue;
+        };
+        match DocumentText::parse(&xhtml) {
+            Some(text) => documents.push((index, item.href.clone(), text)),
+            None => log::warn!("Skipping unparsable document {}", item.href),
+        }
+    }
+    Ok(documents)
+}
+
+/// The CFI of `clipping` in `documents`, searching the document a Kobo
+/// named first.
+fn locate(clipping: &Clipping, documents: &[(usize, String, DocumentText)]) -> Option<String> {
+    let needle = normalize(&clipping.text);
+    if needle.is_empty() {
+        return None;
+    }
+    let named = clipping.document.as_deref().and_then(|document| {
+        documents
+            .iter()
+            .find(|(_, href, _)| href.ends_with(document))
+    });
+    named
+        .into_iter()
+        .chain(documents)
+        .find_map(|(index, _, text)| {
+            let (first, last) = text.find(&needle)?;
+            Some(text.range_cfi(*index, first, last))
+        })
+}
+
+/// A random id like the frontend's `uniqueId`.
+pub(crate) fn unique_id() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(7)
+        .map(|c| char::from(c).to_ascii_lowercase())
+        .collect()
+}
+
+/// Adds `notes` to the config of `book_hash`, keeping it encrypted if it
+/// was, and skipping the ones it already has. Returns the ones added.
+fn add_notes(app: &AppHandle, book_hash: &str, notes: Vec<Value>) -> Result<Vec<Value>> {
+    let path = storage::config_path(app, book_hash)?;
+    let (mut config, encrypted) = match std::fs::read(&path) {
+        Ok(data) => {
+            let encrypted = storage::is_encrypted(&data);
+            (
+                serde_json::from_slice::<Value>(&storage::decrypt(data)?)?,
+                encrypted,
+            )
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (json!({}), false),
+        Err(e) => return Err(e.into()),
+    };
+    let Some(object) = config.as_object_mut() else {
+        return Err(Error::InvalidBook(format!("invalid config of {book_hash}")));
+    };
+    let booknotes = object
+        .entry("booknotes")
+        .or_insert_with(|| Value::Array(Vec::new()));
+    let Some(booknotes) = booknotes.as_array_mut() else {
+        return Err(Error::InvalidBook(format!("invalid notes of {book_hash}")));
+    };
+
+    let existing = booknotes
+        .iter()
+        .filter(|note| note["deletedAt"].is_null())
+        .filter_map(|note| note["cfi"].as_str().map(String::from))
+        .collect::<HashSet<_>>();
+    let added = notes
+        .into_iter()
+        .filter(|note| {
+            note["cfi"]
+                .as_str()
+                .is_some_and(|cfi| !existing.contains(cfi))
+        })
+        .collect::<Vec<_>>();
+    if !added.is_empty() {
+        booknotes.extend(added.iter().cloned());
+        object.insert("updatedAt".into(), now_millis().into());
+        storage::write_file(&path, serde_json::to_vec(&config)?, encrypted)?;
+    }
+    Ok(added)
+}
+
+/// Imports the highlights and notes in the Kindle clippings file or Kobo
+/// database at `path`, or on the device mounted there, into the matching
+/// library books.
+#[command]
+pub async fn import_annotations(
+    app: AppHandle,
+    source: AnnotationSource,
+    path: PathBuf,
+) -> Result<AnnotationImport> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let clippings = read_source(source, &path)?;
+        let books = {
+            let db = app.state::<db::LibraryDb>();
+            let page = db::query_books(&db.conn(), &db::BookQuery::default())?;
+            page.books
+        };
+
+        let mut by_book: HashMap<&str, (&db::Book, Vec<Clipping>)> = HashMap::new();
+        let mut result = AnnotationImport::default();
+        for clipping in clippings {
+            match match_book(&clipping, &books) {
+                Some(book) => by_book
+                    .entry(&book.hash)
+                    .or_insert_with(|| (book, Vec::new()))
+                    .1
+                    .push(clipping),
+                None if !result.unmatched.contains(&clipping.title) => {
+                    result.unmatched.push(clipping.title)
+                }
+                None => {}
+            }
+        }
+
+        for (hash, (book, clippings)) in by_book {
+            let documents = match library::book_path(&app, book) {
+                Some(path) if book.format.eq_ignore_ascii_case("epub") => epub_documents(&path)
+                    .unwrap_or_else(|e| {
+                        log::warn!("Failed to read {path:?}: {e}");
+                        Vec::new()
+                    }),
+                _ => Vec::new(),
+            };
+            let total = clippings.len();
+            let notes = clippings
+                .into_iter()
+                .filter_map(|clipping| {
+                    let cfi = locate(&clipping, &documents)?;
+                    Some(json!({
+                        "id": unique_id(),
+                        "type": "annotation",
+                        "cfi": cfi,
+                        "text": clipping.text,
+                        "style": "highlight",
+                        "color": "yellow",
+                        "note": clipping.note,
+                        "createdAt": clipping.created_at,
+                        "updatedAt": clipping.updated_at,
+                    }))
+                })
+                .collect::<Vec<_>>();
+            let placed = notes.len();
+            let added = add_notes(&app, hash, notes)?;
+            for note in &added {
+                hooks::annotation_added(&app, book, note);
+            }
+            result.imported += added.len();
+            result.duplicates += placed - added.len();
+            result.unplaced += total - placed;
+        }
+        Ok(result)
+    })
+    .await?
+}
@@ -0,0 +1,3 @@
+//! Reading data made in other apps and devices into the library.
+
+pub mod annotations;
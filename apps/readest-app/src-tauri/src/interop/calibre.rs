@@ -0,0 +1,495 @@
+//! Import of Calibre libraries. The `metadata.db` of a library folder is read
+//! directly; the preferred format of each book is copied into the books dir
+//! together with its cover, and its metadata, series, tags, rating and
+//! custom columns are added to the library index.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use rusqlite::types::Value as SqlValue;
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use serde::Serialize;
+use serde_json::{Map, Number, Value};
+use tauri::{command, AppHandle, Emitter, Manager};
+
+use crate::error::{Error, Result};
+use crate::library::{self, db};
+use crate::utils::{now_millis, parse_rfc3339};
+
+const METADATA_DB: &str = "metadata.db";
+
+/// Calibre formats the reader can open, most preferred first. Calibre names
+/// formats like the frontend does, in upper case.
+const FORMATS: &[&str] = &["EPUB", "AZW3", "MOBI", "AZW", "FB2", "FBZ", "CBZ", "PDF"];
+
+/// Calibre stores unset dates as the first of January of the year 101.
+const UNDEFINED_YEAR: &str = "0101";
+
+/// A book as recorded in `metadata.db`.
+struct CalibreBook {
+    id: i64,
+    title: String,
+    /// Folder of the book relative to the library root.
+    path: String,
+    has_cover: bool,
+    added: Option<String>,
+    published: Option<String>,
+    series_index: Option<f64>,
+    uuid: Option<String>,
+    authors: Vec<String>,
+    tags: Vec<String>,
+    series: Option<String>,
+    /// From 0 to 10, two points per star.
+    rating: Option<i64>,
+    publisher: Option<String>,
+    comments: Option<String>,
+    languages: Vec<String>,
+    identifiers: Vec<(String, String)>,
+    /// Format names and the file names without extension.
+    formats: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CalibreImportResult {
+    pub calibre_id: i64,
+    pub title: String,
+    /// The imported book, or the library book with the same content.
+    pub book: Option<db::Book>,
+    /// True when the book was already in the library and was left untouched.
+    pub duplicate: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ImportProgress {
+    current: usize,
+    total: usize,
+    result: CalibreImportResult,
+}
+
+fn open_library(dir: &Path) -> Result<Connection> {
+    let path = dir.join(METADATA_DB);
+    if !path.is_file() {
+        return Err(Error::InvalidLibrary(format!(
+            "{METADATA_DB} not found in {}",
+            dir.display()
+        )));
+    }
+    Ok(Connection::open_with_flags(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?)
+}
+
+fn read_books(conn: &Connection) -> Result<Vec<CalibreBook>> {
+    let strings = |sql: &str, id: i64| -> Result<Vec<String>> {
+        let mut stmt = conn.prepare_cached(sql)?;
+        let values = stmt
+            .query_map([id], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(values)
+    };
+    let first =
+        |sql: &str, id: i64| -> Result<Option<String>> { Ok(strings(sql, id)?.into_iter().next()) };
+
+    let mut stmt = conn.prepare(
+        "SELECT id, title, path, has_cover, timestamp, pubdate, series_index, uuid \
+         FROM books ORDER BY id",
+    )?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok(CalibreBook {
+                id: row.get(0)?,
+                title: row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                path: row.get(2)?,
+                has_cover: row.get::<_, Option<bool>>(3)?.unwrap_or(false),
+                added: row.get(4)?,
+                published: row.get(5)?,
+                series_index: row.get(6)?,
+                uuid: row.get(7)?,
+                authors: Vec::new(),
+                tags: Vec::new(),
+                series: None,
+                rating: None,
+                publisher: None,
+                comments: None,
+                languages: Vec::new(),
+                identifiers: Vec::new(),
+                formats: Vec::new(),
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut identifiers = conn.prepare("SELECT type, val FROM identifiers WHERE book = ?1")?;
+    let mut formats = conn.prepare("SELECT format, name FROM data WHERE book = ?1")?;
+    let mut rating = conn.prepare(
+        "SELECT r.rating FROM books_ratings_link l JOIN ratings r ON r.id = l.rating \
+         WHERE l.book = ?1",
+    )?;
+    let mut books = Vec::with_capacity(rows.len());
+    for mut book in rows {
+        let id = book.id;
+        book.authors = strings(
+            "SELECT a.name FROM books_authors_link l JOIN authors a ON a.id = l.author \
+             WHERE l.book = ?1 ORDER BY l.id",
+            id,
+        )?;
+        book.tags = strings(
+            "SELECT t.name FROM books_tags_link l JOIN tags t ON t.id = l.tag \
+             WHERE l.book = ?1 ORDER BY t.name",
+            id,
+        )?;
+        book.series = first(
+            "SELECT s.name FROM books_series_link l JOIN series s ON s.id = l.series \
+             WHERE l.book = ?1",
+            id,
+        )?;
+        book.publisher = first(
+            "SELECT p.name FROM books_publishers_link l JOIN publishers p ON p.id = l.publisher \
+             WHERE l.book = ?1",
+            id,
+        )?;
+        book.comments = first("SELECT text FROM comments WHERE book = ?1", id)?;
+        book.languages = strings(
+            "SELECT g.lang_code FROM books_languages_link l JOIN languages g ON g.id = l.lang_code \
+             WHERE l.book = ?1 ORDER BY l.item_order",
+            id,
+        )?;
+        book.rating = rating
+            .query_row([id], |row| row.get::<_, Option<i64>>(0))
+            .optional()?
+            .flatten()
+            .filter(|&rating| rating > 0);
+        book.identifiers = identifiers
+            .query_map([id], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        book.formats = formats
+            .query_map([id], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        books.push(book);
+    }
+    Ok(books)
+}
+
+fn json_value(value: SqlValue) -> Value {
+    match value {
+        SqlValue::Null | SqlValue::Blob(_) => Value::Null,
+        SqlValue::Integer(n) => n.into(),
+        SqlValue::Real(n) => Number::from_f64(n).map_or(Value::Null, Value::Number),
+        SqlValue::Text(text) => text.into(),
+    }
+}
+
+/// Values of the user-defined columns by book id, keyed by column label.
+fn custom_columns(conn: &Connection) -> Result<HashMap<i64, Map<String, Value>>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, label, datatype, is_multiple, normalized FROM custom_columns \
+         WHERE mark_for_delete = 0 ORDER BY id",
+    )?;
+    let columns = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, bool>(3)?,
+                row.get::<_, bool>(4)?,
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut values: HashMap<i64, Map<String, Value>> = HashMap::new();
+    for (id, label, datatype, multiple, normalized) in columns {
+        // Composite columns are computed by Calibre and not stored
+        if datatype == "composite" {
+            continue;
+        }
+        // `id` comes from the database, the table names are Calibre's own
+        let sql = match (normalized, datatype.as_str()) {
+            (true, "series") => format!(
+                "SELECT l.book, c.value, l.extra FROM books_custom_column_{id}_link l \
+                 JOIN custom_column_{id} c ON c.id = l.value ORDER BY l.id"
+            ),
+            (true, _) => format!(
+                "SELECT l.book, c.value, NULL FROM books_custom_column_{id}_link l \
+                 JOIN custom_column_{id} c ON c.id = l.value ORDER BY l.id"
+            ),
+            (false, _) => format!("SELECT book, value, NULL FROM custom_column_{id}"),
+        };
+        let mut stmt = match conn.prepare(&sql) {
+            Ok(stmt) => stmt,
+            Err(e) => {
+                log::warn!("Skipping Calibre column {label}: {e}");
+                continue;
+            }
+        };
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, SqlValue>(1)?,
+                row.get::<_, SqlValue>(2)?,
+            ))
+        })?;
+        for row in rows {
+            let (book, value, extra) = row?;
+            let value = match (datatype.as_str(), value) {
+                ("bool", SqlValue::Integer(n)) => Value::Bool(n != 0),
+                ("rating", SqlValue::Integer(n)) => {
+                    Number::from_f64(n as f64 / 2.0).map_or(Value::Null, Value::Number)
+                }
+                ("series", value) => {
+                    let mut series = Map::new();
+                    series.insert("name".into(), json_value(value));
+                    series.insert("index".into(), json_value(extra));
+                    Value::Object(series)
+                }
+                (_, value) => json_value(value),
+            };
+            let book = values.entry(book).or_default();
+            if multiple {
+                let entry = book
+                    .entry(label.clone())
+                    .or_insert_with(|| Value::Array(Vec::new()));
+                if let Value::Array(items) = entry {
+                    items.push(value);
+                }
+            } else {
+                book.insert(label.clone(), value);
+            }
+        }
+    }
+    Ok(values)
+}
+
+/// ISO 639-1 code for the ISO 639-2 codes Calibre uses, for the languages
+/// books are most often in. Other codes are kept as they are.
+fn language_code(code: &str) -> String {
+    let short = match code {
+        "ara" => "ar",
+        "cat" => "ca",
+        "ces" | "cze" => "cs",
+        "dan" => "da",
+        "deu" | "ger" => "de",
+        "ell" | "gre" => "el",
+        "eng" => "en",
+        "spa" => "es",
+        "fas" | "per" => "fa",
+        "fin" => "fi",
+        "fra" | "fre" => "fr",
+        "heb" => "he",
+        "hin" => "hi",
+        "hun" => "hu",
+        "ind" => "id",
+        "ita" => "it",
+        "jpn" => "ja",
+        "kor" => "ko",
+        "nld" | "dut" => "nl",
+        "nor" => "no",
+        "nob" => "nb",
+        "pol" => "pl",
+        "por" => "pt",
+        "ron" | "rum" => "ro",
+        "rus" => "ru",
+        "swe" => "sv",
+        "tha" => "th",
+        "tur" => "tr",
+        "ukr" => "uk",
+        "vie" => "vi",
+        "zho" | "chi" => "zh",
+        code => code,
+    };
+    short.to_string()
+}
+
+/// The `BookMetadata` the frontend reads, plus what only Calibre has.
+fn book_metadata(book: &CalibreBook, custom: Option<Map<String, Value>>) -> Value {
+    let mut metadata = Map::new();
+    let mut set = |key: &str, value: Option<Value>| {
+        if let Some(value) = value {
+            metadata.insert(key.into(), value);
+        }
+    };
+    let languages = book
+        .languages
+        .iter()
+        .map(|code| language_code(code).into())
+        .collect::<Vec<Value>>();
+    let published = book
+        .published
+        .as_deref()
+        .filter(|date| !date.starts_with(UNDEFINED_YEAR))
+        .and_then(|date| date.get(..10));
+    let isbn = book
+        .identifiers
+        .iter()
+        .find(|(kind, _)| kind == "isbn")
+        .map(|(_, value)| value.clone().into());
+    set("title", Some(book.title.clone().into()));
+    set("author", Some(book.authors.join(", ").into()));
+    set("language", Some(languages.into()));
+    set("publisher", book.publisher.clone().map(Value::from));
+    set("published", published.map(Value::from));
+    set("description", book.comments.clone().map(Value::from));
+    set("subject", Some(book.tags.clone().into()));
+    set("identifier", isbn);
+    set("series", book.series.clone().map(Value::from));
+    set(
+        "seriesIndex",
+        book.series
+            .as_ref()
+            .and(book.series_index)
+            .and_then(Number::from_f64)
+            .map(Value::Number),
+    );
+    set(
+        "rating",
+        book.rating
+            .and_then(|rating| Number::from_f64(rating as f64 / 2.0))
+            .map(Value::Number),
+    );
+
+    let mut calibre = Map::new();
+    calibre.insert("id".into(), book.id.into());
+    if let Some(uuid) = &book.uuid {
+        calibre.insert("uuid".into(), uuid.clone().into());
+    }
+    let identifiers = book
+        .identifiers
+        .iter()
+        .map(|(kind, value)| (kind.clone(), Value::from(value.clone())))
+        .collect::<Map<_, _>>();
+    calibre.insert("identifiers".into(), identifiers.into());
+    calibre.insert("customColumns".into(), custom.unwrap_or_default().into());
+    set("calibre", Some(calibre.into()));
+    Value::Object(metadata)
+}
+
+/// Copies `book` into the library. Returns the library book and whether it
+/// was already there.
+fn import_book(
+    app: &AppHandle,
+    root: &Path,
+    book: &CalibreBook,
+    custom: Option<Map<String, Value>>,
+) -> Result<(db::Book, bool)> {
+    let (format, name) = FORMATS
+        .iter()
+        .find_map(|format| {
+            book.formats
+                .iter()
+                .find(|(candidate, _)| candidate.eq_ignore_ascii_case(format))
+                .map(|(_, name)| (*format, name))
+        })
+        .ok_or_else(|| {
+            let formats = book.formats.iter().map(|(format, _)| format.as_str());
+            match formats.collect::<Vec<_>>().join(", ") {
+                formats if formats.is_empty() => {
+                    Error::InvalidLibrary(format!("{} has no files", book.title))
+                }
+                formats => Error::UnsupportedFormat(formats),
+            }
+        })?;
+    let folder = root.join(&book.path);
+    let source = folder.join(format!("{name}.{}", format.to_ascii_lowercase()));
+    if !source.is_file() {
+        return Err(Error::InvalidLibrary(format!(
+            "missing {}",
+            source.display()
+        )));
+    }
+
+    let hash = library::partial_md5(&source)?;
+    let state = app.state::<db::LibraryDb>();
+    let existing = db::get_book(&state.conn(), &hash)?;
+    if let Some(existing) = existing.as_ref().filter(|b| b.deleted_at.is_none()) {
+        return Ok((existing.clone(), true));
+    }
+
+    let now = now_millis();
+    let imported = db::Book {
+        hash: hash.clone(),
+        format: format.to_string(),
+        title: book.title.clone(),
+        source_title: Some(book.title.clone()),
+        author: book.authors.join(", "),
+        group_id: None,
+        group_name: None,
+        tags: book.tags.clone(),
+        cover_image_url: None,
+        file_path: None,
+        url: None,
+        primary_language: book.languages.first().map(|code| language_code(code)),
+        progress: existing.as_ref().and_then(|b| b.progress),
+        metadata: Some(book_metadata(book, custom)),
+        created_at: book.added.as_deref().and_then(parse_rfc3339).unwrap_or(now),
+        updated_at: now,
+        deleted_at: None,
+        uploaded_at: None,
+        downloaded_at: Some(now),
+        cover_downloaded_at: None,
+    };
+
+    let books_dir = library::books_dir(app)?;
+    std::fs::create_dir_all(books_dir.join(&hash))?;
+    std::fs::copy(
+        &source,
+        books_dir.join(library::local_book_filename(&imported)),
+    )?;
+    let cover = folder.join("cover.jpg");
+    if book.has_cover && cover.is_file() {
+        library::write_cover(app, &hash, &std::fs::read(&cover)?)?;
+    }
+    db::upsert_books(&mut state.conn(), std::slice::from_ref(&imported))?;
+    #[cfg(any(target_os = "macos", windows))]
+    crate::system_search::update(app, std::slice::from_ref(&imported));
+    Ok((imported, false))
+}
+
+/// Imports every book of the Calibre library at `path`, skipping books whose
+/// content is already in the library, and emits a `calibre-import-progress`
+/// event after each book.
+#[command]
+pub async fn import_calibre_library(
+    app: AppHandle,
+    path: PathBuf,
+) -> Result<Vec<CalibreImportResult>> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let conn = open_library(&path)?;
+        let books = read_books(&conn)?;
+        let mut custom = custom_columns(&conn)?;
+        drop(conn);
+
+        let total = books.len();
+        let mut results = Vec::with_capacity(total);
+        for (i, book) in books.iter().enumerate() {
+            let (imported, duplicate, error) =
+                match import_book(&app, &path, book, custom.remove(&book.id)) {
+                    Ok((imported, duplicate)) => (Some(imported), duplicate, None),
+                    Err(e) => {
+                        log::warn!("Failed to import {:?} from Calibre: {e}", book.title);
+                        (None, false, Some(e.to_string()))
+                    }
+                };
+            let result = CalibreImportResult {
+                calibre_id: book.id,
+                title: book.title.clone(),
+                book: imported,
+                duplicate,
+                error,
+            };
+            let _ = app.emit(
+                "calibre-import-progress",
+                ImportProgress {
+                    current: i + 1,
+                    total,
+                    result: result.clone(),
+                },
+            );
+            results.push(result);
+        }
+        Ok(results)
+    })
+    .await?
+}
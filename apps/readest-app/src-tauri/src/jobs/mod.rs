@@ -0,0 +1,276 @@
+//! Long-running commands as jobs, so the frontend can show them in one
+//! task center and the user can cancel them. Every job sends
+//! `job-progress` events with its id, the phase it is in, how far along it
+//! is and a message, and a last one when it is done, failed or cancelled.
+//!
+//! Cancelling sets a flag the job checks between steps, and makes the
+//! command return at once from work that cannot be interrupted, such as a
+//! blocking conversion or a stalled request, leaving it to end on its own.
+
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use futures::channel::oneshot;
+use futures::future::{Either, FutureExt, Shared};
+use serde::Serialize;
+use tauri::{command, AppHandle, Emitter, Manager, State};
+
+use crate::error::{Error, Result};
+
+pub const EVENT: &str = "job-progress";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum JobState {
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobProgress {
+    pub id: String,
+    /// What the job does: `import`, `convert`, `index` or `sync`.
+    pub kind: &'static str,
+    pub state: JobState,
+    /// The step the job is in, such as `scanning` before an import.
+    pub phase: &'static str,
+    /// From 0 to 100, or `None` while the amount of work is unknown.
+    pub percent: Option<f32>,
+    /// What is being worked on, or why the job failed.
+    pub message: Option<String>,
+}
+
+impl JobProgress {
+    /// The title of a notification that the job is done or failed, in
+    /// English as the key of its translation.
+    pub fn outcome(&self) -> Option<&'static str> {
+        let failed = match self.state {
+            JobState::Done => false,
+            JobState::Failed => true,
+            JobState::Running | JobState::Cancelled => return None,
+        };
+        Some(match self.kind {
+            "import" if failed => "Import failed",
+            "import" => "Import finished",
+            "convert" if failed => "Conversion failed",
+            "convert" => "Conversion finished",
+            "index" if failed => "Indexing failed",
+            "index" => "Indexing finished",
+            "sync" if failed => "Sync failed",
+            "sync" => "Sync finished",
+            _ => return None,
+        })
+    }
+}
+
+struct Entry {
+    progress: JobProgress,
+    cancelled: Arc<AtomicBool>,
+    cancel: Option<oneshot::Sender<()>>,
+}
+
+/// The jobs started since launch that have not ended yet.
+#[derive(Default)]
+pub struct Jobs {
+    next_id: AtomicU64,
+    entries: Mutex<Vec<Entry>>,
+}
+
+struct Inner {
+    app: AppHandle,
+    id: String,
+    cancelled: Arc<AtomicBool>,
+    on_cancel: Shared<oneshot::Receiver<()>>,
+}
+
+impl Jobs {
+    /// The progress of the running jobs.
+    pub fn running(&self) -> Vec<JobProgress> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|e| e.progress.state == JobState::Running)
+            .map(|e| e.progress.clone())
+            .collect()
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        let jobs = self.app.state::<Jobs>();
+        jobs.entries
+            .lock()
+            .unwrap()
+            .retain(|e| e.progress.id != self.id);
+        #[cfg(windows)]
+        crate::windows::taskbar::refresh(&self.app);
+    }
+}
+
+/// A running job, cloned into the threads doing its work. It leaves the
+/// list when the last clone is dropped.
+#[derive(Clone)]
+pub struct Job(Arc<Inner>);
+
+impl Job {
+    pub fn start(app: &AppHandle, kind: &'static str) -> Self {
+        let jobs = app.state::<Jobs>();
+        let id = format!("job-{}", jobs.next_id.fetch_add(1, Ordering::Relaxed) + 1);
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let (cancel, on_cancel) = oneshot::channel();
+        let progress = JobProgress {
+            id: id.clone(),
+            kind,
+            state: JobState::Running,
+            phase: "starting",
+            percent: None,
+            message: None,
+        };
+        let _ = app.emit(EVENT, &progress);
+        jobs.entries.lock().unwrap().push(Entry {
+            progress,
+            cancelled: cancelled.clone(),
+            cancel: Some(cancel),
+        });
+        Self(Arc::new(Inner {
+            app: app.clone(),
+            id,
+            cancelled,
+            on_cancel: on_cancel.shared(),
+        }))
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::Relaxed)
+    }
+
+    fn update(&self, f: impl FnOnce(&mut JobProgress)) {
+        let jobs = self.0.app.state::<Jobs>();
+        let mut entries = jobs.entries.lock().unwrap();
+        let Some(entry) = entries.iter_mut().find(|e| e.progress.id == self.0.id) else {
+            return;
+        };
+        f(&mut entry.progress);
+        let _ = self.0.app.emit(EVENT, &entry.progress);
+        #[cfg(any(windows, target_os = "linux"))]
+        {
+            let progress = entry.progress.clone();
+            drop(entries);
+            #[cfg(windows)]
+            crate::windows::taskbar::refresh(&self.0.app);
+            #[cfg(windows)]
+            crate::windows::toast::notify(&self.0.app, &progress);
+            #[cfg(target_os = "linux")]
+            crate::linux::notifications::notify(&self.0.app, &progress);
+        }
+    }
+
+    /// Enters `phase`, whose amount of work is not known.
+    pub fn phase(&self, phase: &'static str, message: Option<String>) {
+        self.update(|progress| {
+            progress.phase = phase;
+            progress.percent = None;
+            progress.message = message;
+        });
+    }
+
+    /// Reports `done` of `total` steps of `phase` finished.
+    pub fn progress(
+        &self,
+        phase: &'static str,
+        done: usize,
+        total: usize,
+        message: Option<String>,
+    ) {
+        self.update(|progress| {
+            progress.phase = phase;
+            progress.percent = Some(if total == 0 {
+                100.0
+            } else {
+                done as f32 * 100.0 / total as f32
+            });
+            progress.message = message;
+        });
+    }
+
+    /// Awaits `future`, or fails with [`Error::Cancelled`] as soon as the
+    /// job is cancelled.
+    pub async fn cancellable<T>(&self, future: impl Future<Output = Result<T>>) -> Result<T> {
+        futures::pin_mut!(future);
+        match futures::future::select(future, self.0.on_cancel.clone()).await {
+            Either::Left((result, _)) => result,
+            Either::Right(_) => Err(Error::Cancelled),
+        }
+    }
+
+    /// Runs `f` on the blocking thread pool like the other commands do,
+    /// returning early when the job is cancelled.
+    pub async fn run_blocking<T: Send + 'static>(
+        &self,
+        f: impl FnOnce() -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        self.cancellable(async move { tauri::async_runtime::spawn_blocking(f).await? })
+            .await
+    }
+
+    /// Sends the last event of the job, with the outcome of `result`, and
+    /// passes `result` on. A job cancelled part way may still succeed with
+    /// what it did before it stopped.
+    pub fn finish<T>(&self, result: Result<T>) -> Result<T> {
+        let cancelled = self.is_cancelled() || matches!(result, Err(Error::Cancelled));
+        self.update(|progress| {
+            match &result {
+                _ if cancelled => progress.state = JobState::Cancelled,
+                Ok(_) => {
+                    progress.state = JobState::Done;
+                    progress.percent = Some(100.0);
+                    progress.message = None;
+                }
+                Err(e) => {
+                    progress.state = JobState::Failed;
+                    progress.message = Some(e.to_string());
+                }
+            };
+        });
+        result
+    }
+}
+
+/// Runs `f` as a job of `kind`, ending it with the result.
+pub async fn run<T, F, Fut>(app: &AppHandle, kind: &'static str, f: F) -> Result<T>
+where
+    F: FnOnce(Job) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let job = Job::start(app, kind);
+    let result = f(job.clone()).await;
+    job.finish(result)
+}
+
+/// Lists the running jobs, for a task center opened after they started.
+#[command]
+pub fn list_jobs(jobs: State<'_, Jobs>) -> Vec<JobProgress> {
+    jobs.running()
+}
+
+/// Asks the job `id` to stop, returning `false` if it is not running.
+#[command]
+pub fn cancel_job(jobs: State<'_, Jobs>, id: String) -> bool {
+    let mut entries = jobs.entries.lock().unwrap();
+    let Some(entry) = entries
+        .iter_mut()
+        .find(|e| e.progress.id == id && e.progress.state == JobState::Running)
+    else {
+        return false;
+    };
+    entry.cancelled.store(true, Ordering::Relaxed);
+    if let Some(cancel) = entry.cancel.take() {
+        let _ = cancel.send(());
+    }
+    true
+}
@@ -17,34 +17,174 @@ use tauri::{AppHandle, Listener, Manager, Url};
 #[cfg(desktop)]
 use tauri_plugin_fs::FsExt;
 
+mod analysis;
+#[cfg(desktop)]
+mod api;
+#[cfg(desktop)]
+mod appearance;
+#[cfg(all(desktop, not(target_os = "macos")))]
+mod associations;
+#[cfg(desktop)]
+mod audio;
+#[cfg(all(desktop, feature = "cli"))]
+pub mod cli;
+#[cfg(desktop)]
+mod clipboard;
+mod cloud;
+mod commands;
+mod convert;
+#[cfg(desktop)]
+mod crash;
+#[cfg(desktop)]
+mod deep_link;
+#[cfg(desktop)]
+mod devices;
+#[cfg(desktop)]
+mod diagnostics;
+mod dict;
+mod drm;
+mod error;
+mod export;
+mod feeds;
+mod focus;
+mod fonts;
+mod formats;
+mod goals;
+mod hooks;
+#[cfg(desktop)]
+mod i18n;
+mod images;
+#[cfg(desktop)]
+mod import;
+mod interop;
+mod jobs;
+mod library;
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(desktop)]
+mod logging;
 #[cfg(target_os = "macos")]
 mod macos;
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+mod media_controls;
+mod metadata;
+mod net;
+#[cfg(feature = "ocr")]
+mod ocr;
+mod opds;
+mod paths;
+mod plugins;
+#[cfg(desktop)]
+mod print;
+#[cfg(desktop)]
+mod profiles;
+#[cfg(desktop)]
+mod recent;
+mod resources;
+mod search;
+mod secrets;
+mod send;
+#[cfg(desktop)]
+mod session;
+#[cfg(desktop)]
+mod share;
+#[cfg(desktop)]
+mod shortcuts;
+mod stats;
+mod store;
+mod sync;
+#[cfg(any(target_os = "macos", windows))]
+mod system_search;
+mod tasks;
 mod transfer_file;
+mod translate;
+#[cfg(desktop)]
+mod tray;
+#[cfg(desktop)]
+mod tts;
+mod typeset;
+#[cfg(desktop)]
+mod updates;
+mod utils;
+mod vocab;
+#[cfg(desktop)]
+mod voice;
+#[cfg(desktop)]
+mod window_manager;
+#[cfg(windows)]
+mod windows;
+mod zim;
+
+/// Entry point of the crash monitor, a copy of the app started by itself.
+#[cfg(desktop)]
+pub use crash::monitor::run as run_crash_monitor;
+
+/// Picks the data directory, which `main` reads from `--data-dir`.
+#[cfg(desktop)]
+pub use paths::portable::{set_data_dir, DATA_DIR_ARG};
+
+/// Entry point of the Quick Look extension binary.
+#[cfg(all(target_os = "macos", feature = "quicklook"))]
+pub use macos::quicklook::run_extension as run_quicklook_extension;
+
+/// For the benchmarks in `benches/`.
+#[doc(hidden)]
+pub use formats::bench;
+
+/// The hidden `--bench-open` mode, which `main` runs before the app.
+#[cfg(desktop)]
+pub use formats::bench::run as run_bench_open;
+
 use tauri::{command, Emitter, WebviewUrl, WebviewWindowBuilder, Window};
 use tauri_plugin_oauth::start;
 use transfer_file::{download_file, upload_file};
 
+/// Release builds on Windows are GUI programs without a console of their
+/// own, so output of the command line modes goes to the console of the
+/// shell that started them.
+#[cfg(all(desktop, windows))]
+fn attach_console() {
+    use windows::Win32::System::Console::{AttachConsole, ATTACH_PARENT_PROCESS};
+    // Fails when there is no parent console or one is already attached
+    let _ = unsafe { AttachConsole(ATTACH_PARENT_PROCESS) };
+}
+
+#[cfg(all(desktop, not(windows)))]
+fn attach_console() {}
+
 #[cfg(desktop)]
 fn allow_file_in_scopes(app: &AppHandle, files: Vec<PathBuf>) {
     let fs_scope = app.fs_scope();
     let asset_protocol_scope = app.asset_protocol_scope();
     for file in &files {
         if let Err(e) = fs_scope.allow_file(file) {
-            eprintln!("Failed to allow file in fs_scope: {e}");
+            log::warn!("Failed to allow file in fs_scope: {e}");
         } else {
-            println!("Allowed file in fs_scope: {file:?}");
+            log::info!("Allowed file in fs_scope: {file:?}");
         }
         if let Err(e) = asset_protocol_scope.allow_file(file) {
-            eprintln!("Failed to allow file in asset_protocol_scope: {e}");
+            log::warn!("Failed to allow file in asset_protocol_scope: {e}");
         } else {
-            println!("Allowed file in asset_protocol_scope: {file:?}");
+            log::info!("Allowed file in asset_protocol_scope: {file:?}");
         }
     }
 }
 
+#[cfg(desktop)]
+fn allow_dir_in_scopes(app: &AppHandle, dir: &std::path::Path) {
+    if let Err(e) = app.fs_scope().allow_directory(dir, true) {
+        log::warn!("Failed to allow directory in fs_scope: {e}");
+    }
+    if let Err(e) = app.asset_protocol_scope().allow_directory(dir, true) {
+        log::warn!("Failed to allow directory in asset_protocol_scope: {e}");
+    }
+}
+
 #[cfg(desktop)]
 fn get_files_from_argv(argv: Vec<String>) -> Vec<PathBuf> {
     let mut files = Vec::new();
+    // The value of `--data-dir` is no file to open
+    let argv = paths::portable::without_data_dir_arg(argv);
     // NOTICE: `args` may include URL protocol (`your-app-protocol://`)
     // or arguments (`--`) if your app supports them.
     // files may also be passed as `file://path/to/file`
@@ -55,6 +195,9 @@ fn get_files_from_argv(argv: Vec<String>) -> Vec<PathBuf> {
         }
         // handle `file://` path urls and skip other urls
         if let Ok(url) = Url::parse(maybe_file) {
+            if url.scheme() == deep_link::SCHEME {
+                continue;
+            }
             if let Ok(path) = url.to_file_path() {
                 files.push(path);
             } else {
@@ -67,6 +210,64 @@ fn get_files_from_argv(argv: Vec<String>) -> Vec<PathBuf> {
     files
 }
 
+/// Resolves the relative file arguments of `argv` against `cwd`, the working
+/// directory of the instance they were given to.
+#[cfg(desktop)]
+fn absolute_argv(argv: Vec<String>, cwd: &str) -> Vec<String> {
+    argv.into_iter()
+        .enumerate()
+        .map(|(i, arg)| {
+            // Single-letter schemes are Windows drive letters, not URLs
+            let is_url = Url::parse(&arg).is_ok_and(|url| url.scheme().len() > 1);
+            let path = std::path::Path::new(&arg);
+            if i == 0 || arg.starts_with('-') || is_url || !path.is_relative() {
+                return arg;
+            }
+            std::path::Path::new(cwd)
+                .join(path)
+                .to_string_lossy()
+                .into_owned()
+        })
+        .collect()
+}
+
+/// Brings the main window to the front, restoring it when minimized.
+#[cfg(desktop)]
+fn show_main_window(app: &AppHandle) {
+    let Some(window) = app.get_webview_window("main") else {
+        return;
+    };
+    let _ = window.unminimize();
+    let _ = window.show();
+    let _ = window.set_focus();
+}
+
+/// Replaces convertible book paths in `argv` with their EPUB conversions,
+/// and text files in other encodings with their UTF-8 copies.
+#[cfg(desktop)]
+fn convert_argv(app: &AppHandle, argv: Vec<String>) -> Vec<String> {
+    argv.into_iter()
+        .enumerate()
+        .map(|(i, arg)| {
+            if i == 0 {
+                return arg;
+            }
+            match get_files_from_argv(vec![String::new(), arg.clone()]).pop() {
+                Some(file)
+                    if convert::needs_conversion(app, &file)
+                        || convert::transcode::is_text_file(&file) =>
+                {
+                    convert::prepare_open_files(app, vec![file])
+                        .pop()
+                        .map(|path| path.to_string_lossy().into_owned())
+                        .unwrap_or(arg)
+                }
+                _ => arg,
+            }
+        })
+        .collect()
+}
+
 #[cfg(desktop)]
 fn set_window_open_with_files(app: &AppHandle, files: Vec<PathBuf>) {
     let files = files
@@ -83,7 +284,7 @@ fn set_window_open_with_files(app: &AppHandle, files: Vec<PathBuf>) {
     let window = app.get_webview_window("main").unwrap();
     let script = format!("window.OPEN_WITH_FILES = [{files}];");
     if let Err(e) = window.eval(&script) {
-        eprintln!("Failed to set open files variable: {e}");
+        log::warn!("Failed to set open files variable: {e}");
     }
 }
 
@@ -92,7 +293,7 @@ fn set_rounded_window(app: &AppHandle, rounded: bool) {
     let window = app.get_webview_window("main").unwrap();
     let script = format!("window.IS_ROUNDED = {rounded};");
     if let Err(e) = window.eval(&script) {
-        eprintln!("Failed to set IS_ROUNDED variable: {e}");
+        log::warn!("Failed to set IS_ROUNDED variable: {e}");
     }
 }
 
@@ -118,22 +319,580 @@ struct Payload {
     cwd: String,
 }
 
+/// Hands `files` the app was opened with to the window once it is ready.
+/// This runs on the main thread, so books that have to be converted or
+/// transcoded first are prepared on the blocking pool instead, and handed
+/// over like those of a second instance when done.
+#[cfg(desktop)]
+fn open_with_files(app: &AppHandle, files: Vec<PathBuf>) {
+    if files.is_empty() {
+        return;
+    }
+    let prepare = files
+        .iter()
+        .any(|file| convert::needs_conversion(app, file) || convert::transcode::is_text_file(file));
+    if !prepare {
+        let app_handle = app.clone();
+        allow_file_in_scopes(app, files.clone());
+        app.listen("window-ready", move |_| {
+            log::info!("Window is ready, proceeding to handle files.");
+            set_window_open_with_files(&app_handle, files.clone());
+        });
+        return;
+    }
+    let app = app.clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        let files = convert::prepare_open_files(&app, files);
+        allow_file_in_scopes(&app, files.clone());
+        // For a window that has not read them yet, and one that has
+        set_window_open_with_files(&app, files.clone());
+        let args = std::iter::once(String::new())
+            .chain(files.iter().map(|file| file.to_string_lossy().into_owned()))
+            .collect();
+        let payload = Payload {
+            args,
+            cwd: String::new(),
+        };
+        if let Err(e) = app.emit("single-instance", payload) {
+            log::warn!("Failed to hand over the opened files: {e}");
+        }
+    });
+}
+
+/// Opens the books and links of a command line handed over by another
+/// instance, or by the desktop over D-Bus on Linux.
+#[cfg(desktop)]
+fn open_argv(app: &AppHandle, argv: Vec<String>, cwd: String) {
+    show_main_window(app);
+    let app = app.clone();
+    // Converting the books may take a while, keep it off the main thread
+    tauri::async_runtime::spawn_blocking(move || {
+        let links = deep_link::links_from_argv(&argv);
+        let argv = convert_argv(&app, absolute_argv(argv, &cwd));
+        let files = get_files_from_argv(argv.clone());
+        if !files.is_empty() {
+            allow_file_in_scopes(&app, files.clone());
+        }
+        if let Err(e) = app.emit("single-instance", Payload { args: argv, cwd }) {
+            log::warn!("Failed to forward arguments of second instance: {e}");
+        }
+        deep_link::dispatch(&app, links);
+    });
+}
+
+fn context() -> tauri::Context<tauri::Wry> {
+    tauri::generate_context!()
+}
+
+/// Opens the library and manages the state that commands and jobs share,
+/// for both the app and the headless [`cli`].
+fn init_state(app: &tauri::AppHandle) -> Result<(), Box<dyn std::error::Error>> {
+    use tauri::Manager;
+
+    net::proxy::init(app);
+    net::downloads::init(app);
+    library::db::init(app)?;
+    library::access::init(app);
+    library::storage::init(app)?;
+    cloud::cache::init(app)?;
+    metadata::fetch::init(app);
+    if let Err(e) = search::init(app) {
+        log::warn!("Failed to open search index: {e}");
+    }
+
+    app.manage(jobs::Jobs::default());
+    app.manage(analysis::Analyzed::default());
+    app.manage(library::tags::TagHistory::default());
+    app.manage(commands::comic::OpenComics::default());
+    #[cfg(all(desktop, feature = "lcp"))]
+    app.manage(drm::lcp::LcpKeys::default());
+    #[cfg(feature = "djvu")]
+    app.manage(commands::djvu::OpenDjvus::default());
+    app.manage(commands::fb2::OpenFb2Books::default());
+    app.manage(commands::text::OpenTextBooks::default());
+    app.manage(dict::Dictionaries::default());
+    app.manage(fonts::SystemFonts::default());
+    app.manage(typeset::hyphenation::Hyphenators::default());
+    #[cfg(feature = "mecab")]
+    app.manage(typeset::transform::japanese::Mecab::default());
+    app.manage(translate::TranslationCache::default());
+    #[cfg(feature = "ocr")]
+    app.manage(ocr::OcrEngine::default());
+    app.manage(plugins::Plugins::default());
+    app.manage(hooks::Hooks::default());
+    #[cfg(desktop)]
+    app.manage(i18n::Localization::default());
+    Ok(())
+}
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     let builder = tauri::Builder::default()
         .plugin(tauri_plugin_process::init())
         .plugin(tauri_plugin_oauth::init())
+        .register_asynchronous_uri_scheme_protocol(
+            library::thumbs::PROTOCOL,
+            |ctx, request, responder| {
+                let app = ctx.app_handle().clone();
+                // Generating a thumbnail may decode a large cover, keep it off the main thread
+                tauri::async_runtime::spawn_blocking(move || {
+                    responder.respond(library::thumbs::handle_request(&app, &request));
+                });
+            },
+        )
+        .register_asynchronous_uri_scheme_protocol(
+            resources::PROTOCOL,
+            |ctx, request, responder| {
+                let app = ctx.app_handle().clone();
+                tauri::async_runtime::spawn_blocking(move || {
+                    responder.respond(resources::handle_request(&app, &request));
+                });
+            },
+        )
+        .register_asynchronous_uri_scheme_protocol(zim::PROTOCOL, |ctx, request, responder| {
+            let app = ctx.app_handle().clone();
+            // Opening an archive and decompressing clusters both block
+            tauri::async_runtime::spawn_blocking(move || {
+                responder.respond(zim::handle_request(&app, &request));
+            });
+        })
+        .manage(resources::BookResources::default())
+        .manage(resources::prefetch::Prefetcher::default())
+        .manage(images::ImageViewport::default())
+        .manage(zim::ZimArchives::default())
         .invoke_handler(tauri::generate_handler![
             start_server,
             download_file,
             upload_file,
             get_environment_variable,
+            convert::convert_book_to_epub,
+            convert::transcode::get_book_encoding,
+            convert::transcode::set_book_encoding,
+            dict::get_dictionaries,
+            dict::add_dictionary,
+            dict::remove_dictionary,
+            dict::lookup_word,
+            zim::get_zim_archives,
+            zim::add_zim_archive,
+            zim::remove_zim_archive,
+            zim::zim_lookup,
+            feeds::get_feeds,
+            feeds::set_feeds,
+            feeds::add_feed,
+            feeds::remove_feed,
+            feeds::update_feeds,
+            feeds::clip::clip_url,
+            tasks::get_scheduled_tasks,
+            tasks::set_scheduled_task,
+            tasks::run_scheduled_task,
+            tasks::report_scheduled_task,
+            jobs::list_jobs,
+            jobs::cancel_job,
+            net::proxy::get_proxy_config,
+            net::proxy::set_proxy_config,
+            net::proxy::test_proxy_config,
+            export::annotations::export_annotations,
+            export::catalog::export_catalog,
+            export::pdf::export_pdf,
+            export::templates::get_note_templates,
+            export::templates::set_note_templates,
+            export::templates::preview_note_template,
+            export::templates::apply_note_template,
+            #[cfg(desktop)]
+            import::annotations::import_annotations,
+            fonts::list_system_fonts,
+            fonts::embed_font,
+            #[cfg(feature = "ocr")]
+            ocr::ocr_page_region,
+            typeset::hyphenation::hyphenate_words,
+            typeset::pagination::get_pagination,
+            typeset::pagination::set_chapter_pages,
+            typeset::pagination::clear_pagination,
+            typeset::transform::get_text_transforms,
+            typeset::transform::set_text_transforms,
+            typeset::transform::transform_html,
+            typeset::math::render_math,
+            typeset::styles::get_user_styles,
+            typeset::styles::set_user_styles,
+            typeset::styles::apply_user_style,
+            typeset::styles::preview_user_style,
+            translate::get_translator,
+            translate::set_translator,
+            translate::translate_selection,
+            interop::calibre::import_calibre_library,
+            commands::comic::open_comic,
+            commands::comic::read_comic_page,
+            commands::comic::get_comic_thumbnail,
+            #[cfg(feature = "djvu")]
+            commands::djvu::get_djvu_info,
+            #[cfg(feature = "djvu")]
+            commands::djvu::render_djvu_page,
+            commands::fb2::open_fb2,
+            commands::fb2::read_fb2_chapter,
+            commands::fb2::read_fb2_binary,
+            commands::metadata::extract_epub_metadata,
+            commands::metadata::extract_epub_metadata_batch,
+            commands::pdf::get_pdf_info,
+            commands::pdf::extract_pdf_text,
+            commands::search::index_book,
+            commands::search::index_books,
+            commands::search::remove_from_search_index,
+            commands::search::search_library,
+            commands::sync::get_sync_provider,
+            commands::sync::set_sync_provider,
+            commands::sync::test_sync_provider,
+            commands::sync::sync_book_data,
+            commands::text::open_text_book,
+            commands::text::read_text_chapter,
+            #[cfg(feature = "acsm")]
+            drm::acsm::inspect_acsm,
+            #[cfg(feature = "acsm")]
+            drm::acsm::fulfill_acsm,
+            #[cfg(all(desktop, feature = "lcp"))]
+            drm::lcp::get_lcp_license,
+            #[cfg(all(desktop, feature = "lcp"))]
+            drm::lcp::unlock_lcp,
+            #[cfg(all(desktop, feature = "lcp"))]
+            drm::lcp::fulfill_lcp_license,
+            analysis::get_book_analysis,
+            analysis::analyze_library,
+            library::access::get_access_status,
+            library::access::set_access_pin,
+            library::access::set_library_lock,
+            library::access::set_collection_lock,
+            library::access::unlock_content,
+            library::access::lock_content,
+            library::backup::backup_library,
+            library::backup::restore_library,
+            library::collections::list_collections,
+            library::collections::create_collection,
+            library::collections::update_collection,
+            library::collections::delete_collection,
+            library::collections::evaluate_collection,
+            library::collections::preview_collection,
+            library::db::library_upsert_books,
+            library::db::library_get_book,
+            library::db::library_query_books,
+            library::db::library_delete_books,
+            library::dedup::find_duplicates,
+            library::dedup::check_duplicates,
+            library::identifiers::get_book_identifiers,
+            library::import::import_directory,
+            library::storage::get_library_storage,
+            library::storage::set_library_storage_mode,
+            library::storage::relocate_library_store,
+            library::storage::library_get_book_file,
+            library::tags::list_tags,
+            library::tags::rename_tag,
+            library::tags::merge_tags,
+            library::tags::delete_tags,
+            library::tags::delete_unused_tags,
+            library::tags::tag_books,
+            library::tags::undo_tag_change,
+            library::trash::list_trash,
+            library::trash::restore_book,
+            library::trash::empty_trash,
+            library::trash::get_trash_settings,
+            library::trash::set_trash_settings,
+            library::verify::verify_library,
+            library::verify::repair_library,
+            library::editions::remap_annotations,
+            library::editions::replace_book_edition,
+            cloud::get_cloud_accounts,
+            cloud::set_cloud_accounts,
+            cloud::test_cloud_account,
+            cloud::list_cloud_folder,
+            cloud::add_cloud_books,
+            cloud::get_cloud_cache,
+            cloud::set_cloud_cache_limit,
+            metadata::fetch::fetch_metadata,
+            metadata::fetch::get_metadata_proposals,
+            metadata::fetch::dismiss_metadata_proposal,
+            metadata::fetch::apply_metadata,
+            plugins::get_plugins,
+            plugins::install_plugin,
+            plugins::uninstall_plugin,
+            plugins::set_plugin_enabled,
+            plugins::reload_plugins,
+            plugins::export_with_plugin,
+            hooks::get_hooks,
+            hooks::set_hooks,
+            hooks::trigger_hooks,
+            opds::client::opds_browse,
+            opds::client::opds_search,
+            opds::client::opds_download,
+            net::downloads::list_downloads,
+            net::downloads::pause_download,
+            net::downloads::resume_download,
+            net::downloads::cancel_download,
+            net::downloads::clear_downloads,
+            net::downloads::get_download_settings,
+            net::downloads::set_download_settings,
+            opds::server::get_opds_server_config,
+            opds::server::get_opds_server_status,
+            opds::server::set_opds_server_config,
+            #[cfg(desktop)]
+            api::server::get_api_server_config,
+            #[cfg(desktop)]
+            api::server::get_api_server_status,
+            #[cfg(desktop)]
+            api::server::set_api_server_config,
+            #[cfg(desktop)]
+            api::server::get_api_token,
+            #[cfg(desktop)]
+            api::server::reset_api_token,
+            resources::open_book_resources,
+            images::set_image_viewport,
+            images::clear_image_cache,
+            resources::close_book_resources,
+            resources::prefetch::prefetch_book_resources,
+            send::email::get_email_config,
+            send::email::set_email_config,
+            send::email::test_email_config,
+            send::email::send_book,
+            stats::stats_record_session,
+            stats::stats_get_period_totals,
+            stats::stats_get_book_totals,
+            stats::stats_get_summary,
+            stats::stats_clear_sessions,
+            goals::get_reading_goal,
+            goals::set_reading_goal,
+            goals::get_goal_progress,
+            focus::get_focus_settings,
+            focus::set_focus_settings,
+            focus::get_focus_session,
+            focus::start_focus_session,
+            focus::stop_focus_session,
+            vocab::add_vocab_word,
+            vocab::list_vocab,
+            vocab::update_vocab_definition,
+            vocab::delete_vocab_words,
+            vocab::review_vocab_word,
+            vocab::export_vocab,
+            #[cfg(desktop)]
+            deep_link::take_pending_deep_links,
+            #[cfg(desktop)]
+            devices::list_devices,
+            #[cfg(desktop)]
+            devices::list_device_books,
+            #[cfg(desktop)]
+            devices::copy_to_device,
+            #[cfg(desktop)]
+            devices::eject_device,
+            #[cfg(desktop)]
+            print::print_chapter,
+            #[cfg(desktop)]
+            recent::add_recent_book,
+            #[cfg(desktop)]
+            recent::clear_recent_books,
+            #[cfg(desktop)]
+            recent::get_recent_books,
+            #[cfg(all(desktop, not(target_os = "macos")))]
+            associations::get_file_associations,
+            #[cfg(all(desktop, not(target_os = "macos")))]
+            associations::register_file_associations,
+            #[cfg(desktop)]
+            library::watcher::get_watch_folders,
+            #[cfg(desktop)]
+            library::watcher::add_watch_folder,
+            #[cfg(desktop)]
+            library::watcher::remove_watch_folder,
+            #[cfg(desktop)]
+            library::watcher::scan_watch_folders,
+            #[cfg(desktop)]
+            share::lan::get_lan_sharing,
+            #[cfg(desktop)]
+            share::lan::set_lan_sharing,
+            #[cfg(desktop)]
+            share::lan::discover_lan_peers,
+            #[cfg(desktop)]
+            share::lan::share_books_lan,
+            #[cfg(desktop)]
+            share::lan::respond_lan_share,
+            #[cfg(desktop)]
+            clipboard::watcher::get_clipboard_watcher,
+            #[cfg(desktop)]
+            clipboard::watcher::set_clipboard_watcher,
+            #[cfg(desktop)]
+            clipboard::add_copied_book,
+            #[cfg(desktop)]
+            secrets::store_secret,
+            #[cfg(desktop)]
+            secrets::get_secret,
+            #[cfg(desktop)]
+            secrets::storage::read_book_config,
+            #[cfg(desktop)]
+            secrets::notes::get_notes_protection,
+            #[cfg(desktop)]
+            secrets::notes::protect_book_notes,
+            #[cfg(desktop)]
+            secrets::notes::unlock_book_notes,
+            #[cfg(desktop)]
+            secrets::notes::lock_book_notes,
+            #[cfg(desktop)]
+            secrets::notes::unprotect_book_notes,
+            #[cfg(desktop)]
+            secrets::storage::write_book_config,
+            #[cfg(desktop)]
+            sync::sidecar::get_sidecar_settings,
+            #[cfg(desktop)]
+            sync::sidecar::set_sidecar_settings,
+            #[cfg(desktop)]
+            sync::sidecar::sync_sidecars,
+            #[cfg(desktop)]
+            shortcuts::get_global_shortcuts,
+            #[cfg(desktop)]
+            shortcuts::register_global_shortcut,
+            #[cfg(desktop)]
+            shortcuts::unregister_global_shortcut,
+            #[cfg(desktop)]
+            tray::get_tray_settings,
+            #[cfg(desktop)]
+            tray::set_tray_settings,
+            #[cfg(desktop)]
+            tray::set_tray_status,
+            #[cfg(desktop)]
+            window_manager::open_book_window,
+            #[cfg(desktop)]
+            window_manager::list_windows,
+            #[cfg(desktop)]
+            window_manager::eink::get_eink_settings,
+            #[cfg(desktop)]
+            window_manager::eink::set_eink_settings,
+            #[cfg(desktop)]
+            window_manager::eink::flash_refresh,
+            #[cfg(desktop)]
+            window_manager::eink::eink_page_turn,
+            #[cfg(desktop)]
+            session::update_reader_session,
+            #[cfg(desktop)]
+            session::take_restored_session,
+            #[cfg(desktop)]
+            diagnostics::create_diagnostics_bundle,
+            #[cfg(desktop)]
+            diagnostics::report_issue,
+            #[cfg(desktop)]
+            crash::get_crash_settings,
+            #[cfg(desktop)]
+            crash::set_crash_settings,
+            #[cfg(desktop)]
+            crash::list_crash_reports,
+            #[cfg(desktop)]
+            crash::delete_crash_report,
+            #[cfg(desktop)]
+            crash::clear_crash_reports,
+            #[cfg(desktop)]
+            crash::upload_crash_report,
+            #[cfg(desktop)]
+            updates::get_update_settings,
+            #[cfg(desktop)]
+            updates::set_update_settings,
+            #[cfg(desktop)]
+            updates::check_for_update,
+            #[cfg(desktop)]
+            updates::download_update,
+            #[cfg(desktop)]
+            updates::install_update,
+            #[cfg(desktop)]
+            paths::portable::get_data_dir,
+            #[cfg(desktop)]
+            paths::portable::migrate_data_dir,
+            #[cfg(desktop)]
+            profiles::list_profiles,
+            #[cfg(desktop)]
+            profiles::create_profile,
+            #[cfg(desktop)]
+            profiles::rename_profile,
+            #[cfg(desktop)]
+            profiles::switch_profile,
+            #[cfg(desktop)]
+            profiles::delete_profile,
+            #[cfg(desktop)]
+            profiles::set_pick_profile_at_startup,
+            #[cfg(desktop)]
+            i18n::set_locale,
+            #[cfg(any(target_os = "macos", target_os = "linux"))]
+            media_controls::set_now_playing,
+            #[cfg(desktop)]
+            audio::audio_open,
+            #[cfg(desktop)]
+            audio::audio_play,
+            #[cfg(desktop)]
+            audio::audio_pause,
+            #[cfg(desktop)]
+            audio::audio_seek,
+            #[cfg(desktop)]
+            audio::audio_set_speed,
+            #[cfg(desktop)]
+            audio::audio_close,
+            #[cfg(desktop)]
+            audio::overlay::media_overlay_open,
+            #[cfg(desktop)]
+            audio::overlay::media_overlay_play,
+            #[cfg(desktop)]
+            audio::overlay::media_overlay_pause,
+            #[cfg(desktop)]
+            audio::overlay::media_overlay_set_speed,
+            #[cfg(desktop)]
+            audio::overlay::media_overlay_close,
+            #[cfg(desktop)]
+            appearance::get_appearance_settings,
+            #[cfg(desktop)]
+            appearance::set_appearance_settings,
+            #[cfg(desktop)]
+            appearance::get_appearance_schedule,
+            #[cfg(desktop)]
+            voice::get_voice_note_settings,
+            #[cfg(desktop)]
+            voice::set_voice_note_settings,
+            #[cfg(desktop)]
+            voice::start_voice_note,
+            #[cfg(desktop)]
+            voice::get_voice_recording,
+            #[cfg(desktop)]
+            voice::stop_voice_note,
+            #[cfg(desktop)]
+            voice::cancel_voice_note,
+            #[cfg(desktop)]
+            voice::list_voice_notes,
+            #[cfg(desktop)]
+            voice::delete_voice_note,
+            #[cfg(desktop)]
+            voice::transcribe_voice_note,
+            #[cfg(desktop)]
+            tts::tts_get_voices,
+            #[cfg(desktop)]
+            tts::tts_speak,
+            #[cfg(desktop)]
+            tts::tts_pause,
+            #[cfg(desktop)]
+            tts::tts_resume,
+            #[cfg(desktop)]
+            tts::tts_stop,
+            #[cfg(desktop)]
+            tts::tts_set_rate,
+            #[cfg(desktop)]
+            tts::tts_set_voice,
             #[cfg(target_os = "macos")]
             macos::safari_auth::auth_with_safari,
             #[cfg(target_os = "macos")]
             macos::apple_auth::start_apple_sign_in,
             #[cfg(target_os = "macos")]
             macos::traffic_light::set_traffic_lights,
+            #[cfg(target_os = "macos")]
+            macos::menu::set_menu_reading,
+            #[cfg(target_os = "macos")]
+            macos::touch_bar::get_touch_bar_settings,
+            #[cfg(target_os = "macos")]
+            macos::touch_bar::set_touch_bar_settings,
+            #[cfg(any(target_os = "macos", windows))]
+            system_search::get_system_search_enabled,
+            #[cfg(any(target_os = "macos", windows))]
+            system_search::set_system_search_enabled,
+            #[cfg(windows)]
+            windows::shell::get_shell_integration,
+            #[cfg(windows)]
+            windows::shell::set_shell_integration,
         ])
         .plugin(tauri_plugin_shell::init())
         .plugin(tauri_plugin_opener::init())
@@ -142,29 +901,56 @@ pub fn run() {
         .plugin(tauri_plugin_dialog::init())
         .plugin(tauri_plugin_native_bridge::init())
         .plugin(tauri_plugin_native_tts::init())
-        .plugin(tauri_plugin_fs::init());
+        .plugin(tauri_plugin_fs::init())
+        .plugin(tauri_plugin_notification::init());
 
     #[cfg(desktop)]
     let builder = builder.plugin(tauri_plugin_single_instance::init(|app, argv, cwd| {
-        let _ = app
-            .get_webview_window("main")
-            .expect("no main window")
-            .set_focus();
-        let files = get_files_from_argv(argv.clone());
-        if !files.is_empty() {
-            allow_file_in_scopes(app, files.clone());
-        }
-        app.emit("single-instance", Payload { args: argv, cwd })
-            .unwrap();
+        open_argv(app, argv, cwd);
     }));
 
+    #[cfg(desktop)]
+    let builder = builder.manage(deep_link::DeepLinks::default());
+
+    #[cfg(desktop)]
+    let builder = builder
+        .register_asynchronous_uri_scheme_protocol(voice::PROTOCOL, |ctx, request, responder| {
+            let app = ctx.app_handle().clone();
+            tauri::async_runtime::spawn_blocking(move || {
+                responder.respond(voice::handle_request(&app, &request));
+            });
+        })
+        .manage(voice::VoiceNotes::default());
+
+    #[cfg(desktop)]
+    let builder = builder
+        .plugin(shortcuts::plugin())
+        .manage(shortcuts::GlobalShortcuts::default());
+
+    #[cfg(desktop)]
+    let builder = builder
+        .on_page_load(window_manager::eink::on_page_load)
+        .manage(tray::Tray::default())
+        .manage(updates::Updates::default())
+        .on_window_event(|window, event| {
+            tray::on_window_event(window, event);
+            appearance::on_window_event(window, event);
+            window_manager::on_window_event(window, event);
+            session::on_window_event(window, event);
+        });
+
     let builder = builder.plugin(tauri_plugin_deep_link::init());
 
     #[cfg(desktop)]
     let builder = builder.plugin(tauri_plugin_updater::Builder::new().build());
 
     #[cfg(desktop)]
-    let builder = builder.plugin(tauri_plugin_window_state::Builder::default().build());
+    let builder = builder.plugin(
+        tauri_plugin_window_state::Builder::default()
+            // Book windows are placed by the window manager
+            .with_filter(|label| !label.starts_with(window_manager::LABEL_PREFIX))
+            .build(),
+    );
 
     #[cfg(target_os = "macos")]
     let builder = builder.plugin(macos::traffic_light::init());
@@ -180,19 +966,72 @@ pub fn run() {
 
     builder
         .setup(|#[allow(unused_variables)] app| {
+            // Before anything opens the files it may move
+            #[cfg(desktop)]
+            let migration = paths::portable::init(app.handle());
+            #[cfg(desktop)]
+            profiles::init(app.handle());
+            #[cfg(desktop)]
+            logging::init(app.handle(), tracing_subscriber::filter::LevelFilter::INFO);
+            #[cfg(desktop)]
+            match migration {
+                Ok(true) => log::info!("Moved the data to {:?}", paths::data_dir(app.handle())),
+                Ok(false) => {}
+                Err(e) => log::warn!("Failed to move the data directory: {e}"),
+            }
+            #[cfg(desktop)]
+            crash::init(app.handle());
+
+            #[cfg(desktop)]
+            open_with_files(
+                app.handle(),
+                get_files_from_argv(std::env::args().collect()),
+            );
+
+            init_state(app.handle())?;
+
+            // Links may clip pages or add copied books, which needs the library
             #[cfg(desktop)]
             {
-                let files = get_files_from_argv(std::env::args().collect());
-                if !files.is_empty() {
-                    let app_handle = app.handle().clone();
-                    allow_file_in_scopes(&app_handle, files.clone());
-                    app.listen("window-ready", move |_| {
-                        println!("Window is ready, proceeding to handle files.");
-                        set_window_open_with_files(&app_handle, files.clone());
-                    });
-                }
+                clipboard::watcher::init(app.handle());
+                let argv = std::env::args().collect::<Vec<_>>();
+                deep_link::dispatch(app.handle(), deep_link::links_from_argv(&argv));
             }
 
+            opds::server::init(app.handle());
+            #[cfg(desktop)]
+            api::server::init(app.handle());
+            app.manage(feeds::Feeds::default());
+
+            #[cfg(desktop)]
+            app.manage(tts::Tts::default());
+            #[cfg(desktop)]
+            app.manage(audio::Audio::default());
+            #[cfg(desktop)]
+            app.manage(audio::overlay::Overlays::default());
+            #[cfg(any(target_os = "macos", target_os = "linux"))]
+            app.manage(media_controls::MediaSession::default());
+
+            #[cfg(desktop)]
+            shortcuts::init(app.handle());
+
+            #[cfg(desktop)]
+            appearance::init(app.handle());
+
+            #[cfg(desktop)]
+            share::lan::init(app.handle());
+
+            #[cfg(desktop)]
+            if let Err(e) = library::watcher::init(app.handle()) {
+                log::warn!("Failed to start library watcher: {e}");
+            }
+
+            goals::init(app.handle());
+            app.manage(focus::FocusTimer::default());
+            #[cfg(desktop)]
+            app.manage(secrets::notes::PrivateNotes::default());
+            tasks::init(app.handle());
+
             #[cfg(desktop)]
             {
                 app.handle().plugin(tauri_plugin_cli::init())?;
@@ -213,13 +1052,10 @@ pub fn run() {
                     }
                     #[cfg(target_os = "linux")]
                     {
-                        let is_appimage = std::env::var("APPIMAGE").is_ok()
-                            || std::env::current_exe()
-                                .map(|path| path.to_string_lossy().contains("/tmp/.mount_"))
-                                .unwrap_or(false);
-
-                        let script =
-                            format!("window.__READEST_UPDATER_DISABLED = {};", !is_appimage);
+                        let script = format!(
+                            "window.__READEST_UPDATER_DISABLED = {};",
+                            !updates::supported()
+                        );
                         webview
                             .eval(&script)
                             .expect("Failed to set updater disabled config");
@@ -233,12 +1069,13 @@ pub fn run() {
                 let _ = app.deep_link().register_all();
             }
 
-            if let Err(e) = app.handle().plugin(
-                tauri_plugin_log::Builder::default()
-                    .level(log::LevelFilter::Info)
-                    .build(),
-            ) {
-                eprintln!("Failed to initialize tauri_plugin_log: {e}");
+            // The frontend logs through the plugin, into the log files
+            #[cfg(desktop)]
+            let log_plugin = tauri_plugin_log::Builder::default().skip_logger();
+            #[cfg(mobile)]
+            let log_plugin = tauri_plugin_log::Builder::default().level(log::LevelFilter::Info);
+            if let Err(e) = app.handle().plugin(log_plugin.build()) {
+                log::warn!("Failed to initialize tauri_plugin_log: {e}");
             };
 
             let win_builder = WebviewWindowBuilder::new(app, "main", WebviewUrl::default())
@@ -248,6 +1085,11 @@ pub fn run() {
             #[cfg(desktop)]
             let win_builder = win_builder.inner_size(800.0, 600.0).resizable(true);
 
+            #[cfg(desktop)]
+            let win_builder = paths::configure_webview(app.handle(), win_builder);
+            #[cfg(desktop)]
+            let win_builder = window_manager::eink::configure_webview(app.handle(), win_builder);
+
             #[cfg(target_os = "macos")]
             let win_builder = win_builder
                 .decorations(true)
@@ -282,29 +1124,61 @@ pub fn run() {
 
             #[cfg(target_os = "macos")]
             macos::menu::setup_macos_menu(app.handle())?;
+            #[cfg(target_os = "macos")]
+            macos::services::init(app.handle());
+            #[cfg(target_os = "linux")]
+            linux::dbus::init(app.handle());
+
+            #[cfg(desktop)]
+            tray::init(app.handle());
+
+            #[cfg(desktop)]
+            recent::init(app.handle());
+
+            #[cfg(desktop)]
+            window_manager::init(app.handle());
+
+            #[cfg(desktop)]
+            session::init(app.handle());
+
+            #[cfg(desktop)]
+            updates::init(app.handle());
+
+            #[cfg(any(target_os = "macos", windows))]
+            system_search::init(app.handle());
 
             app.handle().emit("window-ready", ()).unwrap();
 
             Ok(())
         })
-        .build(tauri::generate_context!())
+        .build(context())
         .expect("error while running tauri application")
         .run(
             #[allow(unused_variables)]
             |app_handle, event| {
+                #[cfg(desktop)]
+                if let tauri::RunEvent::Exit = event {
+                    window_manager::on_exit(app_handle);
+                    session::on_exit(app_handle);
+                    updates::on_exit(app_handle);
+                    focus::on_exit(app_handle);
+                }
                 #[cfg(target_os = "macos")]
                 if let tauri::RunEvent::Opened { urls } = event {
+                    let (links, urls): (Vec<_>, Vec<_>) = urls
+                        .into_iter()
+                        .partition(|url| url.scheme() == deep_link::SCHEME);
+                    let links = links
+                        .iter()
+                        .filter_map(deep_link::DeepLink::parse)
+                        .collect();
+                    deep_link::dispatch(app_handle, links);
+
                     let files = urls
                         .into_iter()
                         .filter_map(|url| url.to_file_path().ok())
                         .collect::<Vec<_>>();
-
-                    let app_handler_clone = app_handle.clone();
-                    allow_file_in_scopes(app_handle, files.clone());
-                    app_handle.listen("window-ready", move |_| {
-                        println!("Window is ready, proceeding to handle files.");
-                        set_window_open_with_files(&app_handler_clone, files.clone());
-                    });
+                    open_with_files(app_handle, files);
                 }
             },
         );
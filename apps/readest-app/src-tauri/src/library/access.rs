@@ -0,0 +1,415 @@
+//! PIN locks on content, so that a child sharing the app, in a profile of
+//! their own or not, sees only the books meant for them. A profile can lock
+//! its whole library, and any collection can be locked, which hides the
+//! books its rule matches. Locks are enforced where books are queried, not
+//! in the frontend: the commands that list, get or search books leave out
+//! the locked ones until the PIN unlocks them, for the rest of the run or
+//! until everything is locked again.
+
+use std::collections::HashSet;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use rusqlite::types::Value;
+use rusqlite::{params_from_iter, Connection};
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle, Manager, State};
+
+use super::collections::{self, Collection, Rule};
+use super::db::LibraryDb;
+use crate::error::{Error, Result};
+use crate::store;
+use crate::utils::now_millis;
+
+/// Kept per profile, as are the collections it locks.
+const ACCESS_FILE: &str = "access.json";
+const MIN_PIN_LEN: usize = 4;
+/// Wrong PINs in a row after which the next try has to wait.
+const MAX_FAILURES: u32 = 5;
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct Settings {
+    /// Argon2 hash of the PIN, in PHC format. Nothing is locked without one.
+    pin: Option<String>,
+    lock_library: bool,
+}
+
+#[derive(Default)]
+struct Session {
+    settings: Settings,
+    /// Whether the PIN unlocked everything this run.
+    all_unlocked: bool,
+    /// Locked collections unlocked this run.
+    unlocked: HashSet<i64>,
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl Session {
+    fn library_locked(&self) -> bool {
+        self.settings.pin.is_some() && self.settings.lock_library && !self.all_unlocked
+    }
+
+    fn collection_locked(&self, collection: &Collection) -> bool {
+        self.library_locked()
+            || (self.settings.pin.is_some()
+                && !self.all_unlocked
+                && collection.locked
+                && !self.unlocked.contains(&collection.id))
+    }
+
+    /// Checks `pin` against the one set, making whoever guesses wait after
+    /// a few wrong tries.
+    fn verify(&mut self, pin: &str) -> Result<()> {
+        let Some(hash) = &self.settings.pin else {
+            return Err(access_error("no PIN is set"));
+        };
+        if let Some(at) = self.retry_at {
+            let now = Instant::now();
+            if now < at {
+                return Err(access_error(format!(
+                    "too many wrong PINs, try again in {} s",
+                    (at - now).as_secs() + 1
+                )));
+            }
+        }
+        let hash = PasswordHash::new(hash).map_err(|e| access_error(e.to_string()))?;
+        if Argon2::default()
+            .verify_password(pin.as_bytes(), &hash)
+            .is_ok()
+        {
+            self.failures = 0;
+            self.retry_at = None;
+            return Ok(());
+        }
+        self.failures += 1;
+        if self.failures >= MAX_FAILURES {
+            self.retry_at = Some(Instant::now() + RETRY_DELAY);
+        }
+        Err(access_error("wrong PIN"))
+    }
+}
+
+pub struct Access(Mutex<Session>);
+
+impl Access {
+    fn session(&self) -> MutexGuard<'_, Session> {
+        self.0.lock().unwrap()
+    }
+
+    /// What queries have to leave out right now.
+    pub fn lock(&self, conn: &Connection) -> Result<Lock> {
+        let session = self.session();
+        if session.settings.pin.is_none() {
+            return Ok(Lock::None);
+        }
+        if session.library_locked() {
+            return Ok(Lock::All);
+        }
+        let rules = collections::list(conn)?
+            .into_iter()
+            .filter(|collection| session.collection_locked(collection))
+            .map(|collection| collection.rule)
+            .collect::<Vec<_>>();
+        Ok(Lock::rules(rules))
+    }
+
+    /// What queries have to leave out once everything is locked again, for
+    /// what outlives the run, such as the system search index.
+    #[cfg(any(target_os = "macos", windows))]
+    pub fn locked(&self, conn: &Connection) -> Result<Lock> {
+        let settings = self.session().settings.clone();
+        if settings.pin.is_none() {
+            return Ok(Lock::None);
+        }
+        if settings.lock_library {
+            return Ok(Lock::All);
+        }
+        let rules = collections::list(conn)?
+            .into_iter()
+            .filter(|collection| collection.locked)
+            .map(|collection| collection.rule)
+            .collect::<Vec<_>>();
+        Ok(Lock::rules(rules))
+    }
+
+    /// Whether the books of `collection` are hidden right now.
+    pub fn is_locked(&self, collection: &Collection) -> bool {
+        self.session().collection_locked(collection)
+    }
+}
+
+/// The books a query leaves out.
+#[derive(Debug, Clone, Default)]
+pub enum Lock {
+    #[default]
+    None,
+    /// The whole library.
+    All,
+    /// The books matching any of these, the rules of locked collections.
+    Rules(Vec<Rule>),
+}
+
+impl Lock {
+    fn rules(rules: Vec<Rule>) -> Self {
+        if rules.is_empty() {
+            Lock::None
+        } else {
+            Lock::Rules(rules)
+        }
+    }
+
+    /// SQL conditions over `books b` that the books left in meet, with
+    /// their parameters appended to `params`.
+    pub fn conditions(&self, params: &mut Vec<Value>) -> Vec<String> {
+        match self {
+            Lock::None => Vec::new(),
+            Lock::All => vec!["0".to_string()],
+            Lock::Rules(rules) => {
+                let now = now_millis();
+                rules
+                    .iter()
+                    .map(|rule| {
+                        // A condition that is NULL for a book hides it too
+                        format!(
+                            "NOT COALESCE({}, 1)",
+                            collections::condition(rule, now, params)
+                        )
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// The hashes of the books left out, e.g. to drop them from search
+    /// results, or `None` when they all are.
+    pub fn hidden_books(&self, conn: &Connection) -> Result<Option<Vec<String>>> {
+        let mut params = Vec::new();
+        let conditions = match self {
+            Lock::None => return Ok(Some(Vec::new())),
+            Lock::All => return Ok(None),
+            Lock::Rules(_) => self.conditions(&mut params),
+        };
+        let sql = format!(
+            "SELECT b.hash FROM books b WHERE NOT ({})",
+            conditions.join(" AND ")
+        );
+        let mut stmt = conn.prepare(&sql)?;
+        let hashes = stmt
+            .query_map(params_from_iter(params.iter()), |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(Some(hashes))
+    }
+}
+
+/// What queries have to leave out right now, for callers without the
+/// [`Access`] state at hand.
+pub fn lock(app: &AppHandle, conn: &Connection) -> Result<Lock> {
+    match app.try_state::<Access>() {
+        Some(access) => access.lock(conn),
+        None => Ok(Lock::None),
+    }
+}
+
+/// What queries have to leave out with everything locked, see
+/// [`Access::locked`].
+#[cfg(any(target_os = "macos", windows))]
+pub fn locked(app: &AppHandle, conn: &Connection) -> Result<Lock> {
+    match app.try_state::<Access>() {
+        Some(access) => access.locked(conn),
+        None => Ok(Lock::None),
+    }
+}
+
+pub fn init(app: &AppHandle) {
+    app.manage(Access(Mutex::new(Session {
+        settings: store::load(app, ACCESS_FILE),
+        ..Session::default()
+    })));
+}
+
+fn access_error(message: impl Into<String>) -> Error {
+    Error::Access(message.into())
+}
+
+fn hash_pin(pin: &str) -> Result<String> {
+    if pin.chars().count() < MIN_PIN_LEN {
+        return Err(access_error(format!(
+            "a PIN needs at least {MIN_PIN_LEN} characters"
+        )));
+    }
+    let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>())
+        .map_err(|e| access_error(e.to_string()))?;
+    Ok(Argon2::default()
+        .hash_password(pin.as_bytes(), &salt)
+        .map_err(|e| access_error(e.to_string()))?
+        .to_string())
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccessStatus {
+    pub has_pin: bool,
+    pub lock_library: bool,
+    /// Whether the library is hidden right now.
+    pub library_locked: bool,
+    pub locked_collections: Vec<i64>,
+    /// Locked collections unlocked this run.
+    pub unlocked_collections: Vec<i64>,
+}
+
+#[command]
+pub async fn get_access_status(
+    db: State<'_, LibraryDb>,
+    access: State<'_, Access>,
+) -> Result<AccessStatus> {
+    let locked = collections::list(&db.conn())?
+        .into_iter()
+        .filter(|collection| collection.locked)
+        .collect::<Vec<_>>();
+    let session = access.session();
+    Ok(AccessStatus {
+        has_pin: session.settings.pin.is_some(),
+        lock_library: session.settings.lock_library,
+        library_locked: session.library_locked(),
+        locked_collections: locked.iter().map(|collection| collection.id).collect(),
+        unlocked_collections: locked
+            .iter()
+            .filter(|collection| !session.collection_locked(collection))
+            .map(|collection| collection.id)
+            .collect(),
+    })
+}
+
+/// Shows what the lock leaves to the menus listing books, once it changed.
+fn changed(app: &AppHandle) {
+    #[cfg(desktop)]
+    if let Err(e) = crate::recent::refresh(app) {
+        log::warn!("Failed to show recent books: {e}");
+    }
+    #[cfg(not(desktop))]
+    let _ = app;
+}
+
+/// Like [`changed`], for when what is locked for good changed too.
+fn locks_changed(app: &AppHandle) {
+    changed(app);
+    #[cfg(any(target_os = "macos", windows))]
+    crate::system_search::refresh(app);
+}
+
+/// Sets, changes or, when `new_pin` is `None`, removes the PIN, which takes
+/// the current one once there is one. Removing it lifts every lock.
+#[command]
+pub async fn set_access_pin(
+    app: AppHandle,
+    db: State<'_, LibraryDb>,
+    access: State<'_, Access>,
+    current_pin: Option<String>,
+    new_pin: Option<String>,
+) -> Result<()> {
+    {
+        // In the order queries take them
+        let conn = db.conn();
+        let mut session = access.session();
+        if session.settings.pin.is_some() {
+            session.verify(current_pin.as_deref().unwrap_or_default())?;
+        }
+        let mut settings = session.settings.clone();
+        match new_pin {
+            Some(pin) => settings.pin = Some(hash_pin(&pin)?),
+            None => {
+                conn.execute("UPDATE collections SET locked = 0 WHERE locked = 1", [])?;
+                settings = Settings::default();
+            }
+        }
+        store::save(&app, ACCESS_FILE, &settings)?;
+        session.settings = settings;
+    }
+    locks_changed(&app);
+    Ok(())
+}
+
+/// Locks or unlocks the whole library of the profile for good, which
+/// takes the PIN.
+#[command]
+pub async fn set_library_lock(
+    app: AppHandle,
+    access: State<'_, Access>,
+    pin: String,
+    locked: bool,
+) -> Result<()> {
+    {
+        let mut session = access.session();
+        session.verify(&pin)?;
+        let mut settings = session.settings.clone();
+        settings.lock_library = locked;
+        store::save(&app, ACCESS_FILE, &settings)?;
+        session.settings = settings;
+    }
+    locks_changed(&app);
+    Ok(())
+}
+
+/// Locks or unlocks collection `id` for good, which takes the PIN.
+#[command]
+pub async fn set_collection_lock(
+    app: AppHandle,
+    db: State<'_, LibraryDb>,
+    access: State<'_, Access>,
+    id: i64,
+    pin: String,
+    locked: bool,
+) -> Result<Collection> {
+    let collection = {
+        let conn = db.conn();
+        access.session().verify(&pin)?;
+        collections::get(&conn, id)?;
+        conn.execute(
+            "UPDATE collections SET locked = ?1 WHERE id = ?2",
+            rusqlite::params![locked, id],
+        )?;
+        collections::get(&conn, id)?
+    };
+    locks_changed(&app);
+    Ok(collection)
+}
+
+/// Unlocks collection `id`, or the library and every collection when there
+/// is none, until the app quits or [`lock_content`] is called.
+#[command]
+pub async fn unlock_content(
+    app: AppHandle,
+    access: State<'_, Access>,
+    pin: String,
+    collection_id: Option<i64>,
+) -> Result<()> {
+    {
+        let mut session = access.session();
+        session.verify(&pin)?;
+        match collection_id {
+            Some(id) => {
+                session.unlocked.insert(id);
+            }
+            None => session.all_unlocked = true,
+        }
+    }
+    changed(&app);
+    Ok(())
+}
+
+/// Locks again everything unlocked this run.
+#[command]
+pub async fn lock_content(app: AppHandle, access: State<'_, Access>) -> Result<()> {
+    {
+        let mut session = access.session();
+        session.all_unlocked = false;
+        session.unlocked.clear();
+    }
+    changed(&app);
+    Ok(())
+}
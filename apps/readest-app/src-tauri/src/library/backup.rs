@@ -0,0 +1,433 @@
+//! Backups of the whole library as a single zip: the catalog database, the
+//! per-book configs that hold annotations and reading progress, the
+//! settings, and optionally the book files. `manifest.json` lists every
+//! other entry with its size and SHA-256, and a restore verifies them all
+//! before anything is replaced.
+//!
+//! Configs encrypted at rest are copied as they are, so they can only be
+//! read where the keychain holds their key.
+//!
+//! Only the settings files of preferences are backed up and restored, those
+//! in `SETTINGS_FILES`. Those that run programs, open servers, send data
+//! elsewhere or lock the library, such as approved hooks or the PIN, never
+//! come from a backup, which could have been made by anyone.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tauri::{command, AppHandle, Emitter, Manager};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use super::db::LibraryDb;
+use crate::error::{Error, Result};
+use crate::formats::is_book_file;
+use crate::paths;
+use crate::utils::now_millis;
+
+const FORMAT_VERSION: u32 = 1;
+const MANIFEST: &str = "manifest.json";
+const DB_ENTRY: &str = "library.db";
+const SETTINGS_PREFIX: &str = "settings/";
+const BOOKS_PREFIX: &str = "books/";
+
+/// The settings files a backup carries: the frontend's and the native
+/// preferences of reading and of the library.
+const SETTINGS_FILES: &[&str] = &[
+    "settings.json",
+    "locale.json",
+    "user_styles.json",
+    "text_transforms.json",
+    "note_templates.json",
+    "book-encodings.json",
+    "global-shortcuts.json",
+    "touch-bar.json",
+    "tray.json",
+    "eink.json",
+    "appearance.json",
+    "clipboard.json",
+    "dictionaries.json",
+    "downloads.json",
+    "feeds.json",
+    "focus.json",
+    "goals.json",
+    "recent-books.json",
+    "sidecars.json",
+    "system-search.json",
+    "trash.json",
+    "updates.json",
+    "crash-reporting.json",
+];
+
+/// Where the database snapshot and restored files are staged, under the
+/// app data dir.
+const SNAPSHOT_FILE: &str = "library-backup.db";
+const STAGING_DIR: &str = "restore.tmp";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    version: u32,
+    created_at: i64,
+    include_books: bool,
+    files: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ManifestEntry {
+    path: String,
+    size: u64,
+    sha256: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Progress {
+    current: usize,
+    total: usize,
+    path: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupSummary {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RestoreSummary {
+    pub files: usize,
+    pub include_books: bool,
+    pub created_at: i64,
+}
+
+fn invalid(message: impl Into<String>) -> Error {
+    Error::InvalidBackup(message.into())
+}
+
+/// Copies `reader` to `writer`, returning the number of bytes and their SHA-256.
+fn copy_hashed(mut reader: impl Read, mut writer: impl Write) -> Result<(u64, String)> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        hasher.update(&buf[..n]);
+        writer.write_all(&buf[..n])?;
+        size += n as u64;
+    }
+    Ok((size, format!("{:x}", hasher.finalize())))
+}
+
+/// Appends the files under `dir` accepted by `filter` to `out`, named by
+/// their path relative to `dir` after `prefix`.
+fn collect(
+    dir: &Path,
+    prefix: &str,
+    recursive: bool,
+    filter: &dyn Fn(&Path) -> bool,
+    out: &mut Vec<(String, PathBuf)>,
+) -> Result<()> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() && recursive {
+            collect(&path, &format!("{prefix}{name}/"), true, filter, out)?;
+        } else if file_type.is_file()
+            && path.extension().map_or(true, |ext| ext != "tmp")
+            && filter(&path)
+        {
+            out.push((format!("{prefix}{name}"), path));
+        }
+    }
+    Ok(())
+}
+
+fn is_settings_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| SETTINGS_FILES.contains(&name))
+}
+
+/// Where an entry other than the database is restored to, or `None` if its
+/// name is not one a backup contains.
+fn destination(config_dir: &Path, books_dir: &Path, name: &str) -> Option<PathBuf> {
+    let (dir, rest) = if let Some(rest) = name.strip_prefix(SETTINGS_PREFIX) {
+        if !SETTINGS_FILES.contains(&rest) {
+            return None;
+        }
+        (config_dir, rest)
+    } else if let Some(rest) = name.strip_prefix(BOOKS_PREFIX) {
+        (books_dir, rest)
+    } else {
+        return None;
+    };
+    let rest = Path::new(rest);
+    let safe = rest.components().next().is_some()
+        && rest.components().all(|c| matches!(c, Component::Normal(_)));
+    safe.then(|| dir.join(rest))
+}
+
+pub(crate) fn backup(app: &AppHandle, dest: &Path, include_books: bool) -> Result<BackupSummary> {
+    let data_dir = paths::data_dir(app)?;
+    let snapshot = data_dir.join(SNAPSHOT_FILE);
+    let _ = std::fs::remove_file(&snapshot);
+    app.state::<LibraryDb>().snapshot(&snapshot)?;
+
+    let mut sources = vec![(DB_ENTRY.to_string(), snapshot.clone())];
+    collect(
+        &paths::config_dir(app)?,
+        SETTINGS_PREFIX,
+        false,
+        &|path| is_settings_file(path),
+        &mut sources,
+    )?;
+    collect(
+        &super::books_dir(app)?,
+        BOOKS_PREFIX,
+        true,
+        &|path| include_books || !is_book_file(path),
+        &mut sources,
+    )?;
+
+    let tmp = dest.with_extension("tmp");
+    let result = write_archive(app, &tmp, &sources, include_books);
+    let _ = std::fs::remove_file(&snapshot);
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result?;
+    std::fs::rename(&tmp, dest)?;
+    Ok(BackupSummary {
+        files: sources.len(),
+        bytes: std::fs::metadata(dest)?.len(),
+    })
+}
+
+fn write_archive(
+    app: &AppHandle,
+    path: &Path,
+    sources: &[(String, PathBuf)],
+    include_books: bool,
+) -> Result<()> {
+    let mut zip = ZipWriter::new(BufWriter::new(File::create(path)?));
+    let mut files = Vec::with_capacity(sources.len());
+    for (i, (name, source)) in sources.iter().enumerate() {
+        let file = File::open(source)?;
+        // Books and covers are compressed already
+        let compressed = is_book_file(source)
+            || source
+                .extension()
+                .is_some_and(|ext| ext == "png" || ext == "jpg");
+        let options = SimpleFileOptions::default()
+            .compression_method(if compressed {
+                CompressionMethod::Stored
+            } else {
+                CompressionMethod::Deflated
+            })
+            .large_file(file.metadata()?.len() >= u32::MAX as u64);
+        zip.start_file(name.as_str(), options)?;
+        let (size, sha256) = copy_hashed(BufReader::new(file), &mut zip)?;
+        files.push(ManifestEntry {
+            path: name.clone(),
+            size,
+            sha256,
+        });
+        let _ = app.emit(
+            "backup-progress",
+            Progress {
+                current: i + 1,
+                total: sources.len(),
+                path: name.clone(),
+            },
+        );
+    }
+    let manifest = Manifest {
+        version: FORMAT_VERSION,
+        created_at: now_millis(),
+        include_books,
+        files,
+    };
+    zip.start_file(MANIFEST, SimpleFileOptions::default())?;
+    zip.write_all(&serde_json::to_vec_pretty(&manifest)?)?;
+    zip.finish()?.flush()?;
+    Ok(())
+}
+
+fn restore(app: &AppHandle, source: &Path) -> Result<RestoreSummary> {
+    let mut zip = ZipArchive::new(BufReader::new(File::open(source)?))?;
+    let manifest: Manifest = match zip.by_name(MANIFEST) {
+        Ok(entry) => serde_json::from_reader(entry)?,
+        Err(_) => return Err(invalid("missing manifest")),
+    };
+    if manifest.version > FORMAT_VERSION {
+        return Err(invalid(format!("unsupported version {}", manifest.version)));
+    }
+    if !manifest.files.iter().any(|entry| entry.path == DB_ENTRY) {
+        return Err(invalid("missing library database"));
+    }
+
+    let staging = paths::data_dir(app)?.join(STAGING_DIR);
+    if staging.exists() {
+        std::fs::remove_dir_all(&staging)?;
+    }
+    std::fs::create_dir_all(&staging)?;
+    let result = extract(app, &mut zip, &manifest, &staging);
+    let _ = std::fs::remove_dir_all(&staging);
+    result?;
+    Ok(RestoreSummary {
+        files: manifest.files.len(),
+        include_books: manifest.include_books,
+        created_at: manifest.created_at,
+    })
+}
+
+/// Extracts and verifies every entry into `staging`, then moves them into
+/// place, so a damaged backup leaves the library untouched.
+fn extract(
+    app: &AppHandle,
+    zip: &mut ZipArchive<BufReader<File>>,
+    manifest: &Manifest,
+    staging: &Path,
+) -> Result<()> {
+    let config_dir = paths::config_dir(app)?;
+    let books_dir = super::books_dir(app)?;
+    let total = manifest.files.len();
+    let mut staged = Vec::with_capacity(total);
+    for (i, entry) in manifest.files.iter().enumerate() {
+        let dest = match entry.path.as_str() {
+            DB_ENTRY => None,
+            // Older backups have every settings file
+            name if name.starts_with(SETTINGS_PREFIX)
+                && destination(&config_dir, &books_dir, name).is_none() =>
+            {
+                log::info!("Not restoring {name}");
+                continue;
+            }
+            name => Some(
+                destination(&config_dir, &books_dir, name)
+                    .ok_or_else(|| invalid(format!("unexpected entry {name}")))?,
+            ),
+        };
+        let path = staging.join(&entry.path);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = zip
+            .by_name(&entry.path)
+            .map_err(|_| invalid(format!("missing entry {}", entry.path)))?;
+        let mut out = BufWriter::new(File::create(&path)?);
+        let (size, sha256) = copy_hashed(file, &mut out)?;
+        out.flush()?;
+        if size != entry.size || sha256 != entry.sha256 {
+            return Err(invalid(format!("{} is damaged", entry.path)));
+        }
+        staged.push((path, dest));
+        let _ = app.emit(
+            "restore-progress",
+            Progress {
+                current: i + 1,
+                total,
+                path: entry.path.clone(),
+            },
+        );
+    }
+
+    for (path, dest) in staged {
+        match dest {
+            Some(dest) => super::move_file(&path, &dest)?,
+            None => app.state::<LibraryDb>().replace(&path)?,
+        }
+    }
+    Ok(())
+}
+
+/// Writes a backup of the library to `path`, with the book files when
+/// `include_books` is set.
+#[command]
+pub async fn backup_library(
+    app: AppHandle,
+    path: String,
+    include_books: bool,
+) -> Result<BackupSummary> {
+    tauri::async_runtime::spawn_blocking(move || backup(&app, Path::new(&path), include_books))
+        .await?
+}
+
+/// Restores the backup at `path` over the current library, keeping files it
+/// does not contain. The frontend should reload afterwards, since the
+/// settings and library changed under it.
+#[command]
+pub async fn restore_library(app: AppHandle, path: String) -> Result<RestoreSummary> {
+    tauri::async_runtime::spawn_blocking(move || restore(&app, Path::new(&path))).await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_stay_in_their_directory() {
+        let (config, books) = (Path::new("/config"), Path::new("/books"));
+        assert_eq!(
+            destination(config, books, "settings/settings.json"),
+            Some(config.join("settings.json"))
+        );
+        assert_eq!(
+            destination(config, books, "books/Author/book.epub"),
+            Some(books.join("Author/book.epub"))
+        );
+        for name in [
+            "books/",
+            "books/../settings.json",
+            "books/a/../../b.epub",
+            "books//etc/passwd",
+            "books/./book.epub",
+            "settings/../settings.json",
+            "settings/sub/settings.json",
+            "settings/unknown.json",
+            "library.db",
+            "other/book.epub",
+            "",
+        ] {
+            assert_eq!(destination(config, books, name), None, "{name}");
+        }
+    }
+
+    #[test]
+    fn files_with_powers_are_not_settings() {
+        for name in ["hooks.json", "access.json", "hook-approvals.json"] {
+            assert!(
+                !is_settings_file(&Path::new("/config").join(name)),
+                "{name}"
+            );
+            assert_eq!(
+                destination(
+                    Path::new("/config"),
+                    Path::new("/books"),
+                    &format!("settings/{name}")
+                ),
+                None
+            );
+        }
+        assert!(is_settings_file(Path::new("/config/settings.json")));
+        assert!(!is_settings_file(Path::new("/")));
+    }
+}
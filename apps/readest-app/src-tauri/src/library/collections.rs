@@ -0,0 +1,531 @@
+//! Smart collections: saved rules such as "tagged sci-fi, unfinished and
+//! added in the last 30 days" whose books are whatever matches right now.
+//! A rule compiles to one SQL condition over the library index, so
+//! evaluating a collection is a single query however large the library is.
+
+use rusqlite::types::Value;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use tauri::{command, State};
+
+use super::access::Access;
+use super::db::{self, BookPage, BookQuery, LibraryDb};
+use crate::analysis;
+use crate::error::{Error, Result};
+use crate::utils::now_millis;
+
+const DAY_MILLIS: i64 = 24 * 60 * 60 * 1000;
+/// Reading progress in percent, 0 for books that were never opened.
+const PROGRESS: &str = "COALESCE(100.0 * b.progress_current / NULLIF(b.progress_total, 0), 0)";
+/// When the book was last read, 0 for books that never were.
+const LAST_READ_AT: &str =
+    "COALESCE((SELECT MAX(s.ended_at) FROM reading_sessions s WHERE s.book_hash = b.hash), 0)";
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TextOp {
+    Is,
+    Contains,
+    StartsWith,
+    EndsWith,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TextRule {
+    pub op: TextOp,
+    /// Compared case-insensitively.
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum NumberOp {
+    Eq,
+    Lt,
+    Lte,
+    Gt,
+    Gte,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NumberRule {
+    pub op: NumberOp,
+    pub value: f64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DateOp {
+    /// `value` is a timestamp in milliseconds.
+    Before,
+    After,
+    /// `value` is a number of days before now.
+    WithinDays,
+    OlderThanDays,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DateRule {
+    pub op: DateOp,
+    pub value: i64,
+}
+
+/// A condition on books, or a combination of them. The frontend sends
+/// them as e.g. `{"type": "all", "rules": [{"type": "tag", "op": "is",
+/// "value": "sci-fi"}, {"type": "progress", "op": "lt", "value": 100},
+/// {"type": "addedAt", "op": "withinDays", "value": 30}]}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Rule {
+    /// Matches every book when empty.
+    All {
+        rules: Vec<Rule>,
+    },
+    /// Matches no book when empty.
+    Any {
+        rules: Vec<Rule>,
+    },
+    Not {
+        rule: Box<Rule>,
+    },
+    Title(TextRule),
+    Author(TextRule),
+    Format(TextRule),
+    Language(TextRule),
+    Group(TextRule),
+    /// Books with at least one tag matching.
+    Tag(TextRule),
+    /// Percent read.
+    Progress(NumberRule),
+    /// Words, which books not analyzed yet never match.
+    Words(NumberRule),
+    AddedAt(DateRule),
+    UpdatedAt(DateRule),
+    LastReadAt(DateRule),
+    /// Books whose file is on this device rather than only in the cloud.
+    Downloaded {
+        value: bool,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Collection {
+    pub id: i64,
+    pub name: String,
+    pub rule: Rule,
+    /// Whether the PIN is needed to see its books, see [`super::access`].
+    pub locked: bool,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CollectionSummary {
+    #[serde(flatten)]
+    pub collection: Collection,
+    /// Books currently in the collection.
+    pub count: u64,
+}
+
+fn text_condition(column: &str, rule: &TextRule, params: &mut Vec<Value>) -> String {
+    let value = db::like_literal(&rule.value);
+    let value = match rule.op {
+        TextOp::Is => rule.value.clone(),
+        TextOp::Contains => format!("%{value}%"),
+        TextOp::StartsWith => format!("{value}%"),
+        TextOp::EndsWith => format!("%{value}"),
+    };
+    params.push(Value::Text(value));
+    let n = params.len();
+    match rule.op {
+        TextOp::Is => format!("{column} = ?{n} COLLATE NOCASE"),
+        _ => format!("{column} LIKE ?{n} ESCAPE '\\'"),
+    }
+}
+
+fn number_condition(column: &str, rule: &NumberRule, params: &mut Vec<Value>) -> String {
+    let op = match rule.op {
+        NumberOp::Eq => "=",
+        NumberOp::Lt => "<",
+        NumberOp::Lte => "<=",
+        NumberOp::Gt => ">",
+        NumberOp::Gte => ">=",
+    };
+    params.push(Value::Real(rule.value));
+    format!("{column} {op} ?{}", params.len())
+}
+
+fn date_condition(column: &str, rule: &DateRule, now: i64, params: &mut Vec<Value>) -> String {
+    let (op, at) = match rule.op {
+        DateOp::Before => ("<", rule.value),
+        DateOp::After => (">=", rule.value),
+        DateOp::WithinDays => (">=", now - rule.value * DAY_MILLIS),
+        DateOp::OlderThanDays => ("<", now - rule.value * DAY_MILLIS),
+    };
+    params.push(Value::Integer(at));
+    format!("{column} {op} ?{}", params.len())
+}
+
+/// `rules` joined by `joiner`, or `empty` when there are none.
+fn group(rules: &[Rule], joiner: &str, empty: &str, now: i64, params: &mut Vec<Value>) -> String {
+    if rules.is_empty() {
+        return empty.to_string();
+    }
+    let parts = rules
+        .iter()
+        .map(|rule| condition(rule, now, params))
+        .collect::<Vec<_>>();
+    format!("({})", parts.join(joiner))
+}
+
+/// Compiles `rule` to an SQL condition over `books b`, appending its
+/// parameters to `params`. Relative dates are taken from `now`.
+pub(crate) fn condition(rule: &Rule, now: i64, params: &mut Vec<Value>) -> String {
+    match rule {
+        Rule::All { rules } => group(rules, " AND ", "1", now, params),
+        Rule::Any { rules } => group(rules, " OR ", "0", now, params),
+        Rule::Not { rule } => format!("NOT ({})", condition(rule, now, params)),
+        Rule::Title(rule) => text_condition("b.title", rule, params),
+        Rule::Author(rule) => text_condition("b.author", rule, params),
+        Rule::Format(rule) => text_condition("b.format", rule, params),
+        Rule::Language(rule) => text_condition(
+            &format!("COALESCE({}, '')", analysis::LANGUAGE),
+            rule,
+            params,
+        ),
+        Rule::Group(rule) => text_condition("COALESCE(b.group_name, '')", rule, params),
+        Rule::Tag(rule) => format!(
+            "EXISTS (SELECT 1 FROM book_tags t WHERE t.book_hash = b.hash AND {})",
+            text_condition("t.tag", rule, params)
+        ),
+        Rule::Progress(rule) => number_condition(PROGRESS, rule, params),
+        Rule::Words(rule) => number_condition(analysis::WORDS, rule, params),
+        Rule::AddedAt(rule) => date_condition("b.created_at", rule, now, params),
+        Rule::UpdatedAt(rule) => date_condition("b.updated_at", rule, now, params),
+        Rule::LastReadAt(rule) => date_condition(LAST_READ_AT, rule, now, params),
+        Rule::Downloaded { value: true } => "b.downloaded_at IS NOT NULL".to_string(),
+        Rule::Downloaded { value: false } => "b.downloaded_at IS NULL".to_string(),
+    }
+}
+
+/// The `WHERE` clause for books matching both `rule` and `query`.
+fn filter(rule: &Rule, query: &BookQuery, params: &mut Vec<Value>) -> String {
+    let base = db::where_clause(query, params);
+    let rule = condition(rule, now_millis(), params);
+    if base.is_empty() {
+        format!("WHERE {rule}")
+    } else {
+        format!("{base} AND {rule}")
+    }
+}
+
+/// The page of books matching `rule`, further narrowed, sorted and paged
+/// by `query`.
+pub fn evaluate(conn: &Connection, rule: &Rule, query: &BookQuery) -> Result<BookPage> {
+    let mut params = Vec::new();
+    let filter = filter(rule, query, &mut params);
+    db::query_page(conn, &filter, params, query)
+}
+
+pub fn count(conn: &Connection, rule: &Rule, query: &BookQuery) -> Result<u64> {
+    let mut params = Vec::new();
+    let filter = filter(rule, query, &mut params);
+    Ok(conn.query_row(
+        &format!("SELECT COUNT(*) FROM books b {filter}"),
+        rusqlite::params_from_iter(params.iter()),
+        |row| row.get(0),
+    )?)
+}
+
+type CollectionRow = (i64, String, String, bool, i64, i64);
+
+fn collection_from_row(row: &Row) -> rusqlite::Result<CollectionRow> {
+    Ok((
+        row.get(0)?,
+        row.get(1)?,
+        row.get(2)?,
+        row.get(3)?,
+        row.get(4)?,
+        row.get(5)?,
+    ))
+}
+
+fn parse_collection(
+    (id, name, rule, locked, created_at, updated_at): CollectionRow,
+) -> Result<Collection> {
+    Ok(Collection {
+        id,
+        name,
+        rule: serde_json::from_str(&rule)?,
+        locked,
+        created_at,
+        updated_at,
+    })
+}
+
+pub fn get(conn: &Connection, id: i64) -> Result<Collection> {
+    let row = conn
+        .query_row(
+            "SELECT id, name, rule, locked, created_at, updated_at FROM collections WHERE id = ?1",
+            [id],
+            collection_from_row,
+        )
+        .optional()?
+        .ok_or(Error::UnknownCollection(id))?;
+    parse_collection(row)
+}
+
+pub fn list(conn: &Connection) -> Result<Vec<Collection>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, name, rule, locked, created_at, updated_at FROM collections \
+         ORDER BY name COLLATE NOCASE",
+    )?;
+    let rows = stmt
+        .query_map([], collection_from_row)?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    rows.into_iter().map(parse_collection).collect()
+}
+
+#[command]
+pub async fn list_collections(
+    db: State<'_, LibraryDb>,
+    access: State<'_, Access>,
+) -> Result<Vec<CollectionSummary>> {
+    let conn = db.conn();
+    let query = BookQuery {
+        lock: access.lock(&conn)?,
+        ..BookQuery::default()
+    };
+    list(&conn)?
+        .into_iter()
+        .map(|collection| {
+            Ok(CollectionSummary {
+                count: count(&conn, &collection.rule, &query)?,
+                collection,
+            })
+        })
+        .collect()
+}
+
+#[command]
+pub async fn create_collection(
+    db: State<'_, LibraryDb>,
+    name: String,
+    rule: Rule,
+) -> Result<Collection> {
+    let conn = db.conn();
+    let now = now_millis();
+    conn.execute(
+        "INSERT INTO collections (name, rule, created_at, updated_at) VALUES (?1, ?2, ?3, ?3)",
+        params![name, serde_json::to_string(&rule)?, now],
+    )?;
+    get(&conn, conn.last_insert_rowid())
+}
+
+/// Renames collection `id` and/or replaces its rule.
+#[command]
+pub async fn update_collection(
+    db: State<'_, LibraryDb>,
+    id: i64,
+    name: Option<String>,
+    rule: Option<Rule>,
+) -> Result<Collection> {
+    let conn = db.conn();
+    let mut collection = get(&conn, id)?;
+    if let Some(name) = name {
+        collection.name = name;
+    }
+    if let Some(rule) = rule {
+        collection.rule = rule;
+    }
+    conn.execute(
+        "UPDATE collections SET name = ?1, rule = ?2, updated_at = ?3 WHERE id = ?4",
+        params![
+            collection.name,
+            serde_json::to_string(&collection.rule)?,
+            now_millis(),
+            id
+        ],
+    )?;
+    get(&conn, id)
+}
+
+#[command]
+pub async fn delete_collection(db: State<'_, LibraryDb>, id: i64) -> Result<()> {
+    db.conn()
+        .execute("DELETE FROM collections WHERE id = ?1", [id])?;
+    Ok(())
+}
+
+/// The books in collection `id`, narrowed, sorted and paged by `query`.
+/// A locked collection has to be unlocked first.
+#[command]
+pub async fn evaluate_collection(
+    db: State<'_, LibraryDb>,
+    access: State<'_, Access>,
+    id: i64,
+    query: Option<BookQuery>,
+) -> Result<BookPage> {
+    let conn = db.conn();
+    let collection = get(&conn, id)?;
+    if access.is_locked(&collection) {
+        return Err(Error::Access(format!("{} is locked", collection.name)));
+    }
+    let query = BookQuery {
+        lock: access.lock(&conn)?,
+        ..query.unwrap_or_default()
+    };
+    evaluate(&conn, &collection.rule, &query)
+}
+
+/// The books an unsaved rule matches, for previewing it while editing.
+#[command]
+pub async fn preview_collection(
+    db: State<'_, LibraryDb>,
+    access: State<'_, Access>,
+    rule: Rule,
+    query: Option<BookQuery>,
+) -> Result<BookPage> {
+    let conn = db.conn();
+    let query = BookQuery {
+        lock: access.lock(&conn)?,
+        ..query.unwrap_or_default()
+    };
+    evaluate(&conn, &rule, &query)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::library::db::Book;
+
+    fn book(hash: &str, title: &str, created_at: i64) -> Book {
+        Book {
+            hash: hash.to_string(),
+            format: "EPUB".to_string(),
+            title: title.to_string(),
+            source_title: None,
+            author: String::new(),
+            group_id: None,
+            group_name: None,
+            tags: Vec::new(),
+            cover_image_url: None,
+            file_path: None,
+            url: None,
+            primary_language: None,
+            progress: None,
+            metadata: None,
+            created_at,
+            updated_at: created_at,
+            deleted_at: None,
+            uploaded_at: None,
+            downloaded_at: None,
+            cover_downloaded_at: None,
+        }
+    }
+
+    fn library(books: &[Book]) -> LibraryDb {
+        let db = LibraryDb::open(Path::new(":memory:")).unwrap();
+        db::upsert_books(&mut db.conn(), books).unwrap();
+        db
+    }
+
+    /// The hashes of the books matching `rule`, in order.
+    fn matching(db: &LibraryDb, rule: serde_json::Value) -> Vec<String> {
+        let rule = serde_json::from_value::<Rule>(rule).unwrap();
+        let conn = db.conn();
+        let page = evaluate(&conn, &rule, &BookQuery::default()).unwrap();
+        assert_eq!(
+            count(&conn, &rule, &BookQuery::default()).unwrap(),
+            page.total
+        );
+        let mut hashes = page.books.into_iter().map(|b| b.hash).collect::<Vec<_>>();
+        hashes.sort();
+        hashes
+    }
+
+    #[test]
+    fn rules_combine_tags_progress_and_dates() {
+        let now = now_millis();
+        let tagged = |mut book: Book, progress| {
+            book.tags = vec!["sci-fi".to_string()];
+            book.progress = progress;
+            book
+        };
+        let db = library(&[
+            tagged(book("a", "Started", now), Some((2, 10))),
+            tagged(book("b", "Finished", now), Some((10, 10))),
+            tagged(book("c", "Old", now - 60 * DAY_MILLIS), None),
+            book("d", "Untagged", now),
+        ]);
+        let rule = json!({
+            "type": "all",
+            "rules": [
+                { "type": "tag", "op": "is", "value": "SCI-FI" },
+                { "type": "progress", "op": "lt", "value": 100 },
+                { "type": "addedAt", "op": "withinDays", "value": 30 },
+            ],
+        });
+        assert_eq!(matching(&db, rule), ["a"]);
+        let rule = json!({ "type": "addedAt", "op": "olderThanDays", "value": 30 });
+        assert_eq!(matching(&db, rule), ["c"]);
+    }
+
+    #[test]
+    fn text_rules_match_literally() {
+        let db = library(&[
+            book("a", "50% Off", 0),
+            book("b", "500 Off", 0),
+            book("c", "Under_score", 0),
+            book("d", "UnderXscore", 0),
+        ]);
+        let title = |op, value| matching(&db, json!({ "type": "title", "op": op, "value": value }));
+        assert_eq!(title("contains", "50%"), ["a"]);
+        assert_eq!(title("startsWith", "50"), ["a", "b"]);
+        assert_eq!(title("endsWith", "_score"), ["c"]);
+        assert_eq!(title("is", "under_score"), ["c"]);
+        assert!(title("is", "Under").is_empty());
+    }
+
+    #[test]
+    fn empty_groups_words_and_downloads() {
+        let mut downloaded = book("a", "Downloaded", 0);
+        downloaded.downloaded_at = Some(1);
+        let db = library(&[downloaded, book("b", "In the cloud", 0)]);
+        db.conn()
+            .execute(
+                "INSERT INTO book_analysis (book_hash, words, characters, reading_minutes,
+                    analyzed_at) VALUES ('a', 90000, 500000, 378, 0)",
+                [],
+            )
+            .unwrap();
+
+        assert_eq!(
+            matching(&db, json!({ "type": "all", "rules": [] })),
+            ["a", "b"]
+        );
+        assert!(matching(&db, json!({ "type": "any", "rules": [] })).is_empty());
+        let none = json!({ "type": "any", "rules": [] });
+        assert_eq!(
+            matching(&db, json!({ "type": "not", "rule": none })),
+            ["a", "b"]
+        );
+        assert_eq!(
+            matching(&db, json!({ "type": "downloaded", "value": false })),
+            ["b"]
+        );
+        let words = |op| matching(&db, json!({ "type": "words", "op": op, "value": 100000 }));
+        assert_eq!(words("lt"), ["a"]);
+        // Books not analyzed yet match neither way
+        assert!(words("gte").is_empty());
+    }
+}
@@ -0,0 +1,829 @@
+//! SQLite-backed library catalog so large libraries can be listed, sorted and
+//! filtered without loading everything into the webview.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle, Manager};
+
+use super::access::{Access, Lock};
+use crate::analysis;
+use crate::error::Result;
+use crate::hooks::{self, HookEvent};
+use crate::paths;
+use crate::utils::now_millis;
+
+const DB_FILE: &str = "library.db";
+
+/// Each entry upgrades the schema from version `index` to `index + 1`.
+const MIGRATIONS: &[&str] = &[
+    r#"
+    CREATE TABLE books (
+        hash TEXT PRIMARY KEY NOT NULL,
+        format TEXT NOT NULL,
+        title TEXT NOT NULL,
+        source_title TEXT,
+        author TEXT NOT NULL DEFAULT '',
+        group_id TEXT,
+        group_name TEXT,
+        cover_image_url TEXT,
+        file_path TEXT,
+        url TEXT,
+        primary_language TEXT,
+        progress_current INTEGER,
+        progress_total INTEGER,
+        metadata TEXT,
+        created_at INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL,
+        deleted_at INTEGER,
+        uploaded_at INTEGER,
+        downloaded_at INTEGER,
+        cover_downloaded_at INTEGER
+    );
+    CREATE TABLE book_tags (
+        book_hash TEXT NOT NULL REFERENCES books(hash) ON DELETE CASCADE,
+        tag TEXT NOT NULL,
+        PRIMARY KEY (book_hash, tag)
+    );
+    CREATE INDEX idx_books_updated_at ON books(updated_at);
+    CREATE INDEX idx_books_title ON books(title COLLATE NOCASE);
+    CREATE INDEX idx_books_author ON books(author COLLATE NOCASE);
+    CREATE INDEX idx_book_tags_tag ON book_tags(tag);
+"#,
+    r#"
+    CREATE TABLE reading_sessions (
+        id INTEGER PRIMARY KEY,
+        book_hash TEXT NOT NULL,
+        started_at INTEGER NOT NULL,
+        ended_at INTEGER NOT NULL,
+        pages INTEGER NOT NULL DEFAULT 0,
+        words INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE INDEX idx_reading_sessions_started_at ON reading_sessions(started_at);
+    CREATE INDEX idx_reading_sessions_book_hash ON reading_sessions(book_hash);
+"#,
+    r#"
+    CREATE TABLE book_hashes (
+        book_hash TEXT PRIMARY KEY NOT NULL REFERENCES books(hash) ON DELETE CASCADE,
+        file_size INTEGER NOT NULL,
+        modified_at INTEGER NOT NULL,
+        file_hash TEXT NOT NULL,
+        content_hash TEXT
+    );
+    CREATE INDEX idx_book_hashes_file_hash ON book_hashes(file_hash);
+    CREATE INDEX idx_book_hashes_content_hash ON book_hashes(content_hash);
+"#,
+    r#"
+    CREATE TABLE collections (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        rule TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL
+    );
+    CREATE INDEX idx_books_created_at ON books(created_at);
+"#,
+    r#"
+    CREATE TABLE book_identifiers (
+        book_hash TEXT NOT NULL REFERENCES books(hash) ON DELETE CASCADE,
+        scheme TEXT NOT NULL,
+        value TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        PRIMARY KEY (book_hash, scheme, value)
+    );
+    CREATE INDEX idx_book_identifiers_value ON book_identifiers(scheme, value);
+    -- Fingerprints now include the identifiers, so every book is scanned again
+    DELETE FROM book_hashes;
+"#,
+    r#"
+    ALTER TABLE collections ADD COLUMN locked INTEGER NOT NULL DEFAULT 0;
+"#,
+    r#"
+    CREATE TABLE book_analysis (
+        book_hash TEXT PRIMARY KEY NOT NULL REFERENCES books(hash) ON DELETE CASCADE,
+        words INTEGER NOT NULL,
+        characters INTEGER NOT NULL,
+        reading_minutes INTEGER NOT NULL,
+        language TEXT,
+        analyzed_at INTEGER NOT NULL
+    );
+"#,
+    r#"
+    CREATE TABLE vocab_words (
+        id INTEGER PRIMARY KEY,
+        word TEXT NOT NULL COLLATE NOCASE,
+        language TEXT NOT NULL DEFAULT '',
+        definition TEXT,
+        lookups INTEGER NOT NULL DEFAULT 1,
+        reps INTEGER NOT NULL DEFAULT 0,
+        lapses INTEGER NOT NULL DEFAULT 0,
+        ease REAL NOT NULL DEFAULT 2.5,
+        interval_days INTEGER NOT NULL DEFAULT 0,
+        due_at INTEGER NOT NULL,
+        reviewed_at INTEGER,
+        created_at INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL,
+        UNIQUE (word, language)
+    );
+    CREATE INDEX idx_vocab_words_due_at ON vocab_words(due_at);
+    CREATE TABLE vocab_contexts (
+        id INTEGER PRIMARY KEY,
+        word_id INTEGER NOT NULL REFERENCES vocab_words(id) ON DELETE CASCADE,
+        book_hash TEXT,
+        sentence TEXT NOT NULL,
+        cfi TEXT,
+        created_at INTEGER NOT NULL,
+        UNIQUE (word_id, sentence)
+    );
+    CREATE INDEX idx_vocab_contexts_book_hash ON vocab_contexts(book_hash);
+"#,
+    r#"
+    CREATE TABLE focus_sessions (
+        id INTEGER PRIMARY KEY,
+        book_hash TEXT,
+        started_at INTEGER NOT NULL,
+        ended_at INTEGER NOT NULL,
+        breaks INTEGER NOT NULL DEFAULT 0,
+        completed INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE INDEX idx_focus_sessions_started_at ON focus_sessions(started_at);
+"#,
+    r#"
+    CREATE TABLE book_chapters (
+        book_hash TEXT NOT NULL,
+        spine_index INTEGER NOT NULL,
+        href TEXT NOT NULL,
+        hash TEXT NOT NULL,
+        PRIMARY KEY (book_hash, spine_index)
+    );
+    CREATE INDEX idx_book_chapters_hash ON book_chapters(hash);
+    -- Fingerprinted again to hash the chapters
+    DELETE FROM book_hashes;
+"#,
+];
+
+/// Mirrors the `Book` type used by the frontend.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Book {
+    pub hash: String,
+    pub format: String,
+    pub title: String,
+    pub source_title: Option<String>,
+    #[serde(default)]
+    pub author: String,
+    pub group_id: Option<String>,
+    pub group_name: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub cover_image_url: Option<String>,
+    pub file_path: Option<String>,
+    pub url: Option<String>,
+    pub primary_language: Option<String>,
+    pub progress: Option<(i64, i64)>,
+    pub metadata: Option<serde_json::Value>,
+    pub created_at: i64,
+    pub updated_at: i64,
+    pub deleted_at: Option<i64>,
+    pub uploaded_at: Option<i64>,
+    pub downloaded_at: Option<i64>,
+    pub cover_downloaded_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SortField {
+    Title,
+    Author,
+    CreatedAt,
+    #[default]
+    UpdatedAt,
+    Format,
+    Progress,
+    /// Words, books not yet analyzed first.
+    Length,
+}
+
+impl SortField {
+    fn column(self) -> &'static str {
+        match self {
+            SortField::Title => "b.title COLLATE NOCASE",
+            SortField::Author => "b.author COLLATE NOCASE",
+            SortField::CreatedAt => "b.created_at",
+            SortField::UpdatedAt => "b.updated_at",
+            SortField::Format => "b.format",
+            SortField::Progress => "CAST(b.progress_current AS REAL) / NULLIF(b.progress_total, 0)",
+            SortField::Length => analysis::WORDS,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BookQuery {
+    /// Case-insensitive match against title and author.
+    pub search: Option<String>,
+    pub format: Option<String>,
+    pub group_id: Option<String>,
+    pub tags: Option<Vec<String>>,
+    /// The language of the metadata or, when it names none, of the text.
+    pub language: Option<String>,
+    /// Bounds on the words of the books, which leave out those not
+    /// analyzed yet.
+    pub min_words: Option<u64>,
+    pub max_words: Option<u64>,
+    #[serde(default)]
+    pub include_deleted: bool,
+    #[serde(default)]
+    pub sort_by: SortField,
+    #[serde(default)]
+    pub ascending: bool,
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+    /// Set by the commands of the frontend, so that locked books are left
+    /// out; the app itself sees them all.
+    #[serde(skip)]
+    pub(crate) lock: Lock,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BookPage {
+    pub books: Vec<Book>,
+    pub total: u64,
+}
+
+pub struct LibraryDb(Mutex<Connection>);
+
+impl LibraryDb {
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(Self(Mutex::new(connect(path)?)))
+    }
+
+    pub fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.0.lock().unwrap()
+    }
+
+    /// Writes a consistent copy of the database to `dest`, which must not exist.
+    pub fn snapshot(&self, dest: &Path) -> Result<()> {
+        self.conn()
+            .execute("VACUUM INTO ?1", [dest.to_string_lossy()])?;
+        Ok(())
+    }
+
+    /// Replaces the database with the one at `source`, which is upgraded to
+    /// the current schema and then moved into place.
+    pub fn replace(&self, source: &Path) -> Result<()> {
+        drop(connect(source)?);
+        let mut conn = self.conn();
+        let path = PathBuf::from(conn.path().unwrap_or_default());
+        // Closing checkpoints the WAL, so none of the old data is left behind
+        drop(std::mem::replace(&mut *conn, Connection::open_in_memory()?));
+        std::fs::rename(source, &path)?;
+        *conn = connect(&path)?;
+        Ok(())
+    }
+}
+
+fn connect(path: &Path) -> Result<Connection> {
+    let mut conn = Connection::open(path)?;
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    conn.pragma_update(None, "foreign_keys", true)?;
+    migrate(&mut conn)?;
+    Ok(conn)
+}
+
+pub fn init(app: &AppHandle) -> Result<()> {
+    let dir = paths::data_dir(app)?;
+    std::fs::create_dir_all(&dir)?;
+    app.manage(LibraryDb::open(&dir.join(DB_FILE))?);
+    Ok(())
+}
+
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", index + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+const BOOK_COLUMNS: &str = "b.hash, b.format, b.title, b.source_title, b.author, b.group_id, \
+    b.group_name, b.cover_image_url, b.file_path, b.url, b.primary_language, \
+    b.progress_current, b.progress_total, b.metadata, b.created_at, b.updated_at, \
+    b.deleted_at, b.uploaded_at, b.downloaded_at, b.cover_downloaded_at";
+
+fn book_from_row(row: &Row) -> rusqlite::Result<Book> {
+    let progress = match (row.get(11)?, row.get(12)?) {
+        (Some(current), Some(total)) => Some((current, total)),
+        _ => None,
+    };
+    let metadata: Option<String> = row.get(13)?;
+    Ok(Book {
+        hash: row.get(0)?,
+        format: row.get(1)?,
+        title: row.get(2)?,
+        source_title: row.get(3)?,
+        author: row.get(4)?,
+        group_id: row.get(5)?,
+        group_name: row.get(6)?,
+        tags: Vec::new(),
+        cover_image_url: row.get(7)?,
+        file_path: row.get(8)?,
+        url: row.get(9)?,
+        primary_language: row.get(10)?,
+        progress,
+        metadata: metadata.and_then(|m| serde_json::from_str(&m).ok()),
+        created_at: row.get(14)?,
+        updated_at: row.get(15)?,
+        deleted_at: row.get(16)?,
+        uploaded_at: row.get(17)?,
+        downloaded_at: row.get(18)?,
+        cover_downloaded_at: row.get(19)?,
+    })
+}
+
+fn load_tags(conn: &Connection, books: &mut [Book]) -> Result<()> {
+    let mut stmt =
+        conn.prepare_cached("SELECT tag FROM book_tags WHERE book_hash = ?1 ORDER BY tag")?;
+    for book in books.iter_mut() {
+        book.tags = stmt
+            .query_map([&book.hash], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+    }
+    Ok(())
+}
+
+pub fn upsert_books(conn: &mut Connection, books: &[Book]) -> Result<()> {
+    let tx = conn.transaction()?;
+    {
+        let mut upsert = tx.prepare_cached(
+            "INSERT INTO books (hash, format, title, source_title, author, group_id, group_name,
+                cover_image_url, file_path, url, primary_language, progress_current, progress_total,
+                metadata, created_at, updated_at, deleted_at, uploaded_at, downloaded_at,
+                cover_downloaded_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17,
+                ?18, ?19, ?20)
+             ON CONFLICT(hash) DO UPDATE SET
+                format = excluded.format, title = excluded.title,
+                source_title = excluded.source_title, author = excluded.author,
+                group_id = excluded.group_id, group_name = excluded.group_name,
+                cover_image_url = excluded.cover_image_url, file_path = excluded.file_path,
+                url = excluded.url, primary_language = excluded.primary_language,
+                progress_current = excluded.progress_current,
+                progress_total = excluded.progress_total, metadata = excluded.metadata,
+                updated_at = excluded.updated_at, deleted_at = excluded.deleted_at,
+                uploaded_at = excluded.uploaded_at, downloaded_at = excluded.downloaded_at,
+                cover_downloaded_at = excluded.cover_downloaded_at",
+        )?;
+        let mut clear_tags = tx.prepare_cached("DELETE FROM book_tags WHERE book_hash = ?1")?;
+        let mut insert_tag =
+            tx.prepare_cached("INSERT OR IGNORE INTO book_tags (book_hash, tag) VALUES (?1, ?2)")?;
+        for book in books {
+            let metadata = book.metadata.as_ref().map(|m| m.to_string());
+            upsert.execute(params![
+                book.hash,
+                book.format,
+                book.title,
+                book.source_title,
+                book.author,
+                book.group_id,
+                book.group_name,
+                book.cover_image_url,
+                book.file_path,
+                book.url,
+                book.primary_language,
+                book.progress.map(|p| p.0),
+                book.progress.map(|p| p.1),
+                metadata,
+                book.created_at,
+                book.updated_at,
+                book.deleted_at,
+                book.uploaded_at,
+                book.downloaded_at,
+                book.cover_downloaded_at,
+            ])?;
+            clear_tags.execute([&book.hash])?;
+            for tag in &book.tags {
+                insert_tag.execute([&book.hash, tag])?;
+            }
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+pub fn get_book(conn: &Connection, hash: &str) -> Result<Option<Book>> {
+    let sql = format!("SELECT {BOOK_COLUMNS} FROM books b WHERE b.hash = ?1");
+    let book = conn.query_row(&sql, [hash], book_from_row).optional()?;
+    match book {
+        Some(book) => {
+            let mut books = [book];
+            load_tags(conn, &mut books)?;
+            let [book] = books;
+            Ok(Some(book))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Book `hash`, unless `lock` leaves it out.
+pub fn get_unlocked_book(conn: &Connection, hash: &str, lock: &Lock) -> Result<Option<Book>> {
+    let mut params = vec![Value::Text(hash.to_string())];
+    let conditions = lock.conditions(&mut params);
+    if !conditions.is_empty() {
+        let sql = format!(
+            "SELECT 1 FROM books b WHERE b.hash = ?1 AND {}",
+            conditions.join(" AND ")
+        );
+        let found = conn
+            .query_row(&sql, params_from_iter(params.iter()), |_| Ok(()))
+            .optional()?;
+        if found.is_none() {
+            return Ok(None);
+        }
+    }
+    get_book(conn, hash)
+}
+
+/// `LIKE` pattern matching `value` literally, with `\` as the escape.
+pub(crate) fn like_literal(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// Builds the `WHERE` clause for `query` and appends its parameters to `params`.
+pub(crate) fn where_clause(query: &BookQuery, params: &mut Vec<Value>) -> String {
+    let mut conditions = query.lock.conditions(params);
+    if !query.include_deleted {
+        conditions.push("b.deleted_at IS NULL".to_string());
+    }
+    if let Some(search) = query.search.as_deref().filter(|s| !s.trim().is_empty()) {
+        params.push(Value::Text(format!("%{}%", like_literal(search.trim()))));
+        let n = params.len();
+        conditions.push(format!(
+            "(b.title LIKE ?{n} ESCAPE '\\' OR b.author LIKE ?{n} ESCAPE '\\')"
+        ));
+    }
+    if let Some(format) = &query.format {
+        params.push(Value::Text(format.clone()));
+        conditions.push(format!("b.format = ?{}", params.len()));
+    }
+    if let Some(group_id) = &query.group_id {
+        params.push(Value::Text(group_id.clone()));
+        conditions.push(format!("b.group_id = ?{}", params.len()));
+    }
+    if let Some(language) = &query.language {
+        params.push(Value::Text(language.clone()));
+        conditions.push(format!("{} = ?{}", analysis::LANGUAGE, params.len()));
+    }
+    if let Some(min_words) = query.min_words {
+        params.push(Value::Integer(min_words as i64));
+        conditions.push(format!("{} >= ?{}", analysis::WORDS, params.len()));
+    }
+    if let Some(max_words) = query.max_words {
+        params.push(Value::Integer(max_words as i64));
+        conditions.push(format!("{} <= ?{}", analysis::WORDS, params.len()));
+    }
+    for tag in query.tags.iter().flatten() {
+        params.push(Value::Text(tag.clone()));
+        conditions.push(format!(
+            "EXISTS (SELECT 1 FROM book_tags t WHERE t.book_hash = b.hash AND t.tag = ?{})",
+            params.len()
+        ));
+    }
+    if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", conditions.join(" AND "))
+    }
+}
+
+pub fn query_books(conn: &Connection, query: &BookQuery) -> Result<BookPage> {
+    let mut params = Vec::new();
+    let filter = where_clause(query, &mut params);
+    query_page(conn, &filter, params, query)
+}
+
+/// The page of books matching `filter`, a `WHERE` clause with `params`,
+/// sorted and paged as `query` says.
+pub(crate) fn query_page(
+    conn: &Connection,
+    filter: &str,
+    mut params: Vec<Value>,
+    query: &BookQuery,
+) -> Result<BookPage> {
+    let total: u64 = conn.query_row(
+        &format!("SELECT COUNT(*) FROM books b {filter}"),
+        params_from_iter(params.iter()),
+        |row| row.get(0),
+    )?;
+
+    let order = if query.ascending { "ASC" } else { "DESC" };
+    let sql = format!(
+        "SELECT {BOOK_COLUMNS} FROM books b {filter} ORDER BY {} {order}, b.hash LIMIT ?{} OFFSET ?{}",
+        query.sort_by.column(),
+        params.len() + 1,
+        params.len() + 2,
+    );
+    params.push(Value::Integer(query.limit.map_or(-1, i64::from)));
+    params.push(Value::Integer(query.offset.unwrap_or(0).into()));
+
+    let mut stmt = conn.prepare(&sql)?;
+    let mut books = stmt
+        .query_map(params_from_iter(params.iter()), book_from_row)?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    load_tags(conn, &mut books)?;
+    Ok(BookPage { books, total })
+}
+
+fn is_finished(progress: Option<(i64, i64)>) -> bool {
+    progress.is_some_and(|(current, total)| total > 0 && current >= total)
+}
+
+/// Whether book `hash` was at its last page, as far as the index knows.
+fn was_finished(conn: &Connection, hash: &str) -> Result<bool> {
+    let progress = conn
+        .query_row(
+            "SELECT progress_current, progress_total FROM books WHERE hash = ?1",
+            [hash],
+            |row| {
+                Ok(row
+                    .get::<_, Option<i64>>(0)?
+                    .zip(row.get::<_, Option<i64>>(1)?))
+            },
+        )
+        .optional()?;
+    Ok(is_finished(progress.flatten()))
+}
+
+/// Adds or updates `books`, running the `bookFinished` hooks of those
+/// that reached their last page and analyzing those not analyzed yet.
+#[command]
+pub async fn library_upsert_books(app: AppHandle, books: Vec<Book>) -> Result<()> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let db = app.state::<LibraryDb>();
+        let mut conn = db.conn();
+        let mut finished = Vec::new();
+        for book in &books {
+            if is_finished(book.progress) && !was_finished(&conn, &book.hash)? {
+                finished.push(book);
+            }
+        }
+        upsert_books(&mut conn, &books)?;
+        drop(conn);
+        analysis::analyze_new(&app, &books);
+        #[cfg(any(target_os = "macos", windows))]
+        crate::system_search::update(&app, &books);
+        for book in finished {
+            hooks::book_event(&app, HookEvent::BookFinished, book);
+        }
+        Ok(())
+    })
+    .await?
+}
+
+#[command]
+pub async fn library_get_book(app: AppHandle, hash: String) -> Result<Option<Book>> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let db = app.state::<LibraryDb>();
+        let conn = db.conn();
+        get_unlocked_book(&conn, &hash, &app.state::<Access>().lock(&conn)?)
+    })
+    .await?
+}
+
+#[command]
+pub async fn library_query_books(app: AppHandle, mut query: BookQuery) -> Result<BookPage> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let db = app.state::<LibraryDb>();
+        let conn = db.conn();
+        query.lock = app.state::<Access>().lock(&conn)?;
+        query_books(&conn, &query)
+    })
+    .await?
+}
+
+/// Marks books as deleted, moving them to the trash, or removes them for
+/// good when `purge` is set.
+#[command]
+pub async fn library_delete_books(
+    app: AppHandle,
+    hashes: Vec<String>,
+    purge: Option<bool>,
+) -> Result<()> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let db = app.state::<LibraryDb>();
+        let mut conn = db.conn();
+        let mut deleted = Vec::new();
+        for hash in &hashes {
+            deleted.extend(get_book(&conn, hash)?.filter(|book| book.deleted_at.is_none()));
+        }
+        if purge.unwrap_or(false) {
+            drop(conn);
+            super::trash::purge(&app, &hashes)?;
+        } else {
+            let tx = conn.transaction()?;
+            let now = now_millis();
+            {
+                let mut stmt = tx
+                    .prepare("UPDATE books SET deleted_at = ?2, updated_at = ?2 WHERE hash = ?1")?;
+                for hash in &hashes {
+                    stmt.execute(params![hash, now])?;
+                }
+            }
+            tx.commit()?;
+            drop(conn);
+            super::trash::trash(&app, &deleted)?;
+            #[cfg(any(target_os = "macos", windows))]
+            crate::system_search::remove(&app, &hashes);
+        }
+        for book in &deleted {
+            hooks::book_event(&app, HookEvent::BookDeleted, book);
+        }
+        Ok(())
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn book(hash: &str, title: &str, author: &str) -> Book {
+        Book {
+            hash: hash.to_string(),
+            format: "EPUB".to_string(),
+            title: title.to_string(),
+            source_title: None,
+            author: author.to_string(),
+            group_id: None,
+            group_name: None,
+            tags: Vec::new(),
+            cover_image_url: None,
+            file_path: None,
+            url: None,
+            primary_language: None,
+            progress: None,
+            metadata: None,
+            created_at: 0,
+            updated_at: 0,
+            deleted_at: None,
+            uploaded_at: None,
+            downloaded_at: None,
+            cover_downloaded_at: None,
+        }
+    }
+
+    fn library(books: &[Book]) -> Connection {
+        let mut conn = connect(Path::new(":memory:")).unwrap();
+        upsert_books(&mut conn, books).unwrap();
+        conn
+    }
+
+    fn hashes(conn: &Connection, query: BookQuery) -> Vec<String> {
+        let query = BookQuery {
+            sort_by: SortField::Title,
+            ascending: true,
+            ..query
+        };
+        let page = query_books(conn, &query).unwrap();
+        page.books.into_iter().map(|book| book.hash).collect()
+    }
+
+    #[test]
+    fn upserts_replace_the_book_and_its_tags() {
+        let mut first = book("a", "Dune", "Frank Herbert");
+        first.tags = vec!["sf".to_string(), "classic".to_string()];
+        first.progress = Some((3, 10));
+        first.metadata = Some(json!({ "isbn": "0441013597" }));
+        let mut conn = library(&[first.clone()]);
+        let second = Book {
+            title: "Dune Messiah".to_string(),
+            tags: vec!["sf".to_string()],
+            ..first
+        };
+        upsert_books(&mut conn, &[second]).unwrap();
+
+        let stored = get_book(&conn, "a").unwrap().unwrap();
+        assert_eq!(stored.title, "Dune Messiah");
+        assert_eq!(stored.tags, ["sf"]);
+        assert_eq!(stored.progress, Some((3, 10)));
+        assert_eq!(stored.metadata, Some(json!({ "isbn": "0441013597" })));
+        assert!(get_book(&conn, "b").unwrap().is_none());
+    }
+
+    #[test]
+    fn searches_match_titles_and_authors_literally() {
+        let conn = library(&[
+            book("a", "100% Pure", "Ann"),
+            book("b", "100 Pure", "Ann_Lee"),
+            book("c", "Other", "AnnXLee"),
+        ]);
+        let search = |text: &str| {
+            hashes(
+                &conn,
+                BookQuery {
+                    search: Some(text.to_string()),
+                    ..BookQuery::default()
+                },
+            )
+        };
+        assert_eq!(search(" pure "), ["b", "a"]);
+        assert_eq!(search("100%"), ["a"]);
+        assert_eq!(search("ann_"), ["b"]);
+        assert!(search("\\").is_empty());
+        assert_eq!(search("  "), ["b", "a", "c"]);
+    }
+
+    #[test]
+    fn filters_narrow_each_other() {
+        let mut dune = book("a", "Dune", "Frank Herbert");
+        dune.tags = vec!["sf".to_string(), "classic".to_string()];
+        let mut emma = book("b", "Emma", "Jane Austen");
+        emma.format = "PDF".to_string();
+        emma.tags = vec!["classic".to_string()];
+        let mut gone = book("c", "Gone", "Nobody");
+        gone.tags = vec!["classic".to_string()];
+        gone.deleted_at = Some(1);
+        let conn = library(&[dune, emma, gone]);
+
+        let tags = |tags: &[&str]| Some(tags.iter().map(|tag| tag.to_string()).collect());
+        let query = |query| hashes(&conn, query);
+        assert_eq!(query(BookQuery::default()), ["a", "b"]);
+        assert_eq!(
+            query(BookQuery {
+                include_deleted: true,
+                ..BookQuery::default()
+            }),
+            ["a", "b", "c"]
+        );
+        assert_eq!(
+            query(BookQuery {
+                tags: tags(&["classic"]),
+                ..BookQuery::default()
+            }),
+            ["a", "b"]
+        );
+        assert_eq!(
+            query(BookQuery {
+                tags: tags(&["classic", "sf"]),
+                ..BookQuery::default()
+            }),
+            ["a"]
+        );
+        assert_eq!(
+            query(BookQuery {
+                format: Some("PDF".to_string()),
+                tags: tags(&["classic"]),
+                ..BookQuery::default()
+            }),
+            ["b"]
+        );
+        assert!(query(BookQuery {
+            lock: Lock::All,
+            ..BookQuery::default()
+        })
+        .is_empty());
+        assert!(get_unlocked_book(&conn, "a", &Lock::All).unwrap().is_none());
+        assert!(get_unlocked_book(&conn, "a", &Lock::None)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn pages_are_counted_before_the_limit() {
+        let conn = library(&[
+            book("a", "Alpha", ""),
+            book("b", "beta", ""),
+            book("c", "Gamma", ""),
+        ]);
+        let page = query_books(
+            &conn,
+            &BookQuery {
+                sort_by: SortField::Title,
+                limit: Some(1),
+                offset: Some(1),
+                ..BookQuery::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(page.total, 3);
+        let titles = page
+            .books
+            .iter()
+            .map(|book| &book.title)
+            .collect::<Vec<_>>();
+        assert_eq!(titles, ["beta"]);
+    }
+}
@@ -0,0 +1,395 @@
+//! Duplicate detection. Book hashes only sample the file (see
+//! [`super::partial_md5`]), so files are also hashed whole, and EPUBs by
+//! their content too: the spine documents in reading order, which stay the
+//! same when a book is re-zipped or has its metadata edited. Books with an
+//! ISBN, ASIN or DOI in common (see [`super::identifiers`]) are likely
+//! copies too, if not of the same file.
+//!
+//! Fingerprints are cached in the database with the size and modification
+//! time of the file, so only new or changed files are read again.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tauri::{command, AppHandle, Manager};
+
+use super::access;
+use super::db::{self, BookQuery, LibraryDb};
+use super::editions::{self, ChapterHash};
+use super::identifiers::{self, Identifier};
+use crate::error::Result;
+use crate::formats::epub::EpubArchive;
+
+#[derive(Debug, Clone)]
+pub struct Fingerprint {
+    pub file_size: u64,
+    pub modified_at: i64,
+    pub file_hash: String,
+    /// Only for formats whose content can be told apart from the container.
+    pub content_hash: Option<String>,
+    pub identifiers: Vec<Identifier>,
+    /// The spine documents of EPUBs, hashed one by one.
+    pub chapters: Vec<ChapterHash>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MatchKind {
+    /// Byte-for-byte the same file.
+    File,
+    /// The same content in a different file.
+    Content,
+    /// The same ISBN, ASIN or DOI, such as another edition of the book.
+    Identifier,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DuplicateMatch {
+    pub book_hash: String,
+    pub kind: MatchKind,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportCheck {
+    pub path: PathBuf,
+    /// Books in the library the file duplicates, empty if none.
+    pub matches: Vec<DuplicateMatch>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DuplicateBook {
+    pub hash: String,
+    pub title: String,
+    pub author: String,
+    pub format: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DuplicateGroup {
+    /// `File` when every book in the group is the same file, `Identifier`
+    /// when they only have an identifier in common.
+    pub kind: MatchKind,
+    /// Oldest first.
+    pub books: Vec<DuplicateBook>,
+}
+
+fn file_stamp(path: &Path) -> Result<(u64, i64)> {
+    let metadata = std::fs::metadata(path)?;
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_millis() as i64);
+    Ok((metadata.len(), modified))
+}
+
+/// Hash of the spine documents of an EPUB, with line endings normalized
+/// since some tools rewrite them, and of each of them.
+fn epub_content_hash(path: &Path) -> Result<(String, Vec<ChapterHash>)> {
+    let mut epub = EpubArchive::open(path)?;
+    let package = epub.package()?;
+    let mut hasher = Sha256::new();
+    let mut chapters = Vec::new();
+    for (index, item) in package.spine_items().enumerate() {
+        let data = epub.read_entry(&item.href)?;
+        let normalized = data.into_iter().filter(|&b| b != b'\r').collect::<Vec<_>>();
+        hasher.update((normalized.len() as u64).to_le_bytes());
+        hasher.update(&normalized);
+        chapters.push(ChapterHash::of(index, &item.href, &normalized));
+    }
+    Ok((format!("{:x}", hasher.finalize()), chapters))
+}
+
+pub fn fingerprint(path: &Path) -> Result<Fingerprint> {
+    let (file_size, modified_at) = file_stamp(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut BufReader::new(File::open(path)?), &mut hasher)?;
+    let is_epub = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("epub"));
+    let (content_hash, chapters) = if is_epub {
+        match epub_content_hash(path) {
+            Ok((hash, chapters)) => (Some(hash), chapters),
+            Err(e) => {
+                log::warn!("Failed to hash the content of {path:?}: {e}");
+                (None, Vec::new())
+            }
+        }
+    } else {
+        (None, Vec::new())
+    };
+    let identifiers = identifiers::scan(path)
+        .map_err(|e| log::warn!("Failed to find the identifiers of {path:?}: {e}"))
+        .unwrap_or_default();
+    Ok(Fingerprint {
+        file_size,
+        modified_at,
+        file_hash: format!("{:x}", hasher.finalize()),
+        content_hash,
+        identifiers,
+        chapters,
+    })
+}
+
+pub fn record(conn: &Connection, book_hash: &str, fingerprint: &Fingerprint) -> Result<()> {
+    conn.execute(
+        "INSERT INTO book_hashes (book_hash, file_size, modified_at, file_hash, content_hash)
+         VALUES (?1, ?2, ?3, ?4, ?5)
+         ON CONFLICT(book_hash) DO UPDATE SET
+            file_size = excluded.file_size, modified_at = excluded.modified_at,
+            file_hash = excluded.file_hash, content_hash = excluded.content_hash",
+        params![
+            book_hash,
+            fingerprint.file_size as i64,
+            fingerprint.modified_at,
+            fingerprint.file_hash,
+            fingerprint.content_hash,
+        ],
+    )?;
+    identifiers::record(conn, book_hash, &fingerprint.identifiers)?;
+    editions::record(conn, book_hash, &fingerprint.chapters)
+}
+
+/// Books in the library that `fingerprint` duplicates.
+pub fn matches(conn: &Connection, fingerprint: &Fingerprint) -> Result<Vec<DuplicateMatch>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT h.book_hash, h.file_hash = ?1 FROM book_hashes h
+         JOIN books b ON b.hash = h.book_hash
+         WHERE b.deleted_at IS NULL AND (h.file_hash = ?1 OR h.content_hash = ?2)
+         ORDER BY b.created_at",
+    )?;
+    let mut matches = stmt
+        .query_map(
+            params![fingerprint.file_hash, fingerprint.content_hash],
+            |row| {
+                Ok(DuplicateMatch {
+                    book_hash: row.get(0)?,
+                    kind: if row.get(1)? {
+                        MatchKind::File
+                    } else {
+                        MatchKind::Content
+                    },
+                })
+            },
+        )?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut stmt = conn.prepare_cached(
+        "SELECT i.book_hash FROM book_identifiers i
+         JOIN books b ON b.hash = i.book_hash
+         WHERE b.deleted_at IS NULL AND i.scheme = ?1 AND i.value = ?2
+         ORDER BY b.created_at",
+    )?;
+    for identifier in &fingerprint.identifiers {
+        let hashes = stmt
+            .query_map(
+                params![identifier.scheme.as_str(), identifier.value],
+                |row| row.get::<_, String>(0),
+            )?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for book_hash in hashes {
+            if !matches.iter().any(|m| m.book_hash == book_hash) {
+                matches.push(DuplicateMatch {
+                    book_hash,
+                    kind: MatchKind::Identifier,
+                });
+            }
+        }
+    }
+    Ok(matches)
+}
+
+/// Fingerprints the books whose files are new or changed since they were
+/// last hashed. The database is only locked between files.
+pub fn refresh(app: &AppHandle) -> Result<()> {
+    let db = app.state::<LibraryDb>();
+    let (books, stamps) = {
+        let conn = db.conn();
+        let books = db::query_books(&conn, &BookQuery::default())?.books;
+        let mut stmt = conn.prepare("SELECT book_hash, file_size, modified_at FROM book_hashes")?;
+        let stamps = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    (row.get::<_, i64>(1)? as u64, row.get(2)?),
+                ))
+            })?
+            .collect::<rusqlite::Result<HashMap<_, _>>>()?;
+        (books, stamps)
+    };
+    for book in books {
+        let Some(path) = super::book_path(app, &book) else {
+            continue;
+        };
+        let Ok(stamp) = file_stamp(&path) else {
+            continue;
+        };
+        if stamps.get(&book.hash) == Some(&stamp) {
+            continue;
+        }
+        match fingerprint(&path) {
+            Ok(fingerprint) => record(&db.conn(), &book.hash, &fingerprint)?,
+            Err(e) => log::warn!("Failed to fingerprint {path:?}: {e}"),
+        }
+    }
+    Ok(())
+}
+
+fn duplicate_groups(conn: &Connection) -> Result<Vec<DuplicateGroup>> {
+    let mut stmt = conn.prepare(
+        "SELECT COALESCE(h.content_hash, h.file_hash), h.file_hash,
+            b.hash, b.title, b.author, b.format
+         FROM book_hashes h JOIN books b ON b.hash = h.book_hash
+         WHERE b.deleted_at IS NULL
+         ORDER BY b.created_at",
+    )?;
+    let mut groups: BTreeMap<String, Vec<(String, DuplicateBook)>> = BTreeMap::new();
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            DuplicateBook {
+                hash: row.get(2)?,
+                title: row.get(3)?,
+                author: row.get(4)?,
+                format: row.get(5)?,
+            },
+        ))
+    })?;
+    for row in rows {
+        let (key, file_hash, book) = row?;
+        groups.entry(key).or_default().push((file_hash, book));
+    }
+    let mut duplicates = groups
+        .into_values()
+        .filter(|books| books.len() > 1)
+        .map(|books| {
+            let kind = if books.iter().all(|(hash, _)| *hash == books[0].0) {
+                MatchKind::File
+            } else {
+                MatchKind::Content
+            };
+            DuplicateGroup {
+                kind,
+                books: books.into_iter().map(|(_, book)| book).collect(),
+            }
+        })
+        .collect::<Vec<_>>();
+    duplicates.extend(identifier_groups(conn, &duplicates)?);
+    Ok(duplicates)
+}
+
+/// Groups of books with an identifier in common, other than those that
+/// are already in one of the `groups` of copies together.
+fn identifier_groups(conn: &Connection, groups: &[DuplicateGroup]) -> Result<Vec<DuplicateGroup>> {
+    let mut stmt = conn.prepare(
+        "SELECT i.scheme || ':' || i.value, b.hash, b.title, b.author, b.format
+         FROM book_identifiers i JOIN books b ON b.hash = i.book_hash
+         WHERE b.deleted_at IS NULL AND (i.scheme, i.value) IN (
+            SELECT scheme, value FROM book_identifiers
+            GROUP BY scheme, value HAVING COUNT(*) > 1
+         )
+         ORDER BY b.created_at",
+    )?;
+    let mut shared: BTreeMap<String, Vec<DuplicateBook>> = BTreeMap::new();
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            DuplicateBook {
+                hash: row.get(1)?,
+                title: row.get(2)?,
+                author: row.get(3)?,
+                format: row.get(4)?,
+            },
+        ))
+    })?;
+    for row in rows {
+        let (identifier, book) = row?;
+        shared.entry(identifier).or_default().push(book);
+    }
+
+    let mut seen = groups
+        .iter()
+        .map(|group| {
+            group
+                .books
+                .iter()
+                .map(|book| book.hash.clone())
+                .collect::<BTreeSet<_>>()
+        })
+        .collect::<Vec<_>>();
+    let mut found = Vec::new();
+    for books in shared.into_values().filter(|books| books.len() > 1) {
+        let hashes = books
+            .iter()
+            .map(|book| book.hash.clone())
+            .collect::<BTreeSet<_>>();
+        if seen.iter().any(|group| hashes.is_subset(group)) {
+            continue;
+        }
+        seen.push(hashes);
+        found.push(DuplicateGroup {
+            kind: MatchKind::Identifier,
+            books,
+        });
+    }
+    Ok(found)
+}
+
+/// Groups of books in the library that are copies of one another, hashing
+/// any files not seen before.
+#[command]
+pub async fn find_duplicates(app: AppHandle) -> Result<Vec<DuplicateGroup>> {
+    tauri::async_runtime::spawn_blocking(move || {
+        refresh(&app)?;
+        let db = app.state::<LibraryDb>();
+        let conn = db.conn();
+        let Some(hidden) = access::lock(&app, &conn)?.hidden_books(&conn)? else {
+            return Ok(Vec::new());
+        };
+        let mut groups = duplicate_groups(&conn)?;
+        for group in &mut groups {
+            group.books.retain(|book| !hidden.contains(&book.hash));
+        }
+        groups.retain(|group| group.books.len() > 1);
+        Ok(groups)
+    })
+    .await?
+}
+
+/// Checks files about to be imported against the books in the library.
+#[command]
+pub async fn check_duplicates(app: AppHandle, paths: Vec<PathBuf>) -> Result<Vec<ImportCheck>> {
+    tauri::async_runtime::spawn_blocking(move || {
+        refresh(&app)?;
+        let db = app.state::<LibraryDb>();
+        let hidden = {
+            let conn = db.conn();
+            access::lock(&app, &conn)?.hidden_books(&conn)?
+        };
+        paths
+            .into_iter()
+            .map(|path| {
+                let fingerprint = fingerprint(&path)?;
+                let mut matches = matches(&db.conn(), &fingerprint)?;
+                // Locked books are not let on to exist
+                match &hidden {
+                    Some(hidden) => matches.retain(|m| !hidden.contains(&m.book_hash)),
+                    None => matches.clear(),
+                }
+                Ok(ImportCheck { path, matches })
+            })
+            .collect()
+    })
+    .await?
+}
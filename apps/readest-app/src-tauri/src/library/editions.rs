@@ -0,0 +1,877 @@
+//! Editions of a book. When an EPUB is replaced by an updated edition, its
+//! highlights, notes and bookmarks are moved to where their text is in the
+//! new one, instead of being left on CFIs that point nowhere.
+//!
+//! Each spine document is hashed along with the rest of the fingerprint
+//! (see [`super::dedup`]), so the chapters that did not change are known:
+//! their notes keep their CFIs, with only the spine step changed. Notes in
+//! chapters that did change are looked up by their text, first where it
+//! is exactly, then between the first and last words of it, and last as
+//! the closest run of text by edit distance. Those found nowhere are put
+//! at the start of the chapter they most likely are in, and listed in the
+//! report for the user to look at.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tauri::{command, AppHandle, Manager};
+
+use super::db::{self, Book, LibraryDb};
+use crate::error::{Error, Result};
+use crate::formats::epub::cfi::{normalize, DocumentText, ANCHOR_CHARS};
+use crate::formats::epub::stream::MappedReader;
+use crate::formats::epub::{EpubArchive, ManifestItem};
+use crate::utils::now_millis;
+
+/// Notes longer than this, in characters, are not matched
+/// by edit distance, which their anchors are more reliable for anyway.
+const MAX_APPROXIMATE_LEN: usize = 400;
+/// Cells of the edit distance table worth filling for one chapter.
+const MAX_APPROXIMATE_CELLS: usize = 20_000_000;
+
+fn edition_error(message: impl Into<String>) -> Error {
+    Error::Edition(message.into())
+}
+
+/// Hash of one spine document, with line endings normalized.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChapterHash {
+    pub spine_index: usize,
+    pub href: String,
+    pub hash: String,
+}
+
+impl ChapterHash {
+    pub fn of(spine_index: usize, href: &str, normalized: &[u8]) -> Self {
+        Self {
+            spine_index,
+            href: href.to_string(),
+            hash: format!("{:x}", Sha256::digest(normalized)),
+        }
+    }
+}
+
+/// The spine documents of the EPUB at `path`, hashed.
+pub fn chapter_hashes(path: &Path) -> Result<Vec<ChapterHash>> {
+    let mut epub = EpubArchive::open(path)?;
+    let package = epub.package()?;
+    let mut chapters = Vec::new();
+    for (index, item) in package.spine_items().enumerate() {
+        let data = epub.read_entry(&item.href)?;
+        let normalized = data.into_iter().filter(|&b| b != b'\r').collect::<Vec<_>>();
+        chapters.push(ChapterHash::of(index, &item.href, &normalized));
+    }
+    Ok(chapters)
+}
+
+/// Replaces the chapter hashes of book `book_hash` with `chapters`.
+pub fn record(conn: &Connection, book_hash: &str, chapters: &[ChapterHash]) -> Result<()> {
+    conn.execute(
+        "DELETE FROM book_chapters WHERE book_hash = ?1",
+        [book_hash],
+    )?;
+    let mut stmt = conn.prepare_cached(
+        "INSERT OR IGNORE INTO book_chapters (book_hash, spine_index, href, hash)
+         VALUES (?1, ?2, ?3, ?4)",
+    )?;
+    for chapter in chapters {
+        stmt.execute(params![
+            book_hash,
+            chapter.spine_index as i64,
+            chapter.href,
+            chapter.hash
+        ])?;
+    }
+    Ok(())
+}
+
+fn recorded(conn: &Connection, book_hash: &str) -> Result<Vec<ChapterHash>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT spine_index, href, hash FROM book_chapters
+         WHERE book_hash = ?1 ORDER BY spine_index",
+    )?;
+    let chapters = stmt
+        .query_map([book_hash], |row| {
+            Ok(ChapterHash {
+                spine_index: row.get::<_, i64>(0)? as usize,
+                href: row.get(1)?,
+                hash: row.get(2)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(chapters)
+}
+
+/// The chapter hashes of `book`, as recorded, or from its file when it is
+/// still the book.
+fn chapters_of(app: &AppHandle, book: &Book) -> Result<Vec<ChapterHash>> {
+    let chapters = recorded(&app.state::<LibraryDb>().conn(), &book.hash)?;
+    if !chapters.is_empty() {
+        return Ok(chapters);
+    }
+    match super::book_path(app, book) {
+        Some(path) if super::partial_md5(&path)? == book.hash => chapter_hashes(&path),
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// The start of a CFI up to the spine item it points into: the steps to
+/// the spine and to the item in it, and whether the item step had the
+/// assertion of its id.
+struct SpineStep<'a> {
+    spine: u32,
+    item: u32,
+    assertion: bool,
+    /// What comes after, from the `!` into the document.
+    rest: &'a str,
+}
+
+fn parse_number(text: &str) -> Option<(u32, &str)> {
+    let end = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    Some((text[..end].parse().ok()?, &text[end..]))
+}
+
+impl<'a> SpineStep<'a> {
+    fn parse(cfi: &'a str) -> Option<Self> {
+        let inner = cfi.trim().strip_prefix("epubcfi(")?.strip_suffix(')')?;
+        let (spine, rest) = parse_number(inner.strip_prefix('/')?)?;
+        let (item, mut rest) = parse_number(rest.strip_prefix('/')?)?;
+        let assertion = rest.starts_with('[');
+        if assertion {
+            rest = &rest[rest.find(']')? + 1..];
+        }
+        Some(Self {
+            spine,
+            item,
+            assertion,
+            rest,
+        })
+    }
+
+    fn spine_index(&self) -> Option<usize> {
+        (self.item >= 2).then(|| self.item as usize / 2 - 1)
+    }
+
+    /// The numbers of the steps into the document, up to where a range
+    /// starts.
+    fn document_steps(&self) -> Vec<u32> {
+        let path = self.rest.split(',').next().unwrap_or_default();
+        path.split(['/', ':', '!'])
+            .filter_map(|step| parse_number(step).map(|(n, _)| n))
+            .collect()
+    }
+
+    /// The start of a CFI into spine item `index` of `items`, like this one.
+    fn for_item(&self, index: usize, items: &[ManifestItem]) -> String {
+        let assertion = match items.get(index) {
+            Some(item) if self.assertion => format!("[{}]", item.id),
+            _ => String::new(),
+        };
+        format!("/{}/{}{assertion}", self.spine, 2 * (index + 1))
+    }
+}
+
+/// A spine document of the new edition: its text and that text as
+/// characters, which are what its positions count.
+struct Chapter {
+    text: DocumentText,
+    chars: Vec<char>,
+}
+
+impl Chapter {
+    /// How alike the steps to the chunk of character `start` are to `old`.
+    fn closeness(&self, start: usize, old: &[u32]) -> usize {
+        let steps = &self.text.chunks[self.text.positions[start].chunk];
+        steps.iter().zip(old).take_while(|(a, b)| a == b).count()
+    }
+}
+
+fn find_all(text: &[char], pattern: &[char]) -> Vec<usize> {
+    if pattern.is_empty() || pattern.len() > text.len() {
+        return Vec::new();
+    }
+    (0..=text.len() - pattern.len())
+        .filter(|&i| text[i..i + pattern.len()] == *pattern)
+        .collect()
+}
+
+/// The run of `text` closest to `pattern` by edit distance, which any run
+/// may start at, with its distance.
+fn closest(text: &[char], pattern: &[char]) -> Option<(usize, usize, usize)> {
+    let m = pattern.len();
+    // Cost and start of the best alignment of each prefix of the pattern
+    let mut prev = (0..=m).map(|i| (i, 0)).collect::<Vec<_>>();
+    let mut cur = vec![(0, 0); m + 1];
+    let mut best: Option<(usize, usize, usize)> = None;
+    for (j, &c) in text.iter().enumerate() {
+        cur[0] = (0, j + 1);
+        for i in 1..=m {
+            let (cost, start) = prev[i - 1];
+            let mut cell = (cost + usize::from(pattern[i - 1] != c), start);
+            if prev[i].0 + 1 < cell.0 {
+                cell = (prev[i].0 + 1, prev[i].1);
+            }
+            if cur[i - 1].0 + 1 < cell.0 {
+                cell = (cur[i - 1].0 + 1, cur[i - 1].1);
+            }
+            cur[i] = cell;
+        }
+        let (cost, start) = cur[m];
+        if start < j + 1 && best.map_or(true, |(_, _, best)| cost < best) {
+            best = Some((start, j + 1, cost));
+        }
+        std::mem::swap(&mut prev, &mut cur);
+    }
+    best
+}
+
+/// How a note was placed in the new edition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Placement {
+    /// In a chapter that did not change.
+    Unchanged,
+    /// Where its text is.
+    Exact,
+    /// Where the text most like it is, or for bookmarks, at the start of
+    /// their chapter.
+    Approximate,
+    Orphaned,
+}
+
+/// The new edition, with the text of its chapters read as needed.
+struct Edition {
+    epub: EpubArchive<MappedReader>,
+    items: Vec<ManifestItem>,
+    chapters: Vec<ChapterHash>,
+    texts: HashMap<usize, Option<Chapter>>,
+}
+
+impl Edition {
+    fn open(path: &Path) -> Result<Self> {
+        let mut epub = EpubArchive::open(path)?;
+        let items = epub.package()?.spine_items().cloned().collect();
+        Ok(Self {
+            chapters: chapter_hashes(path)?,
+            epub,
+            items,
+            texts: HashMap::new(),
+        })
+    }
+
+    fn text(&mut self, index: usize) -> Option<&Chapter> {
+        if !self.texts.contains_key(&index) {
+            let text = self
+                .items
+                .get(index)
+                .filter(|item| item.media_type.contains("html"))
+                .and_then(|item| self.epub.read_entry_string(&item.href).ok())
+                .and_then(|xhtml| DocumentText::parse(&xhtml))
+                .map(|text| Chapter {
+                    chars: text.text.chars().collect(),
+                    text,
+                });
+            self.texts.insert(index, text);
+        }
+        self.texts.get(&index).and_then(Option::as_ref)
+    }
+
+    /// The chapter the same as chapter `index` of the old edition, if any.
+    fn unchanged(&self, old: &[ChapterHash], index: usize) -> Option<usize> {
+        let old = old.iter().find(|chapter| chapter.spine_index == index)?;
+        let same = |chapter: &&ChapterHash| chapter.hash == old.hash;
+        self.chapters
+            .iter()
+            .filter(same)
+            .min_by_key(|chapter| {
+                (
+                    chapter.href != old.href,
+                    chapter.spine_index.abs_diff(index),
+                )
+            })
+            .map(|chapter| chapter.spine_index)
+    }
+
+    /// The chapter most likely to have the text of chapter `index` of the
+    /// old edition: the one of the same document, or the one as far into
+    /// the book.
+    fn likely(&self, old: &[ChapterHash], index: usize) -> usize {
+        let href = old
+            .iter()
+            .find(|chapter| chapter.spine_index == index)
+            .map(|chapter| &chapter.href);
+        if let Some(chapter) = self.chapters.iter().find(|c| Some(&c.href) == href) {
+            return chapter.spine_index;
+        }
+        let last = self.items.len().saturating_sub(1);
+        match old.len() {
+            0 => index.min(last),
+            len => (index * self.items.len() / len).min(last),
+        }
+    }
+
+    /// CFI of the start of chapter `index`.
+    fn start(&mut self, index: usize) -> String {
+        match self.text(index) {
+            Some(chapter) => chapter.text.start_cfi(index),
+            None => format!("epubcfi(/6/{}!/4)", (index + 1) * 2),
+        }
+    }
+
+    /// Where text `pattern` is, looking in chapter `likely` first and going
+    /// out from it, and only near it for text that is not exactly there.
+    fn find(
+        &mut self,
+        likely: usize,
+        pattern: &[char],
+        old: &[u32],
+    ) -> Option<(usize, usize, usize, Placement)> {
+        let count = self.items.len();
+        let order = (0..count)
+            .flat_map(|d| [likely.checked_sub(d), Some(likely + d).filter(|_| d > 0)])
+            .flatten()
+            .filter(|&index| index < count)
+            .collect::<Vec<_>>();
+        for &index in &order {
+            let Some(chapter) = self.text(index) else {
+                continue;
+            };
+            let found = find_all(&chapter.chars, pattern);
+            if let Some(&start) = found
+                .iter()
+                .max_by_key(|&&start| (chapter.closeness(start, old), std::cmp::Reverse(start)))
+            {
+                return Some((index, start, start + pattern.len(), Placement::Exact));
+            }
+        }
+        let near = order.iter().copied().take(3).collect::<Vec<_>>();
+        if pattern.len() >= 2 * ANCHOR_CHARS {
+            let head = &pattern[..ANCHOR_CHARS];
+            let tail = &pattern[pattern.len() - ANCHOR_CHARS..];
+            for &index in &near {
+                let Some(chapter) = self.text(index) else {
+                    continue;
+                };
+                let tails = find_all(&chapter.chars, tail);
+                let best = find_all(&chapter.chars, head)
+                    .into_iter()
+                    .flat_map(|start| {
+                        tails
+                            .iter()
+                            .map(move |&t| (start, t + ANCHOR_CHARS))
+                            .filter(move |&(start, end)| end > start + ANCHOR_CHARS)
+                    })
+                    .filter(|&(start, end)| {
+                        let len = end - start;
+                        2 * len >= pattern.len() && len <= 2 * pattern.len()
+                    })
+                    .min_by_key(|&(start, end)| (end - start).abs_diff(pattern.len()));
+                if let Some((start, end)) = best {
+                    return Some((index, start, end, Placement::Approximate));
+                }
+            }
+        }
+        if pattern.len() <= MAX_APPROXIMATE_LEN {
+            let limit = (pattern.len() / 5).max(2);
+            for &index in &near {
+                let Some(chapter) = self.text(index) else {
+                    continue;
+                };
+                if chapter.chars.len() * pattern.len() > MAX_APPROXIMATE_CELLS {
+                    continue;
+                }
+                if let Some((start, end, distance)) = closest(&chapter.chars, pattern) {
+                    if distance <= limit {
+                        return Some((index, start, end, Placement::Approximate));
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Where note `note` of the old edition goes in this one, and how it was
+    /// placed.
+    fn place(&mut self, old: &[ChapterHash], note: &Value) -> Option<(String, Placement)> {
+        let cfi = note.get("cfi")?.as_str()?;
+        let step = SpineStep::parse(cfi)?;
+        let index = step.spine_index()?;
+        if let Some(new) = self.unchanged(old, index) {
+            let spine = step.for_item(new, &self.items);
+            return Some((
+                format!("epubcfi({spine}{})", step.rest),
+                Placement::Unchanged,
+            ));
+        }
+        let likely = self.likely(old, index);
+        let pattern = normalize(note.get("text").and_then(Value::as_str).unwrap_or_default())
+            .chars()
+            .collect::<Vec<_>>();
+        let is_range = step.rest.contains(',');
+        if !pattern.is_empty() {
+            let old_steps = step.document_steps();
+            if let Some((index, start, end, placement)) = self.find(likely, &pattern, &old_steps) {
+                let text = &self.text(index)?.text;
+                let cfi = if is_range {
+                    text.range_cfi(index, text.positions[start], text.positions[end - 1])
+                } else {
+                    text.point_cfi(index, text.positions[start])
+                };
+                return Some((cfi, placement));
+            }
+        }
+        let placement = if is_range {
+            Placement::Orphaned
+        } else {
+            Placement::Approximate
+        };
+        Some((self.start(likely), placement))
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrphanedNote {
+    pub id: Option<String>,
+    pub text: Option<String>,
+    /// Its CFI in the old edition.
+    pub cfi: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemapReport {
+    /// The book the notes were moved to.
+    pub book_hash: String,
+    /// Chapters of the old edition that are in the new one as they were.
+    pub unchanged_chapters: usize,
+    pub changed_chapters: usize,
+    /// Notes in unchanged chapters.
+    pub unchanged: usize,
+    /// Notes found where their text is exactly.
+    pub exact: usize,
+    /// Notes put where the text most like theirs is.
+    pub approximate: usize,
+    /// Notes whose text is nowhere, put at the start of their chapter.
+    pub orphaned: Vec<OrphanedNote>,
+    /// Notes the new edition had already.
+    pub skipped: usize,
+}
+
+/// The config of book `book_hash` and whether it is encrypted at rest.
+#[cfg(desktop)]
+fn read_config(app: &AppHandle, book_hash: &str) -> Result<(Option<Value>, bool)> {
+    use crate::secrets::storage;
+
+    let path = super::config_path(app, book_hash)?;
+    let encrypted = std::fs::read(&path).is_ok_and(|data| storage::is_encrypted(&data));
+    let config = storage::read_file(&path)?
+        .map(|data| serde_json::from_slice::<Value>(&data))
+        .transpose()?;
+    if config
+        .as_ref()
+        .is_some_and(crate::secrets::notes::is_protected)
+    {
+        return Err(Error::PrivateNotes(
+            "the notes of this book are protected".to_string(),
+        ));
+    }
+    Ok((config, encrypted))
+}
+
+#[cfg(desktop)]
+fn write_config(app: &AppHandle, book_hash: &str, config: &Value, encrypted: bool) -> Result<()> {
+    let path = super::config_path(app, book_hash)?;
+    crate::secrets::storage::write_file(&path, serde_json::to_vec(config)?, encrypted)
+}
+
+#[cfg(not(desktop))]
+fn read_config(app: &AppHandle, book_hash: &str) -> Result<(Option<Value>, bool)> {
+    match std::fs::read(super::config_path(app, book_hash)?) {
+        Ok(data) => Ok((Some(serde_json::from_slice(&data)?), false)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok((None, false)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(not(desktop))]
+fn write_config(app: &AppHandle, book_hash: &str, config: &Value, _encrypted: bool) -> Result<()> {
+    let path = super::config_path(app, book_hash)?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, serde_json::to_vec(config)?)?;
+    Ok(())
+}
+
+fn epub_book(conn: &Connection, hash: &str) -> Result<Book> {
+    let book = db::get_book(conn, hash)?
+        .ok_or_else(|| edition_error(format!("no book {hash} in the library")))?;
+    if !book.format.eq_ignore_ascii_case("epub") {
+        return Err(edition_error("only the notes of EPUBs can be moved"));
+    }
+    Ok(book)
+}
+
+/// Moves the notes and bookmarks of book `from` to book `to`, another
+/// edition of it, leaving those of `from` as they are.
+pub fn remap(app: &AppHandle, from: &str, to: &str) -> Result<RemapReport> {
+    let (old_book, new_book) = {
+        let db = app.state::<LibraryDb>();
+        let conn = db.conn();
+        (epub_book(&conn, from)?, epub_book(&conn, to)?)
+    };
+    let path = super::book_path(app, &new_book)
+        .ok_or_else(|| edition_error(format!("the file of {to} is missing")))?;
+    let old = chapters_of(app, &old_book)?;
+    let mut edition = Edition::open(&path)?;
+
+    let (source, _) = read_config(app, from)?;
+    let Some(source) = source else {
+        return Ok(RemapReport {
+            book_hash: to.to_string(),
+            ..RemapReport::default()
+        });
+    };
+    let (target, encrypted) = read_config(app, to)?;
+    let mut target = target.unwrap_or_else(|| json!({}));
+    let mut notes = match target.get_mut("booknotes").map(Value::take) {
+        Some(Value::Array(notes)) => notes,
+        _ => Vec::new(),
+    };
+
+    let unchanged_chapters = old
+        .iter()
+        .filter(|chapter| edition.unchanged(&old, chapter.spine_index).is_some())
+        .count();
+    let mut report = RemapReport {
+        book_hash: to.to_string(),
+        unchanged_chapters,
+        changed_chapters: old.len() - unchanged_chapters,
+        ..RemapReport::default()
+    };
+    let now = now_millis();
+    let incoming = source
+        .get("booknotes")
+        .and_then(Value::as_array)
+        .cloned()
+        .unwrap_or_default();
+    for mut note in incoming {
+        if note.get("deletedAt").is_some_and(|at| !at.is_null()) {
+            continue;
+        }
+        let id = note.get("id").and_then(Value::as_str).map(str::to_string);
+        if id.is_some()
+            && notes
+                .iter()
+                .any(|n| n.get("id").and_then(Value::as_str) == id.as_deref())
+        {
+            report.skipped += 1;
+            continue;
+        }
+        let old_cfi = note
+            .get("cfi")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string();
+        let (cfi, placement) = edition
+            .place(&old, &note)
+            .unwrap_or_else(|| (old_cfi.clone(), Placement::Orphaned));
+        match placement {
+            Placement::Unchanged => report.unchanged += 1,
+            Placement::Exact => report.exact += 1,
+            Placement::Approximate => report.approximate += 1,
+            Placement::Orphaned => report.orphaned.push(OrphanedNote {
+                id,
+                text: note.get("text").and_then(Value::as_str).map(str::to_string),
+                cfi: old_cfi,
+            }),
+        }
+        if let Some(object) = note.as_object_mut() {
+            object.insert("cfi".into(), cfi.into());
+            object.insert("updatedAt".into(), now.into());
+        }
+        notes.push(note);
+    }
+
+    let location = source
+        .get("location")
+        .and_then(Value::as_str)
+        .filter(|_| target.get("location").map_or(true, Value::is_null))
+        .and_then(|location| {
+            let step = SpineStep::parse(location)?;
+            let index = step.spine_index()?;
+            Some(match edition.unchanged(&old, index) {
+                Some(new) => {
+                    let spine = step.for_item(new, &edition.items);
+                    format!("epubcfi({spine}{})", step.rest)
+                }
+                None => {
+                    let likely = edition.likely(&old, index);
+                    edition.start(likely)
+                }
+            })
+        });
+    let object = target
+        .as_object_mut()
+        .ok_or_else(|| Error::InvalidBook(format!("invalid config of {to}")))?;
+    object.insert("booknotes".into(), Value::Array(notes));
+    if let Some(location) = location {
+        object.insert("location".into(), location.into());
+    }
+    object.insert("updatedAt".into(), now.into());
+    write_config(app, to, &target, encrypted)?;
+    #[cfg(desktop)]
+    crate::sync::sidecar::after_write(app, to);
+    Ok(report)
+}
+
+/// Whether the file at `path` is likely another edition of `book`: an
+/// EPUB with an identifier or a chapter in common with it.
+pub fn is_edition(app: &AppHandle, book: &Book, path: &Path) -> bool {
+    if !book.format.eq_ignore_ascii_case("epub") || crate::formats::extension(path) != "epub" {
+        return false;
+    }
+    let (identifiers, chapters) = {
+        let db = app.state::<LibraryDb>();
+        let conn = db.conn();
+        (
+            super::identifiers::for_book(&conn, &book.hash).unwrap_or_default(),
+            recorded(&conn, &book.hash).unwrap_or_default(),
+        )
+    };
+    let shares_identifier = !identifiers.is_empty()
+        && super::identifiers::scan(path)
+            .unwrap_or_default()
+            .iter()
+            .any(|identifier| identifiers.contains(identifier));
+    shares_identifier
+        || (!chapters.is_empty()
+            && chapter_hashes(path)
+                .unwrap_or_default()
+                .iter()
+                .any(|chapter| chapters.iter().any(|old| old.hash == chapter.hash)))
+}
+
+/// Replaces book `hash` by the edition at `path`: imports it, moves the
+/// notes over, gives it the tags and group of the old one and moves the
+/// old one to the trash, from where it can be restored.
+pub fn replace(app: &AppHandle, hash: &str, path: &Path) -> Result<RemapReport> {
+    let old = db::get_book(&app.state::<LibraryDb>().conn(), hash)?
+        .ok_or_else(|| edition_error(format!("no book {hash} in the library")))?;
+    let mut new = super::import::import_book(app, path)?;
+    if new.hash == hash {
+        return Err(edition_error(format!(
+            "{} is the same edition",
+            path.display()
+        )));
+    }
+    let report = remap(app, hash, &new.hash)?;
+
+    let now = now_millis();
+    new.tags = old.tags.clone();
+    new.group_id = old.group_id.clone();
+    new.group_name = old.group_name.clone();
+    new.updated_at = now;
+    let mut trashed = old;
+    trashed.deleted_at = Some(now);
+    trashed.updated_at = now;
+    {
+        let db = app.state::<LibraryDb>();
+        let mut conn = db.conn();
+        db::upsert_books(&mut conn, &[new, trashed.clone()])?;
+    }
+    super::trash::trash(app, std::slice::from_ref(&trashed))?;
+    #[cfg(any(target_os = "macos", windows))]
+    crate::system_search::remove(app, std::slice::from_ref(&trashed.hash));
+    Ok(report)
+}
+
+/// Moves the notes and bookmarks of book `from_hash` to book `to_hash`,
+/// another edition of it, returning where they went.
+#[command]
+pub async fn remap_annotations(
+    app: AppHandle,
+    from_hash: String,
+    to_hash: String,
+) -> Result<RemapReport> {
+    tauri::async_runtime::spawn_blocking(move || remap(&app, &from_hash, &to_hash)).await?
+}
+
+/// Replaces book `book_hash` by the edition at `path`, see [`replace`].
+#[command]
+pub async fn replace_book_edition(
+    app: AppHandle,
+    book_hash: String,
+    path: std::path::PathBuf,
+) -> Result<RemapReport> {
+    tauri::async_runtime::spawn_blocking(move || replace(&app, &book_hash, &path)).await?
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    use super::*;
+
+    const SAME: &str = "<p>The first chapter stays the same.</p>";
+    const OLD: &str = "<p>Call me Ishmael. Some years ago, never mind how long.</p>";
+    const NEW: &str =
+        "<p>Call me Ishmael. Some years ago \u{2014} never mind how long precisely.</p>";
+
+    fn xhtml(body: &str) -> String {
+        format!(
+            "<html xmlns=\"http://www.w3.org/1999/xhtml\"><head><title>T</title></head>\
+             <body>{body}</body></html>"
+        )
+    }
+
+    /// An EPUB with the spine documents `chapters`, named by id.
+    fn epub(name: &str, chapters: &[(&str, &str)]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("vlarch-{name}-{}.epub", std::process::id()));
+        let mut zip = ZipWriter::new(std::fs::File::create(&path).unwrap());
+        let options = SimpleFileOptions::default();
+        let mut write = |name: &str, data: &str| {
+            zip.start_file(name, options).unwrap();
+            zip.write_all(data.as_bytes()).unwrap();
+        };
+        write("mimetype", "application/epub+zip");
+        write(
+            "META-INF/container.xml",
+            "<container xmlns=\"urn:oasis:names:tc:opendocument:xmlns:container\"><rootfiles>\
+             <rootfile full-path=\"content.opf\"/></rootfiles></container>",
+        );
+        let items = chapters
+            .iter()
+            .map(|(id, _)| {
+                format!(
+                    "<item id=\"{id}\" href=\"{id}.xhtml\" media-type=\"application/xhtml+xml\"/>"
+                )
+            })
+            .collect::<String>();
+        let spine = chapters
+            .iter()
+            .map(|(id, _)| format!("<itemref idref=\"{id}\"/>"))
+            .collect::<String>();
+        write(
+            "content.opf",
+            &format!(
+                "<package xmlns=\"http://www.idpf.org/2007/opf\"><metadata/>\
+                 <manifest>{items}</manifest><spine>{spine}</spine></package>"
+            ),
+        );
+        for (id, body) in chapters {
+            write(&format!("{id}.xhtml"), &xhtml(body));
+        }
+        zip.finish().unwrap();
+        path
+    }
+
+    fn place(
+        edition: &mut Edition,
+        old: &[ChapterHash],
+        cfi: &str,
+        text: &str,
+    ) -> (String, Placement) {
+        edition
+            .place(old, &json!({ "cfi": cfi, "text": text }))
+            .unwrap()
+    }
+
+    #[test]
+    fn cfis_are_split_at_the_spine_item() {
+        let step = SpineStep::parse("epubcfi(/6/4[b]!/4/2/1,/1:0,/1:5)").unwrap();
+        assert_eq!((step.spine, step.item, step.assertion), (6, 4, true));
+        assert_eq!(step.rest, "!/4/2/1,/1:0,/1:5");
+        assert_eq!(step.spine_index(), Some(1));
+        assert_eq!(step.document_steps(), [4, 2, 1]);
+        assert!(SpineStep::parse("/6/4!/4").is_none());
+        assert_eq!(
+            SpineStep::parse("epubcfi(/6/0!/4)").unwrap().spine_index(),
+            None
+        );
+    }
+
+    #[test]
+    fn closest_runs_are_found_by_edit_distance() {
+        let chars = |text: &str| text.chars().collect::<Vec<_>>();
+        let text = chars("the kitten sat on the mat");
+        assert_eq!(find_all(&text, &chars("the")), [0, 18]);
+        assert!(find_all(&text, &chars("")).is_empty());
+        assert_eq!(closest(&text, &chars("sitten")), Some((4, 10, 1)));
+        assert_eq!(closest(&text, &chars("mat")), Some((22, 25, 0)));
+    }
+
+    #[test]
+    fn notes_follow_their_text_into_the_new_edition() {
+        let old = [
+            ChapterHash::of(0, "a.xhtml", xhtml(SAME).as_bytes()),
+            ChapterHash::of(1, "b.xhtml", xhtml(OLD).as_bytes()),
+        ];
+        let path = epub(
+            "edition",
+            &[
+                ("preface", "<p>A new preface.</p>"),
+                ("a", SAME),
+                ("b", NEW),
+            ],
+        );
+        let mut edition = Edition::open(&path).unwrap();
+        assert_eq!(edition.unchanged(&old, 0), Some(1));
+        assert_eq!(edition.unchanged(&old, 1), None);
+        assert_eq!(edition.likely(&old, 1), 2);
+
+        assert_eq!(
+            place(&mut edition, &old, "epubcfi(/6/2[a]!/4/2/1,:0,:5)", "The f"),
+            (
+                "epubcfi(/6/4[a]!/4/2/1,:0,:5)".to_string(),
+                Placement::Unchanged
+            )
+        );
+        assert_eq!(
+            place(
+                &mut edition,
+                &old,
+                "epubcfi(/6/4[b]!/4/2,/1:0,/1:16)",
+                "Call me Ishmael."
+            ),
+            (
+                "epubcfi(/6/6!/4/2,/1:0,/1:16)".to_string(),
+                Placement::Exact
+            )
+        );
+        let (cfi, placement) = place(
+            &mut edition,
+            &old,
+            "epubcfi(/6/4[b]!/4/2,/1:40,/1:60)",
+            "never mind how long precisly",
+        );
+        assert_eq!(placement, Placement::Approximate);
+        assert!(cfi.starts_with("epubcfi(/6/6!/4/2,/1:"), "{cfi}");
+        assert_eq!(
+            place(
+                &mut edition,
+                &old,
+                "epubcfi(/6/4[b]!/4/2,/1:0,/1:5)",
+                "Nowhere to be seen"
+            ),
+            ("epubcfi(/6/6!/4)".to_string(), Placement::Orphaned)
+        );
+        // Bookmarks without their text go to the start of their chapter
+        assert_eq!(
+            place(&mut edition, &old, "epubcfi(/6/4[b]!/4/2/1:3)", ""),
+            ("epubcfi(/6/6!/4)".to_string(), Placement::Approximate)
+        );
+        assert!(edition.place(&old, &json!({ "cfi": "nonsense" })).is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+}
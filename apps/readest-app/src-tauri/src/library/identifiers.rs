@@ -0,0 +1,347 @@
+//! ISBNs, Amazon ASINs and DOIs of books, from their metadata and from the
+//! text of their front and back matter, where copyright pages and
+//! colophons print them. They are found when books are fingerprinted, see
+//! [`super::dedup`], and kept in the index as keys for duplicate detection
+//! and metadata lookups.
+
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use regex::Regex;
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use tauri::{command, State};
+
+use super::db::LibraryDb;
+use crate::error::Result;
+use crate::formats::epub::{self, EpubArchive};
+use crate::formats::fb2::Fb2Book;
+use crate::formats::html::html_to_text;
+use crate::formats::pdf::PdfDocument;
+use crate::formats::text::{TextBook, TextOptions};
+use crate::formats::{self, Chapter};
+
+/// Documents or pages read at the start of a book, and at the end.
+const FRONT_DOCS: usize = 4;
+const BACK_DOCS: usize = 2;
+/// Characters read at each end of a document.
+const MAX_CHARS: usize = 20_000;
+/// Documents with more ISBNs than this are catalogs of other books.
+const MAX_ISBNS_PER_DOC: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scheme {
+    Isbn,
+    Asin,
+    Doi,
+}
+
+impl Scheme {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scheme::Isbn => "isbn",
+            Scheme::Asin => "asin",
+            Scheme::Doi => "doi",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "isbn" => Some(Scheme::Isbn),
+            "asin" => Some(Scheme::Asin),
+            "doi" => Some(Scheme::Doi),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Identifier {
+    pub scheme: Scheme,
+    /// ISBNs as the 13 digits of an ISBN-13, ASINs in upper case and DOIs
+    /// in lower case, so equal identifiers compare equal.
+    pub value: String,
+}
+
+impl Identifier {
+    fn new(scheme: Scheme, value: String) -> Self {
+        Self { scheme, value }
+    }
+}
+
+/// The ISBN-13 of the first valid ISBN-10 or ISBN-13 at the start of
+/// `text`, such as `978-0-441-17271-9` or `0441172717`.
+pub fn normalize_isbn(text: &str) -> Option<String> {
+    let digits = text
+        .chars()
+        .filter(|c| c.is_ascii_digit() || matches!(c, 'X' | 'x'))
+        .take(13)
+        .map(|c| c.to_ascii_uppercase())
+        .collect::<String>();
+    if digits.len() == 13 && is_isbn13(&digits) {
+        return Some(digits);
+    }
+    let isbn10 = digits.get(..10)?;
+    is_isbn10(isbn10).then(|| {
+        let body = format!("978{}", &isbn10[..9]);
+        let check = isbn13_check(&body);
+        format!("{body}{check}")
+    })
+}
+
+fn is_isbn10(digits: &str) -> bool {
+    let bytes = digits.as_bytes();
+    if bytes.len() != 10 || !bytes[..9].iter().all(u8::is_ascii_digit) {
+        return false;
+    }
+    let check = match bytes[9] {
+        b'X' => 10,
+        b @ b'0'..=b'9' => u32::from(b - b'0'),
+        _ => return false,
+    };
+    let sum = bytes[..9]
+        .iter()
+        .enumerate()
+        .map(|(i, b)| (10 - i as u32) * u32::from(b - b'0'))
+        .sum::<u32>()
+        + check;
+    sum % 11 == 0
+}
+
+/// The check digit of the 12 digits of an ISBN-13 without it.
+fn isbn13_check(body: &str) -> u32 {
+    let sum = body
+        .bytes()
+        .enumerate()
+        .map(|(i, b)| u32::from(b - b'0') * if i % 2 == 0 { 1 } else { 3 })
+        .sum::<u32>();
+    (10 - sum % 10) % 10
+}
+
+fn is_isbn13(digits: &str) -> bool {
+    digits.len() == 13
+        && digits.bytes().all(|b| b.is_ascii_digit())
+        && (digits.starts_with("978") || digits.starts_with("979"))
+        && isbn13_check(&digits[..12]) == u32::from(digits.as_bytes()[12] - b'0')
+}
+
+fn normalize_doi(text: &str) -> String {
+    text.trim_end_matches(['.', ',', ';', ':', ')', ']', '}', '>', '"', '\''])
+        .to_lowercase()
+}
+
+/// The identifiers in `text`, in the order they appear. ISBNs and ASINs
+/// count when labelled as such, and ISBN-13s also when they stand alone;
+/// either way an ISBN has to have a valid check digit.
+pub fn find(text: &str) -> Vec<Identifier> {
+    static ISBN: OnceLock<Regex> = OnceLock::new();
+    let isbn = ISBN.get_or_init(|| {
+        Regex::new(
+            r"(?i)\bISBN(?:[ -]?1[03])?\b[^0-9\n]{0,24}?([0-9][0-9Xx‐‑–\- ]{8,24})|\b(97[89](?:[‐‑–\- ]?[0-9]){10})\b",
+        )
+        .expect("valid ISBN pattern")
+    });
+    static ASIN: OnceLock<Regex> = OnceLock::new();
+    let asin = ASIN.get_or_init(|| {
+        Regex::new(r"\b(?i:ASIN)\b[:\s]*([A-Z0-9]{10})\b").expect("valid ASIN pattern")
+    });
+    static DOI: OnceLock<Regex> = OnceLock::new();
+    let doi = DOI.get_or_init(|| {
+        Regex::new(r"\b(10\.[0-9]{4,9}/[-._;()/:A-Za-z0-9]+)").expect("valid DOI pattern")
+    });
+
+    let mut found = Vec::new();
+    for captures in isbn.captures_iter(text) {
+        let Some(digits) = captures.get(1).or_else(|| captures.get(2)) else {
+            continue;
+        };
+        if let Some(isbn) = normalize_isbn(digits.as_str()) {
+            found.push((digits.start(), Identifier::new(Scheme::Isbn, isbn)));
+        }
+    }
+    for captures in asin.captures_iter(text) {
+        let value = &captures[1];
+        found.push((
+            captures.get(1).map_or(0, |m| m.start()),
+            Identifier::new(Scheme::Asin, value.to_string()),
+        ));
+    }
+    for m in doi.find_iter(text) {
+        found.push((
+            m.start(),
+            Identifier::new(Scheme::Doi, normalize_doi(m.as_str())),
+        ));
+    }
+    found.sort_by_key(|(start, _)| *start);
+    found
+        .into_iter()
+        .map(|(_, identifier)| identifier)
+        .collect()
+}
+
+/// The identifiers among those in the metadata of a book.
+fn from_metadata(identifiers: &[epub::Identifier]) -> Vec<Identifier> {
+    let mut found = Vec::new();
+    for identifier in identifiers {
+        let scheme = identifier
+            .scheme
+            .as_deref()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let value = identifier.value.trim();
+        match scheme.as_str() {
+            "isbn" => {
+                found.extend(normalize_isbn(value).map(|isbn| Identifier::new(Scheme::Isbn, isbn)))
+            }
+            "asin" | "mobi-asin" | "amazon" => {
+                found.push(Identifier::new(Scheme::Asin, value.to_ascii_uppercase()))
+            }
+            "doi" => found.push(Identifier::new(
+                Scheme::Doi,
+                normalize_doi(value.trim_start_matches("doi:")),
+            )),
+            // Such as `urn:isbn:9780441172719`
+            _ if value.to_ascii_lowercase().starts_with("urn:isbn:") => found.extend(
+                normalize_isbn(&value[9..]).map(|isbn| Identifier::new(Scheme::Isbn, isbn)),
+            ),
+            _ => found.extend(find(value)),
+        }
+    }
+    found
+}
+
+/// The first `FRONT_DOCS` and last `BACK_DOCS` of `items`, each once.
+fn ends<T>(items: &[T]) -> impl Iterator<Item = &T> {
+    let front = FRONT_DOCS.min(items.len());
+    let back = items.len().saturating_sub(BACK_DOCS).max(front);
+    items[..front].iter().chain(&items[back..])
+}
+
+/// The start and end of `text`, for documents that hold a whole book.
+fn trim_text(text: String) -> String {
+    if text.chars().count() <= 2 * MAX_CHARS {
+        return text;
+    }
+    let head = text.chars().take(MAX_CHARS).collect::<String>();
+    let tail_start = text
+        .char_indices()
+        .rev()
+        .nth(MAX_CHARS - 1)
+        .map_or(0, |(i, _)| i);
+    format!("{head}\n{}", &text[tail_start..])
+}
+
+fn chapter_texts(chapters: &[Chapter]) -> Vec<String> {
+    ends(chapters).map(|chapter| chapter.text.clone()).collect()
+}
+
+/// The metadata identifiers of the book at `path`, and the text of its
+/// front and back matter, for the formats with a native parser.
+fn read_book(path: &Path) -> Result<(Vec<Identifier>, Vec<String>)> {
+    Ok(match formats::extension(path).as_str() {
+        "epub" => {
+            let mut epub = EpubArchive::open(path)?;
+            let package = epub.package()?;
+            let hrefs = package
+                .spine_items()
+                .filter(|item| item.media_type.contains("html"))
+                .map(|item| item.href.clone())
+                .collect::<Vec<_>>();
+            let mut texts = Vec::new();
+            for href in ends(&hrefs) {
+                match epub.read_entry_string(href) {
+                    Ok(html) => texts.push(html_to_text(&html)),
+                    Err(e) => log::warn!("Skipping unreadable chapter {href}: {e}"),
+                }
+            }
+            (from_metadata(&package.metadata.identifiers), texts)
+        }
+        "pdf" => {
+            let pdf = PdfDocument::open(path)?;
+            let pages = (1..=pdf.page_count()).collect::<Vec<_>>();
+            let texts = ends(&pages)
+                .filter_map(|&page| pdf.page_text(page).ok())
+                .collect();
+            (from_metadata(&pdf.metadata().identifiers), texts)
+        }
+        "fb2" | "fbz" => {
+            let book = Fb2Book::open(path)?;
+            (
+                from_metadata(&book.metadata().identifiers),
+                chapter_texts(&book.chapters()),
+            )
+        }
+        "txt" | "md" | "markdown" => {
+            let book = TextBook::open(path, &TextOptions::default())?;
+            (Vec::new(), chapter_texts(&book.chapters()))
+        }
+        _ => (Vec::new(), Vec::new()),
+    })
+}
+
+/// The identifiers of the book at `path`, from its metadata first.
+pub fn scan(path: &Path) -> Result<Vec<Identifier>> {
+    let (mut identifiers, texts) = read_book(path)?;
+    for text in texts {
+        let found = find(&trim_text(text));
+        let isbns = found
+            .iter()
+            .filter(|identifier| identifier.scheme == Scheme::Isbn)
+            .collect::<HashSet<_>>()
+            .len();
+        if isbns <= MAX_ISBNS_PER_DOC {
+            identifiers.extend(found);
+        }
+    }
+    let mut seen = HashSet::new();
+    identifiers.retain(|identifier| seen.insert(identifier.clone()));
+    Ok(identifiers)
+}
+
+/// Replaces the identifiers of book `book_hash` with `identifiers`.
+pub fn record(conn: &Connection, book_hash: &str, identifiers: &[Identifier]) -> Result<()> {
+    conn.execute(
+        "DELETE FROM book_identifiers WHERE book_hash = ?1",
+        [book_hash],
+    )?;
+    let mut stmt = conn.prepare_cached(
+        "INSERT OR IGNORE INTO book_identifiers (book_hash, scheme, value, position)
+         VALUES (?1, ?2, ?3, ?4)",
+    )?;
+    for (position, identifier) in identifiers.iter().enumerate() {
+        stmt.execute(params![
+            book_hash,
+            identifier.scheme.as_str(),
+            identifier.value,
+            position as i64
+        ])?;
+    }
+    Ok(())
+}
+
+/// The identifiers of book `book_hash`, as [`scan`] found them.
+pub fn for_book(conn: &Connection, book_hash: &str) -> Result<Vec<Identifier>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT scheme, value FROM book_identifiers WHERE book_hash = ?1 ORDER BY position",
+    )?;
+    let rows = stmt
+        .query_map([book_hash], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|(scheme, value)| Some(Identifier::new(Scheme::parse(&scheme)?, value)))
+        .collect())
+}
+
+#[command]
+pub async fn get_book_identifiers(
+    db: State<'_, LibraryDb>,
+    hash: String,
+) -> Result<Vec<Identifier>> {
+    for_book(&db.conn(), &hash)
+}
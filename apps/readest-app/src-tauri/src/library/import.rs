@@ -0,0 +1,320 @@
+//! Bulk import of a folder of books. Files are read on a pool of worker
+//! threads, each one hashed, parsed for its metadata and cover, stored
+//! where the storage mode keeps books and added to the library index, with
+//! an `import-progress` event as each file finishes, in whatever order that
+//! happens. Imports run as an `import` job the user can cancel, which
+//! keeps the books imported so far.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use rayon::prelude::*;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tauri::{command, AppHandle, Emitter, Manager};
+
+use super::db::{self, LibraryDb};
+use super::dedup::{self, DuplicateMatch};
+use super::storage::{Storage, StorageMode};
+use crate::analysis::{self, Analyzed};
+use crate::error::{Error, Result};
+use crate::formats::comic::ComicArchive;
+use crate::formats::epub::{EpubArchive, Metadata};
+use crate::formats::fb2::Fb2Book;
+use crate::formats::pdf::PdfDocument;
+use crate::formats::text::{TextBook, TextOptions};
+use crate::formats::{self, is_book_file};
+use crate::hooks::{self, HookEvent};
+use crate::jobs::{self, Job};
+use crate::utils::now_millis;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportResult {
+    pub path: PathBuf,
+    /// The imported book, or the library book with the same hash.
+    pub book: Option<db::Book>,
+    /// True when the book was already in the library and was left untouched.
+    pub duplicate: bool,
+    /// Other books with the same content, imported regardless so the user
+    /// can choose which to keep.
+    pub similar: Vec<DuplicateMatch>,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ImportProgress {
+    current: usize,
+    total: usize,
+    result: ImportResult,
+}
+
+fn scan(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(t) if t.is_dir() => scan(&path, files),
+            Ok(t) if t.is_file() && is_book_file(&path) => files.push(path),
+            _ => {}
+        }
+    }
+}
+
+/// Metadata and cover of the formats with a native parser, or `None` for
+/// the others, which the frontend parses when they are opened.
+fn read_book(path: &Path, ext: &str) -> Result<Option<(Metadata, Option<Vec<u8>>)>> {
+    Ok(Some(match ext {
+        "epub" => {
+            let mut epub = EpubArchive::open(path)?;
+            let package = epub.package()?;
+            let cover = match package.cover() {
+                Some(item) => Some(epub.read_entry(&item.href)?),
+                None => None,
+            };
+            (package.metadata, cover)
+        }
+        "pdf" => (PdfDocument::open(path)?.metadata(), None),
+        "fb2" | "fbz" => {
+            let book = Fb2Book::open(path)?;
+            (book.metadata().clone(), book.cover()?)
+        }
+        "txt" | "md" | "markdown" => {
+            let book = TextBook::open(path, &TextOptions::default())?;
+            (book.metadata().clone(), None)
+        }
+        "cbz" => {
+            let mut comic = ComicArchive::open(path)?;
+            let cover = if comic.pages().is_empty() {
+                None
+            } else {
+                Some(comic.read_page(0)?)
+            };
+            (comic.metadata(), cover)
+        }
+        #[cfg(feature = "djvu")]
+        "djvu" | "djv" => (Metadata::default(), formats::extract_cover(path)?),
+        _ => return Ok(None),
+    }))
+}
+
+/// The `BookMetadata` the frontend reads.
+fn book_metadata(metadata: &Metadata) -> Value {
+    let mut map = Map::new();
+    let mut set = |key: &str, value: Option<Value>| {
+        if let Some(value) = value {
+            map.insert(key.into(), value);
+        }
+    };
+    set("title", Some(metadata.title.clone().into()));
+    set("subtitle", metadata.subtitle.clone().map(Value::from));
+    set("author", Some(metadata.authors.join(", ").into()));
+    set("language", Some(metadata.language.clone().into()));
+    set("publisher", metadata.publisher.clone().map(Value::from));
+    set("published", metadata.published.clone().map(Value::from));
+    set("description", metadata.description.clone().map(Value::from));
+    set("subject", Some(metadata.subjects.clone().into()));
+    set(
+        "identifier",
+        metadata
+            .identifiers
+            .first()
+            .map(|id| id.value.clone().into()),
+    );
+    set("series", metadata.series.clone().map(Value::from));
+    set(
+        "seriesIndex",
+        metadata
+            .series
+            .as_ref()
+            .and(metadata.series_index)
+            .map(Value::from),
+    );
+    Value::Object(map)
+}
+
+/// Adds the book at `path` to the library, stored as the storage mode says.
+/// Returns the library book,
+/// whether it was already there, and the books with the same content.
+fn import_file(app: &AppHandle, path: &Path) -> Result<(db::Book, bool, Vec<DuplicateMatch>)> {
+    let hash = super::partial_md5(path)?;
+    let state = app.state::<LibraryDb>();
+    let existing = db::get_book(&state.conn(), &hash)?;
+    if let Some(existing) = existing.as_ref().filter(|b| b.deleted_at.is_none()) {
+        return Ok((existing.clone(), true, Vec::new()));
+    }
+
+    crate::drm::check(path)?;
+    let ext = formats::extension(path);
+    let (metadata, cover) = read_book(path, &ext)?.unwrap_or_default();
+    let title = match metadata.title.trim() {
+        "" => path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        title => title.to_string(),
+    };
+    let fingerprint = dedup::fingerprint(path)?;
+    let similar = dedup::matches(&state.conn(), &fingerprint)?
+        .into_iter()
+        .filter(|m| m.book_hash != hash)
+        .collect();
+
+    let now = now_millis();
+    let mut imported = db::Book {
+        hash: hash.clone(),
+        format: ext.to_ascii_uppercase(),
+        title: title.clone(),
+        source_title: Some(title),
+        author: metadata.authors.join(", "),
+        group_id: None,
+        group_name: None,
+        tags: Vec::new(),
+        cover_image_url: None,
+        file_path: None,
+        url: None,
+        primary_language: metadata.language.first().cloned(),
+        progress: existing.as_ref().and_then(|b| b.progress),
+        metadata: Some(book_metadata(&metadata)),
+        created_at: now,
+        updated_at: now,
+        deleted_at: None,
+        uploaded_at: None,
+        downloaded_at: Some(now),
+        cover_downloaded_at: None,
+    };
+
+    let storage = app.state::<Storage>();
+    let books_dir = super::books_dir(app)?;
+    std::fs::create_dir_all(books_dir.join(&hash))?;
+    match storage.mode() {
+        StorageMode::Library => {
+            std::fs::copy(path, books_dir.join(super::local_book_filename(&imported)))?;
+        }
+        StorageMode::Reference => imported.file_path = Some(path.to_string_lossy().into_owned()),
+        StorageMode::Managed => {
+            storage.add(&hash, path)?;
+        }
+    }
+    if let Some(cover) = cover {
+        super::write_cover(app, &hash, &cover)?;
+    }
+    app.state::<Analyzed>().insert(&hash);
+    let analysis = analysis::analyze(&hash, path).unwrap_or_else(|e| {
+        log::warn!("Failed to analyze {path:?}: {e}");
+        None
+    });
+    let mut conn = state.conn();
+    db::upsert_books(&mut conn, std::slice::from_ref(&imported))?;
+    dedup::record(&conn, &hash, &fingerprint)?;
+    if let Some(analysis) = &analysis {
+        analysis::record(&conn, analysis)?;
+    }
+    #[cfg(any(target_os = "macos", windows))]
+    crate::system_search::update(app, std::slice::from_ref(&imported));
+    hooks::book_event(app, HookEvent::BookImported, &imported);
+    Ok((imported, false, similar))
+}
+
+/// Adds the book at `path` to the library, as [`import_directory`] does
+/// with each file it finds.
+pub(crate) fn import_book(app: &AppHandle, path: &Path) -> Result<db::Book> {
+    let (book, _, _) = import_file(app, path)?;
+    app.state::<Storage>().save()?;
+    Ok(book)
+}
+
+/// Adds the book at `path`, a file of the app's own such as a download, to
+/// the library and removes the file, unless the library keeps books where
+/// they are.
+pub(crate) fn import_and_discard(app: &AppHandle, path: &Path) -> Result<db::Book> {
+    let result = import_book(app, path);
+    // Only books stored by reference need the file to stay where it is
+    if result.is_err() || app.state::<Storage>().mode() != StorageMode::Reference {
+        if let Err(e) = std::fs::remove_file(path) {
+            log::warn!("Failed to remove {path:?}: {e}");
+        }
+    }
+    result
+}
+
+fn import_files(
+    app: &AppHandle,
+    path: &Path,
+    threads: Option<usize>,
+    job: &Job,
+) -> Result<Vec<ImportResult>> {
+    job.phase("scanning", Some(path.display().to_string()));
+    let mut files = Vec::new();
+    scan(path, &mut files);
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads.unwrap_or(0))
+        .thread_name(|i| format!("import-{i}"))
+        .build()
+        .map_err(std::io::Error::other)?;
+
+    let total = files.len();
+    let done = AtomicUsize::new(0);
+    job.progress("importing", 0, total, None);
+    let results = pool.install(|| {
+        files
+            .into_par_iter()
+            .filter(|_| !job.is_cancelled())
+            .map(|file| {
+                let (book, duplicate, similar, error) = match import_file(app, &file) {
+                    Ok((book, duplicate, similar)) => (Some(book), duplicate, similar, None),
+                    Err(e) => {
+                        log::warn!("Failed to import {file:?}: {e}");
+                        (None, false, Vec::new(), Some(e.to_string()))
+                    }
+                };
+                let current = done.fetch_add(1, Ordering::Relaxed) + 1;
+                let name = file.file_name().map(|n| n.to_string_lossy().into_owned());
+                job.progress("importing", current, total, name);
+                let result = ImportResult {
+                    path: file,
+                    book,
+                    duplicate,
+                    similar,
+                    error,
+                };
+                let _ = app.emit(
+                    "import-progress",
+                    ImportProgress {
+                        current,
+                        total,
+                        result: result.clone(),
+                    },
+                );
+                result
+            })
+            .collect()
+    });
+    app.state::<Storage>().save()?;
+    Ok(results)
+}
+
+/// Imports every book under `path`, recursively, on `threads` workers or
+/// one per CPU. Books already in the library are skipped.
+#[command]
+pub async fn import_directory(
+    app: AppHandle,
+    path: PathBuf,
+    threads: Option<usize>,
+) -> Result<Vec<ImportResult>> {
+    if !path.is_dir() {
+        return Err(Error::Io(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("{} is not a directory", path.display()),
+        )));
+    }
+    let handle = app.clone();
+    jobs::run(&handle, "import", |job| async move {
+        tauri::async_runtime::spawn_blocking(move || import_files(&app, &path, threads, &job))
+            .await?
+    })
+    .await
+}
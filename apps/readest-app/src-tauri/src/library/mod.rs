@@ -0,0 +1,175 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use md5::{Digest, Md5};
+use tauri::{AppHandle, Manager};
+
+use crate::cloud::cache::CloudCache;
+use crate::error::{Error, Result};
+use crate::formats::is_book_file;
+use crate::paths;
+use crate::utils::sanitize_file_name;
+
+pub mod access;
+pub mod backup;
+pub mod collections;
+pub mod db;
+pub mod dedup;
+pub mod editions;
+pub mod identifiers;
+pub mod import;
+pub mod storage;
+pub mod tags;
+pub mod thumbs;
+pub mod trash;
+pub mod verify;
+#[cfg(desktop)]
+pub mod watcher;
+
+/// Folder under the app data dir where the frontend stores imported books,
+/// one sub-folder per book hash (see `LOCAL_BOOKS_SUBDIR` in the frontend).
+const BOOKS_SUBDIR: &str = "VL-Arch/Books";
+/// Name of the cover in the folder of a book.
+const COVER_FILE: &str = "cover.png";
+
+pub fn books_dir(app: &AppHandle) -> Result<PathBuf> {
+    Ok(paths::data_dir(app)?.join(BOOKS_SUBDIR))
+}
+
+/// Name of the frontend's per-book config, see `getConfigFilename`.
+const CONFIG_FILE: &str = "config.json";
+
+/// The config of book `hash`, with its bookmarks, highlights and notes. It
+/// may be encrypted at rest on desktop, see `secrets::storage`.
+pub fn config_path(app: &AppHandle, hash: &str) -> Result<PathBuf> {
+    check_hash(hash)?;
+    Ok(books_dir(app)?.join(hash).join(CONFIG_FILE))
+}
+
+/// Fails unless `hash` looks like a book hash, before it is joined to a
+/// path: hashes come from the webview and must not lead out of the folder.
+pub fn check_hash(hash: &str) -> Result<()> {
+    if hash.is_empty() || !hash.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(Error::InvalidBook(format!("invalid book hash {hash}")));
+    }
+    Ok(())
+}
+
+/// Locates the file of `book` on disk: its copy in the managed store, its
+/// recorded path, the downloaded copy of a cloud book or the copy in the
+/// books dir.
+pub fn book_path(app: &AppHandle, book: &db::Book) -> Option<PathBuf> {
+    let stored = app
+        .try_state::<storage::Storage>()
+        .and_then(|storage| storage.path(&book.hash));
+    let original = book.file_path.as_deref().map(PathBuf::from);
+    let cached = app
+        .try_state::<CloudCache>()
+        .and_then(|cache| cache.path(&book.hash));
+    if let Some(path) = stored
+        .into_iter()
+        .chain(original)
+        .chain(cached)
+        .find(|p| p.is_file())
+    {
+        return Some(path);
+    }
+    books_dir_copy(app, &book.hash)
+}
+
+/// The copy of book `hash` in the books dir, if there is one.
+pub fn books_dir_copy(app: &AppHandle, hash: &str) -> Option<PathBuf> {
+    let dir = books_dir(app).ok()?.join(hash);
+    std::fs::read_dir(dir)
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .find(|path| path.is_file() && is_book_file(path))
+}
+
+/// Moves `from` to `to`, copying when they are on different file systems.
+pub fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    if std::fs::rename(from, to).is_err() {
+        std::fs::copy(from, to)?;
+        std::fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+/// Path of the cover image the frontend extracts next to the book file.
+pub fn cover_path(app: &AppHandle, book_hash: &str) -> Option<PathBuf> {
+    let path = books_dir(app).ok()?.join(book_hash).join(COVER_FILE);
+    path.is_file().then_some(path)
+}
+
+/// Saves `image` as the cover of book `book_hash`, where the frontend looks
+/// for it.
+pub fn write_cover(app: &AppHandle, book_hash: &str, image: &[u8]) -> Result<()> {
+    let dir = books_dir(app)?.join(book_hash);
+    std::fs::create_dir_all(&dir)?;
+    // The frontend keeps covers as they come, whatever the extension says
+    std::fs::write(dir.join(COVER_FILE), image)?;
+    Ok(())
+}
+
+/// The byte ranges of a file of `size` bytes that [`partial_md5`] hashes.
+pub fn partial_md5_samples(size: u64) -> impl Iterator<Item = Range<u64>> {
+    const SAMPLE_SIZE: u64 = 1024;
+    std::iter::once(0)
+        .chain((0..=10).map(|i| 1024u64 << (2 * i)))
+        .take_while(move |&start| start < size)
+        .map(move |start| start..(start + SAMPLE_SIZE).min(size))
+}
+
+/// Book hash as computed by `partialMD5` in the frontend: the MD5 of 1 KiB
+/// samples taken at offsets 0, 1 KiB, 4 KiB, 16 KiB and so on up to 1 GiB.
+pub fn partial_md5(path: &Path) -> Result<String> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut hasher = Md5::new();
+    let mut sample = Vec::new();
+    for range in partial_md5_samples(size) {
+        file.seek(SeekFrom::Start(range.start))?;
+        sample.clear();
+        (&mut file)
+            .take(range.end - range.start)
+            .read_to_end(&mut sample)?;
+        hasher.update(&sample);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Path of a book file under the books dir, relative to it, as built by
+/// `getLocalBookFilename` and `makeSafeFilename` in the frontend.
+pub fn local_book_filename(book: &db::Book) -> String {
+    const MAX_NAME_BYTES: usize = 250;
+    const RESERVED: &[&str] = &["con", "prn", "aux", "nul"];
+
+    let title = book
+        .source_title
+        .as_deref()
+        .filter(|title| !title.is_empty())
+        .unwrap_or(&book.title);
+    let mut name = sanitize_file_name(title);
+    let lower = name.to_ascii_lowercase();
+    let numbered = |prefix: &str| {
+        lower.len() == 4 && lower.starts_with(prefix) && matches!(lower.as_bytes()[3], b'1'..=b'9')
+    };
+    if RESERVED.contains(&lower.as_str()) || numbered("com") || numbered("lpt") {
+        name.push('_');
+    }
+    while name.len() > MAX_NAME_BYTES {
+        name.pop();
+    }
+    format!(
+        "{}/{}.{}",
+        book.hash,
+        name.trim(),
+        book.format.to_ascii_lowercase()
+    )
+}
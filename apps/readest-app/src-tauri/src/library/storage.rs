@@ -0,0 +1,547 @@
+//! Where book files live. By default they are copied into the books dir as
+//! the frontend stores them; alternatively they are referenced where they
+//! were imported from, or copied into a managed store that may live on
+//! another drive. Files in the store are named by their SHA-256, so books
+//! with the same content share one file, and `manifest.json` next to them
+//! maps book hashes to files, so the store can be moved as a whole.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tauri::{command, AppHandle, Emitter, Manager, State};
+
+use super::db::{self, BookQuery, LibraryDb};
+use crate::error::{Error, Result};
+use crate::paths;
+use crate::store;
+
+const CONFIG_FILE: &str = "library-storage.json";
+const MANIFEST_FILE: &str = "manifest.json";
+const OBJECTS_DIR: &str = "objects";
+/// Default location of the store, under the app data dir.
+const STORE_SUBDIR: &str = "VL-Arch/Store";
+const PROGRESS_EVENT: &str = "storage-progress";
+/// Additions between manifest writes; a batch calls [`Storage::save`] when done.
+const SAVE_INTERVAL: usize = 32;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum StorageMode {
+    /// Copies in the books dir, named after the book.
+    #[default]
+    Library,
+    /// The files books were imported from, left in place.
+    Reference,
+    /// Copies in the content-addressed store.
+    Managed,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageConfig {
+    #[serde(default)]
+    pub mode: StorageMode,
+    /// Location of the store, the default one when not set.
+    pub store_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StoredFile {
+    sha256: String,
+    extension: String,
+    size: u64,
+}
+
+impl StoredFile {
+    fn relative_path(&self) -> PathBuf {
+        Path::new(OBJECTS_DIR)
+            .join(&self.sha256[..2])
+            .join(format!("{}.{}", self.sha256, self.extension))
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    /// Stored files by book hash.
+    books: BTreeMap<String, StoredFile>,
+}
+
+struct Inner {
+    config: StorageConfig,
+    dir: PathBuf,
+    manifest: Manifest,
+    unsaved: usize,
+}
+
+impl Inner {
+    fn save(&mut self) -> Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(MANIFEST_FILE);
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec(&self.manifest)?)?;
+        std::fs::rename(&tmp, &path)?;
+        self.unsaved = 0;
+        Ok(())
+    }
+}
+
+pub struct Storage {
+    inner: Mutex<Inner>,
+    /// Held while files are written to or removed from the store, and
+    /// exclusively while it moves, so none land in the old one.
+    files: RwLock<()>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MigrationFailure {
+    pub book_hash: String,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MigrationSummary {
+    pub moved: usize,
+    pub unchanged: usize,
+    /// Books left copied in the app because the file they were imported
+    /// from is gone, when switching to [`StorageMode::Reference`].
+    pub kept: usize,
+    pub failed: Vec<MigrationFailure>,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Progress {
+    current: usize,
+    total: usize,
+}
+
+fn storage_error(message: impl Into<String>) -> Error {
+    Error::Storage(message.into())
+}
+
+fn read_manifest(dir: &Path) -> Manifest {
+    match std::fs::read(dir.join(MANIFEST_FILE)) {
+        Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+            log::warn!("Failed to parse the manifest of {dir:?}: {e}");
+            Manifest::default()
+        }),
+        Err(_) => Manifest::default(),
+    }
+}
+
+fn sha256_file(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut BufReader::new(File::open(path)?), &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+impl Storage {
+    pub fn mode(&self) -> StorageMode {
+        self.inner.lock().unwrap().config.mode
+    }
+
+    /// Path of the stored file of book `hash`, if it has one.
+    pub fn path(&self, hash: &str) -> Option<PathBuf> {
+        let inner = self.inner.lock().unwrap();
+        let file = inner.manifest.books.get(hash)?;
+        Some(inner.dir.join(file.relative_path()))
+    }
+
+    /// The books with a file in the store.
+    pub(super) fn hashes(&self) -> Vec<String> {
+        let inner = self.inner.lock().unwrap();
+        inner.manifest.books.keys().cloned().collect()
+    }
+
+    /// Copies the file at `source` into the store as book `hash`, unless a
+    /// file with the same content is there already.
+    pub fn add(&self, hash: &str, source: &Path) -> Result<PathBuf> {
+        let _files = self.files.read().unwrap();
+        let file = StoredFile {
+            sha256: sha256_file(source)?,
+            extension: source
+                .extension()
+                .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+                .unwrap_or_default(),
+            size: std::fs::metadata(source)?.len(),
+        };
+        let dir = self.inner.lock().unwrap().dir.clone();
+        let path = dir.join(file.relative_path());
+        if !path.is_file() {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let tmp = path.with_extension("tmp");
+            std::fs::copy(source, &tmp)?;
+            std::fs::rename(&tmp, &path)?;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.manifest.books.insert(hash.to_string(), file);
+        inner.unsaved += 1;
+        if inner.unsaved >= SAVE_INTERVAL {
+            inner.save()?;
+        }
+        Ok(path)
+    }
+
+    /// Removes book `hash` from the store, and its file once no other book
+    /// shares it.
+    pub(super) fn remove(&self, hash: &str) -> Result<()> {
+        let _files = self.files.read().unwrap();
+        let mut inner = self.inner.lock().unwrap();
+        let Some(file) = inner.manifest.books.remove(hash) else {
+            return Ok(());
+        };
+        inner.unsaved += 1;
+        if !inner
+            .manifest
+            .books
+            .values()
+            .any(|other| other.sha256 == file.sha256)
+        {
+            match std::fs::remove_file(inner.dir.join(file.relative_path())) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the manifest if books were added or removed since it was last written.
+    pub fn save(&self) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.unsaved > 0 {
+            inner.save()?;
+        }
+        Ok(())
+    }
+
+    fn set_config(&self, app: &AppHandle, config: StorageConfig) -> Result<()> {
+        store::save(app, CONFIG_FILE, &config)?;
+        self.inner.lock().unwrap().config = config;
+        Ok(())
+    }
+}
+
+fn default_store_dir(app: &AppHandle) -> Result<PathBuf> {
+    Ok(paths::data_dir(app)?.join(STORE_SUBDIR))
+}
+
+pub fn init(app: &AppHandle) -> Result<()> {
+    let config: StorageConfig = store::load(app, CONFIG_FILE);
+    let dir = match &config.store_dir {
+        Some(dir) => dir.clone(),
+        None => default_store_dir(app)?,
+    };
+    let manifest = read_manifest(&dir);
+    app.manage(Storage {
+        inner: Mutex::new(Inner {
+            config,
+            dir,
+            manifest,
+            unsaved: 0,
+        }),
+        files: RwLock::default(),
+    });
+    Ok(())
+}
+
+/// The file `book` was imported from, if it is still there and outside
+/// the app's own copies.
+fn original(app: &AppHandle, storage: &Storage, book: &db::Book) -> Option<PathBuf> {
+    let path = PathBuf::from(book.file_path.as_deref()?);
+    let store_dir = storage.inner.lock().unwrap().dir.clone();
+    let books_dir = super::books_dir(app).ok()?;
+    let owned = path.starts_with(&store_dir) || path.starts_with(&books_dir);
+    (path.is_file() && !owned).then_some(path)
+}
+
+/// Moves `book` to where `mode` keeps it. Returns whether anything moved,
+/// or `None` for a book that has to stay copied.
+pub(super) fn migrate_book(
+    app: &AppHandle,
+    storage: &Storage,
+    book: &db::Book,
+    mode: StorageMode,
+) -> Result<Option<bool>> {
+    let stored = storage.path(&book.hash).filter(|path| path.is_file());
+    let copy = super::books_dir_copy(app, &book.hash);
+    let original = original(app, storage, book);
+    match mode {
+        StorageMode::Managed => {
+            if stored.is_some() {
+                return Ok(Some(false));
+            }
+            let source = copy
+                .clone()
+                .or(original)
+                .ok_or_else(|| storage_error("the book file is missing"))?;
+            storage.add(&book.hash, &source)?;
+            if let Some(copy) = copy {
+                std::fs::remove_file(copy)?;
+            }
+            Ok(Some(true))
+        }
+        StorageMode::Reference => {
+            if original.is_none() {
+                return Ok(if stored.is_none() && copy.is_none() {
+                    Some(false)
+                } else {
+                    None
+                });
+            }
+            let moved = stored.is_some() || copy.is_some();
+            storage.remove(&book.hash)?;
+            if let Some(copy) = copy {
+                std::fs::remove_file(copy)?;
+            }
+            Ok(Some(moved))
+        }
+        StorageMode::Library => {
+            if copy.is_some() {
+                return Ok(Some(false));
+            }
+            let source = stored
+                .or(original)
+                .ok_or_else(|| storage_error("the book file is missing"))?;
+            let dest = super::books_dir(app)?.join(super::local_book_filename(book));
+            if let Some(parent) = dest.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::copy(&source, &dest)?;
+            storage.remove(&book.hash)?;
+            Ok(Some(true))
+        }
+    }
+}
+
+fn migrate(app: &AppHandle, mode: StorageMode) -> Result<MigrationSummary> {
+    let storage = app.state::<Storage>();
+    let books = db::query_books(&app.state::<LibraryDb>().conn(), &BookQuery::default())?.books;
+    let mut summary = MigrationSummary::default();
+    for (i, book) in books.iter().enumerate() {
+        match migrate_book(app, &storage, book, mode) {
+            Ok(Some(true)) => summary.moved += 1,
+            Ok(Some(false)) => summary.unchanged += 1,
+            Ok(None) => summary.kept += 1,
+            Err(e) => {
+                log::warn!("Failed to move {} to {mode:?} storage: {e}", book.hash);
+                summary.failed.push(MigrationFailure {
+                    book_hash: book.hash.clone(),
+                    error: e.to_string(),
+                });
+            }
+        }
+        let _ = app.emit(
+            PROGRESS_EVENT,
+            Progress {
+                current: i + 1,
+                total: books.len(),
+            },
+        );
+    }
+    storage.save()?;
+    let config = StorageConfig {
+        mode,
+        ..storage.inner.lock().unwrap().config.clone()
+    };
+    storage.set_config(app, config)?;
+    Ok(summary)
+}
+
+/// `path` with its existing part resolved, so links and `..` cannot hide
+/// where it is.
+fn resolve(path: &Path) -> PathBuf {
+    let mut missing = Vec::new();
+    let mut existing = path;
+    loop {
+        if let Ok(resolved) = std::fs::canonicalize(existing) {
+            return missing
+                .iter()
+                .rev()
+                .fold(resolved, |path: PathBuf, part| path.join(part));
+        }
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                missing.push(name.to_os_string());
+                existing = parent;
+            }
+            _ => return path.to_path_buf(),
+        }
+    }
+}
+
+/// Copies stored `file` from `source` to `dest`, checking the copy against
+/// the SHA-256 it is named by.
+fn copy_verified(source: &Path, dest: &Path, file: &StoredFile) -> Result<()> {
+    let to = dest.join(file.relative_path());
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp = to.with_extension("tmp");
+    std::fs::copy(source.join(file.relative_path()), &tmp)?;
+    if sha256_file(&tmp)? != file.sha256 {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(storage_error(format!(
+            "the copy of {} does not match",
+            file.sha256
+        )));
+    }
+    std::fs::rename(&tmp, &to)?;
+    Ok(())
+}
+
+/// Moves the store to `dest`, which must be empty or not exist yet. Every
+/// file is copied and checked before the store switches over, and the old
+/// files are only removed after that, so a failure leaves the store where
+/// it was, whole.
+fn relocate(app: &AppHandle, dest: PathBuf) -> Result<()> {
+    let storage = app.state::<Storage>();
+    let _files = storage.files.write().unwrap();
+    let (source, files) = {
+        let inner = storage.inner.lock().unwrap();
+        let mut files = BTreeMap::new();
+        for file in inner.manifest.books.values() {
+            files.insert(file.relative_path(), file.clone());
+        }
+        (inner.dir.clone(), files)
+    };
+    let (resolved_source, resolved_dest) = (resolve(&source), resolve(&dest));
+    if resolved_dest == resolved_source {
+        return Ok(());
+    }
+    if resolved_dest.starts_with(&resolved_source) {
+        return Err(storage_error("the store cannot move into itself"));
+    }
+    if std::fs::read_dir(&dest).is_ok_and(|mut entries| entries.next().is_some()) {
+        return Err(storage_error(format!("{} is not empty", dest.display())));
+    }
+    let existed = dest.exists();
+    // Leaves `dest` as it was found, empty or missing
+    let clean_up = || {
+        let cleared = match existed {
+            true => std::fs::read_dir(&dest).and_then(|entries| {
+                entries
+                    .flatten()
+                    .try_for_each(|entry| match entry.file_type()?.is_dir() {
+                        true => std::fs::remove_dir_all(entry.path()),
+                        false => std::fs::remove_file(entry.path()),
+                    })
+            }),
+            false => std::fs::remove_dir_all(&dest),
+        };
+        if let Err(e) = cleared {
+            log::warn!("Failed to clean up {dest:?}: {e}");
+        }
+    };
+
+    std::fs::create_dir_all(&dest)?;
+    let mut copied = Vec::new();
+    for (i, (path, file)) in files.iter().enumerate() {
+        if source.join(path).is_file() {
+            if let Err(e) = copy_verified(&source, &dest, file) {
+                clean_up();
+                return Err(e);
+            }
+            copied.push(path);
+        }
+        let _ = app.emit(
+            PROGRESS_EVENT,
+            Progress {
+                current: i + 1,
+                total: files.len(),
+            },
+        );
+    }
+
+    let switched = {
+        let mut inner = storage.inner.lock().unwrap();
+        inner.dir = dest.clone();
+        let config = StorageConfig {
+            store_dir: Some(dest.clone()),
+            ..inner.config.clone()
+        };
+        let saved = inner
+            .save()
+            .and_then(|()| store::save(app, CONFIG_FILE, &config));
+        match saved {
+            Ok(()) => inner.config = config,
+            Err(_) => inner.dir = source.clone(),
+        }
+        saved
+    };
+    if let Err(e) = switched {
+        clean_up();
+        return Err(e);
+    }
+
+    // Only what was moved: anything else in the old store is not the app's
+    let removed = copied
+        .into_iter()
+        .map(|path| source.join(path))
+        .chain([source.join(MANIFEST_FILE)])
+        .try_for_each(|path| match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        });
+    if let Err(e) = removed {
+        log::warn!("Failed to remove the old store {source:?}: {e}");
+    }
+    let objects = source.join(OBJECTS_DIR);
+    for entry in std::fs::read_dir(&objects).into_iter().flatten().flatten() {
+        let _ = std::fs::remove_dir(entry.path());
+    }
+    let _ = std::fs::remove_dir(&objects);
+    let _ = std::fs::remove_dir(&source);
+    Ok(())
+}
+
+#[command]
+pub fn get_library_storage(storage: State<'_, Storage>) -> StorageConfig {
+    let inner = storage.inner.lock().unwrap();
+    StorageConfig {
+        mode: inner.config.mode,
+        store_dir: Some(inner.dir.clone()),
+    }
+}
+
+/// Switches to `mode`, moving the book files there, and emits a
+/// `storage-progress` event after each book.
+#[command]
+pub async fn set_library_storage_mode(
+    app: AppHandle,
+    mode: StorageMode,
+) -> Result<MigrationSummary> {
+    tauri::async_runtime::spawn_blocking(move || migrate(&app, mode)).await?
+}
+
+/// Moves the store to `path`, for example on another drive, and emits a
+/// `storage-progress` event after each file.
+#[command]
+pub async fn relocate_library_store(app: AppHandle, path: PathBuf) -> Result<()> {
+    tauri::async_runtime::spawn_blocking(move || relocate(&app, path)).await?
+}
+
+/// Where the file of book `hash` is, wherever it is stored. Books in a
+/// cloud account are downloaded first, see [`crate::cloud::fetch`].
+#[command]
+pub async fn library_get_book_file(
+    app: AppHandle,
+    db: State<'_, LibraryDb>,
+    hash: String,
+) -> Result<Option<PathBuf>> {
+    let book = db::get_book(&db.conn(), &hash)?;
+    match book.and_then(|book| super::book_path(&app, &book)) {
+        Some(path) => Ok(Some(path)),
+        None => crate::cloud::fetch(&app, &hash).await,
+    }
+}
@@ -0,0 +1,474 @@
+//! Tags across the library: renaming, merging and deleting them and
+//! tagging many books at once, each in a single transaction against the
+//! index however many books it touches. Every change can be undone, the
+//! latest first, until the app quits.
+//!
+//! Changed books get a new `updatedAt`, so that they sync, and their
+//! hashes are emitted as `library-tags-changed` for the frontend to reload
+//! them before it upserts them again.
+
+use std::sync::Mutex;
+
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection};
+use serde::Serialize;
+use tauri::{command, AppHandle, Emitter, State};
+
+use super::access::Access;
+use super::db::LibraryDb;
+use crate::error::{Error, Result};
+use crate::utils::now_millis;
+
+const TAGS_CHANGED_EVENT: &str = "library-tags-changed";
+/// Changes kept to undo.
+const MAX_UNDO: usize = 20;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TagCount {
+    pub tag: String,
+    /// Books with the tag that are not deleted, 0 for unused tags.
+    pub books: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TagChange {
+    /// What was done, such as `rename "scifi" to "sci-fi"`.
+    pub label: String,
+    /// Hashes of the books whose tags changed.
+    pub books: Vec<String>,
+}
+
+struct Edit {
+    label: String,
+    /// The tags of the changed books before the change.
+    before: Vec<(String, Vec<String>)>,
+}
+
+/// The changes that can be undone, the latest last.
+#[derive(Default)]
+pub struct TagHistory(Mutex<Vec<Edit>>);
+
+impl TagHistory {
+    fn push(&self, edit: Edit) {
+        let mut edits = self.0.lock().unwrap();
+        edits.push(edit);
+        if edits.len() > MAX_UNDO {
+            edits.remove(0);
+        }
+    }
+}
+
+/// `tag` trimmed, which must leave something.
+fn normalize(tag: &str) -> Result<String> {
+    let tag = tag.trim();
+    if tag.is_empty() {
+        return Err(Error::InvalidTag("tags cannot be empty".to_string()));
+    }
+    Ok(tag.to_string())
+}
+
+fn normalize_all(tags: &[String]) -> Result<Vec<String>> {
+    tags.iter().map(|tag| normalize(tag)).collect()
+}
+
+fn quoted(tags: &[String]) -> String {
+    tags.iter()
+        .map(|tag| format!("{tag:?}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Hashes of the books with any of `tags`.
+fn books_tagged(conn: &Connection, tags: &[String]) -> Result<Vec<String>> {
+    if tags.is_empty() {
+        return Ok(Vec::new());
+    }
+    let placeholders = vec!["?"; tags.len()].join(", ");
+    let sql = format!(
+        "SELECT DISTINCT book_hash FROM book_tags WHERE tag IN ({placeholders}) ORDER BY 1"
+    );
+    let mut stmt = conn.prepare(&sql)?;
+    let hashes = stmt
+        .query_map(params_from_iter(tags), |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(hashes)
+}
+
+fn tags_of(conn: &Connection, hash: &str) -> Result<Vec<String>> {
+    let mut stmt =
+        conn.prepare_cached("SELECT tag FROM book_tags WHERE book_hash = ?1 ORDER BY tag")?;
+    let tags = stmt
+        .query_map([hash], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(tags)
+}
+
+/// Replaces the tags of book `hash` with `tags`, false when it is not in
+/// the index.
+fn set_tags(conn: &Connection, hash: &str, tags: &[String], now: i64) -> Result<bool> {
+    let touched = conn
+        .prepare_cached("UPDATE books SET updated_at = ?2 WHERE hash = ?1")?
+        .execute(params![hash, now])?;
+    if touched == 0 {
+        return Ok(false);
+    }
+    conn.prepare_cached("DELETE FROM book_tags WHERE book_hash = ?1")?
+        .execute([hash])?;
+    let mut insert =
+        conn.prepare_cached("INSERT OR IGNORE INTO book_tags (book_hash, tag) VALUES (?1, ?2)")?;
+    for tag in tags {
+        insert.execute([hash, tag])?;
+    }
+    Ok(true)
+}
+
+/// Gives each of books `hashes` the tags `edit` makes of theirs, in one
+/// transaction, returning the tags before of those that changed.
+fn rewrite(
+    conn: &mut Connection,
+    hashes: &[String],
+    edit: impl Fn(&mut Vec<String>),
+) -> Result<Vec<(String, Vec<String>)>> {
+    let tx = conn.transaction()?;
+    let now = now_millis();
+    let mut before = Vec::new();
+    for hash in hashes {
+        let tags = tags_of(&tx, hash)?;
+        let mut edited = tags.clone();
+        edit(&mut edited);
+        edited.sort();
+        edited.dedup();
+        if edited != tags && set_tags(&tx, hash, &edited, now)? {
+            before.push((hash.clone(), tags));
+        }
+    }
+    tx.commit()?;
+    Ok(before)
+}
+
+/// Gives the books back the tags they had before a change, returning the
+/// hashes of those still in the index.
+fn restore(conn: &mut Connection, before: &[(String, Vec<String>)]) -> Result<Vec<String>> {
+    let tx = conn.transaction()?;
+    let now = now_millis();
+    let mut books = Vec::new();
+    for (hash, tags) in before {
+        // Books purged since are gone for good
+        if set_tags(&tx, hash, tags, now)? {
+            books.push(hash.clone());
+        }
+    }
+    tx.commit()?;
+    Ok(books)
+}
+
+/// Lets the frontend and the system search know of the books whose tags
+/// changed.
+fn changed(app: &AppHandle, conn: &Connection, hashes: &[String]) -> Result<()> {
+    if hashes.is_empty() {
+        return Ok(());
+    }
+    #[cfg(any(target_os = "macos", windows))]
+    {
+        let mut books = Vec::new();
+        for hash in hashes {
+            books.extend(super::db::get_book(conn, hash)?);
+        }
+        crate::system_search::update(app, &books);
+    }
+    #[cfg(not(any(target_os = "macos", windows)))]
+    let _ = conn;
+    let _ = app.emit(TAGS_CHANGED_EVENT, hashes);
+    Ok(())
+}
+
+/// Rewrites the tags of books `hashes` with `edit` as one undoable change.
+fn change(
+    app: &AppHandle,
+    db: &LibraryDb,
+    history: &TagHistory,
+    label: String,
+    hashes: &[String],
+    edit: impl Fn(&mut Vec<String>),
+) -> Result<TagChange> {
+    let mut conn = db.conn();
+    let before = rewrite(&mut conn, hashes, edit)?;
+    let books = before
+        .iter()
+        .map(|(hash, _)| hash.clone())
+        .collect::<Vec<_>>();
+    changed(app, &conn, &books)?;
+    drop(conn);
+    if !before.is_empty() {
+        history.push(Edit {
+            label: label.clone(),
+            before,
+        });
+    }
+    Ok(TagChange { label, books })
+}
+
+/// The tags in the library with the books that have them, unused tags,
+/// those only deleted books have, included. Tags of locked books are left
+/// out while they are.
+#[command]
+pub async fn list_tags(
+    db: State<'_, LibraryDb>,
+    access: State<'_, Access>,
+) -> Result<Vec<TagCount>> {
+    let conn = db.conn();
+    let mut params = Vec::<Value>::new();
+    let conditions = access.lock(&conn)?.conditions(&mut params);
+    let filter = if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", conditions.join(" AND "))
+    };
+    let sql = format!(
+        "SELECT t.tag, SUM(b.deleted_at IS NULL) FROM book_tags t \
+         JOIN books b ON b.hash = t.book_hash {filter} \
+         GROUP BY t.tag ORDER BY t.tag COLLATE NOCASE"
+    );
+    let mut stmt = conn.prepare(&sql)?;
+    let tags = stmt
+        .query_map(params_from_iter(params.iter()), |row| {
+            Ok(TagCount {
+                tag: row.get(0)?,
+                books: row.get(1)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(tags)
+}
+
+/// Renames tag `from` to `to` on every book, merging it into `to` where a
+/// book has both.
+#[command]
+pub async fn rename_tag(
+    app: AppHandle,
+    db: State<'_, LibraryDb>,
+    history: State<'_, TagHistory>,
+    from: String,
+    to: String,
+) -> Result<TagChange> {
+    let to = normalize(&to)?;
+    let hashes = books_tagged(&db.conn(), std::slice::from_ref(&from))?;
+    let label = format!("rename {from:?} to {to:?}");
+    change(&app, &db, &history, label, &hashes, |tags| {
+        for tag in tags.iter_mut().filter(|tag| **tag == from) {
+            tag.clone_from(&to);
+        }
+    })
+}
+
+/// Replaces `tags` by `into` on every book with any of them.
+#[command]
+pub async fn merge_tags(
+    app: AppHandle,
+    db: State<'_, LibraryDb>,
+    history: State<'_, TagHistory>,
+    tags: Vec<String>,
+    into: String,
+) -> Result<TagChange> {
+    let into = normalize(&into)?;
+    let hashes = books_tagged(&db.conn(), &tags)?;
+    let label = format!("merge {} into {into:?}", quoted(&tags));
+    change(&app, &db, &history, label, &hashes, |book_tags| {
+        if book_tags.iter().any(|tag| tags.contains(tag)) {
+            book_tags.retain(|tag| !tags.contains(tag));
+            book_tags.push(into.clone());
+        }
+    })
+}
+
+/// Removes `tags` from every book.
+#[command]
+pub async fn delete_tags(
+    app: AppHandle,
+    db: State<'_, LibraryDb>,
+    history: State<'_, TagHistory>,
+    tags: Vec<String>,
+) -> Result<TagChange> {
+    let hashes = books_tagged(&db.conn(), &tags)?;
+    let label = format!("delete {}", quoted(&tags));
+    change(&app, &db, &history, label, &hashes, |book_tags| {
+        book_tags.retain(|tag| !tags.contains(tag));
+    })
+}
+
+/// Removes the tags that only deleted books have.
+#[command]
+pub async fn delete_unused_tags(
+    app: AppHandle,
+    db: State<'_, LibraryDb>,
+    history: State<'_, TagHistory>,
+) -> Result<TagChange> {
+    let (tags, hashes) = {
+        let conn = db.conn();
+        let mut stmt = conn.prepare(
+            "SELECT t.tag FROM book_tags t JOIN books b ON b.hash = t.book_hash \
+             GROUP BY t.tag HAVING SUM(b.deleted_at IS NULL) = 0",
+        )?;
+        let tags = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        let hashes = books_tagged(&conn, &tags)?;
+        (tags, hashes)
+    };
+    let label = format!("delete unused {}", quoted(&tags));
+    change(&app, &db, &history, label, &hashes, |book_tags| {
+        book_tags.retain(|tag| !tags.contains(tag));
+    })
+}
+
+/// Adds tags `add` to and removes tags `remove` from books `hashes`.
+#[command]
+pub async fn tag_books(
+    app: AppHandle,
+    db: State<'_, LibraryDb>,
+    history: State<'_, TagHistory>,
+    hashes: Vec<String>,
+    add: Option<Vec<String>>,
+    remove: Option<Vec<String>>,
+) -> Result<TagChange> {
+    let add = normalize_all(&add.unwrap_or_default())?;
+    let remove = remove.unwrap_or_default();
+    let label = match (add.is_empty(), remove.is_empty()) {
+        (false, true) => format!("tag {} books with {}", hashes.len(), quoted(&add)),
+        (true, false) => format!("untag {} books of {}", hashes.len(), quoted(&remove)),
+        _ => format!("retag {} books", hashes.len()),
+    };
+    change(&app, &db, &history, label, &hashes, |tags| {
+        tags.retain(|tag| !remove.contains(tag));
+        tags.extend(add.iter().cloned());
+    })
+}
+
+/// Undoes the latest tag change not undone yet, giving back the tags the
+/// books had before it; `None` when there is none.
+#[command]
+pub async fn undo_tag_change(
+    app: AppHandle,
+    db: State<'_, LibraryDb>,
+    history: State<'_, TagHistory>,
+) -> Result<Option<TagChange>> {
+    let Some(edit) = history.0.lock().unwrap().pop() else {
+        return Ok(None);
+    };
+    let mut conn = db.conn();
+    let books = restore(&mut conn, &edit.before)?;
+    changed(&app, &conn, &books)?;
+    Ok(Some(TagChange {
+        label: edit.label,
+        books,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::library::db::{self, Book};
+
+    fn book(hash: &str, tags: &[&str]) -> Book {
+        Book {
+            hash: hash.to_string(),
+            format: "EPUB".to_string(),
+            title: hash.to_string(),
+            source_title: None,
+            author: String::new(),
+            group_id: None,
+            group_name: None,
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            cover_image_url: None,
+            file_path: None,
+            url: None,
+            primary_language: None,
+            progress: None,
+            metadata: None,
+            created_at: 0,
+            updated_at: 0,
+            deleted_at: None,
+            uploaded_at: None,
+            downloaded_at: None,
+            cover_downloaded_at: None,
+        }
+    }
+
+    fn library(books: &[Book]) -> LibraryDb {
+        let db = LibraryDb::open(Path::new(":memory:")).unwrap();
+        db::upsert_books(&mut db.conn(), books).unwrap();
+        db
+    }
+
+    fn hashes(hashes: &[&str]) -> Vec<String> {
+        hashes.iter().map(|hash| hash.to_string()).collect()
+    }
+
+    #[test]
+    fn rewrites_record_only_the_books_they_change() {
+        let db = library(&[
+            book("a", &["scifi"]),
+            book("b", &["scifi", "sci-fi"]),
+            book("c", &[]),
+        ]);
+        let mut conn = db.conn();
+        let before = rewrite(&mut conn, &hashes(&["a", "b", "c"]), |tags| {
+            for tag in tags.iter_mut().filter(|tag| *tag == "scifi") {
+                *tag = "sci-fi".to_string();
+            }
+        })
+        .unwrap();
+
+        assert_eq!(
+            before,
+            [
+                ("a".to_string(), hashes(&["scifi"])),
+                ("b".to_string(), hashes(&["sci-fi", "scifi"])),
+            ]
+        );
+        assert_eq!(tags_of(&conn, "a").unwrap(), ["sci-fi"]);
+        assert_eq!(tags_of(&conn, "b").unwrap(), ["sci-fi"]);
+        let book = db::get_book(&conn, "a").unwrap().unwrap();
+        assert!(book.updated_at > 0);
+        assert_eq!(db::get_book(&conn, "c").unwrap().unwrap().updated_at, 0);
+    }
+
+    #[test]
+    fn undoing_gives_back_the_tags_of_the_books_left() {
+        let db = library(&[book("a", &["old"]), book("b", &["old", "keep"])]);
+        let mut conn = db.conn();
+        let before = rewrite(&mut conn, &hashes(&["a", "b"]), |tags| {
+            tags.retain(|tag| tag != "old")
+        })
+        .unwrap();
+        assert_eq!(
+            books_tagged(&conn, &hashes(&["old"])).unwrap(),
+            Vec::<String>::new()
+        );
+
+        conn.execute("DELETE FROM books WHERE hash = 'a'", [])
+            .unwrap();
+        assert_eq!(restore(&mut conn, &before).unwrap(), ["b"]);
+        assert_eq!(tags_of(&conn, "b").unwrap(), ["keep", "old"]);
+        assert!(tags_of(&conn, "a").unwrap().is_empty());
+    }
+
+    #[test]
+    fn history_keeps_the_latest_changes() {
+        let history = TagHistory::default();
+        for i in 0..MAX_UNDO + 5 {
+            history.push(Edit {
+                label: i.to_string(),
+                before: Vec::new(),
+            });
+        }
+        let edits = history.0.lock().unwrap();
+        assert_eq!(edits.len(), MAX_UNDO);
+        assert_eq!(edits.first().unwrap().label, "5");
+        assert_eq!(edits.last().unwrap().label, (MAX_UNDO + 4).to_string());
+    }
+}
@@ -0,0 +1,220 @@
+//! Downscaled book covers for the library grid, served through the `thumb://`
+//! protocol as `thumb://localhost/<book hash>/<size>`. Thumbnails are cached
+//! under the app data dir by book hash and regenerated when the cover changes.
+
+use std::borrow::Cow;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use tauri::http::{header, Request, Response, StatusCode};
+use tauri::{AppHandle, Manager};
+
+use super::db;
+use crate::error::Result;
+use crate::formats;
+use crate::paths;
+
+pub const PROTOCOL: &str = "thumb";
+const THUMBS_DIR: &str = "thumbnails";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThumbSize {
+    Small,
+    Medium,
+    Large,
+}
+
+impl ThumbSize {
+    const ALL: [ThumbSize; 3] = [ThumbSize::Small, ThumbSize::Medium, ThumbSize::Large];
+
+    fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|size| size.name() == name)
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ThumbSize::Small => "small",
+            ThumbSize::Medium => "medium",
+            ThumbSize::Large => "large",
+        }
+    }
+
+    /// Longest side in pixels.
+    fn max_size(self) -> u32 {
+        match self {
+            ThumbSize::Small => 160,
+            ThumbSize::Medium => 320,
+            ThumbSize::Large => 640,
+        }
+    }
+}
+
+fn thumbs_dir(app: &AppHandle, book_hash: &str) -> Result<PathBuf> {
+    super::check_hash(book_hash)?;
+    Ok(paths::data_dir(app)?.join(THUMBS_DIR).join(book_hash))
+}
+
+/// Where the cover of a book comes from: the image the frontend saved, or
+/// else the book file itself.
+enum CoverSource {
+    Image(PathBuf),
+    Book(PathBuf),
+}
+
+impl CoverSource {
+    fn find(app: &AppHandle, book_hash: &str) -> Result<Option<Self>> {
+        if let Some(path) = super::cover_path(app, book_hash) {
+            return Ok(Some(Self::Image(path)));
+        }
+        let db = app.state::<db::LibraryDb>();
+        let book = db::get_book(&db.conn(), book_hash)?;
+        Ok(book
+            .and_then(|book| super::book_path(app, &book))
+            .map(Self::Book))
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        let (Self::Image(path) | Self::Book(path)) = self;
+        std::fs::metadata(path).ok()?.modified().ok()
+    }
+
+    fn read(&self) -> Result<Option<Vec<u8>>> {
+        match self {
+            Self::Image(path) => Ok(Some(std::fs::read(path)?)),
+            Self::Book(path) => formats::extract_cover(path),
+        }
+    }
+}
+
+/// Returns the path of the cached thumbnail, generating every size from the
+/// cover when the cache is missing or older than the cover.
+pub fn thumbnail(app: &AppHandle, book_hash: &str, size: ThumbSize) -> Result<Option<PathBuf>> {
+    let dir = thumbs_dir(app, book_hash)?;
+    let path = dir.join(format!("{}.jpg", size.name()));
+    let Some(source) = CoverSource::find(app, book_hash)? else {
+        return Ok(None);
+    };
+    let cached = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+    if let (Some(cached), Some(modified)) = (cached, source.modified()) {
+        if cached >= modified {
+            return Ok(Some(path));
+        }
+    }
+
+    let Some(cover) = source.read()? else {
+        return Ok(None);
+    };
+    let image = image::load_from_memory(&cover)?;
+    std::fs::create_dir_all(&dir)?;
+    for size in ThumbSize::ALL {
+        let data = formats::encode_thumbnail(&image, size.max_size())?;
+        // Write then rename so concurrent requests never see a partial file
+        let target = dir.join(format!("{}.jpg", size.name()));
+        let tmp = target.with_extension("jpg.tmp");
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(&tmp, &target)?;
+    }
+    Ok(Some(path))
+}
+
+/// Whether every size of the thumbnail of a book is cached.
+pub(super) fn has_thumbnails(app: &AppHandle, book_hash: &str) -> Result<bool> {
+    let dir = thumbs_dir(app, book_hash)?;
+    Ok(ThumbSize::ALL
+        .into_iter()
+        .all(|size| dir.join(format!("{}.jpg", size.name())).is_file()))
+}
+
+/// The books with cached thumbnails.
+pub(super) fn cached_books(app: &AppHandle) -> Result<Vec<String>> {
+    let Ok(entries) = std::fs::read_dir(paths::data_dir(app)?.join(THUMBS_DIR)) else {
+        return Ok(Vec::new());
+    };
+    Ok(entries
+        .flatten()
+        .filter(|entry| entry.path().is_dir())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect())
+}
+
+/// Generates every size of the thumbnail of a book again, returning
+/// whether it has a cover to make them of.
+pub(super) fn regenerate(app: &AppHandle, book_hash: &str) -> Result<bool> {
+    remove_thumbnails(app, book_hash)?;
+    Ok(thumbnail(app, book_hash, ThumbSize::Small)?.is_some())
+}
+
+/// Removes the cached thumbnails of a book.
+pub fn remove_thumbnails(app: &AppHandle, book_hash: &str) -> Result<()> {
+    let dir = thumbs_dir(app, book_hash)?;
+    if dir.is_dir() {
+        std::fs::remove_dir_all(dir)?;
+    }
+    Ok(())
+}
+
+fn respond_status(status: StatusCode) -> Response<Cow<'static, [u8]>> {
+    Response::builder()
+        .status(status)
+        .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+        .body(Cow::Borrowed(&[][..]))
+        .unwrap()
+}
+
+/// Handles a `thumb://` request. The size segment is optional and defaults to medium.
+pub fn handle_request(app: &AppHandle, request: &Request<Vec<u8>>) -> Response<Cow<'static, [u8]>> {
+    let segments = request
+        .uri()
+        .path()
+        .trim_matches('/')
+        .split('/')
+        .collect::<Vec<_>>();
+    let (hash, size) = match segments.as_slice() {
+        [hash] => (*hash, Some(ThumbSize::Medium)),
+        [hash, size] => (*hash, ThumbSize::parse(size)),
+        _ => return respond_status(StatusCode::NOT_FOUND),
+    };
+    let Some(size) = size else {
+        return respond_status(StatusCode::NOT_FOUND);
+    };
+    if super::check_hash(hash).is_err() {
+        return respond_status(StatusCode::BAD_REQUEST);
+    }
+
+    let path = match thumbnail(app, hash, size) {
+        Ok(Some(path)) => path,
+        Ok(None) => return respond_status(StatusCode::NOT_FOUND),
+        Err(e) => {
+            log::warn!("Failed to generate thumbnail for {hash}: {e}");
+            return respond_status(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    let modified = std::fs::metadata(&path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|m| m.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map(|d| d.as_millis())
+        .unwrap_or_default();
+    let etag = format!("\"{modified:x}\"");
+    let not_modified = request
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .is_some_and(|value| value.as_bytes() == etag.as_bytes());
+    let builder = Response::builder()
+        .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .header(header::ETAG, &etag);
+    if not_modified {
+        return builder
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Cow::Borrowed(&[][..]))
+            .unwrap();
+    }
+    match std::fs::read(&path) {
+        Ok(data) => builder
+            .header(header::CONTENT_TYPE, "image/jpeg")
+            .body(Cow::Owned(data))
+            .unwrap(),
+        Err(_) => respond_status(StatusCode::NOT_FOUND),
+    }
+}
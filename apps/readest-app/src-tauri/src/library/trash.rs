@@ -0,0 +1,290 @@
+//! The trash: books deleted from the library are kept for a while, with
+//! their annotations and reading progress, so that deleting the wrong
+//! books does not lose them. A deleted book stays in the index marked as
+//! deleted, and its folder in the books dir, with its copy of the file,
+//! its cover and its config, is moved to a folder of its own in the trash,
+//! next to `entry.json` recording when it was deleted. Files in the
+//! managed store stay where they are until the trash is emptied.
+//!
+//! Books are removed for good once they have been in the trash for longer
+//! than the retention period, by a scheduled task, or when the trash is
+//! emptied by hand.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle, Manager, State};
+
+use super::access::Access;
+use super::db::{self, Book, LibraryDb};
+use super::storage::Storage;
+use crate::error::{Error, Result};
+use crate::paths;
+use crate::store;
+use crate::utils::now_millis;
+
+/// Where trashed books are kept, under the app data dir.
+const TRASH_SUBDIR: &str = "VL-Arch/Trash";
+const ENTRY_FILE: &str = "entry.json";
+const SETTINGS_FILE: &str = "trash.json";
+const DAY_MILLIS: i64 = 24 * 60 * 60 * 1000;
+const DEFAULT_RETENTION_DAYS: u32 = 30;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TrashSettings {
+    /// Days books are kept in the trash, 0 to keep them until it is
+    /// emptied.
+    pub retention_days: u32,
+}
+
+impl Default for TrashSettings {
+    fn default() -> Self {
+        Self {
+            retention_days: DEFAULT_RETENTION_DAYS,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Entry {
+    /// The book as it was before it was deleted.
+    book: Book,
+    trashed_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrashedBook {
+    pub book: Book,
+    pub trashed_at: i64,
+    /// When it is removed for good, `None` when it is kept until the trash
+    /// is emptied.
+    pub expires_at: Option<i64>,
+    /// Bytes its files take in the trash.
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EmptiedTrash {
+    pub books: usize,
+    /// Bytes freed in the trash, not counting the files of the store.
+    pub size: u64,
+}
+
+pub(super) fn trash_dir(app: &AppHandle) -> Result<PathBuf> {
+    Ok(paths::data_dir(app)?.join(TRASH_SUBDIR))
+}
+
+fn dir_size(dir: &Path) -> u64 {
+    std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => dir_size(&entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+/// Moves the files in `from` to `to`, leaving out those `to` has already.
+fn move_files(from: &Path, to: &Path, skip: &str) -> Result<()> {
+    let Ok(entries) = std::fs::read_dir(from) else {
+        return Ok(());
+    };
+    std::fs::create_dir_all(to)?;
+    for entry in entries.flatten() {
+        let dest = to.join(entry.file_name());
+        if entry.file_name() == skip || dest.exists() {
+            continue;
+        }
+        std::fs::rename(entry.path(), dest)?;
+    }
+    Ok(())
+}
+
+fn read_entry(dir: &Path) -> Result<Entry> {
+    Ok(serde_json::from_slice(&std::fs::read(
+        dir.join(ENTRY_FILE),
+    )?)?)
+}
+
+fn entries(app: &AppHandle) -> Result<Vec<(PathBuf, Entry)>> {
+    let Ok(dirs) = std::fs::read_dir(trash_dir(app)?) else {
+        return Ok(Vec::new());
+    };
+    let mut entries = Vec::new();
+    for dir in dirs.flatten().map(|entry| entry.path()) {
+        match read_entry(&dir) {
+            Ok(entry) => entries.push((dir, entry)),
+            Err(e) => log::warn!("Ignoring invalid trash entry {dir:?}: {e}"),
+        }
+    }
+    entries.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.trashed_at));
+    Ok(entries)
+}
+
+/// Moves the files of `books`, just deleted from the library, to the trash.
+pub fn trash(app: &AppHandle, books: &[Book]) -> Result<()> {
+    let trash_dir = trash_dir(app)?;
+    let books_dir = super::books_dir(app)?;
+    let trashed_at = now_millis();
+    for book in books {
+        let dir = trash_dir.join(&book.hash);
+        move_files(&books_dir.join(&book.hash), &dir, ENTRY_FILE)?;
+        let _ = std::fs::remove_dir(books_dir.join(&book.hash));
+        std::fs::create_dir_all(&dir)?;
+        let entry = Entry {
+            book: book.clone(),
+            trashed_at,
+        };
+        std::fs::write(dir.join(ENTRY_FILE), serde_json::to_vec_pretty(&entry)?)?;
+    }
+    Ok(())
+}
+
+/// Removes books `hashes` for good: from the index, the trash, the store
+/// and the thumbnails.
+pub fn purge(app: &AppHandle, hashes: &[String]) -> Result<()> {
+    for hash in hashes {
+        super::check_hash(hash)?;
+    }
+    let db = app.state::<LibraryDb>();
+    {
+        let mut conn = db.conn();
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare("DELETE FROM books WHERE hash = ?1")?;
+            for hash in hashes {
+                stmt.execute([hash])?;
+            }
+        }
+        tx.commit()?;
+    }
+    let trash_dir = trash_dir(app)?;
+    let storage = app.state::<Storage>();
+    for hash in hashes {
+        let dir = trash_dir.join(hash);
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir)?;
+        }
+        storage.remove(hash)?;
+        if let Err(e) = super::thumbs::remove_thumbnails(app, hash) {
+            log::warn!("Failed to remove thumbnails of {hash}: {e}");
+        }
+    }
+    storage.save()?;
+    #[cfg(any(target_os = "macos", windows))]
+    crate::system_search::remove(app, hashes);
+    Ok(())
+}
+
+fn empty(
+    app: &AppHandle,
+    expired_before: Option<i64>,
+    only: Option<&[String]>,
+) -> Result<EmptiedTrash> {
+    let mut hashes = Vec::new();
+    let mut size = 0;
+    for (dir, entry) in entries(app)? {
+        let expired = expired_before.map_or(true, |before| entry.trashed_at < before);
+        let chosen = only.map_or(true, |only| only.contains(&entry.book.hash));
+        if expired && chosen {
+            size += dir_size(&dir);
+            hashes.push(entry.book.hash);
+        }
+    }
+    purge(app, &hashes)?;
+    Ok(EmptiedTrash {
+        books: hashes.len(),
+        size,
+    })
+}
+
+/// Removes the books that have been in the trash for longer than the
+/// retention period, for the scheduled task.
+pub fn empty_expired(app: &AppHandle) -> Result<EmptiedTrash> {
+    let settings: TrashSettings = store::load(app, SETTINGS_FILE);
+    if settings.retention_days == 0 {
+        return Ok(EmptiedTrash::default());
+    }
+    let before = now_millis() - i64::from(settings.retention_days) * DAY_MILLIS;
+    empty(app, Some(before), None)
+}
+
+/// The books in the trash, the most recently deleted first, but for those
+/// locked while they are.
+#[command]
+pub async fn list_trash(app: AppHandle, access: State<'_, Access>) -> Result<Vec<TrashedBook>> {
+    let hidden = {
+        let db = app.state::<LibraryDb>();
+        let conn = db.conn();
+        access.lock(&conn)?.hidden_books(&conn)?
+    };
+    let Some(hidden) = hidden else {
+        return Ok(Vec::new());
+    };
+    let settings: TrashSettings = store::load(&app, SETTINGS_FILE);
+    let retention = i64::from(settings.retention_days) * DAY_MILLIS;
+    Ok(entries(&app)?
+        .into_iter()
+        .filter(|(_, entry)| !hidden.contains(&entry.book.hash))
+        .map(|(dir, entry)| TrashedBook {
+            size: dir_size(&dir),
+            expires_at: (retention > 0).then_some(entry.trashed_at + retention),
+            trashed_at: entry.trashed_at,
+            book: entry.book,
+        })
+        .collect())
+}
+
+/// Takes book `hash` out of the trash, with its files, annotations and
+/// progress, and returns it as it is in the library again.
+#[command]
+pub async fn restore_book(app: AppHandle, hash: String) -> Result<Book> {
+    tauri::async_runtime::spawn_blocking(move || restore(&app, &hash)).await?
+}
+
+fn restore(app: &AppHandle, hash: &str) -> Result<Book> {
+    super::check_hash(hash)?;
+    let dir = trash_dir(app)?.join(hash);
+    let entry = read_entry(&dir).map_err(|_| Error::NotInTrash(hash.to_string()))?;
+    move_files(&dir, &super::books_dir(app)?.join(hash), ENTRY_FILE)?;
+    std::fs::remove_dir_all(&dir)?;
+
+    let db = app.state::<LibraryDb>();
+    let mut conn = db.conn();
+    // The index has the book as it was since, unless it was purged
+    let book = db::get_book(&conn, hash)?.unwrap_or(entry.book);
+    let book = Book {
+        deleted_at: None,
+        updated_at: now_millis(),
+        ..book
+    };
+    db::upsert_books(&mut conn, std::slice::from_ref(&book))?;
+    drop(conn);
+    #[cfg(any(target_os = "macos", windows))]
+    crate::system_search::update(app, std::slice::from_ref(&book));
+    Ok(book)
+}
+
+/// Removes books `hashes` from the trash for good, all of them when not
+/// given.
+#[command]
+pub async fn empty_trash(app: AppHandle, hashes: Option<Vec<String>>) -> Result<EmptiedTrash> {
+    tauri::async_runtime::spawn_blocking(move || empty(&app, None, hashes.as_deref())).await?
+}
+
+#[command]
+pub async fn get_trash_settings(app: AppHandle) -> Result<TrashSettings> {
+    Ok(store::load(&app, SETTINGS_FILE))
+}
+
+#[command]
+pub async fn set_trash_settings(app: AppHandle, settings: TrashSettings) -> Result<()> {
+    store::save(&app, SETTINGS_FILE, &settings)
+}
@@ -0,0 +1,390 @@
+//! Integrity checks of the library, run as a job: that the file of every
+//! book in the index is still there and is the book its hash says, that
+//! its cover and thumbnails are, and that its annotations point into it,
+//! and that nothing is left of books no longer in the index. The report
+//! lists each problem with the fix the frontend can offer for it, which
+//! [`repair_library`] carries out.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle, Manager};
+
+use super::db::{self, Book, BookQuery, LibraryDb};
+use super::editions;
+use super::storage::{self, Storage};
+use crate::error::{Error, Result};
+use crate::export::annotations;
+use crate::formats;
+use crate::formats::epub::EpubArchive;
+use crate::jobs::{self, Job};
+use crate::utils::now_millis;
+
+/// Where files of a book can be left behind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Place {
+    /// Its folder in the books dir, with its copy, cover and config.
+    Books,
+    Thumbnails,
+    Trash,
+    /// Its file in the managed store.
+    Store,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum Problem {
+    /// The file of the book is nowhere to be found.
+    MissingFile,
+    /// The file at `path` is not the book, but one whose hash is `actual`.
+    HashMismatch {
+        path: PathBuf,
+        actual: String,
+    },
+    /// The book had a cover, which neither its image nor its file has now.
+    MissingCover,
+    MissingThumbnails,
+    /// Annotations whose CFI points to no part of the book.
+    InvalidAnnotations {
+        cfis: Vec<String>,
+    },
+    /// Files of a book that is not in the index.
+    Orphan {
+        place: Place,
+    },
+}
+
+/// A fix for a problem, which the frontend sends back as it is but for
+/// `path` of [`Fix::Relocate`], the new place of the file, which it asks
+/// the user for.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum Fix {
+    Relocate {
+        book_hash: String,
+        path: Option<PathBuf>,
+    },
+    /// Takes the file at `path`, another edition of the book, in its stead,
+    /// moving its notes over.
+    ReplaceEdition {
+        book_hash: String,
+        path: PathBuf,
+    },
+    RegenerateThumbnails {
+        book_hash: String,
+    },
+    RemoveOrphan {
+        book_hash: String,
+        place: Place,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Issue {
+    /// The book the problem is with, in the index or left behind.
+    pub book_hash: String,
+    /// Its title, for books in the index.
+    pub title: Option<String>,
+    pub problem: Problem,
+    /// `None` for problems only the user can do something about.
+    pub fix: Option<Fix>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IntegrityReport {
+    /// Books in the index that were checked.
+    pub books: usize,
+    pub issues: Vec<Issue>,
+    pub checked_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RepairFailure {
+    pub fix: Fix,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RepairSummary {
+    pub fixed: usize,
+    pub failed: Vec<RepairFailure>,
+}
+
+/// Names of folders the app keeps per book, which are book hashes.
+fn is_book_hash(name: &str) -> bool {
+    name.len() == 32 && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn book_dirs(dir: &Path) -> Vec<String> {
+    std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|entry| entry.path().is_dir())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| is_book_hash(name))
+        .collect()
+}
+
+/// Books only in the cloud, which have no file here to check.
+fn is_cloud_only(book: &Book) -> bool {
+    book.uploaded_at.is_some() && book.downloaded_at.is_none()
+}
+
+fn check_book(app: &AppHandle, book: &Book) -> Result<Vec<Issue>> {
+    let issue = |problem, fix| Issue {
+        book_hash: book.hash.clone(),
+        title: Some(book.title.clone()),
+        problem,
+        fix,
+    };
+    let mut issues = Vec::new();
+    let path = super::book_path(app, book);
+    match &path {
+        None if is_cloud_only(book) => {}
+        None => issues.push(issue(
+            Problem::MissingFile,
+            Some(Fix::Relocate {
+                book_hash: book.hash.clone(),
+                path: None,
+            }),
+        )),
+        Some(path) => {
+            let actual = super::partial_md5(path)?;
+            if actual != book.hash {
+                let fix = if editions::is_edition(app, book, path) {
+                    Fix::ReplaceEdition {
+                        book_hash: book.hash.clone(),
+                        path: path.clone(),
+                    }
+                } else {
+                    Fix::Relocate {
+                        book_hash: book.hash.clone(),
+                        path: None,
+                    }
+                };
+                issues.push(issue(
+                    Problem::HashMismatch {
+                        path: path.clone(),
+                        actual,
+                    },
+                    Some(fix),
+                ));
+            }
+        }
+    }
+
+    if !super::thumbs::has_thumbnails(app, &book.hash)? {
+        let has_cover = super::cover_path(app, &book.hash).is_some()
+            || match &path {
+                Some(path) => formats::extract_cover(path).ok().flatten().is_some(),
+                None => false,
+            };
+        if has_cover {
+            let fix = Fix::RegenerateThumbnails {
+                book_hash: book.hash.clone(),
+            };
+            issues.push(issue(Problem::MissingThumbnails, Some(fix)));
+        } else if book.cover_image_url.is_some() && path.is_some() {
+            issues.push(issue(Problem::MissingCover, None));
+        }
+    }
+
+    if let Some(path) = path.filter(|path| formats::extension(path) == "epub") {
+        let notes = annotations::note_locations(app, &book.hash)?;
+        if !notes.is_empty() {
+            let spine = EpubArchive::open(&path)?.package()?.spine_items().count();
+            let cfis = notes
+                .into_iter()
+                .filter(|(_, index)| index.map_or(true, |index| index >= spine))
+                .map(|(cfi, _)| cfi)
+                .collect::<Vec<_>>();
+            if !cfis.is_empty() {
+                issues.push(issue(Problem::InvalidAnnotations { cfis }, None));
+            }
+        }
+    }
+    Ok(issues)
+}
+
+/// What is left of books not in the index, in each place.
+fn orphans(app: &AppHandle, indexed: &HashSet<String>) -> Result<Vec<Issue>> {
+    let places = [
+        (Place::Books, book_dirs(&super::books_dir(app)?)),
+        (Place::Thumbnails, super::thumbs::cached_books(app)?),
+        (Place::Trash, book_dirs(&super::trash::trash_dir(app)?)),
+        (Place::Store, app.state::<Storage>().hashes()),
+    ];
+    let mut issues = Vec::new();
+    for (place, hashes) in places {
+        for hash in hashes.into_iter().filter(|hash| !indexed.contains(hash)) {
+            issues.push(Issue {
+                book_hash: hash.clone(),
+                title: None,
+                problem: Problem::Orphan { place },
+                fix: Some(Fix::RemoveOrphan {
+                    book_hash: hash,
+                    place,
+                }),
+            });
+        }
+    }
+    Ok(issues)
+}
+
+fn verify(app: &AppHandle, job: &Job) -> Result<IntegrityReport> {
+    job.phase("listing", None);
+    let query = BookQuery {
+        include_deleted: true,
+        ..BookQuery::default()
+    };
+    let books = db::query_books(&app.state::<LibraryDb>().conn(), &query)?.books;
+    let indexed = books
+        .iter()
+        .map(|book| book.hash.clone())
+        .collect::<HashSet<_>>();
+
+    let mut issues = Vec::new();
+    // Deleted books are checked with the trash, not here
+    let live = books
+        .iter()
+        .filter(|book| book.deleted_at.is_none())
+        .collect::<Vec<_>>();
+    for (i, book) in live.iter().enumerate() {
+        if job.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        job.progress("checking", i, live.len(), Some(book.title.clone()));
+        match check_book(app, book) {
+            Ok(found) => issues.extend(found),
+            Err(e) => log::warn!("Failed to check {}: {e}", book.hash),
+        }
+    }
+    job.phase("orphans", None);
+    issues.extend(orphans(app, &indexed)?);
+    Ok(IntegrityReport {
+        books: live.len(),
+        issues,
+        checked_at: now_millis(),
+    })
+}
+
+/// Points book `hash` to the file at `path`, which must be the same book,
+/// and copies it to where the library keeps its books.
+fn relocate(app: &AppHandle, hash: &str, path: PathBuf) -> Result<()> {
+    if super::partial_md5(&path)? != hash {
+        return Err(Error::Repair(format!(
+            "{} is not the same book",
+            path.display()
+        )));
+    }
+    let db = app.state::<LibraryDb>();
+    let mut conn = db.conn();
+    let mut book = db::get_book(&conn, hash)?
+        .ok_or_else(|| Error::Repair(format!("no book {hash} in the library")))?;
+    book.file_path = Some(path.to_string_lossy().into_owned());
+    book.updated_at = now_millis();
+    db::upsert_books(&mut conn, std::slice::from_ref(&book))?;
+    drop(conn);
+    let storage = app.state::<Storage>();
+    storage::migrate_book(app, &storage, &book, storage.mode())?;
+    storage.save()
+}
+
+fn remove_orphan(app: &AppHandle, hash: &str, place: Place) -> Result<()> {
+    let db = app.state::<LibraryDb>();
+    if db::get_book(&db.conn(), hash)?.is_some() {
+        return Err(Error::Repair(format!("{hash} is in the library")));
+    }
+    let dir = match place {
+        Place::Books => super::books_dir(app)?.join(hash),
+        Place::Trash => super::trash::trash_dir(app)?.join(hash),
+        Place::Thumbnails => return super::thumbs::remove_thumbnails(app, hash),
+        Place::Store => {
+            let storage = app.state::<Storage>();
+            storage.remove(hash)?;
+            return storage.save();
+        }
+    };
+    if dir.is_dir() {
+        std::fs::remove_dir_all(dir)?;
+    }
+    Ok(())
+}
+
+fn repair(app: &AppHandle, fix: &Fix) -> Result<()> {
+    match fix {
+        Fix::Relocate { path: None, .. } => Err(Error::Repair(
+            "the new place of the file is needed".to_string(),
+        )),
+        Fix::Relocate {
+            book_hash,
+            path: Some(path),
+        } => relocate(app, book_hash, path.clone()),
+        Fix::ReplaceEdition { book_hash, path } => {
+            let report = editions::replace(app, book_hash, path)?;
+            log::info!(
+                "Moved the notes of {book_hash} to {}, {} of them orphaned",
+                report.book_hash,
+                report.orphaned.len()
+            );
+            Ok(())
+        }
+        Fix::RegenerateThumbnails { book_hash } => {
+            if super::thumbs::regenerate(app, book_hash)? {
+                Ok(())
+            } else {
+                Err(Error::Repair("the book has no cover".to_string()))
+            }
+        }
+        Fix::RemoveOrphan { book_hash, place } => remove_orphan(app, book_hash, *place),
+    }
+}
+
+/// Checks the whole library as a `verify` job, returning what is wrong
+/// with it.
+#[command]
+pub async fn verify_library(app: AppHandle) -> Result<IntegrityReport> {
+    let handle = app.clone();
+    jobs::run(&handle, "verify", |job| async move {
+        job.run_blocking({
+            let job = job.clone();
+            move || verify(&app, &job)
+        })
+        .await
+    })
+    .await
+}
+
+/// Carries out `fixes` from a report, going on past those that fail.
+#[command]
+pub async fn repair_library(app: AppHandle, fixes: Vec<Fix>) -> Result<RepairSummary> {
+    Ok(tauri::async_runtime::spawn_blocking(move || {
+        let mut summary = RepairSummary::default();
+        for fix in fixes {
+            match repair(&app, &fix) {
+                Ok(()) => summary.fixed += 1,
+                Err(e) => summary.failed.push(RepairFailure {
+                    fix,
+                    error: e.to_string(),
+                }),
+            }
+        }
+        summary
+    })
+    .await?)
+}
@@ -0,0 +1,230 @@
+//! Watches user-configured folders and notifies the frontend when ebook files
+//! are added, removed or renamed so the library can update itself. Folders
+//! are picked in a native dialog rather than named by the webview, since
+//! watching one opens it to the file system and asset protocol scopes.
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use notify_debouncer_full::notify::event::{EventKind, ModifyKind, RenameMode};
+use notify_debouncer_full::notify::{RecommendedWatcher, RecursiveMode};
+use notify_debouncer_full::{
+    new_debouncer, DebounceEventResult, DebouncedEvent, Debouncer, RecommendedCache,
+};
+use serde::Serialize;
+use tauri::{command, AppHandle, Emitter, Manager, State};
+use tauri_plugin_dialog::DialogExt;
+
+use crate::error::Result;
+use crate::formats::is_book_file;
+use crate::store;
+
+const WATCH_FOLDERS_FILE: &str = "watch-folders.json";
+const DEBOUNCE_TIMEOUT: Duration = Duration::from_millis(1500);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+pub enum LibraryChange {
+    Added { path: PathBuf },
+    Removed { path: PathBuf },
+    Renamed { from: PathBuf, to: PathBuf },
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WatchFolder {
+    pub path: PathBuf,
+    /// Whether the folder is currently being watched. Folders on unmounted
+    /// drives stay in the list but are inactive until the next restart.
+    pub active: bool,
+}
+
+struct Inner {
+    debouncer: Debouncer<RecommendedWatcher, RecommendedCache>,
+    folders: Vec<WatchFolder>,
+}
+
+pub struct LibraryWatcher(Mutex<Inner>);
+
+impl Inner {
+    fn watch(&mut self, app: &AppHandle, path: PathBuf) {
+        let active = match self.debouncer.watch(&path, RecursiveMode::Recursive) {
+            Ok(()) => {
+                crate::allow_dir_in_scopes(app, &path);
+                true
+            }
+            Err(e) => {
+                log::warn!("Failed to watch {path:?}: {e}");
+                false
+            }
+        };
+        self.folders.push(WatchFolder { path, active });
+    }
+
+    fn save(&self, app: &AppHandle) -> Result<()> {
+        let paths = self.folders.iter().map(|f| &f.path).collect::<Vec<_>>();
+        store::save(app, WATCH_FOLDERS_FILE, &paths)
+    }
+}
+
+pub fn init(app: &AppHandle) -> Result<()> {
+    let handle = app.clone();
+    let debouncer = new_debouncer(
+        DEBOUNCE_TIMEOUT,
+        None,
+        move |result: DebounceEventResult| match result {
+            Ok(events) => {
+                let changes = collect_changes(events);
+                if !changes.is_empty() {
+                    let _ = handle.emit("library-changed", changes);
+                }
+            }
+            Err(errors) => errors
+                .iter()
+                .for_each(|e| log::warn!("Library watcher error: {e}")),
+        },
+    )?;
+
+    let mut inner = Inner {
+        debouncer,
+        folders: Vec::new(),
+    };
+    let paths: Vec<PathBuf> = store::load(app, WATCH_FOLDERS_FILE);
+    for path in paths {
+        inner.watch(app, path);
+    }
+    app.manage(LibraryWatcher(Mutex::new(inner)));
+    Ok(())
+}
+
+fn collect_changes(events: Vec<DebouncedEvent>) -> Vec<LibraryChange> {
+    let mut changes = Vec::new();
+    for event in events {
+        let paths = &event.paths;
+        match event.kind {
+            EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+                changes.extend(
+                    paths
+                        .iter()
+                        .filter(|p| is_book_file(p) && p.is_file())
+                        .map(|p| LibraryChange::Added { path: p.clone() }),
+                );
+            }
+            EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+                changes.extend(
+                    paths
+                        .iter()
+                        .filter(|p| is_book_file(p))
+                        .map(|p| LibraryChange::Removed { path: p.clone() }),
+                );
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if paths.len() == 2 => {
+                let (from, to) = (&paths[0], &paths[1]);
+                match (is_book_file(from), is_book_file(to)) {
+                    (true, true) => changes.push(LibraryChange::Renamed {
+                        from: from.clone(),
+                        to: to.clone(),
+                    }),
+                    (true, false) => changes.push(LibraryChange::Removed { path: from.clone() }),
+                    (false, true) => changes.push(LibraryChange::Added { path: to.clone() }),
+                    (false, false) => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    changes
+}
+
+fn scan_folder(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(t) if t.is_dir() => scan_folder(&path, files),
+            Ok(t) if t.is_file() && is_book_file(&path) => files.push(path),
+            _ => {}
+        }
+    }
+}
+
+#[command]
+pub fn get_watch_folders(watcher: State<'_, LibraryWatcher>) -> Vec<WatchFolder> {
+    watcher.0.lock().unwrap().folders.clone()
+}
+
+/// Watches a folder the user picks in a folder dialog, returning the
+/// folders, unchanged if the dialog was cancelled.
+#[command]
+pub async fn add_watch_folder(app: AppHandle) -> Result<Vec<WatchFolder>> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let picked = app.dialog().file().blocking_pick_folder();
+        let watcher = app.state::<LibraryWatcher>();
+        let mut inner = watcher.0.lock().unwrap();
+        let Some(folder) = picked else {
+            return Ok(inner.folders.clone());
+        };
+        let path = folder
+            .into_path()
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        if !inner.folders.iter().any(|f| f.path == path) {
+            inner.watch(&app, path);
+            inner.save(&app)?;
+        }
+        Ok(inner.folders.clone())
+    })
+    .await?
+}
+
+#[command]
+pub fn remove_watch_folder(
+    app: AppHandle,
+    watcher: State<'_, LibraryWatcher>,
+    path: PathBuf,
+) -> Result<Vec<WatchFolder>> {
+    let mut inner = watcher.0.lock().unwrap();
+    if let Some(index) = inner.folders.iter().position(|f| f.path == path) {
+        let folder = inner.folders.remove(index);
+        if folder.active {
+            if let Err(e) = inner.debouncer.unwatch(&folder.path) {
+                log::warn!("Failed to unwatch {:?}: {e}", folder.path);
+            }
+        }
+        inner.save(&app)?;
+    }
+    Ok(inner.folders.clone())
+}
+
+impl LibraryWatcher {
+    pub(crate) fn active_folders(&self) -> Vec<PathBuf> {
+        self.0
+            .lock()
+            .unwrap()
+            .folders
+            .iter()
+            .filter(|f| f.active)
+            .map(|f| f.path.clone())
+            .collect()
+    }
+}
+
+/// Every ebook currently present in `folders` and their subfolders.
+pub(crate) fn scan_folders(folders: &[PathBuf]) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for folder in folders {
+        scan_folder(folder, &mut files);
+    }
+    files
+}
+
+/// Lists every ebook currently present in the watched folders, letting the
+/// frontend reconcile changes that happened while the app was not running.
+#[command]
+pub async fn scan_watch_folders(watcher: State<'_, LibraryWatcher>) -> Result<Vec<PathBuf>> {
+    let folders = watcher.active_folders();
+    let files = tauri::async_runtime::spawn_blocking(move || scan_folders(&folders)).await?;
+    Ok(files)
+}
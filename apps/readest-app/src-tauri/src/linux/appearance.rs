@@ -0,0 +1,123 @@
+//! The color scheme of the desktop, from the `org.freedesktop.appearance`
+//! setting of the XDG desktop portal, and Night Light with the sunrise and
+//! sunset it goes by, from the color plugin of GNOME's settings daemon.
+//! Both are watched for changes over the session bus.
+
+use zbus::blocking::{Connection, MessageIterator, Proxy};
+use zbus::message::Type;
+use zbus::zvariant::{OwnedValue, Value};
+use zbus::MatchRule;
+
+use crate::error::Result;
+
+const PORTAL_NAME: &str = "org.freedesktop.portal.Desktop";
+const PORTAL_PATH: &str = "/org/freedesktop/portal/desktop";
+const SETTINGS_INTERFACE: &str = "org.freedesktop.portal.Settings";
+const APPEARANCE: &str = "org.freedesktop.appearance";
+const COLOR_SCHEME: &str = "color-scheme";
+/// Values of the color scheme setting.
+const PREFER_DARK: u32 = 1;
+const PREFER_LIGHT: u32 = 2;
+
+const COLOR_NAME: &str = "org.gnome.SettingsDaemon.Color";
+const COLOR_PATH: &str = "/org/gnome/SettingsDaemon/Color";
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+
+pub struct NightLight {
+    pub active: bool,
+    /// Local sunrise and sunset, in hours after midnight, when the daemon
+    /// knows where it is.
+    pub sunrise: Option<f64>,
+    pub sunset: Option<f64>,
+}
+
+fn connection() -> Result<Connection> {
+    match super::dbus::connection() {
+        Some(connection) => Ok(connection.clone()),
+        None => Ok(Connection::session()?),
+    }
+}
+
+/// Whether the desktop prefers dark, None when it has no preference or
+/// there is no portal to ask.
+pub fn prefers_dark() -> Option<bool> {
+    let connection = connection().ok()?;
+    // `Read` rather than `ReadOne`, which older portals lack; it wraps the
+    // value in another variant
+    let reply = connection
+        .call_method(
+            Some(PORTAL_NAME),
+            PORTAL_PATH,
+            Some(SETTINGS_INTERFACE),
+            "Read",
+            &(APPEARANCE, COLOR_SCHEME),
+        )
+        .ok()?;
+    let value = reply.body().deserialize::<OwnedValue>().ok()?;
+    let mut value: &Value = &value;
+    while let Value::Value(inner) = value {
+        value = inner;
+    }
+    match u32::try_from(value).ok()? {
+        PREFER_DARK => Some(true),
+        PREFER_LIGHT => Some(false),
+        _ => None,
+    }
+}
+
+/// Night Light, None without GNOME's settings daemon.
+pub fn night_light() -> Option<NightLight> {
+    let connection = connection().ok()?;
+    let proxy = Proxy::new(&connection, COLOR_NAME, COLOR_PATH, COLOR_NAME).ok()?;
+    let active = proxy.get_property::<bool>("NightLightActive").ok()?;
+    // Negative until the location is known
+    let hours = |name| {
+        proxy
+            .get_property::<f64>(name)
+            .ok()
+            .filter(|hours| (0.0..24.0).contains(hours))
+    };
+    Some(NightLight {
+        active,
+        sunrise: hours("Sunrise"),
+        sunset: hours("Sunset"),
+    })
+}
+
+/// Calls `changed` on a thread named `name` for each signal of `rule`.
+fn watch(
+    connection: &Connection,
+    name: &str,
+    rule: MatchRule<'static>,
+    changed: impl Fn() + Send + 'static,
+) -> Result<()> {
+    let signals = MessageIterator::for_match_rule(rule, connection, None)?;
+    std::thread::Builder::new()
+        .name(name.to_string())
+        .spawn(move || {
+            for _ in signals.flatten() {
+                changed();
+            }
+        })?;
+    Ok(())
+}
+
+/// Calls `changed` whenever the color scheme or Night Light changes.
+pub fn listen(changed: impl Fn() + Clone + Send + 'static) -> Result<()> {
+    let connection = connection()?;
+    let scheme = MatchRule::builder()
+        .msg_type(Type::Signal)
+        .interface(SETTINGS_INTERFACE)?
+        .member("SettingChanged")?
+        .path(PORTAL_PATH)?
+        .arg(0, APPEARANCE)?
+        .build();
+    watch(&connection, "color-scheme", scheme, changed.clone())?;
+    let night_light = MatchRule::builder()
+        .msg_type(Type::Signal)
+        .interface(PROPERTIES_INTERFACE)?
+        .member("PropertiesChanged")?
+        .path(COLOR_PATH)?
+        .build();
+    watch(&connection, "night-light", night_light, changed)
+}
@@ -0,0 +1,77 @@
+//! The `org.freedesktop.Application` interface on the session bus, under
+//! the app's identifier, so the desktop can open files and links in the
+//! running app and start it through the D-Bus service file that the
+//! packages install when it is not running. Its one action, `import-folder`,
+//! is the one of the desktop entry.
+
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use tauri::AppHandle;
+use zbus::blocking::Connection;
+use zbus::zvariant::OwnedValue;
+
+use crate::deep_link::{self, DeepLink};
+use crate::error::Result;
+
+/// The bus name, the identifier of the app in `tauri.conf.json`.
+const NAME: &str = "com.vlarch.vlarch";
+const PATH: &str = "/com/vlarch/vlarch";
+
+static CONNECTION: OnceLock<Connection> = OnceLock::new();
+
+struct Application {
+    app: AppHandle,
+}
+
+#[zbus::interface(name = "org.freedesktop.Application")]
+impl Application {
+    fn activate(&self, _platform_data: HashMap<String, OwnedValue>) {
+        crate::show_main_window(&self.app);
+    }
+
+    /// Opens the `file://` and `vlarch://` URIs like arguments of a second
+    /// instance.
+    fn open(&self, uris: Vec<String>, _platform_data: HashMap<String, OwnedValue>) {
+        let exe = std::env::current_exe()
+            .map(|exe| exe.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let argv = std::iter::once(exe).chain(uris).collect();
+        crate::open_argv(&self.app, argv, "/".into());
+    }
+
+    fn activate_action(
+        &self,
+        action_name: String,
+        _parameter: Vec<OwnedValue>,
+        _platform_data: HashMap<String, OwnedValue>,
+    ) {
+        match action_name.as_str() {
+            "import-folder" => deep_link::dispatch(&self.app, vec![DeepLink::ImportFolder]),
+            _ => log::warn!("Ignoring unknown desktop action {action_name}"),
+        }
+    }
+}
+
+/// The session bus connection the app is served on, once it is.
+pub fn connection() -> Option<&'static Connection> {
+    CONNECTION.get()
+}
+
+fn serve(app: &AppHandle) -> Result<()> {
+    let connection = zbus::blocking::connection::Builder::session()?
+        .name(NAME)?
+        .serve_at(PATH, Application { app: app.clone() })?
+        .build()?;
+    let _ = CONNECTION.set(connection);
+    Ok(())
+}
+
+pub fn init(app: &AppHandle) {
+    let app = app.clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        if let Err(e) = serve(&app) {
+            log::warn!("Failed to serve the app on D-Bus: {e}");
+        }
+    });
+}
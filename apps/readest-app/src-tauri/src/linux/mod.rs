@@ -0,0 +1,4 @@
+pub mod appearance;
+pub mod dbus;
+pub mod notifications;
+pub mod recent_files;
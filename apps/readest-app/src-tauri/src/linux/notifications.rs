@@ -0,0 +1,138 @@
+//! Desktop notifications through `org.freedesktop.Notifications` for
+//! background jobs that end while the window is not in front, and for
+//! questions answered by clicking them, the Linux counterpart of the
+//! Windows toasts.
+
+use std::collections::HashMap;
+
+use tauri::{AppHandle, Manager};
+use zbus::blocking::{Connection, MessageIterator};
+use zbus::message::Type;
+use zbus::zvariant::Value;
+use zbus::MatchRule;
+
+use crate::error::Result;
+use crate::i18n;
+use crate::jobs::{JobProgress, JobState};
+use crate::utils::escape_xml;
+
+/// Name of the desktop entry and the icon the packages install.
+const DESKTOP_ENTRY: &str = "vl-arch";
+/// Lets the server pick how long the notification stays.
+const DEFAULT_TIMEOUT: i32 = -1;
+
+const BUS_NAME: &str = "org.freedesktop.Notifications";
+const PATH: &str = "/org/freedesktop/Notifications";
+/// Key of the action of [`ask`], beside the `default` one of clicking the
+/// notification itself.
+const ACCEPT_ACTION: &str = "accept";
+
+fn connection() -> Result<Connection> {
+    match super::dbus::connection() {
+        Some(connection) => Ok(connection.clone()),
+        None => Ok(Connection::session()?),
+    }
+}
+
+/// Shows a notification with `actions`, pairs of keys and labels, and
+/// returns its id.
+fn send(
+    connection: &Connection,
+    app: &AppHandle,
+    summary: &str,
+    body: &str,
+    actions: &[&str],
+) -> Result<u32> {
+    let hints = HashMap::from([("desktop-entry", Value::from(DESKTOP_ENTRY))]);
+    let reply = connection.call_method(
+        Some(BUS_NAME),
+        PATH,
+        Some(BUS_NAME),
+        "Notify",
+        &(
+            app.package_info().name.as_str(),
+            0u32,
+            DESKTOP_ENTRY,
+            summary,
+            body,
+            actions,
+            hints,
+            DEFAULT_TIMEOUT,
+        ),
+    )?;
+    Ok(reply.body().deserialize()?)
+}
+
+fn show(app: &AppHandle, summary: &str, body: &str) -> Result<()> {
+    send(&connection()?, app, summary, body, &[])?;
+    Ok(())
+}
+
+/// Asks the user whether to do `action`, running `accept` once they click
+/// it or the notification. `body` is plain text.
+pub fn ask(
+    app: &AppHandle,
+    summary: &str,
+    body: &str,
+    action: &str,
+    accept: impl FnOnce() + Send + 'static,
+) -> Result<()> {
+    let connection = connection()?;
+    let rule = MatchRule::builder()
+        .msg_type(Type::Signal)
+        .interface(BUS_NAME)?
+        .path(PATH)?
+        .build();
+    // Listening before the notification is shown, not to miss the answer
+    let signals = MessageIterator::for_match_rule(rule, &connection, None)?;
+    let actions = ["default", action, ACCEPT_ACTION, action];
+    let id = send(&connection, app, summary, &escape_xml(body), &actions)?;
+    std::thread::Builder::new()
+        .name("notification".to_string())
+        .spawn(move || {
+            for message in signals.flatten() {
+                let header = message.header();
+                match header.member().map(|member| member.as_str()) {
+                    Some("ActionInvoked") => {
+                        let Ok((of, _)) = message.body().deserialize::<(u32, String)>() else {
+                            continue;
+                        };
+                        if of == id {
+                            accept();
+                            return;
+                        }
+                    }
+                    Some("NotificationClosed") => {
+                        if matches!(message.body().deserialize::<(u32, u32)>(), Ok((of, _)) if of == id)
+                        {
+                            return;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Tells the user that the job of `progress` is done or failed, unless they
+/// are looking at the app.
+pub fn notify(app: &AppHandle, progress: &JobProgress) {
+    let Some(title) = progress.outcome() else {
+        return;
+    };
+    let in_front = app
+        .get_webview_window("main")
+        .is_some_and(|window| window.is_focused().unwrap_or(false));
+    if in_front {
+        return;
+    }
+    // Servers may read the body as markup
+    let body = match progress.state {
+        JobState::Failed => escape_xml(progress.message.as_deref().unwrap_or_default()),
+        _ => String::new(),
+    };
+    if let Err(e) = show(app, &i18n::t(app, title), &body) {
+        log::warn!("Failed to show a notification: {e}");
+    }
+}
@@ -0,0 +1,120 @@
+//! Books opened in the app among the desktop's recently used files, the
+//! `recently-used.xbel` that GTK's recent manager and file choosers read.
+//! The file is shared by every app, so the app's bookmark is spliced into
+//! it and the rest is kept as written. Clearing the app's own list leaves
+//! the file alone.
+
+use std::path::PathBuf;
+
+use tauri::{AppHandle, Manager, Url};
+
+use crate::error::{Error, Result};
+use crate::formats::{extension, mime_type};
+use crate::recent::RecentBook;
+use crate::utils::{escape_xml, format_rfc3339, now_millis};
+
+const FILE: &str = "recently-used.xbel";
+const BOOKMARK_NS: &str = "http://www.freedesktop.org/standards/desktop-bookmarks";
+const EMPTY: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+<xbel version=\"1.0\"\n      \
+xmlns:bookmark=\"http://www.freedesktop.org/standards/desktop-bookmarks\"\n      \
+xmlns:mime=\"http://www.freedesktop.org/standards/shared-mime-info\"\n>\n</xbel>\n";
+
+fn xbel_file(app: &AppHandle) -> Result<PathBuf> {
+    Ok(app.path().data_dir()?.join(FILE))
+}
+
+/// The command line the bookmark says the app opened the book with.
+fn exec() -> Result<String> {
+    let exe = match std::env::var_os("APPIMAGE") {
+        Some(path) => PathBuf::from(path),
+        None => std::env::current_exe()?,
+    };
+    Ok(format!("'{} %u'", exe.display()))
+}
+
+fn bookmark(app: &AppHandle, href: &str, mime: &str, added: &str, count: u32) -> Result<String> {
+    let now = format_rfc3339(now_millis());
+    Ok(format!(
+        "  <bookmark href=\"{href}\" added=\"{added}\" modified=\"{now}\" visited=\"{now}\">\n    \
+         <info>\n      \
+         <metadata owner=\"http://freedesktop.org\">\n        \
+         <mime:mime-type type=\"{mime}\"/>\n        \
+         <bookmark:applications>\n          \
+         <bookmark:application name=\"{}\" exec=\"{}\" modified=\"{now}\" count=\"{count}\"/>\n        \
+         </bookmark:applications>\n      \
+         </metadata>\n    \
+         </info>\n  \
+         </bookmark>\n",
+        escape_xml(&app.package_info().name),
+        escape_xml(&exec()?),
+    ))
+}
+
+/// Records that `book` was just opened.
+pub fn add(app: &AppHandle, book: &RecentBook) -> Result<()> {
+    let Some(path) = &book.path else {
+        return Ok(());
+    };
+    let Ok(href) = Url::from_file_path(path) else {
+        return Ok(());
+    };
+    let file = xbel_file(app)?;
+    let xbel = match std::fs::read_to_string(&file) {
+        Ok(xbel) => xbel,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => EMPTY.to_string(),
+        Err(e) => return Err(e.into()),
+    };
+    // A file the app cannot read is left for its owner to repair
+    let doc = roxmltree::Document::parse(&xbel)
+        .map_err(|e| Error::InvalidBook(format!("{FILE}: {e}")))?;
+    let root = doc.root_element();
+    let existing = root.children().find(|node| {
+        node.has_tag_name("bookmark") && node.attribute("href") == Some(href.as_str())
+    });
+
+    let name = app.package_info().name.as_str();
+    let (added, count) = match existing {
+        Some(node) => {
+            let count = node
+                .descendants()
+                .find(|node| {
+                    node.has_tag_name((BOOKMARK_NS, "application"))
+                        && node.attribute("name") == Some(name)
+                })
+                .and_then(|node| node.attribute("count")?.parse::<u32>().ok())
+                .unwrap_or(0);
+            (node.attribute("added").map(str::to_string), count)
+        }
+        None => (None, 0),
+    };
+    let added = added.unwrap_or_else(|| format_rfc3339(now_millis()));
+    let mime = mime_type(&extension(path));
+    let entry = bookmark(app, &escape_xml(href.as_str()), mime, &added, count + 1)?;
+
+    let updated = match existing {
+        Some(node) => {
+            // Replaces the old bookmark with its indentation and line break
+            let range = node.range();
+            let start = xbel[..range.start].trim_end_matches([' ', '\t']).len();
+            let end = range.end + usize::from(xbel[range.end..].starts_with('\n'));
+            format!("{}{entry}{}", &xbel[..start], &xbel[end..])
+        }
+        None => {
+            // Appended before the closing tag of the root
+            let end = xbel[..root.range().end]
+                .rfind("</")
+                .ok_or_else(|| Error::InvalidBook(FILE.into()))?;
+            format!("{}{entry}{}", &xbel[..end], &xbel[end..])
+        }
+    };
+
+    // Written beside it and moved over it, as readers watch the file
+    let temp = file.with_extension("xbel.tmp");
+    if let Some(dir) = file.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(&temp, updated)?;
+    std::fs::rename(&temp, &file)?;
+    Ok(())
+}
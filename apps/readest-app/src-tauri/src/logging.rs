@@ -0,0 +1,70 @@
+//! Logging of the backend, and of the frontend through the log plugin, to
+//! stderr and to daily files under `logs/` in the app data directory. A
+//! week of files is kept for [`crate::diagnostics`] bundles. `RUST_LOG`
+//! overrides the levels, e.g. `RUST_LOG=vlarchlib=debug`.
+
+use std::path::PathBuf;
+
+use tauri::AppHandle;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::prelude::*;
+
+use crate::error::Result;
+use crate::paths;
+
+const LOG_DIR: &str = "logs";
+/// Start of the names of the log files, which end in the date.
+pub const FILE_PREFIX: &str = "vl-arch";
+const KEEP_FILES: usize = 7;
+
+pub fn log_dir(app: &AppHandle) -> Result<PathBuf> {
+    Ok(paths::data_dir(app)?.join(LOG_DIR))
+}
+
+fn filter(default: LevelFilter) -> EnvFilter {
+    EnvFilter::builder()
+        .with_default_directive(default.into())
+        .from_env_lossy()
+}
+
+fn file_appender(app: &AppHandle) -> std::result::Result<RollingFileAppender, String> {
+    let dir = log_dir(app).map_err(|e| e.to_string())?;
+    RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix(FILE_PREFIX)
+        .filename_suffix("log")
+        .max_log_files(KEEP_FILES)
+        .build(dir)
+        .map_err(|e| e.to_string())
+}
+
+/// Sends the records of `tracing` and `log` to stderr from `console` up and
+/// to the log files from info up. Lines are written as they come, so those
+/// before a crash are kept.
+pub fn init(app: &AppHandle, console: LevelFilter) {
+    let (file, file_error) = match file_appender(app) {
+        Ok(appender) => (Some(appender), None),
+        Err(e) => (None, Some(e)),
+    };
+    let file_layer = file.map(|appender| {
+        tracing_subscriber::fmt::layer()
+            .with_ansi(false)
+            .with_writer(appender)
+            .with_filter(filter(LevelFilter::INFO))
+    });
+    let console_layer = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_filter(filter(console));
+    if let Err(e) = tracing_subscriber::registry()
+        .with(console_layer)
+        .with(file_layer)
+        .try_init()
+    {
+        eprintln!("Failed to initialize logging: {e}");
+        return;
+    }
+    if let Some(e) = file_error {
+        log::warn!("Failed to open the log files: {e}");
+    }
+}
@@ -0,0 +1,22 @@
+//! Focus on macOS, which apps cannot turn on themselves. The user makes a
+//! shortcut in the Shortcuts app that sets a Focus, and another that turns
+//! it off, and the app runs them by the names given in its settings.
+
+use std::process::Command;
+
+use crate::error::{Error, Result};
+
+/// Runs the shortcut `name` and waits for it to finish.
+pub fn run_shortcut(name: &str) -> Result<()> {
+    let output = Command::new("/usr/bin/shortcuts")
+        .args(["run", name])
+        .output()?;
+    if !output.status.success() {
+        let message = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::Focus(format!(
+            "shortcut {name:?} failed: {}",
+            message.trim()
+        )));
+    }
+    Ok(())
+}
@@ -1,23 +1,106 @@
+use std::sync::Mutex;
+
+use cocoa::base::{id, nil};
+use cocoa::foundation::NSString;
+use objc::{class, msg_send, sel, sel_impl};
+use serde::Serialize;
 use tauri::menu::MenuEvent;
-use tauri::menu::{SubmenuBuilder, HELP_SUBMENU_ID};
-use tauri::AppHandle;
+use tauri::menu::{
+    AboutMetadata, Menu, MenuItem, PredefinedMenuItem, Submenu, SubmenuBuilder, HELP_SUBMENU_ID,
+    WINDOW_SUBMENU_ID,
+};
+use tauri::{command, AppHandle, Emitter, Manager, Wry};
 use tauri_plugin_opener::OpenerExt;
 
-pub fn setup_macos_menu(app: &AppHandle) -> tauri::Result<()> {
-    let global_menu = app.menu().unwrap();
+use crate::deep_link::{self, DeepLink};
+use crate::diagnostics;
+use crate::error::Result;
+use crate::i18n;
+use crate::recent::{self, RecentBook};
+
+const OPEN_RECENT_PREFIX: &str = "open_recent:";
+const CLEAR_RECENT_ID: &str = "clear_recent";
+pub const EVENT: &str = "menu-action";
+
+/// Menu items the frontend carries out, sent to it as `menu-action` events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MenuAction {
+    Open,
+    ImportFolder,
+    ToggleToc,
+    ToggleAnnotations,
+    ZoomIn,
+    ZoomOut,
+    ResetZoom,
+    NextChapter,
+    PreviousChapter,
+}
+
+impl MenuAction {
+    /// In declaration order, so an action indexes its item.
+    const ALL: [MenuAction; 9] = [
+        MenuAction::Open,
+        MenuAction::ImportFolder,
+        MenuAction::ToggleToc,
+        MenuAction::ToggleAnnotations,
+        MenuAction::ZoomIn,
+        MenuAction::ZoomOut,
+        MenuAction::ResetZoom,
+        MenuAction::NextChapter,
+        MenuAction::PreviousChapter,
+    ];
 
-    if let Some(item) = global_menu.get(HELP_SUBMENU_ID) {
-        global_menu.remove(&item)?;
+    fn id(self) -> &'static str {
+        match self {
+            MenuAction::Open => "menu_open",
+            MenuAction::ImportFolder => "menu_import_folder",
+            MenuAction::ToggleToc => "menu_toggle_toc",
+            MenuAction::ToggleAnnotations => "menu_toggle_annotations",
+            MenuAction::ZoomIn => "menu_zoom_in",
+            MenuAction::ZoomOut => "menu_zoom_out",
+            MenuAction::ResetZoom => "menu_reset_zoom",
+            MenuAction::NextChapter => "menu_next_chapter",
+            MenuAction::PreviousChapter => "menu_previous_chapter",
+        }
     }
 
-    global_menu.append(
-        &SubmenuBuilder::new(app, "Help")
-            .text("privacy_policy", "Privacy Policy")
-            .separator()
-            .text("report_issue", "Report An Issue...")
-            .text("vlarch_help", "VL-Arch Help")
-            .build()?,
-    )?;
+    fn label(self) -> &'static str {
+        match self {
+            MenuAction::Open => "Open...",
+            MenuAction::ImportFolder => "Import Folder...",
+            MenuAction::ToggleToc => "Toggle Table of Contents",
+            MenuAction::ToggleAnnotations => "Toggle Annotations",
+            MenuAction::ZoomIn => "Zoom In",
+            MenuAction::ZoomOut => "Zoom Out",
+            MenuAction::ResetZoom => "Actual Size",
+            MenuAction::NextChapter => "Next Chapter",
+            MenuAction::PreviousChapter => "Previous Chapter",
+        }
+    }
+
+    /// Whether the item only applies to an open book.
+    fn needs_book(self) -> bool {
+        !matches!(self, MenuAction::Open | MenuAction::ImportFolder)
+    }
+}
+
+#[derive(Default)]
+struct MenuState {
+    /// The "Open Recent" submenu of the File menu.
+    recent: Option<Submenu<Wry>>,
+    /// Items that only apply to an open book, see [`MenuAction::needs_book`].
+    reader_items: Vec<MenuItem<Wry>>,
+    reading: bool,
+}
+
+/// The parts of the menu bar that change, replaced along with it.
+#[derive(Default)]
+struct AppMenu(Mutex<MenuState>);
+
+pub fn setup_macos_menu(app: &AppHandle) -> tauri::Result<()> {
+    app.manage(AppMenu::default());
+    app.set_menu(build_menu(app)?)?;
 
     app.on_menu_event(|app, event| {
         handle_menu_event(app, &event);
@@ -26,12 +109,249 @@ pub fn setup_macos_menu(app: &AppHandle) -> tauri::Result<()> {
     Ok(())
 }
 
+/// The menu bar in the current language. The items of the app have no
+/// accelerators, as the reader handles its own keyboard shortcuts.
+fn build_menu(app: &AppHandle) -> tauri::Result<Menu<Wry>> {
+    let name = app.package_info().name.clone();
+    let config = app.config();
+    let about = AboutMetadata {
+        name: Some(name.clone()),
+        version: Some(app.package_info().version.to_string()),
+        copyright: config.bundle.copyright.clone(),
+        authors: config.bundle.publisher.clone().map(|p| vec![p]),
+        ..Default::default()
+    };
+    let tr = |key: &str| i18n::t(app, key);
+    let reading = app.state::<AppMenu>().0.lock().unwrap().reading;
+    let items = MenuAction::ALL
+        .iter()
+        .map(|action| {
+            MenuItem::with_id(
+                app,
+                action.id(),
+                tr(action.label()),
+                reading || !action.needs_book(),
+                None::<&str>,
+            )
+        })
+        .collect::<tauri::Result<Vec<_>>>()?;
+    let item = |action: MenuAction| &items[action as usize];
+
+    let recent_menu = Submenu::with_id(app, "open_recent", tr("Open Recent"), true)?;
+    let menu = Menu::with_items(
+        app,
+        &[
+            &SubmenuBuilder::new(app, name)
+                .item(&PredefinedMenuItem::about(
+                    app,
+                    Some(tr("About {{name}}").as_str()),
+                    Some(about),
+                )?)
+                .separator()
+                .item(&PredefinedMenuItem::services(
+                    app,
+                    Some(tr("Services").as_str()),
+                )?)
+                .separator()
+                .item(&PredefinedMenuItem::hide(
+                    app,
+                    Some(tr("Hide {{name}}").as_str()),
+                )?)
+                .item(&PredefinedMenuItem::hide_others(
+                    app,
+                    Some(tr("Hide Others").as_str()),
+                )?)
+                .separator()
+                .item(&PredefinedMenuItem::quit(
+                    app,
+                    Some(tr("Quit {{name}}").as_str()),
+                )?)
+                .build()?,
+            &SubmenuBuilder::new(app, tr("File"))
+                .item(item(MenuAction::Open))
+                .item(&recent_menu)
+                .item(item(MenuAction::ImportFolder))
+                .separator()
+                .item(&PredefinedMenuItem::close_window(
+                    app,
+                    Some(tr("Close Window").as_str()),
+                )?)
+                .build()?,
+            &SubmenuBuilder::new(app, tr("Edit"))
+                .item(&PredefinedMenuItem::undo(app, Some(tr("Undo").as_str()))?)
+                .item(&PredefinedMenuItem::redo(app, Some(tr("Redo").as_str()))?)
+                .separator()
+                .item(&PredefinedMenuItem::cut(app, Some(tr("Cut").as_str()))?)
+                .item(&PredefinedMenuItem::copy(app, Some(tr("Copy").as_str()))?)
+                .item(&PredefinedMenuItem::paste(app, Some(tr("Paste").as_str()))?)
+                .item(&PredefinedMenuItem::select_all(
+                    app,
+                    Some(tr("Select All").as_str()),
+                )?)
+                .build()?,
+            &SubmenuBuilder::new(app, tr("View"))
+                .item(item(MenuAction::ToggleToc))
+                .item(item(MenuAction::ToggleAnnotations))
+                .separator()
+                .item(item(MenuAction::ZoomIn))
+                .item(item(MenuAction::ZoomOut))
+                .item(item(MenuAction::ResetZoom))
+                .separator()
+                .item(&PredefinedMenuItem::fullscreen(
+                    app,
+                    Some(tr("Toggle Full Screen").as_str()),
+                )?)
+                .build()?,
+            &SubmenuBuilder::new(app, tr("Go"))
+                .item(item(MenuAction::PreviousChapter))
+                .item(item(MenuAction::NextChapter))
+                .build()?,
+            &SubmenuBuilder::with_id(app, WINDOW_SUBMENU_ID, tr("Window"))
+                .item(&PredefinedMenuItem::minimize(
+                    app,
+                    Some(tr("Minimize").as_str()),
+                )?)
+                .item(&PredefinedMenuItem::maximize(
+                    app,
+                    Some(tr("Zoom").as_str()),
+                )?)
+                .separator()
+                .item(&PredefinedMenuItem::close_window(
+                    app,
+                    Some(tr("Close Window").as_str()),
+                )?)
+                .build()?,
+            &SubmenuBuilder::with_id(app, HELP_SUBMENU_ID, tr("Help"))
+                .text("privacy_policy", tr("Privacy Policy"))
+                .separator()
+                .text("report_issue", tr("Report An Issue..."))
+                .text("vlarch_help", tr("{{name}} Help"))
+                .build()?,
+        ],
+    )?;
+    let state = app.state::<AppMenu>();
+    let mut state = state.0.lock().unwrap();
+    state.recent = Some(recent_menu);
+    state.reader_items = MenuAction::ALL
+        .iter()
+        .zip(items)
+        .filter(|(action, _)| action.needs_book())
+        .map(|(_, item)| item)
+        .collect();
+    Ok(menu)
+}
+
+/// Replaces the menu bar with one in the current language; the recent
+/// books have to be listed again.
+pub fn rebuild(app: &AppHandle) -> Result<()> {
+    app.set_menu(build_menu(app)?)?;
+    Ok(())
+}
+
+/// Enables the items for an open book while the reader shows one, and
+/// shows the reading controls on the Touch Bar.
+#[command]
+pub fn set_menu_reading(app: AppHandle, reading: bool) -> Result<()> {
+    if let Some(state) = app.try_state::<AppMenu>() {
+        let mut state = state.0.lock().unwrap();
+        state.reading = reading;
+        for item in &state.reader_items {
+            item.set_enabled(reading)?;
+        }
+    }
+    crate::macos::touch_bar::set_reading(&app, reading)
+}
+
+/// Lists `books` under "Open Recent", followed by "Clear Menu".
+pub fn set_recent_books(app: &AppHandle, books: &[RecentBook]) -> Result<()> {
+    let Some(menu) = app
+        .try_state::<AppMenu>()
+        .and_then(|state| state.0.lock().unwrap().recent.clone())
+    else {
+        return Ok(());
+    };
+    for item in menu.items()? {
+        menu.remove(&item)?;
+    }
+    for book in books {
+        let id = format!("{OPEN_RECENT_PREFIX}{}", book.hash);
+        menu.append(&MenuItem::with_id(
+            app,
+            id,
+            &book.title,
+            true,
+            None::<&str>,
+        )?)?;
+    }
+    if !books.is_empty() {
+        menu.append(&PredefinedMenuItem::separator(app)?)?;
+    }
+    menu.append(&MenuItem::with_id(
+        app,
+        CLEAR_RECENT_ID,
+        i18n::t(app, "Clear Menu"),
+        !books.is_empty(),
+        None::<&str>,
+    )?)?;
+    Ok(())
+}
+
+/// Adds the file of `book` to the recent documents the Dock shows for the
+/// app. Picking one there opens the file like opening it from Finder.
+pub fn note_recent_document(app: &AppHandle, book: &RecentBook) -> Result<()> {
+    let Some(path) = book.path.clone() else {
+        return Ok(());
+    };
+    app.run_on_main_thread(move || unsafe {
+        let path: id = NSString::alloc(nil).init_str(&path.to_string_lossy());
+        let url: id = msg_send![class!(NSURL), fileURLWithPath: path];
+        let _: () = msg_send![path, release];
+        let controller: id = msg_send![class!(NSDocumentController), sharedDocumentController];
+        let _: () = msg_send![controller, noteNewRecentDocumentURL: url];
+    })?;
+    Ok(())
+}
+
+pub fn clear_recent_documents(app: &AppHandle) -> Result<()> {
+    app.run_on_main_thread(|| unsafe {
+        let controller: id = msg_send![class!(NSDocumentController), sharedDocumentController];
+        let _: () = msg_send![controller, clearRecentDocuments: nil];
+    })?;
+    Ok(())
+}
+
 pub fn handle_menu_event(app: &AppHandle, event: &MenuEvent) {
+    if let Some(hash) = event.id().as_ref().strip_prefix(OPEN_RECENT_PREFIX) {
+        let link = DeepLink::Open {
+            book: hash.to_string(),
+            cfi: None,
+        };
+        deep_link::dispatch(app, vec![link]);
+        return;
+    }
+    if event.id() == CLEAR_RECENT_ID {
+        let app = app.clone();
+        tauri::async_runtime::spawn_blocking(move || {
+            if let Err(e) = recent::clear(&app) {
+                log::warn!("Failed to clear recent books: {e}");
+            }
+        });
+        return;
+    }
+
+    if let Some(action) = MenuAction::ALL
+        .iter()
+        .find(|action| event.id() == action.id())
+    {
+        let _ = app.emit(EVENT, action);
+        return;
+    }
+
     let opener = app.opener();
     if event.id() == "privacy_policy" {
         let _ = opener.open_url("https://vlarch.com/privacy-policy", None::<&str>);
     } else if event.id() == "report_issue" {
-        let _ = opener.open_url("https://github.com/vl-arch/vl-arch/issues", None::<&str>);
+        diagnostics::report_issue(app.clone());
     } else if event.id() == "vlarch_help" {
         let _ = opener.open_url("https://vlarch.com/support", None::<&str>);
     }
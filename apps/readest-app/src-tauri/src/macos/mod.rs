@@ -1,4 +1,11 @@
 pub mod apple_auth;
+pub mod focus;
 pub mod menu;
+pub mod night_shift;
+#[cfg(feature = "quicklook")]
+pub mod quicklook;
 pub mod safari_auth;
+pub mod services;
+pub mod spotlight;
+pub mod touch_bar;
 pub mod traffic_light;
@@ -0,0 +1,98 @@
+//! Night Shift, which only the private CoreBrightness framework tells
+//! about. Its `CBBlueLightClient` is what Control Center uses; the status
+//! it fills in is laid out as reverse engineered by f.lux and others.
+
+use std::sync::OnceLock;
+
+use objc2::rc::Retained;
+use objc2::runtime::{AnyClass, AnyObject, Bool, Sel};
+use objc2::{msg_send, sel};
+use objc2_foundation::{NSBundle, NSString};
+
+const FRAMEWORK: &str = "/System/Library/PrivateFrameworks/CoreBrightness.framework";
+
+/// How Night Shift is scheduled.
+pub enum Mode {
+    Off,
+    SunsetToSunrise,
+    /// From and to, in minutes after midnight.
+    Custom(u32, u32),
+}
+
+pub struct Status {
+    pub active: bool,
+    pub mode: Mode,
+}
+
+#[repr(C)]
+struct Time {
+    hour: i32,
+    minute: i32,
+}
+
+#[repr(C)]
+struct RawStatus {
+    _active: Bool,
+    /// Whether it is on now, by hand or by its schedule.
+    enabled: Bool,
+    _sun_schedule_permitted: Bool,
+    mode: i32,
+    from: Time,
+    to: Time,
+    _disable_flags: u64,
+    available: Bool,
+    /// Room for what later releases may add.
+    _reserved: [u64; 4],
+}
+
+impl Time {
+    fn minutes(&self) -> u32 {
+        (self.hour.clamp(0, 23) * 60 + self.minute.clamp(0, 59)) as u32
+    }
+}
+
+fn client_class() -> Option<&'static AnyClass> {
+    static LOADED: OnceLock<bool> = OnceLock::new();
+    let loaded = *LOADED.get_or_init(|| {
+        NSBundle::bundleWithPath(&NSString::from_str(FRAMEWORK))
+            // SAFETY: loading a system framework runs no code of ours
+            .is_some_and(|bundle| unsafe { bundle.load() })
+    });
+    loaded
+        .then(|| AnyClass::get(c"CBBlueLightClient"))
+        .flatten()
+}
+
+/// Night Shift as it is now, None where the Mac has none.
+pub fn status() -> Option<Status> {
+    let class = client_class()?;
+    // SAFETY: `new` returns a retained instance of the class
+    let client: Retained<AnyObject> = unsafe { msg_send![class, new] };
+    // SAFETY: zeroes are valid for all the fields
+    let mut raw: RawStatus = unsafe { std::mem::zeroed() };
+    // The method takes a pointer to a struct, which `msg_send!` would check
+    // against an encoding we do not know for sure
+    type GetStatus = unsafe extern "C-unwind" fn(*const AnyObject, Sel, *mut RawStatus) -> Bool;
+    // SAFETY: `objc_msgSend` is called with the signature of the method
+    let filled = unsafe {
+        let get: GetStatus =
+            std::mem::transmute(objc2::ffi::objc_msgSend as unsafe extern "C-unwind" fn());
+        get(
+            Retained::as_ptr(&client),
+            sel!(getBlueLightStatus:),
+            &mut raw,
+        )
+    };
+    if !filled.as_bool() || !raw.available.as_bool() {
+        return None;
+    }
+    let mode = match raw.mode {
+        1 => Mode::SunsetToSunrise,
+        2 => Mode::Custom(raw.from.minutes(), raw.to.minutes()),
+        _ => Mode::Off,
+    };
+    Some(Status {
+        active: raw.enabled.as_bool(),
+        mode,
+    })
+}
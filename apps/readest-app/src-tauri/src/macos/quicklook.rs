@@ -0,0 +1,206 @@
+//! Quick Look previews of EPUB files in Finder, showing the cover and
+//! metadata of the book as a small HTML page. The page is built here, from
+//! the same EPUB reader the library imports with, and served by a data-based
+//! preview extension bundled in the app's `PlugIns`, whose binary runs
+//! [`run_extension`]. `scripts/build-quicklook.sh` builds and signs it.
+
+use std::ffi::{c_char, c_int};
+use std::fmt::Write;
+use std::path::Path;
+use std::ptr::NonNull;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use block2::{DynBlock, RcBlock};
+use objc2::rc::Retained;
+use objc2::runtime::NSObjectProtocol;
+use objc2::{define_class, AllocAnyThread, ClassType};
+use objc2_core_foundation::CGSize;
+use objc2_foundation::{NSData, NSError, NSString};
+use objc2_quick_look_ui::{
+    QLFilePreviewRequest, QLPreviewProvider, QLPreviewReply, QLPreviewingController,
+};
+use objc2_uniform_type_identifiers::UTTypeHTML;
+
+use crate::error::Result;
+use crate::formats::encode_thumbnail;
+use crate::formats::epub::{EpubArchive, Metadata};
+use crate::formats::html::html_to_text;
+use crate::utils::escape_xml;
+
+/// Largest side of the cover in the preview.
+const COVER_SIZE: u32 = 512;
+/// Size Quick Look shows the preview at until it is rendered.
+const PREVIEW_SIZE: CGSize = CGSize {
+    width: 720.0,
+    height: 480.0,
+};
+const ERROR_DOMAIN: &str = "com.vlarch.quicklook";
+
+const STYLE: &str = "\
+:root { color-scheme: light dark; font: 13px -apple-system, sans-serif; }
+body { display: flex; gap: 24px; margin: 24px; }
+img { max-width: 40%; max-height: 90vh; align-self: flex-start; box-shadow: 0 2px 8px #0004; }
+h1 { margin: 0 0 4px; font-size: 22px; }
+.authors { margin: 0 0 16px; font-size: 15px; opacity: .8; }
+dl { display: grid; grid-template-columns: max-content auto; gap: 4px 12px; margin: 0 0 16px; }
+dt { opacity: .6; }
+dd { margin: 0; }
+.description { white-space: pre-line; line-height: 1.4; }
+";
+
+/// The cover of the book as a JPEG `data:` URL.
+fn cover_url(
+    epub: &mut EpubArchive<impl std::io::Read + std::io::Seek>,
+    href: &str,
+) -> Option<String> {
+    let data = epub.read_entry(href).ok()?;
+    let image = image::load_from_memory(&data).ok()?;
+    let jpeg = encode_thumbnail(&image, COVER_SIZE).ok()?;
+    Some(format!("data:image/jpeg;base64,{}", BASE64.encode(jpeg)))
+}
+
+fn details(metadata: &Metadata) -> Vec<(&'static str, String)> {
+    let mut details = Vec::new();
+    if let Some(series) = &metadata.series {
+        let series = match metadata.series_index {
+            Some(index) => format!("{series} #{index}"),
+            None => series.clone(),
+        };
+        details.push(("Series", series));
+    }
+    if let Some(publisher) = &metadata.publisher {
+        details.push(("Publisher", publisher.clone()));
+    }
+    if let Some(published) = &metadata.published {
+        details.push(("Published", published.clone()));
+    }
+    if !metadata.language.is_empty() {
+        details.push(("Language", metadata.language.join(", ")));
+    }
+    if !metadata.subjects.is_empty() {
+        details.push(("Subjects", metadata.subjects.join(", ")));
+    }
+    details
+}
+
+/// The preview page of the EPUB at `path`.
+fn preview(path: &Path) -> Result<String> {
+    let mut epub = EpubArchive::open(path)?;
+    let package = epub.package()?;
+    let metadata = &package.metadata;
+    let cover = package
+        .cover()
+        .and_then(|item| cover_url(&mut epub, &item.href));
+    let title = match metadata.title.trim() {
+        "" => path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        title => title.to_string(),
+    };
+
+    let mut html = format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{}</title><style>{STYLE}</style></head><body>",
+        escape_xml(&title)
+    );
+    if let Some(cover) = cover {
+        let _ = write!(html, "<img src=\"{cover}\" alt=\"\">");
+    }
+    let _ = write!(html, "<main><h1>{}</h1>", escape_xml(&title));
+    if !metadata.authors.is_empty() {
+        let _ = write!(
+            html,
+            "<p class=\"authors\">{}</p>",
+            escape_xml(&metadata.authors.join(", "))
+        );
+    }
+    let details = details(metadata);
+    if !details.is_empty() {
+        html.push_str("<dl>");
+        for (name, value) in details {
+            let _ = write!(html, "<dt>{name}</dt><dd>{}</dd>", escape_xml(&value));
+        }
+        html.push_str("</dl>");
+    }
+    if let Some(description) = &metadata.description {
+        // Descriptions are often markup themselves
+        let _ = write!(
+            html,
+            "<p class=\"description\">{}</p>",
+            escape_xml(html_to_text(description).trim())
+        );
+    }
+    html.push_str("</main></body></html>");
+    Ok(html)
+}
+
+define_class!(
+    /// The principal class of the extension, named in its `Info.plist`.
+    #[unsafe(super(QLPreviewProvider))]
+    #[name = "VLArchPreviewProvider"]
+    struct PreviewProvider;
+
+    unsafe impl NSObjectProtocol for PreviewProvider {}
+
+    unsafe impl QLPreviewingController for PreviewProvider {
+        #[unsafe(method(providePreviewForFileRequest:completionHandler:))]
+        fn provide_preview(
+            &self,
+            request: &QLFilePreviewRequest,
+            handler: &DynBlock<dyn Fn(*mut QLPreviewReply, *mut NSError)>,
+        ) {
+            let Some(path) = (unsafe { request.fileURL() }).path() else {
+                handler.call((std::ptr::null_mut(), error_ptr()));
+                return;
+            };
+            let path = path.to_string();
+            let create = RcBlock::new(
+                move |_reply: NonNull<QLPreviewReply>, error: *mut *mut NSError| match preview(
+                    Path::new(&path),
+                ) {
+                    Ok(html) => Retained::autorelease_ptr(NSData::with_bytes(html.as_bytes())),
+                    Err(_) => {
+                        if let Some(error) = unsafe { error.as_mut() } {
+                            *error = error_ptr();
+                        }
+                        std::ptr::null_mut()
+                    }
+                },
+            );
+            let reply = unsafe {
+                QLPreviewReply::initWithDataOfContentType_contentSize_dataCreationBlock(
+                    QLPreviewReply::alloc(),
+                    UTTypeHTML,
+                    PREVIEW_SIZE,
+                    &create,
+                )
+            };
+            handler.call((Retained::as_ptr(&reply).cast_mut(), std::ptr::null_mut()));
+        }
+    }
+);
+
+/// An autoreleased error, for which Quick Look shows its generic preview.
+fn error_ptr() -> *mut NSError {
+    Retained::autorelease_ptr(NSError::new(0, &NSString::from_str(ERROR_DOMAIN)))
+}
+
+extern "C" {
+    fn NSExtensionMain(argc: c_int, argv: *mut *mut c_char) -> c_int;
+}
+
+/// Runs the preview extension; the `main` of its binary.
+pub fn run_extension() -> i32 {
+    // Registers the class, so the extension finds its principal class
+    let _ = PreviewProvider::class();
+    let args = std::env::args()
+        .filter_map(|arg| std::ffi::CString::new(arg).ok())
+        .collect::<Vec<_>>();
+    let mut argv = args
+        .iter()
+        .map(|arg| arg.as_ptr().cast_mut())
+        .chain(std::iter::once(std::ptr::null_mut()))
+        .collect::<Vec<_>>();
+    unsafe { NSExtensionMain(args.len() as c_int, argv.as_mut_ptr()) }
+}
@@ -0,0 +1,130 @@
+//! The app's entries in the Services menu of other apps, declared under
+//! `NSServices` in `Info.plist`: "Add to VL-Arch Library" imports the
+//! selected book files, or clips the selected web page, and opens the book,
+//! and "Look Up in VL-Arch Dictionary" looks the selected text up, as the
+//! `vlarch://clip` and `vlarch://lookup` links do.
+
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use objc2::rc::Retained;
+use objc2::runtime::{AnyObject, NSObject};
+use objc2::{class, define_class, msg_send, AllocAnyThread};
+use objc2_foundation::{NSArray, NSString};
+use tauri::{AppHandle, Url};
+
+use crate::deep_link::{self, DeepLink};
+use crate::formats::is_book_file;
+use crate::library::import::import_book;
+
+/// Longest selection looked up; longer ones are not a word or phrase.
+const MAX_LOOKUP_LEN: usize = 200;
+
+static APP: OnceLock<AppHandle> = OnceLock::new();
+
+define_class!(
+    /// The services provider of the app, whose methods are named by the
+    /// `NSMessage` of each service.
+    #[unsafe(super(NSObject))]
+    #[name = "VLArchServicesProvider"]
+    struct ServicesProvider;
+
+    impl ServicesProvider {
+        #[unsafe(method(addToLibrary:userData:error:))]
+        fn add_to_library(
+            &self,
+            pasteboard: &AnyObject,
+            _user_data: *mut AnyObject,
+            _error: *mut *mut AnyObject,
+        ) {
+            if let Some(app) = APP.get() {
+                add_to_library(app, strings(pasteboard, "public.file-url"), strings(pasteboard, "public.url"));
+            }
+        }
+
+        #[unsafe(method(lookUpInDictionary:userData:error:))]
+        fn look_up_in_dictionary(
+            &self,
+            pasteboard: &AnyObject,
+            _user_data: *mut AnyObject,
+            _error: *mut *mut AnyObject,
+        ) {
+            let Some(app) = APP.get() else {
+                return;
+            };
+            let word = strings(pasteboard, "public.utf8-plain-text")
+                .into_iter()
+                .next()
+                .map(|text| text.split_whitespace().collect::<Vec<_>>().join(" "))
+                .filter(|word| !word.is_empty() && word.chars().count() <= MAX_LOOKUP_LEN);
+            if let Some(word) = word {
+                deep_link::dispatch(app, vec![DeepLink::Lookup { word }]);
+            }
+        }
+    }
+);
+
+/// The values of `kind` on the items of `pasteboard`.
+fn strings(pasteboard: &AnyObject, kind: &str) -> Vec<String> {
+    let kind = NSString::from_str(kind);
+    let items: Option<Retained<NSArray<AnyObject>>> =
+        unsafe { msg_send![pasteboard, pasteboardItems] };
+    items
+        .iter()
+        .flat_map(|items| items.iter())
+        .filter_map(|item| {
+            let value: Option<Retained<NSString>> =
+                unsafe { msg_send![&*item, stringForType: &*kind] };
+            value.map(|value| value.to_string())
+        })
+        .collect()
+}
+
+/// Imports the books among `files`, or clips the first web page of `urls`
+/// when there are none, and opens the first book.
+fn add_to_library(app: &AppHandle, files: Vec<String>, urls: Vec<String>) {
+    let paths = files
+        .iter()
+        .filter_map(|file| Url::parse(file).ok()?.to_file_path().ok())
+        .filter(|path| is_book_file(path))
+        .collect::<Vec<PathBuf>>();
+    if paths.is_empty() {
+        let page = urls
+            .into_iter()
+            .find(|url| url.starts_with("https://") || url.starts_with("http://"));
+        if let Some(url) = page {
+            deep_link::dispatch(app, vec![DeepLink::Clip { url }]);
+        }
+        return;
+    }
+    let app = app.clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        let mut first = None;
+        for path in paths {
+            match import_book(&app, &path) {
+                Ok(book) => {
+                    first.get_or_insert(book.hash);
+                }
+                Err(e) => log::warn!("Failed to import {path:?} from the Services menu: {e}"),
+            }
+        }
+        if let Some(book) = first {
+            deep_link::dispatch(&app, vec![DeepLink::Open { book, cfi: None }]);
+        }
+    });
+}
+
+/// Makes the app the provider of its services. Has to run on the main
+/// thread, as in `setup`.
+pub fn init(app: &AppHandle) {
+    if APP.set(app.clone()).is_err() {
+        return;
+    }
+    let provider: Retained<ServicesProvider> =
+        unsafe { msg_send![ServicesProvider::alloc(), init] };
+    unsafe {
+        let application: *mut AnyObject = msg_send![class!(NSApplication), sharedApplication];
+        // Kept by the application
+        let _: () = msg_send![application, setServicesProvider: &*provider];
+    }
+}
@@ -0,0 +1,196 @@
+//! Library books in Spotlight, as Core Spotlight items identified by their
+//! `vlarch://open` links. Picking one continues a `CSSearchableItemActionType`
+//! user activity, which the app delegate from tao does not know about, so
+//! its `application:continueUserActivity:restorationHandler:` is replaced
+//! with one that opens the link and leaves other activities to the original.
+
+use std::sync::OnceLock;
+
+use block2::RcBlock;
+use objc2::rc::Retained;
+use objc2::runtime::{AnyObject, Bool, Imp, Sel};
+use objc2::{class, msg_send, sel, AllocAnyThread, Message};
+use objc2_core_spotlight::{
+    CSSearchableIndex, CSSearchableItem, CSSearchableItemActionType,
+    CSSearchableItemActivityIdentifier, CSSearchableItemAttributeSet,
+};
+use objc2_foundation::{NSArray, NSError, NSString, NSUserActivity, NSURL};
+use objc2_uniform_type_identifiers::{UTType, UTTypeContent};
+use tauri::{AppHandle, Url};
+
+use crate::deep_link::{self, DeepLink};
+use crate::error::Result;
+use crate::system_search::{link, Entry};
+
+/// Groups the items of the library, so they can be removed together.
+const DOMAIN: &str = "library";
+
+static APP: OnceLock<AppHandle> = OnceLock::new();
+/// The delegate's own implementation, called for other activities.
+static CONTINUE_USER_ACTIVITY: OnceLock<Imp> = OnceLock::new();
+
+type ContinueUserActivity = unsafe extern "C-unwind" fn(
+    &AnyObject,
+    Sel,
+    *mut AnyObject,
+    &NSUserActivity,
+    *mut AnyObject,
+) -> Bool;
+
+/// The link of the Spotlight item `activity` was started from.
+fn spotlight_link(activity: &NSUserActivity) -> Option<DeepLink> {
+    if !activity
+        .activityType()
+        .isEqualToString(unsafe { CSSearchableItemActionType })
+    {
+        return None;
+    }
+    let info = activity.userInfo()?;
+    let identifier = unsafe { info.objectForKey(CSSearchableItemActivityIdentifier.as_ref()) }?;
+    let identifier = identifier.downcast::<NSString>().ok()?;
+    DeepLink::parse(&Url::parse(&identifier.to_string()).ok()?)
+}
+
+unsafe extern "C-unwind" fn continue_user_activity(
+    this: &AnyObject,
+    sel: Sel,
+    application: *mut AnyObject,
+    activity: &NSUserActivity,
+    restoration_handler: *mut AnyObject,
+) -> Bool {
+    if let Some(link) = spotlight_link(activity) {
+        if let Some(app) = APP.get() {
+            deep_link::dispatch(app, vec![link]);
+        }
+        return Bool::YES;
+    }
+    match CONTINUE_USER_ACTIVITY.get() {
+        Some(original) => {
+            let original = unsafe { std::mem::transmute::<Imp, ContinueUserActivity>(*original) };
+            unsafe { original(this, sel, application, activity, restoration_handler) }
+        }
+        None => Bool::NO,
+    }
+}
+
+/// Routes picked Spotlight results to the app. Has to run on the main
+/// thread once the app delegate is set, as in `setup`.
+pub fn init(app: &AppHandle) {
+    if APP.set(app.clone()).is_err() {
+        return;
+    }
+    let sel = sel!(application:continueUserActivity:restorationHandler:);
+    let delegate = unsafe {
+        let application: *mut AnyObject = msg_send![class!(NSApplication), sharedApplication];
+        let delegate: *mut AnyObject = msg_send![application, delegate];
+        delegate.as_ref()
+    };
+    let Some(method) = delegate.and_then(|delegate| delegate.class().instance_method(sel)) else {
+        log::warn!("The app delegate does not continue user activities");
+        return;
+    };
+    let original = unsafe {
+        method.set_implementation(std::mem::transmute::<ContinueUserActivity, Imp>(
+            continue_user_activity,
+        ))
+    };
+    let _ = CONTINUE_USER_ACTIVITY.set(original);
+}
+
+fn strings(values: &[&str]) -> Retained<NSArray<NSString>> {
+    let values = values
+        .iter()
+        .map(|value| NSString::from_str(value))
+        .collect::<Vec<_>>();
+    NSArray::from_retained_slice(&values)
+}
+
+fn item(entry: &Entry) -> Retained<CSSearchableItem> {
+    let content_type = UTType::typeWithFilenameExtension(&NSString::from_str(&entry.format))
+        .unwrap_or_else(|| unsafe { UTTypeContent }.retain());
+    unsafe {
+        let attributes = CSSearchableItemAttributeSet::initWithContentType(
+            CSSearchableItemAttributeSet::alloc(),
+            &content_type,
+        );
+        attributes.setTitle(Some(&NSString::from_str(&entry.title)));
+        attributes.setDisplayName(Some(&NSString::from_str(&entry.title)));
+        if !entry.author.is_empty() {
+            attributes.setAuthorNames(Some(&strings(&[&entry.author])));
+            attributes.setContentDescription(Some(&NSString::from_str(&entry.author)));
+        }
+        let tags = entry.tags.iter().map(String::as_str).collect::<Vec<_>>();
+        attributes.setKeywords(Some(&strings(&tags)));
+        if let Some(cover) = &entry.cover {
+            let path = NSString::from_str(&cover.to_string_lossy());
+            attributes.setThumbnailURL(Some(&NSURL::fileURLWithPath(&path)));
+        }
+        CSSearchableItem::initWithUniqueIdentifier_domainIdentifier_attributeSet(
+            CSSearchableItem::alloc(),
+            Some(&NSString::from_str(&link(&entry.hash))),
+            Some(&NSString::from_str(DOMAIN)),
+            &attributes,
+        )
+    }
+}
+
+/// Logs the error Core Spotlight reports once it is done.
+fn completion(what: &'static str) -> RcBlock<dyn Fn(*mut NSError)> {
+    RcBlock::new(move |error: *mut NSError| {
+        if let Some(error) = unsafe { error.as_ref() } {
+            log::warn!(
+                "Failed to {what} Spotlight items: {}",
+                error.localizedDescription()
+            );
+        }
+    })
+}
+
+fn index() -> Option<Retained<CSSearchableIndex>> {
+    unsafe { CSSearchableIndex::isIndexingAvailable() }
+        .then(|| unsafe { CSSearchableIndex::defaultSearchableIndex() })
+}
+
+/// Adds `entries` to Spotlight, replacing the items they had.
+pub fn publish(_app: &AppHandle, entries: &[Entry]) -> Result<()> {
+    let Some(index) = index() else {
+        return Ok(());
+    };
+    let items = entries.iter().map(item).collect::<Vec<_>>();
+    unsafe {
+        index.indexSearchableItems_completionHandler(
+            &NSArray::from_retained_slice(&items),
+            Some(&completion("index")),
+        );
+    }
+    Ok(())
+}
+
+pub fn remove(_app: &AppHandle, hashes: &[String]) -> Result<()> {
+    let Some(index) = index() else {
+        return Ok(());
+    };
+    let links = hashes.iter().map(|hash| link(hash)).collect::<Vec<_>>();
+    let links = links.iter().map(String::as_str).collect::<Vec<_>>();
+    unsafe {
+        index.deleteSearchableItemsWithIdentifiers_completionHandler(
+            &strings(&links),
+            Some(&completion("remove")),
+        );
+    }
+    Ok(())
+}
+
+/// Removes every item of the library from Spotlight.
+pub fn clear(_app: &AppHandle) -> Result<()> {
+    let Some(index) = index() else {
+        return Ok(());
+    };
+    unsafe {
+        index.deleteSearchableItemsWithDomainIdentifiers_completionHandler(
+            &strings(&[DOMAIN]),
+            Some(&completion("remove")),
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,173 @@
+//! Reading controls on the Touch Bar while a book is open: previous and
+//! next chapter and page, and text-to-speech playback. They reach the
+//! frontend as `touch-bar-action` events with the same actions as the
+//! global shortcuts. They can be turned off in the settings.
+
+use std::cell::OnceCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+use objc2::rc::{Allocated, Retained};
+use objc2::runtime::{AnyObject, NSObject, Sel};
+use objc2::{class, define_class, msg_send, sel, MainThreadOnly};
+use objc2_foundation::{MainThreadMarker, NSArray, NSString};
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle, Emitter, Manager};
+
+use crate::error::Result;
+use crate::shortcuts::ShortcutAction;
+use crate::store;
+
+const SETTINGS_FILE: &str = "touch-bar.json";
+pub const EVENT: &str = "touch-bar-action";
+
+/// The controls from left to right, with their item identifiers and the
+/// system images they show.
+const CONTROLS: &[(ShortcutAction, &str, &str)] = &[
+    (
+        ShortcutAction::PreviousChapter,
+        "com.vlarch.touchbar.previous-chapter",
+        "NSTouchBarSkipBackTemplate",
+    ),
+    (
+        ShortcutAction::PreviousPage,
+        "com.vlarch.touchbar.previous-page",
+        "NSTouchBarGoBackTemplate",
+    ),
+    (
+        ShortcutAction::ToggleTts,
+        "com.vlarch.touchbar.toggle-tts",
+        "NSTouchBarPlayPauseTemplate",
+    ),
+    (
+        ShortcutAction::NextPage,
+        "com.vlarch.touchbar.next-page",
+        "NSTouchBarGoForwardTemplate",
+    ),
+    (
+        ShortcutAction::NextChapter,
+        "com.vlarch.touchbar.next-chapter",
+        "NSTouchBarSkipAheadTemplate",
+    ),
+];
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TouchBarSettings {
+    pub enabled: bool,
+}
+
+impl Default for TouchBarSettings {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+static APP: OnceLock<AppHandle> = OnceLock::new();
+static READING: AtomicBool = AtomicBool::new(false);
+
+define_class!(
+    /// The target of the buttons, which tell the actions apart by their tag.
+    #[unsafe(super(NSObject))]
+    #[thread_kind = MainThreadOnly]
+    #[name = "VLArchTouchBarTarget"]
+    struct Target;
+
+    impl Target {
+        #[unsafe(method(touchBarAction:))]
+        fn touch_bar_action(&self, sender: &AnyObject) {
+            let tag: isize = unsafe { msg_send![sender, tag] };
+            let action = usize::try_from(tag).ok().and_then(|i| CONTROLS.get(i));
+            if let (Some(app), Some((action, _, _))) = (APP.get(), action) {
+                let _ = app.emit(EVENT, action);
+            }
+        }
+    }
+);
+
+thread_local! {
+    // Buttons only keep a weak reference to their target
+    static TARGET: OnceCell<Retained<Target>> = const { OnceCell::new() };
+}
+
+fn action() -> Sel {
+    sel!(touchBarAction:)
+}
+
+/// A new Touch Bar with the reading controls.
+fn build_touch_bar(mtm: MainThreadMarker) -> Retained<AnyObject> {
+    let target = TARGET.with(|target| {
+        target
+            .get_or_init(|| unsafe { msg_send![Target::alloc(mtm), init] })
+            .clone()
+    });
+    unsafe {
+        let mut identifiers = Vec::new();
+        let mut items = Vec::new();
+        for (tag, (_, identifier, image)) in CONTROLS.iter().enumerate() {
+            let identifier = NSString::from_str(identifier);
+            let image: Option<Retained<AnyObject>> =
+                msg_send![class!(NSImage), imageNamed: &*NSString::from_str(image)];
+            let button: Retained<AnyObject> = msg_send![
+                class!(NSButton),
+                buttonWithImage: image.as_deref(),
+                target: &*target,
+                action: action()
+            ];
+            let _: () = msg_send![&*button, setTag: tag as isize];
+            let item: Allocated<AnyObject> = msg_send![class!(NSCustomTouchBarItem), alloc];
+            let item: Retained<AnyObject> = msg_send![item, initWithIdentifier: &*identifier];
+            let _: () = msg_send![&*item, setView: &*button];
+            identifiers.push(identifier);
+            items.push(item);
+        }
+        let bar: Retained<AnyObject> = msg_send![class!(NSTouchBar), new];
+        let items = NSArray::from_retained_slice(&items);
+        let items: Retained<AnyObject> = msg_send![class!(NSSet), setWithArray: &*items];
+        let _: () = msg_send![&*bar, setTemplateItems: &*items];
+        let identifiers = NSArray::from_retained_slice(&identifiers);
+        let _: () = msg_send![&*bar, setDefaultItemIdentifiers: &*identifiers];
+        bar
+    }
+}
+
+/// Shows the controls on the Touch Bar of the main window while a book is
+/// open and they are enabled, and removes them otherwise.
+fn refresh(app: &AppHandle) -> Result<()> {
+    let settings: TouchBarSettings = store::load(app, SETTINGS_FILE);
+    let show = settings.enabled && READING.load(Ordering::Relaxed);
+    let Some(window) = app.get_webview_window("main") else {
+        return Ok(());
+    };
+    app.run_on_main_thread(move || {
+        let Ok(ns_window) = window.ns_window() else {
+            return;
+        };
+        let Some(mtm) = MainThreadMarker::new() else {
+            return;
+        };
+        let bar = show.then(|| build_touch_bar(mtm));
+        unsafe {
+            let _: () = msg_send![ns_window.cast::<AnyObject>(), setTouchBar: bar.as_deref()];
+        }
+    })?;
+    Ok(())
+}
+
+/// Shows or removes the controls as the reader opens or closes a book.
+pub fn set_reading(app: &AppHandle, reading: bool) -> Result<()> {
+    let _ = APP.set(app.clone());
+    READING.store(reading, Ordering::Relaxed);
+    refresh(app)
+}
+
+#[command]
+pub fn get_touch_bar_settings(app: AppHandle) -> TouchBarSettings {
+    store::load(&app, SETTINGS_FILE)
+}
+
+#[command]
+pub fn set_touch_bar_settings(app: AppHandle, settings: TouchBarSettings) -> Result<()> {
+    store::save(&app, SETTINGS_FILE, &settings)?;
+    refresh(&app)
+}
@@ -1,6 +1,37 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+/// The directory of `--data-dir <path>` or `--data-dir=<path>`, under which
+/// the app keeps all its files as in portable mode.
+#[cfg(desktop)]
+fn data_dir_arg() -> Option<std::path::PathBuf> {
+    let prefix = format!("{}=", vlarchlib::DATA_DIR_ARG);
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == vlarchlib::DATA_DIR_ARG {
+            return args.next().map(Into::into);
+        }
+        if let Some(path) = arg.strip_prefix(&prefix) {
+            return Some(path.into());
+        }
+    }
+    None
+}
+
 fn main() {
+    #[cfg(desktop)]
+    if let Some(code) = vlarchlib::run_crash_monitor() {
+        std::process::exit(code);
+    }
+    #[cfg(desktop)]
+    if let Some(code) = vlarchlib::run_bench_open() {
+        std::process::exit(code);
+    }
+    #[cfg(desktop)]
+    vlarchlib::set_data_dir(data_dir_arg());
+    #[cfg(all(desktop, feature = "cli"))]
+    if let Some(code) = vlarchlib::cli::run() {
+        std::process::exit(code);
+    }
     vlarchlib::run();
 }
@@ -0,0 +1,154 @@
+//! System media controls while text-to-speech is playing: an MPRIS player on
+//! Linux and Now Playing on macOS, showing the chapter and book being read.
+//! Media keys and the system player widget send `media-control` events,
+//! since the frontend decides what is spoken next. On Windows the speech
+//! engine's `MediaPlayer` already shows in the system media controls.
+
+use std::sync::Mutex;
+
+use serde::Serialize;
+use souvlaki::{MediaControlEvent, MediaControls, MediaMetadata, MediaPlayback, PlatformConfig};
+use tauri::{command, AppHandle, Emitter, Manager, Url};
+
+use crate::error::{Error, Result};
+use crate::library;
+use crate::tts::Playback;
+
+pub const EVENT: &str = "media-control";
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MediaAction {
+    Play,
+    Pause,
+    Toggle,
+    Next,
+    Previous,
+    Stop,
+}
+
+#[derive(Debug, Default)]
+struct NowPlaying {
+    /// The chapter, or the book when there is no chapter.
+    title: String,
+    book_title: Option<String>,
+    author: Option<String>,
+    cover_url: Option<String>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Present while speech is playing or paused, so the player disappears
+    /// from the system when it stops.
+    controls: Option<MediaControls>,
+    now_playing: NowPlaying,
+}
+
+#[derive(Default)]
+pub struct MediaSession(Mutex<Inner>);
+
+fn media_error(e: souvlaki::Error) -> Error {
+    Error::MediaControls(e.to_string())
+}
+
+fn handle_event(app: &AppHandle, event: MediaControlEvent) {
+    let action = match event {
+        MediaControlEvent::Play => MediaAction::Play,
+        MediaControlEvent::Pause => MediaAction::Pause,
+        MediaControlEvent::Toggle => MediaAction::Toggle,
+        MediaControlEvent::Next => MediaAction::Next,
+        MediaControlEvent::Previous => MediaAction::Previous,
+        MediaControlEvent::Stop => MediaAction::Stop,
+        MediaControlEvent::Raise => return crate::show_main_window(app),
+        _ => return,
+    };
+    let _ = app.emit(EVENT, action);
+}
+
+fn metadata(now_playing: &NowPlaying) -> MediaMetadata<'_> {
+    MediaMetadata {
+        title: Some(now_playing.title.as_str()).filter(|title| !title.is_empty()),
+        album: now_playing.book_title.as_deref(),
+        artist: now_playing.author.as_deref(),
+        cover_url: now_playing.cover_url.as_deref(),
+        duration: None,
+    }
+}
+
+fn update(app: &AppHandle, playback: Playback) -> Result<()> {
+    let session = app.state::<MediaSession>();
+    let mut inner = session.0.lock().unwrap();
+    let Inner {
+        controls,
+        now_playing,
+    } = &mut *inner;
+    let playback = match playback {
+        Playback::Playing => MediaPlayback::Playing { progress: None },
+        Playback::Paused => MediaPlayback::Paused { progress: None },
+        Playback::Stopped => {
+            // Dropping the controls detaches them
+            *controls = None;
+            return Ok(());
+        }
+    };
+    if controls.is_none() {
+        let mut created = MediaControls::new(PlatformConfig {
+            dbus_name: "vlarch",
+            display_name: "VL-Arch",
+            hwnd: None,
+        })
+        .map_err(media_error)?;
+        let app = app.clone();
+        created
+            .attach(move |event| handle_event(&app, event))
+            .map_err(media_error)?;
+        created
+            .set_metadata(metadata(now_playing))
+            .map_err(media_error)?;
+        *controls = Some(created);
+    }
+    if let Some(controls) = controls {
+        controls.set_playback(playback).map_err(media_error)?;
+    }
+    Ok(())
+}
+
+/// Shows `playback` in the system media controls, which are created on the
+/// first utterance and removed when speech stops.
+pub fn set_playback(app: &AppHandle, playback: Playback) {
+    if let Err(e) = update(app, playback) {
+        log::warn!("Failed to update media controls: {e}");
+    }
+}
+
+/// Sets what the media controls show as playing. The book's cover is used
+/// as artwork when `book_hash` is given.
+#[command]
+pub fn set_now_playing(
+    app: AppHandle,
+    book_hash: Option<String>,
+    book_title: Option<String>,
+    author: Option<String>,
+    chapter: Option<String>,
+) -> Result<()> {
+    let cover_url = book_hash
+        .and_then(|hash| library::cover_path(&app, &hash))
+        .and_then(|path| Url::from_file_path(path).ok())
+        .map(String::from);
+    let now_playing = NowPlaying {
+        title: chapter.or_else(|| book_title.clone()).unwrap_or_default(),
+        book_title,
+        author,
+        cover_url,
+    };
+
+    let session = app.state::<MediaSession>();
+    let mut inner = session.0.lock().unwrap();
+    if let Some(controls) = inner.controls.as_mut() {
+        controls
+            .set_metadata(metadata(&now_playing))
+            .map_err(media_error)?;
+    }
+    inner.now_playing = now_playing;
+    Ok(())
+}
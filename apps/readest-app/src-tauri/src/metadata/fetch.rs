@@ -0,0 +1,900 @@
+//! Looks library books up on Open Library and Google Books, by ISBN when
+//! the book has one and by title and author otherwise, and proposes the
+//! metadata and covers found. Nothing changes until the user approves the
+//! fields to take with [`apply_metadata`].
+//!
+//! Each service is asked at most once per interval. Books looked up while
+//! offline are queued and looked up again by the `metadata` scheduled
+//! task, and proposals wait in the queue file until they are applied or
+//! dismissed.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use regex::Regex;
+use reqwest::{RequestBuilder, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tauri::{command, AppHandle, Emitter, Manager, State};
+
+use crate::error::{Error, Result};
+use crate::jobs::{self, Job};
+use crate::library::identifiers::{self, normalize_isbn, Scheme};
+use crate::library::{self, db};
+use crate::net;
+use crate::plugins;
+use crate::store;
+use crate::utils::{now_millis, similarity, title_words, words};
+
+const QUEUE_FILE: &str = "metadata-queue.json";
+const PROPOSALS_EVENT: &str = "metadata-proposals";
+const OPEN_LIBRARY_URL: &str = "https://openlibrary.org";
+const OPEN_LIBRARY_FIELDS: &str = "key,title,subtitle,author_name,publisher,first_publish_year,\
+    subject,language,cover_i,cover_edition_key,isbn";
+const GOOGLE_BOOKS_URL: &str = "https://www.googleapis.com/books/v1/volumes";
+const MAX_RESULTS: &str = "5";
+/// Open Library lists hundreds of subjects for popular books.
+const MAX_SUBJECTS: usize = 10;
+/// Results found by title and author less alike than this are left out.
+const MIN_SCORE: f64 = 0.5;
+
+/// Spaces out the requests to one service.
+struct RateLimiter {
+    interval: Duration,
+    next: Mutex<Option<Instant>>,
+}
+
+impl RateLimiter {
+    const fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next: Mutex::new(None),
+        }
+    }
+
+    /// Waits for the next free slot and takes it.
+    async fn wait(&self) {
+        let delay = {
+            let mut next = self.next.lock().unwrap();
+            let now = Instant::now();
+            let at = next.map_or(now, |next| next.max(now));
+            *next = Some(at + self.interval);
+            at - now
+        };
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+/// Open Library asks clients to keep to about one request a second.
+static OPEN_LIBRARY: RateLimiter = RateLimiter::new(Duration::from_secs(1));
+static GOOGLE_BOOKS: RateLimiter = RateLimiter::new(Duration::from_millis(500));
+
+/// The metadata fields that can be looked up, each `None` when unknown.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Fields {
+    pub title: Option<String>,
+    pub subtitle: Option<String>,
+    /// Authors joined with commas, as the library keeps them.
+    pub author: Option<String>,
+    pub publisher: Option<String>,
+    pub published: Option<String>,
+    pub description: Option<String>,
+    pub subjects: Option<Vec<String>>,
+    pub isbn: Option<String>,
+    pub language: Option<String>,
+    pub series: Option<String>,
+    pub series_index: Option<f32>,
+}
+
+impl Fields {
+    /// `self` with the fields it lacks taken from `other`.
+    fn or(self, other: &Fields) -> Fields {
+        Fields {
+            title: self.title.or_else(|| other.title.clone()),
+            subtitle: self.subtitle.or_else(|| other.subtitle.clone()),
+            author: self.author.or_else(|| other.author.clone()),
+            publisher: self.publisher.or_else(|| other.publisher.clone()),
+            published: self.published.or_else(|| other.published.clone()),
+            description: self.description.or_else(|| other.description.clone()),
+            subjects: self.subjects.or_else(|| other.subjects.clone()),
+            isbn: self.isbn.or_else(|| other.isbn.clone()),
+            language: self.language.or_else(|| other.language.clone()),
+            series: self.series.or_else(|| other.series.clone()),
+            series_index: self.series_index.or(other.series_index),
+        }
+    }
+
+    /// The fields of `self` that differ from `current`.
+    fn changes_from(self, current: &Fields) -> Fields {
+        fn changed<T: PartialEq>(value: Option<T>, current: &Option<T>) -> Option<T> {
+            value.filter(|value| Some(value) != current.as_ref())
+        }
+        Fields {
+            title: changed(self.title, &current.title),
+            subtitle: changed(self.subtitle, &current.subtitle),
+            author: changed(self.author, &current.author),
+            publisher: changed(self.publisher, &current.publisher),
+            published: changed(self.published, &current.published),
+            description: changed(self.description, &current.description),
+            subjects: changed(self.subjects, &current.subjects),
+            isbn: changed(self.isbn, &current.isbn),
+            language: changed(self.language, &current.language),
+            series: changed(self.series, &current.series),
+            series_index: changed(self.series_index, &current.series_index),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Source {
+    OpenLibrary,
+    GoogleBooks,
+    /// A metadata plugin, see [`crate::plugins`].
+    Plugin,
+}
+
+/// One book found by a service.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Candidate {
+    pub source: Source,
+    /// How alike the result is to the library book, from 0 to 1.
+    pub score: f64,
+    #[serde(flatten)]
+    pub fields: Fields,
+    pub cover_url: Option<String>,
+    /// The id of the plugin that found it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub plugin: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Proposal {
+    pub book_hash: String,
+    pub current: Fields,
+    /// The fields found that differ from `current`, each taken from the
+    /// best result that has it.
+    pub changes: Fields,
+    /// Cover images found, the best result first.
+    pub covers: Vec<String>,
+    pub candidates: Vec<Candidate>,
+    pub fetched_at: i64,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MetadataLookup {
+    pub proposals: Vec<Proposal>,
+    /// Books that could not be looked up while offline, queued for later.
+    pub queued: Vec<String>,
+    /// Error messages by book hash.
+    pub failed: BTreeMap<String, String>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct Queue {
+    /// Books to look up once back online.
+    pending: BTreeSet<String>,
+    /// Proposals waiting for the user, by book hash.
+    proposals: BTreeMap<String, Proposal>,
+}
+
+pub struct MetadataQueue(Mutex<Queue>);
+
+impl MetadataQueue {
+    fn update<T>(&self, app: &AppHandle, f: impl FnOnce(&mut Queue) -> T) -> Result<T> {
+        let mut queue = self.0.lock().unwrap();
+        let result = f(&mut queue);
+        store::save(app, QUEUE_FILE, &*queue)?;
+        Ok(result)
+    }
+}
+
+pub fn init(app: &AppHandle) {
+    app.manage(MetadataQueue(Mutex::new(store::load(app, QUEUE_FILE))));
+}
+
+/// Whether `error` means the services could not be reached, so the
+/// lookup is worth trying again later.
+fn is_offline(error: &Error) -> bool {
+    match error {
+        Error::Http(e) => e.is_connect() || e.is_timeout(),
+        Error::HttpStatus(status) => matches!(*status, 429 | 503),
+        _ => false,
+    }
+}
+
+/// The ISBN in the `identifier` of book metadata, which may as well be a
+/// UUID or an address.
+fn metadata_isbn(identifier: &str) -> Option<String> {
+    let identifier = identifier.trim().to_ascii_lowercase();
+    let isbn = identifier
+        .trim_start_matches("urn:")
+        .trim_start_matches("isbn:");
+    isbn.chars()
+        .all(|c| c.is_ascii_digit() || matches!(c, 'x' | '-' | ' '))
+        .then(|| normalize_isbn(isbn))
+        .flatten()
+}
+
+/// Splits an Open Library series such as `Foundation series ; 1` or
+/// `Discworld -- 12` into its name and the number of the book.
+fn parse_series(text: &str) -> (String, Option<f32>) {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    let pattern = PATTERN.get_or_init(|| {
+        Regex::new(r"(?i)^(.*?)[\s,;:#(-]+(?:book|volume|vol\.?|no\.?|#)?\s*(\d+(?:\.\d+)?)\)?$")
+            .expect("valid series pattern")
+    });
+    match pattern.captures(text.trim()) {
+        Some(captures) if !captures[1].trim().is_empty() => {
+            (captures[1].trim().to_string(), captures[2].parse().ok())
+        }
+        _ => (text.trim().to_string(), None),
+    }
+}
+
+/// ISO 639-1 code of a MARC language code, as Open Library uses.
+fn language_code(marc: &str) -> Option<String> {
+    let code = match marc {
+        "eng" => "en",
+        "fre" | "fra" => "fr",
+        "ger" | "deu" => "de",
+        "spa" => "es",
+        "ita" => "it",
+        "por" => "pt",
+        "rus" => "ru",
+        "jpn" => "ja",
+        "chi" | "zho" => "zh",
+        "kor" => "ko",
+        "dut" | "nld" => "nl",
+        "pol" => "pl",
+        "swe" => "sv",
+        "ara" => "ar",
+        "tur" => "tr",
+        _ => return None,
+    };
+    Some(code.to_string())
+}
+
+fn non_empty(items: Vec<String>) -> Option<Vec<String>> {
+    (!items.is_empty()).then_some(items)
+}
+
+fn join_authors(authors: Vec<String>) -> Option<String> {
+    (!authors.is_empty()).then(|| authors.join(", "))
+}
+
+/// Sends `request` once `limiter` allows and parses the JSON answer, or
+/// `None` when there is nothing at that address.
+async fn get_json<T: DeserializeOwned>(
+    limiter: &RateLimiter,
+    request: RequestBuilder,
+) -> Result<Option<T>> {
+    limiter.wait().await;
+    let response = request.send().await?;
+    match response.status() {
+        StatusCode::NOT_FOUND => Ok(None),
+        status if !status.is_success() => Err(Error::HttpStatus(status.as_u16())),
+        _ => Ok(Some(response.json().await?)),
+    }
+}
+
+/// What the library knows of a book to look it up by.
+struct Query {
+    isbn: Option<String>,
+    title: String,
+    author: String,
+}
+
+impl Query {
+    fn score(&self, fields: &Fields) -> f64 {
+        let found_title = fields.title.as_deref().unwrap_or_default();
+        let title = similarity(&title_words(&self.title), &title_words(found_title))
+            .max(similarity(&words(&self.title), &words(found_title)));
+        if self.author.trim().is_empty() {
+            return title;
+        }
+        let author = similarity(
+            &words(&self.author),
+            &words(fields.author.as_deref().unwrap_or_default()),
+        );
+        0.7 * title + 0.3 * author
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct SearchResults {
+    docs: Vec<SearchDoc>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct SearchDoc {
+    /// The work, as `/works/OL45804W`.
+    key: String,
+    title: Option<String>,
+    subtitle: Option<String>,
+    author_name: Vec<String>,
+    publisher: Vec<String>,
+    first_publish_year: Option<i32>,
+    subject: Vec<String>,
+    language: Vec<String>,
+    cover_i: Option<i64>,
+    cover_edition_key: Option<String>,
+    isbn: Vec<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct Edition {
+    publishers: Vec<String>,
+    publish_date: Option<String>,
+    series: Vec<String>,
+    covers: Vec<i64>,
+    languages: Vec<Key>,
+    isbn_13: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Key {
+    key: String,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct Work {
+    /// Either a string or `{"type": "/type/text", "value": "..."}`.
+    description: Option<Value>,
+}
+
+fn open_library_cover(id: i64) -> String {
+    // Without `default=false` a missing cover is a blank image
+    format!("https://covers.openlibrary.org/b/id/{id}-L.jpg?default=false")
+}
+
+impl SearchDoc {
+    fn fields(&self) -> Fields {
+        Fields {
+            title: self.title.clone(),
+            subtitle: self.subtitle.clone(),
+            author: join_authors(self.author_name.clone()),
+            publisher: self.publisher.first().cloned(),
+            published: self.first_publish_year.map(|year| year.to_string()),
+            description: None,
+            subjects: non_empty(self.subject.iter().take(MAX_SUBJECTS).cloned().collect()),
+            isbn: self.isbn.iter().find(|isbn| isbn.len() == 13).cloned(),
+            // Every language of every edition is listed
+            language: match self.language.as_slice() {
+                [language] => language_code(language),
+                _ => None,
+            },
+            series: None,
+            series_index: None,
+        }
+    }
+}
+
+/// Adds what the edition and work of `doc` say to `fields`: the series,
+/// publisher and description that search results lack.
+async fn enrich(
+    doc: &SearchDoc,
+    isbn: Option<&str>,
+    fields: &mut Fields,
+    cover: &mut Option<String>,
+) -> Result<()> {
+    let edition_url = match (isbn, &doc.cover_edition_key) {
+        (Some(isbn), _) => Some(format!("{OPEN_LIBRARY_URL}/isbn/{isbn}.json")),
+        (None, Some(key)) => Some(format!("{OPEN_LIBRARY_URL}/books/{key}.json")),
+        (None, None) => None,
+    };
+    if let Some(url) = edition_url {
+        if let Some(edition) = get_json::<Edition>(&OPEN_LIBRARY, net::client().get(url)).await? {
+            if let Some(series) = edition.series.first() {
+                let (name, index) = parse_series(series);
+                fields.series = Some(name);
+                fields.series_index = index;
+            }
+            if let Some(publisher) = edition.publishers.into_iter().next() {
+                fields.publisher = Some(publisher);
+            }
+            if edition.publish_date.is_some() {
+                fields.published = edition.publish_date;
+            }
+            if let Some(language) = edition.languages.first() {
+                fields.language = language_code(language.key.trim_start_matches("/languages/"));
+            }
+            if let Some(isbn) = edition.isbn_13.into_iter().next() {
+                fields.isbn = Some(isbn);
+            }
+            if let Some(&id) = edition.covers.iter().find(|&&id| id > 0) {
+                *cover = Some(open_library_cover(id));
+            }
+        }
+    }
+    if doc.key.starts_with("/works/") {
+        let url = format!("{OPEN_LIBRARY_URL}{}.json", doc.key);
+        if let Some(work) = get_json::<Work>(&OPEN_LIBRARY, net::client().get(url)).await? {
+            fields.description = match work.description {
+                Some(Value::String(text)) => Some(text),
+                Some(Value::Object(text)) => text
+                    .get("value")
+                    .and_then(Value::as_str)
+                    .map(str::to_string),
+                _ => None,
+            };
+        }
+    }
+    Ok(())
+}
+
+async fn open_library(query: &Query, isbn: Option<&str>) -> Result<Vec<Candidate>> {
+    let mut params = vec![("fields", OPEN_LIBRARY_FIELDS), ("limit", MAX_RESULTS)];
+    match isbn {
+        Some(isbn) => params.push(("isbn", isbn)),
+        None => {
+            params.push(("title", query.title.as_str()));
+            if !query.author.trim().is_empty() {
+                params.push(("author", query.author.as_str()));
+            }
+        }
+    }
+    let request = net::client()
+        .get(format!("{OPEN_LIBRARY_URL}/search.json"))
+        .query(&params);
+    let Some(results) = get_json::<SearchResults>(&OPEN_LIBRARY, request).await? else {
+        return Ok(Vec::new());
+    };
+    let mut found = results
+        .docs
+        .iter()
+        .map(|doc| {
+            let fields = doc.fields();
+            Candidate {
+                source: Source::OpenLibrary,
+                score: if isbn.is_some() {
+                    1.0
+                } else {
+                    query.score(&fields)
+                },
+                cover_url: doc.cover_i.filter(|&id| id > 0).map(open_library_cover),
+                fields,
+                plugin: None,
+            }
+        })
+        .collect::<Vec<_>>();
+    // Only the best result is worth the requests for its edition and work
+    if let Some(best) = (0..found.len()).max_by(|&a, &b| found[a].score.total_cmp(&found[b].score))
+    {
+        let candidate = &mut found[best];
+        enrich(
+            &results.docs[best],
+            isbn,
+            &mut candidate.fields,
+            &mut candidate.cover_url,
+        )
+        .await?;
+    }
+    Ok(found)
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct Volumes {
+    items: Vec<Volume>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Volume {
+    id: String,
+    #[serde(default)]
+    volume_info: VolumeInfo,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct VolumeInfo {
+    title: Option<String>,
+    subtitle: Option<String>,
+    authors: Vec<String>,
+    publisher: Option<String>,
+    published_date: Option<String>,
+    description: Option<String>,
+    industry_identifiers: Vec<IndustryIdentifier>,
+    categories: Vec<String>,
+    language: Option<String>,
+    image_links: Option<Value>,
+}
+
+#[derive(Deserialize)]
+struct IndustryIdentifier {
+    #[serde(rename = "type")]
+    kind: String,
+    identifier: String,
+}
+
+async fn google_books(query: &Query, isbn: Option<&str>) -> Result<Vec<Candidate>> {
+    let terms = match isbn {
+        Some(isbn) => format!("isbn:{isbn}"),
+        None if query.author.trim().is_empty() => {
+            format!("intitle:\"{}\"", query.title.replace('"', ""))
+        }
+        None => format!(
+            "intitle:\"{}\" inauthor:\"{}\"",
+            query.title.replace('"', ""),
+            query.author.replace('"', "")
+        ),
+    };
+    let request = net::client().get(GOOGLE_BOOKS_URL).query(&[
+        ("q", terms.as_str()),
+        ("maxResults", MAX_RESULTS),
+        ("printType", "books"),
+    ]);
+    let Some(volumes) = get_json::<Volumes>(&GOOGLE_BOOKS, request).await? else {
+        return Ok(Vec::new());
+    };
+    Ok(volumes
+        .items
+        .into_iter()
+        .map(|volume| {
+            let info = volume.volume_info;
+            let isbn_of = |kind: &str| {
+                info.industry_identifiers
+                    .iter()
+                    .find(|id| id.kind == kind)
+                    .map(|id| id.identifier.clone())
+            };
+            let fields = Fields {
+                isbn: isbn_of("ISBN_13").or_else(|| isbn_of("ISBN_10")),
+                title: info.title,
+                subtitle: info.subtitle,
+                author: join_authors(info.authors),
+                publisher: info.publisher,
+                published: info.published_date,
+                description: info.description,
+                subjects: non_empty(info.categories),
+                language: info.language,
+                series: None,
+                series_index: None,
+            };
+            Candidate {
+                source: Source::GoogleBooks,
+                score: if isbn.is_some() { 1.0 } else { query.score(&fields) },
+                // The thumbnails in the answer are tiny; `fife` asks for
+                // the cover scaled to a width
+                cover_url: info.image_links.map(|_| {
+                    format!(
+                        "https://books.google.com/books/content?id={}&printsec=frontcover&img=1&fife=w1200",
+                        volume.id
+                    )
+                }),
+                fields,
+                plugin: None,
+            }
+        })
+        .collect())
+}
+
+/// Asks both services, by ISBN or else by title and author. One service
+/// failing is fine unless it is because the network is down.
+async fn search(query: &Query, isbn: Option<&str>) -> Result<Vec<Candidate>> {
+    let (open_library, google_books) =
+        futures::join!(open_library(query, isbn), google_books(query, isbn));
+    let mut candidates = Vec::new();
+    let mut error = None;
+    for (source, result) in [
+        (Source::OpenLibrary, open_library),
+        (Source::GoogleBooks, google_books),
+    ] {
+        match result {
+            Ok(found) => candidates.extend(found),
+            Err(e) if is_offline(&e) => return Err(e),
+            Err(e) => {
+                log::warn!("Failed to look up {:?} on {source:?}: {e}", query.title);
+                error = Some(e);
+            }
+        }
+    }
+    match error {
+        Some(e) if candidates.is_empty() => Err(e),
+        _ => Ok(candidates),
+    }
+}
+
+async fn lookup(query: &Query) -> Result<Vec<Candidate>> {
+    let mut candidates = match &query.isbn {
+        Some(isbn) => search(query, Some(isbn)).await?,
+        None => Vec::new(),
+    };
+    if candidates.is_empty() {
+        candidates = search(query, None).await?;
+    }
+    candidates.retain(|candidate| candidate.score >= MIN_SCORE);
+    candidates.sort_by(|a, b| b.score.total_cmp(&a.score));
+    Ok(candidates)
+}
+
+fn current_fields(book: &db::Book) -> Fields {
+    let metadata = book.metadata.as_ref();
+    let value = |key: &str| metadata.and_then(|metadata| metadata.get(key));
+    let text = |key: &str| {
+        value(key)
+            .and_then(Value::as_str)
+            .filter(|text| !text.trim().is_empty())
+            .map(str::to_string)
+    };
+    Fields {
+        title: Some(book.title.clone()).filter(|title| !title.is_empty()),
+        subtitle: text("subtitle"),
+        author: Some(book.author.clone()).filter(|author| !author.is_empty()),
+        publisher: text("publisher"),
+        published: text("published"),
+        description: text("description"),
+        subjects: match value("subject") {
+            Some(Value::Array(items)) => non_empty(
+                items
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(str::to_string)
+                    .collect(),
+            ),
+            Some(Value::String(subject)) => Some(vec![subject.clone()]),
+            _ => None,
+        },
+        isbn: text("identifier").and_then(|id| metadata_isbn(&id)),
+        language: book.primary_language.clone(),
+        series: text("series"),
+        series_index: value("seriesIndex")
+            .and_then(Value::as_f64)
+            .map(|index| index as f32),
+    }
+}
+
+/// Looks book `hash` up, or `None` when nothing was found.
+async fn propose(app: &AppHandle, hash: &str) -> Result<Option<Proposal>> {
+    let state = app.state::<db::LibraryDb>();
+    let book = db::get_book(&state.conn(), hash)?
+        .ok_or_else(|| Error::InvalidBook(format!("no book {hash}")))?;
+    let current = current_fields(&book);
+    // One printed in the book when its metadata has none
+    let found_isbn = identifiers::for_book(&state.conn(), hash)?
+        .into_iter()
+        .find(|identifier| identifier.scheme == Scheme::Isbn)
+        .map(|identifier| identifier.value);
+    let query = Query {
+        isbn: current.isbn.clone().or(found_isbn),
+        title: book.title.clone(),
+        author: book.author.clone(),
+    };
+    let mut candidates = lookup(&query).await?;
+    let handle = app.clone();
+    let (book_hash, fields) = (hash.to_string(), current.clone());
+    let found = tauri::async_runtime::spawn_blocking(move || {
+        plugins::find_metadata(&handle, &book_hash, &fields)
+    })
+    .await??;
+    if !found.is_empty() {
+        candidates.extend(found.into_iter().map(|(plugin, found)| Candidate {
+            source: Source::Plugin,
+            score: query.score(&found.fields),
+            fields: found.fields,
+            cover_url: found.cover_url,
+            plugin: Some(plugin),
+        }));
+        candidates.retain(|candidate| candidate.score >= MIN_SCORE);
+        candidates.sort_by(|a, b| b.score.total_cmp(&a.score));
+    }
+    let changes = candidates
+        .iter()
+        .fold(Fields::default(), |merged, candidate| {
+            merged.or(&candidate.fields)
+        })
+        .changes_from(&current);
+    let mut covers = Vec::new();
+    for url in candidates
+        .iter()
+        .filter_map(|candidate| candidate.cover_url.as_ref())
+    {
+        if !covers.contains(url) {
+            covers.push(url.clone());
+        }
+    }
+    if changes == Fields::default() && covers.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(Proposal {
+        book_hash: hash.to_string(),
+        current,
+        changes,
+        covers,
+        candidates,
+        fetched_at: now_millis(),
+    }))
+}
+
+/// Looks up each of `hashes`, queueing them all from the first that fails
+/// for want of a network, and keeps the proposals for the user.
+async fn propose_all(app: &AppHandle, hashes: Vec<String>, job: &Job) -> Result<MetadataLookup> {
+    let mut lookup = MetadataLookup::default();
+    let total = hashes.len();
+    let mut done = Vec::new();
+    let mut offline = false;
+    for (i, hash) in hashes.into_iter().enumerate() {
+        if offline {
+            lookup.queued.push(hash);
+            continue;
+        }
+        job.progress("fetching", i, total, None);
+        match job.cancellable(propose(app, &hash)).await {
+            Ok(Some(proposal)) => lookup.proposals.push(proposal),
+            Ok(None) => {}
+            // What was found so far is kept
+            Err(Error::Cancelled) => break,
+            Err(e) if is_offline(&e) => {
+                offline = true;
+                lookup.queued.push(hash);
+                continue;
+            }
+            Err(e) => {
+                lookup.failed.insert(hash.clone(), e.to_string());
+            }
+        }
+        done.push(hash);
+    }
+    app.state::<MetadataQueue>().update(app, |queue| {
+        for hash in &done {
+            queue.pending.remove(hash);
+        }
+        queue.pending.extend(lookup.queued.iter().cloned());
+        for proposal in &lookup.proposals {
+            queue
+                .proposals
+                .insert(proposal.book_hash.clone(), proposal.clone());
+        }
+    })?;
+    Ok(lookup)
+}
+
+/// Looks up the books queued while offline, for the `metadata` scheduled
+/// task, and tells the frontend of the proposals found.
+pub async fn process_queue(app: AppHandle) -> Result<()> {
+    let pending = app
+        .state::<MetadataQueue>()
+        .0
+        .lock()
+        .unwrap()
+        .pending
+        .iter()
+        .cloned()
+        .collect::<Vec<_>>();
+    if pending.is_empty() {
+        return Ok(());
+    }
+    let handle = app.clone();
+    let lookup = jobs::run(&handle, "metadata", |job| async move {
+        propose_all(&app, pending, &job).await
+    })
+    .await?;
+    if !lookup.proposals.is_empty() {
+        handle.emit(PROPOSALS_EVENT, &lookup.proposals)?;
+    }
+    Ok(())
+}
+
+/// Saves the image at `url` as the cover of book `hash`.
+async fn download_cover(app: &AppHandle, hash: &str, url: &str) -> Result<()> {
+    let response = net::client().get(url).send().await?;
+    if !response.status().is_success() {
+        return Err(Error::HttpStatus(response.status().as_u16()));
+    }
+    let image = response.bytes().await?;
+    // Rejects error pages served in place of the image
+    image::guess_format(&image)?;
+    library::write_cover(app, hash, &image)
+}
+
+/// Writes the approved `fields` to `book` and its metadata.
+fn apply(book: &mut db::Book, fields: Fields) {
+    if let Some(title) = &fields.title {
+        book.title = title.clone();
+    }
+    if let Some(author) = &fields.author {
+        book.author = author.clone();
+    }
+    if let Some(language) = &fields.language {
+        book.primary_language = Some(language.clone());
+    }
+    let metadata = book
+        .metadata
+        .get_or_insert_with(|| Value::Object(Map::new()));
+    if let Value::Object(map) = metadata {
+        let mut set = |key: &str, value: Option<Value>| {
+            if let Some(value) = value {
+                map.insert(key.into(), value);
+            }
+        };
+        set("title", fields.title.map(Value::from));
+        set("subtitle", fields.subtitle.map(Value::from));
+        set("author", fields.author.map(Value::from));
+        set("publisher", fields.publisher.map(Value::from));
+        set("published", fields.published.map(Value::from));
+        set("description", fields.description.map(Value::from));
+        set("subject", fields.subjects.map(Value::from));
+        set("identifier", fields.isbn.map(Value::from));
+        set("language", fields.language.map(Value::from));
+        set("series", fields.series.map(Value::from));
+        set("seriesIndex", fields.series_index.map(Value::from));
+    }
+    book.updated_at = now_millis();
+}
+
+/// Looks `hashes` up as a `metadata` job. Books that cannot be looked up
+/// while offline are queued rather than failed.
+#[command]
+pub async fn fetch_metadata(app: AppHandle, hashes: Vec<String>) -> Result<MetadataLookup> {
+    let handle = app.clone();
+    jobs::run(&handle, "metadata", |job| async move {
+        propose_all(&app, hashes, &job).await
+    })
+    .await
+}
+
+/// The proposals waiting for the user, including those of queued books.
+#[command]
+pub fn get_metadata_proposals(queue: State<'_, MetadataQueue>) -> Vec<Proposal> {
+    queue
+        .0
+        .lock()
+        .unwrap()
+        .proposals
+        .values()
+        .cloned()
+        .collect()
+}
+
+#[command]
+pub fn dismiss_metadata_proposal(
+    app: AppHandle,
+    queue: State<'_, MetadataQueue>,
+    hash: String,
+) -> Result<()> {
+    queue.update(&app, |queue| {
+        queue.proposals.remove(&hash);
+    })
+}
+
+/// Updates book `hash` with the `fields` the user approved and, when
+/// `cover_url` is given, the cover at that address.
+#[command]
+pub async fn apply_metadata(
+    app: AppHandle,
+    hash: String,
+    fields: Fields,
+    cover_url: Option<String>,
+) -> Result<db::Book> {
+    if let Some(url) = &cover_url {
+        download_cover(&app, &hash, url).await?;
+    }
+    let state = app.state::<db::LibraryDb>();
+    let mut conn = state.conn();
+    let mut book =
+        db::get_book(&conn, &hash)?.ok_or_else(|| Error::InvalidBook(format!("no book {hash}")))?;
+    apply(&mut book, fields);
+    db::upsert_books(&mut conn, std::slice::from_ref(&book))?;
+    drop(conn);
+    #[cfg(any(target_os = "macos", windows))]
+    crate::system_search::update(&app, std::slice::from_ref(&book));
+    app.state::<MetadataQueue>().update(&app, |queue| {
+        queue.proposals.remove(&hash);
+    })?;
+    Ok(book)
+}
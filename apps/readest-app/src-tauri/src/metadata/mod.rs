@@ -0,0 +1,5 @@
+//! Book metadata from outside the book files. [`fetch`] looks books up
+//! online; the metadata in the files themselves is read by the format
+//! parsers when they are imported.
+
+pub mod fetch;
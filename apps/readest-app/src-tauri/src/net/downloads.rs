@@ -0,0 +1,951 @@
+//! Downloads of remote books, for OPDS catalogs, cloud accounts and links
+//! the user adds. They wait in a queue and run a few at a time, each over
+//! several connections when the server sends ranges, all of them within
+//! the rate limit the user set. A download that is paused, or whose
+//! connection drops, goes on from where it stopped, and its file is only
+//! moved into place once it matches the checksum it was queued with.
+//!
+//! Every change is sent with a `download-progress` event, at most a few
+//! times a second while bytes arrive, for the queue to show.
+
+use std::future::Future;
+use std::io::SeekFrom;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future::{self, BoxFuture, Either};
+use md5::{Digest, Md5};
+use reqwest::header::{ACCEPT_RANGES, CONTENT_TYPE, RANGE};
+use reqwest::{RequestBuilder, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tauri::{command, AppHandle, Emitter, Manager, State};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::watch;
+
+use crate::error::{Error, Result};
+use crate::store;
+use crate::transfer_file::TransferStats;
+use crate::utils::unique_path;
+
+const SETTINGS_FILE: &str = "downloads.json";
+const EVENT: &str = "download-progress";
+/// Smallest part a file is split into for its connections.
+const MIN_SEGMENT: u64 = 4 * 1024 * 1024;
+const MAX_ATTEMPTS: u32 = 3;
+/// Waited before the second attempt, twice as long before the third.
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+const EVENT_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct DownloadSettings {
+    /// Downloads running at once, the others wait in the queue.
+    pub parallel: usize,
+    /// Connections of each download, for servers that send ranges.
+    pub connections: usize,
+    /// Bytes per second of all downloads together, 0 for no limit.
+    pub rate_limit: u64,
+}
+
+impl Default for DownloadSettings {
+    fn default() -> Self {
+        Self {
+            parallel: 3,
+            connections: 4,
+            rate_limit: 0,
+        }
+    }
+}
+
+/// What the downloaded file must hash to, in hex.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Checksum {
+    Sha256(String),
+    Md5(String),
+    /// The hash books have in the library, see
+    /// [`crate::library::partial_md5`].
+    PartialMd5(String),
+}
+
+fn hash_file<D: Digest + std::io::Write>(path: &Path) -> Result<String> {
+    let mut hasher = D::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+impl Checksum {
+    fn verify(&self, path: &Path) -> Result<()> {
+        let (expected, actual) = match self {
+            Checksum::Sha256(expected) => (expected, hash_file::<Sha256>(path)?),
+            Checksum::Md5(expected) => (expected, hash_file::<Md5>(path)?),
+            Checksum::PartialMd5(expected) => (expected, crate::library::partial_md5(path)?),
+        };
+        if !actual.eq_ignore_ascii_case(expected.trim()) {
+            return Err(Error::Download(format!(
+                "the file hashes to {actual} instead of {expected}"
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Where a download is saved.
+pub enum Dest {
+    File(PathBuf),
+    /// In `dir`, under the name `name` gives it from the first response,
+    /// made unique there.
+    Named {
+        dir: PathBuf,
+        name: fn(&Response) -> String,
+    },
+}
+
+pub struct DownloadRequest {
+    /// Shown in the queue.
+    pub name: String,
+    pub dest: Dest,
+    /// For servers that do not say.
+    pub size: Option<u64>,
+    pub checksum: Option<Checksum>,
+}
+
+/// Sends the request for the bytes of `range` of the file, all of them for
+/// `None`, failing on answers other than success. Servers may send all of
+/// them anyway.
+pub type Fetch =
+    Arc<dyn Fn(Option<Range<u64>>) -> BoxFuture<'static, Result<Response>> + Send + Sync>;
+
+/// [`Fetch`] for the requests `request` builds, for plain HTTP servers.
+pub fn fetch_with(request: impl Fn() -> RequestBuilder + Send + Sync + 'static) -> Fetch {
+    Arc::new(move |range| {
+        let request = match range {
+            Some(range) => request().header(
+                RANGE,
+                format!("bytes={}-{}", range.start, range.end.saturating_sub(1)),
+            ),
+            None => request(),
+        };
+        Box::pin(async move {
+            let response = request.send().await?;
+            if !response.status().is_success() {
+                return Err(Error::HttpStatus(response.status().as_u16()));
+            }
+            Ok(response)
+        })
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DownloadState {
+    Queued,
+    Running,
+    Paused,
+    /// Checked against its checksum, which can no longer be stopped.
+    Verifying,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+impl DownloadState {
+    fn is_finished(self) -> bool {
+        matches!(self, Self::Done | Self::Failed | Self::Cancelled)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadProgress {
+    pub id: String,
+    pub name: String,
+    pub state: DownloadState,
+    pub received: u64,
+    pub total: Option<u64>,
+    /// Bytes per second.
+    pub speed: u64,
+    /// Of the file, once done.
+    pub path: Option<PathBuf>,
+    pub error: Option<String>,
+}
+
+pub struct Downloaded {
+    pub path: PathBuf,
+    pub mime_type: Option<String>,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Control {
+    Run,
+    Pause,
+    Cancel,
+}
+
+/// Bytes `start..end` of the file, `done` of them written. The end of a
+/// file of unknown size is known once it is all there.
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    start: u64,
+    end: Option<u64>,
+    done: u64,
+}
+
+impl Segment {
+    fn next(&self) -> u64 {
+        self.start + self.done
+    }
+
+    fn is_done(&self) -> bool {
+        self.end.is_some_and(|end| self.next() >= end)
+    }
+}
+
+struct Transfer {
+    progress: DownloadProgress,
+    /// Empty until the first response.
+    segments: Vec<Segment>,
+    /// Set once a server sent the whole file for a range.
+    single: bool,
+    dest: Option<PathBuf>,
+    mime_type: Option<String>,
+    stats: TransferStats,
+    emitted_at: Instant,
+}
+
+struct Task {
+    dest: Dest,
+    /// Written until the download is done.
+    part: PathBuf,
+    size: Option<u64>,
+    checksum: Option<Checksum>,
+    fetch: Fetch,
+    transfer: Mutex<Transfer>,
+    /// Whether a run of the task has not returned yet, which may be after
+    /// it was paused and resumed.
+    active: AtomicBool,
+    control: watch::Sender<Control>,
+    updates: watch::Sender<DownloadProgress>,
+}
+
+impl Task {
+    fn id(&self) -> String {
+        self.transfer.lock().unwrap().progress.id.clone()
+    }
+
+    fn progress(&self) -> DownloadProgress {
+        self.transfer.lock().unwrap().progress.clone()
+    }
+}
+
+/// Spreads the `rate` bytes per second over the downloads, letting each
+/// take what it needs and wait for what it took too much.
+struct RateLimiter {
+    rate: u64,
+    available: f64,
+    at: Instant,
+}
+
+impl RateLimiter {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            available: 0.0,
+            at: Instant::now(),
+        }
+    }
+
+    /// How long to wait before `bytes` more fit in the rate.
+    fn take(&mut self, bytes: usize) -> Duration {
+        if self.rate == 0 {
+            return Duration::ZERO;
+        }
+        let now = Instant::now();
+        let rate = self.rate as f64;
+        // A second of bytes at most after a pause
+        self.available =
+            (self.available + now.duration_since(self.at).as_secs_f64() * rate).min(rate);
+        self.at = now;
+        self.available -= bytes as f64;
+        match self.available {
+            available if available >= 0.0 => Duration::ZERO,
+            available => Duration::from_secs_f64(-available / rate),
+        }
+    }
+}
+
+pub struct Downloads {
+    tasks: Mutex<Vec<Arc<Task>>>,
+    settings: Mutex<DownloadSettings>,
+    limiter: Mutex<RateLimiter>,
+}
+
+impl Downloads {
+    fn settings(&self) -> DownloadSettings {
+        self.settings.lock().unwrap().clone()
+    }
+
+    fn find(&self, id: &str) -> Option<Arc<Task>> {
+        self.tasks
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|task| task.id() == id)
+            .cloned()
+    }
+
+    async fn throttle(&self, bytes: usize) {
+        let wait = self.limiter.lock().unwrap().take(bytes);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Applies `f` to the transfer of `task` and tells those following it.
+fn update(app: &AppHandle, task: &Task, f: impl FnOnce(&mut Transfer)) {
+    let mut transfer = task.transfer.lock().unwrap();
+    let state = transfer.progress.state;
+    f(&mut transfer);
+    let progress = transfer.progress.clone();
+    let due = progress.state != state || transfer.emitted_at.elapsed() >= EVENT_INTERVAL;
+    if due {
+        transfer.emitted_at = Instant::now();
+    }
+    drop(transfer);
+    task.updates.send_replace(progress.clone());
+    if due {
+        let _ = app.emit(EVENT, progress);
+    }
+}
+
+/// Moves `task` to `to` if it is in one of the states `from`.
+fn transition(app: &AppHandle, task: &Task, from: &[DownloadState], to: DownloadState) -> bool {
+    let mut moved = false;
+    update(app, task, |transfer| {
+        if from.contains(&transfer.progress.state) {
+            transfer.progress.state = to;
+            moved = true;
+        }
+    });
+    moved
+}
+
+fn remove_part(task: &Task) {
+    match std::fs::remove_file(&task.part) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            log::warn!("Failed to remove {:?}: {e}", task.part);
+        }
+        _ => {}
+    }
+}
+
+/// Awaits `future`, or fails with [`Error::Cancelled`] as soon as the
+/// download is paused or cancelled.
+async fn stoppable<T>(
+    control: &watch::Receiver<Control>,
+    future: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let mut control = control.clone();
+    let stopped = async move {
+        let _ = control.wait_for(|control| *control != Control::Run).await;
+    };
+    futures::pin_mut!(future, stopped);
+    match future::select(future, stopped).await {
+        Either::Left((result, _)) => result,
+        Either::Right(_) => Err(Error::Cancelled),
+    }
+}
+
+/// Writes segment `index` of `task` from `response`, or from a request for
+/// what it misses.
+async fn fetch_segment(
+    app: &AppHandle,
+    task: &Task,
+    index: usize,
+    response: Option<Response>,
+    control: &watch::Receiver<Control>,
+) -> Result<()> {
+    let mut segment = task.transfer.lock().unwrap().segments[index];
+    let mut response = match response {
+        Some(response) => response,
+        None => {
+            let range = segment.next()..segment.end.unwrap_or(u64::MAX);
+            let response = stoppable(control, (task.fetch)(Some(range))).await?;
+            if response.status() != StatusCode::PARTIAL_CONTENT && segment.next() > 0 {
+                // Sent from the start again, which only one segment can take
+                let single = task.transfer.lock().unwrap().segments.len() == 1;
+                update(app, task, |transfer| {
+                    transfer.progress.received = 0;
+                    if single {
+                        transfer.segments[0].done = 0;
+                    } else {
+                        transfer.segments.clear();
+                        transfer.single = true;
+                    }
+                });
+                if !single {
+                    return Err(Error::RangeIgnored);
+                }
+                segment.done = 0;
+            }
+            response
+        }
+    };
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(&task.part)
+        .await?;
+    file.seek(SeekFrom::Start(segment.next())).await?;
+    let downloads = app.state::<Downloads>();
+    let mut at = segment.next();
+    while let Some(chunk) = stoppable(control, async { Ok(response.chunk().await?) }).await? {
+        let len = match segment.end {
+            Some(end) => chunk.len().min(end.saturating_sub(at) as usize),
+            None => chunk.len(),
+        };
+        downloads.throttle(len).await;
+        file.write_all(&chunk[..len]).await?;
+        at += len as u64;
+        update(app, task, |transfer| {
+            transfer.segments[index].done += len as u64;
+            transfer.progress.received += len as u64;
+            transfer.stats.record_chunk_transfer(len);
+            transfer.progress.speed = transfer.stats.transfer_speed;
+        });
+        if segment.end.is_some_and(|end| at >= end) {
+            break;
+        }
+    }
+    file.flush().await?;
+    match segment.end {
+        None => {
+            let mut transfer = task.transfer.lock().unwrap();
+            transfer.segments[index].end = Some(at);
+            transfer.progress.total = Some(at);
+        }
+        Some(end) if at < end => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "the connection closed before the end",
+            )
+            .into())
+        }
+        Some(_) => {}
+    }
+    Ok(())
+}
+
+/// Splits a file of `total` bytes between `connections`.
+fn split(total: u64, connections: u64) -> Vec<Segment> {
+    let count = connections.min(total / MIN_SEGMENT).max(1);
+    let len = total.div_ceil(count);
+    (0..count)
+        .map(|i| Segment {
+            start: i * len,
+            end: Some(((i + 1) * len).min(total)),
+            done: 0,
+        })
+        .collect()
+}
+
+/// The first request of `task`, which tells its size, its name and whether
+/// it can be fetched in ranges. Returns the response when it is to be read
+/// whole.
+async fn start(
+    app: &AppHandle,
+    task: &Task,
+    control: &watch::Receiver<Control>,
+) -> Result<Option<Response>> {
+    let response = stoppable(control, (task.fetch)(None)).await?;
+    let total = response
+        .content_length()
+        .filter(|&len| len > 0)
+        .or(task.size);
+    let ranges = response
+        .headers()
+        .get(ACCEPT_RANGES)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("bytes"));
+    let mime_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.split(';').next().unwrap_or(value).trim().to_string());
+    let dest = match &task.dest {
+        Dest::File(path) => path.clone(),
+        Dest::Named { dir, name } => unique_path(dir, &name(&response)),
+    };
+    if let Some(parent) = task.part.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let file = tokio::fs::File::create(&task.part).await?;
+    if let Some(total) = total {
+        file.set_len(total).await?;
+    }
+    let connections = app.state::<Downloads>().settings().connections.max(1) as u64;
+    let single = task.transfer.lock().unwrap().single;
+    let segments = match total {
+        Some(total) if ranges && !single && connections > 1 => split(total, connections),
+        _ => vec![Segment {
+            start: 0,
+            end: total,
+            done: 0,
+        }],
+    };
+    let whole = segments.len() == 1;
+    update(app, task, |transfer| {
+        transfer.segments = segments;
+        transfer.dest = Some(dest);
+        transfer.mime_type = mime_type;
+        transfer.progress.total = total;
+        transfer.progress.received = 0;
+    });
+    Ok(whole.then_some(response))
+}
+
+/// Downloads what `task` misses and moves it into place.
+async fn transfer(app: &AppHandle, task: &Task) -> Result<PathBuf> {
+    let control = task.control.subscribe();
+    loop {
+        let started = !task.transfer.lock().unwrap().segments.is_empty();
+        let mut first = match started {
+            true => None,
+            false => start(app, task, &control).await?,
+        };
+        let pending = task
+            .transfer
+            .lock()
+            .unwrap()
+            .segments
+            .iter()
+            .enumerate()
+            .filter(|(_, segment)| !segment.is_done())
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+        let fetched = future::try_join_all(pending.into_iter().map(|index| {
+            let response = first.take();
+            let control = &control;
+            async move { fetch_segment(app, task, index, response, control).await }
+        }))
+        .await;
+        match fetched {
+            Ok(_) => break,
+            // The segments were dropped, start over in one piece
+            Err(Error::RangeIgnored) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    if !transition(
+        app,
+        task,
+        &[DownloadState::Running],
+        DownloadState::Verifying,
+    ) {
+        return Err(Error::Cancelled);
+    }
+    if let Some(checksum) = task.checksum.clone() {
+        let part = task.part.clone();
+        tauri::async_runtime::spawn_blocking(move || checksum.verify(&part)).await??;
+    }
+    let dest = task
+        .transfer
+        .lock()
+        .unwrap()
+        .dest
+        .clone()
+        .ok_or_else(|| Error::Download("no destination was chosen".into()))?;
+    tokio::fs::rename(&task.part, &dest).await?;
+    Ok(dest)
+}
+
+/// Whether an attempt that failed with `error` is worth another.
+fn retryable(error: &Error) -> bool {
+    match error {
+        Error::HttpStatus(status) => *status >= 500 || *status == 408 || *status == 429,
+        Error::Cancelled | Error::Download(_) => false,
+        _ => true,
+    }
+}
+
+async fn run(app: &AppHandle, task: &Task) {
+    use DownloadState::*;
+    let control = task.control.subscribe();
+    let mut attempt = 1;
+    let result = loop {
+        match transfer(app, task).await {
+            Err(e) if attempt < MAX_ATTEMPTS && retryable(&e) => {
+                log::warn!("Download of {} failed, retrying: {e}", task.progress().name);
+                let delay = async {
+                    tokio::time::sleep(RETRY_DELAY * attempt).await;
+                    Ok(())
+                };
+                if let Err(e) = stoppable(&control, delay).await {
+                    break Err(e);
+                }
+                attempt += 1;
+            }
+            result => break result,
+        }
+    };
+    match result {
+        Ok(path) => {
+            update(app, task, |transfer| {
+                transfer.progress.state = Done;
+                transfer.progress.path = Some(path);
+                transfer.progress.speed = 0;
+            });
+        }
+        // Paused or cancelled, already in that state
+        Err(Error::Cancelled) => {
+            if *task.control.borrow() == Control::Cancel {
+                remove_part(task);
+            }
+        }
+        Err(e) => {
+            log::warn!("Failed to download {}: {e}", task.progress().name);
+            remove_part(task);
+            update(app, task, |transfer| {
+                transfer.progress.state = Failed;
+                transfer.progress.error = Some(e.to_string());
+                transfer.progress.speed = 0;
+            });
+        }
+    }
+}
+
+/// Starts queued downloads while fewer than the settings allow run.
+fn schedule(app: &AppHandle) {
+    use DownloadState::*;
+    let downloads = app.state::<Downloads>();
+    let parallel = downloads.settings().parallel.max(1);
+    let tasks = downloads.tasks.lock().unwrap();
+    let mut running = tasks
+        .iter()
+        .filter(|task| task.active.load(Ordering::Relaxed))
+        .count();
+    for task in tasks.iter() {
+        if running >= parallel {
+            break;
+        }
+        if task.active.load(Ordering::Relaxed) || !transition(app, task, &[Queued], Running) {
+            continue;
+        }
+        task.active.store(true, Ordering::Relaxed);
+        task.control.send_replace(Control::Run);
+        running += 1;
+        let (app, task) = (app.clone(), task.clone());
+        tauri::async_runtime::spawn(async move {
+            run(&app, &task).await;
+            task.active.store(false, Ordering::Relaxed);
+            schedule(&app);
+        });
+    }
+}
+
+fn cancel(app: &AppHandle, task: &Task) -> bool {
+    use DownloadState::*;
+    let active = task.active.load(Ordering::Relaxed);
+    if !transition(app, task, &[Queued, Running, Paused], Cancelled) {
+        return false;
+    }
+    task.control.send_replace(Control::Cancel);
+    // Running ones remove theirs once they stop
+    if !active {
+        remove_part(task);
+    }
+    true
+}
+
+/// Cancels the download of a caller that stopped waiting for it.
+struct CancelOnDrop<'a> {
+    app: &'a AppHandle,
+    task: &'a Task,
+    armed: bool,
+}
+
+impl Drop for CancelOnDrop<'_> {
+    fn drop(&mut self) {
+        if self.armed {
+            cancel(self.app, self.task);
+        }
+    }
+}
+
+/// Queues the download of `request` from `fetch` and waits until it is
+/// done, passing its progress to `on_progress` as it changes. A download
+/// paused in the queue is waited for until resumed; dropping the future
+/// cancels it.
+pub async fn download(
+    app: &AppHandle,
+    request: DownloadRequest,
+    fetch: Fetch,
+    mut on_progress: impl FnMut(&DownloadProgress),
+) -> Result<Downloaded> {
+    use rand::Rng;
+    let id = format!("{:016x}", rand::thread_rng().gen::<u64>());
+    let part = match &request.dest {
+        Dest::File(path) => {
+            let mut part = path.clone().into_os_string();
+            part.push(".part");
+            PathBuf::from(part)
+        }
+        Dest::Named { dir, .. } => dir.join(format!(".{id}.part")),
+    };
+    let progress = DownloadProgress {
+        id,
+        name: request.name,
+        state: DownloadState::Queued,
+        received: 0,
+        total: request.size,
+        speed: 0,
+        path: None,
+        error: None,
+    };
+    let task = Arc::new(Task {
+        dest: request.dest,
+        part,
+        size: request.size,
+        checksum: request.checksum,
+        fetch,
+        transfer: Mutex::new(Transfer {
+            progress: progress.clone(),
+            segments: Vec::new(),
+            single: false,
+            dest: None,
+            mime_type: None,
+            stats: TransferStats::default(),
+            emitted_at: Instant::now(),
+        }),
+        active: AtomicBool::new(false),
+        control: watch::Sender::new(Control::Run),
+        updates: watch::Sender::new(progress.clone()),
+    });
+    let mut updates = task.updates.subscribe();
+    app.state::<Downloads>()
+        .tasks
+        .lock()
+        .unwrap()
+        .push(task.clone());
+    let _ = app.emit(EVENT, progress);
+    let mut guard = CancelOnDrop {
+        app,
+        task: &task,
+        armed: true,
+    };
+    schedule(app);
+
+    loop {
+        let progress = updates.borrow_and_update().clone();
+        on_progress(&progress);
+        match progress.state {
+            DownloadState::Done => {
+                guard.armed = false;
+                return Ok(Downloaded {
+                    path: progress.path.unwrap_or_default(),
+                    mime_type: task.transfer.lock().unwrap().mime_type.clone(),
+                    size: progress.received,
+                });
+            }
+            DownloadState::Failed => {
+                guard.armed = false;
+                return Err(Error::Download(progress.error.unwrap_or_default()));
+            }
+            DownloadState::Cancelled => return Err(Error::Cancelled),
+            _ => {}
+        }
+        if updates.changed().await.is_err() {
+            return Err(Error::Cancelled);
+        }
+    }
+}
+
+pub fn init(app: &AppHandle) {
+    let settings: DownloadSettings = store::load(app, SETTINGS_FILE);
+    app.manage(Downloads {
+        tasks: Mutex::new(Vec::new()),
+        limiter: Mutex::new(RateLimiter::new(settings.rate_limit)),
+        settings: Mutex::new(settings),
+    });
+}
+
+/// The downloads in the queue, running and finished, oldest first.
+#[command]
+pub fn list_downloads(downloads: State<'_, Downloads>) -> Vec<DownloadProgress> {
+    let tasks = downloads.tasks.lock().unwrap();
+    tasks.iter().map(|task| task.progress()).collect()
+}
+
+/// Pauses download `id`, returning `false` if it is not queued or running.
+#[command]
+pub fn pause_download(app: AppHandle, id: String) -> bool {
+    use DownloadState::*;
+    let Some(task) = app.state::<Downloads>().find(&id) else {
+        return false;
+    };
+    if !transition(&app, &task, &[Queued, Running], Paused) {
+        return false;
+    }
+    task.control.send_replace(Control::Pause);
+    update(&app, &task, |transfer| transfer.progress.speed = 0);
+    true
+}
+
+/// Puts paused download `id` back in the queue, from where it stopped.
+#[command]
+pub fn resume_download(app: AppHandle, id: String) -> bool {
+    use DownloadState::*;
+    let Some(task) = app.state::<Downloads>().find(&id) else {
+        return false;
+    };
+    if !transition(&app, &task, &[Paused], Queued) {
+        return false;
+    }
+    schedule(&app);
+    true
+}
+
+/// Cancels download `id`, returning `false` if it is finished or being
+/// verified.
+#[command]
+pub fn cancel_download(app: AppHandle, id: String) -> bool {
+    match app.state::<Downloads>().find(&id) {
+        Some(task) => cancel(&app, &task),
+        None => false,
+    }
+}
+
+/// Removes the finished downloads from the list.
+#[command]
+pub fn clear_downloads(downloads: State<'_, Downloads>) {
+    let mut tasks = downloads.tasks.lock().unwrap();
+    tasks.retain(|task| !task.progress().state.is_finished());
+}
+
+#[command]
+pub fn get_download_settings(downloads: State<'_, Downloads>) -> DownloadSettings {
+    downloads.settings()
+}
+
+/// Saves `settings`, which the running downloads keep but for the rate
+/// limit.
+#[command]
+pub fn set_download_settings(app: AppHandle, settings: DownloadSettings) -> Result<()> {
+    store::save(&app, SETTINGS_FILE, &settings)?;
+    let downloads = app.state::<Downloads>();
+    *downloads.limiter.lock().unwrap() = RateLimiter::new(settings.rate_limit);
+    *downloads.settings.lock().unwrap() = settings;
+    schedule(&app);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    #[test]
+    fn files_are_split_into_segments_of_a_minimum_size() {
+        let ends = |segments: Vec<Segment>| {
+            segments
+                .iter()
+                .map(|segment| (segment.start, segment.end.unwrap()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ends(split(MIB, 4)), [(0, MIB)]);
+        assert_eq!(
+            ends(split(10 * MIB, 4)),
+            [(0, 5 * MIB), (5 * MIB, 10 * MIB)]
+        );
+        let segments = ends(split(100 * MIB + 3, 4));
+        assert_eq!(segments.len(), 4);
+        assert_eq!(segments.first().unwrap().0, 0);
+        assert_eq!(segments.last().unwrap().1, 100 * MIB + 3);
+        assert!(segments.windows(2).all(|pair| pair[0].1 == pair[1].0));
+
+        let empty = split(0, 4);
+        assert_eq!(empty.len(), 1);
+        assert!(empty[0].is_done());
+    }
+
+    #[test]
+    fn segments_go_on_from_what_they_hold() {
+        let mut segment = Segment {
+            start: 100,
+            end: Some(200),
+            done: 60,
+        };
+        assert_eq!(segment.next(), 160);
+        assert!(!segment.is_done());
+        segment.done = 100;
+        assert!(segment.is_done());
+        segment.end = None;
+        assert!(!segment.is_done());
+    }
+
+    #[test]
+    fn rate_limits_make_takers_wait_for_what_they_overdraw() {
+        assert_eq!(RateLimiter::new(0).take(usize::MAX), Duration::ZERO);
+        let mut limiter = RateLimiter::new(1000);
+        let wait = limiter.take(500);
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500));
+        // A pause saves up a second of bytes at most
+        limiter.at -= Duration::from_secs(10);
+        assert_eq!(limiter.take(400), Duration::ZERO);
+        assert!(limiter.take(1000) > Duration::from_millis(300));
+    }
+
+    #[test]
+    fn only_transient_failures_are_retried() {
+        assert!(retryable(&Error::HttpStatus(503)));
+        assert!(retryable(&Error::HttpStatus(429)));
+        assert!(!retryable(&Error::HttpStatus(404)));
+        assert!(!retryable(&Error::Cancelled));
+        assert!(!retryable(&Error::Download("bad checksum".to_string())));
+        assert!(retryable(&Error::Io(
+            std::io::ErrorKind::ConnectionReset.into()
+        )));
+    }
+
+    #[test]
+    fn checksums_are_compared_in_any_case() {
+        let path = std::env::temp_dir().join(format!("vlarch-download-{}", std::process::id()));
+        std::fs::write(&path, "hello").unwrap();
+        Checksum::Md5("5d41402abc4b2a76b9719d911017c592".to_string())
+            .verify(&path)
+            .unwrap();
+        Checksum::Sha256(
+            " 2CF24DBA5FB0A30E26E83B2AC5B9E29E1B161E5C1FA7425E73043362938B9824".to_string(),
+        )
+        .verify(&path)
+        .unwrap();
+        let wrong = Checksum::Md5("0".repeat(32)).verify(&path);
+        assert!(matches!(wrong, Err(Error::Download(_))));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn stopped_downloads_stop_waiting() {
+        let (control, receiver) = watch::channel(Control::Run);
+        let ready = stoppable(&receiver, async { Ok(1) });
+        assert_eq!(futures::executor::block_on(ready).unwrap(), 1);
+        control.send_replace(Control::Pause);
+        let pending = stoppable(&receiver, future::pending::<Result<()>>());
+        assert!(matches!(
+            futures::executor::block_on(pending),
+            Err(Error::Cancelled)
+        ));
+    }
+}
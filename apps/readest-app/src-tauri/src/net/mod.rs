@@ -0,0 +1,43 @@
+//! HTTP client shared by features that talk to remote servers.
+
+use std::sync::RwLock;
+
+use reqwest::{redirect, Client};
+
+use crate::error::Result;
+
+pub mod downloads;
+pub mod proxy;
+
+const USER_AGENT: &str = concat!("VL-Arch/", env!("CARGO_PKG_VERSION"));
+
+static CLIENT: RwLock<Option<Client>> = RwLock::new(None);
+/// The proxy config of the shared client.
+static PROXY: RwLock<proxy::ProxyConfig> = RwLock::new(proxy::ProxyConfig::System);
+
+/// Returns the process-wide client so connections are pooled across
+/// requests. It goes through the configured proxy, see [`proxy`].
+pub fn client() -> Client {
+    if let Some(client) = CLIENT.read().unwrap().as_ref() {
+        return client.clone();
+    }
+    CLIENT
+        .write()
+        .unwrap()
+        .get_or_insert_with(|| proxy::build_client(&proxy::ProxyConfig::System).unwrap_or_default())
+        .clone()
+}
+
+/// A client of its own going through the same proxy as [`client`], that
+/// follows redirects only as `policy` lets it, for requests that must vet
+/// every host they reach.
+pub fn client_with_redirects(policy: redirect::Policy) -> Result<Client> {
+    let config = PROXY.read().unwrap().clone();
+    Ok(proxy::client_builder(&config)?.redirect(policy).build()?)
+}
+
+/// Replaces the shared client, used once the proxy changes to `config`.
+fn set_client(client: Client, config: proxy::ProxyConfig) {
+    *CLIENT.write().unwrap() = Some(client);
+    *PROXY.write().unwrap() = config;
+}
@@ -0,0 +1,149 @@
+//! The proxy every request of the shared client goes through. By default
+//! that is the system proxy, from the `HTTP_PROXY`, `HTTPS_PROXY` and
+//! `NO_PROXY` variables and, on macOS and Windows, the network settings.
+//! Users behind a proxy the system does not know about can set an HTTP,
+//! HTTPS or SOCKS5 proxy themselves, with a username and password.
+
+use reqwest::{Client, ClientBuilder, NoProxy, Proxy};
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle};
+use url::Url;
+
+use crate::error::{Error, Result};
+use crate::secrets;
+use crate::store;
+
+const PROXY_CONFIG_FILE: &str = "proxy.json";
+/// Keychain entry of the proxy password, which stays out of the config file.
+#[cfg(desktop)]
+const PASSWORD_SECRET: &str = "proxy.password";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "camelCase")]
+pub enum ProxyConfig {
+    #[default]
+    System,
+    /// Direct connections, even where the system has a proxy.
+    None,
+    Manual(ManualProxy),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ManualProxy {
+    /// Such as `http://proxy.example.com:8080` or `socks5://localhost:1080`.
+    /// With `socks5h` host names are resolved by the proxy.
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Hosts reached without the proxy, comma separated like `NO_PROXY`.
+    pub no_proxy: Option<String>,
+}
+
+impl ManualProxy {
+    fn proxy(&self) -> Result<Proxy> {
+        let mut url = Url::parse(self.url.trim())?;
+        if !matches!(url.scheme(), "http" | "https" | "socks5" | "socks5h") {
+            return Err(Error::Proxy(format!("unsupported scheme {}", url.scheme())));
+        }
+        // Credentials in the URL work for SOCKS5 as well as HTTP proxies
+        if let Some(username) = self.username.as_deref().filter(|u| !u.is_empty()) {
+            let invalid = |_| Error::Proxy("the proxy URL cannot have credentials".into());
+            url.set_username(username).map_err(invalid)?;
+            url.set_password(self.password.as_deref())
+                .map_err(invalid)?;
+        }
+        let proxy = Proxy::all(url.as_str())?;
+        Ok(match &self.no_proxy {
+            Some(hosts) => proxy.no_proxy(NoProxy::from_string(hosts)),
+            None => proxy,
+        })
+    }
+}
+
+/// Builds a client going through the proxy of `config`.
+pub fn build_client(config: &ProxyConfig) -> Result<Client> {
+    Ok(client_builder(config)?.build()?)
+}
+
+/// A builder of clients going through the proxy of `config`, for those
+/// that need more settings.
+pub fn client_builder(config: &ProxyConfig) -> Result<ClientBuilder> {
+    let builder = Client::builder().user_agent(super::USER_AGENT);
+    Ok(match config {
+        ProxyConfig::System => builder,
+        ProxyConfig::None => builder.no_proxy(),
+        ProxyConfig::Manual(manual) => builder.proxy(manual.proxy()?),
+    })
+}
+
+/// Saves the config, moving the password to the keychain.
+#[cfg(desktop)]
+fn save_config(app: &AppHandle, mut config: ProxyConfig) -> Result<()> {
+    let password = match &mut config {
+        ProxyConfig::Manual(manual) => manual.password.take(),
+        _ => None,
+    };
+    secrets::store(PASSWORD_SECRET, password.as_deref())?;
+    store::save(app, PROXY_CONFIG_FILE, &config)
+}
+
+#[cfg(desktop)]
+fn load_config(app: &AppHandle) -> Result<ProxyConfig> {
+    let mut config: ProxyConfig = store::load(app, PROXY_CONFIG_FILE);
+    if let ProxyConfig::Manual(manual) = &mut config {
+        if manual.username.is_some() {
+            manual.password = secrets::get(PASSWORD_SECRET)?;
+        }
+    }
+    Ok(config)
+}
+
+#[cfg(not(desktop))]
+fn save_config(app: &AppHandle, config: ProxyConfig) -> Result<()> {
+    store::save(app, PROXY_CONFIG_FILE, &config)
+}
+
+#[cfg(not(desktop))]
+fn load_config(app: &AppHandle) -> Result<ProxyConfig> {
+    Ok(store::load(app, PROXY_CONFIG_FILE))
+}
+
+/// Switches the shared client to the configured proxy. Until then, and
+/// when the config cannot be used, it keeps the system proxy.
+pub fn init(app: &AppHandle) {
+    let app = app.clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        match load_config(&app).and_then(|config| Ok((build_client(&config)?, config))) {
+            Ok((client, config)) => super::set_client(client, config),
+            Err(e) => log::warn!("Failed to use the configured proxy: {e}"),
+        }
+    });
+}
+
+#[command]
+pub async fn get_proxy_config(app: AppHandle) -> Result<ProxyConfig> {
+    secrets::blocking(move || load_config(&app)).await
+}
+
+/// Saves `config` and sends every later request through its proxy.
+#[command]
+pub async fn set_proxy_config(app: AppHandle, config: ProxyConfig) -> Result<()> {
+    let client = build_client(&config)?;
+    let saved = config.clone();
+    secrets::blocking(move || save_config(&app, saved)).await?;
+    super::set_client(client, config);
+    Ok(())
+}
+
+/// Fetches `url` through the proxy of `config`, so the user can see that
+/// it works before saving it.
+#[command]
+pub async fn test_proxy_config(config: ProxyConfig, url: String) -> Result<()> {
+    let response = build_client(&config)?.head(url.trim()).send().await?;
+    // Any answer from the server means the proxy let the request through
+    if response.status() == reqwest::StatusCode::PROXY_AUTHENTICATION_REQUIRED {
+        return Err(Error::Proxy("the proxy rejected the credentials".into()));
+    }
+    Ok(())
+}
@@ -0,0 +1,203 @@
+//! Text recognition in scanned books, so the pages of image-only PDFs,
+//! DjVu documents and comics can be searched, selected and looked up in
+//! the dictionary. Built with the `ocr` feature, which links Tesseract.
+//!
+//! Trained models are read from `tessdata` in the app data dir when it
+//! exists, for languages the user downloaded, and else from the system.
+
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use image::imageops::FilterType;
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle, Manager};
+
+use crate::error::Result;
+use crate::paths;
+
+mod tesseract;
+
+use tesseract::Engine;
+
+const DEFAULT_LANGUAGES: &str = "eng";
+const TESSDATA_DIR: &str = "tessdata";
+/// Narrower images are scaled up, Tesseract misreads small print.
+const MIN_OCR_WIDTH: u32 = 1600;
+const MAX_UPSCALE: u32 = 4;
+/// Resolution the images are taken to have, for the engine's size
+/// heuristics.
+const ASSUMED_PPI: u32 = 300;
+/// Width DjVu pages are rendered at for recognition.
+#[cfg(feature = "djvu")]
+const DJVU_OCR_WIDTH: u32 = 2400;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum OcrSource {
+    /// A page of a comic archive.
+    Comic { path: PathBuf, index: usize },
+    #[cfg(feature = "djvu")]
+    Djvu { path: PathBuf, page: u32 },
+    /// An image file, such as a page of a PDF rendered by the reader.
+    Image { path: PathBuf },
+}
+
+/// Part of a page, in fractions of its width and height from the top left.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OcrRegion {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OcrWord {
+    pub text: String,
+    /// From 0 to 100.
+    pub confidence: f32,
+    /// Bounding box in fractions of the page, like [`OcrRegion`].
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    /// Line of the word, counted from 0.
+    pub line: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OcrResult {
+    /// The words in reading order, a line of text per line.
+    pub text: String,
+    pub words: Vec<OcrWord>,
+}
+
+/// The engine of the last languages used, loading models is slow.
+#[derive(Default)]
+pub struct OcrEngine(Mutex<Option<Engine>>);
+
+fn is_cjk(c: char) -> bool {
+    c >= '\u{2e80}'
+}
+
+fn load_image(app: &AppHandle, source: OcrSource) -> Result<DynamicImage> {
+    let data = match source {
+        OcrSource::Comic { path, index } => app
+            .state::<crate::commands::comic::OpenComics>()
+            .with(&path, |comic| comic.read_page(index))?,
+        #[cfg(feature = "djvu")]
+        OcrSource::Djvu { path, page } => app
+            .state::<crate::commands::djvu::OpenDjvus>()
+            .with(&path, |djvu| djvu.render_page(page, DJVU_OCR_WIDTH))?,
+        OcrSource::Image { path } => std::fs::read(path)?,
+    };
+    Ok(image::load_from_memory(&data)?)
+}
+
+/// Pixel bounds of `region` in an image of `width` × `height`.
+fn pixel_bounds(region: OcrRegion, width: u32, height: u32) -> (u32, u32, u32, u32) {
+    let scale = |value: f32, size: u32| (value.clamp(0.0, 1.0) * size as f32).round() as u32;
+    let (x, y) = (scale(region.x, width), scale(region.y, height));
+    let right = scale(region.x + region.width, width).max(x + 1).min(width);
+    let bottom = scale(region.y + region.height, height)
+        .max(y + 1)
+        .min(height);
+    (x.min(width - 1), y.min(height - 1), right, bottom)
+}
+
+fn recognize(
+    app: &AppHandle,
+    source: OcrSource,
+    region: Option<OcrRegion>,
+    languages: &str,
+) -> Result<OcrResult> {
+    let page = load_image(app, source)?;
+    let (page_width, page_height) = (page.width().max(1), page.height().max(1));
+    let (x, y, right, bottom) = match region {
+        Some(region) => pixel_bounds(region, page_width, page_height),
+        None => (0, 0, page_width, page_height),
+    };
+    let crop = page.crop_imm(x, y, right - x, bottom - y);
+    let upscale = MIN_OCR_WIDTH.div_ceil(crop.width()).clamp(1, MAX_UPSCALE);
+    let image = if upscale > 1 {
+        crop.resize(
+            crop.width() * upscale,
+            crop.height() * upscale,
+            FilterType::CatmullRom,
+        )
+    } else {
+        crop
+    }
+    .into_luma8();
+
+    let words = {
+        let state = app.state::<OcrEngine>();
+        let mut engine = state.0.lock().unwrap();
+        if engine.as_ref().map(Engine::languages) != Some(languages) {
+            // Free the old models before loading others
+            *engine = None;
+            let tessdata = paths::data_dir(app)?.join(TESSDATA_DIR);
+            let tessdata = tessdata.is_dir().then_some(tessdata);
+            *engine = Some(Engine::new(tessdata.as_deref(), languages)?);
+        }
+        engine.as_mut().unwrap().recognize(&image, ASSUMED_PPI)?
+    };
+
+    // Back from the scaled crop to fractions of the page
+    let to_page = |value: u32, offset: u32, size: u32| {
+        (offset as f32 + value as f32 / upscale as f32) / size as f32
+    };
+    let mut text = String::new();
+    let mut line = 0;
+    let mut result = Vec::with_capacity(words.len());
+    for (i, word) in words.into_iter().enumerate() {
+        if i > 0 && word.line_start {
+            line += 1;
+            text.push('\n');
+        } else if i > 0 {
+            let joins = text.chars().last().is_some_and(is_cjk)
+                && word.text.chars().next().is_some_and(is_cjk);
+            if !joins {
+                text.push(' ');
+            }
+        }
+        text.push_str(&word.text);
+        let left = to_page(word.left, x, page_width);
+        let top = to_page(word.top, y, page_height);
+        result.push(OcrWord {
+            x: left,
+            y: top,
+            width: to_page(word.right, x, page_width) - left,
+            height: to_page(word.bottom, y, page_height) - top,
+            text: word.text,
+            confidence: word.confidence,
+            line,
+        });
+    }
+    Ok(OcrResult {
+        text,
+        words: result,
+    })
+}
+
+/// Recognizes the text of a page, or of `region` of it, in `languages`,
+/// Tesseract codes joined by `+` such as `eng+chi_sim`.
+#[command]
+pub async fn ocr_page_region(
+    app: AppHandle,
+    source: OcrSource,
+    region: Option<OcrRegion>,
+    languages: Option<String>,
+) -> Result<OcrResult> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let languages = languages
+            .filter(|languages| !languages.trim().is_empty())
+            .unwrap_or_else(|| DEFAULT_LANGUAGES.to_string());
+        recognize(&app, source, region, languages.trim())
+    })
+    .await?
+}
@@ -0,0 +1,209 @@
+//! Text recognition through the C API of Tesseract. An engine loads the
+//! trained models of its languages once, which takes a while, and then
+//! recognizes any number of images.
+
+use std::ffi::{CStr, CString};
+use std::os::raw::c_int;
+use std::path::Path;
+
+use image::GrayImage;
+
+use crate::error::{Error, Result};
+
+#[allow(non_camel_case_types)]
+mod ffi {
+    use std::os::raw::{c_char, c_float, c_int, c_uchar, c_void};
+
+    pub enum TessBaseAPI {}
+    pub enum TessResultIterator {}
+    pub enum TessPageIterator {}
+
+    /// `RIL_TEXTLINE` of `TessPageIteratorLevel`.
+    pub const RIL_TEXTLINE: c_int = 2;
+    /// `RIL_WORD` of `TessPageIteratorLevel`.
+    pub const RIL_WORD: c_int = 3;
+
+    #[link(name = "tesseract")]
+    extern "C" {
+        pub fn TessBaseAPICreate() -> *mut TessBaseAPI;
+        pub fn TessBaseAPIDelete(handle: *mut TessBaseAPI);
+        pub fn TessBaseAPIEnd(handle: *mut TessBaseAPI);
+        pub fn TessBaseAPIInit3(
+            handle: *mut TessBaseAPI,
+            datapath: *const c_char,
+            language: *const c_char,
+        ) -> c_int;
+        pub fn TessBaseAPISetImage(
+            handle: *mut TessBaseAPI,
+            imagedata: *const c_uchar,
+            width: c_int,
+            height: c_int,
+            bytes_per_pixel: c_int,
+            bytes_per_line: c_int,
+        );
+        pub fn TessBaseAPISetSourceResolution(handle: *mut TessBaseAPI, ppi: c_int);
+        pub fn TessBaseAPIRecognize(handle: *mut TessBaseAPI, monitor: *mut c_void) -> c_int;
+        pub fn TessBaseAPIClear(handle: *mut TessBaseAPI);
+        pub fn TessBaseAPIGetIterator(handle: *mut TessBaseAPI) -> *mut TessResultIterator;
+        pub fn TessResultIteratorDelete(handle: *mut TessResultIterator);
+        pub fn TessResultIteratorNext(handle: *mut TessResultIterator, level: c_int) -> c_int;
+        pub fn TessResultIteratorGetUTF8Text(
+            handle: *const TessResultIterator,
+            level: c_int,
+        ) -> *mut c_char;
+        pub fn TessResultIteratorConfidence(
+            handle: *const TessResultIterator,
+            level: c_int,
+        ) -> c_float;
+        pub fn TessResultIteratorGetPageIteratorConst(
+            handle: *const TessResultIterator,
+        ) -> *const TessPageIterator;
+        pub fn TessPageIteratorIsAtBeginningOf(
+            handle: *const TessPageIterator,
+            level: c_int,
+        ) -> c_int;
+        pub fn TessPageIteratorBoundingBox(
+            handle: *const TessPageIterator,
+            level: c_int,
+            left: *mut c_int,
+            top: *mut c_int,
+            right: *mut c_int,
+            bottom: *mut c_int,
+        ) -> c_int;
+        pub fn TessDeleteText(text: *const c_char);
+    }
+}
+
+/// A recognized word and its bounding box in pixels of the image.
+pub struct Word {
+    pub text: String,
+    /// From 0 to 100.
+    pub confidence: f32,
+    pub left: u32,
+    pub top: u32,
+    pub right: u32,
+    pub bottom: u32,
+    /// Whether the word starts a line.
+    pub line_start: bool,
+}
+
+pub struct Engine {
+    handle: *mut ffi::TessBaseAPI,
+    languages: String,
+}
+
+// The handle is only ever used through `&mut self`, behind a lock.
+unsafe impl Send for Engine {}
+
+impl Engine {
+    /// Loads the models of `languages`, Tesseract codes joined by `+` such
+    /// as `eng+chi_sim`, from `tessdata` or else the system's models.
+    pub fn new(tessdata: Option<&Path>, languages: &str) -> Result<Self> {
+        let language = CString::new(languages)
+            .map_err(|_| Error::Ocr(format!("invalid languages {languages}")))?;
+        let datapath = tessdata
+            .map(|dir| {
+                dir.to_str()
+                    .and_then(|dir| CString::new(dir).ok())
+                    .ok_or_else(|| Error::Ocr(format!("unsupported path {}", dir.display())))
+            })
+            .transpose()?;
+        let handle = unsafe { ffi::TessBaseAPICreate() };
+        if handle.is_null() {
+            return Err(Error::Ocr("cannot create the engine".into()));
+        }
+        // Dropping `engine` frees the handle if loading fails
+        let engine = Self {
+            handle,
+            languages: languages.to_string(),
+        };
+        let datapath = datapath
+            .as_ref()
+            .map_or(std::ptr::null(), |path| path.as_ptr());
+        if unsafe { ffi::TessBaseAPIInit3(handle, datapath, language.as_ptr()) } != 0 {
+            return Err(Error::Ocr(format!("no trained data for {languages}")));
+        }
+        Ok(engine)
+    }
+
+    pub fn languages(&self) -> &str {
+        &self.languages
+    }
+
+    /// Recognizes the words of `image`, scanned at `ppi`, in reading order.
+    pub fn recognize(&mut self, image: &GrayImage, ppi: u32) -> Result<Vec<Word>> {
+        let (width, height) = image.dimensions();
+        unsafe {
+            ffi::TessBaseAPISetImage(
+                self.handle,
+                image.as_raw().as_ptr(),
+                width as c_int,
+                height as c_int,
+                1,
+                width as c_int,
+            );
+            ffi::TessBaseAPISetSourceResolution(self.handle, ppi as c_int);
+        }
+        let words = if unsafe { ffi::TessBaseAPIRecognize(self.handle, std::ptr::null_mut()) } == 0
+        {
+            Ok(unsafe { self.words() })
+        } else {
+            Err(Error::Ocr("recognition failed".into()))
+        };
+        // The engine keeps a copy of the image until cleared
+        unsafe { ffi::TessBaseAPIClear(self.handle) };
+        words
+    }
+
+    /// Walks the words of the last recognition.
+    unsafe fn words(&self) -> Vec<Word> {
+        let mut words = Vec::new();
+        let results = ffi::TessBaseAPIGetIterator(self.handle);
+        if results.is_null() {
+            return words;
+        }
+        loop {
+            let text = ffi::TessResultIteratorGetUTF8Text(results, ffi::RIL_WORD);
+            if !text.is_null() {
+                let value = CStr::from_ptr(text).to_string_lossy().trim().to_string();
+                ffi::TessDeleteText(text);
+                let page = ffi::TessResultIteratorGetPageIteratorConst(results);
+                let (mut left, mut top, mut right, mut bottom) = (0, 0, 0, 0);
+                let has_box = ffi::TessPageIteratorBoundingBox(
+                    page,
+                    ffi::RIL_WORD,
+                    &mut left,
+                    &mut top,
+                    &mut right,
+                    &mut bottom,
+                ) != 0;
+                if has_box && !value.is_empty() {
+                    words.push(Word {
+                        text: value,
+                        confidence: ffi::TessResultIteratorConfidence(results, ffi::RIL_WORD),
+                        left: left.max(0) as u32,
+                        top: top.max(0) as u32,
+                        right: right.max(0) as u32,
+                        bottom: bottom.max(0) as u32,
+                        line_start: ffi::TessPageIteratorIsAtBeginningOf(page, ffi::RIL_TEXTLINE)
+                            != 0,
+                    });
+                }
+            }
+            if ffi::TessResultIteratorNext(results, ffi::RIL_WORD) == 0 {
+                break;
+            }
+        }
+        ffi::TessResultIteratorDelete(results);
+        words
+    }
+}
+
+impl Drop for Engine {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::TessBaseAPIEnd(self.handle);
+            ffi::TessBaseAPIDelete(self.handle);
+        }
+    }
+}
@@ -0,0 +1,232 @@
+//! Browse OPDS catalogs (Calibre-Web, Kavita, COPS, ...) and download
+//! publications from them.
+
+use std::path::{Path, PathBuf};
+
+use reqwest::header::{ACCEPT, CONTENT_DISPOSITION, CONTENT_TYPE};
+use reqwest::Response;
+use serde::{Deserialize, Serialize};
+use tauri::{command, ipc::Channel, AppHandle};
+use url::Url;
+
+use super::feed::{self, Feed};
+use crate::error::{Error, Result};
+use crate::net::{
+    self,
+    downloads::{self, Checksum, Dest, DownloadRequest},
+};
+use crate::paths;
+use crate::transfer_file::ProgressPayload;
+use crate::utils::sanitize_file_name;
+
+const FEED_ACCEPT: &str = "application/atom+xml;profile=opds-catalog, application/opds+json, \
+     application/atom+xml;q=0.9, application/json;q=0.8, */*;q=0.5";
+const OPENSEARCH_TYPE: &str = "application/opensearchdescription+xml";
+const DOWNLOADS_DIR: &str = "opds";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Credentials {
+    pub username: String,
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadedBook {
+    pub path: PathBuf,
+    pub mime_type: Option<String>,
+    pub size: u64,
+}
+
+async fn get(url: &Url, accept: &str, credentials: Option<&Credentials>) -> Result<Response> {
+    let mut request = net::client().get(url.clone()).header(ACCEPT, accept);
+    if let Some(credentials) = credentials {
+        request = request.basic_auth(&credentials.username, credentials.password.as_ref());
+    }
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        return Err(Error::HttpStatus(response.status().as_u16()));
+    }
+    Ok(response)
+}
+
+fn content_type(response: &Response) -> Option<String> {
+    response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(';').next().unwrap_or(v).trim().to_string())
+}
+
+async fn fetch_feed(url: &Url, credentials: Option<&Credentials>) -> Result<Feed> {
+    let response = get(url, FEED_ACCEPT, credentials).await?;
+    // Follow redirects so relative links resolve against the final location
+    let url = response.url().clone();
+    let content_type = content_type(&response);
+    let body = response.text().await?;
+    feed::parse_feed(&url, content_type.as_deref(), &body)
+}
+
+#[command]
+pub async fn opds_browse(url: String, credentials: Option<Credentials>) -> Result<Feed> {
+    fetch_feed(&Url::parse(&url)?, credentials.as_ref()).await
+}
+
+/// Runs `query` against the search endpoint advertised by the feed at `url`.
+#[command]
+pub async fn opds_search(
+    url: String,
+    query: String,
+    credentials: Option<Credentials>,
+) -> Result<Feed> {
+    let credentials = credentials.as_ref();
+    let catalog = fetch_feed(&Url::parse(&url)?, credentials).await?;
+    let search = catalog
+        .search
+        .ok_or_else(|| Error::InvalidFeed("catalog does not support search".into()))?;
+
+    let template = if search.mime_type.as_deref() == Some(OPENSEARCH_TYPE) {
+        let description_url = Url::parse(&search.href)?;
+        let response = get(&description_url, OPENSEARCH_TYPE, credentials).await?;
+        let body = response.text().await?;
+        feed::parse_opensearch_template(&description_url, &body)?
+    } else {
+        search.href
+    };
+    let search_url = Url::parse(&feed::expand_search_template(&template, &query))?;
+    fetch_feed(&search_url, credentials).await
+}
+
+/// Extracts the file name from a `Content-Disposition` header, preferring the
+/// RFC 5987 `filename*` form.
+fn disposition_filename(header: &str) -> Option<String> {
+    let params = header.split(';').map(str::trim);
+    let mut plain = None;
+    for param in params {
+        if let Some(value) = param.strip_prefix("filename*=") {
+            let encoded = value.splitn(3, '\'').nth(2).unwrap_or(value);
+            let decoded = percent_encoding::percent_decode_str(encoded).decode_utf8_lossy();
+            return Some(decoded.into_owned());
+        }
+        if let Some(value) = param.strip_prefix("filename=") {
+            plain = Some(value.trim_matches('"').to_string());
+        }
+    }
+    plain
+}
+
+fn extension_for_mime(mime_type: &str) -> Option<&'static str> {
+    let ext = match mime_type {
+        "application/epub+zip" => "epub",
+        "application/pdf" => "pdf",
+        "application/x-mobipocket-ebook" => "mobi",
+        "application/vnd.amazon.ebook" | "application/x-mobi8-ebook" => "azw3",
+        "application/vnd.comicbook+zip" | "application/x-cbz" => "cbz",
+        "application/x-fictionbook+xml" | "application/fb2" => "fb2",
+        "application/x-zip-compressed-fb2" | "application/fb2+zip" => "fbz",
+        // Fulfilled from the token, see `drm::acsm`
+        "application/vnd.adobe.adept+xml" => "acsm",
+        _ => return None,
+    };
+    Some(ext)
+}
+
+fn download_filename(response: &Response, mime_type: Option<&str>) -> String {
+    let from_header = response
+        .headers()
+        .get(CONTENT_DISPOSITION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(disposition_filename);
+    let from_url = || {
+        response
+            .url()
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                percent_encoding::percent_decode_str(s)
+                    .decode_utf8_lossy()
+                    .into_owned()
+            })
+    };
+    let name = from_header
+        .or_else(from_url)
+        .unwrap_or_else(|| "book".to_string());
+    let mut name = sanitize_file_name(&name);
+
+    if let Some(ext) = mime_type.and_then(extension_for_mime) {
+        let has_ext = Path::new(&name)
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case(ext));
+        if !has_ext {
+            name = format!("{name}.{ext}");
+        }
+    }
+    name
+}
+
+/// Downloads an acquisition link into `dir`, or into the app's download
+/// staging folder, from where the frontend imports it into the library.
+/// It goes through the download queue, checked against `checksum` when
+/// the catalog gives one.
+#[command]
+pub async fn opds_download(
+    app: AppHandle,
+    url: String,
+    credentials: Option<Credentials>,
+    dir: Option<PathBuf>,
+    checksum: Option<Checksum>,
+    on_progress: Channel<ProgressPayload>,
+) -> Result<DownloadedBook> {
+    let dir = match dir {
+        Some(dir) => dir,
+        None => paths::cache_dir(&app)?.join(DOWNLOADS_DIR),
+    };
+    let url = Url::parse(&url)?;
+    let name = url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            percent_encoding::percent_decode_str(s)
+                .decode_utf8_lossy()
+                .into_owned()
+        })
+        .unwrap_or_else(|| url.to_string());
+    let fetch = downloads::fetch_with(move || {
+        let request = net::client().get(url.clone()).header(ACCEPT, "*/*");
+        match &credentials {
+            Some(credentials) => {
+                request.basic_auth(&credentials.username, credentials.password.as_ref())
+            }
+            None => request,
+        }
+    });
+    let request = DownloadRequest {
+        name,
+        dest: Dest::Named {
+            dir,
+            name: |response| download_filename(response, content_type(response).as_deref()),
+        },
+        size: None,
+        checksum,
+    };
+    let downloaded = downloads::download(&app, request, fetch, |progress| {
+        let _ = on_progress.send(ProgressPayload {
+            progress: progress.received,
+            total: progress.total.unwrap_or(0),
+            transfer_speed: progress.speed,
+        });
+    })
+    .await?;
+
+    #[cfg(desktop)]
+    crate::allow_file_in_scopes(&app, vec![downloaded.path.clone()]);
+
+    Ok(DownloadedBook {
+        path: downloaded.path,
+        mime_type: downloaded.mime_type,
+        size: downloaded.size,
+    })
+}
@@ -0,0 +1,349 @@
+//! Parsing of OPDS 1.2 (Atom) and OPDS 2.0 (JSON) catalog feeds into a single model.
+
+use roxmltree::Node;
+use serde::Serialize;
+use serde_json::Value;
+use url::Url;
+
+use crate::error::{Error, Result};
+use crate::formats::epub::{attr, parse_xml};
+
+pub const REL_ACQUISITION: &str = "http://opds-spec.org/acquisition";
+pub const REL_IMAGE: &str = "http://opds-spec.org/image";
+pub const REL_THUMBNAIL: &str = "http://opds-spec.org/image/thumbnail";
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Link {
+    pub href: String,
+    pub rel: Option<String>,
+    pub mime_type: Option<String>,
+    pub title: Option<String>,
+}
+
+impl Link {
+    fn has_rel(&self, rel: &str) -> bool {
+        self.rel
+            .as_deref()
+            .is_some_and(|r| r.split_whitespace().any(|r| r == rel))
+    }
+
+    fn is_acquisition(&self) -> bool {
+        self.rel
+            .as_deref()
+            .is_some_and(|r| r.starts_with(REL_ACQUISITION))
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Entry {
+    pub id: Option<String>,
+    pub title: String,
+    pub authors: Vec<String>,
+    pub summary: Option<String>,
+    pub language: Option<String>,
+    pub updated: Option<String>,
+    pub cover: Option<String>,
+    pub thumbnail: Option<String>,
+    /// Links to download the publication.
+    pub acquisitions: Vec<Link>,
+    /// Link to a sub-catalog, for navigation entries.
+    pub navigation: Option<Link>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Feed {
+    pub url: String,
+    pub title: String,
+    pub entries: Vec<Entry>,
+    pub next: Option<String>,
+    pub previous: Option<String>,
+    pub first: Option<String>,
+    pub last: Option<String>,
+    pub start: Option<String>,
+    pub up: Option<String>,
+    pub search: Option<Link>,
+    pub links: Vec<Link>,
+}
+
+impl Feed {
+    fn apply_links(&mut self) {
+        let find = |rel: &str| {
+            self.links
+                .iter()
+                .find(|l| l.has_rel(rel))
+                .map(|l| l.href.clone())
+        };
+        self.next = find("next");
+        self.previous = find("previous").or_else(|| find("prev"));
+        self.first = find("first");
+        self.last = find("last");
+        self.start = find("start");
+        self.up = find("up");
+        self.search = self.links.iter().find(|l| l.has_rel("search")).cloned();
+    }
+}
+
+/// Resolves `href` against `base`, keeping URI template expressions such as
+/// `{?query}` intact instead of percent-encoding their braces.
+fn resolve(base: &Url, href: &str) -> String {
+    let (path, template) = href.split_at(href.find('{').unwrap_or(href.len()));
+    match base.join(path) {
+        Ok(url) => format!("{url}{template}"),
+        Err(_) => href.to_string(),
+    }
+}
+
+/// Parses a feed body, picking the OPDS version from the content type or the body itself.
+pub fn parse_feed(url: &Url, content_type: Option<&str>, body: &str) -> Result<Feed> {
+    let is_json =
+        content_type.is_some_and(|t| t.contains("json")) || body.trim_start().starts_with('{');
+    if is_json {
+        parse_opds2(url, body)
+    } else {
+        parse_opds1(url, body)
+    }
+}
+
+fn child_text(node: Node, name: &str) -> Option<String> {
+    node.children()
+        .find(|n| n.is_element() && n.tag_name().name() == name)
+        .and_then(|n| n.text())
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty())
+}
+
+fn atom_links(base: &Url, node: Node) -> Vec<Link> {
+    node.children()
+        .filter(|n| n.is_element() && n.tag_name().name() == "link")
+        .filter_map(|n| {
+            Some(Link {
+                href: resolve(base, attr(n, "href")?),
+                rel: attr(n, "rel").map(String::from),
+                mime_type: attr(n, "type").map(String::from),
+                title: attr(n, "title").map(String::from),
+            })
+        })
+        .collect()
+}
+
+fn parse_opds1(url: &Url, body: &str) -> Result<Feed> {
+    let doc = parse_xml(body)?;
+    let root = doc.root_element();
+    if root.tag_name().name() != "feed" {
+        return Err(Error::InvalidFeed("expected an Atom feed".into()));
+    }
+    let mut feed = Feed {
+        url: url.to_string(),
+        title: child_text(root, "title").unwrap_or_default(),
+        links: atom_links(url, root),
+        ..Feed::default()
+    };
+    feed.apply_links();
+
+    for node in root
+        .children()
+        .filter(|n| n.is_element() && n.tag_name().name() == "entry")
+    {
+        let links = atom_links(url, node);
+        let authors = node
+            .children()
+            .filter(|n| n.is_element() && n.tag_name().name() == "author")
+            .filter_map(|n| child_text(n, "name"))
+            .collect();
+        let acquisitions = links
+            .iter()
+            .filter(|l| l.is_acquisition())
+            .cloned()
+            .collect::<Vec<_>>();
+        let navigation = if acquisitions.is_empty() {
+            links
+                .iter()
+                .find(|l| {
+                    l.mime_type
+                        .as_deref()
+                        .is_some_and(|t| t.contains("opds-catalog") || t.contains("atom+xml"))
+                })
+                .cloned()
+        } else {
+            None
+        };
+        feed.entries.push(Entry {
+            id: child_text(node, "id"),
+            title: child_text(node, "title").unwrap_or_default(),
+            authors,
+            summary: child_text(node, "summary").or_else(|| child_text(node, "content")),
+            language: child_text(node, "language"),
+            updated: child_text(node, "updated"),
+            cover: links
+                .iter()
+                .find(|l| l.has_rel(REL_IMAGE))
+                .map(|l| l.href.clone()),
+            thumbnail: links
+                .iter()
+                .find(|l| l.has_rel(REL_THUMBNAIL))
+                .map(|l| l.href.clone()),
+            acquisitions,
+            navigation,
+        });
+    }
+    Ok(feed)
+}
+
+fn json_str(value: &Value, key: &str) -> Option<String> {
+    value.get(key).and_then(Value::as_str).map(String::from)
+}
+
+fn json_links(base: &Url, value: Option<&Value>) -> Vec<Link> {
+    value
+        .and_then(Value::as_array)
+        .map(|links| {
+            links
+                .iter()
+                .filter_map(|l| {
+                    let rel = match l.get("rel") {
+                        Some(Value::Array(rels)) => Some(
+                            rels.iter()
+                                .filter_map(Value::as_str)
+                                .collect::<Vec<_>>()
+                                .join(" "),
+                        ),
+                        Some(Value::String(rel)) => Some(rel.clone()),
+                        _ => None,
+                    };
+                    Some(Link {
+                        href: resolve(base, l.get("href")?.as_str()?),
+                        rel,
+                        mime_type: json_str(l, "type"),
+                        title: json_str(l, "title"),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Contributors can be a string, an object with a `name`, or an array of either.
+fn json_contributors(value: Option<&Value>) -> Vec<String> {
+    match value {
+        Some(Value::String(name)) => vec![name.clone()],
+        Some(Value::Object(_)) => value
+            .and_then(|v| v.get("name"))
+            .and_then(|n| match n {
+                Value::String(s) => Some(s.clone()),
+                // Localized names: pick any translation
+                Value::Object(map) => map.values().find_map(Value::as_str).map(String::from),
+                _ => None,
+            })
+            .into_iter()
+            .collect(),
+        Some(Value::Array(items)) => items
+            .iter()
+            .flat_map(|item| json_contributors(Some(item)))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn parse_publication(base: &Url, publication: &Value) -> Entry {
+    let metadata = publication.get("metadata").cloned().unwrap_or_default();
+    let links = json_links(base, publication.get("links"));
+    let images = json_links(base, publication.get("images"));
+    let language = match metadata.get("language") {
+        Some(Value::Array(langs)) => langs.first().and_then(Value::as_str).map(String::from),
+        Some(Value::String(lang)) => Some(lang.clone()),
+        _ => None,
+    };
+    Entry {
+        id: json_str(&metadata, "identifier"),
+        title: json_str(&metadata, "title").unwrap_or_default(),
+        authors: json_contributors(metadata.get("author")),
+        summary: json_str(&metadata, "description"),
+        language,
+        updated: json_str(&metadata, "modified"),
+        cover: images.first().map(|l| l.href.clone()),
+        thumbnail: images.last().map(|l| l.href.clone()),
+        acquisitions: links.into_iter().filter(|l| l.is_acquisition()).collect(),
+        navigation: None,
+    }
+}
+
+fn parse_opds2(url: &Url, body: &str) -> Result<Feed> {
+    let json: Value = serde_json::from_str(body)?;
+    let metadata = json.get("metadata").cloned().unwrap_or_default();
+    let mut feed = Feed {
+        url: url.to_string(),
+        title: json_str(&metadata, "title").unwrap_or_default(),
+        links: json_links(url, json.get("links")),
+        ..Feed::default()
+    };
+    feed.apply_links();
+
+    for link in json_links(url, json.get("navigation")) {
+        feed.entries.push(Entry {
+            title: link.title.clone().unwrap_or_default(),
+            navigation: Some(link),
+            ..Entry::default()
+        });
+    }
+    let groups = json
+        .get("groups")
+        .and_then(Value::as_array)
+        .cloned()
+        .unwrap_or_default();
+    let publications = std::iter::once(&json)
+        .chain(groups.iter())
+        .filter_map(|v| v.get("publications").and_then(Value::as_array))
+        .flatten();
+    for publication in publications {
+        feed.entries.push(parse_publication(url, publication));
+    }
+    Ok(feed)
+}
+
+/// Finds the Atom search template in an OpenSearch description document.
+pub fn parse_opensearch_template(url: &Url, body: &str) -> Result<String> {
+    let doc = parse_xml(body)?;
+    doc.descendants()
+        .filter(|n| n.is_element() && n.tag_name().name() == "Url")
+        .find(|n| attr(*n, "type").is_some_and(|t| t.contains("atom") || t.contains("opds")))
+        .and_then(|n| attr(n, "template"))
+        .map(|t| resolve(url, t))
+        .ok_or_else(|| Error::InvalidFeed("no search template in OpenSearch description".into()))
+}
+
+/// Fills an OpenSearch (`{searchTerms}`) or URI template (`{?query}`) with `query`.
+pub fn expand_search_template(template: &str, query: &str) -> String {
+    let encoded = url::form_urlencoded::byte_serialize(query.as_bytes()).collect::<String>();
+    let mut out = String::with_capacity(template.len() + encoded.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('}') else {
+            rest = &rest[start..];
+            break;
+        };
+        let expr = &rest[start + 1..start + end];
+        rest = &rest[start + end + 1..];
+        match expr.trim_end_matches('?') {
+            "searchTerms" => out.push_str(&encoded),
+            e if e.starts_with('?') => {
+                let names = e[1..].split(',');
+                let params = names
+                    .filter(|n| matches!(*n, "query" | "q" | "title"))
+                    .map(|n| format!("{n}={encoded}"))
+                    .collect::<Vec<_>>();
+                if !params.is_empty() {
+                    out.push(if out.contains('?') { '&' } else { '?' });
+                    out.push_str(&params.join("&"));
+                }
+            }
+            // Optional OpenSearch parameters such as {startPage?} are left empty
+            _ => {}
+        }
+    }
+    out.push_str(rest);
+    out
+}
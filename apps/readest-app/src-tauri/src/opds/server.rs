@@ -0,0 +1,561 @@
+//! Opt-in OPDS 1.2 server exposing the local library to reading apps. It
+//! only takes connections from this device unless LAN sharing is turned on
+//! as well. The password is kept in the keychain, see [`crate::secrets`].
+
+use std::fmt::Write as _;
+use std::fs::File;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle, Manager, State};
+use tiny_http::{Header, Request, Response, Server, StatusCode};
+
+use crate::error::Result;
+use crate::formats;
+use crate::library::{self, access, db};
+#[cfg(desktop)]
+use crate::secrets;
+use crate::store;
+use crate::utils::{escape_xml, format_rfc3339, same};
+
+const CONFIG_FILE: &str = "opds-server.json";
+const DEFAULT_PORT: u16 = 8760;
+const PAGE_SIZE: u32 = 50;
+const ROOT: &str = "/opds";
+const NAVIGATION_TYPE: &str = "application/atom+xml;profile=opds-catalog;kind=navigation";
+const ACQUISITION_TYPE: &str = "application/atom+xml;profile=opds-catalog;kind=acquisition";
+const OPENSEARCH_TYPE: &str = "application/opensearchdescription+xml";
+/// Keychain entry of the password, which stays out of the config file.
+#[cfg(desktop)]
+const PASSWORD_SECRET: &str = "opds.password";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct OpdsServerConfig {
+    pub enabled: bool,
+    pub port: u16,
+    /// Whether other devices on the network may connect; otherwise only
+    /// apps on this one can.
+    pub lan: bool,
+    /// Clients must authenticate with these credentials when a username is set.
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl Default for OpdsServerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: DEFAULT_PORT,
+            lan: false,
+            username: None,
+            password: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OpdsServerStatus {
+    pub running: bool,
+    pub port: Option<u16>,
+    pub error: Option<String>,
+}
+
+struct Running {
+    server: Arc<Server>,
+    listener: JoinHandle<()>,
+}
+
+struct Inner {
+    config: OpdsServerConfig,
+    running: Option<Running>,
+    error: Option<String>,
+}
+
+pub struct OpdsServer(Mutex<Inner>);
+
+impl Inner {
+    fn stop(&mut self) {
+        if let Some(running) = self.running.take() {
+            running.server.unblock();
+            let _ = running.listener.join();
+        }
+    }
+
+    /// Binds the port, retrying briefly while a previous server releases it.
+    fn bind(lan: bool, port: u16) -> std::result::Result<Server, String> {
+        let address = match lan {
+            true => Ipv4Addr::UNSPECIFIED,
+            false => Ipv4Addr::LOCALHOST,
+        };
+        let mut attempts = 0;
+        loop {
+            match Server::http((address, port)) {
+                Ok(server) => return Ok(server),
+                Err(_) if attempts < 5 => {
+                    attempts += 1;
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+    }
+
+    fn start(&mut self, app: &AppHandle) {
+        self.stop();
+        self.error = None;
+        if !self.config.enabled {
+            return;
+        }
+        match Self::bind(self.config.lan, self.config.port) {
+            Ok(server) => {
+                let server = Arc::new(server);
+                let auth = expected_auth(&self.config).map(Arc::new);
+                let (app, incoming) = (app.clone(), server.clone());
+                let listener = std::thread::spawn(move || {
+                    for request in incoming.incoming_requests() {
+                        let (app, auth) = (app.clone(), auth.clone());
+                        std::thread::spawn(move || handle(&app, auth.as_deref(), request));
+                    }
+                });
+                self.running = Some(Running { server, listener });
+            }
+            Err(e) => {
+                log::warn!("Failed to start OPDS server: {e}");
+                self.error = Some(e);
+            }
+        }
+    }
+
+    fn status(&self) -> OpdsServerStatus {
+        OpdsServerStatus {
+            running: self.running.is_some(),
+            port: self
+                .running
+                .as_ref()
+                .and_then(|r| r.server.server_addr().to_ip())
+                .map(|addr| addr.port()),
+            error: self.error.clone(),
+        }
+    }
+}
+
+/// Saves the config, moving the password to the keychain.
+#[cfg(desktop)]
+fn save_config(app: &AppHandle, mut config: OpdsServerConfig) -> Result<()> {
+    secrets::store(PASSWORD_SECRET, config.password.take().as_deref())?;
+    store::save(app, CONFIG_FILE, &config)
+}
+
+/// Loads the config with its password. A password found in the file,
+/// written before it moved to the keychain, is moved now.
+#[cfg(desktop)]
+fn load_config(app: &AppHandle) -> Result<OpdsServerConfig> {
+    let mut config: OpdsServerConfig = store::load(app, CONFIG_FILE);
+    if config.password.is_some() {
+        save_config(app, config.clone())?;
+    } else if config.username.is_some() {
+        config.password = secrets::get(PASSWORD_SECRET)?;
+    }
+    Ok(config)
+}
+
+#[cfg(not(desktop))]
+fn save_config(app: &AppHandle, config: OpdsServerConfig) -> Result<()> {
+    store::save(app, CONFIG_FILE, &config)
+}
+
+#[cfg(not(desktop))]
+fn load_config(app: &AppHandle) -> Result<OpdsServerConfig> {
+    Ok(store::load(app, CONFIG_FILE))
+}
+
+/// Starts the server if it is enabled, once its password is read from the
+/// keychain, which is not reached from the main thread.
+pub fn init(app: &AppHandle) {
+    let inner = Inner {
+        config: store::load(app, CONFIG_FILE),
+        running: None,
+        error: None,
+    };
+    let enabled = inner.config.enabled;
+    app.manage(OpdsServer(Mutex::new(inner)));
+    if enabled {
+        let app = app.clone();
+        tauri::async_runtime::spawn_blocking(move || {
+            let loaded = load_config(&app);
+            let server = app.state::<OpdsServer>();
+            let mut inner = server.0.lock().unwrap();
+            match loaded {
+                Ok(config) => {
+                    inner.config = config;
+                    inner.start(&app);
+                }
+                Err(e) => {
+                    log::warn!("Failed to load the OPDS server config: {e}");
+                    inner.error = Some(e.to_string());
+                }
+            }
+        });
+    }
+}
+
+/// The `Authorization` header value clients must send, if auth is configured.
+fn expected_auth(config: &OpdsServerConfig) -> Option<String> {
+    let username = config.username.as_deref().filter(|u| !u.is_empty())?;
+    let password = config.password.as_deref().unwrap_or_default();
+    let token = base64::engine::general_purpose::STANDARD.encode(format!("{username}:{password}"));
+    Some(format!("Basic {token}"))
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+}
+
+fn respond(request: Request, response: Response<impl std::io::Read>) {
+    if let Err(e) = request.respond(response) {
+        log::warn!("Failed to send OPDS response: {e}");
+    }
+}
+
+fn respond_status(request: Request, code: u16) {
+    respond(request, Response::empty(StatusCode(code)));
+}
+
+fn respond_xml(request: Request, content_type: &str, xml: String) {
+    let response = Response::from_string(xml).with_header(header("Content-Type", content_type));
+    respond(request, response);
+}
+
+fn handle(app: &AppHandle, auth: Option<&String>, request: Request) {
+    if let Some(expected) = auth {
+        let authorized = request
+            .headers()
+            .iter()
+            .any(|h| h.field.equiv("Authorization") && same(h.value.as_str(), expected));
+        if !authorized {
+            let response = Response::empty(StatusCode(401))
+                .with_header(header("WWW-Authenticate", "Basic realm=\"VL-Arch\""));
+            respond(request, response);
+            return;
+        }
+    }
+
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let params = url::form_urlencoded::parse(query.as_bytes()).collect::<Vec<_>>();
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.to_string())
+    };
+
+    let segments = path
+        .strip_prefix(ROOT)
+        .map(|rest| rest.trim_matches('/').split('/').collect::<Vec<_>>());
+    match segments.as_deref() {
+        Some([""]) => respond_xml(request, NAVIGATION_TYPE, root_feed()),
+        Some(["opensearch.xml"]) => respond_xml(request, OPENSEARCH_TYPE, opensearch()),
+        Some(["books"]) | Some(["search"]) => {
+            let page = param("page")
+                .and_then(|p| p.parse::<u32>().ok())
+                .unwrap_or(0);
+            // The next page has to be within reach too
+            if page
+                .checked_add(1)
+                .and_then(|next| next.checked_mul(PAGE_SIZE))
+                .is_none()
+            {
+                return respond_status(request, 400);
+            }
+            let sort = param("sort");
+            match books_feed(app, param("q"), sort.as_deref(), page) {
+                Ok(xml) => respond_xml(request, ACQUISITION_TYPE, xml),
+                Err(e) => {
+                    log::warn!("Failed to build OPDS feed: {e}");
+                    respond_status(request, 500);
+                }
+            }
+        }
+        Some(["books", hash, _]) if !hash.chars().all(|c| c.is_ascii_alphanumeric()) => {
+            respond_status(request, 400)
+        }
+        Some(["books", hash, "file"]) => serve_book(app, request, hash),
+        Some(["books", hash, "cover"]) => {
+            match unlocked_book(app, hash).and_then(|_| library::cover_path(app, hash)) {
+                Some(path) => serve_file(request, &path, "image/png", None),
+                None => respond_status(request, 404),
+            }
+        }
+        _ => respond_status(request, 404),
+    }
+}
+
+fn serve_file(request: Request, path: &std::path::Path, content_type: &str, name: Option<&str>) {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return respond_status(request, 404),
+    };
+    let mut response = Response::from_file(file).with_header(header("Content-Type", content_type));
+    if let Some(name) = name {
+        let encoded =
+            percent_encoding::utf8_percent_encode(name, percent_encoding::NON_ALPHANUMERIC);
+        response = response.with_header(header(
+            "Content-Disposition",
+            &format!("attachment; filename*=UTF-8''{encoded}"),
+        ));
+    }
+    respond(request, response);
+}
+
+/// Book `hash`, unless it is deleted or locked.
+fn unlocked_book(app: &AppHandle, hash: &str) -> Option<db::Book> {
+    let db = app.state::<db::LibraryDb>();
+    let conn = db.conn();
+    access::lock(app, &conn)
+        .and_then(|lock| db::get_unlocked_book(&conn, hash, &lock))
+        .ok()
+        .flatten()
+        .filter(|book| book.deleted_at.is_none())
+}
+
+fn serve_book(app: &AppHandle, request: Request, hash: &str) {
+    let Some(book) = unlocked_book(app, hash) else {
+        return respond_status(request, 404);
+    };
+    let Some(path) = library::book_path(app, &book) else {
+        return respond_status(request, 404);
+    };
+    let ext = path
+        .extension()
+        .map(|e| e.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned());
+    serve_file(request, &path, formats::mime_type(&ext), name.as_deref());
+}
+
+fn feed_header(xml: &mut String, id: &str, title: &str, self_href: &str, kind: &str) {
+    let _ = write!(
+        xml,
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom" xmlns:dc="http://purl.org/dc/terms/" xmlns:opds="http://opds-spec.org/2010/catalog">
+<id>{id}</id>
+<title>{title}</title>
+<updated>{updated}</updated>
+<author><name>VL-Arch</name></author>
+<link rel="self" href="{self_href}" type="{kind}"/>
+<link rel="start" href="{ROOT}" type="{NAVIGATION_TYPE}"/>
+<link rel="search" href="{ROOT}/opensearch.xml" type="{OPENSEARCH_TYPE}"/>
+"#,
+        id = escape_xml(id),
+        title = escape_xml(title),
+        updated = format_rfc3339(crate::utils::now_millis()),
+        self_href = escape_xml(self_href),
+    );
+}
+
+fn root_feed() -> String {
+    let mut xml = String::new();
+    feed_header(
+        &mut xml,
+        "urn:vlarch:root",
+        "VL-Arch Library",
+        ROOT,
+        NAVIGATION_TYPE,
+    );
+    let updated = format_rfc3339(crate::utils::now_millis());
+    for (id, title, sort) in [
+        ("recent", "Recently added", "createdAt"),
+        ("title", "By title", "title"),
+        ("author", "By author", "author"),
+    ] {
+        let _ = write!(
+            xml,
+            r#"<entry>
+<id>urn:vlarch:{id}</id>
+<title>{title}</title>
+<updated>{updated}</updated>
+<link rel="subsection" href="{ROOT}/books?sort={sort}" type="{ACQUISITION_TYPE}"/>
+</entry>
+"#
+        );
+    }
+    xml.push_str("</feed>\n");
+    xml
+}
+
+fn opensearch() -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<OpenSearchDescription xmlns="http://a9.com/-/spec/opensearch/1.1/">
+<ShortName>VL-Arch</ShortName>
+<Description>Search the VL-Arch library</Description>
+<InputEncoding>UTF-8</InputEncoding>
+<OutputEncoding>UTF-8</OutputEncoding>
+<Url type="{ACQUISITION_TYPE}" template="{ROOT}/search?q={{searchTerms}}"/>
+</OpenSearchDescription>
+"#
+    )
+}
+
+fn books_feed(
+    app: &AppHandle,
+    search: Option<String>,
+    sort: Option<&str>,
+    page: u32,
+) -> Result<String> {
+    let (sort_by, ascending, sort) = match sort {
+        Some("title") => (db::SortField::Title, true, Some("title")),
+        Some("author") => (db::SortField::Author, true, Some("author")),
+        Some("createdAt") => (db::SortField::CreatedAt, false, Some("createdAt")),
+        _ => (db::SortField::UpdatedAt, false, None),
+    };
+    let query = db::BookQuery {
+        search: search.clone(),
+        sort_by,
+        ascending,
+        limit: Some(PAGE_SIZE),
+        offset: Some(page * PAGE_SIZE),
+        ..db::BookQuery::default()
+    };
+    let result = {
+        let db = app.state::<db::LibraryDb>();
+        let conn = db.conn();
+        let query = db::BookQuery {
+            lock: access::lock(app, &conn)?,
+            ..query
+        };
+        db::query_books(&conn, &query)?
+    };
+
+    let mut base = format!("{ROOT}/books?");
+    if let Some(search) = &search {
+        let q = url::form_urlencoded::byte_serialize(search.as_bytes()).collect::<String>();
+        base = format!("{ROOT}/search?q={q}&");
+    }
+    if let Some(sort) = sort {
+        let _ = write!(base, "sort={sort}&");
+    }
+    let page_href = |page: u32| format!("{base}page={page}");
+
+    let title = match &search {
+        Some(search) => format!("Search: {search}"),
+        None => "Books".to_string(),
+    };
+    let mut xml = String::new();
+    feed_header(
+        &mut xml,
+        "urn:vlarch:books",
+        &title,
+        &page_href(page),
+        ACQUISITION_TYPE,
+    );
+    if u64::from((page + 1) * PAGE_SIZE) < result.total {
+        let _ = writeln!(
+            xml,
+            r#"<link rel="next" href="{}" type="{ACQUISITION_TYPE}"/>"#,
+            escape_xml(&page_href(page + 1))
+        );
+    }
+    if page > 0 {
+        let _ = writeln!(
+            xml,
+            r#"<link rel="previous" href="{}" type="{ACQUISITION_TYPE}"/>"#,
+            escape_xml(&page_href(page - 1))
+        );
+    }
+    for book in &result.books {
+        book_entry(app, &mut xml, book);
+    }
+    xml.push_str("</feed>\n");
+    Ok(xml)
+}
+
+fn book_entry(app: &AppHandle, xml: &mut String, book: &db::Book) {
+    let hash = escape_xml(&book.hash);
+    let _ = write!(
+        xml,
+        "<entry>\n<id>urn:vlarch:book:{hash}</id>\n<title>{}</title>\n<updated>{}</updated>\n",
+        escape_xml(&book.title),
+        format_rfc3339(book.updated_at),
+    );
+    for author in book
+        .author
+        .split(',')
+        .map(str::trim)
+        .filter(|a| !a.is_empty())
+    {
+        let _ = writeln!(xml, "<author><name>{}</name></author>", escape_xml(author));
+    }
+    if let Some(language) = &book.primary_language {
+        let _ = writeln!(xml, "<dc:language>{}</dc:language>", escape_xml(language));
+    }
+    let description = book
+        .metadata
+        .as_ref()
+        .and_then(|m| m.get("description"))
+        .and_then(|d| d.as_str());
+    if let Some(description) = description {
+        let _ = writeln!(
+            xml,
+            r#"<summary type="text">{}</summary>"#,
+            escape_xml(description)
+        );
+    }
+    for tag in &book.tags {
+        let _ = writeln!(
+            xml,
+            r#"<category term="{0}" label="{0}"/>"#,
+            escape_xml(tag)
+        );
+    }
+    if library::cover_path(app, &book.hash).is_some() {
+        for rel in [
+            "http://opds-spec.org/image",
+            "http://opds-spec.org/image/thumbnail",
+        ] {
+            let _ = writeln!(
+                xml,
+                r#"<link rel="{rel}" href="{ROOT}/books/{hash}/cover" type="image/png"/>"#
+            );
+        }
+    }
+    let _ = write!(
+        xml,
+        "<link rel=\"http://opds-spec.org/acquisition\" href=\"{ROOT}/books/{hash}/file\" type=\"{}\"/>\n</entry>\n",
+        formats::mime_type(&book.format),
+    );
+}
+
+#[command]
+pub fn get_opds_server_config(server: State<'_, OpdsServer>) -> OpdsServerConfig {
+    server.0.lock().unwrap().config.clone()
+}
+
+#[command]
+pub fn get_opds_server_status(server: State<'_, OpdsServer>) -> OpdsServerStatus {
+    server.0.lock().unwrap().status()
+}
+
+/// Saves `config` and restarts the server to apply it.
+#[command]
+pub async fn set_opds_server_config(
+    app: AppHandle,
+    config: OpdsServerConfig,
+) -> Result<OpdsServerStatus> {
+    tauri::async_runtime::spawn_blocking(move || {
+        save_config(&app, config.clone())?;
+        let server = app.state::<OpdsServer>();
+        let mut inner = server.0.lock().unwrap();
+        inner.config = config;
+        inner.start(&app);
+        Ok(inner.status())
+    })
+    .await?
+}
@@ -0,0 +1,155 @@
+//! Where the app keeps its files. Installed, it uses the config, data and
+//! cache directories of the system; in [`portable`] mode they all live
+//! under one directory, so the app can run from a removable drive with
+//! whatever it keeps. Either way, profiles other than the default one keep
+//! theirs under `profiles/<id>` in each, see [`crate::profiles`].
+
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Manager};
+
+use crate::error::Result;
+
+#[cfg(desktop)]
+pub mod portable;
+
+const PROFILES_DIR: &str = "profiles";
+
+/// The portable directory of this run, if any.
+static ROOT: OnceLock<Option<PathBuf>> = OnceLock::new();
+/// The profile of this run, unless it is the default one.
+static PROFILE: OnceLock<Option<String>> = OnceLock::new();
+
+fn root() -> Option<&'static Path> {
+    ROOT.get().and_then(Option::as_deref)
+}
+
+pub fn profile() -> Option<&'static str> {
+    PROFILE.get().and_then(Option::as_deref)
+}
+
+/// Picks the profile of this run, before anything resolves a directory.
+#[cfg(desktop)]
+pub fn set_profile(id: Option<String>) {
+    let _ = PROFILE.set(id);
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DataDirs {
+    pub config: PathBuf,
+    pub data: PathBuf,
+    pub cache: PathBuf,
+}
+
+impl DataDirs {
+    fn system(app: &AppHandle) -> Result<Self> {
+        Ok(Self {
+            config: app.path().app_config_dir()?,
+            data: app.path().app_data_dir()?,
+            cache: app.path().app_cache_dir()?,
+        })
+    }
+
+    fn portable(root: &Path, system: &Self) -> Self {
+        let data = root.join("data");
+        // Config and data are apart only where the system keeps them apart,
+        // so that either layout can be moved to the other
+        let config = if system.config == system.data {
+            data.clone()
+        } else {
+            root.join("config")
+        };
+        Self {
+            config,
+            data,
+            cache: root.join("cache"),
+        }
+    }
+
+    /// The directories of portable mode or of the system, which hold those
+    /// of the profiles.
+    fn base(app: &AppHandle) -> Result<Self> {
+        let system = Self::system(app)?;
+        Ok(match root() {
+            Some(root) => Self::portable(root, &system),
+            None => system,
+        })
+    }
+
+    fn profile(&self, id: &str) -> Self {
+        Self {
+            config: self.config.join(PROFILES_DIR).join(id),
+            data: self.data.join(PROFILES_DIR).join(id),
+            cache: self.cache.join(PROFILES_DIR).join(id),
+        }
+    }
+
+    fn current(app: &AppHandle) -> Result<Self> {
+        let base = Self::base(app)?;
+        Ok(match profile() {
+            Some(id) => base.profile(id),
+            None => base,
+        })
+    }
+}
+
+pub fn config_dir(app: &AppHandle) -> Result<PathBuf> {
+    Ok(DataDirs::current(app)?.config)
+}
+
+pub fn data_dir(app: &AppHandle) -> Result<PathBuf> {
+    Ok(DataDirs::current(app)?.data)
+}
+
+pub fn cache_dir(app: &AppHandle) -> Result<PathBuf> {
+    Ok(DataDirs::current(app)?.cache)
+}
+
+/// The config directory all profiles share, for what is kept per device.
+#[cfg(desktop)]
+pub fn shared_config_dir(app: &AppHandle) -> Result<PathBuf> {
+    Ok(DataDirs::base(app)?.config)
+}
+
+/// The directories of profile `id`.
+#[cfg(desktop)]
+pub fn profile_dirs(app: &AppHandle, id: &str) -> Result<DataDirs> {
+    Ok(DataDirs::base(app)?.profile(id))
+}
+
+/// Has the webviews of `builder` keep their storage with the files of the
+/// portable directory or of the profile, and tells the frontend where
+/// those are, as the base directories it would use are those of the
+/// system. Builders of the system directories are left as they are.
+#[cfg(desktop)]
+pub fn configure_webview<'a, M: Manager<tauri::Wry>>(
+    app: &AppHandle,
+    builder: tauri::WebviewWindowBuilder<'a, tauri::Wry, M>,
+) -> tauri::WebviewWindowBuilder<'a, tauri::Wry, M> {
+    let (Ok(system), Ok(dirs), Ok(log)) = (
+        DataDirs::system(app),
+        DataDirs::current(app),
+        crate::logging::log_dir(app),
+    ) else {
+        return builder;
+    };
+    if dirs == system {
+        return builder;
+    }
+    let webview = match (profile(), root()) {
+        (None, Some(root)) => root.join("webview"),
+        _ => dirs.data.join("webview"),
+    };
+    let dirs = serde_json::json!({
+        "settings": dirs.config,
+        "data": dirs.data,
+        "cache": dirs.cache,
+        "log": log,
+    });
+    builder
+        .data_directory(webview)
+        .initialization_script(format!("window.__VLARCH_DATA_DIRS = {dirs};"))
+}
@@ -0,0 +1,227 @@
+//! Portable mode, chosen with `--data-dir <path>`, or by a `portable` file
+//! beside the executable (beside the app bundle on macOS) holding the
+//! directory, relative to where it is, or `VL-Arch Data` beside it when
+//! empty. [`migrate_data_dir`] moves the files of one mode to the other,
+//! which happens on the next launch, before anything has them open.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle};
+
+use super::{root, DataDirs, ROOT};
+use crate::error::{Error, Result};
+use crate::library;
+
+/// Flag of `main` naming the portable directory.
+pub const DATA_DIR_ARG: &str = "--data-dir";
+const MARKER_FILE: &str = "portable";
+/// Portable directory beside the executable, when the marker names none.
+const DEFAULT_DIR: &str = "VL-Arch Data";
+/// Plan of a migration, left in the config directory of the new location
+/// for the next launch.
+const MIGRATION_FILE: &str = "data-migration.json";
+
+/// Whether the portable directory was given with `--data-dir`, which no
+/// migration can change.
+static FROM_ARG: AtomicBool = AtomicBool::new(false);
+
+/// The directory of the executable, or of the app bundle on macOS, which
+/// must not change to stay signed.
+fn exe_dir() -> Option<PathBuf> {
+    let exe = std::env::current_exe().ok()?;
+    let dir = exe.parent()?;
+    if cfg!(target_os = "macos") && dir.ends_with("Contents/MacOS") {
+        return dir.parent()?.parent()?.parent().map(Path::to_path_buf);
+    }
+    Some(dir.to_path_buf())
+}
+
+/// The portable directory the marker file names.
+fn marker_dir() -> Option<PathBuf> {
+    let exe_dir = exe_dir()?;
+    let contents = std::fs::read_to_string(exe_dir.join(MARKER_FILE)).ok()?;
+    let dir = contents.trim();
+    Some(exe_dir.join(if dir.is_empty() { DEFAULT_DIR } else { dir }))
+}
+
+fn absolute(path: PathBuf) -> PathBuf {
+    std::env::current_dir()
+        .map(|cwd| cwd.join(&path))
+        .unwrap_or(path)
+}
+
+/// Picks the directories of this run: `arg` of `--data-dir`, else the one
+/// the marker file names, else those of the system. Called by `main` before
+/// anything else.
+pub fn set_data_dir(arg: Option<PathBuf>) {
+    FROM_ARG.store(arg.is_some(), Ordering::Relaxed);
+    let _ = ROOT.set(arg.map(absolute).or_else(marker_dir));
+}
+
+/// `args` without `--data-dir` and its value, for the parsers that do not
+/// know the flag.
+pub fn without_data_dir_arg(args: impl IntoIterator<Item = String>) -> Vec<String> {
+    let prefix = format!("{DATA_DIR_ARG}=");
+    let mut args = args.into_iter();
+    let mut rest = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg == DATA_DIR_ARG {
+            args.next();
+        } else if !arg.starts_with(&prefix) {
+            rest.push(arg);
+        }
+    }
+    rest
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Migration {
+    from: DataDirs,
+}
+
+fn pairs<'a>(from: &'a DataDirs, to: &'a DataDirs) -> [(&'a Path, &'a Path); 3] {
+    [
+        (&from.config, &to.config),
+        (&from.data, &to.data),
+        (&from.cache, &to.cache),
+    ]
+}
+
+/// Moves what `from` holds into `to`, over the files of the same names.
+fn move_contents(from: &Path, to: &Path) -> Result<()> {
+    let Ok(entries) = std::fs::read_dir(from) else {
+        return Ok(());
+    };
+    std::fs::create_dir_all(to)?;
+    for entry in entries {
+        let entry = entry?;
+        let source = entry.path();
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            if target.exists() || std::fs::rename(&source, &target).is_err() {
+                move_contents(&source, &target)?;
+            }
+        } else {
+            library::move_file(&source, &target)?;
+        }
+    }
+    // Left where the system put something of its own there
+    let _ = std::fs::remove_dir(from);
+    Ok(())
+}
+
+/// Moves the files of the old location in, when the last run asked to.
+fn finish_migration(app: &AppHandle) -> Result<bool> {
+    let dirs = DataDirs::base(app)?;
+    let plan = dirs.config.join(MIGRATION_FILE);
+    let Ok(bytes) = std::fs::read(&plan) else {
+        return Ok(false);
+    };
+    let migration: Migration = serde_json::from_slice(&bytes)?;
+    for (from, to) in pairs(&migration.from, &dirs) {
+        if from != to {
+            move_contents(from, to)?;
+        }
+    }
+    std::fs::remove_file(plan)?;
+    Ok(true)
+}
+
+/// Finishes a migration and lets the frontend at the portable directory,
+/// returning whether there was a migration. Runs first in setup, before any
+/// file is opened, so its result is logged by the caller once logging is.
+pub fn init(app: &AppHandle) -> Result<bool> {
+    if let Some(root) = root() {
+        crate::allow_dir_in_scopes(app, root);
+    }
+    finish_migration(app)
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DataDirInfo {
+    /// The portable directory, if any.
+    pub portable: Option<PathBuf>,
+    /// Whether the portable directory was given with `--data-dir`, so that
+    /// [`migrate_data_dir`] cannot change it.
+    pub from_arg: bool,
+    pub dirs: DataDirs,
+}
+
+#[command]
+pub fn get_data_dir(app: AppHandle) -> Result<DataDirInfo> {
+    Ok(DataDirInfo {
+        portable: root().map(Path::to_path_buf),
+        from_arg: FROM_ARG.load(Ordering::Relaxed),
+        dirs: DataDirs::current(&app)?,
+    })
+}
+
+/// Points the marker at `path`, relative to the executable when it is
+/// beside it so that it moves along, or removes the marker when `None`.
+fn write_marker(path: Option<&Path>) -> Result<()> {
+    let exe_dir =
+        exe_dir().ok_or_else(|| Error::DataDir("the executable has no directory".into()))?;
+    let marker = exe_dir.join(MARKER_FILE);
+    let result = match path {
+        Some(path) => {
+            let path = path.strip_prefix(&exe_dir).unwrap_or(path);
+            std::fs::write(&marker, path.to_string_lossy().as_bytes())
+        }
+        None => match std::fs::remove_file(&marker) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        },
+    };
+    result.map_err(|e| Error::DataDir(format!("failed to update {}: {e}", marker.display())))
+}
+
+/// Moves the files of the app to the portable directory `path`, or back to
+/// the directories of the system when `None`, and restarts to finish the
+/// move. The portable directory must be empty or not exist yet.
+#[command]
+pub fn migrate_data_dir(app: AppHandle, path: Option<PathBuf>) -> Result<()> {
+    if FROM_ARG.load(Ordering::Relaxed) {
+        return Err(Error::DataDir(format!(
+            "the data directory was given with {DATA_DIR_ARG}"
+        )));
+    }
+    // With those of every profile
+    let from = DataDirs::base(&app)?;
+    let system = DataDirs::system(&app)?;
+    let path = path.map(absolute);
+    let to = match &path {
+        Some(path) => DataDirs::portable(path, &system),
+        None => system,
+    };
+    if to == from {
+        return Ok(());
+    }
+    if let Some(path) = &path {
+        let overlaps = [&from.config, &from.data, &from.cache]
+            .into_iter()
+            .any(|dir| dir.starts_with(path) || path.starts_with(dir));
+        if overlaps {
+            return Err(Error::DataDir(format!(
+                "{} overlaps the current data directories",
+                path.display()
+            )));
+        }
+        if std::fs::read_dir(path).is_ok_and(|mut entries| entries.next().is_some()) {
+            return Err(Error::DataDir(format!("{} is not empty", path.display())));
+        }
+    }
+
+    std::fs::create_dir_all(&to.config)?;
+    let plan = to.config.join(MIGRATION_FILE);
+    std::fs::write(&plan, serde_json::to_vec_pretty(&Migration { from })?)?;
+    // The plan must not be found later if the app does not start from there
+    if let Err(e) = write_marker(path.as_deref()) {
+        let _ = std::fs::remove_file(&plan);
+        return Err(e);
+    }
+    app.restart();
+}
@@ -0,0 +1,155 @@
+//! The `plugin.json` that describes a plugin and the capabilities it asks
+//! for:
+//!
+//! ```json
+//! {
+//!   "id": "org.example.lit",
+//!   "name": "Microsoft Reader",
+//!   "version": "1.2.0",
+//!   "description": "Opens LIT books",
+//!   "author": "Example",
+//!   "apiVersion": 1,
+//!   "main": "plugin.wasm",
+//!   "capabilities": {
+//!     "metadata": false,
+//!     "formats": ["lit"],
+//!     "exports": [{ "id": "csv", "name": "Reading log", "extension": "csv" }],
+//!     "network": ["api.example.org", "*.example.net"]
+//!   }
+//! }
+//! ```
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+/// The version of the plugin API described in [`super`].
+pub const API_VERSION: u32 = 1;
+pub const MANIFEST_FILE: &str = "plugin.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    /// Reverse domain name, such as `org.example.lit`, which names the
+    /// folder the plugin is installed in.
+    pub id: String,
+    pub name: String,
+    pub version: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub author: String,
+    pub api_version: u32,
+    /// The WebAssembly module, next to the manifest.
+    #[serde(default = "default_main")]
+    pub main: String,
+    #[serde(default)]
+    pub capabilities: Capabilities,
+}
+
+fn default_main() -> String {
+    "plugin.wasm".to_string()
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Capabilities {
+    /// Proposes metadata for library books, through `vlarch_metadata`.
+    pub metadata: bool,
+    /// Extensions of the formats converted to EPUB through `vlarch_decode`.
+    pub formats: Vec<String>,
+    /// Formats annotations are exported to through `vlarch_export`.
+    pub exports: Vec<ExportTarget>,
+    /// Hosts `http_get` may fetch from; `*.example.org` also covers its
+    /// subdomains. Without any the plugin has no network access.
+    pub network: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportTarget {
+    pub id: String,
+    pub name: String,
+    /// Extension of the exported files, without the dot.
+    pub extension: String,
+}
+
+fn is_name(text: &str) -> bool {
+    !text.is_empty()
+        && text
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+        && !text.starts_with('.')
+}
+
+impl Manifest {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let manifest: Self = serde_json::from_slice(data)
+            .map_err(|e| Error::InvalidPlugin(format!("invalid {MANIFEST_FILE}: {e}")))?;
+        manifest.validate()?;
+        Ok(manifest)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let invalid = |message: String| Err(Error::InvalidPlugin(message));
+        if !is_name(&self.id) {
+            return invalid(format!("invalid id {:?}", self.id));
+        }
+        if self.api_version != API_VERSION {
+            return invalid(format!(
+                "{} needs plugin API {}, this version has {API_VERSION}",
+                self.id, self.api_version
+            ));
+        }
+        if !is_name(&self.main) || !self.main.ends_with(".wasm") {
+            return invalid(format!("invalid module name {:?}", self.main));
+        }
+        let capabilities = &self.capabilities;
+        if let Some(format) = capabilities
+            .formats
+            .iter()
+            .find(|format| format.is_empty() || !format.chars().all(|c| c.is_ascii_alphanumeric()))
+        {
+            return invalid(format!("invalid format extension {format:?}"));
+        }
+        if let Some(target) = capabilities
+            .exports
+            .iter()
+            .find(|target| !is_name(&target.id) || !is_name(&target.extension))
+        {
+            return invalid(format!("invalid export target {:?}", target.id));
+        }
+        if let Some(host) = capabilities.network.iter().find(|host| {
+            let host = host.strip_prefix("*.").unwrap_or(host);
+            !host.contains('.') || !is_name(host)
+        }) {
+            return invalid(format!("invalid network host {host:?}"));
+        }
+        Ok(())
+    }
+
+    /// Whether the plugin may fetch from `host`.
+    pub fn allows_host(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        self.capabilities.network.iter().any(|allowed| {
+            let allowed = allowed.to_ascii_lowercase();
+            match allowed.strip_prefix("*.") {
+                Some(domain) => {
+                    host == domain
+                        || host
+                            .strip_suffix(domain)
+                            .is_some_and(|sub| sub.ends_with('.'))
+                }
+                None => host == allowed,
+            }
+        })
+    }
+
+    /// Whether the plugin converts files with extension `ext`.
+    pub fn decodes(&self, ext: &str) -> bool {
+        self.capabilities
+            .formats
+            .iter()
+            .any(|format| format.eq_ignore_ascii_case(ext))
+    }
+}
@@ -0,0 +1,524 @@
+//! Third-party extensions as sandboxed WebAssembly modules. A plugin is a
+//! folder, or a zip of one, with a [`manifest`] and a module, installed
+//! under the app data dir. Its capabilities decide which hooks it provides:
+//!
+//! - metadata: proposes book metadata next to the online lookups of
+//!   [`crate::metadata::fetch`]
+//! - formats: converts files with other extensions to EPUB, like the
+//!   native converters in [`crate::convert`]
+//! - exports: writes highlights and notes in formats of its own
+//!
+//! Plugin API 1: data crosses the sandbox as `(ptr, len)` pairs in the
+//! plugin's memory, passed as two `i32`s and returned as one `i64`, `ptr`
+//! in the high half and 0 for nothing. Modules export `memory`,
+//! `vlarch_alloc(len) -> ptr` for the host to copy input into, and their
+//! hooks, each `(ptr, len) -> i64`:
+//!
+//! - `vlarch_metadata` takes `{ bookHash, current }` with the book's
+//!   current fields as in [`Fields`], and returns the fields it found,
+//!   plus a `coverUrl`, as JSON
+//! - `vlarch_decode` takes the bytes of the book file, and returns a
+//!   [`DecodedBook`] as JSON
+//! - `vlarch_export` takes `{ target, books }`, the export target id and
+//!   the books as in the JSON export of annotations, and returns the file
+//!
+//! They may import from module `vlarch`: `log(level, ptr, len)` with
+//! levels 0 to 3 from error to debug, `error(ptr, len)` to fail the call
+//! with a message, and with network hosts `http_get(ptr, len) -> i64`,
+//! which returns the body, -1 when the request failed or -2 when the host
+//! is not allowed. Nothing else can be imported, see [`runtime`].
+
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tauri::{command, AppHandle, Manager};
+use wasmi::{Engine, Module};
+
+use crate::convert::epub::{EpubBuilder, NavPoint};
+use crate::error::{Error, Result};
+use crate::export::annotations::{self, ExportFormat};
+use crate::formats::epub::Metadata;
+use crate::metadata::fetch::Fields;
+use crate::paths;
+use crate::store;
+use crate::utils::escape_xml;
+
+pub mod manifest;
+mod runtime;
+
+use manifest::{Manifest, MANIFEST_FILE};
+
+const PLUGINS_DIR: &str = "plugins";
+const SETTINGS_FILE: &str = "plugins.json";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct PluginSettings {
+    /// Ids of the installed plugins the user turned off.
+    disabled: Vec<String>,
+}
+
+/// An installed plugin, with its module unless it failed to compile.
+struct Loaded {
+    manifest: Manifest,
+    module: Option<Module>,
+    error: Option<String>,
+}
+
+struct Host {
+    engine: Engine,
+    plugins: Vec<Arc<Loaded>>,
+    settings: PluginSettings,
+}
+
+impl Loaded {
+    fn call(&self, engine: &Engine, hook: &str, input: &[u8]) -> Result<Option<Vec<u8>>> {
+        let module = self
+            .module
+            .as_ref()
+            .ok_or_else(|| Error::Plugin(format!("{} is not loaded", self.manifest.id)))?;
+        runtime::call(engine, &self.manifest, module, hook, input)
+    }
+}
+
+impl Host {
+    /// The plugins that are turned on and compiled.
+    fn enabled(&self) -> impl Iterator<Item = &Arc<Loaded>> {
+        self.plugins.iter().filter(|plugin| {
+            plugin.module.is_some() && !self.settings.disabled.contains(&plugin.manifest.id)
+        })
+    }
+
+    fn infos(&self) -> Vec<PluginInfo> {
+        self.plugins
+            .iter()
+            .map(|plugin| PluginInfo {
+                manifest: plugin.manifest.clone(),
+                enabled: !self.settings.disabled.contains(&plugin.manifest.id),
+                error: plugin.error.clone(),
+            })
+            .collect()
+    }
+}
+
+/// The installed plugins, loaded on first use.
+#[derive(Default)]
+pub struct Plugins(Mutex<Option<Host>>);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PluginInfo {
+    #[serde(flatten)]
+    pub manifest: Manifest,
+    pub enabled: bool,
+    /// Why the module could not be loaded.
+    pub error: Option<String>,
+}
+
+fn plugins_dir(app: &AppHandle) -> Result<PathBuf> {
+    Ok(paths::data_dir(app)?.join(PLUGINS_DIR))
+}
+
+/// Reads the plugin installed in `dir`, compiling its module.
+fn load(engine: &Engine, dir: &Path) -> Result<Loaded> {
+    let manifest = Manifest::parse(&std::fs::read(dir.join(MANIFEST_FILE))?)?;
+    let compiled = std::fs::read(dir.join(&manifest.main))
+        .map_err(Error::from)
+        .and_then(|wasm| runtime::compile(engine, &manifest, &wasm));
+    let (module, error) = match compiled {
+        Ok(module) => (Some(module), None),
+        Err(e) => {
+            log::warn!("Failed to load plugin {}: {e}", manifest.id);
+            (None, Some(e.to_string()))
+        }
+    };
+    Ok(Loaded {
+        manifest,
+        module,
+        error,
+    })
+}
+
+fn load_all(app: &AppHandle) -> Host {
+    let engine = runtime::engine();
+    let mut plugins = Vec::new();
+    let entries = plugins_dir(app).and_then(|dir| Ok(std::fs::read_dir(dir)?));
+    for entry in entries.into_iter().flatten().flatten() {
+        let dir = entry.path();
+        // Such as the staging folder of `install`
+        if !dir.is_dir() || entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        match load(&engine, &dir) {
+            Ok(plugin) => plugins.push(Arc::new(plugin)),
+            Err(e) => log::warn!("Skipping plugin in {dir:?}: {e}"),
+        }
+    }
+    plugins.sort_by_cached_key(|plugin| plugin.manifest.name.to_lowercase());
+    Host {
+        engine,
+        plugins,
+        settings: store::load(app, SETTINGS_FILE),
+    }
+}
+
+/// Runs `f` on the plugins, loading the installed ones first if this is
+/// the first call.
+fn with_host<T>(app: &AppHandle, f: impl FnOnce(&mut Host) -> T) -> T {
+    let state = app.state::<Plugins>();
+    let mut host = state.0.lock().unwrap();
+    f(host.get_or_insert_with(|| load_all(app)))
+}
+
+/// The manifest and module of the plugin at `source`, a folder or a zip.
+fn read_package(source: &Path) -> Result<(Vec<u8>, Manifest, Vec<u8>)> {
+    if source.is_dir() {
+        let data = std::fs::read(source.join(MANIFEST_FILE))?;
+        let manifest = Manifest::parse(&data)?;
+        let wasm = std::fs::read(source.join(&manifest.main))?;
+        return Ok((data, manifest, wasm));
+    }
+    let mut archive = zip::ZipArchive::new(std::fs::File::open(source)?)?;
+    let mut read = |name: &str| -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        archive.by_name(name)?.read_to_end(&mut data)?;
+        Ok(data)
+    };
+    let data = read(MANIFEST_FILE)?;
+    let manifest = Manifest::parse(&data)?;
+    let wasm = read(&manifest.main)?;
+    Ok((data, manifest, wasm))
+}
+
+/// Installs the plugin at `source`, replacing an installed version.
+fn install(app: &AppHandle, host: &mut Host, source: &Path) -> Result<()> {
+    let (data, manifest, wasm) = read_package(source)?;
+    let module = runtime::compile(&host.engine, &manifest, &wasm)?;
+
+    let dir = plugins_dir(app)?.join(&manifest.id);
+    let staging = plugins_dir(app)?.join(".installing");
+    if staging.exists() {
+        std::fs::remove_dir_all(&staging)?;
+    }
+    std::fs::create_dir_all(&staging)?;
+    std::fs::write(staging.join(MANIFEST_FILE), data)?;
+    std::fs::write(staging.join(&manifest.main), wasm)?;
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir)?;
+    }
+    std::fs::rename(&staging, &dir)?;
+
+    host.plugins
+        .retain(|plugin| plugin.manifest.id != manifest.id);
+    host.plugins.push(Arc::new(Loaded {
+        manifest,
+        module: Some(module),
+        error: None,
+    }));
+    host.plugins
+        .sort_by_cached_key(|plugin| plugin.manifest.name.to_lowercase());
+    Ok(())
+}
+
+/// What a metadata plugin found for a book.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FoundMetadata {
+    #[serde(flatten)]
+    pub fields: Fields,
+    pub cover_url: Option<String>,
+}
+
+/// Asks each metadata plugin about book `book_hash`, returning what they
+/// found by plugin id. Plugins that fail are logged and skipped.
+pub fn find_metadata(
+    app: &AppHandle,
+    book_hash: &str,
+    current: &Fields,
+) -> Result<Vec<(String, FoundMetadata)>> {
+    let (engine, plugins) = with_host(app, |host| {
+        let plugins = host
+            .enabled()
+            .filter(|plugin| plugin.manifest.capabilities.metadata)
+            .cloned()
+            .collect::<Vec<_>>();
+        (host.engine.clone(), plugins)
+    });
+    if plugins.is_empty() {
+        return Ok(Vec::new());
+    }
+    let input = serde_json::to_vec(&json!({ "bookHash": book_hash, "current": current }))?;
+    let mut found = Vec::new();
+    for plugin in plugins {
+        let manifest = &plugin.manifest;
+        let output =
+            plugin
+                .call(&engine, "vlarch_metadata", &input)
+                .and_then(|output| match output {
+                    Some(output) => Ok(Some(serde_json::from_slice::<FoundMetadata>(&output)?)),
+                    None => Ok(None),
+                });
+        match output {
+            Ok(Some(metadata)) => found.push((manifest.id.clone(), metadata)),
+            Ok(None) => {}
+            Err(e) => log::warn!("Plugin {} failed to find metadata: {e}", manifest.id),
+        }
+    }
+    Ok(found)
+}
+
+/// A book as a format plugin decodes it.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DecodedBook {
+    pub title: String,
+    #[serde(default)]
+    pub authors: Vec<String>,
+    pub language: Option<String>,
+    pub publisher: Option<String>,
+    pub description: Option<String>,
+    /// The text in reading order.
+    pub chapters: Vec<DecodedChapter>,
+    /// Images and stylesheets the chapters link to, by relative URL.
+    #[serde(default)]
+    pub resources: Vec<DecodedResource>,
+    pub cover: Option<DecodedResource>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DecodedChapter {
+    /// Table of contents entry, none for chapters left out of it.
+    pub title: Option<String>,
+    /// The content of the chapter's `body`.
+    pub html: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DecodedResource {
+    /// Such as `images/map.png`, as the chapters link to it.
+    pub href: String,
+    pub media_type: String,
+    /// The content, base64 encoded.
+    pub data: String,
+}
+
+/// The plugin that converts files like `path`, when no native converter
+/// does.
+pub struct Decoder {
+    engine: Engine,
+    plugin: Arc<Loaded>,
+}
+
+impl Decoder {
+    /// Changes with the plugin, so books are converted again on updates.
+    pub fn version(&self) -> String {
+        format!(
+            "{}@{}",
+            self.plugin.manifest.id, self.plugin.manifest.version
+        )
+    }
+
+    /// Converts the book at `source` into an EPUB at `output`.
+    pub fn decode(&self, source: &Path, output: &Path) -> Result<()> {
+        let manifest = &self.plugin.manifest;
+        let input = std::fs::read(source)?;
+        let decoded = self
+            .plugin
+            .call(&self.engine, "vlarch_decode", &input)?
+            .ok_or_else(|| Error::Plugin(format!("{} could not decode the book", manifest.id)))?;
+        let book = serde_json::from_slice::<DecodedBook>(&decoded)
+            .map_err(|e| Error::Plugin(format!("{}: invalid book: {e}", manifest.id)))?;
+        build_epub(book)?.write(output)
+    }
+}
+
+/// Whether `href` stays inside the book, so resources cannot be written
+/// over the package files or out of the archive.
+fn is_relative(href: &str) -> bool {
+    !href.is_empty()
+        && !href.starts_with('/')
+        && !href.contains('\\')
+        && !href.contains(':')
+        && href
+            .split('/')
+            .all(|part| !part.is_empty() && part != "." && part != "..")
+        && !matches!(href, "content.opf" | "nav.xhtml")
+}
+
+fn build_epub(book: DecodedBook) -> Result<EpubBuilder> {
+    let invalid = |message: String| Error::Plugin(format!("invalid book: {message}"));
+    let mut epub = EpubBuilder::new(Metadata {
+        title: book.title,
+        authors: book.authors,
+        language: book.language.into_iter().collect(),
+        publisher: book.publisher,
+        description: book.description,
+        ..Metadata::default()
+    });
+    if book.chapters.is_empty() {
+        return Err(invalid("no chapters".into()));
+    }
+    for (i, chapter) in book.chapters.into_iter().enumerate() {
+        let href = format!("chapter{:04}.xhtml", i + 1);
+        let title = chapter.title.unwrap_or_default();
+        let document = format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"/><title>{}</title></head>\n<body>{}</body></html>\n",
+            escape_xml(&title),
+            chapter.html
+        );
+        epub.add_chapter(&href, "text/html", document.into_bytes());
+        if !title.trim().is_empty() {
+            epub.toc.push(NavPoint {
+                label: title,
+                href,
+                children: Vec::new(),
+            });
+        }
+    }
+    let decode = |resource: &DecodedResource| {
+        if !is_relative(&resource.href) || resource.href.starts_with("chapter") {
+            return Err(invalid(format!("resource {:?}", resource.href)));
+        }
+        BASE64
+            .decode(&resource.data)
+            .map_err(|e| invalid(format!("resource {:?}: {e}", resource.href)))
+    };
+    for resource in &book.resources {
+        epub.add_resource(&resource.href, &resource.media_type, decode(resource)?);
+    }
+    if let Some(cover) = &book.cover {
+        epub.add_cover(&cover.href, &cover.media_type, decode(cover)?);
+    }
+    Ok(epub)
+}
+
+/// The enabled plugin that converts files with the extension of `path`.
+pub fn decoder(app: &AppHandle, path: &Path) -> Option<Decoder> {
+    let ext = path.extension()?.to_str()?;
+    with_host(app, |host| {
+        let plugin = host.enabled().find(|plugin| plugin.manifest.decodes(ext))?;
+        Some(Decoder {
+            engine: host.engine.clone(),
+            plugin: plugin.clone(),
+        })
+    })
+}
+
+#[command]
+pub async fn get_plugins(app: AppHandle) -> Result<Vec<PluginInfo>> {
+    // Loading compiles the modules
+    let plugins =
+        tauri::async_runtime::spawn_blocking(move || with_host(&app, |host| host.infos())).await?;
+    Ok(plugins)
+}
+
+/// Installs the plugin in folder or zip `path`, or updates it, turned on.
+#[command]
+pub async fn install_plugin(app: AppHandle, path: PathBuf) -> Result<Vec<PluginInfo>> {
+    tauri::async_runtime::spawn_blocking(move || {
+        with_host(&app, |host| {
+            install(&app, host, &path)?;
+            Ok(host.infos())
+        })
+    })
+    .await?
+}
+
+#[command]
+pub async fn uninstall_plugin(app: AppHandle, id: String) -> Result<Vec<PluginInfo>> {
+    tauri::async_runtime::spawn_blocking(move || {
+        with_host(&app, |host| {
+            if !host.plugins.iter().any(|plugin| plugin.manifest.id == id) {
+                return Err(Error::UnknownPlugin(id));
+            }
+            let dir = plugins_dir(&app)?.join(&id);
+            if dir.exists() {
+                std::fs::remove_dir_all(dir)?;
+            }
+            host.plugins.retain(|plugin| plugin.manifest.id != id);
+            host.settings.disabled.retain(|disabled| *disabled != id);
+            store::save(&app, SETTINGS_FILE, &host.settings)?;
+            Ok(host.infos())
+        })
+    })
+    .await?
+}
+
+#[command]
+pub async fn set_plugin_enabled(
+    app: AppHandle,
+    id: String,
+    enabled: bool,
+) -> Result<Vec<PluginInfo>> {
+    tauri::async_runtime::spawn_blocking(move || {
+        with_host(&app, |host| {
+            if !host.plugins.iter().any(|plugin| plugin.manifest.id == id) {
+                return Err(Error::UnknownPlugin(id));
+            }
+            host.settings.disabled.retain(|disabled| *disabled != id);
+            if !enabled {
+                host.settings.disabled.push(id);
+            }
+            store::save(&app, SETTINGS_FILE, &host.settings)?;
+            Ok(host.infos())
+        })
+    })
+    .await?
+}
+
+/// Loads the installed plugins again, picking up ones copied in by hand.
+#[command]
+pub async fn reload_plugins(app: AppHandle) -> Result<Vec<PluginInfo>> {
+    let plugins = tauri::async_runtime::spawn_blocking(move || {
+        let host = load_all(&app);
+        let infos = host.infos();
+        *app.state::<Plugins>().0.lock().unwrap() = Some(host);
+        infos
+    })
+    .await?;
+    Ok(plugins)
+}
+
+/// Exports the highlights and notes of `book_hash`, or of every book when
+/// omitted, through export `target` of plugin `id`, to a file the user
+/// picks. Returns where the file was written, or `None` if the dialog was
+/// cancelled.
+#[command]
+pub async fn export_with_plugin(
+    app: AppHandle,
+    id: String,
+    target: String,
+    book_hash: Option<String>,
+) -> Result<Option<String>> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let (engine, plugin) = with_host(&app, |host| {
+            host.enabled()
+                .find(|plugin| plugin.manifest.id == id)
+                .map(|plugin| (host.engine.clone(), plugin.clone()))
+        })
+        .ok_or_else(|| Error::UnknownPlugin(id.clone()))?;
+        let manifest = &plugin.manifest;
+        let export = manifest
+            .capabilities
+            .exports
+            .iter()
+            .find(|export| export.id == target)
+            .ok_or_else(|| Error::Plugin(format!("{id} has no export target {target}")))?;
+
+        let (name, books) = annotations::render(&app, book_hash.as_deref(), ExportFormat::Json)?;
+        let books = serde_json::from_str::<Value>(&books)?;
+        let input = serde_json::to_vec(&json!({ "target": target, "books": books }))?;
+        let content = plugin
+            .call(&engine, "vlarch_export", &input)?
+            .ok_or_else(|| Error::Plugin(format!("{id} exported nothing")))?;
+        annotations::save_with_dialog(&app, &name, &export.name, &export.extension, &content)
+    })
+    .await?
+}
@@ -0,0 +1,319 @@
+//! The sandbox plugins run in: a WebAssembly interpreter whose modules see
+//! nothing but their own memory and the `vlarch` functions their manifest
+//! grants. Each call gets a fresh instance with a memory and fuel budget,
+//! so a plugin keeps no state between calls and cannot run away.
+
+use std::time::Duration;
+
+use reqwest::redirect;
+use wasmi::{
+    Caller, Config, Engine, Extern, ExternType, Instance, Linker, Memory, Module, Store,
+    StoreLimits, StoreLimitsBuilder,
+};
+
+use super::manifest::Manifest;
+use crate::error::{Error, Result};
+use crate::net;
+
+/// Linear memory a call may grow to.
+const MAX_MEMORY: usize = 256 << 20;
+/// Instructions a call may run, roughly; a few seconds to a minute.
+const FUEL: u64 = 10_000_000_000;
+/// Largest response `http_get` hands to a plugin.
+const MAX_RESPONSE: usize = 16 << 20;
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_REDIRECTS: usize = 10;
+
+/// `http_get` results other than a response.
+const HTTP_FAILED: i64 = -1;
+const HTTP_DENIED: i64 = -2;
+
+/// Whether `manifest` grants host function `name`.
+fn grants(manifest: &Manifest, name: &str) -> bool {
+    match name {
+        "log" | "error" => true,
+        "http_get" => !manifest.capabilities.network.is_empty(),
+        _ => false,
+    }
+}
+
+struct HostState {
+    manifest: Manifest,
+    limits: StoreLimits,
+    /// Set by the plugin through `error` when a call fails.
+    error: Option<String>,
+}
+
+pub fn engine() -> Engine {
+    let mut config = Config::default();
+    config.consume_fuel(true);
+    Engine::new(&config)
+}
+
+fn invalid(manifest: &Manifest, message: impl std::fmt::Display) -> Error {
+    Error::InvalidPlugin(format!("{}: {message}", manifest.id))
+}
+
+fn failed(manifest: &Manifest, message: impl std::fmt::Display) -> Error {
+    Error::Plugin(format!("{}: {message}", manifest.id))
+}
+
+/// Compiles `wasm`, checking that it only imports what `manifest` grants
+/// and exports the hooks for the capabilities it declares.
+pub fn compile(engine: &Engine, manifest: &Manifest, wasm: &[u8]) -> Result<Module> {
+    let module = Module::new(engine, wasm).map_err(|e| invalid(manifest, e))?;
+    for import in module.imports() {
+        let granted = import.module() == "vlarch"
+            && matches!(import.ty(), ExternType::Func(_))
+            && grants(manifest, import.name());
+        if !granted {
+            return Err(invalid(
+                manifest,
+                format!(
+                    "imports {}.{}, which its capabilities do not grant",
+                    import.module(),
+                    import.name()
+                ),
+            ));
+        }
+    }
+    let capabilities = &manifest.capabilities;
+    let hooks = [
+        ("memory", true),
+        ("vlarch_alloc", true),
+        ("vlarch_metadata", capabilities.metadata),
+        ("vlarch_decode", !capabilities.formats.is_empty()),
+        ("vlarch_export", !capabilities.exports.is_empty()),
+    ];
+    if let Some((name, _)) = hooks
+        .iter()
+        .find(|(name, needed)| *needed && module.get_export(name).is_none())
+    {
+        return Err(invalid(manifest, format!("does not export {name}")));
+    }
+    Ok(module)
+}
+
+/// `ptr` and `len` of a plugin's memory, as `ptr << 32 | len`.
+fn pack(ptr: u32, len: u32) -> i64 {
+    (i64::from(ptr) << 32) | i64::from(len)
+}
+
+fn unpack(packed: i64) -> (usize, usize) {
+    (
+        (packed as u64 >> 32) as usize,
+        (packed as u64 & 0xffff_ffff) as usize,
+    )
+}
+
+fn memory(caller: &Caller<'_, HostState>) -> std::result::Result<Memory, wasmi::Error> {
+    caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| wasmi::Error::new("the plugin exports no memory"))
+}
+
+fn read_bytes(
+    caller: &Caller<'_, HostState>,
+    ptr: i32,
+    len: i32,
+) -> std::result::Result<Vec<u8>, wasmi::Error> {
+    let data = memory(caller)?.data(caller);
+    let start = ptr as u32 as usize;
+    start
+        .checked_add(len as u32 as usize)
+        .and_then(|end| data.get(start..end))
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| wasmi::Error::new("out of bounds memory access"))
+}
+
+fn read_string(
+    caller: &Caller<'_, HostState>,
+    ptr: i32,
+    len: i32,
+) -> std::result::Result<String, wasmi::Error> {
+    Ok(String::from_utf8_lossy(&read_bytes(caller, ptr, len)?).into_owned())
+}
+
+/// Copies `data` into memory the plugin allocates for it.
+fn write_bytes(
+    caller: &mut Caller<'_, HostState>,
+    data: &[u8],
+) -> std::result::Result<i64, wasmi::Error> {
+    let len = u32::try_from(data.len()).map_err(|_| wasmi::Error::new("data too large"))?;
+    let alloc = caller
+        .get_export("vlarch_alloc")
+        .and_then(Extern::into_func)
+        .ok_or_else(|| wasmi::Error::new("the plugin exports no vlarch_alloc"))?
+        .typed::<i32, i32>(&*caller)?;
+    let ptr = alloc.call(&mut *caller, len as i32)? as u32;
+    memory(caller)?
+        .write(&mut *caller, ptr as usize, data)
+        .map_err(|e| wasmi::Error::new(e.to_string()))?;
+    Ok(pack(ptr, len))
+}
+
+/// Whether `manifest` lets its plugin fetch `url`.
+fn may_fetch(manifest: &Manifest, url: &url::Url) -> bool {
+    matches!(url.scheme(), "https" | "http")
+        && url
+            .host_str()
+            .is_some_and(|host| manifest.allows_host(host))
+}
+
+/// Fetches `url`, which is on a host `manifest` allows, and so must be
+/// every host it redirects to.
+async fn fetch(manifest: &Manifest, url: &str) -> Result<Vec<u8>> {
+    let allowed = manifest.clone();
+    let policy = redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            attempt.error("too many redirects")
+        } else if may_fetch(&allowed, attempt.url()) {
+            attempt.follow()
+        } else {
+            let denied = format!("redirect to {} is not allowed", attempt.url());
+            attempt.error(denied)
+        }
+    });
+    let mut response = net::client_with_redirects(policy)?
+        .get(url)
+        .timeout(HTTP_TIMEOUT)
+        .send()
+        .await?
+        .error_for_status()?;
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > MAX_RESPONSE {
+            return Err(Error::Plugin(format!("response of {url} is too large")));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+fn http_get(
+    mut caller: Caller<'_, HostState>,
+    ptr: i32,
+    len: i32,
+) -> std::result::Result<i64, wasmi::Error> {
+    let url = read_string(&caller, ptr, len)?;
+    let manifest = caller.data().manifest.clone();
+    if !url::Url::parse(&url).is_ok_and(|parsed| may_fetch(&manifest, &parsed)) {
+        log::warn!("Plugin {} may not fetch {url}", manifest.id);
+        return Ok(HTTP_DENIED);
+    }
+    // Plugins run on blocking threads, see `call`
+    match tauri::async_runtime::block_on(fetch(&manifest, &url)) {
+        Ok(body) => write_bytes(&mut caller, &body),
+        Err(e) => {
+            log::warn!("Plugin {} failed to fetch {url}: {e}", manifest.id);
+            Ok(HTTP_FAILED)
+        }
+    }
+}
+
+fn linker(engine: &Engine) -> Result<Linker<HostState>> {
+    let mut linker = Linker::new(engine);
+    linker
+        .func_wrap(
+            "vlarch",
+            "log",
+            |caller: Caller<'_, HostState>, level: i32, ptr: i32, len: i32| {
+                let message = read_string(&caller, ptr, len)?;
+                let id = &caller.data().manifest.id;
+                match level {
+                    0 => log::error!("[{id}] {message}"),
+                    1 => log::warn!("[{id}] {message}"),
+                    2 => log::info!("[{id}] {message}"),
+                    _ => log::debug!("[{id}] {message}"),
+                }
+                Ok(())
+            },
+        )
+        .and_then(|linker| {
+            linker.func_wrap(
+                "vlarch",
+                "error",
+                |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| {
+                    let message = read_string(&caller, ptr, len)?;
+                    caller.data_mut().error = Some(message);
+                    Ok(())
+                },
+            )
+        })
+        .and_then(|linker| linker.func_wrap("vlarch", "http_get", http_get))
+        .map_err(|e| Error::Plugin(e.to_string()))?;
+    Ok(linker)
+}
+
+fn instantiate(
+    engine: &Engine,
+    manifest: &Manifest,
+    module: &Module,
+) -> Result<(Store<HostState>, Instance)> {
+    let mut store = Store::new(
+        engine,
+        HostState {
+            manifest: manifest.clone(),
+            limits: StoreLimitsBuilder::new()
+                .memory_size(MAX_MEMORY)
+                .instances(1)
+                .build(),
+            error: None,
+        },
+    );
+    store.limiter(|state| &mut state.limits);
+    store.set_fuel(FUEL).map_err(|e| failed(manifest, e))?;
+    let instance = linker(engine)?
+        .instantiate(&mut store, module)
+        .and_then(|pre| pre.start(&mut store))
+        .map_err(|e| failed(manifest, e))?;
+    // Modules built as WASI reactors set themselves up here
+    if let Ok(initialize) = instance.get_typed_func::<(), ()>(&store, "_initialize") {
+        initialize
+            .call(&mut store, ())
+            .map_err(|e| failed(manifest, e))?;
+    }
+    Ok((store, instance))
+}
+
+/// Calls export `hook` of a fresh instance of `module` with `input`, and
+/// returns what it returned, or `None` when it returned nothing. Blocks
+/// until the plugin is done, so it has to run on a blocking thread.
+pub fn call(
+    engine: &Engine,
+    manifest: &Manifest,
+    module: &Module,
+    hook: &str,
+    input: &[u8],
+) -> Result<Option<Vec<u8>>> {
+    let (mut store, instance) = instantiate(engine, manifest, module)?;
+    let memory = instance
+        .get_memory(&store, "memory")
+        .ok_or_else(|| invalid(manifest, "does not export memory"))?;
+    let len = i32::try_from(input.len()).map_err(|_| failed(manifest, "input too large"))?;
+    let ptr = instance
+        .get_typed_func::<i32, i32>(&store, "vlarch_alloc")
+        .and_then(|alloc| alloc.call(&mut store, len))
+        .map_err(|e| failed(manifest, e))?;
+    memory
+        .write(&mut store, ptr as u32 as usize, input)
+        .map_err(|e| failed(manifest, e))?;
+
+    let result = instance
+        .get_typed_func::<(i32, i32), i64>(&store, hook)
+        .and_then(|hook| hook.call(&mut store, (ptr, len)));
+    if let Some(error) = store.data_mut().error.take() {
+        return Err(failed(manifest, error));
+    }
+    let packed = result.map_err(|e| failed(manifest, e))?;
+    if packed == 0 {
+        return Ok(None);
+    }
+    let (ptr, len) = unpack(packed);
+    let data = memory.data(&store);
+    ptr.checked_add(len)
+        .and_then(|end| data.get(ptr..end))
+        .map(|output| Some(output.to_vec()))
+        .ok_or_else(|| failed(manifest, "returned data out of bounds"))
+}
@@ -0,0 +1,164 @@
+//! Printing through a PDF rendered natively, since printing the webview
+//! breaks pages in the middle of lines and falls back to system fonts. PDF
+//! pages are copied as they are; EPUB chapters and selections are set as
+//! text in a font the book embeds when one covers it. The result goes to
+//! the system print dialog, or on Linux, which has none of its own, to the
+//! default PDF viewer.
+
+use std::path::{Path, PathBuf};
+#[cfg(any(target_os = "macos", windows))]
+use std::process::Command;
+
+use serde::Deserialize;
+use tauri::{command, AppHandle};
+
+use crate::error::{Error, Result};
+use crate::export::pdf::{book_font, typeset, Block, Font, PageSetup};
+use crate::formats::epub::EpubArchive;
+use crate::formats::html::html_to_text;
+use crate::formats::pdf::PdfDocument;
+use crate::paths;
+
+const PRINT_DIR: &str = "print";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum PrintRange {
+    /// A spine document of an EPUB.
+    Chapter { href: String },
+    /// Pages `from..=to` of a PDF, numbered from 1.
+    Pages { from: u32, to: u32 },
+    /// Text selected in the reader, one paragraph per line.
+    Selection { text: String },
+}
+
+fn print_error(message: impl Into<String>) -> Error {
+    Error::Print(message.into())
+}
+
+fn extension(path: &Path) -> String {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+/// Text and title of the spine document `href`, which may carry a fragment.
+fn chapter_text(
+    epub: &mut EpubArchive<impl std::io::Read + std::io::Seek>,
+    href: &str,
+) -> Result<(String, String)> {
+    let href = href.split('#').next().unwrap_or_default();
+    let package = epub.package()?;
+    let item = package
+        .spine_items()
+        .find(|item| item.href == href || item.href.ends_with(&format!("/{href}")))
+        .ok_or_else(|| print_error(format!("no chapter {href}")))?;
+    let text = html_to_text(&epub.read_entry_string(&item.href)?);
+    let title = text
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .unwrap_or(&package.metadata.title)
+        .to_string();
+    Ok((text, title))
+}
+
+/// Renders `range` of the book at `path` to a PDF in `dir`.
+fn render(path: &Path, range: &PrintRange, dir: &Path) -> Result<PathBuf> {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    std::fs::create_dir_all(dir)?;
+    let dest = dir.join(format!("{stem}.pdf"));
+    let setup = PageSetup::default();
+    let mut doc = match (extension(path).as_str(), range) {
+        ("pdf", PrintRange::Pages { from, to }) => {
+            PdfDocument::open(path)?.save_pages(*from, *to, &dest)?;
+            return Ok(dest);
+        }
+        ("pdf", PrintRange::Selection { text }) => {
+            typeset(&Block::paragraphs(text), &Font::Times, &stem, &setup)?
+        }
+        ("epub", PrintRange::Chapter { href }) => {
+            let mut epub = EpubArchive::open(path)?;
+            let (text, title) = chapter_text(&mut epub, href)?;
+            let font = book_font(&mut epub, &text);
+            typeset(&Block::paragraphs(&text), &font, &title, &setup)?
+        }
+        ("epub", PrintRange::Selection { text }) => {
+            let mut epub = EpubArchive::open(path)?;
+            let font = book_font(&mut epub, text);
+            typeset(&Block::paragraphs(text), &font, &stem, &setup)?
+        }
+        ("pdf" | "epub", range) => {
+            return Err(print_error(format!("cannot print {range:?} of this book")))
+        }
+        (ext, _) => return Err(Error::UnsupportedFormat(ext.to_string())),
+    };
+    doc.save(&dest)?;
+    Ok(dest)
+}
+
+#[cfg(any(target_os = "macos", windows))]
+fn run(command: &mut Command) -> Result<()> {
+    let output = command.output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(print_error(stderr.trim().to_string()));
+    }
+    Ok(())
+}
+
+/// Prints through Preview, which shows the standard print sheet.
+#[cfg(target_os = "macos")]
+fn open_print_dialog(_app: &AppHandle, pdf: &Path) -> Result<()> {
+    run(Command::new("osascript")
+        .args([
+            "-e",
+            "on run argv",
+            "-e",
+            "tell application \"Preview\"",
+            "-e",
+            "activate",
+            "-e",
+            "print POSIX file (item 1 of argv) with print dialog",
+            "-e",
+            "end tell",
+            "-e",
+            "end run",
+        ])
+        .arg(pdf))
+}
+
+/// Uses the Print verb of the default PDF handler, which shows its dialog.
+#[cfg(windows)]
+fn open_print_dialog(_app: &AppHandle, pdf: &Path) -> Result<()> {
+    let pdf = pdf.to_string_lossy().replace('\'', "''");
+    let script = format!("Start-Process -FilePath '{pdf}' -Verb Print");
+    run(Command::new("powershell").args(["-NoProfile", "-NonInteractive", "-Command", &script]))
+}
+
+#[cfg(target_os = "linux")]
+fn open_print_dialog(app: &AppHandle, pdf: &Path) -> Result<()> {
+    use tauri_plugin_opener::OpenerExt;
+
+    app.opener()
+        .open_path(pdf.to_string_lossy(), None::<&str>)
+        .map_err(|e| print_error(e.to_string()))
+}
+
+/// Renders `range` of the book at `path` to a PDF and opens the print
+/// dialog on it. Returns the PDF, which stays in the cache dir.
+#[command]
+pub async fn print_chapter(app: AppHandle, path: PathBuf, range: PrintRange) -> Result<PathBuf> {
+    let dir = paths::cache_dir(&app)?.join(PRINT_DIR);
+    // Preview only returns once its dialog is closed
+    tauri::async_runtime::spawn_blocking(move || {
+        let pdf = render(&path, &range, &dir)?;
+        open_print_dialog(&app, &pdf)?;
+        Ok(pdf)
+    })
+    .await?
+}
@@ -0,0 +1,239 @@
+//! Profiles, so that the people sharing a device each have their library,
+//! reading progress and settings. The default profile keeps the files of
+//! the app where they always were; the others keep theirs under
+//! `profiles/<id>` (see [`crate::paths`]), along with the storage of their
+//! webviews and their keychain entries. A run has one profile: switching
+//! restarts the app into the other, and so does picking one other than the
+//! last at startup, which the frontend offers when `showPicker` is set.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle, Manager, State};
+
+use crate::error::{Error, Result};
+use crate::paths;
+use crate::store;
+use crate::utils::{format_rfc3339, now_millis};
+
+/// Shared by all profiles, in the config directory they are under.
+const PROFILES_FILE: &str = "profiles.json";
+pub const DEFAULT_PROFILE: &str = "default";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Profile {
+    pub id: String,
+    pub name: String,
+    pub created_at: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct ProfilesFile {
+    /// The profiles besides the default one.
+    profiles: Vec<Profile>,
+    /// Name the user gave the default profile.
+    default_name: Option<String>,
+    current: String,
+    pick_at_startup: bool,
+    /// Set when the app restarts into the profile just picked, so that the
+    /// picker does not show again.
+    switched: bool,
+}
+
+impl Default for ProfilesFile {
+    fn default() -> Self {
+        Self {
+            profiles: Vec::new(),
+            default_name: None,
+            current: DEFAULT_PROFILE.into(),
+            pick_at_startup: true,
+            switched: false,
+        }
+    }
+}
+
+impl ProfilesFile {
+    fn default_profile(&self) -> Profile {
+        // Named after the account the app was first used from
+        let name = self.default_name.clone().unwrap_or_else(|| {
+            std::env::var("USER")
+                .or_else(|_| std::env::var("USERNAME"))
+                .unwrap_or_else(|_| "Default".into())
+        });
+        Profile {
+            id: DEFAULT_PROFILE.into(),
+            name,
+            created_at: String::new(),
+        }
+    }
+
+    fn all(&self) -> Vec<Profile> {
+        std::iter::once(self.default_profile())
+            .chain(self.profiles.iter().cloned())
+            .collect()
+    }
+
+    fn contains(&self, id: &str) -> bool {
+        id == DEFAULT_PROFILE || self.profiles.iter().any(|profile| profile.id == id)
+    }
+}
+
+/// Whether the picker is due this run.
+#[derive(Default)]
+pub struct Startup(AtomicBool);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProfileList {
+    pub profiles: Vec<Profile>,
+    pub current: String,
+    pub pick_at_startup: bool,
+    /// Whether the frontend should let the user pick a profile before the
+    /// library opens.
+    pub show_picker: bool,
+}
+
+fn load(app: &AppHandle) -> ProfilesFile {
+    store::load_shared(app, PROFILES_FILE)
+}
+
+fn save(app: &AppHandle, file: &ProfilesFile) -> Result<()> {
+    store::save_shared(app, PROFILES_FILE, file)
+}
+
+fn profile_error(message: impl Into<String>) -> Error {
+    Error::Profile(message.into())
+}
+
+/// Picks the profile of this run, the one last used. Runs in setup before
+/// anything resolves a directory of the app.
+pub fn init(app: &AppHandle) {
+    let mut file = load(app);
+    if !file.contains(&file.current) {
+        file.current = DEFAULT_PROFILE.into();
+    }
+    let show_picker = file.pick_at_startup && !file.profiles.is_empty() && !file.switched;
+    if file.switched {
+        file.switched = false;
+        if let Err(e) = save(app, &file) {
+            log::warn!("Failed to save the profiles: {e}");
+        }
+    }
+    app.manage(Startup(AtomicBool::new(show_picker)));
+    paths::set_profile((file.current != DEFAULT_PROFILE).then_some(file.current));
+}
+
+#[command]
+pub fn list_profiles(app: AppHandle, startup: State<'_, Startup>) -> ProfileList {
+    let file = load(&app);
+    ProfileList {
+        profiles: file.all(),
+        current: paths::profile().unwrap_or(DEFAULT_PROFILE).into(),
+        pick_at_startup: file.pick_at_startup,
+        show_picker: startup.0.load(Ordering::Relaxed),
+    }
+}
+
+#[command]
+pub fn create_profile(app: AppHandle, name: String) -> Result<Profile> {
+    let name = name.trim().to_string();
+    if name.is_empty() {
+        return Err(profile_error("a profile needs a name"));
+    }
+    let mut file = load(&app);
+    let id: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(8)
+        .map(char::from)
+        .collect::<String>()
+        .to_lowercase();
+    let profile = Profile {
+        id,
+        name,
+        created_at: format_rfc3339(now_millis()),
+    };
+    let dirs = paths::profile_dirs(&app, &profile.id)?;
+    for dir in [&dirs.config, &dirs.data, &dirs.cache] {
+        std::fs::create_dir_all(dir)?;
+    }
+    file.profiles.push(profile.clone());
+    save(&app, &file)?;
+    Ok(profile)
+}
+
+#[command]
+pub fn rename_profile(app: AppHandle, id: String, name: String) -> Result<()> {
+    let name = name.trim().to_string();
+    if name.is_empty() {
+        return Err(profile_error("a profile needs a name"));
+    }
+    let mut file = load(&app);
+    if id == DEFAULT_PROFILE {
+        file.default_name = Some(name);
+    } else {
+        file.profiles
+            .iter_mut()
+            .find(|profile| profile.id == id)
+            .ok_or_else(|| profile_error(format!("no profile {id}")))?
+            .name = name;
+    }
+    save(&app, &file)
+}
+
+/// Switches to profile `id`, restarting the app into it unless it is the
+/// current one, in which case the picker is only dismissed.
+#[command]
+pub fn switch_profile(app: AppHandle, startup: State<'_, Startup>, id: String) -> Result<()> {
+    startup.0.store(false, Ordering::Relaxed);
+    let mut file = load(&app);
+    if !file.contains(&id) {
+        return Err(profile_error(format!("no profile {id}")));
+    }
+    if id == paths::profile().unwrap_or(DEFAULT_PROFILE) {
+        return Ok(());
+    }
+    file.current = id;
+    file.switched = true;
+    save(&app, &file)?;
+    app.restart();
+}
+
+/// Deletes profile `id` with all its files. Neither the default profile
+/// nor the current one can be deleted.
+#[command]
+pub async fn delete_profile(app: AppHandle, id: String) -> Result<()> {
+    if id == DEFAULT_PROFILE || Some(id.as_str()) == paths::profile() {
+        return Err(profile_error(
+            "the default and the current profile cannot be deleted",
+        ));
+    }
+    let mut file = load(&app);
+    let len = file.profiles.len();
+    file.profiles.retain(|profile| profile.id != id);
+    if file.profiles.len() == len {
+        return Err(profile_error(format!("no profile {id}")));
+    }
+    save(&app, &file)?;
+    let dirs = paths::profile_dirs(&app, &id)?;
+    tauri::async_runtime::spawn_blocking(move || {
+        for dir in [&dirs.config, &dirs.data, &dirs.cache] {
+            match std::fs::remove_dir_all(dir) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    })
+    .await?
+}
+
+#[command]
+pub fn set_pick_profile_at_startup(app: AppHandle, enabled: bool) -> Result<()> {
+    let mut file = load(&app);
+    file.pick_at_startup = enabled;
+    save(&app, &file)
+}
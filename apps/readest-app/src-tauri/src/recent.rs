@@ -0,0 +1,117 @@
+//! Books opened lately, listed in the "Open Recent" menu and the Dock on
+//! macOS and in the Jump List on Windows. The frontend reports each book it
+//! opens; picking one from the list opens it like a `vlarch://open` link.
+//! On Linux each book is also added to the desktop's recently used files,
+//! which clearing the list leaves alone as other apps share them.
+
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle, Manager};
+
+use crate::error::Result;
+use crate::library::{self, access, db};
+use crate::store;
+
+const RECENT_BOOKS_FILE: &str = "recent-books.json";
+const MAX_RECENT_BOOKS: usize = 10;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecentBook {
+    pub hash: String,
+    pub title: String,
+    /// Location of the book file, when it is available locally.
+    pub path: Option<std::path::PathBuf>,
+}
+
+/// The stored hashes, most recent first, that are still in the library.
+pub fn recent_books(app: &AppHandle) -> Result<Vec<RecentBook>> {
+    let hashes: Vec<String> = store::load(app, RECENT_BOOKS_FILE);
+    let db = app.state::<db::LibraryDb>();
+    let conn = db.conn();
+    let lock = access::lock(app, &conn)?;
+    let mut books = Vec::new();
+    for hash in hashes {
+        let Some(book) = db::get_unlocked_book(&conn, &hash, &lock)? else {
+            continue;
+        };
+        if book.deleted_at.is_some() {
+            continue;
+        }
+        books.push(RecentBook {
+            path: library::book_path(app, &book),
+            hash: book.hash,
+            title: book.title,
+        });
+    }
+    Ok(books)
+}
+
+/// Shows `books` in the platform menus and the tray.
+fn show(app: &AppHandle, books: &[RecentBook]) -> Result<()> {
+    #[cfg(target_os = "macos")]
+    crate::macos::menu::set_recent_books(app, books)?;
+    #[cfg(windows)]
+    crate::windows::jumplist::set_recent_books(books)?;
+    #[cfg(not(any(target_os = "macos", windows)))]
+    let _ = books;
+    crate::tray::refresh(app)
+}
+
+/// Lists the recent books again, e.g. in menus that were rebuilt.
+pub fn refresh(app: &AppHandle) -> Result<()> {
+    show(app, &recent_books(app)?)
+}
+
+/// Fills the platform menus at startup, dropping books removed since.
+pub fn init(app: &AppHandle) {
+    let app = app.clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        if let Err(e) = refresh(&app) {
+            log::warn!("Failed to show recent books: {e}");
+        }
+    });
+}
+
+/// Empties the list, e.g. from the "Clear Menu" item.
+pub fn clear(app: &AppHandle) -> Result<()> {
+    store::save(app, RECENT_BOOKS_FILE, &Vec::<String>::new())?;
+    #[cfg(target_os = "macos")]
+    crate::macos::menu::clear_recent_documents(app)?;
+    refresh(app)
+}
+
+/// Moves `book_hash` to the top of the recent books.
+#[command]
+pub async fn add_recent_book(app: AppHandle, book_hash: String) -> Result<()> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let mut hashes: Vec<String> = store::load(&app, RECENT_BOOKS_FILE);
+        hashes.retain(|hash| *hash != book_hash);
+        hashes.insert(0, book_hash.clone());
+        hashes.truncate(MAX_RECENT_BOOKS);
+        store::save(&app, RECENT_BOOKS_FILE, &hashes)?;
+
+        let books = recent_books(&app)?;
+        #[cfg(target_os = "macos")]
+        if let Some(book) = books.first().filter(|book| book.hash == book_hash) {
+            crate::macos::menu::note_recent_document(&app, book)?;
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(book) = books.first().filter(|book| book.hash == book_hash) {
+            if let Err(e) = crate::linux::recent_files::add(&app, book) {
+                log::warn!("Failed to add {} to the recent files: {e}", book.title);
+            }
+        }
+        show(&app, &books)
+    })
+    .await?
+}
+
+#[command]
+pub async fn clear_recent_books(app: AppHandle) -> Result<()> {
+    tauri::async_runtime::spawn_blocking(move || clear(&app)).await?
+}
+
+#[command]
+pub async fn get_recent_books(app: AppHandle) -> Result<Vec<RecentBook>> {
+    tauri::async_runtime::spawn_blocking(move || recent_books(&app)).await?
+}
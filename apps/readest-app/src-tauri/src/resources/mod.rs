@@ -0,0 +1,511 @@
+//! Streams resources straight out of zipped books (EPUB, CBZ, FBZ) over the
+//! `book://` protocol, so opening a book does not unpack it anywhere. A
+//! library book is registered with [`open_book_resources`] and its entries
+//! are then served as `book://localhost/<id>/<entry path>`, with support for
+//! range requests.
+//! Archives are memory-mapped, see [`MappedArchive`]. With the `lcp`
+//! feature, the entries of LCP-protected EPUBs are decrypted as they are
+//! served, once their license is unlocked. Chapters of books with text
+//! transforms turned on are served transformed, see [`crate::typeset::transform`],
+//! with the user styles that apply to them, see [`crate::typeset::styles`],
+//! and images are converted and scaled for the webview, see [`crate::images`].
+//! The chapters after the one being read are made ready ahead, see [`prefetch`].
+
+pub mod prefetch;
+
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use percent_encoding::percent_decode_str;
+use tauri::http::{header, Request, Response, StatusCode};
+use tauri::{command, AppHandle, Manager, State};
+
+use crate::error::{Error, Result};
+use crate::formats::epub::stream::MappedArchive;
+use crate::images;
+use crate::library::{self, access, db};
+use crate::typeset::styles::{self, Injection};
+use crate::typeset::transform::{self, Transforms};
+use prefetch::Prefetcher;
+
+pub const PROTOCOL: &str = "book";
+
+/// Archives kept open between requests; others are reopened on demand.
+const MAX_OPEN_ARCHIVES: usize = 4;
+
+/// A zipped book with, when it is LCP-protected, what decrypts its entries.
+struct BookArchive {
+    zip: MappedArchive,
+    #[cfg(all(desktop, feature = "lcp"))]
+    lcp: Option<crate::drm::lcp::Decryptor>,
+}
+
+impl BookArchive {
+    fn size(&mut self, name: &str) -> Result<Option<u64>> {
+        #[cfg(all(desktop, feature = "lcp"))]
+        if let Some(lcp) = self.lcp.as_ref().filter(|lcp| lcp.is_encrypted(name)) {
+            return lcp.size(&mut self.zip, name);
+        }
+        self.zip.size(name)
+    }
+
+    fn read_range(&mut self, name: &str, range: std::ops::Range<u64>) -> Result<Vec<u8>> {
+        #[cfg(all(desktop, feature = "lcp"))]
+        if let Some(lcp) = self.lcp.as_ref().filter(|lcp| lcp.is_encrypted(name)) {
+            return lcp.read_range(&mut self.zip, name, range);
+        }
+        Ok(self.zip.read_range(name, range)?.into_vec())
+    }
+
+    fn read(&mut self, name: &str) -> Result<Vec<u8>> {
+        #[cfg(all(desktop, feature = "lcp"))]
+        if let Some(lcp) = self.lcp.as_ref().filter(|lcp| lcp.is_encrypted(name)) {
+            return lcp.read_range(&mut self.zip, name, 0..u64::MAX);
+        }
+        Ok(self.zip.read(name)?.into_vec())
+    }
+}
+
+type Archive = Arc<Mutex<BookArchive>>;
+
+#[derive(Clone)]
+struct Registered {
+    path: PathBuf,
+    /// Hash of the book in the library, for its settings.
+    book_hash: String,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Registered books by id. Only these files can be read through the protocol.
+    books: HashMap<String, Registered>,
+    /// Recently used archives, most recent last.
+    open: Vec<(String, Archive)>,
+}
+
+#[derive(Default)]
+pub struct BookResources(Mutex<Inner>);
+
+impl BookResources {
+    fn archive(&self, app: &AppHandle, id: &str) -> Option<(Registered, Result<Archive>)> {
+        let mut inner = self.0.lock().unwrap();
+        let book = inner.books.get(id)?.clone();
+        if let Some(i) = inner.open.iter().position(|(open, _)| open == id) {
+            let entry = inner.open.remove(i);
+            let archive = entry.1.clone();
+            inner.open.push(entry);
+            return Some((book, Ok(archive)));
+        }
+        let archive = match open_archive(app, &book.path) {
+            Ok(archive) => archive,
+            Err(e) => return Some((book, Err(e))),
+        };
+        if inner.open.len() >= MAX_OPEN_ARCHIVES {
+            inner.open.remove(0);
+        }
+        inner.open.push((id.to_string(), archive.clone()));
+        Some((book, Ok(archive)))
+    }
+}
+
+fn open_archive(app: &AppHandle, path: &Path) -> Result<Archive> {
+    #[allow(unused_mut)]
+    let mut zip = MappedArchive::open(path)?;
+    #[cfg(all(desktop, feature = "lcp"))]
+    let lcp = crate::drm::lcp::decryptor(app, &mut zip)?;
+    #[cfg(not(all(desktop, feature = "lcp")))]
+    let _ = app;
+    Ok(Arc::new(Mutex::new(BookArchive {
+        zip,
+        #[cfg(all(desktop, feature = "lcp"))]
+        lcp,
+    })))
+}
+
+fn book_id(path: &Path) -> String {
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// The file of library book `hash`, unless it is deleted or locked. Only
+/// these are served, the webview does not name files.
+fn library_file(app: &AppHandle, hash: &str) -> Result<PathBuf> {
+    library::check_hash(hash)?;
+    let db = app.state::<db::LibraryDb>();
+    let book = {
+        let conn = db.conn();
+        let lock = access::lock(app, &conn)?;
+        db::get_unlocked_book(&conn, hash, &lock)?
+    };
+    let path = book
+        .filter(|book| book.deleted_at.is_none())
+        .and_then(|book| library::book_path(app, &book))
+        .ok_or_else(|| Error::InvalidBook(format!("no book {hash} in the library")))?;
+    Ok(std::fs::canonicalize(path)?)
+}
+
+/// Registers zipped library book `book_hash` for streaming, with its text
+/// transforms, and returns the id to use in `book://` URLs.
+#[command]
+pub async fn open_book_resources(
+    app: AppHandle,
+    resources: State<'_, BookResources>,
+    book_hash: String,
+) -> Result<String> {
+    let (path, archive) = tauri::async_runtime::spawn_blocking({
+        let book_hash = book_hash.clone();
+        move || {
+            let path = library_file(&app, &book_hash)?;
+            let archive = open_archive(&app, &path)?;
+            Ok::<_, Error>((path, archive))
+        }
+    })
+    .await??;
+    let id = book_id(&path);
+    let mut inner = resources.0.lock().unwrap();
+    inner
+        .books
+        .insert(id.clone(), Registered { path, book_hash });
+    inner.open.retain(|(open, _)| open != &id);
+    if inner.open.len() >= MAX_OPEN_ARCHIVES {
+        inner.open.remove(0);
+    }
+    inner.open.push((id.clone(), archive));
+    Ok(id)
+}
+
+#[command]
+pub fn close_book_resources(
+    resources: State<'_, BookResources>,
+    prefetcher: State<'_, Prefetcher>,
+    id: String,
+) {
+    let mut inner = resources.0.lock().unwrap();
+    inner.books.remove(&id);
+    inner.open.retain(|(open, _)| open != &id);
+    prefetcher.forget(&id);
+}
+
+fn content_type(name: &str) -> &'static str {
+    let ext = name
+        .rsplit_once('.')
+        .map(|(_, ext)| ext)
+        .unwrap_or_default();
+    match ext.to_ascii_lowercase().as_str() {
+        "xhtml" | "xht" => "application/xhtml+xml",
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "js" => "text/javascript",
+        "xml" | "opf" | "ncx" | "smil" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "heic" | "heif" => "image/heic",
+        "jxl" => "image/jxl",
+        "bmp" => "image/bmp",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "mp3" => "audio/mpeg",
+        "m4a" => "audio/mp4",
+        "ogg" | "oga" => "audio/ogg",
+        "mp4" | "m4v" => "video/mp4",
+        "webm" => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Parses a single `bytes=` range against a resource of `size` bytes into an
+/// inclusive `(start, end)`. Returns `Err` when the range cannot be satisfied.
+pub(crate) fn parse_range(value: &str, size: u64) -> std::result::Result<Option<(u64, u64)>, ()> {
+    let Some(spec) = value.trim().strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    // Multiple ranges are rare for media; serve the whole resource instead
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let (start, end) = spec.split_once('-').ok_or(())?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix = suffix.parse::<u64>().map_err(|_| ())?;
+            (size.saturating_sub(suffix), size.checked_sub(1).ok_or(())?)
+        }
+        (start, "") => (start.parse().map_err(|_| ())?, size.saturating_sub(1)),
+        (start, end) => (
+            start.parse().map_err(|_| ())?,
+            end.parse::<u64>()
+                .map_err(|_| ())?
+                .min(size.saturating_sub(1)),
+        ),
+    };
+    if start > end || start >= size {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+enum Resource {
+    NotFound,
+    Full(Vec<u8>),
+    Partial {
+        start: u64,
+        end: u64,
+        size: u64,
+        data: Vec<u8>,
+    },
+    Unsatisfiable {
+        size: u64,
+    },
+}
+
+/// Reads entry `name`, or the part of it selected by the `Range` header.
+/// Ranges of compressed entries are inflated only up to their end.
+fn read_resource(archive: &Archive, name: &str, range_header: Option<&str>) -> Result<Resource> {
+    let mut archive = archive.lock().unwrap();
+    let Some(size) = archive.size(name)? else {
+        return Ok(Resource::NotFound);
+    };
+    let range = match range_header.map(|value| parse_range(value, size)) {
+        Some(Ok(range)) => range,
+        Some(Err(())) => return Ok(Resource::Unsatisfiable { size }),
+        None => None,
+    };
+    let Some((start, end)) = range else {
+        return Ok(Resource::Full(archive.read(name)?));
+    };
+    Ok(Resource::Partial {
+        start,
+        end,
+        size,
+        data: archive.read_range(name, start..end + 1)?,
+    })
+}
+
+/// Reads the whole of entry `name`, `None` when there is no such entry.
+fn read_entry(archive: &Archive, name: &str) -> Result<Option<Vec<u8>>> {
+    let mut archive = archive.lock().unwrap();
+    if archive.size(name)?.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(archive.read(name)?))
+}
+
+/// What is done to an entry before it is served.
+#[derive(Debug, PartialEq)]
+enum Processing {
+    None,
+    /// A chapter, with the text transforms of its book and the user
+    /// styles that apply to it.
+    Chapter(Transforms, Injection),
+    /// An image, made fit for the webview.
+    Image,
+}
+
+impl Processing {
+    fn of(app: &AppHandle, book: &Registered, mime: &str) -> Self {
+        match (&book.book_hash, mime) {
+            (hash, "application/xhtml+xml" | "text/html") => {
+                let transforms = transform::for_book(app, hash);
+                let injection = styles::for_book(app, Some(hash));
+                if transforms.is_empty() && injection.is_empty() {
+                    Self::None
+                } else {
+                    Self::Chapter(transforms, injection)
+                }
+            }
+            (_, "image/svg+xml" | "image/gif") => Self::None,
+            (_, mime) if mime.starts_with("image/") => Self::Image,
+            _ => Self::None,
+        }
+    }
+
+    /// Processes `data`, entry `name` of `book`, into what is served and
+    /// its content type. Entries that fail are served as they are.
+    fn apply(
+        &self,
+        app: &AppHandle,
+        book: &Path,
+        name: &str,
+        data: Vec<u8>,
+        mime: &'static str,
+    ) -> (Vec<u8>, &'static str) {
+        match self {
+            Self::None => (data, mime),
+            Self::Chapter(transforms, injection) => (
+                transform_chapter(app, book, name, data, transforms, injection),
+                mime,
+            ),
+            Self::Image => match images::prepare(app, book, name, &data) {
+                Ok(Some(prepared)) => prepared,
+                Ok(None) => (data, mime),
+                Err(e) => {
+                    log::warn!("Failed to prepare image {name} of {book:?}: {e}");
+                    (data, mime)
+                }
+            },
+        }
+    }
+}
+
+fn respond_status(status: StatusCode) -> Response<Cow<'static, [u8]>> {
+    Response::builder()
+        .status(status)
+        .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+        .body(Cow::Borrowed(&[][..]))
+        .unwrap()
+}
+
+fn transform_chapter(
+    app: &AppHandle,
+    file: &Path,
+    name: &str,
+    data: Vec<u8>,
+    transforms: &Transforms,
+    injection: &Injection,
+) -> Vec<u8> {
+    let html = match String::from_utf8(data) {
+        Ok(html) => html,
+        Err(e) => return e.into_bytes(),
+    };
+    let html = if transforms.is_empty() {
+        html
+    } else {
+        match transform::transform(app, &html, transforms) {
+            Ok(transformed) => transformed,
+            Err(e) => {
+                log::warn!("Failed to transform {name} of {file:?}: {e}");
+                html
+            }
+        }
+    };
+    injection.inject(&html).into_bytes()
+}
+
+/// Handles a `book://` request.
+pub fn handle_request(app: &AppHandle, request: &Request<Vec<u8>>) -> Response<Cow<'static, [u8]>> {
+    let path = request.uri().path().trim_start_matches('/');
+    let Some((id, name)) = path.split_once('/') else {
+        return respond_status(StatusCode::NOT_FOUND);
+    };
+    let name = percent_decode_str(name).decode_utf8_lossy();
+    let Some((book, archive)) = app.state::<BookResources>().archive(app, id) else {
+        return respond_status(StatusCode::NOT_FOUND);
+    };
+    let file = &book.path;
+    let archive = match archive {
+        Ok(archive) => archive,
+        Err(e) => {
+            log::warn!("Failed to open {file:?}: {e}");
+            return respond_status(StatusCode::NOT_FOUND);
+        }
+    };
+
+    let mut mime = content_type(&name);
+    let processing = Processing::of(app, &book, mime);
+    // Processed entries are served whole, their ranges would be of the original
+    let range_header = request
+        .headers()
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .filter(|_| processing == Processing::None);
+    let builder = Response::builder()
+        .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+        .header(header::ACCEPT_RANGES, "bytes");
+    if range_header.is_none() {
+        if let Some((data, mime)) = app.state::<Prefetcher>().get(id, &name, &processing) {
+            return builder
+                .status(StatusCode::OK)
+                .header(header::CONTENT_TYPE, mime)
+                .header(header::CONTENT_LENGTH, data.len())
+                .body(Cow::Owned(data))
+                .unwrap();
+        }
+    }
+    let resource = match read_resource(&archive, &name, range_header) {
+        Ok(resource) => resource,
+        Err(e) => {
+            log::warn!("Failed to read {name} from {file:?}: {e}");
+            return respond_status(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let (builder, data) = match resource {
+        Resource::NotFound => return respond_status(StatusCode::NOT_FOUND),
+        Resource::Unsatisfiable { size } => {
+            return builder
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{size}"))
+                .body(Cow::Borrowed(&[][..]))
+                .unwrap();
+        }
+        Resource::Full(data) => {
+            let (data, processed_mime) = processing.apply(app, file, &name, data, mime);
+            mime = processed_mime;
+            (builder.status(StatusCode::OK), data)
+        }
+        Resource::Partial {
+            start,
+            end,
+            size,
+            data,
+        } => (
+            builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_RANGE, format!("bytes {start}-{end}/{size}")),
+            data,
+        ),
+    };
+    builder
+        .header(header::CONTENT_TYPE, mime)
+        .header(header::CONTENT_LENGTH, data.len())
+        .body(Cow::Owned(data))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_are_clamped_to_the_resource() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Ok(Some((0, 99))));
+        assert_eq!(parse_range(" bytes=900- ", 1000), Ok(Some((900, 999))));
+        assert_eq!(parse_range("bytes=-100", 1000), Ok(Some((900, 999))));
+        assert_eq!(parse_range("bytes=-5000", 1000), Ok(Some((0, 999))));
+        assert_eq!(parse_range("bytes=10-5000", 1000), Ok(Some((10, 999))));
+    }
+
+    #[test]
+    fn other_units_and_multiple_ranges_get_everything() {
+        assert_eq!(parse_range("items=0-1", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=0-1,5-9", 1000), Ok(None));
+    }
+
+    #[test]
+    fn malformed_and_unsatisfiable_ranges() {
+        for (value, size) in [
+            ("bytes=5", 1000),
+            ("bytes=a-b", 1000),
+            ("bytes=-", 1000),
+            ("bytes=-1-2", 1000),
+            ("bytes=10-5", 1000),
+            ("bytes=1000-", 1000),
+            ("bytes=-0", 1000),
+            ("bytes=18446744073709551616-", 1000),
+            ("bytes=-1", 0),
+            ("bytes=0-", 0),
+        ] {
+            assert_eq!(parse_range(value, size), Err(()), "{value} of {size}");
+        }
+    }
+}
@@ -0,0 +1,269 @@
+//! Prefetching of the chapters after the one being read, and of their
+//! images, so that reading into the next chapter of a large book does not
+//! wait on it being decompressed, decrypted and transformed. The reader
+//! says where it is with [`prefetch_book_resources`], or through the
+//! reading session, and the next chapters are made ready in a background
+//! thread: their markup is kept in memory until it is served, and their
+//! images, when they are converted or scaled, are put in the image cache.
+//! Comics prefetch their next pages the same way.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use regex::Regex;
+use tauri::{command, AppHandle, Manager, State};
+
+use super::{content_type, read_entry, BookResources, Processing};
+use crate::error::Result;
+use crate::formats;
+use crate::formats::comic::ComicArchive;
+use crate::formats::epub::{resolve_href, EpubArchive};
+use crate::formats::html::decode_entities;
+use crate::typeset::styles::Injection;
+use crate::typeset::transform::Transforms;
+
+const DEFAULT_CHAPTERS: usize = 2;
+const MAX_CHAPTERS: usize = 8;
+/// Bytes of prepared chapters kept in memory, the oldest dropped first.
+const MAX_PREPARED_BYTES: usize = 32 * 1024 * 1024;
+
+struct Prepared {
+    id: String,
+    name: String,
+    /// What the chapter was transformed and styled with, if it was.
+    chapter: Option<(Transforms, Injection)>,
+    data: Vec<u8>,
+    mime: &'static str,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Most recent last.
+    prepared: Vec<Prepared>,
+    /// Entries of books in reading order, read when first prefetched.
+    orders: HashMap<String, Arc<Vec<String>>>,
+    /// The latest prefetch of each book, which stops those before it.
+    runs: HashMap<String, u64>,
+}
+
+#[derive(Default)]
+pub struct Prefetcher(Mutex<Inner>);
+
+impl Prefetcher {
+    /// Entry `name` of book `id` as prepared, when it was with the same
+    /// processing as now.
+    pub(super) fn get(
+        &self,
+        id: &str,
+        name: &str,
+        processing: &Processing,
+    ) -> Option<(Vec<u8>, &'static str)> {
+        let chapter = match processing {
+            Processing::None => None,
+            Processing::Chapter(transforms, injection) => Some((transforms, injection)),
+            Processing::Image => return None,
+        };
+        let inner = self.0.lock().unwrap();
+        inner
+            .prepared
+            .iter()
+            .find(|p| {
+                p.id == id && p.name == name && p.chapter.as_ref().map(|(t, i)| (t, i)) == chapter
+            })
+            .map(|p| (p.data.clone(), p.mime))
+    }
+
+    fn has(&self, id: &str, name: &str) -> bool {
+        let inner = self.0.lock().unwrap();
+        inner.prepared.iter().any(|p| p.id == id && p.name == name)
+    }
+
+    fn insert(&self, prepared: Prepared) {
+        let mut inner = self.0.lock().unwrap();
+        inner
+            .prepared
+            .retain(|p| p.id != prepared.id || p.name != prepared.name);
+        inner.prepared.push(prepared);
+        let mut used = inner.prepared.iter().map(|p| p.data.len()).sum::<usize>();
+        while used > MAX_PREPARED_BYTES && inner.prepared.len() > 1 {
+            used -= inner.prepared.remove(0).data.len();
+        }
+    }
+
+    /// Drops what was prepared of book `id`, once it is closed.
+    pub(super) fn forget(&self, id: &str) {
+        let mut inner = self.0.lock().unwrap();
+        inner.prepared.retain(|p| p.id != id);
+        inner.orders.remove(id);
+        inner.runs.remove(id);
+    }
+
+    fn start(&self, id: &str) -> u64 {
+        let mut inner = self.0.lock().unwrap();
+        let run = inner.runs.entry(id.to_string()).or_default();
+        *run += 1;
+        *run
+    }
+
+    fn is_current(&self, id: &str, run: u64) -> bool {
+        self.0.lock().unwrap().runs.get(id) == Some(&run)
+    }
+
+    fn order(&self, id: &str, path: &Path) -> Result<Arc<Vec<String>>> {
+        if let Some(order) = self.0.lock().unwrap().orders.get(id) {
+            return Ok(order.clone());
+        }
+        let order = Arc::new(reading_order(path)?);
+        let mut inner = self.0.lock().unwrap();
+        inner.orders.insert(id.to_string(), order.clone());
+        Ok(order)
+    }
+}
+
+/// Entries of the book at `path` in reading order: the documents of the
+/// spine of an EPUB, the pages of a comic, none of other books.
+fn reading_order(path: &Path) -> Result<Vec<String>> {
+    Ok(match formats::extension(path).as_str() {
+        "epub" => EpubArchive::open(path)?
+            .package()?
+            .spine_items()
+            .map(|item| item.href.clone())
+            .collect(),
+        "cbz" => ComicArchive::open(path)?
+            .pages()
+            .iter()
+            .map(|page| page.name.clone())
+            .collect(),
+        _ => Vec::new(),
+    })
+}
+
+/// Entries of the images chapter `name` shows, in the same archive.
+fn image_entries(name: &str, html: &str) -> Vec<String> {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    let pattern = PATTERN.get_or_init(|| {
+        Regex::new(r#"(?i)<(?:img|image)\b[^>]*?\s(?:src|xlink:href|href)\s*=\s*["']([^"']+)["']"#)
+            .expect("valid image pattern")
+    });
+    let mut entries = Vec::<String>::new();
+    for src in pattern.captures_iter(html).filter_map(|c| c.get(1)) {
+        let src = decode_entities(src.as_str());
+        // Data URLs and images elsewhere are not in the book
+        if src.contains(':') {
+            continue;
+        }
+        let entry = resolve_href(name, &src);
+        if !entries.contains(&entry) {
+            entries.push(entry);
+        }
+    }
+    entries
+}
+
+/// Prepares the `count` entries of book `id` after `entry`, unless a
+/// later prefetch of the book starts first.
+fn prefetch(app: &AppHandle, id: &str, entry: &str, count: usize, run: u64) -> Result<()> {
+    let prefetcher = app.state::<Prefetcher>();
+    let Some((book, archive)) = app.state::<BookResources>().archive(app, id) else {
+        return Ok(());
+    };
+    let archive = archive?;
+    let order = prefetcher.order(id, &book.path)?;
+    let Some(position) = order.iter().position(|name| name == entry) else {
+        return Ok(());
+    };
+    for name in order.iter().skip(position + 1).take(count) {
+        if !prefetcher.is_current(id, run) {
+            break;
+        }
+        if prefetcher.has(id, name) {
+            continue;
+        }
+        let mime = content_type(name);
+        let processing = Processing::of(app, &book, mime);
+        let Some(data) = read_entry(&archive, name)? else {
+            continue;
+        };
+        let images = if mime.contains("html") {
+            image_entries(name, &String::from_utf8_lossy(&data))
+        } else {
+            Vec::new()
+        };
+        let (data, mime) = processing.apply(app, &book.path, name, data, mime);
+        for image in &images {
+            if !prefetcher.is_current(id, run) {
+                break;
+            }
+            let image_mime = content_type(image);
+            let image_processing = Processing::of(app, &book, image_mime);
+            if image_processing != Processing::Image {
+                continue;
+            }
+            if let Some(data) = read_entry(&archive, image)? {
+                image_processing.apply(app, &book.path, image, data, image_mime);
+            }
+        }
+        // Images are kept in the image cache instead, when processed at all
+        let chapter = match processing {
+            Processing::None => None,
+            Processing::Chapter(transforms, injection) => Some((transforms, injection)),
+            Processing::Image => continue,
+        };
+        prefetcher.insert(Prepared {
+            id: id.to_string(),
+            name: name.clone(),
+            chapter,
+            data,
+            mime,
+        });
+    }
+    Ok(())
+}
+
+fn spawn(app: &AppHandle, id: String, entry: String, count: usize) {
+    let run = app.state::<Prefetcher>().start(&id);
+    let app = app.clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        if let Err(e) = prefetch(&app, &id, &entry, count, run) {
+            log::warn!("Failed to prefetch after {entry} of {id}: {e}");
+        }
+    });
+}
+
+/// Prefetches after chapter `chapter` of the open books of library book
+/// `book_hash`, as the reading session reports it.
+#[cfg(desktop)]
+pub fn after_position(app: &AppHandle, book_hash: &str, chapter: &str) {
+    let resources = app.state::<BookResources>();
+    let ids = {
+        let inner = resources.0.lock().unwrap();
+        inner
+            .books
+            .iter()
+            .filter(|(_, book)| book.book_hash == book_hash)
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>()
+    };
+    for id in ids {
+        spawn(app, id, chapter.to_string(), DEFAULT_CHAPTERS);
+    }
+}
+
+/// Prepares in the background the `count` entries, two by default, that
+/// come after `entry` of book `id` in reading order, for the reader to
+/// call as it moves into another chapter.
+#[command]
+pub fn prefetch_book_resources(
+    app: AppHandle,
+    resources: State<'_, BookResources>,
+    id: String,
+    entry: String,
+    count: Option<usize>,
+) {
+    if !resources.0.lock().unwrap().books.contains_key(&id) {
+        return;
+    }
+    let count = count.unwrap_or(DEFAULT_CHAPTERS).min(MAX_CHAPTERS);
+    spawn(&app, id, entry, count);
+}
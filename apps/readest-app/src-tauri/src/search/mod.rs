@@ -0,0 +1,219 @@
+//! Full-text index over the chapters of every book in the library.
+
+use std::path::Path;
+use std::sync::Mutex;
+
+use serde::Serialize;
+use tantivy::collector::TopDocs;
+use tantivy::directory::MmapDirectory;
+use tantivy::query::{BooleanQuery, Occur, Query, QueryParser, TermQuery};
+use tantivy::schema::{Field, IndexRecordOption, Schema, Value, INDEXED, STORED, STRING, TEXT};
+use tantivy::snippet::SnippetGenerator;
+use tantivy::{doc, Index, IndexReader, IndexWriter, ReloadPolicy, TantivyDocument, Term};
+use tauri::{AppHandle, Manager};
+
+use crate::error::Result;
+use crate::formats::Chapter;
+use crate::paths;
+
+const WRITER_MEMORY_BUDGET: usize = 50_000_000;
+const SNIPPET_MAX_CHARS: usize = 160;
+const INDEX_DIR: &str = "search-index";
+
+#[derive(Clone, Copy)]
+struct Fields {
+    book_hash: Field,
+    fingerprint: Field,
+    chapter: Field,
+    href: Field,
+    text: Field,
+}
+
+impl Fields {
+    fn schema() -> (Schema, Fields) {
+        let mut builder = Schema::builder();
+        let fields = Fields {
+            book_hash: builder.add_text_field("book_hash", STRING | STORED),
+            fingerprint: builder.add_text_field("fingerprint", STRING | STORED),
+            chapter: builder.add_u64_field("chapter", INDEXED | STORED),
+            href: builder.add_text_field("href", STORED),
+            text: builder.add_text_field("text", TEXT | STORED),
+        };
+        (builder.build(), fields)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchHit {
+    pub book_hash: String,
+    pub chapter: u64,
+    pub href: String,
+    /// Character offset of the first match within the chapter text.
+    pub offset: Option<usize>,
+    pub snippet: String,
+    pub score: f32,
+}
+
+pub struct SearchIndex {
+    index: Index,
+    reader: IndexReader,
+    writer: Mutex<IndexWriter>,
+    fields: Fields,
+}
+
+impl SearchIndex {
+    pub fn open(dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let (schema, fields) = Fields::schema();
+        let directory = MmapDirectory::open(dir).map_err(tantivy::TantivyError::from)?;
+        let index = Index::open_or_create(directory, schema)?;
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::OnCommitWithDelay)
+            .try_into()?;
+        let writer = index.writer(WRITER_MEMORY_BUDGET)?;
+        Ok(Self {
+            index,
+            reader,
+            writer: Mutex::new(writer),
+            fields,
+        })
+    }
+
+    fn book_term(&self, book_hash: &str) -> Term {
+        Term::from_field_text(self.fields.book_hash, book_hash)
+    }
+
+    /// Returns the fingerprint recorded when `book_hash` was last indexed.
+    pub fn fingerprint(&self, book_hash: &str) -> Result<Option<String>> {
+        let searcher = self.reader.searcher();
+        let query = TermQuery::new(self.book_term(book_hash), IndexRecordOption::Basic);
+        let top = searcher.search(&query, &TopDocs::with_limit(1))?;
+        let Some((_, address)) = top.into_iter().next() else {
+            return Ok(None);
+        };
+        let doc: TantivyDocument = searcher.doc(address)?;
+        Ok(doc
+            .get_first(self.fields.fingerprint)
+            .and_then(|v| v.as_str())
+            .map(String::from))
+    }
+
+    /// Replaces all indexed chapters of `book_hash`. A book without any
+    /// gets a document of just its fingerprint, so it is not indexed again.
+    pub fn index_book(
+        &self,
+        book_hash: &str,
+        fingerprint: &str,
+        chapters: &[Chapter],
+    ) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.delete_term(self.book_term(book_hash));
+        if chapters.is_empty() {
+            writer.add_document(doc!(
+                self.fields.book_hash => book_hash,
+                self.fields.fingerprint => fingerprint,
+            ))?;
+        }
+        for chapter in chapters {
+            writer.add_document(doc!(
+                self.fields.book_hash => book_hash,
+                self.fields.fingerprint => fingerprint,
+                self.fields.chapter => chapter.index as u64,
+                self.fields.href => chapter.href.as_str(),
+                self.fields.text => chapter.text.as_str(),
+            ))?;
+        }
+        writer.commit()?;
+        Ok(())
+    }
+
+    pub fn remove_books(&self, book_hashes: &[String]) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        for hash in book_hashes {
+            writer.delete_term(self.book_term(hash));
+        }
+        writer.commit()?;
+        Ok(())
+    }
+
+    pub fn search(
+        &self,
+        query: &str,
+        book_hashes: Option<&[String]>,
+        excluded: &[String],
+        limit: usize,
+    ) -> Result<Vec<SearchHit>> {
+        self.reader.reload()?;
+        let searcher = self.reader.searcher();
+        let parser = QueryParser::for_index(&self.index, vec![self.fields.text]);
+        let (text_query, _errors) = parser.parse_query_lenient(query);
+
+        let query: Box<dyn Query> = match book_hashes {
+            Some(hashes) if !hashes.is_empty() => {
+                let books = hashes
+                    .iter()
+                    .map(|hash| {
+                        let term = TermQuery::new(self.book_term(hash), IndexRecordOption::Basic);
+                        (Occur::Should, Box::new(term) as Box<dyn Query>)
+                    })
+                    .collect::<Vec<_>>();
+                Box::new(BooleanQuery::new(vec![
+                    (Occur::Must, text_query),
+                    (Occur::Must, Box::new(BooleanQuery::new(books))),
+                ]))
+            }
+            _ => text_query,
+        };
+        let query: Box<dyn Query> = if excluded.is_empty() {
+            query
+        } else {
+            let mut clauses = vec![(Occur::Must, query)];
+            clauses.extend(excluded.iter().map(|hash| {
+                let term = TermQuery::new(self.book_term(hash), IndexRecordOption::Basic);
+                (Occur::MustNot, Box::new(term) as Box<dyn Query>)
+            }));
+            Box::new(BooleanQuery::new(clauses))
+        };
+
+        let mut snippets = SnippetGenerator::create(&searcher, &*query, self.fields.text)?;
+        snippets.set_max_num_chars(SNIPPET_MAX_CHARS);
+
+        let top = searcher.search(&query, &TopDocs::with_limit(limit))?;
+        let mut hits = Vec::with_capacity(top.len());
+        for (score, address) in top {
+            let doc: TantivyDocument = searcher.doc(address)?;
+            let get_str = |field| {
+                doc.get_first(field)
+                    .and_then(|v| v.as_str())
+                    .unwrap_or_default()
+                    .to_string()
+            };
+            let text = get_str(self.fields.text);
+            let snippet = snippets.snippet(&text);
+            let offset = snippet.highlighted().first().and_then(|range| {
+                let fragment_start = text.find(snippet.fragment())?;
+                Some(text[..fragment_start + range.start].chars().count())
+            });
+            hits.push(SearchHit {
+                book_hash: get_str(self.fields.book_hash),
+                chapter: doc
+                    .get_first(self.fields.chapter)
+                    .and_then(|v| v.as_u64())
+                    .unwrap_or_default(),
+                href: get_str(self.fields.href),
+                offset,
+                snippet: snippet.fragment().to_string(),
+                score,
+            });
+        }
+        Ok(hits)
+    }
+}
+
+pub fn init(app: &AppHandle) -> Result<()> {
+    let dir = paths::data_dir(app)?.join(INDEX_DIR);
+    app.manage(SearchIndex::open(&dir)?);
+    Ok(())
+}
@@ -0,0 +1,109 @@
+//! Secrets in the OS keychain: the macOS Keychain, the Windows Credential
+//! Manager (DPAPI) and the Secret Service on Linux. Passwords and sync
+//! passphrases live here instead of in the plaintext config store, and so
+//! does the key that encrypts annotations at rest (see [`storage`]). Notes
+//! protected with a passphrase of their own are in [`notes`].
+//!
+//! The Secret Service is reached over D-Bus from the async runtime, so calls
+//! must come from blocking pool threads, never the main thread; commands
+//! run what reaches the keychain through [`blocking`]. Mobile builds have
+//! no keychain here, only that helper.
+
+#[cfg(desktop)]
+use tauri::command;
+
+#[cfg(desktop)]
+use crate::error::Error;
+use crate::error::Result;
+#[cfg(desktop)]
+use crate::paths;
+
+#[cfg(desktop)]
+pub mod notes;
+#[cfg(desktop)]
+pub mod storage;
+
+/// Service name the entries are filed under, the bundle identifier, to
+/// which profiles but the default add their id.
+#[cfg(desktop)]
+const SERVICE: &str = "com.vlarch.vlarch";
+
+/// Prefix of the entries the frontend manages, which keeps it away from
+/// the ones used here.
+#[cfg(desktop)]
+const APP_PREFIX: &str = "app.";
+
+#[cfg(desktop)]
+fn entry(key: &str) -> Result<keyring::Entry> {
+    let service = match paths::profile() {
+        Some(id) => format!("{SERVICE}.{id}"),
+        None => SERVICE.to_string(),
+    };
+    keyring::Entry::new(&service, key).map_err(keychain_error)
+}
+
+/// Says so when there is no keychain to reach, as on Linux desktops
+/// without a Secret Service, rather than passing on a D-Bus error.
+#[cfg(desktop)]
+fn keychain_error(e: keyring::Error) -> Error {
+    match e {
+        keyring::Error::PlatformFailure(_) | keyring::Error::NoStorageAccess(_) => {
+            Error::KeychainUnavailable(e.to_string())
+        }
+        e => e.into(),
+    }
+}
+
+#[cfg(desktop)]
+pub fn get(key: &str) -> Result<Option<String>> {
+    match entry(key)?.get_password() {
+        Ok(value) => Ok(Some(value)),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(e) => Err(keychain_error(e)),
+    }
+}
+
+#[cfg(desktop)]
+fn set(key: &str, value: &str) -> Result<()> {
+    entry(key)?.set_password(value).map_err(keychain_error)
+}
+
+#[cfg(desktop)]
+fn delete(key: &str) -> Result<()> {
+    match entry(key)?.delete_credential() {
+        Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+        Err(e) => Err(keychain_error(e)),
+    }
+}
+
+/// Stores `value` as `key`, or removes the entry when `value` is `None`.
+#[cfg(desktop)]
+pub fn store(key: &str, value: Option<&str>) -> Result<()> {
+    match value {
+        Some(value) => set(key, value),
+        None => delete(key),
+    }
+}
+
+/// Runs `f`, which reaches the keychain, on a blocking pool thread.
+pub async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    tauri::async_runtime::spawn_blocking(f).await?
+}
+
+/// Stores `value` under `key` in the keychain, or removes it when `value`
+/// is not given.
+#[cfg(desktop)]
+#[command]
+pub async fn store_secret(key: String, value: Option<String>) -> Result<()> {
+    let key = format!("{APP_PREFIX}{key}");
+    blocking(move || store(&key, value.as_deref())).await
+}
+
+#[cfg(desktop)]
+#[command]
+pub async fn get_secret(key: String) -> Result<Option<String>> {
+    let key = format!("{APP_PREFIX}{key}");
+    blocking(move || get(&key)).await
+}
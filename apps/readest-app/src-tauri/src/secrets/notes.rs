@@ -0,0 +1,391 @@
+//! Private notes: the highlights and notes of a book sealed with a
+//! passphrase of its own, for those who keep journals in them. Argon2id
+//! stretches the passphrase, with a random salt kept beside the sealed
+//! notes, into an AES-256-GCM key. The notes are kept in the book's config
+//! under `privateNotes`, and its `booknotes` stay empty.
+//!
+//! A book unlocked with its passphrase stays unlocked until it is locked
+//! again or the app quits; the key is only ever kept in memory. While it
+//! is unlocked, [`on_read`] gives the frontend its notes and [`on_write`]
+//! seals what the frontend writes back. While it is locked the frontend
+//! sees no notes, and writing any is refused. Sidecars and the command
+//! line sync leave protected books alone.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tauri::{command, AppHandle, Manager};
+
+use super::storage::{config_path, is_encrypted, read_file, write_file};
+use crate::error::{Error, Result};
+
+const VERSION: u32 = 1;
+/// Field of the config the sealed notes are kept in.
+const FIELD: &str = "privateNotes";
+/// Field of the configs the frontend reads, telling it whether the notes
+/// are locked; dropped from those it writes.
+const LOCKED_FIELD: &str = "notesLocked";
+const NOTES_FIELD: &str = "booknotes";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const MIN_PASSPHRASE_LEN: usize = 6;
+
+fn notes_error(message: impl std::fmt::Display) -> Error {
+    Error::PrivateNotes(message.to_string())
+}
+
+/// Sealed notes as kept in the config, with how the key is derived.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Sealed {
+    version: u32,
+    salt: String,
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// The key of an unlocked book, with the salt and costs it was derived
+/// with, which sealing again keeps.
+#[derive(Clone)]
+struct Key {
+    cipher: Aes256Gcm,
+    salt: String,
+    params: Params,
+}
+
+impl Key {
+    /// Slow by design; call it off the async runtime.
+    fn derive(passphrase: &str, salt: String, params: Params) -> Result<Key> {
+        let salt_bytes = BASE64.decode(&salt).map_err(notes_error)?;
+        let mut output = [0u8; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone())
+            .hash_password_into(passphrase.as_bytes(), &salt_bytes, &mut output)
+            .map_err(notes_error)?;
+        let cipher = Aes256Gcm::new_from_slice(&output).map_err(notes_error)?;
+        Ok(Key {
+            cipher,
+            salt,
+            params,
+        })
+    }
+
+    /// A key with a new salt and the default costs.
+    fn create(passphrase: &str) -> Result<Key> {
+        if passphrase.chars().count() < MIN_PASSPHRASE_LEN {
+            return Err(notes_error(format!(
+                "a passphrase needs at least {MIN_PASSPHRASE_LEN} characters"
+            )));
+        }
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        Key::derive(passphrase, BASE64.encode(salt), Params::default())
+    }
+
+    /// The key the notes of book `book_hash` were sealed with, and the
+    /// notes, if `passphrase` is their passphrase.
+    fn of(passphrase: &str, book_hash: &str, sealed: &Sealed) -> Result<(Key, Vec<Value>)> {
+        let params = Params::new(
+            sealed.memory_kib,
+            sealed.iterations,
+            sealed.parallelism,
+            Some(32),
+        )
+        .map_err(notes_error)?;
+        let key = Key::derive(passphrase, sealed.salt.clone(), params)?;
+        let notes = key
+            .open(book_hash, sealed)
+            .map_err(|_| notes_error("wrong passphrase"))?;
+        Ok((key, notes))
+    }
+
+    /// Seals `notes`, bound to book `book_hash` so they cannot be passed
+    /// off as another book's.
+    fn seal(&self, book_hash: &str, notes: &Value) -> Result<Sealed> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let plaintext = serde_json::to_vec(notes)?;
+        let payload = Payload {
+            msg: &plaintext,
+            aad: book_hash.as_bytes(),
+        };
+        let ciphertext = self.cipher.encrypt(&nonce, payload).map_err(notes_error)?;
+        Ok(Sealed {
+            version: VERSION,
+            salt: self.salt.clone(),
+            memory_kib: self.params.m_cost(),
+            iterations: self.params.t_cost(),
+            parallelism: self.params.p_cost(),
+            nonce: BASE64.encode(nonce),
+            ciphertext: BASE64.encode(ciphertext),
+        })
+    }
+
+    fn open(&self, book_hash: &str, sealed: &Sealed) -> Result<Vec<Value>> {
+        if sealed.version != VERSION {
+            return Err(notes_error(format!(
+                "unsupported version {}",
+                sealed.version
+            )));
+        }
+        let nonce = BASE64.decode(&sealed.nonce).map_err(notes_error)?;
+        if nonce.len() != NONCE_LEN {
+            return Err(notes_error("invalid nonce"));
+        }
+        let ciphertext = BASE64.decode(&sealed.ciphertext).map_err(notes_error)?;
+        let payload = Payload {
+            msg: &ciphertext,
+            aad: book_hash.as_bytes(),
+        };
+        let plaintext = self
+            .cipher
+            .decrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| notes_error("notes could not be decrypted"))?;
+        Ok(serde_json::from_slice(&plaintext)?)
+    }
+}
+
+/// Keys of the books unlocked in this session.
+#[derive(Default)]
+pub struct PrivateNotes(Mutex<HashMap<String, Key>>);
+
+impl PrivateNotes {
+    fn key(&self, book_hash: &str) -> Option<Key> {
+        self.0.lock().unwrap().get(book_hash).cloned()
+    }
+}
+
+fn sealed_of(config: &Value) -> Result<Option<Sealed>> {
+    config
+        .get(FIELD)
+        .map(|sealed| serde_json::from_value(sealed.clone()))
+        .transpose()
+        .map_err(Into::into)
+}
+
+/// Whether `config` has its notes sealed.
+pub(crate) fn is_protected(config: &Value) -> bool {
+    config.get(FIELD).is_some()
+}
+
+fn fields(config: &mut Value) -> Result<&mut serde_json::Map<String, Value>> {
+    config
+        .as_object_mut()
+        .ok_or_else(|| Error::InvalidBook("invalid book config".to_string()))
+}
+
+fn take_notes(config: &mut Value) -> Result<Vec<Value>> {
+    Ok(match fields(config)?.remove(NOTES_FIELD) {
+        Some(Value::Array(notes)) => notes,
+        _ => Vec::new(),
+    })
+}
+
+/// `config` of book `book_hash`, as read from its file, made ready for
+/// the frontend: with the notes opened when the book is unlocked, and
+/// none while it is locked.
+pub(crate) fn on_read(app: &AppHandle, book_hash: &str, config: &mut Value) -> Result<()> {
+    let Some(sealed) = sealed_of(config)? else {
+        return Ok(());
+    };
+    let key = app.state::<PrivateNotes>().key(book_hash);
+    // Notes written to the file by others, such as imports, are sealed
+    // with the rest on the next write
+    let plain = take_notes(config)?;
+    let object = fields(config)?;
+    object.remove(FIELD);
+    match key {
+        Some(key) => {
+            let mut notes = key.open(book_hash, &sealed)?;
+            notes.extend(plain);
+            object.insert(NOTES_FIELD.into(), Value::Array(notes));
+            object.insert(LOCKED_FIELD.into(), false.into());
+        }
+        None => {
+            object.insert(NOTES_FIELD.into(), json!([]));
+            object.insert(LOCKED_FIELD.into(), true.into());
+        }
+    }
+    Ok(())
+}
+
+/// `config` of book `book_hash`, as the frontend wrote it, made ready for
+/// its file, given the file's current contents. The notes are sealed when
+/// the book is protected, and refused while it is locked.
+pub(crate) fn on_write(
+    app: &AppHandle,
+    book_hash: &str,
+    config: &mut Value,
+    current: Option<&Value>,
+) -> Result<()> {
+    let sealed = current.map(sealed_of).transpose()?.flatten();
+    let object = fields(config)?;
+    object.remove(LOCKED_FIELD);
+    object.remove(FIELD);
+    let Some(sealed) = sealed else {
+        return Ok(());
+    };
+    let notes = take_notes(config)?;
+    // While locked, notes others wrote in plain text stay for the next
+    // unlock to pick up
+    let (sealed, plain) = match app.state::<PrivateNotes>().key(book_hash) {
+        Some(key) => (key.seal(book_hash, &Value::Array(notes))?, json!([])),
+        None if notes.is_empty() => {
+            let plain = current.and_then(|config| config.get(NOTES_FIELD));
+            (sealed, plain.cloned().unwrap_or_else(|| json!([])))
+        }
+        None => return Err(notes_error("the notes of this book are locked")),
+    };
+    let object = fields(config)?;
+    object.insert(NOTES_FIELD.into(), plain);
+    object.insert(FIELD.into(), serde_json::to_value(sealed)?);
+    Ok(())
+}
+
+/// The config of book `book_hash` and whether it is encrypted at rest.
+fn read_config(app: &AppHandle, book_hash: &str) -> Result<(Value, bool)> {
+    let path = config_path(app, book_hash)?;
+    let encrypted = std::fs::read(&path)
+        .map(|data| is_encrypted(&data))
+        .unwrap_or(false);
+    let config = match read_file(&path)? {
+        Some(data) => serde_json::from_slice(&data)?,
+        None => json!({}),
+    };
+    Ok((config, encrypted))
+}
+
+fn write_config(app: &AppHandle, book_hash: &str, config: &Value, encrypted: bool) -> Result<()> {
+    write_file(
+        &config_path(app, book_hash)?,
+        serde_json::to_vec(config)?,
+        encrypted,
+    )
+}
+
+fn sealed_or_err(config: &Value) -> Result<Sealed> {
+    sealed_of(config)?.ok_or_else(|| notes_error("the notes of this book are not protected"))
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NotesProtection {
+    pub protected: bool,
+    /// Whether the notes can be read in this session.
+    pub unlocked: bool,
+}
+
+#[command]
+pub async fn get_notes_protection(app: AppHandle, book_hash: String) -> Result<NotesProtection> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let (config, _) = read_config(&app, &book_hash)?;
+        let protected = is_protected(&config);
+        Ok(NotesProtection {
+            protected,
+            unlocked: protected && app.state::<PrivateNotes>().key(&book_hash).is_some(),
+        })
+    })
+    .await?
+}
+
+/// Seals the notes of book `book_hash` with `passphrase`, leaving the book
+/// unlocked. The config has to be read again.
+#[command]
+pub async fn protect_book_notes(
+    app: AppHandle,
+    book_hash: String,
+    passphrase: String,
+) -> Result<()> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let (mut config, encrypted) = read_config(&app, &book_hash)?;
+        if is_protected(&config) {
+            return Err(notes_error("the notes of this book are protected already"));
+        }
+        let key = Key::create(&passphrase)?;
+        let notes = take_notes(&mut config)?;
+        let sealed = key.seal(&book_hash, &Value::Array(notes))?;
+        let object = fields(&mut config)?;
+        object.insert(NOTES_FIELD.into(), json!([]));
+        object.insert(FIELD.into(), serde_json::to_value(sealed)?);
+        write_config(&app, &book_hash, &config, encrypted)?;
+        app.state::<PrivateNotes>()
+            .0
+            .lock()
+            .unwrap()
+            .insert(book_hash, key);
+        Ok(())
+    })
+    .await?
+}
+
+/// Unlocks the notes of book `book_hash` for this session. The config has
+/// to be read again.
+#[command]
+pub async fn unlock_book_notes(
+    app: AppHandle,
+    book_hash: String,
+    passphrase: String,
+) -> Result<()> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let (config, _) = read_config(&app, &book_hash)?;
+        let sealed = sealed_or_err(&config)?;
+        let (key, _) = Key::of(&passphrase, &book_hash, &sealed)?;
+        app.state::<PrivateNotes>()
+            .0
+            .lock()
+            .unwrap()
+            .insert(book_hash, key);
+        Ok(())
+    })
+    .await?
+}
+
+/// Locks the notes of book `book_hash` again, or of every book when not
+/// given.
+#[command]
+pub fn lock_book_notes(app: AppHandle, book_hash: Option<String>) {
+    let state = app.state::<PrivateNotes>();
+    let mut keys = state.0.lock().unwrap();
+    match book_hash {
+        Some(hash) => {
+            keys.remove(&hash);
+        }
+        None => keys.clear(),
+    }
+}
+
+/// Stops protecting the notes of book `book_hash`, putting them back in
+/// plain text once `passphrase` is checked.
+#[command]
+pub async fn unprotect_book_notes(
+    app: AppHandle,
+    book_hash: String,
+    passphrase: String,
+) -> Result<()> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let (mut config, encrypted) = read_config(&app, &book_hash)?;
+        let sealed = sealed_or_err(&config)?;
+        let (_, mut notes) = Key::of(&passphrase, &book_hash, &sealed)?;
+        notes.extend(take_notes(&mut config)?);
+        let object = fields(&mut config)?;
+        object.remove(FIELD);
+        object.insert(NOTES_FIELD.into(), Value::Array(notes));
+        write_config(&app, &book_hash, &config, encrypted)?;
+        app.state::<PrivateNotes>()
+            .0
+            .lock()
+            .unwrap()
+            .remove(&book_hash);
+        Ok(())
+    })
+    .await?
+}
@@ -0,0 +1,202 @@
+//! Optional encryption at rest of the per-book configs, which hold the
+//! bookmarks, highlights and notes. The data key is random and kept in the
+//! keychain, so the files are unreadable without the user's OS login.
+//! Encrypted files start with a magic header; files without one are read
+//! as plain JSON, so turning encryption on or off needs no migration.
+
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard, OnceLock, PoisonError};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use tauri::{command, AppHandle};
+
+use super::notes;
+use crate::error::{Error, Result};
+
+/// The path of the per-book configs this module reads and writes.
+pub(crate) use crate::library::config_path;
+
+const DATA_KEY: &str = "data-key";
+const MAGIC: &[u8] = b"VLAE\x01";
+const NONCE_LEN: usize = 24;
+
+fn encryption_error(message: impl std::fmt::Display) -> Error {
+    Error::Encryption(message.to_string())
+}
+
+/// The data cipher, with its key loaded from the keychain once, or created
+/// on first use.
+fn cipher() -> Result<&'static XChaCha20Poly1305> {
+    static CIPHER: OnceLock<XChaCha20Poly1305> = OnceLock::new();
+    /// Held while the key is loaded, so two first uses cannot each create
+    /// one and encrypt with a key the keychain no longer has.
+    static LOADING: Mutex<()> = Mutex::new(());
+    if let Some(cipher) = CIPHER.get() {
+        return Ok(cipher);
+    }
+    let _loading = LOADING.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(cipher) = CIPHER.get() {
+        return Ok(cipher);
+    }
+    let key = match super::get(DATA_KEY)? {
+        Some(key) => BASE64.decode(key).map_err(encryption_error)?,
+        None => {
+            let key = XChaCha20Poly1305::generate_key(&mut OsRng);
+            super::store(DATA_KEY, Some(&BASE64.encode(key)))?;
+            key.to_vec()
+        }
+    };
+    let cipher = XChaCha20Poly1305::new_from_slice(&key).map_err(encryption_error)?;
+    Ok(CIPHER.get_or_init(|| cipher))
+}
+
+pub fn encrypt(plaintext: &[u8]) -> Result<Vec<u8>> {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher()?
+        .encrypt(&nonce, plaintext)
+        .map_err(encryption_error)?;
+    Ok([MAGIC, &nonce, &ciphertext].concat())
+}
+
+/// Decrypts `data`, or returns it unchanged if it is not encrypted.
+pub fn decrypt(data: Vec<u8>) -> Result<Vec<u8>> {
+    let Some(sealed) = data.strip_prefix(MAGIC) else {
+        return Ok(data);
+    };
+    if sealed.len() < NONCE_LEN {
+        return Err(encryption_error("file is truncated"));
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    cipher()?
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| encryption_error("file could not be decrypted"))
+}
+
+pub(crate) fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Reads a file that may be encrypted, or `None` if it does not exist.
+pub fn read_file(path: &Path) -> Result<Option<Vec<u8>>> {
+    match std::fs::read(path) {
+        Ok(data) => decrypt(data).map(Some),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Replaces the file at `path` with `contents`, encrypted when `encrypt`
+/// is set, through a temporary file so it is never left half written.
+pub(crate) fn write_file(path: &Path, contents: Vec<u8>, encrypt: bool) -> Result<()> {
+    let data = if encrypt {
+        self::encrypt(&contents)?
+    } else {
+        contents
+    };
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, data)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Held while a book config is written, so writers that read it first do
+/// not lose each other's changes.
+fn lock_writes() -> MutexGuard<'static, ()> {
+    static WRITES: Mutex<()> = Mutex::new(());
+    WRITES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The config of a book, decrypted if needed, with what its sidecar has
+/// merged in and its private notes as the session allows.
+pub(crate) fn load_book_config(app: &AppHandle, book_hash: &str) -> Result<Option<String>> {
+    if let Err(e) = crate::sync::sidecar::reconcile(app, book_hash) {
+        log::warn!("Failed to merge the sidecar of {book_hash}: {e}");
+    }
+    let Some(data) = read_file(&config_path(app, book_hash)?)? else {
+        return Ok(None);
+    };
+    let Ok(mut config) = serde_json::from_slice::<serde_json::Value>(&data) else {
+        return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+    };
+    if !notes::is_protected(&config) {
+        return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+    }
+    notes::on_read(app, book_hash, &mut config)?;
+    Ok(Some(serde_json::to_string(&config)?))
+}
+
+/// Writes `config` as the config of a book, encrypted when `encrypt` is
+/// set, sealing its notes when they are private.
+pub(crate) fn save_book_config(
+    app: &AppHandle,
+    book_hash: &str,
+    config: serde_json::Value,
+    encrypt: bool,
+) -> Result<()> {
+    let _writing = lock_writes();
+    write_book_config_unlocked(app, book_hash, config, encrypt)
+}
+
+/// Changes the config of a book, empty when it has none yet, with `change`
+/// and writes it back as it is at rest, encrypted or not. No other write
+/// comes between reading and writing it.
+pub(crate) fn update_book_config<T>(
+    app: &AppHandle,
+    book_hash: &str,
+    change: impl FnOnce(&mut serde_json::Value) -> Result<T>,
+) -> Result<T> {
+    let _writing = lock_writes();
+    let mut config = match load_book_config(app, book_hash)? {
+        Some(config) => serde_json::from_str(&config)?,
+        None => serde_json::json!({}),
+    };
+    let changed = change(&mut config)?;
+    let encrypted =
+        std::fs::read(config_path(app, book_hash)?).is_ok_and(|data| is_encrypted(&data));
+    write_book_config_unlocked(app, book_hash, config, encrypted)?;
+    Ok(changed)
+}
+
+fn write_book_config_unlocked(
+    app: &AppHandle,
+    book_hash: &str,
+    mut config: serde_json::Value,
+    encrypt: bool,
+) -> Result<()> {
+    let path = config_path(app, book_hash)?;
+    let current = read_file(&path)?
+        .map(|data| serde_json::from_slice::<serde_json::Value>(&data))
+        .transpose()
+        .unwrap_or_default();
+    notes::on_write(app, book_hash, &mut config, current.as_ref())?;
+    write_file(&path, serde_json::to_vec(&config)?, encrypt)?;
+    crate::sync::sidecar::after_write(app, book_hash);
+    Ok(())
+}
+
+/// Reads the config of a book, see [`load_book_config`].
+#[command]
+pub async fn read_book_config(app: AppHandle, book_hash: String) -> Result<Option<String>> {
+    tauri::async_runtime::spawn_blocking(move || load_book_config(&app, &book_hash)).await?
+}
+
+/// Writes the config of a book, encrypted when `encrypt` is set.
+#[command]
+pub async fn write_book_config(
+    app: AppHandle,
+    book_hash: String,
+    contents: String,
+    encrypt: bool,
+) -> Result<()> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let config = serde_json::from_str::<serde_json::Value>(&contents)?;
+        save_book_config(&app, &book_hash, config, encrypt)
+    })
+    .await?
+}
@@ -0,0 +1,199 @@
+//! Sending books as email attachments over SMTP, to a Send to Kindle
+//! address or anyone else. Kindle takes EPUB but no longer MOBI or AZW3,
+//! so those can be converted first.
+
+use std::path::{Path, PathBuf};
+
+use lettre::message::header::ContentType;
+use lettre::message::{Attachment, Mailbox, MultiPart, SinglePart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle};
+
+use crate::convert;
+use crate::error::{Error, Result};
+use crate::formats::mime_type;
+use crate::secrets;
+use crate::store;
+
+const EMAIL_CONFIG_FILE: &str = "send-email.json";
+/// Keychain entry of the SMTP password, which stays out of the config file.
+#[cfg(desktop)]
+const PASSWORD_SECRET: &str = "send-email.password";
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Security {
+    /// TLS from the start, usually on port 465.
+    Tls,
+    /// Plain connection upgraded with STARTTLS, usually on port 587.
+    #[default]
+    StartTls,
+    /// No encryption, only for servers on the local machine.
+    None,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EmailConfig {
+    pub host: String,
+    /// Defaults to the usual port for `security`.
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub security: Security,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Sender address, which Kindle only accepts if it is on the approved
+    /// list of the account.
+    pub from: String,
+    /// Default recipient, such as the user's Send to Kindle address.
+    pub kindle_address: Option<String>,
+}
+
+fn email_error(message: impl std::fmt::Display) -> Error {
+    Error::Email(message.to_string())
+}
+
+/// Saves the config, moving the password to the keychain.
+#[cfg(desktop)]
+fn save_config(app: &AppHandle, mut config: Option<EmailConfig>) -> Result<()> {
+    let password = config.as_mut().and_then(|config| config.password.take());
+    secrets::store(PASSWORD_SECRET, password.as_deref())?;
+    store::save(app, EMAIL_CONFIG_FILE, &config)
+}
+
+#[cfg(desktop)]
+fn load_config(app: &AppHandle) -> Result<Option<EmailConfig>> {
+    let config: Option<EmailConfig> = store::load(app, EMAIL_CONFIG_FILE);
+    let Some(mut config) = config else {
+        return Ok(None);
+    };
+    config.password = secrets::get(PASSWORD_SECRET)?;
+    Ok(Some(config))
+}
+
+#[cfg(not(desktop))]
+fn save_config(app: &AppHandle, config: Option<EmailConfig>) -> Result<()> {
+    store::save(app, EMAIL_CONFIG_FILE, &config)
+}
+
+#[cfg(not(desktop))]
+fn load_config(app: &AppHandle) -> Result<Option<EmailConfig>> {
+    Ok(store::load(app, EMAIL_CONFIG_FILE))
+}
+
+fn transport(config: &EmailConfig) -> Result<AsyncSmtpTransport<Tokio1Executor>> {
+    let builder = match config.security {
+        Security::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host),
+        Security::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host),
+        Security::None => Ok(AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(
+            &config.host,
+        )),
+    }
+    .map_err(email_error)?;
+    let builder = match config.port {
+        Some(port) => builder.port(port),
+        None => builder,
+    };
+    let builder = match (&config.username, &config.password) {
+        (Some(username), Some(password)) => {
+            builder.credentials(Credentials::new(username.clone(), password.clone()))
+        }
+        _ => builder,
+    };
+    Ok(builder.build())
+}
+
+fn mailbox(address: &str) -> Result<Mailbox> {
+    address
+        .trim()
+        .parse()
+        .map_err(|e| email_error(format!("invalid address {address}: {e}")))
+}
+
+/// A message to `to` with the file at `path` attached, named `filename`.
+async fn book_message(from: &str, to: &str, path: &Path, filename: String) -> Result<Message> {
+    let ext = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let content_type = ContentType::parse(mime_type(&ext)).map_err(email_error)?;
+    let subject = Path::new(&filename)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let body = tokio::fs::read(path).await?;
+    Message::builder()
+        .from(mailbox(from)?)
+        .to(mailbox(to)?)
+        .subject(subject)
+        .multipart(
+            MultiPart::mixed()
+                .singlepart(SinglePart::plain(String::new()))
+                .singlepart(Attachment::new(filename).body(body, content_type)),
+        )
+        .map_err(email_error)
+}
+
+#[command]
+pub async fn get_email_config(app: AppHandle) -> Result<Option<EmailConfig>> {
+    secrets::blocking(move || load_config(&app)).await
+}
+
+#[command]
+pub async fn set_email_config(app: AppHandle, config: Option<EmailConfig>) -> Result<()> {
+    secrets::blocking(move || save_config(&app, config)).await
+}
+
+/// Verifies that the SMTP server of `config` is reachable and accepts its
+/// credentials before the user saves it.
+#[command]
+pub async fn test_email_config(config: EmailConfig) -> Result<()> {
+    if transport(&config)?
+        .test_connection()
+        .await
+        .map_err(email_error)?
+    {
+        Ok(())
+    } else {
+        Err(email_error("the server did not respond"))
+    }
+}
+
+/// Emails the book at `path` to `to`, or to the configured Kindle address.
+/// With `convert` set, formats Kindle no longer takes are sent as EPUB.
+#[command]
+pub async fn send_book(
+    app: AppHandle,
+    path: PathBuf,
+    to: Option<String>,
+    convert: Option<bool>,
+) -> Result<()> {
+    let loading = app.clone();
+    let config = tauri::async_runtime::spawn_blocking(move || load_config(&loading))
+        .await??
+        .ok_or(Error::EmailNotConfigured)?;
+    let to = to
+        .or_else(|| config.kindle_address.clone())
+        .filter(|to| !to.trim().is_empty())
+        .ok_or_else(|| email_error("no recipient"))?;
+
+    let path = if convert.unwrap_or(false) && convert::is_convertible(&path) {
+        tauri::async_runtime::spawn_blocking(move || convert::convert_to_epub(&app, &path))
+            .await??
+            .path
+    } else {
+        path
+    };
+    let filename = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| Error::InvalidBook(format!("{} is not a file", path.display())))?;
+    let message = book_message(&config.from, &to, &path, filename).await?;
+    transport(&config)?
+        .send(message)
+        .await
+        .map_err(email_error)?;
+    Ok(())
+}
@@ -0,0 +1,3 @@
+//! Delivery of book files to other devices, such as a Kindle by email.
+
+pub mod email;
@@ -0,0 +1,211 @@
+//! The reader session, kept on disk by the backend so that it survives the
+//! process dying, which loses what the webview had not stored yet. Reader
+//! windows report their books as the reader moves; the session is written
+//! once they stop for a moment, and right away when a chapter changes. It
+//! is marked as running until a clean quit, so one still marked on launch
+//! was left by a crash and the user is offered to restore it.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tauri::async_runtime::JoinHandle;
+use tauri::{command, AppHandle, Emitter, Manager, State, Window, WindowEvent};
+use tauri_plugin_dialog::{DialogExt, MessageDialogButtons, MessageDialogKind};
+
+use crate::i18n;
+use crate::store;
+use crate::utils::now_millis;
+
+const SESSION_FILE: &str = "session.json";
+/// How long the reader must stay put before its position is written.
+const DEBOUNCE: Duration = Duration::from_secs(2);
+/// Tells the frontend to reopen the books of the session the user chose to
+/// restore.
+pub const RESTORE_EVENT: &str = "session-restore";
+
+/// A book open in a reader window and where it is read.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReaderState {
+    pub book_hash: String,
+    /// CFI or page of the reading position.
+    pub location: String,
+    /// Section being read, a change of which is written at once.
+    #[serde(default)]
+    pub chapter: Option<String>,
+    #[serde(default)]
+    pub progress: Option<f32>,
+    /// Panels as the frontend laid them out, e.g. the sidebar and notebook
+    /// and their widths.
+    #[serde(default)]
+    pub layout: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct SavedSession {
+    /// Set while the app runs, cleared on a clean quit.
+    running: bool,
+    updated_at: i64,
+    /// Books open in each reader window, by label.
+    windows: BTreeMap<String, Vec<ReaderState>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    session: SavedSession,
+    timer: Option<JoinHandle<()>>,
+    /// Windows of a crashed session the user chose to restore, until the
+    /// frontend takes them.
+    restored: Option<Vec<Vec<ReaderState>>>,
+}
+
+#[derive(Default)]
+pub struct ReaderSession(Mutex<Inner>);
+
+fn write(app: &AppHandle) {
+    let state = app.state::<ReaderSession>();
+    let mut inner = state.0.lock().unwrap();
+    inner.session.updated_at = now_millis();
+    if let Err(e) = store::save(app, SESSION_FILE, &inner.session) {
+        log::warn!("Failed to save the reader session: {e}");
+    }
+}
+
+/// Writes the session once nothing has changed for [`DEBOUNCE`].
+fn schedule_write(app: &AppHandle, inner: &mut Inner) {
+    if let Some(timer) = inner.timer.take() {
+        timer.abort();
+    }
+    let app = app.clone();
+    inner.timer = Some(tauri::async_runtime::spawn(async move {
+        tokio::time::sleep(DEBOUNCE).await;
+        tauri::async_runtime::spawn_blocking(move || write(&app));
+    }));
+}
+
+fn offer_restore(app: &AppHandle, windows: Vec<Vec<ReaderState>>) {
+    let app = app.clone();
+    app.dialog()
+        .message(i18n::t(
+            &app,
+            "{{name}} quit unexpectedly. Reopen the books that were open?",
+        ))
+        .title(i18n::t(&app, "Restore Previous Session?"))
+        .kind(MessageDialogKind::Info)
+        .buttons(MessageDialogButtons::OkCancelCustom(
+            i18n::t(&app, "Restore"),
+            i18n::t(&app, "Don't Restore"),
+        ))
+        .show(move |restore| {
+            if !restore {
+                return;
+            }
+            app.state::<ReaderSession>().0.lock().unwrap().restored = Some(windows.clone());
+            if let Err(e) = app.emit_to("main", RESTORE_EVENT, windows) {
+                log::warn!("Failed to restore the reader session: {e}");
+            }
+        });
+}
+
+/// Starts a new session, offering to restore the last one if the app did
+/// not quit cleanly.
+pub fn init(app: &AppHandle) {
+    let previous: SavedSession = store::load(app, SESSION_FILE);
+    app.manage(ReaderSession::default());
+    {
+        let state = app.state::<ReaderSession>();
+        state.0.lock().unwrap().session.running = true;
+    }
+    write(app);
+
+    let windows = previous
+        .windows
+        .into_values()
+        .filter(|books| !books.is_empty())
+        .collect::<Vec<_>>();
+    if previous.running && !windows.is_empty() {
+        offer_restore(app, windows);
+    }
+}
+
+pub fn on_window_event(window: &Window, event: &WindowEvent) {
+    if !matches!(event, WindowEvent::Destroyed) {
+        return;
+    }
+    let app = window.app_handle();
+    let Some(state) = app.try_state::<ReaderSession>() else {
+        return;
+    };
+    let mut inner = state.0.lock().unwrap();
+    if inner.session.windows.remove(window.label()).is_some() {
+        schedule_write(app, &mut inner);
+    }
+}
+
+/// Marks the session as ended cleanly.
+pub fn on_exit(app: &AppHandle) {
+    let Some(state) = app.try_state::<ReaderSession>() else {
+        return;
+    };
+    {
+        let mut inner = state.0.lock().unwrap();
+        if let Some(timer) = inner.timer.take() {
+            timer.abort();
+        }
+        inner.session.running = false;
+    }
+    write(app);
+}
+
+/// Records the books open in the calling window, none when it went back
+/// to the library.
+#[command]
+pub fn update_reader_session(
+    app: AppHandle,
+    window: Window,
+    session: State<'_, ReaderSession>,
+    books: Vec<ReaderState>,
+) {
+    let mut inner = session.0.lock().unwrap();
+    let label = window.label().to_string();
+    let previous = inner.session.windows.get(&label);
+    let chapter_changed = books.iter().any(|book| {
+        previous
+            .and_then(|previous| previous.iter().find(|p| p.book_hash == book.book_hash))
+            .map_or(true, |previous| previous.chapter != book.chapter)
+    });
+    if previous.is_some_and(|previous| *previous == books) {
+        return;
+    }
+    if chapter_changed {
+        for book in &books {
+            if let Some(chapter) = &book.chapter {
+                crate::resources::prefetch::after_position(&app, &book.book_hash, chapter);
+            }
+        }
+    }
+    if books.is_empty() {
+        inner.session.windows.remove(&label);
+    } else {
+        inner.session.windows.insert(label, books);
+    }
+    if chapter_changed {
+        if let Some(timer) = inner.timer.take() {
+            timer.abort();
+        }
+        drop(inner);
+        tauri::async_runtime::spawn_blocking(move || write(&app));
+    } else {
+        schedule_write(&app, &mut inner);
+    }
+}
+
+/// The books of each window of the restored session, once, for a frontend
+/// that was not listening yet when the user chose to restore it.
+#[command]
+pub fn take_restored_session(session: State<'_, ReaderSession>) -> Option<Vec<Vec<ReaderState>>> {
+    session.0.lock().unwrap().restored.take()
+}
@@ -0,0 +1,927 @@
+//! Sending books to other instances on the same network. An instance the
+//! user made visible answers mDNS queries for `_vlarch._tcp` and listens
+//! for transfers on a TCP port of its own.
+//!
+//! A transfer starts with an exchange of ephemeral X25519 keys, from which
+//! each direction gets its ChaCha20-Poly1305 key and both ends a six digit
+//! code the users can compare, since nothing else proves who is on the
+//! other end. The receiver is then offered the books and prompted with a
+//! `lan-share-offer` event; only once it accepts are the files sent, each
+//! with its config holding the annotations and reading progress, and
+//! imported into its library.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use ring::agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519};
+use ring::rand::SystemRandom;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use socket2::{Domain, Protocol, Socket, Type};
+use tauri::{command, AppHandle, Emitter, Manager, State};
+
+use super::mdns::{self, Data, Record};
+use crate::error::{Error, Result};
+use crate::jobs::{self, Job};
+use crate::library::{self, access, db};
+use crate::paths;
+use crate::secrets::storage::{config_path, read_file};
+use crate::store;
+use crate::utils::sanitize_file_name;
+
+const SERVICE: &str = "_vlarch._tcp.local";
+const SETTINGS_FILE: &str = "lan-share.json";
+/// Where received books are written under the app data dir before they
+/// are imported.
+const RECEIVED_DIR: &str = "lan-share";
+const OFFER_EVENT: &str = "lan-share-offer";
+const RECEIVED_EVENT: &str = "lan-share-received";
+const MAGIC: &[u8] = b"VLSHARE1";
+const KEY_LEN: usize = 32;
+/// How long answers to a discovery query are waited for.
+const DISCOVERY_TIME: Duration = Duration::from_secs(2);
+/// How long the receiving user has to accept an offer.
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(120);
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often the service threads check whether they should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+const CHUNK_SIZE: usize = 64 * 1024;
+const MAX_FRAME: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct LanShareSettings {
+    /// Whether other instances can find this one and send it books.
+    pub visible: bool,
+    /// Name shown to the other instances.
+    pub name: String,
+}
+
+impl Default for LanShareSettings {
+    fn default() -> Self {
+        Self {
+            visible: false,
+            name: tauri_plugin_os::hostname(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LanPeer {
+    /// Random id of the instance, new at every launch.
+    pub id: String,
+    pub name: String,
+    pub address: Ipv4Addr,
+    pub port: u16,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OfferedBook {
+    pub hash: String,
+    pub title: String,
+    pub author: String,
+    pub format: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ShareOffer {
+    /// Id to answer the offer with, see [`respond_lan_share`].
+    id: String,
+    from: String,
+    address: SocketAddr,
+    /// The code the sender shows too.
+    code: String,
+    books: Vec<OfferedBook>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ReceivedBooks {
+    from: String,
+    books: Vec<db::Book>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum Message {
+    Offer {
+        from: String,
+        books: Vec<OfferedBook>,
+    },
+    Answer {
+        accept: bool,
+    },
+    /// Followed by frames holding the `size` bytes of the file.
+    Book {
+        hash: String,
+        name: String,
+        size: u64,
+        config: Option<String>,
+    },
+    Done,
+}
+
+pub struct LanShare {
+    id: String,
+    /// Stops the running service when set.
+    service: Mutex<Option<Arc<AtomicBool>>>,
+    /// Offers waiting for the user, by id.
+    offers: Mutex<HashMap<String, mpsc::Sender<bool>>>,
+    /// Hosts a transfer is being received from, which get no second one
+    /// meanwhile so that none can flood the user with offers.
+    receiving: Mutex<HashSet<IpAddr>>,
+}
+
+impl Default for LanShare {
+    fn default() -> Self {
+        Self {
+            id: random_id(),
+            service: Mutex::new(None),
+            offers: Mutex::new(HashMap::new()),
+            receiving: Mutex::new(HashSet::new()),
+        }
+    }
+}
+
+fn random_id() -> String {
+    format!("{:016x}", rand::thread_rng().gen::<u64>())
+}
+
+fn share_error(message: impl std::fmt::Display) -> Error {
+    Error::Share(message.to_string())
+}
+
+fn derive_key(secret: &[u8], transcript: &[u8], label: &[u8]) -> [u8; KEY_LEN] {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(transcript);
+    mac.update(label);
+    mac.finalize().into_bytes().into()
+}
+
+/// A connection whose frames are encrypted and authenticated, numbered by
+/// a counter in each direction so none can be replayed or reordered.
+struct Channel {
+    stream: TcpStream,
+    sealing: ChaCha20Poly1305,
+    opening: ChaCha20Poly1305,
+    sent: u64,
+    received: u64,
+}
+
+impl Channel {
+    /// Agrees on the keys with the other end, returning the channel and
+    /// the code to compare.
+    fn handshake(mut stream: TcpStream, sender: bool) -> Result<(Self, String)> {
+        let private = EphemeralPrivateKey::generate(&X25519, &SystemRandom::new())
+            .map_err(|_| share_error("key generation failed"))?;
+        let public = private
+            .compute_public_key()
+            .map_err(|_| share_error("key generation failed"))?;
+        stream.write_all(&[MAGIC, public.as_ref()].concat())?;
+        let mut hello = [0; MAGIC.len() + KEY_LEN];
+        stream.read_exact(&mut hello)?;
+        let peer = hello
+            .strip_prefix(MAGIC)
+            .ok_or_else(|| share_error("the other end is not VL-Arch"))?;
+        let secret =
+            agreement::agree_ephemeral(private, &UnparsedPublicKey::new(&X25519, peer), |secret| {
+                secret.to_vec()
+            })
+            .map_err(|_| share_error("key agreement failed"))?;
+
+        let (first, second) = if sender {
+            (public.as_ref(), peer)
+        } else {
+            (peer, public.as_ref())
+        };
+        let transcript = [MAGIC, first, second].concat();
+        let to_receiver = derive_key(&secret, &transcript, b"sender");
+        let to_sender = derive_key(&secret, &transcript, b"receiver");
+        let code = derive_key(&secret, &transcript, b"code");
+        let code = u32::from_be_bytes([code[0], code[1], code[2], code[3]]) % 1_000_000;
+        let (sealing, opening) = if sender {
+            (to_receiver, to_sender)
+        } else {
+            (to_sender, to_receiver)
+        };
+        let cipher = |key: [u8; KEY_LEN]| {
+            ChaCha20Poly1305::new_from_slice(&key).map_err(|e| share_error(e.to_string()))
+        };
+        let channel = Self {
+            stream,
+            sealing: cipher(sealing)?,
+            opening: cipher(opening)?,
+            sent: 0,
+            received: 0,
+        };
+        Ok((channel, format!("{code:06}")))
+    }
+
+    fn nonce(counter: u64) -> [u8; 12] {
+        let mut nonce = [0; 12];
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
+        nonce
+    }
+
+    fn send_frame(&mut self, data: &[u8]) -> Result<()> {
+        let nonce = Self::nonce(self.sent);
+        let sealed = self
+            .sealing
+            .encrypt(Nonce::from_slice(&nonce), data)
+            .map_err(|_| share_error("encryption failed"))?;
+        self.sent += 1;
+        self.stream
+            .write_all(&(sealed.len() as u32).to_be_bytes())?;
+        self.stream.write_all(&sealed)?;
+        Ok(())
+    }
+
+    fn receive_frame(&mut self) -> Result<Vec<u8>> {
+        let mut len = [0; 4];
+        self.stream.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_FRAME {
+            return Err(share_error("frame too large"));
+        }
+        let mut sealed = vec![0; len];
+        self.stream.read_exact(&mut sealed)?;
+        let nonce = Self::nonce(self.received);
+        let data = self
+            .opening
+            .decrypt(Nonce::from_slice(&nonce), sealed.as_slice())
+            .map_err(|_| share_error("the data was tampered with"))?;
+        self.received += 1;
+        Ok(data)
+    }
+
+    fn send(&mut self, message: &Message) -> Result<()> {
+        self.send_frame(&serde_json::to_vec(message)?)
+    }
+
+    fn receive(&mut self) -> Result<Message> {
+        Ok(serde_json::from_slice(&self.receive_frame()?)?)
+    }
+}
+
+fn unexpected() -> Error {
+    share_error("unexpected message")
+}
+
+/// The records announcing the instance `id` as `name`, listening on `port`.
+fn records(id: &str, name: &str, port: u16) -> Vec<Record> {
+    let label = name
+        .chars()
+        .filter(|c| *c != '.' && !c.is_control())
+        .take(40)
+        .collect::<String>();
+    let label = if label.trim().is_empty() {
+        "VL-Arch".to_string()
+    } else {
+        label
+    };
+    let instance = format!("{label}.{SERVICE}");
+    vec![
+        Record {
+            name: SERVICE.to_string(),
+            data: Data::Ptr(instance.clone()),
+        },
+        Record {
+            name: instance.clone(),
+            data: Data::Srv {
+                port,
+                target: format!("{id}.local"),
+            },
+        },
+        Record {
+            name: instance,
+            data: Data::Txt(vec![
+                "v=1".to_string(),
+                format!("id={id}"),
+                format!("name={name}"),
+            ]),
+        },
+    ]
+}
+
+/// A socket on the mDNS port, shared with the system responder if any.
+fn multicast_socket() -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, mdns::PORT)).into())?;
+    socket.join_multicast_v4(&mdns::ADDR, &Ipv4Addr::UNSPECIFIED)?;
+    let socket = UdpSocket::from(socket);
+    socket.set_read_timeout(Some(POLL_INTERVAL))?;
+    Ok(socket)
+}
+
+/// Answers queries for the service until `stop` is set.
+fn respond(socket: UdpSocket, records: Vec<Record>, stop: Arc<AtomicBool>) {
+    let mut buf = [0; 9000];
+    while !stop.load(Ordering::Relaxed) {
+        let Ok((len, from)) = socket.recv_from(&mut buf) else {
+            continue;
+        };
+        let Some(message) = mdns::parse(&buf[..len]).filter(|m| !m.response) else {
+            continue;
+        };
+        let questions = message
+            .questions
+            .into_iter()
+            .filter(|q| q.name.eq_ignore_ascii_case(SERVICE))
+            .filter(|q| matches!(q.kind, mdns::TYPE_PTR | mdns::TYPE_ANY))
+            .collect::<Vec<_>>();
+        if questions.is_empty() {
+            continue;
+        }
+        // Queries from other ports are one-shot and get a unicast answer
+        let sent = if from.port() == mdns::PORT {
+            socket.send_to(&mdns::response(0, &[], &records), mdns::GROUP)
+        } else {
+            socket.send_to(&mdns::response(message.id, &questions, &records), from)
+        };
+        if let Err(e) = sent {
+            log::warn!("Failed to answer mDNS query from {from}: {e}");
+        }
+    }
+}
+
+/// Takes connections until `stop` is set, each on a thread of its own.
+fn listen(app: AppHandle, listener: TcpListener, stop: Arc<AtomicBool>) {
+    while !stop.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, address)) => {
+                let state = app.state::<LanShare>();
+                if !state.receiving.lock().unwrap().insert(address.ip()) {
+                    log::warn!("Refused a second transfer at once from {}", address.ip());
+                    continue;
+                }
+                let handle = app.clone();
+                let spawned = std::thread::Builder::new()
+                    .name("lan-share-receive".to_string())
+                    .spawn(move || {
+                        if let Err(e) = receive(&handle, stream, address) {
+                            log::warn!("Failed to receive books from {address}: {e}");
+                        }
+                        let state = handle.state::<LanShare>();
+                        state.receiving.lock().unwrap().remove(&address.ip());
+                    });
+                if let Err(e) = spawned {
+                    log::warn!("Failed to receive books from {address}: {e}");
+                    state.receiving.lock().unwrap().remove(&address.ip());
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                std::thread::sleep(POLL_INTERVAL);
+            }
+            Err(e) => {
+                log::warn!("Failed to accept a LAN share connection: {e}");
+                std::thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+}
+
+fn start(app: &AppHandle, settings: &LanShareSettings) -> Result<()> {
+    let state = app.state::<LanShare>();
+    let mut service = state.service.lock().unwrap();
+    if service.is_some() {
+        return Ok(());
+    }
+    let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    listener.set_nonblocking(true)?;
+    let port = listener.local_addr()?.port();
+    let socket = multicast_socket()?;
+    let records = records(&state.id, &settings.name, port);
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let (handle, stopping) = (app.clone(), stop.clone());
+    std::thread::Builder::new()
+        .name("lan-share".to_string())
+        .spawn(move || listen(handle, listener, stopping))?;
+    let stopping = stop.clone();
+    std::thread::Builder::new()
+        .name("lan-share-mdns".to_string())
+        .spawn(move || respond(socket, records, stopping))?;
+    *service = Some(stop);
+    Ok(())
+}
+
+fn stop(app: &AppHandle) {
+    if let Some(stop) = app.state::<LanShare>().service.lock().unwrap().take() {
+        stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Asks the network for instances, leaving out this one.
+fn discover(own_id: &str) -> Result<Vec<LanPeer>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_read_timeout(Some(Duration::from_millis(200)))?;
+    socket.send_to(&mdns::query(rand::random(), SERVICE), mdns::GROUP)?;
+
+    let deadline = Instant::now() + DISCOVERY_TIME;
+    let mut peers: Vec<LanPeer> = Vec::new();
+    let mut buf = [0; 9000];
+    while Instant::now() < deadline {
+        let Ok((len, SocketAddr::V4(from))) = socket.recv_from(&mut buf) else {
+            continue;
+        };
+        let Some(message) = mdns::parse(&buf[..len]).filter(|m| m.response) else {
+            continue;
+        };
+        let instances = message
+            .records
+            .iter()
+            .filter_map(|record| match &record.data {
+                Data::Ptr(instance) if record.name.eq_ignore_ascii_case(SERVICE) => Some(instance),
+                _ => None,
+            });
+        for instance in instances {
+            let of_instance = || {
+                message
+                    .records
+                    .iter()
+                    .filter(|record| record.name.eq_ignore_ascii_case(instance))
+            };
+            let port = of_instance().find_map(|record| match record.data {
+                Data::Srv { port, .. } => Some(port),
+                _ => None,
+            });
+            let txt = of_instance()
+                .find_map(|record| match &record.data {
+                    Data::Txt(strings) => Some(strings.clone()),
+                    _ => None,
+                })
+                .unwrap_or_default();
+            let value = |key: &str| {
+                txt.iter()
+                    .find_map(|s| s.strip_prefix(key)?.strip_prefix('='))
+                    .map(String::from)
+            };
+            let (Some(id), Some(port)) = (value("id"), port) else {
+                continue;
+            };
+            if id == own_id || peers.iter().any(|peer| peer.id == id) {
+                continue;
+            }
+            let name = value("name").unwrap_or_else(|| {
+                instance
+                    .strip_suffix(SERVICE)
+                    .unwrap_or(instance)
+                    .trim_end_matches('.')
+                    .to_string()
+            });
+            peers.push(LanPeer {
+                id,
+                name,
+                address: *from.ip(),
+                port,
+            });
+        }
+    }
+    Ok(peers)
+}
+
+/// A path for `name` in `dir` that is not taken.
+fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    let name = sanitize_file_name(name);
+    let name = Path::new(&name)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "book".to_string());
+    let path = dir.join(&name);
+    if !path.exists() {
+        return path;
+    }
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    (1..)
+        .map(|i| dir.join(format!("{stem} ({i}){ext}")))
+        .find(|p| !p.exists())
+        .unwrap()
+}
+
+/// Imports a received file with its config, which only replaces none.
+fn import_received(app: &AppHandle, path: &Path, config: Option<String>) -> Result<db::Book> {
+    let book = library::import::import_and_discard(app, path)?;
+    if let Some(config) = config {
+        let config_path = config_path(app, &book.hash)?;
+        if !config_path.exists() {
+            if let Some(parent) = config_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(&config_path, config)?;
+        }
+    }
+    Ok(book)
+}
+
+/// Takes a transfer, asking the user first.
+fn receive(app: &AppHandle, stream: TcpStream, address: SocketAddr) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    let (mut channel, code) = Channel::handshake(stream, false)?;
+    let Message::Offer { from, books } = channel.receive()? else {
+        return Err(unexpected());
+    };
+
+    let state = app.state::<LanShare>();
+    let id = random_id();
+    let (answer, answered) = mpsc::channel();
+    state.offers.lock().unwrap().insert(id.clone(), answer);
+    let offer = ShareOffer {
+        id: id.clone(),
+        from: from.clone(),
+        address,
+        code,
+        books: books.clone(),
+    };
+    let _ = app.emit(OFFER_EVENT, offer);
+    let accept = answered.recv_timeout(ANSWER_TIMEOUT).unwrap_or(false);
+    state.offers.lock().unwrap().remove(&id);
+    channel.send(&Message::Answer { accept })?;
+    if !accept {
+        return Ok(());
+    }
+
+    let dir = paths::data_dir(app)?.join(RECEIVED_DIR);
+    std::fs::create_dir_all(&dir)?;
+    let mut received = Vec::new();
+    let mut seen = HashSet::new();
+    loop {
+        let (name, size, config) = match channel.receive()? {
+            Message::Book {
+                hash,
+                name,
+                size,
+                config,
+            } if books.iter().any(|b| b.hash == hash && b.size == size) => {
+                // Each book offered is taken once
+                if !seen.insert(hash) {
+                    return Err(unexpected());
+                }
+                (name, size, config)
+            }
+            Message::Done => break,
+            _ => return Err(unexpected()),
+        };
+        let path = unique_path(&dir, &name);
+        let mut file = File::create(&path)?;
+        let mut left = size;
+        while left > 0 {
+            let chunk = channel.receive_frame()?;
+            if chunk.len() as u64 > left {
+                drop(file);
+                let _ = std::fs::remove_file(&path);
+                return Err(unexpected());
+            }
+            file.write_all(&chunk)?;
+            left -= chunk.len() as u64;
+        }
+        drop(file);
+        match import_received(app, &path, config) {
+            Ok(book) => received.push(book),
+            Err(e) => log::warn!("Failed to import {name} from {from}: {e}"),
+        }
+    }
+    channel.send(&Message::Done)?;
+    let _ = app.emit(
+        RECEIVED_EVENT,
+        ReceivedBooks {
+            from,
+            books: received,
+        },
+    );
+    Ok(())
+}
+
+/// Sends `books` to `peer` once it accepts them.
+fn send(
+    app: &AppHandle,
+    peer: &LanPeer,
+    books: Vec<(OfferedBook, PathBuf, Option<String>)>,
+    job: &Job,
+) -> Result<()> {
+    job.phase("connecting", Some(peer.name.clone()));
+    let address = SocketAddr::from((peer.address, peer.port));
+    let stream = TcpStream::connect_timeout(&address, IO_TIMEOUT)?;
+    stream.set_read_timeout(Some(ANSWER_TIMEOUT + IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    let (mut channel, code) = Channel::handshake(stream, true)?;
+    // The code is shown while the other end decides
+    job.phase("waiting", Some(code));
+    let settings: LanShareSettings = store::load(app, SETTINGS_FILE);
+    channel.send(&Message::Offer {
+        from: settings.name,
+        books: books.iter().map(|(book, ..)| book.clone()).collect(),
+    })?;
+    match channel.receive()? {
+        Message::Answer { accept: true } => {}
+        Message::Answer { accept: false } => return Err(share_error("the books were declined")),
+        _ => return Err(unexpected()),
+    }
+
+    let total = books.iter().map(|(book, ..)| book.size).sum::<u64>();
+    let mut sent = 0;
+    let mut buf = vec![0; CHUNK_SIZE];
+    for (book, path, config) in books {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| format!("{}.{}", book.title, book.format));
+        channel.send(&Message::Book {
+            hash: book.hash,
+            name,
+            size: book.size,
+            config,
+        })?;
+        let mut file = File::open(&path)?;
+        let mut left = book.size;
+        while left > 0 {
+            if job.is_cancelled() {
+                return Err(Error::Cancelled);
+            }
+            let n = file.read(&mut buf[..CHUNK_SIZE.min(left as usize)])?;
+            if n == 0 {
+                return Err(share_error(format!("{} changed while sent", book.title)));
+            }
+            channel.send_frame(&buf[..n])?;
+            left -= n as u64;
+            sent += n as u64;
+            job.progress(
+                "sending",
+                sent as usize,
+                total as usize,
+                Some(book.title.clone()),
+            );
+        }
+    }
+    channel.send(&Message::Done)?;
+    // The receiver answers once it imported everything
+    match channel.receive()? {
+        Message::Done => Ok(()),
+        _ => Err(unexpected()),
+    }
+}
+
+/// The books `hashes` with their files and configs, ready to send.
+fn gather(
+    app: &AppHandle,
+    hashes: &[String],
+) -> Result<Vec<(OfferedBook, PathBuf, Option<String>)>> {
+    let found = {
+        let db = app.state::<db::LibraryDb>();
+        let conn = db.conn();
+        let lock = access::lock(app, &conn)?;
+        // The receiver takes each book once
+        let mut seen = HashSet::new();
+        hashes
+            .iter()
+            .filter(|hash| seen.insert(hash.as_str()))
+            .map(|hash| {
+                db::get_unlocked_book(&conn, hash, &lock)?
+                    .ok_or_else(|| Error::InvalidBook(format!("no book {hash} in the library")))
+            })
+            .collect::<Result<Vec<_>>>()?
+    };
+    let mut books = Vec::with_capacity(found.len());
+    for book in found {
+        let path = library::book_path(app, &book)
+            .ok_or_else(|| share_error(format!("the file of {} is missing", book.title)))?;
+        let config = read_file(&config_path(app, &book.hash)?)?
+            .map(|config| String::from_utf8_lossy(&config).into_owned());
+        let offered = OfferedBook {
+            size: std::fs::metadata(&path)?.len(),
+            hash: book.hash,
+            title: book.title,
+            author: book.author,
+            format: book.format,
+        };
+        books.push((offered, path, config));
+    }
+    Ok(books)
+}
+
+pub fn init(app: &AppHandle) {
+    app.manage(LanShare::default());
+    let settings: LanShareSettings = store::load(app, SETTINGS_FILE);
+    if settings.visible {
+        if let Err(e) = start(app, &settings) {
+            log::warn!("Failed to start LAN sharing: {e}");
+        }
+    }
+}
+
+#[command]
+pub fn get_lan_sharing(app: AppHandle) -> LanShareSettings {
+    store::load(&app, SETTINGS_FILE)
+}
+
+/// Saves `settings`, starting or stopping the service to match.
+#[command]
+pub fn set_lan_sharing(app: AppHandle, settings: LanShareSettings) -> Result<()> {
+    store::save(&app, SETTINGS_FILE, &settings)?;
+    stop(&app);
+    if settings.visible {
+        start(&app, &settings)?;
+    }
+    Ok(())
+}
+
+/// Lists the visible instances on the network.
+#[command]
+pub async fn discover_lan_peers(state: State<'_, LanShare>) -> Result<Vec<LanPeer>> {
+    let id = state.id.clone();
+    tauri::async_runtime::spawn_blocking(move || discover(&id)).await?
+}
+
+/// Offers the books `hashes` to `peer` and sends them if accepted, as a
+/// `share` job whose message is the code while the peer decides.
+#[command]
+pub async fn share_books_lan(app: AppHandle, peer: LanPeer, hashes: Vec<String>) -> Result<()> {
+    let handle = app.clone();
+    jobs::run(&handle, "share", |job| async move {
+        let worker = job.clone();
+        job.run_blocking(move || {
+            let books = gather(&app, &hashes)?;
+            send(&app, &peer, books, &worker)
+        })
+        .await
+    })
+    .await
+}
+
+/// Accepts or declines the offer `id`, returning `false` if it is no
+/// longer waiting.
+#[command]
+pub fn respond_lan_share(state: State<'_, LanShare>, id: String, accept: bool) -> bool {
+    state
+        .offers
+        .lock()
+        .unwrap()
+        .remove(&id)
+        .is_some_and(|answer| answer.send(accept).is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    /// Runs `peer` on the accepted end of a loopback connection, returning
+    /// the connecting end and what `peer` returned.
+    fn connect<T: Send + 'static>(
+        peer: impl FnOnce(TcpStream) -> T + Send + 'static,
+    ) -> (TcpStream, thread::JoinHandle<T>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        let peer = thread::spawn(move || peer(listener.accept().unwrap().0));
+        (TcpStream::connect(address).unwrap(), peer)
+    }
+
+    fn pair() -> ((Channel, String), (Channel, String)) {
+        let (stream, receiver) = connect(|stream| Channel::handshake(stream, false).unwrap());
+        let sender = Channel::handshake(stream, true).unwrap();
+        (sender, receiver.join().unwrap())
+    }
+
+    /// A frame of `data` sealed by `channel` as its frame `counter`.
+    fn frame(channel: &Channel, counter: u64, data: &[u8]) -> Vec<u8> {
+        let nonce = Channel::nonce(counter);
+        let sealed = channel
+            .sealing
+            .encrypt(Nonce::from_slice(&nonce), data)
+            .unwrap();
+        [&(sealed.len() as u32).to_be_bytes()[..], &sealed].concat()
+    }
+
+    /// Answers a handshake with `hello`, after reading the other end's.
+    fn impostor(hello: Vec<u8>) -> (TcpStream, thread::JoinHandle<()>) {
+        connect(move |mut stream| {
+            stream.read_exact(&mut [0; MAGIC.len() + KEY_LEN]).unwrap();
+            stream.write_all(&hello).unwrap();
+        })
+    }
+
+    #[test]
+    fn both_ends_agree_on_keys_and_code() {
+        let ((mut sender, sent_code), (mut receiver, received_code)) = pair();
+        assert_eq!(sent_code, received_code);
+        assert_eq!(sent_code.len(), 6);
+        assert!(sent_code.bytes().all(|b| b.is_ascii_digit()));
+
+        let offer = Message::Offer {
+            from: "Desk".into(),
+            books: Vec::new(),
+        };
+        sender.send(&offer).unwrap();
+        sender.send_frame(&[]).unwrap();
+        assert!(
+            matches!(receiver.receive().unwrap(), Message::Offer { from, .. } if from == "Desk")
+        );
+        assert!(receiver.receive_frame().unwrap().is_empty());
+        receiver.send(&Message::Answer { accept: true }).unwrap();
+        assert!(matches!(
+            sender.receive().unwrap(),
+            Message::Answer { accept: true }
+        ));
+    }
+
+    #[test]
+    fn frames_do_not_open_on_other_connections() {
+        let ((sender, _), _) = pair();
+        let ((mut other, _), (mut receiver, _)) = pair();
+        other
+            .stream
+            .write_all(&frame(&sender, 0, b"hello"))
+            .unwrap();
+        assert!(matches!(receiver.receive_frame(), Err(Error::Share(_))));
+    }
+
+    #[test]
+    fn reflected_frames_are_refused() {
+        let ((mut sender, _), (mut receiver, _)) = pair();
+        // A frame of the receiver's sent back to it
+        let reflected = frame(&receiver, 0, b"hello");
+        sender.stream.write_all(&reflected).unwrap();
+        assert!(matches!(receiver.receive_frame(), Err(Error::Share(_))));
+    }
+
+    #[test]
+    fn tampered_frames_are_refused() {
+        let ((mut sender, _), (mut receiver, _)) = pair();
+        let mut tampered = frame(&sender, 0, b"hello");
+        tampered[4] ^= 1;
+        sender.stream.write_all(&tampered).unwrap();
+        assert!(matches!(receiver.receive_frame(), Err(Error::Share(_))));
+    }
+
+    #[test]
+    fn replayed_and_reordered_frames_are_refused() {
+        let ((mut sender, _), (mut receiver, _)) = pair();
+        let first = frame(&sender, 0, b"first");
+        sender.stream.write_all(&first).unwrap();
+        assert_eq!(receiver.receive_frame().unwrap(), b"first");
+        sender.stream.write_all(&first).unwrap();
+        assert!(matches!(receiver.receive_frame(), Err(Error::Share(_))));
+
+        let ((mut sender, _), (mut receiver, _)) = pair();
+        let second = frame(&sender, 1, b"second");
+        sender.stream.write_all(&second).unwrap();
+        assert!(matches!(receiver.receive_frame(), Err(Error::Share(_))));
+    }
+
+    #[test]
+    fn oversized_frames_are_not_read() {
+        let ((mut sender, _), (mut receiver, _)) = pair();
+        let len = MAX_FRAME as u32 + 1;
+        sender.stream.write_all(&len.to_be_bytes()).unwrap();
+        assert!(matches!(receiver.receive_frame(), Err(Error::Share(_))));
+    }
+
+    #[test]
+    fn other_peers_fail_the_handshake() {
+        let (stream, peer) = impostor(b"HTTP/1.1 400 Bad Request\r\n\r\n0123456789ab".to_vec());
+        assert!(matches!(
+            Channel::handshake(stream, true),
+            Err(Error::Share(_))
+        ));
+        peer.join().unwrap();
+
+        // A key of all zeros, which would give a known secret
+        let (stream, peer) = impostor([MAGIC, &[0; KEY_LEN]].concat());
+        assert!(matches!(
+            Channel::handshake(stream, true),
+            Err(Error::Share(_))
+        ));
+        peer.join().unwrap();
+
+        let (stream, peer) = impostor(MAGIC.to_vec());
+        assert!(matches!(
+            Channel::handshake(stream, true),
+            Err(Error::Io(_))
+        ));
+        peer.join().unwrap();
+    }
+}
@@ -0,0 +1,230 @@
+//! Just enough multicast DNS (RFC 6762) and DNS-SD (RFC 6763) to find
+//! other instances on the network: PTR queries for the service type and
+//! answers with the PTR, SRV and TXT records of an instance. Addresses are
+//! taken from where answers come from, so no A records are needed.
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+pub const ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+pub const PORT: u16 = 5353;
+pub const GROUP: SocketAddrV4 = SocketAddrV4::new(ADDR, PORT);
+
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_SRV: u16 = 33;
+pub const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Set in the class of questions wanting a unicast answer, and of records
+/// replacing the ones cached before.
+const CLASS_FLAG: u16 = 0x8000;
+const FLAG_RESPONSE: u16 = 0x8400;
+const TTL: u32 = 120;
+
+#[derive(Debug, Clone)]
+pub struct Question {
+    pub name: String,
+    pub kind: u16,
+}
+
+#[derive(Debug, Clone)]
+pub enum Data {
+    Ptr(String),
+    Srv { port: u16, target: String },
+    Txt(Vec<String>),
+    Other,
+}
+
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub name: String,
+    pub data: Data,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Message {
+    pub id: u16,
+    pub response: bool,
+    pub questions: Vec<Question>,
+    /// Answers and additional records together.
+    pub records: Vec<Record>,
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn u8(&mut self) -> Option<u8> {
+        let byte = *self.buf.get(self.pos)?;
+        self.pos += 1;
+        Some(byte)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes([self.u8()?, self.u8()?]))
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&[u8]> {
+        let bytes = self.buf.get(self.pos..self.pos + len)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    /// Reads a name, following compression pointers.
+    fn name(&mut self) -> Option<String> {
+        let mut labels = Vec::new();
+        let mut pos = self.pos;
+        let mut jumped = false;
+        // Bounds the pointers followed, which could loop
+        for _ in 0..64 {
+            let len = *self.buf.get(pos)? as usize;
+            if len == 0 {
+                if !jumped {
+                    self.pos = pos + 1;
+                }
+                return Some(labels.join("."));
+            }
+            if len & 0xC0 == 0xC0 {
+                let target = (len & 0x3F) << 8 | *self.buf.get(pos + 1)? as usize;
+                if !jumped {
+                    self.pos = pos + 2;
+                }
+                jumped = true;
+                pos = target;
+                continue;
+            }
+            let label = self.buf.get(pos + 1..pos + 1 + len)?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            pos += 1 + len;
+        }
+        None
+    }
+}
+
+pub fn parse(buf: &[u8]) -> Option<Message> {
+    let mut reader = Reader { buf, pos: 0 };
+    let id = reader.u16()?;
+    let flags = reader.u16()?;
+    let counts = [reader.u16()?, reader.u16()?, reader.u16()?, reader.u16()?];
+    let mut message = Message {
+        id,
+        response: flags & 0x8000 != 0,
+        ..Message::default()
+    };
+    for _ in 0..counts[0] {
+        let name = reader.name()?;
+        let kind = reader.u16()?;
+        reader.u16()?;
+        message.questions.push(Question { name, kind });
+    }
+    for _ in 0..u32::from(counts[1]) + u32::from(counts[2]) + u32::from(counts[3]) {
+        let name = reader.name()?;
+        let kind = reader.u16()?;
+        reader.bytes(6)?;
+        let len = reader.u16()? as usize;
+        let end = reader.pos + len;
+        let data = match kind {
+            TYPE_PTR => Data::Ptr(reader.name()?),
+            TYPE_SRV => {
+                reader.bytes(4)?;
+                let port = reader.u16()?;
+                Data::Srv {
+                    port,
+                    target: reader.name()?,
+                }
+            }
+            TYPE_TXT => {
+                let mut strings = Vec::new();
+                while reader.pos < end {
+                    let len = reader.u8()? as usize;
+                    strings.push(String::from_utf8_lossy(reader.bytes(len)?).into_owned());
+                }
+                Data::Txt(strings)
+            }
+            _ => Data::Other,
+        };
+        reader.pos = end;
+        message.records.push(Record { name, data });
+    }
+    Some(message)
+}
+
+fn write_name(out: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        out.push(label.len() as u8);
+        out.extend_from_slice(label);
+    }
+    out.push(0);
+}
+
+fn write_header(out: &mut Vec<u8>, id: u16, flags: u16, questions: u16, answers: u16) {
+    for value in [id, flags, questions, answers, 0, 0] {
+        out.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+/// A query for the PTR records of `service`, asking for unicast answers.
+pub fn query(id: u16, service: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_header(&mut out, id, 0, 1, 0);
+    write_name(&mut out, service);
+    out.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    out.extend_from_slice(&(CLASS_IN | CLASS_FLAG).to_be_bytes());
+    out
+}
+
+/// An answer with `records`, repeating `questions` as answers to legacy
+/// unicast queries must.
+pub fn response(id: u16, questions: &[Question], records: &[Record]) -> Vec<u8> {
+    let records = records
+        .iter()
+        .filter(|record| !matches!(record.data, Data::Other))
+        .collect::<Vec<_>>();
+    let mut out = Vec::new();
+    write_header(
+        &mut out,
+        id,
+        FLAG_RESPONSE,
+        questions.len() as u16,
+        records.len() as u16,
+    );
+    for question in questions {
+        write_name(&mut out, &question.name);
+        out.extend_from_slice(&question.kind.to_be_bytes());
+        out.extend_from_slice(&CLASS_IN.to_be_bytes());
+    }
+    for record in records {
+        let (kind, class) = match record.data {
+            Data::Ptr(_) => (TYPE_PTR, CLASS_IN),
+            Data::Srv { .. } => (TYPE_SRV, CLASS_IN | CLASS_FLAG),
+            Data::Txt(_) => (TYPE_TXT, CLASS_IN | CLASS_FLAG),
+            Data::Other => continue,
+        };
+        write_name(&mut out, &record.name);
+        out.extend_from_slice(&kind.to_be_bytes());
+        out.extend_from_slice(&class.to_be_bytes());
+        out.extend_from_slice(&TTL.to_be_bytes());
+        let mut data = Vec::new();
+        match &record.data {
+            Data::Ptr(name) => write_name(&mut data, name),
+            Data::Srv { port, target } => {
+                data.extend_from_slice(&[0, 0, 0, 0]);
+                data.extend_from_slice(&port.to_be_bytes());
+                write_name(&mut data, target);
+            }
+            Data::Txt(strings) => {
+                for string in strings {
+                    let string = &string.as_bytes()[..string.len().min(255)];
+                    data.push(string.len() as u8);
+                    data.extend_from_slice(string);
+                }
+            }
+            Data::Other => {}
+        }
+        out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        out.extend_from_slice(&data);
+    }
+    out
+}
@@ -0,0 +1,4 @@
+//! Sharing books directly with other devices, without a server.
+
+pub mod lan;
+mod mdns;
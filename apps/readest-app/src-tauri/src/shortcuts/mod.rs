@@ -0,0 +1,210 @@
+//! System-wide keyboard shortcuts the user binds to reader actions, which
+//! work while another app is in front, e.g. to pause text-to-speech. The
+//! bindings are kept in the config store and registered again at startup.
+//! Showing and hiding the window is handled here; the other actions reach
+//! the frontend as `global-shortcut` events.
+
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use tauri::plugin::TauriPlugin;
+use tauri::{command, AppHandle, Emitter, Manager, State, Wry};
+use tauri_plugin_global_shortcut::{GlobalShortcutExt, Shortcut, ShortcutState};
+
+use crate::error::{Error, Result};
+use crate::store;
+
+const SHORTCUTS_FILE: &str = "global-shortcuts.json";
+pub const EVENT: &str = "global-shortcut";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ShortcutAction {
+    ToggleTts,
+    NextChapter,
+    PreviousChapter,
+    NextPage,
+    PreviousPage,
+    ToggleWindow,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StoredBinding {
+    action: ShortcutAction,
+    /// Accelerator as typed by the user, e.g. `CommandOrControl+Shift+Space`.
+    accelerator: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShortcutBinding {
+    pub action: ShortcutAction,
+    pub accelerator: String,
+    /// Whether the OS accepted the shortcut. Ones another app took in the
+    /// meantime stay bound but inactive until they are set again.
+    pub active: bool,
+}
+
+struct Binding {
+    action: ShortcutAction,
+    accelerator: String,
+    /// Set while registered with the OS.
+    shortcut: Option<Shortcut>,
+}
+
+/// All bindings of the user, in the order they were made.
+#[derive(Default)]
+pub struct GlobalShortcuts(Mutex<Vec<Binding>>);
+
+fn parse(accelerator: &str) -> Result<Shortcut> {
+    accelerator
+        .parse()
+        .map_err(|e| tauri_plugin_global_shortcut::Error::from(e).into())
+}
+
+fn save(app: &AppHandle, bindings: &[Binding]) -> Result<()> {
+    let bindings = bindings
+        .iter()
+        .map(|binding| StoredBinding {
+            action: binding.action,
+            accelerator: binding.accelerator.clone(),
+        })
+        .collect::<Vec<_>>();
+    store::save(app, SHORTCUTS_FILE, &bindings)
+}
+
+fn toggle_window(app: &AppHandle) {
+    let Some(window) = app.get_webview_window("main") else {
+        return;
+    };
+    let in_front = window.is_visible().unwrap_or(false)
+        && !window.is_minimized().unwrap_or(false)
+        && window.is_focused().unwrap_or(false);
+    if in_front {
+        let _ = window.hide();
+    } else {
+        crate::show_main_window(app);
+    }
+}
+
+fn trigger(app: &AppHandle, shortcut: &Shortcut) {
+    let action = {
+        let bindings = app.state::<GlobalShortcuts>();
+        let bindings = bindings.0.lock().unwrap();
+        bindings
+            .iter()
+            .find(|binding| binding.shortcut.as_ref() == Some(shortcut))
+            .map(|binding| binding.action)
+    };
+    match action {
+        Some(ShortcutAction::ToggleWindow) => toggle_window(app),
+        Some(action) => {
+            let _ = app.emit(EVENT, action);
+        }
+        None => {}
+    }
+}
+
+pub fn plugin() -> TauriPlugin<Wry> {
+    tauri_plugin_global_shortcut::Builder::new()
+        .with_handler(|app, shortcut, event| {
+            if event.state == ShortcutState::Pressed {
+                trigger(app, shortcut);
+            }
+        })
+        .build()
+}
+
+/// Registers the stored bindings with the OS.
+pub fn init(app: &AppHandle) {
+    let stored: Vec<StoredBinding> = store::load(app, SHORTCUTS_FILE);
+    let bindings = stored
+        .into_iter()
+        .map(|binding| {
+            let registered = parse(&binding.accelerator).and_then(|shortcut| {
+                app.global_shortcut().register(shortcut)?;
+                Ok(shortcut)
+            });
+            if let Err(e) = &registered {
+                log::warn!("Failed to register shortcut {}: {e}", binding.accelerator);
+            }
+            Binding {
+                action: binding.action,
+                accelerator: binding.accelerator,
+                shortcut: registered.ok(),
+            }
+        })
+        .collect();
+    *app.state::<GlobalShortcuts>().0.lock().unwrap() = bindings;
+}
+
+#[command]
+pub fn get_global_shortcuts(shortcuts: State<'_, GlobalShortcuts>) -> Vec<ShortcutBinding> {
+    let bindings = shortcuts.0.lock().unwrap();
+    bindings
+        .iter()
+        .map(|binding| ShortcutBinding {
+            action: binding.action,
+            accelerator: binding.accelerator.clone(),
+            active: binding.shortcut.is_some(),
+        })
+        .collect()
+}
+
+/// Binds `accelerator` to `action`, replacing the previous binding of the
+/// action. Fails without changes if the keys are bound to another action or
+/// the OS refuses them.
+#[command]
+pub fn register_global_shortcut(
+    app: AppHandle,
+    action: ShortcutAction,
+    accelerator: String,
+) -> Result<()> {
+    let shortcut = parse(&accelerator)?;
+    let state = app.state::<GlobalShortcuts>();
+    let mut bindings = state.0.lock().unwrap();
+    // Inactive bindings are compared by their keys too, to keep them usable
+    let conflict = bindings.iter().any(|binding| {
+        binding.action != action && parse(&binding.accelerator).is_ok_and(|keys| keys == shortcut)
+    });
+    if conflict {
+        return Err(Error::ShortcutConflict(accelerator));
+    }
+
+    let global_shortcut = app.global_shortcut();
+    let previous = bindings.iter().position(|binding| binding.action == action);
+    let previous = previous.map(|i| bindings.remove(i));
+    if let Some(old) = previous.as_ref().and_then(|binding| binding.shortcut) {
+        global_shortcut.unregister(old)?;
+    }
+    if let Err(e) = global_shortcut.register(shortcut) {
+        // Restore the previous binding as it was
+        if let Some(mut binding) = previous {
+            binding.shortcut = binding
+                .shortcut
+                .filter(|&old| global_shortcut.register(old).is_ok());
+            bindings.push(binding);
+        }
+        return Err(e.into());
+    }
+    bindings.push(Binding {
+        action,
+        accelerator,
+        shortcut: Some(shortcut),
+    });
+    save(&app, &bindings)
+}
+
+#[command]
+pub fn unregister_global_shortcut(app: AppHandle, action: ShortcutAction) -> Result<()> {
+    let state = app.state::<GlobalShortcuts>();
+    let mut bindings = state.0.lock().unwrap();
+    let Some(i) = bindings.iter().position(|binding| binding.action == action) else {
+        return Ok(());
+    };
+    if let Some(shortcut) = bindings.remove(i).shortcut {
+        app.global_shortcut().unregister(shortcut)?;
+    }
+    save(&app, &bindings)
+}
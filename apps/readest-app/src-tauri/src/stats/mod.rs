@@ -0,0 +1,347 @@
+//! Reading statistics: the sessions the reader records while a book is
+//! open, kept in the library database, and the aggregates dashboards show.
+//! Totals are computed here so the raw log never goes through the webview.
+//! Days are local days, from the UTC offset the frontend passes as
+//! `-new Date().getTimezoneOffset()`. Sessions of the [`focus`](crate::focus)
+//! timer are kept beside the reading sessions, and counted apart from them.
+
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle, State};
+
+use crate::error::{Error, Result};
+use crate::library::db::LibraryDb;
+use crate::utils::{format_rfc3339, now_millis};
+
+pub const DAY_MS: i64 = 86_400_000;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewSession {
+    pub book_hash: String,
+    /// Milliseconds since the Unix epoch.
+    pub started_at: i64,
+    pub ended_at: i64,
+    /// Pages turned and words read during the session.
+    #[serde(default)]
+    pub pages: u32,
+    #[serde(default)]
+    pub words: u32,
+}
+
+/// A session of the focus timer, which may span several reading sessions
+/// or none.
+#[derive(Debug, Clone)]
+pub struct NewFocusSession {
+    pub book_hash: Option<String>,
+    pub started_at: i64,
+    pub ended_at: i64,
+    /// Break reminders given during it.
+    pub breaks: u32,
+    /// Whether it ran its planned length rather than being stopped.
+    pub completed: bool,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Period {
+    Day,
+    /// Weeks starting on Monday.
+    Week,
+}
+
+impl Period {
+    /// Local day number of the first day of the period of `started_at`,
+    /// given the offset in milliseconds as `?1`.
+    fn bucket(self) -> &'static str {
+        match self {
+            Period::Day => "((started_at + ?1) / 86400000)",
+            // Day 0 was a Thursday
+            Period::Week => {
+                "((started_at + ?1) / 86400000 - ((started_at + ?1) / 86400000 + 3) % 7)"
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeriodTotal {
+    /// First day of the period, as `YYYY-MM-DD`.
+    pub start: String,
+    pub duration_ms: i64,
+    pub pages: i64,
+    pub words: i64,
+    pub sessions: u32,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BookTotal {
+    pub book_hash: String,
+    /// From the catalog; missing for books no longer in the library.
+    pub title: Option<String>,
+    pub duration_ms: i64,
+    pub pages: i64,
+    pub words: i64,
+    pub sessions: u32,
+    pub first_read_at: i64,
+    pub last_read_at: i64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Summary {
+    pub total_ms: i64,
+    pub today_ms: i64,
+    pub sessions: u32,
+    pub books: u32,
+    /// Consecutive days with reading up to today, or up to yesterday while
+    /// nothing was read today yet.
+    pub current_streak: u32,
+    pub longest_streak: u32,
+    /// Time in focus sessions, and how many of them ran their length.
+    pub focus_ms: i64,
+    pub focus_completed: u32,
+}
+
+pub fn offset_ms(utc_offset_minutes: i32) -> i64 {
+    utc_offset_minutes as i64 * 60_000
+}
+
+pub fn local_day(millis: i64, offset_ms: i64) -> i64 {
+    (millis + offset_ms).div_euclid(DAY_MS)
+}
+
+/// Formats a local day number as `YYYY-MM-DD`.
+fn date(day: i64) -> String {
+    format_rfc3339(day * DAY_MS)[..10].to_string()
+}
+
+pub fn record_session(conn: &Connection, session: &NewSession) -> Result<i64> {
+    if session.ended_at < session.started_at {
+        return Err(Error::InvalidSession("ends before it starts".to_string()));
+    }
+    conn.execute(
+        "INSERT INTO reading_sessions (book_hash, started_at, ended_at, pages, words)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            session.book_hash,
+            session.started_at,
+            session.ended_at,
+            session.pages,
+            session.words,
+        ],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// What was read today, from the sessions started since local midnight.
+pub fn today_total(conn: &Connection, utc_offset_minutes: i32) -> Result<PeriodTotal> {
+    let offset = offset_ms(utc_offset_minutes);
+    let today = local_day(now_millis(), offset);
+    let totals = period_totals(
+        conn,
+        Period::Day,
+        today * DAY_MS - offset,
+        i64::MAX,
+        utc_offset_minutes,
+    )?;
+    Ok(totals.into_iter().next().unwrap_or_else(|| PeriodTotal {
+        start: date(today),
+        duration_ms: 0,
+        pages: 0,
+        words: 0,
+        sessions: 0,
+    }))
+}
+
+pub fn record_focus_session(conn: &Connection, session: &NewFocusSession) -> Result<i64> {
+    conn.execute(
+        "INSERT INTO focus_sessions (book_hash, started_at, ended_at, breaks, completed)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            session.book_hash,
+            session.started_at,
+            session.ended_at.max(session.started_at),
+            session.breaks,
+            session.completed,
+        ],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Totals per day or week of the sessions started between `from` and `to`.
+/// Periods without reading are left out.
+pub fn period_totals(
+    conn: &Connection,
+    period: Period,
+    from: i64,
+    to: i64,
+    utc_offset_minutes: i32,
+) -> Result<Vec<PeriodTotal>> {
+    let sql = format!(
+        "SELECT {} AS bucket, SUM(ended_at - started_at), SUM(pages), SUM(words), COUNT(*)
+         FROM reading_sessions
+         WHERE started_at >= ?2 AND started_at < ?3
+         GROUP BY bucket ORDER BY bucket",
+        period.bucket()
+    );
+    let mut stmt = conn.prepare(&sql)?;
+    let totals = stmt
+        .query_map(params![offset_ms(utc_offset_minutes), from, to], |row| {
+            Ok(PeriodTotal {
+                start: date(row.get(0)?),
+                duration_ms: row.get(1)?,
+                pages: row.get(2)?,
+                words: row.get(3)?,
+                sessions: row.get(4)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(totals)
+}
+
+/// Totals per book, most read first.
+pub fn book_totals(conn: &Connection, limit: Option<u32>) -> Result<Vec<BookTotal>> {
+    let mut stmt = conn.prepare(
+        "SELECT s.book_hash, b.title, SUM(s.ended_at - s.started_at) AS duration,
+            SUM(s.pages), SUM(s.words), COUNT(*), MIN(s.started_at), MAX(s.ended_at)
+         FROM reading_sessions s LEFT JOIN books b ON b.hash = s.book_hash
+         GROUP BY s.book_hash ORDER BY duration DESC, s.book_hash LIMIT ?1",
+    )?;
+    let totals = stmt
+        .query_map([limit.map_or(-1, i64::from)], |row| {
+            Ok(BookTotal {
+                book_hash: row.get(0)?,
+                title: row.get(1)?,
+                duration_ms: row.get(2)?,
+                pages: row.get(3)?,
+                words: row.get(4)?,
+                sessions: row.get(5)?,
+                first_read_at: row.get(6)?,
+                last_read_at: row.get(7)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(totals)
+}
+
+/// Current and longest runs of consecutive `days`, which are sorted.
+fn streaks(days: &[i64], today: i64) -> (u32, u32) {
+    let (mut longest, mut run) = (0, 0);
+    for (i, &day) in days.iter().enumerate() {
+        run = if i > 0 && days[i - 1] + 1 == day {
+            run + 1
+        } else {
+            1
+        };
+        longest = longest.max(run);
+    }
+    let current = match days.last() {
+        Some(&last) if last >= today - 1 => run,
+        _ => 0,
+    };
+    (current, longest)
+}
+
+pub fn summary(conn: &Connection, utc_offset_minutes: i32) -> Result<Summary> {
+    let offset = offset_ms(utc_offset_minutes);
+    let today = local_day(now_millis(), offset);
+    let (total_ms, sessions, books) = conn.query_row(
+        "SELECT COALESCE(SUM(ended_at - started_at), 0), COUNT(*), COUNT(DISTINCT book_hash)
+         FROM reading_sessions",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )?;
+    let today_ms = conn.query_row(
+        "SELECT COALESCE(SUM(ended_at - started_at), 0) FROM reading_sessions
+         WHERE started_at >= ?1",
+        [today * DAY_MS - offset],
+        |row| row.get(0),
+    )?;
+    let mut stmt = conn.prepare(
+        "SELECT DISTINCT (started_at + ?1) / 86400000 AS day FROM reading_sessions ORDER BY day",
+    )?;
+    let days = stmt
+        .query_map([offset], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<i64>>>()?;
+    let (current_streak, longest_streak) = streaks(&days, today);
+    let (focus_ms, focus_completed) = conn.query_row(
+        "SELECT COALESCE(SUM(ended_at - started_at), 0), COALESCE(SUM(completed), 0)
+         FROM focus_sessions",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    Ok(Summary {
+        total_ms,
+        today_ms,
+        sessions,
+        books,
+        current_streak,
+        longest_streak,
+        focus_ms,
+        focus_completed,
+    })
+}
+
+/// Records a finished reading session and returns its id, then sends the
+/// notice of the reading goal it may have brought closer.
+#[command]
+pub async fn stats_record_session(
+    app: AppHandle,
+    db: State<'_, LibraryDb>,
+    session: NewSession,
+) -> Result<i64> {
+    let id = record_session(&db.conn(), &session)?;
+    crate::goals::check(&app);
+    Ok(id)
+}
+
+#[command]
+pub async fn stats_get_period_totals(
+    db: State<'_, LibraryDb>,
+    period: Period,
+    from: i64,
+    to: i64,
+    utc_offset_minutes: i32,
+) -> Result<Vec<PeriodTotal>> {
+    period_totals(&db.conn(), period, from, to, utc_offset_minutes)
+}
+
+#[command]
+pub async fn stats_get_book_totals(
+    db: State<'_, LibraryDb>,
+    limit: Option<u32>,
+) -> Result<Vec<BookTotal>> {
+    book_totals(&db.conn(), limit)
+}
+
+#[command]
+pub async fn stats_get_summary(
+    db: State<'_, LibraryDb>,
+    utc_offset_minutes: i32,
+) -> Result<Summary> {
+    summary(&db.conn(), utc_offset_minutes)
+}
+
+/// Forgets the sessions of a book, or of all books when `book_hash` is not
+/// given, focus sessions included.
+#[command]
+pub async fn stats_clear_sessions(
+    db: State<'_, LibraryDb>,
+    book_hash: Option<String>,
+) -> Result<()> {
+    let conn = db.conn();
+    match book_hash {
+        Some(hash) => {
+            conn.execute("DELETE FROM reading_sessions WHERE book_hash = ?1", [&hash])?;
+            conn.execute("DELETE FROM focus_sessions WHERE book_hash = ?1", [&hash])?
+        }
+        None => {
+            conn.execute("DELETE FROM reading_sessions", [])?;
+            conn.execute("DELETE FROM focus_sessions", [])?
+        }
+    };
+    Ok(())
+}
@@ -0,0 +1,68 @@
+//! Small JSON documents persisted under the app config directory, that of
+//! the current profile, or the one all profiles share for the few kept per
+//! device.
+
+use std::path::{Path, PathBuf};
+
+use serde::{de::DeserializeOwned, Serialize};
+use tauri::AppHandle;
+
+use crate::error::Result;
+use crate::paths;
+
+fn store_path(app: &AppHandle, name: &str) -> Result<PathBuf> {
+    Ok(paths::config_dir(app)?.join(name))
+}
+
+fn read<T: DeserializeOwned + Default>(path: Result<PathBuf>, name: &str) -> T {
+    let path = match path {
+        Ok(path) => path,
+        Err(e) => {
+            log::warn!("Failed to resolve path for {name}: {e}");
+            return T::default();
+        }
+    };
+    match std::fs::read(&path) {
+        Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+            log::warn!("Failed to parse {path:?}: {e}");
+            T::default()
+        }),
+        Err(_) => T::default(),
+    }
+}
+
+fn write<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(value)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Loads `name` from the config directory, falling back to the default value
+/// when the file is missing or unreadable.
+pub fn load<T: DeserializeOwned + Default>(app: &AppHandle, name: &str) -> T {
+    read(store_path(app, name), name)
+}
+
+/// Writes `value` to `name` in the config directory, replacing the previous file atomically.
+pub fn save<T: Serialize>(app: &AppHandle, name: &str, value: &T) -> Result<()> {
+    write(&store_path(app, name)?, value)
+}
+
+/// Loads `name` from the config directory all profiles share.
+#[cfg(desktop)]
+pub fn load_shared<T: DeserializeOwned + Default>(app: &AppHandle, name: &str) -> T {
+    read(
+        paths::shared_config_dir(app).map(|dir| dir.join(name)),
+        name,
+    )
+}
+
+/// Writes `value` to `name` in the config directory all profiles share.
+#[cfg(desktop)]
+pub fn save_shared<T: Serialize>(app: &AppHandle, name: &str, value: &T) -> Result<()> {
+    write(&paths::shared_config_dir(app)?.join(name), value)
+}
@@ -0,0 +1,102 @@
+//! Client of the self-hostable sync endpoint, a minimal HTTP object store
+//! that sees only encrypted data. A server implements:
+//!
+//! - `GET /v1/objects/{name}`: the object with its `ETag`, or 404.
+//! - `PUT /v1/objects/{name}`: stores the body if `If-Match` names the
+//!   current `ETag`, or with `If-None-Match: *` if there is no object yet;
+//!   412 otherwise.
+//!
+//! Requests carry `Authorization: Bearer <token>` when a token is set.
+//! Object names are lowercase letters, digits, `-` and `.`.
+
+use reqwest::header::{CONTENT_TYPE, ETAG, IF_MATCH, IF_NONE_MATCH};
+use reqwest::{Method, RequestBuilder, StatusCode};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use super::protocol::Transport;
+use crate::error::{Error, Result};
+use crate::net;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EndpointConfig {
+    /// Base URL of the server, e.g. `https://sync.example.com/`.
+    pub url: String,
+    pub token: Option<String>,
+    /// Encrypts all data before it leaves the device, and is required.
+    /// Every device needs the same passphrase.
+    pub passphrase: Option<String>,
+}
+
+pub struct EndpointTransport {
+    token: Option<String>,
+    objects: Url,
+}
+
+impl EndpointTransport {
+    pub fn new(config: &EndpointConfig) -> Result<Self> {
+        let mut base = Url::parse(&config.url)?;
+        if !base.path().ends_with('/') {
+            base.set_path(&format!("{}/", base.path()));
+        }
+        Ok(Self {
+            token: config.token.clone(),
+            objects: base.join("v1/objects/")?,
+        })
+    }
+
+    fn request(&self, method: Method, name: &str) -> Result<RequestBuilder> {
+        let request = net::client().request(method, self.objects.join(name)?);
+        Ok(match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        })
+    }
+
+    /// Checks that the server is reachable and accepts the token.
+    pub async fn check(&self) -> Result<()> {
+        let status = self
+            .request(Method::HEAD, "sync-key.json")?
+            .send()
+            .await?
+            .status();
+        if !status.is_success() && status != StatusCode::NOT_FOUND {
+            return Err(Error::HttpStatus(status.as_u16()));
+        }
+        Ok(())
+    }
+}
+
+impl Transport for EndpointTransport {
+    async fn get(&self, name: &str) -> Result<Option<(Vec<u8>, Option<String>)>> {
+        let response = self.request(Method::GET, name)?.send().await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => return Ok(None),
+            status if !status.is_success() => return Err(Error::HttpStatus(status.as_u16())),
+            _ => {}
+        }
+        let etag = response
+            .headers()
+            .get(ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from);
+        Ok(Some((response.bytes().await?.to_vec(), etag)))
+    }
+
+    async fn put(&self, name: &str, body: Vec<u8>, version: Option<&str>) -> Result<()> {
+        let request = self
+            .request(Method::PUT, name)?
+            .header(CONTENT_TYPE, "application/json")
+            .body(body);
+        let request = match version {
+            Some(etag) => request.header(IF_MATCH, etag),
+            None => request.header(IF_NONE_MATCH, "*"),
+        };
+        match request.send().await?.status() {
+            StatusCode::PRECONDITION_FAILED => Err(Error::SyncConflict),
+            status if status.is_success() => Ok(()),
+            status => Err(Error::HttpStatus(status.as_u16())),
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! Client of the KOReader sync server (KOSync), which keeps the reading
+//! position of each document, by the same partial MD5 as the book hash, so
+//! progress made in KOReader on an e-reader shows up here and back.
+//!
+//! The server only knows positions: bookmarks and highlights stay local.
+//! EPUB positions are exchanged as KOReader XPointers and PDF ones as page
+//! numbers, with the percentage for everything else.
+
+use md5::{Digest, Md5};
+use reqwest::header::ACCEPT;
+use reqwest::{Method, RequestBuilder, StatusCode};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use super::BookSyncData;
+use crate::error::{Error, Result};
+use crate::net;
+
+const DEFAULT_URL: &str = "https://sync.koreader.rocks/";
+const MEDIA_TYPE: &str = "application/vnd.koreader.v1+json";
+/// Name of the device in the progress other devices see.
+const DEVICE: &str = "VL-Arch";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KoSyncConfig {
+    /// Base URL of the server, the public KOReader one when unset.
+    pub url: Option<String>,
+    pub username: String,
+    pub password: Option<String>,
+}
+
+/// A position as stored by the server.
+#[derive(Debug, Serialize, Deserialize)]
+struct Progress {
+    document: String,
+    progress: String,
+    percentage: f64,
+    device: String,
+    device_id: String,
+    /// Set by the server, in seconds.
+    #[serde(default, skip_serializing)]
+    timestamp: Option<i64>,
+}
+
+pub struct KoSyncProvider {
+    base: Url,
+    username: String,
+    /// The server is given the MD5 of the password, as KOReader does.
+    key: String,
+    device_id: String,
+}
+
+fn md5_hex(value: &str) -> String {
+    format!("{:x}", Md5::digest(value.as_bytes()))
+}
+
+impl KoSyncProvider {
+    pub fn new(config: &KoSyncConfig) -> Result<Self> {
+        let url = config
+            .url
+            .as_deref()
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .unwrap_or(DEFAULT_URL);
+        let mut base = Url::parse(url)?;
+        if !base.path().ends_with('/') {
+            base.set_path(&format!("{}/", base.path()));
+        }
+        Ok(Self {
+            base,
+            username: config.username.clone(),
+            key: md5_hex(config.password.as_deref().unwrap_or_default()),
+            // Stable across launches, so the server tells this device apart
+            device_id: md5_hex(&format!("{DEVICE}:{}", tauri_plugin_os::hostname())).to_uppercase(),
+        })
+    }
+
+    fn request(&self, method: Method, path: &str) -> Result<RequestBuilder> {
+        Ok(net::client()
+            .request(method, self.base.join(path)?)
+            .header(ACCEPT, MEDIA_TYPE)
+            .header("x-auth-user", &self.username)
+            .header("x-auth-key", &self.key))
+    }
+
+    /// Checks that the server accepts the username and password.
+    pub async fn check(&self) -> Result<()> {
+        let status = self
+            .request(Method::GET, "users/auth")?
+            .send()
+            .await?
+            .status();
+        if !status.is_success() {
+            return Err(Error::HttpStatus(status.as_u16()));
+        }
+        Ok(())
+    }
+
+    async fn pull(&self, document: &str) -> Result<Option<Progress>> {
+        let response = self
+            .request(Method::GET, &format!("syncs/progress/{document}"))?
+            .send()
+            .await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => return Ok(None),
+            status if !status.is_success() => return Err(Error::HttpStatus(status.as_u16())),
+            _ => {}
+        }
+        // Documents without progress come back as an empty object
+        let value: serde_json::Value = response.json().await?;
+        if value.get("progress").is_none() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_value(value)?))
+    }
+
+    async fn push(&self, progress: &Progress) -> Result<()> {
+        let status = self
+            .request(Method::PUT, "syncs/progress")?
+            .json(progress)
+            .send()
+            .await?
+            .status();
+        if !status.is_success() {
+            return Err(Error::HttpStatus(status.as_u16()));
+        }
+        Ok(())
+    }
+
+    /// The position of `local` as KOReader stores it, if it has one.
+    fn progress_of(&self, local: &BookSyncData) -> Option<Progress> {
+        let percentage = local
+            .progress
+            .filter(|(_, total)| *total > 0)
+            .map(|(current, total)| (current as f64 / total as f64).clamp(0.0, 1.0));
+        let progress = match (&local.xpointer, local.progress) {
+            (Some(xpointer), _) => xpointer.clone(),
+            (None, Some((current, _))) => current.to_string(),
+            (None, None) => return None,
+        };
+        Some(Progress {
+            document: local.book_hash.clone(),
+            progress,
+            percentage: percentage.unwrap_or_default(),
+            device: DEVICE.to_string(),
+            device_id: self.device_id.clone(),
+            timestamp: None,
+        })
+    }
+
+    /// Takes the server's position when it is newer than the local one and
+    /// uploads the local one otherwise. Notes are returned as they are.
+    pub async fn sync(&self, local: &BookSyncData) -> Result<BookSyncData> {
+        let remote = self.pull(&local.book_hash).await?;
+        let remote_updated_at = remote
+            .as_ref()
+            .and_then(|remote| remote.timestamp)
+            .map_or(0, |seconds| seconds * 1000);
+        match remote {
+            // The server dates what this device uploaded by the upload time,
+            // which is always newer, so only other devices' positions count
+            Some(remote)
+                if remote_updated_at > local.updated_at && remote.device_id != self.device_id =>
+            {
+                let mut merged = local.clone();
+                // PDF pages are the same everywhere, but reflowed pages are
+                // counted differently on each device, so the percentage is
+                // applied to the local page count
+                let total = local.progress.map_or(0, |(_, total)| total);
+                merged.progress = (total > 0).then(|| match remote.progress.parse::<i64>() {
+                    Ok(page) => (page.clamp(1, total), total),
+                    Err(_) => {
+                        let current = (remote.percentage.clamp(0.0, 1.0) * total as f64).round();
+                        ((current as i64).max(1), total)
+                    }
+                });
+                // Page numbers from PDFs are not XPointers
+                let is_xpointer = remote.progress.starts_with('/');
+                merged.xpointer = is_xpointer.then_some(remote.progress);
+                merged.location = None;
+                merged.updated_at = remote_updated_at;
+                Ok(merged)
+            }
+            remote => {
+                if let Some(progress) = self.progress_of(local) {
+                    let unchanged = remote.is_some_and(|remote| {
+                        remote.progress == progress.progress
+                            && (remote.percentage - progress.percentage).abs() < 1e-4
+                    });
+                    if !unchanged {
+                        self.push(&progress).await?;
+                    }
+                }
+                Ok(local.clone())
+            }
+        }
+    }
+}
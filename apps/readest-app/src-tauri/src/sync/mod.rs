@@ -0,0 +1,124 @@
+//! Synchronization of per-book reading state (progress, bookmarks and
+//! highlights) with a remote storage provider.
+
+use std::collections::HashMap;
+use std::future::Future;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::error::Result;
+
+pub mod endpoint;
+pub mod kosync;
+pub mod protocol;
+#[cfg(desktop)]
+pub mod sidecar;
+pub mod webdav;
+
+/// A bookmark, highlight or note. Only the fields needed for merging are
+/// typed; the rest of the frontend's `BookNote` is carried through untouched.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BookNote {
+    pub id: String,
+    pub updated_at: i64,
+    #[serde(default)]
+    pub deleted_at: Option<i64>,
+    #[serde(flatten)]
+    pub fields: Map<String, Value>,
+}
+
+/// Reading state of one book as exchanged with sync providers.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BookSyncData {
+    pub book_hash: String,
+    pub progress: Option<(i64, i64)>,
+    pub location: Option<String>,
+    pub xpointer: Option<String>,
+    #[serde(default)]
+    pub booknotes: Vec<BookNote>,
+    /// When the progress and location were last changed.
+    pub updated_at: i64,
+}
+
+/// Remote storage for [`BookSyncData`]. `version` is an opaque token
+/// (such as an ETag) that lets providers detect concurrent writes.
+pub trait SyncProvider {
+    fn pull(
+        &self,
+        book_hash: &str,
+    ) -> impl Future<Output = Result<Option<(BookSyncData, Option<String>)>>> + Send;
+
+    /// Uploads `data`, failing with [`crate::error::Error::SyncConflict`] if
+    /// the remote copy no longer matches `version`.
+    fn push(
+        &self,
+        data: &BookSyncData,
+        version: Option<&str>,
+    ) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// Merges two copies of a book's state. The reading position comes from the
+/// most recently updated copy; notes are merged one by one, keeping the newer
+/// version of each so deletions propagate as tombstones.
+pub fn merge(local: &BookSyncData, remote: &BookSyncData) -> BookSyncData {
+    let newer = if remote.updated_at > local.updated_at {
+        remote
+    } else {
+        local
+    };
+
+    let mut notes: HashMap<&str, &BookNote> = HashMap::new();
+    for note in remote.booknotes.iter().chain(&local.booknotes) {
+        let keep = notes.get(note.id.as_str()).map_or(true, |existing| {
+            note_timestamp(note) >= note_timestamp(existing)
+        });
+        if keep {
+            notes.insert(&note.id, note);
+        }
+    }
+    let mut booknotes = notes.into_values().cloned().collect::<Vec<_>>();
+    booknotes.sort_by(|a, b| a.id.cmp(&b.id));
+
+    BookSyncData {
+        book_hash: local.book_hash.clone(),
+        progress: newer.progress,
+        location: newer.location.clone(),
+        xpointer: newer.xpointer.clone(),
+        booknotes,
+        updated_at: newer.updated_at,
+    }
+}
+
+fn note_timestamp(note: &BookNote) -> i64 {
+    note.updated_at.max(note.deleted_at.unwrap_or_default())
+}
+
+/// Retries of a push that lost a race with another device.
+const MAX_CONFLICT_RETRIES: usize = 3;
+
+/// Pulls the remote state of `local.book_hash`, merges it with `local` and
+/// pushes the result back when it differs. Returns the merged state.
+pub async fn sync_book<P: SyncProvider>(
+    provider: &P,
+    local: &BookSyncData,
+) -> Result<BookSyncData> {
+    let mut attempt = 0;
+    loop {
+        let (merged, remote, version) = match provider.pull(&local.book_hash).await? {
+            Some((remote, version)) => (merge(local, &remote), Some(remote), version),
+            None => (local.clone(), None, None),
+        };
+        if remote.as_ref() == Some(&merged) {
+            return Ok(merged);
+        }
+        match provider.push(&merged, version.as_deref()).await {
+            Err(crate::error::Error::SyncConflict) if attempt < MAX_CONFLICT_RETRIES => {
+                attempt += 1;
+            }
+            result => return result.map(|_| merged),
+        }
+    }
+}
@@ -0,0 +1,410 @@
+//! End-to-end encrypted sync over any [`Transport`], so the server only
+//! ever stores ciphertext under opaque names.
+//!
+//! The passphrase never leaves the device. Argon2id stretches it, with a
+//! random salt kept next to the data in `sync-key.json`, into two keys: one
+//! encrypts each book's state with XChaCha20-Poly1305, the other names its
+//! object by an HMAC of the book hash. `sync-key.json` also holds a sealed
+//! check value, to tell a wrong passphrase from damaged data.
+
+use std::future::Future;
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use super::{BookSyncData, SyncProvider};
+use crate::error::{Error, Result};
+
+pub const VERSION: u32 = 1;
+
+const KEY_OBJECT: &str = "sync-key.json";
+const CHECK_VALUE: &[u8] = b"vl-arch-sync";
+const SALT_LEN: usize = 16;
+/// The most the server's `sync-key.json` may ask a device to spend on
+/// deriving the keys, so a tampered one cannot exhaust its memory or CPU.
+const MAX_MEMORY_KIB: u32 = 1024 * 1024;
+const MAX_ITERATIONS: u32 = 10;
+const MAX_PARALLELISM: u32 = 8;
+
+/// Storage of named JSON objects with optimistic concurrency. `version` is
+/// an opaque token, such as an ETag, of the copy last read.
+pub trait Transport {
+    fn get(
+        &self,
+        name: &str,
+    ) -> impl Future<Output = Result<Option<(Vec<u8>, Option<String>)>>> + Send;
+
+    /// Stores `body` as `name`, failing with [`Error::SyncConflict`] if the
+    /// object changed since `version` was read, or exists when `version` is
+    /// `None`.
+    fn put(
+        &self,
+        name: &str,
+        body: Vec<u8>,
+        version: Option<&str>,
+    ) -> impl Future<Output = Result<()>> + Send;
+}
+
+fn encryption_error(message: impl std::fmt::Display) -> Error {
+    Error::SyncEncryption(message.to_string())
+}
+
+/// A sealed payload as stored on the server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Envelope {
+    version: u32,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Contents of `sync-key.json`: how the keys are derived, but not the keys.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct KeyHeader {
+    version: u32,
+    salt: String,
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+    check: Envelope,
+}
+
+struct SyncKey {
+    cipher: XChaCha20Poly1305,
+    names: [u8; 32],
+}
+
+impl SyncKey {
+    /// Slow by design; call it off the async runtime.
+    fn derive(passphrase: &str, salt: &[u8], params: Params) -> Result<SyncKey> {
+        let mut output = [0u8; 64];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), salt, &mut output)
+            .map_err(encryption_error)?;
+        let cipher = XChaCha20Poly1305::new_from_slice(&output[..32]).map_err(encryption_error)?;
+        let mut names = [0u8; 32];
+        names.copy_from_slice(&output[32..]);
+        Ok(SyncKey { cipher, names })
+    }
+
+    fn object_name(&self, book_hash: &str) -> String {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.names)
+            .expect("HMAC takes keys of any size");
+        mac.update(book_hash.as_bytes());
+        format!("{:x}.json", mac.finalize().into_bytes())
+    }
+
+    /// Encrypts `plaintext`, bound to the object `name` so the server cannot
+    /// pass off one book's state as another's.
+    fn seal(&self, name: &str, plaintext: &[u8]) -> Result<Envelope> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: plaintext,
+            aad: name.as_bytes(),
+        };
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, payload)
+            .map_err(encryption_error)?;
+        Ok(Envelope {
+            version: VERSION,
+            nonce: BASE64.encode(nonce),
+            ciphertext: BASE64.encode(ciphertext),
+        })
+    }
+
+    fn open(&self, name: &str, envelope: &Envelope) -> Result<Vec<u8>> {
+        if envelope.version != VERSION {
+            return Err(encryption_error(format!(
+                "unsupported version {}",
+                envelope.version
+            )));
+        }
+        let nonce = BASE64.decode(&envelope.nonce).map_err(encryption_error)?;
+        if nonce.len() != 24 {
+            return Err(encryption_error("invalid nonce"));
+        }
+        let ciphertext = BASE64
+            .decode(&envelope.ciphertext)
+            .map_err(encryption_error)?;
+        let payload = Payload {
+            msg: &ciphertext,
+            aad: name.as_bytes(),
+        };
+        self.cipher
+            .decrypt(XNonce::from_slice(&nonce), payload)
+            .map_err(|_| encryption_error("data could not be decrypted"))
+    }
+}
+
+/// Argon2id costs, with output for both keys.
+fn key_params(memory_kib: u32, iterations: u32, parallelism: u32) -> Result<Params> {
+    if memory_kib > MAX_MEMORY_KIB || iterations > MAX_ITERATIONS || parallelism > MAX_PARALLELISM {
+        return Err(encryption_error(format!(
+            "key derivation costs {memory_kib} KiB, {iterations} iterations and \
+             {parallelism} lanes exceed the limits"
+        )));
+    }
+    Params::new(memory_kib, iterations, parallelism, Some(64)).map_err(encryption_error)
+}
+
+async fn derive(passphrase: &str, salt: Vec<u8>, params: Params) -> Result<SyncKey> {
+    let passphrase = passphrase.to_string();
+    tauri::async_runtime::spawn_blocking(move || SyncKey::derive(&passphrase, &salt, params))
+        .await?
+}
+
+/// A [`SyncProvider`] that encrypts book state before it reaches `T`.
+pub struct EncryptedProvider<T> {
+    transport: T,
+    key: SyncKey,
+}
+
+impl<T: Transport + Sync> EncryptedProvider<T> {
+    /// Derives the keys for `passphrase`, setting up encryption on the
+    /// server if this is the first device. Fails if another device set it
+    /// up with a different passphrase.
+    pub async fn new(transport: T, passphrase: &str) -> Result<Self> {
+        loop {
+            if let Some((body, _)) = transport.get(KEY_OBJECT).await? {
+                let header: KeyHeader = serde_json::from_slice(&body)?;
+                let salt = BASE64.decode(&header.salt).map_err(encryption_error)?;
+                let params = key_params(header.memory_kib, header.iterations, header.parallelism)?;
+                let key = derive(passphrase, salt, params).await?;
+                if key.open(KEY_OBJECT, &header.check).ok().as_deref() != Some(CHECK_VALUE) {
+                    return Err(encryption_error("wrong passphrase"));
+                }
+                return Ok(Self { transport, key });
+            }
+
+            let mut salt = vec![0u8; SALT_LEN];
+            OsRng.fill_bytes(&mut salt);
+            let params = key_params(
+                Params::DEFAULT_M_COST,
+                Params::DEFAULT_T_COST,
+                Params::DEFAULT_P_COST,
+            )?;
+            let key = derive(passphrase, salt.clone(), params.clone()).await?;
+            let header = KeyHeader {
+                version: VERSION,
+                salt: BASE64.encode(&salt),
+                memory_kib: params.m_cost(),
+                iterations: params.t_cost(),
+                parallelism: params.p_cost(),
+                check: key.seal(KEY_OBJECT, CHECK_VALUE)?,
+            };
+            match transport
+                .put(KEY_OBJECT, serde_json::to_vec(&header)?, None)
+                .await
+            {
+                // Another device set up encryption at the same time; use its key
+                Err(Error::SyncConflict) => continue,
+                result => return result.map(|_| Self { transport, key }),
+            }
+        }
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+}
+
+impl<T: Transport + Sync> SyncProvider for EncryptedProvider<T> {
+    async fn pull(&self, book_hash: &str) -> Result<Option<(BookSyncData, Option<String>)>> {
+        let name = self.key.object_name(book_hash);
+        let Some((body, version)) = self.transport.get(&name).await? else {
+            return Ok(None);
+        };
+        let envelope: Envelope = serde_json::from_slice(&body)?;
+        let data = serde_json::from_slice(&self.key.open(&name, &envelope)?)?;
+        Ok(Some((data, version)))
+    }
+
+    async fn push(&self, data: &BookSyncData, version: Option<&str>) -> Result<()> {
+        let name = self.key.object_name(&data.book_hash);
+        let envelope = self.key.seal(&name, &serde_json::to_vec(data)?)?;
+        self.transport
+            .put(&name, serde_json::to_vec(&envelope)?, version)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    fn key(passphrase: &str) -> SyncKey {
+        SyncKey::derive(
+            passphrase,
+            b"0123456789abcdef",
+            key_params(8, 1, 1).unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn sealed_data_opens_under_its_name_only() {
+        let key = key("secret");
+        let envelope = key.seal("a.json", b"state").unwrap();
+        assert_eq!(key.open("a.json", &envelope).unwrap(), b"state");
+        assert!(key.open("b.json", &envelope).is_err());
+        assert!(self::key("wrong").open("a.json", &envelope).is_err());
+        // A fresh nonce each time
+        assert_ne!(key.seal("a.json", b"state").unwrap().nonce, envelope.nonce);
+    }
+
+    #[test]
+    fn tampered_envelopes_do_not_open() {
+        let key = key("secret");
+        let envelope = key.seal("a.json", b"state").unwrap();
+
+        let mut ciphertext = BASE64.decode(&envelope.ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        let flipped = Envelope {
+            ciphertext: BASE64.encode(&ciphertext),
+            ..envelope.clone()
+        };
+        let truncated = Envelope {
+            ciphertext: BASE64.encode(&ciphertext[..8]),
+            ..envelope.clone()
+        };
+        let short_nonce = Envelope {
+            nonce: BASE64.encode([0u8; 12]),
+            ..envelope.clone()
+        };
+        let not_base64 = Envelope {
+            nonce: "*".into(),
+            ..envelope.clone()
+        };
+        let newer = Envelope {
+            version: VERSION + 1,
+            ..envelope.clone()
+        };
+        for envelope in [flipped, truncated, short_nonce, not_base64, newer] {
+            assert!(
+                matches!(key.open("a.json", &envelope), Err(Error::SyncEncryption(_))),
+                "{envelope:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn object_names_hide_the_book_hash() {
+        let (key, other) = (key("secret"), key("other"));
+        let name = key.object_name("hash");
+        assert_eq!(name, key.object_name("hash"));
+        assert_ne!(name, key.object_name("hash2"));
+        assert_ne!(name, other.object_name("hash"));
+        assert!(!name.contains("hash"));
+        assert!(name.ends_with(".json"));
+    }
+
+    #[test]
+    fn key_costs_are_bounded() {
+        assert!(key_params(MAX_MEMORY_KIB, MAX_ITERATIONS, MAX_PARALLELISM).is_ok());
+        assert!(key_params(MAX_MEMORY_KIB + 1, 1, 1).is_err());
+        assert!(key_params(64, MAX_ITERATIONS + 1, 1).is_err());
+        assert!(key_params(64, 1, MAX_PARALLELISM + 1).is_err());
+        // Less memory than Argon2 takes for its lanes
+        assert!(key_params(1, 1, 1).is_err());
+    }
+
+    /// Bodies and versions by name, the version counting the writes.
+    type Objects = HashMap<String, (Vec<u8>, u32)>;
+
+    #[derive(Clone, Default)]
+    struct MemoryTransport(Arc<Mutex<Objects>>);
+
+    impl Transport for MemoryTransport {
+        async fn get(&self, name: &str) -> Result<Option<(Vec<u8>, Option<String>)>> {
+            let objects = self.0.lock().unwrap();
+            Ok(objects
+                .get(name)
+                .map(|(body, version)| (body.clone(), Some(version.to_string()))))
+        }
+
+        async fn put(&self, name: &str, body: Vec<u8>, version: Option<&str>) -> Result<()> {
+            let mut objects = self.0.lock().unwrap();
+            let current = objects.get(name).map(|(_, version)| version.to_string());
+            if current.as_deref() != version {
+                return Err(Error::SyncConflict);
+            }
+            let next = objects.get(name).map_or(0, |(_, version)| version + 1);
+            objects.insert(name.to_string(), (body, next));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn devices_share_state_under_one_passphrase() {
+        tauri::async_runtime::block_on(async {
+            let transport = MemoryTransport::default();
+            let first = EncryptedProvider::new(transport.clone(), "secret")
+                .await
+                .unwrap();
+            let data = BookSyncData {
+                book_hash: "hash".into(),
+                progress: Some((3, 10)),
+                location: Some("epubcfi(/6/4)".into()),
+                xpointer: None,
+                booknotes: Vec::new(),
+                updated_at: 1,
+            };
+            first.push(&data, None).await.unwrap();
+            assert!(transport
+                .0
+                .lock()
+                .unwrap()
+                .values()
+                .all(|(body, _)| !String::from_utf8_lossy(body).contains("epubcfi")));
+
+            let second = EncryptedProvider::new(transport.clone(), "secret")
+                .await
+                .unwrap();
+            let (pulled, version) = second.pull("hash").await.unwrap().unwrap();
+            assert_eq!(pulled, data);
+            assert_eq!(version.as_deref(), Some("0"));
+            assert!(second.pull("other").await.unwrap().is_none());
+
+            assert!(matches!(
+                EncryptedProvider::new(transport.clone(), "wrong").await,
+                Err(Error::SyncEncryption(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn hostile_key_headers_are_refused() {
+        tauri::async_runtime::block_on(async {
+            let transport = MemoryTransport::default();
+            EncryptedProvider::new(transport.clone(), "secret")
+                .await
+                .unwrap();
+            {
+                let mut objects = transport.0.lock().unwrap();
+                let (body, _) = objects.get_mut(KEY_OBJECT).unwrap();
+                let mut header: KeyHeader = serde_json::from_slice(body).unwrap();
+                header.memory_kib = u32::MAX;
+                *body = serde_json::to_vec(&header).unwrap();
+            }
+            assert!(matches!(
+                EncryptedProvider::new(transport.clone(), "secret").await,
+                Err(Error::SyncEncryption(_))
+            ));
+
+            transport.0.lock().unwrap().get_mut(KEY_OBJECT).unwrap().0 = b"{}".to_vec();
+            assert!(EncryptedProvider::new(transport, "secret").await.is_err());
+        });
+    }
+}
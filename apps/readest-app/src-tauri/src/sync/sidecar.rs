@@ -0,0 +1,265 @@
+//! Sidecar files of the reading state of books: the bookmarks, highlights,
+//! notes and progress of each book written to a `.vlarch.json` file next
+//! to the book or in a mirror of the books dir elsewhere, as well as to
+//! its config. Sidecars outlive the app data, so a library imported again
+//! gets its annotations back, and any file-sync tool can carry them to
+//! other devices.
+//!
+//! A sidecar and a config are merged whenever the config is read or
+//! written, the same way as with sync providers, see [`super::merge`], so
+//! changes made on either side are kept. Configs encrypted at rest get no
+//! sidecar, which would hold their notes in plain text.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tauri::{command, AppHandle, Emitter, Manager};
+
+use super::{merge, BookNote, BookSyncData};
+use crate::error::{Error, Result};
+use crate::jobs;
+use crate::library::{self, db};
+use crate::secrets::storage;
+use crate::store;
+
+const SETTINGS_FILE: &str = "sidecars.json";
+const SIDECAR_SUFFIX: &str = ".vlarch.json";
+const SIDECAR_VERSION: u32 = 1;
+/// Emitted with the hashes of the books whose config took in changes from
+/// their sidecar, for the frontend to read them again.
+const MERGED_EVENT: &str = "sidecar-merged";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum SidecarMode {
+    #[default]
+    Off,
+    /// `Dune.epub.vlarch.json` next to `Dune.epub`, where the book was
+    /// imported from while it is still there, else next to the app's copy.
+    NextToBook,
+    /// In `dir`, laid out like the books dir.
+    Mirror { dir: PathBuf },
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SidecarSettings {
+    pub mode: SidecarMode,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Sidecar {
+    version: u32,
+    /// For people and tools finding the file.
+    title: String,
+    author: String,
+    #[serde(flatten)]
+    data: BookSyncData,
+}
+
+/// The fields of the frontend's per-book `config.json` kept in sidecars.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StoredState {
+    progress: Option<(i64, i64)>,
+    location: Option<String>,
+    xpointer: Option<String>,
+    #[serde(default)]
+    booknotes: Vec<BookNote>,
+    #[serde(default)]
+    updated_at: i64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SidecarSummary {
+    /// Books whose config took in changes from their sidecar.
+    pub merged: Vec<String>,
+    pub failed: usize,
+}
+
+fn sidecar_path(app: &AppHandle, mode: &SidecarMode, book: &db::Book) -> Option<PathBuf> {
+    let path = match mode {
+        SidecarMode::Off => return None,
+        SidecarMode::NextToBook => book
+            .file_path
+            .as_deref()
+            .map(PathBuf::from)
+            .filter(|path| path.is_file())
+            .or_else(|| library::book_path(app, book))?,
+        SidecarMode::Mirror { dir } => dir.join(library::local_book_filename(book)),
+    };
+    let name = path.file_name()?.to_string_lossy().into_owned();
+    Some(path.with_file_name(name + SIDECAR_SUFFIX))
+}
+
+fn read_sidecar(path: &Path) -> Result<Option<Sidecar>> {
+    match std::fs::read(path) {
+        Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_sidecar(path: &Path, book: &db::Book, data: &BookSyncData) -> Result<()> {
+    let sidecar = Sidecar {
+        version: SIDECAR_VERSION,
+        title: book.title.clone(),
+        author: book.author.clone(),
+        data: data.clone(),
+    };
+    // Without encryption, like any other file
+    storage::write_file(path, serde_json::to_vec_pretty(&sidecar)?, false)
+}
+
+/// The state of book `book_hash` in its config, its notes in the order
+/// [`merge`] leaves them.
+fn state_of(book_hash: &str, config: &Value) -> Result<BookSyncData> {
+    let state = serde_json::from_value::<StoredState>(config.clone())?;
+    let mut booknotes = state.booknotes;
+    booknotes.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(BookSyncData {
+        book_hash: book_hash.to_string(),
+        progress: state.progress,
+        location: state.location,
+        xpointer: state.xpointer,
+        booknotes,
+        updated_at: state.updated_at,
+    })
+}
+
+fn apply(config: &mut Value, data: &BookSyncData) -> Result<()> {
+    let Some(object) = config.as_object_mut() else {
+        return Err(Error::InvalidBook(format!(
+            "invalid config of {}",
+            data.book_hash
+        )));
+    };
+    object.insert("progress".into(), json!(data.progress));
+    object.insert("location".into(), json!(data.location));
+    object.insert("xpointer".into(), json!(data.xpointer));
+    object.insert("booknotes".into(), serde_json::to_value(&data.booknotes)?);
+    object.insert("updatedAt".into(), data.updated_at.into());
+    Ok(())
+}
+
+/// Merges the config of book `book_hash` with its sidecar, writing either
+/// that lacks something the other has. Returns whether the config changed.
+pub fn reconcile(app: &AppHandle, book_hash: &str) -> Result<bool> {
+    let settings: SidecarSettings = store::load(app, SETTINGS_FILE);
+    if settings.mode == SidecarMode::Off {
+        return Ok(false);
+    }
+    let db = app.state::<db::LibraryDb>();
+    let book = db::get_book(&db.conn(), book_hash)?;
+    let Some(book) = book else {
+        return Ok(false);
+    };
+    let Some(path) = sidecar_path(app, &settings.mode, &book) else {
+        return Ok(false);
+    };
+
+    let config_path = storage::config_path(app, book_hash)?;
+    let config = match std::fs::read(&config_path) {
+        Ok(data) if storage::is_encrypted(&data) => return Ok(false),
+        Ok(data) => match serde_json::from_slice::<Value>(&data)? {
+            // Sealed notes cannot be merged
+            config if crate::secrets::notes::is_protected(&config) => return Ok(false),
+            config => Some(config),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    let local = config
+        .as_ref()
+        .map(|config| state_of(book_hash, config))
+        .transpose()?;
+    let sidecar = read_sidecar(&path)?
+        .map(|sidecar| sidecar.data)
+        .filter(|data| data.book_hash == book_hash);
+    let merged = match (&local, &sidecar) {
+        (Some(local), Some(sidecar)) => merge(local, sidecar),
+        (Some(state), None) | (None, Some(state)) => state.clone(),
+        (None, None) => return Ok(false),
+    };
+
+    if sidecar.as_ref() != Some(&merged) {
+        write_sidecar(&path, &book, &merged)?;
+    }
+    if local.as_ref() == Some(&merged) {
+        return Ok(false);
+    }
+    let mut config = config.unwrap_or_else(|| json!({}));
+    apply(&mut config, &merged)?;
+    storage::write_file(&config_path, serde_json::to_vec(&config)?, false)?;
+    Ok(true)
+}
+
+/// Reconciles book `book_hash` after its config was written by the
+/// frontend, telling it when the config has to be read again.
+pub fn after_write(app: &AppHandle, book_hash: &str) {
+    match reconcile(app, book_hash) {
+        Ok(true) => {
+            let _ = app.emit(MERGED_EVENT, [book_hash]);
+        }
+        Ok(false) => {}
+        Err(e) => log::warn!("Failed to merge the sidecar of {book_hash}: {e}"),
+    }
+}
+
+#[command]
+pub async fn get_sidecar_settings(app: AppHandle) -> Result<SidecarSettings> {
+    Ok(store::load(&app, SETTINGS_FILE))
+}
+
+/// Sets where sidecars are kept; [`sync_sidecars`] writes them for the
+/// books there are already.
+#[command]
+pub async fn set_sidecar_settings(app: AppHandle, settings: SidecarSettings) -> Result<()> {
+    if let SidecarMode::Mirror { dir } = &settings.mode {
+        std::fs::create_dir_all(dir)?;
+    }
+    store::save(&app, SETTINGS_FILE, &settings)
+}
+
+/// Merges the config and the sidecar of every book in the library, as a
+/// `sidecars` job.
+#[command]
+pub async fn sync_sidecars(app: AppHandle) -> Result<SidecarSummary> {
+    let handle = app.clone();
+    jobs::run(&handle, "sidecars", |job| async move {
+        job.run_blocking({
+            let job = job.clone();
+            move || {
+                let books = {
+                    let db = app.state::<db::LibraryDb>();
+                    let page = db::query_books(&db.conn(), &db::BookQuery::default())?;
+                    page.books
+                };
+                let mut summary = SidecarSummary::default();
+                for (i, book) in books.iter().enumerate() {
+                    if job.is_cancelled() {
+                        return Err(Error::Cancelled);
+                    }
+                    job.progress("merging", i, books.len(), Some(book.title.clone()));
+                    match reconcile(&app, &book.hash) {
+                        Ok(true) => summary.merged.push(book.hash.clone()),
+                        Ok(false) => {}
+                        Err(e) => {
+                            log::warn!("Failed to merge the sidecar of {}: {e}", book.hash);
+                            summary.failed += 1;
+                        }
+                    }
+                }
+                if !summary.merged.is_empty() {
+                    let _ = app.emit(MERGED_EVENT, &summary.merged);
+                }
+                Ok(summary)
+            }
+        })
+        .await
+    })
+    .await
+}
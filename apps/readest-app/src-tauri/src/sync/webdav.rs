@@ -0,0 +1,177 @@
+//! WebDAV sync provider (Nextcloud, ownCloud, Synology, Apache mod_dav, ...).
+//! Each book is stored as `<root>/<hash>.json` on the server, or when a
+//! passphrase is set, encrypted under an opaque name by [`super::protocol`].
+
+use reqwest::header::{CONTENT_TYPE, ETAG, IF_MATCH, IF_NONE_MATCH};
+use reqwest::{Method, RequestBuilder, StatusCode};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use super::protocol::Transport;
+use super::{BookSyncData, SyncProvider};
+use crate::error::{Error, Result};
+use crate::library::check_hash;
+use crate::net;
+
+const DEFAULT_ROOT: &str = "VL-Arch/sync";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebDavConfig {
+    /// Base URL of the WebDAV share, e.g. `https://cloud.example.com/remote.php/dav/files/me/`.
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Folder under the share that holds the sync files.
+    pub root: Option<String>,
+    /// Encrypts the sync files when set. Every device needs the same
+    /// passphrase.
+    pub passphrase: Option<String>,
+}
+
+pub struct WebDavProvider {
+    config: WebDavConfig,
+    base: Url,
+    /// Folders of the root under the share.
+    root_segments: Vec<String>,
+    root: Url,
+}
+
+/// `base` with `segments` appended, each percent-encoded, so a name cannot
+/// lead out of its folder or carry a query.
+fn with_segments<'a>(base: &Url, segments: impl IntoIterator<Item = &'a str>) -> Result<Url> {
+    let mut url = base.clone();
+    url.path_segments_mut()
+        .map_err(|()| url::ParseError::RelativeUrlWithCannotBeABaseBase)?
+        .pop_if_empty()
+        .extend(segments);
+    Ok(url)
+}
+
+impl WebDavProvider {
+    pub fn new(config: WebDavConfig) -> Result<Self> {
+        let mut base = Url::parse(&config.url)?;
+        if !base.path().ends_with('/') {
+            base.set_path(&format!("{}/", base.path()));
+        }
+        let root_segments = config
+            .root
+            .as_deref()
+            .unwrap_or(DEFAULT_ROOT)
+            .split('/')
+            .filter(|segment| !matches!(*segment, "" | "." | ".."))
+            .map(String::from)
+            .collect::<Vec<_>>();
+        let root = with_segments(&base, root_segments.iter().map(String::as_str).chain([""]))?;
+        Ok(Self {
+            config,
+            base,
+            root_segments,
+            root,
+        })
+    }
+
+    fn request(&self, method: Method, url: Url) -> RequestBuilder {
+        let request = net::client().request(method, url);
+        match &self.config.username {
+            Some(username) => request.basic_auth(username, self.config.password.as_ref()),
+            None => request,
+        }
+    }
+
+    fn file_url(&self, name: &str) -> Result<Url> {
+        with_segments(&self.root, [name])
+    }
+
+    /// Creates the root collection and its parents, ignoring ones that already exist.
+    async fn ensure_root(&self) -> Result<()> {
+        let mkcol = Method::from_bytes(b"MKCOL").unwrap();
+        let segments = self.root_segments.iter().map(String::as_str);
+        for depth in 1..=self.root_segments.len() {
+            let url = with_segments(&self.base, segments.clone().take(depth).chain([""]))?;
+            let status = self.request(mkcol.clone(), url).send().await?.status();
+            // 405 Method Not Allowed means the collection already exists
+            if !status.is_success() && status != StatusCode::METHOD_NOT_ALLOWED {
+                return Err(Error::HttpStatus(status.as_u16()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the share is reachable with the configured credentials.
+    pub async fn check(&self) -> Result<()> {
+        let propfind = Method::from_bytes(b"PROPFIND").unwrap();
+        let response = self
+            .request(propfind, self.base.clone())
+            .header("Depth", "0")
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(Error::HttpStatus(response.status().as_u16()));
+        }
+        Ok(())
+    }
+}
+
+impl Transport for WebDavProvider {
+    async fn get(&self, name: &str) -> Result<Option<(Vec<u8>, Option<String>)>> {
+        let response = self
+            .request(Method::GET, self.file_url(name)?)
+            .send()
+            .await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => return Ok(None),
+            status if !status.is_success() => return Err(Error::HttpStatus(status.as_u16())),
+            _ => {}
+        }
+        let etag = response
+            .headers()
+            .get(ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from);
+        Ok(Some((response.bytes().await?.to_vec(), etag)))
+    }
+
+    async fn put(&self, name: &str, body: Vec<u8>, version: Option<&str>) -> Result<()> {
+        let put = |body: Vec<u8>| {
+            let request = self
+                .request(Method::PUT, self.file_url(name)?)
+                .header(CONTENT_TYPE, "application/json")
+                .body(body);
+            // Only overwrite the copy that was merged, or create a new file
+            Ok::<_, Error>(match version {
+                Some(etag) => request.header(IF_MATCH, etag),
+                None => request.header(IF_NONE_MATCH, "*"),
+            })
+        };
+
+        let mut response = put(body.clone())?.send().await?;
+        if response.status() == StatusCode::CONFLICT || response.status() == StatusCode::NOT_FOUND {
+            // The parent collection is missing
+            self.ensure_root().await?;
+            response = put(body)?.send().await?;
+        }
+        match response.status() {
+            StatusCode::PRECONDITION_FAILED => Err(Error::SyncConflict),
+            status if status.is_success() => Ok(()),
+            status => Err(Error::HttpStatus(status.as_u16())),
+        }
+    }
+}
+
+/// Unencrypted sync, with each book readable on the server.
+impl SyncProvider for WebDavProvider {
+    async fn pull(&self, book_hash: &str) -> Result<Option<(BookSyncData, Option<String>)>> {
+        check_hash(book_hash)?;
+        match self.get(&format!("{book_hash}.json")).await? {
+            Some((body, etag)) => Ok(Some((serde_json::from_slice(&body)?, etag))),
+            None => Ok(None),
+        }
+    }
+
+    async fn push(&self, data: &BookSyncData, version: Option<&str>) -> Result<()> {
+        check_hash(&data.book_hash)?;
+        let name = format!("{}.json", data.book_hash);
+        self.put(&name, serde_json::to_vec(data)?, version).await
+    }
+}
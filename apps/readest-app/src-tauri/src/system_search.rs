@@ -0,0 +1,198 @@
+//! Library books in the system-wide search, Spotlight on macOS and Windows
+//! Search on Windows, so a book can be found by title, author or tag and
+//! opened straight from the results through its `vlarch://open` link. The
+//! index follows the library as books are added, edited and deleted, until
+//! the user turns publishing off, which removes everything published.
+
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle, Manager};
+
+use crate::deep_link;
+use crate::error::Result;
+use crate::library::{self, access, db};
+use crate::store;
+
+#[cfg(target_os = "macos")]
+use crate::macos::spotlight as platform;
+#[cfg(windows)]
+use crate::windows::search as platform;
+
+const SETTINGS_FILE: &str = "system-search.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct Settings {
+    enabled: bool,
+    /// Whether the whole library was published since publishing was turned
+    /// on; later changes are published as they happen.
+    published: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            published: false,
+        }
+    }
+}
+
+/// What the system search knows about a book.
+pub struct Entry {
+    pub hash: String,
+    pub title: String,
+    pub author: String,
+    pub tags: Vec<String>,
+    /// Lower case extension of the book file, such as `epub`.
+    pub format: String,
+    pub cover: Option<PathBuf>,
+}
+
+/// The link a search result opens.
+pub fn link(hash: &str) -> String {
+    format!("{}://open?book={hash}", deep_link::SCHEME)
+}
+
+fn entry(app: &AppHandle, book: &db::Book) -> Entry {
+    Entry {
+        hash: book.hash.clone(),
+        title: book.title.clone(),
+        author: book.author.clone(),
+        tags: book.tags.clone(),
+        format: book.format.to_ascii_lowercase(),
+        cover: library::cover_path(app, &book.hash),
+    }
+}
+
+/// Publishes `books`, removing those deleted or in a locked collection:
+/// the index outlives unlocking them.
+fn publish(app: &AppHandle, books: &[db::Book]) -> Result<()> {
+    let hidden = {
+        let db = app.state::<db::LibraryDb>();
+        let conn = db.conn();
+        access::locked(app, &conn)?.hidden_books(&conn)?
+    };
+    let (live, deleted): (Vec<_>, Vec<_>) = books.iter().partition(|book| {
+        book.deleted_at.is_none()
+            && hidden
+                .as_ref()
+                .is_some_and(|hidden| !hidden.contains(&book.hash))
+    });
+    let entries = live
+        .into_iter()
+        .map(|book| entry(app, book))
+        .collect::<Vec<_>>();
+    if !entries.is_empty() {
+        platform::publish(app, &entries)?;
+    }
+    if !deleted.is_empty() {
+        let hashes = deleted
+            .into_iter()
+            .map(|book| book.hash.clone())
+            .collect::<Vec<_>>();
+        platform::remove(app, &hashes)?;
+    }
+    Ok(())
+}
+
+/// Publishes every book in the library, replacing what was published.
+fn rebuild(app: &AppHandle) -> Result<()> {
+    let books = {
+        let db = app.state::<db::LibraryDb>();
+        let conn = db.conn();
+        db::query_books(&conn, &db::BookQuery::default())?.books
+    };
+    platform::clear(app)?;
+    publish(app, &books)
+}
+
+fn in_background(
+    app: &AppHandle,
+    what: &'static str,
+    f: impl FnOnce(&AppHandle) -> Result<()> + Send + 'static,
+) {
+    let app = app.clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        if let Err(e) = f(&app) {
+            log::warn!("Failed to {what} system search: {e}");
+        }
+    });
+}
+
+/// Starts handling picked results, and publishes the library the first
+/// time, in the background.
+pub fn init(app: &AppHandle) {
+    // Windows starts the app with the link, like other `vlarch://` links
+    #[cfg(target_os = "macos")]
+    platform::init(app);
+    let settings: Settings = store::load(app, SETTINGS_FILE);
+    if settings.enabled && !settings.published {
+        in_background(app, "fill", |app| {
+            rebuild(app)?;
+            store::save(
+                app,
+                SETTINGS_FILE,
+                &Settings {
+                    enabled: true,
+                    published: true,
+                },
+            )
+        });
+    }
+}
+
+/// Publishes the changes to `books` in the background, removing the ones
+/// marked deleted.
+pub fn update(app: &AppHandle, books: &[db::Book]) {
+    if !store::load::<Settings>(app, SETTINGS_FILE).enabled {
+        return;
+    }
+    let books = books.to_vec();
+    in_background(app, "update", move |app| publish(app, &books));
+}
+
+/// Publishes the library again in the background, once what is locked
+/// changed.
+pub fn refresh(app: &AppHandle) {
+    if store::load::<Settings>(app, SETTINGS_FILE).enabled {
+        in_background(app, "refresh", rebuild);
+    }
+}
+
+/// Removes books `hashes` in the background.
+pub fn remove(app: &AppHandle, hashes: &[String]) {
+    if !store::load::<Settings>(app, SETTINGS_FILE).enabled {
+        return;
+    }
+    let hashes = hashes.to_vec();
+    in_background(app, "update", move |app| platform::remove(app, &hashes));
+}
+
+#[command]
+pub async fn get_system_search_enabled(app: AppHandle) -> bool {
+    store::load::<Settings>(&app, SETTINGS_FILE).enabled
+}
+
+/// Turns publishing on, publishing the whole library, or off, removing it
+/// from the system search.
+#[command]
+pub async fn set_system_search_enabled(app: AppHandle, enabled: bool) -> Result<()> {
+    tauri::async_runtime::spawn_blocking(move || {
+        if enabled {
+            rebuild(&app)?;
+        } else {
+            platform::clear(&app)?;
+        }
+        store::save(
+            &app,
+            SETTINGS_FILE,
+            &Settings {
+                enabled,
+                published: enabled,
+            },
+        )
+    })
+    .await?
+}
@@ -0,0 +1,199 @@
+//! Periodic background tasks: sync, news feeds, rescans of the watched
+//! folders, emptying the trash, backups and reading goal reminders. The
+//! [`scheduler`] runs them, and the settings UI shows when each last ran
+//! and how it went, changes their schedules and starts them by hand.
+//!
+//! Sync is carried out by the frontend, which holds the book data: the
+//! task only sends it a `sync-due` event, and the frontend reports the
+//! outcome with [`report_scheduled_task`].
+
+use tauri::{command, AppHandle, Emitter, Manager, State};
+
+use crate::error::Result;
+use crate::paths;
+use crate::utils::{format_rfc3339, now_millis};
+
+mod scheduler;
+
+use scheduler::{Job, JobFuture, Scheduler, TaskConfig, TaskStatus};
+
+const SYNC_EVENT: &str = "sync-due";
+#[cfg(desktop)]
+const SCAN_EVENT: &str = "library-scanned";
+/// Where scheduled backups are written under the app data dir.
+const BACKUPS_DIR: &str = "backups";
+/// Scheduled backups kept, the oldest are removed.
+const MAX_BACKUPS: usize = 5;
+
+fn jobs() -> Vec<Job> {
+    vec![
+        Job {
+            id: "sync",
+            interval_minutes: 30,
+            enabled: true,
+            run: run_sync,
+        },
+        Job {
+            id: "feeds",
+            interval_minutes: 24 * 60,
+            enabled: true,
+            run: run_feeds,
+        },
+        #[cfg(desktop)]
+        Job {
+            id: "rescan",
+            interval_minutes: 6 * 60,
+            enabled: true,
+            run: run_rescan,
+        },
+        Job {
+            id: "metadata",
+            interval_minutes: 60,
+            enabled: true,
+            run: run_metadata,
+        },
+        Job {
+            id: "trash",
+            interval_minutes: 24 * 60,
+            enabled: true,
+            run: run_trash,
+        },
+        Job {
+            id: "backup",
+            interval_minutes: 7 * 24 * 60,
+            enabled: false,
+            run: run_backup,
+        },
+        Job {
+            id: "goals",
+            interval_minutes: 5,
+            enabled: true,
+            run: run_goals,
+        },
+    ]
+}
+
+fn run_sync(app: AppHandle) -> JobFuture {
+    Box::pin(async move {
+        if crate::commands::sync::is_configured(&app) {
+            app.emit(SYNC_EVENT, ())?;
+        }
+        Ok(())
+    })
+}
+
+fn run_feeds(app: AppHandle) -> JobFuture {
+    Box::pin(crate::feeds::update_on_schedule(app))
+}
+
+/// Looks up the books queued while offline.
+fn run_metadata(app: AppHandle) -> JobFuture {
+    Box::pin(crate::metadata::fetch::process_queue(app))
+}
+
+/// Lists the books in the watched folders for the frontend to reconcile,
+/// catching changes the watcher missed.
+#[cfg(desktop)]
+fn run_rescan(app: AppHandle) -> JobFuture {
+    use crate::library::watcher::{scan_folders, LibraryWatcher};
+
+    Box::pin(async move {
+        let Some(folders) = app
+            .try_state::<LibraryWatcher>()
+            .map(|watcher| watcher.active_folders())
+        else {
+            return Ok(());
+        };
+        if folders.is_empty() {
+            return Ok(());
+        }
+        let files = tauri::async_runtime::spawn_blocking(move || scan_folders(&folders)).await?;
+        app.emit(SCAN_EVENT, files)?;
+        Ok(())
+    })
+}
+
+/// Removes the books that have been in the trash for too long.
+fn run_trash(app: AppHandle) -> JobFuture {
+    Box::pin(async move {
+        tauri::async_runtime::spawn_blocking(move || {
+            crate::library::trash::empty_expired(&app).map(|_| ())
+        })
+        .await?
+    })
+}
+
+/// Backs the library up without the book files, which are usually kept
+/// elsewhere too.
+fn run_backup(app: AppHandle) -> JobFuture {
+    Box::pin(async move {
+        tauri::async_runtime::spawn_blocking(move || {
+            let dir = paths::data_dir(&app)?.join(BACKUPS_DIR);
+            std::fs::create_dir_all(&dir)?;
+            // Names sort by date
+            let date = format_rfc3339(now_millis())[..19].replace(':', "-");
+            let dest = dir.join(format!("library-{date}.zip"));
+            crate::library::backup::backup(&app, &dest, false)?;
+            let mut backups = std::fs::read_dir(&dir)?
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().is_some_and(|ext| ext == "zip"))
+                .collect::<Vec<_>>();
+            backups.sort();
+            for old in &backups[..backups.len().saturating_sub(MAX_BACKUPS)] {
+                std::fs::remove_file(old)?;
+            }
+            Ok(())
+        })
+        .await?
+    })
+}
+
+/// Sends the reminder of the reading goal once it is time.
+fn run_goals(app: AppHandle) -> JobFuture {
+    Box::pin(async move {
+        crate::goals::check(&app);
+        Ok(())
+    })
+}
+
+pub fn init(app: &AppHandle) {
+    scheduler::init(app, jobs());
+}
+
+#[command]
+pub fn get_scheduled_tasks(scheduler: State<'_, Scheduler>) -> Vec<TaskStatus> {
+    scheduler.statuses()
+}
+
+#[command]
+pub fn set_scheduled_task(
+    app: AppHandle,
+    scheduler: State<'_, Scheduler>,
+    id: String,
+    config: TaskConfig,
+) -> Result<TaskStatus> {
+    scheduler.configure(&app, &id, config)
+}
+
+/// Runs a task now, returning `false` if it is already running.
+#[command]
+pub fn run_scheduled_task(
+    app: AppHandle,
+    scheduler: State<'_, Scheduler>,
+    id: String,
+) -> Result<bool> {
+    scheduler.run_now(&app, &id)
+}
+
+/// Records the outcome of a sync the frontend ran after `sync-due`, with
+/// the error if it failed, so it is retried and shown like other tasks.
+#[command]
+pub fn report_scheduled_task(
+    app: AppHandle,
+    scheduler: State<'_, Scheduler>,
+    id: String,
+    error: Option<String>,
+) -> Result<()> {
+    scheduler.report(&app, &id, error)
+}
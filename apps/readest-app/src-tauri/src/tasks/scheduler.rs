@@ -0,0 +1,275 @@
+//! A small scheduler for periodic background jobs. Each job runs once per
+//! interval, give or take a little so jobs started together drift apart,
+//! and after a failure it is retried sooner, waiting twice as long after
+//! each failure in a row up to its interval. When each job last ran and
+//! how it went is kept across restarts, so the schedule carries on.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter, Manager};
+
+use crate::error::{Error, Result};
+use crate::store;
+use crate::utils::now_millis;
+
+const CONFIG_FILE: &str = "scheduled-tasks.json";
+const STATE_FILE: &str = "scheduled-tasks-state.json";
+const EVENT: &str = "scheduled-task";
+/// How often due jobs are looked for.
+const TICK: Duration = Duration::from_secs(30);
+/// No job runs sooner after startup, so they do not slow it down.
+const STARTUP_DELAY_MINUTES: i64 = 2;
+/// Fraction of the interval a run may come early or late.
+const JITTER: f64 = 0.1;
+/// Wait before retrying a job that failed once.
+const RETRY_MINUTES: i64 = 1;
+
+pub type JobFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+/// A periodic job and its default schedule.
+#[derive(Clone, Copy)]
+pub struct Job {
+    pub id: &'static str,
+    pub interval_minutes: u32,
+    pub enabled: bool,
+    pub run: fn(AppHandle) -> JobFuture,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TaskConfig {
+    pub enabled: bool,
+    pub interval_minutes: u32,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct TaskState {
+    last_run: Option<i64>,
+    last_success: Option<i64>,
+    last_error: Option<String>,
+    /// Failures in a row.
+    failures: u32,
+    next_run: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TaskStatus {
+    pub id: &'static str,
+    pub enabled: bool,
+    pub interval_minutes: u32,
+    pub running: bool,
+    /// Milliseconds since the Unix epoch, like the other times.
+    pub last_run: Option<i64>,
+    pub last_success: Option<i64>,
+    /// Why the last run failed, or `None` if it succeeded.
+    pub last_error: Option<String>,
+    pub failures: u32,
+    pub next_run: Option<i64>,
+}
+
+struct Inner {
+    configs: BTreeMap<String, TaskConfig>,
+    states: BTreeMap<String, TaskState>,
+    running: Vec<&'static str>,
+}
+
+impl Inner {
+    fn config(&self, job: &Job) -> TaskConfig {
+        self.configs.get(job.id).copied().unwrap_or(TaskConfig {
+            enabled: job.enabled,
+            interval_minutes: job.interval_minutes,
+        })
+    }
+
+    fn status(&self, job: &Job) -> TaskStatus {
+        let config = self.config(job);
+        let state = self.states.get(job.id).cloned().unwrap_or_default();
+        TaskStatus {
+            id: job.id,
+            enabled: config.enabled,
+            interval_minutes: config.interval_minutes,
+            running: self.running.contains(&job.id),
+            last_run: state.last_run,
+            last_success: state.last_success,
+            last_error: state.last_error,
+            failures: state.failures,
+            next_run: state.next_run,
+        }
+    }
+}
+
+pub struct Scheduler {
+    jobs: Vec<Job>,
+    inner: Mutex<Inner>,
+}
+
+fn minutes(n: i64) -> i64 {
+    n * 60_000
+}
+
+/// One interval after `from`, give or take the jitter.
+fn after_interval(from: i64, interval_minutes: u32) -> i64 {
+    let interval = minutes(i64::from(interval_minutes.max(1))) as f64;
+    let jitter = rand::thread_rng().gen_range(-JITTER..=JITTER);
+    from + (interval * (1.0 + jitter)) as i64
+}
+
+/// When to retry after `failures` failures in a row.
+fn after_failure(from: i64, failures: u32, interval_minutes: u32) -> i64 {
+    let wait = RETRY_MINUTES << failures.saturating_sub(1).min(20);
+    from + minutes(wait.min(i64::from(interval_minutes.max(1))))
+}
+
+impl Scheduler {
+    fn new(app: &AppHandle, jobs: Vec<Job>) -> Self {
+        let configs = store::load(app, CONFIG_FILE);
+        let mut states: BTreeMap<String, TaskState> = store::load(app, STATE_FILE);
+        let start = now_millis() + minutes(STARTUP_DELAY_MINUTES);
+        for job in &jobs {
+            let state = states.entry(job.id.to_string()).or_default();
+            state.next_run = Some(state.next_run.map_or(start, |next| next.max(start)));
+        }
+        Self {
+            jobs,
+            inner: Mutex::new(Inner {
+                configs,
+                states,
+                running: Vec::new(),
+            }),
+        }
+    }
+
+    fn job(&self, id: &str) -> Result<Job> {
+        self.jobs
+            .iter()
+            .find(|job| job.id == id)
+            .copied()
+            .ok_or_else(|| Error::UnknownTask(id.to_string()))
+    }
+
+    pub fn statuses(&self) -> Vec<TaskStatus> {
+        let inner = self.inner.lock().unwrap();
+        self.jobs.iter().map(|job| inner.status(job)).collect()
+    }
+
+    /// Changes the schedule of `id`. A job that last succeeded is due one
+    /// new interval after that run.
+    pub fn configure(&self, app: &AppHandle, id: &str, config: TaskConfig) -> Result<TaskStatus> {
+        let job = self.job(id)?;
+        let mut inner = self.inner.lock().unwrap();
+        inner.configs.insert(job.id.to_string(), config);
+        let state = inner.states.entry(job.id.to_string()).or_default();
+        if let (Some(last), 0) = (state.last_run, state.failures) {
+            state.next_run = Some(after_interval(last, config.interval_minutes));
+        }
+        store::save(app, CONFIG_FILE, &inner.configs)?;
+        store::save(app, STATE_FILE, &inner.states)?;
+        Ok(inner.status(&job))
+    }
+
+    /// Runs `id` now, whatever its schedule. Returns `false` if it is
+    /// already running.
+    pub fn run_now(&self, app: &AppHandle, id: &str) -> Result<bool> {
+        let job = self.job(id)?;
+        Ok(self.start(app, job))
+    }
+
+    /// Records the outcome of a run of `id` carried out elsewhere, as the
+    /// frontend does for jobs that only tell it they are due.
+    pub fn report(&self, app: &AppHandle, id: &str, error: Option<String>) -> Result<()> {
+        let job = self.job(id)?;
+        self.finish(app, &job, error);
+        Ok(())
+    }
+
+    /// Starts the enabled jobs that are due and not running yet.
+    fn run_due(&self, app: &AppHandle) {
+        let now = now_millis();
+        let due = {
+            let inner = self.inner.lock().unwrap();
+            self.jobs
+                .iter()
+                .filter(|job| inner.config(job).enabled && !inner.running.contains(&job.id))
+                .filter(|job| {
+                    inner
+                        .states
+                        .get(job.id)
+                        .and_then(|state| state.next_run)
+                        .map_or(true, |next| next <= now)
+                })
+                .copied()
+                .collect::<Vec<_>>()
+        };
+        for job in due {
+            self.start(app, job);
+        }
+    }
+
+    fn start(&self, app: &AppHandle, job: Job) -> bool {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.running.contains(&job.id) {
+                return false;
+            }
+            inner.running.push(job.id);
+            let _ = app.emit(EVENT, inner.status(&job));
+        }
+        let app = app.clone();
+        tauri::async_runtime::spawn(async move {
+            let error = (job.run)(app.clone()).await.err().map(|e| e.to_string());
+            app.state::<Scheduler>().finish(&app, &job, error);
+        });
+        true
+    }
+
+    /// Records how a run of `job` went and when it runs next.
+    fn finish(&self, app: &AppHandle, job: &Job, error: Option<String>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.running.retain(|id| *id != job.id);
+        let interval = inner.config(job).interval_minutes;
+        let now = now_millis();
+        let state = inner.states.entry(job.id.to_string()).or_default();
+        state.last_run = Some(now);
+        match error {
+            None => {
+                state.last_success = Some(now);
+                state.last_error = None;
+                state.failures = 0;
+                state.next_run = Some(after_interval(now, interval));
+            }
+            Some(e) => {
+                log::warn!("Scheduled task {} failed: {e}", job.id);
+                state.failures += 1;
+                state.next_run = Some(after_failure(now, state.failures, interval));
+                state.last_error = Some(e);
+            }
+        }
+        if let Err(e) = store::save(app, STATE_FILE, &inner.states) {
+            log::warn!("Failed to save the state of scheduled tasks: {e}");
+        }
+        let _ = app.emit(EVENT, inner.status(job));
+    }
+}
+
+/// Starts running `jobs` on their schedules.
+pub fn init(app: &AppHandle, jobs: Vec<Job>) {
+    app.manage(Scheduler::new(app, jobs));
+    let app = app.clone();
+    let spawned = std::thread::Builder::new()
+        .name("scheduler".to_string())
+        .spawn(move || loop {
+            std::thread::sleep(TICK);
+            app.state::<Scheduler>().run_due(&app);
+        });
+    if let Err(e) = spawned {
+        log::warn!("Failed to start the task scheduler: {e}");
+    }
+}
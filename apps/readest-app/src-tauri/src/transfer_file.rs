@@ -96,9 +96,9 @@ impl Serialize for Error {
 #[derive(Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ProgressPayload {
-    progress: u64,
-    total: u64,
-    transfer_speed: u64,
+    pub progress: u64,
+    pub total: u64,
+    pub transfer_speed: u64,
 }
 
 #[command]
@@ -115,7 +115,7 @@ pub async fn download_file(
 
     const PART_SIZE: u64 = 1024 * 1024;
 
-    let client = reqwest::Client::new();
+    let client = crate::net::client();
 
     // Check if server supports range requests
     let range_resp = client.get(url).header("Range", "bytes=0-0").send().await?;
@@ -247,7 +247,7 @@ pub async fn upload_file(
     let file = File::open(file_path).await?;
     let file_len = file.metadata().await.unwrap().len();
 
-    let client = reqwest::Client::new();
+    let client = crate::net::client();
     let mut request = match method.to_uppercase().as_str() {
         "POST" => client.post(url),
         "PUT" => client.put(url),
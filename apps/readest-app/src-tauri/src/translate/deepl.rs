@@ -0,0 +1,89 @@
+//! The DeepL API. Keys of free accounts end in `:fx` and are sent to the
+//! free endpoint.
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::{fetch_json, translation_error, Translation, TranslationRequest, Translator};
+use crate::error::Result;
+use crate::net;
+
+const PRO_URL: &str = "https://api.deepl.com/v2/translate";
+const FREE_URL: &str = "https://api-free.deepl.com/v2/translate";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeepLConfig {
+    pub api_key: Option<String>,
+}
+
+pub struct DeepLTranslator {
+    api_key: String,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    translations: Vec<ResponseTranslation>,
+}
+
+#[derive(Deserialize)]
+struct ResponseTranslation {
+    detected_source_language: Option<String>,
+    text: String,
+}
+
+/// The DeepL code of `lang`: uppercase, and for targets with the variant
+/// DeepL requires for English and Portuguese.
+fn language(lang: &str, target: bool) -> String {
+    let lang = lang.to_uppercase().replace('_', "-");
+    let primary = lang.split('-').next().unwrap_or_default().to_string();
+    match primary.as_str() {
+        _ if !target => primary,
+        "EN" if lang == "EN" => "EN-US".to_string(),
+        "PT" if lang == "PT" => "PT-PT".to_string(),
+        "EN" | "PT" | "ZH" => lang,
+        _ => primary,
+    }
+}
+
+impl DeepLTranslator {
+    pub fn new(config: DeepLConfig) -> Result<Self> {
+        let api_key = config
+            .api_key
+            .ok_or_else(|| translation_error("no DeepL API key is set"))?;
+        Ok(Self { api_key })
+    }
+}
+
+impl Translator for DeepLTranslator {
+    async fn translate(&self, request: &TranslationRequest<'_>) -> Result<Translation> {
+        let url = if self.api_key.ends_with(":fx") {
+            FREE_URL
+        } else {
+            PRO_URL
+        };
+        let mut body = json!({
+            "text": [request.text],
+            "target_lang": language(request.target, true),
+        });
+        if let Some(source) = request.source {
+            body["source_lang"] = language(source, false).into();
+        }
+        let request = net::client()
+            .post(url)
+            .header("Authorization", format!("DeepL-Auth-Key {}", self.api_key))
+            .json(&body);
+        let response: Response = fetch_json(request).await?;
+        let translation = response
+            .translations
+            .into_iter()
+            .next()
+            .ok_or_else(|| translation_error("DeepL returned no translation"))?;
+        Ok(Translation {
+            text: translation.text,
+            detected_language: translation
+                .detected_source_language
+                .map(|lang| lang.to_lowercase()),
+        })
+    }
+}
@@ -0,0 +1,75 @@
+//! Google Cloud Translation, the basic edition, with an API key.
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::{fetch_json, translation_error, Translation, TranslationRequest, Translator};
+use crate::error::Result;
+use crate::net;
+
+const API_URL: &str = "https://translation.googleapis.com/language/translate/v2";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GoogleConfig {
+    pub api_key: Option<String>,
+}
+
+pub struct GoogleTranslator {
+    api_key: String,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    data: ResponseData,
+}
+
+#[derive(Deserialize)]
+struct ResponseData {
+    translations: Vec<ResponseTranslation>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ResponseTranslation {
+    translated_text: String,
+    detected_source_language: Option<String>,
+}
+
+impl GoogleTranslator {
+    pub fn new(config: GoogleConfig) -> Result<Self> {
+        let api_key = config
+            .api_key
+            .ok_or_else(|| translation_error("no Google API key is set"))?;
+        Ok(Self { api_key })
+    }
+}
+
+impl Translator for GoogleTranslator {
+    async fn translate(&self, request: &TranslationRequest<'_>) -> Result<Translation> {
+        let mut body = json!({
+            "q": request.text,
+            "target": request.target,
+            // Plain text, so markup in the selection is not interpreted
+            "format": "text",
+        });
+        if let Some(source) = request.source {
+            body["source"] = source.into();
+        }
+        let request = net::client()
+            .post(API_URL)
+            .query(&[("key", &self.api_key)])
+            .json(&body);
+        let response: Response = fetch_json(request).await?;
+        let translation = response
+            .data
+            .translations
+            .into_iter()
+            .next()
+            .ok_or_else(|| translation_error("Google returned no translation"))?;
+        Ok(Translation {
+            text: translation.translated_text,
+            detected_language: translation.detected_source_language,
+        })
+    }
+}
@@ -0,0 +1,77 @@
+//! A LibreTranslate server, which runs open translation models on the
+//! machine itself or one on the local network, so translating works
+//! offline and no text leaves it.
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use url::Url;
+
+use super::{fetch_json, Translation, TranslationRequest, Translator};
+use crate::error::Result;
+use crate::net;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LocalConfig {
+    /// Base URL of the server, e.g. `http://localhost:5000/`.
+    pub url: String,
+    /// Only needed for servers that require keys.
+    pub api_key: Option<String>,
+}
+
+pub struct LocalTranslator {
+    endpoint: Url,
+    api_key: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Response {
+    translated_text: String,
+    detected_language: Option<DetectedLanguage>,
+}
+
+#[derive(Deserialize)]
+struct DetectedLanguage {
+    language: String,
+}
+
+impl LocalTranslator {
+    pub fn new(config: LocalConfig) -> Result<Self> {
+        let mut base = Url::parse(&config.url)?;
+        if !base.path().ends_with('/') {
+            base.set_path(&format!("{}/", base.path()));
+        }
+        Ok(Self {
+            endpoint: base.join("translate")?,
+            api_key: config.api_key,
+        })
+    }
+}
+
+impl Translator for LocalTranslator {
+    async fn translate(&self, request: &TranslationRequest<'_>) -> Result<Translation> {
+        // Models are per language, without regional variants
+        let primary = |lang: &str| {
+            lang.split(['-', '_'])
+                .next()
+                .unwrap_or_default()
+                .to_lowercase()
+        };
+        let mut body = json!({
+            "q": request.text,
+            "source": request.source.map_or_else(|| "auto".to_string(), primary),
+            "target": primary(request.target),
+            "format": "text",
+        });
+        if let Some(key) = &self.api_key {
+            body["api_key"] = key.as_str().into();
+        }
+        let request = net::client().post(self.endpoint.clone()).json(&body);
+        let response: Response = fetch_json(request).await?;
+        Ok(Translation {
+            text: response.translated_text,
+            detected_language: response.detected_language.map(|detected| detected.language),
+        })
+    }
+}
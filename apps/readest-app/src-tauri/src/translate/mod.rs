@@ -0,0 +1,281 @@
+//! Translation of selected text through a configured provider: DeepL,
+//! Google Cloud Translation, or a LibreTranslate server running locally
+//! for translating offline. Requests are made from here so API keys, kept
+//! in the keychain, never reach the webview, and recent translations are
+//! cached so selecting the same sentence again costs no request.
+
+use std::future::Future;
+use std::sync::Mutex;
+
+use reqwest::RequestBuilder;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle, Manager};
+
+use crate::error::{Error, Result};
+use crate::secrets;
+use crate::store;
+
+pub mod deepl;
+pub mod google;
+pub mod local;
+
+const TRANSLATOR_FILE: &str = "translator.json";
+/// Keychain entries of the API keys, one per provider so switching between
+/// them keeps both.
+#[cfg(desktop)]
+const SECRET_PREFIX: &str = "translator.";
+/// Where the API keys are kept without a keychain.
+#[cfg(not(desktop))]
+const KEYS_FILE: &str = "translator-keys.json";
+/// Longest selection translated, in characters.
+const MAX_TEXT_CHARS: usize = 5000;
+const MAX_CACHED: usize = 256;
+
+/// Text to translate into `target`, from `source` or the language the
+/// provider detects. Languages are BCP 47 codes such as `en` or `pt-BR`.
+pub struct TranslationRequest<'a> {
+    pub text: &'a str,
+    pub source: Option<&'a str>,
+    pub target: &'a str,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Translation {
+    pub text: String,
+    /// Language of the original, when the provider detected it.
+    pub detected_language: Option<String>,
+}
+
+pub trait Translator {
+    fn translate(
+        &self,
+        request: &TranslationRequest<'_>,
+    ) -> impl Future<Output = Result<Translation>> + Send;
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum TranslatorConfig {
+    DeepL(deepl::DeepLConfig),
+    Google(google::GoogleConfig),
+    /// A LibreTranslate server, usually on this machine, so no text leaves
+    /// the device.
+    Local(local::LocalConfig),
+}
+
+impl TranslatorConfig {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::DeepL(_) => "deepl",
+            Self::Google(_) => "google",
+            Self::Local(_) => "local",
+        }
+    }
+
+    fn api_key_mut(&mut self) -> &mut Option<String> {
+        match self {
+            Self::DeepL(config) => &mut config.api_key,
+            Self::Google(config) => &mut config.api_key,
+            Self::Local(config) => &mut config.api_key,
+        }
+    }
+}
+
+const KINDS: [&str; 3] = ["deepl", "google", "local"];
+
+fn translation_error(message: impl std::fmt::Display) -> Error {
+    Error::Translation(message.to_string())
+}
+
+/// Sends `request` and parses the JSON response.
+async fn fetch_json<T: DeserializeOwned>(request: RequestBuilder) -> Result<T> {
+    let response = request.send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(Error::HttpStatus(status.as_u16()));
+    }
+    Ok(response.json().await?)
+}
+
+#[cfg(desktop)]
+fn load_key(_app: &AppHandle, kind: &str) -> Result<Option<String>> {
+    secrets::get(&format!("{SECRET_PREFIX}{kind}"))
+}
+
+#[cfg(desktop)]
+fn store_key(_app: &AppHandle, kind: &str, key: Option<&str>) -> Result<()> {
+    secrets::store(&format!("{SECRET_PREFIX}{kind}"), key)
+}
+
+#[cfg(not(desktop))]
+fn load_key(app: &AppHandle, kind: &str) -> Result<Option<String>> {
+    let keys: std::collections::BTreeMap<String, String> = store::load(app, KEYS_FILE);
+    Ok(keys.get(kind).cloned())
+}
+
+#[cfg(not(desktop))]
+fn store_key(app: &AppHandle, kind: &str, key: Option<&str>) -> Result<()> {
+    let mut keys: std::collections::BTreeMap<String, String> = store::load(app, KEYS_FILE);
+    match key {
+        Some(key) => keys.insert(kind.to_string(), key.to_string()),
+        None => keys.remove(kind),
+    };
+    store::save(app, KEYS_FILE, &keys)
+}
+
+/// Saves the config, moving its API key out of it. A config without a key
+/// keeps the one stored for its provider, an empty key removes it, and no
+/// config removes them all.
+fn save_config(app: &AppHandle, mut config: Option<TranslatorConfig>) -> Result<()> {
+    match config.as_mut() {
+        Some(config) => {
+            let kind = config.kind();
+            if let Some(key) = config.api_key_mut().take() {
+                let key = key.trim();
+                store_key(app, kind, (!key.is_empty()).then_some(key))?;
+            }
+        }
+        None => {
+            for kind in KINDS {
+                store_key(app, kind, None)?;
+            }
+        }
+    }
+    store::save(app, TRANSLATOR_FILE, &config)
+}
+
+/// Loads the config with the API key of its provider.
+fn load_config(app: &AppHandle) -> Result<Option<TranslatorConfig>> {
+    let config: Option<TranslatorConfig> = store::load(app, TRANSLATOR_FILE);
+    let Some(mut config) = config else {
+        return Ok(None);
+    };
+    *config.api_key_mut() = load_key(app, config.kind())?;
+    Ok(Some(config))
+}
+
+enum Provider {
+    DeepL(deepl::DeepLTranslator),
+    Google(google::GoogleTranslator),
+    Local(local::LocalTranslator),
+}
+
+impl Provider {
+    fn new(config: TranslatorConfig) -> Result<Self> {
+        Ok(match config {
+            TranslatorConfig::DeepL(config) => Self::DeepL(deepl::DeepLTranslator::new(config)?),
+            TranslatorConfig::Google(config) => {
+                Self::Google(google::GoogleTranslator::new(config)?)
+            }
+            TranslatorConfig::Local(config) => Self::Local(local::LocalTranslator::new(config)?),
+        })
+    }
+
+    async fn translate(&self, request: &TranslationRequest<'_>) -> Result<Translation> {
+        match self {
+            Self::DeepL(provider) => provider.translate(request).await,
+            Self::Google(provider) => provider.translate(request).await,
+            Self::Local(provider) => provider.translate(request).await,
+        }
+    }
+}
+
+/// Provider, source language, target language and text of a translation.
+type CacheKey = (String, Option<String>, String, String);
+
+/// Recent translations, most recent last. Cleared when the provider
+/// changes.
+#[derive(Default)]
+pub struct TranslationCache(Mutex<Vec<(CacheKey, Translation)>>);
+
+impl TranslationCache {
+    fn get(&self, key: &CacheKey) -> Option<Translation> {
+        let mut entries = self.0.lock().unwrap();
+        let i = entries.iter().position(|(k, _)| k == key)?;
+        let entry = entries.remove(i);
+        let translation = entry.1.clone();
+        entries.push(entry);
+        Some(translation)
+    }
+
+    fn insert(&self, key: CacheKey, translation: Translation) {
+        let mut entries = self.0.lock().unwrap();
+        if entries.len() >= MAX_CACHED {
+            entries.remove(0);
+        }
+        entries.push((key, translation));
+    }
+
+    fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TranslatorSettings {
+    /// The config without its API key.
+    #[serde(flatten)]
+    pub config: TranslatorConfig,
+    pub has_api_key: bool,
+}
+
+#[command]
+pub async fn get_translator(app: AppHandle) -> Result<Option<TranslatorSettings>> {
+    let config = secrets::blocking(move || load_config(&app)).await?;
+    Ok(config.map(|mut config| TranslatorSettings {
+        has_api_key: config.api_key_mut().take().is_some(),
+        config,
+    }))
+}
+
+/// Saves the translation provider. Its API key is kept when `config` has
+/// none, see [`save_config`].
+#[command]
+pub async fn set_translator(app: AppHandle, config: Option<TranslatorConfig>) -> Result<()> {
+    let saving = app.clone();
+    secrets::blocking(move || save_config(&saving, config)).await?;
+    app.state::<TranslationCache>().clear();
+    Ok(())
+}
+
+/// Translates `text` into `target` with the configured provider.
+#[command]
+pub async fn translate_selection(
+    app: AppHandle,
+    text: String,
+    target: String,
+    source: Option<String>,
+) -> Result<Translation> {
+    let text = text.trim().to_string();
+    if text.is_empty() {
+        return Err(translation_error("nothing to translate"));
+    }
+    if text.chars().count() > MAX_TEXT_CHARS {
+        return Err(translation_error(format!(
+            "selections are translated up to {MAX_TEXT_CHARS} characters"
+        )));
+    }
+    let loading = app.clone();
+    let config = tauri::async_runtime::spawn_blocking(move || load_config(&loading))
+        .await??
+        .ok_or(Error::TranslationNotConfigured)?;
+
+    let key = (config.kind().to_string(), source, target, text);
+    let cache = app.state::<TranslationCache>();
+    if let Some(translation) = cache.get(&key) {
+        return Ok(translation);
+    }
+    let (_, source, target, text) = &key;
+    let translation = Provider::new(config)?
+        .translate(&TranslationRequest {
+            text,
+            source: source.as_deref(),
+            target,
+        })
+        .await?;
+    cache.insert(key.clone(), translation.clone());
+    Ok(translation)
+}
@@ -0,0 +1,210 @@
+//! Optional system tray icon with the book being read, playback controls
+//! and the recent books, for listening with text-to-speech while the window
+//! is out of the way. With close-to-tray on, closing the window hides it
+//! and the app keeps running until it is quit from the tray.
+
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use tauri::menu::{Menu, MenuEvent, MenuItem, PredefinedMenuItem, Submenu};
+use tauri::tray::{MouseButton, MouseButtonState, TrayIconBuilder, TrayIconEvent};
+use tauri::{command, AppHandle, Emitter, Manager, State, Window, WindowEvent, Wry};
+
+use crate::deep_link::{self, DeepLink};
+use crate::error::Result;
+use crate::i18n;
+use crate::recent;
+use crate::shortcuts::ShortcutAction;
+use crate::store;
+
+const SETTINGS_FILE: &str = "tray.json";
+const TRAY_ID: &str = "main";
+pub const EVENT: &str = "tray-action";
+
+const OPEN_RECENT_PREFIX: &str = "tray_open:";
+const TOGGLE_TTS_ID: &str = "tray_toggle_tts";
+const PREVIOUS_PAGE_ID: &str = "tray_previous_page";
+const NEXT_PAGE_ID: &str = "tray_next_page";
+const SHOW_ID: &str = "tray_show";
+const QUIT_ID: &str = "tray_quit";
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TraySettings {
+    pub enabled: bool,
+    /// Hide the window instead of quitting when it is closed. Only applies
+    /// while the tray icon is shown.
+    pub close_to_tray: bool,
+}
+
+#[derive(Default)]
+struct Status {
+    book_title: Option<String>,
+    tts_playing: bool,
+}
+
+#[derive(Default)]
+pub struct Tray(Mutex<Status>);
+
+fn build_menu(app: &AppHandle) -> Result<Menu<Wry>> {
+    let (book_title, tts_playing) = {
+        let tray = app.state::<Tray>();
+        let status = tray.0.lock().unwrap();
+        (status.book_title.clone(), status.tts_playing)
+    };
+    let reading = book_title.is_some();
+    let title = book_title.unwrap_or_else(|| i18n::t(app, "No book open"));
+
+    let recent_menu = Submenu::new(app, i18n::t(app, "Recent Books"), true)?;
+    let books = recent::recent_books(app)?;
+    for book in &books {
+        let id = format!("{OPEN_RECENT_PREFIX}{}", book.hash);
+        recent_menu.append(&MenuItem::with_id(
+            app,
+            id,
+            &book.title,
+            true,
+            None::<&str>,
+        )?)?;
+    }
+    recent_menu.set_enabled(!books.is_empty())?;
+
+    let tts_label = i18n::t(app, if tts_playing { "Pause" } else { "Play" });
+    let menu = Menu::with_items(
+        app,
+        &[
+            &MenuItem::new(app, title, false, None::<&str>)?,
+            &PredefinedMenuItem::separator(app)?,
+            &MenuItem::with_id(app, TOGGLE_TTS_ID, tts_label, reading, None::<&str>)?,
+            &MenuItem::with_id(
+                app,
+                PREVIOUS_PAGE_ID,
+                i18n::t(app, "Previous Page"),
+                reading,
+                None::<&str>,
+            )?,
+            &MenuItem::with_id(
+                app,
+                NEXT_PAGE_ID,
+                i18n::t(app, "Next Page"),
+                reading,
+                None::<&str>,
+            )?,
+            &PredefinedMenuItem::separator(app)?,
+            &recent_menu,
+            &PredefinedMenuItem::separator(app)?,
+            &MenuItem::with_id(
+                app,
+                SHOW_ID,
+                i18n::t(app, "Show {{name}}"),
+                true,
+                None::<&str>,
+            )?,
+            &MenuItem::with_id(app, QUIT_ID, i18n::t(app, "Quit"), true, None::<&str>)?,
+        ],
+    )?;
+    Ok(menu)
+}
+
+fn handle_menu_event(app: &AppHandle, event: &MenuEvent) {
+    let id = event.id().as_ref();
+    if let Some(hash) = id.strip_prefix(OPEN_RECENT_PREFIX) {
+        let link = DeepLink::Open {
+            book: hash.to_string(),
+            cfi: None,
+        };
+        deep_link::dispatch(app, vec![link]);
+        return;
+    }
+    let action = match id {
+        TOGGLE_TTS_ID => ShortcutAction::ToggleTts,
+        PREVIOUS_PAGE_ID => ShortcutAction::PreviousPage,
+        NEXT_PAGE_ID => ShortcutAction::NextPage,
+        SHOW_ID => return crate::show_main_window(app),
+        QUIT_ID => return app.exit(0),
+        _ => return,
+    };
+    let _ = app.emit(EVENT, action);
+}
+
+/// Shows, updates or removes the tray icon to match the settings.
+pub fn refresh(app: &AppHandle) -> Result<()> {
+    let settings: TraySettings = store::load(app, SETTINGS_FILE);
+    if !settings.enabled {
+        app.remove_tray_by_id(TRAY_ID);
+        return Ok(());
+    }
+    let menu = build_menu(app)?;
+    if let Some(tray) = app.tray_by_id(TRAY_ID) {
+        tray.set_menu(Some(menu))?;
+        return Ok(());
+    }
+
+    let mut builder = TrayIconBuilder::with_id(TRAY_ID)
+        .tooltip("VL-Arch")
+        .menu(&menu)
+        .show_menu_on_left_click(false)
+        .on_menu_event(|app, event| handle_menu_event(app, &event))
+        .on_tray_icon_event(|tray, event| {
+            if let TrayIconEvent::Click {
+                button: MouseButton::Left,
+                button_state: MouseButtonState::Up,
+                ..
+            } = event
+            {
+                crate::show_main_window(tray.app_handle());
+            }
+        });
+    if let Some(icon) = app.default_window_icon() {
+        builder = builder.icon(icon.clone());
+    }
+    builder.build(app)?;
+    Ok(())
+}
+
+pub fn init(app: &AppHandle) {
+    if let Err(e) = refresh(app) {
+        log::warn!("Failed to show tray icon: {e}");
+    }
+}
+
+/// Hides the main window instead of closing it when close-to-tray is on.
+pub fn on_window_event(window: &Window, event: &WindowEvent) {
+    let WindowEvent::CloseRequested { api, .. } = event else {
+        return;
+    };
+    if window.label() != "main" {
+        return;
+    }
+    let settings: TraySettings = store::load(window.app_handle(), SETTINGS_FILE);
+    if settings.enabled && settings.close_to_tray {
+        api.prevent_close();
+        let _ = window.hide();
+    }
+}
+
+#[command]
+pub fn get_tray_settings(app: AppHandle) -> TraySettings {
+    store::load(&app, SETTINGS_FILE)
+}
+
+#[command]
+pub fn set_tray_settings(app: AppHandle, settings: TraySettings) -> Result<()> {
+    store::save(&app, SETTINGS_FILE, &settings)?;
+    refresh(&app)
+}
+
+/// Updates the book and playback state shown in the tray menu.
+#[command]
+pub fn set_tray_status(
+    app: AppHandle,
+    tray: State<'_, Tray>,
+    book_title: Option<String>,
+    tts_playing: bool,
+) -> Result<()> {
+    *tray.0.lock().unwrap() = Status {
+        book_title,
+        tts_playing,
+    };
+    refresh(&app)
+}
@@ -0,0 +1,194 @@
+//! AVSpeechSynthesizer engine. Word boundaries come from the delegate's
+//! `willSpeakRangeOfSpeechString` callback, whose ranges are already in UTF-16.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use objc2::rc::Retained;
+use objc2::runtime::ProtocolObject;
+use objc2::{define_class, msg_send, AllocAnyThread, DefinedClass};
+use objc2_avf_audio::{
+    AVSpeechBoundary, AVSpeechSynthesisVoice, AVSpeechSynthesizer, AVSpeechSynthesizerDelegate,
+    AVSpeechUtterance, AVSpeechUtteranceDefaultSpeechRate, AVSpeechUtteranceMaximumSpeechRate,
+    AVSpeechUtteranceMinimumSpeechRate,
+};
+use objc2_foundation::{NSObject, NSObjectProtocol, NSRange, NSString};
+
+use super::{Backend, Events, TtsEvent, Voice};
+use crate::error::{Error, Result};
+
+pub struct DelegateIvars {
+    events: Events,
+    /// Utterance ids by the address of their `AVSpeechUtterance`.
+    utterances: Mutex<HashMap<usize, u64>>,
+}
+
+define_class!(
+    #[unsafe(super(NSObject))]
+    #[name = "VLArchSpeechSynthesizerDelegate"]
+    #[ivars = DelegateIvars]
+    pub struct SpeechDelegate;
+
+    unsafe impl NSObjectProtocol for SpeechDelegate {}
+
+    unsafe impl AVSpeechSynthesizerDelegate for SpeechDelegate {
+        #[unsafe(method(speechSynthesizer:didStartSpeechUtterance:))]
+        fn did_start(&self, _synthesizer: &AVSpeechSynthesizer, utterance: &AVSpeechUtterance) {
+            if let Some(utterance_id) = self.utterance_id(utterance, false) {
+                (self.ivars().events)(TtsEvent::Start { utterance_id });
+            }
+        }
+
+        #[unsafe(method(speechSynthesizer:didFinishSpeechUtterance:))]
+        fn did_finish(&self, _synthesizer: &AVSpeechSynthesizer, utterance: &AVSpeechUtterance) {
+            if let Some(utterance_id) = self.utterance_id(utterance, true) {
+                (self.ivars().events)(TtsEvent::End { utterance_id });
+            }
+        }
+
+        #[unsafe(method(speechSynthesizer:didCancelSpeechUtterance:))]
+        fn did_cancel(&self, _synthesizer: &AVSpeechSynthesizer, utterance: &AVSpeechUtterance) {
+            if let Some(utterance_id) = self.utterance_id(utterance, true) {
+                (self.ivars().events)(TtsEvent::Cancelled { utterance_id });
+            }
+        }
+
+        #[unsafe(method(speechSynthesizer:willSpeakRangeOfSpeechString:utterance:))]
+        fn will_speak_range(
+            &self,
+            _synthesizer: &AVSpeechSynthesizer,
+            range: NSRange,
+            utterance: &AVSpeechUtterance,
+        ) {
+            if let Some(utterance_id) = self.utterance_id(utterance, false) {
+                (self.ivars().events)(TtsEvent::Word {
+                    utterance_id,
+                    char_index: range.location as u32,
+                    char_length: range.length as u32,
+                });
+            }
+        }
+    }
+);
+
+// The ivars are thread safe, and AVFoundation may call the delegate from any thread
+unsafe impl Send for SpeechDelegate {}
+unsafe impl Sync for SpeechDelegate {}
+
+impl SpeechDelegate {
+    fn new(events: Events) -> Retained<Self> {
+        let this = Self::alloc().set_ivars(DelegateIvars {
+            events,
+            utterances: Mutex::new(HashMap::new()),
+        });
+        unsafe { msg_send![super(this), init] }
+    }
+
+    fn utterance_id(&self, utterance: &AVSpeechUtterance, finished: bool) -> Option<u64> {
+        let key = utterance as *const AVSpeechUtterance as usize;
+        let mut utterances = self.ivars().utterances.lock().unwrap();
+        if finished {
+            utterances.remove(&key)
+        } else {
+            utterances.get(&key).copied()
+        }
+    }
+}
+
+pub struct Engine {
+    synthesizer: Retained<AVSpeechSynthesizer>,
+    /// The synthesizer only holds a weak reference to its delegate.
+    delegate: Retained<SpeechDelegate>,
+    voice: Option<Retained<AVSpeechSynthesisVoice>>,
+    rate: f32,
+}
+
+// AVSpeechSynthesizer can be driven from any thread; the engine is only
+// used behind the `Tts` mutex
+unsafe impl Send for Engine {}
+
+impl Backend for Engine {
+    fn new(events: Events) -> Result<Self> {
+        let synthesizer = unsafe { AVSpeechSynthesizer::new() };
+        let delegate = SpeechDelegate::new(events);
+        unsafe { synthesizer.setDelegate(Some(ProtocolObject::from_ref(&*delegate))) };
+        Ok(Self {
+            synthesizer,
+            delegate,
+            voice: None,
+            rate: unsafe { AVSpeechUtteranceDefaultSpeechRate },
+        })
+    }
+
+    fn voices(&mut self) -> Result<Vec<Voice>> {
+        let voices = unsafe { AVSpeechSynthesisVoice::speechVoices() };
+        Ok(voices
+            .iter()
+            .map(|voice| unsafe {
+                Voice {
+                    id: voice.identifier().to_string(),
+                    name: voice.name().to_string(),
+                    lang: voice.language().to_string(),
+                }
+            })
+            .collect())
+    }
+
+    fn speak(&mut self, utterance_id: u64, text: &str) -> Result<()> {
+        unsafe {
+            self.synthesizer
+                .stopSpeakingAtBoundary(AVSpeechBoundary::Immediate);
+            let utterance = AVSpeechUtterance::speechUtteranceWithString(&NSString::from_str(text));
+            utterance.setVoice(self.voice.as_deref());
+            utterance.setRate(self.rate);
+            let key = Retained::as_ptr(&utterance) as usize;
+            self.delegate
+                .ivars()
+                .utterances
+                .lock()
+                .unwrap()
+                .insert(key, utterance_id);
+            self.synthesizer.speakUtterance(&utterance);
+        }
+        Ok(())
+    }
+
+    fn pause(&mut self) -> Result<()> {
+        unsafe {
+            self.synthesizer
+                .pauseSpeakingAtBoundary(AVSpeechBoundary::Word)
+        };
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        unsafe { self.synthesizer.continueSpeaking() };
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        unsafe {
+            self.synthesizer
+                .stopSpeakingAtBoundary(AVSpeechBoundary::Immediate)
+        };
+        Ok(())
+    }
+
+    fn set_rate(&mut self, rate: f32) -> Result<()> {
+        // The default rate is halfway between the minimum and the maximum
+        self.rate = unsafe {
+            (AVSpeechUtteranceDefaultSpeechRate * rate).clamp(
+                AVSpeechUtteranceMinimumSpeechRate,
+                AVSpeechUtteranceMaximumSpeechRate,
+            )
+        };
+        Ok(())
+    }
+
+    fn set_voice(&mut self, voice_id: &str) -> Result<()> {
+        let voice =
+            unsafe { AVSpeechSynthesisVoice::voiceWithIdentifier(&NSString::from_str(voice_id)) };
+        self.voice = Some(voice.ok_or_else(|| Error::Tts(format!("unknown voice {voice_id}")))?);
+        Ok(())
+    }
+}
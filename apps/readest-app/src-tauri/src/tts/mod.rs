@@ -0,0 +1,176 @@
+//! Text-to-speech through the system speech engine: AVSpeechSynthesizer on
+//! macOS, WinRT speech synthesis on Windows and speech-dispatcher elsewhere.
+//! Unlike the Web Speech API it keeps speaking while the window is in the
+//! background, and it reports which word is being spoken as `tts-event`
+//! events so the reader can follow along.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use tauri::{command, AppHandle, Emitter, Manager};
+
+use crate::error::Result;
+
+#[cfg(target_os = "macos")]
+mod avfoundation;
+#[cfg(all(unix, not(target_os = "macos")))]
+mod speechd;
+#[cfg(windows)]
+mod winrt;
+
+#[cfg(target_os = "macos")]
+use avfoundation::Engine;
+#[cfg(all(unix, not(target_os = "macos")))]
+use speechd::Engine;
+#[cfg(windows)]
+use winrt::Engine;
+
+pub const EVENT: &str = "tts-event";
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Voice {
+    pub id: String,
+    pub name: String,
+    /// BCP 47 language tag, e.g. `en-US`.
+    pub lang: String,
+}
+
+/// Progress of an utterance. Word offsets are in UTF-16 code units of the
+/// spoken text, so they index JavaScript strings directly.
+#[derive(Debug, Clone, Serialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum TtsEvent {
+    Start {
+        utterance_id: u64,
+    },
+    Word {
+        utterance_id: u64,
+        char_index: u32,
+        char_length: u32,
+    },
+    End {
+        utterance_id: u64,
+    },
+    /// The utterance was stopped or replaced by another one.
+    Cancelled {
+        utterance_id: u64,
+    },
+    Error {
+        utterance_id: u64,
+        message: String,
+    },
+}
+
+/// Sends events from the engine, which may call it on any thread.
+type Events = Arc<dyn Fn(TtsEvent) + Send + Sync>;
+
+/// What every platform engine provides. Speaking a new utterance interrupts
+/// the current one.
+trait Backend: Sized + Send {
+    fn new(events: Events) -> Result<Self>;
+    fn voices(&mut self) -> Result<Vec<Voice>>;
+    fn speak(&mut self, utterance_id: u64, text: &str) -> Result<()>;
+    fn pause(&mut self) -> Result<()>;
+    fn resume(&mut self) -> Result<()>;
+    fn stop(&mut self) -> Result<()>;
+    /// `rate` is a multiple of the normal speaking rate.
+    fn set_rate(&mut self, rate: f32) -> Result<()>;
+    fn set_voice(&mut self, voice_id: &str) -> Result<()>;
+}
+
+/// State of speech as a whole, across utterances.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Playback {
+    Playing,
+    Paused,
+    Stopped,
+}
+
+/// Mirrors `playback` in the system media controls where the engine does
+/// not do it itself.
+fn set_playback(app: &AppHandle, playback: Playback) {
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    crate::media_controls::set_playback(app, playback);
+    #[cfg(not(any(target_os = "macos", target_os = "linux")))]
+    let _ = (app, playback);
+}
+
+#[derive(Default)]
+pub struct Tts {
+    /// Created on first use, so the speech engine is not started for users
+    /// who never listen.
+    engine: Mutex<Option<Engine>>,
+    next_id: AtomicU64,
+}
+
+fn with_engine<T>(app: &AppHandle, f: impl FnOnce(&mut Engine) -> Result<T>) -> Result<T> {
+    let tts = app.state::<Tts>();
+    let mut engine = tts.engine.lock().unwrap();
+    if engine.is_none() {
+        let app = app.clone();
+        let events: Events = Arc::new(move |event| {
+            let _ = app.emit(EVENT, event);
+        });
+        *engine = Some(Engine::new(events)?);
+    }
+    f(engine.as_mut().unwrap())
+}
+
+/// Runs `f` off the async runtime, as synthesis calls may block.
+async fn run<T: Send + 'static>(
+    app: AppHandle,
+    f: impl FnOnce(&mut Engine) -> Result<T> + Send + 'static,
+) -> Result<T> {
+    tauri::async_runtime::spawn_blocking(move || with_engine(&app, f)).await?
+}
+
+#[command]
+pub async fn tts_get_voices(app: AppHandle) -> Result<Vec<Voice>> {
+    run(app, |engine| engine.voices()).await
+}
+
+/// Starts speaking `text` and returns the utterance id used in its events.
+#[command]
+pub async fn tts_speak(app: AppHandle, text: String) -> Result<u64> {
+    let id = app.state::<Tts>().next_id.fetch_add(1, Ordering::Relaxed) + 1;
+    run(app.clone(), move |engine| engine.speak(id, &text)).await?;
+    set_playback(&app, Playback::Playing);
+    Ok(id)
+}
+
+#[command]
+pub async fn tts_pause(app: AppHandle) -> Result<()> {
+    run(app.clone(), |engine| engine.pause()).await?;
+    set_playback(&app, Playback::Paused);
+    Ok(())
+}
+
+#[command]
+pub async fn tts_resume(app: AppHandle) -> Result<()> {
+    run(app.clone(), |engine| engine.resume()).await?;
+    set_playback(&app, Playback::Playing);
+    Ok(())
+}
+
+#[command]
+pub async fn tts_stop(app: AppHandle) -> Result<()> {
+    run(app.clone(), |engine| engine.stop()).await?;
+    set_playback(&app, Playback::Stopped);
+    Ok(())
+}
+
+#[command]
+pub async fn tts_set_rate(app: AppHandle, rate: f32) -> Result<()> {
+    run(app, move |engine| engine.set_rate(rate)).await
+}
+
+#[command]
+pub async fn tts_set_voice(app: AppHandle, voice_id: String) -> Result<()> {
+    run(app, move |engine| engine.set_voice(&voice_id)).await
+}
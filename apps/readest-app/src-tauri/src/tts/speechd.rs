@@ -0,0 +1,394 @@
+//! speech-dispatcher client speaking SSIP over its Unix socket. Word
+//! boundaries come from SSML marks placed before every word, which the server
+//! reports back as index mark events while it speaks.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use super::{Backend, Events, TtsEvent, Voice};
+use crate::error::{Error, Result};
+use crate::utils::escape_xml;
+
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait for an autospawned server to open its socket.
+const SPAWN_TIMEOUT: Duration = Duration::from_secs(3);
+/// Prefix of the SSML marks, followed by `<offset>_<length>` of the word.
+const WORD_MARK: &str = "w";
+
+/// A complete server response: the status code, the data lines and the
+/// final status text.
+struct Reply {
+    code: u16,
+    lines: Vec<String>,
+    text: String,
+}
+
+impl Reply {
+    fn is_ok(&self) -> bool {
+        (200..300).contains(&self.code)
+    }
+}
+
+#[derive(Default)]
+struct Messages {
+    /// Utterances sent with `SPEAK` whose message id has not arrived yet.
+    queued: VecDeque<u64>,
+    /// Utterance ids by server message id.
+    ids: HashMap<String, u64>,
+    /// Set when the server closed the connection.
+    closed: bool,
+}
+
+struct Connection {
+    stream: UnixStream,
+    replies: Receiver<Reply>,
+    messages: Arc<Mutex<Messages>>,
+}
+
+impl Connection {
+    fn open(events: Events) -> Result<Self> {
+        let path = socket_path();
+        let stream = match UnixStream::connect(&path) {
+            Ok(stream) => stream,
+            Err(_) => spawn_server(&path)?,
+        };
+        let messages = Arc::new(Mutex::new(Messages::default()));
+        let (sender, replies) = mpsc::channel();
+        let reader = BufReader::new(stream.try_clone()?);
+        let shared = messages.clone();
+        thread::Builder::new()
+            .name("speechd-events".into())
+            .spawn(move || read_responses(reader, sender, shared, events))?;
+
+        let mut connection = Self {
+            stream,
+            replies,
+            messages,
+        };
+        let user = std::env::var("USER").unwrap_or_else(|_| "user".into());
+        connection.command(&format!("SET self CLIENT_NAME {user}:vl-arch:tts"))?;
+        connection.command("SET self NOTIFICATION ALL on")?;
+        connection.command("SET self SSML_MODE on")?;
+        Ok(connection)
+    }
+
+    fn send(&mut self, data: &str) -> Result<Reply> {
+        self.stream.write_all(data.as_bytes())?;
+        let reply = match self.replies.recv_timeout(REPLY_TIMEOUT) {
+            Ok(reply) => reply,
+            Err(RecvTimeoutError::Timeout) => {
+                return Err(Error::Tts("speech-dispatcher did not respond".into()))
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(Error::Tts("speech-dispatcher closed the connection".into()))
+            }
+        };
+        if !reply.is_ok() && reply.code != 230 {
+            return Err(Error::Tts(format!("{} {}", reply.code, reply.text)));
+        }
+        Ok(reply)
+    }
+
+    fn is_closed(&self) -> bool {
+        self.messages.lock().unwrap().closed
+    }
+
+    fn command(&mut self, line: &str) -> Result<Reply> {
+        self.send(&format!("{line}\r\n"))
+    }
+
+    fn speak(&mut self, utterance_id: u64, ssml: &str) -> Result<()> {
+        self.command("SPEAK")?;
+        self.messages.lock().unwrap().queued.push_back(utterance_id);
+        // The SSML is a single line starting with `<`, so it never needs dot-stuffing
+        if let Err(e) = self.send(&format!("{ssml}\r\n.\r\n")) {
+            self.messages
+                .lock()
+                .unwrap()
+                .queued
+                .retain(|&id| id != utterance_id);
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let _ = self.stream.write_all(b"QUIT\r\n");
+        let _ = self.stream.shutdown(std::net::Shutdown::Both);
+    }
+}
+
+fn socket_path() -> PathBuf {
+    if let Some(path) = std::env::var("SPEECHD_ADDRESS")
+        .ok()
+        .and_then(|address| address.strip_prefix("unix_socket:").map(PathBuf::from))
+    {
+        return path;
+    }
+    let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .unwrap_or_else(std::env::temp_dir);
+    runtime_dir.join("speech-dispatcher").join("speechd.sock")
+}
+
+/// Starts a per-user server the way libspeechd does when none is running.
+fn spawn_server(path: &Path) -> Result<UnixStream> {
+    let status = Command::new("speech-dispatcher")
+        .arg("--spawn")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map_err(|e| Error::Tts(format!("speech-dispatcher is not available: {e}")))?;
+    if !status.success() {
+        log::warn!("speech-dispatcher --spawn exited with {status}");
+    }
+    let step = Duration::from_millis(100);
+    let mut waited = Duration::ZERO;
+    loop {
+        match UnixStream::connect(path) {
+            Ok(stream) => return Ok(stream),
+            Err(_) if waited < SPAWN_TIMEOUT => {
+                thread::sleep(step);
+                waited += step;
+            }
+            Err(e) => {
+                return Err(Error::Tts(format!(
+                    "cannot connect to speech-dispatcher: {e}"
+                )))
+            }
+        }
+    }
+}
+
+/// Reads server responses, turning 7xx notifications into events and passing
+/// everything else on as replies to the pending command.
+fn read_responses(
+    reader: BufReader<UnixStream>,
+    replies: Sender<Reply>,
+    messages: Arc<Mutex<Messages>>,
+    events: Events,
+) {
+    let mut lines = Vec::new();
+    for line in reader.lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        let line = line.trim_end_matches('\r');
+        let Some(code) = line.get(..3).and_then(|code| code.parse::<u16>().ok()) else {
+            log::warn!("Unexpected speech-dispatcher response: {line}");
+            continue;
+        };
+        let rest = line.get(4..).unwrap_or_default().to_string();
+        // `NNN-data` lines continue a response, `NNN text` ends it
+        if line.as_bytes().get(3) == Some(&b'-') {
+            lines.push(rest);
+            continue;
+        }
+        let reply = Reply {
+            code,
+            lines: std::mem::take(&mut lines),
+            text: rest,
+        };
+        match reply.code {
+            700..=799 => notify(&reply, &messages, &events),
+            225 => {
+                let mut messages = messages.lock().unwrap();
+                if let (Some(msg_id), Some(id)) = (reply.lines.first(), messages.queued.pop_front())
+                {
+                    messages.ids.insert(msg_id.clone(), id);
+                }
+                drop(messages);
+                let _ = replies.send(reply);
+            }
+            _ => {
+                let _ = replies.send(reply);
+            }
+        }
+    }
+    // Utterances in flight will never finish now
+    let ids = {
+        let mut messages = messages.lock().unwrap();
+        messages.closed = true;
+        std::mem::take(&mut messages.ids)
+    };
+    for utterance_id in ids.into_values() {
+        events(TtsEvent::Error {
+            utterance_id,
+            message: "speech-dispatcher closed the connection".into(),
+        });
+    }
+}
+
+fn notify(reply: &Reply, messages: &Mutex<Messages>, events: &Events) {
+    // Notifications carry the message id, the client id and for index marks the mark name
+    let Some(msg_id) = reply.lines.first() else {
+        return;
+    };
+    let mut messages = messages.lock().unwrap();
+    let Some(&utterance_id) = messages.ids.get(msg_id) else {
+        return;
+    };
+    let event = match reply.code {
+        700 => {
+            let Some((char_index, char_length)) = reply.lines.get(2).and_then(|m| parse_mark(m))
+            else {
+                return;
+            };
+            TtsEvent::Word {
+                utterance_id,
+                char_index,
+                char_length,
+            }
+        }
+        701 => TtsEvent::Start { utterance_id },
+        702 => {
+            messages.ids.remove(msg_id);
+            TtsEvent::End { utterance_id }
+        }
+        703 => {
+            messages.ids.remove(msg_id);
+            TtsEvent::Cancelled { utterance_id }
+        }
+        _ => return,
+    };
+    drop(messages);
+    events(event);
+}
+
+fn parse_mark(name: &str) -> Option<(u32, u32)> {
+    let (offset, len) = name.strip_prefix(WORD_MARK)?.split_once('_')?;
+    Some((offset.parse().ok()?, len.parse().ok()?))
+}
+
+/// Wraps `text` in SSML on a single line, with a mark before every word
+/// recording its UTF-16 offset and length.
+fn to_ssml(text: &str) -> String {
+    let mut ssml = String::with_capacity(text.len() * 2);
+    ssml.push_str("<speak>");
+    let mut offset = 0;
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        if is_separator(c) {
+            ssml.push(' ');
+            offset += c.len_utf16();
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+        let end = rest.find(is_separator).unwrap_or(rest.len());
+        let (word, tail) = rest.split_at(end);
+        let len = word.encode_utf16().count();
+        let _ = write!(ssml, "<mark name=\"{WORD_MARK}{offset}_{len}\"/>");
+        ssml.push_str(&escape_xml(word));
+        offset += len;
+        rest = tail;
+    }
+    ssml.push_str("</speak>");
+    ssml
+}
+
+fn is_separator(c: char) -> bool {
+    c.is_whitespace() || c.is_control()
+}
+
+/// Maps a rate multiple onto SSIP's -100..=100, where 0 is the normal rate
+/// and the ends are a quarter and four times as fast.
+fn ssip_rate(rate: f32) -> i32 {
+    (rate.clamp(0.25, 4.0).log2() * 50.0).round() as i32
+}
+
+pub struct Engine {
+    events: Events,
+    connection: Option<Connection>,
+    rate: i32,
+    voice: Option<String>,
+}
+
+impl Engine {
+    /// The connection, reopened if the server went away since the last call.
+    fn connection(&mut self) -> Result<&mut Connection> {
+        if self.connection.as_ref().map_or(true, Connection::is_closed) {
+            self.connection = None;
+            let mut connection = Connection::open(self.events.clone())?;
+            connection.command(&format!("SET self RATE {}", self.rate))?;
+            if let Some(voice) = &self.voice {
+                connection.command(&format!("SET self SYNTHESIS_VOICE {voice}"))?;
+            }
+            self.connection = Some(connection);
+        }
+        Ok(self.connection.as_mut().unwrap())
+    }
+}
+
+impl Backend for Engine {
+    fn new(events: Events) -> Result<Self> {
+        Ok(Self {
+            connection: Some(Connection::open(events.clone())?),
+            events,
+            rate: 0,
+            voice: None,
+        })
+    }
+
+    fn voices(&mut self) -> Result<Vec<Voice>> {
+        let reply = self.connection()?.command("LIST SYNTHESIS_VOICES")?;
+        Ok(reply
+            .lines
+            .iter()
+            .filter_map(|line| {
+                // `name<TAB>language<TAB>variant`
+                let mut fields = line.split('\t');
+                let name = fields.next().filter(|name| !name.is_empty())?;
+                let lang = fields.next().unwrap_or_default();
+                Some(Voice {
+                    id: name.to_string(),
+                    name: name.to_string(),
+                    lang: lang.to_string(),
+                })
+            })
+            .collect())
+    }
+
+    fn speak(&mut self, utterance_id: u64, text: &str) -> Result<()> {
+        let ssml = to_ssml(text);
+        let connection = self.connection()?;
+        connection.command("CANCEL self")?;
+        connection.speak(utterance_id, &ssml)
+    }
+
+    fn pause(&mut self) -> Result<()> {
+        self.connection()?.command("PAUSE self").map(drop)
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        self.connection()?.command("RESUME self").map(drop)
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.connection()?.command("CANCEL self").map(drop)
+    }
+
+    fn set_rate(&mut self, rate: f32) -> Result<()> {
+        self.rate = ssip_rate(rate);
+        let command = format!("SET self RATE {}", self.rate);
+        self.connection()?.command(&command).map(drop)
+    }
+
+    fn set_voice(&mut self, voice_id: &str) -> Result<()> {
+        let command = format!("SET self SYNTHESIS_VOICE {voice_id}");
+        self.connection()?.command(&command)?;
+        self.voice = Some(voice_id.to_string());
+        Ok(())
+    }
+}
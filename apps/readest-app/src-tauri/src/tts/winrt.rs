@@ -0,0 +1,210 @@
+//! WinRT speech synthesis engine. Each utterance is synthesized to a stream
+//! with word boundary metadata and played through a `MediaPlayer`, whose
+//! `SpeechWord` cue track reports the words as they are spoken.
+
+use std::sync::{Arc, Mutex};
+
+use windows::core::{Interface, Ref, HSTRING};
+use windows::Foundation::Collections::{CollectionChange, IVectorChangedEventArgs};
+use windows::Foundation::TypedEventHandler;
+use windows::Media::Core::{MediaCueEventArgs, MediaSource, SpeechCue, TimedMetadataTrack};
+use windows::Media::Playback::{
+    IMediaPlaybackSource, MediaPlaybackItem, MediaPlayer, MediaPlayerFailedEventArgs,
+    TimedMetadataTrackPresentationMode,
+};
+use windows::Media::SpeechSynthesis::SpeechSynthesizer;
+use windows::Win32::System::WinRT::{RoInitialize, RO_INIT_MULTITHREADED};
+
+use super::{Backend, Events, TtsEvent, Voice};
+use crate::error::{Error, Result};
+
+/// Id of the cue track carrying word boundaries.
+const WORD_TRACK: &str = "SpeechWord";
+
+/// Joins the multithreaded apartment. Commands run on blocking pool threads
+/// that have not initialized WinRT; repeated calls are harmless.
+fn enter_apartment() {
+    let _ = unsafe { RoInitialize(RO_INIT_MULTITHREADED) };
+}
+
+pub struct Engine {
+    events: Events,
+    synthesizer: SpeechSynthesizer,
+    player: MediaPlayer,
+    /// Utterance loaded in the player. Handlers of earlier items check it so
+    /// they stay quiet once replaced.
+    current: Arc<Mutex<Option<u64>>>,
+}
+
+impl Engine {
+    /// Ends the current utterance as cancelled, if there is one.
+    fn cancel_current(&mut self) {
+        if let Some(utterance_id) = self.current.lock().unwrap().take() {
+            (self.events)(TtsEvent::Cancelled { utterance_id });
+        }
+    }
+}
+
+/// Shows the cues of track `index` to the app when it is the word track.
+fn watch_words(
+    item: &MediaPlaybackItem,
+    index: u32,
+    utterance_id: u64,
+    current: &Arc<Mutex<Option<u64>>>,
+    events: &Events,
+) -> windows::core::Result<()> {
+    let tracks = item.TimedMetadataTracks()?;
+    let track = tracks.GetAt(index)?;
+    if track.Id()? != WORD_TRACK {
+        return Ok(());
+    }
+    tracks.SetPresentationMode(
+        index,
+        TimedMetadataTrackPresentationMode::ApplicationPresented,
+    )?;
+    let (current, events) = (current.clone(), events.clone());
+    track.CueEntered(&TypedEventHandler::new(
+        move |_: Ref<TimedMetadataTrack>, args: Ref<MediaCueEventArgs>| {
+            if *current.lock().unwrap() != Some(utterance_id) {
+                return Ok(());
+            }
+            let cue = args.ok()?.Cue()?.cast::<SpeechCue>()?;
+            let start = cue.StartPositionInInput()?.Value()?;
+            let end = cue.EndPositionInInput()?.Value()?;
+            events(TtsEvent::Word {
+                utterance_id,
+                char_index: start.max(0) as u32,
+                char_length: (end - start + 1).max(0) as u32,
+            });
+            Ok(())
+        },
+    ))?;
+    Ok(())
+}
+
+impl Backend for Engine {
+    fn new(events: Events) -> Result<Self> {
+        enter_apartment();
+        let synthesizer = SpeechSynthesizer::new()?;
+        synthesizer
+            .Options()?
+            .SetIncludeWordBoundaryMetadata(true)?;
+        let player = MediaPlayer::new()?;
+        let current = Arc::new(Mutex::new(None));
+
+        player.MediaEnded(&TypedEventHandler::new({
+            let (current, events) = (current.clone(), events.clone());
+            move |_: Ref<MediaPlayer>, _: Ref<windows::core::IInspectable>| {
+                if let Some(utterance_id) = current.lock().unwrap().take() {
+                    events(TtsEvent::End { utterance_id });
+                }
+                Ok(())
+            }
+        }))?;
+        player.MediaFailed(&TypedEventHandler::new({
+            let (current, events) = (current.clone(), events.clone());
+            move |_: Ref<MediaPlayer>, args: Ref<MediaPlayerFailedEventArgs>| {
+                if let Some(utterance_id) = current.lock().unwrap().take() {
+                    let message = args.ok()?.ErrorMessage()?.to_string();
+                    events(TtsEvent::Error {
+                        utterance_id,
+                        message,
+                    });
+                }
+                Ok(())
+            }
+        }))?;
+
+        Ok(Self {
+            events,
+            synthesizer,
+            player,
+            current,
+        })
+    }
+
+    fn voices(&mut self) -> Result<Vec<Voice>> {
+        enter_apartment();
+        let mut voices = Vec::new();
+        for voice in SpeechSynthesizer::AllVoices()? {
+            voices.push(Voice {
+                id: voice.Id()?.to_string(),
+                name: voice.DisplayName()?.to_string(),
+                lang: voice.Language()?.to_string(),
+            });
+        }
+        Ok(voices)
+    }
+
+    fn speak(&mut self, utterance_id: u64, text: &str) -> Result<()> {
+        enter_apartment();
+        self.player.Pause()?;
+        self.cancel_current();
+
+        let stream = self
+            .synthesizer
+            .SynthesizeTextToStreamAsync(&HSTRING::from(text))?
+            .join()?;
+        let source = MediaSource::CreateFromStream(&stream, &stream.ContentType()?)?;
+        let item = MediaPlaybackItem::Create(&source)?;
+        // The metadata tracks show up once the source has been opened
+        item.TimedMetadataTracksChanged(&TypedEventHandler::new({
+            let (current, events) = (self.current.clone(), self.events.clone());
+            move |item: Ref<MediaPlaybackItem>, args: Ref<IVectorChangedEventArgs>| {
+                let args = args.ok()?;
+                if args.CollectionChange()? == CollectionChange::ItemInserted {
+                    watch_words(item.ok()?, args.Index()?, utterance_id, &current, &events)?;
+                }
+                Ok(())
+            }
+        }))?;
+        for index in 0..item.TimedMetadataTracks()?.Size()? {
+            watch_words(&item, index, utterance_id, &self.current, &self.events)?;
+        }
+
+        *self.current.lock().unwrap() = Some(utterance_id);
+        self.player.SetSource(&item)?;
+        self.player.Play()?;
+        (self.events)(TtsEvent::Start { utterance_id });
+        Ok(())
+    }
+
+    fn pause(&mut self) -> Result<()> {
+        enter_apartment();
+        self.player.Pause()?;
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        enter_apartment();
+        self.player.Play()?;
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        enter_apartment();
+        self.player.Pause()?;
+        self.player.SetSource(None::<&IMediaPlaybackSource>)?;
+        self.cancel_current();
+        Ok(())
+    }
+
+    fn set_rate(&mut self, rate: f32) -> Result<()> {
+        enter_apartment();
+        // WinRT takes 0.5 to 6 times the normal rate
+        let rate = (rate as f64).clamp(0.5, 6.0);
+        self.synthesizer.Options()?.SetSpeakingRate(rate)?;
+        Ok(())
+    }
+
+    fn set_voice(&mut self, voice_id: &str) -> Result<()> {
+        enter_apartment();
+        for voice in SpeechSynthesizer::AllVoices()? {
+            if voice.Id()? == voice_id {
+                self.synthesizer.SetVoice(&voice)?;
+                return Ok(());
+            }
+        }
+        Err(Error::Tts(format!("unknown voice {voice_id}")))
+    }
+}
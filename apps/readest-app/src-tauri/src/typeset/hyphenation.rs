@@ -0,0 +1,287 @@
+//! Hyphenation with TeX patterns, by Liang's algorithm, so justified text
+//! can break words where a dictionary would. Patterns are the plain text
+//! files of the hyph-utf8 project, bundled as resources and loaded the
+//! first time a language is asked for.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use tauri::path::BaseDirectory;
+use tauri::{command, AppHandle, Manager};
+
+use crate::error::Result;
+
+const PATTERNS_DIR: &str = "resources/hyphenation";
+/// Longer words are left alone, they are rarely words.
+const MAX_WORD_LEN: usize = 64;
+
+/// Pattern files of languages that are not named by their bare code.
+const ALIASES: &[(&str, &str)] = &[
+    ("en", "en-us"),
+    ("de", "de-1996"),
+    ("de-ch", "de-ch-1901"),
+    ("el", "el-monoton"),
+    ("mn", "mn-cyrl"),
+    ("sr", "sr-cyrl"),
+];
+
+/// Fewest letters left before and after a break, in the languages that
+/// do not use the usual two and two.
+const MIN_LENGTHS: &[(&str, (usize, usize))] = &[
+    ("en", (2, 3)),
+    ("fr", (2, 3)),
+    ("pt", (2, 3)),
+    ("sv", (1, 2)),
+];
+
+pub struct Hyphenator {
+    /// Letters of each pattern and the values between and around them.
+    patterns: HashMap<String, Vec<u8>>,
+    /// Words hyphenated by hand, with their break positions.
+    exceptions: HashMap<String, Vec<usize>>,
+    /// Letters in the longest pattern.
+    longest: usize,
+    min_before: usize,
+    min_after: usize,
+}
+
+fn lowercase(c: char) -> char {
+    // One to one, so break positions stay the positions in the word
+    c.to_lowercase().next().unwrap_or(c)
+}
+
+impl Hyphenator {
+    pub fn parse(
+        patterns: &str,
+        exceptions: &str,
+        (min_before, min_after): (usize, usize),
+    ) -> Self {
+        let mut parsed = HashMap::new();
+        let mut longest = 0;
+        for pattern in patterns.split_whitespace() {
+            if pattern.starts_with('%') {
+                continue;
+            }
+            let mut letters = String::new();
+            let mut values = vec![0];
+            for c in pattern.chars() {
+                match c.to_digit(10) {
+                    Some(value) => *values.last_mut().unwrap() = value as u8,
+                    None => {
+                        letters.push(lowercase(c));
+                        values.push(0);
+                    }
+                }
+            }
+            longest = longest.max(values.len() - 1);
+            parsed.insert(letters, values);
+        }
+        let exceptions = exceptions
+            .split_whitespace()
+            .filter(|word| !word.starts_with('%'))
+            .map(|word| {
+                let mut letters = String::new();
+                let mut breaks = Vec::new();
+                for c in word.chars() {
+                    if c == '-' {
+                        breaks.push(letters.chars().count());
+                    } else {
+                        letters.push(lowercase(c));
+                    }
+                }
+                (letters, breaks)
+            })
+            .collect();
+        Self {
+            patterns: parsed,
+            exceptions,
+            longest,
+            min_before,
+            min_after,
+        }
+    }
+
+    /// Positions, in characters, that `word` can be broken before.
+    pub fn breaks(&self, word: &str) -> Vec<usize> {
+        let letters = word.chars().map(lowercase).collect::<Vec<_>>();
+        let len = letters.len();
+        if len < self.min_before + self.min_after || len > MAX_WORD_LEN {
+            return Vec::new();
+        }
+        let allowed = |&at: &usize| at >= self.min_before.max(1) && at + self.min_after <= len;
+        if let Some(breaks) = self.exceptions.get(&letters.iter().collect::<String>()) {
+            return breaks.iter().copied().filter(allowed).collect();
+        }
+        // The word between dots, which patterns use for its ends
+        let dotted = [&['.'][..], &letters, &['.']].concat();
+        let mut values = vec![0u8; dotted.len() + 1];
+        for start in 0..dotted.len() {
+            let mut key = String::new();
+            for &c in dotted.iter().skip(start).take(self.longest) {
+                key.push(c);
+                if let Some(pattern) = self.patterns.get(&key) {
+                    for (value, &v) in values[start..].iter_mut().zip(pattern) {
+                        *value = (*value).max(v);
+                    }
+                }
+            }
+        }
+        // Odd values allow a break, the one before letter `at` follows the dot
+        (1..len)
+            .filter(allowed)
+            .filter(|&at| values[at + 1] % 2 == 1)
+            .collect()
+    }
+}
+
+/// Hyphenators loaded so far by language, `None` for the languages there
+/// are no patterns for.
+#[derive(Default)]
+pub struct Hyphenators(Mutex<HashMap<String, Option<Arc<Hyphenator>>>>);
+
+/// Pattern file names to try for `lang`, most specific first.
+fn candidates(lang: &str) -> Vec<String> {
+    let lang = lang.trim().to_lowercase().replace('_', "-");
+    let primary = lang.split('-').next().unwrap_or_default().to_string();
+    let mut candidates = Vec::new();
+    for code in [lang, primary] {
+        let alias = ALIASES.iter().find(|(from, _)| *from == code);
+        for name in [Some(code.clone()), alias.map(|(_, to)| to.to_string())]
+            .into_iter()
+            .flatten()
+        {
+            if !candidates.contains(&name) {
+                candidates.push(name);
+            }
+        }
+    }
+    candidates
+}
+
+fn load(dir: &Path, lang: &str) -> Result<Option<Hyphenator>> {
+    for name in candidates(lang) {
+        let patterns = dir.join(format!("hyph-{name}.pat.txt"));
+        if !patterns.is_file() {
+            continue;
+        }
+        let exceptions =
+            std::fs::read_to_string(dir.join(format!("hyph-{name}.hyp.txt"))).unwrap_or_default();
+        let primary = name.split('-').next().unwrap_or_default();
+        let min_lengths = MIN_LENGTHS
+            .iter()
+            .find(|(code, _)| *code == primary)
+            .map_or((2, 2), |&(_, lengths)| lengths);
+        let hyphenator = Hyphenator::parse(
+            &std::fs::read_to_string(patterns)?,
+            &exceptions,
+            min_lengths,
+        );
+        log::info!("Loaded hyphenation patterns {name} for {lang}");
+        return Ok(Some(hyphenator));
+    }
+    Ok(None)
+}
+
+impl Hyphenators {
+    fn get(&self, app: &AppHandle, lang: &str) -> Result<Option<Arc<Hyphenator>>> {
+        if let Some(hyphenator) = self.0.lock().unwrap().get(lang) {
+            return Ok(hyphenator.clone());
+        }
+        // Parse without the lock, large pattern files take a moment
+        let dir = app.path().resolve(PATTERNS_DIR, BaseDirectory::Resource)?;
+        let hyphenator = load(&dir, lang)?.map(Arc::new);
+        self.0
+            .lock()
+            .unwrap()
+            .insert(lang.to_string(), hyphenator.clone());
+        Ok(hyphenator)
+    }
+}
+
+/// Returns where each of `words` can be hyphenated, as UTF-16 offsets
+/// like JavaScript strings use, or `None` when there are no patterns for
+/// `lang`.
+#[command]
+pub async fn hyphenate_words(
+    app: AppHandle,
+    lang: String,
+    words: Vec<String>,
+) -> Result<Option<Vec<Vec<u32>>>> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let Some(hyphenator) = app.state::<Hyphenators>().get(&app, &lang)? else {
+            return Ok(None);
+        };
+        let breaks = words
+            .iter()
+            .map(|word| {
+                let offsets = word
+                    .chars()
+                    .scan(0, |offset, c| {
+                        let at = *offset;
+                        *offset += c.len_utf16() as u32;
+                        Some(at)
+                    })
+                    .collect::<Vec<_>>();
+                hyphenator
+                    .breaks(word)
+                    .into_iter()
+                    .map(|at| offsets[at])
+                    .collect()
+            })
+            .collect();
+        Ok(Some(breaks))
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The patterns the TeXbook hyphenates "hyphenation" with.
+    const PATTERNS: &str = "% Liang's example\nhy3ph he2n hena4 hen5at 1na n2at 1tio 2io o2n";
+
+    #[test]
+    fn patterns_break_words_where_values_are_odd() {
+        let hyphenator = Hyphenator::parse(PATTERNS, "", (2, 3));
+        assert_eq!(hyphenator.breaks("hyphenation"), [2, 6]);
+        assert_eq!(hyphenator.breaks("Hyphenation"), [2, 6]);
+        assert!(hyphenator.breaks("hyp").is_empty());
+        assert!(hyphenator.breaks(&"hyphenation".repeat(6)).is_empty());
+    }
+
+    #[test]
+    fn exceptions_win_over_patterns_within_the_lengths() {
+        let hyphenator = Hyphenator::parse(PATTERNS, "% words\nhy-phen-a-tion ta-b-le", (2, 3));
+        assert_eq!(hyphenator.breaks("HYPHENATION"), [2, 6, 7]);
+        // Too close to the end for one of its breaks
+        assert_eq!(hyphenator.breaks("table"), [2]);
+    }
+
+    #[test]
+    fn languages_fall_back_to_their_primary_code_and_aliases() {
+        assert_eq!(candidates("en_GB"), ["en-gb", "en", "en-us"]);
+        assert_eq!(
+            candidates("DE-CH"),
+            ["de-ch", "de-ch-1901", "de", "de-1996"]
+        );
+        assert_eq!(candidates("fr"), ["fr"]);
+    }
+
+    #[test]
+    fn pattern_files_are_loaded_with_their_lengths() {
+        let dir = std::env::temp_dir().join(format!("vlarch-hyphenation-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("hyph-en-us.pat.txt"), PATTERNS).unwrap();
+        std::fs::write(dir.join("hyph-sv.pat.txt"), PATTERNS).unwrap();
+
+        let english = load(&dir, "en-AU").unwrap().unwrap();
+        assert_eq!((english.min_before, english.min_after), (2, 3));
+        assert_eq!(english.breaks("hyphenation"), [2, 6]);
+        let swedish = load(&dir, "sv").unwrap().unwrap();
+        assert_eq!((swedish.min_before, swedish.min_after), (1, 2));
+        assert!(load(&dir, "fi").unwrap().is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,1284 @@
+//! The math mode of LaTeX, as books write it for MathJax and KaTeX: the
+//! commands of amsmath that turn up in books, the environments for
+//! matrices, cases and aligned equations, and the symbols of the standard
+//! fonts. Unknown commands are kept as text, so what is not understood
+//! still reads as what the author wrote.
+
+use super::layout::is_movable;
+use super::{Align, Kind, Node, Stretch, Token, Variant};
+use crate::error::{Error, Result};
+
+/// Symbols spaced as ordinary ones, mostly letters.
+const IDENTIFIERS: &[(&str, &str)] = &[
+    ("alpha", "α"),
+    ("beta", "β"),
+    ("gamma", "γ"),
+    ("delta", "δ"),
+    ("epsilon", "ϵ"),
+    ("varepsilon", "ε"),
+    ("zeta", "ζ"),
+    ("eta", "η"),
+    ("theta", "θ"),
+    ("vartheta", "ϑ"),
+    ("iota", "ι"),
+    ("kappa", "κ"),
+    ("varkappa", "ϰ"),
+    ("lambda", "λ"),
+    ("mu", "μ"),
+    ("nu", "ν"),
+    ("xi", "ξ"),
+    ("omicron", "ο"),
+    ("pi", "π"),
+    ("varpi", "ϖ"),
+    ("rho", "ρ"),
+    ("varrho", "ϱ"),
+    ("sigma", "σ"),
+    ("varsigma", "ς"),
+    ("tau", "τ"),
+    ("upsilon", "υ"),
+    ("phi", "ϕ"),
+    ("varphi", "φ"),
+    ("chi", "χ"),
+    ("psi", "ψ"),
+    ("omega", "ω"),
+    ("Gamma", "Γ"),
+    ("Delta", "Δ"),
+    ("Theta", "Θ"),
+    ("Lambda", "Λ"),
+    ("Xi", "Ξ"),
+    ("Pi", "Π"),
+    ("Sigma", "Σ"),
+    ("Upsilon", "Υ"),
+    ("Phi", "Φ"),
+    ("Psi", "Ψ"),
+    ("Omega", "Ω"),
+    ("infty", "∞"),
+    ("partial", "∂"),
+    ("nabla", "∇"),
+    ("emptyset", "∅"),
+    ("varnothing", "∅"),
+    ("forall", "∀"),
+    ("exists", "∃"),
+    ("nexists", "∄"),
+    ("neg", "¬"),
+    ("lnot", "¬"),
+    ("hbar", "ℏ"),
+    ("hslash", "ℏ"),
+    ("ell", "ℓ"),
+    ("wp", "℘"),
+    ("Re", "ℜ"),
+    ("Im", "ℑ"),
+    ("aleph", "ℵ"),
+    ("beth", "ℶ"),
+    ("imath", "ı"),
+    ("jmath", "ȷ"),
+    ("prime", "′"),
+    ("angle", "∠"),
+    ("measuredangle", "∡"),
+    ("triangle", "△"),
+    ("top", "⊤"),
+    ("bot", "⊥"),
+    ("surd", "√"),
+    ("flat", "♭"),
+    ("sharp", "♯"),
+    ("natural", "♮"),
+    ("clubsuit", "♣"),
+    ("diamondsuit", "♢"),
+    ("heartsuit", "♡"),
+    ("spadesuit", "♠"),
+    ("Box", "□"),
+    ("square", "□"),
+    ("blacksquare", "■"),
+    ("checkmark", "✓"),
+    ("degree", "°"),
+    ("backslash", "\\"),
+    ("S", "§"),
+    ("P", "¶"),
+    ("ldots", "…"),
+    ("dots", "…"),
+    ("dotsc", "…"),
+    ("dotso", "…"),
+    ("cdots", "⋯"),
+    ("dotsb", "⋯"),
+    ("dotsm", "⋯"),
+    ("vdots", "⋮"),
+    ("ddots", "⋱"),
+];
+
+const OPERATORS: &[(&str, &str)] = &[
+    ("leq", "≤"),
+    ("le", "≤"),
+    ("geq", "≥"),
+    ("ge", "≥"),
+    ("leqslant", "⩽"),
+    ("geqslant", "⩾"),
+    ("neq", "≠"),
+    ("ne", "≠"),
+    ("approx", "≈"),
+    ("equiv", "≡"),
+    ("sim", "∼"),
+    ("simeq", "≃"),
+    ("cong", "≅"),
+    ("propto", "∝"),
+    ("in", "∈"),
+    ("notin", "∉"),
+    ("ni", "∋"),
+    ("subset", "⊂"),
+    ("supset", "⊃"),
+    ("subseteq", "⊆"),
+    ("supseteq", "⊇"),
+    ("subsetneq", "⊊"),
+    ("supsetneq", "⊋"),
+    ("ll", "≪"),
+    ("gg", "≫"),
+    ("prec", "≺"),
+    ("succ", "≻"),
+    ("preceq", "⪯"),
+    ("succeq", "⪰"),
+    ("mid", "∣"),
+    ("nmid", "∤"),
+    ("parallel", "∥"),
+    ("nparallel", "∦"),
+    ("perp", "⊥"),
+    ("to", "→"),
+    ("rightarrow", "→"),
+    ("leftarrow", "←"),
+    ("gets", "←"),
+    ("leftrightarrow", "↔"),
+    ("Rightarrow", "⇒"),
+    ("Leftarrow", "⇐"),
+    ("Leftrightarrow", "⇔"),
+    ("implies", "⟹"),
+    ("impliedby", "⟸"),
+    ("iff", "⟺"),
+    ("mapsto", "↦"),
+    ("longrightarrow", "⟶"),
+    ("longleftarrow", "⟵"),
+    ("longleftrightarrow", "⟷"),
+    ("Longrightarrow", "⟹"),
+    ("Longleftarrow", "⟸"),
+    ("Longleftrightarrow", "⟺"),
+    ("longmapsto", "⟼"),
+    ("uparrow", "↑"),
+    ("downarrow", "↓"),
+    ("updownarrow", "↕"),
+    ("Uparrow", "⇑"),
+    ("Downarrow", "⇓"),
+    ("nearrow", "↗"),
+    ("searrow", "↘"),
+    ("hookrightarrow", "↪"),
+    ("hookleftarrow", "↩"),
+    ("rightharpoonup", "⇀"),
+    ("leftharpoonup", "↼"),
+    ("rightleftharpoons", "⇌"),
+    ("doteq", "≐"),
+    ("coloneqq", "≔"),
+    ("asymp", "≍"),
+    ("lesssim", "≲"),
+    ("gtrsim", "≳"),
+    ("vdash", "⊢"),
+    ("dashv", "⊣"),
+    ("models", "⊨"),
+    ("sqsubset", "⊏"),
+    ("sqsupset", "⊐"),
+    ("sqsubseteq", "⊑"),
+    ("sqsupseteq", "⊒"),
+    ("bowtie", "⋈"),
+    ("smile", "⌣"),
+    ("frown", "⌢"),
+    ("triangleq", "≜"),
+    ("nleq", "≰"),
+    ("ngeq", "≱"),
+    ("nless", "≮"),
+    ("ngtr", "≯"),
+    ("ncong", "≇"),
+    ("nsim", "≁"),
+    ("colon", ":"),
+    ("pm", "±"),
+    ("mp", "∓"),
+    ("times", "×"),
+    ("div", "÷"),
+    ("cdot", "⋅"),
+    ("ast", "∗"),
+    ("star", "⋆"),
+    ("circ", "∘"),
+    ("bullet", "∙"),
+    ("cap", "∩"),
+    ("cup", "∪"),
+    ("uplus", "⊎"),
+    ("sqcap", "⊓"),
+    ("sqcup", "⊔"),
+    ("wedge", "∧"),
+    ("land", "∧"),
+    ("vee", "∨"),
+    ("lor", "∨"),
+    ("oplus", "⊕"),
+    ("ominus", "⊖"),
+    ("otimes", "⊗"),
+    ("oslash", "⊘"),
+    ("odot", "⊙"),
+    ("setminus", "∖"),
+    ("diamond", "⋄"),
+    ("bigtriangleup", "△"),
+    ("bigtriangledown", "▽"),
+    ("triangleleft", "◁"),
+    ("triangleright", "▷"),
+    ("dagger", "†"),
+    ("ddagger", "‡"),
+    ("wr", "≀"),
+    ("amalg", "⨿"),
+    ("langle", "⟨"),
+    ("rangle", "⟩"),
+    ("lceil", "⌈"),
+    ("rceil", "⌉"),
+    ("lfloor", "⌊"),
+    ("rfloor", "⌋"),
+    ("lvert", "|"),
+    ("rvert", "|"),
+    ("vert", "|"),
+    ("lVert", "‖"),
+    ("rVert", "‖"),
+    ("Vert", "‖"),
+    ("lbrace", "{"),
+    ("rbrace", "}"),
+    ("lbrack", "["),
+    ("rbrack", "]"),
+    ("llbracket", "⟦"),
+    ("rrbracket", "⟧"),
+    ("sum", "∑"),
+    ("prod", "∏"),
+    ("coprod", "∐"),
+    ("bigcup", "⋃"),
+    ("bigcap", "⋂"),
+    ("bigvee", "⋁"),
+    ("bigwedge", "⋀"),
+    ("bigoplus", "⨁"),
+    ("bigotimes", "⨂"),
+    ("bigodot", "⨀"),
+    ("biguplus", "⨄"),
+    ("bigsqcup", "⨆"),
+    ("int", "∫"),
+    ("iint", "∬"),
+    ("iiint", "∭"),
+    ("oint", "∮"),
+];
+
+/// Names of functions, set upright.
+const FUNCTIONS: &[(&str, &str)] = &[
+    ("arccos", "arccos"),
+    ("arcsin", "arcsin"),
+    ("arctan", "arctan"),
+    ("arg", "arg"),
+    ("cos", "cos"),
+    ("cosh", "cosh"),
+    ("cot", "cot"),
+    ("coth", "coth"),
+    ("csc", "csc"),
+    ("deg", "deg"),
+    ("det", "det"),
+    ("dim", "dim"),
+    ("exp", "exp"),
+    ("gcd", "gcd"),
+    ("hom", "hom"),
+    ("inf", "inf"),
+    ("ker", "ker"),
+    ("lg", "lg"),
+    ("lim", "lim"),
+    ("liminf", "lim inf"),
+    ("limsup", "lim sup"),
+    ("ln", "ln"),
+    ("log", "log"),
+    ("max", "max"),
+    ("min", "min"),
+    ("Pr", "Pr"),
+    ("sec", "sec"),
+    ("sin", "sin"),
+    ("sinh", "sinh"),
+    ("sup", "sup"),
+    ("tan", "tan"),
+    ("tanh", "tanh"),
+    ("argmax", "arg max"),
+    ("argmin", "arg min"),
+];
+
+/// Accents over or under what follows them: the command, the character,
+/// whether it grows to the width of what it accents, and whether it goes
+/// under.
+const ACCENTS: &[(&str, &str, bool, bool)] = &[
+    ("hat", "ˆ", false, false),
+    ("widehat", "ˆ", true, false),
+    ("check", "ˇ", false, false),
+    ("widecheck", "ˇ", true, false),
+    ("tilde", "˜", false, false),
+    ("widetilde", "˜", true, false),
+    ("bar", "¯", false, false),
+    ("vec", "→", false, false),
+    ("dot", "˙", false, false),
+    ("ddot", "¨", false, false),
+    ("acute", "´", false, false),
+    ("grave", "`", false, false),
+    ("breve", "˘", false, false),
+    ("mathring", "˚", false, false),
+    ("overline", "‾", true, false),
+    ("underline", "_", true, true),
+    ("overrightarrow", "→", true, false),
+    ("overleftarrow", "←", true, false),
+    ("overleftrightarrow", "↔", true, false),
+    ("underrightarrow", "→", true, true),
+    ("underleftarrow", "←", true, true),
+];
+
+/// Negated relations for `\not`.
+const NEGATIONS: &[(char, char)] = &[
+    ('=', '≠'),
+    ('<', '≮'),
+    ('>', '≯'),
+    ('≤', '≰'),
+    ('≥', '≱'),
+    ('∈', '∉'),
+    ('∋', '∌'),
+    ('⊂', '⊄'),
+    ('⊃', '⊅'),
+    ('⊆', '⊈'),
+    ('⊇', '⊉'),
+    ('≡', '≢'),
+    ('∼', '≁'),
+    ('≅', '≇'),
+    ('∣', '∤'),
+    ('∥', '∦'),
+];
+
+fn error(message: impl Into<String>) -> Error {
+    Error::Math(message.into())
+}
+
+/// What a list of atoms stopped at.
+#[derive(Debug)]
+enum End {
+    Input,
+    Group,
+    Cell,
+    Row,
+    /// `\right` and its delimiter, `None` for `.`.
+    Right(Option<String>),
+    Env(String),
+}
+
+fn unexpected(end: &End) -> Error {
+    error(match end {
+        End::Input => "unexpected end of input".to_string(),
+        End::Group => "unexpected }".to_string(),
+        End::Cell => "& outside of an environment".to_string(),
+        End::Row => "\\\\ outside of an environment".to_string(),
+        End::Right(_) => "\\right without \\left".to_string(),
+        End::Env(name) => format!("\\end{{{name}}} without \\begin{{{name}}}"),
+    })
+}
+
+fn fence(text: &str) -> Node {
+    Node::Token(Token {
+        kind: Kind::Operator,
+        text: text.to_string(),
+        variant: Variant::Auto,
+        stretch: Stretch::Fit,
+    })
+}
+
+fn is_empty(node: &Node) -> bool {
+    matches!(node, Node::Row(nodes) if nodes.is_empty())
+}
+
+fn symbol(name: &str) -> Option<Node> {
+    let find = |table: &[(&str, &'static str)]| {
+        table
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|&(_, text)| text)
+    };
+    if let Some(text) = find(IDENTIFIERS) {
+        Some(Node::token(Kind::Identifier, text))
+    } else if let Some(text) = find(OPERATORS) {
+        Some(Node::token(Kind::Operator, text))
+    } else {
+        find(FUNCTIONS).map(|text| Node::token(Kind::Function, text))
+    }
+}
+
+/// Width of space commands, in ems.
+fn space(name: &str) -> Option<f32> {
+    Some(match name {
+        "," | "thinspace" => 1.0 / 6.0,
+        ":" | ">" | "medspace" => 2.0 / 9.0,
+        ";" | "thickspace" => 5.0 / 18.0,
+        "!" | "negthinspace" => -1.0 / 6.0,
+        "negmedspace" => -2.0 / 9.0,
+        "negthickspace" => -5.0 / 18.0,
+        " " => 0.25,
+        "enspace" => 0.5,
+        "quad" => 1.0,
+        "qquad" => 2.0,
+        _ => return None,
+    })
+}
+
+/// A TeX length such as `3mu` or `0.5em`, in ems.
+fn length(text: &str) -> Option<f32> {
+    let text = text.trim();
+    let unit_start = text
+        .find(|c: char| c.is_ascii_alphabetic())
+        .unwrap_or(text.len());
+    let value = text[..unit_start].replace(' ', "").parse::<f32>().ok()?;
+    let unit = match text[unit_start..].trim() {
+        "em" => 1.0,
+        "ex" => 0.43,
+        "mu" => 1.0 / 18.0,
+        "pt" => 0.1,
+        "px" => 1.0 / 16.0,
+        "mm" => 0.285,
+        "cm" => 2.85,
+        "in" => 7.2,
+        _ => return None,
+    };
+    Some(value * unit)
+}
+
+/// Text of `\text`, with the escapes of text mode undone.
+fn text_of(raw: &str) -> String {
+    let mut out = String::with_capacity(raw.len());
+    let mut chars = raw.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(' ') | None => out.push(' '),
+                Some(c) if c.is_ascii_alphabetic() => {
+                    // Commands in text are dropped, their arguments kept
+                    while chars.peek().is_some_and(char::is_ascii_alphabetic) {
+                        chars.next();
+                    }
+                }
+                Some(c) => out.push(c),
+            },
+            '~' => out.push('\u{a0}'),
+            '{' | '}' | '$' => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Cells of `aligned` and its kin: right and left aligned in turn, with
+/// room between the pairs, and relations starting the left aligned ones
+/// spaced as if what is left of them came before.
+fn aligned(rows: Vec<Vec<Node>>) -> Vec<Vec<Node>> {
+    rows.into_iter()
+        .map(|row| {
+            row.into_iter()
+                .enumerate()
+                .map(|(i, cell)| match cell {
+                    Node::Row(mut nodes) if i % 2 == 1 => {
+                        nodes.insert(0, Node::Row(Vec::new()));
+                        Node::Row(nodes)
+                    }
+                    cell if i > 0 => Node::Row(vec![Node::Space(2.0), cell]),
+                    cell => cell,
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn alternating(rows: &[Vec<Node>]) -> Vec<Align> {
+    let columns = rows.iter().map(Vec::len).max().unwrap_or(1);
+    (0..columns)
+        .map(|i| match i % 2 {
+            0 => Align::Right,
+            _ => Align::Left,
+        })
+        .collect()
+}
+
+struct Parser {
+    src: Vec<char>,
+    pos: usize,
+    /// Of letters and digits, set by `\mathbf` and the like.
+    variant: Variant,
+    /// Set by `\operatorname*` for what follows to take limits.
+    limits: bool,
+}
+
+impl Parser {
+    fn new(tex: &str) -> Parser {
+        Parser {
+            src: tex.chars().collect(),
+            pos: 0,
+            variant: Variant::Auto,
+            limits: false,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src.get(self.pos).copied()
+    }
+
+    fn skip_spaces(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == '%' {
+                while self.peek().is_some_and(|c| c != '\n') {
+                    self.pos += 1;
+                }
+            } else if c.is_whitespace() {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// The name of the command whose backslash was just read.
+    fn command_name(&mut self) -> String {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_alphabetic()) {
+            self.pos += 1;
+        }
+        if self.pos == start && self.peek().is_some() {
+            self.pos += 1;
+        }
+        self.src[start..self.pos].iter().collect()
+    }
+
+    /// Reads command `\name` if it comes next.
+    fn eat_command(&mut self, name: &str) -> bool {
+        self.skip_spaces();
+        let len = name.chars().count();
+        let matches = self.peek() == Some('\\')
+            && self.src[self.pos + 1..]
+                .iter()
+                .take(len)
+                .copied()
+                .eq(name.chars())
+            && !self
+                .src
+                .get(self.pos + 1 + len)
+                .is_some_and(char::is_ascii_alphabetic);
+        if matches {
+            self.pos += 1 + len;
+        }
+        matches
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_spaces();
+        let matches = self.peek() == Some(c);
+        if matches {
+            self.pos += 1;
+        }
+        matches
+    }
+
+    /// The raw text between braces, or of the single character or command
+    /// there is instead.
+    fn braced_text(&mut self) -> Result<String> {
+        self.skip_spaces();
+        match self.peek() {
+            Some('{') => {}
+            Some('\\') => {
+                self.pos += 1;
+                return Ok(format!("\\{}", self.command_name()));
+            }
+            Some(c) => {
+                self.pos += 1;
+                return Ok(c.to_string());
+            }
+            None => return Err(error("missing argument")),
+        }
+        self.pos += 1;
+        let start = self.pos;
+        let mut depth = 0;
+        while let Some(c) = self.peek() {
+            match c {
+                '\\' => self.pos += 1,
+                '{' => depth += 1,
+                '}' if depth == 0 => {
+                    let text = self.src[start..self.pos].iter().collect();
+                    self.pos += 1;
+                    return Ok(text);
+                }
+                '}' => depth -= 1,
+                _ => {}
+            }
+            self.pos += 1;
+        }
+        Err(error("unterminated group"))
+    }
+
+    /// The raw text of an optional argument in brackets.
+    fn optional_text(&mut self) -> Option<String> {
+        if !self.eat('[') {
+            return None;
+        }
+        let start = self.pos;
+        let mut depth = 0;
+        while let Some(c) = self.peek() {
+            match c {
+                '\\' => self.pos += 1,
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                ']' if depth == 0 => {
+                    let text = self.src[start..self.pos].iter().collect();
+                    self.pos += 1;
+                    return Some(text);
+                }
+                _ => {}
+            }
+            self.pos += 1;
+        }
+        Some(self.src[start..].iter().collect())
+    }
+
+    /// The length after `\kern` and its kin, which is not braced.
+    fn inline_length(&mut self) -> f32 {
+        self.skip_spaces();
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_digit() || matches!(c, '.' | '-' | '+' | ' '))
+        {
+            self.pos += 1;
+        }
+        let mut units = 0;
+        while units < 2 && self.peek().is_some_and(|c| c.is_ascii_alphabetic()) {
+            self.pos += 1;
+            units += 1;
+        }
+        let text = self.src[start..self.pos].iter().collect::<String>();
+        length(&text).unwrap_or(0.0)
+    }
+
+    fn group(&mut self) -> Result<Node> {
+        // The group was opened by the caller
+        let variant = self.variant;
+        let (nodes, end) = self.list()?;
+        self.variant = variant;
+        match end {
+            End::Group => Ok(Node::Row(nodes)),
+            End::Input => Err(error("unterminated group")),
+            end => Err(unexpected(&end)),
+        }
+    }
+
+    /// The argument of a command: a group, or a single character or
+    /// command.
+    fn argument(&mut self) -> Result<Node> {
+        self.skip_spaces();
+        match self.peek() {
+            None => Err(error("missing argument")),
+            Some('{') => {
+                self.pos += 1;
+                self.group()
+            }
+            Some('\\') => {
+                self.pos += 1;
+                let name = self.command_name();
+                Ok(self.command(&name)?.unwrap_or(Node::Row(Vec::new())))
+            }
+            Some(c) => {
+                self.pos += 1;
+                Ok(self.character(c, false))
+            }
+        }
+    }
+
+    /// Atom of character `c`, just read, with the digits that follow it
+    /// for numbers unless `single`.
+    fn character(&mut self, c: char, single: bool) -> Node {
+        let variant = self.variant;
+        let token = |kind, text: String| {
+            Node::Token(Token {
+                kind,
+                text,
+                variant,
+                stretch: Stretch::No,
+            })
+        };
+        match c {
+            '0'..='9' => {
+                let mut text = c.to_string();
+                while let (false, Some(next)) = (single, self.peek()) {
+                    match (next, self.src.get(self.pos + 1)) {
+                        (d, _) if d.is_ascii_digit() => text.push(d),
+                        ('.', Some(d)) if d.is_ascii_digit() => text.push('.'),
+                        _ => break,
+                    }
+                    self.pos += 1;
+                }
+                token(Kind::Number, text)
+            }
+            c if c.is_alphabetic() => token(Kind::Identifier, c.to_string()),
+            '~' => Node::Space(0.33),
+            '-' => Node::token(Kind::Operator, "−"),
+            '*' => Node::token(Kind::Operator, "∗"),
+            '\'' => Node::token(Kind::Operator, "′"),
+            c => Node::token(Kind::Operator, c.to_string()),
+        }
+    }
+
+    /// Atoms up to the end of the group, cell, row or environment they are
+    /// in.
+    fn list(&mut self) -> Result<(Vec<Node>, End)> {
+        let mut nodes = Vec::new();
+        // What is before `\over` and its kin, and which it was
+        let mut over: Option<(Vec<Node>, String)> = None;
+        let end = loop {
+            self.skip_spaces();
+            let Some(c) = self.peek() else {
+                break End::Input;
+            };
+            self.pos += 1;
+            let node = match c {
+                '}' => break End::Group,
+                '&' => break End::Cell,
+                '{' => self.group()?,
+                // Scripts with nothing to attach to
+                '^' | '_' => {
+                    self.pos -= 1;
+                    Node::Row(Vec::new())
+                }
+                '\\' => {
+                    let name = self.command_name();
+                    match name.as_str() {
+                        "\\" | "cr" | "newline" => {
+                            self.optional_text();
+                            break End::Row;
+                        }
+                        "right" => break End::Right(self.delimiter()?),
+                        "end" => break End::Env(self.braced_text()?),
+                        "displaystyle" | "textstyle" | "scriptstyle" | "scriptscriptstyle" => {
+                            let (rest, end) = self.list()?;
+                            nodes.push(Node::Style {
+                                display: name == "displaystyle",
+                                body: Box::new(Node::Row(rest)),
+                            });
+                            break end;
+                        }
+                        "over" | "choose" | "atop" => {
+                            over = Some((std::mem::take(&mut nodes), name));
+                            continue;
+                        }
+                        "rm" | "it" | "bf" | "cal" | "sf" | "tt" => {
+                            self.variant = match name.as_str() {
+                                "rm" => Variant::Normal,
+                                "it" => Variant::Italic,
+                                "bf" => Variant::Bold,
+                                "cal" => Variant::Script,
+                                "sf" => Variant::SansSerif,
+                                _ => Variant::Monospace,
+                            };
+                            continue;
+                        }
+                        _ => match self.command(&name)? {
+                            Some(node) => node,
+                            None => continue,
+                        },
+                    }
+                }
+                c => self.character(c, false),
+            };
+            let node = self.attach(node)?;
+            nodes.push(node);
+        };
+        if let Some((num, kind)) = over {
+            let frac = Node::Frac {
+                num: Box::new(Node::Row(num)),
+                den: Box::new(Node::Row(std::mem::take(&mut nodes))),
+                line: kind == "over",
+            };
+            nodes = match kind.as_str() {
+                "choose" => vec![fence("("), frac, fence(")")],
+                _ => vec![frac],
+            };
+        }
+        Ok((nodes, end))
+    }
+
+    /// `base` with the scripts and primes that follow it.
+    fn attach(&mut self, base: Node) -> Result<Node> {
+        let takes_limits = std::mem::take(&mut self.limits);
+        let mut limits = None;
+        let mut sub = None;
+        let mut sup = None;
+        let mut primes = 0;
+        loop {
+            self.skip_spaces();
+            match self.peek() {
+                Some('^') if sup.is_none() => {
+                    self.pos += 1;
+                    sup = Some(self.argument()?);
+                }
+                Some('_') if sub.is_none() => {
+                    self.pos += 1;
+                    sub = Some(self.argument()?);
+                }
+                Some('\'') if sup.is_none() => {
+                    self.pos += 1;
+                    primes += 1;
+                }
+                Some('\\') if self.eat_command("limits") => limits = Some(true),
+                Some('\\') if self.eat_command("nolimits") => limits = Some(false),
+                _ => break,
+            }
+        }
+        if primes > 0 {
+            let prime = Node::token(Kind::Operator, "′".repeat(primes));
+            sup = Some(match sup {
+                Some(sup) => Node::Row(vec![prime, sup]),
+                None => prime,
+            });
+        }
+        if sub.is_none() && sup.is_none() {
+            return Ok(base);
+        }
+        let movable = takes_limits
+            || match &base {
+                Node::Token(token) => {
+                    matches!(token.kind, Kind::Operator | Kind::Function) && is_movable(&token.text)
+                }
+                _ => false,
+            };
+        let (sub, sup) = (sub.map(Box::new), sup.map(Box::new));
+        let base = Box::new(base);
+        Ok(match limits.unwrap_or(movable) {
+            true => Node::UnderOver {
+                base,
+                under: sub,
+                over: sup,
+                movable: limits.is_none(),
+                accent: false,
+            },
+            false => Node::Scripts { base, sub, sup },
+        })
+    }
+
+    /// The delimiter after `\left` and its kin, `None` for `.`.
+    fn delimiter(&mut self) -> Result<Option<String>> {
+        self.skip_spaces();
+        let Some(c) = self.peek() else {
+            return Err(error("missing delimiter"));
+        };
+        self.pos += 1;
+        Ok(match c {
+            '.' => None,
+            '<' => Some("⟨".to_string()),
+            '>' => Some("⟩".to_string()),
+            '\\' => {
+                let name = self.command_name();
+                match name.as_str() {
+                    "{" | "}" => Some(name),
+                    "|" => Some("‖".to_string()),
+                    _ => match symbol(&name) {
+                        Some(Node::Token(token)) => Some(token.text),
+                        _ => return Err(error(format!("\\{name} is not a delimiter"))),
+                    },
+                }
+            }
+            c => Some(c.to_string()),
+        })
+    }
+
+    /// Rows of cells up to where `done` says they end.
+    fn rows(&mut self, done: impl Fn(&End) -> bool) -> Result<Vec<Vec<Node>>> {
+        let mut rows = Vec::new();
+        let mut row = Vec::new();
+        loop {
+            let (nodes, end) = self.list()?;
+            row.push(Node::Row(nodes));
+            match end {
+                End::Cell => {}
+                End::Row => rows.push(std::mem::take(&mut row)),
+                end if done(&end) => break,
+                end => return Err(unexpected(&end)),
+            }
+        }
+        // Rows often end with `\\`, which starts no new one
+        if rows.is_empty() || !(row.len() == 1 && is_empty(&row[0])) {
+            rows.push(row);
+        }
+        Ok(rows)
+    }
+
+    fn environment(&mut self, name: &str) -> Result<Node> {
+        let base = name.trim_end_matches('*');
+        let spec = match base {
+            "array" | "subarray" => {
+                self.optional_text();
+                Some(self.braced_text()?)
+            }
+            "alignat" | "alignedat" => {
+                self.braced_text()?;
+                None
+            }
+            "aligned" | "gathered" => {
+                self.optional_text();
+                None
+            }
+            _ => None,
+        };
+        let rows = self.rows(|end| matches!(end, End::Env(end) if end == name))?;
+        let (rows, align, spacing) = match base {
+            "cases" | "dcases" | "rcases" => (rows, vec![Align::Left], 1.0),
+            "aligned" | "align" | "alignat" | "alignedat" | "split" | "eqnarray" | "flalign" => {
+                let align = alternating(&rows);
+                (aligned(rows), align, 0.0)
+            }
+            "gather" | "gathered" | "equation" | "multline" => {
+                if rows.len() == 1 && rows[0].len() == 1 {
+                    return Ok(rows
+                        .into_iter()
+                        .flatten()
+                        .next()
+                        .unwrap_or(Node::Space(0.0)));
+                }
+                (rows, vec![Align::Center], 0.0)
+            }
+            "array" | "subarray" => {
+                let align = spec
+                    .unwrap_or_default()
+                    .chars()
+                    .filter_map(|c| match c {
+                        'l' => Some(Align::Left),
+                        'c' => Some(Align::Center),
+                        'r' => Some(Align::Right),
+                        _ => None,
+                    })
+                    .collect();
+                (rows, align, 1.0)
+            }
+            _ => (rows, vec![Align::Center], 1.0),
+        };
+        let table = Node::Table {
+            rows,
+            align,
+            spacing,
+        };
+        let (open, close) = match base {
+            "pmatrix" => ("(", ")"),
+            "bmatrix" => ("[", "]"),
+            "Bmatrix" => ("{", "}"),
+            "vmatrix" => ("|", "|"),
+            "Vmatrix" => ("‖", "‖"),
+            "cases" | "dcases" => ("{", ""),
+            "rcases" => ("", "}"),
+            _ => return Ok(table),
+        };
+        let mut nodes = Vec::new();
+        if !open.is_empty() {
+            nodes.push(fence(open));
+        }
+        nodes.push(table);
+        if !close.is_empty() {
+            nodes.push(fence(close));
+        }
+        Ok(Node::Row(nodes))
+    }
+
+    /// The atom of command `\name`, just read, `None` for those that make
+    /// none.
+    fn command(&mut self, name: &str) -> Result<Option<Node>> {
+        if let Some(width) = space(name) {
+            return Ok(Some(Node::Space(width)));
+        }
+        if let Some(node) = symbol(name) {
+            return Ok(Some(node));
+        }
+        if let Some(&(_, text, wide, under)) = ACCENTS.iter().find(|(n, ..)| *n == name) {
+            let base = Box::new(self.argument()?);
+            let accent = Some(Box::new(Node::Token(Token {
+                kind: Kind::Operator,
+                text: text.to_string(),
+                variant: Variant::Auto,
+                stretch: if wide { Stretch::Fit } else { Stretch::No },
+            })));
+            let (under, over) = if under {
+                (accent, None)
+            } else {
+                (None, accent)
+            };
+            return Ok(Some(Node::UnderOver {
+                base,
+                under,
+                over,
+                movable: false,
+                accent: true,
+            }));
+        }
+        let node = match name {
+            "frac" | "dfrac" | "tfrac" | "cfrac" | "binom" | "dbinom" | "tbinom" => {
+                let num = Box::new(self.argument()?);
+                let den = Box::new(self.argument()?);
+                let binom = name.ends_with("binom");
+                let frac = Node::Frac {
+                    num,
+                    den,
+                    line: !binom,
+                };
+                let frac = match name {
+                    "dfrac" | "cfrac" | "dbinom" => Node::Style {
+                        display: true,
+                        body: Box::new(frac),
+                    },
+                    "tfrac" | "tbinom" => Node::Style {
+                        display: false,
+                        body: Box::new(frac),
+                    },
+                    _ => frac,
+                };
+                match binom {
+                    true => Node::Row(vec![fence("("), frac, fence(")")]),
+                    false => frac,
+                }
+            }
+            "sqrt" => {
+                let index = self
+                    .optional_text()
+                    .map(|index| parse(&index))
+                    .transpose()?
+                    .map(Box::new);
+                Node::Root {
+                    base: Box::new(self.argument()?),
+                    index,
+                }
+            }
+            "left" => {
+                let open = self.delimiter()?;
+                let (body, end) = self.list()?;
+                let End::Right(close) = end else {
+                    return Err(error("\\left without \\right"));
+                };
+                let mut nodes = Vec::new();
+                nodes.extend(open.as_deref().map(fence));
+                nodes.extend(body);
+                nodes.extend(close.as_deref().map(fence));
+                Node::Row(nodes)
+            }
+            "middle" => match self.delimiter()? {
+                Some(delimiter) => fence(&delimiter),
+                None => return Ok(None),
+            },
+            "big" | "Big" | "bigg" | "Bigg" | "bigl" | "Bigl" | "biggl" | "Biggl" | "bigr"
+            | "Bigr" | "biggr" | "Biggr" | "bigm" | "Bigm" | "biggm" | "Biggm" => {
+                let height = match name.trim_end_matches(['l', 'r', 'm']) {
+                    "big" => 1.2,
+                    "Big" => 1.8,
+                    "bigg" => 2.4,
+                    _ => 3.0,
+                };
+                match self.delimiter()? {
+                    Some(text) => Node::Token(Token {
+                        kind: Kind::Operator,
+                        text,
+                        variant: Variant::Auto,
+                        stretch: Stretch::To(height),
+                    }),
+                    None => return Ok(None),
+                }
+            }
+            "text" | "textrm" | "textnormal" | "textup" | "mbox" | "hbox" | "textit" | "emph"
+            | "textbf" | "textsf" | "texttt" => {
+                let variant = match name {
+                    "textit" | "emph" => Variant::Italic,
+                    "textbf" => Variant::Bold,
+                    "textsf" => Variant::SansSerif,
+                    "texttt" => Variant::Monospace,
+                    _ => Variant::Normal,
+                };
+                Node::Token(Token {
+                    kind: Kind::Text,
+                    text: text_of(&self.braced_text()?),
+                    variant,
+                    stretch: Stretch::No,
+                })
+            }
+            "operatorname" | "DeclareMathOperator" => {
+                self.limits = self.eat('*');
+                Node::Token(Token {
+                    kind: Kind::Function,
+                    text: text_of(&self.braced_text()?),
+                    variant: Variant::Normal,
+                    stretch: Stretch::No,
+                })
+            }
+            "mathrm" | "mathup" | "mathit" | "mathbf" | "boldsymbol" | "bm" | "mathbb"
+            | "mathcal" | "mathscr" | "mathfrak" | "mathsf" | "mathtt" => {
+                let variant = self.variant;
+                self.variant = match name {
+                    "mathrm" | "mathup" => Variant::Normal,
+                    "mathit" => Variant::Italic,
+                    "mathbf" => Variant::Bold,
+                    "boldsymbol" | "bm" => Variant::BoldItalic,
+                    "mathbb" => Variant::DoubleStruck,
+                    "mathcal" | "mathscr" => Variant::Script,
+                    "mathfrak" => Variant::Fraktur,
+                    "mathsf" => Variant::SansSerif,
+                    _ => Variant::Monospace,
+                };
+                let node = self.argument();
+                self.variant = variant;
+                node?
+            }
+            "overbrace" | "underbrace" => {
+                let base = Box::new(self.argument()?);
+                let over = name == "overbrace";
+                let brace = Some(Box::new(fence(if over { "⏞" } else { "⏟" })));
+                let (under, over_brace) = if over { (None, brace) } else { (brace, None) };
+                let braced = Node::UnderOver {
+                    base,
+                    under,
+                    over: over_brace,
+                    movable: false,
+                    accent: true,
+                };
+                // The script on the side of the brace labels it
+                if self.eat(if over { '^' } else { '_' }) {
+                    let label = Some(Box::new(self.argument()?));
+                    let (under, over) = if over { (None, label) } else { (label, None) };
+                    Node::UnderOver {
+                        base: Box::new(braced),
+                        under,
+                        over,
+                        movable: false,
+                        accent: false,
+                    }
+                } else {
+                    braced
+                }
+            }
+            "overset" | "stackrel" | "underset" => {
+                let limit = Some(Box::new(self.argument()?));
+                let base = Box::new(self.argument()?);
+                let (under, over) = match name {
+                    "underset" => (limit, None),
+                    _ => (None, limit),
+                };
+                Node::UnderOver {
+                    base,
+                    under,
+                    over,
+                    movable: false,
+                    accent: false,
+                }
+            }
+            "xrightarrow" | "xleftarrow" => {
+                let under = self
+                    .optional_text()
+                    .map(|under| parse(&under))
+                    .transpose()?
+                    .map(Box::new);
+                let over = Some(Box::new(self.argument()?));
+                let arrow = if name == "xrightarrow" { "⟶" } else { "⟵" };
+                Node::UnderOver {
+                    base: Box::new(fence(arrow)),
+                    under,
+                    over,
+                    movable: false,
+                    accent: false,
+                }
+            }
+            "substack" => {
+                if !self.eat('{') {
+                    return Err(error("missing argument"));
+                }
+                let variant = self.variant;
+                let rows = self.rows(|end| matches!(end, End::Group))?;
+                self.variant = variant;
+                Node::Table {
+                    rows,
+                    align: vec![Align::Center],
+                    spacing: 0.0,
+                }
+            }
+            "phantom" | "hphantom" | "vphantom" => Node::Phantom(Box::new(self.argument()?)),
+            "mathstrut" => Node::Phantom(Box::new(Node::token(Kind::Operator, "("))),
+            "not" => match self.argument()? {
+                Node::Token(mut token) => {
+                    let mut chars = token.text.chars();
+                    token.text = match (chars.next(), chars.next()) {
+                        (Some(c), None) => match NEGATIONS.iter().find(|&&(from, _)| from == c) {
+                            Some(&(_, negated)) => negated.to_string(),
+                            None => format!("{c}\u{338}"),
+                        },
+                        _ => token.text,
+                    };
+                    Node::Token(token)
+                }
+                node => node,
+            },
+            "pmod" | "pod" => {
+                let argument = self.argument()?;
+                let mut nodes = vec![Node::Space(1.0), Node::token(Kind::Operator, "(")];
+                if name == "pmod" {
+                    nodes.push(Node::token(Kind::Function, "mod"));
+                    nodes.push(Node::Space(1.0 / 3.0));
+                }
+                nodes.extend([argument, Node::token(Kind::Operator, ")")]);
+                Node::Row(nodes)
+            }
+            "bmod" | "mod" => Node::Row(vec![
+                Node::Space(5.0 / 18.0),
+                Node::token(Kind::Function, "mod"),
+                Node::Space(5.0 / 18.0),
+            ]),
+            "mathop" | "mathbin" | "mathrel" | "mathord" | "mathopen" | "mathclose"
+            | "mathpunct" | "boxed" | "fbox" => self.argument()?,
+            "textcolor" | "colorbox" => {
+                self.braced_text()?;
+                self.argument()?
+            }
+            "color" | "tag" | "label" | "eqref" | "ref" => {
+                self.eat('*');
+                self.braced_text()?;
+                return Ok(None);
+            }
+            "hspace" | "mspace" | "hskip" | "mskip" | "kern" | "mkern" => {
+                self.eat('*');
+                self.skip_spaces();
+                let width = if self.peek() == Some('{') {
+                    length(&self.braced_text()?).unwrap_or(0.0)
+                } else {
+                    self.inline_length()
+                };
+                Node::Space(width)
+            }
+            "begin" => {
+                let env = self.braced_text()?;
+                self.environment(&env)?
+            }
+            "hline" | "nonumber" | "notag" | "protect" | "allowbreak" | "nobreak" | "limits"
+            | "nolimits" | "displaylimits" | "strut" | "relax" => return Ok(None),
+            "{" | "}" | "%" | "$" | "&" | "#" | "_" => Node::token(Kind::Operator, name),
+            "|" => Node::token(Kind::Operator, "‖"),
+            // Kept as written, for the reader to make out
+            _ => Node::Token(Token {
+                kind: Kind::Text,
+                text: format!("\\{name}"),
+                variant: Variant::Normal,
+                stretch: Stretch::No,
+            }),
+        };
+        Ok(Some(node))
+    }
+}
+
+/// The tree of LaTeX math `tex`, without its delimiters.
+pub fn parse(tex: &str) -> Result<Node> {
+    let mut parser = Parser::new(tex);
+    let mut rows = parser.rows(|end| matches!(end, End::Input))?;
+    if rows.len() == 1 && rows[0].len() == 1 {
+        return Ok(rows.remove(0).remove(0));
+    }
+    // `&` and `\\` outside of an environment, lines aligned as in `aligned`
+    let align = alternating(&rows);
+    Ok(Node::Table {
+        rows: aligned(rows),
+        align,
+        spacing: 0.0,
+    })
+}
@@ -0,0 +1,1008 @@
+//! Layout of math as boxes, in ems of the text around it, with the sizes
+//! and spacing of TeX, and the drawing of the boxes as SVG. Glyph widths
+//! and heights are estimated per kind of character, as the font is the
+//! webview's to pick; each run of text is drawn to the width it was laid
+//! out at, so the layout holds in whatever font it is drawn in.
+
+use std::fmt::Write;
+
+use super::{Align, Kind, Node, Stretch, Token, Variant};
+
+const ASCENT: f32 = 0.75;
+const DESCENT: f32 = 0.25;
+/// Height of the math axis, which fraction bars and operators center on.
+const AXIS: f32 = 0.25;
+const RULE: f32 = 0.05;
+const SCRIPT_SCALE: f32 = 0.71;
+const MIN_SIZE: f32 = 0.5;
+/// After scripts.
+const SCRIPT_SPACE: f32 = 0.05;
+const THIN: f32 = 1.0 / 6.0;
+const MEDIUM: f32 = 2.0 / 9.0;
+const THICK: f32 = 5.0 / 18.0;
+/// Of the box of the SVG around the math, so strokes are not cut.
+const PADDING: f32 = 0.05;
+/// SVG units in an em.
+const UNITS: f32 = 1000.0;
+const FONT_FAMILY: &str =
+    "'STIX Two Math', 'Cambria Math', 'Latin Modern Math', STIXGeneral, 'Times New Roman', serif";
+
+const RELATIONS: &str =
+    "=<>≤≥≠≈≡≢∼≃≅≇≁∝∈∉∋∌⊂⊃⊆⊇⊄⊅⊈⊉⊊⊋≪≫≺≻⪯⪰∣∤∥∦⊥→←↔⇒⇐⇔↦⟶⟵⟷⟹⟸⟺⟼↑↓↕⇑⇓↗↘↪↩⇀↼⇌≐≔:≍≲≳⩽⩾≮≯≰≱⊢⊣⊨⊏⊐⊑⊒⋈⌣⌢≊≜≝≟∷";
+const BINARIES: &str = "+−-±∓×÷⋅·∗∘∙∩∪⊎⊓⊔∧∨⊕⊖⊗⊘⊙∖⋆⋄△▽◁▷†‡≀⨿";
+const OPENS: &str = "([{⟨⌈⌊⟦⦃";
+const CLOSES: &str = ")]}⟩⌉⌋⟧⦄";
+const PUNCTUATION: &str = ",;";
+const LARGE: &str = "∑∏∐⋃⋂⋁⋀⨁⨂⨀⨄⨆∫∬∭∮∯∰";
+const INTEGRALS: &str = "∫∬∭∮∯∰";
+/// Delimiters that grow to the height of their row.
+const VERTICAL: &str = "([{⟨⌈⌊⟦⦃)]}⟩⌉⌋⟧⦄|‖∣∥";
+/// Accents drawn as a line the width of their base.
+const LINES: &str = "¯‾_";
+/// Accents and limits that grow to the width of their base.
+const HORIZONTAL: &str = "¯‾_→←↔⇒⇐⇔⟶⟵⏞⏟⎴⎵";
+const ACCENTS: &str = "^~ˆˇ˜¯‾˙¨´`˘˚→←↔⃗";
+/// Names with limits that go to the side out of display math.
+const MOVABLE_NAMES: &[&str] = &[
+    "lim", "lim inf", "lim sup", "max", "min", "sup", "inf", "det", "gcd", "Pr", "arg max",
+    "arg min",
+];
+
+fn is_single(text: &str, set: &str) -> bool {
+    let mut chars = text.chars();
+    matches!((chars.next(), chars.next()), (Some(c), None) if set.contains(c))
+}
+
+/// Whether the limits of operator `text` go to the side out of display
+/// math, as those of sums do and those of integrals do not.
+pub(super) fn is_movable(text: &str) -> bool {
+    (is_single(text, LARGE) && !is_single(text, INTEGRALS)) || MOVABLE_NAMES.contains(&text)
+}
+
+/// Whether operator `text` stretches unless told not to.
+pub(super) fn is_stretchy(text: &str) -> bool {
+    is_single(text, VERTICAL) || is_single(text, HORIZONTAL)
+}
+
+pub(super) fn is_accent(text: &str) -> bool {
+    is_single(text, ACCENTS)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Style {
+    size: f32,
+    display: bool,
+    /// In a script, where most spacing is dropped.
+    script: bool,
+}
+
+impl Style {
+    fn script(self) -> Style {
+        Style {
+            size: (self.size * SCRIPT_SCALE).max(MIN_SIZE),
+            display: false,
+            script: true,
+        }
+    }
+
+    /// Of the parts of a fraction.
+    fn fraction(self) -> Style {
+        if self.display {
+            Style {
+                display: false,
+                ..self
+            }
+        } else {
+            self.script()
+        }
+    }
+}
+
+enum Item {
+    Text {
+        x: f32,
+        y: f32,
+        text: String,
+        size: f32,
+        /// What the text is drawn to, before scaling.
+        width: f32,
+        italic: bool,
+        bold: bool,
+        family: Option<&'static str>,
+        /// Of the text about its left end and `center`, for stretched
+        /// delimiters.
+        scale: (f32, f32),
+        center: f32,
+    },
+    /// `y` is the top.
+    Rule {
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+    },
+    Line {
+        points: Vec<(f32, f32)>,
+        thickness: f32,
+    },
+}
+
+impl Item {
+    fn offset(&mut self, dx: f32, dy: f32) {
+        match self {
+            Item::Text { x, y, center, .. } => {
+                *x += dx;
+                *y += dy;
+                *center += dy;
+            }
+            Item::Rule { x, y, .. } => {
+                *x += dx;
+                *y += dy;
+            }
+            Item::Line { points, .. } => {
+                for (x, y) in points {
+                    *x += dx;
+                    *y += dy;
+                }
+            }
+        }
+    }
+}
+
+/// A box with its origin at the left end of its baseline, `y` growing
+/// down as in SVG.
+#[derive(Default)]
+struct Layout {
+    width: f32,
+    ascent: f32,
+    descent: f32,
+    items: Vec<Item>,
+}
+
+impl Layout {
+    fn space(width: f32) -> Layout {
+        Layout {
+            width,
+            ..Layout::default()
+        }
+    }
+
+    /// Puts `other` with its origin at `x`, `y`.
+    fn put(&mut self, other: Layout, x: f32, y: f32) {
+        self.width = self.width.max(x + other.width);
+        self.ascent = self.ascent.max(other.ascent - y);
+        self.descent = self.descent.max(other.descent + y);
+        self.items.extend(other.items.into_iter().map(|mut item| {
+            item.offset(x, y);
+            item
+        }));
+    }
+}
+
+/// Width of `c` in ems.
+fn advance(c: char) -> f32 {
+    match c {
+        '\u{2061}'..='\u{2064}' | '\u{200b}' | '\u{338}' | '\u{20d7}' => 0.0,
+        ' ' | '\u{a0}' => 0.25,
+        'i' | 'j' | 'l' | 'I' | '|' | '∣' | '.' | ',' | ';' | ':' | '!' | '\'' | '′' => 0.28,
+        'f' | 't' | 'r' | 'J' | 's' => 0.38,
+        'm' | 'w' | 'M' | 'W' => 0.82,
+        '0'..='9' | 'a'..='z' => 0.5,
+        'A'..='Z' => 0.68,
+        'α'..='ω' | 'ϑ' | 'ϕ' | 'ϵ' | 'ϖ' | 'ϱ' | 'ϰ' => 0.55,
+        'Α'..='Ω' => 0.68,
+        '(' | ')' | '[' | ']' | '{' | '}' | '⟨' | '⟩' | '⌈' | '⌉' | '⌊' | '⌋' | '⟦' | '⟧' => {
+            0.39
+        }
+        '‖' | '∥' => 0.5,
+        '∫' | '∬' | '∭' | '∮' | '∯' | '∰' => 0.5,
+        '∑' | '∏' | '∐' | '⋃' | '⋂' | '⋁' | '⋀' | '⨁' | '⨂' | '⨀' | '⨄' | '⨆' => {
+            0.9
+        }
+        '∞' | '⟶' | '⟵' | '⟷' | '⟹' | '⟸' | '⟺' | '⟼' | '⏞' | '⏟' => 1.0,
+        '…' | '⋯' => 0.9,
+        '\u{1d400}'..='\u{1d7ff}' | 'ℂ' | 'ℍ' | 'ℕ' | 'ℙ' | 'ℚ' | 'ℝ' | 'ℤ' => 0.72,
+        c if RELATIONS.contains(c) || BINARIES.contains(c) => 0.78,
+        c if ('\u{2e80}'..='\u{9fff}').contains(&c) || ('\u{ac00}'..='\u{d7af}').contains(&c) => {
+            1.0
+        }
+        _ => 0.6,
+    }
+}
+
+/// Height above and depth below the baseline of `c`, in ems.
+fn extent(c: char) -> (f32, f32) {
+    match c {
+        '\u{2061}'..='\u{2064}' | '\u{200b}' => (0.0, 0.0),
+        'g' | 'p' | 'q' | 'y' => (0.45, 0.2),
+        'j' => (0.66, 0.2),
+        'f' => (0.7, 0.2),
+        'b' | 'd' | 'h' | 'k' | 'l' => (0.7, 0.0),
+        'i' | 't' => (0.66, 0.0),
+        'a'..='z' => (0.45, 0.0),
+        'A'..='Z' | '0'..='9' | 'Α'..='Ω' => (0.7, 0.0),
+        'β' | 'ζ' | 'ξ' | 'φ' | 'ψ' | 'ϕ' => (0.7, 0.2),
+        'δ' | 'θ' | 'λ' | 'ϑ' => (0.7, 0.0),
+        'γ' | 'η' | 'μ' | 'ρ' | 'χ' | 'ς' | 'ϱ' => (0.45, 0.2),
+        'α'..='ω' => (0.45, 0.0),
+        '.' | ',' | ';' => (0.12, 0.15),
+        'ˆ' | 'ˇ' | '˜' | '¯' | '˙' | '¨' | '´' | '`' | '˘' | '˚' | '^' | '~' => {
+            (0.72, 0.0)
+        }
+        c if RELATIONS.contains(c) || BINARIES.contains(c) => (0.55, 0.05),
+        c if VERTICAL.contains(c) || LARGE.contains(c) => (ASCENT, DESCENT),
+        _ => (0.7, 0.2),
+    }
+}
+
+fn is_invisible(c: char) -> bool {
+    matches!(c, '\u{2061}'..='\u{2064}' | '\u{200b}')
+}
+
+/// `c` in the math alphabet of `variant`, for those drawn with their own
+/// characters.
+fn styled(c: char, variant: Variant) -> char {
+    let (upper, lower, digits, exceptions): (u32, u32, Option<u32>, &[(char, char)]) = match variant
+    {
+        Variant::DoubleStruck => (
+            0x1d538,
+            0x1d552,
+            Some(0x1d7d8),
+            &[
+                ('C', 'ℂ'),
+                ('H', 'ℍ'),
+                ('N', 'ℕ'),
+                ('P', 'ℙ'),
+                ('Q', 'ℚ'),
+                ('R', 'ℝ'),
+                ('Z', 'ℤ'),
+            ],
+        ),
+        Variant::Script => (
+            0x1d49c,
+            0x1d4b6,
+            None,
+            &[
+                ('B', 'ℬ'),
+                ('E', 'ℰ'),
+                ('F', 'ℱ'),
+                ('H', 'ℋ'),
+                ('I', 'ℐ'),
+                ('L', 'ℒ'),
+                ('M', 'ℳ'),
+                ('R', 'ℛ'),
+                ('e', 'ℯ'),
+                ('g', 'ℊ'),
+                ('o', 'ℴ'),
+            ],
+        ),
+        Variant::Fraktur => (
+            0x1d504,
+            0x1d51e,
+            None,
+            &[('C', 'ℭ'), ('H', 'ℌ'), ('I', 'ℑ'), ('R', 'ℜ'), ('Z', 'ℨ')],
+        ),
+        _ => return c,
+    };
+    if let Some(&(_, mapped)) = exceptions.iter().find(|&&(from, _)| from == c) {
+        return mapped;
+    }
+    let mapped = match c {
+        'A'..='Z' => upper + (c as u32 - 'A' as u32),
+        'a'..='z' => lower + (c as u32 - 'a' as u32),
+        '0'..='9' => match digits {
+            Some(digits) => digits + (c as u32 - '0' as u32),
+            None => return c,
+        },
+        _ => return c,
+    };
+    char::from_u32(mapped).unwrap_or(c)
+}
+
+/// Whether identifier `text` is set in italic without a variant: single
+/// letters but capital Greek ones.
+fn is_italic_letter(text: &str) -> bool {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => {
+            c.is_alphabetic()
+                && !('Α'..='Ω').contains(&c)
+                && !('\u{1d400}'..='\u{1d7ff}').contains(&c)
+                && (c.is_ascii() || ('α'..='ω').contains(&c) || "ϑϕϵϖϱϰℓıȷ".contains(c))
+        }
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Class {
+    Ord,
+    Op,
+    Bin,
+    Rel,
+    Open,
+    Close,
+    Punct,
+    /// Spaces and invisible operators, which spacing passes over.
+    None,
+}
+
+fn token_class(token: &Token) -> Class {
+    match token.kind {
+        Kind::Function => Class::Op,
+        Kind::Operator => {
+            let mut chars = token.text.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) if is_invisible(c) => Class::None,
+                (Some(c), None) if LARGE.contains(c) => Class::Op,
+                (Some(c), None) if RELATIONS.contains(c) => Class::Rel,
+                (Some(c), None) if BINARIES.contains(c) => Class::Bin,
+                (Some(c), None) if OPENS.contains(c) => Class::Open,
+                (Some(c), None) if CLOSES.contains(c) || c == '!' => Class::Close,
+                (Some(c), None) if PUNCTUATION.contains(c) => Class::Punct,
+                (None, _) => Class::None,
+                _ if token.text.chars().all(char::is_alphabetic) => Class::Op,
+                _ => Class::Ord,
+            }
+        }
+        _ => Class::Ord,
+    }
+}
+
+/// The class of `node` in a row; operators with scripts or limits keep
+/// that of the operator.
+fn class(node: &Node) -> Class {
+    match node {
+        Node::Token(token) => token_class(token),
+        Node::Space(_) => Class::None,
+        Node::Scripts { base, .. } | Node::UnderOver { base, .. } => match class(base) {
+            Class::None => Class::Ord,
+            class => class,
+        },
+        Node::Row(nodes) if nodes.len() == 1 => class(&nodes[0]),
+        _ => Class::Ord,
+    }
+}
+
+/// Classes of `nodes`, with binary operators where nothing is on one side
+/// of them taken as ordinary, as a leading minus is.
+fn classes(nodes: &[Node]) -> Vec<Class> {
+    let mut classes = nodes.iter().map(class).collect::<Vec<_>>();
+    let mut previous = Class::Open;
+    for class in classes.iter_mut() {
+        if *class == Class::Bin
+            && matches!(
+                previous,
+                Class::Bin | Class::Op | Class::Rel | Class::Open | Class::Punct
+            )
+        {
+            *class = Class::Ord;
+        }
+        if *class != Class::None {
+            previous = *class;
+        }
+    }
+    let mut next = Class::Close;
+    for class in classes.iter_mut().rev() {
+        if *class == Class::Bin && matches!(next, Class::Rel | Class::Close | Class::Punct) {
+            *class = Class::Ord;
+        }
+        if *class != Class::None {
+            next = *class;
+        }
+    }
+    classes
+}
+
+/// Space between atoms of classes `left` and `right`, in ems of the size.
+fn spacing(left: Class, right: Class, script: bool) -> f32 {
+    use Class::*;
+    let (space, in_scripts) = match (left, right) {
+        (Ord | Close, Op) | (Op, Ord | Op) => (THIN, true),
+        (Ord | Op | Close, Bin) | (Bin, Ord | Op | Open) => (MEDIUM, false),
+        (Ord | Op | Close, Rel) | (Rel, Ord | Op | Open) => (THICK, false),
+        (Punct, Ord | Op | Rel | Open | Close | Punct) => (THIN, false),
+        _ => (0.0, true),
+    };
+    if script && !in_scripts {
+        0.0
+    } else {
+        space
+    }
+}
+
+fn layout(node: &Node, style: Style) -> Layout {
+    match node {
+        Node::Token(token) => token_layout(token, style, None),
+        Node::Row(nodes) => row(nodes, style),
+        Node::Frac { num, den, line } => fraction(num, den, *line, style),
+        Node::Root { base, index } => root(base, index.as_deref(), style),
+        Node::Scripts { base, sub, sup } => scripts(base, sub.as_deref(), sup.as_deref(), style),
+        Node::UnderOver {
+            base,
+            under,
+            over,
+            movable,
+            accent,
+        } => {
+            if *movable && !style.display {
+                scripts(base, under.as_deref(), over.as_deref(), style)
+            } else {
+                under_over(base, under.as_deref(), over.as_deref(), *accent, style)
+            }
+        }
+        Node::Table {
+            rows,
+            align,
+            spacing,
+        } => table(rows, align, *spacing, style),
+        Node::Style { display, body } => layout(
+            body,
+            Style {
+                display: *display,
+                ..style
+            },
+        ),
+        Node::Phantom(body) => {
+            let mut layout = layout(body, style);
+            layout.items.clear();
+            layout
+        }
+        Node::Space(width) => Layout::space(width * style.size),
+    }
+}
+
+/// `token` at its natural size, or `width` wide when given.
+fn token_layout(token: &Token, style: Style, width: Option<f32>) -> Layout {
+    let text = token
+        .text
+        .chars()
+        .map(|c| styled(c, token.variant))
+        .collect::<String>();
+    if text.chars().all(is_invisible) {
+        return Layout::default();
+    }
+    let large = token.kind == Kind::Operator && is_single(&text, LARGE);
+    let size = match large {
+        true if style.display => style.size * 1.5,
+        true => style.size * 1.15,
+        false => style.size,
+    };
+    let (italic, bold) = match token.variant {
+        Variant::Auto => (
+            token.kind == Kind::Identifier && is_italic_letter(&text),
+            false,
+        ),
+        Variant::Italic => (true, false),
+        Variant::Bold => (false, true),
+        Variant::BoldItalic => (true, true),
+        _ => (false, false),
+    };
+    let family = match token.variant {
+        Variant::SansSerif => Some("sans-serif"),
+        Variant::Monospace => Some("monospace"),
+        _ => None,
+    };
+    let natural = text.chars().map(advance).sum::<f32>() * size * if bold { 1.05 } else { 1.0 };
+    let (ascent, descent) = text
+        .chars()
+        .map(extent)
+        .fold((0.0f32, 0.0f32), |(a, d), (ca, cd)| (a.max(ca), d.max(cd)));
+    // Large operators center on the axis
+    let dy = if large {
+        AXIS * (size - style.size)
+    } else {
+        0.0
+    };
+    let width = width.unwrap_or(natural).max(natural);
+    Layout {
+        width,
+        ascent: ascent * size - dy,
+        descent: descent * size + dy,
+        items: vec![Item::Text {
+            x: 0.0,
+            y: dy,
+            text,
+            size,
+            width,
+            italic,
+            bold,
+            family,
+            scale: (1.0, 1.0),
+            center: 0.0,
+        }],
+    }
+}
+
+/// Delimiter `token` grown to cover `ascent` and `descent` evenly about
+/// the axis, or to its own size.
+fn delimiter(token: &Token, ascent: f32, descent: f32, style: Style) -> Layout {
+    let axis = AXIS * style.size;
+    let height = match token.stretch {
+        Stretch::To(height) => height * style.size,
+        _ => 2.0 * (ascent - axis).max(descent + axis) * 1.05,
+    };
+    let mut layout = token_layout(token, style, None);
+    let natural = (ASCENT + DESCENT) * style.size;
+    if height <= natural * 1.05 {
+        return layout;
+    }
+    let scale_y = height / natural;
+    let scale_x = (1.0 + (scale_y - 1.0) * 0.15).min(1.6);
+    for item in &mut layout.items {
+        if let Item::Text { scale, center, .. } = item {
+            *scale = (scale_x, scale_y);
+            *center = -axis;
+        }
+    }
+    layout.width *= scale_x;
+    layout.ascent = axis + height / 2.0;
+    layout.descent = height / 2.0 - axis;
+    layout
+}
+
+fn is_vertical_stretch(node: &Node) -> Option<&Token> {
+    match node {
+        Node::Token(token) if token.kind == Kind::Operator => match token.stretch {
+            Stretch::Fit if is_single(&token.text, VERTICAL) => Some(token),
+            Stretch::To(_) => Some(token),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn row(nodes: &[Node], style: Style) -> Layout {
+    let mut boxes = nodes
+        .iter()
+        .map(|node| match is_vertical_stretch(node) {
+            Some(_) => None,
+            None => Some(layout(node, style)),
+        })
+        .collect::<Vec<_>>();
+    let laid = boxes.iter().flatten();
+    let ascent = laid.clone().map(|b| b.ascent).fold(0.0, f32::max);
+    let descent = laid.map(|b| b.descent).fold(0.0, f32::max);
+    let (ascent, descent) = if ascent + descent > 0.0 {
+        (ascent, descent)
+    } else {
+        (ASCENT * style.size * 0.9, DESCENT * style.size * 0.9)
+    };
+    for (node, laid) in nodes.iter().zip(&mut boxes) {
+        if let Some(token) = is_vertical_stretch(node) {
+            *laid = Some(delimiter(token, ascent, descent, style));
+        }
+    }
+
+    let classes = classes(nodes);
+    let mut out = Layout::default();
+    let mut x = 0.0;
+    let mut previous = None;
+    for (laid, class) in boxes.into_iter().flatten().zip(classes) {
+        if class != Class::None {
+            if let Some(previous) = previous {
+                x += spacing(previous, class, style.script) * style.size;
+            }
+            previous = Some(class);
+        }
+        let width = laid.width;
+        out.put(laid, x, 0.0);
+        x += width;
+    }
+    out.width = x;
+    out
+}
+
+fn fraction(num: &Node, den: &Node, line: bool, style: Style) -> Layout {
+    let inner = style.fraction();
+    let num = layout(num, inner);
+    let den = layout(den, inner);
+    let size = style.size;
+    let thickness = if line { RULE * size } else { 0.0 };
+    let gap = if style.display { 0.15 } else { 0.1 } * size + if line { 0.0 } else { 0.1 * size };
+    let padding = 0.12 * size;
+    let width = num.width.max(den.width) + 2.0 * padding;
+    let axis = AXIS * size;
+    let num_y = -(axis + thickness / 2.0 + gap + num.descent);
+    let den_y = -axis + thickness / 2.0 + gap + den.ascent;
+
+    let mut out = Layout::space(width);
+    let (num_width, den_width) = (num.width, den.width);
+    out.put(num, (width - num_width) / 2.0, num_y);
+    out.put(den, (width - den_width) / 2.0, den_y);
+    if line {
+        out.items.push(Item::Rule {
+            x: padding / 2.0,
+            y: -axis - thickness / 2.0,
+            width: width - padding,
+            height: thickness,
+        });
+    }
+    out
+}
+
+fn root(base: &Node, index: Option<&Node>, style: Style) -> Layout {
+    let size = style.size;
+    let body = layout(base, style);
+    let thickness = RULE * size;
+    let gap = 0.12 * size;
+    let top = -(body.ascent + gap + thickness / 2.0);
+    let bottom = body.descent;
+    let height = bottom - top;
+    let sign = 0.5 * size + 0.08 * height;
+
+    let index = index.map(|index| layout(index, style.script().script()));
+    // The index sits in the crook of the sign, which moves right to fit it
+    let shift = index
+        .as_ref()
+        .map_or(0.0, |index| (index.width - 0.45 * sign).max(0.0));
+    let mut out = Layout::default();
+    let body_width = body.width;
+    out.put(body, shift + sign, 0.0);
+    let x = shift;
+    out.items.push(Item::Line {
+        points: vec![
+            (x, bottom - 0.45 * height),
+            (x + 0.2 * sign, bottom - 0.52 * height),
+            (x + 0.5 * sign, bottom),
+            (x + sign, top),
+            (x + sign + body_width + 0.1 * size, top),
+        ],
+        thickness,
+    });
+    out.width = shift + sign + body_width + 0.15 * size;
+    out.ascent = out.ascent.max(-top + thickness);
+    out.descent = out.descent.max(bottom + thickness);
+    if let Some(index) = index {
+        let y = bottom - 0.6 * height - index.descent;
+        let width = index.width;
+        out.put(index, shift + 0.45 * sign - width, y);
+    }
+    out
+}
+
+fn scripts(base: &Node, sub: Option<&Node>, sup: Option<&Node>, style: Style) -> Layout {
+    let size = style.size;
+    let base = layout(base, style);
+    let inner = style.script();
+    let sup = sup.map(|sup| layout(sup, inner));
+    let sub = sub.map(|sub| layout(sub, inner));
+
+    let mut up = sup.as_ref().map_or(0.0, |sup| {
+        (0.4 * size)
+            .max(base.ascent - 0.35 * size)
+            .max(sup.descent + 0.2 * size)
+    });
+    let mut down = sub.as_ref().map_or(0.0, |sub| {
+        (0.2 * size)
+            .max(base.descent - 0.1 * size)
+            .max(sub.ascent - 0.45 * size)
+    });
+    if let (Some(sup), Some(sub)) = (&sup, &sub) {
+        let gap = (down - sub.ascent) + (up - sup.descent);
+        if gap < 0.15 * size {
+            down += 0.15 * size - gap;
+        }
+        up = up.max(0.0);
+    }
+
+    let x = base.width;
+    let mut out = Layout::default();
+    out.put(base, 0.0, 0.0);
+    let mut width = 0.0f32;
+    if let Some(sup) = sup {
+        width = width.max(sup.width + 0.03 * size);
+        out.put(sup, x + 0.03 * size, -up);
+    }
+    if let Some(sub) = sub {
+        width = width.max(sub.width);
+        out.put(sub, x, down);
+    }
+    out.width = x + width + SCRIPT_SPACE * size;
+    out
+}
+
+/// `node` laid out over or under a base `width` wide, lines and arrows
+/// grown to it.
+fn limit(node: &Node, width: f32, style: Style) -> Layout {
+    let Node::Token(token) = node else {
+        return layout(node, style);
+    };
+    if token.stretch != Stretch::Fit || !is_single(&token.text, HORIZONTAL) {
+        return token_layout(token, style, None);
+    }
+    if is_single(&token.text, LINES) {
+        let thickness = RULE * style.size;
+        return Layout {
+            width,
+            ascent: thickness,
+            descent: 0.0,
+            items: vec![Item::Rule {
+                x: 0.0,
+                y: -thickness,
+                width,
+                height: thickness,
+            }],
+        };
+    }
+    token_layout(token, style, Some(width))
+}
+
+/// How far accent `token` is drawn down from where its box would go, in
+/// ems of its size: accent glyphs are drawn well above their baseline.
+fn accent_drop(token: &Token) -> f32 {
+    if token.stretch == Stretch::Fit && is_single(&token.text, LINES) {
+        0.0
+    } else if is_single(&token.text, "→←↔⃗") {
+        0.15
+    } else if is_accent(&token.text) {
+        0.5
+    } else {
+        0.0
+    }
+}
+
+fn under_over(
+    base: &Node,
+    under: Option<&Node>,
+    over: Option<&Node>,
+    accent: bool,
+    style: Style,
+) -> Layout {
+    let size = style.size;
+    let inner = if accent { style } else { style.script() };
+    let drop = match over {
+        Some(Node::Token(token)) if accent => accent_drop(token) * inner.size,
+        _ => 0.0,
+    };
+    // Arrows and braces with something over or under them grow to its width
+    let stretchy = matches!(base, Node::Token(token)
+        if token.stretch == Stretch::Fit && is_single(&token.text, HORIZONTAL));
+    let (base, over, under) = if stretchy {
+        let over = over.map(|over| layout(over, inner));
+        let under = under.map(|under| layout(under, inner));
+        let width = over
+            .iter()
+            .chain(&under)
+            .map(|limit| limit.width)
+            .fold(size, f32::max);
+        (limit(base, width + 0.3 * size, style), over, under)
+    } else {
+        let base = layout(base, style);
+        let over = over.map(|over| limit(over, base.width, inner));
+        let under = under.map(|under| limit(under, base.width, inner));
+        (base, over, under)
+    };
+    let gap = if accent { 0.05 } else { 0.15 } * size;
+    let width = [Some(&base), over.as_ref(), under.as_ref()]
+        .into_iter()
+        .flatten()
+        .map(|layout| layout.width)
+        .fold(0.0, f32::max);
+
+    let mut out = Layout::space(width);
+    let (base_ascent, base_descent) = (base.ascent, base.descent);
+    let base_width = base.width;
+    out.put(base, (width - base_width) / 2.0, 0.0);
+    if let Some(over) = over {
+        let y = -(base_ascent + gap + over.descent) + drop;
+        let over_width = over.width;
+        out.put(over, (width - over_width) / 2.0, y);
+    }
+    if let Some(under) = under {
+        let y = base_descent + gap + under.ascent;
+        let under_width = under.width;
+        out.put(under, (width - under_width) / 2.0, y);
+    }
+    out
+}
+
+fn table(rows: &[Vec<Node>], align: &[Align], spacing: f32, style: Style) -> Layout {
+    let size = style.size;
+    let inner = Style {
+        display: false,
+        ..style
+    };
+    let cells = rows
+        .iter()
+        .map(|row| {
+            row.iter()
+                .map(|cell| layout(cell, inner))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let columns = cells.iter().map(Vec::len).max().unwrap_or(0);
+    let widths = (0..columns)
+        .map(|column| {
+            cells
+                .iter()
+                .filter_map(|row| row.get(column))
+                .map(|cell| cell.width)
+                .fold(0.0, f32::max)
+        })
+        .collect::<Vec<_>>();
+    let row_gap = 0.3 * size;
+    let heights = cells
+        .iter()
+        .map(|row| {
+            let ascent = row
+                .iter()
+                .map(|cell| cell.ascent)
+                .fold(0.7 * size, f32::max);
+            let descent = row
+                .iter()
+                .map(|cell| cell.descent)
+                .fold(0.2 * size, f32::max);
+            (ascent, descent)
+        })
+        .collect::<Vec<_>>();
+    let total = heights.iter().map(|(a, d)| a + d).sum::<f32>()
+        + row_gap * heights.len().saturating_sub(1) as f32;
+
+    let mut out = Layout::default();
+    let mut y = -AXIS * size - total / 2.0;
+    for (row, (ascent, descent)) in cells.into_iter().zip(heights) {
+        y += ascent;
+        let mut x = 0.0;
+        for (column, cell) in row.into_iter().enumerate() {
+            let width = widths[column];
+            let align = align
+                .get(column)
+                .or(align.last())
+                .copied()
+                .unwrap_or(Align::Center);
+            let offset = match align {
+                Align::Left => 0.0,
+                Align::Center => (width - cell.width) / 2.0,
+                Align::Right => width - cell.width,
+            };
+            out.put(cell, x + offset, y);
+            x += width + spacing * size;
+        }
+        y += descent + row_gap;
+    }
+    out.width = widths.iter().sum::<f32>() + spacing * size * columns.saturating_sub(1) as f32;
+    out.ascent = out.ascent.max(AXIS * size + total / 2.0);
+    out.descent = out.descent.max(total / 2.0 - AXIS * size);
+    out
+}
+
+fn escape(text: &str, out: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            c => out.push(c),
+        }
+    }
+}
+
+/// `value` in ems as SVG units.
+fn units(value: f32) -> i64 {
+    (value * UNITS).round() as i64
+}
+
+fn write_item(item: &Item, out: &mut String) {
+    match item {
+        Item::Text {
+            x,
+            y,
+            text,
+            size,
+            width,
+            italic,
+            bold,
+            family,
+            scale,
+            center,
+        } => {
+            let _ = write!(
+                out,
+                r#"<text x="{}" y="{}" font-size="{}" textLength="{}" lengthAdjust="spacingAndGlyphs""#,
+                units(*x),
+                units(*y),
+                units(*size),
+                units(*width / scale.0)
+            );
+            if *italic {
+                out.push_str(r#" font-style="italic""#);
+            }
+            if *bold {
+                out.push_str(r#" font-weight="bold""#);
+            }
+            if let Some(family) = family {
+                let _ = write!(out, r#" font-family="{family}""#);
+            }
+            if *scale != (1.0, 1.0) {
+                let _ = write!(
+                    out,
+                    r#" transform="matrix({} 0 0 {} {} {})""#,
+                    scale.0,
+                    scale.1,
+                    units(*x * (1.0 - scale.0)),
+                    units(*center * (1.0 - scale.1))
+                );
+            }
+            out.push('>');
+            escape(text, out);
+            out.push_str("</text>");
+        }
+        Item::Rule {
+            x,
+            y,
+            width,
+            height,
+        } => {
+            let _ = write!(
+                out,
+                r#"<rect x="{}" y="{}" width="{}" height="{}"/>"#,
+                units(*x),
+                units(*y),
+                units(*width),
+                units(*height)
+            );
+        }
+        Item::Line { points, thickness } => {
+            out.push_str("<path d=\"");
+            for (i, (x, y)) in points.iter().enumerate() {
+                let _ = write!(
+                    out,
+                    "{}{} {}",
+                    if i == 0 { "M" } else { " L" },
+                    units(*x),
+                    units(*y)
+                );
+            }
+            let _ = write!(
+                out,
+                r#"" fill="none" stroke="currentColor" stroke-width="{}" stroke-linejoin="round" stroke-linecap="round"/>"#,
+                units(*thickness)
+            );
+        }
+    }
+}
+
+/// `node` as an SVG element sized in ems, sitting on the baseline of the
+/// text around it, or centered on a line of its own for `display` math.
+/// `label` is read out in its place.
+pub(super) fn svg(node: &Node, display: bool, label: &str) -> String {
+    let style = Style {
+        size: 1.0,
+        display,
+        script: false,
+    };
+    let layout = layout(node, style);
+    let width = layout.width.max(0.0) + 2.0 * PADDING;
+    let ascent = layout.ascent + PADDING;
+    let descent = layout.descent + PADDING;
+    let height = ascent + descent;
+
+    let mut out = String::new();
+    out.push_str(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" class="vl-math" role="img" aria-label=""#,
+    );
+    escape(label, &mut out);
+    let _ = write!(
+        out,
+        r#"" width="{:.3}em" height="{:.3}em" viewBox="{} {} {} {}" style="vertical-align:{:.3}em;overflow:visible{}" fill="currentColor" font-family="{FONT_FAMILY}">"#,
+        width,
+        height,
+        units(-PADDING),
+        units(-ascent),
+        units(width),
+        units(height),
+        -descent,
+        if display {
+            ";display:block;margin:0.5em auto"
+        } else {
+            ""
+        }
+    );
+    for item in &layout.items {
+        write_item(item, &mut out);
+    }
+    out.push_str("</svg>");
+    out
+}
@@ -0,0 +1,771 @@
+//! Presentation MathML as books have it: the elements of MathML 3 core
+//! and the older `mfenced`, in XHTML with or without a namespace prefix
+//! and with the named entities of MathML, which XML parsers do not know.
+
+use std::sync::OnceLock;
+
+use regex::Regex;
+use roxmltree::Node as Element;
+
+use super::layout::{is_accent, is_movable, is_stretchy};
+use super::{Align, Kind, Node, Stretch, Token, Variant};
+use crate::error::{Error, Result};
+use crate::formats::epub::{attr, parse_xml};
+use crate::formats::html::rewrite_named_entities;
+
+const MATHML_NS: &str = "http://www.w3.org/1998/Math/MathML";
+
+/// Named entities of MathML beyond those of HTML the app decodes anyway.
+const ENTITIES: &[(&str, char)] = &[
+    ("alpha", 'α'),
+    ("beta", 'β'),
+    ("gamma", 'γ'),
+    ("delta", 'δ'),
+    ("epsilon", 'ϵ'),
+    ("epsiv", 'ε'),
+    ("varepsilon", 'ε'),
+    ("zeta", 'ζ'),
+    ("eta", 'η'),
+    ("theta", 'θ'),
+    ("thetav", 'ϑ'),
+    ("iota", 'ι'),
+    ("kappa", 'κ'),
+    ("lambda", 'λ'),
+    ("mu", 'μ'),
+    ("nu", 'ν'),
+    ("xi", 'ξ'),
+    ("omicron", 'ο'),
+    ("pi", 'π'),
+    ("piv", 'ϖ'),
+    ("rho", 'ρ'),
+    ("sigma", 'σ'),
+    ("sigmaf", 'ς'),
+    ("tau", 'τ'),
+    ("upsilon", 'υ'),
+    ("phi", 'φ'),
+    ("phiv", 'ϕ'),
+    ("chi", 'χ'),
+    ("psi", 'ψ'),
+    ("omega", 'ω'),
+    ("Gamma", 'Γ'),
+    ("Delta", 'Δ'),
+    ("Theta", 'Θ'),
+    ("Lambda", 'Λ'),
+    ("Xi", 'Ξ'),
+    ("Pi", 'Π'),
+    ("Sigma", 'Σ'),
+    ("Upsilon", 'Υ'),
+    ("Phi", 'Φ'),
+    ("Psi", 'Ψ'),
+    ("Omega", 'Ω'),
+    ("InvisibleTimes", '\u{2062}'),
+    ("it", '\u{2062}'),
+    ("ApplyFunction", '\u{2061}'),
+    ("af", '\u{2061}'),
+    ("InvisibleComma", '\u{2063}'),
+    ("ic", '\u{2063}'),
+    ("minus", '−'),
+    ("plus", '+'),
+    ("PlusMinus", '±'),
+    ("plusmn", '±'),
+    ("mp", '∓'),
+    ("divide", '÷'),
+    ("sdot", '⋅'),
+    ("middot", '·'),
+    ("cdot", '⋅'),
+    ("ast", '∗'),
+    ("compfn", '∘'),
+    ("le", '≤'),
+    ("leq", '≤'),
+    ("ge", '≥'),
+    ("geq", '≥'),
+    ("ne", '≠'),
+    ("NotEqual", '≠'),
+    ("equiv", '≡'),
+    ("approx", '≈'),
+    ("sim", '∼'),
+    ("cong", '≅'),
+    ("prop", '∝'),
+    ("isin", '∈'),
+    ("in", '∈'),
+    ("notin", '∉'),
+    ("ni", '∋'),
+    ("sub", '⊂'),
+    ("sup", '⊃'),
+    ("sube", '⊆'),
+    ("supe", '⊇'),
+    ("cap", '∩'),
+    ("cup", '∪'),
+    ("and", '∧'),
+    ("or", '∨'),
+    ("not", '¬'),
+    ("forall", '∀'),
+    ("exist", '∃'),
+    ("empty", '∅'),
+    ("nabla", '∇'),
+    ("part", '∂'),
+    ("PartialD", '∂'),
+    ("DifferentialD", 'ⅆ'),
+    ("dd", 'ⅆ'),
+    ("ExponentialE", 'ⅇ'),
+    ("ee", 'ⅇ'),
+    ("ImaginaryI", 'ⅈ'),
+    ("ii", 'ⅈ'),
+    ("infin", '∞'),
+    ("sum", '∑'),
+    ("Sum", '∑'),
+    ("prod", '∏'),
+    ("Product", '∏'),
+    ("int", '∫'),
+    ("Integral", '∫'),
+    ("Int", '∬'),
+    ("oint", '∮'),
+    ("ContourIntegral", '∮'),
+    ("radic", '√'),
+    ("Sqrt", '√'),
+    ("rarr", '→'),
+    ("larr", '←'),
+    ("harr", '↔'),
+    ("rArr", '⇒'),
+    ("lArr", '⇐'),
+    ("hArr", '⇔'),
+    ("uarr", '↑'),
+    ("darr", '↓'),
+    ("map", '↦'),
+    ("RightArrow", '→'),
+    ("LeftArrow", '←'),
+    ("Implies", '⇒'),
+    ("langle", '⟨'),
+    ("rangle", '⟩'),
+    ("lang", '⟨'),
+    ("rang", '⟩'),
+    ("lceil", '⌈'),
+    ("rceil", '⌉'),
+    ("lfloor", '⌊'),
+    ("rfloor", '⌋'),
+    ("lbrace", '{'),
+    ("rbrace", '}'),
+    ("lbrack", '['),
+    ("rbrack", ']'),
+    ("lpar", '('),
+    ("rpar", ')'),
+    ("verbar", '|'),
+    ("vert", '|'),
+    ("Verbar", '‖'),
+    ("mid", '∣'),
+    ("par", '∥'),
+    ("perp", '⊥'),
+    ("prime", '′'),
+    ("Prime", '″'),
+    ("angle", '∠'),
+    ("hbar", 'ℏ'),
+    ("planck", 'ℏ'),
+    ("ell", 'ℓ'),
+    ("aleph", 'ℵ'),
+    ("Re", 'ℜ'),
+    ("Im", 'ℑ'),
+    ("reals", 'ℝ'),
+    ("Ropf", 'ℝ'),
+    ("integers", 'ℤ'),
+    ("Zopf", 'ℤ'),
+    ("naturals", 'ℕ'),
+    ("Nopf", 'ℕ'),
+    ("rationals", 'ℚ'),
+    ("Qopf", 'ℚ'),
+    ("complexes", 'ℂ'),
+    ("Copf", 'ℂ'),
+    ("ctdot", '⋯'),
+    ("cdots", '⋯'),
+    ("vellip", '⋮'),
+    ("dtdot", '⋱'),
+    ("OverBar", '‾'),
+    ("macr", '¯'),
+    ("UnderBar", '_'),
+    ("Hat", '^'),
+    ("circ", 'ˆ'),
+    ("tilde", '˜'),
+    ("caron", 'ˇ'),
+    ("dot", '˙'),
+    ("die", '¨'),
+    ("uml", '¨'),
+    ("acute", '´'),
+    ("grave", '`'),
+    ("breve", '˘'),
+    ("OverBrace", '⏞'),
+    ("UnderBrace", '⏟'),
+    ("ThinSpace", '\u{2009}'),
+    ("MediumSpace", '\u{205f}'),
+    ("ThickSpace", '\u{2005}'),
+    ("NegativeThinSpace", '\u{200b}'),
+    ("ZeroWidthSpace", '\u{200b}'),
+    ("NewLine", '\n'),
+    ("Tab", '\t'),
+];
+
+fn error(message: impl Into<String>) -> Error {
+    Error::Math(message.into())
+}
+
+pub struct Math {
+    pub node: Node,
+    pub display: bool,
+    /// What is read out for the math.
+    pub label: String,
+}
+
+/// `markup` as an XML parser takes it: named entities other than those
+/// of XML as numeric references, and the namespace prefixes declared on
+/// the chapter root declared on the element itself.
+fn prepare(markup: &str) -> String {
+    let markup = rewrite_named_entities(markup, |name, decoded| {
+        let decoded = ENTITIES
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|&(_, c)| c)
+            .or(decoded);
+        match decoded {
+            Some(c) => format!("&#x{:x};", c as u32),
+            // Unknown, which the parser turns down
+            None => format!("&{name};"),
+        }
+    });
+
+    let Some(open_end) = markup.find('>') else {
+        return markup.into_owned();
+    };
+    let open = &markup[..open_end];
+    let root_prefix = open
+        .trim_start_matches('<')
+        .split(char::is_whitespace)
+        .next()
+        .and_then(|name| name.split_once(':'))
+        .map(|(prefix, _)| prefix);
+    static PREFIXED: OnceLock<Regex> = OnceLock::new();
+    let prefixed = PREFIXED.get_or_init(|| {
+        Regex::new(r"(?:<|\s)([A-Za-z][\w.-]*):[A-Za-z]").expect("valid prefix pattern")
+    });
+    let mut declarations = String::new();
+    let mut seen = Vec::new();
+    for caps in prefixed.captures_iter(&markup) {
+        let prefix = caps.get(1).map_or("", |m| m.as_str());
+        if matches!(prefix, "xml" | "xmlns")
+            || seen.contains(&prefix)
+            || markup.contains(&format!("xmlns:{prefix}="))
+        {
+            continue;
+        }
+        seen.push(prefix);
+        let ns = match Some(prefix) == root_prefix {
+            true => MATHML_NS.to_string(),
+            false => format!("urn:x-prefix:{prefix}"),
+        };
+        declarations.push_str(&format!(" xmlns:{prefix}=\"{ns}\""));
+    }
+    if declarations.is_empty() {
+        return markup.into_owned();
+    }
+    // Before the end of the opening tag, or of `/>` for empty math
+    let at = if open.ends_with('/') {
+        open_end - 1
+    } else {
+        open_end
+    };
+    format!("{}{}{}", &markup[..at], declarations, &markup[at..])
+}
+
+fn variant_of(name: &str) -> Option<Variant> {
+    Some(match name {
+        "normal" => Variant::Normal,
+        "italic" => Variant::Italic,
+        "bold" | "bold-sans-serif" => Variant::Bold,
+        "bold-italic" | "sans-serif-bold-italic" => Variant::BoldItalic,
+        "double-struck" => Variant::DoubleStruck,
+        "script" | "bold-script" => Variant::Script,
+        "fraktur" | "bold-fraktur" => Variant::Fraktur,
+        "sans-serif" | "sans-serif-italic" => Variant::SansSerif,
+        "monospace" => Variant::Monospace,
+        _ => return None,
+    })
+}
+
+/// A MathML length in ems, with the named spaces of MathML 2.
+fn length(value: &str) -> Option<f32> {
+    let value = value.trim();
+    let named = match value {
+        "veryverythinmathspace" => Some(1.0),
+        "verythinmathspace" => Some(2.0),
+        "thinmathspace" => Some(3.0),
+        "mediummathspace" => Some(4.0),
+        "thickmathspace" => Some(5.0),
+        "verythickmathspace" => Some(6.0),
+        "veryverythickmathspace" => Some(7.0),
+        _ => None,
+    };
+    if let Some(named) = named {
+        return Some(named / 18.0);
+    }
+    let unit_start = value
+        .find(|c: char| c.is_ascii_alphabetic() || c == '%')
+        .unwrap_or(value.len());
+    let number = value[..unit_start].parse::<f32>().ok()?;
+    let unit = match &value[unit_start..] {
+        "em" => 1.0,
+        "ex" => 0.43,
+        "px" => 1.0 / 16.0,
+        "pt" => 0.1,
+        "mm" => 0.285,
+        "cm" => 2.85,
+        "in" => 7.2,
+        "mu" => 1.0 / 18.0,
+        "%" => 0.01,
+        "" => 1.0,
+        _ => return None,
+    };
+    Some(number * unit)
+}
+
+fn is_true(value: Option<&str>) -> Option<bool> {
+    value.map(|value| value.trim() == "true")
+}
+
+/// Local name of element `el`.
+fn name<'a>(el: Element<'a, '_>) -> &'a str {
+    el.tag_name().name()
+}
+
+fn elements<'a, 'input>(el: Element<'a, 'input>) -> impl Iterator<Item = Element<'a, 'input>> {
+    el.children().filter(Element::is_element)
+}
+
+/// Text of token element `el`, its whitespace collapsed.
+fn token_text(el: Element) -> String {
+    el.descendants()
+        .filter(Element::is_text)
+        .filter_map(|n| n.text())
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Text of the content of `el` but its annotations, for its label.
+fn spoken(el: Element, out: &mut Vec<String>) {
+    for child in el.children() {
+        if child.is_text() {
+            let text = child.text().unwrap_or_default().trim();
+            if !text.is_empty() {
+                out.push(text.to_string());
+            }
+        } else if child.is_element() && !name(child).starts_with("annotation") {
+            spoken(child, out);
+        }
+    }
+}
+
+struct Converter {
+    /// Inherited from `mstyle`.
+    variant: Variant,
+}
+
+impl Converter {
+    fn token(&self, el: Element, kind: Kind) -> Node {
+        let mut text = token_text(el);
+        let variant = attr(el, "mathvariant")
+            .and_then(variant_of)
+            .unwrap_or(self.variant);
+        let stretch = match kind {
+            Kind::Operator => {
+                if text == "-" {
+                    text = "−".to_string();
+                }
+                match is_true(attr(el, "stretchy")) {
+                    Some(true) => Stretch::Fit,
+                    Some(false) => Stretch::No,
+                    None if is_stretchy(&text) => Stretch::Fit,
+                    None => Stretch::No,
+                }
+            }
+            _ => Stretch::No,
+        };
+        Node::Token(Token {
+            kind,
+            text,
+            variant,
+            stretch,
+        })
+    }
+
+    fn children(&mut self, el: Element) -> Result<Vec<Node>> {
+        elements(el).map(|child| self.convert(child)).collect()
+    }
+
+    /// The children of `el` as one node, as the inferred `mrow` of MathML.
+    fn row(&mut self, el: Element) -> Result<Node> {
+        let mut nodes = self.children(el)?;
+        Ok(match nodes.len() {
+            1 => nodes.remove(0),
+            _ => Node::Row(nodes),
+        })
+    }
+
+    /// The `count` children of `el`, which must have exactly that many.
+    fn arguments(&mut self, el: Element, count: usize) -> Result<Vec<Node>> {
+        let nodes = self.children(el)?;
+        if nodes.len() != count {
+            return Err(error(format!(
+                "{} has {} children instead of {count}",
+                name(el),
+                nodes.len()
+            )));
+        }
+        Ok(nodes)
+    }
+
+    /// Whether the limits of `el`, the base of `munderover` and its kin,
+    /// move to the side out of display math.
+    fn is_movable_base(el: Option<Element>) -> bool {
+        let Some(el) = el else {
+            return false;
+        };
+        match name(el) {
+            "mo" => match is_true(attr(el, "movablelimits")) {
+                Some(movable) => movable,
+                None => is_movable(&token_text(el)),
+            },
+            "mi" => is_movable(&token_text(el)),
+            "mrow" | "mstyle" => {
+                let mut children = elements(el);
+                match (children.next(), children.next()) {
+                    (Some(child), None) => Self::is_movable_base(Some(child)),
+                    _ => false,
+                }
+            }
+            _ => false,
+        }
+    }
+
+    fn is_accent_of(el: Option<Element>, attribute: Option<&str>) -> bool {
+        match is_true(attribute) {
+            Some(accent) => accent,
+            None => el.is_some_and(|el| name(el) == "mo" && is_accent(&token_text(el))),
+        }
+    }
+
+    fn convert(&mut self, el: Element) -> Result<Node> {
+        let box_ = |nodes: &mut Vec<Node>| Box::new(nodes.remove(0));
+        Ok(match name(el) {
+            "mi" => self.token(el, Kind::Identifier),
+            "mn" => self.token(el, Kind::Number),
+            "mo" => self.token(el, Kind::Operator),
+            "mtext" => self.token(el, Kind::Text),
+            "ms" => {
+                let mut node = self.token(el, Kind::Text);
+                if let Node::Token(token) = &mut node {
+                    let open = attr(el, "lquote").unwrap_or("\"");
+                    let close = attr(el, "rquote").unwrap_or("\"");
+                    token.text = format!("{open}{}{close}", token.text);
+                }
+                node
+            }
+            "mspace" => Node::Space(attr(el, "width").and_then(length).unwrap_or(0.0)),
+            "mglyph" | "none" | "mprescripts" | "annotation" | "annotation-xml" => {
+                Node::Row(Vec::new())
+            }
+            "mstyle" => {
+                let variant = self.variant;
+                if let Some(mathvariant) = attr(el, "mathvariant").and_then(variant_of) {
+                    self.variant = mathvariant;
+                }
+                let body = self.row(el);
+                self.variant = variant;
+                match is_true(attr(el, "displaystyle")) {
+                    Some(display) => Node::Style {
+                        display,
+                        body: Box::new(body?),
+                    },
+                    None => body?,
+                }
+            }
+            "mfrac" => {
+                let mut nodes = self.arguments(el, 2)?;
+                let line = attr(el, "linethickness")
+                    .map(str::trim)
+                    .map_or(true, |thickness| {
+                        !matches!(thickness, "0" | "0px" | "0em" | "0pt")
+                    });
+                Node::Frac {
+                    num: box_(&mut nodes),
+                    den: box_(&mut nodes),
+                    line,
+                }
+            }
+            "msqrt" => Node::Root {
+                base: Box::new(self.row(el)?),
+                index: None,
+            },
+            "mroot" => {
+                let mut nodes = self.arguments(el, 2)?;
+                Node::Root {
+                    base: box_(&mut nodes),
+                    index: Some(box_(&mut nodes)),
+                }
+            }
+            "msub" | "msup" | "msubsup" => {
+                let count = if name(el) == "msubsup" { 3 } else { 2 };
+                let mut nodes = self.arguments(el, count)?;
+                let base = box_(&mut nodes);
+                let (sub, sup) = match name(el) {
+                    "msub" => (Some(box_(&mut nodes)), None),
+                    "msup" => (None, Some(box_(&mut nodes))),
+                    _ => (Some(box_(&mut nodes)), Some(box_(&mut nodes))),
+                };
+                Node::Scripts { base, sub, sup }
+            }
+            "munder" | "mover" | "munderover" => {
+                let count = if name(el) == "munderover" { 3 } else { 2 };
+                let children = elements(el).collect::<Vec<_>>();
+                let mut nodes = self.arguments(el, count)?;
+                let movable = Self::is_movable_base(children.first().copied());
+                let base = box_(&mut nodes);
+                let (under, over, accent) = match name(el) {
+                    "munder" => (
+                        Some(box_(&mut nodes)),
+                        None,
+                        Self::is_accent_of(children.get(1).copied(), attr(el, "accentunder")),
+                    ),
+                    "mover" => (
+                        None,
+                        Some(box_(&mut nodes)),
+                        Self::is_accent_of(children.get(1).copied(), attr(el, "accent")),
+                    ),
+                    _ => (
+                        Some(box_(&mut nodes)),
+                        Some(box_(&mut nodes)),
+                        Self::is_accent_of(children.get(2).copied(), attr(el, "accent")),
+                    ),
+                };
+                Node::UnderOver {
+                    base,
+                    under,
+                    over,
+                    movable,
+                    accent,
+                }
+            }
+            "mmultiscripts" => {
+                // Only the first scripts after the base, which is all but
+                // tensor notation has
+                let mut nodes = self
+                    .children(el)?
+                    .into_iter()
+                    .zip(elements(el))
+                    .take_while(|(_, child)| name(*child) != "mprescripts")
+                    .map(|(node, child)| (name(child) != "none").then_some(node))
+                    .collect::<Vec<_>>()
+                    .into_iter();
+                let base = nodes.next().flatten().unwrap_or(Node::Row(Vec::new()));
+                let sub = nodes.next().flatten().map(Box::new);
+                let sup = nodes.next().flatten().map(Box::new);
+                Node::Scripts {
+                    base: Box::new(base),
+                    sub,
+                    sup,
+                }
+            }
+            "mtable" => {
+                let align = attr(el, "columnalign")
+                    .unwrap_or("center")
+                    .split_whitespace()
+                    .map(|align| match align {
+                        "left" => Align::Left,
+                        "right" => Align::Right,
+                        _ => Align::Center,
+                    })
+                    .collect();
+                let mut rows = Vec::new();
+                for tr in elements(el) {
+                    let cells = elements(tr)
+                        // The label of a numbered equation is left out
+                        .skip(usize::from(name(tr) == "mlabeledtr"))
+                        .map(|td| match name(td) {
+                            "mtd" => self.row(td),
+                            _ => self.convert(td),
+                        })
+                        .collect::<Result<Vec<_>>>()?;
+                    rows.push(cells);
+                }
+                Node::Table {
+                    rows,
+                    align,
+                    spacing: 0.8,
+                }
+            }
+            "mfenced" => {
+                let open = attr(el, "open").unwrap_or("(");
+                let close = attr(el, "close").unwrap_or(")");
+                let separators = attr(el, "separators")
+                    .unwrap_or(",")
+                    .chars()
+                    .filter(|c| !c.is_whitespace())
+                    .collect::<Vec<_>>();
+                let fence = |text: &str| {
+                    Node::Token(Token {
+                        kind: Kind::Operator,
+                        text: text.to_string(),
+                        variant: Variant::Auto,
+                        stretch: Stretch::Fit,
+                    })
+                };
+                let mut nodes = Vec::new();
+                if !open.is_empty() {
+                    nodes.push(fence(open));
+                }
+                for (i, child) in self.children(el)?.into_iter().enumerate() {
+                    if i > 0 {
+                        if let Some(&separator) = separators.get(i - 1).or(separators.last()) {
+                            nodes.push(Node::token(Kind::Operator, separator.to_string()));
+                        }
+                    }
+                    nodes.push(child);
+                }
+                if !close.is_empty() {
+                    nodes.push(fence(close));
+                }
+                Node::Row(nodes)
+            }
+            "mphantom" => Node::Phantom(Box::new(self.row(el)?)),
+            "semantics" => match elements(el).next() {
+                Some(child) => self.convert(child)?,
+                None => Node::Row(Vec::new()),
+            },
+            "maction" => {
+                let selection = attr(el, "selection")
+                    .and_then(|selection| selection.trim().parse::<usize>().ok())
+                    .unwrap_or(1);
+                match elements(el).nth(selection.saturating_sub(1)) {
+                    Some(child) => self.convert(child)?,
+                    None => Node::Row(Vec::new()),
+                }
+            }
+            // `math`, `mrow` and those drawn as their content, such as
+            // `menclose`, `mpadded` and `merror`
+            _ => self.row(el)?,
+        })
+    }
+}
+
+/// The `<math>` element `markup`.
+pub fn parse(markup: &str) -> Result<Math> {
+    let markup = prepare(markup);
+    let doc = parse_xml(&markup)?;
+    let root = doc.root_element();
+    if name(root) != "math" {
+        return Err(error(format!("<{}> is not math", name(root))));
+    }
+    let display = attr(root, "display") == Some("block") || attr(root, "mode") == Some("display");
+    let label = match attr(root, "alttext") {
+        Some(alttext) => alttext.to_string(),
+        None => {
+            let mut words = Vec::new();
+            spoken(root, &mut words);
+            words.join(" ")
+        }
+    };
+    let node = Converter {
+        variant: Variant::Auto,
+    }
+    .row(root)?;
+    Ok(Math {
+        node,
+        display,
+        label,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(node: &Node) -> Vec<&str> {
+        match node {
+            Node::Token(token) => vec![token.text.as_str()],
+            Node::Row(nodes) => nodes.iter().flat_map(texts).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn prefixed_math_with_mathml_entities() {
+        let math = parse(
+            "<m:math display=\"block\"><m:mi>&alpha;</m:mi><m:mo>&le;</m:mo>\
+             <m:mn>2</m:mn><m:mo>-</m:mo></m:math>",
+        )
+        .unwrap();
+        assert!(math.display);
+        assert_eq!(math.label, "α ≤ 2 -");
+        assert_eq!(texts(&math.node), ["α", "≤", "2", "−"]);
+    }
+
+    #[test]
+    fn alttext_is_the_label() {
+        let math = parse("<math alttext=\"x squared\"><msup><mi>x</mi><mn>2</mn></msup></math>");
+        let math = math.unwrap();
+        assert_eq!(math.label, "x squared");
+        assert!(!math.display);
+        assert!(matches!(
+            math.node,
+            Node::Scripts {
+                sub: None,
+                sup: Some(_),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_markup() {
+        for markup in [
+            "",
+            "<math",
+            "<math><mi>x</math>",
+            "<math><mi>&nosuch;</mi></math>",
+            "<div><math/></div>",
+            "<math><mfrac><mn>1</mn></mfrac></math>",
+            "<math><mroot><mn>1</mn><mn>2</mn><mn>3</mn></mroot></math>",
+            "<math><munderover><mo>&sum;</mo></munderover></math>",
+        ] {
+            assert!(parse(markup).is_err(), "{markup}");
+        }
+    }
+
+    #[test]
+    fn missing_parts_become_empty() {
+        let math = parse(
+            "<math><mmultiscripts><mi>R</mi><none/><mi>j</mi><mprescripts/><mi>k</mi>\
+             </mmultiscripts><maction selection=\"9\"><mi>a</mi></maction><semantics/></math>",
+        )
+        .unwrap();
+        let Node::Row(nodes) = &math.node else {
+            panic!("{:?}", math.node);
+        };
+        assert!(matches!(
+            &nodes[0],
+            Node::Scripts {
+                sub: None,
+                sup: Some(_),
+                ..
+            }
+        ));
+        assert!(matches!(&nodes[1], Node::Row(row) if row.is_empty()));
+        assert!(matches!(&nodes[2], Node::Row(row) if row.is_empty()));
+    }
+
+    #[test]
+    fn mfenced_reuses_its_last_separator() {
+        let math = parse(
+            "<math><mfenced separators=\";\"><mi>a</mi><mi>b</mi><mi>c</mi></mfenced></math>",
+        )
+        .unwrap();
+        assert_eq!(texts(&math.node), ["(", "a", ";", "b", ";", "c", ")"]);
+    }
+}
@@ -0,0 +1,243 @@
+//! Math of chapters drawn natively as SVG, for technical books whose
+//! equations take MathJax or the webview's MathML seconds per chapter to
+//! lay out. MathML elements and LaTeX between `\(`/`\)`, `\[`/`\]` or
+//! `$$` are read into one tree of boxes, laid out the way TeX does, and
+//! drawn as inline SVG taking the color of the text around it.
+//!
+//! It is turned on per book with the text transforms, so chapters have
+//! their math replaced as they are served over the `book://` protocol;
+//! [`render_math`] renders single fragments for everything else.
+
+mod latex;
+mod layout;
+mod mathml;
+
+use std::ops::Range;
+
+use serde::Deserialize;
+use tauri::command;
+
+use crate::error::Result;
+use crate::formats::html::{decode_entities, tag_name};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Identifier,
+    Number,
+    Operator,
+    /// Names of functions such as `sin`, upright and spaced like
+    /// operators.
+    Function,
+    Text,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Variant {
+    /// Italic for single letters, upright for the rest.
+    #[default]
+    Auto,
+    Normal,
+    Italic,
+    Bold,
+    BoldItalic,
+    DoubleStruck,
+    Script,
+    Fraktur,
+    SansSerif,
+    Monospace,
+}
+
+/// How a delimiter or an accent grows with what it goes with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stretch {
+    No,
+    /// To the height of the row, or the width of what it is over.
+    Fit,
+    /// To this many ems high, as `\big` and its kin.
+    To(f32),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Token {
+    kind: Kind,
+    text: String,
+    variant: Variant,
+    stretch: Stretch,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Token(Token),
+    Row(Vec<Node>),
+    Frac {
+        num: Box<Node>,
+        den: Box<Node>,
+        /// Without one for binomials.
+        line: bool,
+    },
+    Root {
+        base: Box<Node>,
+        index: Option<Box<Node>>,
+    },
+    Scripts {
+        base: Box<Node>,
+        sub: Option<Box<Node>>,
+        sup: Option<Box<Node>>,
+    },
+    UnderOver {
+        base: Box<Node>,
+        under: Option<Box<Node>>,
+        over: Option<Box<Node>>,
+        /// Limits that go to the side, as scripts, out of display math.
+        movable: bool,
+        /// Accents sit close and keep the size of their base.
+        accent: bool,
+    },
+    Table {
+        rows: Vec<Vec<Node>>,
+        /// Of each column, the last for those after it.
+        align: Vec<Align>,
+        /// Between columns, in ems.
+        spacing: f32,
+    },
+    Style {
+        display: bool,
+        body: Box<Node>,
+    },
+    /// Takes its room without being drawn.
+    Phantom(Box<Node>),
+    /// In ems, negative to move back.
+    Space(f32),
+}
+
+impl Node {
+    fn token(kind: Kind, text: impl Into<String>) -> Node {
+        Node::Token(Token {
+            kind,
+            text: text.into(),
+            variant: Variant::Auto,
+            stretch: Stretch::No,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Notation {
+    Mathml,
+    Latex,
+}
+
+/// MathML `markup`, a `<math>` element, as SVG.
+fn mathml_to_svg(markup: &str) -> Result<String> {
+    let math = mathml::parse(markup)?;
+    Ok(layout::svg(&math.node, math.display, &math.label))
+}
+
+fn latex_to_svg(tex: &str, display: bool) -> Result<String> {
+    let node = latex::parse(tex)?;
+    Ok(layout::svg(&node, display, tex.trim()))
+}
+
+/// Length of the `<math>` element whose opening tag `rest` follows, up to
+/// the end of its closing tag, `None` when it is not closed.
+pub fn element_len(rest: &str) -> Option<usize> {
+    let lower = rest.to_ascii_lowercase();
+    let mut from = 0;
+    while let Some(i) = lower[from..].find("</") {
+        let start = from + i + 2;
+        let end = start + lower[start..].find('>')?;
+        // Math elements do not nest, the first closing one is the end
+        if tag_name(&lower[start..end]) == "math" {
+            return Some(end + 1);
+        }
+        from = start;
+    }
+    None
+}
+
+/// `<math>` element `markup` of a chapter as SVG, `None` when it cannot
+/// be drawn and is better left to the webview.
+pub fn render_element(markup: &str) -> Option<String> {
+    match mathml_to_svg(markup) {
+        Ok(svg) => Some(svg),
+        Err(e) => {
+            log::warn!("Failed to render MathML: {e}");
+            None
+        }
+    }
+}
+
+/// LaTeX in a text of a chapter, between its delimiters.
+pub struct Delimited {
+    /// Of the whole, delimiters included.
+    pub range: Range<usize>,
+    tex: Range<usize>,
+    display: bool,
+}
+
+/// The first LaTeX in `text`, raw text of a chapter. A single `$` is too
+/// often money to be taken for math.
+pub fn find_latex(text: &str) -> Option<Delimited> {
+    const DELIMITERS: [(&str, &str, bool); 3] = [
+        ("\\(", "\\)", false),
+        ("\\[", "\\]", true),
+        ("$$", "$$", true),
+    ];
+    let mut from = 0;
+    loop {
+        let (start, open, close, display) = DELIMITERS
+            .iter()
+            .filter_map(|&(open, close, display)| {
+                let start = from + text[from..].find(open)?;
+                Some((start, open, close, display))
+            })
+            .min_by_key(|&(start, ..)| start)?;
+        let inner = start + open.len();
+        match text[inner..].find(close) {
+            Some(len) => {
+                return Some(Delimited {
+                    range: start..inner + len + close.len(),
+                    tex: inner..inner + len,
+                    display,
+                })
+            }
+            None => from = inner,
+        }
+    }
+}
+
+/// `latex`, found in `text`, as SVG, `None` when it cannot be drawn.
+pub fn render_latex(text: &str, latex: &Delimited) -> Option<String> {
+    let tex = decode_entities(&text[latex.tex.clone()]);
+    match latex_to_svg(&tex, latex.display) {
+        Ok(svg) => Some(svg),
+        Err(e) => {
+            log::warn!("Failed to render LaTeX {tex:?}: {e}");
+            None
+        }
+    }
+}
+
+/// Renders `source`, in MathML or LaTeX, as SVG, for math that is not in
+/// chapters served over the `book://` protocol. `display` is for LaTeX,
+/// MathML says it itself.
+#[command]
+pub async fn render_math(
+    source: String,
+    notation: Notation,
+    display: Option<bool>,
+) -> Result<String> {
+    tauri::async_runtime::spawn_blocking(move || match notation {
+        Notation::Mathml => mathml_to_svg(&source),
+        Notation::Latex => latex_to_svg(&source, display.unwrap_or(false)),
+    })
+    .await?
+}
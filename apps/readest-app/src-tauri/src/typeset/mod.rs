@@ -0,0 +1,8 @@
+//! Typesetting help for the reader's layout engine that is too large or too
+//! slow to ship as JavaScript.
+
+pub mod hyphenation;
+pub mod math;
+pub mod pagination;
+pub mod styles;
+pub mod transform;
@@ -0,0 +1,380 @@
+//! Pagination of books, kept between openings so that a very large book
+//! does not have every chapter measured in the webview again each time it
+//! is opened. A pagination is kept per book and layout: what changes where
+//! pages break, such as the font and its size or the size of the page.
+//!
+//! Pages are first estimated here from the text of the chapters, with the
+//! widths of characters taken as averages of their scripts, and then made
+//! exact chapter by chapter as the reader measures the chapters it lays
+//! out. Offsets are in characters of the text of a chapter as
+//! [`crate::formats::extract_chapters`] has it, a line break between
+//! blocks; images are not counted, so chapters of pictures come out
+//! short until measured.
+
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tauri::{command, AppHandle};
+
+use crate::error::{Error, Result};
+use crate::formats;
+use crate::paths;
+use crate::utils::now_millis;
+
+const CACHE_DIR: &str = "pagination";
+/// Layouts kept per book, the least recently used are deleted.
+const MAX_LAYOUTS: usize = 8;
+/// Version of the estimate, paginations of others are estimated again.
+const ESTIMATE_VERSION: u32 = 1;
+
+/// Widths, in ems, of the characters of proportional fonts on average.
+const LETTER_WIDTH: f64 = 0.5;
+const SPACE_WIDTH: f64 = 0.25;
+const WIDE_WIDTH: f64 = 1.0;
+
+/// What pages break by, in CSS pixels: the reader's font and spacing and
+/// the size of the text on a page.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LayoutSettings {
+    pub font_family: String,
+    pub font_size: f64,
+    /// Of the font size.
+    pub line_height: f64,
+    /// Between paragraphs, in ems.
+    #[serde(default)]
+    pub paragraph_spacing: f64,
+    /// Of the text on a page, margins and gaps between columns taken off.
+    pub page_width: f64,
+    pub page_height: f64,
+    #[serde(default = "one_column")]
+    pub columns: u32,
+    /// Anything else the reader lays out by, such as whether text is
+    /// justified or hyphenated, only told apart.
+    #[serde(default)]
+    pub extra: Option<serde_json::Value>,
+}
+
+fn one_column() -> u32 {
+    1
+}
+
+impl LayoutSettings {
+    /// Key of the settings among those of a book.
+    fn key(&self) -> Result<String> {
+        let hash = Sha256::digest(serde_json::to_vec(self)?);
+        Ok(format!("{hash:x}")[..16].to_string())
+    }
+
+    fn check(&self) -> Result<()> {
+        let sizes = [
+            self.font_size,
+            self.line_height,
+            self.page_width,
+            self.page_height,
+        ];
+        if sizes.iter().any(|size| !size.is_finite() || *size <= 0.0) || self.columns == 0 {
+            return Err(Error::Pagination("invalid layout settings".to_string()));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChapterPages {
+    /// Position in reading order.
+    pub index: usize,
+    pub href: String,
+    pub characters: u64,
+    /// Offset of the chapter in the book.
+    pub start: u64,
+    /// Offsets in the chapter where its pages start, the first at 0.
+    pub pages: Vec<u64>,
+    /// Whether the reader measured the pages, rather than they were
+    /// estimated.
+    pub measured: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Pagination {
+    pub book_hash: String,
+    pub chapters: Vec<ChapterPages>,
+    pub characters: u64,
+    pub pages: u64,
+    pub updated_at: i64,
+    version: u32,
+}
+
+impl Pagination {
+    /// Sets the offsets of the chapters and the totals from their pages.
+    fn sum(&mut self) {
+        let mut start = 0;
+        for chapter in &mut self.chapters {
+            chapter.start = start;
+            start += chapter.characters;
+        }
+        self.characters = start;
+        self.pages = self.chapters.iter().map(|c| c.pages.len() as u64).sum();
+        self.updated_at = now_millis();
+    }
+}
+
+fn advance(c: char) -> f64 {
+    match c as u32 {
+        0x1100..=0x115f
+        | 0x2e80..=0xa4cf
+        | 0xac00..=0xd7a3
+        | 0xf900..=0xfaff
+        | 0xfe30..=0xfe4f
+        | 0xff00..=0xff60
+        | 0xffe0..=0xffe6
+        | 0x1f300..=0x1faff
+        | 0x20000..=0x3fffd => WIDE_WIDTH,
+        _ if c.is_whitespace() => SPACE_WIDTH,
+        _ => LETTER_WIDTH,
+    }
+}
+
+/// Fills lines and pages with text, breaking lines between words.
+struct Paginator {
+    /// Width of a line, in ems.
+    line_width: f64,
+    lines_per_page: u32,
+    /// Lines between paragraphs.
+    paragraph_lines: f64,
+    pages: Vec<u64>,
+    /// Lines on the current page, gaps between paragraphs included.
+    lines: f64,
+}
+
+impl Paginator {
+    fn new(layout: &LayoutSettings) -> Self {
+        let line = layout.font_size * layout.line_height;
+        let lines_per_column = (layout.page_height / line).floor().max(1.0) as u32;
+        Self {
+            line_width: layout.page_width / layout.columns as f64 / layout.font_size,
+            lines_per_page: lines_per_column * layout.columns,
+            paragraph_lines: layout.paragraph_spacing / layout.line_height,
+            pages: vec![0],
+            lines: 0.0,
+        }
+    }
+
+    /// Moves on to a line starting at `offset`, or a page.
+    fn new_line(&mut self, offset: u64) {
+        self.lines += 1.0;
+        self.break_page(offset);
+    }
+
+    /// Starts a page at `offset` unless another line fits on this one.
+    fn break_page(&mut self, offset: u64) {
+        if self.lines + 1.0 > self.lines_per_page as f64 {
+            if self.pages.last().is_some_and(|&last| last < offset) {
+                self.pages.push(offset);
+            }
+            self.lines = 0.0;
+        }
+    }
+
+    /// Lays out a paragraph starting at `offset`.
+    fn paragraph(&mut self, text: &str, offset: u64) {
+        // Widths of the words on the current line and of the word being read
+        let (mut line, mut word) = (0.0, 0.0);
+        let mut word_start = offset;
+        for (i, c) in text.chars().enumerate() {
+            let at = offset + i as u64;
+            let width = advance(c);
+            if c.is_whitespace() {
+                line += word + width;
+                word = 0.0;
+                word_start = at + 1;
+                continue;
+            }
+            // Wide characters break anywhere, as CJK text does
+            if width == WIDE_WIDTH {
+                line += word;
+                word = 0.0;
+                word_start = at;
+            }
+            if line + word + width > self.line_width {
+                if line > 0.0 {
+                    self.new_line(word_start);
+                    line = 0.0;
+                } else {
+                    // A word longer than the line
+                    self.new_line(at);
+                    word = 0.0;
+                    word_start = at;
+                }
+            }
+            word += width;
+        }
+        let end = offset + text.chars().count() as u64;
+        if line + word > 0.0 {
+            self.new_line(end + 1);
+            // Paragraphs are not spaced at the top of a page
+            if self.lines > 0.0 {
+                self.lines += self.paragraph_lines;
+                self.break_page(end + 1);
+            }
+        }
+    }
+
+    fn finish(mut self, characters: u64) -> Vec<u64> {
+        // A break at the very end starts no page
+        while self.pages.len() > 1 && self.pages.last().is_some_and(|&at| at >= characters) {
+            self.pages.pop();
+        }
+        self.pages
+    }
+}
+
+/// Estimated pages of `text`, the text of a chapter, in `layout`.
+fn estimate(text: &str, layout: &LayoutSettings) -> Vec<u64> {
+    let mut paginator = Paginator::new(layout);
+    let mut offset = 0;
+    for paragraph in text.split('\n') {
+        paginator.paragraph(paragraph, offset);
+        offset += paragraph.chars().count() as u64 + 1;
+    }
+    paginator.finish(text.chars().count() as u64)
+}
+
+fn estimate_book(
+    book_hash: &str,
+    path: &std::path::Path,
+    layout: &LayoutSettings,
+) -> Result<Pagination> {
+    let chapters = formats::extract_chapters(path)?
+        .into_iter()
+        .map(|chapter| ChapterPages {
+            index: chapter.index,
+            pages: estimate(&chapter.text, layout),
+            href: chapter.href,
+            characters: chapter.text.chars().count() as u64,
+            start: 0,
+            measured: false,
+        })
+        .collect();
+    let mut pagination = Pagination {
+        book_hash: book_hash.to_string(),
+        chapters,
+        characters: 0,
+        pages: 0,
+        updated_at: 0,
+        version: ESTIMATE_VERSION,
+    };
+    pagination.sum();
+    Ok(pagination)
+}
+
+fn book_dir(app: &AppHandle, book_hash: &str) -> Result<PathBuf> {
+    if book_hash.is_empty() || !book_hash.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(Error::Pagination(format!("invalid book hash {book_hash}")));
+    }
+    Ok(paths::cache_dir(app)?.join(CACHE_DIR).join(book_hash))
+}
+
+fn cache_path(app: &AppHandle, book_hash: &str, layout: &LayoutSettings) -> Result<PathBuf> {
+    Ok(book_dir(app, book_hash)?.join(format!("{}.json", layout.key()?)))
+}
+
+fn load(path: &std::path::Path) -> Option<Pagination> {
+    let bytes = std::fs::read(path).ok()?;
+    serde_json::from_slice::<Pagination>(&bytes)
+        .ok()
+        .filter(|pagination| pagination.version == ESTIMATE_VERSION)
+}
+
+/// Writes `pagination` to `path`, deleting the least recently used other
+/// layouts of the book past the most kept.
+fn save(path: &std::path::Path, pagination: &Pagination) -> Result<()> {
+    let dir = path.parent().unwrap_or(path);
+    std::fs::create_dir_all(dir)?;
+    std::fs::write(path, serde_json::to_vec(pagination)?)?;
+    let mut others = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path() != path)
+        .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+        .collect::<Vec<_>>();
+    others.sort();
+    while others.len() >= MAX_LAYOUTS {
+        let _ = std::fs::remove_file(others.remove(0).1);
+    }
+    Ok(())
+}
+
+/// The pagination of book `book_hash`, at `path`, in `layout`: the one
+/// kept from before or, the first time, an estimate.
+#[command]
+pub async fn get_pagination(
+    app: AppHandle,
+    book_hash: String,
+    path: PathBuf,
+    layout: LayoutSettings,
+) -> Result<Pagination> {
+    layout.check()?;
+    let cached = cache_path(&app, &book_hash, &layout)?;
+    tauri::async_runtime::spawn_blocking(move || {
+        if let Some(pagination) = load(&cached) {
+            return Ok(pagination);
+        }
+        let pagination = estimate_book(&book_hash, &path, &layout)?;
+        save(&cached, &pagination)?;
+        Ok(pagination)
+    })
+    .await?
+}
+
+/// Replaces the estimated pages of chapter `index` with `pages`, as the
+/// reader measured them, and returns the pagination with them.
+#[command]
+pub async fn set_chapter_pages(
+    app: AppHandle,
+    book_hash: String,
+    layout: LayoutSettings,
+    index: usize,
+    characters: u64,
+    pages: Vec<u64>,
+) -> Result<Pagination> {
+    layout.check()?;
+    let cached = cache_path(&app, &book_hash, &layout)?;
+    if pages.first() != Some(&0) || pages.windows(2).any(|pair| pair[0] >= pair[1]) {
+        return Err(Error::Pagination(
+            "pages have to start at 0 and go up".to_string(),
+        ));
+    }
+    tauri::async_runtime::spawn_blocking(move || {
+        let mut pagination = load(&cached).ok_or_else(|| {
+            Error::Pagination(format!("no pagination of {book_hash} in this layout"))
+        })?;
+        let chapter = pagination
+            .chapters
+            .iter_mut()
+            .find(|chapter| chapter.index == index)
+            .ok_or_else(|| Error::Pagination(format!("no chapter {index}")))?;
+        chapter.characters = characters;
+        chapter.pages = pages;
+        chapter.measured = true;
+        pagination.sum();
+        save(&cached, &pagination)?;
+        Ok(pagination)
+    })
+    .await?
+}
+
+/// Forgets the paginations of book `book_hash`, or of every book.
+#[command]
+pub async fn clear_pagination(app: AppHandle, book_hash: Option<String>) -> Result<()> {
+    let dir = match &book_hash {
+        Some(hash) => book_dir(&app, hash)?,
+        None => paths::cache_dir(&app)?.join(CACHE_DIR),
+    };
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,339 @@
+//! User styles: CSS, and scripts for those who want them, written by the
+//! user for every book or for some, and put into chapters as they are
+//! served over the `book://` protocol. Being in the chapter itself, they
+//! apply to content the frontend cannot reach into, such as chapters in
+//! sandboxed frames, and come after the book's own styles in the cascade.
+//!
+//! Whether scripts run is up to the frame the chapter is shown in; styles
+//! always apply.
+
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle, Emitter};
+
+use crate::error::{Error, Result};
+use crate::store;
+
+const STYLES_FILE: &str = "user_styles.json";
+/// Emitted with the hashes of the books whose chapters are served with
+/// other styles now, all of them for global styles.
+const CHANGED_EVENT: &str = "user-styles-changed";
+const MAX_CODE_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum StyleKind {
+    Css,
+    Script,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserStyle {
+    /// Given when saved without one.
+    #[serde(default)]
+    pub id: String,
+    pub name: String,
+    pub kind: StyleKind,
+    pub code: String,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    /// Applies to every book, else to `books`.
+    #[serde(default)]
+    pub global: bool,
+    /// Hashes of the books it applies to.
+    #[serde(default)]
+    pub books: Vec<String>,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+impl UserStyle {
+    fn applies_to(&self, book_hash: Option<&str>) -> bool {
+        self.enabled
+            && (self.global || book_hash.is_some_and(|hash| self.books.iter().any(|b| b == hash)))
+    }
+}
+
+/// What is put into a chapter: styles at the end of its head, scripts at
+/// the end of its body.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Injection {
+    head: String,
+    body: String,
+}
+
+impl Injection {
+    fn of<'a>(styles: impl IntoIterator<Item = &'a UserStyle>) -> Injection {
+        let mut injection = Injection::default();
+        for style in styles {
+            let id = escape_attribute(&style.id);
+            // In CDATA for chapters parsed as XHTML, hidden from CSS and JS
+            match style.kind {
+                StyleKind::Css => injection.head.push_str(&format!(
+                    "<style data-vl-user-style=\"{id}\">/*<![CDATA[*/\n{}\n/*]]>*/</style>",
+                    style.code
+                )),
+                StyleKind::Script => injection.body.push_str(&format!(
+                    "<script data-vl-user-style=\"{id}\">//<![CDATA[\n{}\n//]]></script>",
+                    style.code
+                )),
+            }
+        }
+        injection
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.is_empty() && self.body.is_empty()
+    }
+
+    /// `html`, a chapter, with the styles and scripts in it.
+    pub fn inject(&self, html: &str) -> String {
+        if self.is_empty() {
+            return html.to_string();
+        }
+        let lower = html.to_ascii_lowercase();
+        let mut out = String::with_capacity(html.len() + self.head.len() + self.body.len());
+        let after_tag = |name: &str| {
+            let start = lower.find(name)?;
+            Some(start + lower[start..].find('>')? + 1)
+        };
+        // Without a head, at the start of the body
+        let head_at = lower
+            .find("</head")
+            .or_else(|| after_tag("<body"))
+            .or_else(|| after_tag("<html"))
+            .unwrap_or(0);
+        let body_at = lower
+            .rfind("</body")
+            .filter(|&at| at >= head_at)
+            .unwrap_or(html.len());
+        out.push_str(&html[..head_at]);
+        out.push_str(&self.head);
+        out.push_str(&html[head_at..body_at]);
+        out.push_str(&self.body);
+        out.push_str(&html[body_at..]);
+        out
+    }
+}
+
+fn escape_attribute(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+}
+
+fn style_error(style: &UserStyle, message: impl std::fmt::Display) -> Error {
+    Error::UserStyle(format!("{}: {message}", style.name))
+}
+
+fn line_at(code: &str, at: usize) -> usize {
+    code[..at].matches('\n').count() + 1
+}
+
+/// Checks that CSS `code` is whole: its comments, strings and blocks all
+/// closed, so it cannot spill into what comes after it.
+fn check_css(code: &str) -> std::result::Result<(), String> {
+    let bytes = code.as_bytes();
+    let mut depth = 0usize;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'/' if bytes.get(i + 1) == Some(&b'*') => match code[i + 2..].find("*/") {
+                Some(end) => i += end + 3,
+                None => return Err(format!("unclosed comment on line {}", line_at(code, i))),
+            },
+            quote @ (b'"' | b'\'') => {
+                let start = i;
+                i += 1;
+                loop {
+                    match bytes.get(i) {
+                        Some(b'\\') => i += 1,
+                        Some(&c) if c == quote => break,
+                        Some(b'\n') | None => {
+                            return Err(format!("unclosed string on line {}", line_at(code, start)))
+                        }
+                        _ => {}
+                    }
+                    i += 1;
+                }
+            }
+            b'{' => depth += 1,
+            b'}' if depth == 0 => {
+                return Err(format!("unexpected }} on line {}", line_at(code, i)));
+            }
+            b'}' => depth -= 1,
+            b'@' if code[i..]
+                .get(..7)
+                .is_some_and(|at| at.eq_ignore_ascii_case("@import")) =>
+            {
+                // Chapters are not to load anything from the network
+                return Err(format!("@import on line {}", line_at(code, i)));
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    if depth > 0 {
+        return Err("unclosed {".to_string());
+    }
+    Ok(())
+}
+
+fn validate(style: &UserStyle) -> Result<()> {
+    if style.name.trim().is_empty() {
+        return Err(Error::UserStyle("a style needs a name".to_string()));
+    }
+    if style.code.len() > MAX_CODE_LEN {
+        return Err(style_error(
+            style,
+            format!("longer than {} KiB", MAX_CODE_LEN / 1024),
+        ));
+    }
+    let lower = style.code.to_ascii_lowercase();
+    let closing = match style.kind {
+        StyleKind::Css => "</style",
+        StyleKind::Script => "</script",
+    };
+    if lower.contains(closing) || style.code.contains("]]>") {
+        return Err(style_error(
+            style,
+            format!("{closing}> and ]]> cannot be in it"),
+        ));
+    }
+    if style.kind == StyleKind::Css {
+        check_css(&style.code).map_err(|e| style_error(style, e))?;
+    }
+    Ok(())
+}
+
+fn load(app: &AppHandle) -> Vec<UserStyle> {
+    store::load(app, STYLES_FILE)
+}
+
+fn save(app: &AppHandle, styles: &[UserStyle]) -> Result<()> {
+    store::save(app, STYLES_FILE, &styles)
+}
+
+fn new_id() -> String {
+    use rand::Rng;
+    format!("{:016x}", rand::thread_rng().gen::<u64>())
+}
+
+/// Tells the frontend which books to reload chapters of after `before`
+/// became `after`.
+fn notify(app: &AppHandle, before: &[UserStyle], after: &[UserStyle]) {
+    let changed = |style: &UserStyle| before.iter().find(|old| old.id == style.id) != Some(style);
+    let affected = after
+        .iter()
+        .filter(|style| changed(style))
+        .chain(
+            before
+                .iter()
+                .filter(|old| !after.iter().any(|style| style.id == old.id)),
+        )
+        .chain(
+            // Changed ones may have been for other books before
+            before.iter().filter(|old| {
+                after
+                    .iter()
+                    .find(|style| style.id == old.id)
+                    .is_some_and(&changed)
+            }),
+        )
+        .collect::<Vec<_>>();
+    let all = affected.iter().any(|style| style.global);
+    let mut books = Vec::new();
+    if !all {
+        for hash in affected.iter().flat_map(|style| &style.books) {
+            if !books.contains(hash) {
+                books.push(hash.clone());
+            }
+        }
+        if books.is_empty() {
+            return;
+        }
+    }
+    let _ = app.emit(CHANGED_EVENT, books);
+}
+
+/// What is put into the chapters of book `book_hash`, or of books not in
+/// the library for `None`.
+pub fn for_book(app: &AppHandle, book_hash: Option<&str>) -> Injection {
+    Injection::of(load(app).iter().filter(|style| style.applies_to(book_hash)))
+}
+
+/// The styles, in the order they are put into chapters, or only those for
+/// book `book_hash`.
+#[command]
+pub async fn get_user_styles(app: AppHandle, book_hash: Option<String>) -> Result<Vec<UserStyle>> {
+    let mut styles = load(&app);
+    if let Some(hash) = &book_hash {
+        styles.retain(|style| style.global || style.books.contains(hash));
+    }
+    Ok(styles)
+}
+
+/// Replaces the styles, once each of them is checked; those without an
+/// id get one. The chapters served from then on have them. An empty list
+/// of books is sent with `user-styles-changed` for all of them.
+#[command]
+pub async fn set_user_styles(app: AppHandle, mut styles: Vec<UserStyle>) -> Result<Vec<UserStyle>> {
+    for style in &mut styles {
+        validate(style)?;
+        if style.id.is_empty() {
+            style.id = new_id();
+        }
+    }
+    let before = load(&app);
+    save(&app, &styles)?;
+    notify(&app, &before, &styles);
+    Ok(styles)
+}
+
+/// Applies style `id` to book `book_hash` or stops applying it, for styles
+/// that are not global.
+#[command]
+pub async fn apply_user_style(
+    app: AppHandle,
+    id: String,
+    book_hash: String,
+    applied: bool,
+) -> Result<UserStyle> {
+    let before = load(&app);
+    let mut styles = before.clone();
+    let style = styles
+        .iter_mut()
+        .find(|style| style.id == id)
+        .ok_or_else(|| Error::UserStyle(format!("no style with id {id}")))?;
+    style.books.retain(|hash| *hash != book_hash);
+    if applied {
+        style.books.push(book_hash);
+        style.enabled = true;
+    }
+    let style = style.clone();
+    save(&app, &styles)?;
+    notify(&app, &before, &styles);
+    Ok(style)
+}
+
+/// `html`, a chapter of book `book_hash`, with `style`, which need not be
+/// saved, put into it along with the other styles of the book, for the
+/// style editor to show.
+#[command]
+pub async fn preview_user_style(
+    app: AppHandle,
+    style: UserStyle,
+    html: String,
+    book_hash: Option<String>,
+) -> Result<String> {
+    validate(&style)?;
+    let styles = load(&app);
+    let others = styles
+        .iter()
+        .filter(|other| other.id != style.id || style.id.is_empty())
+        .filter(|other| other.applies_to(book_hash.as_deref()));
+    Ok(Injection::of(others.chain([&style])).inject(&html))
+}
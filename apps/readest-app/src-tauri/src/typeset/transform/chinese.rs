@@ -0,0 +1,30 @@
+//! Pinyin over Chinese characters. Words are not segmented, so each
+//! character gets its most common reading, which for the few heteronyms,
+//! such as 行 in 银行, is not always the one meant.
+
+use pinyin::ToPinyin;
+
+use super::Piece;
+
+pub fn annotate(text: &str) -> Vec<Piece<'_>> {
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        let Some(reading) = c.to_pinyin() else {
+            continue;
+        };
+        if start < i {
+            pieces.push(Piece::Text(&text[start..i]));
+        }
+        let end = i + c.len_utf8();
+        pieces.push(Piece::Ruby {
+            base: &text[i..end],
+            reading: reading.with_tone().to_string(),
+        });
+        start = end;
+    }
+    if start < text.len() {
+        pieces.push(Piece::Text(&text[start..]));
+    }
+    pieces
+}
@@ -0,0 +1,219 @@
+//! Furigana through the C API of MeCab, which splits Japanese into words
+//! and reads them. Readings are taken where the IPADIC layout of features
+//! has them, which mecab-ipadic and its NEologd and NAIST offshoots share;
+//! words of other dictionaries are left without. Only the kanji of a word
+//! get the reading, the kana written after or before them do not.
+
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::sync::{Mutex, MutexGuard};
+
+use super::Piece;
+use crate::error::{Error, Result};
+
+#[allow(non_camel_case_types)]
+mod ffi {
+    use std::os::raw::{c_char, c_float, c_long, c_short, c_uchar, c_uint, c_ushort, c_void};
+
+    pub enum mecab_t {}
+
+    /// `MECAB_NOR_NODE` of `stat`, a word of the dictionary.
+    pub const MECAB_NOR_NODE: c_uchar = 0;
+    /// `MECAB_UNK_NODE` of `stat`, a word it does not have.
+    pub const MECAB_UNK_NODE: c_uchar = 1;
+
+    #[repr(C)]
+    pub struct mecab_node_t {
+        pub prev: *mut mecab_node_t,
+        pub next: *mut mecab_node_t,
+        pub enext: *mut mecab_node_t,
+        pub bnext: *mut mecab_node_t,
+        pub rpath: *mut c_void,
+        pub lpath: *mut c_void,
+        /// Not terminated, `length` bytes of the parsed text.
+        pub surface: *const c_char,
+        pub feature: *const c_char,
+        pub id: c_uint,
+        pub length: c_ushort,
+        pub rlength: c_ushort,
+        pub rc_attr: c_ushort,
+        pub lc_attr: c_ushort,
+        pub posid: c_ushort,
+        pub char_type: c_uchar,
+        pub stat: c_uchar,
+        pub isbest: c_uchar,
+        pub alpha: c_float,
+        pub beta: c_float,
+        pub prob: c_float,
+        pub wcost: c_short,
+        pub cost: c_long,
+    }
+
+    #[link(name = "mecab")]
+    extern "C" {
+        pub fn mecab_new2(arg: *const c_char) -> *mut mecab_t;
+        pub fn mecab_destroy(mecab: *mut mecab_t);
+        pub fn mecab_strerror(mecab: *mut mecab_t) -> *const c_char;
+        pub fn mecab_sparse_tonode2(
+            mecab: *mut mecab_t,
+            text: *const c_char,
+            len: usize,
+        ) -> *const mecab_node_t;
+    }
+}
+
+/// Feature of the reading, in katakana, in the IPADIC layout.
+const READING_FEATURE: usize = 7;
+
+fn is_kanji(c: char) -> bool {
+    matches!(c as u32, 0x3400..=0x4dbf | 0x4e00..=0x9fff | 0xf900..=0xfaff | 0x20000..=0x2fa1f)
+        || c == '々'
+}
+
+fn is_kana(c: char) -> bool {
+    matches!(c as u32, 0x3041..=0x3096 | 0x30a1..=0x30fa | 0x30fc)
+}
+
+fn hiragana(c: char) -> char {
+    match c as u32 {
+        0x30a1..=0x30f6 => char::from_u32(c as u32 - 0x60).unwrap_or(c),
+        _ => c,
+    }
+}
+
+/// Word `surface`, read as `reading`, with its kanji annotated.
+fn push_word<'a>(surface: &'a str, reading: &str, pieces: &mut Vec<Piece<'a>>) {
+    let surface_chars = surface.char_indices().collect::<Vec<_>>();
+    let reading = reading.chars().map(hiragana).collect::<Vec<_>>();
+    let (mut first, mut last) = (0, surface_chars.len());
+    let (mut reading_first, mut reading_last) = (0, reading.len());
+    // Kana the word starts or ends with are written as they are read
+    while first < last
+        && reading_first < reading_last
+        && is_kana(surface_chars[first].1)
+        && hiragana(surface_chars[first].1) == reading[reading_first]
+    {
+        first += 1;
+        reading_first += 1;
+    }
+    while last > first
+        && reading_last > reading_first
+        && is_kana(surface_chars[last - 1].1)
+        && hiragana(surface_chars[last - 1].1) == reading[reading_last - 1]
+    {
+        last -= 1;
+        reading_last -= 1;
+    }
+    let byte = |i: usize| surface_chars.get(i).map_or(surface.len(), |&(at, _)| at);
+    let base = &surface[byte(first)..byte(last)];
+    if reading_first == reading_last || !base.chars().any(is_kanji) {
+        pieces.push(Piece::Text(surface));
+        return;
+    }
+    if first > 0 {
+        pieces.push(Piece::Text(&surface[..byte(first)]));
+    }
+    pieces.push(Piece::Ruby {
+        base,
+        reading: reading[reading_first..reading_last].iter().collect(),
+    });
+    if last < surface_chars.len() {
+        pieces.push(Piece::Text(&surface[byte(last)..]));
+    }
+}
+
+pub struct Tagger {
+    handle: *mut ffi::mecab_t,
+}
+
+// The handle is only ever used through `&mut self`, behind a lock.
+unsafe impl Send for Tagger {}
+
+impl Drop for Tagger {
+    fn drop(&mut self) {
+        unsafe { ffi::mecab_destroy(self.handle) };
+    }
+}
+
+impl Tagger {
+    /// A tagger with the system's default dictionary, as its `mecabrc`
+    /// names.
+    pub fn new() -> Result<Self> {
+        let handle = unsafe { ffi::mecab_new2(c"".as_ptr()) };
+        if handle.is_null() {
+            let error = unsafe { CStr::from_ptr(ffi::mecab_strerror(std::ptr::null_mut())) };
+            return Err(Error::Transform(format!(
+                "cannot start MeCab: {}",
+                error.to_string_lossy()
+            )));
+        }
+        Ok(Self { handle })
+    }
+
+    /// `text` split into words, with readings over those with kanji.
+    pub(super) fn annotate<'a>(&mut self, text: &'a str) -> Result<Vec<Piece<'a>>> {
+        if !text.chars().any(is_kanji) {
+            return Ok(vec![Piece::Text(text)]);
+        }
+        let start = text.as_ptr() as usize;
+        let mut node = unsafe {
+            ffi::mecab_sparse_tonode2(self.handle, text.as_ptr() as *const c_char, text.len())
+        };
+        if node.is_null() {
+            let error = unsafe { CStr::from_ptr(ffi::mecab_strerror(self.handle)) };
+            return Err(Error::Transform(error.to_string_lossy().into_owned()));
+        }
+        let mut pieces = Vec::new();
+        // End of the text taken into pieces so far
+        let mut done = 0;
+        while !node.is_null() {
+            let current = unsafe { &*node };
+            node = current.next;
+            if !matches!(current.stat, ffi::MECAB_NOR_NODE | ffi::MECAB_UNK_NODE) {
+                continue;
+            }
+            let Some(offset) = (current.surface as usize).checked_sub(start) else {
+                continue;
+            };
+            let end = offset + current.length as usize;
+            let (Some(surface), Some(before)) = (text.get(offset..end), text.get(done..offset))
+            else {
+                continue;
+            };
+            if !before.is_empty() {
+                pieces.push(Piece::Text(before));
+            }
+            done = end;
+            let feature = unsafe { CStr::from_ptr(current.feature) }.to_string_lossy();
+            match feature
+                .split(',')
+                .nth(READING_FEATURE)
+                .filter(|reading| reading.chars().all(is_kana))
+            {
+                Some(reading) if surface.chars().any(is_kanji) => {
+                    push_word(surface, reading, &mut pieces)
+                }
+                _ => pieces.push(Piece::Text(surface)),
+            }
+        }
+        if done < text.len() {
+            pieces.push(Piece::Text(&text[done..]));
+        }
+        Ok(pieces)
+    }
+}
+
+/// The tagger, started the first time furigana are asked for: loading the
+/// dictionary takes a moment.
+#[derive(Default)]
+pub struct Mecab(Mutex<Option<Tagger>>);
+
+impl Mecab {
+    pub fn tagger(&self) -> Result<MutexGuard<'_, Option<Tagger>>> {
+        let mut tagger = self.0.lock().unwrap();
+        if tagger.is_none() {
+            *tagger = Some(Tagger::new()?);
+        }
+        Ok(tagger)
+    }
+}
@@ -0,0 +1,341 @@
+//! Accessibility transforms of chapter markup, turned on per book and done
+//! as chapters are served over the `book://` protocol, so books are never
+//! rewritten and chapters open about as fast as without:
+//!
+//! - bionic reading, the first half or so of each word in bold, for the
+//!   eye to jump from word to word;
+//! - furigana, the readings of kanji in Japanese as ruby, from MeCab with
+//!   the `mecab` feature;
+//! - pinyin over Chinese characters, as ruby;
+//! - math, MathML elements and LaTeX in the text, drawn as SVG by
+//!   [`crate::typeset::math`].
+//!
+//! But for math, only text is changed, never tags or attributes, and not
+//! the text of code, of existing ruby or of what is not shown, such as the
+//! head.
+
+mod chinese;
+#[cfg(feature = "mecab")]
+pub mod japanese;
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "mecab")]
+use tauri::Manager;
+use tauri::{command, AppHandle};
+
+use crate::error::{Error, Result};
+use crate::formats::html::tag_name;
+use crate::store;
+use crate::typeset::math;
+
+const TRANSFORMS_FILE: &str = "text_transforms.json";
+/// Elements whose text is left alone.
+const SKIPPED_TAGS: &[&str] = &[
+    "head", "title", "script", "style", "svg", "math", "ruby", "rt", "rp", "code", "pre", "kbd",
+    "samp", "textarea",
+];
+/// Apostrophes, which words such as "don't" have within them.
+const APOSTROPHES: &[char] = &['\'', '\u{2019}'];
+/// Longest entity reference, `&CounterClockwiseContourIntegral;`.
+const MAX_ENTITY_LEN: usize = 33;
+
+/// Annotations put over the characters of a book.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Ruby {
+    /// Readings of the kanji of Japanese.
+    Furigana,
+    /// Pinyin of Chinese characters.
+    Pinyin,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Transforms {
+    pub bionic: bool,
+    pub ruby: Option<Ruby>,
+    pub math: bool,
+}
+
+impl Transforms {
+    pub fn is_empty(&self) -> bool {
+        !self.bionic && self.ruby.is_none() && !self.math
+    }
+}
+
+/// The transforms of book `book_hash`, none unless turned on.
+pub fn for_book(app: &AppHandle, book_hash: &str) -> Transforms {
+    store::load::<BTreeMap<String, Transforms>>(app, TRANSFORMS_FILE)
+        .remove(book_hash)
+        .unwrap_or_default()
+}
+
+/// Part of a text: as it is, or with an annotation over it.
+enum Piece<'a> {
+    Text(&'a str),
+    Ruby { base: &'a str, reading: String },
+}
+
+/// Letters of the scripts written with spaces between words, which are the
+/// ones bionic reading is for.
+fn is_spaced_letter(c: char) -> bool {
+    c.is_alphabetic()
+        && !matches!(c as u32,
+            0xe00..=0xe7f | 0x2e80..=0x9fff | 0xac00..=0xd7af | 0xf900..=0xfaff
+                | 0xff66..=0xff9f | 0x20000..=0x2fa1f)
+}
+
+/// Letters of a word of `len` letters put in bold.
+fn fixation(len: usize) -> usize {
+    match len {
+        0..=3 => 1,
+        len => len.div_ceil(2),
+    }
+}
+
+fn push_bionic(text: &str, out: &mut String) {
+    let mut rest = text;
+    while let Some(start) = rest.find(is_spaced_letter) {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        // Ends of the letters of the word, apostrophes within it included
+        let mut ends = Vec::new();
+        let mut chars = rest.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            if is_spaced_letter(c) {
+                ends.push(i + c.len_utf8());
+            } else if !(APOSTROPHES.contains(&c)
+                && chars
+                    .peek()
+                    .is_some_and(|&(_, next)| is_spaced_letter(next)))
+            {
+                break;
+            }
+        }
+        let end = ends.last().copied().unwrap_or_default();
+        let word = &rest[..end];
+        let split = ends[fixation(ends.len()) - 1];
+        out.push_str("<b class=\"vl-bionic\">");
+        out.push_str(&word[..split]);
+        out.push_str("</b>");
+        out.push_str(&word[split..]);
+        rest = &rest[end..];
+    }
+    out.push_str(rest);
+}
+
+struct Transformer<'a> {
+    transforms: &'a Transforms,
+    #[cfg(feature = "mecab")]
+    tagger: Option<std::sync::MutexGuard<'a, Option<japanese::Tagger>>>,
+}
+
+impl Transformer<'_> {
+    fn push_plain(&self, text: &str, out: &mut String) {
+        if self.transforms.bionic {
+            push_bionic(text, out);
+        } else {
+            out.push_str(text);
+        }
+    }
+
+    fn pieces<'t>(&mut self, text: &'t str) -> Result<Vec<Piece<'t>>> {
+        Ok(match self.transforms.ruby {
+            Some(Ruby::Pinyin) => chinese::annotate(text),
+            #[cfg(feature = "mecab")]
+            Some(Ruby::Furigana) => match self.tagger.as_deref_mut() {
+                Some(Some(tagger)) => tagger.annotate(text)?,
+                _ => vec![Piece::Text(text)],
+            },
+            _ => vec![Piece::Text(text)],
+        })
+    }
+
+    /// Transforms `text`, without entity references.
+    fn push_segment(&mut self, text: &str, out: &mut String) -> Result<()> {
+        for piece in self.pieces(text)? {
+            match piece {
+                Piece::Text(text) => self.push_plain(text, out),
+                Piece::Ruby { base, reading } => {
+                    out.push_str("<ruby>");
+                    out.push_str(base);
+                    out.push_str("<rp>(</rp><rt>");
+                    out.push_str(&reading);
+                    out.push_str("</rt><rp>)</rp></ruby>");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Transforms the raw text between two tags, keeping its entity
+    /// references as they are.
+    fn push_text(&mut self, text: &str, out: &mut String) -> Result<()> {
+        let mut rest = text;
+        while let Some(start) = rest.find('&') {
+            let entity = rest[start + 1..]
+                .char_indices()
+                .take(MAX_ENTITY_LEN)
+                .find(|&(_, c)| !(c.is_ascii_alphanumeric() || c == '#'))
+                .filter(|&(i, c)| c == ';' && i > 0)
+                .map(|(i, _)| start + i + 2);
+            let Some(end) = entity else {
+                // A stray ampersand, part of the text
+                self.push_segment(&rest[..start + 1], out)?;
+                rest = &rest[start + 1..];
+                continue;
+            };
+            self.push_segment(&rest[..start], out)?;
+            out.push_str(&rest[start..end]);
+            rest = &rest[end..];
+        }
+        self.push_segment(rest, out)
+    }
+
+    /// Transforms the raw text between two tags, with the LaTeX in it
+    /// drawn when math is on.
+    fn push_run(&mut self, text: &str, out: &mut String) -> Result<()> {
+        let mut rest = text;
+        if self.transforms.math {
+            while let Some(latex) = math::find_latex(rest) {
+                self.push_text(&rest[..latex.range.start], out)?;
+                match math::render_latex(rest, &latex) {
+                    Some(svg) => out.push_str(&svg),
+                    None => out.push_str(&rest[latex.range.clone()]),
+                }
+                rest = &rest[latex.range.end..];
+            }
+        }
+        self.push_text(rest, out)
+    }
+}
+
+/// `html`, a chapter, with `transforms` applied to its text.
+pub fn transform(app: &AppHandle, html: &str, transforms: &Transforms) -> Result<String> {
+    #[cfg(feature = "mecab")]
+    let mecab = app.state::<japanese::Mecab>();
+    #[cfg(not(feature = "mecab"))]
+    let _ = app;
+    let mut transformer = Transformer {
+        transforms,
+        #[cfg(feature = "mecab")]
+        tagger: match transforms.ruby {
+            Some(Ruby::Furigana) => Some(mecab.tagger()?),
+            _ => None,
+        },
+    };
+
+    let mut out = String::with_capacity(html.len() + html.len() / 2);
+    // The skipped element the text is in, and how deep in others of its name
+    let mut skipped: Option<(String, usize)> = None;
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        let text = &rest[..start];
+        if skipped.is_some() {
+            out.push_str(text);
+        } else {
+            transformer.push_run(text, &mut out)?;
+        }
+        rest = &rest[start..];
+        let end = if rest.starts_with("<!--") {
+            rest.find("-->").map_or(rest.len(), |end| end + 3)
+        } else if rest.starts_with("<![CDATA[") {
+            rest.find("]]>").map_or(rest.len(), |end| end + 3)
+        } else {
+            rest.find('>').map_or(rest.len(), |end| end + 1)
+        };
+        let tag = &rest[..end];
+        rest = &rest[end..];
+        let inner = tag.trim_start_matches('<').trim_end_matches('>');
+        let opens_math =
+            !inner.starts_with('/') && !inner.ends_with('/') && tag_name(inner) == "math";
+        if transforms.math && skipped.is_none() && opens_math {
+            let rendered = math::element_len(rest).and_then(|len| {
+                let svg = math::render_element(&format!("{tag}{}", &rest[..len]))?;
+                Some((svg, len))
+            });
+            // Else left to the webview, as math not drawn here is
+            if let Some((svg, len)) = rendered {
+                out.push_str(&svg);
+                rest = &rest[len..];
+                continue;
+            }
+        }
+        out.push_str(tag);
+
+        if inner.starts_with(['!', '?']) || inner.ends_with('/') {
+            continue;
+        }
+        let closing = inner.starts_with('/');
+        let name = tag_name(inner);
+        match &mut skipped {
+            Some((skipped_name, depth)) if *skipped_name == name => {
+                if closing {
+                    *depth -= 1;
+                    if *depth == 0 {
+                        skipped = None;
+                    }
+                } else {
+                    *depth += 1;
+                }
+            }
+            Some(_) => {}
+            None if !closing && SKIPPED_TAGS.contains(&name.as_str()) => {
+                skipped = Some((name, 1));
+            }
+            None => {}
+        }
+    }
+    if skipped.is_some() {
+        out.push_str(rest);
+    } else {
+        transformer.push_run(rest, &mut out)?;
+    }
+    Ok(out)
+}
+
+fn check(transforms: &Transforms) -> Result<()> {
+    if cfg!(not(feature = "mecab")) && transforms.ruby == Some(Ruby::Furigana) {
+        return Err(Error::Transform(
+            "furigana needs MeCab, which this build does not include".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[command]
+pub async fn get_text_transforms(app: AppHandle, book_hash: String) -> Result<Transforms> {
+    Ok(for_book(&app, &book_hash))
+}
+
+/// Sets the transforms of book `book_hash`, which apply to the chapters
+/// served from then on.
+#[command]
+pub async fn set_text_transforms(
+    app: AppHandle,
+    book_hash: String,
+    transforms: Transforms,
+) -> Result<()> {
+    check(&transforms)?;
+    let mut all: BTreeMap<String, Transforms> = store::load(&app, TRANSFORMS_FILE);
+    if transforms.is_empty() {
+        all.remove(&book_hash);
+    } else {
+        all.insert(book_hash, transforms);
+    }
+    store::save(&app, TRANSFORMS_FILE, &all)
+}
+
+/// Applies `transforms` to `html`, for the chapters of books that are not
+/// served over the `book://` protocol.
+#[command]
+pub async fn transform_html(
+    app: AppHandle,
+    html: String,
+    transforms: Transforms,
+) -> Result<String> {
+    check(&transforms)?;
+    tauri::async_runtime::spawn_blocking(move || transform(&app, &html, &transforms)).await?
+}
@@ -0,0 +1,165 @@
+//! Delta updates. The manifest of a release may list, for each platform,
+//! bsdiff patches from earlier versions:
+//!
+//! ```json
+//! "platforms": {
+//!   "linux-x86_64": {
+//!     "url": "...", "signature": "...",
+//!     "deltas": { "25.9.2": { "url": "...", "size": 1234567 } }
+//!   }
+//! }
+//! ```
+//!
+//! A patch rebuilds the artifact of the new version from that of the
+//! installed one, so the result is checked against the signature of the
+//! full artifact. On Linux the installed artifact is the AppImage itself;
+//! elsewhere the artifact last installed is kept in the cache for the next
+//! update.
+
+use std::path::PathBuf;
+
+use base64::Engine;
+use minisign_verify::{PublicKey, Signature};
+use qbsdiff::Bspatch;
+use serde::Deserialize;
+use tauri::{AppHandle, Url};
+use tauri_plugin_updater::Update;
+
+use crate::error::{Error, Result};
+use crate::net;
+use crate::paths;
+
+const BASES_DIR: &str = "updates";
+/// Most a patch may take when the manifest does not give its size, as large
+/// as the artifacts get.
+const MAX_PATCH_SIZE: u64 = 512 * 1024 * 1024;
+
+#[derive(Debug, Deserialize)]
+struct Delta {
+    url: Url,
+    #[serde(default)]
+    size: Option<u64>,
+}
+
+/// The patch the manifest of `update` offers from the installed version.
+fn delta_for(update: &Update) -> Option<Delta> {
+    let delta = update
+        .raw_json
+        .get("platforms")?
+        .get(&update.target)?
+        .get("deltas")?
+        .get(&update.current_version)?;
+    serde_json::from_value(delta.clone()).ok()
+}
+
+fn bases_dir(app: &AppHandle) -> Result<PathBuf> {
+    Ok(paths::cache_dir(app)?.join(BASES_DIR))
+}
+
+/// The artifact of the installed version, if it is at hand.
+fn base_path(app: &AppHandle, update: &Update) -> Option<PathBuf> {
+    let path = if cfg!(target_os = "linux") {
+        std::env::var_os("APPIMAGE").map(PathBuf::from)
+    } else {
+        bases_dir(app)
+            .ok()
+            .map(|dir| dir.join(&update.current_version))
+    };
+    path.filter(|path| path.is_file())
+}
+
+/// Whether `update` can be had as a patch.
+pub fn available(app: &AppHandle, update: &Update) -> bool {
+    delta_for(update).is_some() && base_path(app, update).is_some()
+}
+
+fn decode_base64(text: &str) -> Result<String> {
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(text)
+        .map_err(|e| Error::Update(e.to_string()))?;
+    String::from_utf8(bytes).map_err(|e| Error::Update(e.to_string()))
+}
+
+/// Checks `data` against the minisign `signature` the way the updater
+/// checks full downloads.
+fn verify(data: &[u8], signature: &str, pubkey: &str) -> Result<()> {
+    let public_key =
+        PublicKey::decode(&decode_base64(pubkey)?).map_err(|e| Error::Update(e.to_string()))?;
+    let signature =
+        Signature::decode(&decode_base64(signature)?).map_err(|e| Error::Update(e.to_string()))?;
+    public_key
+        .verify(data, &signature, true)
+        .map_err(|e| Error::Update(format!("patched update is not signed: {e}")))
+}
+
+fn pubkey(app: &AppHandle) -> Result<String> {
+    app.config()
+        .plugins
+        .0
+        .get("updater")
+        .and_then(|updater| updater.get("pubkey"))
+        .and_then(|pubkey| pubkey.as_str())
+        .map(str::to_string)
+        .ok_or_else(|| Error::Update("no updater public key is configured".into()))
+}
+
+/// Downloads the patch for `update` and applies it, returning the artifact,
+/// or `None` when no patch applies.
+pub async fn download(
+    app: &AppHandle,
+    update: &Update,
+    mut on_chunk: impl FnMut(usize, Option<u64>),
+) -> Result<Option<Vec<u8>>> {
+    let (Some(delta), Some(base)) = (delta_for(update), base_path(app, update)) else {
+        return Ok(None);
+    };
+    let mut response = net::client().get(delta.url).send().await?;
+    if !response.status().is_success() {
+        return Err(Error::HttpStatus(response.status().as_u16()));
+    }
+    // The patch may be no larger than the manifest says, whatever the server
+    // of the patch says
+    let limit = delta.size.unwrap_or(MAX_PATCH_SIZE);
+    let too_large = || Error::Update(format!("update patch is over {limit} bytes"));
+    if response
+        .content_length()
+        .is_some_and(|length| length > limit)
+    {
+        return Err(too_large());
+    }
+    let total = delta.size.or(response.content_length());
+    let mut patch = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if (patch.len() + chunk.len()) as u64 > limit {
+            return Err(too_large());
+        }
+        on_chunk(chunk.len(), total);
+        patch.extend_from_slice(&chunk);
+    }
+
+    let signature = update.signature.clone();
+    let pubkey = pubkey(app)?;
+    let artifact = tauri::async_runtime::spawn_blocking(move || -> Result<Vec<u8>> {
+        let base = std::fs::read(base)?;
+        let mut artifact = Vec::new();
+        Bspatch::new(&patch)?.apply(&base, &mut artifact)?;
+        verify(&artifact, &signature, &pubkey)?;
+        Ok(artifact)
+    })
+    .await??;
+    Ok(Some(artifact))
+}
+
+/// Keeps `artifact`, about to be installed, as the base of the next patch,
+/// in place of the one before.
+pub fn remember(app: &AppHandle, update: &Update, artifact: &[u8]) -> Result<()> {
+    // The AppImage is replaced by the new one
+    if cfg!(target_os = "linux") {
+        return Ok(());
+    }
+    let dir = bases_dir(app)?;
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(dir.join(&update.version), artifact)?;
+    Ok(())
+}
@@ -0,0 +1,308 @@
+//! Updates of the app from the stable or the beta channel, which the user
+//! can switch between at any time. Updates are downloaded in the
+//! background, reporting `update-progress`, as a [`delta`] patch of the
+//! installed version when the release offers one and as a whole otherwise,
+//! and `update-ready` tells the frontend once one can be installed. With
+//! `install_on_quit` on it is installed when the app quits rather than
+//! when the user asks.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle, Emitter, Manager, Url};
+use tauri_plugin_updater::{Update, UpdaterExt};
+
+use crate::error::{Error, Result};
+use crate::store;
+
+mod delta;
+
+/// The app updates for every profile alike.
+const SETTINGS_FILE: &str = "updates.json";
+/// Stable updates come from the endpoints in the config.
+const BETA_ENDPOINTS: &[&str] = &["https://download.vlarch.com/releases/beta.json"];
+const PROGRESS_EVENT: &str = "update-progress";
+const READY_EVENT: &str = "update-ready";
+/// How long after launch the background check waits, out of the way of
+/// opening the library.
+const STARTUP_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Channel {
+    #[default]
+    Stable,
+    Beta,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct UpdateSettings {
+    pub channel: Channel,
+    /// Check for an update and download it in the background on launch.
+    pub auto_download: bool,
+    /// Install a downloaded update when the app quits instead of asking.
+    pub install_on_quit: bool,
+}
+
+impl Default for UpdateSettings {
+    fn default() -> Self {
+        Self {
+            channel: Channel::Stable,
+            auto_download: true,
+            install_on_quit: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateInfo {
+    pub version: String,
+    pub current_version: String,
+    pub date: Option<String>,
+    pub notes: Option<String>,
+    pub channel: Channel,
+    /// Whether the update can be downloaded as a patch.
+    pub delta: bool,
+    /// Whether the update is downloaded and can be installed.
+    pub ready: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Progress {
+    version: String,
+    delta: bool,
+    downloaded: u64,
+    total: Option<u64>,
+}
+
+#[derive(Default)]
+struct Pending {
+    update: Option<Update>,
+    channel: Channel,
+    artifact: Option<Vec<u8>>,
+    downloading: bool,
+}
+
+/// The update last found, and its artifact once downloaded.
+#[derive(Default)]
+pub struct Updates(Mutex<Pending>);
+
+/// Whether the app can update itself. On Linux only the AppImage can, the
+/// packages being updated by the system.
+pub fn supported() -> bool {
+    if !cfg!(target_os = "linux") {
+        return true;
+    }
+    std::env::var("APPIMAGE").is_ok()
+        || std::env::current_exe()
+            .map(|path| path.to_string_lossy().contains("/tmp/.mount_"))
+            .unwrap_or(false)
+}
+
+fn settings(app: &AppHandle) -> UpdateSettings {
+    store::load_shared(app, SETTINGS_FILE)
+}
+
+fn info(app: &AppHandle, update: &Update, channel: Channel, ready: bool) -> UpdateInfo {
+    UpdateInfo {
+        version: update.version.clone(),
+        current_version: update.current_version.clone(),
+        date: update.date.map(|date| date.to_string()),
+        notes: update.body.clone(),
+        channel,
+        delta: delta::available(app, update),
+        ready,
+    }
+}
+
+/// Checks the channel of the settings for an update, keeping the one found.
+async fn check(app: &AppHandle) -> Result<Option<UpdateInfo>> {
+    let channel = settings(app).channel;
+    let mut builder = app.updater_builder();
+    if channel == Channel::Beta {
+        let endpoints = BETA_ENDPOINTS
+            .iter()
+            .map(|url| Url::parse(url))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        builder = builder.endpoints(endpoints)?;
+    }
+    let update = builder.build()?.check().await?;
+
+    let state = app.state::<Updates>();
+    let mut pending = state.0.lock().unwrap();
+    let Some(update) = update else {
+        *pending = Pending::default();
+        return Ok(None);
+    };
+    // The same update need not be downloaded again
+    let same = pending
+        .update
+        .as_ref()
+        .is_some_and(|known| known.version == update.version && pending.channel == channel);
+    if !same {
+        pending.artifact = None;
+    }
+    let ready = same && pending.artifact.is_some();
+    let info = info(app, &update, channel, ready);
+    pending.update = Some(update);
+    pending.channel = channel;
+    Ok(Some(info))
+}
+
+/// Downloads `update` as a patch if one applies, and whole otherwise.
+async fn fetch(app: &AppHandle, update: &Update) -> Result<Vec<u8>> {
+    let progress = |delta: bool| {
+        let app = app.clone();
+        let version = update.version.clone();
+        let mut downloaded = 0;
+        move |chunk: usize, total: Option<u64>| {
+            downloaded += chunk as u64;
+            let _ = app.emit(
+                PROGRESS_EVENT,
+                Progress {
+                    version: version.clone(),
+                    delta,
+                    downloaded,
+                    total,
+                },
+            );
+        }
+    };
+    match delta::download(app, update, progress(true)).await {
+        Ok(Some(artifact)) => return Ok(artifact),
+        Ok(None) => {}
+        Err(e) => log::warn!("Failed to apply the update patch, downloading it whole: {e}"),
+    }
+    Ok(update.download(progress(false), || {}).await?)
+}
+
+/// Downloads the update last found, unless it is already.
+async fn download(app: &AppHandle) -> Result<()> {
+    let state = app.state::<Updates>();
+    let (update, channel) = {
+        let mut pending = state.0.lock().unwrap();
+        if pending.downloading || pending.artifact.is_some() {
+            return Ok(());
+        }
+        let update = pending
+            .update
+            .clone()
+            .ok_or_else(|| Error::Update("no update was found".into()))?;
+        pending.downloading = true;
+        (update, pending.channel)
+    };
+    let result = fetch(app, &update).await;
+
+    let mut pending = state.0.lock().unwrap();
+    pending.downloading = false;
+    let artifact = result?;
+    // The check after a switch of channel may have found another update
+    if pending
+        .update
+        .as_ref()
+        .is_some_and(|known| known.version == update.version)
+    {
+        pending.artifact = Some(artifact);
+        drop(pending);
+        let _ = app.emit(READY_EVENT, info(app, &update, channel, true));
+    }
+    Ok(())
+}
+
+/// Installs `artifact`, kept as the base of the next patch.
+fn install(app: &AppHandle, update: &Update, artifact: &[u8]) -> Result<()> {
+    if let Err(e) = delta::remember(app, update, artifact) {
+        log::warn!("Failed to keep the update for the next patch: {e}");
+    }
+    update.install(artifact)?;
+    Ok(())
+}
+
+fn take_ready(app: &AppHandle) -> Option<(Update, Vec<u8>)> {
+    let state = app.state::<Updates>();
+    let mut pending = state.0.lock().unwrap();
+    let artifact = pending.artifact.take()?;
+    Some((pending.update.clone()?, artifact))
+}
+
+/// Checks for an update a while after launch and downloads it, when the
+/// user wants it done in the background.
+pub fn init(app: &AppHandle) {
+    if !supported() || !settings(app).auto_download {
+        return;
+    }
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        tokio::time::sleep(STARTUP_DELAY).await;
+        let result = match check(&app).await {
+            Ok(Some(_)) => download(&app).await,
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            log::warn!("Failed to update in the background: {e}");
+        }
+    });
+}
+
+/// Installs a downloaded update as the app quits, when the user chose so.
+pub fn on_exit(app: &AppHandle) {
+    if !settings(app).install_on_quit {
+        return;
+    }
+    if let Some((update, artifact)) = take_ready(app) {
+        if let Err(e) = install(app, &update, &artifact) {
+            log::warn!("Failed to install the update on quit: {e}");
+        }
+    }
+}
+
+#[command]
+pub fn get_update_settings(app: AppHandle) -> UpdateSettings {
+    settings(&app)
+}
+
+/// Saves `settings`, forgetting the update found on the other channel when
+/// the channel changes.
+#[command]
+pub fn set_update_settings(app: AppHandle, settings: UpdateSettings) -> Result<()> {
+    store::save_shared(&app, SETTINGS_FILE, &settings)?;
+    let state = app.state::<Updates>();
+    let mut pending = state.0.lock().unwrap();
+    if pending.channel != settings.channel && !pending.downloading {
+        *pending = Pending::default();
+    }
+    Ok(())
+}
+
+#[command]
+pub async fn check_for_update(app: AppHandle) -> Result<Option<UpdateInfo>> {
+    check(&app).await
+}
+
+/// Starts downloading the update last found, reporting progress through
+/// `update-progress` and the end through `update-ready`.
+#[command]
+pub fn download_update(app: AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) = download(&app).await {
+            log::warn!("Failed to download the update: {e}");
+        }
+    });
+}
+
+/// Installs the update last found, downloading it first if needed, and
+/// restarts into it.
+#[command]
+pub async fn install_update(app: AppHandle) -> Result<()> {
+    download(&app).await?;
+    let (update, artifact) =
+        take_ready(&app).ok_or_else(|| Error::Update("the update is not downloaded".into()))?;
+    let handle = app.clone();
+    tauri::async_runtime::spawn_blocking(move || install(&handle, &update, &artifact)).await??;
+    app.restart();
+}
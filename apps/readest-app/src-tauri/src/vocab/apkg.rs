@@ -0,0 +1,343 @@
+//! Anki packages (`.apkg`): a zip of the collection, an Anki 2.1 SQLite
+//! database named `collection.anki2`, and `media`, the map of the media
+//! files, none here. The note type and deck have fixed ids and each note a
+//! GUID derived from its word, so importing a later export updates the
+//! notes of an earlier one rather than adding them again.
+//!
+//! Words come in as new cards: the reviews done in VL-Arch stay here, as
+//! Anki schedules by its own algorithm.
+
+use std::io::{Cursor, Write};
+use std::path::Path;
+
+use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY, SHA256};
+use rusqlite::{params, Connection};
+use serde_json::json;
+use tauri::AppHandle;
+use zip::write::SimpleFileOptions;
+use zip::ZipWriter;
+
+use super::VocabWord;
+use crate::error::Result;
+use crate::paths;
+use crate::utils::now_millis;
+
+const COLLECTION_FILE: &str = "vocabulary.anki2";
+const MODEL_ID: i64 = 1_717_171_717_001;
+const DECK_ID: i64 = 1_717_171_717_002;
+const DECK_NAME: &str = "VL-Arch Vocabulary";
+/// Separates the fields of a note.
+const FIELD_SEPARATOR: &str = "\u{1f}";
+
+const SCHEMA: &str = r#"
+CREATE TABLE col (
+    id integer primary key, crt integer not null, mod integer not null,
+    scm integer not null, ver integer not null, dty integer not null,
+    usn integer not null, ls integer not null, conf text not null,
+    models text not null, decks text not null, dconf text not null,
+    tags text not null
+);
+CREATE TABLE notes (
+    id integer primary key, guid text not null, mid integer not null,
+    mod integer not null, usn integer not null, tags text not null,
+    flds text not null, sfld integer not null, csum integer not null,
+    flags integer not null, data text not null
+);
+CREATE TABLE cards (
+    id integer primary key, nid integer not null, did integer not null,
+    ord integer not null, mod integer not null, usn integer not null,
+    type integer not null, queue integer not null, due integer not null,
+    ivl integer not null, factor integer not null, reps integer not null,
+    lapses integer not null, left integer not null, odue integer not null,
+    odid integer not null, flags integer not null, data text not null
+);
+CREATE TABLE revlog (
+    id integer primary key, cid integer not null, usn integer not null,
+    ease integer not null, ivl integer not null, lastIvl integer not null,
+    factor integer not null, time integer not null, type integer not null
+);
+CREATE TABLE graves (usn integer not null, oid integer not null, type integer not null);
+CREATE INDEX ix_notes_usn on notes (usn);
+CREATE INDEX ix_cards_usn on cards (usn);
+CREATE INDEX ix_revlog_usn on revlog (usn);
+CREATE INDEX ix_cards_nid on cards (nid);
+CREATE INDEX ix_cards_sched on cards (did, queue, due);
+CREATE INDEX ix_revlog_cid on revlog (cid);
+CREATE INDEX ix_notes_csum on notes (csum);
+"#;
+
+const FRONT: &str = r#"<div class="word">{{Word}}</div>
+{{#Context}}<div class="context">{{Context}}</div>{{/Context}}"#;
+const BACK: &str = r#"{{FrontSide}}
+<hr id="answer">
+<div class="definition">{{Definition}}</div>
+{{#Book}}<div class="book">{{Book}}</div>{{/Book}}"#;
+const CSS: &str = ".card { font-family: sans-serif; font-size: 20px; text-align: center; }
+.word { font-size: 32px; font-weight: bold; }
+.context { margin-top: 1em; font-style: italic; }
+.definition { text-align: left; }
+.book { margin-top: 1em; font-size: 14px; color: #888; }";
+const FIELDS: &[&str] = &["Word", "Definition", "Context", "Book"];
+
+pub(super) fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// `sentence` escaped, with the first occurrence of `word` as a word of
+/// its own in bold.
+fn highlight(sentence: &str, word: &str) -> String {
+    let word = word.to_lowercase();
+    let lower = sentence.to_lowercase();
+    let is_boundary = |c: Option<char>| !c.is_some_and(char::is_alphanumeric);
+    // Lowercasing may change lengths, in which case nothing is highlighted
+    let found = (lower.len() == sentence.len())
+        .then(|| {
+            lower.match_indices(&word).map(|(i, _)| i).find(|&i| {
+                is_boundary(sentence[..i].chars().next_back())
+                    && is_boundary(sentence[i + word.len()..].chars().next())
+            })
+        })
+        .flatten();
+    match found {
+        Some(i) => format!(
+            "{}<b>{}</b>{}",
+            escape(&sentence[..i]),
+            escape(&sentence[i..i + word.len()]),
+            escape(&sentence[i + word.len()..])
+        ),
+        None => escape(sentence),
+    }
+}
+
+/// The HTML fields of the note of a word, but for the word itself.
+pub(super) struct Note {
+    pub definition: String,
+    pub context: String,
+    pub book: String,
+    pub tags: Vec<String>,
+}
+
+impl Note {
+    pub fn new(word: &VocabWord) -> Self {
+        let context = word
+            .contexts
+            .iter()
+            .map(|context| highlight(&context.sentence, &word.word))
+            .collect::<Vec<_>>()
+            .join("<br>");
+        let mut books = Vec::<&str>::new();
+        for title in word.contexts.iter().filter_map(|c| c.book_title.as_deref()) {
+            if !books.contains(&title) {
+                books.push(title);
+            }
+        }
+        let mut tags = vec!["vl-arch".to_string()];
+        tags.extend(word.language.clone());
+        Self {
+            definition: word.definition.clone().unwrap_or_default(),
+            context,
+            book: escape(&books.join(", ")),
+            tags,
+        }
+    }
+}
+
+fn guid(word: &VocabWord) -> String {
+    let key = format!(
+        "{}\u{1f}{}",
+        word.language.as_deref().unwrap_or_default(),
+        word.word.to_lowercase()
+    );
+    let hash = digest(&SHA256, key.as_bytes());
+    hash.as_ref()[..10]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Anki's checksum of the sort field, for finding duplicates.
+fn checksum(field: &str) -> i64 {
+    let hash = digest(&SHA1_FOR_LEGACY_USE_ONLY, field.as_bytes());
+    i64::from(u32::from_be_bytes(hash.as_ref()[..4].try_into().unwrap()))
+}
+
+fn write_collection(conn: &Connection, words: &[VocabWord]) -> Result<()> {
+    let now = now_millis();
+    let secs = now / 1000;
+    conn.execute_batch(SCHEMA)?;
+    let model = json!({
+        "id": MODEL_ID,
+        "name": DECK_NAME,
+        "type": 0,
+        "mod": secs,
+        "usn": -1,
+        "sortf": 0,
+        "did": DECK_ID,
+        "tmpls": [{
+            "name": "Recognition",
+            "ord": 0,
+            "qfmt": FRONT,
+            "afmt": BACK,
+            "bqfmt": "",
+            "bafmt": "",
+            "did": null,
+        }],
+        "flds": FIELDS.iter().enumerate().map(|(ord, name)| json!({
+            "name": name,
+            "ord": ord,
+            "sticky": false,
+            "rtl": false,
+            "font": "Arial",
+            "size": 20,
+            "media": [],
+        })).collect::<Vec<_>>(),
+        "css": CSS,
+        "latexPre": "\\documentclass[12pt]{article}\n\\special{papersize=3in,5in}\n\\usepackage{amssymb,amsmath}\n\\pagestyle{empty}\n\\setlength{\\parindent}{0in}\n\\begin{document}\n",
+        "latexPost": "\\end{document}",
+        "latexsvg": false,
+        "req": [[0, "any", [0]]],
+        "tags": [],
+        "vers": [],
+    });
+    let deck = |id: i64, name: &str| {
+        json!({
+            "id": id,
+            "name": name,
+            "desc": "",
+            "mod": secs,
+            "usn": -1,
+            "collapsed": false,
+            "conf": 1,
+            "dyn": 0,
+            "extendNew": 10,
+            "extendRev": 50,
+            "newToday": [0, 0],
+            "revToday": [0, 0],
+            "lrnToday": [0, 0],
+            "timeToday": [0, 0],
+        })
+    };
+    let conf = json!({
+        "activeDecks": [1],
+        "curDeck": 1,
+        "curModel": MODEL_ID.to_string(),
+        "nextPos": words.len() + 1,
+        "sortType": "noteFld",
+        "sortBackwards": false,
+        "addToCur": true,
+        "newBump": true,
+        "newSpread": 0,
+        "dueCounts": true,
+        "estTimes": true,
+        "timeLim": 0,
+        "collapseTime": 1200,
+    });
+    let dconf = json!({
+        "1": {
+            "id": 1,
+            "name": "Default",
+            "mod": 0,
+            "usn": 0,
+            "maxTaken": 60,
+            "timer": 0,
+            "autoplay": true,
+            "replayq": true,
+            "new": {
+                "bury": true,
+                "delays": [1, 10],
+                "initialFactor": 2500,
+                "ints": [1, 4, 7],
+                "order": 1,
+                "perDay": 20,
+                "separate": true,
+            },
+            "lapse": {
+                "delays": [10],
+                "leechAction": 0,
+                "leechFails": 8,
+                "minInt": 1,
+                "mult": 0,
+            },
+            "rev": {
+                "bury": true,
+                "ease4": 1.3,
+                "fuzz": 0.05,
+                "ivlFct": 1,
+                "maxIvl": 36500,
+                "minSpace": 1,
+                "perDay": 100,
+            },
+        },
+    });
+    conn.execute(
+        "INSERT INTO col VALUES (1, ?1, ?2, ?2, 11, 0, 0, 0, ?3, ?4, ?5, ?6, '{}')",
+        params![
+            secs,
+            now,
+            conf.to_string(),
+            json!({ MODEL_ID.to_string(): model }).to_string(),
+            json!({
+                "1": deck(1, "Default"),
+                DECK_ID.to_string(): deck(DECK_ID, DECK_NAME),
+            })
+            .to_string(),
+            dconf.to_string(),
+        ],
+    )?;
+
+    let mut note_stmt =
+        conn.prepare("INSERT INTO notes VALUES (?1, ?2, ?3, ?4, -1, ?5, ?6, ?7, ?8, 0, '')")?;
+    let mut card_stmt = conn.prepare(
+        "INSERT INTO cards VALUES (?1, ?2, ?3, 0, ?4, -1, 0, 0, ?5, 0, 0, 0, 0, 0, 0, 0, 0, '')",
+    )?;
+    for (i, word) in words.iter().enumerate() {
+        let note = Note::new(word);
+        let fields = [escape(&word.word), note.definition, note.context, note.book];
+        let id = now + i as i64;
+        note_stmt.execute(params![
+            id,
+            guid(word),
+            MODEL_ID,
+            secs,
+            format!(" {} ", note.tags.join(" ")),
+            fields.join(FIELD_SEPARATOR),
+            word.word,
+            checksum(&word.word),
+        ])?;
+        card_stmt.execute(params![id, id, DECK_ID, secs, i as i64 + 1])?;
+    }
+    Ok(())
+}
+
+/// The collection of `words`, written to `path`.
+fn collection(path: &Path, words: &[VocabWord]) -> Result<Vec<u8>> {
+    let mut conn = Connection::open(path)?;
+    let tx = conn.transaction()?;
+    write_collection(&tx, words)?;
+    tx.commit()?;
+    drop(conn);
+    Ok(std::fs::read(path)?)
+}
+
+/// An Anki package of `words`.
+pub fn package(app: &AppHandle, words: &[VocabWord]) -> Result<Vec<u8>> {
+    let dir = paths::cache_dir(app)?;
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join(COLLECTION_FILE);
+    if path.exists() {
+        std::fs::remove_file(&path)?;
+    }
+    let result = collection(&path, words);
+    let _ = std::fs::remove_file(&path);
+    let collection = result?;
+
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    zip.start_file("collection.anki2", SimpleFileOptions::default())?;
+    zip.write_all(&collection)?;
+    zip.start_file("media", SimpleFileOptions::default())?;
+    zip.write_all(b"{}")?;
+    Ok(zip.finish()?.into_inner())
+}
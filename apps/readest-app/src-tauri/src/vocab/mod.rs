@@ -0,0 +1,531 @@
+//! Vocabulary builder: the words the reader looks up, kept in the library
+//! database with the sentences they were met in, reviewed with spaced
+//! repetition and exported as decks for Anki. Words are kept once per
+//! language whatever their case; each lookup in a new sentence adds it to
+//! the word's contexts.
+//!
+//! Reviews follow SM-2: a grade from 0 to 5, 3 and up meaning the word was
+//! remembered, sets when it is due next.
+
+mod apkg;
+
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle, Manager, State};
+
+use crate::error::{Error, Result};
+use crate::export::annotations::save_with_dialog;
+use crate::library::db::{self, LibraryDb};
+use crate::utils::{format_rfc3339, now_millis};
+
+const DAY_MS: i64 = 86_400_000;
+/// Longest sentence kept as a context, in characters.
+const MAX_SENTENCE_LEN: usize = 1000;
+const MIN_EASE: f64 = 1.3;
+
+/// Where a word was looked up.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct WordContext {
+    pub book_hash: Option<String>,
+    /// The sentence the word is in.
+    pub sentence: Option<String>,
+    pub cfi: Option<String>,
+    /// Language of the book, as its metadata names it.
+    pub language: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VocabContext {
+    pub book_hash: Option<String>,
+    /// From the catalog; missing for books no longer in the library.
+    pub book_title: Option<String>,
+    pub sentence: String,
+    pub cfi: Option<String>,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VocabWord {
+    pub id: i64,
+    pub word: String,
+    /// ISO 639 code, without region.
+    pub language: Option<String>,
+    /// HTML, as the dictionary has it.
+    pub definition: Option<String>,
+    pub lookups: u32,
+    pub contexts: Vec<VocabContext>,
+    /// Reviews in a row the word was remembered.
+    pub reps: u32,
+    pub lapses: u32,
+    pub ease: f64,
+    pub interval_days: u32,
+    pub due_at: i64,
+    pub reviewed_at: Option<i64>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VocabQuery {
+    /// Case-insensitive match against the word and its definition.
+    pub search: Option<String>,
+    pub language: Option<String>,
+    /// Words met in this book.
+    pub book_hash: Option<String>,
+    /// Only the words due for review now, soonest first, rather than the
+    /// latest looked up first.
+    #[serde(default)]
+    pub due: bool,
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VocabPage {
+    pub words: Vec<VocabWord>,
+    pub total: u64,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VocabFormat {
+    /// An Anki package, imported with File > Import.
+    Apkg,
+    /// Tab-separated notes Anki imports as text, with HTML fields.
+    Tsv,
+}
+
+/// `word` without the punctuation selections often include.
+fn clean_word(word: &str) -> &str {
+    word.trim()
+        .trim_matches(|c: char| !c.is_alphanumeric() && c != '\'' && c != '-')
+}
+
+fn clean_language(language: Option<&str>) -> String {
+    language
+        .and_then(|language| language.split(['-', '_']).next())
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase()
+}
+
+fn clean_sentence(sentence: &str) -> String {
+    sentence
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+        .take(MAX_SENTENCE_LEN)
+        .collect()
+}
+
+const WORD_COLUMNS: &str = "w.id, w.word, w.language, w.definition, w.lookups, w.reps, w.lapses, \
+    w.ease, w.interval_days, w.due_at, w.reviewed_at, w.created_at, w.updated_at";
+
+fn word_from_row(row: &Row) -> rusqlite::Result<VocabWord> {
+    Ok(VocabWord {
+        id: row.get(0)?,
+        word: row.get(1)?,
+        language: Some(row.get::<_, String>(2)?).filter(|language| !language.is_empty()),
+        definition: row.get(3)?,
+        lookups: row.get(4)?,
+        contexts: Vec::new(),
+        reps: row.get(5)?,
+        lapses: row.get(6)?,
+        ease: row.get(7)?,
+        interval_days: row.get(8)?,
+        due_at: row.get(9)?,
+        reviewed_at: row.get(10)?,
+        created_at: row.get(11)?,
+        updated_at: row.get(12)?,
+    })
+}
+
+fn load_contexts(conn: &Connection, words: &mut [VocabWord]) -> Result<()> {
+    let mut stmt = conn.prepare(
+        "SELECT c.book_hash, b.title, c.sentence, c.cfi, c.created_at
+         FROM vocab_contexts c LEFT JOIN books b ON b.hash = c.book_hash
+         WHERE c.word_id = ?1 ORDER BY c.created_at",
+    )?;
+    for word in words {
+        word.contexts = stmt
+            .query_map([word.id], |row| {
+                Ok(VocabContext {
+                    book_hash: row.get(0)?,
+                    book_title: row.get(1)?,
+                    sentence: row.get(2)?,
+                    cfi: row.get(3)?,
+                    created_at: row.get(4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+    }
+    Ok(())
+}
+
+fn get(conn: &Connection, id: i64) -> Result<VocabWord> {
+    let mut word = conn
+        .query_row(
+            &format!("SELECT {WORD_COLUMNS} FROM vocab_words w WHERE w.id = ?1"),
+            [id],
+            word_from_row,
+        )
+        .optional()?
+        .ok_or(Error::UnknownVocabWord(id))?;
+    load_contexts(conn, std::slice::from_mut(&mut word))?;
+    Ok(word)
+}
+
+/// Adds `word` to the vocabulary, or counts another lookup of it, with
+/// the sentence of `context`. A `definition` replaces the one kept.
+pub fn save(
+    conn: &Connection,
+    word: &str,
+    definition: Option<&str>,
+    context: &WordContext,
+) -> Result<VocabWord> {
+    let word = clean_word(word);
+    if word.is_empty() {
+        return Err(Error::InvalidVocab("the word is empty".to_string()));
+    }
+    let now = now_millis();
+    let id: i64 = conn.query_row(
+        "INSERT INTO vocab_words (word, language, definition, created_at, updated_at, due_at)
+         VALUES (?1, ?2, ?3, ?4, ?4, ?4)
+         ON CONFLICT(word, language) DO UPDATE SET
+            lookups = lookups + 1, updated_at = excluded.updated_at,
+            definition = COALESCE(excluded.definition, definition)
+         RETURNING id",
+        params![
+            word,
+            clean_language(context.language.as_deref()),
+            definition.filter(|definition| !definition.trim().is_empty()),
+            now,
+        ],
+        |row| row.get(0),
+    )?;
+    if let Some(sentence) = context.sentence.as_deref().map(clean_sentence) {
+        if !sentence.is_empty() {
+            conn.execute(
+                "INSERT INTO vocab_contexts (word_id, book_hash, sentence, cfi, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT(word_id, sentence) DO NOTHING",
+                params![id, context.book_hash, sentence, context.cfi, now],
+            )?;
+        }
+    }
+    get(conn, id)
+}
+
+fn where_clause(query: &VocabQuery, params: &mut Vec<Value>) -> String {
+    let mut conditions = Vec::new();
+    if let Some(search) = query.search.as_deref().filter(|s| !s.trim().is_empty()) {
+        params.push(Value::Text(format!(
+            "%{}%",
+            db::like_literal(search.trim())
+        )));
+        let n = params.len();
+        conditions.push(format!(
+            "(w.word LIKE ?{n} ESCAPE '\\' OR w.definition LIKE ?{n} ESCAPE '\\')"
+        ));
+    }
+    if let Some(language) = &query.language {
+        params.push(Value::Text(clean_language(Some(language))));
+        conditions.push(format!("w.language = ?{}", params.len()));
+    }
+    if let Some(book_hash) = &query.book_hash {
+        params.push(Value::Text(book_hash.clone()));
+        conditions.push(format!(
+            "EXISTS (SELECT 1 FROM vocab_contexts c WHERE c.word_id = w.id AND c.book_hash = ?{})",
+            params.len()
+        ));
+    }
+    if query.due {
+        params.push(Value::Integer(now_millis()));
+        conditions.push(format!("w.due_at <= ?{}", params.len()));
+    }
+    if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", conditions.join(" AND "))
+    }
+}
+
+pub fn query(conn: &Connection, query: &VocabQuery) -> Result<VocabPage> {
+    let mut params = Vec::new();
+    let filter = where_clause(query, &mut params);
+    let total: u64 = conn.query_row(
+        &format!("SELECT COUNT(*) FROM vocab_words w {filter}"),
+        params_from_iter(params.iter()),
+        |row| row.get(0),
+    )?;
+    let order = if query.due {
+        "w.due_at ASC"
+    } else {
+        "w.updated_at DESC"
+    };
+    let sql = format!(
+        "SELECT {WORD_COLUMNS} FROM vocab_words w {filter} ORDER BY {order}, w.id \
+         LIMIT ?{} OFFSET ?{}",
+        params.len() + 1,
+        params.len() + 2,
+    );
+    params.push(Value::Integer(query.limit.map_or(-1, i64::from)));
+    params.push(Value::Integer(query.offset.unwrap_or(0).into()));
+    let mut stmt = conn.prepare(&sql)?;
+    let mut words = stmt
+        .query_map(params_from_iter(params.iter()), word_from_row)?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    load_contexts(conn, &mut words)?;
+    Ok(VocabPage { words, total })
+}
+
+/// Schedules the next review of `word` after one graded `grade`, by SM-2.
+fn schedule(word: &mut VocabWord, grade: u8, now: i64) {
+    let q = f64::from(grade);
+    word.ease = (word.ease + 0.1 - (5.0 - q) * (0.08 + (5.0 - q) * 0.02)).max(MIN_EASE);
+    if grade < 3 {
+        if word.reps > 0 {
+            word.lapses += 1;
+        }
+        word.reps = 0;
+        word.interval_days = 1;
+    } else {
+        word.interval_days = match word.reps {
+            0 => 1,
+            1 => 6,
+            _ => (f64::from(word.interval_days) * word.ease).round() as u32,
+        };
+        word.reps += 1;
+    }
+    word.due_at = now + i64::from(word.interval_days) * DAY_MS;
+    word.reviewed_at = Some(now);
+}
+
+fn tsv_field(field: &str) -> String {
+    field
+        .replace('\t', " ")
+        .replace("\r\n", "<br>")
+        .replace(['\r', '\n'], "<br>")
+}
+
+/// Notes for Anki's text import, with a header naming the fields.
+fn to_tsv(words: &[VocabWord]) -> String {
+    let mut out = String::from(
+        "#separator:tab\n#html:true\n#tags column:5\n#columns:Word\tDefinition\tContext\tBook\tTags\n",
+    );
+    for word in words {
+        let note = apkg::Note::new(word);
+        let fields = [
+            apkg::escape(&word.word),
+            note.definition,
+            note.context,
+            note.book,
+            note.tags.join(" "),
+        ];
+        out.push_str(&fields.map(|field| tsv_field(&field)).join("\t"));
+        out.push('\n');
+    }
+    out
+}
+
+/// Adds `word` to the vocabulary, as looking it up with a context does.
+#[command]
+pub async fn add_vocab_word(
+    db: State<'_, LibraryDb>,
+    word: String,
+    definition: Option<String>,
+    context: Option<WordContext>,
+) -> Result<VocabWord> {
+    save(
+        &db.conn(),
+        &word,
+        definition.as_deref(),
+        &context.unwrap_or_default(),
+    )
+}
+
+#[command]
+pub async fn list_vocab(db: State<'_, LibraryDb>, query: VocabQuery) -> Result<VocabPage> {
+    self::query(&db.conn(), &query)
+}
+
+#[command]
+pub async fn update_vocab_definition(
+    db: State<'_, LibraryDb>,
+    id: i64,
+    definition: Option<String>,
+) -> Result<VocabWord> {
+    let conn = db.conn();
+    get(&conn, id)?;
+    conn.execute(
+        "UPDATE vocab_words SET definition = ?2, updated_at = ?3 WHERE id = ?1",
+        params![id, definition, now_millis()],
+    )?;
+    get(&conn, id)
+}
+
+#[command]
+pub async fn delete_vocab_words(db: State<'_, LibraryDb>, ids: Vec<i64>) -> Result<()> {
+    let mut conn = db.conn();
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare("DELETE FROM vocab_words WHERE id = ?1")?;
+        for id in &ids {
+            stmt.execute([id])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Records a review of word `id`, `grade` from 0 (forgotten) to 5
+/// (remembered at once), and returns when it is due next.
+#[command]
+pub async fn review_vocab_word(db: State<'_, LibraryDb>, id: i64, grade: u8) -> Result<VocabWord> {
+    if grade > 5 {
+        return Err(Error::InvalidVocab(format!("grade {grade} is not 0 to 5")));
+    }
+    let conn = db.conn();
+    let mut word = get(&conn, id)?;
+    schedule(&mut word, grade, now_millis());
+    conn.execute(
+        "UPDATE vocab_words SET reps = ?2, lapses = ?3, ease = ?4, interval_days = ?5,
+            due_at = ?6, reviewed_at = ?7
+         WHERE id = ?1",
+        params![
+            id,
+            word.reps,
+            word.lapses,
+            word.ease,
+            word.interval_days,
+            word.due_at,
+            word.reviewed_at,
+        ],
+    )?;
+    Ok(word)
+}
+
+/// Exports the words `query` matches, or every word, as a deck in
+/// `format` to a file the user picks in a save dialog. Returns where the
+/// file was written, or `None` if the dialog was cancelled.
+#[command]
+pub async fn export_vocab(
+    app: AppHandle,
+    format: VocabFormat,
+    query: Option<VocabQuery>,
+) -> Result<Option<String>> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let query = VocabQuery {
+            limit: None,
+            offset: None,
+            ..query.unwrap_or_default()
+        };
+        let words = self::query(&app.state::<LibraryDb>().conn(), &query)?.words;
+        if words.is_empty() {
+            return Err(Error::NoVocabulary);
+        }
+        let name = format!("vocabulary-{}", &format_rfc3339(now_millis())[..10]);
+        match format {
+            VocabFormat::Apkg => {
+                let package = apkg::package(&app, &words)?;
+                save_with_dialog(&app, &name, "Anki package", "apkg", &package)
+            }
+            VocabFormat::Tsv => save_with_dialog(
+                &app,
+                &name,
+                "Tab-separated values",
+                "tsv",
+                to_tsv(&words).as_bytes(),
+            ),
+        }
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn word() -> VocabWord {
+        VocabWord {
+            id: 1,
+            word: "serendipity".to_string(),
+            language: Some("en".to_string()),
+            definition: None,
+            lookups: 1,
+            contexts: Vec::new(),
+            reps: 0,
+            lapses: 0,
+            ease: 2.5,
+            interval_days: 0,
+            due_at: 0,
+            reviewed_at: None,
+            created_at: 0,
+            updated_at: 0,
+        }
+    }
+
+    fn assert_ease(word: &VocabWord, ease: f64) {
+        assert!(
+            (word.ease - ease).abs() < 1e-9,
+            "ease {} is not {ease}",
+            word.ease
+        );
+    }
+
+    #[test]
+    fn remembered_words_wait_longer_each_review() {
+        let mut word = word();
+        schedule(&mut word, 4, 1000);
+        assert_eq!((word.reps, word.interval_days), (1, 1));
+        assert_ease(&word, 2.5);
+        assert_eq!(word.due_at, 1000 + DAY_MS);
+        assert_eq!(word.reviewed_at, Some(1000));
+
+        schedule(&mut word, 5, 2000);
+        assert_eq!((word.reps, word.interval_days), (2, 6));
+        assert_ease(&word, 2.6);
+
+        schedule(&mut word, 5, 3000);
+        // 6 days times the ease of 2.7, rounded
+        assert_eq!((word.reps, word.interval_days), (3, 16));
+        assert_ease(&word, 2.7);
+        assert_eq!(word.due_at, 3000 + 16 * DAY_MS);
+        assert_eq!(word.lapses, 0);
+    }
+
+    #[test]
+    fn forgotten_words_start_over_as_lapses() {
+        let mut word = word();
+        schedule(&mut word, 5, 0);
+        schedule(&mut word, 5, 0);
+        schedule(&mut word, 1, 5000);
+        assert_eq!((word.reps, word.lapses, word.interval_days), (0, 1, 1));
+        assert_ease(&word, 2.16);
+        assert_eq!(word.due_at, 5000 + DAY_MS);
+
+        // New words never learned are not lapses
+        let mut word = self::word();
+        schedule(&mut word, 2, 0);
+        assert_eq!((word.reps, word.lapses), (0, 0));
+    }
+
+    #[test]
+    fn ease_never_falls_below_the_minimum() {
+        let mut word = word();
+        for _ in 0..10 {
+            schedule(&mut word, 0, 0);
+        }
+        assert_ease(&word, MIN_EASE);
+        schedule(&mut word, 3, 0);
+        assert_ease(&word, MIN_EASE);
+        assert_eq!(word.interval_days, 1);
+    }
+}
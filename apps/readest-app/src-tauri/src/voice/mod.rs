@@ -0,0 +1,421 @@
+//! Voice notes on highlights: recorded from the microphone (see
+//! [`crate::audio::capture`]), kept as FLAC next to the book's config and
+//! played back over the `voicenote://` protocol, as
+//! `voicenote://localhost/<book hash>/<id>`. Each is linked to an
+//! annotation of the book by its id.
+//!
+//! When turned on, recordings are transcribed as they are saved, with a
+//! local whisper.cpp (see [`whisper`]), and the transcript is sent as a
+//! `voice-note` event once it is ready.
+
+mod whisper;
+
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use flacenc::component::BitRepr;
+use flacenc::error::Verify;
+use serde::{Deserialize, Serialize};
+use tauri::http::{header, Request, Response, StatusCode};
+use tauri::{command, AppHandle, Emitter, Manager, State};
+
+use crate::audio::capture::{Recorder, SAMPLE_RATE};
+use crate::error::{Error, Result};
+use crate::library;
+use crate::store;
+use crate::utils::now_millis;
+
+pub const PROTOCOL: &str = "voicenote";
+const SETTINGS_FILE: &str = "voice-notes.json";
+/// Per book, in its folder in the books dir.
+const INDEX_FILE: &str = "voice-notes.json";
+const AUDIO_DIR: &str = "voice-notes";
+const EVENT: &str = "voice-note";
+/// Recordings stop taking sound after this long.
+const MAX_DURATION: Duration = Duration::from_secs(10 * 60);
+
+fn voice_error(message: impl Into<String>) -> Error {
+    Error::VoiceNote(message.into())
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct VoiceNoteSettings {
+    /// Whether recordings are transcribed as they are saved.
+    pub transcribe: bool,
+    /// The whisper.cpp tool, `whisper-cli` on the PATH when not set.
+    pub whisper_path: Option<PathBuf>,
+    /// The whisper model, a `ggml-*.bin` file.
+    pub model_path: Option<PathBuf>,
+    /// Language spoken, detected when not set.
+    pub language: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VoiceNote {
+    pub id: String,
+    /// Id of the annotation it is on.
+    pub note_id: String,
+    pub created_at: i64,
+    pub duration_ms: u64,
+    pub size: u64,
+    pub transcript: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+enum VoiceNoteEvent {
+    Transcribed {
+        book_hash: String,
+        voice_note: VoiceNote,
+    },
+    TranscriptionFailed {
+        book_hash: String,
+        id: String,
+        error: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VoiceRecording {
+    pub book_hash: String,
+    pub note_id: String,
+    pub elapsed_ms: u64,
+}
+
+struct Recording {
+    book_hash: String,
+    note_id: String,
+    recorder: Recorder,
+}
+
+#[derive(Default)]
+pub struct VoiceNotes {
+    recording: Mutex<Option<Recording>>,
+    /// Held while an index is read and written back.
+    index: Mutex<()>,
+}
+
+/// Hashes and ids come from the frontend and end up in paths.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+fn book_dir(app: &AppHandle, book_hash: &str) -> Result<PathBuf> {
+    if !is_valid_name(book_hash) {
+        return Err(voice_error(format!("invalid book {book_hash:?}")));
+    }
+    Ok(library::books_dir(app)?.join(book_hash))
+}
+
+fn audio_path(app: &AppHandle, book_hash: &str, id: &str) -> Result<PathBuf> {
+    if !is_valid_name(id) {
+        return Err(voice_error(format!("invalid voice note {id:?}")));
+    }
+    Ok(book_dir(app, book_hash)?
+        .join(AUDIO_DIR)
+        .join(format!("{id}.flac")))
+}
+
+fn load_index(app: &AppHandle, book_hash: &str) -> Result<Vec<VoiceNote>> {
+    match std::fs::read(book_dir(app, book_hash)?.join(INDEX_FILE)) {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn save_index(app: &AppHandle, book_hash: &str, notes: &[VoiceNote]) -> Result<()> {
+    let dir = book_dir(app, book_hash)?;
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(dir.join(INDEX_FILE), serde_json::to_vec_pretty(notes)?)?;
+    Ok(())
+}
+
+/// Changes the index of book `book_hash` with `change`.
+fn update_index<T>(
+    app: &AppHandle,
+    book_hash: &str,
+    change: impl FnOnce(&mut Vec<VoiceNote>) -> Result<T>,
+) -> Result<T> {
+    let voice = app.state::<VoiceNotes>();
+    let _guard = voice.index.lock().unwrap();
+    let mut notes = load_index(app, book_hash)?;
+    let result = change(&mut notes)?;
+    save_index(app, book_hash, &notes)?;
+    Ok(result)
+}
+
+fn encode(samples: &[i16]) -> Result<Vec<u8>> {
+    let samples = samples.iter().map(|&s| i32::from(s)).collect::<Vec<_>>();
+    let config = flacenc::config::Encoder::default()
+        .into_verified()
+        .map_err(|(_, e)| voice_error(format!("{e:?}")))?;
+    let source = flacenc::source::MemSource::from_samples(&samples, 1, 16, SAMPLE_RATE as usize);
+    let stream = flacenc::encode_with_fixed_block_size(&config, source, config.block_size)
+        .map_err(|e| voice_error(format!("{e:?}")))?;
+    let mut sink = flacenc::bitsink::ByteSink::new();
+    stream
+        .write(&mut sink)
+        .map_err(|e| voice_error(format!("{e:?}")))?;
+    Ok(sink.as_slice().to_vec())
+}
+
+fn decode(path: &Path) -> Result<Vec<i16>> {
+    let file = std::io::BufReader::new(std::fs::File::open(path)?);
+    let decoder = rodio::Decoder::new(file).map_err(|e| voice_error(e.to_string()))?;
+    Ok(decoder.collect())
+}
+
+/// Transcribes voice note `id` of book `book_hash` from `samples`, and
+/// saves the transcript.
+fn transcribe(app: &AppHandle, book_hash: &str, id: &str, samples: &[i16]) -> Result<VoiceNote> {
+    let settings: VoiceNoteSettings = store::load(app, SETTINGS_FILE);
+    let transcript = whisper::transcribe(&settings, samples)?;
+    update_index(app, book_hash, |notes| {
+        let note = notes
+            .iter_mut()
+            .find(|note| note.id == id)
+            .ok_or_else(|| voice_error(format!("no voice note {id}")))?;
+        note.transcript = Some(transcript);
+        Ok(note.clone())
+    })
+}
+
+#[command]
+pub fn get_voice_note_settings(app: AppHandle) -> VoiceNoteSettings {
+    store::load(&app, SETTINGS_FILE)
+}
+
+#[command]
+pub fn set_voice_note_settings(app: AppHandle, settings: VoiceNoteSettings) -> Result<()> {
+    store::save(&app, SETTINGS_FILE, &settings)
+}
+
+/// Starts recording a voice note on annotation `note_id` of book
+/// `book_hash`.
+#[command]
+pub async fn start_voice_note(app: AppHandle, book_hash: String, note_id: String) -> Result<()> {
+    book_dir(&app, &book_hash)?;
+    tauri::async_runtime::spawn_blocking(move || {
+        let voice = app.state::<VoiceNotes>();
+        let mut recording = voice.recording.lock().unwrap();
+        if recording.is_some() {
+            return Err(voice_error("a voice note is being recorded already"));
+        }
+        *recording = Some(Recording {
+            book_hash,
+            note_id,
+            recorder: Recorder::start(MAX_DURATION)?,
+        });
+        Ok(())
+    })
+    .await?
+}
+
+#[command]
+pub fn get_voice_recording(voice: State<'_, VoiceNotes>) -> Option<VoiceRecording> {
+    voice
+        .recording
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|recording| VoiceRecording {
+            book_hash: recording.book_hash.clone(),
+            note_id: recording.note_id.clone(),
+            elapsed_ms: recording.recorder.elapsed().min(MAX_DURATION).as_millis() as u64,
+        })
+}
+
+/// Stops recording and saves the voice note, which is then transcribed in
+/// the background when the settings say so.
+#[command]
+pub async fn stop_voice_note(app: AppHandle) -> Result<VoiceNote> {
+    let recording = app
+        .state::<VoiceNotes>()
+        .recording
+        .lock()
+        .unwrap()
+        .take()
+        .ok_or_else(|| voice_error("no voice note is being recorded"))?;
+    let handle = app.clone();
+    let (book_hash, note, samples) = tauri::async_runtime::spawn_blocking(move || {
+        let app = handle;
+        let Recording {
+            book_hash,
+            note_id,
+            recorder,
+        } = recording;
+        let samples = recorder.stop();
+        if samples.is_empty() {
+            return Err(voice_error("nothing was recorded"));
+        }
+        let id = {
+            use rand::Rng;
+            format!("{:016x}", rand::thread_rng().gen::<u64>())
+        };
+        let path = audio_path(&app, &book_hash, &id)?;
+        let data = encode(&samples)?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&path, &data)?;
+        let note = VoiceNote {
+            id,
+            note_id,
+            created_at: now_millis(),
+            duration_ms: samples.len() as u64 * 1000 / u64::from(SAMPLE_RATE),
+            size: data.len() as u64,
+            transcript: None,
+        };
+        update_index(&app, &book_hash, |notes| {
+            notes.push(note.clone());
+            Ok(())
+        })?;
+        Ok((book_hash, note, samples))
+    })
+    .await??;
+
+    let settings: VoiceNoteSettings = store::load(&app, SETTINGS_FILE);
+    if settings.transcribe {
+        let id = note.id.clone();
+        tauri::async_runtime::spawn_blocking(move || {
+            let event = match transcribe(&app, &book_hash, &id, &samples) {
+                Ok(voice_note) => VoiceNoteEvent::Transcribed {
+                    book_hash,
+                    voice_note,
+                },
+                Err(e) => {
+                    log::warn!("Failed to transcribe voice note {id}: {e}");
+                    VoiceNoteEvent::TranscriptionFailed {
+                        book_hash,
+                        id,
+                        error: e.to_string(),
+                    }
+                }
+            };
+            let _ = app.emit(EVENT, event);
+        });
+    }
+    Ok(note)
+}
+
+/// Stops recording, keeping nothing.
+#[command]
+pub async fn cancel_voice_note(app: AppHandle) -> Result<()> {
+    let recording = app.state::<VoiceNotes>().recording.lock().unwrap().take();
+    if let Some(recording) = recording {
+        tauri::async_runtime::spawn_blocking(move || recording.recorder.stop()).await?;
+    }
+    Ok(())
+}
+
+/// The voice notes of book `book_hash`, only those on annotation `note_id`
+/// when given, oldest first.
+#[command]
+pub async fn list_voice_notes(
+    app: AppHandle,
+    book_hash: String,
+    note_id: Option<String>,
+) -> Result<Vec<VoiceNote>> {
+    let mut notes = load_index(&app, &book_hash)?;
+    notes.retain(|note| note_id.as_ref().map_or(true, |id| &note.note_id == id));
+    Ok(notes)
+}
+
+#[command]
+pub async fn delete_voice_note(app: AppHandle, book_hash: String, id: String) -> Result<()> {
+    let path = audio_path(&app, &book_hash, &id)?;
+    update_index(&app, &book_hash, |notes| {
+        notes.retain(|note| note.id != id);
+        Ok(())
+    })?;
+    match std::fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Transcribes voice note `id` of book `book_hash` again, or for the first
+/// time when it was saved without.
+#[command]
+pub async fn transcribe_voice_note(
+    app: AppHandle,
+    book_hash: String,
+    id: String,
+) -> Result<VoiceNote> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let samples = decode(&audio_path(&app, &book_hash, &id)?)?;
+        transcribe(&app, &book_hash, &id, &samples)
+    })
+    .await?
+}
+
+fn respond_status(status: StatusCode) -> Response<Cow<'static, [u8]>> {
+    Response::builder()
+        .status(status)
+        .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+        .body(Cow::Borrowed(&[][..]))
+        .unwrap()
+}
+
+/// Serves the audio of a voice note, with support for range requests.
+pub fn handle_request(app: &AppHandle, request: &Request<Vec<u8>>) -> Response<Cow<'static, [u8]>> {
+    let path = request.uri().path().trim_matches('/');
+    let Some((book_hash, id)) = path.split_once('/') else {
+        return respond_status(StatusCode::NOT_FOUND);
+    };
+    let Ok(path) = audio_path(app, book_hash, id.trim_end_matches(".flac")) else {
+        return respond_status(StatusCode::BAD_REQUEST);
+    };
+    let data = match std::fs::read(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return respond_status(StatusCode::NOT_FOUND)
+        }
+        Err(e) => {
+            log::warn!("Failed to read {path:?}: {e}");
+            return respond_status(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    let size = data.len() as u64;
+    let builder = Response::builder()
+        .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::CONTENT_TYPE, "audio/flac");
+    let range = request
+        .headers()
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| crate::resources::parse_range(value, size));
+    match range {
+        Some(Err(())) => builder
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(header::CONTENT_RANGE, format!("bytes */{size}"))
+            .body(Cow::Borrowed(&[][..]))
+            .unwrap(),
+        Some(Ok(Some((start, end)))) => {
+            let part = data[start as usize..=end as usize].to_vec();
+            builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_RANGE, format!("bytes {start}-{end}/{size}"))
+                .header(header::CONTENT_LENGTH, part.len())
+                .body(Cow::Owned(part))
+                .unwrap()
+        }
+        _ => builder
+            .status(StatusCode::OK)
+            .header(header::CONTENT_LENGTH, data.len())
+            .body(Cow::Owned(data))
+            .unwrap(),
+    }
+}
@@ -0,0 +1,82 @@
+//! Transcription with whisper.cpp, run locally as its command line tool
+//! with a model the user downloads. Nothing leaves the machine.
+
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+
+use super::VoiceNoteSettings;
+use crate::audio::capture::SAMPLE_RATE;
+use crate::error::{Error, Result};
+
+/// The tool as whisper.cpp builds name it, looked up on the PATH when its
+/// place is not set.
+const PROGRAM: &str = "whisper-cli";
+
+fn whisper_error(message: impl Into<String>) -> Error {
+    Error::VoiceNote(message.into())
+}
+
+/// Writes `samples` to `path` as a 16-bit mono WAV file, which is what the
+/// tool reads.
+fn write_wav(path: &Path, samples: &[i16]) -> Result<()> {
+    let data_len = (samples.len() * 2) as u32;
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    file.write_all(b"RIFF")?;
+    file.write_all(&(36 + data_len).to_le_bytes())?;
+    file.write_all(b"WAVEfmt ")?;
+    file.write_all(&16u32.to_le_bytes())?;
+    // PCM, one channel
+    file.write_all(&1u16.to_le_bytes())?;
+    file.write_all(&1u16.to_le_bytes())?;
+    file.write_all(&SAMPLE_RATE.to_le_bytes())?;
+    file.write_all(&(SAMPLE_RATE * 2).to_le_bytes())?;
+    file.write_all(&2u16.to_le_bytes())?;
+    file.write_all(&16u16.to_le_bytes())?;
+    file.write_all(b"data")?;
+    file.write_all(&data_len.to_le_bytes())?;
+    for sample in samples {
+        file.write_all(&sample.to_le_bytes())?;
+    }
+    file.flush()?;
+    Ok(())
+}
+
+/// The text spoken in `samples`, which are at [`SAMPLE_RATE`].
+pub fn transcribe(settings: &VoiceNoteSettings, samples: &[i16]) -> Result<String> {
+    let model = settings
+        .model_path
+        .as_deref()
+        .ok_or_else(|| whisper_error("no whisper model is set"))?;
+    let wav = {
+        use rand::Rng;
+        let id = rand::thread_rng().gen::<u64>();
+        std::env::temp_dir().join(format!("vlarch-voice-{id:016x}.wav"))
+    };
+    write_wav(&wav, samples)?;
+    let program = settings
+        .whisper_path
+        .as_deref()
+        .unwrap_or(Path::new(PROGRAM));
+    let language = settings.language.as_deref().unwrap_or("auto");
+    let output = Command::new(program)
+        .arg("--model")
+        .arg(model)
+        .arg("--file")
+        .arg(&wav)
+        .args(["--language", language, "--no-timestamps", "--no-prints"])
+        .output();
+    let _ = std::fs::remove_file(&wav);
+    let output = output.map_err(|e| whisper_error(format!("{}: {e}", program.display())))?;
+    if !output.status.success() {
+        let message = String::from_utf8_lossy(&output.stderr);
+        return Err(whisper_error(format!("whisper failed: {}", message.trim())));
+    }
+    let text = String::from_utf8_lossy(&output.stdout);
+    Ok(text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(" "))
+}
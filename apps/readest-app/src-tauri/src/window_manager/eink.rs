@@ -0,0 +1,234 @@
+//! E-ink mode, for the Linux build on e-ink tablets like the Boox or the
+//! PineNote. Animations and transitions are turned off in every frame of
+//! every window, since the panel shows each of their steps as ghosting,
+//! and `window.__VLARCH_EINK` tells the frontend to skip its own. Each page
+//! turn repaints the whole window so the panel updates all of it, and every
+//! few turns the window flashes black and white to clear what ghosting is
+//! left, as [`flash_refresh`] does on request.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tauri::webview::{PageLoadEvent, PageLoadPayload};
+use tauri::{command, AppHandle, Manager, Webview, WebviewWindow, WebviewWindowBuilder, Wry};
+
+use crate::error::Result;
+use crate::store;
+
+const SETTINGS_FILE: &str = "eink.json";
+
+/// Stops animations and transitions without dropping the events that
+/// mark their end, which the frontend may wait for.
+const STYLE: &str = "*, *::before, *::after { \
+    animation-duration: 0s !important; \
+    animation-delay: 0s !important; \
+    animation-iteration-count: 1 !important; \
+    transition: none !important; \
+    scroll-behavior: auto !important; \
+    caret-color: transparent !important; }";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct EinkSettings {
+    pub enabled: bool,
+    /// Page turns between flashes, 0 for none.
+    pub flash_every: u32,
+    /// How long a flash stays black, and then white, in milliseconds.
+    pub flash_ms: u32,
+}
+
+impl Default for EinkSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            flash_every: 6,
+            flash_ms: 120,
+        }
+    }
+}
+
+/// Page turns since the last flash, by window label.
+#[derive(Default)]
+pub struct Eink(Mutex<HashMap<String, u32>>);
+
+/// Defines `__vlarchEink` in the frame, which turns the style on or off,
+/// and calls it with `enabled`.
+fn init_script(enabled: bool) -> String {
+    let style = serde_json::to_string(STYLE).unwrap_or_default();
+    format!(
+        r#"(() => {{
+  const apply = (on) => {{
+    let style = document.getElementById("vlarch-eink");
+    if (on && !style) {{
+      style = document.createElement("style");
+      style.id = "vlarch-eink";
+      style.textContent = {style};
+      (document.head || document.documentElement).appendChild(style);
+    }} else if (!on && style) {{
+      style.remove();
+    }}
+  }};
+  window.__vlarchEink = (on) => {{
+    window.__VLARCH_EINK = on;
+    if (document.head) {{
+      apply(on);
+    }} else {{
+      document.addEventListener("DOMContentLoaded", () => apply(window.__VLARCH_EINK), {{ once: true }});
+    }}
+  }};
+  window.__vlarchEink({enabled});
+}})();"#
+    )
+}
+
+/// Turns the style on or off in the window and the frames in it that it
+/// can reach.
+fn toggle_script(enabled: bool) -> String {
+    format!(
+        r#"(() => {{
+  const walk = (win) => {{
+    try {{
+      if (win.__vlarchEink) win.__vlarchEink({enabled});
+    }} catch (e) {{}}
+    for (let i = 0; i < win.frames.length; i++) walk(win.frames[i]);
+  }};
+  walk(window);
+}})();"#
+    )
+}
+
+/// Covers the window in black and then white, `ms` each.
+fn flash_script(ms: u32) -> String {
+    format!(
+        r##"(() => {{
+  const flash = document.createElement("div");
+  flash.style.cssText = "position:fixed;inset:0;z-index:2147483647;background:#000;pointer-events:none";
+  document.documentElement.appendChild(flash);
+  setTimeout(() => {{
+    flash.style.background = "#fff";
+    setTimeout(() => flash.remove(), {ms});
+  }}, {ms});
+}})();"##
+    )
+}
+
+/// Adds the script of e-ink mode to the frames of a window the app builds.
+pub fn configure_webview<'a, M: Manager<Wry>>(
+    app: &AppHandle,
+    builder: WebviewWindowBuilder<'a, Wry, M>,
+) -> WebviewWindowBuilder<'a, Wry, M> {
+    let settings: EinkSettings = store::load(app, SETTINGS_FILE);
+    builder.initialization_script_for_all_frames(init_script(settings.enabled))
+}
+
+/// Windows the frontend opens lack the script in their frames, so it is
+/// added to their page at least.
+pub fn on_page_load(webview: &Webview, payload: &PageLoadPayload<'_>) {
+    if payload.event() != PageLoadEvent::Finished {
+        return;
+    }
+    let settings: EinkSettings = store::load(webview.app_handle(), SETTINGS_FILE);
+    if !settings.enabled {
+        return;
+    }
+    let script = format!("{}{}", init_script(true), toggle_script(true));
+    if let Err(e) = webview.eval(script) {
+        log::warn!("Failed to turn on e-ink mode: {e}");
+    }
+}
+
+/// Has the window drawn again as a whole, which makes the compositor send
+/// all of it to the panel.
+#[cfg(target_os = "linux")]
+fn repaint(window: &WebviewWindow) -> Result<()> {
+    use gtk::prelude::WidgetExt;
+
+    window.gtk_window()?.queue_draw();
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn repaint(window: &WebviewWindow) -> Result<()> {
+    window.eval(
+        "(() => { const root = document.documentElement; \
+         root.style.transform = \"translateZ(0)\"; \
+         requestAnimationFrame(() => { root.style.transform = \"\"; }); })();",
+    )?;
+    Ok(())
+}
+
+fn flash(window: &WebviewWindow, settings: &EinkSettings) -> Result<()> {
+    window.eval(flash_script(settings.flash_ms))?;
+    let window = window.clone();
+    let wait = Duration::from_millis(u64::from(settings.flash_ms) * 2);
+    tauri::async_runtime::spawn(async move {
+        tokio::time::sleep(wait).await;
+        if let Err(e) = repaint(&window) {
+            log::warn!("Failed to repaint {}: {e}", window.label());
+        }
+    });
+    Ok(())
+}
+
+/// Drops the page turns of a window that is closed.
+pub fn forget(app: &AppHandle, label: &str) {
+    if let Some(eink) = app.try_state::<Eink>() {
+        eink.0.lock().unwrap().remove(label);
+    }
+}
+
+#[command]
+pub fn get_eink_settings(app: AppHandle) -> EinkSettings {
+    store::load(&app, SETTINGS_FILE)
+}
+
+/// Saves `settings` and turns e-ink mode on or off in the open windows.
+#[command]
+pub fn set_eink_settings(app: AppHandle, settings: EinkSettings) -> Result<()> {
+    store::save(&app, SETTINGS_FILE, &settings)?;
+    app.state::<Eink>().0.lock().unwrap().clear();
+    let script = toggle_script(settings.enabled);
+    for window in app.webview_windows().values() {
+        if let Err(e) = window.eval(&script) {
+            log::warn!("Failed to set e-ink mode in {}: {e}", window.label());
+        }
+    }
+    Ok(())
+}
+
+/// Flashes the window black and white, clearing the ghosting of the panel.
+#[command]
+pub fn flash_refresh(app: AppHandle, window: WebviewWindow) -> Result<()> {
+    let settings: EinkSettings = store::load(&app, SETTINGS_FILE);
+    app.state::<Eink>().0.lock().unwrap().remove(window.label());
+    flash(&window, &settings)
+}
+
+/// Tells that a page was turned in the window, which is then repainted, or
+/// flashed when it is time. Returns whether it flashed.
+#[command]
+pub fn eink_page_turn(app: AppHandle, window: WebviewWindow) -> Result<bool> {
+    let settings: EinkSettings = store::load(&app, SETTINGS_FILE);
+    if !settings.enabled {
+        return Ok(false);
+    }
+    let due = {
+        let eink = app.state::<Eink>();
+        let mut turns = eink.0.lock().unwrap();
+        let count = turns.entry(window.label().to_string()).or_default();
+        *count += 1;
+        let due = settings.flash_every > 0 && *count >= settings.flash_every;
+        if due {
+            *count = 0;
+        }
+        due
+    };
+    if due {
+        flash(&window, &settings)?;
+    } else {
+        repaint(&window)?;
+    }
+    Ok(due)
+}
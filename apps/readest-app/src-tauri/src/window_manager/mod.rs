@@ -0,0 +1,295 @@
+//! Books read in windows of their own. Each book's window comes back where
+//! it was last left, on the same monitor when it is still connected, and
+//! the windows of the books open at quit are opened again on launch.
+//! Closing a window takes its book out of the session; quitting does not.
+//! The window-state plugin keeps handling the main window and the reader
+//! windows the frontend opens.
+//!
+//! All windows can be made to suit e-ink panels, see [`eink`].
+
+pub mod eink;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use tauri::{
+    command, AppHandle, LogicalPosition, LogicalSize, Manager, Monitor, WebviewUrl,
+    WebviewWindowBuilder, Window, WindowEvent,
+};
+
+use crate::error::Result;
+use crate::library::db;
+use crate::paths;
+use crate::store;
+
+const SESSION_FILE: &str = "windows.json";
+/// Labels of book windows, which the default capability grants as reader
+/// windows.
+pub const LABEL_PREFIX: &str = "reader-book-";
+
+static NEXT_WINDOW: AtomicUsize = AtomicUsize::new(0);
+
+/// Where a window is and how it is shown, in logical pixels.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WindowState {
+    x: f64,
+    y: f64,
+    width: f64,
+    height: f64,
+    /// Name of the monitor the window was on.
+    monitor: Option<String>,
+    maximized: bool,
+    fullscreen: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct Session {
+    /// Books in windows of their own, in the order they were opened.
+    open: Vec<String>,
+    /// Last state of the window of each book, by hash.
+    books: HashMap<String, WindowState>,
+    /// Book of each open window, by label.
+    #[serde(skip)]
+    windows: HashMap<String, String>,
+}
+
+#[derive(Default)]
+pub struct BookWindows(Mutex<Session>);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WindowInfo {
+    pub label: String,
+    pub title: String,
+    /// Book read in the window, for book windows.
+    pub book_hash: Option<String>,
+    pub focused: bool,
+    pub visible: bool,
+    pub monitor: Option<String>,
+}
+
+fn save(app: &AppHandle) -> Result<()> {
+    let state = app.state::<BookWindows>();
+    let session = state.0.lock().unwrap();
+    store::save(app, SESSION_FILE, &*session)
+}
+
+/// The saved state of a window, if it still fits on one of `monitors`.
+fn placement<'a>(state: &'a WindowState, monitors: &[Monitor]) -> Option<&'a WindowState> {
+    let fits = monitors.iter().any(|monitor| {
+        let scale = monitor.scale_factor();
+        let position = monitor.position().to_logical::<f64>(scale);
+        let size = monitor.size().to_logical::<f64>(scale);
+        let on_it = state.monitor.is_none() || monitor.name() == state.monitor.as_ref();
+        on_it
+            && state.x >= position.x
+            && state.y >= position.y
+            && state.x < position.x + size.width
+            && state.y < position.y + size.height
+    });
+    fits.then_some(state)
+}
+
+fn build_window(app: &AppHandle, book_hash: &str) -> Result<String> {
+    let label = format!(
+        "{LABEL_PREFIX}{}",
+        NEXT_WINDOW.fetch_add(1, Ordering::Relaxed)
+    );
+    let ids: String = url::form_urlencoded::byte_serialize(book_hash.as_bytes()).collect();
+    let url = format!("reader?ids={ids}");
+    let builder = WebviewWindowBuilder::new(app, &label, WebviewUrl::App(url.into()))
+        .resizable(true)
+        .inner_size(800.0, 600.0);
+    let builder = paths::configure_webview(app, builder);
+    let builder = eink::configure_webview(app, builder);
+
+    // The same look as the reader windows of the frontend
+    #[cfg(target_os = "macos")]
+    let builder = builder
+        .decorations(true)
+        .title_bar_style(tauri::TitleBarStyle::Overlay)
+        .title("");
+    #[cfg(not(target_os = "macos"))]
+    let builder = builder
+        .decorations(false)
+        .transparent(true)
+        .shadow(true)
+        .title("VL-Arch");
+
+    let saved = {
+        let state = app.state::<BookWindows>();
+        let session = state.0.lock().unwrap();
+        session.books.get(book_hash).cloned()
+    };
+    let monitors = app.available_monitors().unwrap_or_default();
+    let builder = match saved.as_ref().and_then(|saved| placement(saved, &monitors)) {
+        Some(saved) => builder
+            .position(saved.x, saved.y)
+            .inner_size(saved.width, saved.height)
+            .maximized(saved.maximized)
+            .fullscreen(saved.fullscreen),
+        None => builder.center(),
+    };
+    builder.build()?;
+
+    let state = app.state::<BookWindows>();
+    let mut session = state.0.lock().unwrap();
+    session.windows.insert(label.clone(), book_hash.to_string());
+    if !session.open.iter().any(|hash| hash == book_hash) {
+        session.open.push(book_hash.to_string());
+    }
+    Ok(label)
+}
+
+/// Brings the window of `book_hash` to the front, opening one if the book
+/// has none yet.
+fn open_window(app: &AppHandle, book_hash: &str) -> Result<String> {
+    let existing = {
+        let state = app.state::<BookWindows>();
+        let session = state.0.lock().unwrap();
+        session
+            .windows
+            .iter()
+            .find(|(_, hash)| *hash == book_hash)
+            .map(|(label, _)| label.clone())
+    };
+    if let Some(window) = existing.and_then(|label| app.get_webview_window(&label)) {
+        let _ = window.unminimize();
+        let _ = window.show();
+        let _ = window.set_focus();
+        return Ok(window.label().to_string());
+    }
+    let label = build_window(app, book_hash)?;
+    save(app)?;
+    Ok(label)
+}
+
+/// The state `window` is in now, keeping the size it had before it was
+/// maximized or made full screen.
+fn current_state(window: &Window, previous: Option<&WindowState>) -> Option<WindowState> {
+    let scale = window.scale_factor().ok()?;
+    let maximized = window.is_maximized().unwrap_or(false);
+    let fullscreen = window.is_fullscreen().unwrap_or(false);
+    let monitor = window
+        .current_monitor()
+        .ok()
+        .flatten()
+        .and_then(|monitor| monitor.name().cloned());
+    if let Some(previous) = previous.filter(|_| maximized || fullscreen) {
+        return Some(WindowState {
+            monitor,
+            maximized,
+            fullscreen,
+            ..previous.clone()
+        });
+    }
+    let position: LogicalPosition<f64> = window.outer_position().ok()?.to_logical(scale);
+    let size: LogicalSize<f64> = window.inner_size().ok()?.to_logical(scale);
+    Some(WindowState {
+        x: position.x,
+        y: position.y,
+        width: size.width,
+        height: size.height,
+        monitor,
+        maximized,
+        fullscreen,
+    })
+}
+
+pub fn on_window_event(window: &Window, event: &WindowEvent) {
+    let app = window.app_handle();
+    if matches!(event, WindowEvent::Destroyed) {
+        eink::forget(app, window.label());
+    }
+    if !window.label().starts_with(LABEL_PREFIX) {
+        return;
+    }
+    let state = app.state::<BookWindows>();
+    let mut session = state.0.lock().unwrap();
+    let Some(book_hash) = session.windows.get(window.label()).cloned() else {
+        return;
+    };
+    match event {
+        WindowEvent::Moved(_)
+        | WindowEvent::Resized(_)
+        | WindowEvent::ScaleFactorChanged { .. } => {
+            if let Some(current) = current_state(window, session.books.get(&book_hash)) {
+                session.books.insert(book_hash, current);
+            }
+        }
+        WindowEvent::CloseRequested { .. } => {
+            session.open.retain(|hash| *hash != book_hash);
+            drop(session);
+            if let Err(e) = save(app) {
+                log::warn!("Failed to save the window session: {e}");
+            }
+        }
+        WindowEvent::Destroyed => {
+            session.windows.remove(window.label());
+        }
+        _ => {}
+    }
+}
+
+/// Keeps the last place of each window for the next launch.
+pub fn on_exit(app: &AppHandle) {
+    if let Err(e) = save(app) {
+        log::warn!("Failed to save the window session: {e}");
+    }
+}
+
+/// Opens the windows of the books that were open at quit, leaving out
+/// books removed from the library since.
+pub fn init(app: &AppHandle) {
+    let mut session: Session = store::load(app, SESSION_FILE);
+    let open = std::mem::take(&mut session.open);
+    app.manage(BookWindows(Mutex::new(session)));
+    app.manage(eink::Eink::default());
+
+    let db = app.state::<db::LibraryDb>();
+    for book_hash in open {
+        let in_library = db::get_book(&db.conn(), &book_hash)
+            .ok()
+            .flatten()
+            .is_some_and(|book| book.deleted_at.is_none());
+        if !in_library {
+            continue;
+        }
+        if let Err(e) = build_window(app, &book_hash) {
+            log::warn!("Failed to reopen the window of {book_hash}: {e}");
+        }
+    }
+}
+
+#[command]
+pub async fn open_book_window(app: AppHandle, book_hash: String) -> Result<String> {
+    open_window(&app, &book_hash)
+}
+
+#[command]
+pub fn list_windows(app: AppHandle) -> Vec<WindowInfo> {
+    let state = app.state::<BookWindows>();
+    let session = state.0.lock().unwrap();
+    let mut windows = app
+        .webview_windows()
+        .into_values()
+        .map(|window| WindowInfo {
+            label: window.label().to_string(),
+            title: window.title().unwrap_or_default(),
+            book_hash: session.windows.get(window.label()).cloned(),
+            focused: window.is_focused().unwrap_or(false),
+            visible: window.is_visible().unwrap_or(false),
+            monitor: window
+                .current_monitor()
+                .ok()
+                .flatten()
+                .and_then(|monitor| monitor.name().cloned()),
+        })
+        .collect::<Vec<_>>();
+    windows.sort_by(|a, b| a.label.cmp(&b.label));
+    windows
+}
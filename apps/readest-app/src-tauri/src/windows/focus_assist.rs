@@ -0,0 +1,76 @@
+//! Focus Assist, Do Not Disturb on Windows 11, which has no public API.
+//! Its profile is the WNF state the Settings app and the shell change,
+//! read and written with the undocumented calls of `ntdll` they use.
+
+use std::ffi::c_void;
+
+use windows::Win32::Foundation::NTSTATUS;
+
+use crate::error::Result;
+
+/// `WNF_SHEL_QUIETHOURS_ACTIVE_PROFILE_CHANGED`.
+const QUIET_HOURS_PROFILE: u64 = 0x0D83_063E_A3BF_1C75;
+
+/// Profiles of Focus Assist.
+pub const OFF: u32 = 0;
+/// Only notifications of the apps on the priority list.
+pub const PRIORITY_ONLY: u32 = 1;
+
+#[link(name = "ntdll")]
+extern "system" {
+    fn NtQueryWnfStateData(
+        state_name: *const u64,
+        type_id: *const c_void,
+        explicit_scope: *const c_void,
+        change_stamp: *mut u32,
+        buffer: *mut c_void,
+        buffer_size: *mut u32,
+    ) -> NTSTATUS;
+    fn NtUpdateWnfStateData(
+        state_name: *const u64,
+        buffer: *const c_void,
+        length: u32,
+        type_id: *const c_void,
+        explicit_scope: *const c_void,
+        matching_change_stamp: u32,
+        check_stamp: u32,
+    ) -> NTSTATUS;
+}
+
+/// The profile in use, [`OFF`] when none is.
+pub fn profile() -> Result<u32> {
+    let mut profile = OFF;
+    let mut size = std::mem::size_of::<u32>() as u32;
+    let mut stamp = 0;
+    // SAFETY: the buffer is as big as `size` says
+    unsafe {
+        NtQueryWnfStateData(
+            &QUIET_HOURS_PROFILE,
+            std::ptr::null(),
+            std::ptr::null(),
+            &mut stamp,
+            (&mut profile as *mut u32).cast(),
+            &mut size,
+        )
+    }
+    .ok()?;
+    // Never set since the system started
+    Ok(if size == 0 { OFF } else { profile })
+}
+
+pub fn set_profile(profile: u32) -> Result<()> {
+    // SAFETY: the buffer is as big as the length says
+    unsafe {
+        NtUpdateWnfStateData(
+            &QUIET_HOURS_PROFILE,
+            (&profile as *const u32).cast(),
+            std::mem::size_of::<u32>() as u32,
+            std::ptr::null(),
+            std::ptr::null(),
+            0,
+            0,
+        )
+    }
+    .ok()?;
+    Ok(())
+}
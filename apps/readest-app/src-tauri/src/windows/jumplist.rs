@@ -0,0 +1,90 @@
+//! "Recent books" category of the taskbar Jump List. Each entry starts the
+//! app with a `vlarch://open` link, which the single-instance guard hands
+//! to the running instance.
+
+use windows::core::{Interface, HSTRING};
+use windows::Win32::Storage::EnhancedStorage::PKEY_Title;
+use windows::Win32::System::Com::StructuredStorage::PROPVARIANT;
+use windows::Win32::System::Com::{
+    CoCreateInstance, CoInitializeEx, CLSCTX_INPROC_SERVER, COINIT_MULTITHREADED,
+};
+use windows::Win32::UI::Shell::Common::{IObjectArray, IObjectCollection};
+use windows::Win32::UI::Shell::PropertiesSystem::IPropertyStore;
+use windows::Win32::UI::Shell::{
+    DestinationList, EnumerableObjectCollection, ICustomDestinationList, IShellLinkW, ShellLink,
+};
+
+use crate::deep_link;
+use crate::error::Result;
+use crate::recent::RecentBook;
+
+const CATEGORY: &str = "Recent Books";
+
+fn arguments(book: &RecentBook) -> String {
+    format!("{}://open?book={}", deep_link::SCHEME, book.hash)
+}
+
+/// Arguments of the entries the user removed from the list, which may not
+/// be added back.
+fn removed_arguments(removed: &IObjectArray) -> Result<Vec<String>> {
+    let mut arguments = Vec::new();
+    for i in 0..unsafe { removed.GetCount()? } {
+        let Ok(link) = (unsafe { removed.GetAt::<IShellLinkW>(i) }) else {
+            continue;
+        };
+        let mut buffer = [0u16; 1024];
+        unsafe { link.GetArguments(&mut buffer)? };
+        let len = buffer.iter().position(|&c| c == 0).unwrap_or(buffer.len());
+        arguments.push(String::from_utf16_lossy(&buffer[..len]));
+    }
+    Ok(arguments)
+}
+
+fn shell_link(exe: &HSTRING, book: &RecentBook) -> Result<IShellLinkW> {
+    unsafe {
+        let link: IShellLinkW = CoCreateInstance(&ShellLink, None, CLSCTX_INPROC_SERVER)?;
+        link.SetPath(exe)?;
+        link.SetArguments(&HSTRING::from(arguments(book)))?;
+        link.SetIconLocation(exe, 0)?;
+        link.SetDescription(&HSTRING::from(&book.title))?;
+        // Jump List entries show the title property rather than the description
+        let properties: IPropertyStore = link.cast()?;
+        properties.SetValue(&PKEY_Title, &PROPVARIANT::from(book.title.as_str()))?;
+        properties.Commit()?;
+        Ok(link)
+    }
+}
+
+/// Replaces the category with `books`, as many as the taskbar has room for.
+pub fn set_recent_books(books: &[RecentBook]) -> Result<()> {
+    // Commands run on blocking pool threads that have not initialized COM;
+    // repeated calls are harmless
+    let _ = unsafe { CoInitializeEx(None, COINIT_MULTITHREADED) };
+    let exe = HSTRING::from(std::env::current_exe()?.as_os_str());
+
+    unsafe {
+        let list: ICustomDestinationList =
+            CoCreateInstance(&DestinationList, None, CLSCTX_INPROC_SERVER)?;
+        let mut slots = 0u32;
+        let removed: IObjectArray = list.BeginList(&mut slots)?;
+        let removed = removed_arguments(&removed)?;
+
+        let collection: IObjectCollection =
+            CoCreateInstance(&EnumerableObjectCollection, None, CLSCTX_INPROC_SERVER)?;
+        let books = books
+            .iter()
+            .filter(|book| !removed.contains(&arguments(book)))
+            .take(slots as usize);
+        let mut count = 0;
+        for book in books {
+            collection.AddObject(&shell_link(&exe, book)?)?;
+            count += 1;
+        }
+        if count > 0 {
+            let items: IObjectArray = collection.cast()?;
+            list.AppendCategory(&HSTRING::from(CATEGORY), &items)?;
+        }
+        list.CommitList()?;
+    }
+    Ok(())
+}
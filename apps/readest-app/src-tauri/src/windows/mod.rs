@@ -0,0 +1,9 @@
+pub mod focus_assist;
+pub mod jumplist;
+pub mod night_light;
+pub mod search;
+pub mod shell;
+pub mod taskbar;
+#[cfg(feature = "thumbnails")]
+pub mod thumbnails;
+pub mod toast;
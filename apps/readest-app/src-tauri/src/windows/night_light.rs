@@ -0,0 +1,58 @@
+//! Night light, which has no public API. Whether it is on is in the state
+//! the Settings app keeps in the cloud store of the registry, and the key
+//! is watched for it to change.
+
+use windows::Win32::System::Registry::{RegNotifyChangeKeyValue, HKEY, REG_NOTIFY_CHANGE_LAST_SET};
+use windows_registry::CURRENT_USER;
+
+use crate::error::Result;
+
+const STATE_KEY: &str = r"Software\Microsoft\Windows\CurrentVersion\CloudStore\Store\DefaultAccount\Current\default$windows.data.bluelightreduction.bluelightreductionstate\windows.data.bluelightreduction.bluelightreductionstate";
+const STATE_VALUE: &str = "Data";
+/// The byte of the state that is set while night light is on, and the
+/// value it has then.
+const ACTIVE_OFFSET: usize = 18;
+const ACTIVE: u8 = 0x15;
+
+/// Whether night light is on, None when it has never been set up.
+pub fn active() -> Option<bool> {
+    let data = CURRENT_USER
+        .open(STATE_KEY)
+        .and_then(|key| key.get_value(STATE_VALUE))
+        .ok()?;
+    Some(data.get(ACTIVE_OFFSET) == Some(&ACTIVE))
+}
+
+/// Calls `changed` whenever night light turns on or off.
+pub fn listen(changed: impl Fn() + Send + 'static) -> Result<()> {
+    std::thread::Builder::new()
+        .name("night-light".to_string())
+        .spawn(move || {
+            // Keys cannot move between threads
+            let key = match CURRENT_USER.open(STATE_KEY) {
+                Ok(key) => key,
+                Err(e) => {
+                    log::warn!("Failed to watch night light: {e}");
+                    return;
+                }
+            };
+            loop {
+                // SAFETY: the key stays open for as long as the loop runs
+                let result = unsafe {
+                    RegNotifyChangeKeyValue(
+                        HKEY(key.as_raw()),
+                        false,
+                        REG_NOTIFY_CHANGE_LAST_SET,
+                        None,
+                        false,
+                    )
+                };
+                if let Err(e) = result.ok() {
+                    log::warn!("Stopped watching night light: {e}");
+                    return;
+                }
+                changed();
+            }
+        })?;
+    Ok(())
+}
@@ -0,0 +1,124 @@
+//! Library books in Windows Search. Apps outside the Store cannot add their
+//! own items to the indexer, so each book gets a shortcut that starts the
+//! app with its `vlarch://open` link, in a folder of the app data added to
+//! the indexer's crawl scope. Shortcuts are named after the book, one per
+//! folder named by hash, so results read as titles and a book's shortcut
+//! can be replaced or removed without looking through the others.
+
+use std::path::{Path, PathBuf};
+
+use tauri::AppHandle;
+use windows::core::{Interface, HSTRING};
+use windows::Win32::Storage::EnhancedStorage::PKEY_Title;
+use windows::Win32::System::Com::StructuredStorage::PROPVARIANT;
+use windows::Win32::System::Com::{
+    CoCreateInstance, CoInitializeEx, IPersistFile, CLSCTX_INPROC_SERVER, CLSCTX_LOCAL_SERVER,
+    COINIT_MULTITHREADED,
+};
+use windows::Win32::System::Search::{CSearchManager, ISearchManager};
+use windows::Win32::UI::Shell::PropertiesSystem::IPropertyStore;
+use windows::Win32::UI::Shell::{IShellLinkW, ShellLink};
+
+use crate::error::Result;
+use crate::paths;
+use crate::system_search::{link, Entry};
+use crate::utils::sanitize_file_name;
+
+/// Characters of a title kept in the name of its shortcut.
+const MAX_NAME: usize = 120;
+
+fn shortcuts_dir(app: &AppHandle) -> Result<PathBuf> {
+    Ok(paths::data_dir(app)?.join("search"))
+}
+
+/// Adds `dir` to the crawl scope of the system index for this user, if it
+/// is not covered yet.
+fn include_in_index(dir: &Path) -> Result<()> {
+    let url = HSTRING::from(format!(
+        "file:///{}/",
+        dir.to_string_lossy().replace('\\', "/")
+    ));
+    unsafe {
+        let manager: ISearchManager = CoCreateInstance(&CSearchManager, None, CLSCTX_LOCAL_SERVER)?;
+        let scopes = manager
+            .GetCatalog(&HSTRING::from("SystemIndex"))?
+            .GetCrawlScopeManager()?;
+        if scopes.IncludedInCrawlScope(&url)?.as_bool() {
+            return Ok(());
+        }
+        scopes.AddUserScopeRule(&url, true, false, 0)?;
+        scopes.SaveAll()?;
+    }
+    Ok(())
+}
+
+fn save_shortcut(exe: &HSTRING, entry: &Entry, path: &Path) -> Result<()> {
+    let mut description = entry.author.clone();
+    if !entry.tags.is_empty() {
+        if !description.is_empty() {
+            description.push_str(" · ");
+        }
+        description.push_str(&entry.tags.join(", "));
+    }
+    unsafe {
+        let link_file: IShellLinkW = CoCreateInstance(&ShellLink, None, CLSCTX_INPROC_SERVER)?;
+        link_file.SetPath(exe)?;
+        link_file.SetArguments(&HSTRING::from(link(&entry.hash)))?;
+        link_file.SetIconLocation(exe, 0)?;
+        // Indexed as the comment, which search matches too
+        link_file.SetDescription(&HSTRING::from(description))?;
+        let properties: IPropertyStore = link_file.cast()?;
+        properties.SetValue(&PKEY_Title, &PROPVARIANT::from(entry.title.as_str()))?;
+        properties.Commit()?;
+        let file: IPersistFile = link_file.cast()?;
+        file.Save(&HSTRING::from(path), true)?;
+    }
+    Ok(())
+}
+
+/// Writes the shortcuts of `entries`, replacing the ones they had.
+pub fn publish(app: &AppHandle, entries: &[Entry]) -> Result<()> {
+    // Runs on blocking pool threads that have not initialized COM;
+    // repeated calls are harmless
+    let _ = unsafe { CoInitializeEx(None, COINIT_MULTITHREADED) };
+    let dir = shortcuts_dir(app)?;
+    std::fs::create_dir_all(&dir)?;
+    if let Err(e) = include_in_index(&dir) {
+        log::warn!("Failed to add {dir:?} to the search index: {e}");
+    }
+    let exe = HSTRING::from(std::env::current_exe()?.as_os_str());
+    for entry in entries {
+        let book_dir = dir.join(&entry.hash);
+        let _ = std::fs::remove_dir_all(&book_dir);
+        std::fs::create_dir_all(&book_dir)?;
+        let title = sanitize_file_name(entry.title.trim());
+        let title = title.chars().take(MAX_NAME).collect::<String>();
+        // Windows drops trailing dots and spaces from file names
+        let title = match title.trim_end_matches(['.', ' ']) {
+            "" => entry.hash.as_str(),
+            title => title,
+        };
+        let name = format!("{title}.lnk");
+        save_shortcut(&exe, entry, &book_dir.join(name))?;
+    }
+    Ok(())
+}
+
+pub fn remove(app: &AppHandle, hashes: &[String]) -> Result<()> {
+    let dir = shortcuts_dir(app)?;
+    for hash in hashes {
+        match std::fs::remove_dir_all(dir.join(hash)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Removes every shortcut, which the indexer then drops.
+pub fn clear(app: &AppHandle) -> Result<()> {
+    match std::fs::remove_dir_all(shortcuts_dir(app)?) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,158 @@
+//! Explorer integration from settings: an "Open with VL-Arch" entry in the
+//! context menu of book files, and thumbnails of their covers from the
+//! provider in `vlarch-thumbnails.dll`, which installers built with
+//! `scripts/build-thumbnails.sh` bundle. Both are registered per user
+//! under `HKEY_CURRENT_USER\Software\Classes`, like the file associations,
+//! and read back from there.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle, Manager};
+use windows::core::GUID;
+use windows::Win32::UI::Shell::{SHChangeNotify, SHCNE_ASSOCCHANGED, SHCNF_IDLIST};
+use windows_registry::CURRENT_USER;
+
+use crate::associations::FILE_TYPES;
+use crate::error::Result;
+use crate::i18n;
+
+const CLASSES: &str = r"Software\Classes";
+const VERB: &str = "VL-Arch";
+pub const THUMBNAIL_DLL: &str = "vlarch-thumbnails.dll";
+/// Class of the thumbnail provider.
+pub const THUMBNAIL_CLSID: GUID = GUID::from_u128(0x6d2a4b7e_3c1f_4e8a_9b5d_2f7c8e1a4b90);
+/// The shell extension point of thumbnail providers.
+const THUMBNAIL_HANDLER: &str = "{e357fccd-a995-4576-b01f-234630154e96}";
+/// Formats whose covers the provider reads.
+const THUMBNAIL_EXTENSIONS: &[&str] = &["epub", "cbz", "fb2"];
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShellSettings {
+    pub context_menu: bool,
+    pub thumbnails: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShellIntegration {
+    #[serde(flatten)]
+    pub settings: ShellSettings,
+    /// The installer bundles the thumbnail provider.
+    pub thumbnails_available: bool,
+}
+
+fn clsid() -> String {
+    format!("{{{THUMBNAIL_CLSID:?}}}")
+}
+
+fn thumbnail_dll(app: &AppHandle) -> Option<PathBuf> {
+    let dll = app.path().resource_dir().ok()?.join(THUMBNAIL_DLL);
+    dll.is_file().then_some(dll)
+}
+
+fn verb_key(ext: &str) -> String {
+    format!(r"{CLASSES}\SystemFileAssociations\.{ext}\shell\{VERB}")
+}
+
+fn context_menu_command() -> Result<String> {
+    let exe = std::env::current_exe()?;
+    Ok(format!("\"{}\" \"%1\"", exe.display()))
+}
+
+fn set_context_menu(app: &AppHandle, enabled: bool) -> Result<()> {
+    let exe = std::env::current_exe()?;
+    let command = context_menu_command()?;
+    let label = i18n::t(app, "Open with {{name}}");
+    for file_type in FILE_TYPES {
+        let key = verb_key(file_type.extension);
+        if !enabled {
+            let _ = CURRENT_USER.remove_tree(&key);
+            continue;
+        }
+        let verb = CURRENT_USER.create(&key)?;
+        verb.set_string("MUIVerb", &label)?;
+        verb.set_string("Icon", format!("\"{}\",0", exe.display()))?;
+        verb.create("command")?.set_string("", &command)?;
+    }
+    Ok(())
+}
+
+fn set_thumbnails(dll: Option<PathBuf>) -> Result<()> {
+    let clsid = clsid();
+    let class_key = format!(r"{CLASSES}\CLSID\{clsid}");
+    let Some(dll) = dll else {
+        for ext in THUMBNAIL_EXTENSIONS {
+            let handler = format!(r"{CLASSES}\.{ext}\ShellEx\{THUMBNAIL_HANDLER}");
+            // Leaves another app's provider alone
+            let ours = CURRENT_USER
+                .open(&handler)
+                .and_then(|key| key.get_string(""))
+                .is_ok_and(|value| value.eq_ignore_ascii_case(&clsid));
+            if ours {
+                let _ = CURRENT_USER.remove_tree(&handler);
+            }
+        }
+        let _ = CURRENT_USER.remove_tree(&class_key);
+        return Ok(());
+    };
+    let class = CURRENT_USER.create(&class_key)?;
+    class.set_string("", "VL-Arch Thumbnail Provider")?;
+    let server = class.create("InprocServer32")?;
+    server.set_string("", dll.to_string_lossy())?;
+    server.set_string("ThreadingModel", "Apartment")?;
+    for ext in THUMBNAIL_EXTENSIONS {
+        CURRENT_USER
+            .create(format!(r"{CLASSES}\.{ext}\ShellEx\{THUMBNAIL_HANDLER}"))?
+            .set_string("", &clsid)?;
+    }
+    Ok(())
+}
+
+fn current(app: &AppHandle) -> Result<ShellIntegration> {
+    let command = context_menu_command()?;
+    // An entry left behind by a copy of the app elsewhere does not count
+    let context_menu = FILE_TYPES.iter().all(|file_type| {
+        CURRENT_USER
+            .open(format!(r"{}\command", verb_key(file_type.extension)))
+            .and_then(|key| key.get_string(""))
+            .is_ok_and(|value| value == command)
+    });
+    let dll = thumbnail_dll(app);
+    let thumbnails = dll.as_ref().is_some_and(|dll| {
+        CURRENT_USER
+            .open(format!(r"{CLASSES}\CLSID\{}\InprocServer32", clsid()))
+            .and_then(|key| key.get_string(""))
+            .is_ok_and(|value| Path::new(&value) == dll)
+    });
+    Ok(ShellIntegration {
+        settings: ShellSettings {
+            context_menu,
+            thumbnails,
+        },
+        thumbnails_available: dll.is_some(),
+    })
+}
+
+#[command]
+pub async fn get_shell_integration(app: AppHandle) -> Result<ShellIntegration> {
+    tauri::async_runtime::spawn_blocking(move || current(&app)).await?
+}
+
+/// Adds or removes the context menu entry and the thumbnail provider, and
+/// returns what is registered now.
+#[command]
+pub async fn set_shell_integration(
+    app: AppHandle,
+    settings: ShellSettings,
+) -> Result<ShellIntegration> {
+    tauri::async_runtime::spawn_blocking(move || {
+        set_context_menu(&app, settings.context_menu)?;
+        set_thumbnails(thumbnail_dll(&app).filter(|_| settings.thumbnails))?;
+        // Tells Explorer to pick up the changes without a restart
+        unsafe { SHChangeNotify(SHCNE_ASSOCCHANGED, SHCNF_IDLIST, None, None) };
+        current(&app)
+    })
+    .await?
+}
@@ -0,0 +1,85 @@
+//! Progress of imports and conversions on the taskbar button of the main
+//! window, so a long import can be followed while the window is minimized.
+//! Several jobs show their average; one whose amount of work is not known
+//! yet makes the bar indeterminate.
+
+use std::sync::Mutex;
+
+use tauri::{AppHandle, Manager};
+use windows::Win32::Foundation::HWND;
+use windows::Win32::System::Com::{CoCreateInstance, CLSCTX_INPROC_SERVER};
+use windows::Win32::UI::Shell::{
+    ITaskbarList3, TaskbarList, TBPFLAG, TBPF_INDETERMINATE, TBPF_NOPROGRESS, TBPF_NORMAL,
+};
+
+use crate::jobs::Jobs;
+
+/// Kinds of jobs shown on the taskbar.
+const KINDS: &[&str] = &["import", "convert"];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Progress {
+    None,
+    Indeterminate,
+    Percent(u64),
+}
+
+/// What the taskbar shows, so it is only updated when that changes.
+static SHOWN: Mutex<Progress> = Mutex::new(Progress::None);
+
+fn current(app: &AppHandle) -> Progress {
+    let percents = app
+        .state::<Jobs>()
+        .running()
+        .into_iter()
+        .filter(|job| KINDS.contains(&job.kind))
+        .map(|job| job.percent)
+        .collect::<Option<Vec<_>>>();
+    match percents {
+        None => Progress::Indeterminate,
+        Some(percents) if percents.is_empty() => Progress::None,
+        Some(percents) => {
+            Progress::Percent((percents.iter().sum::<f32>() / percents.len() as f32).round() as u64)
+        }
+    }
+}
+
+fn show(hwnd: HWND, progress: Progress) -> windows::core::Result<()> {
+    unsafe {
+        // Created on the main thread, where the window lives
+        let taskbar: ITaskbarList3 = CoCreateInstance(&TaskbarList, None, CLSCTX_INPROC_SERVER)?;
+        taskbar.HrInit()?;
+        let state: TBPFLAG = match progress {
+            Progress::None => TBPF_NOPROGRESS,
+            Progress::Indeterminate => TBPF_INDETERMINATE,
+            Progress::Percent(percent) => {
+                taskbar.SetProgressValue(hwnd, percent, 100)?;
+                TBPF_NORMAL
+            }
+        };
+        taskbar.SetProgressState(hwnd, state)
+    }
+}
+
+/// Brings the taskbar button up to date with the running jobs.
+pub fn refresh(app: &AppHandle) {
+    let progress = current(app);
+    {
+        let mut shown = SHOWN.lock().unwrap();
+        if *shown == progress {
+            return;
+        }
+        *shown = progress;
+    }
+    let Some(window) = app.get_webview_window("main") else {
+        return;
+    };
+    let _ = app.run_on_main_thread(move || {
+        let Ok(hwnd) = window.hwnd() else {
+            return;
+        };
+        if let Err(e) = show(HWND(hwnd.0), progress) {
+            log::warn!("Failed to show progress on the taskbar: {e}");
+        }
+    });
+}
@@ -0,0 +1,237 @@
+//! The Explorer thumbnail provider, an in-process COM server in the DLL of
+//! the library built with the `thumbnails` feature, which
+//! `scripts/build-thumbnails.sh` bundles as `vlarch-thumbnails.dll`. It
+//! shows the cover of the book, found the way the library finds it on
+//! import; [`super::shell`] registers it.
+
+use std::cell::RefCell;
+use std::ffi::c_void;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use rand::{distributions::Alphanumeric, Rng};
+use windows::core::{implement, Interface, Ref, BOOL, GUID, HRESULT};
+use windows::Win32::Foundation::{
+    CLASS_E_CLASSNOTAVAILABLE, CLASS_E_NOAGGREGATION, E_FAIL, E_POINTER, S_FALSE, S_OK,
+};
+use windows::Win32::Graphics::Gdi::{
+    CreateDIBSection, BITMAPINFO, BITMAPINFOHEADER, BI_RGB, DIB_RGB_COLORS, HBITMAP,
+};
+use windows::Win32::System::Com::{
+    CoTaskMemFree, IClassFactory, IClassFactory_Impl, IStream, STATFLAG_DEFAULT, STATSTG,
+};
+use windows::Win32::UI::Shell::PropertiesSystem::{
+    IInitializeWithStream, IInitializeWithStream_Impl,
+};
+use windows::Win32::UI::Shell::{
+    IThumbnailProvider, IThumbnailProvider_Impl, WTSAT_RGB, WTS_ALPHATYPE,
+};
+
+use super::shell::THUMBNAIL_CLSID;
+use crate::formats::extract_cover;
+
+/// Largest book read for a thumbnail.
+const MAX_BOOK_SIZE: usize = 256 * 1024 * 1024;
+
+/// Objects and locks handed out, for [`DllCanUnloadNow`].
+static REFERENCES: AtomicUsize = AtomicUsize::new(0);
+
+struct Book {
+    data: Vec<u8>,
+    extension: String,
+}
+
+#[implement(IThumbnailProvider, IInitializeWithStream)]
+struct ThumbnailProvider {
+    book: RefCell<Option<Book>>,
+}
+
+impl ThumbnailProvider {
+    fn new() -> Self {
+        REFERENCES.fetch_add(1, Ordering::Relaxed);
+        Self {
+            book: RefCell::new(None),
+        }
+    }
+}
+
+impl Drop for ThumbnailProvider {
+    fn drop(&mut self) {
+        REFERENCES.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The extension of the file behind `stream`, which formats are told
+/// apart by.
+fn stream_extension(stream: &IStream) -> Option<String> {
+    let mut stat = STATSTG::default();
+    unsafe { stream.Stat(&mut stat, STATFLAG_DEFAULT).ok()? };
+    if stat.pwcsName.is_null() {
+        return None;
+    }
+    let name = unsafe { stat.pwcsName.to_string() };
+    unsafe { CoTaskMemFree(Some(stat.pwcsName.0 as *const c_void)) };
+    let name = name.ok()?;
+    Path::new(&name)
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+}
+
+fn read_stream(stream: &IStream) -> windows::core::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let mut read = 0u32;
+        let hr = unsafe {
+            stream.Read(
+                buffer.as_mut_ptr().cast(),
+                buffer.len() as u32,
+                Some(&mut read),
+            )
+        };
+        hr.ok()?;
+        if read == 0 {
+            return Ok(data);
+        }
+        data.extend_from_slice(&buffer[..read as usize]);
+        if data.len() > MAX_BOOK_SIZE {
+            return Err(E_FAIL.into());
+        }
+    }
+}
+
+/// The cover of `book`, read from a temporary copy as the formats read
+/// files.
+fn cover(book: &Book) -> Option<image::DynamicImage> {
+    let suffix: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(12)
+        .map(char::from)
+        .collect();
+    let path = std::env::temp_dir().join(format!("vlarch-thumbnail-{suffix}.{}", book.extension));
+    let cover = std::fs::File::create(&path)
+        .and_then(|mut file| file.write_all(&book.data))
+        .ok()
+        .and_then(|_| extract_cover(&path).ok().flatten());
+    let _ = std::fs::remove_file(&path);
+    image::load_from_memory(&cover?).ok()
+}
+
+/// A top-down 32-bit bitmap of `image` scaled to fit `size`.
+fn bitmap(image: &image::DynamicImage, size: u32) -> windows::core::Result<HBITMAP> {
+    let image = image.thumbnail(size, size).into_rgba8();
+    let (width, height) = image.dimensions();
+    let info = BITMAPINFO {
+        bmiHeader: BITMAPINFOHEADER {
+            biSize: std::mem::size_of::<BITMAPINFOHEADER>() as u32,
+            biWidth: width as i32,
+            biHeight: -(height as i32),
+            biPlanes: 1,
+            biBitCount: 32,
+            biCompression: BI_RGB.0,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mut bits: *mut c_void = std::ptr::null_mut();
+    let bitmap = unsafe { CreateDIBSection(None, &info, DIB_RGB_COLORS, &mut bits, None, 0)? };
+    let pixels = unsafe { std::slice::from_raw_parts_mut(bits.cast::<u8>(), image.as_raw().len()) };
+    for (out, pixel) in pixels.chunks_exact_mut(4).zip(image.pixels()) {
+        let [r, g, b, _] = pixel.0;
+        out.copy_from_slice(&[b, g, r, 0xff]);
+    }
+    Ok(bitmap)
+}
+
+impl IInitializeWithStream_Impl for ThumbnailProvider_Impl {
+    fn Initialize(&self, stream: Ref<IStream>, _mode: u32) -> windows::core::Result<()> {
+        let stream = stream.ok()?;
+        let extension = stream_extension(stream).unwrap_or_else(|| "epub".into());
+        let data = read_stream(stream)?;
+        self.book.replace(Some(Book { data, extension }));
+        Ok(())
+    }
+}
+
+impl IThumbnailProvider_Impl for ThumbnailProvider_Impl {
+    fn GetThumbnail(
+        &self,
+        size: u32,
+        bitmap_out: *mut HBITMAP,
+        alpha: *mut WTS_ALPHATYPE,
+    ) -> windows::core::Result<()> {
+        if bitmap_out.is_null() || alpha.is_null() {
+            return Err(E_POINTER.into());
+        }
+        let book = self.book.borrow();
+        let image = book.as_ref().and_then(cover).ok_or(E_FAIL)?;
+        unsafe {
+            *bitmap_out = bitmap(&image, size)?;
+            *alpha = WTSAT_RGB;
+        }
+        Ok(())
+    }
+}
+
+#[implement(IClassFactory)]
+struct ClassFactory;
+
+impl IClassFactory_Impl for ClassFactory_Impl {
+    fn CreateInstance(
+        &self,
+        outer: Ref<windows::core::IUnknown>,
+        iid: *const GUID,
+        object: *mut *mut c_void,
+    ) -> windows::core::Result<()> {
+        if object.is_null() {
+            return Err(E_POINTER.into());
+        }
+        unsafe { *object = std::ptr::null_mut() };
+        if !outer.is_null() {
+            return Err(CLASS_E_NOAGGREGATION.into());
+        }
+        let provider: IThumbnailProvider = ThumbnailProvider::new().into();
+        unsafe { provider.query(iid, object) }.ok()
+    }
+
+    fn LockServer(&self, lock: BOOL) -> windows::core::Result<()> {
+        if lock.as_bool() {
+            REFERENCES.fetch_add(1, Ordering::Relaxed);
+        } else {
+            REFERENCES.fetch_sub(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+/// Hands Explorer the class factory of the provider.
+///
+/// # Safety
+///
+/// Called by COM with valid pointers.
+#[no_mangle]
+pub unsafe extern "system" fn DllGetClassObject(
+    clsid: *const GUID,
+    iid: *const GUID,
+    object: *mut *mut c_void,
+) -> HRESULT {
+    if clsid.is_null() || object.is_null() {
+        return E_POINTER;
+    }
+    *object = std::ptr::null_mut();
+    if *clsid != THUMBNAIL_CLSID {
+        return CLASS_E_CLASSNOTAVAILABLE;
+    }
+    let factory: IClassFactory = ClassFactory.into();
+    factory.query(iid, object)
+}
+
+#[no_mangle]
+pub extern "system" fn DllCanUnloadNow() -> HRESULT {
+    if REFERENCES.load(Ordering::Relaxed) == 0 {
+        S_OK
+    } else {
+        S_FALSE
+    }
+}
@@ -0,0 +1,75 @@
+//! Toast notifications for background jobs that end while the window is
+//! not in front, and for questions answered by clicking them. They are sent as the app's user model id, which the
+//! installer sets on the Start menu shortcut, so portable copies without
+//! one get none.
+
+use tauri::{AppHandle, Manager};
+use windows::core::HSTRING;
+use windows::Data::Xml::Dom::XmlDocument;
+use windows::Win32::System::Com::{CoInitializeEx, COINIT_MULTITHREADED};
+use windows::UI::Notifications::{ToastNotification, ToastNotificationManager};
+
+use crate::error::Result;
+use crate::i18n;
+use crate::jobs::{JobProgress, JobState};
+use crate::utils::escape_xml;
+
+fn text(title: &str, body: Option<&str>) -> String {
+    let mut text = format!("<text>{}</text>", escape_xml(title));
+    if let Some(body) = body {
+        text.push_str(&format!("<text>{}</text>", escape_xml(body)));
+    }
+    format!("<visual><binding template=\"ToastGeneric\">{text}</binding></visual>")
+}
+
+fn toast(app: &AppHandle, content: &str) -> Result<()> {
+    // Jobs and the clipboard watcher run on threads without COM initialized
+    let _ = unsafe { CoInitializeEx(None, COINIT_MULTITHREADED) };
+    let xml = XmlDocument::new()?;
+    xml.LoadXml(&HSTRING::from(content))?;
+    let toast = ToastNotification::CreateToastNotification(&xml)?;
+    let app_id = HSTRING::from(&app.config().identifier);
+    ToastNotificationManager::CreateToastNotifierWithId(&app_id)?.Show(&toast)?;
+    Ok(())
+}
+
+fn show(app: &AppHandle, title: &str, body: Option<&str>) -> Result<()> {
+    toast(app, &format!("<toast>{}</toast>", text(title, body)))
+}
+
+/// Asks the user whether to do `action`, opening `link`, a `vlarch://`
+/// link, once they click it or the toast.
+pub fn ask(app: &AppHandle, title: &str, body: &str, action: &str, link: &str) -> Result<()> {
+    let link = escape_xml(link);
+    toast(
+        app,
+        &format!(
+            "<toast launch=\"{link}\" activationType=\"protocol\">{}<actions>\
+             <action content=\"{}\" arguments=\"{link}\" activationType=\"protocol\"/>\
+             </actions></toast>",
+            text(title, Some(body)),
+            escape_xml(action),
+        ),
+    )
+}
+
+/// Tells the user that the job of `progress` is done or failed, unless they
+/// are looking at the app.
+pub fn notify(app: &AppHandle, progress: &JobProgress) {
+    let Some(title) = progress.outcome() else {
+        return;
+    };
+    let in_front = app
+        .get_webview_window("main")
+        .is_some_and(|window| window.is_focused().unwrap_or(false));
+    if in_front {
+        return;
+    }
+    let body = match progress.state {
+        JobState::Failed => progress.message.as_deref(),
+        _ => None,
+    };
+    if let Err(e) = show(app, &i18n::t(app, title), body) {
+        log::warn!("Failed to show a notification: {e}");
+    }
+}
@@ -0,0 +1,339 @@
+//! Reading ZIM files, the archives Kiwix publishes Wikipedia, Wiktionary
+//! and other wikis in. The file is memory-mapped: directory entries are
+//! found by binary search on its URL and title pointer lists, and only the
+//! clusters holding the entries asked for are decompressed. Clusters are
+//! stored or compressed with Zstandard; the xz compression of dumps made
+//! before 2021 is not supported.
+
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::error::{Error, Result};
+use crate::formats::epub::stream::MappedFile;
+
+/// `ZIM\x04` read as a little-endian number.
+const MAGIC: u32 = 0x044D_495A;
+const HEADER_SIZE: usize = 80;
+const REDIRECT: u16 = 0xFFFF;
+/// Mime types of link targets and deleted entries, which have no content.
+const NO_CONTENT: [u16; 2] = [0xFFFE, 0xFFFD];
+/// Redirects followed before giving up on a loop.
+const MAX_REDIRECTS: usize = 8;
+/// Decompressed clusters kept, articles usually share them with neighbours.
+const MAX_CACHED_CLUSTERS: usize = 4;
+
+const COMPRESSION_NONE: u8 = 1;
+const COMPRESSION_XZ: u8 = 4;
+const COMPRESSION_ZSTD: u8 = 5;
+/// Set in the cluster info byte when blob offsets take 8 bytes.
+const EXTENDED_CLUSTER: u8 = 0x10;
+
+fn invalid(message: &str) -> Error {
+    Error::InvalidBook(format!("invalid ZIM file: {message}"))
+}
+
+enum Target {
+    Blob { cluster: u32, blob: u32 },
+    Redirect(u32),
+    None,
+}
+
+/// An entry of the directory, an article, a resource or a redirect.
+pub struct DirEntry {
+    mime: u16,
+    pub namespace: u8,
+    pub url: String,
+    /// The title, or the URL when the entry has none.
+    pub title: String,
+    target: Target,
+}
+
+pub struct ZimArchive {
+    data: MappedFile,
+    entry_count: u32,
+    cluster_count: u32,
+    url_pointers: usize,
+    title_pointers: usize,
+    cluster_pointers: usize,
+    /// Where the checksum starts, which ends the last cluster.
+    checksum: usize,
+    mime_types: Vec<String>,
+    content_namespace: u8,
+    /// Recently decompressed clusters, most recent last.
+    clusters: Mutex<Vec<(u32, Arc<Vec<u8>>)>>,
+}
+
+fn read_u16(data: &[u8], at: usize) -> Result<u16> {
+    data.get(at..at + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| invalid("truncated"))
+}
+
+fn read_u32(data: &[u8], at: usize) -> Result<u32> {
+    data.get(at..at + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| invalid("truncated"))
+}
+
+fn read_u64(data: &[u8], at: usize) -> Result<u64> {
+    data.get(at..at + 8)
+        .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| invalid("truncated"))
+}
+
+fn read_offset(data: &[u8], at: usize) -> Result<usize> {
+    usize::try_from(read_u64(data, at)?).map_err(|_| invalid("offset out of range"))
+}
+
+/// The NUL-terminated string at `at`, and where the next field starts.
+fn read_string(data: &[u8], at: usize) -> Result<(String, usize)> {
+    let rest = data.get(at..).ok_or_else(|| invalid("truncated"))?;
+    let len = rest
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or_else(|| invalid("unterminated string"))?;
+    Ok((
+        String::from_utf8_lossy(&rest[..len]).into_owned(),
+        at + len + 1,
+    ))
+}
+
+/// Blob `index` of a cluster body, whose offsets are `width` bytes each.
+fn blob(body: &[u8], index: u32, width: usize) -> Result<Vec<u8>> {
+    let offset = |i: usize| -> Result<usize> {
+        if width == 8 {
+            read_offset(body, i * 8)
+        } else {
+            Ok(read_u32(body, i * 4)? as usize)
+        }
+    };
+    let count = offset(0)? / width;
+    let index = index as usize;
+    if index + 1 >= count {
+        return Err(invalid("no such blob"));
+    }
+    body.get(offset(index)?..offset(index + 1)?)
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| invalid("blob out of bounds"))
+}
+
+impl ZimArchive {
+    pub fn open(path: &Path) -> Result<Self> {
+        let data = MappedFile::open(path)?;
+        let bytes = data.as_ref();
+        if bytes.len() < HEADER_SIZE || read_u32(bytes, 0)? != MAGIC {
+            return Err(invalid("not a ZIM file"));
+        }
+        let version = (read_u16(bytes, 4)?, read_u16(bytes, 6)?);
+        let entry_count = read_u32(bytes, 24)?;
+        let cluster_count = read_u32(bytes, 28)?;
+        let mut mime_types = Vec::new();
+        let mut at = read_offset(bytes, 56)?;
+        loop {
+            let (mime, next) = read_string(bytes, at)?;
+            if mime.is_empty() {
+                break;
+            }
+            mime_types.push(mime);
+            at = next;
+        }
+        Ok(Self {
+            entry_count,
+            cluster_count,
+            url_pointers: read_offset(bytes, 32)?,
+            title_pointers: read_offset(bytes, 40)?,
+            cluster_pointers: read_offset(bytes, 48)?,
+            checksum: read_offset(bytes, 72)?.min(bytes.len()),
+            mime_types,
+            // Since 6.1 all content is in `C`, before articles were in `A`
+            content_namespace: if version >= (6, 1) { b'C' } else { b'A' },
+            clusters: Mutex::new(Vec::new()),
+            data,
+        })
+    }
+
+    fn bytes(&self) -> &[u8] {
+        self.data.as_ref()
+    }
+
+    /// The namespace articles are in.
+    pub fn content_namespace(&self) -> u8 {
+        self.content_namespace
+    }
+
+    pub fn entry_count(&self) -> u32 {
+        self.entry_count
+    }
+
+    /// Entry `index` in URL order.
+    pub fn entry(&self, index: u32) -> Result<DirEntry> {
+        if index >= self.entry_count {
+            return Err(invalid("no such entry"));
+        }
+        let bytes = self.bytes();
+        let at = read_offset(bytes, self.url_pointers + index as usize * 8)?;
+        let mime = read_u16(bytes, at)?;
+        let namespace = *bytes.get(at + 3).ok_or_else(|| invalid("truncated"))?;
+        let (target, strings) = match mime {
+            REDIRECT => (Target::Redirect(read_u32(bytes, at + 8)?), at + 12),
+            _ if NO_CONTENT.contains(&mime) => (Target::None, at + 8),
+            _ => (
+                Target::Blob {
+                    cluster: read_u32(bytes, at + 8)?,
+                    blob: read_u32(bytes, at + 12)?,
+                },
+                at + 16,
+            ),
+        };
+        let (url, next) = read_string(bytes, strings)?;
+        let (title, _) = read_string(bytes, next)?;
+        Ok(DirEntry {
+            mime,
+            namespace,
+            title: if title.is_empty() { url.clone() } else { title },
+            url,
+            target,
+        })
+    }
+
+    /// Binary search over the entries in the order `key` sorts them by.
+    fn search(
+        &self,
+        key: impl Fn(u32) -> Result<(u8, String)>,
+        target: (u8, &str),
+    ) -> Result<Option<u32>> {
+        let (mut low, mut high) = (0, self.entry_count);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let (namespace, name) = key(mid)?;
+            match (namespace, name.as_str()).cmp(&target) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Ok(Some(mid)),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Index of the entry at `url` in `namespace`.
+    pub fn find_by_url(&self, namespace: u8, url: &str) -> Result<Option<u32>> {
+        self.search(
+            |index| {
+                let entry = self.entry(index)?;
+                Ok((entry.namespace, entry.url))
+            },
+            (namespace, url),
+        )
+    }
+
+    /// Index of the entry titled `title` in `namespace`.
+    pub fn find_by_title(&self, namespace: u8, title: &str) -> Result<Option<u32>> {
+        let bytes = self.bytes();
+        self.search(
+            |position| {
+                let index = read_u32(bytes, self.title_pointers + position as usize * 4)?;
+                let entry = self.entry(index)?;
+                Ok((entry.namespace, entry.title))
+            },
+            (namespace, title),
+        )
+        .and_then(|position| {
+            position
+                .map(|position| read_u32(bytes, self.title_pointers + position as usize * 4))
+                .transpose()
+        })
+    }
+
+    /// The entry `index` redirects to, or itself.
+    pub fn resolve(&self, index: u32) -> Result<(u32, DirEntry)> {
+        let mut index = index;
+        for _ in 0..MAX_REDIRECTS {
+            let entry = self.entry(index)?;
+            match entry.target {
+                Target::Redirect(next) => index = next,
+                _ => return Ok((index, entry)),
+            }
+        }
+        Err(invalid("redirect loop"))
+    }
+
+    pub fn mime_type(&self, entry: &DirEntry) -> &str {
+        self.mime_types
+            .get(entry.mime as usize)
+            .map_or("application/octet-stream", String::as_str)
+    }
+
+    /// Decompressed body of a compressed cluster, from the cache if it was
+    /// read recently.
+    fn decompressed(&self, cluster: u32, compressed: &[u8]) -> Result<Arc<Vec<u8>>> {
+        let mut clusters = self.clusters.lock().unwrap();
+        if let Some(i) = clusters.iter().position(|(c, _)| *c == cluster) {
+            let entry = clusters.remove(i);
+            let body = entry.1.clone();
+            clusters.push(entry);
+            return Ok(body);
+        }
+        let body = Arc::new(zstd::stream::decode_all(compressed)?);
+        if clusters.len() >= MAX_CACHED_CLUSTERS {
+            clusters.remove(0);
+        }
+        clusters.push((cluster, body.clone()));
+        Ok(body)
+    }
+
+    /// Content of `entry`, which must not be a redirect.
+    pub fn content(&self, entry: &DirEntry) -> Result<Vec<u8>> {
+        let Target::Blob {
+            cluster,
+            blob: index,
+        } = entry.target
+        else {
+            return Err(invalid("entry has no content"));
+        };
+        if cluster >= self.cluster_count {
+            return Err(invalid("no such cluster"));
+        }
+        let bytes = self.bytes();
+        let start = read_offset(bytes, self.cluster_pointers + cluster as usize * 8)?;
+        let end = if cluster + 1 < self.cluster_count {
+            read_offset(bytes, self.cluster_pointers + (cluster as usize + 1) * 8)?
+        } else {
+            self.checksum
+        };
+        let cluster_bytes = bytes
+            .get(start..end)
+            .filter(|cluster| !cluster.is_empty())
+            .ok_or_else(|| invalid("cluster out of bounds"))?;
+        let info = cluster_bytes[0];
+        let width = if info & EXTENDED_CLUSTER != 0 { 8 } else { 4 };
+        match info & 0x0F {
+            0 | COMPRESSION_NONE => blob(&cluster_bytes[1..], index, width),
+            COMPRESSION_ZSTD => blob(
+                &self.decompressed(cluster, &cluster_bytes[1..])?,
+                index,
+                width,
+            ),
+            COMPRESSION_XZ => Err(Error::UnsupportedFormat(
+                "xz-compressed ZIM file".to_string(),
+            )),
+            _ => Err(invalid("unknown cluster compression")),
+        }
+    }
+
+    /// Content and mime type of the entry at `url` in `namespace`,
+    /// following redirects.
+    pub fn read(&self, namespace: u8, url: &str) -> Result<Option<(String, Vec<u8>)>> {
+        let Some(index) = self.find_by_url(namespace, url)? else {
+            return Ok(None);
+        };
+        let (_, entry) = self.resolve(index)?;
+        let data = self.content(&entry)?;
+        Ok(Some((self.mime_type(&entry).to_string(), data)))
+    }
+
+    /// Metadata value `name`, such as `Title` or `Language`.
+    pub fn metadata(&self, name: &str) -> Option<String> {
+        let (_, data) = self.read(b'M', name).ok()??;
+        Some(String::from_utf8_lossy(&data).trim().to_string()).filter(|value| !value.is_empty())
+    }
+}
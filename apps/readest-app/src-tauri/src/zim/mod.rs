@@ -0,0 +1,342 @@
+//! Offline Wikipedia and Wiktionary lookups in ZIM files from Kiwix. The
+//! selection popup gets the article HTML with its links and images
+//! pointing at the `zim://` protocol, which serves any entry of a
+//! configured archive as `zim://localhost/<id>/<namespace>/<url>`.
+
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use percent_encoding::percent_decode_str;
+use regex::{Captures, Regex};
+use serde::Serialize;
+use tauri::http::{header, Request, Response, StatusCode};
+use tauri::{command, AppHandle, Manager};
+use url::Url;
+
+use crate::error::Result;
+use crate::store;
+
+mod archive;
+
+use archive::ZimArchive;
+
+pub const PROTOCOL: &str = "zim";
+const ARCHIVES_FILE: &str = "zim-archives.json";
+
+/// Where the webview reaches custom protocols.
+#[cfg(any(windows, target_os = "android"))]
+const PROTOCOL_ORIGIN: &str = "http://zim.localhost";
+#[cfg(not(any(windows, target_os = "android")))]
+const PROTOCOL_ORIGIN: &str = "zim://localhost";
+
+struct Loaded {
+    path: PathBuf,
+    id: String,
+    archive: Option<Arc<ZimArchive>>,
+    name: String,
+}
+
+impl Loaded {
+    fn open(path: PathBuf) -> Self {
+        let archive = match ZimArchive::open(&path) {
+            Ok(archive) => Some(Arc::new(archive)),
+            Err(e) => {
+                log::warn!("Failed to open ZIM file {path:?}: {e}");
+                None
+            }
+        };
+        let name = archive
+            .as_ref()
+            .and_then(|archive| archive.metadata("Title"))
+            .unwrap_or_else(|| {
+                path.file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned())
+                    .unwrap_or_default()
+            });
+        Self {
+            id: archive_id(&path),
+            path,
+            archive,
+            name,
+        }
+    }
+
+    fn info(&self) -> ZimInfo {
+        ZimInfo {
+            id: self.id.clone(),
+            path: self.path.clone(),
+            name: self.name.clone(),
+            language: self
+                .archive
+                .as_ref()
+                .and_then(|archive| archive.metadata("Language")),
+            entry_count: self
+                .archive
+                .as_ref()
+                .map_or(0, |archive| archive.entry_count()),
+            available: self.archive.is_some(),
+        }
+    }
+}
+
+fn archive_id(path: &Path) -> String {
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ZimInfo {
+    /// Id of the archive in `zim://` URLs.
+    pub id: String,
+    pub path: PathBuf,
+    pub name: String,
+    /// ISO 639-3 codes, such as `eng`.
+    pub language: Option<String>,
+    pub entry_count: u32,
+    /// Whether the file could be opened, like [`crate::dict::DictionaryInfo`].
+    pub available: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ZimArticle {
+    /// Name of the archive the article comes from.
+    pub archive: String,
+    pub title: String,
+    /// The article's own `zim://` URL.
+    pub url: String,
+    /// The body of the article with its links and images made absolute.
+    pub html: String,
+}
+
+/// The configured archives in lookup order, opened on first use.
+#[derive(Default)]
+pub struct ZimArchives(Mutex<Option<Vec<Loaded>>>);
+
+fn with_archives<T>(app: &AppHandle, f: impl FnOnce(&mut Vec<Loaded>) -> T) -> T {
+    let state = app.state::<ZimArchives>();
+    let mut archives = state.0.lock().unwrap();
+    let archives = archives.get_or_insert_with(|| {
+        let paths: Vec<PathBuf> = store::load(app, ARCHIVES_FILE);
+        paths.into_iter().map(Loaded::open).collect()
+    });
+    f(archives)
+}
+
+fn save(app: &AppHandle, archives: &[Loaded]) -> Result<Vec<ZimInfo>> {
+    let paths = archives.iter().map(|a| &a.path).collect::<Vec<_>>();
+    store::save(app, ARCHIVES_FILE, &paths)?;
+    Ok(archives.iter().map(Loaded::info).collect())
+}
+
+/// Spellings of a selection to look for, since article titles are
+/// capitalized in Wikipedia and lowercase in Wiktionary.
+fn title_candidates(word: &str) -> Vec<String> {
+    let word = word.trim();
+    let capitalize = |word: &str| {
+        let mut chars = word.chars();
+        chars
+            .next()
+            .map(|first| first.to_uppercase().chain(chars).collect::<String>())
+            .unwrap_or_default()
+    };
+    let lower = word.to_lowercase();
+    let mut candidates = Vec::new();
+    for candidate in [
+        word.to_string(),
+        capitalize(word),
+        lower.clone(),
+        capitalize(&lower),
+    ] {
+        if !candidate.is_empty() && !candidates.contains(&candidate) {
+            candidates.push(candidate);
+        }
+    }
+    candidates
+}
+
+/// The article of `archive` titled like `word`, with its URL path.
+fn find_article(archive: &ZimArchive, word: &str) -> Result<Option<(String, String, Vec<u8>)>> {
+    let namespace = archive.content_namespace();
+    for candidate in title_candidates(word) {
+        let index = match archive.find_by_title(namespace, &candidate)? {
+            Some(index) => Some(index),
+            None => archive.find_by_url(namespace, &candidate.replace(' ', "_"))?,
+        };
+        let Some(index) = index else {
+            continue;
+        };
+        let (_, entry) = archive.resolve(index)?;
+        if !archive.mime_type(&entry).starts_with("text/html") {
+            continue;
+        }
+        let path = format!("{}/{}", entry.namespace as char, entry.url);
+        return Ok(Some((entry.title.clone(), path, archive.content(&entry)?)));
+    }
+    Ok(None)
+}
+
+/// The body of an article page, without scripts, with relative links and
+/// images resolved against `base` so they work outside the page.
+fn rewrite_article(html: &str, base: &Url) -> String {
+    static BODY: OnceLock<Regex> = OnceLock::new();
+    let body = BODY
+        .get_or_init(|| Regex::new(r"(?is)<body[^>]*>(.*)</body>").expect("valid body pattern"));
+    let html = body
+        .captures(html)
+        .and_then(|captures| captures.get(1))
+        .map_or(html, |body| body.as_str());
+    static SCRIPTS: OnceLock<Regex> = OnceLock::new();
+    let scripts = SCRIPTS
+        .get_or_init(|| Regex::new(r"(?is)<script\b.*?</script>").expect("valid script pattern"));
+    let html = scripts.replace_all(html, "");
+    // Responsive image sources would need resolving one by one
+    static SRCSET: OnceLock<Regex> = OnceLock::new();
+    let srcset = SRCSET.get_or_init(|| {
+        Regex::new(r#"(?i)\ssrcset\s*=\s*("[^"]*"|'[^']*')"#).expect("valid srcset pattern")
+    });
+    let html = srcset.replace_all(&html, "");
+    static LINKS: OnceLock<Regex> = OnceLock::new();
+    let links = LINKS.get_or_init(|| {
+        Regex::new(r#"(?i)(\s(?:src|href)\s*=\s*)(?:"([^"]*)"|'([^']*)')"#)
+            .expect("valid link pattern")
+    });
+    links
+        .replace_all(&html, |captures: &Captures| {
+            let value = captures
+                .get(2)
+                .or_else(|| captures.get(3))
+                .map_or("", |value| value.as_str());
+            let resolved = if value.starts_with('#') {
+                value.to_string()
+            } else {
+                base.join(value)
+                    .map_or_else(|_| value.to_string(), String::from)
+            };
+            format!("{}\"{}\"", &captures[1], resolved.replace('"', "%22"))
+        })
+        .into_owned()
+}
+
+#[command]
+pub async fn get_zim_archives(app: AppHandle) -> Result<Vec<ZimInfo>> {
+    let archives = tauri::async_runtime::spawn_blocking(move || {
+        with_archives(&app, |archives| archives.iter().map(Loaded::info).collect())
+    })
+    .await?;
+    Ok(archives)
+}
+
+/// Adds a ZIM file after the archives already configured.
+#[command]
+pub async fn add_zim_archive(app: AppHandle, path: PathBuf) -> Result<Vec<ZimInfo>> {
+    tauri::async_runtime::spawn_blocking(move || {
+        // Fail on files that cannot be read rather than list them
+        ZimArchive::open(&path)?;
+        let loaded = Loaded::open(path);
+        with_archives(&app, |archives| {
+            match archives.iter_mut().find(|a| a.path == loaded.path) {
+                Some(existing) => *existing = loaded,
+                None => archives.push(loaded),
+            }
+            save(&app, archives)
+        })
+    })
+    .await?
+}
+
+#[command]
+pub async fn remove_zim_archive(app: AppHandle, path: PathBuf) -> Result<Vec<ZimInfo>> {
+    tauri::async_runtime::spawn_blocking(move || {
+        with_archives(&app, |archives| {
+            archives.retain(|a| a.path != path);
+            save(&app, archives)
+        })
+    })
+    .await?
+}
+
+/// Looks `word` up in every available archive, returning the article of
+/// each that has one.
+#[command]
+pub async fn zim_lookup(app: AppHandle, word: String) -> Result<Vec<ZimArticle>> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let archives = with_archives(&app, |archives| {
+            archives
+                .iter()
+                .filter_map(|a| Some((a.id.clone(), a.name.clone(), a.archive.clone()?)))
+                .collect::<Vec<_>>()
+        });
+        let word = word.trim_matches(|c: char| !c.is_alphanumeric() && c != ' ');
+        let mut articles = Vec::new();
+        for (id, name, archive) in archives {
+            let (title, path, data) = match find_article(&archive, word) {
+                Ok(Some(article)) => article,
+                Ok(None) => continue,
+                Err(e) => {
+                    log::warn!("Lookup of {word:?} in {name} failed: {e}");
+                    continue;
+                }
+            };
+            let url = Url::parse(&format!("{PROTOCOL_ORIGIN}/{id}/"))?.join(&path)?;
+            articles.push(ZimArticle {
+                archive: name,
+                title,
+                html: rewrite_article(&String::from_utf8_lossy(&data), &url),
+                url: url.into(),
+            });
+        }
+        Ok(articles)
+    })
+    .await?
+}
+
+fn respond_status(status: StatusCode) -> Response<Cow<'static, [u8]>> {
+    Response::builder()
+        .status(status)
+        .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+        .body(Cow::Borrowed(&[][..]))
+        .unwrap()
+}
+
+/// Handles a `zim://` request.
+pub fn handle_request(app: &AppHandle, request: &Request<Vec<u8>>) -> Response<Cow<'static, [u8]>> {
+    let path = request.uri().path().trim_start_matches('/');
+    let Some((id, rest)) = path.split_once('/') else {
+        return respond_status(StatusCode::NOT_FOUND);
+    };
+    let rest = percent_decode_str(rest).decode_utf8_lossy();
+    let Some((namespace, url)) = rest.split_once('/') else {
+        return respond_status(StatusCode::NOT_FOUND);
+    };
+    let &[namespace] = namespace.as_bytes() else {
+        return respond_status(StatusCode::NOT_FOUND);
+    };
+    let archive = with_archives(app, |archives| {
+        archives
+            .iter()
+            .find(|a| a.id == id)
+            .and_then(|a| a.archive.clone())
+    });
+    let Some(archive) = archive else {
+        return respond_status(StatusCode::NOT_FOUND);
+    };
+    match archive.read(namespace, url) {
+        Ok(Some((mime, data))) => Response::builder()
+            .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+            .header(header::CONTENT_TYPE, mime)
+            .header(header::CONTENT_LENGTH, data.len())
+            .body(Cow::Owned(data))
+            .unwrap(),
+        Ok(None) => respond_status(StatusCode::NOT_FOUND),
+        Err(e) => {
+            log::warn!("Failed to read {rest} from ZIM file {id}: {e}");
+            respond_status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
@@ -26,7 +26,8 @@ export function useOpenWithBooks() {
     if (filePath.startsWith('file://')) {
       filePath = decodeURI(filePath.replace('file://', ''));
     }
-    if (!/^(https?:|data:|blob:)/i.test(filePath)) {
+    // vlarch:// links are handled by the deep link events from the Rust side
+    if (!/^(https?:|data:|blob:|vlarch:)/i.test(filePath)) {
       const settings = useSettingsStore.getState().settings;
       if (appService?.hasWindow && settings.openBookInNewWindow) {
         showLibraryWindow(appService, [filePath]);
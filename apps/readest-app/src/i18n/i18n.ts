@@ -2,6 +2,8 @@ import i18n from 'i18next';
 import { initReactI18next } from 'react-i18next';
 import HttpApi from 'i18next-http-backend';
 import LanguageDetector from 'i18next-browser-languagedetector';
+import { invoke } from '@tauri-apps/api/core';
+import { isTauriAppPlatform } from '@/services/environment';
 import { options } from '../../i18next-scanner.config';
 
 i18n
@@ -41,6 +43,10 @@ i18n
 
 i18n.on('languageChanged', (lng) => {
   console.log('Language changed to', lng);
+  if (isTauriAppPlatform()) {
+    // The native menus and the tray follow the language of the app
+    invoke('set_locale', { locale: lng }).catch(() => {});
+  }
 });
 
 export default i18n;
@@ -41,6 +41,7 @@ declare global {
   interface Window {
     IS_ROUNDED?: boolean;
     __READEST_UPDATER_DISABLED?: boolean;
+    __VLARCH_DATA_DIRS?: { settings: string; data: string; cache: string; log: string };
   }
 }
 
@@ -63,10 +64,42 @@ if (typeof window !== 'undefined') {
   setTimeout(initializeOSType, 0);
 }
 
+// In portable mode the backend keeps the app files in a directory of its own
+// instead of the base directories of the system, so paths are absolute there
+const resolvePortablePath = (path: string, base: BaseDir): ResolvedPath | null => {
+  const dirs = typeof window !== 'undefined' ? window.__VLARCH_DATA_DIRS : undefined;
+  if (!dirs) return null;
+  const sep = OS_TYPE === 'windows' ? '\\' : '/';
+  const portable = (dir: string, fp: string): ResolvedPath => ({
+    baseDir: 0,
+    basePrefix: async () => '',
+    fp: fp ? `${dir}${sep}${fp}` : dir,
+    base,
+  });
+  switch (base) {
+    case 'Settings':
+      return portable(dirs.settings, path);
+    case 'Data':
+      return portable(dirs.data, path);
+    case 'Cache':
+      return portable(dirs.cache, path);
+    case 'Log':
+      return portable(dirs.log, path);
+    case 'Books':
+      return portable(dirs.data, `${LOCAL_BOOKS_SUBDIR}/${path}`);
+    case 'Fonts':
+      return portable(dirs.data, `${LOCAL_FONTS_SUBDIR}/${path}`);
+    default:
+      return null;
+  }
+};
+
 // Usage:
 // 1. baseDir + fp
 // 2. basePrefix + fp or the getPrefix util in FileSystem
 const resolvePath = (path: string, base: BaseDir): ResolvedPath => {
+  const portable = resolvePortablePath(path, base);
+  if (portable) return portable;
   switch (base) {
     case 'Settings':
       return { baseDir: BaseDirectory.AppConfig, basePrefix: appConfigDir, fp: path, base };